@@ -0,0 +1,123 @@
+//! ARM Instrumentation Trace Macrocell (ITM).
+//!
+//! The ITM sends words written to its stimulus ports to a connected debugger,
+//! usually over the SWO pin. It is available on Cortex-M3 and later cores.
+//! This driver only enables the ITM and a stimulus port: the debugger
+//! configures the SWO output (protocol and baud rate, which depends on the
+//! core clock), for example with OpenOCD:
+//!
+//! ```shell
+//! > tpiu config internal trace.bin uart off 64000000
+//! > itm port 1 on
+//! ```
+//!
+//! `Itm` implements `kernel::trace::TraceSink`, to send kernel trace records
+//! to a stimulus port.
+//!
+//! ```rust,ignore
+//! let itm = static_init!(cortexm::itm::Itm, cortexm::itm::Itm::new(1));
+//! itm.enable();
+//! ```
+
+use kernel::common::registers::interfaces::{ReadWriteable, Readable, Writeable};
+use kernel::common::registers::{register_bitfields, register_structs, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::trace::{TraceSink, RECORD_LEN};
+
+register_structs! {
+    ItmRegisters {
+        /// Stimulus Port Registers 0-31
+        (0x000 => stim: [ReadWrite<u32>; 32]),
+        (0x080 => _reserved0),
+        /// Trace Enable Register
+        (0xe00 => ter: ReadWrite<u32>),
+        (0xe04 => _reserved1),
+        /// Trace Privilege Register
+        (0xe40 => tpr: ReadWrite<u32>),
+        (0xe44 => _reserved2),
+        /// Trace Control Register
+        (0xe80 => tcr: ReadWrite<u32, TraceControl::Register>),
+        (0xe84 => _reserved3),
+        /// Lock Access Register
+        (0xfb0 => lar: WriteOnly<u32>),
+        (0xfb4 => @END),
+    }
+}
+
+register_bitfields![u32,
+    TraceControl [
+        /// Set while the ITM is sending a packet.
+        BUSY OFFSET(23) NUMBITS(1),
+        /// Identifier of the trace stream, for debuggers that merge streams.
+        TRACEBUSID OFFSET(16) NUMBITS(7),
+        /// Enable differential timestamps.
+        TSENA OFFSET(1) NUMBITS(1),
+        /// Enable synchronization packets.
+        SYNCENA OFFSET(2) NUMBITS(1),
+        /// Enable the ITM.
+        ITMENA OFFSET(0) NUMBITS(1)
+    ],
+    DebugExceptionMonitorControl [
+        /// Enable the DWT and ITM units.
+        TRCENA OFFSET(24) NUMBITS(1)
+    ]
+];
+
+const ITM: StaticRef<ItmRegisters> = unsafe { StaticRef::new(0xE000_0000 as *const ItmRegisters) };
+
+/// Debug Exception and Monitor Control Register, in the core debug block.
+const DEMCR: StaticRef<ReadWrite<u32, DebugExceptionMonitorControl::Register>> =
+    unsafe { StaticRef::new(0xE000_EDFC as *const _) };
+
+/// Key to unlock writes to the ITM registers.
+const UNLOCK_KEY: u32 = 0xC5AC_CE55;
+
+/// A stimulus port of the ITM.
+pub struct Itm {
+    port: usize,
+}
+
+impl Itm {
+    /// `port` is the stimulus port to write to, from `0` to `31`.
+    pub const fn new(port: usize) -> Itm {
+        Itm { port: port }
+    }
+
+    /// Enable the ITM and this stimulus port.
+    pub unsafe fn enable(&self) {
+        DEMCR.modify(DebugExceptionMonitorControl::TRCENA::SET);
+        ITM.lar.set(UNLOCK_KEY);
+        ITM.tcr.modify(
+            TraceControl::ITMENA::SET
+                + TraceControl::SYNCENA::SET
+                + TraceControl::TRACEBUSID.val(1),
+        );
+        ITM.ter.set(ITM.ter.get() | 1 << self.port);
+    }
+
+    /// Whether the ITM and this port are enabled. They may also have been
+    /// enabled by the debugger.
+    pub fn is_enabled(&self) -> bool {
+        ITM.tcr.is_set(TraceControl::ITMENA) && ITM.ter.get() & (1 << self.port) != 0
+    }
+
+    /// Write `word` to the port, waiting until its FIFO has room.
+    pub fn write_word(&self, word: u32) {
+        let stim = &ITM.stim[self.port];
+        // The port reads as `1` when it can accept a word.
+        while stim.get() & 1 == 0 {}
+        stim.set(word);
+    }
+}
+
+impl TraceSink for Itm {
+    fn write(&self, record: &[u8; RECORD_LEN]) {
+        // Without a debugger reading the port, the FIFO would never drain.
+        if !self.is_enabled() {
+            return;
+        }
+        for word in record.chunks(4) {
+            self.write_word(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        }
+    }
+}
@@ -8,6 +8,7 @@
 
 use core::fmt::Write;
 
+pub mod itm;
 pub mod mpu;
 pub mod nvic;
 pub mod scb;
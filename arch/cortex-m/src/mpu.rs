@@ -511,6 +511,25 @@ impl<const NUM_REGIONS: usize> kernel::mpu::MPU for MPU<NUM_REGIONS> {
         Some(mpu::Region::new(start as *const u8, size))
     }
 
+    fn remove_memory_region(
+        &self,
+        region: mpu::Region,
+        config: &mut Self::MpuConfig,
+    ) -> Result<(), ()> {
+        let (number, _) = config
+            .regions
+            .iter()
+            .enumerate()
+            .filter(|(number, _)| *number != APP_MEMORY_REGION_NUM)
+            .find(|(_, r)| r.location() == Some((region.start_address(), region.size())))
+            .ok_or(())?;
+
+        config.regions[number] = CortexMRegion::empty(number);
+        config.is_dirty.set(true);
+
+        Ok(())
+    }
+
     fn allocate_app_memory_region(
         &self,
         unallocated_memory_start: *const u8,
@@ -256,9 +256,38 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
             kernel::syscall::ContextSwitchReason::Interrupted
         };
 
+        // Faults the hard fault handler did not see leave no fault status.
+        if switch_reason == kernel::syscall::ContextSwitchReason::Fault && app_fault != 1 {
+            write_volatile(&mut SCB_REGISTERS, [0; 5]);
+        }
+
         (switch_reason, Some(new_stack_pointer as *const u8))
     }
 
+    fn get_stack_pointer(&self, state: &CortexMStoredState) -> *const u8 {
+        state.psp as *const u8
+    }
+
+    fn get_fault_info(&self, _state: &CortexMStoredState) -> kernel::syscall::FaultInfo {
+        // Only the last fault is kept, and the kernel asks right after it.
+        let scb_registers = unsafe { read_volatile(&SCB_REGISTERS) };
+        let cfsr = scb_registers[1];
+        let address = if cfsr & (1 << 7) != 0 {
+            // MMARVALID
+            Some(scb_registers[3])
+        } else if cfsr & (1 << 15) != 0 {
+            // BFARVALID
+            Some(scb_registers[4])
+        } else {
+            None
+        };
+        kernel::syscall::FaultInfo {
+            status: cfsr,
+            hard_fault_status: scb_registers[2],
+            address: address,
+        }
+    }
+
     unsafe fn print_context(
         &self,
         accessible_memory_start: *const u8,
@@ -407,6 +407,29 @@ impl<const MAX_AVAILABLE_REGIONS_OVER_TWO: usize> kernel::mpu::MPU
         Some(mpu::Region::new(start as *const u8, size))
     }
 
+    fn remove_memory_region(
+        &self,
+        region: mpu::Region,
+        config: &mut Self::MpuConfig,
+    ) -> Result<(), ()> {
+        let (number, _) = config
+            .regions
+            .iter()
+            .enumerate()
+            .filter(|(number, _)| !config.app_memory_region.contains(number))
+            .find(|(_, r)| {
+                r.map_or(false, |r| {
+                    r.location() == (region.start_address(), region.size())
+                })
+            })
+            .ok_or(())?;
+
+        config.regions[number] = None;
+        config.is_dirty.set(true);
+
+        Ok(())
+    }
+
     fn allocate_app_memory_region(
         &self,
         unallocated_memory_start: *const u8,
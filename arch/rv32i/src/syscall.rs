@@ -426,6 +426,29 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
         (ret, Some(new_stack_pointer as *const u8))
     }
 
+    fn get_stack_pointer(&self, state: &Riscv32iStoredState) -> *const u8 {
+        state.regs[R_SP] as *const u8
+    }
+
+    fn get_fault_info(&self, state: &Riscv32iStoredState) -> kernel::syscall::FaultInfo {
+        // `mtval` holds the faulting address for misaligned and access
+        // faults.
+        let address = match mcause::Trap::from(state.mcause as usize) {
+            mcause::Trap::Exception(mcause::Exception::InstructionMisaligned)
+            | mcause::Trap::Exception(mcause::Exception::InstructionFault)
+            | mcause::Trap::Exception(mcause::Exception::LoadMisaligned)
+            | mcause::Trap::Exception(mcause::Exception::LoadFault)
+            | mcause::Trap::Exception(mcause::Exception::StoreMisaligned)
+            | mcause::Trap::Exception(mcause::Exception::StoreFault) => Some(state.mtval),
+            _ => None,
+        };
+        kernel::syscall::FaultInfo {
+            status: state.mcause,
+            hard_fault_status: 0,
+            address: address,
+        }
+    }
+
     unsafe fn print_context(
         &self,
         _accessible_memory_start: *const u8,
@@ -17,6 +17,7 @@ use kernel::component::Component;
 use kernel::hil::gpio::Interrupt;
 use kernel::hil::i2c::I2CMaster;
 use kernel::hil::led::LedHigh;
+use kernel::hil::radio::RadioEnergy;
 use kernel::hil::symmetric_encryption::AES128;
 use kernel::hil::time::Alarm;
 use kernel::hil::time::Counter;
@@ -387,6 +388,25 @@ pub unsafe fn main() {
         nrf52840::pinmux::Pinmux::new(I2C_SDA_PIN as u32),
     );
     base_peripherals.twim1.set_master_client(sensors_i2c_bus);
+    base_peripherals.twim1.set_recovery_pins(
+        &nrf52840_peripherals.gpio_port[I2C_SCL_PIN],
+        &nrf52840_peripherals.gpio_port[I2C_SDA_PIN],
+    );
+
+    // Time out operations on a stuck bus
+    let virtual_alarm_i2c = static_init!(
+        capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+        capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+    );
+    let i2c_timer = static_init!(
+        capsules::virtual_i2c::MuxI2CTimer<
+            'static,
+            capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+        >,
+        capsules::virtual_i2c::MuxI2CTimer::new(sensors_i2c_bus, virtual_alarm_i2c)
+    );
+    virtual_alarm_i2c.set_alarm_client(i2c_timer);
+    sensors_i2c_bus.set_transaction_timer(i2c_timer);
 
     let apds9960_i2c = static_init!(
         capsules::virtual_i2c::I2CDevice,
@@ -508,6 +528,10 @@ pub unsafe fn main() {
         nrf52840::ieee802154_radio::Radio,
         nrf52840::aes::AesECB<'static>
     ));
+    ieee802154_radio.set_energy_scanner(&base_peripherals.ieee802154_radio);
+    base_peripherals
+        .ieee802154_radio
+        .set_energy_scan_client(ieee802154_radio);
 
     //--------------------------------------------------------------------------
     // FINAL SETUP AND BOARD BOOT
@@ -14,6 +14,17 @@
 //! )
 //! .finalize(());
 //! ```
+//!
+//! Boards that print long bursts of debug output on a slow UART can give the
+//! debug writer a larger buffer, of which the first 64 bytes are the output
+//! buffer:
+//!
+//! ```rust
+//! let debug_buffer = static_init!([u8; 4096], [0; 4096]);
+//! DebugWriterComponent::new(uart_mux)
+//!     .with_buffer(debug_buffer)
+//!     .finalize(());
+//! ```
 
 // Author: Brad Campbell <bradjc@virginia.edu>
 // Last modified: 11/07/2019
@@ -39,11 +50,32 @@ const DEBUG_BUFFER_SPLIT: usize = 64;
 
 pub struct DebugWriterComponent {
     uart_mux: &'static MuxUart<'static>,
+    buffer: Option<&'static mut [u8]>,
 }
 
 impl DebugWriterComponent {
     pub fn new(uart_mux: &'static MuxUart) -> DebugWriterComponent {
-        DebugWriterComponent { uart_mux: uart_mux }
+        DebugWriterComponent {
+            uart_mux: uart_mux,
+            buffer: None,
+        }
+    }
+
+    /// Use `buffer` instead of the default 1 KiB buffer.
+    pub fn with_buffer(mut self, buffer: &'static mut [u8]) -> DebugWriterComponent {
+        self.buffer = Some(buffer);
+        self
+    }
+}
+
+/// The buffer given to the component, or the default one.
+unsafe fn debug_buffer(buffer: Option<&'static mut [u8]>) -> &'static mut [u8] {
+    match buffer {
+        Some(buffer) => buffer,
+        None => static_init!(
+            [u8; 1024 * DEBUG_BUFFER_KBYTE],
+            [0; 1024 * DEBUG_BUFFER_KBYTE]
+        ),
     }
 }
 
@@ -55,10 +87,7 @@ impl Component for DebugWriterComponent {
     type Output = ();
 
     unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
-        let buf = static_init!(
-            [u8; 1024 * DEBUG_BUFFER_KBYTE],
-            [0; 1024 * DEBUG_BUFFER_KBYTE]
-        );
+        let buf = debug_buffer(self.buffer);
         let (output_buf, internal_buf) = buf.split_at_mut(DEBUG_BUFFER_SPLIT);
 
         // Create virtual device for kernel debug.
@@ -81,11 +110,18 @@ impl Component for DebugWriterComponent {
 
 pub struct DebugWriterNoMuxComponent<U: uart::Uart<'static> + uart::Transmit<'static> + 'static> {
     uart: &'static U,
+    buffer: Option<&'static mut [u8]>,
 }
 
 impl<U: uart::Uart<'static> + uart::Transmit<'static> + 'static> DebugWriterNoMuxComponent<U> {
     pub fn new(uart: &'static U) -> Self {
-        Self { uart }
+        Self { uart, buffer: None }
+    }
+
+    /// Use `buffer` instead of the default 1 KiB buffer.
+    pub fn with_buffer(mut self, buffer: &'static mut [u8]) -> Self {
+        self.buffer = Some(buffer);
+        self
     }
 }
 
@@ -96,10 +132,7 @@ impl<U: uart::Uart<'static> + uart::Transmit<'static> + 'static> Component
     type Output = ();
 
     unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
-        let buf = static_init!(
-            [u8; 1024 * DEBUG_BUFFER_KBYTE],
-            [0; 1024 * DEBUG_BUFFER_KBYTE]
-        );
+        let buf = debug_buffer(self.buffer);
         let (output_buf, internal_buf) = buf.split_at_mut(DEBUG_BUFFER_SPLIT);
 
         // Create virtual device for kernel debug.
@@ -0,0 +1,143 @@
+//! Components for the SSD1680 and IL0373 e-paper screens.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!
+//! let bus = components::bus::SpiMasterBusComponent::new().finalize(
+//!     components::spi_bus_component_helper!(
+//!         // spi type
+//!         nrf52840::spi::SPIM,
+//!         // chip select
+//!         &nrf52840::gpio::PORT[GPIO_D4],
+//!         // spi mux
+//!         spi_mux
+//!     ),
+//! );
+//!
+//! let epaper = components::epaper::EPaperComponent::new(mux_alarm).finalize(
+//!     components::epaper_component_helper!(
+//!         // screen
+//!         &capsules::epaper::SSD1680,
+//!         // bus type
+//!         capsules::bus::SpiMasterBus<
+//!             'static,
+//!             VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>,
+//!         >,
+//!         // bus
+//!         &bus,
+//!         // timer type
+//!         nrf52840::rtc::Rtc,
+//!         // pin type
+//!         nrf52::gpio::GPIOPin<'static>,
+//!         // dc
+//!         &nrf52840::gpio::PORT[GPIO_D3],
+//!         // reset
+//!         &nrf52840::gpio::PORT[GPIO_D2],
+//!         // busy
+//!         &nrf52840::gpio::PORT[GPIO_D5]
+//!     ),
+//! );
+//! epaper.init();
+//! ```
+use capsules::bus;
+use capsules::epaper::{EPaper, EPaperScreen};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use kernel::component::Component;
+use kernel::hil::gpio;
+use kernel::hil::time::{self, Alarm};
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! epaper_component_helper {
+    ($screen:expr, $B: ty, $bus:expr, $A:ty, $P:ty, $dc:expr, $reset:expr, $busy:expr $(,)?) => {{
+        use capsules::epaper::{EPaper, Step, BUFFER_SIZE, SEQUENCE_BUFFER_SIZE};
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use capsules::virtual_spi::VirtualSpiMasterDevice;
+        use core::mem::MaybeUninit;
+        let epaper_bus: &$B = $bus;
+        static mut BUFFER: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+        static mut SEQUENCE_BUFFER: [Step; SEQUENCE_BUFFER_SIZE] =
+            [Step::Nop; SEQUENCE_BUFFER_SIZE];
+        static mut epaper_alarm: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut epaper: MaybeUninit<EPaper<'static, VirtualMuxAlarm<'static, $A>, $B, $P>> =
+            MaybeUninit::uninit();
+        (
+            epaper_bus,
+            &mut epaper_alarm,
+            ($dc, $reset, $busy),
+            &mut epaper,
+            $screen,
+            &mut BUFFER,
+            &mut SEQUENCE_BUFFER,
+        )
+    };};
+}
+
+pub struct EPaperComponent<
+    A: 'static + time::Alarm<'static>,
+    B: 'static + bus::Bus<'static>,
+    P: 'static + gpio::Pin,
+> {
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    _bus: PhantomData<B>,
+    _pin: PhantomData<P>,
+}
+
+impl<A: 'static + time::Alarm<'static>, B: 'static + bus::Bus<'static>, P: 'static + gpio::Pin>
+    EPaperComponent<A, B, P>
+{
+    pub fn new(alarm_mux: &'static MuxAlarm<'static, A>) -> EPaperComponent<A, B, P> {
+        EPaperComponent {
+            alarm_mux: alarm_mux,
+            _bus: PhantomData,
+            _pin: PhantomData,
+        }
+    }
+}
+
+impl<A: 'static + time::Alarm<'static>, B: 'static + bus::Bus<'static>, P: 'static + gpio::Pin>
+    Component for EPaperComponent<A, B, P>
+{
+    type StaticInput = (
+        &'static B,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        (&'static P, &'static P, &'static P),
+        &'static mut MaybeUninit<EPaper<'static, VirtualMuxAlarm<'static, A>, B, P>>,
+        &'static EPaperScreen,
+        &'static mut [u8],
+        &'static mut [capsules::epaper::Step],
+    );
+    type Output = &'static EPaper<'static, VirtualMuxAlarm<'static, A>, B, P>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let epaper_alarm = static_init_half!(
+            static_buffer.1,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+
+        let (dc, reset, busy) = static_buffer.2;
+        let epaper = static_init_half!(
+            static_buffer.3,
+            EPaper<'static, VirtualMuxAlarm<'static, A>, B, P>,
+            EPaper::new(
+                static_buffer.0,
+                epaper_alarm,
+                dc,
+                reset,
+                busy,
+                static_buffer.5,
+                static_buffer.6,
+                static_buffer.4
+            )
+        );
+        static_buffer.0.set_client(epaper);
+        epaper_alarm.set_alarm_client(epaper);
+
+        epaper
+    }
+}
@@ -39,7 +39,6 @@ use kernel::static_init_half;
 macro_rules! hmac_mux_component_helper {
     ($A:ty, $T:ty $(,)?) => {{
         use capsules::virtual_hmac::MuxHmac;
-        use capsules::virtual_hmac::VirtualMuxHmac;
         use core::mem::MaybeUninit;
         static mut BUF1: MaybeUninit<MuxHmac<'static, $A, $T>> = MaybeUninit::uninit();
         &mut BUF1
@@ -47,15 +46,17 @@ macro_rules! hmac_mux_component_helper {
 }
 
 pub struct HmacMuxComponent<
-    A: 'static + digest::Digest<'static, T>,
+    A: 'static + digest::Digest<'static, T> + digest::HMACSha256,
     T: 'static + digest::DigestType,
 > {
     hmac: &'static A,
     phantom: PhantomData<&'static T>,
 }
 
-impl<A: 'static + digest::Digest<'static, T>, T: 'static + digest::DigestType>
-    HmacMuxComponent<A, T>
+impl<
+        A: 'static + digest::Digest<'static, T> + digest::HMACSha256,
+        T: 'static + digest::DigestType,
+    > HmacMuxComponent<A, T>
 {
     pub fn new(hmac: &'static A) -> HmacMuxComponent<A, T> {
         HmacMuxComponent {
@@ -65,14 +66,17 @@ impl<A: 'static + digest::Digest<'static, T>, T: 'static + digest::DigestType>
     }
 }
 
-impl<A: 'static + digest::Digest<'static, T>, T: 'static + digest::DigestType> Component
-    for HmacMuxComponent<A, T>
+impl<
+        A: 'static + digest::Digest<'static, T> + digest::HMACSha256,
+        T: 'static + digest::DigestType,
+    > Component for HmacMuxComponent<A, T>
 {
     type StaticInput = &'static mut MaybeUninit<MuxHmac<'static, A, T>>;
     type Output = &'static MuxHmac<'static, A, T>;
 
     unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
         let mux_hmac = static_init_half!(s, MuxHmac<'static, A, T>, MuxHmac::new(self.hmac));
+        digest::Digest::set_client(self.hmac, mux_hmac);
 
         mux_hmac
     }
@@ -83,7 +87,6 @@ impl<A: 'static + digest::Digest<'static, T>, T: 'static + digest::DigestType> C
 macro_rules! hmac_component_helper {
     ($A:ty, $T:ty $(,)?) => {{
         use capsules::hmac::HmacDriver;
-        use capsules::virtual_hmac::MuxHmac;
         use capsules::virtual_hmac::VirtualMuxHmac;
         use core::mem::MaybeUninit;
         static mut BUF1: MaybeUninit<VirtualMuxHmac<'static, $A, $T>> = MaybeUninit::uninit();
@@ -93,7 +96,10 @@ macro_rules! hmac_component_helper {
     };};
 }
 
-pub struct HmacComponent<A: 'static + digest::Digest<'static, T>, T: 'static + digest::DigestType> {
+pub struct HmacComponent<
+    A: 'static + digest::Digest<'static, T> + digest::HMACSha256,
+    T: 'static + digest::DigestType,
+> {
     board_kernel: &'static kernel::Kernel,
     mux_hmac: &'static MuxHmac<'static, A, T>,
     data_buffer: &'static mut [u8],
@@ -101,7 +107,11 @@ pub struct HmacComponent<A: 'static + digest::Digest<'static, T>, T: 'static + d
     phantom: PhantomData<&'static T>,
 }
 
-impl<A: 'static + digest::Digest<'static, T>, T: 'static + digest::DigestType> HmacComponent<A, T> {
+impl<
+        A: 'static + digest::Digest<'static, T> + digest::HMACSha256,
+        T: 'static + digest::DigestType,
+    > HmacComponent<A, T>
+{
     pub fn new(
         board_kernel: &'static kernel::Kernel,
         mux_hmac: &'static MuxHmac<'static, A, T>,
@@ -138,6 +148,7 @@ impl<
             VirtualMuxHmac<'static, A, T>,
             VirtualMuxHmac::new(self.mux_hmac)
         );
+        virtual_hmac_user.setup();
 
         let hmac = static_init_half!(
             s.1,
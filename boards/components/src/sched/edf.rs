@@ -0,0 +1,78 @@
+//! Component for an earliest-deadline-first scheduler.
+//!
+//! This provides one Component, EdfComponent.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let scheduler = components::sched::edf::EdfComponent::new(board_kernel, mux_alarm)
+//!     .finalize(components::edf_component_helper!(
+//!         nrf52::rtc::Rtc<'static>,
+//!         NUM_PROCS
+//!     ));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::component::Component;
+use kernel::hil::time;
+use kernel::static_init_half;
+use kernel::{EdfProcessNode, EdfSched};
+
+#[macro_export]
+macro_rules! edf_component_helper {
+    ($A:ty, $N:expr $(,)?) => {{
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        use kernel::hil::time::Time;
+        use kernel::{EdfProcessNode, EdfSched};
+        type Ticks = <VirtualMuxAlarm<'static, $A> as Time>::Ticks;
+        static mut BUF1: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<EdfSched<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        const NODE: EdfProcessNode<Ticks> = EdfProcessNode::new();
+        static mut BUF3: [EdfProcessNode<Ticks>; $N] = [NODE; $N];
+        (&mut BUF1, &mut BUF2, &BUF3)
+    };};
+}
+
+pub struct EdfComponent<A: 'static + time::Alarm<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: 'static + time::Alarm<'static>> EdfComponent<A> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> EdfComponent<A> {
+        EdfComponent {
+            board_kernel,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: 'static + time::Alarm<'static>> Component for EdfComponent<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<EdfSched<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static [EdfProcessNode<A::Ticks>],
+    );
+    type Output = &'static mut EdfSched<'static, VirtualMuxAlarm<'static, A>>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let (alarm_buf, sched_buf, proc_nodes) = static_buffer;
+        let scheduler_alarm = static_init_half!(
+            alarm_buf,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        static_init_half!(
+            sched_buf,
+            EdfSched<'static, VirtualMuxAlarm<'static, A>>,
+            EdfSched::new(self.board_kernel, scheduler_alarm, proc_nodes)
+        )
+    }
+}
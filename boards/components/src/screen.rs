@@ -25,6 +25,14 @@
 //!     components::screen::ScreenComponent::new(board_kernel, tft, Some(tft))
 //!         .finalize(components::screen_buffer_size!(40960));
 //! ```
+//!
+//! // Screen with a back buffer for a 240x240 RGB565 display
+//! ```rust
+//! let screen =
+//!     components::screen::ScreenComponent::new(board_kernel, tft, Some(tft))
+//!         .finalize(components::screen_buffer_size!(40960));
+//! screen.set_back_buffer(components::screen_buffer_size!(115200));
+//! ```
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::create_capability;
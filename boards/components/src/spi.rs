@@ -18,8 +18,8 @@
 //! ```rust
 //! let mux_spi = components::spi::SpiMuxComponent::new(&sam4l::spi::SPI).finalize(
 //!     components::spi_mux_component_helper!(sam4l::spi::SpiHw));
-//! let spi_syscalls = SpiSyscallComponent::new(mux_spi, 3).finalize(
-//!     components::spi_syscalls_component_helper!(sam4l::spi::SpiHw));
+//! let spi_syscalls = SpiSyscallComponent::new(board_kernel, mux_spi, 3, mux_alarm).finalize(
+//!     components::spi_syscall_component_helper!(sam4l::spi::SpiHw, sam4l::ast::Ast));
 //! let rf233_spi = SpiComponent::new(mux_spi, 3).finalize(
 //!     components::spi_component_helper!(sam4l::spi::SpiHw));
 //! ```
@@ -31,10 +31,12 @@ use core::mem::MaybeUninit;
 
 use capsules::spi_controller::{Spi, DEFAULT_READ_BUF_LENGTH, DEFAULT_WRITE_BUF_LENGTH};
 use capsules::spi_peripheral::SpiPeripheral;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules::virtual_spi::{MuxSpiMaster, SpiSlaveDevice, VirtualSpiMasterDevice};
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::hil::spi;
+use kernel::hil::time::{self, Alarm};
 use kernel::{create_capability, static_init, static_init_half};
 
 // Setup static space for the objects.
@@ -50,14 +52,17 @@ macro_rules! spi_mux_component_helper {
 
 #[macro_export]
 macro_rules! spi_syscall_component_helper {
-    ($S:ty $(,)?) => {{
+    ($S:ty, $A:ty $(,)?) => {{
         use capsules::spi_controller::Spi;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
         use capsules::virtual_spi::VirtualSpiMasterDevice;
         use core::mem::MaybeUninit;
         static mut BUF1: MaybeUninit<VirtualSpiMasterDevice<'static, $S>> = MaybeUninit::uninit();
-        static mut BUF2: MaybeUninit<Spi<'static, VirtualSpiMasterDevice<'static, $S>>> =
-            MaybeUninit::uninit();
-        (&mut BUF1, &mut BUF2)
+        static mut BUF2: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<
+            Spi<'static, VirtualSpiMasterDevice<'static, $S>, VirtualMuxAlarm<'static, $A>>,
+        > = MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2, &mut BUF3)
     };};
 }
 
@@ -98,10 +103,11 @@ pub struct SpiMuxComponent<S: 'static + spi::SpiMaster> {
     spi: &'static S,
 }
 
-pub struct SpiSyscallComponent<S: 'static + spi::SpiMaster> {
+pub struct SpiSyscallComponent<S: 'static + spi::SpiMaster, A: 'static + time::Alarm<'static>> {
     board_kernel: &'static kernel::Kernel,
     spi_mux: &'static MuxSpiMaster<'static, S>,
     chip_select: S::ChipSelect,
+    alarm_mux: &'static MuxAlarm<'static, A>,
 }
 
 pub struct SpiSyscallPComponent<S: 'static + spi::SpiSlave> {
@@ -138,26 +144,34 @@ impl<S: 'static + spi::SpiMaster> Component for SpiMuxComponent<S> {
     }
 }
 
-impl<S: 'static + spi::SpiMaster> SpiSyscallComponent<S> {
+impl<S: 'static + spi::SpiMaster, A: 'static + time::Alarm<'static>> SpiSyscallComponent<S, A> {
     pub fn new(
         board_kernel: &'static kernel::Kernel,
         mux: &'static MuxSpiMaster<'static, S>,
         chip_select: S::ChipSelect,
+        alarm_mux: &'static MuxAlarm<'static, A>,
     ) -> Self {
         SpiSyscallComponent {
             board_kernel: board_kernel,
             spi_mux: mux,
             chip_select: chip_select,
+            alarm_mux: alarm_mux,
         }
     }
 }
 
-impl<S: 'static + spi::SpiMaster> Component for SpiSyscallComponent<S> {
+impl<S: 'static + spi::SpiMaster, A: 'static + time::Alarm<'static>> Component
+    for SpiSyscallComponent<S, A>
+{
     type StaticInput = (
         &'static mut MaybeUninit<VirtualSpiMasterDevice<'static, S>>,
-        &'static mut MaybeUninit<Spi<'static, VirtualSpiMasterDevice<'static, S>>>,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<
+            Spi<'static, VirtualSpiMasterDevice<'static, S>, VirtualMuxAlarm<'static, A>>,
+        >,
     );
-    type Output = &'static Spi<'static, VirtualSpiMasterDevice<'static, S>>;
+    type Output =
+        &'static Spi<'static, VirtualSpiMasterDevice<'static, S>, VirtualMuxAlarm<'static, A>>;
 
     unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
         let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
@@ -168,11 +182,18 @@ impl<S: 'static + spi::SpiMaster> Component for SpiSyscallComponent<S> {
             VirtualSpiMasterDevice::new(self.spi_mux, self.chip_select)
         );
 
-        let spi_syscalls = static_init_half!(
+        let spi_alarm = static_init_half!(
             static_buffer.1,
-            Spi<'static, VirtualSpiMasterDevice<'static, S>>,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+
+        let spi_syscalls = static_init_half!(
+            static_buffer.2,
+            Spi<'static, VirtualSpiMasterDevice<'static, S>, VirtualMuxAlarm<'static, A>>,
             Spi::new(
                 syscall_spi_device,
+                spi_alarm,
                 self.board_kernel.create_grant(&grant_cap)
             )
         );
@@ -187,6 +208,7 @@ impl<S: 'static + spi::SpiMaster> Component for SpiSyscallComponent<S> {
 
         spi_syscalls.config_buffers(spi_read_buf, spi_write_buf);
         syscall_spi_device.set_client(spi_syscalls);
+        spi_alarm.set_alarm_client(spi_syscalls);
 
         spi_syscalls
     }
@@ -17,8 +17,12 @@
 //!        mux_alarm,
 //!        MAX_PAYLOAD_LEN,
 //!    )
-//!    .finalize();
+//!    .finalize(components::udp_mux_component_helper!(nrf52840::rtc::Rtc, 2));
 //! ```
+//!
+//! The optional second argument of the helper is the number of IPv6 packets
+//! 6LoWPAN can reassemble at the same time, from one or several neighbors.
+//! Each needs a 1280 byte buffer; the default is 1.
 
 // Author: Hudson Ayers <hayers@stanford.edu>
 // Last Modified: 5/21/2019
@@ -50,14 +54,14 @@ use kernel::{static_init, static_init_half};
 // The UDP stack requires several packet buffers:
 //
 //   1. RADIO_BUF: buffer the IP6_Sender uses to pass frames to the radio after fragmentation
-//   2. SIXLOWPAN_RX_BUF: Buffer to hold full IP packets after they are decompressed by 6LoWPAN
+//   2. RX_STATE_BUFS (in the helper): Buffers to hold full IP packets after they are
+//      decompressed and reassembled by 6LoWPAN, one per concurrent reassembly
 //   3. UDP_DGRAM: The payload of the IP6_Packet, which holds full IP Packets before they are tx'd.
 //
 //   Additionally, every capsule using the stack needs an additional buffer to craft packets for
 //   tx which can then be passed to the MuxUdpSender for tx.
 
 static mut RADIO_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
-static mut SIXLOWPAN_RX_BUF: [u8; 1280] = [0x00; 1280];
 
 pub const MAX_PAYLOAD_LEN: usize = 200; //The max size UDP message that can be sent by userspace apps or capsules
 static mut UDP_DGRAM: [u8; MAX_PAYLOAD_LEN] = [0; MAX_PAYLOAD_LEN];
@@ -77,7 +81,10 @@ static mut USED_KERNEL_PORTS: [Option<SocketBindingEntry>; MAX_NUM_BOUND_PORTS]
 // Setup static space for the objects.
 #[macro_export]
 macro_rules! udp_mux_component_helper {
-    ($A:ty $(,)?) => {{
+    ($A:ty $(,)?) => {
+        $crate::udp_mux_component_helper!($A, 1)
+    };
+    ($A:ty, $N:expr $(,)?) => {{
         use capsules;
         use capsules::net::sixlowpan::{sixlowpan_compression, sixlowpan_state};
         use capsules::net::udp::udp_send::MuxUdpSender;
@@ -93,7 +100,9 @@ macro_rules! udp_mux_component_helper {
                 sixlowpan_compression::Context,
             >,
         > = MaybeUninit::uninit();
-        static mut BUF3: MaybeUninit<sixlowpan_state::RxState<'static>> = MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<[sixlowpan_state::RxState<'static>; $N]> =
+            MaybeUninit::uninit();
+        static mut RX_STATE_BUFS: [[u8; 1280]; $N] = [[0x00; 1280]; $N];
         static mut BUF4: MaybeUninit<
             capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, $A>>,
         > = MaybeUninit::uninit();
@@ -106,8 +115,18 @@ macro_rules! udp_mux_component_helper {
                 >,
             >,
         > = MaybeUninit::uninit();
+        let rx_states = core::slice::from_raw_parts_mut(
+            BUF3.as_mut_ptr() as *mut MaybeUninit<sixlowpan_state::RxState<'static>>,
+            $N,
+        );
         (
-            &mut BUF0, &mut BUF1, &mut BUF2, &mut BUF3, &mut BUF4, &mut BUF5,
+            &mut BUF0,
+            &mut BUF1,
+            &mut BUF2,
+            rx_states,
+            &mut RX_STATE_BUFS[..],
+            &mut BUF4,
+            &mut BUF5,
         )
     };};
 }
@@ -155,7 +174,8 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
                 sixlowpan_compression::Context,
             >,
         >,
-        &'static mut MaybeUninit<sixlowpan_state::RxState<'static>>,
+        &'static mut [MaybeUninit<sixlowpan_state::RxState<'static>>],
+        &'static mut [[u8; 1280]],
         &'static mut MaybeUninit<
             capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
         >,
@@ -215,12 +235,14 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
 
         let sixlowpan_state = sixlowpan as &dyn sixlowpan_state::SixlowpanState;
         let sixlowpan_tx = sixlowpan_state::TxState::new(sixlowpan_state);
-        let default_rx_state = static_init_half!(
-            static_buffer.3,
-            sixlowpan_state::RxState<'static>,
-            sixlowpan_state::RxState::new(&mut SIXLOWPAN_RX_BUF)
-        );
-        sixlowpan_state.add_rx_state(default_rx_state);
+        for (rx_state, rx_buf) in static_buffer.3.iter_mut().zip(static_buffer.4.iter_mut()) {
+            let rx_state = static_init_half!(
+                rx_state,
+                sixlowpan_state::RxState<'static>,
+                sixlowpan_state::RxState::new(rx_buf)
+            );
+            sixlowpan_state.add_rx_state(rx_state);
+        }
         udp_mac.set_receive_client(sixlowpan);
 
         let tr_hdr = TransportHeader::UDP(UDPHeader::new());
@@ -237,7 +259,7 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
         // if multiple senders want to send to different addresses on a local network.
         // This will be fixed once we have an ipv6_nd cache mapping IP addresses to dst macs
         let ip_send = static_init_half!(
-            static_buffer.4,
+            static_buffer.5,
             capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
             capsules::net::ipv6::ipv6_send::IP6SendStruct::new(
                 ip6_dg,
@@ -268,7 +290,7 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
         ip_receive.set_client(udp_recv_mux);
 
         let udp_send_mux = static_init_half!(
-            static_buffer.5,
+            static_buffer.6,
             MuxUdpSender<
                 'static,
                 capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
@@ -63,6 +63,7 @@ struct Hail {
     spi: &'static capsules::spi_controller::Spi<
         'static,
         VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>,
+        VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
     >,
     nrf51822: &'static capsules::nrf51822_serialization::Nrf51822Serialization<'static>,
     adc: &'static capsules::adc::AdcDedicated<'static, sam4l::adc::Adc>,
@@ -301,8 +302,10 @@ pub unsafe fn main() {
     let mux_spi = components::spi::SpiMuxComponent::new(&peripherals.spi)
         .finalize(components::spi_mux_component_helper!(sam4l::spi::SpiHw));
     // Create the SPI system call capsule.
-    let spi_syscalls = components::spi::SpiSyscallComponent::new(board_kernel, mux_spi, 0)
-        .finalize(components::spi_syscall_component_helper!(sam4l::spi::SpiHw));
+    let spi_syscalls =
+        components::spi::SpiSyscallComponent::new(board_kernel, mux_spi, 0, mux_alarm).finalize(
+            components::spi_syscall_component_helper!(sam4l::spi::SpiHw, sam4l::ast::Ast),
+        );
 
     // LEDs
     let led = components::led::LedsComponent::new(components::led_component_helper!(
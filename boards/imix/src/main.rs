@@ -125,6 +125,7 @@ struct Imix {
     spi: &'static capsules::spi_controller::Spi<
         'static,
         VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>,
+        VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
     >,
     ipc: kernel::ipc::IPC<NUM_PROCS>,
     ninedof: &'static capsules::ninedof::NineDof<'static>,
@@ -136,6 +137,7 @@ struct Imix {
     >,
     nrf51822: &'static capsules::nrf51822_serialization::Nrf51822Serialization<'static>,
     nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+    power_manager: &'static kernel::power::PowerManager<'static>,
 }
 
 // The RF233 radio stack requires our buffers for its SPI operations:
@@ -179,6 +181,10 @@ impl kernel::Platform for Imix {
             _ => f(None),
         }
     }
+
+    fn power_manager(&self) -> Option<&kernel::power::PowerManager> {
+        Some(self.power_manager)
+    }
 }
 
 unsafe fn set_pin_primary_functions(peripherals: &Sam4lDefaultPeripherals) {
@@ -330,6 +336,15 @@ pub unsafe fn main() {
     let alarm = AlarmDriverComponent::new(board_kernel, mux_alarm)
         .finalize(components::alarm_component_helper!(sam4l::ast::Ast));
 
+    // The AST keeps running in RETENTION mode, so the only thing that must
+    // keep the chip out of it is an alarm about to expire. Peripherals that
+    // need the fast clocks are checked by the chip itself.
+    let power_clients = static_init!([&'static dyn kernel::power::PowerClient; 1], [mux_alarm]);
+    let power_manager = static_init!(
+        kernel::power::PowerManager<'static>,
+        kernel::power::PowerManager::new(power_clients)
+    );
+
     // # I2C and I2C Sensors
     let mux_i2c = static_init!(
         MuxI2C<'static>,
@@ -350,8 +365,9 @@ pub unsafe fn main() {
     let mux_spi = components::spi::SpiMuxComponent::new(&peripherals.spi)
         .finalize(components::spi_mux_component_helper!(sam4l::spi::SpiHw));
 
-    let spi_syscalls = SpiSyscallComponent::new(board_kernel, mux_spi, 2)
-        .finalize(components::spi_syscall_component_helper!(sam4l::spi::SpiHw));
+    let spi_syscalls = SpiSyscallComponent::new(board_kernel, mux_spi, 2, mux_alarm).finalize(
+        components::spi_syscall_component_helper!(sam4l::spi::SpiHw, sam4l::ast::Ast),
+    );
     let rf233_spi = SpiComponent::new(mux_spi, 3)
         .finalize(components::spi_component_helper!(sam4l::spi::SpiHw));
     let rf233 = RF233Component::new(
@@ -549,6 +565,7 @@ pub unsafe fn main() {
         usb_driver,
         nrf51822: nrf_serialization,
         nonvolatile_storage,
+        power_manager,
     };
 
     // Need to initialize the UART for the nRF51 serialization.
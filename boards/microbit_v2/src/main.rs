@@ -11,6 +11,7 @@
 use kernel::capabilities;
 use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
 use kernel::component::Component;
+use kernel::hil::adc::AdcReference;
 use kernel::hil::time::Counter;
 
 #[allow(unused_imports)]
@@ -364,7 +365,7 @@ pub unsafe fn main() {
     //--------------------------------------------------------------------------
     // ADC
     //--------------------------------------------------------------------------
-    base_peripherals.adc.calibrate();
+    let _ = base_peripherals.adc.calibrate();
 
     let adc_mux = components::adc::AdcMuxComponent::new(&base_peripherals.adc)
         .finalize(components::adc_mux_component_helper!(nrf52833::adc::Adc));
@@ -13,6 +13,7 @@ use kernel::capabilities;
 use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
 use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
 use kernel::component::Component;
+use kernel::hil::adc::AdcReference;
 use kernel::hil::gpio::Configure;
 use kernel::Platform;
 use kernel::{create_capability, debug, static_init};
@@ -363,6 +364,10 @@ pub unsafe fn main() {
         .select_ref_voltage(msp432::ref_module::ReferenceVoltage::Volt2_5);
     // Enable the internal temperature sensor on ADC Channel 22
     peripherals.adc_ref.enable_temp_sensor(true);
+    // Sample against that reference
+    let _ = peripherals
+        .adc
+        .set_voltage_reference(kernel::hil::adc::VoltageReference::Internal);
 
     let msp_exp432p4014 = MspExp432P401R {
         led: leds,
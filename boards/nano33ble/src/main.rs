@@ -17,6 +17,7 @@ use kernel::hil::gpio::Interrupt;
 use kernel::hil::gpio::Output;
 use kernel::hil::i2c::I2CMaster;
 use kernel::hil::led::LedLow;
+use kernel::hil::radio::RadioEnergy;
 use kernel::hil::symmetric_encryption::AES128;
 use kernel::hil::time::Counter;
 use kernel::hil::usb::Client;
@@ -416,6 +417,10 @@ pub unsafe fn main() {
         nrf52840::ieee802154_radio::Radio,
         nrf52840::aes::AesECB<'static>
     ));
+    ieee802154_radio.set_energy_scanner(&base_peripherals.ieee802154_radio);
+    base_peripherals
+        .ieee802154_radio
+        .set_energy_scan_client(ieee802154_radio);
     use capsules::net::ipv6::ip_utils::IPAddr;
 
     let local_ip_ifaces = static_init!(
@@ -14,6 +14,7 @@ use capsules::virtual_alarm::VirtualMuxAlarm;
 use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
 use kernel::component::Component;
 use kernel::hil::led::LedLow;
+use kernel::hil::radio::RadioEnergy;
 use kernel::hil::symmetric_encryption::AES128;
 use kernel::hil::time::Counter;
 #[allow(unused_imports)]
@@ -300,6 +301,10 @@ pub unsafe fn main() {
         nrf52840::ieee802154_radio::Radio,
         nrf52840::aes::AesECB<'static>
     ));
+    ieee802154_radio.set_energy_scanner(&base_peripherals.ieee802154_radio);
+    base_peripherals
+        .ieee802154_radio
+        .set_energy_scan_client(ieee802154_radio);
 
     let temp =
         components::temperature::TemperatureComponent::new(board_kernel, &base_peripherals.temp)
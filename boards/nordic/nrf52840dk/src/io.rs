@@ -9,6 +9,7 @@ use kernel::hil::uart::Configure;
 use nrf52840::gpio::Pin;
 
 use crate::CHIP;
+use crate::CRASH_DUMP;
 use crate::PROCESSES;
 
 enum Writer {
@@ -95,12 +96,14 @@ pub unsafe extern "C" fn panic_fmt(pi: &PanicInfo) -> ! {
     let led_kernel_pin = &nrf52840::gpio::GPIOPin::new(Pin::P0_13);
     let led = &mut led::LedLow::new(led_kernel_pin);
     let writer = &mut WRITER;
-    debug::panic(
-        &mut [led],
-        writer,
-        pi,
-        &cortexm4::support::nop,
-        &PROCESSES,
-        &CHIP,
-    )
+    match CRASH_DUMP {
+        Some(crash_dump) => {
+            // Also save what is printed to flash.
+            let mut writer = crash_dump.panic_writer(writer);
+            debug::panic_print(&mut writer, pi, &cortexm4::support::nop, &PROCESSES, &CHIP);
+            writer.save();
+        }
+        None => debug::panic_print(writer, pi, &cortexm4::support::nop, &PROCESSES, &CHIP),
+    }
+    debug::panic_blink_forever(&mut [led])
 }
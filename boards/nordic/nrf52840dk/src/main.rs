@@ -73,6 +73,7 @@ use capsules::virtual_alarm::VirtualMuxAlarm;
 use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
 use kernel::component::Component;
 use kernel::hil::led::LedLow;
+use kernel::hil::radio::RadioEnergy;
 use kernel::hil::symmetric_encryption::AES128;
 use kernel::hil::time::Counter;
 #[allow(unused_imports)]
@@ -135,6 +136,15 @@ static mut PROCESSES: [Option<&'static dyn kernel::procs::Process>; NUM_PROCS] =
 
 static mut CHIP: Option<&'static nrf52840::chip::NRF52<Nrf52840DefaultPeripherals>> = None;
 
+// Crash dump the panic handler saves to.
+static mut CRASH_DUMP: Option<
+    &'static capsules::crash_dump::CrashDump<'static, nrf52840::nvmc::Nvmc>,
+> = None;
+
+/// Flash page for the crash dump, the last one, at 0xFF000. The layout keeps
+/// it out of the application region.
+const CRASH_DUMP_PAGE: usize = 255;
+
 /// Dummy buffer that causes the linker to reserve enough space for the stack.
 #[no_mangle]
 #[link_section = ".stack_buffer"]
@@ -162,6 +172,8 @@ pub struct Platform {
     rng: &'static capsules::rng::RngDriver<'static>,
     temp: &'static capsules::temperature::TemperatureSensor<'static>,
     ipc: kernel::ipc::IPC<NUM_PROCS>,
+    ipc_queue: kernel::ipc_queue::IPCQueue<NUM_PROCS>,
+    shared_memory: &'static kernel::shared_memory::SharedMemory<NUM_PROCS>,
     analog_comparator: &'static capsules::analog_comparator::AnalogComparator<
         'static,
         nrf52840::acomp::Comparator<'static>,
@@ -172,6 +184,9 @@ pub struct Platform {
     >,
     nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
     udp_driver: &'static capsules::net::udp::UDPDriver<'static>,
+    crash_dump: &'static capsules::crash_dump::CrashDump<'static, nrf52840::nvmc::Nvmc>,
+    syscall_filter: kernel::TbfHeaderFilterDefaultAllow,
+    power_manager: &'static kernel::power::PowerManager<'static>,
 }
 
 impl kernel::Platform for Platform {
@@ -193,9 +208,28 @@ impl kernel::Platform for Platform {
             capsules::nonvolatile_storage_driver::DRIVER_NUM => f(Some(self.nonvolatile_storage)),
             capsules::net::udp::DRIVER_NUM => f(Some(self.udp_driver)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
+            kernel::ipc_queue::DRIVER_NUM => f(Some(&self.ipc_queue)),
+            kernel::shared_memory::DRIVER_NUM => f(Some(self.shared_memory)),
             _ => f(None),
         }
     }
+
+    fn filter_syscall(
+        &self,
+        process: &dyn kernel::procs::Process,
+        syscall: &kernel::syscall::Syscall,
+    ) -> Result<(), kernel::ErrorCode> {
+        kernel::SyscallFilter::filter_syscall(&self.syscall_filter, process, syscall)
+    }
+
+    fn process_fault_hook(&self, process: &dyn kernel::procs::Process) -> Result<(), ()> {
+        let _ = self.crash_dump.record_fault(process);
+        Err(())
+    }
+
+    fn power_manager(&self) -> Option<&kernel::power::PowerManager> {
+        Some(self.power_manager)
+    }
 }
 
 /// This is in a separate, inline(never) function so that its stack frame is
@@ -333,6 +367,13 @@ pub unsafe fn main() {
 
     let rtc = &base_peripherals.rtc;
     let _ = rtc.start();
+
+    // Lets apps slow down GPIO shift out for slow shift registers.
+    let shift_out_delay = static_init!(
+        capsules::gpio::TimeDelay<'static, nrf52840::rtc::Rtc<'static>>,
+        capsules::gpio::TimeDelay::new(rtc)
+    );
+    gpio.set_shift_out_delay(shift_out_delay);
     let mux_alarm = components::alarm::AlarmMuxComponent::new(rtc)
         .finalize(components::alarm_mux_component_helper!(nrf52840::rtc::Rtc));
     let alarm = components::alarm::AlarmDriverComponent::new(board_kernel, mux_alarm)
@@ -362,6 +403,21 @@ pub unsafe fn main() {
         components::process_console::ProcessConsoleComponent::new(board_kernel, uart_mux)
             .finalize(());
 
+    // Save the last panic or process fault to flash, and let the process
+    // console print it.
+    let crash_dump = static_init!(
+        capsules::crash_dump::CrashDump<'static, nrf52840::nvmc::Nvmc>,
+        capsules::crash_dump::CrashDump::new(
+            &base_peripherals.nvmc,
+            CRASH_DUMP_PAGE,
+            static_init!(nrf52840::nvmc::NrfPage, nrf52840::nvmc::NrfPage::default())
+        )
+    );
+    kernel::hil::flash::HasClient::set_client(&base_peripherals.nvmc, crash_dump);
+    let _ = crash_dump.load();
+    pconsole.set_crash_dump(crash_dump);
+    CRASH_DUMP = Some(crash_dump);
+
     // Setup the console.
     let console = components::console::ConsoleComponent::new(board_kernel, uart_mux).finalize(());
     // Create the debugger object that handles calls to `debug!()`.
@@ -397,6 +453,25 @@ pub unsafe fn main() {
         nrf52840::ieee802154_radio::Radio,
         nrf52840::aes::AesECB<'static>
     ));
+    ieee802154_radio.set_energy_scanner(&base_peripherals.ieee802154_radio);
+    base_peripherals
+        .ieee802154_radio
+        .set_energy_scan_client(ieee802154_radio);
+
+    // Both radios need the crystal oscillator while they are on, and the
+    // alarm must not be delayed by the crystal start-up time.
+    let power_clients = static_init!(
+        [&'static dyn kernel::power::PowerClient; 3],
+        [
+            mux_alarm,
+            &base_peripherals.ble_radio,
+            &base_peripherals.ieee802154_radio
+        ]
+    );
+    let power_manager = static_init!(
+        kernel::power::PowerManager<'static>,
+        kernel::power::PowerManager::new(power_clients)
+    );
 
     let local_ip_ifaces = static_init!(
         [IPAddr; 3],
@@ -424,7 +499,7 @@ pub unsafe fn main() {
         local_ip_ifaces,
         mux_alarm,
     )
-    .finalize(components::udp_mux_component_helper!(nrf52840::rtc::Rtc));
+    .finalize(components::udp_mux_component_helper!(nrf52840::rtc::Rtc, 2));
 
     // UDP driver initialization happens here
     let udp_driver = components::udp_driver::UDPDriverComponent::new(
@@ -535,6 +610,12 @@ pub unsafe fn main() {
     // ctap.enable();
     // ctap.attach();
 
+    let shared_memory = static_init!(
+        kernel::shared_memory::SharedMemory<NUM_PROCS>,
+        kernel::shared_memory::SharedMemory::new(board_kernel, &memory_allocation_capability)
+    );
+    board_kernel.set_process_termination_client(shared_memory, &process_management_capability);
+
     let platform = Platform {
         button,
         ble_radio,
@@ -549,7 +630,12 @@ pub unsafe fn main() {
         analog_comparator,
         nonvolatile_storage,
         udp_driver,
+        crash_dump,
+        syscall_filter: kernel::TbfHeaderFilterDefaultAllow {},
         ipc: kernel::ipc::IPC::new(board_kernel, &memory_allocation_capability),
+        ipc_queue: kernel::ipc_queue::IPCQueue::new(board_kernel, &memory_allocation_capability),
+        shared_memory,
+        power_manager,
     };
 
     let _ = platform.pconsole.start();
@@ -237,7 +237,7 @@ unsafe fn get_peripherals() -> (
     let dma1 = static_init!(stm32f429zi::dma1::Dma1, stm32f429zi::dma1::Dma1::new(rcc));
     let peripherals = static_init!(
         Stm32f429ziDefaultPeripherals,
-        Stm32f429ziDefaultPeripherals::new(rcc, exti, dma1)
+        Stm32f429ziDefaultPeripherals::new(rcc, exti, dma1, syscfg)
     );
     (peripherals, syscfg, dma1)
 }
@@ -16,6 +16,7 @@ use kernel::component::Component;
 use kernel::hil::gpio;
 use kernel::hil::led::LedLow;
 use kernel::hil::screen::ScreenRotation;
+use kernel::hil::time::Alarm;
 use kernel::Platform;
 use kernel::{create_capability, debug, static_init};
 use stm32f412g::interrupt_service::Stm32f412gDefaultPeripherals;
@@ -618,6 +619,31 @@ pub unsafe fn main() {
         dynamic_deferred_caller,
     )
     .finalize(components::i2c_mux_component_helper!());
+    base_peripherals.i2c1.set_recovery_pins(
+        base_peripherals
+            .gpio_ports
+            .get_pin(stm32f412g::gpio::PinId::PB06)
+            .unwrap(),
+        base_peripherals
+            .gpio_ports
+            .get_pin(stm32f412g::gpio::PinId::PB07)
+            .unwrap(),
+    );
+
+    // Time out operations on a stuck bus
+    let virtual_alarm_i2c = static_init!(
+        VirtualMuxAlarm<'static, stm32f412g::tim2::Tim2>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let i2c_timer = static_init!(
+        capsules::virtual_i2c::MuxI2CTimer<
+            'static,
+            VirtualMuxAlarm<'static, stm32f412g::tim2::Tim2>,
+        >,
+        capsules::virtual_i2c::MuxI2CTimer::new(mux_i2c, virtual_alarm_i2c)
+    );
+    virtual_alarm_i2c.set_alarm_client(i2c_timer);
+    mux_i2c.set_transaction_timer(i2c_timer);
 
     let ft6x06 = components::ft6x06::Ft6x06Component::new(
         base_peripherals
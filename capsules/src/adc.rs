@@ -4,7 +4,9 @@
 //!
 //! The first, called AdcDedicated, assumes that it has complete (dedicated)
 //! control of the kernel ADC. This capsule provides userspace with
-//! the ability to perform single, continuous, and high speed samples.
+//! the ability to perform single, continuous, and high speed samples, to
+//! stream samples of a sequence of channels into its buffers, and to select
+//! the voltage reference of the ADC and calibrate it.
 //! However, using this capsule means that no other
 //! capsule or kernel service can use the ADC. It also allows only
 //! a single process to use the ADC: other processes will receive
@@ -73,9 +75,12 @@ pub struct AdcVirtualized<'a> {
 
 /// ADC syscall driver, used by applications to interact with ADC.
 /// Not currently virtualized: does not share the ADC with other capsules
-/// and only one application can use it at a time. Supports continuous and
-/// high speed sampling.
-pub struct AdcDedicated<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> {
+/// and only one application can use it at a time. Supports continuous,
+/// high speed and channel sequence sampling.
+pub struct AdcDedicated<
+    'a,
+    A: hil::adc::Adc + hil::adc::AdcHighSpeed + hil::adc::AdcStreaming + hil::adc::AdcReference,
+> {
     // ADC driver
     adc: &'a A,
     channels: &'a [&'a <A as hil::adc::Adc>::Channel],
@@ -88,6 +93,7 @@ pub struct AdcDedicated<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> {
     apps: Grant<App>,
     appid: OptionalCell<ProcessId>,
     channel: Cell<usize>,
+    sequence_length: Cell<usize>,
 
     // ADC buffers
     adc_buf1: TakeCell<'static, [u16]>,
@@ -104,6 +110,8 @@ pub(crate) enum AdcMode {
     ContinuousSample = 1,
     SingleBuffer = 2,
     ContinuousBuffer = 3,
+    ContinuousSequence = 4,
+    Calibration = 5,
 }
 
 // Datas passed by the application to us
@@ -159,7 +167,14 @@ pub static mut ADC_BUFFER1: [u16; 128] = [0; 128];
 pub static mut ADC_BUFFER2: [u16; 128] = [0; 128];
 pub static mut ADC_BUFFER3: [u16; 128] = [0; 128];
 
-impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
+/// The most channels a sequence sampled by `AdcDedicated` can hold.
+pub const MAX_SEQUENCE_LENGTH: usize = 8;
+
+impl<
+        'a,
+        A: hil::adc::Adc + hil::adc::AdcHighSpeed + hil::adc::AdcStreaming + hil::adc::AdcReference,
+    > AdcDedicated<'a, A>
+{
     /// Create a new `Adc` application interface.
     ///
     /// - `adc` - ADC driver to provide application access to
@@ -187,6 +202,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
             apps: grant,
             appid: OptionalCell::empty(),
             channel: Cell::new(0),
+            sequence_length: Cell::new(1),
 
             // ADC buffers
             adc_buf1: TakeCell::new(adc_buf1),
@@ -231,6 +247,11 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
         &self.adc_buf3
     }
 
+    /// Round a number of samples down to a whole number of sequences.
+    fn whole_sequences(&self, samples: usize) -> usize {
+        samples - samples % self.sequence_length.get()
+    }
+
     /// Find a buffer to give to the ADC to store samples in.
     ///
     /// - `closure` - function to run on the found buffer
@@ -360,6 +381,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
         // save state for callback
         self.active.set(true);
         self.mode.set(AdcMode::SingleBuffer);
+        self.sequence_length.set(1);
         let ret = self.appid.map_or(Err(ErrorCode::NOMEM), |id| {
             self.apps
                 .enter(*id, |app| {
@@ -455,6 +477,101 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
         }
         let chan = self.channels[channel];
 
+        self.sample_buffers_continuous(
+            AdcMode::ContinuousBuffer,
+            channel,
+            1,
+            |buf1, len1, buf2, len2| {
+                self.adc
+                    .sample_highspeed(chan, frequency, buf1, len1, buf2, len2)
+            },
+        )
+    }
+
+    /// Collect analog samples continuously on a sequence of channels.
+    ///
+    /// Every channel of the sequence is sampled in turn, and the samples are
+    /// stored one after the other into the "allowed" application buffers as
+    /// with `sample_buffer_continuous`. Each buffer holds a whole number of
+    /// sequences.
+    ///
+    /// - `channel_mask` - bitmask of indices into `channels` array, the
+    ///   channels of the sequence in increasing order
+    /// - `frequency` - number of sequences per second to collect
+    fn sample_sequence_continuous(
+        &self,
+        channel_mask: usize,
+        frequency: u32,
+    ) -> Result<(), ErrorCode> {
+        // only one sample at a time
+        if self.active.get() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        // convert channel indices
+        let sequence_length = channel_mask.count_ones() as usize;
+        if sequence_length == 0
+            || channel_mask
+                .checked_shr(self.channels.len() as u32)
+                .unwrap_or(0)
+                != 0
+        {
+            return Err(ErrorCode::INVAL);
+        }
+        if sequence_length > cmp::min(MAX_SEQUENCE_LENGTH, self.adc.max_sequence_length()) {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        let mut sequence = [self.channels[0]; MAX_SEQUENCE_LENGTH];
+        let selected = self
+            .channels
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| channel_mask & (1 << index) != 0);
+        for (slot, (_, &chan)) in sequence.iter_mut().zip(selected) {
+            *slot = chan;
+        }
+
+        self.sample_buffers_continuous(
+            AdcMode::ContinuousSequence,
+            channel_mask,
+            sequence_length,
+            |buf1, len1, buf2, len2| {
+                self.adc.sample_sequence(
+                    &sequence[..sequence_length],
+                    frequency,
+                    buf1,
+                    len1,
+                    buf2,
+                    len2,
+                )
+            },
+        )
+    }
+
+    /// Start filling the "allowed" application buffers in turn, with
+    /// sampling started by `start` on the first two ADC buffers and the
+    /// number of samples to put in each.
+    ///
+    /// - `mode` - continuous buffered mode to sample in
+    /// - `channel` - channel or channels sampled, reported to the application
+    /// - `sequence_length` - number of channels sampled in turn
+    fn sample_buffers_continuous<F>(
+        &self,
+        mode: AdcMode,
+        channel: usize,
+        sequence_length: usize,
+        start: F,
+    ) -> Result<(), ErrorCode>
+    where
+        F: FnOnce(
+            &'static mut [u16],
+            usize,
+            &'static mut [u16],
+            usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])>,
+    {
+        self.sequence_length.set(sequence_length);
+
         // cannot continuously sample without two buffers
         let mut app_buf_length = 0;
         let mut next_app_buf_length = 0;
@@ -463,7 +580,8 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
                 .enter(*id, |state| {
                     app_buf_length = state.app_buf1.len();
                     next_app_buf_length = state.app_buf2.len();
-                    state.app_buf1.len() > 0 && state.app_buf2.len() > 0
+                    self.whole_sequences(app_buf_length / 2) > 0
+                        && self.whole_sequences(next_app_buf_length / 2) > 0
                 })
                 .map_err(|err| {
                     if err == kernel::procs::Error::NoSuchApp
@@ -480,7 +598,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
 
         // save state for callback
         self.active.set(true);
-        self.mode.set(AdcMode::ContinuousBuffer);
+        self.mode.set(mode);
 
         let ret = self.appid.map_or(Err(ErrorCode::NOMEM), |id| {
             self.apps
@@ -493,49 +611,50 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
                             .take()
                             .map_or(Err(ErrorCode::BUSY), move |buf2| {
                                 // determine request lengths
-                                let samples_needed = app_buf_length / 2;
-                                let next_samples_needed = next_app_buf_length / 2;
+                                let samples_needed = self.whole_sequences(app_buf_length / 2);
+                                let next_samples_needed =
+                                    self.whole_sequences(next_app_buf_length / 2);
+                                let buf1_len = self.whole_sequences(buf1.len());
+                                let buf2_len = self.whole_sequences(buf2.len());
 
                                 // determine request lengths
                                 let len1;
                                 let len2;
-                                if samples_needed <= buf1.len() {
+                                if samples_needed <= buf1_len {
                                     // we can fit the entire app_buffer request in the first
                                     // buffer. The second buffer will be used for the next
                                     // app_buffer
                                     len1 = samples_needed;
-                                    len2 = cmp::min(next_samples_needed, buf2.len());
+                                    len2 = cmp::min(next_samples_needed, buf2_len);
                                     app.samples_remaining.set(0);
                                     app.samples_outstanding.set(len1);
-                                } else if samples_needed <= (buf1.len() + buf2.len()) {
+                                } else if samples_needed <= (buf1_len + buf2_len) {
                                     // we can fit the entire app_buffer request between the two
                                     // buffers
-                                    len1 = buf1.len();
-                                    len2 = samples_needed - buf1.len();
+                                    len1 = buf1_len;
+                                    len2 = samples_needed - buf1_len;
                                     app.samples_remaining.set(0);
                                     app.samples_outstanding.set(len1 + len2);
                                 } else {
                                     // the app_buffer is larger than both buffers, so just
                                     // request max lengths
-                                    len1 = buf1.len();
-                                    len2 = buf2.len();
+                                    len1 = buf1_len;
+                                    len2 = buf2_len;
                                     app.samples_remaining.set(samples_needed - len1 - len2);
                                     app.samples_outstanding.set(len1 + len2);
                                 }
 
                                 // begin sampling
                                 app.using_app_buf1.set(true);
-                                self.adc
-                                    .sample_highspeed(chan, frequency, buf1, len1, buf2, len2)
-                                    .map_or_else(
-                                        |(ecode, buf1, buf2)| {
-                                            // store buffers again
-                                            self.replace_buffer(buf1);
-                                            self.replace_buffer(buf2);
-                                            Err(ecode)
-                                        },
-                                        |()| Ok(()),
-                                    )
+                                start(buf1, len1, buf2, len2).map_or_else(
+                                    |(ecode, buf1, buf2)| {
+                                        // store buffers again
+                                        self.replace_buffer(buf1);
+                                        self.replace_buffer(buf2);
+                                        Err(ecode)
+                                    },
+                                    |()| Ok(()),
+                                )
                             })
                     })
                 })
@@ -575,8 +694,11 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
     /// Any active operation by the ADC is canceled. No additional callbacks
     /// will occur. Also retrieves buffers from the ADC (if any).
     fn stop_sampling(&self) -> Result<(), ErrorCode> {
-        if !self.active.get() || self.mode.get() == AdcMode::NoMode {
-            // already inactive!
+        if !self.active.get()
+            || self.mode.get() == AdcMode::NoMode
+            || self.mode.get() == AdcMode::Calibration
+        {
+            // already inactive, or calibrating, which cannot be stopped
             return Ok(());
         }
 
@@ -626,6 +748,46 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
     fn get_voltage_reference_mv(&self) -> Option<usize> {
         self.adc.get_voltage_reference_mv()
     }
+
+    /// Select the voltage reference of the samples.
+    ///
+    /// - `reference` - 0 for the internal reference, 1 for an external one, 2
+    ///   for the supply voltage
+    fn set_voltage_reference(&self, reference: usize) -> Result<(), ErrorCode> {
+        let reference = match reference {
+            0 => hil::adc::VoltageReference::Internal,
+            1 => hil::adc::VoltageReference::External,
+            2 => hil::adc::VoltageReference::Vdd,
+            _ => return Err(ErrorCode::INVAL),
+        };
+
+        // cannot change the reference while sampling
+        if self.active.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.adc.set_voltage_reference(reference)
+    }
+
+    /// Start a self-calibration of the ADC, after which the application is
+    /// called back.
+    fn calibrate(&self) -> Result<(), ErrorCode> {
+        // only one operation at a time
+        if self.active.get() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        // save state for callback
+        self.active.set(true);
+        self.mode.set(AdcMode::Calibration);
+
+        // start the calibration
+        let res = self.adc.calibrate();
+        if res != Ok(()) {
+            self.active.set(false);
+            self.mode.set(AdcMode::NoMode);
+        }
+        res
+    }
 }
 
 /// Functions to create, initialize, and interact with the virtualized ADC
@@ -687,7 +849,10 @@ impl<'a> AdcVirtualized<'a> {
 }
 
 /// Callbacks from the ADC driver
-impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::Client for AdcDedicated<'_, A> {
+impl<
+        A: hil::adc::Adc + hil::adc::AdcHighSpeed + hil::adc::AdcStreaming + hil::adc::AdcReference,
+    > hil::adc::Client for AdcDedicated<'_, A>
+{
     /// Single sample operation complete.
     ///
     /// Collects the sample and provides a callback to the application.
@@ -756,8 +921,49 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::Client for AdcDedicate
     }
 }
 
+/// Callbacks from the ADC calibration
+impl<
+        A: hil::adc::Adc + hil::adc::AdcHighSpeed + hil::adc::AdcStreaming + hil::adc::AdcReference,
+    > hil::adc::CalibrationClient for AdcDedicated<'_, A>
+{
+    /// Calibration complete.
+    ///
+    /// Provides a callback to the application with the result.
+    ///
+    /// - `result` - whether the calibration succeeded
+    fn calibration_done(&self, result: Result<(), ErrorCode>) {
+        if self.active.get() && self.mode.get() == AdcMode::Calibration {
+            // calibration complete, clean up state
+            self.active.set(false);
+            self.mode.set(AdcMode::NoMode);
+
+            // perform callback
+            self.appid.map(|id| {
+                self.apps
+                    .enter(*id, |app| {
+                        app.callback.schedule(
+                            AdcMode::Calibration as usize,
+                            kernel::into_statuscode(result),
+                            0,
+                        );
+                    })
+                    .map_err(|err| {
+                        if err == kernel::procs::Error::NoSuchApp
+                            || err == kernel::procs::Error::InactiveApp
+                        {
+                            self.appid.clear();
+                        }
+                    })
+            });
+        }
+    }
+}
+
 /// Callbacks from the High Speed ADC driver
-impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for AdcDedicated<'_, A> {
+impl<
+        A: hil::adc::Adc + hil::adc::AdcHighSpeed + hil::adc::AdcStreaming + hil::adc::AdcReference,
+    > hil::adc::HighSpeedClient for AdcDedicated<'_, A>
+{
     /// Internal buffer has filled from a buffered sampling operation.
     /// Copies data over to application buffer, determines if more data is
     /// needed, and performs a callback to the application if ready. If
@@ -779,7 +985,8 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for Ad
         // do we expect a buffer?
         if self.active.get()
             && (self.mode.get() == AdcMode::SingleBuffer
-                || self.mode.get() == AdcMode::ContinuousBuffer)
+                || self.mode.get() == AdcMode::ContinuousBuffer
+                || self.mode.get() == AdcMode::ContinuousSequence)
         {
             // we did expect a buffer. Determine the current application state
             self.appid.map(|id| {
@@ -819,13 +1026,14 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for Ad
                                 // we need
                                 perform_callback = true;
 
-                                if self.mode.get() == AdcMode::ContinuousBuffer {
+                                if self.mode.get() != AdcMode::SingleBuffer {
                                     // it's time to switch to the next app_buffer, but
                                     // there's already an outstanding request to the ADC
                                     // for the next app_buffer that was placed last
                                     // time, so we need to account for that
-                                    let samples_needed =
-                                        next_app_buf.map_or(0, |buf| buf.len() / 2);
+                                    let samples_needed = self.whole_sequences(
+                                        next_app_buf.map_or(0, |buf| buf.len() / 2),
+                                    );
                                     app.samples_remaining
                                         .set(samples_needed - app.next_samples_outstanding.get());
                                     app.samples_outstanding
@@ -854,10 +1062,13 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for Ad
                                         // We'll just make a request and handle the
                                         // state updating on next callback
                                         self.take_and_map_buffer(|adc_buf| {
-                                            let samples_needed =
-                                                next_next_app_buf.map_or(0, |buf| buf.len() / 2);
-                                            let request_len =
-                                                cmp::min(samples_needed, adc_buf.len());
+                                            let samples_needed = self.whole_sequences(
+                                                next_next_app_buf.map_or(0, |buf| buf.len() / 2),
+                                            );
+                                            let request_len = self.whole_sequences(cmp::min(
+                                                samples_needed,
+                                                adc_buf.len(),
+                                            ));
                                             app.next_samples_outstanding.set(request_len);
                                             let _ = self
                                                 .adc
@@ -872,10 +1083,10 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for Ad
 
                                         // provide a new buffer and update state
                                         self.take_and_map_buffer(|adc_buf| {
-                                            let request_len = cmp::min(
+                                            let request_len = self.whole_sequences(cmp::min(
                                                 app.samples_remaining.get(),
                                                 adc_buf.len(),
-                                            );
+                                            ));
                                             app.samples_remaining
                                                 .set(app.samples_remaining.get() - request_len);
                                             app.samples_outstanding
@@ -895,7 +1106,7 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for Ad
                                 // one the ADC is currently acting on)
                                 perform_callback = false;
 
-                                if self.mode.get() == AdcMode::ContinuousBuffer {
+                                if self.mode.get() != AdcMode::SingleBuffer {
                                     // we're in continuous mode, so we need to start the
                                     // first request for the next app_buffer
 
@@ -905,9 +1116,13 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for Ad
                                     // just make a request and handle the state updating
                                     // on next callback
                                     self.take_and_map_buffer(|adc_buf| {
-                                        let samples_needed =
-                                            next_app_buf.map_or(0, |buf| buf.len() / 2);
-                                        let request_len = cmp::min(samples_needed, adc_buf.len());
+                                        let samples_needed = self.whole_sequences(
+                                            next_app_buf.map_or(0, |buf| buf.len() / 2),
+                                        );
+                                        let request_len = self.whole_sequences(cmp::min(
+                                            samples_needed,
+                                            adc_buf.len(),
+                                        ));
                                         app.next_samples_outstanding.set(request_len);
                                         let _ = self
                                             .adc
@@ -924,8 +1139,10 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for Ad
 
                             // provide a new buffer and update state
                             self.take_and_map_buffer(|adc_buf| {
-                                let request_len =
-                                    cmp::min(app.samples_remaining.get(), adc_buf.len());
+                                let request_len = self.whole_sequences(cmp::min(
+                                    app.samples_remaining.get(),
+                                    adc_buf.len(),
+                                ));
                                 app.samples_remaining
                                     .set(app.samples_remaining.get() - request_len);
                                 app.samples_outstanding
@@ -994,7 +1211,12 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for Ad
                         // if the app_buffer is filled, perform callback
                         if perform_callback {
                             // actually schedule the callback
-                            let len_chan = ((buf_len / 2) << 8) | (self.channel.get() & 0xFF);
+                            let len_chan = if self.mode.get() == AdcMode::ContinuousSequence {
+                                (self.whole_sequences(buf_len / 2) << 8)
+                                    | (self.sequence_length.get() & 0xFF)
+                            } else {
+                                ((buf_len / 2) << 8) | (self.channel.get() & 0xFF)
+                            };
                             app.callback.schedule(
                                 self.mode.get() as usize,
                                 len_chan,
@@ -1077,7 +1299,10 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for Ad
 }
 
 /// Implementations of application syscalls
-impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Driver for AdcDedicated<'_, A> {
+impl<
+        A: hil::adc::Adc + hil::adc::AdcHighSpeed + hil::adc::AdcStreaming + hil::adc::AdcReference,
+    > Driver for AdcDedicated<'_, A>
+{
     /// Provides access to a buffer from the application to store data in or
     /// read data from.
     ///
@@ -1307,6 +1532,16 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Driver for AdcDedicated<'_, A> {
                 }),
             },
 
+            // Continuous buffered sampling on a sequence of channels
+            6 => match self.sample_sequence_continuous(channel, frequency as u32) {
+                Ok(()) => CommandReturn::success(),
+                e => CommandReturn::failure(if let Ok(err) = ErrorCode::try_from(e) {
+                    err
+                } else {
+                    panic!("ADC: invalid return code")
+                }),
+            },
+
             // Stop sampling
             5 => match self.stop_sampling() {
                 Ok(()) => CommandReturn::success(),
@@ -1327,6 +1562,29 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Driver for AdcDedicated<'_, A> {
                     CommandReturn::failure(ErrorCode::NOSUPPORT)
                 }
             }
+            // Get the most channels a sequence can hold
+            103 => CommandReturn::success_u32(cmp::min(
+                MAX_SEQUENCE_LENGTH,
+                self.adc.max_sequence_length(),
+            ) as u32),
+            // Get voltage reference uV
+            104 => {
+                if let Some(voltage) = self.adc.get_voltage_reference_uv() {
+                    CommandReturn::success_u32(voltage as u32)
+                } else {
+                    CommandReturn::failure(ErrorCode::NOSUPPORT)
+                }
+            }
+            // Select voltage reference
+            105 => match self.set_voltage_reference(channel) {
+                Ok(()) => CommandReturn::success(),
+                Err(e) => CommandReturn::failure(e),
+            },
+            // Calibrate
+            106 => match self.calibrate() {
+                Ok(()) => CommandReturn::success(),
+                Err(e) => CommandReturn::failure(e),
+            },
 
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
@@ -0,0 +1,1732 @@
+//! Provides userspace access to an AES-128 engine.
+//!
+//! The driver supports the CTR and CBC modes of the underlying
+//! `AES128` implementation, and the GCM mode of an optional `AES128GCM`
+//! implementation. A single application uses the hardware at a
+//! time; requests from other applications are queued and started when the
+//! current operation completes.
+//!
+//! Nonce Management
+//! ----------------
+//!
+//! Reusing a nonce with the same key in CTR mode leaks the XOR of the two
+//! plaintexts. To make reuse impossible by construction, an application can
+//! enable capsule-managed nonces. In that mode the capsule ignores the IV
+//! provided by the application for CTR encryption and instead builds the
+//! initial counter block from a 64-bit nonce prefix and a 32-bit message
+//! counter:
+//!
+//! ```text
+//! counter block: [ prefix (8 bytes) | message (4 bytes) | block (4 bytes, 0) ]
+//! ```
+//!
+//! The counter is kept in the grant of the app and starts over with a fresh
+//! prefix every time the app loads a key, so its state is bounded per app and
+//! goes away with the app. The message counter is incremented after every
+//! encryption; once it is exhausted, further encryptions are refused with
+//! `NOMEM` until a key is loaded again.
+//!
+//! A prefix is the sum of a 64-bit random value drawn from an entropy source
+//! when the board boots and the number of prefixes handed out since then.
+//! Within a boot no two key loads, of the same or of different apps, get the
+//! same prefix. After a reset the prefixes start from a new random value, so
+//! restarting the board does not replay the nonces of the previous boot.
+//! Managed nonces therefore need an entropy source, set with `set_entropy`,
+//! and fail with `NOSUPPORT` without one and with `BUSY` until the random
+//! value has been drawn.
+//!
+//! CMAC
+//! ----
+//!
+//! The driver computes and verifies AES-CMAC (OMAC1, RFC 4493) tags under the
+//! loaded key. The subkeys are derived by encrypting a zero block, and the
+//! message is chained with CBC encryption under a zero IV, so CMAC needs no
+//! support from the hardware beyond the CBC mode. Messages of any length,
+//! including empty ones, are supported. Verification compares the tags in
+//! constant time.
+//!
+//! GCM
+//! ---
+//!
+//! AES-GCM encrypts and authenticates the source, and also authenticates
+//! the additional data the app shares. Encryption writes the ciphertext and
+//! then the 16 byte tag to the destination. Decryption takes the ciphertext
+//! and then the tag as the source, and writes the plaintext only if the tag
+//! is valid. The nonce is 12 bytes long. Managed nonces apply to GCM
+//! encryption too, with the prefix followed by the message counter.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let aes_source = static_init!([u8; 64], [0; 64]);
+//! let aes = static_init!(
+//!     capsules::aes::AesDriver<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::aes::AesDriver::new(
+//!         &sam4l::aes::AES,
+//!         aes_source,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! hil::symmetric_encryption::AES128::set_client(&sam4l::aes::AES, aes);
+//! ```
+//!
+//! To support GCM, the driver uses the AES hardware through
+//! `capsules::aes_gcm::Aes128Gcm`:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let gcm_crypt_buf = static_init!([u8; 64], [0; 64]);
+//! let aes_gcm = static_init!(
+//!     capsules::aes_gcm::Aes128Gcm<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::aes_gcm::Aes128Gcm::new(&sam4l::aes::AES, gcm_crypt_buf)
+//! );
+//! hil::symmetric_encryption::AES128::set_client(&sam4l::aes::AES, aes_gcm);
+//!
+//! let aes_source = static_init!([u8; 64], [0; 64]);
+//! let aes = static_init!(
+//!     capsules::aes::AesDriver<'static, capsules::aes_gcm::Aes128Gcm<'static, sam4l::aes::Aes<'static>>>,
+//!     capsules::aes::AesDriver::new(
+//!         aes_gcm,
+//!         aes_source,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! hil::symmetric_encryption::AES128::set_client(aes_gcm, aes);
+//! let gcm_buffer = static_init!([u8; 256], [0; 256]);
+//! aes.set_gcm(aes_gcm, gcm_buffer);
+//! hil::symmetric_encryption::AES128GCM::set_client(aes_gcm, aes);
+//! ```
+//!
+//! Managed nonces draw their per-boot random value from an entropy source:
+//!
+//! ```rust
+//! aes.set_entropy(&nrf52840::trng::TRNG);
+//! hil::entropy::Entropy32::set_client(&nrf52840::trng::TRNG, aes);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow ReadOnly
+//!
+//! - `0`: The 16 byte key. Read when the key is loaded with command `2`.
+//! - `1`: The 16 byte IV (or initial counter block), the expected tag
+//!   when verifying a CMAC, or the 12 byte GCM nonce.
+//! - `2`: The source data. Its length must be a multiple of the block size,
+//!   except for CMAC and GCM.
+//! - `3`: The additional authenticated data for GCM.
+//!
+//! ### Allow ReadWrite
+//!
+//! - `0`: The destination buffer, which must be at least as long as the
+//!   source, and 16 bytes longer for GCM encryption. A computed CMAC tag is
+//!   written to its first 16 bytes.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Operation complete. The upcall receives the status and the number
+//!   of bytes written to the destination buffer. After a CMAC verification
+//!   or a GCM decryption the third argument is `1` if the tag matched and `0`
+//!   otherwise.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Set the mode. `data1` is `0` for CTR, `1` for CBC or `2` for GCM,
+//!   `data2` is `1` to encrypt or `0` to decrypt. GCM fails with
+//!   `NOSUPPORT` without an `AES128GCM` implementation.
+//! - `2`: Load the key from allow slot `0`.
+//! - `3`: Run the configured operation over the source buffer.
+//! - `4`: Enable (`data1 != 0`) or disable capsule-managed CTR nonces.
+//! - `5`: Return the next managed nonce of the loaded key, as the 64-bit
+//!   prefix and the 32-bit message counter. Returns `RESERVE` if no key has
+//!   been loaded, and `NOMEM` if the message counter is used up.
+//! - `6`: Compute the CMAC of the source buffer.
+//! - `7`: Verify the CMAC of the source buffer against the tag in allow
+//!   slot `1`.
+
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::entropy::{Client32, Continue, Entropy32};
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{
+    AES128Ctr, AES128, AES128CBC, AES128GCM, AES128_BLOCK_SIZE, AES128_KEY_SIZE, GCM_NONCE_LENGTH,
+    GCM_TAG_LENGTH,
+};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Aes as usize;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum AesMode {
+    Ctr,
+    Cbc,
+    Gcm,
+}
+
+impl Default for AesMode {
+    fn default() -> Self {
+        AesMode::Ctr
+    }
+}
+
+/// The operation an app runs with its next run command.
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Crypt,
+    Cmac,
+    CmacVerify,
+}
+
+impl Default for Operation {
+    fn default() -> Self {
+        Operation::Crypt
+    }
+}
+
+/// The step of a CMAC computation the hardware is working on.
+#[derive(Copy, Clone, PartialEq)]
+enum CmacStep {
+    /// Encrypting the zero block to derive the subkeys.
+    Subkey,
+    /// Chaining the message blocks before the last one.
+    Chain,
+    /// Encrypting the last block, which produces the tag.
+    Final,
+}
+
+/// Multiply a block by x in GF(2^128), as used to derive the CMAC subkeys.
+fn cmac_double(block: &[u8; AES128_BLOCK_SIZE]) -> [u8; AES128_BLOCK_SIZE] {
+    let mut out = [0; AES128_BLOCK_SIZE];
+    let mut carry = 0;
+    for i in (0..AES128_BLOCK_SIZE).rev() {
+        out[i] = (block[i] << 1) | carry;
+        carry = block[i] >> 7;
+    }
+    if carry != 0 {
+        out[AES128_BLOCK_SIZE - 1] ^= 0x87;
+    }
+    out
+}
+
+/// Derive the CMAC subkeys K1 and K2 from `l`, the encryption of the zero
+/// block.
+pub fn cmac_subkeys(
+    l: &[u8; AES128_BLOCK_SIZE],
+) -> ([u8; AES128_BLOCK_SIZE], [u8; AES128_BLOCK_SIZE]) {
+    let k1 = cmac_double(l);
+    let k2 = cmac_double(&k1);
+    (k1, k2)
+}
+
+/// Number of message bytes chained before the last block of a CMAC of a
+/// `len` byte message.
+fn cmac_prefix_len(len: usize) -> usize {
+    if len == 0 {
+        0
+    } else {
+        (len - 1) / AES128_BLOCK_SIZE * AES128_BLOCK_SIZE
+    }
+}
+
+/// Build the last block of a CMAC from the message bytes after the prefix:
+/// a complete block is masked with K1, a partial one is padded and masked
+/// with K2.
+pub fn cmac_last_block(
+    last: &[u8],
+    k1: &[u8; AES128_BLOCK_SIZE],
+    k2: &[u8; AES128_BLOCK_SIZE],
+) -> [u8; AES128_BLOCK_SIZE] {
+    let mut block = [0; AES128_BLOCK_SIZE];
+    let subkey = if last.len() == AES128_BLOCK_SIZE {
+        block.copy_from_slice(last);
+        k1
+    } else {
+        block[..last.len()].copy_from_slice(last);
+        block[last.len()] = 0x80;
+        k2
+    };
+    for (b, k) in block.iter_mut().zip(subkey.iter()) {
+        *b ^= *k;
+    }
+    block
+}
+
+/// Compare two tags in constant time.
+pub fn tags_equal(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The managed nonce state of an app under its loaded key.
+#[derive(Copy, Clone)]
+struct NonceCounter {
+    prefix: u64,
+    next_message: u32,
+    exhausted: bool,
+}
+
+impl NonceCounter {
+    fn new(prefix: u64) -> NonceCounter {
+        NonceCounter {
+            prefix: prefix,
+            next_message: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Return the counter block for the next encryption and advance the
+    /// message counter. Returns `NOMEM` if the message counter is used up.
+    fn take_nonce(&mut self) -> Result<[u8; AES128_BLOCK_SIZE], ErrorCode> {
+        if self.exhausted {
+            return Err(ErrorCode::NOMEM);
+        }
+        let mut iv = [0; AES128_BLOCK_SIZE];
+        iv[..8].copy_from_slice(&self.prefix.to_be_bytes());
+        iv[8..12].copy_from_slice(&self.next_message.to_be_bytes());
+        match self.next_message.checked_add(1) {
+            Some(next) => self.next_message = next,
+            None => self.exhausted = true,
+        }
+        Ok(iv)
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    pending_run_app: Option<ProcessId>,
+    key: ReadOnlyAppSlice,
+    iv: ReadOnlyAppSlice,
+    source: ReadOnlyAppSlice,
+    aad: ReadOnlyAppSlice,
+    dest: ReadWriteAppSlice,
+
+    mode: AesMode,
+    encrypting: bool,
+    operation: Operation,
+    pending_operation: Operation,
+    managed_nonce: bool,
+    loaded_key: Option<[u8; AES128_KEY_SIZE]>,
+    // Reset when a key is loaded, and set by the first managed nonce taken
+    // under it.
+    nonce: Option<NonceCounter>,
+}
+
+pub struct AesDriver<'a, A: AES128<'a> + AES128Ctr + AES128CBC> {
+    aes: &'a A,
+    apps: Grant<App>,
+    appid: OptionalCell<ProcessId>,
+
+    buffer: TakeCell<'a, [u8]>,
+    // Offset into the application's source of the chunk being processed.
+    position: Cell<usize>,
+    // Length of the chunk being processed.
+    chunk_len: Cell<usize>,
+
+    // The CMAC step in progress, if the operation is a CMAC, and its subkeys.
+    cmac_step: OptionalCell<CmacStep>,
+    cmac_k1: Cell<[u8; AES128_BLOCK_SIZE]>,
+    cmac_k2: Cell<[u8; AES128_BLOCK_SIZE]>,
+
+    // The GCM implementation, if any, and the buffer it works in.
+    gcm: OptionalCell<&'a dyn AES128GCM<'a>>,
+    gcm_buffer: TakeCell<'static, [u8]>,
+
+    // The source of the per-boot random value of the nonce prefixes, the
+    // value once drawn, and the number of prefixes handed out since.
+    entropy: OptionalCell<&'a dyn Entropy32<'a>>,
+    boot_random: OptionalCell<u64>,
+    entropy_word: OptionalCell<u32>,
+    prefixes: Cell<u64>,
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> AesDriver<'a, A> {
+    pub fn new(aes: &'a A, buffer: &'a mut [u8], grant: Grant<App>) -> AesDriver<'a, A> {
+        AesDriver {
+            aes: aes,
+            apps: grant,
+            appid: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+            position: Cell::new(0),
+            chunk_len: Cell::new(0),
+            cmac_step: OptionalCell::empty(),
+            cmac_k1: Cell::new([0; AES128_BLOCK_SIZE]),
+            cmac_k2: Cell::new([0; AES128_BLOCK_SIZE]),
+            gcm: OptionalCell::empty(),
+            gcm_buffer: TakeCell::empty(),
+            entropy: OptionalCell::empty(),
+            boot_random: OptionalCell::empty(),
+            entropy_word: OptionalCell::empty(),
+            prefixes: Cell::new(0),
+        }
+    }
+
+    /// Support GCM with `gcm`, which must use the same hardware as this
+    /// driver. The additional data, message and tag of an operation must fit
+    /// in `buffer`.
+    pub fn set_gcm(&self, gcm: &'a dyn AES128GCM<'a>, buffer: &'static mut [u8]) {
+        self.gcm.set(gcm);
+        self.gcm_buffer.replace(buffer);
+    }
+
+    /// Support managed nonces, whose prefixes start at a random value drawn
+    /// from `entropy` now.
+    pub fn set_entropy(&self, entropy: &'a dyn Entropy32<'a>) {
+        self.entropy.set(entropy);
+        let _ = entropy.get();
+    }
+
+    /// The managed nonce counter of the app, started with a new prefix if
+    /// the app has not taken a nonce under its loaded key yet.
+    fn nonce_counter<'b>(&self, app: &'b mut App) -> Result<&'b mut NonceCounter, ErrorCode> {
+        if app.loaded_key.is_none() {
+            return Err(ErrorCode::RESERVE);
+        }
+        if app.nonce.is_none() {
+            if self.entropy.is_none() {
+                return Err(ErrorCode::NOSUPPORT);
+            }
+            let random = self.boot_random.extract().ok_or(ErrorCode::BUSY)?;
+            let count = self.prefixes.get();
+            // Handing out 2^64 prefixes would wrap around to the first one.
+            let next = count.checked_add(1).ok_or(ErrorCode::NOMEM)?;
+            self.prefixes.set(next);
+            app.nonce = Some(NonceCounter::new(random.wrapping_add(count)));
+        }
+        app.nonce.as_mut().ok_or(ErrorCode::FAIL)
+    }
+
+    /// The counter block of the next managed encryption of the app.
+    fn take_nonce(&self, app: &mut App) -> Result<[u8; AES128_BLOCK_SIZE], ErrorCode> {
+        self.nonce_counter(app)?.take_nonce()
+    }
+
+    /// Configure the hardware for the current app and start the first chunk.
+    fn run(&self) -> Result<(), ErrorCode> {
+        self.appid.map_or(Err(ErrorCode::RESERVE), |appid| {
+            self.apps
+                .enter(*appid, |app| {
+                    if app.operation != Operation::Crypt {
+                        return self.start_cmac(app);
+                    }
+                    if app.mode == AesMode::Gcm {
+                        return self.start_gcm(app);
+                    }
+                    let source_len = app.source.len();
+                    if source_len == 0 || source_len % AES128_BLOCK_SIZE != 0 {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    if app.dest.len() < source_len {
+                        return Err(ErrorCode::SIZE);
+                    }
+
+                    // Check everything the app provides before enabling the
+                    // hardware, so that a bad request leaves it disabled.
+                    let key = app.loaded_key.ok_or(ErrorCode::RESERVE)?;
+                    let managed = app.managed_nonce && app.encrypting && app.mode == AesMode::Ctr;
+                    let iv = if managed {
+                        self.take_nonce(app)?
+                    } else {
+                        let mut iv = [0; AES128_BLOCK_SIZE];
+                        app.iv.map_or(Err(ErrorCode::RESERVE), |app_iv| {
+                            if app_iv.len() != AES128_BLOCK_SIZE {
+                                return Err(ErrorCode::INVAL);
+                            }
+                            iv.copy_from_slice(app_iv);
+                            Ok(())
+                        })?;
+                        iv
+                    };
+
+                    self.aes.enable();
+                    let res = self.aes.set_key(&key).and_then(|()| {
+                        self.aes.set_iv(&iv)?;
+                        match app.mode {
+                            AesMode::Ctr => self.aes.set_mode_aes128ctr(app.encrypting),
+                            AesMode::Cbc => self.aes.set_mode_aes128cbc(app.encrypting),
+                            AesMode::Gcm => {}
+                        }
+                        self.aes.start_message();
+                        self.position.set(0);
+                        self.crypt_chunk(app)
+                    });
+                    if res.is_err() {
+                        self.aes.disable();
+                    }
+                    res
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        })
+    }
+
+    /// Copy the next chunk of the app's source into the kernel buffer and
+    /// hand it to the hardware.
+    fn crypt_chunk(&self, app: &mut App) -> Result<(), ErrorCode> {
+        let position = self.position.get();
+        self.buffer.take().map_or(Err(ErrorCode::BUSY), |buf| {
+            let chunk_len = app.source.map_or(0, |source| {
+                let remaining = source.len().saturating_sub(position);
+                let len = core::cmp::min(remaining, buf.len());
+                let len = len - (len % AES128_BLOCK_SIZE);
+                buf[..len].copy_from_slice(&source[position..position + len]);
+                len
+            });
+            if chunk_len == 0 {
+                self.buffer.replace(buf);
+                return Err(ErrorCode::INVAL);
+            }
+            self.chunk_len.set(chunk_len);
+
+            match self.aes.crypt(None, buf, 0, chunk_len) {
+                None => Ok(()),
+                Some((res, _, buf)) => {
+                    self.buffer.replace(buf);
+                    res.and(Err(ErrorCode::FAIL))
+                }
+            }
+        })
+    }
+
+    /// Copy the additional data and source of the app in to the GCM buffer,
+    /// and start a GCM operation on it.
+    fn start_gcm(&self, app: &mut App) -> Result<(), ErrorCode> {
+        let gcm = self.gcm.extract().ok_or(ErrorCode::NOSUPPORT)?;
+        let managed = app.managed_nonce && app.encrypting;
+        let (key, nonce) = match app.loaded_key {
+            None => return Err(ErrorCode::RESERVE),
+            Some(key) => {
+                let mut nonce = [0; GCM_NONCE_LENGTH];
+                if managed {
+                    let iv = self.take_nonce(app)?;
+                    nonce.copy_from_slice(&iv[..GCM_NONCE_LENGTH]);
+                } else {
+                    app.iv.map_or(Err(ErrorCode::RESERVE), |app_iv| {
+                        if app_iv.len() != GCM_NONCE_LENGTH {
+                            return Err(ErrorCode::INVAL);
+                        }
+                        nonce.copy_from_slice(app_iv);
+                        Ok(())
+                    })?;
+                }
+                (key, nonce)
+            }
+        };
+
+        // When decrypting, the tag follows the ciphertext in the source.
+        let source_len = app.source.len();
+        let (m_len, out_len) = if app.encrypting {
+            (source_len, source_len + GCM_TAG_LENGTH)
+        } else if source_len >= GCM_TAG_LENGTH {
+            (source_len - GCM_TAG_LENGTH, source_len - GCM_TAG_LENGTH)
+        } else {
+            return Err(ErrorCode::INVAL);
+        };
+        if app.dest.len() < out_len {
+            return Err(ErrorCode::SIZE);
+        }
+        let a_len = app.aad.len();
+
+        self.gcm_buffer.take().map_or(Err(ErrorCode::BUSY), |buf| {
+            if a_len + m_len + GCM_TAG_LENGTH > buf.len() {
+                self.gcm_buffer.replace(buf);
+                return Err(ErrorCode::SIZE);
+            }
+            app.aad.map_or((), |aad| buf[..a_len].copy_from_slice(aad));
+            app.source.map_or((), |source| {
+                buf[a_len..a_len + source_len].copy_from_slice(source)
+            });
+            self.position.set(a_len);
+            self.chunk_len.set(out_len);
+
+            let res = gcm.set_key(&key).and_then(|()| gcm.set_nonce(&nonce));
+            if let Err(e) = res {
+                self.gcm_buffer.replace(buf);
+                return Err(e);
+            }
+            gcm.crypt(buf, 0, a_len, m_len, app.encrypting)
+                .map_err(|(e, buf)| {
+                    self.gcm_buffer.replace(buf);
+                    e
+                })
+        })
+    }
+
+    /// Start a CMAC by deriving the subkeys under the app's key.
+    fn start_cmac(&self, app: &mut App) -> Result<(), ErrorCode> {
+        if app.operation == Operation::CmacVerify {
+            if app.iv.len() != AES128_BLOCK_SIZE {
+                return Err(ErrorCode::INVAL);
+            }
+        } else if app.dest.len() < AES128_BLOCK_SIZE {
+            return Err(ErrorCode::SIZE);
+        }
+        let key = app.loaded_key.ok_or(ErrorCode::RESERVE)?;
+
+        self.aes.enable();
+        let res = self.aes.set_key(&key).and_then(|()| {
+            self.aes.set_iv(&[0; AES128_BLOCK_SIZE])?;
+            self.aes.set_mode_aes128cbc(true);
+            self.aes.start_message();
+            self.position.set(0);
+            self.cmac_step.set(CmacStep::Subkey);
+            self.crypt_block(&[0; AES128_BLOCK_SIZE])
+        });
+        if res.is_err() {
+            self.aes.disable();
+        }
+        res
+    }
+
+    /// Encrypt a single block held by the kernel.
+    fn crypt_block(&self, block: &[u8; AES128_BLOCK_SIZE]) -> Result<(), ErrorCode> {
+        self.buffer.take().map_or(Err(ErrorCode::BUSY), |buf| {
+            if buf.len() < AES128_BLOCK_SIZE {
+                self.buffer.replace(buf);
+                return Err(ErrorCode::SIZE);
+            }
+            buf[..AES128_BLOCK_SIZE].copy_from_slice(block);
+            self.chunk_len.set(AES128_BLOCK_SIZE);
+            match self.aes.crypt(None, buf, 0, AES128_BLOCK_SIZE) {
+                None => Ok(()),
+                Some((res, _, buf)) => {
+                    self.buffer.replace(buf);
+                    res.and(Err(ErrorCode::FAIL))
+                }
+            }
+        })
+    }
+
+    /// Chain the next chunk of the message, or encrypt the last block once
+    /// all blocks before it have been chained.
+    fn cmac_chunk(&self, app: &mut App) -> Result<(), ErrorCode> {
+        let position = self.position.get();
+        let source_len = app.source.len();
+        let prefix_len = cmac_prefix_len(source_len);
+        if position < prefix_len {
+            self.cmac_step.set(CmacStep::Chain);
+            self.buffer.take().map_or(Err(ErrorCode::BUSY), |buf| {
+                let len = core::cmp::min(prefix_len - position, buf.len());
+                let len = len - (len % AES128_BLOCK_SIZE);
+                app.source.map_or((), |source| {
+                    buf[..len].copy_from_slice(&source[position..position + len]);
+                });
+                self.chunk_len.set(len);
+                match self.aes.crypt(None, buf, 0, len) {
+                    None => Ok(()),
+                    Some((res, _, buf)) => {
+                        self.buffer.replace(buf);
+                        res.and(Err(ErrorCode::FAIL))
+                    }
+                }
+            })
+        } else {
+            self.cmac_step.set(CmacStep::Final);
+            let (k1, k2) = (self.cmac_k1.get(), self.cmac_k2.get());
+            // A missing source is an empty message.
+            let block = app.source.map_or(cmac_last_block(&[], &k1, &k2), |source| {
+                let last = &source[core::cmp::min(prefix_len, source.len())..];
+                cmac_last_block(last, &k1, &k2)
+            });
+            self.crypt_block(&block)
+        }
+    }
+
+    /// Handle a completed step of a CMAC. Returns the number of bytes written
+    /// and whether the tag matched once the CMAC is finished, or `None` if
+    /// more steps are in flight.
+    fn cmac_done(
+        &self,
+        step: CmacStep,
+        block: &[u8; AES128_BLOCK_SIZE],
+    ) -> Option<(Result<(), ErrorCode>, usize, bool)> {
+        let appid = self.appid.extract()?;
+        let res = self.apps.enter(appid, |app| match step {
+            CmacStep::Subkey => {
+                let (k1, k2) = cmac_subkeys(block);
+                self.cmac_k1.set(k1);
+                self.cmac_k2.set(k2);
+                // Chain the message as a new CBC message under the zero IV.
+                self.aes.start_message();
+                self.cmac_chunk(app).err().map(|e| (Err(e), 0, false))
+            }
+            CmacStep::Chain => {
+                self.position
+                    .set(self.position.get() + self.chunk_len.get());
+                self.cmac_chunk(app).err().map(|e| (Err(e), 0, false))
+            }
+            CmacStep::Final => {
+                let tag = &block[..];
+                if app.operation == Operation::CmacVerify {
+                    let valid = app.iv.map_or(false, |expected| tags_equal(expected, tag));
+                    Some((Ok(()), 0, valid))
+                } else {
+                    let written = app.dest.mut_map_or(0, |app_dest| {
+                        if app_dest.len() < AES128_BLOCK_SIZE {
+                            return 0;
+                        }
+                        app_dest[..AES128_BLOCK_SIZE].copy_from_slice(tag);
+                        AES128_BLOCK_SIZE
+                    });
+                    if written == 0 {
+                        Some((Err(ErrorCode::SIZE), 0, false))
+                    } else {
+                        Some((Ok(()), written, false))
+                    }
+                }
+            }
+        });
+        res.unwrap_or_else(|err| Some((Err(err.into()), 0, false)))
+    }
+
+    /// Run `operation` for `appid` now if the hardware is idle, or queue it.
+    fn start(&self, appid: ProcessId, operation: Operation) -> CommandReturn {
+        // Release the hardware if the owning app has gone away.
+        let idle = self.appid.map_or(true, |owning_app| {
+            self.apps.enter(*owning_app, |_| false).unwrap_or(true)
+        });
+
+        if idle {
+            let res = self.apps.enter(appid, |app| app.operation = operation);
+            if let Err(e) = res {
+                return e.into();
+            }
+            self.appid.set(appid);
+            match self.run() {
+                Ok(()) => CommandReturn::success(),
+                Err(e) => {
+                    self.appid.clear();
+                    self.cmac_step.clear();
+                    CommandReturn::failure(e)
+                }
+            }
+        } else {
+            self.apps
+                .enter(appid, |app| {
+                    if app.pending_run_app.is_some() {
+                        CommandReturn::failure(ErrorCode::NOMEM)
+                    } else {
+                        app.pending_run_app = Some(appid);
+                        app.pending_operation = operation;
+                        CommandReturn::success()
+                    }
+                })
+                .unwrap_or_else(|err| err.into())
+        }
+    }
+
+    fn check_queue(&self) {
+        for appiter in self.apps.iter() {
+            // If an app is already running let it complete
+            if self.appid.is_some() {
+                break;
+            }
+
+            // If this app has a pending command let's use it. The grant must
+            // be released before `run()` enters it again.
+            let pending = appiter.enter(|app| {
+                let pending = app.pending_run_app.take();
+                if pending.is_some() {
+                    app.operation = app.pending_operation;
+                }
+                pending
+            });
+            if let Some(appid) = pending {
+                self.appid.set(appid);
+                if let Err(e) = self.run() {
+                    self.appid.clear();
+                    self.cmac_step.clear();
+                    let _ = self.apps.enter(appid, |app| {
+                        app.callback.schedule(kernel::into_statuscode(Err(e)), 0, 0);
+                    });
+                }
+            }
+        }
+    }
+
+    /// Finish the current operation, notify the app and start the next one.
+    fn complete(&self, result: Result<(), ErrorCode>, written: usize, tag_valid: bool) {
+        self.aes.disable();
+        self.cmac_step.clear();
+        self.appid.take().map(|id| {
+            let _ = self.apps.enter(id, |app| {
+                app.callback
+                    .schedule(kernel::into_statuscode(result), written, tag_valid as usize);
+            });
+        });
+        self.check_queue();
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::Client<'a>
+    for AesDriver<'a, A>
+{
+    fn crypt_done(&'a self, _source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+        if let Some(step) = self.cmac_step.extract() {
+            // Only the last block of the output matters for a CMAC. Release
+            // the buffer before the next step needs it.
+            let mut block = [0; AES128_BLOCK_SIZE];
+            let end = self.chunk_len.get();
+            if end >= AES128_BLOCK_SIZE && end <= dest.len() {
+                block.copy_from_slice(&dest[end - AES128_BLOCK_SIZE..end]);
+            }
+            self.buffer.replace(dest);
+            if let Some((result, written, tag_valid)) = self.cmac_done(step, &block) {
+                self.complete(result, written, tag_valid);
+            }
+            return;
+        }
+
+        let chunk_len = self.chunk_len.get();
+        let position = self.position.get();
+
+        // Copy the result back to the app before releasing the buffer.
+        self.appid.map(|id| {
+            let _ = self.apps.enter(*id, |app| {
+                app.dest.mut_map_or((), |app_dest| {
+                    if app_dest.len() >= position + chunk_len {
+                        app_dest[position..position + chunk_len]
+                            .copy_from_slice(&dest[..chunk_len]);
+                    }
+                });
+            });
+        });
+        self.buffer.replace(dest);
+        self.position.set(position + chunk_len);
+
+        // Either process the next chunk or report completion.
+        let result = self.appid.map_or(Err(ErrorCode::FAIL), |id| {
+            self.apps
+                .enter(*id, |app| {
+                    if self.position.get() < app.source.len() {
+                        self.crypt_chunk(app).map(|()| false)
+                    } else {
+                        Ok(true)
+                    }
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        });
+
+        match result {
+            // More chunks are in flight.
+            Ok(false) => {}
+            Ok(true) => self.complete(Ok(()), self.position.get(), false),
+            Err(e) => self.complete(Err(e), self.position.get(), false),
+        }
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::GCMClient
+    for AesDriver<'a, A>
+{
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        // The output starts at the message, after the additional data. A
+        // decrypted message is only released if its tag is valid.
+        let (m_off, out_len) = (self.position.get(), self.chunk_len.get());
+        let written = match res {
+            Ok(()) if tag_is_valid => self.appid.map_or(0, |id| {
+                self.apps
+                    .enter(*id, |app| {
+                        app.dest.mut_map_or(0, |app_dest| {
+                            if app_dest.len() < out_len {
+                                return 0;
+                            }
+                            app_dest[..out_len].copy_from_slice(&buf[m_off..m_off + out_len]);
+                            out_len
+                        })
+                    })
+                    .unwrap_or(0)
+            }),
+            _ => 0,
+        };
+        self.gcm_buffer.replace(buf);
+        self.complete(res, written, tag_is_valid);
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> Client32 for AesDriver<'a, A> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> Continue {
+        if error.is_err() {
+            return Continue::Done;
+        }
+        // The random value is built from two words, which may arrive in
+        // separate callbacks.
+        while self.boot_random.is_none() {
+            let word = match entropy.next() {
+                Some(word) => word,
+                None => return Continue::More,
+            };
+            match self.entropy_word.take() {
+                None => self.entropy_word.set(word),
+                Some(high) => self.boot_random.set((high as u64) << 32 | word as u64),
+            }
+        }
+        Continue::Done
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> Driver for AesDriver<'a, A> {
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.key, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.iv, &mut slice);
+                    Ok(())
+                }
+                2 => {
+                    mem::swap(&mut app.source, &mut slice);
+                    Ok(())
+                }
+                3 => {
+                    mem::swap(&mut app.aad, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.dest, &mut slice);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            // check if present
+            0 => CommandReturn::success(),
+
+            // set mode
+            1 => {
+                let mode = match data1 {
+                    0 => AesMode::Ctr,
+                    1 => AesMode::Cbc,
+                    2 if self.gcm.is_some() => AesMode::Gcm,
+                    2 => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+                    _ => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                self.apps
+                    .enter(appid, |app| {
+                        app.mode = mode;
+                        app.encrypting = data2 != 0;
+                        CommandReturn::success()
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            // load key
+            2 => self
+                .apps
+                .enter(appid, |app| {
+                    let mut key = [0; AES128_KEY_SIZE];
+                    let res = app.key.map_or(Err(ErrorCode::RESERVE), |app_key| {
+                        if app_key.len() != AES128_KEY_SIZE {
+                            return Err(ErrorCode::INVAL);
+                        }
+                        key.copy_from_slice(app_key);
+                        Ok(())
+                    });
+                    if let Err(e) = res {
+                        return CommandReturn::failure(e);
+                    }
+                    // Managed nonces under the key start with a new prefix.
+                    app.loaded_key = Some(key);
+                    app.nonce = None;
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // run
+            3 => self.start(appid, Operation::Crypt),
+
+            // enable or disable managed nonces
+            4 => self
+                .apps
+                .enter(appid, |app| {
+                    app.managed_nonce = data1 != 0;
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // query the next managed nonce
+            5 => self
+                .apps
+                .enter(appid, |app| match self.nonce_counter(app) {
+                    Ok(counter) if counter.exhausted => CommandReturn::failure(ErrorCode::NOMEM),
+                    Ok(counter) => {
+                        CommandReturn::success_u64_u32(counter.prefix, counter.next_message)
+                    }
+                    Err(e) => CommandReturn::failure(e),
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // compute a CMAC
+            6 => self.start(appid, Operation::Cmac),
+
+            // verify a CMAC
+            7 => self.start(appid, Operation::CmacVerify),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{
+        cmac_double, cmac_last_block, cmac_prefix_len, cmac_subkeys, tags_equal, AesDriver,
+        NonceCounter, DRIVER_NUM,
+    };
+    use crate::test::mock_process::{self, MockProcess};
+    use core::cell::Cell;
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::entropy::{Client32, Continue, Entropy32};
+    use kernel::hil::symmetric_encryption::{self, AES128Ctr, AES128, AES128CBC};
+    use kernel::{Driver, ErrorCode, Read, ReadWriteAppSlice};
+    use std::vec::Vec;
+
+    // Test vectors of RFC 4493, section 4, for the key
+    // 2b7e1516 28aed2a6 abf71588 09cf4f3c.
+
+    /// AES-128(K, 0)
+    const L: [u8; 16] = [
+        0x7d, 0xf7, 0x6b, 0x0c, 0x1a, 0xb8, 0x99, 0xb3, 0x3e, 0x42, 0xf0, 0x47, 0xb9, 0x1b, 0x54,
+        0x6f,
+    ];
+    const K1: [u8; 16] = [
+        0xfb, 0xee, 0xd6, 0x18, 0x35, 0x71, 0x33, 0x66, 0x7c, 0x85, 0xe0, 0x8f, 0x72, 0x36, 0xa8,
+        0xde,
+    ];
+    const K2: [u8; 16] = [
+        0xf7, 0xdd, 0xac, 0x30, 0x6a, 0xe2, 0x66, 0xcc, 0xf9, 0x0b, 0xc1, 0x1e, 0xe4, 0x6d, 0x51,
+        0x3b,
+    ];
+    const KEY: [u8; 16] = [
+        0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f,
+        0x3c,
+    ];
+    /// The example messages are the first 0, 16, 40 and 64 bytes of this.
+    const MESSAGE: [u8; 64] = [
+        0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17,
+        0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf,
+        0x8e, 0x51, 0x30, 0xc8, 0x1c, 0x46, 0xa3, 0x5c, 0xe4, 0x11, 0xe5, 0xfb, 0xc1, 0x19, 0x1a,
+        0x0a, 0x52, 0xef, 0xf6, 0x9f, 0x24, 0x45, 0xdf, 0x4f, 0x9b, 0x17, 0xad, 0x2b, 0x41, 0x7b,
+        0xe6, 0x6c, 0x37, 0x10,
+    ];
+    /// The tag of example 1, the empty message.
+    const TAG_0: [u8; 16] = [
+        0xbb, 0x1d, 0x69, 0x29, 0xe9, 0x59, 0x37, 0x28, 0x7f, 0xa3, 0x7d, 0x12, 0x9b, 0x75, 0x67,
+        0x46,
+    ];
+    /// The tag of example 2, the first 16 bytes of the message.
+    const TAG_16: [u8; 16] = [
+        0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a, 0x28,
+        0x7c,
+    ];
+    /// The tag of example 3, the first 40 bytes of the message.
+    const TAG_40: [u8; 16] = [
+        0xdf, 0xa6, 0x67, 0x47, 0xde, 0x9a, 0xe6, 0x30, 0x30, 0xca, 0x32, 0x61, 0x14, 0x97, 0xc8,
+        0x27,
+    ];
+    /// The tag of example 4, the whole message.
+    const TAG_64: [u8; 16] = [
+        0x51, 0xf0, 0xbe, 0xbf, 0x7e, 0x3b, 0x9d, 0x92, 0xfc, 0x49, 0x74, 0x17, 0x79, 0x36, 0x3c,
+        0xfe,
+    ];
+
+    #[test]
+    fn test_cmac_subkeys() {
+        assert_eq!(cmac_subkeys(&L), (K1, K2));
+        assert_eq!(cmac_double(&K1), K2);
+    }
+
+    #[test]
+    fn test_cmac_double_without_carry() {
+        let mut block = [0; 16];
+        block[15] = 0x41;
+        let mut doubled = [0; 16];
+        doubled[15] = 0x82;
+        assert_eq!(cmac_double(&block), doubled);
+    }
+
+    #[test]
+    fn test_cmac_last_block_empty() {
+        // Example 1: the empty message is padded and masked with K2.
+        assert_eq!(cmac_prefix_len(0), 0);
+        assert_eq!(
+            cmac_last_block(&[], &K1, &K2),
+            [
+                0x77, 0xdd, 0xac, 0x30, 0x6a, 0xe2, 0x66, 0xcc, 0xf9, 0x0b, 0xc1, 0x1e, 0xe4, 0x6d,
+                0x51, 0x3b
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cmac_last_block_complete() {
+        // Example 2: a complete last block is masked with K1.
+        assert_eq!(cmac_prefix_len(16), 0);
+        assert_eq!(
+            cmac_last_block(&MESSAGE[..16], &K1, &K2),
+            [
+                0x90, 0x2f, 0x68, 0xfa, 0x1b, 0x31, 0xac, 0xf0, 0x95, 0xb8, 0x9e, 0x9e, 0x01, 0xa5,
+                0xbf, 0xf4
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cmac_last_block_partial() {
+        // Example 3: the last 8 of 40 bytes are padded and masked with K2.
+        assert_eq!(cmac_prefix_len(40), 32);
+        assert_eq!(
+            cmac_last_block(&MESSAGE[32..40], &K1, &K2),
+            [
+                0xc7, 0x15, 0xb0, 0x76, 0xc9, 0xbe, 0x82, 0xdd, 0x79, 0x0b, 0xc1, 0x1e, 0xe4, 0x6d,
+                0x51, 0x3b
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cmac_prefix_len() {
+        assert_eq!(cmac_prefix_len(1), 0);
+        assert_eq!(cmac_prefix_len(17), 16);
+        assert_eq!(cmac_prefix_len(32), 16);
+        assert_eq!(cmac_prefix_len(64), 48);
+    }
+
+    #[test]
+    fn test_tags_equal() {
+        let mut tag = TAG_16;
+        assert!(tags_equal(&tag, &TAG_16));
+        tag[15] ^= 1;
+        assert!(!tags_equal(&tag, &TAG_16));
+        assert!(!tags_equal(&TAG_16[..8], &TAG_16));
+        assert!(tags_equal(&[], &[]));
+    }
+
+    #[test]
+    fn test_software_aes() {
+        assert_eq!(aes128_encrypt(&KEY, &[0; 16]), L);
+    }
+
+    /// Multiply in GF(2^8) modulo the AES polynomial.
+    fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+        let mut product = 0;
+        while b != 0 {
+            if b & 1 != 0 {
+                product ^= a;
+            }
+            a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+            b >>= 1;
+        }
+        product
+    }
+
+    /// The AES S-box: the inverse in GF(2^8), then the affine transform.
+    fn sub_byte(byte: u8) -> u8 {
+        let mut inverse = if byte == 0 { 0 } else { 1 };
+        if byte != 0 {
+            // byte^254 is the inverse of byte.
+            for _ in 0..254 {
+                inverse = gf_mul(inverse, byte);
+            }
+        }
+        let mut out = inverse ^ 0x63;
+        for shift in 1..5 {
+            out ^= inverse.rotate_left(shift);
+        }
+        out
+    }
+
+    /// Encrypt a single block with AES-128, in software.
+    fn aes128_encrypt(key: &[u8; 16], block: &[u8; 16]) -> [u8; 16] {
+        let mut words = [[0u8; 4]; 44];
+        for (i, word) in key.chunks(4).enumerate() {
+            words[i].copy_from_slice(word);
+        }
+        let mut rcon = 1;
+        for i in 4..44 {
+            let mut t = words[i - 1];
+            if i % 4 == 0 {
+                t = [
+                    sub_byte(t[1]) ^ rcon,
+                    sub_byte(t[2]),
+                    sub_byte(t[3]),
+                    sub_byte(t[0]),
+                ];
+                rcon = gf_mul(rcon, 2);
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - 4][j] ^ t[j];
+            }
+        }
+
+        // The state is kept column by column, as the block is.
+        let mut state = *block;
+        let add_round_key = |state: &mut [u8; 16], round: usize| {
+            for (i, byte) in state.iter_mut().enumerate() {
+                *byte ^= words[4 * round + i / 4][i % 4];
+            }
+        };
+        add_round_key(&mut state, 0);
+        for round in 1..11 {
+            let old = state;
+            for column in 0..4 {
+                for row in 0..4 {
+                    state[4 * column + row] = sub_byte(old[4 * ((column + row) % 4) + row]);
+                }
+            }
+            if round != 10 {
+                for column in state.chunks_mut(4) {
+                    let old = [column[0], column[1], column[2], column[3]];
+                    let all = old[0] ^ old[1] ^ old[2] ^ old[3];
+                    for row in 0..4 {
+                        column[row] = old[row] ^ all ^ gf_mul(old[row] ^ old[(row + 1) % 4], 2);
+                    }
+                }
+            }
+            add_round_key(&mut state, round);
+        }
+        state
+    }
+
+    /// An AES engine that records the IV it is given, and encrypts in
+    /// software in CBC mode. Operations complete when the test calls
+    /// `finish`.
+    struct MockAes {
+        enabled: Cell<bool>,
+        key: Cell<[u8; 16]>,
+        iv: Cell<[u8; 16]>,
+        cbc: Cell<bool>,
+        // The last ciphertext block of the CBC message.
+        chain: Cell<[u8; 16]>,
+        crypting: TakeCell<'static, [u8]>,
+    }
+
+    impl MockAes {
+        fn new() -> MockAes {
+            MockAes {
+                enabled: Cell::new(false),
+                key: Cell::new([0; 16]),
+                iv: Cell::new([0; 16]),
+                cbc: Cell::new(false),
+                chain: Cell::new([0; 16]),
+                crypting: TakeCell::empty(),
+            }
+        }
+
+        /// Complete the operation in progress.
+        fn finish(&self, driver: &'static AesDriver<'static, MockAes>) {
+            let buf = self.crypting.take().unwrap();
+            symmetric_encryption::Client::crypt_done(driver, None, buf);
+        }
+
+        /// Complete operations until the driver starts no more.
+        fn finish_all(&self, driver: &'static AesDriver<'static, MockAes>) {
+            while self.crypting.is_some() {
+                self.finish(driver);
+            }
+        }
+    }
+
+    impl AES128<'static> for MockAes {
+        fn enable(&self) {
+            self.enabled.set(true);
+        }
+        fn disable(&self) {
+            self.enabled.set(false);
+        }
+        fn set_client(&'static self, _client: &'static dyn symmetric_encryption::Client<'static>) {}
+        fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+            let mut block = [0; 16];
+            block.copy_from_slice(key);
+            self.key.set(block);
+            Ok(())
+        }
+        fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode> {
+            let mut block = [0; 16];
+            block.copy_from_slice(iv);
+            self.iv.set(block);
+            Ok(())
+        }
+        fn start_message(&self) {
+            self.chain.set(self.iv.get());
+        }
+        fn crypt(
+            &'static self,
+            _source: Option<&'static mut [u8]>,
+            dest: &'static mut [u8],
+            start_index: usize,
+            stop_index: usize,
+        ) -> Option<(
+            Result<(), ErrorCode>,
+            Option<&'static mut [u8]>,
+            &'static mut [u8],
+        )> {
+            assert!(self.enabled.get());
+            if self.cbc.get() {
+                for block in dest[start_index..stop_index].chunks_mut(16) {
+                    let mut input = self.chain.get();
+                    for (i, byte) in block.iter().enumerate() {
+                        input[i] ^= byte;
+                    }
+                    let output = aes128_encrypt(&self.key.get(), &input);
+                    block.copy_from_slice(&output);
+                    self.chain.set(output);
+                }
+            }
+            self.crypting.replace(dest);
+            None
+        }
+    }
+
+    impl AES128Ctr for MockAes {
+        fn set_mode_aes128ctr(&self, _encrypting: bool) {
+            self.cbc.set(false);
+        }
+    }
+
+    impl AES128CBC for MockAes {
+        fn set_mode_aes128cbc(&self, encrypting: bool) {
+            // Only CBC encryption is simulated.
+            self.cbc.set(encrypting);
+        }
+    }
+
+    #[derive(Default)]
+    struct MockEntropy {
+        requested: Cell<bool>,
+    }
+
+    impl Entropy32<'static> for MockEntropy {
+        fn get(&self) -> Result<(), ErrorCode> {
+            self.requested.set(true);
+            Ok(())
+        }
+        fn cancel(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn set_client(&'static self, _client: &'static dyn Client32) {}
+    }
+
+    const PREFIX: u64 = 0x1234_5678_9abc_def0;
+
+    fn driver(
+        names: &[&'static str],
+    ) -> (
+        &'static MockAes,
+        &'static AesDriver<'static, MockAes>,
+        Vec<&'static MockProcess>,
+    ) {
+        let (kernel, processes) = mock_process::kernel(names);
+        let aes = mock_process::leak(MockAes::new());
+        let driver = mock_process::leak(AesDriver::new(
+            aes,
+            mock_process::buffer(32),
+            mock_process::grant(kernel),
+        ));
+        (aes, driver, processes)
+    }
+
+    /// Set up the app for managed CTR encryption of a block under `key`.
+    fn load_key(driver: &AesDriver<'static, MockAes>, app: &MockProcess, key: u8) {
+        let id = app.processid();
+        assert!(driver.subscribe(0, app.upcall(DRIVER_NUM, 0), id).is_ok());
+        assert!(driver
+            .allow_readonly(id, 0, app.readonly_slice(&[key; 16]))
+            .is_ok());
+        assert!(driver
+            .allow_readonly(id, 2, app.readonly_slice(&[0; 16]))
+            .is_ok());
+        assert!(driver
+            .allow_readwrite(id, 0, app.readwrite_slice(&[0; 16]))
+            .is_ok());
+        assert!(driver.command(1, 0, 1, id).is_success());
+        assert!(driver.command(2, 0, 0, id).is_success());
+        assert!(driver.command(4, 1, 0, id).is_success());
+    }
+
+    fn next_nonce(driver: &AesDriver<'static, MockAes>, app: &MockProcess) -> Option<(u64, u32)> {
+        driver
+            .command(5, 0, 0, app.processid())
+            .get_success_u64_u32()
+    }
+
+    fn encrypt(
+        aes: &MockAes,
+        driver: &'static AesDriver<'static, MockAes>,
+        app: &MockProcess,
+    ) -> [u8; 16] {
+        assert!(driver.command(3, 0, 0, app.processid()).is_success());
+        aes.finish(driver);
+        assert_eq!(app.take_upcalls(), [(0, 0, 16, 0)]);
+        aes.iv.get()
+    }
+
+    fn counter_block(prefix: u64, message: u32) -> [u8; 16] {
+        let mut iv = [0; 16];
+        iv[..8].copy_from_slice(&prefix.to_be_bytes());
+        iv[8..12].copy_from_slice(&message.to_be_bytes());
+        iv
+    }
+
+    #[test]
+    fn test_managed_nonce_needs_entropy() {
+        let (_, driver, processes) = driver(&["app"]);
+        let app = processes[0];
+        assert_eq!(
+            driver.command(5, 0, 0, app.processid()).get_failure(),
+            Some(ErrorCode::RESERVE)
+        );
+        load_key(driver, app, 1);
+        assert_eq!(
+            driver.command(5, 0, 0, app.processid()).get_failure(),
+            Some(ErrorCode::NOSUPPORT)
+        );
+        assert_eq!(
+            driver.command(3, 0, 0, app.processid()).get_failure(),
+            Some(ErrorCode::NOSUPPORT)
+        );
+    }
+
+    #[test]
+    fn test_managed_nonce_waits_for_entropy() {
+        let (_, driver, processes) = driver(&["app"]);
+        let app = processes[0];
+        let entropy = mock_process::leak(MockEntropy::default());
+        driver.set_entropy(entropy);
+        assert!(entropy.requested.get());
+        load_key(driver, app, 1);
+        assert_eq!(
+            driver.command(5, 0, 0, app.processid()).get_failure(),
+            Some(ErrorCode::BUSY)
+        );
+
+        // The random value may arrive one word at a time.
+        let mut words = [0x1234_5678].iter().copied();
+        assert_eq!(driver.entropy_available(&mut words, Ok(())), Continue::More);
+        let mut words = [0x9abc_def0, 0xffff_ffff].iter().copied();
+        assert_eq!(driver.entropy_available(&mut words, Ok(())), Continue::Done);
+        assert_eq!(next_nonce(driver, app), Some((PREFIX, 0)));
+    }
+
+    fn driver_with_entropy(
+        names: &[&'static str],
+    ) -> (
+        &'static MockAes,
+        &'static AesDriver<'static, MockAes>,
+        Vec<&'static MockProcess>,
+    ) {
+        let (aes, driver, processes) = driver(names);
+        driver.set_entropy(mock_process::leak(MockEntropy::default()));
+        let mut words = [0x1234_5678, 0x9abc_def0].iter().copied();
+        driver.entropy_available(&mut words, Ok(()));
+        (aes, driver, processes)
+    }
+
+    #[test]
+    fn test_managed_nonce_increments() {
+        let (aes, driver, processes) = driver_with_entropy(&["app"]);
+        let app = processes[0];
+        load_key(driver, app, 1);
+        // The app's IV is ignored.
+        assert!(driver
+            .allow_readonly(app.processid(), 1, app.readonly_slice(&[0xff; 16]))
+            .is_ok());
+        for i in 0..3 {
+            assert_eq!(next_nonce(driver, app), Some((PREFIX, i)));
+            assert_eq!(encrypt(aes, driver, app), counter_block(PREFIX, i));
+        }
+    }
+
+    #[test]
+    fn test_managed_nonce_new_prefix_per_key_load() {
+        let (aes, driver, processes) = driver_with_entropy(&["first", "second"]);
+        let (first, second) = (processes[0], processes[1]);
+        load_key(driver, first, 1);
+        assert_eq!(encrypt(aes, driver, first), counter_block(PREFIX, 0));
+        assert_eq!(encrypt(aes, driver, first), counter_block(PREFIX, 1));
+
+        // Loading the same key again starts over under a new prefix.
+        load_key(driver, first, 1);
+        assert_eq!(encrypt(aes, driver, first), counter_block(PREFIX + 1, 0));
+
+        // Another app loading the same key never shares a prefix.
+        load_key(driver, second, 1);
+        assert_eq!(encrypt(aes, driver, second), counter_block(PREFIX + 2, 0));
+        assert_eq!(encrypt(aes, driver, first), counter_block(PREFIX + 1, 1));
+    }
+
+    #[test]
+    fn test_managed_nonce_after_restart() {
+        let (aes, driver, processes) = driver_with_entropy(&["app"]);
+        let app = processes[0];
+        load_key(driver, app, 1);
+        assert_eq!(encrypt(aes, driver, app), counter_block(PREFIX, 0));
+
+        // The counter went away with the grant, and the restarted app's
+        // nonces use a prefix the driver has not handed out before.
+        app.restart();
+        load_key(driver, app, 1);
+        assert_eq!(next_nonce(driver, app), Some((PREFIX + 1, 0)));
+        assert_eq!(encrypt(aes, driver, app), counter_block(PREFIX + 1, 0));
+    }
+
+    #[test]
+    fn test_managed_nonce_after_reset() {
+        // A new boot draws a new random value, rather than starting over
+        // from the prefixes of the previous boot.
+        let (_, driver, processes) = driver(&["app"]);
+        let app = processes[0];
+        driver.set_entropy(mock_process::leak(MockEntropy::default()));
+        let mut words = [0x0fed_cba9, 0x8765_4321].iter().copied();
+        driver.entropy_available(&mut words, Ok(()));
+        load_key(driver, app, 1);
+        assert_eq!(next_nonce(driver, app), Some((0x0fed_cba9_8765_4321, 0)));
+    }
+
+    #[test]
+    fn test_nonce_exhausted() {
+        let mut counter = NonceCounter::new(PREFIX);
+        counter.next_message = u32::MAX - 1;
+        assert_eq!(
+            counter.take_nonce(),
+            Ok(counter_block(PREFIX, u32::MAX - 1))
+        );
+        assert_eq!(counter.take_nonce(), Ok(counter_block(PREFIX, u32::MAX)));
+        assert!(counter.exhausted);
+        assert_eq!(counter.take_nonce(), Err(ErrorCode::NOMEM));
+        assert_eq!(counter.take_nonce(), Err(ErrorCode::NOMEM));
+    }
+
+    #[test]
+    fn test_managed_nonce_exhausted_until_key_load() {
+        let (aes, driver, processes) = driver_with_entropy(&["app"]);
+        let app = processes[0];
+        load_key(driver, app, 1);
+        assert!(next_nonce(driver, app).is_some());
+        let _ = driver.apps.enter(app.processid(), |app| {
+            app.nonce.as_mut().unwrap().next_message = u32::MAX;
+        });
+        assert_eq!(encrypt(aes, driver, app), counter_block(PREFIX, u32::MAX));
+        assert_eq!(
+            driver.command(5, 0, 0, app.processid()).get_failure(),
+            Some(ErrorCode::NOMEM)
+        );
+        assert_eq!(
+            driver.command(3, 0, 0, app.processid()).get_failure(),
+            Some(ErrorCode::NOMEM)
+        );
+        load_key(driver, app, 1);
+        assert_eq!(encrypt(aes, driver, app), counter_block(PREFIX + 1, 0));
+    }
+
+    #[test]
+    fn test_failed_run_leaves_hardware_disabled() {
+        let (aes, driver, processes) = driver_with_entropy(&["app"]);
+        let app = processes[0];
+        let id = app.processid();
+        load_key(driver, app, 1);
+        assert!(driver.command(3, 0, 0, id).is_success());
+        assert!(aes.enabled.get());
+        aes.finish(driver);
+        assert!(!aes.enabled.get());
+        app.take_upcalls();
+
+        // Without managed nonces, CTR needs a complete IV from the app.
+        assert!(driver.command(4, 0, 0, id).is_success());
+        assert_eq!(
+            driver.command(3, 0, 0, id).get_failure(),
+            Some(ErrorCode::RESERVE)
+        );
+        assert!(!aes.enabled.get());
+        assert!(driver
+            .allow_readonly(id, 1, app.readonly_slice(&[0; 8]))
+            .is_ok());
+        assert_eq!(
+            driver.command(3, 0, 0, id).get_failure(),
+            Some(ErrorCode::INVAL)
+        );
+        assert!(!aes.enabled.get());
+
+        // A used up managed nonce.
+        assert!(driver.command(4, 1, 0, id).is_success());
+        let _ = driver.apps.enter(id, |app| {
+            app.nonce.as_mut().unwrap().exhausted = true;
+        });
+        assert_eq!(
+            driver.command(3, 0, 0, id).get_failure(),
+            Some(ErrorCode::NOMEM)
+        );
+        assert!(!aes.enabled.get());
+    }
+
+    /// Compute the CMAC of `message` under the key of RFC 4493.
+    fn cmac(
+        aes: &MockAes,
+        driver: &'static AesDriver<'static, MockAes>,
+        app: &MockProcess,
+        message: &[u8],
+    ) -> [u8; 16] {
+        let id = app.processid();
+        assert!(driver.subscribe(0, app.upcall(DRIVER_NUM, 0), id).is_ok());
+        assert!(driver
+            .allow_readonly(id, 0, app.readonly_slice(&KEY))
+            .is_ok());
+        assert!(driver.command(2, 0, 0, id).is_success());
+        assert!(driver
+            .allow_readonly(id, 2, app.readonly_slice(message))
+            .is_ok());
+        assert!(driver
+            .allow_readwrite(id, 0, app.readwrite_slice(&[0; 16]))
+            .is_ok());
+        assert!(driver.command(6, 0, 0, id).is_success());
+        aes.finish_all(driver);
+        assert_eq!(app.take_upcalls(), [(0, 0, 16, 0)]);
+
+        let dest = driver
+            .allow_readwrite(id, 0, ReadWriteAppSlice::default())
+            .ok()
+            .unwrap();
+        let mut tag = [0; 16];
+        dest.map_or((), |data| tag.copy_from_slice(data));
+        tag
+    }
+
+    /// Verify `tag` as the CMAC of the message the app last shared.
+    fn verify(
+        aes: &MockAes,
+        driver: &'static AesDriver<'static, MockAes>,
+        app: &MockProcess,
+        tag: &[u8],
+    ) -> bool {
+        let id = app.processid();
+        assert!(driver
+            .allow_readonly(id, 1, app.readonly_slice(tag))
+            .is_ok());
+        assert!(driver.command(7, 0, 0, id).is_success());
+        aes.finish_all(driver);
+        let upcalls = app.take_upcalls();
+        assert_eq!(upcalls.len(), 1);
+        let (_, status, written, valid) = upcalls[0];
+        assert_eq!((status, written), (0, 0));
+        valid == 1
+    }
+
+    #[test]
+    fn test_cmac_rfc_4493_examples() {
+        let (aes, driver, processes) = driver(&["app"]);
+        let app = processes[0];
+        for (len, expected) in [(0, TAG_0), (16, TAG_16), (40, TAG_40), (64, TAG_64)].iter() {
+            assert_eq!(cmac(aes, driver, app, &MESSAGE[..*len]), *expected);
+            assert!(verify(aes, driver, app, expected));
+            assert!(!aes.enabled.get());
+        }
+    }
+
+    #[test]
+    fn test_cmac_verify_fails() {
+        let (aes, driver, processes) = driver(&["app"]);
+        let app = processes[0];
+        assert_eq!(cmac(aes, driver, app, &MESSAGE[..40]), TAG_40);
+
+        let mut tag = TAG_40;
+        tag[0] ^= 0x80;
+        assert!(!verify(aes, driver, app, &tag));
+        // The tag of another message.
+        assert!(!verify(aes, driver, app, &TAG_64));
+
+        // A truncated tag is refused before the hardware is used.
+        let id = app.processid();
+        assert!(driver
+            .allow_readonly(id, 1, app.readonly_slice(&TAG_40[..8]))
+            .is_ok());
+        assert_eq!(
+            driver.command(7, 0, 0, id).get_failure(),
+            Some(ErrorCode::INVAL)
+        );
+        assert!(!aes.enabled.get());
+    }
+}
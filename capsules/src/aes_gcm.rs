@@ -0,0 +1,452 @@
+//! Implements AES-GCM encryption/decryption/authentication using an underlying
+//! AES-CTR implementation.
+//!
+//! NIST SP 800-38D. GCM encrypts the message in counter mode and
+//! authenticates the additional data and the ciphertext with GHASH, a
+//! polynomial hash keyed by `H = E(K, 0^128)`. The tag is the GHASH of both
+//! fields and their lengths, masked with `E(K, J0)`, where `J0` is the
+//! 96-bit nonce followed by the 32-bit counter 1.
+//!
+//! Only the block cipher runs on the hardware: three passes of AES-CTR
+//! produce `H`, the mask and the keystream of the message, and GHASH is
+//! computed in software. The message is processed through a small kernel
+//! buffer, so any message length is supported.
+//!
+//! `Aes128Gcm` is the client of the AES hardware, and forwards the `AES128`
+//! interface of the hardware to its other users, such as the AES syscall
+//! driver. Those users and GCM take turns on the hardware: one fails with
+//! `BUSY` while the other is running.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::hil::symmetric_encryption::{AES128, AES128GCM};
+//! # use kernel::static_init;
+//!
+//! let gcm_crypt_buf = static_init!([u8; 64], [0; 64]);
+//! let aes_gcm = static_init!(
+//!     capsules::aes_gcm::Aes128Gcm<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::aes_gcm::Aes128Gcm::new(&sam4l::aes::AES, gcm_crypt_buf)
+//! );
+//! AES128::set_client(&sam4l::aes::AES, aes_gcm);
+//! AES128GCM::set_client(aes_gcm, gcm_client);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{
+    AES128Ctr, AES128, AES128CBC, AES128ECB, AES128_BLOCK_SIZE, AES128_KEY_SIZE, GCM_NONCE_LENGTH,
+    GCM_TAG_LENGTH,
+};
+use kernel::ErrorCode;
+
+use crate::aes::tags_equal;
+
+/// The pass of AES-CTR the hardware is working on.
+#[derive(Copy, Clone, PartialEq)]
+enum GcmStep {
+    /// Encrypting the zero block to derive the hash key `H`.
+    HashKey,
+    /// Encrypting `J0` to derive the tag mask.
+    Mask,
+    /// Encrypting or decrypting a chunk of the message.
+    Message,
+}
+
+/// Multiply two elements of GF(2^128) with the GCM bit order.
+fn gf128_mul(x: u128, y: u128) -> u128 {
+    let mut z = 0;
+    let mut v = y;
+    for i in 0..128 {
+        if (x >> (127 - i)) & 1 == 1 {
+            z ^= v;
+        }
+        v = if v & 1 == 1 {
+            (v >> 1) ^ (0xe1 << 120)
+        } else {
+            v >> 1
+        };
+    }
+    z
+}
+
+/// Absorb `data`, padded with zeros to a whole number of blocks, in to the
+/// GHASH state `y` under the hash key `h`.
+fn ghash_update(mut y: u128, h: u128, data: &[u8]) -> u128 {
+    for chunk in data.chunks(AES128_BLOCK_SIZE) {
+        let mut block = [0; AES128_BLOCK_SIZE];
+        block[..chunk.len()].copy_from_slice(chunk);
+        y = gf128_mul(y ^ u128::from_be_bytes(block), h);
+    }
+    y
+}
+
+pub struct Aes128Gcm<'a, A: AES128<'a> + AES128Ctr> {
+    aes: &'a A,
+    client: OptionalCell<&'a dyn symmetric_encryption::GCMClient>,
+    /// The client of the `AES128` interface passed through to the hardware,
+    /// and whether an operation of it is running.
+    aes_client: OptionalCell<&'a dyn symmetric_encryption::Client<'a>>,
+    aes_busy: Cell<bool>,
+
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    nonce: Cell<[u8; GCM_NONCE_LENGTH]>,
+
+    // Kernel buffer the hardware encrypts in, a whole number of blocks.
+    crypt_buf: TakeCell<'a, [u8]>,
+    buf: TakeCell<'static, [u8]>,
+    step: OptionalCell<GcmStep>,
+    a_off: Cell<usize>,
+    m_off: Cell<usize>,
+    m_len: Cell<usize>,
+    encrypting: Cell<bool>,
+    // Offset into the message of the chunk being processed, and its length.
+    position: Cell<usize>,
+    chunk_len: Cell<usize>,
+
+    hash_key: Cell<u128>,
+    mask: Cell<u128>,
+    ghash: Cell<u128>,
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> Aes128Gcm<'a, A> {
+    pub fn new(aes: &'a A, crypt_buf: &'a mut [u8]) -> Aes128Gcm<'a, A> {
+        Aes128Gcm {
+            aes: aes,
+            client: OptionalCell::empty(),
+            aes_client: OptionalCell::empty(),
+            aes_busy: Cell::new(false),
+            key: Cell::new([0; AES128_KEY_SIZE]),
+            nonce: Cell::new([0; GCM_NONCE_LENGTH]),
+            crypt_buf: TakeCell::new(crypt_buf),
+            buf: TakeCell::empty(),
+            step: OptionalCell::empty(),
+            a_off: Cell::new(0),
+            m_off: Cell::new(0),
+            m_len: Cell::new(0),
+            encrypting: Cell::new(false),
+            position: Cell::new(0),
+            chunk_len: Cell::new(0),
+            hash_key: Cell::new(0),
+            mask: Cell::new(0),
+            ghash: Cell::new(0),
+        }
+    }
+
+    /// The counter block of the nonce with `counter`, which is 1 for `J0`.
+    fn counter_block(&self, counter: u32) -> [u8; AES128_BLOCK_SIZE] {
+        let mut block = [0; AES128_BLOCK_SIZE];
+        block[..GCM_NONCE_LENGTH].copy_from_slice(&self.nonce.get());
+        block[GCM_NONCE_LENGTH..].copy_from_slice(&counter.to_be_bytes());
+        block
+    }
+
+    /// Start a new CTR message with the initial counter `iv`, and encrypt the
+    /// zero block in it.
+    fn crypt_zero_block(&self, step: GcmStep, iv: &[u8]) -> Result<(), ErrorCode> {
+        self.aes.set_iv(iv)?;
+        self.aes.start_message();
+        self.crypt_buf
+            .take()
+            .map_or(Err(ErrorCode::BUSY), |crypt_buf| {
+                if crypt_buf.len() < AES128_BLOCK_SIZE {
+                    self.crypt_buf.replace(crypt_buf);
+                    return Err(ErrorCode::SIZE);
+                }
+                for b in crypt_buf[..AES128_BLOCK_SIZE].iter_mut() {
+                    *b = 0;
+                }
+                self.step.set(step);
+                self.crypt_r(crypt_buf, AES128_BLOCK_SIZE)
+            })
+    }
+
+    /// Copy the next chunk of the message in to the kernel buffer and hand
+    /// it to the hardware.
+    fn crypt_chunk(&self) -> Result<(), ErrorCode> {
+        let position = self.position.get();
+        let (crypt_buf, buf) = match (self.crypt_buf.take(), self.buf.take()) {
+            (Some(crypt_buf), Some(buf)) => (crypt_buf, buf),
+            (crypt_buf, buf) => {
+                crypt_buf.map(|crypt_buf| self.crypt_buf.replace(crypt_buf));
+                buf.map(|buf| self.buf.replace(buf));
+                return Err(ErrorCode::FAIL);
+            }
+        };
+        let capacity = crypt_buf.len() - crypt_buf.len() % AES128_BLOCK_SIZE;
+        let len = cmp::min(self.m_len.get() - position, capacity);
+        let start = self.m_off.get() + position;
+        crypt_buf[..len].copy_from_slice(&buf[start..start + len]);
+        self.buf.replace(buf);
+
+        // The last chunk is padded to a whole block.
+        let padded_len = (len + AES128_BLOCK_SIZE - 1) / AES128_BLOCK_SIZE * AES128_BLOCK_SIZE;
+        for b in crypt_buf[len..padded_len].iter_mut() {
+            *b = 0;
+        }
+        if !self.encrypting.get() {
+            self.ghash.set(ghash_update(
+                self.ghash.get(),
+                self.hash_key.get(),
+                &crypt_buf[..len],
+            ));
+        }
+        self.chunk_len.set(len);
+        self.step.set(GcmStep::Message);
+        self.crypt_r(crypt_buf, padded_len)
+    }
+
+    fn crypt_r(&self, crypt_buf: &'a mut [u8], len: usize) -> Result<(), ErrorCode> {
+        match self.aes.crypt(None, crypt_buf, 0, len) {
+            None => Ok(()),
+            Some((res, _, crypt_buf)) => {
+                self.crypt_buf.replace(crypt_buf);
+                res.and(Err(ErrorCode::FAIL))
+            }
+        }
+    }
+
+    /// Handle a completed pass of the hardware over `crypt_buf`, and start
+    /// the next one. Returns `Ok(true)` once the message is done.
+    fn step_done(&self, step: GcmStep, crypt_buf: &'a mut [u8]) -> Result<bool, ErrorCode> {
+        let mut block = [0; AES128_BLOCK_SIZE];
+        block.copy_from_slice(&crypt_buf[..AES128_BLOCK_SIZE]);
+        match step {
+            GcmStep::HashKey => {
+                self.crypt_buf.replace(crypt_buf);
+                let hash_key = u128::from_be_bytes(block);
+                self.hash_key.set(hash_key);
+                let ghash = self.buf.map_or(0, |buf| {
+                    ghash_update(0, hash_key, &buf[self.a_off.get()..self.m_off.get()])
+                });
+                self.ghash.set(ghash);
+                self.crypt_zero_block(GcmStep::Mask, &self.counter_block(1))
+                    .map(|()| false)
+            }
+            GcmStep::Mask => {
+                self.crypt_buf.replace(crypt_buf);
+                self.mask.set(u128::from_be_bytes(block));
+                // The keystream of the message continues from `J0 + 1`.
+                if self.m_len.get() > 0 {
+                    self.crypt_chunk().map(|()| false)
+                } else {
+                    Ok(true)
+                }
+            }
+            GcmStep::Message => {
+                let len = self.chunk_len.get();
+                if self.encrypting.get() {
+                    self.ghash.set(ghash_update(
+                        self.ghash.get(),
+                        self.hash_key.get(),
+                        &crypt_buf[..len],
+                    ));
+                }
+                let start = self.m_off.get() + self.position.get();
+                self.buf.map(|buf| {
+                    buf[start..start + len].copy_from_slice(&crypt_buf[..len]);
+                });
+                self.crypt_buf.replace(crypt_buf);
+                self.position.set(self.position.get() + len);
+                if self.position.get() < self.m_len.get() {
+                    self.crypt_chunk().map(|()| false)
+                } else {
+                    Ok(true)
+                }
+            }
+        }
+    }
+
+    /// Compute the tag, and write or check it.
+    fn finish(&self, buf: &mut [u8]) -> bool {
+        let a_len = (self.m_off.get() - self.a_off.get()) as u64 * 8;
+        let m_len = self.m_len.get() as u64 * 8;
+        let mut lengths = [0; AES128_BLOCK_SIZE];
+        lengths[..8].copy_from_slice(&a_len.to_be_bytes());
+        lengths[8..].copy_from_slice(&m_len.to_be_bytes());
+        let ghash = ghash_update(self.ghash.get(), self.hash_key.get(), &lengths);
+        let tag = (ghash ^ self.mask.get()).to_be_bytes();
+
+        let tag_off = self.m_off.get() + self.m_len.get();
+        let buf_tag = &mut buf[tag_off..tag_off + GCM_TAG_LENGTH];
+        if self.encrypting.get() {
+            buf_tag.copy_from_slice(&tag);
+            true
+        } else {
+            tags_equal(buf_tag, &tag)
+        }
+    }
+
+    /// Report the end of the GCM operation to the client.
+    fn done(&self, res: Result<(), ErrorCode>) {
+        self.step.clear();
+        self.aes.disable();
+        self.buf.take().map(|buf| {
+            let tag_is_valid = res.is_ok() && self.finish(buf);
+            self.client
+                .map(move |client| client.crypt_done(buf, res, tag_is_valid));
+        });
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> symmetric_encryption::AES128GCM<'a> for Aes128Gcm<'a, A> {
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::GCMClient) {
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        if key.len() != AES128_KEY_SIZE {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut new_key = [0; AES128_KEY_SIZE];
+        new_key.copy_from_slice(key);
+        self.key.set(new_key);
+        Ok(())
+    }
+
+    fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode> {
+        if nonce.len() != GCM_NONCE_LENGTH {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut new_nonce = [0; GCM_NONCE_LENGTH];
+        new_nonce.copy_from_slice(nonce);
+        self.nonce.set(new_nonce);
+        Ok(())
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        a_off: usize,
+        m_off: usize,
+        m_len: usize,
+        encrypting: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.step.is_some() || self.aes_busy.get() {
+            return Err((ErrorCode::BUSY, buf));
+        }
+        if a_off > m_off || m_off + m_len + GCM_TAG_LENGTH > buf.len() {
+            return Err((ErrorCode::INVAL, buf));
+        }
+
+        self.buf.replace(buf);
+        self.a_off.set(a_off);
+        self.m_off.set(m_off);
+        self.m_len.set(m_len);
+        self.encrypting.set(encrypting);
+        self.position.set(0);
+
+        self.aes.enable();
+        // The hardware runs CTR encryption for both directions of GCM.
+        self.aes.set_mode_aes128ctr(true);
+        let res = self
+            .aes
+            .set_key(&self.key.get())
+            .and_then(|()| self.crypt_zero_block(GcmStep::HashKey, &[0; AES128_BLOCK_SIZE]));
+        res.map_err(|e| {
+            self.step.clear();
+            self.aes.disable();
+            (e, self.buf.take().unwrap())
+        })
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> symmetric_encryption::Client<'a> for Aes128Gcm<'a, A> {
+    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+        if self.aes_busy.get() {
+            self.aes_busy.set(false);
+            self.aes_client
+                .map(move |client| client.crypt_done(source, dest));
+            return;
+        }
+        let step = match self.step.extract() {
+            Some(step) => step,
+            None => return,
+        };
+        match self.step_done(step, dest) {
+            Ok(false) => {}
+            Ok(true) => self.done(Ok(())),
+            Err(e) => self.done(Err(e)),
+        }
+    }
+}
+
+// The `AES128` interface of the hardware, passed through for the other users
+// of the hardware.
+impl<'a, A: AES128<'a> + AES128Ctr> AES128<'a> for Aes128Gcm<'a, A> {
+    fn enable(&self) {
+        self.aes.enable();
+    }
+
+    fn disable(&self) {
+        if self.step.is_none() {
+            self.aes.disable();
+        }
+    }
+
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::Client<'a>) {
+        self.aes_client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        if self.step.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.aes.set_key(key)
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode> {
+        if self.step.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.aes.set_iv(iv)
+    }
+
+    fn start_message(&self) {
+        if self.step.is_none() {
+            self.aes.start_message();
+        }
+    }
+
+    fn crypt(
+        &'a self,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(Result<(), ErrorCode>, Option<&'a mut [u8]>, &'a mut [u8])> {
+        if self.step.is_some() {
+            return Some((Err(ErrorCode::BUSY), source, dest));
+        }
+        let res = self.aes.crypt(source, dest, start_index, stop_index);
+        self.aes_busy.set(res.is_none());
+        res
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> AES128Ctr for Aes128Gcm<'a, A> {
+    fn set_mode_aes128ctr(&self, encrypting: bool) {
+        if self.step.is_none() {
+            self.aes.set_mode_aes128ctr(encrypting);
+        }
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> AES128CBC for Aes128Gcm<'a, A> {
+    fn set_mode_aes128cbc(&self, encrypting: bool) {
+        if self.step.is_none() {
+            self.aes.set_mode_aes128cbc(encrypting);
+        }
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128ECB> AES128ECB for Aes128Gcm<'a, A> {
+    fn set_mode_aes128ecb(&self, encrypting: bool) {
+        if self.step.is_none() {
+            self.aes.set_mode_aes128ecb(encrypting);
+        }
+    }
+}
@@ -1,9 +1,20 @@
 //! Tock syscall driver capsule for Alarms, which issue callbacks when
 //! a point in time has been reached.
+//!
+//! Each process also has a stopwatch for measuring elapsed time. The elapsed
+//! time is computed in the kernel with the width of the underlying counter,
+//! so it is correct across a counter wrap as long as less than one full
+//! counter period has passed.
+//!
+//! Processes can also use a 64-bit tick count that does not wrap, and set
+//! alarms against it. The driver extends the counter by counting its wraps,
+//! so once a process has used the 64-bit count, the underlying alarm fires
+//! at least every half counter period.
 
 use core::cell::Cell;
+use core::cmp;
 use core::mem;
-use kernel::hil::time::{self, Alarm, Frequency, Ticks, Ticks32};
+use kernel::hil::time::{self, Alarm, Frequency, OverflowTracker, Ticks, Ticks32};
 use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
 
 /// Syscall driver number.
@@ -19,14 +30,20 @@ enum Expiration {
 #[derive(Copy, Clone)]
 pub struct AlarmData {
     expiration: Expiration,
+    // Expiration of an alarm set against the 64-bit tick count.
+    expiration64: Option<u64>,
     callback: Upcall,
+    // Stopwatch start and last lap counter values, if started.
+    stopwatch: Option<(u32, u32)>,
 }
 
 impl Default for AlarmData {
     fn default() -> AlarmData {
         AlarmData {
             expiration: Expiration::Disabled,
+            expiration64: None,
             callback: Upcall::default(),
+            stopwatch: None,
         }
     }
 }
@@ -36,6 +53,10 @@ pub struct AlarmDriver<'a, A: Alarm<'a>> {
     num_armed: Cell<usize>,
     app_alarms: Grant<AlarmData>,
     next_alarm: Cell<Expiration>,
+    ticks64: OverflowTracker,
+    // Whether a process has used the 64-bit tick count, which must then be
+    // extended at least once per counter period.
+    tracking: Cell<bool>,
 }
 
 impl<'a, A: Alarm<'a>> AlarmDriver<'a, A> {
@@ -45,6 +66,8 @@ impl<'a, A: Alarm<'a>> AlarmDriver<'a, A> {
             num_armed: Cell::new(0),
             app_alarms: grant,
             next_alarm: Cell::new(Expiration::Disabled),
+            ticks64: OverflowTracker::new(),
+            tracking: Cell::new(false),
         }
     }
 
@@ -120,10 +143,9 @@ impl<'a, A: Alarm<'a>> AlarmDriver<'a, A> {
             });
         }
         self.next_alarm.set(earliest_alarm);
-        match earliest_alarm {
-            Expiration::Disabled => {
-                let _ = self.alarm.disarm();
-            }
+        // Ticks until the earliest alarm fires, zero if it has passed.
+        let mut next_dt = match earliest_alarm {
+            Expiration::Disabled => None,
             Expiration::Enabled { reference, dt } => {
                 // This logic handles when the underlying Alarm is wider than
                 // 32 bits; it sets the reference to include the high bits of now
@@ -138,8 +160,34 @@ impl<'a, A: Alarm<'a>> AlarmDriver<'a, A> {
                     high_bits = high_bits.wrapping_sub(bit33);
                 }
                 let real_reference = high_bits.wrapping_add(A::Ticks::from(reference));
-                self.alarm.set_alarm(real_reference, A::Ticks::from(dt));
+                let end = real_reference.wrapping_add(A::Ticks::from(dt));
+                if now.within_range(real_reference, end) {
+                    Some(end.wrapping_sub(now))
+                } else {
+                    Some(A::Ticks::from(0))
+                }
+            }
+        };
+        if self.tracking.get() {
+            // Fire at least every half counter period, so that no wrap of
+            // the counter is missed, and for the earliest 64-bit alarm.
+            let now64 = self.ticks64.extend(now);
+            let mut dt64 = (A::Ticks::max_value().into_u32() / 2) as u64;
+            for alarm in self.app_alarms.iter() {
+                alarm.enter(|alarm| {
+                    if let Some(end) = alarm.expiration64 {
+                        dt64 = cmp::min(dt64, end.saturating_sub(now64));
+                    }
+                });
             }
+            let dt = A::Ticks::from(dt64 as u32);
+            next_dt = Some(next_dt.map_or(dt, |next_dt| cmp::min(next_dt, dt)));
+        }
+        match next_dt {
+            None => {
+                let _ = self.alarm.disarm();
+            }
+            Some(dt) => self.alarm.set_alarm(now, dt),
         }
     }
 }
@@ -183,6 +231,13 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
     /// - `3`: Stop the alarm if it is outstanding
     /// - `4`: Set an alarm to fire at a given clock value `time`.
     /// - `5`: Set an alarm to fire at a given clock value `time` relative to `now` (EXPERIMENTAL).
+    /// - `7`: Start (or restart) the stopwatch. Returns the clock value.
+    /// - `8`: Read the ticks elapsed since the stopwatch was started.
+    /// - `9`: Record a lap. Returns the ticks elapsed since the previous lap
+    ///        (or the start) and since the start.
+    /// - `10`: Read the 64-bit tick count, which does not wrap.
+    /// - `11`: Set an alarm to fire at the 64-bit tick count with lower bits
+    ///         `data` and upper bits `data2`.
     fn command(
         &self,
         cmd_type: usize,
@@ -199,9 +254,10 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
             .enter(caller_id, |td| {
                 // helper function to rearm alarm
                 let mut rearm = |reference: usize, dt: usize| {
-                    if let Expiration::Disabled = td.expiration {
+                    if let (Expiration::Disabled, None) = (td.expiration, td.expiration64) {
                         self.num_armed.set(self.num_armed.get() + 1);
                     }
+                    td.expiration64 = None;
                     td.expiration = Expiration::Enabled {
                         reference: reference as u32,
                         dt: dt as u32,
@@ -222,13 +278,14 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
                         (CommandReturn::success_u32(now.into_u32()), false)
                     },
                     3 /* Stop */ => {
-                        match td.expiration {
-                            Expiration::Disabled => {
+                        match (td.expiration, td.expiration64) {
+                            (Expiration::Disabled, None) => {
                                 // Request to stop when already stopped
                                 (CommandReturn::failure(ErrorCode::ALREADY), false)
                             },
                             _ => {
                                 td.expiration = Expiration::Disabled;
+                                td.expiration64 = None;
                                 let new_num_armed = self.num_armed.get() - 1;
                                 self.num_armed.set(new_num_armed);
                                 (CommandReturn::success(), true)
@@ -258,6 +315,48 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
                         let dt = data2;
                         rearm(reference, dt)
                     }
+                    7 /* Start stopwatch */ => {
+                        td.stopwatch = Some((now.into_u32(), now.into_u32()));
+                        (CommandReturn::success_u32(now.into_u32()), false)
+                    }
+                    8 /* Read stopwatch */ => {
+                        match td.stopwatch {
+                            Some((start, _)) => {
+                                let elapsed = now.wrapping_sub(A::Ticks::from(start));
+                                (CommandReturn::success_u32(elapsed.into_u32()), false)
+                            }
+                            None => (CommandReturn::failure(ErrorCode::RESERVE), false),
+                        }
+                    }
+                    9 /* Stopwatch lap */ => {
+                        match td.stopwatch {
+                            Some((start, lap)) => {
+                                let total = now.wrapping_sub(A::Ticks::from(start));
+                                let lap = now.wrapping_sub(A::Ticks::from(lap));
+                                td.stopwatch = Some((start, now.into_u32()));
+                                (
+                                    CommandReturn::success_u32_u32(lap.into_u32(), total.into_u32()),
+                                    false,
+                                )
+                            }
+                            None => (CommandReturn::failure(ErrorCode::RESERVE), false),
+                        }
+                    }
+                    10 /* Read 64-bit ticks */ => {
+                        // Start keeping the 64-bit count if it is the first
+                        // use.
+                        let reset = !self.tracking.replace(true);
+                        (CommandReturn::success_u64(self.ticks64.extend(now)), reset)
+                    }
+                    11 /* Set 64-bit absolute expiration */ => {
+                        self.tracking.set(true);
+                        if let (Expiration::Disabled, None) = (td.expiration, td.expiration64) {
+                            self.num_armed.set(self.num_armed.get() + 1);
+                        }
+                        td.expiration = Expiration::Disabled;
+                        td.expiration64 = Some((data2 as u64) << 32 | data as u32 as u64);
+                        (CommandReturn::success(), true)
+                    }
                     _ => (CommandReturn::failure(ErrorCode::NOSUPPORT), false)
                 }
             })
@@ -295,13 +394,141 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmDriver<'a, A> {
             }
         });
 
-        // If there are no armed alarms left, skip checking and just disable.
-        // Otherwise, check all the alarms and find the next one, rescheduling
-        // the underlying alarm.
-        if self.num_armed.get() == 0 {
+        if self.tracking.get() {
+            let now64 = self.ticks64.extend(self.alarm.now());
+            self.app_alarms.each(|_, alarm| {
+                if let Some(end) = alarm.expiration64 {
+                    if end <= now64 {
+                        alarm.expiration64 = None;
+                        self.num_armed.set(self.num_armed.get() - 1);
+                        alarm
+                            .callback
+                            .schedule(now64 as u32 as usize, end as u32 as usize, 0);
+                    }
+                }
+            });
+        }
+
+        // If there are no armed alarms left and no 64-bit count to keep,
+        // skip checking and just disable. Otherwise, check all the alarms and
+        // find the next one, rescheduling the underlying alarm.
+        if self.num_armed.get() == 0 && !self.tracking.get() {
             let _ = self.alarm.disarm();
         } else {
             self.reset_active_alarm();
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::AlarmDriver;
+    use crate::test::mock_alarm::MockAlarm;
+    use crate::test::mock_process;
+    use kernel::hil::time::{Ticks, Ticks24, Ticks32};
+    use kernel::{Driver, ErrorCode, ProcessId};
+
+    fn stopwatch<T: Ticks>() -> (
+        &'static MockAlarm<'static, T>,
+        &'static AlarmDriver<'static, MockAlarm<'static, T>>,
+        ProcessId,
+    ) {
+        let (kernel, processes) = mock_process::kernel(&["bench"]);
+        let alarm = mock_process::leak(MockAlarm::new());
+        let driver = mock_process::leak(AlarmDriver::new(alarm, mock_process::grant(kernel)));
+        (alarm, driver, processes[0].processid())
+    }
+
+    #[test]
+    fn test_stopwatch_elapsed() {
+        let (alarm, driver, id): (&MockAlarm<Ticks32>, _, _) = stopwatch();
+        assert_eq!(
+            driver.command(1, 0, 0, id).get_success_u32(),
+            Some(1_000_000)
+        );
+        assert_eq!(
+            driver.command(8, 0, 0, id).get_failure(),
+            Some(ErrorCode::RESERVE)
+        );
+        assert_eq!(
+            driver.command(9, 0, 0, id).get_failure(),
+            Some(ErrorCode::RESERVE)
+        );
+
+        alarm.set_now(1000);
+        assert_eq!(driver.command(7, 0, 0, id).get_success_u32(), Some(1000));
+        alarm.advance(250);
+        assert_eq!(driver.command(8, 0, 0, id).get_success_u32(), Some(250));
+        assert_eq!(
+            driver.command(9, 0, 0, id).get_success_u32_u32(),
+            Some((250, 250))
+        );
+        alarm.advance(100);
+        assert_eq!(
+            driver.command(9, 0, 0, id).get_success_u32_u32(),
+            Some((100, 350))
+        );
+
+        // Restarting resets both the start and the lap.
+        alarm.advance(50);
+        assert_eq!(driver.command(7, 0, 0, id).get_success_u32(), Some(1400));
+        alarm.advance(10);
+        assert_eq!(
+            driver.command(9, 0, 0, id).get_success_u32_u32(),
+            Some((10, 10))
+        );
+    }
+
+    #[test]
+    fn test_stopwatch_across_wrap() {
+        let (alarm, driver, id): (&MockAlarm<Ticks32>, _, _) = stopwatch();
+        alarm.set_now(u32::MAX - 99);
+        assert!(driver.command(7, 0, 0, id).is_success());
+        alarm.advance(60);
+        assert_eq!(
+            driver.command(9, 0, 0, id).get_success_u32_u32(),
+            Some((60, 60))
+        );
+        // The counter wraps between the lap and the read.
+        alarm.advance(240);
+        assert_eq!(driver.command(2, 0, 0, id).get_success_u32(), Some(200));
+        assert_eq!(driver.command(8, 0, 0, id).get_success_u32(), Some(300));
+        assert_eq!(
+            driver.command(9, 0, 0, id).get_success_u32_u32(),
+            Some((240, 300))
+        );
+    }
+
+    #[test]
+    fn test_stopwatch_across_24_bit_wrap() {
+        let (alarm, driver, id): (&MockAlarm<Ticks24>, _, _) = stopwatch();
+        alarm.set_now(0xFF_FF00);
+        assert_eq!(
+            driver.command(7, 0, 0, id).get_success_u32(),
+            Some(0xFF_FF00)
+        );
+        alarm.advance(0x180);
+        assert_eq!(driver.command(2, 0, 0, id).get_success_u32(), Some(0x80));
+        // Subtracting in 32 bits would give a value near 2^32.
+        assert_eq!(driver.command(8, 0, 0, id).get_success_u32(), Some(0x180));
+        assert_eq!(
+            driver.command(9, 0, 0, id).get_success_u32_u32(),
+            Some((0x180, 0x180))
+        );
+
+        // A lap of almost a full period is still measured.
+        alarm.advance(0xFF_FE00);
+        assert_eq!(
+            driver.command(2, 0, 0, id).get_success_u32(),
+            Some(0xFF_FE80)
+        );
+        assert_eq!(
+            driver.command(8, 0, 0, id).get_success_u32(),
+            Some(0xFF_FF80)
+        );
+        assert_eq!(
+            driver.command(9, 0, 0, id).get_success_u32_u32(),
+            Some((0xFF_FE00, 0xFF_FF80))
+        );
+    }
+}
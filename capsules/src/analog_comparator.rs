@@ -27,6 +27,24 @@
 //! For a normal comparison or an interrupt-based comparison, just one analog
 //! comparator is necessary.
 //!
+//! ## Interrupt Modes
+//! Interrupt-based comparisons report rising crossings (Vp > Vn) by default.
+//! Each channel can instead be set to report falling crossings or both
+//! crossings, on hardware that supports it.
+//!
+//! ## Window Comparison
+//! On hardware with a window mode, two comparators sharing a common input can
+//! be used as a window, and applications notified when the common input
+//! enters or leaves the voltage range set by the other two inputs.
+//!
+//! ## Multiple Applications
+//! Each channel is owned by the application that started interrupt-based
+//! comparisons on it, until that application stops them or exits. Other
+//! applications receive `BUSY` when they use a channel owned by another
+//! application, but can use the remaining channels at the same time.
+//! Interrupts on a channel are only delivered to its owner. Windows are owned
+//! the same way.
+//!
 //! For more information on how this capsule works, please take a look at the
 //! README: 00007_analog_comparator.md in doc/syscalls.
 
@@ -39,7 +57,6 @@ pub const DRIVER_NUM: usize = driver::NUM::AnalogComparator as usize;
 
 use core::mem;
 
-use kernel::common::cells::OptionalCell;
 use kernel::hil;
 use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
 
@@ -49,12 +66,15 @@ pub struct AnalogComparator<'a, A: hil::analog_comparator::AnalogComparator<'a>
     channels: &'a [&'a <A as hil::analog_comparator::AnalogComparator<'a>>::Channel],
 
     grants: Grant<App>,
-    current_process: OptionalCell<ProcessId>,
 }
 
 #[derive(Default)]
 pub struct App {
     callback: Upcall,
+    // Bit `i` is set if this app owns channel `i`.
+    channels: u32,
+    // Bit `i` is set if this app owns window `i`.
+    windows: u32,
 }
 
 impl<'a, A: hil::analog_comparator::AnalogComparator<'a>> AnalogComparator<'a, A> {
@@ -68,7 +88,56 @@ impl<'a, A: hil::analog_comparator::AnalogComparator<'a>> AnalogComparator<'a, A
             analog_comparator,
             channels,
             grants: grant,
-            current_process: OptionalCell::empty(),
+        }
+    }
+
+    /// Returns the app that owns `channel`, if any.
+    fn owner(&self, channel: usize) -> Option<ProcessId> {
+        self.grants.iter().find_map(|app| {
+            let appid = app.processid();
+            let owns = app.enter(|app| app.channels & (1 << channel) != 0);
+            if owns {
+                Some(appid)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns the app that owns `window`, if any.
+    fn window_owner(&self, window: usize) -> Option<ProcessId> {
+        self.grants.iter().find_map(|app| {
+            let appid = app.processid();
+            let owns = app.enter(|app| app.windows & (1 << window) != 0);
+            if owns {
+                Some(appid)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Check that `window` can be tracked and is not owned by an app other
+    /// than `appid`. Whether the window exists is up to the hardware.
+    fn check_window(&self, window: usize, appid: ProcessId) -> Result<(), ErrorCode> {
+        if window >= 32 {
+            return Err(ErrorCode::INVAL);
+        }
+        match self.window_owner(window) {
+            Some(owner) if owner != appid => Err(ErrorCode::BUSY),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that `channel` exists and is not owned by an app other than
+    /// `appid`.
+    fn check_channel(&self, channel: usize, appid: ProcessId) -> Result<(), ErrorCode> {
+        if channel >= self.channels.len() || channel >= 32 {
+            return Err(ErrorCode::INVAL);
+        }
+        match self.owner(channel) {
+            Some(owner) if owner != appid => Err(ErrorCode::BUSY),
+            _ => Ok(()),
         }
     }
 
@@ -107,6 +176,20 @@ impl<'a, A: hil::analog_comparator::AnalogComparator<'a>> AnalogComparator<'a, A
 
         result
     }
+
+    // Select the crossings that interrupt on a channel
+    fn set_interrupt_mode(
+        &self,
+        channel: usize,
+        mode: hil::analog_comparator::InterruptMode,
+    ) -> Result<(), ErrorCode> {
+        if channel >= self.channels.len() {
+            return Err(ErrorCode::INVAL);
+        }
+        // Convert channel index
+        let chan = self.channels[channel];
+        self.analog_comparator.set_interrupt_mode(chan, mode)
+    }
 }
 
 impl<'a, A: hil::analog_comparator::AnalogComparator<'a>> Driver for AnalogComparator<'a, A> {
@@ -124,11 +207,21 @@ impl<'a, A: hil::analog_comparator::AnalogComparator<'a>> Driver for AnalogCompa
     /// - `3`: Stop interrupt-based comparisons.
     ///        Input x chooses the desired comparator ACx (e.g. 0 or 1 for
     ///        hail, 0-3 for imix)
+    /// - `4`: Set the interrupt mode of a comparator.
+    ///        Input x chooses the desired comparator ACx, input y the mode:
+    ///        0 for rising edge, 1 for falling edge, 2 for both edges.
+    /// - `5`: Start interrupt-based window comparisons.
+    ///        Input x chooses the window, input y the event: 0 for entering
+    ///        the window, 1 for leaving it, 2 for both.
+    /// - `6`: Stop interrupt-based window comparisons.
+    ///        Input x chooses the window.
+    /// - `7`: Perform a simple window comparison.
+    ///        Input x chooses the window. Returns 1 if inside the window.
     fn command(
         &self,
         command_num: usize,
         channel: usize,
-        _: usize,
+        mode: usize,
         appid: ProcessId,
     ) -> CommandReturn {
         if command_num == 0 {
@@ -136,16 +229,16 @@ impl<'a, A: hil::analog_comparator::AnalogComparator<'a>> Driver for AnalogCompa
             return CommandReturn::success_u32(self.channels.len() as u32);
         }
 
-        // Check if this driver is free, or already dedicated to this process.
-        let match_or_empty_or_nonexistant = self.current_process.map_or(true, |current_process| {
-            self.grants
-                .enter(*current_process, |_| current_process == &appid)
-                .unwrap_or(true)
-        });
-        if match_or_empty_or_nonexistant {
-            self.current_process.set(appid);
-        } else {
-            return CommandReturn::failure(ErrorCode::NOMEM);
+        // Check that the channel is free, or already owned by this process.
+        if command_num <= 4 {
+            if let Err(e) = self.check_channel(channel, appid) {
+                return CommandReturn::failure(e);
+            }
+        } else if command_num <= 7 {
+            // For window commands the first argument is the window.
+            if let Err(e) = self.check_window(channel, appid) {
+                return CommandReturn::failure(e);
+            }
         }
 
         match command_num {
@@ -156,9 +249,71 @@ impl<'a, A: hil::analog_comparator::AnalogComparator<'a>> Driver for AnalogCompa
                 Err(e) => CommandReturn::failure(e),
             },
 
-            2 => self.start_comparing(channel).into(),
+            2 => self
+                .grants
+                .enter(appid, |app| {
+                    let res = self.start_comparing(channel);
+                    if res.is_ok() {
+                        app.channels |= 1 << channel;
+                    }
+                    res.into()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            3 => self
+                .grants
+                .enter(appid, |app| {
+                    let res = self.stop_comparing(channel);
+                    if res.is_ok() {
+                        app.channels &= !(1 << channel);
+                    }
+                    res.into()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            4 => {
+                let mode = match mode {
+                    0 => hil::analog_comparator::InterruptMode::RisingEdge,
+                    1 => hil::analog_comparator::InterruptMode::FallingEdge,
+                    2 => hil::analog_comparator::InterruptMode::Toggle,
+                    _ => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                self.set_interrupt_mode(channel, mode).into()
+            }
+
+            5 => {
+                let event = match mode {
+                    0 => hil::analog_comparator::WindowEvent::Enter,
+                    1 => hil::analog_comparator::WindowEvent::Leave,
+                    2 => hil::analog_comparator::WindowEvent::Toggle,
+                    _ => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                self.grants
+                    .enter(appid, |app| {
+                        let res = self.analog_comparator.start_window(channel, event);
+                        if res.is_ok() {
+                            app.windows |= 1 << channel;
+                        }
+                        res.into()
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            6 => self
+                .grants
+                .enter(appid, |app| {
+                    let res = self.analog_comparator.stop_window(channel);
+                    if res.is_ok() {
+                        app.windows &= !(1 << channel);
+                    }
+                    res.into()
+                })
+                .unwrap_or_else(|err| err.into()),
 
-            3 => self.stop_comparing(channel).into(),
+            7 => match self.analog_comparator.window_comparison(channel) {
+                Ok(b) => CommandReturn::success_u32(b as u32),
+                Err(e) => CommandReturn::failure(e),
+            },
 
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
@@ -196,10 +351,25 @@ impl<'a, A: hil::analog_comparator::AnalogComparator<'a>> hil::analog_comparator
 {
     /// Upcall to userland, signaling the application
     fn fired(&self, channel: usize) {
-        self.current_process.map(|appid| {
-            let _ = self.grants.enter(*appid, |app| {
+        if channel >= 32 {
+            return;
+        }
+        self.grants.each(|_, app| {
+            if app.channels & (1 << channel) != 0 {
                 app.callback.schedule(channel, 0, 0);
-            });
+            }
+        });
+    }
+
+    /// Upcall to userland, signaling the owner of the window
+    fn window_fired(&self, window: usize, inside: bool) {
+        if window >= 32 {
+            return;
+        }
+        self.grants.each(|_, app| {
+            if app.windows & (1 << window) != 0 {
+                app.callback.schedule(window, 1, inside as usize);
+            }
         });
     }
 }
@@ -21,7 +21,38 @@
 //!     capsules::app_flash_driver::AppFlash::new(nv_to_page,
 //!         board_kernel.create_grant(&grant_cap), &mut APP_FLASH_BUFFER));
 //! ```
+//!
+//! A/B Update Slots
+//! ----------------
+//!
+//! Boards can additionally give the driver two update slots and a small
+//! metadata record that tracks which slot is active and which slots hold a
+//! valid image. An update is written to the inactive slot, verified against
+//! a CRC-32 supplied by the updating app, and only then is the metadata
+//! rewritten to make the updated slot active. A failed or interrupted update
+//! therefore leaves the previously active slot in use. A bootloader reads the
+//! same metadata record at boot to decide which slot to run.
+//!
+//! The metadata record is eight bytes: the magic `ABSL`, the active slot
+//! (`0` for A, `1` for B), a bit mask of valid slots (bit 0 for A, bit 1 for
+//! B), and two reserved bytes. If no valid record is found, slot A is active
+//! and valid.
+//!
+//! Unlike writes to an app's own flash, the update slots are shared by all
+//! apps, so boards should only configure them when the apps they run are
+//! trusted to perform updates.
+//!
+//! ```
+//! app_flash.set_update_slots(capsules::app_flash_driver::UpdateSlots::new(
+//!     0x7F000, // metadata record
+//!     0x40000, // slot A
+//!     0x5F000, // slot B
+//!     0x1F000, // slot length
+//! ));
+//! app_flash.load_update_metadata();
+//! ```
 
+use core::cell::Cell;
 use core::cmp;
 use core::mem;
 use kernel::common::cells::{OptionalCell, TakeCell};
@@ -33,6 +64,114 @@ use kernel::{CommandReturn, Driver, Grant, ProcessId, Read, ReadOnlyAppSlice, Up
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::AppFlash as usize;
 
+/// Magic bytes at the start of the update metadata record.
+const METADATA_MAGIC: [u8; 4] = *b"ABSL";
+/// Length of the update metadata record in bytes.
+pub(crate) const METADATA_LENGTH: usize = 8;
+
+/// Flash layout of the A/B update slots.
+#[derive(Clone, Copy)]
+pub struct UpdateSlots {
+    pub(crate) metadata_address: usize,
+    pub(crate) slot_addresses: [usize; 2],
+    pub(crate) slot_length: usize,
+}
+
+impl UpdateSlots {
+    pub fn new(
+        metadata_address: usize,
+        slot_a_address: usize,
+        slot_b_address: usize,
+        slot_length: usize,
+    ) -> UpdateSlots {
+        UpdateSlots {
+            metadata_address: metadata_address,
+            slot_addresses: [slot_a_address, slot_b_address],
+            slot_length: slot_length,
+        }
+    }
+}
+
+/// Returns the active update slot (`0` for A, `1` for B) recorded in the
+/// update metadata `record`. Boards use this at boot to load processes from
+/// the active slot.
+pub fn active_slot(record: &[u8]) -> usize {
+    SlotMetadata::parse(record).active
+}
+
+/// Which update slot is active and which slots hold a valid image.
+#[derive(Clone, Copy)]
+pub(crate) struct SlotMetadata {
+    pub(crate) active: usize,
+    pub(crate) valid: u8,
+}
+
+impl SlotMetadata {
+    pub(crate) fn parse(record: &[u8]) -> SlotMetadata {
+        if record.len() >= METADATA_LENGTH
+            && record[0..4] == METADATA_MAGIC
+            && record[4] <= 1
+            && record[5] & (1 << record[4]) != 0
+        {
+            SlotMetadata {
+                active: record[4] as usize,
+                valid: record[5] & 0b11,
+            }
+        } else {
+            // No update has ever been committed.
+            SlotMetadata {
+                active: 0,
+                valid: 0b01,
+            }
+        }
+    }
+
+    pub(crate) fn serialize(&self, record: &mut [u8]) {
+        record[0..4].copy_from_slice(&METADATA_MAGIC);
+        record[4] = self.active as u8;
+        record[5] = self.valid;
+        record[6] = 0;
+        record[7] = 0;
+    }
+
+    pub(crate) fn inactive(&self) -> usize {
+        1 - self.active
+    }
+}
+
+/// Update the CRC-32 (IEEE 802.3) `crc` with `data`. Start from `0` and use
+/// the previous return value to continue over several chunks.
+pub(crate) fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// The flash operation currently in progress.
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    /// Writing to the current app's own flash.
+    AppWrite,
+    /// Writing metadata that marks the inactive update slot invalid, before
+    /// writing to it.
+    Invalidate,
+    /// Writing to the inactive update slot.
+    SlotWrite,
+    /// Reading back the inactive update slot to verify it.
+    Verify,
+    /// Writing updated metadata to commit an update.
+    Commit,
+    /// Reading the metadata record.
+    LoadMetadata,
+}
+
 #[derive(Default)]
 pub struct App {
     callback: Upcall,
@@ -46,6 +185,17 @@ pub struct AppFlash<'a> {
     apps: Grant<App>,
     current_app: OptionalCell<ProcessId>,
     buffer: TakeCell<'static, [u8]>,
+
+    operation: Cell<Operation>,
+    update_slots: OptionalCell<UpdateSlots>,
+    slot_metadata: OptionalCell<SlotMetadata>,
+    // Offset within the inactive slot of the write in progress.
+    slot_write_offset: Cell<usize>,
+    // Progress of verifying the inactive slot.
+    verify_offset: Cell<usize>,
+    verify_length: Cell<usize>,
+    verify_crc: Cell<u32>,
+    expected_crc: Cell<u32>,
 }
 
 impl<'a> AppFlash<'a> {
@@ -59,9 +209,169 @@ impl<'a> AppFlash<'a> {
             apps: grant,
             current_app: OptionalCell::empty(),
             buffer: TakeCell::new(buffer),
+            operation: Cell::new(Operation::Idle),
+            update_slots: OptionalCell::empty(),
+            slot_metadata: OptionalCell::empty(),
+            slot_write_offset: Cell::new(0),
+            verify_offset: Cell::new(0),
+            verify_length: Cell::new(0),
+            verify_crc: Cell::new(0),
+            expected_crc: Cell::new(0),
         }
     }
 
+    /// Enable A/B update slots with the given flash layout.
+    pub fn set_update_slots(&self, slots: UpdateSlots) {
+        self.update_slots.set(slots);
+    }
+
+    /// Read the update metadata record. Must be called once after
+    /// `set_update_slots()` before apps can use the update slots.
+    pub fn load_update_metadata(&self) -> Result<(), ErrorCode> {
+        let slots = self.update_slots.extract().ok_or(ErrorCode::NOSUPPORT)?;
+        if self.operation.get() != Operation::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
+            self.operation.set(Operation::LoadMetadata);
+            self.driver
+                .read(buffer, slots.metadata_address, METADATA_LENGTH)
+                .map_err(|e| {
+                    self.operation.set(Operation::Idle);
+                    e
+                })
+        })
+    }
+
+    /// Check that the update slots are configured and their metadata has
+    /// been loaded, and that no flash operation is in progress.
+    fn update_state(&self) -> Result<(UpdateSlots, SlotMetadata), ErrorCode> {
+        let slots = self.update_slots.extract().ok_or(ErrorCode::NOSUPPORT)?;
+        let metadata = self.slot_metadata.extract().ok_or(ErrorCode::BUSY)?;
+        if self.operation.get() != Operation::Idle || self.current_app.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        Ok((slots, metadata))
+    }
+
+    /// Write the allowed buffer to `offset` within the inactive update slot.
+    /// If the metadata record says the slot holds a valid image, the record
+    /// is first rewritten to mark it invalid, so that an interrupted update
+    /// is never booted.
+    fn write_inactive_slot(&self, offset: usize, appid: ProcessId) -> Result<(), ErrorCode> {
+        let (slots, metadata) = self.update_state()?;
+        let inactive = metadata.inactive();
+        let app_length = self
+            .apps
+            .enter(appid, |app| app.buffer.len())
+            .map_err(ErrorCode::from)?;
+        if app_length == 0 {
+            return Err(ErrorCode::RESERVE);
+        }
+        let length = self.buffer.map_or(Err(ErrorCode::BUSY), |buffer| {
+            Ok(cmp::min(buffer.len(), app_length))
+        })?;
+        if offset >= slots.slot_length || offset + length > slots.slot_length {
+            return Err(ErrorCode::INVAL);
+        }
+
+        self.current_app.set(appid);
+        self.slot_write_offset.set(offset);
+        let res = if metadata.valid & (1 << inactive) != 0 {
+            let invalidated = SlotMetadata {
+                active: metadata.active,
+                valid: metadata.valid & !(1 << inactive),
+            };
+            self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
+                invalidated.serialize(buffer);
+                self.operation.set(Operation::Invalidate);
+                self.driver
+                    .write(buffer, slots.metadata_address, METADATA_LENGTH)
+            })
+        } else {
+            self.write_slot(slots, metadata)
+        };
+        res.map_err(|e| {
+            self.current_app.clear();
+            self.operation.set(Operation::Idle);
+            e
+        })
+    }
+
+    /// Copy the current app's buffer in and write it to the inactive slot.
+    fn write_slot(&self, slots: UpdateSlots, metadata: SlotMetadata) -> Result<(), ErrorCode> {
+        let appid = self.current_app.extract().ok_or(ErrorCode::FAIL)?;
+        let offset = self.slot_write_offset.get();
+        self.apps
+            .enter(appid, |app| {
+                app.buffer.map_or(Err(ErrorCode::RESERVE), |app_buffer| {
+                    self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
+                        let length = cmp::min(buffer.len(), app_buffer.len());
+                        buffer[..length].copy_from_slice(&app_buffer[..length]);
+                        self.operation.set(Operation::SlotWrite);
+                        self.driver.write(
+                            buffer,
+                            slots.slot_addresses[metadata.inactive()] + offset,
+                            length,
+                        )
+                    })
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    /// Verify the first `length` bytes of the inactive slot against
+    /// `expected_crc` and, if they match, make it the active slot.
+    fn commit_inactive_slot(
+        &self,
+        length: usize,
+        expected_crc: u32,
+        appid: ProcessId,
+    ) -> Result<(), ErrorCode> {
+        let (slots, metadata) = self.update_state()?;
+        if length == 0 || length > slots.slot_length {
+            return Err(ErrorCode::INVAL);
+        }
+        self.apps.enter(appid, |_| ()).map_err(ErrorCode::from)?;
+        self.current_app.set(appid);
+        self.operation.set(Operation::Verify);
+        self.verify_offset.set(0);
+        self.verify_length.set(length);
+        self.verify_crc.set(0);
+        self.expected_crc.set(expected_crc);
+        self.verify_next(slots, metadata).map_err(|e| {
+            self.current_app.clear();
+            self.operation.set(Operation::Idle);
+            e
+        })
+    }
+
+    /// Read the next chunk of the inactive slot to verify.
+    fn verify_next(&self, slots: UpdateSlots, metadata: SlotMetadata) -> Result<(), ErrorCode> {
+        self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
+            let offset = self.verify_offset.get();
+            let length = cmp::min(buffer.len(), self.verify_length.get() - offset);
+            self.driver.read(
+                buffer,
+                slots.slot_addresses[metadata.inactive()] + offset,
+                length,
+            )
+        })
+    }
+
+    /// Finish the current update slot operation and notify the app.
+    fn update_done(&self, result: Result<(), ErrorCode>) {
+        self.operation.set(Operation::Idle);
+        let active = self.slot_metadata.extract().map_or(0, |m| m.active);
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback
+                    .schedule(kernel::into_statuscode(result), active, 0);
+            });
+        });
+        self.check_queue();
+    }
+
     // Check to see if we are doing something. If not, go ahead and do this
     // command. If so, this is queued and will be run when the pending command
     // completes.
@@ -78,8 +388,9 @@ impl<'a> AppFlash<'a> {
                     return Err(ErrorCode::INVAL);
                 }
 
-                if self.current_app.is_none() {
+                if self.current_app.is_none() && self.operation.get() == Operation::Idle {
                     self.current_app.set(appid);
+                    self.operation.set(Operation::AppWrite);
 
                     app.buffer.map_or(Err(ErrorCode::RESERVE), |app_buffer| {
                         // Copy contents to internal buffer and write it.
@@ -108,22 +419,8 @@ impl<'a> AppFlash<'a> {
             })
             .unwrap_or_else(|err| Err(err.into()))
     }
-}
-
-impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for AppFlash<'_> {
-    fn read_done(&self, _buffer: &'static mut [u8], _length: usize) {}
-
-    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
-        // Put our write buffer back.
-        self.buffer.replace(buffer);
-
-        // Notify the current application that the command finished.
-        self.current_app.take().map(|appid| {
-            let _ = self.apps.enter(appid, |app| {
-                app.callback.schedule(0, 0, 0);
-            });
-        });
 
+    fn check_queue(&self) {
         // Check if there are any pending events.
         for cntr in self.apps.iter() {
             let appid = cntr.processid();
@@ -131,6 +428,7 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for AppFlash<'_
                 if app.pending_command {
                     app.pending_command = false;
                     self.current_app.set(appid);
+                    self.operation.set(Operation::AppWrite);
                     let flash_address = app.flash_address;
 
                     app.buffer.map_or(false, |app_buffer| {
@@ -164,6 +462,103 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for AppFlash<'_
     }
 }
 
+impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for AppFlash<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        match self.operation.get() {
+            Operation::LoadMetadata => {
+                self.slot_metadata
+                    .set(SlotMetadata::parse(&buffer[..length]));
+                self.buffer.replace(buffer);
+                self.operation.set(Operation::Idle);
+                self.check_queue();
+            }
+            Operation::Verify => {
+                self.verify_crc
+                    .set(crc32_update(self.verify_crc.get(), &buffer[..length]));
+                self.verify_offset.set(self.verify_offset.get() + length);
+                self.buffer.replace(buffer);
+
+                let (slots, metadata) =
+                    match (self.update_slots.extract(), self.slot_metadata.extract()) {
+                        (Some(slots), Some(metadata)) => (slots, metadata),
+                        _ => return self.update_done(Err(ErrorCode::FAIL)),
+                    };
+                if self.verify_offset.get() < self.verify_length.get() {
+                    if let Err(e) = self.verify_next(slots, metadata) {
+                        self.update_done(Err(e));
+                    }
+                } else if self.verify_crc.get() != self.expected_crc.get() {
+                    // The image is corrupt, keep the active slot.
+                    self.update_done(Err(ErrorCode::FAIL));
+                } else {
+                    // Switch to the verified slot.
+                    let inactive = metadata.inactive();
+                    let committed = SlotMetadata {
+                        active: inactive,
+                        valid: metadata.valid | (1 << inactive),
+                    };
+                    let res = self.buffer.take().map_or(Err(ErrorCode::FAIL), |buffer| {
+                        committed.serialize(buffer);
+                        self.operation.set(Operation::Commit);
+                        self.driver
+                            .write(buffer, slots.metadata_address, METADATA_LENGTH)
+                    });
+                    if let Err(e) = res {
+                        self.update_done(Err(e));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        match self.operation.get() {
+            Operation::Invalidate => {
+                // The stored record no longer names the inactive slot valid,
+                // so it can be overwritten.
+                let metadata = SlotMetadata::parse(buffer);
+                self.buffer.replace(buffer);
+                self.slot_metadata.set(metadata);
+                let res = self
+                    .update_slots
+                    .extract()
+                    .map_or(Err(ErrorCode::FAIL), |slots| {
+                        self.write_slot(slots, metadata)
+                    });
+                if let Err(e) = res {
+                    self.update_done(Err(e));
+                }
+            }
+            Operation::SlotWrite => {
+                self.buffer.replace(buffer);
+                self.update_done(Ok(()));
+            }
+            Operation::Commit => {
+                // The metadata record now names the updated slot as active.
+                let metadata = SlotMetadata::parse(buffer);
+                self.buffer.replace(buffer);
+                self.slot_metadata.set(metadata);
+                self.update_done(Ok(()));
+            }
+            _ => {
+                // Put our write buffer back.
+                self.buffer.replace(buffer);
+                self.operation.set(Operation::Idle);
+
+                // Notify the current application that the command finished.
+                self.current_app.take().map(|appid| {
+                    let _ = self.apps.enter(appid, |app| {
+                        app.callback.schedule(0, 0, 0);
+                    });
+                });
+
+                self.check_queue();
+            }
+        }
+    }
+}
+
 impl Driver for AppFlash<'_> {
     /// Setup buffer to write from.
     ///
@@ -227,11 +622,20 @@ impl Driver for AppFlash<'_> {
     ///
     /// - `0`: Driver check.
     /// - `1`: Write the memory from the `allow` buffer to the address in flash.
+    /// - `2`: Return the active update slot (`0` for A, `1` for B) and the
+    ///        mask of slots holding a valid image.
+    /// - `3`: Write the `allow` buffer at offset `arg1` of the inactive
+    ///        update slot. This marks the inactive slot invalid, in the
+    ///        metadata record too, until the next commit.
+    /// - `4`: Commit an update: verify that the first `arg1` bytes of the
+    ///        inactive slot have the CRC-32 `arg2`, and if so make it the
+    ///        active slot. The callback receives the status and the active
+    ///        slot.
     fn command(
         &self,
         command_num: usize,
         arg1: usize,
-        _: usize,
+        arg2: usize,
         appid: ProcessId,
     ) -> CommandReturn {
         match command_num {
@@ -250,7 +654,258 @@ impl Driver for AppFlash<'_> {
                 }
             }
 
+            2 /* Query the active update slot */ => {
+                if self.update_slots.is_none() {
+                    CommandReturn::failure(ErrorCode::NOSUPPORT)
+                } else {
+                    self.slot_metadata.extract().map_or(
+                        CommandReturn::failure(ErrorCode::BUSY),
+                        |metadata| {
+                            CommandReturn::success_u32_u32(
+                                metadata.active as u32,
+                                metadata.valid as u32,
+                            )
+                        },
+                    )
+                }
+            }
+
+            3 /* Write to the inactive update slot */ => {
+                match self.write_inactive_slot(arg1, appid) {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            4 /* Verify and commit the inactive update slot */ => {
+                match self.commit_inactive_slot(arg1, arg2 as u32, appid) {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
             _ /* Unknown command num */ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{crc32_update, AppFlash, Operation, UpdateSlots, DRIVER_NUM};
+    use crate::test::mock_process::{self, MockProcess};
+    use core::cell::{Cell, RefCell};
+    use kernel::common::cells::{OptionalCell, TakeCell};
+    use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+    use kernel::{Driver, ErrorCode};
+
+    const METADATA: usize = 0;
+    const SLOT_A: usize = 64;
+    const SLOT_B: usize = 128;
+    const SLOT_LENGTH: usize = 64;
+
+    /// Storage that completes an operation when the test asks it to.
+    struct MockStorage {
+        memory: RefCell<[u8; 192]>,
+        client: OptionalCell<&'static dyn NonvolatileStorageClient<'static>>,
+        pending: TakeCell<'static, [u8]>,
+        // (write, address, length) of the pending operation.
+        operation: Cell<(bool, usize, usize)>,
+        fail: Cell<bool>,
+    }
+
+    impl MockStorage {
+        fn new() -> MockStorage {
+            MockStorage {
+                memory: RefCell::new([0xff; 192]),
+                client: OptionalCell::empty(),
+                pending: TakeCell::empty(),
+                operation: Cell::new((false, 0, 0)),
+                fail: Cell::new(false),
+            }
+        }
+
+        /// Complete the pending operation. Returns whether it was a write,
+        /// and its address.
+        fn complete(&self) -> (bool, usize) {
+            let buffer = self.pending.take().expect("no pending operation");
+            let (write, address, length) = self.operation.get();
+            let mut memory = self.memory.borrow_mut();
+            let client = self.client.extract().unwrap();
+            if write {
+                memory[address..address + length].copy_from_slice(&buffer[..length]);
+                drop(memory);
+                client.write_done(buffer, length);
+            } else {
+                buffer[..length].copy_from_slice(&memory[address..address + length]);
+                drop(memory);
+                client.read_done(buffer, length);
+            }
+            (write, address)
+        }
+
+        /// Complete operations until none is pending.
+        fn complete_all(&self) {
+            while self.pending.is_some() {
+                self.complete();
+            }
+        }
+
+        fn start(
+            &self,
+            write: bool,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            if self.fail.get() {
+                return Err(ErrorCode::FAIL);
+            }
+            self.pending.replace(buffer);
+            self.operation.set((write, address, length));
+            Ok(())
+        }
+    }
+
+    impl NonvolatileStorage<'static> for MockStorage {
+        fn set_client(&self, client: &'static dyn NonvolatileStorageClient<'static>) {
+            self.client.set(client);
+        }
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            self.start(false, buffer, address, length)
+        }
+
+        fn write(
+            &self,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            self.start(true, buffer, address, length)
+        }
+    }
+
+    /// A driver with update slots whose metadata has been loaded, used by one
+    /// app.
+    fn ready_driver() -> (
+        &'static MockStorage,
+        &'static AppFlash<'static>,
+        &'static MockProcess,
+    ) {
+        let (kernel, processes) = mock_process::kernel(&["updater"]);
+        let app = processes[0];
+        let storage = mock_process::leak(MockStorage::new());
+        let driver = mock_process::leak(AppFlash::new(
+            storage,
+            mock_process::grant(kernel),
+            mock_process::buffer(16),
+        ));
+        storage.set_client(driver);
+        driver.set_update_slots(UpdateSlots::new(METADATA, SLOT_A, SLOT_B, SLOT_LENGTH));
+        assert_eq!(driver.load_update_metadata(), Ok(()));
+        storage.complete();
+        assert!(driver
+            .subscribe(0, app.upcall(DRIVER_NUM, 0), app.processid())
+            .is_ok());
+        (storage, driver, app)
+    }
+
+    fn slots(driver: &AppFlash, app: &MockProcess) -> Option<(u32, u32)> {
+        driver
+            .command(2, 0, 0, app.processid())
+            .get_success_u32_u32()
+    }
+
+    /// Write `image` to the inactive slot and commit it with `crc`.
+    fn update(storage: &MockStorage, driver: &AppFlash, app: &MockProcess, image: &[u8], crc: u32) {
+        let id = app.processid();
+        assert!(driver
+            .allow_readonly(id, 0, app.readonly_slice(image))
+            .is_ok());
+        assert!(driver.command(3, 0, 0, id).is_success());
+        storage.complete_all();
+        assert_eq!(app.take_upcalls().len(), 1);
+        assert!(driver
+            .command(4, image.len(), crc as usize, id)
+            .is_success());
+        storage.complete_all();
+    }
+
+    #[test]
+    fn test_write_inactive_slot_then_commit() {
+        let (storage, driver, app) = ready_driver();
+        assert_eq!(slots(driver, app), Some((0, 0b01)));
+
+        let image = [0x5a; 16];
+        update(storage, driver, app, &image, crc32_update(0, &image));
+        assert_eq!(app.take_upcalls(), [(0, 0, 1, 0)]);
+        assert_eq!(slots(driver, app), Some((1, 0b11)));
+
+        let memory = storage.memory.borrow();
+        assert_eq!(memory[SLOT_B..SLOT_B + 16], image);
+        assert_eq!(memory[METADATA..METADATA + 6], *b"ABSL\x01\x03");
+    }
+
+    #[test]
+    fn test_failed_commit_keeps_slot_a_active() {
+        let (storage, driver, app) = ready_driver();
+        let image = [0x5a; 16];
+        update(storage, driver, app, &image, crc32_update(0, &image) ^ 1);
+        assert_eq!(
+            app.take_upcalls(),
+            [(0, kernel::into_statuscode(Err(ErrorCode::FAIL)), 0, 0)]
+        );
+        assert_eq!(slots(driver, app), Some((0, 0b01)));
+        // The metadata record was never written.
+        assert_eq!(storage.memory.borrow()[METADATA..METADATA + 8], [0xff; 8]);
+    }
+
+    #[test]
+    fn test_write_invalidates_stored_record_first() {
+        let (storage, driver, app) = ready_driver();
+        let image = [0x5a; 16];
+        update(storage, driver, app, &image, crc32_update(0, &image));
+        app.take_upcalls();
+        assert_eq!(slots(driver, app), Some((1, 0b11)));
+
+        // Slot A is inactive but valid: before any of it is overwritten the
+        // record must stop naming it valid.
+        let id = app.processid();
+        assert!(driver
+            .allow_readonly(id, 0, app.readonly_slice(&[0xa5; 16]))
+            .is_ok());
+        assert!(driver.command(3, 0, 0, id).is_success());
+        assert_eq!(storage.complete(), (true, METADATA));
+        assert_eq!(
+            storage.memory.borrow()[METADATA..METADATA + 6],
+            *b"ABSL\x01\x02"
+        );
+        assert_eq!(storage.memory.borrow()[SLOT_A], 0xff);
+        assert_eq!(storage.complete(), (true, SLOT_A));
+        assert_eq!(storage.memory.borrow()[SLOT_A..SLOT_A + 16], [0xa5; 16]);
+        assert_eq!(app.take_upcalls(), [(0, 0, 1, 0)]);
+        assert_eq!(slots(driver, app), Some((1, 0b10)));
+    }
+
+    #[test]
+    fn test_rejected_slot_write_leaves_driver_idle() {
+        let (storage, driver, app) = ready_driver();
+        let id = app.processid();
+        assert!(driver
+            .allow_readonly(id, 0, app.readonly_slice(&[0x5a; 16]))
+            .is_ok());
+        storage.fail.set(true);
+        assert_eq!(
+            driver.command(3, 0, 0, id).get_failure(),
+            Some(ErrorCode::FAIL)
+        );
+        assert!(driver.current_app.is_none());
+        assert!(driver.operation.get() == Operation::Idle);
+        assert_eq!(slots(driver, app), Some((0, 0b01)));
+    }
+}
@@ -0,0 +1,344 @@
+//! Attribute protocol, and the attribute tables processes define.
+//!
+//! A process describes its services in a table of entries, one after the
+//! other:
+//!
+//! ```text
+//! Service:         | 0x01 | UUID length (2 or 16) | UUID |
+//! Characteristic:  | 0x02 | Properties | Maximum value length |
+//!                    UUID length (2 or 16) | UUID |
+//! ```
+//!
+//! UUIDs are little-endian, as they are sent. Each characteristic belongs to
+//! the service before it. Characteristics are numbered from 0 in the order
+//! of the table, and their values live in the value buffer of the process:
+//! the value of each characteristic takes a byte for its current length and
+//! its maximum length, after the value of the previous characteristic.
+//!
+//! Services have one attribute, their declaration. Characteristics have a
+//! declaration and a value, followed by a client characteristic
+//! configuration descriptor if they support notifications.
+
+use kernel::ErrorCode;
+
+/// L2CAP channel of the attribute protocol.
+pub const ATT_CID: u16 = 0x0004;
+
+/// The default ATT_MTU of the LE transport, which is the only one
+/// supported.
+pub const ATT_MTU: usize = 23;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 3, Part F], section 3.4.8
+pub mod opcode {
+    pub const ERROR_RSP: u8 = 0x01;
+    pub const EXCHANGE_MTU_REQ: u8 = 0x02;
+    pub const EXCHANGE_MTU_RSP: u8 = 0x03;
+    pub const FIND_INFORMATION_REQ: u8 = 0x04;
+    pub const FIND_INFORMATION_RSP: u8 = 0x05;
+    pub const FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+    pub const FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+    pub const READ_BY_TYPE_REQ: u8 = 0x08;
+    pub const READ_BY_TYPE_RSP: u8 = 0x09;
+    pub const READ_REQ: u8 = 0x0a;
+    pub const READ_RSP: u8 = 0x0b;
+    pub const READ_BLOB_REQ: u8 = 0x0c;
+    pub const READ_BLOB_RSP: u8 = 0x0d;
+    pub const READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+    pub const READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+    pub const WRITE_REQ: u8 = 0x12;
+    pub const WRITE_RSP: u8 = 0x13;
+    pub const HANDLE_VALUE_NTF: u8 = 0x1b;
+    pub const WRITE_CMD: u8 = 0x52;
+    /// Commands, which have no response, have this bit set.
+    pub const COMMAND_FLAG: u8 = 0x40;
+}
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 3, Part F], section 3.4.1.1
+pub mod error {
+    pub const INVALID_HANDLE: u8 = 0x01;
+    pub const READ_NOT_PERMITTED: u8 = 0x02;
+    pub const WRITE_NOT_PERMITTED: u8 = 0x03;
+    pub const INVALID_PDU: u8 = 0x04;
+    pub const REQUEST_NOT_SUPPORTED: u8 = 0x06;
+    pub const INVALID_OFFSET: u8 = 0x07;
+    pub const ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+    pub const INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0d;
+    pub const UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+}
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 3, Part G], section 3.3.1.1
+pub mod properties {
+    pub const READ: u8 = 0x02;
+    pub const WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+    pub const WRITE: u8 = 0x08;
+    pub const NOTIFY: u8 = 0x10;
+    pub const SUPPORTED: u8 = READ | WRITE_WITHOUT_RESPONSE | WRITE | NOTIFY;
+}
+
+/// Attribute types of GATT declarations.
+pub const PRIMARY_SERVICE: u16 = 0x2800;
+pub const CHARACTERISTIC: u16 = 0x2803;
+pub const CLIENT_CHARACTERISTIC_CONFIGURATION: u16 = 0x2902;
+
+/// Bit of client characteristic configurations enabling notifications.
+pub const CCC_NOTIFICATIONS: u16 = 0x0001;
+
+/// Most characteristics in the table of a process.
+pub const MAX_CHARACTERISTICS: usize = 32;
+
+const ENTRY_SERVICE: u8 = 0x01;
+const ENTRY_CHARACTERISTIC: u8 = 0x02;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Uuid {
+    len: usize,
+    bytes: [u8; 16],
+}
+
+impl Uuid {
+    pub fn from_u16(uuid: u16) -> Uuid {
+        let mut bytes = [0; 16];
+        bytes[..2].copy_from_slice(&uuid.to_le_bytes());
+        Uuid {
+            len: 2,
+            bytes: bytes,
+        }
+    }
+
+    /// A 16-bit or 128-bit UUID, from its little-endian bytes.
+    pub fn from_slice(slice: &[u8]) -> Option<Uuid> {
+        if slice.len() != 2 && slice.len() != 16 {
+            return None;
+        }
+        let mut bytes = [0; 16];
+        bytes[..slice.len()].copy_from_slice(slice);
+        Some(Uuid {
+            len: slice.len(),
+            bytes: bytes,
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    pub fn is_u16(&self) -> bool {
+        self.len == 2
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Kind {
+    /// Declaration of a primary service with `uuid`, whose last attribute
+    /// has handle `end`.
+    Service { uuid: Uuid, end: u16 },
+    Characteristic {
+        properties: u8,
+        value_handle: u16,
+        uuid: Uuid,
+    },
+    /// Value of the characteristic `index` of a process, at `offset` in its
+    /// value buffer.
+    Value {
+        properties: u8,
+        uuid: Uuid,
+        index: usize,
+        offset: usize,
+        max_len: usize,
+    },
+    /// Client characteristic configuration of the characteristic `index`.
+    Configuration { index: usize },
+    /// A read-only value of the kernel.
+    Constant { uuid: Uuid, value: &'static [u8] },
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Attribute {
+    pub handle: u16,
+    pub kind: Kind,
+}
+
+impl Attribute {
+    /// The type of the attribute.
+    pub fn uuid(&self) -> Uuid {
+        match self.kind {
+            Kind::Service { .. } => Uuid::from_u16(PRIMARY_SERVICE),
+            Kind::Characteristic { .. } => Uuid::from_u16(CHARACTERISTIC),
+            Kind::Value { uuid, .. } => uuid,
+            Kind::Configuration { .. } => Uuid::from_u16(CLIENT_CHARACTERISTIC_CONFIGURATION),
+            Kind::Constant { uuid, .. } => uuid,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Entry {
+    Service(Uuid),
+    Characteristic {
+        properties: u8,
+        max_len: usize,
+        uuid: Uuid,
+    },
+}
+
+impl Entry {
+    fn num_attributes(&self) -> u16 {
+        match *self {
+            Entry::Service(_) => 1,
+            Entry::Characteristic { properties, .. } => {
+                if properties & properties::NOTIFY != 0 {
+                    3
+                } else {
+                    2
+                }
+            }
+        }
+    }
+}
+
+/// Parse the entry at the start of `table`. Returns it, with its length.
+fn parse_entry(table: &[u8]) -> Option<(Entry, usize)> {
+    match *table.get(0)? {
+        ENTRY_SERVICE => {
+            let len = *table.get(1)? as usize;
+            let uuid = Uuid::from_slice(table.get(2..2 + len)?)?;
+            Some((Entry::Service(uuid), 2 + len))
+        }
+        ENTRY_CHARACTERISTIC => {
+            let properties = *table.get(1)?;
+            let max_len = *table.get(2)? as usize;
+            let len = *table.get(3)? as usize;
+            let uuid = Uuid::from_slice(table.get(4..4 + len)?)?;
+            Some((
+                Entry::Characteristic {
+                    properties: properties,
+                    max_len: max_len,
+                    uuid: uuid,
+                },
+                4 + len,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Check an attribute table. Returns the number of attributes it defines.
+pub fn validate(table: &[u8]) -> Result<u16, ErrorCode> {
+    let mut pos = 0;
+    let mut count: u16 = 0;
+    let mut characteristics = 0;
+    while pos < table.len() {
+        let (entry, len) = parse_entry(&table[pos..]).ok_or(ErrorCode::INVAL)?;
+        match entry {
+            Entry::Service(_) => {}
+            Entry::Characteristic { properties, .. } => {
+                if count == 0 || properties & !properties::SUPPORTED != 0 {
+                    return Err(ErrorCode::INVAL);
+                }
+                characteristics += 1;
+            }
+        }
+        count = count
+            .checked_add(entry.num_attributes())
+            .ok_or(ErrorCode::SIZE)?;
+        pos += len;
+    }
+    if count == 0 {
+        Err(ErrorCode::INVAL)
+    } else if characteristics > MAX_CHARACTERISTICS {
+        Err(ErrorCode::SIZE)
+    } else {
+        Ok(count)
+    }
+}
+
+/// How to find an attribute in a table.
+#[derive(Copy, Clone)]
+pub enum Target {
+    Handle(u16),
+    /// The value of a characteristic.
+    Value(usize),
+}
+
+/// Find an attribute of the `count` attributes of `table`, whose first
+/// handle is `base`.
+pub fn find(table: &[u8], base: u16, count: u16, target: Target) -> Option<Attribute> {
+    let end = base.checked_add(count.checked_sub(1)?)?;
+    let mut pos = 0;
+    let mut handle = base;
+    let mut index = 0;
+    let mut offset = 0;
+    while pos < table.len() && handle <= end {
+        let (entry, len) = parse_entry(&table[pos..])?;
+        let next = handle.checked_add(entry.num_attributes())?;
+        match entry {
+            Entry::Service(uuid) => {
+                if let Target::Handle(h) = target {
+                    if h == handle {
+                        // The service ends before the next one.
+                        let mut service_end = next - 1;
+                        let mut rest = pos + len;
+                        while rest < table.len() {
+                            match parse_entry(&table[rest..]) {
+                                Some((entry @ Entry::Characteristic { .. }, len)) => {
+                                    service_end += entry.num_attributes();
+                                    rest += len;
+                                }
+                                _ => break,
+                            }
+                        }
+                        return Some(Attribute {
+                            handle: handle,
+                            kind: Kind::Service {
+                                uuid: uuid,
+                                end: core::cmp::min(service_end, end),
+                            },
+                        });
+                    }
+                }
+            }
+            Entry::Characteristic {
+                properties,
+                max_len,
+                uuid,
+            } => {
+                let value = Attribute {
+                    handle: handle + 1,
+                    kind: Kind::Value {
+                        properties: properties,
+                        uuid: uuid,
+                        index: index,
+                        offset: offset,
+                        max_len: max_len,
+                    },
+                };
+                match target {
+                    Target::Handle(h) if h == handle => {
+                        return Some(Attribute {
+                            handle: handle,
+                            kind: Kind::Characteristic {
+                                properties: properties,
+                                value_handle: handle + 1,
+                                uuid: uuid,
+                            },
+                        });
+                    }
+                    Target::Handle(h) if h == handle + 1 => return Some(value),
+                    Target::Handle(h) if h == handle + 2 && next - handle == 3 => {
+                        return Some(Attribute {
+                            handle: h,
+                            kind: Kind::Configuration { index: index },
+                        });
+                    }
+                    Target::Value(i) if i == index => return Some(value),
+                    _ => {}
+                }
+                index += 1;
+                offset += 1 + max_len;
+            }
+        }
+        handle = next;
+        pos += len;
+    }
+    None
+}
@@ -0,0 +1,377 @@
+//! Scanning and connection initiation for processes.
+//!
+//! Processes scan for advertisements, filtered by the address of the
+//! advertiser or by a pattern in their data, and can connect to an
+//! advertiser as the central. The scan runs while any process scans, and
+//! each advertisement that passes the filter of a process is written to its
+//! report buffer.
+//!
+//! A single connection is supported. Initiating it pauses reports, and
+//! scanning stops once it is created. Data is exchanged over the
+//! connection with the GATT server, like on connections of the peripheral.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ble_central = static_init!(
+//!     capsules::ble_gatt::BleCentral<'static, nrf52::ble_radio::Radio, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::ble_gatt::BleCentral::new(ble_link, board_kernel.create_grant(&grant_cap))
+//! );
+//! ble_link.set_central_client(ble_central);
+//! ```
+
+use crate::ble_gatt::link::pdu_type::{ADV_DIRECT_IND, ADV_IND};
+use crate::ble_gatt::link::{CentralClient, LinkLayer, ADDRESS_LEN};
+use core::{cmp, mem};
+use kernel::common::cells::OptionalCell;
+use kernel::hil::ble_connection::BleConnectionRadio;
+use kernel::hil::time::Alarm;
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BleCentral as usize;
+
+/// Flags of filters.
+const FILTER_ADDRESS: u8 = 0x01;
+const FILTER_RANDOM: u8 = 0x02;
+const FILTER_CONNECTABLE: u8 = 0x04;
+
+/// Length of filters without a pattern: flags, the address and the length
+/// of the pattern.
+const FILTER_HEADER_LEN: usize = 1 + ADDRESS_LEN + 1;
+
+/// Length of reports without data: PDU type, address type, address, RSSI
+/// and the length of the data.
+const REPORT_HEADER_LEN: usize = 1 + 1 + ADDRESS_LEN + 1 + 1;
+
+/// Bit of the high part of addresses in the connect command marking a
+/// random address.
+const RANDOM_FLAG: usize = 1 << 16;
+
+const DEFAULT_INTERVAL_MS: u32 = 50;
+
+pub struct App {
+    report_callback: Upcall,
+    connection_callback: Upcall,
+    reports: ReadWriteAppSlice,
+    filter: ReadOnlyAppSlice,
+    scanning: bool,
+    interval_ms: u32,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            report_callback: Upcall::default(),
+            connection_callback: Upcall::default(),
+            reports: ReadWriteAppSlice::default(),
+            filter: ReadOnlyAppSlice::default(),
+            scanning: false,
+            interval_ms: DEFAULT_INTERVAL_MS,
+        }
+    }
+}
+
+/// Whether an advertisement passes `filter`. An empty filter passes them
+/// all.
+fn matches(filter: &[u8], pdu_type: u8, address: &[u8], random: bool, data: &[u8]) -> bool {
+    if filter.len() < FILTER_HEADER_LEN {
+        return true;
+    }
+    let flags = filter[0];
+    if flags & FILTER_ADDRESS != 0
+        && (address != &filter[1..1 + ADDRESS_LEN] || random != (flags & FILTER_RANDOM != 0))
+    {
+        return false;
+    }
+    if flags & FILTER_CONNECTABLE != 0 && pdu_type != ADV_IND && pdu_type != ADV_DIRECT_IND {
+        return false;
+    }
+    let pattern_len = filter[FILTER_HEADER_LEN - 1] as usize;
+    let pattern = match filter.get(FILTER_HEADER_LEN..FILTER_HEADER_LEN + pattern_len) {
+        Some(pattern) => pattern,
+        None => return false,
+    };
+    pattern.is_empty() || data.windows(pattern.len()).any(|window| window == pattern)
+}
+
+pub struct BleCentral<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> {
+    link: &'a LinkLayer<'a, R, A>,
+    apps: Grant<App>,
+    /// The process initiating or owning the connection.
+    initiator: OptionalCell<ProcessId>,
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> BleCentral<'a, R, A> {
+    pub fn new(link: &'a LinkLayer<'a, R, A>, grant: Grant<App>) -> BleCentral<'a, R, A> {
+        BleCentral {
+            link: link,
+            apps: grant,
+            initiator: OptionalCell::empty(),
+        }
+    }
+
+    /// Stop the scan if no process needs it anymore.
+    fn update_scan(&self) {
+        let mut scanning = false;
+        for cntr in self.apps.iter() {
+            cntr.enter(|app| scanning |= app.scanning);
+        }
+        if !scanning {
+            self.link.stop_scan();
+        }
+    }
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> Driver for BleCentral<'a, R, A> {
+    /// ### `allow_num`
+    ///
+    /// - `0`: Report buffer. Each advertisement is written to it as its PDU
+    ///        type, its address type (`1` for random addresses), its
+    ///        address, least significant byte first, its RSSI in dBm, the
+    ///        length of its data and its data.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.reports, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(slice),
+            Ok(Err(e)) => Err((slice, e)),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// ### `allow_num`
+    ///
+    /// - `0`: Scan filter: flags, an address, least significant byte first,
+    ///        the length of a pattern and the pattern. Flags select
+    ///        advertisements from the address (bit 0), a random address if
+    ///        bit 1 is set, and connectable advertisements (bit 2). Only
+    ///        advertisements whose data contains the pattern are reported.
+    ///        Without a filter, all advertisements are reported.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.filter, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(slice),
+            Ok(Err(e)) => Err((slice, e)),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Subscribe to scan and connection events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: An advertisement was written to the report buffer. Upcall
+    ///        arguments: (report length, 0, 0).
+    /// - `1`: Connection events of the process that initiated the
+    ///        connection. Upcall arguments: (connected, reason). The reason
+    ///        a connection closed is an HCI error code.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.report_callback, &mut callback);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.connection_callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(callback),
+            Ok(Err(e)) => Err((callback, e)),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// BLE central control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Start scanning.
+    /// - `2`: Stop scanning.
+    /// - `3`: Connect to the device whose address has the low 32 bits in
+    ///        `data1` and the high 16 bits in `data2`. Bit 16 of `data2`
+    ///        marks a random address.
+    /// - `4`: Stop initiating the connection.
+    /// - `5`: Set the connection interval of the connections the process
+    ///        initiates to `data1` milliseconds.
+    /// - `6`: Close the connection the process initiated.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // start scanning
+            1 => {
+                let res = self.link.start_scan();
+                if res.is_ok() {
+                    let _ = self.apps.enter(appid, |app| app.scanning = true);
+                }
+                res.into()
+            }
+
+            // stop scanning
+            2 => {
+                let _ = self.apps.enter(appid, |app| app.scanning = false);
+                self.update_scan();
+                CommandReturn::success()
+            }
+
+            // connect
+            3 => {
+                if self.initiator.is_some() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                let mut address = [0; ADDRESS_LEN];
+                address[..4].copy_from_slice(&(data1 as u32).to_le_bytes());
+                address[4..].copy_from_slice(&(data2 as u16).to_le_bytes());
+                let res = self
+                    .apps
+                    .enter(appid, |app| {
+                        self.link
+                            .connect(address, data2 & RANDOM_FLAG != 0, app.interval_ms)
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                if res.is_ok() {
+                    self.initiator.set(appid);
+                }
+                res.into()
+            }
+
+            // cancel connection
+            4 => {
+                if !self.initiator.contains(&appid) || self.link.is_connected() {
+                    return CommandReturn::failure(ErrorCode::ALREADY);
+                }
+                self.initiator.clear();
+                self.link.cancel_connect();
+                CommandReturn::success()
+            }
+
+            // set connection interval
+            5 => self
+                .apps
+                .enter(appid, |app| {
+                    app.interval_ms = data1 as u32;
+                })
+                .map_err(ErrorCode::from)
+                .into(),
+
+            // disconnect
+            6 => {
+                if !self.initiator.contains(&appid) {
+                    return CommandReturn::failure(ErrorCode::OFF);
+                }
+                self.link.disconnect().into()
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> CentralClient for BleCentral<'a, R, A> {
+    fn advertisement(&self, pdu_type: u8, address: &[u8], random: bool, rssi: i8, data: &[u8]) {
+        for cntr in self.apps.iter() {
+            cntr.enter(|app| {
+                if !app.scanning
+                    || !app.filter.map_or(true, |filter| {
+                        matches(filter, pdu_type, address, random, data)
+                    })
+                {
+                    return;
+                }
+                let len = app.reports.mut_map_or(0, |reports| {
+                    if reports.len() < REPORT_HEADER_LEN {
+                        return 0;
+                    }
+                    let data_len = cmp::min(data.len(), reports.len() - REPORT_HEADER_LEN);
+                    reports[0] = pdu_type;
+                    reports[1] = random as u8;
+                    reports[2..2 + ADDRESS_LEN].copy_from_slice(address);
+                    reports[2 + ADDRESS_LEN] = rssi as u8;
+                    reports[3 + ADDRESS_LEN] = data_len as u8;
+                    reports[REPORT_HEADER_LEN..REPORT_HEADER_LEN + data_len]
+                        .copy_from_slice(&data[..data_len]);
+                    REPORT_HEADER_LEN + data_len
+                });
+                if len != 0 {
+                    app.report_callback.schedule(len, 0, 0);
+                }
+            });
+        }
+    }
+
+    fn connected(&self) {
+        // Scanning stopped.
+        for cntr in self.apps.iter() {
+            cntr.enter(|app| app.scanning = false);
+        }
+        self.initiator.map(|appid| {
+            let _ = self.apps.enter(*appid, |app| {
+                app.connection_callback.schedule(1, 0, 0);
+            });
+        });
+    }
+
+    fn disconnected(&self, reason: u8) {
+        if let Some(appid) = self.initiator.extract() {
+            let _ = self.apps.enter(appid, |app| {
+                app.connection_callback.schedule(0, reason as usize, 0);
+            });
+        }
+    }
+}
@@ -0,0 +1,1168 @@
+//! Link layer of a Bluetooth Low Energy peripheral and central.
+//!
+//! The link layer advertises that the device accepts connections, with
+//! `ADV_IND` packets on the three advertising channels, and listens after
+//! each of them for a `CONNECT_IND` of a central. Once connected, it opens
+//! a receive window for every connection event of the central, hopping
+//! between data channels with channel selection algorithm #1, and answers
+//! the first packet of each event. A single connection is supported, and
+//! the device stops advertising while connected.
+//!
+//! As a central, the link layer scans the advertising channels in turn,
+//! reporting the advertisements it receives. To initiate a connection, it
+//! answers the next connectable advertisement of the peripheral with a
+//! `CONNECT_IND`, and then starts each connection event with a packet the
+//! peripheral answers. Scanning and advertising do not overlap, and
+//! scanning stops once connected.
+//!
+//! Upper layers exchange L2CAP frames over the connection, which must fit
+//! in a single 27-byte data PDU. Each frame is sent once the previous one
+//! was acknowledged.
+//!
+//! The link layer answers the control procedures of the central:
+//! connection and channel map updates are applied at their instant, and
+//! features, lengths and encryption beyond the defaults are not supported.
+//!
+//! Timing relies on an alarm. Receive windows are widened by the clock
+//! accuracy of both devices and a margin that covers the latency of the
+//! alarm, and anchor points are estimated from the time packet exchanges
+//! complete.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ble_link = static_init!(
+//!     capsules::ble_gatt::LinkLayer<'static, nrf52::ble_radio::Radio, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::ble_gatt::LinkLayer::new(
+//!         &nrf52::ble_radio::RADIO,
+//!         ble_link_alarm,
+//!         [0x3a, 0x1d, 0xc4, 0x90, 0x5e, 0xf3],
+//!         &mut capsules::ble_gatt::link::TX_BUF,
+//!         &mut capsules::ble_gatt::link::RX_BUF,
+//!         &mut capsules::ble_gatt::link::PENDING_BUF,
+//!     )
+//! );
+//! nrf52::ble_radio::RADIO.set_connection_client(ble_link);
+//! ble_link_alarm.set_alarm_client(ble_link);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_connection::{self, air_time_us, BleConnectionRadio, T_IFS_US};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ErrorCode;
+
+/// Radio buffers hold the header and the payload of the longest
+/// advertising or data channel PDU.
+pub const PACKET_LEN: usize = 2 + 37;
+pub static mut TX_BUF: [u8; PACKET_LEN] = [0; PACKET_LEN];
+pub static mut RX_BUF: [u8; PACKET_LEN] = [0; PACKET_LEN];
+
+/// Longest payload of data channel PDUs.
+pub const MAX_DATA_LEN: usize = 27;
+pub static mut PENDING_BUF: [u8; MAX_DATA_LEN] = [0; MAX_DATA_LEN];
+
+/// Length of the header of L2CAP frames.
+pub const L2CAP_HEADER_LEN: usize = 4;
+
+/// Longest payload of L2CAP frames sent and received.
+pub const MAX_L2CAP_LEN: usize = MAX_DATA_LEN - L2CAP_HEADER_LEN;
+
+pub const MAX_ADV_DATA_LEN: usize = 31;
+
+pub const ADDRESS_LEN: usize = 6;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3
+pub mod pdu_type {
+    pub const ADV_IND: u8 = 0x00;
+    pub const ADV_DIRECT_IND: u8 = 0x01;
+    pub const ADV_NONCONN_IND: u8 = 0x02;
+    pub const SCAN_RSP: u8 = 0x04;
+    pub const CONNECT_IND: u8 = 0x05;
+    pub const ADV_SCAN_IND: u8 = 0x06;
+}
+use self::pdu_type::{ADV_DIRECT_IND, ADV_IND, CONNECT_IND};
+const ADV_PDU_TYPE_MASK: u8 = 0x0f;
+const ADV_TXADD: u8 = 0x40;
+const ADV_RXADD: u8 = 0x80;
+const CONNECT_IND_LEN: usize = 34;
+const LL_DATA_LEN: usize = 22;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.4
+const LLID_MASK: u8 = 0x03;
+const LLID_CONTINUATION: u8 = 0x01;
+const LLID_START: u8 = 0x02;
+const LLID_CONTROL: u8 = 0x03;
+const NESN: u8 = 0x04;
+const SN: u8 = 0x08;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.4.2
+mod control {
+    pub const CONNECTION_UPDATE_IND: u8 = 0x00;
+    pub const CHANNEL_MAP_IND: u8 = 0x01;
+    pub const TERMINATE_IND: u8 = 0x02;
+    pub const ENC_REQ: u8 = 0x03;
+    pub const UNKNOWN_RSP: u8 = 0x07;
+    pub const FEATURE_REQ: u8 = 0x08;
+    pub const FEATURE_RSP: u8 = 0x09;
+    pub const VERSION_IND: u8 = 0x0c;
+    pub const REJECT_IND: u8 = 0x0d;
+    pub const PERIPHERAL_FEATURE_REQ: u8 = 0x0e;
+    pub const PING_REQ: u8 = 0x12;
+    pub const PING_RSP: u8 = 0x13;
+    pub const LENGTH_REQ: u8 = 0x14;
+    pub const LENGTH_RSP: u8 = 0x15;
+}
+
+/// Longest control PDU sent.
+const CONTROL_LEN: usize = 9;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 2, Part D], section 1.3
+pub mod reason {
+    pub const CONNECTION_TIMEOUT: u8 = 0x08;
+    pub const REMOTE_USER_TERMINATED: u8 = 0x13;
+    pub const LOCAL_HOST_TERMINATED: u8 = 0x16;
+    pub const UNSUPPORTED_REMOTE_FEATURE: u8 = 0x1a;
+    pub const FAILED_TO_ESTABLISH: u8 = 0x3e;
+}
+
+/// Version 5.0 of the specification, and no company identifier.
+const VERSION: [u8; 5] = [0x09, 0xff, 0xff, 0x00, 0x00];
+
+/// Clock accuracy of the device, in ppm.
+const SCA_PPM: u32 = 50;
+
+/// Clock accuracy of the central for each value of the SCA field.
+const CENTRAL_SCA_PPM: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+
+/// Receive windows open this much earlier and close this much later, to
+/// cover the ramp up of the radio and the latency of the alarm.
+const WINDOW_MARGIN_US: u32 = 500;
+
+/// Units of connection parameters.
+const UNIT_US: u32 = 1250;
+const TIMEOUT_UNIT_US: u32 = 10_000;
+
+/// Connection events without a packet of the central after which a new
+/// connection fails.
+const ESTABLISH_EVENTS: u16 = 6;
+
+const NUM_DATA_CHANNELS: u8 = 37;
+
+/// How long scans listen on each advertising channel.
+const SCAN_WINDOW_MS: u32 = 100;
+
+/// Transmit window of connections the device initiates, in units of
+/// 1.25 ms. It covers the latency of handling the end of the `CONNECT_IND`.
+const TRANSMIT_WINDOW_SIZE: u8 = 4;
+
+pub trait L2capClient {
+    fn connected(&self);
+
+    /// The connection closed, for `reason`, an HCI error code.
+    fn disconnected(&self, reason: u8);
+
+    /// An L2CAP frame arrived on channel `cid`.
+    fn receive(&self, cid: u16, payload: &[u8]);
+
+    /// The central acknowledged the frame sent, and another one can be sent.
+    fn send_done(&self);
+}
+
+pub trait CentralClient {
+    /// An advertising channel PDU of `pdu_type` was received while
+    /// scanning, from `address`, a random address if `random`, with signal
+    /// strength `rssi`, in dBm. `data` is the rest of its payload.
+    fn advertisement(&self, pdu_type: u8, address: &[u8], random: bool, rssi: i8, data: &[u8]);
+
+    /// The connection the device initiated was created. Connection events
+    /// follow, and the L2CAP client is notified too.
+    fn connected(&self);
+
+    /// The connection the device initiated closed, for `reason`.
+    fn disconnected(&self, reason: u8);
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Standby,
+    /// Waiting for the next advertising event.
+    AdvertisingWait,
+    /// Advertising on the advertising channel with the given index.
+    Advertising(u8),
+    /// Waiting for the receive window of the next connection event.
+    EventWait,
+    Event,
+    /// Scanning the advertising channel with the given index.
+    Scanning(u8),
+}
+
+/// The PDU being sent, until the central acknowledges it.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum InFlight {
+    None,
+    Empty,
+    Control,
+    Data,
+    Terminate,
+}
+
+#[derive(Copy, Clone)]
+struct Update {
+    window_size_us: u32,
+    window_offset_us: u32,
+    interval_us: u32,
+    timeout_us: u32,
+    instant: u16,
+}
+
+/// A connection the device initiates.
+#[derive(Copy, Clone)]
+struct Initiation {
+    address: [u8; ADDRESS_LEN],
+    random: bool,
+    /// Parameters of the connection, as sent in the `CONNECT_IND`.
+    ll_data: [u8; LL_DATA_LEN],
+}
+
+#[derive(Copy, Clone)]
+struct Connection {
+    /// Whether the device is the central.
+    central: bool,
+    access_address: u32,
+    crc_init: u32,
+    interval_us: u32,
+    timeout_us: u32,
+    central_sca_ppm: u32,
+    channel_map: [u8; 5],
+    hop: u8,
+    last_unmapped: u8,
+    /// Counter of the next connection event.
+    event_counter: u16,
+    sn: bool,
+    nesn: bool,
+    /// Whether a packet of the central was received.
+    established: bool,
+    /// Time since the last packet of the central, in microseconds.
+    silence_us: u32,
+    /// Whether to terminate the connection.
+    terminating: bool,
+    update: Option<Update>,
+    channel_map_update: Option<([u8; 5], u16)>,
+}
+
+impl Connection {
+    /// Channel selection algorithm #1 (BLUETOOTH SPECIFICATION Version 5.0
+    /// [Vol 6, Part B], section 4.5.8.2).
+    fn next_channel(&mut self) -> u8 {
+        let unmapped = (self.last_unmapped + self.hop) % NUM_DATA_CHANNELS;
+        self.last_unmapped = unmapped;
+        let used = |channel: u8| self.channel_map[channel as usize / 8] & (1 << (channel % 8)) != 0;
+        if used(unmapped) {
+            return unmapped;
+        }
+        let num_used = (0..NUM_DATA_CHANNELS).filter(|&c| used(c)).count();
+        if num_used == 0 {
+            return unmapped;
+        }
+        (0..NUM_DATA_CHANNELS)
+            .filter(|&c| used(c))
+            .nth(unmapped as usize % num_used)
+            .unwrap_or(unmapped)
+    }
+}
+
+fn read_u16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+fn advertising_channel(index: u8) -> RadioChannel {
+    match index {
+        0 => RadioChannel::AdvertisingChannel37,
+        1 => RadioChannel::AdvertisingChannel38,
+        _ => RadioChannel::AdvertisingChannel39,
+    }
+}
+
+/// Whether `aa` can be the access address of a connection (BLUETOOTH
+/// SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.1.2).
+fn valid_access_address(aa: u32) -> bool {
+    // Bits set where consecutive bits of the address differ.
+    let transitions = (aa ^ (aa >> 1)) & 0x7fff_ffff;
+    let bytes = aa.to_le_bytes();
+    aa != ble_connection::ADVERTISING_ACCESS_ADDRESS
+        && (aa ^ ble_connection::ADVERTISING_ACCESS_ADDRESS).count_ones() > 1
+        && !bytes.iter().all(|&b| b == bytes[0])
+        && transitions.count_ones() <= 24
+        && (transitions >> 26).count_ones() >= 2
+        // No more than six consecutive bits are equal.
+        && (0..=25).all(|i| (transitions >> i) & 0x3f != 0)
+}
+
+pub struct LinkLayer<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    /// Static random address, least significant byte first.
+    address: [u8; ADDRESS_LEN],
+    client: OptionalCell<&'a dyn L2capClient>,
+    central_client: OptionalCell<&'a dyn CentralClient>,
+    state: Cell<State>,
+    advertising: Cell<bool>,
+    scanning: Cell<bool>,
+    initiation: Cell<Option<Initiation>>,
+    adv_data: Cell<[u8; MAX_ADV_DATA_LEN]>,
+    adv_data_len: Cell<usize>,
+    adv_interval_ms: Cell<u32>,
+    random: Cell<u32>,
+    connection: Cell<Connection>,
+    /// Estimated anchor point of the last connection event.
+    anchor: Cell<A::Ticks>,
+    /// When the current connection event is expected, relative to the
+    /// previous anchor point, in microseconds.
+    event_offset_us: Cell<u32>,
+    /// When the receive window of the current connection event closes,
+    /// relative to the previous anchor point.
+    window_end_us: Cell<u32>,
+    /// Transmit window of the next connection event, relative to the
+    /// previous anchor point, if it differs from the connection interval.
+    transmit_window: Cell<Option<(u32, u32)>>,
+    channel: Cell<u8>,
+    in_flight: Cell<InFlight>,
+    /// The PDU the central acknowledged in the current connection event.
+    acked: Cell<InFlight>,
+    /// Whether the packet of the central in the current connection event
+    /// is new.
+    received: Cell<bool>,
+    control: Cell<[u8; CONTROL_LEN]>,
+    control_len: Cell<usize>,
+    pending: TakeCell<'a, [u8]>,
+    pending_len: Cell<usize>,
+    tx_buf: TakeCell<'static, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> LinkLayer<'a, R, A> {
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        address: [u8; ADDRESS_LEN],
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        pending: &'a mut [u8],
+    ) -> LinkLayer<'a, R, A> {
+        LinkLayer {
+            radio: radio,
+            alarm: alarm,
+            address: address,
+            client: OptionalCell::empty(),
+            central_client: OptionalCell::empty(),
+            state: Cell::new(State::Standby),
+            advertising: Cell::new(false),
+            scanning: Cell::new(false),
+            initiation: Cell::new(None),
+            adv_data: Cell::new([0; MAX_ADV_DATA_LEN]),
+            adv_data_len: Cell::new(0),
+            adv_interval_ms: Cell::new(100),
+            random: Cell::new(
+                u32::from_le_bytes([address[0], address[1], address[2], address[3]]) | 1,
+            ),
+            connection: Cell::new(Connection {
+                central: false,
+                access_address: 0,
+                crc_init: 0,
+                interval_us: 0,
+                timeout_us: 0,
+                central_sca_ppm: 0,
+                channel_map: [0; 5],
+                hop: 0,
+                last_unmapped: 0,
+                event_counter: 0,
+                sn: false,
+                nesn: false,
+                established: false,
+                silence_us: 0,
+                terminating: false,
+                update: None,
+                channel_map_update: None,
+            }),
+            anchor: Cell::new(A::Ticks::from(0)),
+            event_offset_us: Cell::new(0),
+            window_end_us: Cell::new(0),
+            transmit_window: Cell::new(None),
+            channel: Cell::new(0),
+            in_flight: Cell::new(InFlight::None),
+            acked: Cell::new(InFlight::None),
+            received: Cell::new(false),
+            control: Cell::new([0; CONTROL_LEN]),
+            control_len: Cell::new(0),
+            pending: TakeCell::new(pending),
+            pending_len: Cell::new(0),
+            tx_buf: TakeCell::new(tx_buf),
+            rx_buf: TakeCell::new(rx_buf),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn L2capClient) {
+        self.client.set(client);
+    }
+
+    pub fn set_central_client(&self, client: &'a dyn CentralClient) {
+        self.central_client.set(client);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self.state.get() {
+            State::EventWait | State::Event => true,
+            _ => false,
+        }
+    }
+
+    /// Advertise `adv_data` every `interval_ms` while not connected,
+    /// including after connections close.
+    pub fn start_advertising(&self, adv_data: &[u8], interval_ms: u32) -> Result<(), ErrorCode> {
+        if adv_data.len() > MAX_ADV_DATA_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        let mut data = [0; MAX_ADV_DATA_LEN];
+        data[..adv_data.len()].copy_from_slice(adv_data);
+        self.adv_data.set(data);
+        self.adv_data_len.set(adv_data.len());
+        // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.4.2.2
+        self.adv_interval_ms.set(cmp::max(interval_ms, 20));
+        self.advertising.set(true);
+        if self.state.get() == State::Standby {
+            self.advertise(0);
+        }
+        Ok(())
+    }
+
+    /// Stop advertising, once the current advertising event ends.
+    pub fn stop_advertising(&self) {
+        self.advertising.set(false);
+        if self.state.get() == State::AdvertisingWait {
+            let _ = self.alarm.disarm();
+            self.state.set(State::Standby);
+        }
+    }
+
+    /// Report the advertisements received to the central client, until
+    /// `stop_scan()`. Reports pause while initiating a connection.
+    pub fn start_scan(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Standby => {
+                self.scanning.set(true);
+                self.scan(0);
+                Ok(())
+            }
+            State::Scanning(_) => {
+                self.scanning.set(true);
+                Ok(())
+            }
+            _ => Err(ErrorCode::BUSY),
+        }
+    }
+
+    pub fn stop_scan(&self) {
+        self.scanning.set(false);
+        self.scan_changed();
+    }
+
+    /// Connect to the peripheral with `address`, a random address if
+    /// `random`, once it advertises, with connection events every
+    /// `interval_ms`. The central client is notified once the connection
+    /// is created.
+    pub fn connect(
+        &self,
+        address: [u8; ADDRESS_LEN],
+        random: bool,
+        interval_ms: u32,
+    ) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Standby | State::Scanning(_) if self.initiation.get().is_none() => {}
+            _ => return Err(ErrorCode::BUSY),
+        }
+        // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3.3.1:
+        // intervals are 7.5 ms to 4 s, and the supervision timeout 100 ms to
+        // 32 s, longer than two intervals.
+        let interval_us = cmp::min(cmp::max(interval_ms.saturating_mul(1000), 7500), 4_000_000);
+        let interval = (interval_us / UNIT_US) as u16;
+        let timeout = cmp::min(
+            cmp::max(interval as u32 * UNIT_US * 6 / TIMEOUT_UNIT_US, 10),
+            3200,
+        );
+        let mut access_address = self.random();
+        while !valid_access_address(access_address) {
+            access_address = self.random();
+        }
+
+        let mut ll_data = [0; LL_DATA_LEN];
+        ll_data[0..4].copy_from_slice(&access_address.to_le_bytes());
+        ll_data[4..7].copy_from_slice(&self.random().to_le_bytes()[..3]);
+        ll_data[7] = TRANSMIT_WINDOW_SIZE;
+        ll_data[10..12].copy_from_slice(&interval.to_le_bytes());
+        ll_data[14..16].copy_from_slice(&(timeout as u16).to_le_bytes());
+        // All data channels are used, and the clock accuracy is the worst
+        // one, 251 to 500 ppm.
+        ll_data[16..21].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x1f]);
+        ll_data[21] = 5 + (self.random() % 12) as u8;
+        self.initiation.set(Some(Initiation {
+            address: address,
+            random: random,
+            ll_data: ll_data,
+        }));
+        self.scan_changed();
+        Ok(())
+    }
+
+    /// Stop initiating a connection.
+    pub fn cancel_connect(&self) {
+        self.initiation.set(None);
+        self.scan_changed();
+    }
+
+    /// Send an L2CAP frame with `payload` on channel `cid`. Returns `BUSY`
+    /// until the central acknowledges the previous frame.
+    pub fn send(&self, cid: u16, payload: &[u8]) -> Result<(), ErrorCode> {
+        if !self.is_connected() {
+            return Err(ErrorCode::OFF);
+        } else if payload.len() > MAX_L2CAP_LEN {
+            return Err(ErrorCode::SIZE);
+        } else if self.pending_len.get() != 0 {
+            return Err(ErrorCode::BUSY);
+        }
+        self.pending.map_or(Err(ErrorCode::NOMEM), |pending| {
+            pending[0..2].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+            pending[2..4].copy_from_slice(&cid.to_le_bytes());
+            pending[L2CAP_HEADER_LEN..L2CAP_HEADER_LEN + payload.len()].copy_from_slice(payload);
+            self.pending_len.set(L2CAP_HEADER_LEN + payload.len());
+            Ok(())
+        })
+    }
+
+    /// Terminate the connection. The client is notified once the central
+    /// acknowledges it.
+    pub fn disconnect(&self) -> Result<(), ErrorCode> {
+        if !self.is_connected() {
+            return Err(ErrorCode::OFF);
+        }
+        let mut connection = self.connection.get();
+        connection.terminating = true;
+        self.connection.set(connection);
+        Ok(())
+    }
+
+    // Returns a new pseudo-random number, with the Xorshift algorithm.
+    fn random(&self) -> u32 {
+        let mut next = self.random.get();
+        next ^= next << 13;
+        next ^= next >> 17;
+        next ^= next << 5;
+        self.random.set(next);
+        next
+    }
+
+    /// Start an exchange of the radio with the packet buffers.
+    fn exchange<F>(&self, start: F) -> Result<(), ErrorCode>
+    where
+        F: FnOnce(
+            &'static mut [u8],
+            &'static mut [u8],
+        ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [u8])>,
+    {
+        match (self.tx_buf.take(), self.rx_buf.take()) {
+            (Some(tx), Some(rx)) => start(tx, rx).map_err(|(e, tx, rx)| {
+                self.tx_buf.replace(tx);
+                self.rx_buf.replace(rx);
+                e
+            }),
+            (tx, rx) => {
+                tx.map(|tx| self.tx_buf.replace(tx));
+                rx.map(|rx| self.rx_buf.replace(rx));
+                Err(ErrorCode::NOMEM)
+            }
+        }
+    }
+
+    /// Send `ADV_IND` on the advertising channel with index `index`.
+    fn advertise(&self, index: u8) {
+        let len = self.adv_data_len.get();
+        let data = self.adv_data.get();
+        self.state.set(State::Advertising(index));
+        let res = self.exchange(|tx, rx| {
+            tx[0] = ADV_IND | ADV_TXADD;
+            tx[1] = (ADDRESS_LEN + len) as u8;
+            tx[2..2 + ADDRESS_LEN].copy_from_slice(&self.address);
+            tx[2 + ADDRESS_LEN..2 + ADDRESS_LEN + len].copy_from_slice(&data[..len]);
+            self.radio.advertise(advertising_channel(index), tx, rx)
+        });
+        match res {
+            Ok(()) => {
+                // Listen until a request would have started.
+                let window_us = air_time_us(2 + ADDRESS_LEN + len) + T_IFS_US + WINDOW_MARGIN_US;
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_us(window_us));
+            }
+            Err(_) => self.advertising_event_done(),
+        }
+    }
+
+    fn advertising_event_done(&self) {
+        if self.advertising.get() {
+            // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section
+            // 4.4.2.2: advDelay is a pseudo-random delay of 0 to 10 ms.
+            let delay_ms = self.adv_interval_ms.get() + self.random() % 11;
+            self.state.set(State::AdvertisingWait);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(delay_ms));
+        } else {
+            self.state.set(State::Standby);
+        }
+    }
+
+    /// Listen on the advertising channel with index `index`, reporting
+    /// advertisements or initiating a connection.
+    fn scan(&self, index: u8) {
+        self.state.set(State::Scanning(index));
+        let answer = self.initiation.get().is_some();
+        let res =
+            self.exchange(|tx, rx| self.radio.scan(advertising_channel(index), answer, tx, rx));
+        match res {
+            Ok(()) => self
+                .alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(SCAN_WINDOW_MS)),
+            Err(_) => {
+                self.scanning.set(false);
+                self.initiation.set(None);
+                self.standby();
+            }
+        }
+    }
+
+    /// Start or stop scanning once reports or initiations are requested or
+    /// no longer needed.
+    fn scan_changed(&self) {
+        let needed = self.scanning.get() || self.initiation.get().is_some();
+        match self.state.get() {
+            State::Standby if needed => self.scan(0),
+            // The scan continues with the new settings on the next channel.
+            State::Scanning(_) => self.radio.close_receive_window(),
+            _ => {}
+        }
+    }
+
+    /// Return to standby, or resume advertising.
+    fn standby(&self) {
+        self.state.set(State::Standby);
+        if self.advertising.get() {
+            self.advertise(0);
+        }
+    }
+
+    /// Set up the connection requested by the `CONNECT_IND` in `rx`, if it
+    /// is one for this device.
+    fn accept(&self, rx: &[u8], len: usize) -> bool {
+        if len != 2 + CONNECT_IND_LEN
+            || rx[0] & ADV_PDU_TYPE_MASK != CONNECT_IND
+            || rx[0] & ADV_RXADD == 0
+            || rx[8..8 + ADDRESS_LEN] != self.address
+        {
+            return false;
+        }
+        let ll_data = &rx[14..14 + LL_DATA_LEN];
+        if !self.set_connection(ll_data, false) {
+            return false;
+        }
+        // The first connection event is in a transmit window that starts
+        // 1.25 ms and the window offset after the end of the CONNECT_IND.
+        let window_offset_us = UNIT_US + read_u16(ll_data, 8) as u32 * UNIT_US;
+        let window_size_us = ll_data[7] as u32 * UNIT_US;
+        self.transmit_window
+            .set(Some((window_offset_us, window_size_us)));
+        true
+    }
+
+    /// Set up a connection with the parameters of a `CONNECT_IND`.
+    fn set_connection(&self, ll_data: &[u8], central: bool) -> bool {
+        let mut channel_map = [0; 5];
+        channel_map.copy_from_slice(&ll_data[16..21]);
+        let interval_us = read_u16(ll_data, 10) as u32 * UNIT_US;
+        let hop = ll_data[21] & 0x1f;
+        if interval_us == 0 || hop < 5 || hop > 16 {
+            return false;
+        }
+        self.connection.set(Connection {
+            central: central,
+            access_address: u32::from_le_bytes([ll_data[0], ll_data[1], ll_data[2], ll_data[3]]),
+            crc_init: u32::from_le_bytes([ll_data[4], ll_data[5], ll_data[6], 0]),
+            interval_us: interval_us,
+            timeout_us: read_u16(ll_data, 14) as u32 * TIMEOUT_UNIT_US,
+            central_sca_ppm: CENTRAL_SCA_PPM[(ll_data[21] >> 5) as usize],
+            channel_map: channel_map,
+            hop: hop,
+            last_unmapped: 0,
+            event_counter: 0,
+            sn: false,
+            nesn: false,
+            established: false,
+            silence_us: 0,
+            terminating: false,
+            update: None,
+            channel_map_update: None,
+        });
+        self.anchor.set(self.alarm.now());
+        self.in_flight.set(InFlight::None);
+        self.control_len.set(0);
+        self.pending_len.set(0);
+        true
+    }
+
+    /// Schedule the receive window of the next connection event.
+    fn schedule_event(&self) {
+        let mut connection = self.connection.get();
+        let counter = connection.event_counter;
+        if let Some(update) = connection.update {
+            if update.instant == counter {
+                self.transmit_window.set(Some((
+                    connection.interval_us + update.window_offset_us,
+                    update.window_size_us,
+                )));
+                connection.interval_us = update.interval_us;
+                connection.timeout_us = update.timeout_us;
+                connection.update = None;
+            }
+        }
+        if let Some((channel_map, instant)) = connection.channel_map_update {
+            if instant == counter {
+                connection.channel_map = channel_map;
+                connection.channel_map_update = None;
+            }
+        }
+        self.channel.set(connection.next_channel());
+        self.connection.set(connection);
+
+        let (offset_us, size_us) = self
+            .transmit_window
+            .take()
+            .unwrap_or((connection.interval_us, 0));
+        if connection.central {
+            // The connection event starts when the central transmits.
+            let anchor = self.anchor.get();
+            self.anchor
+                .set(anchor.wrapping_add(A::ticks_from_us(offset_us)));
+            self.event_offset_us.set(offset_us);
+            self.state.set(State::EventWait);
+            self.alarm.set_alarm(anchor, A::ticks_from_us(offset_us));
+            return;
+        }
+        // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.7
+        let widening_us = ((connection.central_sca_ppm + SCA_PPM) as u64
+            * (offset_us + size_us) as u64
+            / 1_000_000) as u32
+            + 16;
+        self.event_offset_us.set(offset_us);
+        self.window_end_us
+            .set(offset_us + size_us + widening_us + WINDOW_MARGIN_US);
+        self.state.set(State::EventWait);
+        self.alarm.set_alarm(
+            self.anchor.get(),
+            A::ticks_from_us(offset_us.saturating_sub(widening_us + WINDOW_MARGIN_US)),
+        );
+    }
+
+    fn start_event(&self) {
+        let connection = self.connection.get();
+        self.received.set(false);
+        self.acked.set(InFlight::None);
+        self.state.set(State::Event);
+        let channel =
+            RadioChannel::data_channel(self.channel.get()).unwrap_or(RadioChannel::DataChannel0);
+        let res = self.exchange(|tx, rx| {
+            if connection.central {
+                self.prepare(tx);
+                self.radio.central_event(
+                    channel,
+                    connection.access_address,
+                    connection.crc_init,
+                    tx,
+                    rx,
+                )
+            } else {
+                self.radio.connection_event(
+                    channel,
+                    connection.access_address,
+                    connection.crc_init,
+                    tx,
+                    rx,
+                )
+            }
+        });
+        match res {
+            // Listen until the response of the peripheral would have
+            // started.
+            Ok(()) if connection.central => self.alarm.set_alarm(
+                self.alarm.now(),
+                A::ticks_from_us(air_time_us(PACKET_LEN) + T_IFS_US + WINDOW_MARGIN_US),
+            ),
+            Ok(()) => self.alarm.set_alarm(
+                self.anchor.get(),
+                A::ticks_from_us(self.window_end_us.get()),
+            ),
+            Err(_) => self.event_done(0, 0, Err(ErrorCode::NOACK)),
+        }
+    }
+
+    /// Called at the end of a connection event, with the lengths of the
+    /// packets exchanged.
+    fn event_done(&self, rx_len: usize, tx_len: usize, result: Result<(), ErrorCode>) {
+        let mut connection = self.connection.get();
+        let offset_us = self.event_offset_us.get();
+        match result {
+            // The central sets the anchor points.
+            Ok(()) if connection.central => {
+                connection.established = true;
+                connection.silence_us = 0;
+            }
+            Err(_) if connection.central => {
+                connection.silence_us = connection.silence_us.saturating_add(offset_us);
+            }
+            Err(ErrorCode::NOACK) => {
+                self.anchor
+                    .set(self.anchor.get().wrapping_add(A::ticks_from_us(offset_us)));
+                connection.silence_us = connection.silence_us.saturating_add(offset_us);
+            }
+            _ => {
+                // The anchor point is the start of the packet of the central.
+                let elapsed_us = air_time_us(rx_len) + T_IFS_US + air_time_us(tx_len);
+                self.anchor
+                    .set(self.alarm.now().wrapping_sub(A::ticks_from_us(elapsed_us)));
+                if result.is_ok() {
+                    connection.established = true;
+                    connection.silence_us = 0;
+                } else {
+                    connection.silence_us = connection.silence_us.saturating_add(offset_us);
+                }
+            }
+        }
+        connection.event_counter = connection.event_counter.wrapping_add(1);
+        self.connection.set(connection);
+
+        match self.acked.replace(InFlight::None) {
+            InFlight::Terminate => return self.disconnected(reason::LOCAL_HOST_TERMINATED),
+            InFlight::Control => self.control_len.set(0),
+            InFlight::Data => {
+                self.pending_len.set(0);
+                self.client.map(|client| client.send_done());
+            }
+            _ => {}
+        }
+
+        if !connection.established && connection.event_counter >= ESTABLISH_EVENTS {
+            self.disconnected(reason::FAILED_TO_ESTABLISH);
+        } else if connection.silence_us >= connection.timeout_us {
+            self.disconnected(reason::CONNECTION_TIMEOUT);
+        } else if self.is_connected() {
+            self.schedule_event();
+        }
+    }
+
+    fn disconnected(&self, reason: u8) {
+        let _ = self.alarm.disarm();
+        self.state.set(State::Standby);
+        self.pending_len.set(0);
+        self.control_len.set(0);
+        self.in_flight.set(InFlight::None);
+        self.client.map(|client| client.disconnected(reason));
+        if self.connection.get().central {
+            self.central_client
+                .map(|client| client.disconnected(reason));
+        }
+        if self.advertising.get() && self.state.get() == State::Standby {
+            self.advertise(0);
+        }
+    }
+
+    /// Handle a new data channel PDU of the central.
+    fn receive(&self, rx: &[u8]) {
+        let len = cmp::min(rx[1] as usize, rx.len() - 2);
+        let payload = &rx[2..2 + len];
+        match rx[0] & LLID_MASK {
+            LLID_START => {
+                // Frames split over several PDUs are not supported.
+                if len >= L2CAP_HEADER_LEN
+                    && read_u16(payload, 0) as usize == len - L2CAP_HEADER_LEN
+                {
+                    let cid = read_u16(payload, 2);
+                    self.client
+                        .map(|client| client.receive(cid, &payload[L2CAP_HEADER_LEN..]));
+                }
+            }
+            LLID_CONTROL if len > 0 => self.receive_control(payload),
+            _ => {}
+        }
+    }
+
+    fn receive_control(&self, payload: &[u8]) {
+        let mut connection = self.connection.get();
+        let opcode = payload[0];
+        match opcode {
+            // Only the central updates the connection.
+            control::CONNECTION_UPDATE_IND | control::CHANNEL_MAP_IND if connection.central => {}
+            control::CONNECTION_UPDATE_IND if payload.len() >= 12 => {
+                connection.update = Some(Update {
+                    window_size_us: payload[1] as u32 * UNIT_US,
+                    window_offset_us: read_u16(payload, 2) as u32 * UNIT_US,
+                    interval_us: read_u16(payload, 4) as u32 * UNIT_US,
+                    timeout_us: read_u16(payload, 8) as u32 * TIMEOUT_UNIT_US,
+                    instant: read_u16(payload, 10),
+                });
+                self.connection.set(connection);
+            }
+            control::CHANNEL_MAP_IND if payload.len() >= 8 => {
+                let mut channel_map = [0; 5];
+                channel_map.copy_from_slice(&payload[1..6]);
+                connection.channel_map_update = Some((channel_map, read_u16(payload, 6)));
+                self.connection.set(connection);
+            }
+            control::TERMINATE_IND if payload.len() >= 2 => {
+                // The central closes the connection once the acknowledgement
+                // is sent.
+                self.disconnected(payload[1]);
+            }
+            control::ENC_REQ => {
+                self.queue_control(&[control::REJECT_IND, reason::UNSUPPORTED_REMOTE_FEATURE]);
+            }
+            control::FEATURE_REQ | control::PERIPHERAL_FEATURE_REQ => {
+                self.queue_control(&[control::FEATURE_RSP, 0, 0, 0, 0, 0, 0, 0, 0]);
+            }
+            control::VERSION_IND => {
+                let mut pdu = [control::VERSION_IND; 6];
+                pdu[1..].copy_from_slice(&VERSION);
+                self.queue_control(&pdu);
+            }
+            control::PING_REQ => self.queue_control(&[control::PING_RSP]),
+            control::LENGTH_REQ => {
+                // Only the default lengths are supported.
+                let mut pdu = [control::LENGTH_RSP; 9];
+                let octets = (MAX_DATA_LEN as u16).to_le_bytes();
+                let time = (air_time_us(2 + MAX_DATA_LEN) as u16).to_le_bytes();
+                pdu[1..3].copy_from_slice(&octets);
+                pdu[3..5].copy_from_slice(&time);
+                pdu[5..7].copy_from_slice(&octets);
+                pdu[7..9].copy_from_slice(&time);
+                self.queue_control(&pdu);
+            }
+            // Responses and indications that need no answer.
+            control::UNKNOWN_RSP
+            | control::FEATURE_RSP
+            | control::REJECT_IND
+            | control::PING_RSP
+            | control::LENGTH_RSP => {}
+            _ => self.queue_control(&[control::UNKNOWN_RSP, opcode]),
+        }
+    }
+
+    fn queue_control(&self, pdu: &[u8]) {
+        // A single control procedure runs at a time.
+        if self.control_len.get() == 0 {
+            let mut control = [0; CONTROL_LEN];
+            control[..pdu.len()].copy_from_slice(pdu);
+            self.control.set(control);
+            self.control_len.set(pdu.len());
+        }
+    }
+
+    /// Check whether the peer acknowledged the last packet sent, and sent a
+    /// new one (BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section
+    /// 4.5.9).
+    fn acknowledge(&self, rx: &[u8]) {
+        let mut connection = self.connection.get();
+        if rx.len() >= 2 {
+            if (rx[0] & NESN != 0) != connection.sn {
+                connection.sn = !connection.sn;
+                self.acked.set(self.in_flight.replace(InFlight::None));
+            }
+            if (rx[0] & SN != 0) == connection.nesn {
+                connection.nesn = !connection.nesn;
+                self.received.set(true);
+            }
+        }
+        self.connection.set(connection);
+    }
+
+    /// Write the next packet to send to `tx`: the unacknowledged one, or
+    /// the most urgent one.
+    fn prepare(&self, tx: &mut [u8]) {
+        let connection = self.connection.get();
+        let mut next = self.in_flight.get();
+        if next == InFlight::None || next == InFlight::Empty {
+            next = if connection.terminating {
+                InFlight::Terminate
+            } else if self.control_len.get() != 0 {
+                InFlight::Control
+            } else if self.pending_len.get() != 0 {
+                InFlight::Data
+            } else {
+                InFlight::Empty
+            };
+        }
+        let (llid, len) = match next {
+            InFlight::Terminate => {
+                tx[2] = control::TERMINATE_IND;
+                tx[3] = reason::REMOTE_USER_TERMINATED;
+                (LLID_CONTROL, 2)
+            }
+            InFlight::Control => {
+                let len = self.control_len.get();
+                tx[2..2 + len].copy_from_slice(&self.control.get()[..len]);
+                (LLID_CONTROL, len)
+            }
+            InFlight::Data => {
+                let len = self.pending_len.get();
+                self.pending
+                    .map(|pending| tx[2..2 + len].copy_from_slice(&pending[..len]));
+                (LLID_START, len)
+            }
+            _ => (LLID_CONTINUATION, 0),
+        };
+        self.in_flight.set(next);
+        tx[0] = llid | if connection.nesn { NESN } else { 0 } | if connection.sn { SN } else { 0 };
+        tx[1] = len as u8;
+    }
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> ble_connection::ConnectionClient
+    for LinkLayer<'a, R, A>
+{
+    fn respond(&self, rx: &[u8], crc_ok: bool, tx: &mut [u8]) {
+        if crc_ok {
+            self.acknowledge(rx);
+        }
+        self.prepare(tx);
+    }
+
+    fn scanned(&self, rx: &[u8], rssi: i8, tx: &mut [u8]) -> bool {
+        if rx.len() < 2 + ADDRESS_LEN {
+            return false;
+        }
+        let pdu_type = rx[0] & ADV_PDU_TYPE_MASK;
+        let random = rx[0] & ADV_TXADD != 0;
+        let address = &rx[2..2 + ADDRESS_LEN];
+        let initiation = match self.initiation.get() {
+            Some(initiation) => initiation,
+            None => {
+                self.central_client.map(|client| {
+                    client.advertisement(pdu_type, address, random, rssi, &rx[2 + ADDRESS_LEN..])
+                });
+                return false;
+            }
+        };
+        // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.4.4
+        let connectable = match pdu_type {
+            ADV_IND => true,
+            ADV_DIRECT_IND => {
+                rx.len() >= 2 + 2 * ADDRESS_LEN
+                    && rx[0] & ADV_RXADD != 0
+                    && rx[8..8 + ADDRESS_LEN] == self.address
+            }
+            _ => false,
+        };
+        if !connectable || address != initiation.address || random != initiation.random {
+            return false;
+        }
+        tx[0] = CONNECT_IND | ADV_TXADD | if random { ADV_RXADD } else { 0 };
+        tx[1] = CONNECT_IND_LEN as u8;
+        tx[2..2 + ADDRESS_LEN].copy_from_slice(&self.address);
+        tx[8..8 + ADDRESS_LEN].copy_from_slice(address);
+        tx[14..14 + LL_DATA_LEN].copy_from_slice(&initiation.ll_data);
+        true
+    }
+
+    fn exchange_done(
+        &self,
+        tx: &'static mut [u8],
+        rx: &'static mut [u8],
+        len: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        let _ = self.alarm.disarm();
+        let tx_len = tx[1] as usize + 2;
+        self.tx_buf.replace(tx);
+        match self.state.get() {
+            State::Advertising(index) => {
+                let connected = result.is_ok() && self.accept(rx, len);
+                self.rx_buf.replace(rx);
+                if connected {
+                    self.client.map(|client| client.connected());
+                    self.schedule_event();
+                } else if index < 2 {
+                    self.advertise(index + 1);
+                } else {
+                    self.advertising_event_done();
+                }
+            }
+            State::Scanning(index) => {
+                self.rx_buf.replace(rx);
+                match self.initiation.get() {
+                    // The CONNECT_IND was sent.
+                    Some(initiation) if result.is_ok() => {
+                        self.initiation.set(None);
+                        self.scanning.set(false);
+                        self.set_connection(&initiation.ll_data, true);
+                        // Start in the transmit window, which opens 1.25 ms
+                        // after the end of the CONNECT_IND.
+                        self.transmit_window
+                            .set(Some((UNIT_US + WINDOW_MARGIN_US, 0)));
+                        self.client.map(|client| client.connected());
+                        self.central_client.map(|client| client.connected());
+                        self.schedule_event();
+                    }
+                    _ if self.scanning.get() || self.initiation.get().is_some() => {
+                        self.scan((index + 1) % 3)
+                    }
+                    _ => self.standby(),
+                }
+            }
+            State::Event => {
+                if self.connection.get().central && result.is_ok() {
+                    self.acknowledge(&rx[..len]);
+                }
+                // Copy the packet out, since handling it may start the next
+                // exchange.
+                let mut packet = [0; PACKET_LEN];
+                let received = self.received.get() && len >= 2;
+                if received {
+                    packet[..len].copy_from_slice(&rx[..len]);
+                }
+                self.rx_buf.replace(rx);
+                if received {
+                    self.receive(&packet[..len]);
+                }
+                // The central may have closed the connection.
+                if self.is_connected() {
+                    self.event_done(len, tx_len, result);
+                }
+            }
+            _ => {
+                self.rx_buf.replace(rx);
+            }
+        }
+    }
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> time::AlarmClient for LinkLayer<'a, R, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::AdvertisingWait => self.advertise(0),
+            State::EventWait => self.start_event(),
+            State::Advertising(_) | State::Event | State::Scanning(_) => {
+                self.radio.close_receive_window()
+            }
+            State::Standby => {}
+        }
+    }
+}
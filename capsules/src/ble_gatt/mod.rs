@@ -0,0 +1,9 @@
+pub mod att;
+pub mod central;
+pub mod link;
+pub mod server;
+
+pub use self::central::BleCentral;
+pub use self::link::{CentralClient, L2capClient, LinkLayer};
+pub use self::server::GattServer;
+pub use self::server::DRIVER_NUM;
@@ -0,0 +1,860 @@
+//! GATT server, whose services processes define.
+//!
+//! Each process describes its services and characteristics in an attribute
+//! table (see `att`), and shares a buffer holding the values of its
+//! characteristics. Registering the table gives its attributes handles
+//! after those of the kernel, which exposes the mandatory GAP and GATT
+//! services. Handles are assigned in the order processes register their
+//! tables, and remain valid until the device reboots.
+//!
+//! The central reads values straight from the value buffers of processes.
+//! Writes of the central update them, and the process owning the
+//! characteristic is notified. Processes can notify the central of the
+//! value of characteristics whose notifications it enabled.
+//!
+//! Any process can advertise the device, and is notified of connections.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let gatt_server = static_init!(
+//!     capsules::ble_gatt::GattServer<'static, nrf52::ble_radio::Radio, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::ble_gatt::GattServer::new(
+//!         ble_link,
+//!         board_kernel.create_grant(&grant_cap),
+//!         b"Tock",
+//!     )
+//! );
+//! ble_link.set_client(gatt_server);
+//! ```
+
+use crate::ble_gatt::att::{
+    self, error, opcode, properties, Attribute, Kind, Target, Uuid, ATT_CID, ATT_MTU,
+};
+use crate::ble_gatt::link::{L2capClient, LinkLayer};
+use core::cell::Cell;
+use core::{cmp, mem};
+use kernel::common::cells::OptionalCell;
+use kernel::hil::ble_connection::BleConnectionRadio;
+use kernel::hil::time::Alarm;
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BleGatt as usize;
+
+/// Events of GATT upcalls.
+const EVENT_WRITE: usize = 0;
+const EVENT_NOTIFICATIONS: usize = 1;
+const EVENT_NOTIFY_DONE: usize = 2;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 3, Part C], section 12
+const GAP_SERVICE: u16 = 0x1800;
+const GATT_SERVICE: u16 = 0x1801;
+const DEVICE_NAME: u16 = 0x2a00;
+const APPEARANCE: u16 = 0x2a01;
+
+/// Handles 1 to 6 are the attributes of the kernel.
+const FIRST_APP_HANDLE: u16 = 7;
+
+/// Longest value that fits in responses and notifications.
+const MAX_VALUE_LEN: usize = 255;
+
+#[derive(Default)]
+pub struct App {
+    event_callback: Upcall,
+    connection_callback: Upcall,
+    table: ReadOnlyAppSlice,
+    adv_data: ReadOnlyAppSlice,
+    values: ReadWriteAppSlice,
+    /// Handle of the first attribute of the table, once registered.
+    base_handle: u16,
+    attr_count: u16,
+    /// Characteristics whose notifications the central enabled, one bit per
+    /// characteristic.
+    notifications: u32,
+}
+
+impl App {
+    fn contains(&self, handle: u16) -> bool {
+        self.attr_count != 0
+            && handle >= self.base_handle
+            && handle - self.base_handle < self.attr_count
+    }
+
+    fn find(&self, target: Target) -> Option<Attribute> {
+        if self.attr_count == 0 {
+            return None;
+        }
+        self.table.map_or(None, |table| {
+            att::find(table, self.base_handle, self.attr_count, target)
+        })
+    }
+}
+
+pub struct GattServer<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> {
+    link: &'a LinkLayer<'a, R, A>,
+    apps: Grant<App>,
+    device_name: &'static [u8],
+    /// Handle of the first attribute of the next table registered.
+    next_handle: Cell<u16>,
+    /// The process advertising the device.
+    advertiser: OptionalCell<ProcessId>,
+    /// A response the link layer could not send yet.
+    deferred: Cell<[u8; ATT_MTU]>,
+    deferred_len: Cell<usize>,
+    /// The characteristic whose notification is in flight.
+    notifying: OptionalCell<(ProcessId, usize)>,
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> GattServer<'a, R, A> {
+    pub fn new(
+        link: &'a LinkLayer<'a, R, A>,
+        grant: Grant<App>,
+        device_name: &'static [u8],
+    ) -> GattServer<'a, R, A> {
+        GattServer {
+            link: link,
+            apps: grant,
+            device_name: device_name,
+            next_handle: Cell::new(FIRST_APP_HANDLE),
+            advertiser: OptionalCell::empty(),
+            deferred: Cell::new([0; ATT_MTU]),
+            deferred_len: Cell::new(0),
+            notifying: OptionalCell::empty(),
+        }
+    }
+
+    /// The attributes of the kernel: the GAP service, with the device name
+    /// and appearance, and the GATT service.
+    fn builtin(&self, handle: u16) -> Option<Attribute> {
+        let kind = match handle {
+            1 => Kind::Service {
+                uuid: Uuid::from_u16(GAP_SERVICE),
+                end: 5,
+            },
+            2 => Kind::Characteristic {
+                properties: properties::READ,
+                value_handle: 3,
+                uuid: Uuid::from_u16(DEVICE_NAME),
+            },
+            3 => Kind::Constant {
+                uuid: Uuid::from_u16(DEVICE_NAME),
+                value: self.device_name,
+            },
+            4 => Kind::Characteristic {
+                properties: properties::READ,
+                value_handle: 5,
+                uuid: Uuid::from_u16(APPEARANCE),
+            },
+            5 => Kind::Constant {
+                uuid: Uuid::from_u16(APPEARANCE),
+                value: &[0, 0],
+            },
+            6 => Kind::Service {
+                uuid: Uuid::from_u16(GATT_SERVICE),
+                end: 6,
+            },
+            _ => return None,
+        };
+        Some(Attribute {
+            handle: handle,
+            kind: kind,
+        })
+    }
+
+    fn last_handle(&self) -> u16 {
+        self.next_handle.get() - 1
+    }
+
+    /// Call `fun` with the attribute with `handle`, and the process it
+    /// belongs to, unless it is an attribute of the kernel.
+    fn with_attribute<F, T>(&self, handle: u16, fun: F) -> Option<T>
+    where
+        F: FnOnce(Attribute, Option<&mut App>) -> T,
+    {
+        if let Some(attr) = self.builtin(handle) {
+            return Some(fun(attr, None));
+        }
+        let mut fun = Some(fun);
+        for cntr in self.apps.iter() {
+            let res = cntr.enter(|app| {
+                if !app.contains(handle) {
+                    return None;
+                }
+                let attr = app.find(Target::Handle(handle))?;
+                fun.take().map(|fun| fun(attr, Some(app)))
+            });
+            if res.is_some() {
+                return res;
+            }
+        }
+        None
+    }
+
+    /// Write the value of `attr` to `out`. Returns its length, or an ATT
+    /// error code.
+    fn read_value(attr: &Attribute, app: Option<&mut App>, out: &mut [u8]) -> Result<usize, u8> {
+        let mut put = |value: &[u8]| {
+            let len = cmp::min(value.len(), out.len());
+            out[..len].copy_from_slice(&value[..len]);
+            len
+        };
+        match attr.kind {
+            Kind::Service { uuid, .. } => Ok(put(uuid.as_slice())),
+            Kind::Characteristic {
+                properties,
+                value_handle,
+                uuid,
+            } => {
+                let mut value = [0; 3 + 16];
+                value[0] = properties;
+                value[1..3].copy_from_slice(&value_handle.to_le_bytes());
+                value[3..3 + uuid.as_slice().len()].copy_from_slice(uuid.as_slice());
+                Ok(put(&value[..3 + uuid.as_slice().len()]))
+            }
+            Kind::Value {
+                properties,
+                offset,
+                max_len,
+                ..
+            } => {
+                if properties & properties::READ == 0 {
+                    return Err(error::READ_NOT_PERMITTED);
+                }
+                // A missing value buffer holds empty values.
+                Ok(app.map_or(0, |app| {
+                    app.values.map_or(0, |values| {
+                        let len = values.get(offset).map_or(0, |len| *len as usize);
+                        let start = offset + 1;
+                        let end = cmp::min(start + cmp::min(len, max_len), values.len());
+                        values.get(start..end).map_or(0, |value| put(value))
+                    })
+                }))
+            }
+            Kind::Configuration { index } => {
+                let enabled = app.map_or(false, |app| app.notifications & (1 << index) != 0);
+                Ok(put(&[enabled as u8, 0]))
+            }
+            Kind::Constant { value, .. } => Ok(put(value)),
+        }
+    }
+
+    /// Handle a write of the central. Returns an ATT error code if it fails.
+    fn write(&self, handle: u16, value: &[u8], command: bool) -> Result<(), u8> {
+        self.with_attribute(handle, |attr, app| {
+            let app = match app {
+                Some(app) => app,
+                None => return Err(error::WRITE_NOT_PERMITTED),
+            };
+            match attr.kind {
+                Kind::Value {
+                    properties,
+                    index,
+                    offset,
+                    max_len,
+                    ..
+                } => {
+                    let allowed = if command {
+                        properties::WRITE_WITHOUT_RESPONSE
+                    } else {
+                        properties::WRITE
+                    };
+                    if properties & allowed == 0 {
+                        return Err(error::WRITE_NOT_PERMITTED);
+                    } else if value.len() > max_len {
+                        return Err(error::INVALID_ATTRIBUTE_VALUE_LENGTH);
+                    }
+                    app.values
+                        .mut_map_or(Err(error::WRITE_NOT_PERMITTED), |values| {
+                            let slot = values
+                                .get_mut(offset..offset + 1 + max_len)
+                                .ok_or(error::WRITE_NOT_PERMITTED)?;
+                            slot[0] = value.len() as u8;
+                            slot[1..1 + value.len()].copy_from_slice(value);
+                            Ok(())
+                        })?;
+                    app.event_callback.schedule(EVENT_WRITE, index, value.len());
+                    Ok(())
+                }
+                Kind::Configuration { index } => {
+                    if value.len() != 2 {
+                        return Err(error::INVALID_ATTRIBUTE_VALUE_LENGTH);
+                    }
+                    let enabled =
+                        u16::from_le_bytes([value[0], value[1]]) & att::CCC_NOTIFICATIONS != 0;
+                    if enabled {
+                        app.notifications |= 1 << index;
+                    } else {
+                        app.notifications &= !(1 << index);
+                    }
+                    app.event_callback
+                        .schedule(EVENT_NOTIFICATIONS, index, enabled as usize);
+                    Ok(())
+                }
+                _ => Err(error::WRITE_NOT_PERMITTED),
+            }
+        })
+        .unwrap_or(Err(error::INVALID_HANDLE))
+    }
+
+    /// Handle a request of the central, writing the response to `rsp`.
+    /// Returns the length of the response, if there is one.
+    fn handle_request(&self, req: &[u8], rsp: &mut [u8; ATT_MTU]) -> Option<usize> {
+        let op = *req.get(0)?;
+        let res = match op {
+            opcode::EXCHANGE_MTU_REQ if req.len() == 3 => {
+                rsp[0] = opcode::EXCHANGE_MTU_RSP;
+                rsp[1..3].copy_from_slice(&(ATT_MTU as u16).to_le_bytes());
+                Ok(3)
+            }
+            opcode::FIND_INFORMATION_REQ if req.len() == 5 => {
+                self.find_information(read_u16(req, 1), read_u16(req, 3), rsp)
+            }
+            opcode::FIND_BY_TYPE_VALUE_REQ if req.len() >= 7 => self.find_by_type_value(
+                read_u16(req, 1),
+                read_u16(req, 3),
+                read_u16(req, 5),
+                &req[7..],
+                rsp,
+            ),
+            opcode::READ_BY_TYPE_REQ | opcode::READ_BY_GROUP_TYPE_REQ => {
+                match Uuid::from_slice(req.get(5..).unwrap_or(&[])) {
+                    Some(uuid) => {
+                        let (start, end) = (read_u16(req, 1), read_u16(req, 3));
+                        if op == opcode::READ_BY_TYPE_REQ {
+                            self.read_by_type(start, end, uuid, rsp)
+                        } else {
+                            self.read_by_group_type(start, end, uuid, rsp)
+                        }
+                    }
+                    _ => Err((0, error::INVALID_PDU)),
+                }
+            }
+            opcode::READ_REQ | opcode::READ_BLOB_REQ
+                if req.len() == 3 || (op == opcode::READ_BLOB_REQ && req.len() == 5) =>
+            {
+                let handle = read_u16(req, 1);
+                let offset = if op == opcode::READ_BLOB_REQ {
+                    read_u16(req, 3) as usize
+                } else {
+                    0
+                };
+                self.read(handle, offset, rsp)
+                    .map_err(|code| (handle, code))
+            }
+            opcode::WRITE_REQ | opcode::WRITE_CMD if req.len() >= 3 => {
+                let handle = read_u16(req, 1);
+                let res = self.write(handle, &req[3..], op == opcode::WRITE_CMD);
+                if op == opcode::WRITE_CMD {
+                    return None;
+                }
+                res.map(|()| {
+                    rsp[0] = opcode::WRITE_RSP;
+                    1
+                })
+                .map_err(|code| (handle, code))
+            }
+            _ if op & opcode::COMMAND_FLAG != 0 => return None,
+            opcode::EXCHANGE_MTU_REQ
+            | opcode::FIND_INFORMATION_REQ
+            | opcode::FIND_BY_TYPE_VALUE_REQ
+            | opcode::READ_REQ
+            | opcode::READ_BLOB_REQ
+            | opcode::WRITE_REQ => Err((0, error::INVALID_PDU)),
+            _ => Err((0, error::REQUEST_NOT_SUPPORTED)),
+        };
+        Some(res.unwrap_or_else(|(handle, code)| {
+            rsp[0] = opcode::ERROR_RSP;
+            rsp[1] = op;
+            rsp[2..4].copy_from_slice(&handle.to_le_bytes());
+            rsp[4] = code;
+            5
+        }))
+    }
+
+    /// Check the handle range of a request, and clamp it to the handles in
+    /// use.
+    fn range(&self, start: u16, end: u16) -> Result<(u16, u16), (u16, u8)> {
+        if start == 0 || start > end {
+            Err((start, error::INVALID_HANDLE))
+        } else {
+            Ok((start, cmp::min(end, self.last_handle())))
+        }
+    }
+
+    fn find_information(
+        &self,
+        start: u16,
+        end: u16,
+        rsp: &mut [u8; ATT_MTU],
+    ) -> Result<usize, (u16, u8)> {
+        let (start, end) = self.range(start, end)?;
+        rsp[0] = opcode::FIND_INFORMATION_RSP;
+        let mut pos = 2;
+        for handle in start..=end {
+            let uuid = match self.with_attribute(handle, |attr, _| attr.uuid()) {
+                Some(uuid) => uuid,
+                None => continue,
+            };
+            // All UUIDs of a response have the same format.
+            let format = if uuid.is_u16() { 0x01 } else { 0x02 };
+            if pos == 2 {
+                rsp[1] = format;
+            } else if rsp[1] != format {
+                break;
+            }
+            let entry_len = 2 + uuid.as_slice().len();
+            if pos + entry_len > ATT_MTU {
+                break;
+            }
+            rsp[pos..pos + 2].copy_from_slice(&handle.to_le_bytes());
+            rsp[pos + 2..pos + entry_len].copy_from_slice(uuid.as_slice());
+            pos += entry_len;
+        }
+        if pos == 2 {
+            Err((start, error::ATTRIBUTE_NOT_FOUND))
+        } else {
+            Ok(pos)
+        }
+    }
+
+    /// Only the discovery of primary services by UUID is supported.
+    fn find_by_type_value(
+        &self,
+        start: u16,
+        end: u16,
+        attr_type: u16,
+        value: &[u8],
+        rsp: &mut [u8; ATT_MTU],
+    ) -> Result<usize, (u16, u8)> {
+        let (start, end) = self.range(start, end)?;
+        rsp[0] = opcode::FIND_BY_TYPE_VALUE_RSP;
+        let mut pos = 1;
+        if attr_type == att::PRIMARY_SERVICE {
+            for handle in start..=end {
+                if pos + 4 > ATT_MTU {
+                    break;
+                }
+                let found = self.with_attribute(handle, |attr, _| match attr.kind {
+                    Kind::Service { uuid, end } if uuid.as_slice() == value => Some(end),
+                    _ => None,
+                });
+                if let Some(Some(group_end)) = found {
+                    rsp[pos..pos + 2].copy_from_slice(&handle.to_le_bytes());
+                    rsp[pos + 2..pos + 4].copy_from_slice(&group_end.to_le_bytes());
+                    pos += 4;
+                }
+            }
+        }
+        if pos == 1 {
+            Err((start, error::ATTRIBUTE_NOT_FOUND))
+        } else {
+            Ok(pos)
+        }
+    }
+
+    fn read_by_type(
+        &self,
+        start: u16,
+        end: u16,
+        attr_type: Uuid,
+        rsp: &mut [u8; ATT_MTU],
+    ) -> Result<usize, (u16, u8)> {
+        let (start, end) = self.range(start, end)?;
+        rsp[0] = opcode::READ_BY_TYPE_RSP;
+        let mut pos = 2;
+        for handle in start..=end {
+            let mut value = [0; ATT_MTU - 4];
+            let res = self.with_attribute(handle, |attr, app| {
+                if attr.uuid() == attr_type {
+                    Some(Self::read_value(&attr, app, &mut value))
+                } else {
+                    None
+                }
+            });
+            let len = match res {
+                Some(Some(Ok(len))) => len,
+                // The first attribute of the type must be readable.
+                Some(Some(Err(code))) if pos == 2 => return Err((handle, code)),
+                Some(Some(Err(_))) => break,
+                _ => continue,
+            };
+            // All values of a response have the same length.
+            if pos == 2 {
+                rsp[1] = 2 + len as u8;
+            } else if rsp[1] as usize != 2 + len || pos + 2 + len > ATT_MTU {
+                break;
+            }
+            rsp[pos..pos + 2].copy_from_slice(&handle.to_le_bytes());
+            rsp[pos + 2..pos + 2 + len].copy_from_slice(&value[..len]);
+            pos += 2 + len;
+        }
+        if pos == 2 {
+            Err((start, error::ATTRIBUTE_NOT_FOUND))
+        } else {
+            Ok(pos)
+        }
+    }
+
+    /// Only primary services are groups.
+    fn read_by_group_type(
+        &self,
+        start: u16,
+        end: u16,
+        group_type: Uuid,
+        rsp: &mut [u8; ATT_MTU],
+    ) -> Result<usize, (u16, u8)> {
+        let (start, end) = self.range(start, end)?;
+        if group_type != Uuid::from_u16(att::PRIMARY_SERVICE) {
+            return Err((start, error::UNSUPPORTED_GROUP_TYPE));
+        }
+        rsp[0] = opcode::READ_BY_GROUP_TYPE_RSP;
+        let mut pos = 2;
+        for handle in start..=end {
+            let found = self.with_attribute(handle, |attr, _| match attr.kind {
+                Kind::Service { uuid, end } => Some((uuid, end)),
+                _ => None,
+            });
+            let (uuid, group_end) = match found {
+                Some(Some(service)) => service,
+                _ => continue,
+            };
+            let entry_len = 4 + uuid.as_slice().len();
+            if pos == 2 {
+                rsp[1] = entry_len as u8;
+            } else if rsp[1] as usize != entry_len || pos + entry_len > ATT_MTU {
+                break;
+            }
+            rsp[pos..pos + 2].copy_from_slice(&handle.to_le_bytes());
+            rsp[pos + 2..pos + 4].copy_from_slice(&group_end.to_le_bytes());
+            rsp[pos + 4..pos + entry_len].copy_from_slice(uuid.as_slice());
+            pos += entry_len;
+        }
+        if pos == 2 {
+            Err((start, error::ATTRIBUTE_NOT_FOUND))
+        } else {
+            Ok(pos)
+        }
+    }
+
+    /// Read the value of an attribute from `offset`.
+    fn read(&self, handle: u16, offset: usize, rsp: &mut [u8; ATT_MTU]) -> Result<usize, u8> {
+        let mut value = [0; MAX_VALUE_LEN];
+        let len = self
+            .with_attribute(handle, |attr, app| Self::read_value(&attr, app, &mut value))
+            .unwrap_or(Err(error::INVALID_HANDLE))?;
+        if offset > len {
+            return Err(error::INVALID_OFFSET);
+        }
+        rsp[0] = if offset == 0 {
+            opcode::READ_RSP
+        } else {
+            opcode::READ_BLOB_RSP
+        };
+        let count = cmp::min(len - offset, ATT_MTU - 1);
+        rsp[1..1 + count].copy_from_slice(&value[offset..offset + count]);
+        Ok(1 + count)
+    }
+
+    /// Send a PDU, or keep it until the link layer can take it if it is a
+    /// response.
+    fn send(&self, pdu: &[u8]) {
+        if let Err(ErrorCode::BUSY) = self.link.send(ATT_CID, pdu) {
+            let mut deferred = [0; ATT_MTU];
+            deferred[..pdu.len()].copy_from_slice(pdu);
+            self.deferred.set(deferred);
+            self.deferred_len.set(pdu.len());
+        }
+    }
+
+    fn register(&self, app: &mut App) -> Result<(), ErrorCode> {
+        if app.attr_count != 0 {
+            return Err(ErrorCode::ALREADY);
+        } else if self.link.is_connected() {
+            // The central may have cached the handles.
+            return Err(ErrorCode::BUSY);
+        }
+        let count = app
+            .table
+            .map_or(Err(ErrorCode::INVAL), |table| att::validate(table))?;
+        let next = self
+            .next_handle
+            .get()
+            .checked_add(count)
+            .ok_or(ErrorCode::NOMEM)?;
+        app.base_handle = self.next_handle.get();
+        app.attr_count = count;
+        self.next_handle.set(next);
+        Ok(())
+    }
+
+    fn notify(&self, app: &mut App, index: usize) -> Result<(), ErrorCode> {
+        let attr = app.find(Target::Value(index)).ok_or(ErrorCode::INVAL)?;
+        if index >= att::MAX_CHARACTERISTICS || app.notifications & (1 << index) == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut pdu = [0; ATT_MTU];
+        pdu[0] = opcode::HANDLE_VALUE_NTF;
+        pdu[1..3].copy_from_slice(&attr.handle.to_le_bytes());
+        let mut value = [0; MAX_VALUE_LEN];
+        let len = Self::read_value(&attr, Some(app), &mut value).unwrap_or(0);
+        // Notifications carry the start of long values.
+        let len = cmp::min(len, ATT_MTU - 3);
+        pdu[3..3 + len].copy_from_slice(&value[..len]);
+        self.link.send(ATT_CID, &pdu[..3 + len])
+    }
+
+    /// Clear the client configurations of all processes, and tell them the
+    /// connection state changed.
+    fn connection_changed(&self, connected: bool, reason: u8) {
+        for cntr in self.apps.iter() {
+            cntr.enter(|app| {
+                app.notifications = 0;
+                app.connection_callback
+                    .schedule(connected as usize, reason as usize, 0);
+            });
+        }
+    }
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> Driver for GattServer<'a, R, A> {
+    /// ### `allow_num`
+    ///
+    /// - `0`: Value buffer. For each characteristic in the table, its
+    ///        current length followed by room for its maximum length.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.values, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(slice),
+            Ok(Err(e)) => Err((slice, e)),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// ### `allow_num`
+    ///
+    /// - `0`: Attribute table. It must stay shared once registered.
+    /// - `1`: Advertising data.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    if app.attr_count != 0 {
+                        return Err(ErrorCode::BUSY);
+                    }
+                    mem::swap(&mut app.table, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.adv_data, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(slice),
+            Ok(Err(e)) => Err((slice, e)),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Subscribe to GATT events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: GATT events. Upcall arguments: (event, characteristic, value).
+    ///        Events are `0` for a write of the central, whose length is the
+    ///        value, `1` when the central enables (value `1`) or disables
+    ///        (value `0`) notifications, and `2` when a notification was
+    ///        sent.
+    /// - `1`: Connection events. Upcall arguments: (connected, reason). The
+    ///        reason a connection closed is an HCI error code.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.event_callback, &mut callback);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.connection_callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(callback),
+            Ok(Err(e)) => Err((callback, e)),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// GATT server control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Register the attribute table. Returns the handle of its first
+    ///        attribute. Tables cannot be registered while connected.
+    /// - `2`: Advertise the advertising data every `data1` milliseconds.
+    /// - `3`: Stop advertising.
+    /// - `4`: Notify the central of the value of characteristic `data1`.
+    ///        Returns `INVAL` if the central did not enable notifications,
+    ///        and `BUSY` until the previous PDU is acknowledged.
+    /// - `5`: Close the connection.
+    /// - `6`: Whether a central is connected.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // register table
+            1 => self
+                .apps
+                .enter(appid, |app| {
+                    self.register(app).map(|()| app.base_handle as u32)
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+                .map_or_else(CommandReturn::failure, CommandReturn::success_u32),
+
+            // start advertising
+            2 => {
+                if self
+                    .advertiser
+                    .map_or(false, |advertiser| *advertiser != appid)
+                {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                let res = self
+                    .apps
+                    .enter(appid, |app| {
+                        app.adv_data.map_or(Err(ErrorCode::INVAL), |data| {
+                            self.link.start_advertising(data, data1 as u32)
+                        })
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                if res.is_ok() {
+                    self.advertiser.set(appid);
+                }
+                res.into()
+            }
+
+            // stop advertising
+            3 => {
+                if !self.advertiser.contains(&appid) {
+                    return CommandReturn::failure(ErrorCode::ALREADY);
+                }
+                self.advertiser.clear();
+                self.link.stop_advertising();
+                CommandReturn::success()
+            }
+
+            // notify
+            4 => {
+                if !self.link.is_connected() {
+                    return CommandReturn::failure(ErrorCode::OFF);
+                } else if self.notifying.is_some() || self.deferred_len.get() != 0 {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                let res = self
+                    .apps
+                    .enter(appid, |app| self.notify(app, data1))
+                    .unwrap_or_else(|err| Err(err.into()));
+                if res.is_ok() {
+                    self.notifying.set((appid, data1));
+                }
+                res.into()
+            }
+
+            // disconnect
+            5 => self.link.disconnect().into(),
+
+            // connection status
+            6 => CommandReturn::success_u32(self.link.is_connected() as u32),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> L2capClient for GattServer<'a, R, A> {
+    fn connected(&self) {
+        self.connection_changed(true, 0);
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.deferred_len.set(0);
+        self.notifying.clear();
+        self.connection_changed(false, reason);
+    }
+
+    fn receive(&self, cid: u16, payload: &[u8]) {
+        if cid != ATT_CID {
+            return;
+        }
+        let mut rsp = [0; ATT_MTU];
+        if let Some(len) = self.handle_request(payload, &mut rsp) {
+            self.send(&rsp[..len]);
+        }
+    }
+
+    fn send_done(&self) {
+        if let Some((appid, index)) = self.notifying.extract() {
+            let _ = self.apps.enter(appid, |app| {
+                app.event_callback.schedule(EVENT_NOTIFY_DONE, index, 0);
+            });
+        }
+        let len = self.deferred_len.get();
+        if len != 0 {
+            self.deferred_len.set(0);
+            self.send(&self.deferred.get()[..len]);
+        }
+    }
+}
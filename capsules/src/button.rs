@@ -38,6 +38,9 @@
 //! - `2`: Disable interrupts for a button. No affect or reliance on
 //!   registered callback.
 //! - `3`: Read the current state of the button.
+//! - `4`: Inject a synthetic press (`1`) or release (`0`) event for a
+//!   button. Only available to the process the board allows to inject
+//!   events.
 //!
 //! ### Subscribe
 //!
@@ -49,11 +52,31 @@
 //!   no reliance on individual pins being configured as interrupts. The
 //!   interrupt will be called with two parameters: the index of the button
 //!   that triggered the interrupt and the pressed (1) or not pressed (0) state
-//!   of the button.
+//!   of the button. The third parameter is 0, unless the board enabled
+//!   flagging of synthetic events, in which case it is 1 for injected events.
+//!
+//! Synthetic Events
+//! ----------------
+//!
+//! To support automated UI testing, a board can allow one process (identified
+//! by name) to inject button events with `command` 4:
+//!
+//! ```rust
+//! let kernel_info = static_init!(kernel::introspection::KernelInfo,
+//!     kernel::introspection::KernelInfo::new(board_kernel));
+//! button.allow_injection(kernel_info, "ui_test", &PROCESS_MGMT_CAP, false);
+//! ```
+//!
+//! Injected events follow the same path as hardware interrupts, so only
+//! processes that enabled interrupts for the button receive them. Injecting
+//! an event never changes whether the button's pin interrupts.
 
 use core::cell::Cell;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::OptionalCell;
 use kernel::hil::gpio;
 use kernel::hil::gpio::{Configure, Input, InterruptWithValue};
+use kernel::introspection::KernelInfo;
 use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
 
 /// Syscall driver number.
@@ -65,6 +88,16 @@ pub const DRIVER_NUM: usize = driver::NUM::Button as usize;
 /// that app has an interrupt registered for that button.
 pub type SubscribeMap = u32;
 
+/// The process allowed to inject synthetic button events.
+#[derive(Copy, Clone)]
+struct Injector<'a> {
+    kernel_info: &'a KernelInfo,
+    process_name: &'static str,
+    capability: &'a dyn ProcessManagementCapability,
+    /// Mark injected events in the third upcall argument.
+    flag_synthetic: bool,
+}
+
 /// Manages the list of GPIO pins that are connected to buttons and which apps
 /// are listening for interrupts from which buttons.
 pub struct Button<'a, P: gpio::InterruptPin<'a>> {
@@ -74,6 +107,7 @@ pub struct Button<'a, P: gpio::InterruptPin<'a>> {
         gpio::FloatingState,
     )],
     apps: Grant<(Upcall, SubscribeMap)>,
+    injector: OptionalCell<Injector<'a>>,
 }
 
 impl<'a, P: gpio::InterruptPin<'a>> Button<'a, P> {
@@ -94,6 +128,47 @@ impl<'a, P: gpio::InterruptPin<'a>> Button<'a, P> {
         Self {
             pins: pins,
             apps: grant,
+            injector: OptionalCell::empty(),
+        }
+    }
+
+    /// Allow the process named `process_name` to inject synthetic button
+    /// events. If `flag_synthetic` is set, injected events are reported with
+    /// a third upcall argument of 1 instead of 0.
+    pub fn allow_injection(
+        &self,
+        kernel_info: &'a KernelInfo,
+        process_name: &'static str,
+        capability: &'a dyn ProcessManagementCapability,
+        flag_synthetic: bool,
+    ) {
+        self.injector.set(Injector {
+            kernel_info: kernel_info,
+            process_name: process_name,
+            capability: capability,
+            flag_synthetic: flag_synthetic,
+        });
+    }
+
+    /// Deliver a button event to every process listening for that button.
+    fn notify(&self, pin_num: u32, button_state: usize, synthetic: bool) {
+        let interrupt_count = Cell::new(0);
+
+        // schedule callback with the pin number and value
+        self.apps.each(|_, cntr| {
+            if cntr.1 & (1 << pin_num) != 0 {
+                interrupt_count.set(interrupt_count.get() + 1);
+                cntr.0
+                    .schedule(pin_num as usize, button_state, synthetic as usize);
+            }
+        });
+
+        // It's possible we got an interrupt for a process that has since died
+        // (and didn't unregister the interrupt). Lazily disable interrupts for
+        // this button if so. A synthetic event says nothing about the pin, so
+        // it leaves the interrupt alone.
+        if interrupt_count.get() == 0 && !synthetic {
+            self.pins[pin_num as usize].0.disable_interrupts();
         }
     }
 
@@ -153,11 +228,14 @@ impl<'a, P: gpio::InterruptPin<'a>> Driver for Button<'a, P> {
     /// - `2`: Disable interrupts for a button. No affect or reliance on
     ///   registered callback.
     /// - `3`: Read the current state of the button.
+    /// - `4`: Inject a synthetic event for a button. `data2` is 1 for a press
+    ///   and 0 for a release. Returns `NOSUPPORT` if the board does not allow
+    ///   injection and `RESERVE` if the caller is not the allowed process.
     fn command(
         &self,
         command_num: usize,
         data: usize,
-        _: usize,
+        data2: usize,
         appid: ProcessId,
     ) -> CommandReturn {
         let pins = self.pins;
@@ -222,6 +300,26 @@ impl<'a, P: gpio::InterruptPin<'a>> Driver for Button<'a, P> {
                 }
             }
 
+            // inject a synthetic event
+            4 => {
+                if data >= pins.len() || data2 > 1 {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                match self.injector.extract() {
+                    None => CommandReturn::failure(ErrorCode::NOSUPPORT),
+                    Some(injector) => {
+                        let caller = injector
+                            .kernel_info
+                            .process_name(appid, injector.capability);
+                        if caller != injector.process_name {
+                            return CommandReturn::failure(ErrorCode::RESERVE);
+                        }
+                        self.notify(data as u32, data2, injector.flag_synthetic);
+                        CommandReturn::success()
+                    }
+                }
+            }
+
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
@@ -232,21 +330,226 @@ impl<'a, P: gpio::InterruptPin<'a>> gpio::ClientWithValue for Button<'a, P> {
     fn fired(&self, pin_num: u32) {
         // Read the value of the pin and get the button state.
         let button_state = self.get_button_state(pin_num);
-        let interrupt_count = Cell::new(0);
+        self.notify(pin_num, button_state as usize, false);
+    }
+}
 
-        // schedule callback with the pin number and value
-        self.apps.each(|_, cntr| {
-            if cntr.1 & (1 << pin_num) != 0 {
-                interrupt_count.set(interrupt_count.get() + 1);
-                cntr.0.schedule(pin_num as usize, button_state as usize, 0);
-            }
-        });
+#[cfg(test)]
+mod test {
+    use super::{Button, DRIVER_NUM};
+    use crate::test::mock_process::{self, Capability, MockProcess};
+    use core::cell::Cell;
+    use kernel::hil::gpio::{
+        self, ActivationMode, ClientWithValue, Configuration, FloatingState, InterruptEdge,
+        InterruptValueWrapper,
+    };
+    use kernel::introspection::KernelInfo;
+    use kernel::{Driver, ErrorCode, Kernel};
 
-        // It's possible we got an interrupt for a process that has since died
-        // (and didn't unregister the interrupt). Lazily disable interrupts for
-        // this button if so.
-        if interrupt_count.get() == 0 {
-            self.pins[pin_num as usize].0.disable_interrupts();
+    #[derive(Default)]
+    struct MockPin {
+        pressed: Cell<bool>,
+        interrupts: Cell<bool>,
+    }
+
+    impl gpio::Configure for MockPin {
+        fn configuration(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn make_output(&self) -> Configuration {
+            Configuration::Input
         }
+
+        fn disable_output(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn make_input(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn disable_input(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn deactivate_to_low_power(&self) {}
+
+        fn set_floating_state(&self, _state: FloatingState) {}
+
+        fn floating_state(&self) -> FloatingState {
+            FloatingState::PullNone
+        }
+    }
+
+    impl gpio::Output for MockPin {
+        fn set(&self) {}
+
+        fn clear(&self) {}
+
+        fn toggle(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Input for MockPin {
+        fn read(&self) -> bool {
+            self.pressed.get()
+        }
+    }
+
+    impl<'a> gpio::Interrupt<'a> for MockPin {
+        fn set_client(&self, _client: &'a dyn gpio::Client) {}
+
+        fn enable_interrupts(&self, _mode: InterruptEdge) {
+            self.interrupts.set(true);
+        }
+
+        fn disable_interrupts(&self) {
+            self.interrupts.set(false);
+        }
+
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Pin for MockPin {}
+    impl<'a> gpio::InterruptPin<'a> for MockPin {}
+
+    type Pins<'a> = [(
+        &'a InterruptValueWrapper<'a, MockPin>,
+        ActivationMode,
+        FloatingState,
+    ); 2];
+
+    fn pins<'a>(wrappers: &'a [InterruptValueWrapper<'a, MockPin>; 2]) -> Pins<'a> {
+        [
+            (
+                &wrappers[0],
+                ActivationMode::ActiveHigh,
+                FloatingState::PullNone,
+            ),
+            (
+                &wrappers[1],
+                ActivationMode::ActiveHigh,
+                FloatingState::PullNone,
+            ),
+        ]
+    }
+
+    /// Let the process named "ui_test" inject events.
+    fn allow_injection(button: &Button<MockPin>, kernel: &'static Kernel, flag_synthetic: bool) {
+        let kernel_info = mock_process::leak(KernelInfo::new(kernel));
+        button.allow_injection(kernel_info, "ui_test", &Capability, flag_synthetic);
+    }
+
+    fn listen(button: &Button<MockPin>, app: &MockProcess, button_num: usize) {
+        let id = app.processid();
+        assert!(button.subscribe(0, app.upcall(DRIVER_NUM, 0), id).is_ok());
+        assert!(button.command(1, button_num, 0, id).is_success());
+    }
+
+    #[test]
+    fn test_only_designated_process_injects() {
+        let (kernel, processes) = mock_process::kernel(&["ui_test", "other"]);
+        let (injector, other) = (processes[0].processid(), processes[1].processid());
+        let mock_pins = [MockPin::default(), MockPin::default()];
+        let wrappers = [
+            InterruptValueWrapper::new(&mock_pins[0]),
+            InterruptValueWrapper::new(&mock_pins[1]),
+        ];
+        let pins = pins(&wrappers);
+        let button = Button::new(&pins, mock_process::grant(kernel));
+
+        assert_eq!(
+            button.command(4, 0, 1, injector).get_failure(),
+            Some(ErrorCode::NOSUPPORT)
+        );
+        allow_injection(&button, kernel, true);
+        assert_eq!(
+            button.command(4, 0, 1, other).get_failure(),
+            Some(ErrorCode::RESERVE)
+        );
+        assert!(button.command(4, 0, 1, injector).is_success());
+        assert_eq!(
+            button.command(4, 2, 1, injector).get_failure(),
+            Some(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            button.command(4, 0, 2, injector).get_failure(),
+            Some(ErrorCode::INVAL)
+        );
+    }
+
+    #[test]
+    fn test_injected_event_reaches_listeners() {
+        let (kernel, processes) = mock_process::kernel(&["ui_test", "listener", "other"]);
+        let (injector, listener, other) = (processes[0], processes[1], processes[2]);
+        let mock_pins = [MockPin::default(), MockPin::default()];
+        let wrappers = [
+            InterruptValueWrapper::new(&mock_pins[0]),
+            InterruptValueWrapper::new(&mock_pins[1]),
+        ];
+        let pins = pins(&wrappers);
+        let button = Button::new(&pins, mock_process::grant(kernel));
+        allow_injection(&button, kernel, true);
+        listen(&button, listener, 1);
+        listen(&button, other, 0);
+
+        assert!(button.command(4, 1, 1, injector.processid()).is_success());
+        assert!(button.command(4, 1, 0, injector.processid()).is_success());
+        assert_eq!(listener.take_upcalls(), [(0, 1, 1, 1), (0, 1, 0, 1)]);
+        assert_eq!(other.take_upcalls(), []);
+
+        // A real press is not flagged.
+        mock_pins[1].pressed.set(true);
+        button.fired(1);
+        assert_eq!(listener.take_upcalls(), [(0, 1, 1, 0)]);
+    }
+
+    #[test]
+    fn test_injected_event_unflagged() {
+        let (kernel, processes) = mock_process::kernel(&["ui_test", "listener"]);
+        let (injector, listener) = (processes[0], processes[1]);
+        let mock_pins = [MockPin::default(), MockPin::default()];
+        let wrappers = [
+            InterruptValueWrapper::new(&mock_pins[0]),
+            InterruptValueWrapper::new(&mock_pins[1]),
+        ];
+        let pins = pins(&wrappers);
+        let button = Button::new(&pins, mock_process::grant(kernel));
+        allow_injection(&button, kernel, false);
+        listen(&button, listener, 0);
+
+        assert!(button.command(4, 0, 1, injector.processid()).is_success());
+        assert_eq!(listener.take_upcalls(), [(0, 0, 1, 0)]);
+    }
+
+    #[test]
+    fn test_injection_keeps_pin_interrupts() {
+        let (kernel, processes) = mock_process::kernel(&["ui_test", "listener"]);
+        let (injector, listener) = (processes[0], processes[1]);
+        let mock_pins = [MockPin::default(), MockPin::default()];
+        let wrappers = [
+            InterruptValueWrapper::new(&mock_pins[0]),
+            InterruptValueWrapper::new(&mock_pins[1]),
+        ];
+        let pins = pins(&wrappers);
+        let button = Button::new(&pins, mock_process::grant(kernel));
+        allow_injection(&button, kernel, true);
+        listen(&button, listener, 0);
+        assert!(mock_pins[0].interrupts.get());
+
+        // The listener goes away without disabling the interrupt. An event
+        // injected for the button nobody listens to now must not touch the
+        // pin.
+        listener.restart();
+        assert!(button.command(4, 0, 1, injector.processid()).is_success());
+        assert!(mock_pins[0].interrupts.get());
+
+        // A real interrupt still disables it lazily.
+        button.fired(0);
+        assert!(!mock_pins[0].interrupts.get());
     }
 }
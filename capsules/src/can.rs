@@ -0,0 +1,386 @@
+//! Provides userspace with access to a CAN bus.
+//!
+//! Several applications can use the bus at the same time. Frames sent by
+//! applications are queued and sent one at a time. Each application registers
+//! its own acceptance filters, and receives every frame matching one of them,
+//! independently of the other applications. The controller is configured to
+//! accept all frames, and the filters of the applications are applied in
+//! software.
+//!
+//! Identifiers are passed as 32-bit values, with bit 31 set for an extended
+//! (29-bit) identifier and clear for a standard (11-bit) identifier.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let can_buffer = static_init!([u8; 8], [0; 8]);
+//! let can = static_init!(
+//!     capsules::can::CanDriver<'static, CanController>,
+//!     capsules::can::CanDriver::new(
+//!         can_controller,
+//!         can_buffer,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! can_controller.set_controller_client(can);
+//! can_controller.set_transmit_client(can);
+//! can_controller.set_receive_client(can);
+//! can.enable(500_000);
+//! ```
+
+use core::cmp;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::can;
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Can as usize;
+
+/// Number of acceptance filters each application can register.
+pub const MAX_FILTERS: usize = 4;
+
+/// Bit marking an extended identifier in identifiers passed to and from
+/// userspace.
+const EXTENDED_FLAG: usize = 1 << 31;
+
+#[derive(Default)]
+pub struct App {
+    tx_callback: Upcall,
+    rx_callback: Upcall,
+    bus_off_callback: Upcall,
+    tx_data: ReadOnlyAppSlice,
+    rx_data: ReadWriteAppSlice,
+    // A frame waiting for the controller to finish sending another one.
+    pending_tx: Option<(can::Id, usize)>,
+    filters: [Option<can::Filter>; MAX_FILTERS],
+}
+
+pub struct CanDriver<'a, C: can::Can<'a>> {
+    can: &'a C,
+    apps: Grant<App>,
+    tx_in_progress: OptionalCell<ProcessId>,
+    tx_buffer: TakeCell<'static, [u8]>,
+}
+
+/// Decode an identifier passed by userspace.
+fn decode_id(id: usize) -> Option<can::Id> {
+    if id & EXTENDED_FLAG != 0 {
+        can::Id::extended((id & !EXTENDED_FLAG) as u32)
+    } else {
+        can::Id::standard(id as u32)
+    }
+}
+
+/// Encode an identifier to pass to userspace.
+fn encode_id(id: can::Id) -> usize {
+    if id.is_extended() {
+        id.value() as usize | EXTENDED_FLAG
+    } else {
+        id.value() as usize
+    }
+}
+
+impl<'a, C: can::Can<'a>> CanDriver<'a, C> {
+    pub fn new(can: &'a C, tx_buffer: &'static mut [u8], grant: Grant<App>) -> CanDriver<'a, C> {
+        CanDriver {
+            can: can,
+            apps: grant,
+            tx_in_progress: OptionalCell::empty(),
+            tx_buffer: TakeCell::new(tx_buffer),
+        }
+    }
+
+    /// Accept all frames and join the bus at `bitrate` bits per second.
+    pub fn enable(&self, bitrate: u32) -> Result<(), ErrorCode> {
+        for index in 0..self.can.filter_count() {
+            self.can.set_filter(index, None)?;
+        }
+        let all_standard = can::Filter {
+            id: can::Id::Standard(0),
+            mask: 0,
+        };
+        let all_extended = can::Filter {
+            id: can::Id::Extended(0),
+            mask: 0,
+        };
+        self.can.set_filter(0, Some(all_standard))?;
+        // Controllers with a single filter may not receive extended frames.
+        if self.can.filter_count() > 1 {
+            self.can.set_filter(1, Some(all_extended))?;
+        }
+        self.can.enable(bitrate)
+    }
+
+    /// Copy the frame of `app` into the transmit buffer and send it.
+    fn send(
+        &self,
+        appid: ProcessId,
+        app: &mut App,
+        id: can::Id,
+        len: usize,
+    ) -> Result<(), ErrorCode> {
+        self.tx_buffer
+            .take()
+            .map_or(Err(ErrorCode::BUSY), |buffer| {
+                app.tx_data.map_or((), |data| {
+                    buffer[..len].copy_from_slice(&data[..len]);
+                });
+                match self.can.send(id, buffer, len) {
+                    Ok(()) => {
+                        self.tx_in_progress.set(appid);
+                        Ok(())
+                    }
+                    Err((e, buffer)) => {
+                        self.tx_buffer.replace(buffer);
+                        Err(e)
+                    }
+                }
+            })
+    }
+
+    /// Send the next queued frame, if any.
+    fn send_pending(&self) {
+        for cntr in self.apps.iter() {
+            let appid = cntr.processid();
+            let started_tx = cntr.enter(|app| {
+                app.pending_tx.take().map_or(false, |(id, len)| {
+                    // The application may have replaced its buffer since.
+                    let len = cmp::min(len, app.tx_data.len());
+                    match self.send(appid, app, id, len) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            app.tx_callback
+                                .schedule(kernel::into_statuscode(Err(e)), 0, 0);
+                            false
+                        }
+                    }
+                })
+            });
+            if started_tx {
+                break;
+            }
+        }
+    }
+}
+
+impl<'a, C: can::Can<'a>> Driver for CanDriver<'a, C> {
+    /// Setup buffers to send and receive frames.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer the data of received frames is written to.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.rx_data, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer with the data of the frame to send.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.tx_data, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Subscribe to CAN events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: A frame was sent. Upcall arguments: (status, 0, 0).
+    /// - `1`: A frame matching one of the filters was received. Upcall
+    ///        arguments: (identifier, data length, 0).
+    /// - `2`: The controller left the bus after too many errors.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.tx_callback, &mut callback);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.rx_callback, &mut callback);
+                    Ok(())
+                }
+                2 => {
+                    mem::swap(&mut app.bus_off_callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(callback),
+            Ok(Err(e)) => Err((callback, e)),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// Send frames and manage acceptance filters.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Send a frame with identifier `data1`, and the first `data2`
+    ///        bytes of the allowed buffer as data.
+    /// - `2`: Add an acceptance filter matching identifiers equal to `data1`
+    ///        in all bits set in `data2`. Returns the index of the filter.
+    /// - `3`: Remove the acceptance filter with index `data1`.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // send
+            1 => {
+                let id = match decode_id(data1) {
+                    Some(id) => id,
+                    None => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                let len = data2;
+                self.apps
+                    .enter(appid, |app| {
+                        if len > can::MAX_DATA_LEN || len > app.tx_data.len() {
+                            return CommandReturn::failure(ErrorCode::SIZE);
+                        }
+                        if app.pending_tx.is_some() || self.tx_in_progress.contains(&appid) {
+                            return CommandReturn::failure(ErrorCode::BUSY);
+                        }
+                        if self.tx_in_progress.is_none() {
+                            self.send(appid, app, id, len).into()
+                        } else {
+                            app.pending_tx = Some((id, len));
+                            CommandReturn::success()
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            // add filter
+            2 => {
+                let id = match decode_id(data1) {
+                    Some(id) => id,
+                    None => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                let filter = can::Filter {
+                    id: id,
+                    mask: data2 as u32,
+                };
+                self.apps
+                    .enter(appid, |app| {
+                        match app.filters.iter().position(|f| f.is_none()) {
+                            Some(index) => {
+                                app.filters[index] = Some(filter);
+                                CommandReturn::success_u32(index as u32)
+                            }
+                            None => CommandReturn::failure(ErrorCode::NOMEM),
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            // remove filter
+            3 => self
+                .apps
+                .enter(appid, |app| match app.filters.get_mut(data1) {
+                    Some(filter) if filter.is_some() => {
+                        *filter = None;
+                        CommandReturn::success()
+                    }
+                    _ => CommandReturn::failure(ErrorCode::INVAL),
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+impl<'a, C: can::Can<'a>> can::TransmitClient for CanDriver<'a, C> {
+    fn transmit_complete(&self, status: Result<(), ErrorCode>, buffer: &'static mut [u8]) {
+        self.tx_buffer.replace(buffer);
+        self.tx_in_progress.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.tx_callback
+                    .schedule(kernel::into_statuscode(status), 0, 0);
+            });
+        });
+        self.send_pending();
+    }
+}
+
+impl<'a, C: can::Can<'a>> can::ReceiveClient for CanDriver<'a, C> {
+    fn frame_received(&self, id: can::Id, data: &[u8]) {
+        self.apps.each(|_, app| {
+            if app.filters.iter().flatten().any(|f| f.matches(id)) {
+                let len = app.rx_data.mut_map_or(0, |buffer| {
+                    let len = cmp::min(buffer.len(), data.len());
+                    buffer[..len].copy_from_slice(&data[..len]);
+                    len
+                });
+                app.rx_callback.schedule(encode_id(id), len, 0);
+            }
+        });
+    }
+}
+
+impl<'a, C: can::Can<'a>> can::ControllerClient for CanDriver<'a, C> {
+    fn bus_off(&self) {
+        self.apps.each(|_, app| {
+            app.bus_off_callback.schedule(0, 0, 0);
+        });
+    }
+}
@@ -36,11 +36,74 @@
 //! When the buffer has been written successfully, the buffer is released from
 //! the driver. Successive writes must call `allow` each time a buffer is to be
 //! written.
+//!
+//! Rate Limiting
+//! -------------
+//!
+//! A misbehaving process can flood the console and starve the output of other
+//! processes. A board can limit how many bytes each process may write per
+//! interval with a `ConsoleRateLimiter`, which refills the per-process token
+//! buckets from a virtual alarm one interval after a process starts using
+//! its budget:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::console::{ConsoleRateLimiter, RateLimitPolicy};
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//!
+//! let limiter_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm));
+//! let limiter = static_init!(
+//!     ConsoleRateLimiter<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     ConsoleRateLimiter::new(console, limiter_alarm, 4096, 1000, RateLimitPolicy::Drop));
+//! limiter_alarm.set_alarm_client(limiter);
+//! console.set_rate_limit_timer(limiter);
+//! ```
+//!
+//! Writes beyond a process's budget are either truncated, with the number of
+//! dropped bytes reported to the process, or rejected with `BUSY`, depending
+//! on the `RateLimitPolicy`. With `RateLimitPolicy::Busy`, a write longer than
+//! a whole interval's budget can never succeed and is rejected with `SIZE`.
+//!
+//! Line Reads
+//! ----------
+//!
+//! A process can read a line, which completes when a newline is received or
+//! the buffer is full. A line read can also be given a timeout, after which
+//! it completes with the bytes received so far and a "timed out" flag. Line
+//! read timeouts need a `ConsoleReadTimer`:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::console::ConsoleReadTimer;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//!
+//! let read_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm));
+//! let read_timer = static_init!(
+//!     ConsoleReadTimer<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     ConsoleReadTimer::new(console, read_alarm));
+//! read_alarm.set_alarm_client(read_timer);
+//! console.set_read_timer(read_timer);
+//! ```
+//!
+//! Process Output
+//! --------------
+//!
+//! When several processes print, a board can tag each line of their output
+//! with the name of the process, as in `[blink] on`, with
+//! `set_process_prefixes()`. The output of each process can also be given to
+//! a `ConsoleMirror`, such as a `console_log::ConsoleLog`, with `set_mirror()`,
+//! for example to keep it after the serial cable is disconnected.
 
+use core::cell::Cell;
 use core::convert::TryFrom;
 use core::{cmp, mem};
 
 use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::time::{self, Alarm};
 use kernel::hil::uart;
 use kernel::{CommandReturn, Driver};
 use kernel::{ErrorCode, Grant, ProcessId, Upcall};
@@ -61,6 +124,82 @@ pub struct App {
     read_callback: Upcall,
     read_buffer: ReadWriteAppSlice,
     read_len: usize,
+    // Whether the current read is a line read, how many bytes of it have been
+    // received, and whether it timed out.
+    read_line: bool,
+    read_received: usize,
+    read_timed_out: bool,
+
+    // Bytes this app may still write in the current rate-limit interval, or
+    // `None` if the bucket is full.
+    tokens: Option<usize>,
+    // Bytes of the current write dropped by the rate limit.
+    write_dropped: usize,
+    // Total bytes dropped by the rate limit.
+    dropped: usize,
+
+    // Whether the output of the app is in the middle of a line, and how much
+    // of the name prefix of the next line has been sent.
+    mid_line: bool,
+    prefix_sent: usize,
+}
+
+/// Receives the output of each process written to a `Console`.
+pub trait ConsoleMirror {
+    /// Called with each write of the process `process_name`, as it is
+    /// started.
+    fn process_output(&self, process_name: &str, data: &[u8]);
+}
+
+/// What to do with a write that exceeds a process's rate-limit budget.
+#[derive(Copy, Clone, PartialEq)]
+pub enum RateLimitPolicy {
+    /// Write as much as the budget allows and drop the rest.
+    Drop,
+    /// Reject the write with `BUSY`, or with `SIZE` if it is longer than
+    /// the budget of a whole interval.
+    Busy,
+}
+
+#[derive(Copy, Clone)]
+struct RateLimit {
+    bytes_per_interval: usize,
+    policy: RateLimitPolicy,
+}
+
+impl RateLimit {
+    /// Apply the limit to a write of `len` bytes, with `tokens` left in the
+    /// bucket (`None` if it is full). Returns the number of bytes that may be
+    /// written and the tokens left afterwards.
+    fn take(&self, tokens: Option<usize>, len: usize) -> Result<(usize, usize), ErrorCode> {
+        let available = tokens.unwrap_or(self.bytes_per_interval);
+        if len <= available {
+            Ok((len, available - len))
+        } else if self.policy == RateLimitPolicy::Busy {
+            if len > self.bytes_per_interval {
+                Err(ErrorCode::SIZE)
+            } else {
+                Err(ErrorCode::BUSY)
+            }
+        } else {
+            Ok((available, 0))
+        }
+    }
+}
+
+/// Refills the rate-limit budgets of a `Console`.
+pub trait RateLimitTimer {
+    /// Call `Console::refill_rate_limit()` after one interval, unless a
+    /// refill is already pending.
+    fn start(&self);
+}
+
+/// Times out line reads on behalf of a `Console`.
+pub trait ReadTimer {
+    /// Call `Console::read_timeout()` after `timeout_ms` milliseconds.
+    fn start(&self, timeout_ms: u32);
+    /// Stop a running timeout.
+    fn cancel(&self);
 }
 
 pub static mut WRITE_BUF: [u8; 64] = [0; 64];
@@ -73,6 +212,11 @@ pub struct Console<'a> {
     tx_buffer: TakeCell<'static, [u8]>,
     rx_in_progress: OptionalCell<ProcessId>,
     rx_buffer: TakeCell<'static, [u8]>,
+    rate_limit: OptionalCell<RateLimit>,
+    rate_limit_timer: OptionalCell<&'a dyn RateLimitTimer>,
+    read_timer: OptionalCell<&'a dyn ReadTimer>,
+    process_prefixes: Cell<bool>,
+    mirror: OptionalCell<&'a dyn ConsoleMirror>,
 }
 
 impl<'a> Console<'a> {
@@ -89,13 +233,112 @@ impl<'a> Console<'a> {
             tx_buffer: TakeCell::new(tx_buffer),
             rx_in_progress: OptionalCell::empty(),
             rx_buffer: TakeCell::new(rx_buffer),
+            rate_limit: OptionalCell::empty(),
+            rate_limit_timer: OptionalCell::empty(),
+            read_timer: OptionalCell::empty(),
+            process_prefixes: Cell::new(false),
+            mirror: OptionalCell::empty(),
+        }
+    }
+
+    /// Set whether each line written by a process starts with its name in
+    /// brackets.
+    pub fn set_process_prefixes(&self, enabled: bool) {
+        self.process_prefixes.set(enabled);
+    }
+
+    /// Give the output of every process to `mirror` as well.
+    pub fn set_mirror(&self, mirror: &'a dyn ConsoleMirror) {
+        self.mirror.set(mirror);
+    }
+
+    /// The byte at `index` of the prefix `[name] `.
+    fn prefix_byte(name: &[u8], index: usize) -> u8 {
+        match index {
+            0 => b'[',
+            i if i <= name.len() => name[i - 1],
+            i if i == name.len() + 1 => b']',
+            _ => b' ',
         }
     }
 
+    /// Set the timer used for line read timeouts.
+    pub fn set_read_timer(&self, read_timer: &'a dyn ReadTimer) {
+        self.read_timer.set(read_timer);
+    }
+
+    /// Time out the line read in progress, completing it with the bytes
+    /// received so far.
+    pub fn read_timeout(&self) {
+        let timed_out = self.rx_in_progress.map_or(false, |appid| {
+            self.apps
+                .enter(*appid, |app| {
+                    if app.read_line {
+                        app.read_timed_out = true;
+                    }
+                    app.read_line
+                })
+                .unwrap_or(false)
+        });
+        if timed_out {
+            let _ = self.uart.receive_abort();
+        }
+    }
+
+    /// Limit each process to writing `bytes_per_interval` bytes between two
+    /// calls to `refill_rate_limit()`.
+    pub fn set_rate_limit(&self, bytes_per_interval: usize, policy: RateLimitPolicy) {
+        self.rate_limit.set(RateLimit {
+            bytes_per_interval: bytes_per_interval,
+            policy: policy,
+        });
+    }
+
+    /// Set the timer that refills the rate-limit budgets. It is started
+    /// whenever a process uses some of a full budget.
+    pub fn set_rate_limit_timer(&self, timer: &'a dyn RateLimitTimer) {
+        self.rate_limit_timer.set(timer);
+    }
+
+    /// Start a new rate-limit interval, refilling every process's budget.
+    pub fn refill_rate_limit(&self) {
+        self.apps.each(|_, app| {
+            app.tokens = None;
+        });
+    }
+
+    /// Apply the rate limit to a write of `len` bytes. Returns the number of
+    /// bytes that may be written.
+    fn take_tokens(&self, app: &mut App, len: usize) -> Result<usize, ErrorCode> {
+        app.write_dropped = 0;
+        self.rate_limit.map_or(Ok(len), |limit| {
+            let (allowed, tokens) = limit.take(app.tokens, len)?;
+            if tokens < limit.bytes_per_interval {
+                app.tokens = Some(tokens);
+                self.rate_limit_timer.map(|timer| timer.start());
+            }
+            app.write_dropped = len - allowed;
+            app.dropped = app.dropped.saturating_add(len - allowed);
+            Ok(allowed)
+        })
+    }
+
     /// Internal helper function for setting up a new send transaction
     fn send_new(&self, app_id: ProcessId, app: &mut App, len: usize) -> Result<(), ErrorCode> {
-        app.write_len = cmp::min(len, app.write_buffer.len());
+        let len = self.take_tokens(app, cmp::min(len, app.write_buffer.len()))?;
+        app.write_len = len;
         app.write_remaining = app.write_len;
+        self.mirror.map(|mirror| {
+            app.write_buffer.map_or((), |data| {
+                mirror.process_output(app_id.get_process_name(), &data[..len]);
+            });
+        });
+        if len == 0 {
+            // Everything was dropped by the rate limit.
+            let dropped = app.write_dropped;
+            app.write_callback.schedule(0, dropped, 0);
+            return Ok(());
+        }
         self.send(app_id, app);
         Ok(())
     }
@@ -127,20 +370,46 @@ impl<'a> Console<'a> {
                     // what we need to write -- just write what we can.
                     app.write_remaining = len;
                 }
-                let transaction_len = app.write_buffer.map_or(0, |data| {
-                    for (i, c) in data[data.len() - app.write_remaining..data.len()]
-                        .iter()
-                        .enumerate()
-                    {
-                        if buffer.len() <= i {
-                            return i; // Short circuit on partial send
+                let name = if self.process_prefixes.get() {
+                    app_id.get_process_name().as_bytes()
+                } else {
+                    &[]
+                };
+                let prefix_len = if self.process_prefixes.get() {
+                    name.len() + 3
+                } else {
+                    0
+                };
+                let mut transaction_len = 0;
+                let mut consumed = 0;
+                let mut mid_line = app.mid_line;
+                let mut prefix_sent = app.prefix_sent;
+                app.write_buffer.map_or((), |data| {
+                    for c in data[data.len() - app.write_remaining..data.len()].iter() {
+                        if !mid_line {
+                            while prefix_sent < prefix_len && transaction_len < buffer.len() {
+                                buffer[transaction_len] = Self::prefix_byte(name, prefix_sent);
+                                prefix_sent += 1;
+                                transaction_len += 1;
+                            }
+                            if prefix_sent < prefix_len {
+                                break;
+                            }
+                        }
+                        if buffer.len() <= transaction_len {
+                            break; // Short circuit on partial send
                         }
-                        buffer[i] = *c;
+                        buffer[transaction_len] = *c;
+                        transaction_len += 1;
+                        consumed += 1;
+                        mid_line = *c != b'\n';
+                        prefix_sent = 0;
                     }
-                    app.write_remaining
                 });
+                app.mid_line = mid_line;
+                app.prefix_sent = prefix_sent;
 
-                app.write_remaining -= transaction_len;
+                app.write_remaining -= consumed;
                 let _ = self.uart.transmit_buffer(buffer, transaction_len);
             });
         } else {
@@ -171,6 +440,120 @@ impl<'a> Console<'a> {
             Ok(())
         }
     }
+
+    /// Internal helper function for starting a line read, which receives one
+    /// byte at a time until a newline, a full buffer or the timeout.
+    fn receive_line_new(
+        &self,
+        app_id: ProcessId,
+        app: &mut App,
+        len: usize,
+        timeout_ms: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.rx_buffer.is_none() {
+            return Err(ErrorCode::BUSY);
+        }
+        if timeout_ms > 0 && self.read_timer.is_none() {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        let read_len = cmp::min(len, app.read_buffer.len());
+        if read_len == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+
+        app.read_len = read_len;
+        app.read_line = true;
+        app.read_received = 0;
+        app.read_timed_out = false;
+        self.rx_buffer.take().map(|buffer| {
+            self.rx_in_progress.set(app_id);
+            let _ = self.uart.receive_buffer(buffer, 1);
+        });
+        if timeout_ms > 0 {
+            self.read_timer.map(|timer| timer.start(timeout_ms as u32));
+        }
+        Ok(())
+    }
+
+    /// Handle a byte received for a line read. Continues the read or
+    /// completes it and returns the receive buffer.
+    fn received_line(
+        &self,
+        appid: ProcessId,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rcode: Result<(), ErrorCode>,
+        error: uart::Error,
+    ) {
+        let rx_len = cmp::min(rx_len, buffer.len());
+        let more = self
+            .apps
+            .enter(appid, |app| {
+                let ret = match error {
+                    uart::Error::None | uart::Error::Aborted => {
+                        let received = app.read_received;
+                        let count = app.read_buffer.mut_map_or(0, |data| {
+                            let dest = data.iter_mut().skip(received);
+                            let mut c = 0;
+                            for (a, b) in dest.zip(buffer[..rx_len].iter()) {
+                                c = c + 1;
+                                *a = *b;
+                            }
+                            c
+                        });
+                        app.read_received += count;
+                        let newline = buffer[..count].contains(&b'\n');
+
+                        if count < rx_len {
+                            // The buffer shrank or disappeared.
+                            Err(ErrorCode::NOMEM)
+                        } else if error == uart::Error::None
+                            && !newline
+                            && app.read_received < app.read_len
+                        {
+                            return true;
+                        } else if app.read_timed_out {
+                            Ok(())
+                        } else if error == uart::Error::Aborted {
+                            rcode
+                        } else {
+                            Ok(())
+                        }
+                    }
+                    _ => Err(ErrorCode::FAIL),
+                };
+
+                let received = app.read_received;
+                let timed_out = app.read_timed_out;
+                app.read_line = false;
+                app.read_timed_out = false;
+                app.read_callback.schedule(
+                    kernel::into_statuscode(ret),
+                    received,
+                    timed_out as usize,
+                );
+                false
+            })
+            .unwrap_or(false);
+
+        if more {
+            self.rx_in_progress.set(appid);
+            if let Err((e, buffer)) = self.uart.receive_buffer(buffer, 1) {
+                self.rx_in_progress.clear();
+                self.read_timer.map(|timer| timer.cancel());
+                let _ = self.apps.enter(appid, |app| {
+                    let received = app.read_received;
+                    app.read_line = false;
+                    app.read_callback
+                        .schedule(kernel::into_statuscode(Err(e)), received, 0);
+                });
+                self.rx_buffer.replace(buffer);
+            }
+        } else {
+            self.read_timer.map(|timer| timer.cancel());
+            self.rx_buffer.replace(buffer);
+        }
+    }
 }
 
 impl Driver for Console<'_> {
@@ -233,7 +616,12 @@ impl Driver for Console<'_> {
     ///
     /// ### `subscribe_num`
     ///
-    /// - `1`: Write buffer completed callback
+    /// - `1`: Write buffer completed callback. The callback receives the
+    ///        number of bytes written and the number of bytes of the write
+    ///        dropped by the rate limit.
+    /// - `2`: Read buffer completed callback. The callback receives a status
+    ///        code, the number of bytes received and, for line reads, `1` if
+    ///        the read timed out.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -278,7 +666,16 @@ impl Driver for Console<'_> {
     ///        passed in `arg1`
     /// - `3`: Cancel any in progress receives and return (via callback)
     ///        what has been received so far.
-    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+    /// - `4`: Return the number of bytes of this process's output dropped
+    ///        by the rate limit.
+    /// - `5`: Receives a line into a buffer passed via `allow`, up to the
+    ///        length passed in `arg1`. The read completes when a newline is
+    ///        received (and included in the buffer), when the length is
+    ///        reached, or after the timeout in milliseconds passed in `arg2`,
+    ///        with the bytes received so far. A timeout of 0 never times
+    ///        out. Returns `NOSUPPORT` for a non-zero timeout if the board
+    ///        has no read timer.
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
         let res = match cmd_num {
             0 => Ok(Ok(())),
             1 => {
@@ -300,6 +697,23 @@ impl Driver for Console<'_> {
                 let _ = self.uart.receive_abort();
                 Ok(Ok(()))
             }
+            4 => {
+                // Dropped byte count
+                return self
+                    .apps
+                    .enter(appid, |app| CommandReturn::success_u32(app.dropped as u32))
+                    .unwrap_or_else(|err| err.into());
+            }
+            5 => {
+                // getline with timeout
+                let len = arg1;
+                let timeout_ms = arg2;
+                self.apps
+                    .enter(appid, |app| {
+                        self.receive_line_new(appid, app, len, timeout_ms)
+                    })
+                    .map_err(ErrorCode::from)
+            }
             _ => Err(ErrorCode::NOSUPPORT),
         };
         match res {
@@ -332,8 +746,9 @@ impl uart::TransmitClient for Console<'_> {
                         if !more_to_send {
                             // Go ahead and signal the application
                             let written = app.write_len;
+                            let dropped = app.write_dropped;
                             app.write_len = 0;
-                            app.write_callback.schedule(written, 0, 0);
+                            app.write_callback.schedule(written, dropped, 0);
                         }
                     }
                     Err(return_code) => {
@@ -391,6 +806,18 @@ impl uart::ReceiveClient for Console<'_> {
         rcode: Result<(), ErrorCode>,
         error: uart::Error,
     ) {
+        let line = self.rx_in_progress.map_or(None, |appid| {
+            self.apps
+                .enter(*appid, |app| app.read_line)
+                .unwrap_or(false)
+                .then(|| *appid)
+        });
+        if let Some(appid) = line {
+            self.rx_in_progress.clear();
+            self.received_line(appid, buffer, rx_len, rcode, error);
+            return;
+        }
+
         self.rx_in_progress
             .take()
             .map(|appid| {
@@ -468,3 +895,413 @@ impl uart::ReceiveClient for Console<'_> {
         self.rx_buffer.replace(buffer);
     }
 }
+
+/// Refills the rate-limit budget of every process writing to a `Console`
+/// one interval after a process starts using its budget. The alarm is
+/// idle while every budget is full.
+pub struct ConsoleRateLimiter<'a, A: Alarm<'a>> {
+    console: &'a Console<'a>,
+    alarm: &'a A,
+    interval_ms: u32,
+}
+
+impl<'a, A: Alarm<'a>> ConsoleRateLimiter<'a, A> {
+    pub fn new(
+        console: &'a Console<'a>,
+        alarm: &'a A,
+        bytes_per_interval: usize,
+        interval_ms: u32,
+        policy: RateLimitPolicy,
+    ) -> ConsoleRateLimiter<'a, A> {
+        console.set_rate_limit(bytes_per_interval, policy);
+        ConsoleRateLimiter {
+            console: console,
+            alarm: alarm,
+            interval_ms: interval_ms,
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> RateLimitTimer for ConsoleRateLimiter<'a, A> {
+    fn start(&self) {
+        if !self.alarm.is_armed() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(self.interval_ms));
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for ConsoleRateLimiter<'a, A> {
+    fn alarm(&self) {
+        // Every budget is full again, so the alarm stays off until a
+        // process writes.
+        self.console.refill_rate_limit();
+    }
+}
+
+/// Times out line reads of a `Console` with an alarm.
+pub struct ConsoleReadTimer<'a, A: Alarm<'a>> {
+    console: &'a Console<'a>,
+    alarm: &'a A,
+}
+
+impl<'a, A: Alarm<'a>> ConsoleReadTimer<'a, A> {
+    pub fn new(console: &'a Console<'a>, alarm: &'a A) -> ConsoleReadTimer<'a, A> {
+        ConsoleReadTimer {
+            console: console,
+            alarm: alarm,
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> ReadTimer for ConsoleReadTimer<'a, A> {
+    fn start(&self, timeout_ms: u32) {
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(timeout_ms));
+    }
+
+    fn cancel(&self) {
+        let _ = self.alarm.disarm();
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for ConsoleReadTimer<'a, A> {
+    fn alarm(&self) {
+        self.console.read_timeout();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{
+        Console, ConsoleRateLimiter, ConsoleReadTimer, RateLimit, RateLimitPolicy, DRIVER_NUM,
+    };
+    use crate::test::mock_alarm::MockAlarm;
+    use crate::test::mock_process::{self, MockProcess};
+    use core::cell::{Cell, RefCell};
+    use core::cmp;
+    use kernel::common::cells::{OptionalCell, TakeCell};
+    use kernel::hil::time::Alarm;
+    use kernel::hil::uart;
+    use kernel::{Driver, ErrorCode, Read, ReadWriteAppSlice};
+    use std::vec::Vec;
+
+    const DROP: RateLimit = RateLimit {
+        bytes_per_interval: 10,
+        policy: RateLimitPolicy::Drop,
+    };
+    const BUSY: RateLimit = RateLimit {
+        bytes_per_interval: 10,
+        policy: RateLimitPolicy::Busy,
+    };
+
+    #[test]
+    fn test_within_budget() {
+        assert_eq!(DROP.take(None, 4), Ok((4, 6)));
+        assert_eq!(BUSY.take(Some(6), 6), Ok((6, 0)));
+        assert_eq!(BUSY.take(None, 0), Ok((0, 10)));
+    }
+
+    #[test]
+    fn test_drop_truncates() {
+        assert_eq!(DROP.take(Some(3), 5), Ok((3, 0)));
+        assert_eq!(DROP.take(Some(0), 5), Ok((0, 0)));
+        assert_eq!(DROP.take(None, 25), Ok((10, 0)));
+    }
+
+    #[test]
+    fn test_busy_until_refill() {
+        assert_eq!(BUSY.take(Some(3), 5), Err(ErrorCode::BUSY));
+        // Once the bucket is refilled, the same write fits.
+        assert_eq!(BUSY.take(None, 5), Ok((5, 5)));
+    }
+
+    #[test]
+    fn test_busy_longer_than_interval() {
+        // A write that no refill could make room for is rejected for good.
+        assert_eq!(BUSY.take(None, 11), Err(ErrorCode::SIZE));
+        assert_eq!(BUSY.take(Some(0), 11), Err(ErrorCode::SIZE));
+    }
+
+    /// A UART that holds each buffer until the test completes it, and
+    /// records what was transmitted.
+    struct MockUart {
+        tx: TakeCell<'static, [u8]>,
+        tx_len: Cell<usize>,
+        output: RefCell<Vec<u8>>,
+        rx: TakeCell<'static, [u8]>,
+        rx_len: Cell<usize>,
+        tx_client: OptionalCell<&'static dyn uart::TransmitClient>,
+        rx_client: OptionalCell<&'static dyn uart::ReceiveClient>,
+    }
+
+    impl MockUart {
+        fn new() -> MockUart {
+            MockUart {
+                tx: TakeCell::empty(),
+                tx_len: Cell::new(0),
+                output: RefCell::new(Vec::new()),
+                rx: TakeCell::empty(),
+                rx_len: Cell::new(0),
+                tx_client: OptionalCell::empty(),
+                rx_client: OptionalCell::empty(),
+            }
+        }
+
+        /// Finish the transmission in progress. Returns false if there is
+        /// none.
+        fn complete(&self) -> bool {
+            match self.tx.take() {
+                Some(buffer) => {
+                    let len = self.tx_len.get();
+                    self.output.borrow_mut().extend_from_slice(&buffer[..len]);
+                    self.tx_client
+                        .map(move |client| client.transmitted_buffer(buffer, len, Ok(())));
+                    true
+                }
+                None => false,
+            }
+        }
+
+        /// Finish every transmission, including those started by
+        /// completions.
+        fn complete_all(&self) {
+            while self.complete() {}
+        }
+
+        /// Receive `data`, up to the length of the receive in progress.
+        fn receive(&self, data: &[u8]) {
+            let buffer = self.rx.take().expect("no receive in progress");
+            let len = cmp::min(data.len(), self.rx_len.get());
+            buffer[..len].copy_from_slice(&data[..len]);
+            self.rx_client
+                .map(move |client| client.received_buffer(buffer, len, Ok(()), uart::Error::None));
+        }
+    }
+
+    impl<'a> uart::Transmit<'a> for MockUart {
+        fn set_transmit_client(&self, _client: &'a dyn uart::TransmitClient) {}
+
+        fn transmit_buffer(
+            &self,
+            tx_buffer: &'static mut [u8],
+            tx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            assert!(self.tx.is_none(), "transmit while busy");
+            self.tx_len.set(tx_len);
+            self.tx.replace(tx_buffer);
+            Ok(())
+        }
+
+        fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn transmit_abort(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+    }
+
+    impl<'a> uart::Receive<'a> for MockUart {
+        fn set_receive_client(&self, _client: &'a dyn uart::ReceiveClient) {}
+
+        fn receive_buffer(
+            &self,
+            rx_buffer: &'static mut [u8],
+            rx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.rx_len.set(rx_len);
+            self.rx.replace(rx_buffer);
+            Ok(())
+        }
+
+        fn receive_word(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn receive_abort(&self) -> Result<(), ErrorCode> {
+            match self.rx.take() {
+                Some(buffer) => {
+                    self.rx_client.map(move |client| {
+                        client.received_buffer(
+                            buffer,
+                            0,
+                            Err(ErrorCode::CANCEL),
+                            uart::Error::Aborted,
+                        )
+                    });
+                    Ok(())
+                }
+                None => Err(ErrorCode::OFF),
+            }
+        }
+    }
+
+    impl<'a> uart::UartData<'a> for MockUart {}
+
+    /// A console on a mock UART for processes `names`.
+    fn console(
+        names: &[&'static str],
+    ) -> (
+        &'static Console<'static>,
+        &'static MockUart,
+        Vec<&'static MockProcess>,
+    ) {
+        let (kernel, processes) = mock_process::kernel(names);
+        let uart = mock_process::leak(MockUart::new());
+        let console = mock_process::leak(Console::new(
+            uart,
+            mock_process::buffer(64),
+            mock_process::buffer(64),
+            mock_process::grant(kernel),
+        ));
+        uart.tx_client.set(console);
+        uart.rx_client.set(console);
+        (console, uart, processes)
+    }
+
+    /// Allow `data` as the write buffer of `process` and subscribe to write
+    /// completions.
+    fn allow_write(console: &Console, process: &MockProcess, data: &[u8]) {
+        let appid = process.processid();
+        assert!(console
+            .allow_readonly(appid, 1, process.readonly_slice(data))
+            .is_ok());
+        assert!(console
+            .subscribe(1, process.upcall(DRIVER_NUM, 1), appid)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_flood_throttled_others_get_through() {
+        let (console, uart, processes) = console(&["flood", "quiet"]);
+        let (flood, quiet) = (processes[0], processes[1]);
+        let alarm: &MockAlarm = mock_process::leak(MockAlarm::new());
+        let limiter = mock_process::leak(ConsoleRateLimiter::new(
+            console,
+            alarm,
+            16,
+            100,
+            RateLimitPolicy::Drop,
+        ));
+        console.set_rate_limit_timer(limiter);
+        alarm.set_alarm_client(limiter);
+        allow_write(console, flood, &[b'F'; 40]);
+        allow_write(console, quiet, b"quiet\n");
+
+        // The flood is cut to its budget, and the quiet process waits for
+        // the UART behind it.
+        assert!(console.command(1, 40, 0, flood.processid()).is_success());
+        assert!(console.command(1, 6, 0, quiet.processid()).is_success());
+        uart.complete_all();
+        assert_eq!(flood.take_upcalls(), [(1, 16, 24, 0)]);
+        assert_eq!(quiet.take_upcalls(), [(1, 6, 0, 0)]);
+
+        // With its budget spent, the flood is dropped without reaching the
+        // UART, while the quiet process still writes.
+        assert!(console.command(1, 40, 0, flood.processid()).is_success());
+        assert_eq!(flood.take_upcalls(), [(1, 0, 40, 0)]);
+        assert!(!uart.complete());
+        assert!(console.command(1, 6, 0, quiet.processid()).is_success());
+        uart.complete_all();
+        assert_eq!(quiet.take_upcalls(), [(1, 6, 0, 0)]);
+        assert_eq!(
+            console
+                .command(4, 0, 0, flood.processid())
+                .get_success_u32(),
+            Some(64)
+        );
+        assert_eq!(
+            console
+                .command(4, 0, 0, quiet.processid())
+                .get_success_u32(),
+            Some(0)
+        );
+
+        // The next interval refills the flood's budget.
+        assert_eq!(alarm.fire(), Some(100_000));
+        assert!(console.command(1, 40, 0, flood.processid()).is_success());
+        uart.complete_all();
+        assert_eq!(flood.take_upcalls(), [(1, 16, 24, 0)]);
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&[b'F'; 16]);
+        expected.extend_from_slice(b"quiet\nquiet\n");
+        expected.extend_from_slice(&[b'F'; 16]);
+        assert_eq!(*uart.output.borrow(), expected);
+    }
+
+    /// A console of one process that reads lines into a 16 byte buffer, with
+    /// a read timer.
+    fn line_console() -> (
+        &'static Console<'static>,
+        &'static MockUart,
+        &'static MockAlarm<'static>,
+        &'static MockProcess,
+    ) {
+        let (console, uart, processes) = console(&["reader"]);
+        let alarm: &MockAlarm = mock_process::leak(MockAlarm::new());
+        let timer = mock_process::leak(ConsoleReadTimer::new(console, alarm));
+        console.set_read_timer(timer);
+        alarm.set_alarm_client(timer);
+        let reader = processes[0];
+        let appid = reader.processid();
+        assert!(console
+            .allow_readwrite(appid, 1, reader.readwrite_slice(&[0; 16]))
+            .is_ok());
+        assert!(console
+            .subscribe(2, reader.upcall(DRIVER_NUM, 2), appid)
+            .is_ok());
+        (console, uart, alarm, reader)
+    }
+
+    /// The contents of the read buffer of `process`.
+    fn read_buffer(console: &Console, process: &MockProcess) -> Vec<u8> {
+        let appid = process.processid();
+        let slice = console
+            .allow_readwrite(appid, 1, ReadWriteAppSlice::default())
+            .ok()
+            .unwrap();
+        let data = slice.map_or(Vec::new(), |data| data.to_vec());
+        assert!(console.allow_readwrite(appid, 1, slice).is_ok());
+        data
+    }
+
+    #[test]
+    fn test_line_read_ends_at_newline() {
+        let (console, uart, alarm, reader) = line_console();
+        assert!(console.command(5, 16, 100, reader.processid()).is_success());
+        assert_eq!(alarm.until_alarm(), Some(100_000));
+
+        for byte in b"hi\n" {
+            alarm.advance(1_000);
+            uart.receive(&[*byte]);
+        }
+        assert_eq!(reader.take_upcalls(), [(2, 0, 3, 0)]);
+        assert_eq!(&read_buffer(console, reader)[..3], b"hi\n");
+
+        // The timeout was cancelled, and the console can read again.
+        assert_eq!(alarm.until_alarm(), None);
+        alarm.advance(200_000);
+        assert!(reader.take_upcalls().is_empty());
+        assert!(console.command(5, 16, 100, reader.processid()).is_success());
+    }
+
+    #[test]
+    fn test_line_read_times_out_with_partial_data() {
+        let (console, uart, alarm, reader) = line_console();
+        assert!(console.command(5, 16, 50, reader.processid()).is_success());
+        uart.receive(b"a");
+        uart.receive(b"b");
+        assert!(reader.take_upcalls().is_empty());
+
+        // The read completes with the bytes received so far, flagged as
+        // timed out.
+        alarm.advance(50_000);
+        assert_eq!(reader.take_upcalls(), [(2, 0, 2, 1)]);
+        assert_eq!(&read_buffer(console, reader)[..2], b"ab");
+        assert!(console.command(2, 4, 0, reader.processid()).is_success());
+    }
+}
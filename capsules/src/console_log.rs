@@ -0,0 +1,320 @@
+//! Keeps the console output of processes in a persistent log.
+//!
+//! Output printed to the console is lost if no terminal is connected. This
+//! capsule receives the output of each process from the `Console`, as its
+//! `ConsoleMirror`, and appends it to a log from `log.rs`, tagged with the
+//! name of the process. With a circular log, the most recent lines are kept,
+//! and a process can read them back, for example to send them to a terminal
+//! after the serial cable is reconnected.
+//!
+//! Output is staged in RAM and appended to the log in entries of one or more
+//! lines, each line starting with `[name] `. Every write of a process starts
+//! a new line. Output that arrives while the staging buffer is full is
+//! dropped. Entries are written to flash when their page of the log is full.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let console_log = static_init!(
+//!     capsules::console_log::ConsoleLog<'static, capsules::log::Log<'static, FLASH>>,
+//!     capsules::console_log::ConsoleLog::new(
+//!         log,
+//!         &mut capsules::console_log::STAGING_BUF,
+//!         &mut capsules::console_log::APPEND_BUF,
+//!         &mut capsules::console_log::READ_BUF,
+//!         board_kernel.create_grant(&memory_allocation_capability)
+//!     )
+//! );
+//! log.set_read_client(console_log);
+//! log.set_append_client(console_log);
+//! console.set_mirror(console_log);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-write `0`: The buffer entries are read into.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Done upcall, with the status of the read or rewind and the length
+//!   of the entry read.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Rewind to the oldest entry still in the log.
+//! - `2`: Read the next entry into the buffer. Returns `FAIL` at the end of
+//!   the log.
+//! - `3`: Get how many bytes of output were dropped because the staging
+//!   buffer was full.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::{ReadWrite, ReadWriteAppSlice};
+
+use crate::console::ConsoleMirror;
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::ConsoleLog as usize;
+
+pub static mut STAGING_BUF: [u8; 128] = [0; 128];
+pub static mut APPEND_BUF: [u8; 128] = [0; 128];
+pub static mut READ_BUF: [u8; 128] = [0; 128];
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    buffer: ReadWriteAppSlice,
+}
+
+pub struct ConsoleLog<'a, L: LogRead<'a> + LogWrite<'a>> {
+    log: &'a L,
+    apps: Grant<App>,
+    /// Output waiting to be appended, and the buffer of the append in
+    /// progress when it is not here.
+    staging: TakeCell<'static, [u8]>,
+    staging_len: Cell<usize>,
+    spare: TakeCell<'static, [u8]>,
+    read_buffer: TakeCell<'static, [u8]>,
+    reader: OptionalCell<ProcessId>,
+    dropped: Cell<usize>,
+}
+
+impl<'a, L: LogRead<'a> + LogWrite<'a>> ConsoleLog<'a, L> {
+    pub fn new(
+        log: &'a L,
+        staging_buffer: &'static mut [u8],
+        append_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> ConsoleLog<'a, L> {
+        ConsoleLog {
+            log: log,
+            apps: grant,
+            staging: TakeCell::new(staging_buffer),
+            staging_len: Cell::new(0),
+            spare: TakeCell::new(append_buffer),
+            read_buffer: TakeCell::new(read_buffer),
+            reader: OptionalCell::empty(),
+            dropped: Cell::new(0),
+        }
+    }
+
+    /// Append the staged output to the log, unless an append is in
+    /// progress.
+    fn flush(&self) {
+        let len = self.staging_len.get();
+        if len == 0 || self.spare.is_none() {
+            return;
+        }
+        if let Some(staging) = self.staging.take() {
+            match self.log.append(staging, len) {
+                Ok(()) => {
+                    self.staging_len.set(0);
+                    self.spare.take().map(|spare| self.staging.replace(spare));
+                }
+                Err((ErrorCode::BUSY, staging)) => {
+                    // The log is busy reading, try again when it is done.
+                    self.staging.replace(staging);
+                }
+                Err((_, staging)) => {
+                    // The log cannot take this entry, for example because it
+                    // is larger than a page.
+                    self.dropped.set(self.dropped.get().saturating_add(len));
+                    self.staging_len.set(0);
+                    self.staging.replace(staging);
+                }
+            }
+        }
+    }
+
+    /// Tell the reader that its read or rewind finished.
+    fn read_done_upcall(&self, result: Result<(), ErrorCode>, length: usize) {
+        self.reader.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback
+                    .schedule(kernel::into_statuscode(result), length, 0);
+            });
+        });
+    }
+
+    fn read_next(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let buffer = self.read_buffer.take().ok_or(ErrorCode::BUSY)?;
+        let len = buffer.len();
+        match self.log.read(buffer, len) {
+            Ok(()) => {
+                self.reader.set(appid);
+                Ok(())
+            }
+            Err((e, buffer)) => {
+                self.read_buffer.replace(buffer);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a> + LogWrite<'a>> ConsoleMirror for ConsoleLog<'a, L> {
+    fn process_output(&self, process_name: &str, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let newline = data[data.len() - 1] != b'\n';
+        let line_len = process_name.len() + 3 + data.len() + newline as usize;
+        let staged = self.staging.map_or(false, |staging| {
+            let start = self.staging_len.get();
+            if start + line_len > staging.len() {
+                return false;
+            }
+            let line = &mut staging[start..start + line_len];
+            let name_end = process_name.len() + 1;
+            line[0] = b'[';
+            line[1..name_end].copy_from_slice(process_name.as_bytes());
+            line[name_end] = b']';
+            line[name_end + 1] = b' ';
+            line[name_end + 2..name_end + 2 + data.len()].copy_from_slice(data);
+            if newline {
+                line[line_len - 1] = b'\n';
+            }
+            self.staging_len.set(start + line_len);
+            true
+        });
+        if !staged {
+            self.dropped
+                .set(self.dropped.get().saturating_add(data.len()));
+        }
+        self.flush();
+    }
+}
+
+impl<'a, L: LogRead<'a> + LogWrite<'a>> LogWriteClient for ConsoleLog<'a, L> {
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        _length: usize,
+        _records_lost: bool,
+        _error: Result<(), ErrorCode>,
+    ) {
+        self.spare.replace(buffer);
+        self.flush();
+    }
+
+    fn sync_done(&self, _error: Result<(), ErrorCode>) {}
+
+    fn erase_done(&self, _error: Result<(), ErrorCode>) {}
+}
+
+impl<'a, L: LogRead<'a> + LogWrite<'a>> LogReadClient for ConsoleLog<'a, L> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: Result<(), ErrorCode>) {
+        let copied = self.reader.map_or(0, |appid| {
+            self.apps
+                .enter(*appid, |app| {
+                    app.buffer.mut_map_or(0, |dest| {
+                        let len = cmp::min(length, dest.len());
+                        dest[..len].copy_from_slice(&buffer[..len]);
+                        len
+                    })
+                })
+                .unwrap_or(0)
+        });
+        self.read_buffer.replace(buffer);
+        self.read_done_upcall(error, copied);
+        self.flush();
+    }
+
+    fn seek_done(&self, error: Result<(), ErrorCode>) {
+        self.read_done_upcall(error, 0);
+        self.flush();
+    }
+}
+
+impl<'a, L: LogRead<'a> + LogWrite<'a>> Driver for ConsoleLog<'a, L> {
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    core::mem::swap(&mut app.buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    core::mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // rewind
+            1 => {
+                // The log cannot seek while it appends.
+                if self.reader.is_some() || self.spare.is_none() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                match self.log.seek(self.log.log_start()) {
+                    Ok(()) => {
+                        self.reader.set(appid);
+                        CommandReturn::success()
+                    }
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            // read the next entry
+            2 => {
+                if self.reader.is_some() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                match self.read_next(appid) {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            // dropped bytes
+            3 => CommandReturn::success_u32(self.dropped.get() as u32),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Saves the last kernel panic or process fault to flash.
+//!
+//! Without a debugger attached, the text the kernel prints when it panics or
+//! when a process faults is lost. This capsule keeps a copy of it, the crash
+//! record, in a flash page reserved for it, so that it can be read after the
+//! board restarts, for example with the `crash` command of the process
+//! console.
+//!
+//! A record holds either the panic message followed by the state of the CPU
+//! and of the processes, as printed by the panic handler, or the name of the
+//! faulting process, a snapshot of the top of its stack, its registers and
+//! its memory map. Text that does not fit in the page is dropped. A new
+//! crash replaces the previous record.
+//!
+//! The panic handler cannot wait for interrupts, so panic records are only
+//! saved with flash controllers whose `write_page()` writes the page before
+//! it returns, such as the nRF52 NVMC.
+//!
+//! Record Format
+//! -------------
+//!
+//! | Offset | Size | Field                                      |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 4    | Magic, `CRSH`                              |
+//! | 4      | 1    | Kind, `1` for a panic, `2` for a fault     |
+//! | 5      | 1    | Reserved                                   |
+//! | 6      | 2    | Text length, little endian                 |
+//! | 8      |      | Text                                       |
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! let crash_dump = static_init!(
+//!     capsules::crash_dump::CrashDump<'static, nrf52840::nvmc::Nvmc>,
+//!     capsules::crash_dump::CrashDump::new(
+//!         &base_peripherals.nvmc,
+//!         255, // page at 0xFF000
+//!         static_init!(nrf52840::nvmc::NrfPage, nrf52840::nvmc::NrfPage::default())
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(&base_peripherals.nvmc, crash_dump);
+//! crash_dump.load();
+//! ```
+//!
+//! The board then calls `record_fault()` from `Platform::process_fault_hook()`,
+//! and wraps the writer of its panic handler with `panic_writer()`:
+//!
+//! ```rust
+//! let mut writer = crash_dump.panic_writer(writer);
+//! debug::panic_print(&mut writer, pi, &cortexm4::support::nop, &PROCESSES, &CHIP);
+//! writer.save();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::fmt::{self, Write};
+use kernel::common::cells::TakeCell;
+use kernel::debug::IoWrite;
+use kernel::hil;
+use kernel::procs::Process;
+use kernel::ErrorCode;
+
+/// Magic bytes at the start of a crash record.
+const MAGIC: [u8; 4] = *b"CRSH";
+/// Length of the record header in bytes.
+const HEADER_LENGTH: usize = 8;
+/// Bytes of the stack of a faulting process saved in its record.
+const STACK_SNAPSHOT_LENGTH: usize = 128;
+
+/// What caused a crash record.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CrashKind {
+    Panic = 1,
+    ProcessFault = 2,
+}
+
+/// Access to the last crash record, for consoles.
+pub trait CrashRecords {
+    /// Call `f` with the kind and text of the last crash record. Returns
+    /// `false` if there is no record, or if it cannot be read now.
+    fn last_record(&self, f: &mut dyn FnMut(CrashKind, &[u8])) -> bool;
+
+    /// Erase the last crash record.
+    fn clear(&self) -> Result<(), ErrorCode>;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Loading,
+    Writing,
+    Erasing,
+}
+
+/// Formats text into a record, dropping what does not fit.
+struct RecordWriter<'b> {
+    page: &'b mut [u8],
+    length: usize,
+}
+
+impl<'b> RecordWriter<'b> {
+    /// Start a record of `kind` in `page`.
+    fn new(page: &'b mut [u8], kind: CrashKind) -> RecordWriter<'b> {
+        page[..4].copy_from_slice(&MAGIC);
+        page[4] = kind as u8;
+        page[5] = 0;
+        RecordWriter {
+            page: page,
+            length: 0,
+        }
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        let capacity = cmp::min(self.page.len() - HEADER_LENGTH, u16::MAX as usize);
+        let len = cmp::min(bytes.len(), capacity - self.length);
+        let start = HEADER_LENGTH + self.length;
+        self.page[start..start + len].copy_from_slice(&bytes[..len]);
+        self.length += len;
+    }
+
+    /// Write the text length in the header.
+    fn finish(&mut self) {
+        self.page[6..8].copy_from_slice(&(self.length as u16).to_le_bytes());
+    }
+}
+
+impl<'b> Write for RecordWriter<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.append(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub struct CrashDump<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    page_number: usize,
+    /// Copy of the record in flash, while no operation is in progress.
+    page: TakeCell<'static, F::Page>,
+    state: Cell<State>,
+}
+
+impl<'a, F: hil::flash::Flash> CrashDump<'a, F> {
+    /// Keep the record in page `page_number` of `flash`, which must be
+    /// reserved for it.
+    pub fn new(flash: &'a F, page_number: usize, page: &'static mut F::Page) -> CrashDump<'a, F> {
+        CrashDump {
+            flash: flash,
+            page_number: page_number,
+            page: TakeCell::new(page),
+            state: Cell::new(State::Idle),
+        }
+    }
+
+    /// Read the record saved in flash, usually at boot.
+    pub fn load(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let page = self.page.take().ok_or(ErrorCode::BUSY)?;
+        match self.flash.read_page(self.page_number, page) {
+            Ok(()) => {
+                self.state.set(State::Loading);
+                Ok(())
+            }
+            Err((e, page)) => {
+                self.page.replace(page);
+                Err(e)
+            }
+        }
+    }
+
+    /// Save a record of the fault of `process`. The record is not saved if
+    /// the flash is busy with a previous record.
+    pub fn record_fault(&self, process: &dyn Process) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let page = self.page.take().ok_or(ErrorCode::BUSY)?;
+        {
+            let mut record = RecordWriter::new(page.as_mut(), CrashKind::ProcessFault);
+            let _ = write!(record, "Process {} faulted\r\n", process.get_process_name());
+            let mut stack = [0; STACK_SNAPSHOT_LENGTH];
+            if let Some((stack_pointer, len)) = process.debug_stack_snapshot(&mut stack) {
+                let _ = write!(record, "\r\nStack at {:p}:", stack_pointer);
+                for (i, word) in stack[..len].chunks(4).enumerate() {
+                    if i % 4 == 0 {
+                        let _ = write!(record, "\r\n ");
+                    }
+                    let _ = write!(record, " ");
+                    for byte in word.iter().rev() {
+                        let _ = write!(record, "{:02x}", byte);
+                    }
+                }
+                let _ = write!(record, "\r\n");
+            }
+            process.print_full_process(&mut record);
+            record.finish();
+        }
+        self.write(page)
+    }
+
+    /// Wrap the writer of the panic handler, so that what it prints is also
+    /// saved by `PanicWriter::save()`.
+    pub fn panic_writer<'b, W: Write + IoWrite>(
+        &'b self,
+        writer: &'b mut W,
+    ) -> PanicWriter<'a, 'b, F, W> {
+        // If a write is in progress, the panic is not recorded.
+        let page = self.page.take();
+        PanicWriter {
+            crash_dump: self,
+            writer: writer,
+            page: page,
+            length: 0,
+        }
+    }
+
+    fn write(&self, page: &'static mut F::Page) -> Result<(), ErrorCode> {
+        match self.flash.write_page(self.page_number, page) {
+            Ok(()) => {
+                self.state.set(State::Writing);
+                Ok(())
+            }
+            Err((e, page)) => {
+                self.page.replace(page);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'a, F: hil::flash::Flash> CrashRecords for CrashDump<'a, F> {
+    fn last_record(&self, f: &mut dyn FnMut(CrashKind, &[u8])) -> bool {
+        self.page
+            .map(|page| {
+                let page = page.as_mut();
+                let length = u16::from_le_bytes([page[6], page[7]]) as usize;
+                if page[..4] != MAGIC || HEADER_LENGTH + length > page.len() {
+                    return false;
+                }
+                let kind = match page[4] {
+                    1 => CrashKind::Panic,
+                    2 => CrashKind::ProcessFault,
+                    _ => return false,
+                };
+                f(kind, &page[HEADER_LENGTH..HEADER_LENGTH + length]);
+                true
+            })
+            .unwrap_or(false)
+    }
+
+    fn clear(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.flash.erase_page(self.page_number)?;
+        self.page.map(|page| {
+            for byte in page.as_mut()[..HEADER_LENGTH].iter_mut() {
+                *byte = 0xff;
+            }
+        });
+        self.state.set(State::Erasing);
+        Ok(())
+    }
+}
+
+impl<'a, F: hil::flash::Flash> hil::flash::Client<F> for CrashDump<'a, F> {
+    fn read_complete(&self, read_buffer: &'static mut F::Page, _error: hil::flash::Error) {
+        self.page.replace(read_buffer);
+        self.state.set(State::Idle);
+    }
+
+    fn write_complete(&self, write_buffer: &'static mut F::Page, _error: hil::flash::Error) {
+        self.page.replace(write_buffer);
+        self.state.set(State::Idle);
+    }
+
+    fn erase_complete(&self, _error: hil::flash::Error) {
+        self.state.set(State::Idle);
+    }
+}
+
+/// Writer for the panic handler that also builds a panic record.
+pub struct PanicWriter<'a, 'b, F: hil::flash::Flash + 'static, W: Write + IoWrite> {
+    crash_dump: &'b CrashDump<'a, F>,
+    writer: &'b mut W,
+    page: Option<&'static mut F::Page>,
+    length: usize,
+}
+
+impl<'a, 'b, F: hil::flash::Flash, W: Write + IoWrite> PanicWriter<'a, 'b, F, W> {
+    fn append(&mut self, bytes: &[u8]) {
+        let length = self.length;
+        if let Some(page) = self.page.as_mut() {
+            let mut record = RecordWriter {
+                page: page.as_mut(),
+                length: length,
+            };
+            record.append(bytes);
+            self.length = record.length;
+        }
+    }
+
+    /// Write the panic record to flash.
+    pub fn save(self) {
+        if let Some(page) = self.page {
+            let mut record = RecordWriter::new(page.as_mut(), CrashKind::Panic);
+            record.length = self.length;
+            record.finish();
+            let _ = self.crash_dump.write(page);
+        }
+    }
+}
+
+impl<'a, 'b, F: hil::flash::Flash, W: Write + IoWrite> Write for PanicWriter<'a, 'b, F, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.append(s.as_bytes());
+        self.writer.write_str(s)
+    }
+}
+
+impl<'a, 'b, F: hil::flash::Flash, W: Write + IoWrite> IoWrite for PanicWriter<'a, 'b, F, W> {
+    fn write(&mut self, buf: &[u8]) {
+        self.append(buf);
+        self.writer.write(buf);
+    }
+}
@@ -0,0 +1,351 @@
+//! CTR_DRBG cryptographically secure random number generator.
+//!
+//! NIST SP 800-90A, section 10.2.1, with AES-128 and without a derivation
+//! function. The generator is seeded with 256 bits from an `Entropy32`
+//! source, and reseeded from it every `RESEED_INTERVAL` requests. Each
+//! request generates random output from the buffer of the generator less
+//! 32 bytes, and then updates the internal state, so earlier output cannot
+//! be recovered from it. The generator implements `hil::rng::Rng`, so it can
+//! replace a raw entropy source for other capsules and for the RNG syscall
+//! driver.
+//!
+//! The block cipher runs on AES hardware in CTR mode, starting from the
+//! block after `V`. Each pass of the hardware is split so the low 32 bits of
+//! the counter do not wrap within it, so hardware that only increments those
+//! bits produces the same blocks.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::hil::rng::Rng;
+//! # use kernel::static_init;
+//!
+//! let drbg_buffer = static_init!([u8; 96], [0; 96]);
+//! let drbg = static_init!(
+//!     capsules::ctr_drbg::CtrDrbg<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::ctr_drbg::CtrDrbg::new(&sam4l::aes::AES, entropy_source, drbg_buffer)
+//! );
+//! let rng = static_init!(
+//!     capsules::rng::RngDriver<'static>,
+//!     capsules::rng::RngDriver::new(drbg, board_kernel.create_grant(&grant_cap)),
+//! );
+//! drbg.set_client(rng);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::entropy::{self, Entropy32};
+use kernel::hil::rng::{self, Rng};
+use kernel::hil::symmetric_encryption::{
+    self, AES128Ctr, AES128, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+};
+use kernel::ErrorCode;
+
+/// Length of the seed and of the internal state, `Key || V`.
+const SEED_LEN: usize = AES128_KEY_SIZE + AES128_BLOCK_SIZE;
+
+/// Number of requests served between reseeds.
+pub const RESEED_INTERVAL: u32 = 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Collecting the seed from the entropy source.
+    Seeding,
+    /// Updating the state with the seed.
+    Reseeding,
+    /// Generating output and then updating the state.
+    Generating,
+}
+
+pub struct CtrDrbg<'a, A: AES128<'a> + AES128Ctr> {
+    aes: &'a A,
+    egen: &'a dyn Entropy32<'a>,
+    client: OptionalCell<&'a dyn rng::Client>,
+    buffer: TakeCell<'a, [u8]>,
+    state: Cell<State>,
+    cancelled: Cell<bool>,
+    // Whether `get()` was called while a request was running.
+    again: Cell<bool>,
+
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    v: Cell<u128>,
+    // Requests since the last reseed, or 0 before the first seed.
+    reseed_counter: Cell<u32>,
+    seed: Cell<[u8; SEED_LEN]>,
+    seed_len: Cell<usize>,
+
+    // The part of the buffer the current pass of the hardware fills.
+    pass_offset: Cell<usize>,
+    pass_end: Cell<usize>,
+    chunk_len: Cell<usize>,
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> CtrDrbg<'a, A> {
+    /// `buffer` must be a whole number of blocks, and longer than 32 bytes.
+    pub fn new(aes: &'a A, egen: &'a dyn Entropy32<'a>, buffer: &'a mut [u8]) -> CtrDrbg<'a, A> {
+        CtrDrbg {
+            aes: aes,
+            egen: egen,
+            client: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            cancelled: Cell::new(false),
+            again: Cell::new(false),
+            key: Cell::new([0; AES128_KEY_SIZE]),
+            v: Cell::new(0),
+            reseed_counter: Cell::new(0),
+            seed: Cell::new([0; SEED_LEN]),
+            seed_len: Cell::new(0),
+            pass_offset: Cell::new(0),
+            pass_end: Cell::new(0),
+            chunk_len: Cell::new(0),
+        }
+    }
+
+    /// Serve a request, reseeding first if needed.
+    fn start(&self) -> Result<(), ErrorCode> {
+        let counter = self.reseed_counter.get();
+        if counter == 0 || counter > RESEED_INTERVAL {
+            self.seed_len.set(0);
+            self.state.set(State::Seeding);
+            self.egen.get()
+        } else {
+            let len = self.buffer.map_or(0, |buffer| buffer.len());
+            if len <= SEED_LEN || len % AES128_BLOCK_SIZE != 0 {
+                return Err(ErrorCode::SIZE);
+            }
+            self.state.set(State::Generating);
+            self.run_pass(0, len)
+        }
+    }
+
+    /// Fill `buffer[start..end]` with the blocks after `V`, advancing `V`.
+    fn run_pass(&self, start: usize, end: usize) -> Result<(), ErrorCode> {
+        self.pass_offset.set(start);
+        self.pass_end.set(end);
+        self.aes.enable();
+        self.aes.set_mode_aes128ctr(true);
+        self.crypt_next()
+    }
+
+    fn crypt_next(&self) -> Result<(), ErrorCode> {
+        let offset = self.pass_offset.get();
+        let counter = self.v.get().wrapping_add(1);
+        // Stop before the low 32 bits of the counter wrap.
+        let wrap_blocks = (1 << 32) - (counter as u32 as u64);
+        let blocks = cmp::min(
+            ((self.pass_end.get() - offset) / AES128_BLOCK_SIZE) as u64,
+            wrap_blocks,
+        );
+        let len = blocks as usize * AES128_BLOCK_SIZE;
+
+        self.aes.set_key(&self.key.get())?;
+        self.aes.set_iv(&counter.to_be_bytes())?;
+        self.aes.start_message();
+        self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
+            for b in buffer[offset..offset + len].iter_mut() {
+                *b = 0;
+            }
+            self.chunk_len.set(len);
+            match self.aes.crypt(None, buffer, offset, offset + len) {
+                None => Ok(()),
+                Some((res, _, buffer)) => {
+                    self.buffer.replace(buffer);
+                    res.and(Err(ErrorCode::FAIL))
+                }
+            }
+        })
+    }
+
+    /// Take the new `Key || V` from `temp`.
+    fn update(&self, temp: &[u8]) {
+        let mut key = [0; AES128_KEY_SIZE];
+        key.copy_from_slice(&temp[..AES128_KEY_SIZE]);
+        let mut v = [0; AES128_BLOCK_SIZE];
+        v.copy_from_slice(&temp[AES128_KEY_SIZE..SEED_LEN]);
+        self.key.set(key);
+        self.v.set(u128::from_be_bytes(v));
+    }
+
+    /// Handle the end of a pass of the hardware over the buffer.
+    fn pass_done(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Reseeding => {
+                self.buffer.map(|buffer| {
+                    for (b, s) in buffer[..SEED_LEN].iter_mut().zip(self.seed.get().iter()) {
+                        *b ^= *s;
+                    }
+                    self.update(&buffer[..SEED_LEN]);
+                    for b in buffer[..SEED_LEN].iter_mut() {
+                        *b = 0;
+                    }
+                });
+                self.seed.set([0; SEED_LEN]);
+                self.reseed_counter.set(1);
+                self.start()
+            }
+            State::Generating => {
+                self.aes.disable();
+                self.again.set(false);
+                let more = self.buffer.map_or(rng::Continue::Done, |buffer| {
+                    let output_len = buffer.len() - SEED_LEN;
+                    self.update(&buffer[output_len..]);
+                    self.reseed_counter.set(self.reseed_counter.get() + 1);
+
+                    let error = if self.cancelled.get() {
+                        Err(ErrorCode::CANCEL)
+                    } else {
+                        Ok(())
+                    };
+                    let more = self.client.map_or(rng::Continue::Done, |client| {
+                        let mut output = DrbgIter(buffer[..output_len].chunks_exact(4));
+                        client.randomness_available(&mut output, error)
+                    });
+                    // Output is never handed out twice.
+                    for b in buffer.iter_mut() {
+                        *b = 0;
+                    }
+                    more
+                });
+                if (more == rng::Continue::More || self.again.get()) && !self.cancelled.get() {
+                    self.start()
+                } else {
+                    self.state.set(State::Idle);
+                    Ok(())
+                }
+            }
+            State::Idle | State::Seeding => Ok(()),
+        }
+    }
+
+    /// End the request with `error`.
+    fn fail(&self, error: ErrorCode) {
+        self.aes.disable();
+        self.state.set(State::Idle);
+        let error = if self.cancelled.get() {
+            ErrorCode::CANCEL
+        } else {
+            error
+        };
+        self.client.map(|client| {
+            client.randomness_available(&mut core::iter::empty(), Err(error));
+        });
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> Rng<'a> for CtrDrbg<'a, A> {
+    fn get(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            // Serve another request once the running one is done.
+            self.again.set(true);
+            return Ok(());
+        }
+        self.cancelled.set(false);
+        self.start().map_err(|_| {
+            self.aes.disable();
+            self.state.set(State::Idle);
+            ErrorCode::FAIL
+        })
+    }
+
+    fn cancel(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Idle => Ok(()),
+            State::Seeding if self.egen.cancel().is_ok() => {
+                self.state.set(State::Idle);
+                Ok(())
+            }
+            _ => {
+                self.cancelled.set(true);
+                Err(ErrorCode::FAIL)
+            }
+        }
+    }
+
+    /// Also makes the generator the client of the entropy source and of the
+    /// AES hardware.
+    fn set_client(&'a self, client: &'a dyn rng::Client) {
+        self.egen.set_client(self);
+        self.aes.set_client(self);
+        self.client.set(client);
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> entropy::Client32 for CtrDrbg<'a, A> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> entropy::Continue {
+        if self.state.get() != State::Seeding {
+            return entropy::Continue::Done;
+        }
+        if let Err(e) = error {
+            self.fail(e);
+            return entropy::Continue::Done;
+        }
+
+        let mut seed = self.seed.get();
+        let mut seed_len = self.seed_len.get();
+        while seed_len < SEED_LEN {
+            match entropy.next() {
+                Some(word) => {
+                    seed[seed_len..seed_len + 4].copy_from_slice(&word.to_le_bytes());
+                    seed_len += 4;
+                }
+                None => break,
+            }
+        }
+        self.seed.set(seed);
+        self.seed_len.set(seed_len);
+        if seed_len < SEED_LEN {
+            return entropy::Continue::More;
+        }
+
+        // Instantiate from `Key = 0, V = 0`, or reseed from the current
+        // state.
+        if self.reseed_counter.get() == 0 {
+            self.key.set([0; AES128_KEY_SIZE]);
+            self.v.set(0);
+        }
+        self.state.set(State::Reseeding);
+        if let Err(e) = self.run_pass(0, SEED_LEN) {
+            self.fail(e);
+        }
+        entropy::Continue::Done
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> symmetric_encryption::Client<'a> for CtrDrbg<'a, A> {
+    fn crypt_done(&'a self, _source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+        self.buffer.replace(dest);
+        let len = self.chunk_len.get();
+        self.v
+            .set(self.v.get().wrapping_add((len / AES128_BLOCK_SIZE) as u128));
+        self.pass_offset.set(self.pass_offset.get() + len);
+
+        let res = if self.pass_offset.get() < self.pass_end.get() {
+            self.crypt_next()
+        } else {
+            self.pass_done()
+        };
+        if let Err(e) = res {
+            self.fail(e);
+        }
+    }
+}
+
+struct DrbgIter<'b>(core::slice::ChunksExact<'b, u8>);
+
+impl Iterator for DrbgIter<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        self.0
+            .next()
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+    }
+}
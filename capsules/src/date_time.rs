@@ -0,0 +1,197 @@
+//! Provides userspace with the calendar date and time of a real-time clock.
+//!
+//! Processes can read the date and time to timestamp data, set it, and set
+//! an alarm at a date and time. The real-time clock usually keeps counting
+//! across resets, so unlike the `Alarm` driver this one can schedule work at
+//! absolute times. Each process has at most one alarm; the capsule arms the
+//! clock for the earliest one.
+//!
+//! Dates are packed as `year << 16 | month << 8 | day`, and times of day as
+//! `hour << 16 | minute << 8 | second`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! peripherals.rtc.init(stm32f4xx::rcc::RtcClockSource::LSE);
+//! let date_time = static_init!(
+//!     capsules::date_time::DateTimeDriver<'static, stm32f4xx::rtc::Rtc>,
+//!     capsules::date_time::DateTimeDriver::new(
+//!         &peripherals.rtc,
+//!         board_kernel.create_grant(&memory_allocation_capability)
+//!     )
+//! );
+//! hil::date_time::DateTime::set_client(&peripherals.rtc, date_time);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: Alarm upcall, with the date and time at which it fired.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get the date and time. Returns `OFF` if they were never set.
+//! - `2`: Set the date to `data1` and the time to `data2`.
+//! - `3`: Set this process's alarm at the date `data1` and time `data2`,
+//!   replacing its previous alarm. Returns `INVAL` for times that are not in
+//!   the future.
+//! - `4`: Cancel this process's alarm.
+
+use core::cell::Cell;
+use core::mem;
+use kernel::hil::date_time::{DateTime, DateTimeClient, DateTimeValues};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::DateTime as usize;
+
+fn pack(date_time: DateTimeValues) -> (u32, u32) {
+    (
+        (date_time.year as u32) << 16 | (date_time.month as u32) << 8 | date_time.day as u32,
+        (date_time.hour as u32) << 16 | (date_time.minute as u32) << 8 | date_time.second as u32,
+    )
+}
+
+fn unpack(date: usize, time: usize) -> DateTimeValues {
+    DateTimeValues {
+        year: (date >> 16) as u16,
+        month: (date >> 8) as u8,
+        day: date as u8,
+        hour: (time >> 16) as u8,
+        minute: (time >> 8) as u8,
+        second: time as u8,
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    alarm: Option<DateTimeValues>,
+}
+
+pub struct DateTimeDriver<'a, D: DateTime<'a>> {
+    clock: &'a D,
+    apps: Grant<App>,
+}
+
+impl<'a, D: DateTime<'a>> DateTimeDriver<'a, D> {
+    pub fn new(clock: &'a D, grant: Grant<App>) -> DateTimeDriver<'a, D> {
+        DateTimeDriver {
+            clock: clock,
+            apps: grant,
+        }
+    }
+
+    /// Arm the clock for the earliest alarm of all processes, or disarm it.
+    fn rearm(&self) -> Result<(), ErrorCode> {
+        let earliest: Cell<Option<DateTimeValues>> = Cell::new(None);
+        self.apps.each(|_, app| {
+            if let Some(at) = app.alarm {
+                if earliest.get().map_or(true, |e| at < e) {
+                    earliest.set(Some(at));
+                }
+            }
+        });
+        match earliest.get() {
+            Some(at) => self.clock.set_alarm(at),
+            None => self.clock.disarm(),
+        }
+    }
+
+    fn set_alarm(&self, at: DateTimeValues, appid: ProcessId) -> Result<(), ErrorCode> {
+        if !at.is_valid() || at <= self.clock.get_date_time()? {
+            return Err(ErrorCode::INVAL);
+        }
+        self.apps.enter(appid, |app| app.alarm = Some(at))?;
+        self.rearm()
+    }
+}
+
+impl<'a, D: DateTime<'a>> DateTimeClient for DateTimeDriver<'a, D> {
+    fn alarm(&self) {
+        let now = match self.clock.get_date_time() {
+            Ok(now) => now,
+            Err(_) => return,
+        };
+        let (date, time) = pack(now);
+        // The clock may compare only part of the date, so alarms further
+        // ahead stay armed.
+        self.apps.each(|_, app| {
+            if app.alarm.map_or(false, |at| at <= now) {
+                app.alarm = None;
+                app.callback.schedule(date as usize, time as usize, 0);
+            }
+        });
+        let _ = self.rearm();
+    }
+}
+
+impl<'a, D: DateTime<'a>> Driver for DateTimeDriver<'a, D> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // get date and time
+            1 => match self.clock.get_date_time() {
+                Ok(now) => {
+                    let (date, time) = pack(now);
+                    CommandReturn::success_u32_u32(date, time)
+                }
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // set date and time
+            2 => self.clock.set_date_time(unpack(data1, data2)).into(),
+
+            // set alarm
+            3 => self.set_alarm(unpack(data1, data2), appid).into(),
+
+            // cancel alarm
+            4 => {
+                let res = self
+                    .apps
+                    .enter(appid, |app| app.alarm = None)
+                    .map_err(ErrorCode::from)
+                    .and_then(|()| self.rearm());
+                res.into()
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Lets processes declare deadlines to an earliest-deadline-first scheduler.
+//!
+//! A process that must react in bounded time, such as a control loop or an
+//! audio pipeline, declares a job with a deadline, and optionally a period
+//! with which the job repeats. While its job is released and it is ready, it
+//! runs before processes whose deadlines are later and before processes
+//! without jobs. When it finishes the job, it tells the scheduler, which
+//! releases the next job one period after the previous one.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let scheduler = components::sched::edf::EdfComponent::new(board_kernel, mux_alarm)
+//!     .finalize(components::edf_component_helper!(
+//!         nrf52::rtc::Rtc<'static>,
+//!         NUM_PROCS
+//!     ));
+//! let deadline = static_init!(
+//!     capsules::deadline::DeadlineDriver<'static>,
+//!     capsules::deadline::DeadlineDriver::new(scheduler)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Release a job now, which must finish within `data1` microseconds.
+//!   If `data2` is not zero, a new job is released every `data2`
+//!   microseconds. Returns `INVAL` if the deadline is zero or longer than the
+//!   period.
+//! - `2`: The current job finished. Returns `1` if it finished after its
+//!   deadline and `0` otherwise, `INVAL` without a job, and `ALREADY` if the
+//!   next periodic job is not released yet.
+//! - `3`: Remove the jobs of the process.
+
+use kernel::{CommandReturn, DeadlineScheduler, Driver, ErrorCode, ProcessId};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Deadline as usize;
+
+pub struct DeadlineDriver<'a> {
+    scheduler: &'a dyn DeadlineScheduler,
+}
+
+impl<'a> DeadlineDriver<'a> {
+    pub fn new(scheduler: &'a dyn DeadlineScheduler) -> DeadlineDriver<'a> {
+        DeadlineDriver {
+            scheduler: scheduler,
+        }
+    }
+}
+
+impl Driver for DeadlineDriver<'_> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // release a job
+            1 => self
+                .scheduler
+                .set_job(appid, data1 as u32, data2 as u32)
+                .into(),
+
+            // finish the current job
+            2 => match self.scheduler.finish_job(appid) {
+                Ok(missed) => CommandReturn::success_u32(missed as u32),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // remove jobs
+            3 => {
+                self.scheduler.clear_jobs(appid);
+                CommandReturn::success()
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -19,6 +19,12 @@ pub enum NUM {
 
     // Kernel
     Ipc                   = 0x10000,
+    Deadline              = 0x10001,
+    ProcessStats          = 0x10002,
+    FaultReason           = 0x10003,
+    Watchdog              = 0x10004,
+    IpcQueue              = 0x10005,
+    SharedMemory          = 0x10006,
 
     // HW Buses
     Spi                   = 0x20001,
@@ -26,22 +32,39 @@ pub enum NUM {
     I2cMaster             = 0x20003,
     UsbUser               = 0x20005,
     I2cMasterSlave        = 0x20006,
+    OneWire               = 0x20007,
+    Can                   = 0x20008,
+    I2s                   = 0x20009,
+    SMBus                 = 0x2000A,
+    ModbusRtu             = 0x2000B,
 
     // Radio
     BleAdvertising        = 0x30000,
     Ieee802154            = 0x30001,
     Udp                   = 0x30002,
+    Tcp                   = 0x30003,
+    Coap                  = 0x30004,
+    LoRaWan               = 0x30005,
+    BleGatt               = 0x30006,
+    BleCentral            = 0x30007,
+    Sntp                  = 0x30008,
+    Dhcp                  = 0x30009,
+    Dns                   = 0x3000A,
 
     // Cryptography
     Rng                   = 0x40001,
     Crc                   = 0x40002,
     Hmac                  = 0x40003,
     CtapHid               = 0x40004,
+    Aes                   = 0x40006,
 
     // Storage
     AppFlash              = 0x50000,
     NvmStorage            = 0x50001,
     SdCard                = 0x50002,
+    KVStore               = 0x50003,
+    FileSystem            = 0x50004,
+    ConsoleLog            = 0x50005,
 
     // Sensors
     Temperature           = 0x60000,
@@ -50,6 +73,8 @@ pub enum NUM {
     NINEDOF               = 0x60004,
     Proximity             = 0x60005,
     SoundPressure         = 0x60006,
+    Moisture              = 0x60007,
+    Gnss                  = 0x60008,
 
     // Sensor ICs
     Tsl2561               = 0x70000,
@@ -71,5 +96,9 @@ pub enum NUM {
     Screen                = 0x90001,
     Touch                 = 0x90002,
     TextScreen            = 0x90003,
+    PwmInput              = 0x90004,
+    HidInput              = 0x90005,
+    Qdec                  = 0x90006,
+    DateTime              = 0x90007,
 }
 }
@@ -0,0 +1,509 @@
+//! Driver for DS18B20 temperature sensors on a 1-Wire bus.
+//!
+//! <https://datasheets.maximintegrated.com/en/ds/DS18B20.pdf>
+//!
+//! Devices on the bus are found with the 1-Wire ROM search and kept in a
+//! table of up to `MAX_DEVICES` ROM codes. A temperature conversion takes up
+//! to 750 ms, which the capsule waits for with its alarm before reading the
+//! scratchpad of each sensor. The bus times its slots with an alarm of its
+//! own. Sensors must be externally powered; parasite
+//! power is not supported.
+//!
+//! Besides its own syscall interface, which reads any of the sensors, the
+//! driver provides the first DS18B20 on the bus as a `TemperatureDriver`. A
+//! `TemperatureClient` cannot be told about errors, so a reading that fails
+//! after `READ_ATTEMPTS` attempts is dropped, as is one for which no sensor
+//! is found.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ds18b20_alarm = static_init!(
+//!     capsules::virtual_alarm::VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let ds18b20 = static_init!(
+//!     capsules::ds18b20::Ds18b20<
+//!         'static,
+//!         capsules::one_wire::GpioOneWire<
+//!             'static,
+//!             sam4l::gpio::GPIOPin,
+//!             capsules::virtual_alarm::VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!         >,
+//!         capsules::virtual_alarm::VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     >,
+//!     capsules::ds18b20::Ds18b20::new(
+//!         one_wire,
+//!         ds18b20_alarm,
+//!         &mut capsules::ds18b20::ROMS,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! one_wire.set_client(ds18b20);
+//! ds18b20_alarm.set_alarm_client(ds18b20);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: Reading upcall. Called once per sensor read with a status code,
+//!   the index of the sensor, and its temperature in hundredths of a degree
+//!   Celsius (as a signed 32-bit value). The status is `NODEVICE` if the
+//!   sensor did not respond and `FAIL` if its scratchpad CRC was invalid.
+//! - `1`: Search upcall. Called when a search started with command `1`
+//!   finishes, with a status code and the number of devices found. The
+//!   status is `NODEVICE` if no device answered the reset pulse and `FAIL`
+//!   if the search was corrupted.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Search the bus, replacing the device table. The result is
+//!   reported with the search upcall.
+//! - `2`: Get the ROM code of the device at index `data1`, as its lower and
+//!   upper 32 bits.
+//! - `3`: Start a temperature conversion on all sensors. Each sensor found by
+//!   the last search is reported with the reading upcall.
+//! - `4`: Start a temperature conversion on the sensor at index `data1`.
+
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::one_wire::{self, OneWire};
+use kernel::hil::sensors;
+use kernel::hil::time;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::OneWire as usize;
+
+/// Maximum number of devices kept from a ROM search.
+pub const MAX_DEVICES: usize = 8;
+
+/// Family code of the DS18B20.
+pub const DS18B20_FAMILY: u8 = 0x28;
+
+/// Number of times a scratchpad is read for a `TemperatureClient` before the
+/// reading is dropped.
+pub const READ_ATTEMPTS: usize = 3;
+
+pub static mut ROMS: [[u8; 8]; MAX_DEVICES] = [[0; 8]; MAX_DEVICES];
+
+/// Time for a 12-bit temperature conversion.
+const CONVERSION_MS: u32 = 750;
+
+// DS18B20 function commands.
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+
+/// Convert the first two bytes of a DS18B20 scratchpad to hundredths of a
+/// degree Celsius.
+pub fn temperature_centi(lsb: u8, msb: u8) -> i32 {
+    // The sensor reports sixteenths of a degree.
+    let raw = (((msb as u16) << 8) | lsb as u16) as i16;
+    raw as i32 * 100 / 16
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Request {
+    /// Readings of all sensors, or of one, for the processes.
+    Processes(Option<usize>),
+    /// A reading of the sensor at the index for the `TemperatureClient`.
+    Client(usize),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    SearchingForProcesses,
+    /// Searching the bus for the `TemperatureClient`, which then reads the
+    /// first sensor found.
+    SearchingForClient,
+    /// Addressing the sensors and starting a conversion.
+    StartingConversion(Request),
+    /// Waiting for a conversion to finish.
+    Converting(Request),
+    /// Reading the scratchpad of the sensor at the first index, with the
+    /// number of failed attempts.
+    Reading(Request, usize, usize),
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    search_callback: Upcall,
+    pending: bool,
+    searching: bool,
+}
+
+pub struct Ds18b20<'a, W: OneWire<'a>, A: time::Alarm<'a>> {
+    bus: &'a W,
+    alarm: &'a A,
+    apps: Grant<App>,
+    devices: [Cell<Option<[u8; 8]>>; MAX_DEVICES],
+    roms: TakeCell<'static, [[u8; 8]]>,
+    state: Cell<State>,
+    scratchpad: Cell<[u8; 9]>,
+    scratchpad_len: Cell<usize>,
+    temperature_client: OptionalCell<&'a dyn sensors::TemperatureClient>,
+}
+
+impl<'a, W: OneWire<'a>, A: time::Alarm<'a>> Ds18b20<'a, W, A> {
+    pub fn new(
+        bus: &'a W,
+        alarm: &'a A,
+        roms: &'static mut [[u8; 8]],
+        grant: Grant<App>,
+    ) -> Ds18b20<'a, W, A> {
+        Ds18b20 {
+            bus: bus,
+            alarm: alarm,
+            apps: grant,
+            devices: Default::default(),
+            roms: TakeCell::new(roms),
+            state: Cell::new(State::Idle),
+            scratchpad: Cell::new([0; 9]),
+            scratchpad_len: Cell::new(0),
+            temperature_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Start enumerating the devices on the bus.
+    fn search(&self, state: State) -> Result<(), ErrorCode> {
+        let roms = self.roms.take().ok_or(ErrorCode::BUSY)?;
+        if let Err((e, roms)) = self.bus.search(roms) {
+            self.roms.replace(roms);
+            return Err(e);
+        }
+        self.state.set(state);
+        Ok(())
+    }
+
+    /// The ROM code of the sensor at `index`.
+    fn sensor(&self, index: usize) -> Result<[u8; 8], ErrorCode> {
+        match self.devices.get(index).and_then(|d| d.get()) {
+            Some(rom) if rom[0] == DS18B20_FAMILY => Ok(rom),
+            Some(_) => Err(ErrorCode::NOSUPPORT),
+            None => Err(ErrorCode::INVAL),
+        }
+    }
+
+    /// The index of the first sensor from `from` on that `target` selects,
+    /// or any sensor if it is `None`.
+    fn next_sensor(&self, target: Option<usize>, from: usize) -> Option<usize> {
+        (from..MAX_DEVICES)
+            .find(|&index| target.map_or(true, |t| t == index) && self.sensor(index).is_ok())
+    }
+
+    /// Start a conversion on the sensors of `request`.
+    fn start_conversion(&self, request: Request) -> Result<(), ErrorCode> {
+        let rom = match request {
+            Request::Processes(None) => None,
+            Request::Processes(Some(index)) | Request::Client(index) => Some(self.sensor(index)?),
+        };
+        self.bus.select(rom)?;
+        self.state.set(State::StartingConversion(request));
+        Ok(())
+    }
+
+    /// Start a conversion for the process `appid`.
+    fn convert(&self, index: Option<usize>, appid: ProcessId) -> CommandReturn {
+        if self.state.get() != State::Idle {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+        if let Err(e) = self.apps.enter(appid, |_| ()) {
+            return CommandReturn::failure(e.into());
+        }
+        if let Err(e) = self.start_conversion(Request::Processes(index)) {
+            return CommandReturn::failure(e);
+        }
+        let _ = self.apps.enter(appid, |app| app.pending = true);
+        CommandReturn::success()
+    }
+
+    /// The conversion could not be started: fail the readings it was for.
+    fn conversion_failed(&self, request: Request, error: ErrorCode) {
+        self.state.set(State::Idle);
+        if let Request::Processes(target) = request {
+            let mut next = self.next_sensor(target, 0);
+            while let Some(index) = next {
+                self.report(index, Err(error));
+                next = self.next_sensor(target, index + 1);
+            }
+            self.apps.each(|_, app| app.pending = false);
+        }
+    }
+
+    /// Start reading the scratchpad of the sensor at `index`.
+    fn start_read(&self, request: Request, index: usize, attempt: usize) {
+        self.state.set(State::Reading(request, index, attempt));
+        let result = self
+            .sensor(index)
+            .and_then(|rom| self.bus.select(Some(rom)));
+        if let Err(e) = result {
+            self.read_done(Err(e));
+        }
+    }
+
+    /// A scratchpad has been read, or could not be: report the reading and
+    /// move on to the next sensor.
+    fn read_done(&self, result: Result<i32, ErrorCode>) {
+        let (request, index, attempt) = match self.state.get() {
+            State::Reading(request, index, attempt) => (request, index, attempt),
+            _ => return,
+        };
+        match request {
+            Request::Processes(target) => {
+                self.report(index, result);
+                match self.next_sensor(target, index + 1) {
+                    Some(next) => self.start_read(request, next, 0),
+                    None => {
+                        self.state.set(State::Idle);
+                        self.apps.each(|_, app| app.pending = false);
+                    }
+                }
+            }
+            Request::Client(_) => match result {
+                Ok(temperature) => {
+                    self.state.set(State::Idle);
+                    self.temperature_client
+                        .map(|client| client.callback(temperature as usize));
+                }
+                Err(_) if attempt + 1 < READ_ATTEMPTS => {
+                    self.start_read(request, index, attempt + 1)
+                }
+                Err(_) => self.state.set(State::Idle),
+            },
+        }
+    }
+
+    fn report(&self, index: usize, result: Result<i32, ErrorCode>) {
+        let (status, temperature) = match result {
+            Ok(t) => (kernel::into_statuscode(Ok(())), t),
+            Err(e) => (kernel::into_statuscode(Err(e)), 0),
+        };
+        self.apps.each(|_, app| {
+            if app.pending {
+                app.callback.schedule(status, index, temperature as usize);
+            }
+        });
+    }
+
+    /// The index of the first DS18B20 in the device table.
+    fn first_sensor(&self) -> Option<usize> {
+        self.next_sensor(None, 0)
+    }
+}
+
+impl<'a, W: OneWire<'a>, A: time::Alarm<'a>> one_wire::Client for Ds18b20<'a, W, A> {
+    fn reset_done(&self, _present: bool) {}
+
+    fn write_done(&self) {
+        match self.state.get() {
+            // CONVERT_T has been sent.
+            State::StartingConversion(request) => {
+                self.state.set(State::Converting(request));
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(CONVERSION_MS));
+            }
+            // READ_SCRATCHPAD has been sent.
+            State::Reading(..) => {
+                self.scratchpad_len.set(0);
+                if let Err(e) = self.bus.read_byte() {
+                    self.read_done(Err(e));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_bit_done(&self, _bit: bool) {}
+
+    fn read_byte_done(&self, byte: u8) {
+        if let State::Reading(..) = self.state.get() {
+            let mut scratchpad = self.scratchpad.get();
+            let len = self.scratchpad_len.get();
+            scratchpad[len] = byte;
+            self.scratchpad.set(scratchpad);
+            self.scratchpad_len.set(len + 1);
+            if len + 1 < scratchpad.len() {
+                if let Err(e) = self.bus.read_byte() {
+                    self.read_done(Err(e));
+                }
+            } else if one_wire::crc8(&scratchpad[..8]) != scratchpad[8] {
+                self.read_done(Err(ErrorCode::FAIL));
+            } else {
+                self.read_done(Ok(temperature_centi(scratchpad[0], scratchpad[1])));
+            }
+        }
+    }
+
+    fn select_done(&self, result: Result<(), ErrorCode>) {
+        match self.state.get() {
+            State::StartingConversion(request) => {
+                if let Err(e) = result.and_then(|()| self.bus.write_byte(CONVERT_T)) {
+                    self.conversion_failed(request, e);
+                }
+            }
+            State::Reading(..) => {
+                if let Err(e) = result.and_then(|()| self.bus.write_byte(READ_SCRATCHPAD)) {
+                    self.read_done(Err(e));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Replace the device table with the devices found.
+    fn search_done(&self, roms: &'static mut [[u8; 8]], result: Result<usize, ErrorCode>) {
+        let found = result.unwrap_or(0);
+        for (index, device) in self.devices.iter().enumerate() {
+            device.set(if index < found {
+                roms.get(index).copied()
+            } else {
+                None
+            });
+        }
+        self.roms.replace(roms);
+
+        let state = self.state.get();
+        self.state.set(State::Idle);
+        match state {
+            State::SearchingForProcesses => {
+                let status = kernel::into_statuscode(result.map(|_| ()));
+                self.apps.each(|_, app| {
+                    if app.searching {
+                        app.searching = false;
+                        app.search_callback.schedule(status, found, 0);
+                    }
+                });
+            }
+            State::SearchingForClient => {
+                // A `TemperatureClient` cannot be told that no sensor was
+                // found.
+                if let Some(index) = self.first_sensor() {
+                    let _ = self.start_conversion(Request::Client(index));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, W: OneWire<'a>, A: time::Alarm<'a>> time::AlarmClient for Ds18b20<'a, W, A> {
+    /// The conversion has finished: read the sensors.
+    fn alarm(&self) {
+        if let State::Converting(request) = self.state.get() {
+            let first = match request {
+                Request::Processes(target) => self.next_sensor(target, 0),
+                Request::Client(index) => Some(index),
+            };
+            match first {
+                Some(index) => self.start_read(request, index, 0),
+                None => {
+                    self.state.set(State::Idle);
+                    self.apps.each(|_, app| app.pending = false);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, W: OneWire<'a>, A: time::Alarm<'a>> sensors::TemperatureDriver<'a> for Ds18b20<'a, W, A> {
+    fn set_client(&self, client: &'a dyn sensors::TemperatureClient) {
+        self.temperature_client.set(client);
+    }
+
+    /// Read the first DS18B20 on the bus, searching the bus first if no
+    /// sensor is known.
+    fn read_temperature(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        match self.first_sensor() {
+            Some(index) => self.start_conversion(Request::Client(index)),
+            None => self.search(State::SearchingForClient),
+        }
+    }
+}
+
+impl<'a, W: OneWire<'a>, A: time::Alarm<'a>> Driver for Ds18b20<'a, W, A> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            1 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.search_callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        data: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // search the bus
+            1 => {
+                if self.state.get() != State::Idle {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                if let Err(e) = self.apps.enter(appid, |_| ()) {
+                    return CommandReturn::failure(e.into());
+                }
+                if let Err(e) = self.search(State::SearchingForProcesses) {
+                    return CommandReturn::failure(e);
+                }
+                let _ = self.apps.enter(appid, |app| app.searching = true);
+                CommandReturn::success()
+            }
+
+            // get ROM code
+            2 => match self.devices.get(data).and_then(|d| d.get()) {
+                Some(rom) => {
+                    let low = u32::from_le_bytes([rom[0], rom[1], rom[2], rom[3]]);
+                    let high = u32::from_le_bytes([rom[4], rom[5], rom[6], rom[7]]);
+                    CommandReturn::success_u32_u32(low, high)
+                }
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+
+            // convert all sensors
+            3 => self.convert(None, appid),
+
+            // convert one sensor
+            4 => self.convert(Some(data), appid),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! Software verification of ECDSA signatures with the NIST P-256 curve.
+//!
+//! `EcdsaP256Verifier` verifies signatures of SHA-256 digests with a public
+//! key, and `AppCheckerEcdsaP256` uses a verifier to check the credentials of
+//! apps while they are loaded, so boards can refuse to run apps that are not
+//! signed with their key.
+//!
+//! Arithmetic uses Montgomery multiplication over 32-bit limbs. It is not
+//! constant time, which verification does not need, as it only handles
+//! public data. Verifying takes tens of millions of cycles, so it is meant for
+//! checking apps at boot rather than for frequent use.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let verifier = static_init!(
+//!     capsules::ecdsa_p256::EcdsaP256Verifier,
+//!     capsules::ecdsa_p256::EcdsaP256Verifier::new(&APP_SIGNING_KEY)
+//! );
+//! let checker = static_init!(
+//!     capsules::ecdsa_p256::AppCheckerEcdsaP256<'static>,
+//!     capsules::ecdsa_p256::AppCheckerEcdsaP256::new(verifier)
+//! );
+//! let credentials_policy = static_init!(
+//!     kernel::procs::CredentialsPolicy,
+//!     kernel::procs::CredentialsPolicy::new(checker, true, &process_mgmt_cap)
+//! );
+//! kernel::procs::load_processes_checked(
+//!     board_kernel,
+//!     chip,
+//!     app_flash,
+//!     app_memory,
+//!     &mut PROCESSES,
+//!     &FAULT_RESPONSE,
+//!     credentials_policy,
+//!     &process_mgmt_cap,
+//! );
+//! ```
+
+use kernel::hil::signature::SignatureVerify;
+use kernel::procs::{AppCredentialsChecker, CheckResult};
+use kernel::ErrorCode;
+use tock_tbf::types::{TbfFooterV2Credentials, TbfFooterV2CredentialsType};
+
+use crate::sha256::Sha256;
+
+/// A 256-bit integer, as 32-bit limbs from the least significant.
+type U256 = [u32; 8];
+
+const ZERO: U256 = [0; 8];
+
+/// Modulus of the field of the curve, p = 2^256 - 2^224 + 2^192 + 2^96 - 1.
+const P: Modulus = Modulus {
+    m: [
+        0xffffffff, 0xffffffff, 0xffffffff, 0x00000000, 0x00000000, 0x00000000, 0x00000001,
+        0xffffffff,
+    ],
+    m_inv: 0x00000001,
+    r2: [
+        0x00000003, 0x00000000, 0xffffffff, 0xfffffffb, 0xfffffffe, 0xffffffff, 0xfffffffd,
+        0x00000004,
+    ],
+};
+
+/// Order of the base point, n.
+const N: Modulus = Modulus {
+    m: [
+        0xfc632551, 0xf3b9cac2, 0xa7179e84, 0xbce6faad, 0xffffffff, 0xffffffff, 0x00000000,
+        0xffffffff,
+    ],
+    m_inv: 0xee00bc4f,
+    r2: [
+        0xbe79eea2, 0x83244c95, 0x49bd6fa6, 0x4699799c, 0x2b6bec59, 0x2845b239, 0xf3d95620,
+        0x66e12d94,
+    ],
+};
+
+/// The constant b of the curve y^2 = x^3 - 3x + b.
+const B: U256 = [
+    0x27d2604b, 0x3bce3c3e, 0xcc53b0f6, 0x651d06b0, 0x769886bc, 0xb3ebbd55, 0xaa3a93e7, 0x5ac635d8,
+];
+
+/// Coordinates of the base point G.
+const GX: U256 = [
+    0xd898c296, 0xf4a13945, 0x2deb33a0, 0x77037d81, 0x63a440f2, 0xf8bce6e5, 0xe12c4247, 0x6b17d1f2,
+];
+const GY: U256 = [
+    0x37bf51f5, 0xcbb64068, 0x6b315ece, 0x2bce3357, 0x7c0f9e16, 0x8ee7eb4a, 0xfe1a7f9b, 0x4fe342e2,
+];
+
+fn from_be_bytes(bytes: &[u8]) -> U256 {
+    let mut limbs = ZERO;
+    for (i, limb) in limbs.iter_mut().enumerate() {
+        let start = 28 - i * 4;
+        *limb = u32::from_be_bytes([
+            bytes[start],
+            bytes[start + 1],
+            bytes[start + 2],
+            bytes[start + 3],
+        ]);
+    }
+    limbs
+}
+
+fn is_zero(a: &U256) -> bool {
+    a.iter().all(|limb| *limb == 0)
+}
+
+/// Returns whether `a >= b`.
+fn geq(a: &U256, b: &U256) -> bool {
+    for i in (0..8).rev() {
+        if a[i] != b[i] {
+            return a[i] > b[i];
+        }
+    }
+    true
+}
+
+/// Returns `a + b` and the carry out.
+fn add(a: &U256, b: &U256) -> (U256, u32) {
+    let mut sum = ZERO;
+    let mut carry = 0u64;
+    for i in 0..8 {
+        let x = a[i] as u64 + b[i] as u64 + carry;
+        sum[i] = x as u32;
+        carry = x >> 32;
+    }
+    (sum, carry as u32)
+}
+
+/// Returns `a - b` and whether it borrowed.
+fn sub(a: &U256, b: &U256) -> (U256, bool) {
+    let mut difference = ZERO;
+    let mut borrow = 0i64;
+    for i in 0..8 {
+        let x = a[i] as i64 - b[i] as i64 + borrow;
+        difference[i] = x as u32;
+        borrow = x >> 32;
+    }
+    (difference, borrow != 0)
+}
+
+/// Arithmetic modulo an odd 256-bit modulus, with values in Montgomery form
+/// (multiplied by R = 2^256) where noted.
+struct Modulus {
+    m: U256,
+    /// -m^-1 mod 2^32.
+    m_inv: u32,
+    /// R^2 mod m.
+    r2: U256,
+}
+
+impl Modulus {
+    fn add(&self, a: &U256, b: &U256) -> U256 {
+        let (sum, carry) = add(a, b);
+        if carry != 0 || geq(&sum, &self.m) {
+            sub(&sum, &self.m).0
+        } else {
+            sum
+        }
+    }
+
+    fn sub(&self, a: &U256, b: &U256) -> U256 {
+        let (difference, borrow) = sub(a, b);
+        if borrow {
+            add(&difference, &self.m).0
+        } else {
+            difference
+        }
+    }
+
+    /// Returns `a * b / R mod m`. `b` must be less than `m`.
+    fn mul(&self, a: &U256, b: &U256) -> U256 {
+        let mut t = [0u32; 10];
+        for i in 0..8 {
+            let mut carry = 0u64;
+            for j in 0..8 {
+                let x = t[j] as u64 + a[j] as u64 * b[i] as u64 + carry;
+                t[j] = x as u32;
+                carry = x >> 32;
+            }
+            let x = t[8] as u64 + carry;
+            t[8] = x as u32;
+            t[9] = (x >> 32) as u32;
+
+            // Add a multiple of m that makes the lowest limb zero, and shift
+            // it out.
+            let u = t[0].wrapping_mul(self.m_inv);
+            let mut carry = (t[0] as u64 + u as u64 * self.m[0] as u64) >> 32;
+            for j in 1..8 {
+                let x = t[j] as u64 + u as u64 * self.m[j] as u64 + carry;
+                t[j - 1] = x as u32;
+                carry = x >> 32;
+            }
+            let x = t[8] as u64 + carry;
+            t[7] = x as u32;
+            t[8] = t[9] + (x >> 32) as u32;
+        }
+
+        let mut result = ZERO;
+        result.copy_from_slice(&t[..8]);
+        if t[8] != 0 || geq(&result, &self.m) {
+            sub(&result, &self.m).0
+        } else {
+            result
+        }
+    }
+
+    fn to_montgomery(&self, a: &U256) -> U256 {
+        self.mul(a, &self.r2)
+    }
+
+    fn from_montgomery(&self, a: &U256) -> U256 {
+        self.mul(a, &[1, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    /// Returns `a^-1` for `a` in Montgomery form, as `a^(m-2)`.
+    fn invert(&self, a: &U256) -> U256 {
+        let exponent = sub(&self.m, &[2, 0, 0, 0, 0, 0, 0, 0]).0;
+        let mut result = self.to_montgomery(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        for i in (0..256).rev() {
+            result = self.mul(&result, &result);
+            if exponent[i / 32] >> (i % 32) & 1 == 1 {
+                result = self.mul(&result, a);
+            }
+        }
+        result
+    }
+}
+
+/// A point of the curve in Jacobian coordinates (x = X/Z^2, y = Y/Z^3), in
+/// Montgomery form. The point at infinity has Z = 0.
+#[derive(Clone, Copy)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+}
+
+impl Point {
+    const INFINITY: Point = Point {
+        x: ZERO,
+        y: ZERO,
+        z: ZERO,
+    };
+
+    /// The point with affine coordinates `x` and `y` in Montgomery form.
+    fn affine(x: U256, y: U256) -> Point {
+        Point {
+            x: x,
+            y: y,
+            z: P.to_montgomery(&[1, 0, 0, 0, 0, 0, 0, 0]),
+        }
+    }
+
+    fn is_infinity(&self) -> bool {
+        is_zero(&self.z)
+    }
+
+    fn double(&self) -> Point {
+        if self.is_infinity() {
+            return *self;
+        }
+        let delta = P.mul(&self.z, &self.z);
+        let gamma = P.mul(&self.y, &self.y);
+        let beta = P.mul(&self.x, &gamma);
+        // alpha = 3 * (x - delta) * (x + delta), as a = -3.
+        let t = P.mul(&P.sub(&self.x, &delta), &P.add(&self.x, &delta));
+        let alpha = P.add(&P.add(&t, &t), &t);
+
+        let beta4 = P.add(&P.add(&beta, &beta), &P.add(&beta, &beta));
+        let x = P.sub(&P.mul(&alpha, &alpha), &P.add(&beta4, &beta4));
+        let y_plus_z = P.add(&self.y, &self.z);
+        let z = P.sub(&P.sub(&P.mul(&y_plus_z, &y_plus_z), &gamma), &delta);
+        let gamma2 = P.mul(&gamma, &gamma);
+        let gamma2_4 = P.add(&P.add(&gamma2, &gamma2), &P.add(&gamma2, &gamma2));
+        let y = P.sub(
+            &P.mul(&alpha, &P.sub(&beta4, &x)),
+            &P.add(&gamma2_4, &gamma2_4),
+        );
+        Point { x: x, y: y, z: z }
+    }
+
+    fn add(&self, other: &Point) -> Point {
+        if self.is_infinity() {
+            return *other;
+        }
+        if other.is_infinity() {
+            return *self;
+        }
+        let z1z1 = P.mul(&self.z, &self.z);
+        let z2z2 = P.mul(&other.z, &other.z);
+        let u1 = P.mul(&self.x, &z2z2);
+        let u2 = P.mul(&other.x, &z1z1);
+        let s1 = P.mul(&P.mul(&self.y, &other.z), &z2z2);
+        let s2 = P.mul(&P.mul(&other.y, &self.z), &z1z1);
+        let h = P.sub(&u2, &u1);
+        let r = P.sub(&s2, &s1);
+        if is_zero(&h) {
+            return if is_zero(&r) {
+                self.double()
+            } else {
+                Point::INFINITY
+            };
+        }
+        let r = P.add(&r, &r);
+
+        let h2 = P.add(&h, &h);
+        let i = P.mul(&h2, &h2);
+        let j = P.mul(&h, &i);
+        let v = P.mul(&u1, &i);
+        let x = P.sub(&P.sub(&P.mul(&r, &r), &j), &P.add(&v, &v));
+        let s1j = P.mul(&s1, &j);
+        let y = P.sub(&P.mul(&r, &P.sub(&v, &x)), &P.add(&s1j, &s1j));
+        let z1_plus_z2 = P.add(&self.z, &other.z);
+        let z = P.mul(
+            &P.sub(&P.sub(&P.mul(&z1_plus_z2, &z1_plus_z2), &z1z1), &z2z2),
+            &h,
+        );
+        Point { x: x, y: y, z: z }
+    }
+
+    /// Returns `a * self + b * other`.
+    fn double_mul(&self, a: &U256, other: &Point, b: &U256) -> Point {
+        let sum = self.add(other);
+        let mut result = Point::INFINITY;
+        for i in (0..256).rev() {
+            result = result.double();
+            let bit_a = a[i / 32] >> (i % 32) & 1 == 1;
+            let bit_b = b[i / 32] >> (i % 32) & 1 == 1;
+            result = match (bit_a, bit_b) {
+                (true, true) => result.add(&sum),
+                (true, false) => result.add(self),
+                (false, true) => result.add(other),
+                (false, false) => result,
+            };
+        }
+        result
+    }
+
+    /// Returns the affine x coordinate, not in Montgomery form.
+    fn affine_x(&self) -> U256 {
+        let z_inv = P.invert(&self.z);
+        P.from_montgomery(&P.mul(&self.x, &P.mul(&z_inv, &z_inv)))
+    }
+}
+
+/// Verifies ECDSA P-256 signatures with a public key.
+pub struct EcdsaP256Verifier {
+    public_key: [u8; 64],
+}
+
+impl EcdsaP256Verifier {
+    /// Create a verifier for `public_key`: the x and y coordinates of the
+    /// key, as 32 byte big-endian integers.
+    pub fn new(public_key: &[u8; 64]) -> EcdsaP256Verifier {
+        EcdsaP256Verifier {
+            public_key: *public_key,
+        }
+    }
+
+    /// Returns the public key as a point, if it is a point of the curve.
+    fn public_point(&self) -> Option<Point> {
+        let x = from_be_bytes(&self.public_key[..32]);
+        let y = from_be_bytes(&self.public_key[32..]);
+        if geq(&x, &P.m) || geq(&y, &P.m) {
+            return None;
+        }
+        let x = P.to_montgomery(&x);
+        let y = P.to_montgomery(&y);
+
+        // Check that y^2 = x^3 - 3x + b.
+        let x3 = P.mul(&P.mul(&x, &x), &x);
+        let x_3 = P.add(&P.add(&x, &x), &x);
+        let rhs = P.add(&P.sub(&x3, &x_3), &P.to_montgomery(&B));
+        if P.mul(&y, &y) != rhs {
+            return None;
+        }
+        Some(Point::affine(x, y))
+    }
+}
+
+impl SignatureVerify for EcdsaP256Verifier {
+    /// `hash` is a SHA-256 digest, and `signature` the `r` and `s` values
+    /// as 32 byte big-endian integers. Fails with `INVAL` if the public key is
+    /// not a point of the curve.
+    fn verify(&self, hash: &[u8], signature: &[u8]) -> Result<bool, ErrorCode> {
+        if hash.len() != 32 || signature.len() != 64 {
+            return Err(ErrorCode::SIZE);
+        }
+        let public_point = self.public_point().ok_or(ErrorCode::INVAL)?;
+
+        let r = from_be_bytes(&signature[..32]);
+        let s = from_be_bytes(&signature[32..]);
+        if is_zero(&r) || is_zero(&s) || geq(&r, &N.m) || geq(&s, &N.m) {
+            return Ok(false);
+        }
+        let mut e = from_be_bytes(hash);
+        if geq(&e, &N.m) {
+            e = sub(&e, &N.m).0;
+        }
+
+        // u1 = e / s and u2 = r / s, modulo n. Multiplying a value by one in
+        // Montgomery form gives a value that is not.
+        let s_inv = N.invert(&N.to_montgomery(&s));
+        let u1 = N.mul(&e, &s_inv);
+        let u2 = N.mul(&r, &s_inv);
+
+        let base = Point::affine(P.to_montgomery(&GX), P.to_montgomery(&GY));
+        let point = base.double_mul(&u1, &public_point, &u2);
+        if point.is_infinity() {
+            return Ok(false);
+        }
+        let mut x = point.affine_x();
+        if geq(&x, &N.m) {
+            x = sub(&x, &N.m).0;
+        }
+        Ok(x == r)
+    }
+}
+
+/// Checks the ECDSA P-256 credentials of apps. Apps with a valid signature
+/// are accepted, and apps with an invalid one are rejected. Other credentials
+/// are passed on.
+pub struct AppCheckerEcdsaP256<'a> {
+    verifier: &'a dyn SignatureVerify,
+}
+
+impl<'a> AppCheckerEcdsaP256<'a> {
+    pub fn new(verifier: &'a dyn SignatureVerify) -> AppCheckerEcdsaP256<'a> {
+        AppCheckerEcdsaP256 { verifier: verifier }
+    }
+}
+
+impl AppCredentialsChecker for AppCheckerEcdsaP256<'_> {
+    fn check_credentials(
+        &self,
+        credentials: &TbfFooterV2Credentials,
+        integrity_region: &[u8],
+    ) -> CheckResult {
+        if credentials.format != TbfFooterV2CredentialsType::EcdsaNistP256 {
+            return CheckResult::Pass;
+        }
+        let signature = match credentials.data.get(..64) {
+            Some(signature) => signature,
+            None => return CheckResult::Reject,
+        };
+        let mut sha = Sha256::new();
+        sha.update(integrity_region);
+        match self.verifier.verify(&sha.finish(), signature) {
+            Ok(true) => CheckResult::Accept,
+            _ => CheckResult::Reject,
+        }
+    }
+}
@@ -0,0 +1,911 @@
+//! Driver for the Microchip ENC28J60 SPI Ethernet controller.
+//!
+//! <https://www.microchip.com/wwwproducts/en/ENC28J60>
+//!
+//! The controller has a 10 Mbit/s MAC and PHY, and 8 KB of buffer memory,
+//! split here into a ring of received frames and room for one frame to
+//! send. It is driven over SPI, one command at a time. Most control
+//! registers are in one of four banks, and the driver switches banks as
+//! needed. The controller pulls its INT pin low when it has received a
+//! frame, finished sending one, or when the link changes; the pin must be
+//! connected to an interrupt-capable GPIO pin.
+//!
+//! Every operation is a short program of commands, run one SPI transfer at
+//! a time. Operations requested while a program runs, such as a new filter
+//! or a frame to send, wait until it is done. Received frames are read from
+//! the buffer memory into the SPI read buffer and passed to the client from
+//! there, so no other buffer is needed.
+//!
+//! The PHY does not negotiate the link, so it runs in half duplex mode. The
+//! driver follows the workarounds of the silicon errata for the receive
+//! read pointer, the packet interrupt flag and the transmit logic.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let enc28j60_spi = static_init!(
+//!     capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>,
+//!     capsules::virtual_spi::VirtualSpiMasterDevice::new(mux_spi, &nrf52840::gpio::PORT[CS]));
+//! let enc28j60 = static_init!(
+//!     capsules::enc28j60::Enc28j60<'static,
+//!         capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>>,
+//!     capsules::enc28j60::Enc28j60::new(
+//!         enc28j60_spi,
+//!         &nrf52840::gpio::PORT[INT],
+//!         &mut capsules::enc28j60::SPI_BUF,
+//!         &mut capsules::enc28j60::SPI_READ_BUF,
+//!     ));
+//! enc28j60_spi.set_client(enc28j60);
+//! nrf52840::gpio::PORT[INT].set_client(enc28j60);
+//! enc28j60.initialize();
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ethernet::{self, Filter, LinkStatus, MacAddress};
+use kernel::hil::gpio;
+use kernel::hil::spi;
+use kernel::ErrorCode;
+
+/// Length of the SPI buffers: a command, the control byte written before
+/// a frame to send, and the frame.
+pub const SPI_BUF_LEN: usize = 2 + ethernet::MAX_FRAME_LEN;
+
+pub static mut SPI_BUF: [u8; SPI_BUF_LEN] = [0; SPI_BUF_LEN];
+pub static mut SPI_READ_BUF: [u8; SPI_BUF_LEN] = [0; SPI_BUF_LEN];
+
+/// MAC and MII registers cannot be read reliably below 8 MHz.
+const SPI_SPEED: u32 = 8_000_000;
+
+/// Buffer memory layout: received frames, then the frame to send.
+const RX_START: u16 = 0x0000;
+const RX_END: u16 = 0x19FF;
+const TX_START: u16 = 0x1A00;
+
+/// Length of the header the controller writes before each received frame:
+/// the address of the next frame and the receive status vector.
+const RX_HEADER_LEN: usize = 6;
+
+/// Length of the frame check sequence.
+const FCS_LEN: usize = 4;
+
+/// SPI commands.
+const RCR: u8 = 0x00;
+const RBM: u8 = 0x3A;
+const WCR: u8 = 0x40;
+const WBM: u8 = 0x7A;
+const BFS: u8 = 0x80;
+const BFC: u8 = 0xA0;
+
+/// The bank is not known until it has been selected.
+const BANK_UNKNOWN: u8 = 0xFF;
+
+/// A control register.
+#[derive(Copy, Clone, PartialEq)]
+struct Reg {
+    address: u8,
+    /// The bank of the register, or `None` for the registers common to
+    /// every bank.
+    bank: Option<u8>,
+    /// MAC and MII registers are read after a dummy byte.
+    mac: bool,
+}
+
+impl Reg {
+    const fn common(address: u8) -> Reg {
+        Reg {
+            address: address,
+            bank: None,
+            mac: false,
+        }
+    }
+
+    const fn eth(bank: u8, address: u8) -> Reg {
+        Reg {
+            address: address,
+            bank: Some(bank),
+            mac: false,
+        }
+    }
+
+    const fn mac(bank: u8, address: u8) -> Reg {
+        Reg {
+            address: address,
+            bank: Some(bank),
+            mac: true,
+        }
+    }
+}
+
+mod reg {
+    use super::Reg;
+
+    pub(super) const EIE: Reg = Reg::common(0x1B);
+    pub(super) const EIR: Reg = Reg::common(0x1C);
+    pub(super) const ESTAT: Reg = Reg::common(0x1D);
+    pub(super) const ECON2: Reg = Reg::common(0x1E);
+    pub(super) const ECON1: Reg = Reg::common(0x1F);
+
+    pub(super) const ERDPTL: Reg = Reg::eth(0, 0x00);
+    pub(super) const ERDPTH: Reg = Reg::eth(0, 0x01);
+    pub(super) const EWRPTL: Reg = Reg::eth(0, 0x02);
+    pub(super) const EWRPTH: Reg = Reg::eth(0, 0x03);
+    pub(super) const ETXSTL: Reg = Reg::eth(0, 0x04);
+    pub(super) const ETXSTH: Reg = Reg::eth(0, 0x05);
+    pub(super) const ETXNDL: Reg = Reg::eth(0, 0x06);
+    pub(super) const ETXNDH: Reg = Reg::eth(0, 0x07);
+    pub(super) const ERXSTL: Reg = Reg::eth(0, 0x08);
+    pub(super) const ERXSTH: Reg = Reg::eth(0, 0x09);
+    pub(super) const ERXNDL: Reg = Reg::eth(0, 0x0A);
+    pub(super) const ERXNDH: Reg = Reg::eth(0, 0x0B);
+    pub(super) const ERXRDPTL: Reg = Reg::eth(0, 0x0C);
+    pub(super) const ERXRDPTH: Reg = Reg::eth(0, 0x0D);
+
+    pub(super) const EHT0: Reg = Reg::eth(1, 0x00);
+    pub(super) const EHT1: Reg = Reg::eth(1, 0x01);
+    pub(super) const EHT2: Reg = Reg::eth(1, 0x02);
+    pub(super) const EHT3: Reg = Reg::eth(1, 0x03);
+    pub(super) const EHT4: Reg = Reg::eth(1, 0x04);
+    pub(super) const EHT5: Reg = Reg::eth(1, 0x05);
+    pub(super) const EHT6: Reg = Reg::eth(1, 0x06);
+    pub(super) const EHT7: Reg = Reg::eth(1, 0x07);
+    pub(super) const ERXFCON: Reg = Reg::eth(1, 0x18);
+    pub(super) const EPKTCNT: Reg = Reg::eth(1, 0x19);
+
+    pub(super) const MACON1: Reg = Reg::mac(2, 0x00);
+    pub(super) const MACON3: Reg = Reg::mac(2, 0x02);
+    pub(super) const MACON4: Reg = Reg::mac(2, 0x03);
+    pub(super) const MABBIPG: Reg = Reg::mac(2, 0x04);
+    pub(super) const MAIPGL: Reg = Reg::mac(2, 0x06);
+    pub(super) const MAIPGH: Reg = Reg::mac(2, 0x07);
+    pub(super) const MAMXFLL: Reg = Reg::mac(2, 0x0A);
+    pub(super) const MAMXFLH: Reg = Reg::mac(2, 0x0B);
+    pub(super) const MICMD: Reg = Reg::mac(2, 0x12);
+    pub(super) const MIREGADR: Reg = Reg::mac(2, 0x14);
+    pub(super) const MIWRL: Reg = Reg::mac(2, 0x16);
+    pub(super) const MIWRH: Reg = Reg::mac(2, 0x17);
+    pub(super) const MIRDH: Reg = Reg::mac(2, 0x19);
+
+    pub(super) const MAADR5: Reg = Reg::mac(3, 0x00);
+    pub(super) const MAADR6: Reg = Reg::mac(3, 0x01);
+    pub(super) const MAADR3: Reg = Reg::mac(3, 0x02);
+    pub(super) const MAADR4: Reg = Reg::mac(3, 0x03);
+    pub(super) const MAADR1: Reg = Reg::mac(3, 0x04);
+    pub(super) const MAADR2: Reg = Reg::mac(3, 0x05);
+    pub(super) const MISTAT: Reg = Reg::mac(3, 0x0A);
+}
+
+/// PHY registers, accessed through the MII registers.
+mod phy {
+    pub const PHCON2: u8 = 0x10;
+    pub const PHSTAT2: u8 = 0x11;
+    pub const PHIE: u8 = 0x12;
+    pub const PHIR: u8 = 0x13;
+}
+
+mod bits {
+    // EIE and EIR
+    pub const INTIE: u8 = 1 << 7;
+    pub const PKTIE: u8 = 1 << 6;
+    pub const LINKIF: u8 = 1 << 4;
+    pub const TXIF: u8 = 1 << 3;
+    pub const TXERIF: u8 = 1 << 1;
+    pub const RXERIF: u8 = 1 << 0;
+
+    // ESTAT
+    pub const CLKRDY: u8 = 1 << 0;
+
+    // ECON2
+    pub const AUTOINC: u8 = 1 << 7;
+    pub const PKTDEC: u8 = 1 << 6;
+
+    // ECON1
+    pub const TXRST: u8 = 1 << 7;
+    pub const RXRST: u8 = 1 << 6;
+    pub const TXRTS: u8 = 1 << 3;
+    pub const RXEN: u8 = 1 << 2;
+    pub const BSEL: u8 = 0x03;
+
+    // ERXFCON
+    pub const UCEN: u8 = 1 << 7;
+    pub const CRCEN: u8 = 1 << 5;
+    pub const HTEN: u8 = 1 << 2;
+    pub const MCEN: u8 = 1 << 1;
+    pub const BCEN: u8 = 1 << 0;
+
+    // MACON1
+    pub const TXPAUS: u8 = 1 << 3;
+    pub const RXPAUS: u8 = 1 << 2;
+    pub const MARXEN: u8 = 1 << 0;
+
+    // MACON3: pad short frames to 60 bytes, and add the CRC.
+    pub const PADCFG0: u8 = 1 << 5;
+    pub const TXCRCEN: u8 = 1 << 4;
+    pub const FRMLNEN: u8 = 1 << 1;
+
+    // MACON4
+    pub const DEFER: u8 = 1 << 6;
+
+    // MICMD
+    pub const MIIRD: u8 = 1 << 0;
+
+    // MISTAT
+    pub const BUSY: u8 = 1 << 0;
+
+    // High byte of PHCON2
+    pub const HDLDIS: u8 = 1 << 0;
+
+    // PHIE
+    pub const PLNKIE: u8 = 1 << 4;
+    pub const PGEIE: u8 = 1 << 1;
+
+    // High byte of PHSTAT2
+    pub const LSTAT: u8 = 1 << 2;
+    pub const DPXSTAT: u8 = 1 << 1;
+}
+
+/// A command of a program.
+#[derive(Copy, Clone, PartialEq)]
+enum Op {
+    Write(Reg, u8),
+    /// Set bits of a register that is not a MAC or MII register.
+    Set(Reg, u8),
+    /// Clear bits of a register that is not a MAC or MII register.
+    Clear(Reg, u8),
+    /// Read a register into one of the read values.
+    Read(Reg, usize),
+    /// Read a register until its value masked by the first byte equals the
+    /// second.
+    Poll(Reg, u8, u8),
+    /// Write the control byte and the frame to send to the buffer memory.
+    WriteFrame(usize),
+    /// Read this many bytes of the buffer memory into the SPI read buffer.
+    ReadBuffer(usize),
+}
+
+impl Op {
+    fn bank(&self) -> Option<u8> {
+        match *self {
+            Op::Write(reg, _)
+            | Op::Set(reg, _)
+            | Op::Clear(reg, _)
+            | Op::Read(reg, _)
+            | Op::Poll(reg, _, _) => reg.bank,
+            Op::WriteFrame(_) | Op::ReadBuffer(_) => None,
+        }
+    }
+}
+
+/// The programs the driver runs.
+#[derive(Copy, Clone, PartialEq)]
+enum Program {
+    /// Set up the controller once its clock is ready.
+    Init,
+    /// Read the link status from the PHY.
+    Link,
+    Filter,
+    Transmit,
+    Disable,
+    /// Read why the controller interrupted.
+    Interrupt,
+    TransmitDone,
+    ReceiveHeader,
+    ReceiveFrame,
+    /// Free the memory of the frame that was read.
+    ReceiveRelease,
+    ReceiveError,
+    /// Let the controller interrupt again.
+    EndInterrupt,
+}
+
+pub struct Enc28j60<'a, S: spi::SpiMasterDevice> {
+    spi: &'a S,
+    int_pin: &'a dyn gpio::InterruptPin<'a>,
+    client: OptionalCell<&'a dyn ethernet::Client>,
+    spi_buf: TakeCell<'static, [u8]>,
+    spi_read_buf: TakeCell<'static, [u8]>,
+
+    /// The program running, and its next command.
+    program: OptionalCell<Program>,
+    step: Cell<usize>,
+    bank: Cell<u8>,
+    /// Whether the SPI transfer in progress selects a bank.
+    selecting_bank: Cell<bool>,
+    values: Cell<[u8; 2]>,
+
+    on: Cell<bool>,
+    address: Cell<MacAddress>,
+    filter: Cell<Filter>,
+    multicast_hash: Cell<u64>,
+    link: Cell<LinkStatus>,
+
+    /// Work waiting for the running program to finish.
+    interrupt_pending: Cell<bool>,
+    link_pending: Cell<bool>,
+    filter_pending: Cell<bool>,
+    disable_pending: Cell<bool>,
+
+    /// The interrupt flags and number of received frames left to handle,
+    /// while handling an interrupt.
+    servicing: Cell<bool>,
+    flags: Cell<u8>,
+    packets: Cell<u8>,
+    next_packet: Cell<u16>,
+    rx_len: Cell<usize>,
+
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_started: Cell<bool>,
+}
+
+impl<'a, S: spi::SpiMasterDevice> Enc28j60<'a, S> {
+    pub fn new(
+        spi: &'a S,
+        int_pin: &'a dyn gpio::InterruptPin<'a>,
+        spi_buf: &'static mut [u8],
+        spi_read_buf: &'static mut [u8],
+    ) -> Enc28j60<'a, S> {
+        Enc28j60 {
+            spi: spi,
+            int_pin: int_pin,
+            client: OptionalCell::empty(),
+            spi_buf: TakeCell::new(spi_buf),
+            spi_read_buf: TakeCell::new(spi_read_buf),
+            program: OptionalCell::empty(),
+            step: Cell::new(0),
+            bank: Cell::new(BANK_UNKNOWN),
+            selecting_bank: Cell::new(false),
+            values: Cell::new([0; 2]),
+            on: Cell::new(false),
+            address: Cell::new(MacAddress([0; 6])),
+            filter: Cell::new(Filter::default()),
+            multicast_hash: Cell::new(0),
+            link: Cell::new(LinkStatus::Down),
+            interrupt_pending: Cell::new(false),
+            link_pending: Cell::new(false),
+            filter_pending: Cell::new(false),
+            disable_pending: Cell::new(false),
+            servicing: Cell::new(false),
+            flags: Cell::new(0),
+            packets: Cell::new(0),
+            next_packet: Cell::new(RX_START),
+            rx_len: Cell::new(0),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_started: Cell::new(false),
+        }
+    }
+
+    /// Configure the SPI bus and the interrupt pin.
+    pub fn initialize(&self) {
+        self.spi.configure(
+            spi::ClockPolarity::IdleLow,
+            spi::ClockPhase::SampleLeading,
+            SPI_SPEED,
+        );
+        self.int_pin.make_input();
+        self.int_pin
+            .enable_interrupts(gpio::InterruptEdge::FallingEdge);
+    }
+
+    fn init_ops(&self) -> [Op; 37] {
+        let a = self.address.get().0;
+        let max_len = (ethernet::MAX_FRAME_LEN + FCS_LEN) as u16;
+        [
+            Op::Poll(reg::ESTAT, bits::CLKRDY, bits::CLKRDY),
+            Op::Clear(reg::ECON1, bits::RXEN),
+            Op::Set(reg::ECON1, bits::TXRST | bits::RXRST),
+            Op::Clear(reg::ECON1, bits::TXRST | bits::RXRST),
+            Op::Write(reg::EIE, 0),
+            Op::Clear(reg::EIR, bits::TXIF | bits::TXERIF | bits::RXERIF),
+            Op::Write(reg::ERXSTL, RX_START as u8),
+            Op::Write(reg::ERXSTH, (RX_START >> 8) as u8),
+            Op::Write(reg::ERXNDL, RX_END as u8),
+            Op::Write(reg::ERXNDH, (RX_END >> 8) as u8),
+            Op::Write(reg::ERXRDPTL, RX_END as u8),
+            Op::Write(reg::ERXRDPTH, (RX_END >> 8) as u8),
+            Op::Write(reg::MACON1, bits::MARXEN | bits::TXPAUS | bits::RXPAUS),
+            Op::Write(reg::MACON3, bits::PADCFG0 | bits::TXCRCEN | bits::FRMLNEN),
+            Op::Write(reg::MACON4, bits::DEFER),
+            Op::Write(reg::MAMXFLL, max_len as u8),
+            Op::Write(reg::MAMXFLH, (max_len >> 8) as u8),
+            // Inter-packet gaps recommended for half duplex.
+            Op::Write(reg::MABBIPG, 0x12),
+            Op::Write(reg::MAIPGL, 0x12),
+            Op::Write(reg::MAIPGH, 0x0C),
+            Op::Write(reg::MAADR1, a[0]),
+            Op::Write(reg::MAADR2, a[1]),
+            Op::Write(reg::MAADR3, a[2]),
+            Op::Write(reg::MAADR4, a[3]),
+            Op::Write(reg::MAADR5, a[4]),
+            Op::Write(reg::MAADR6, a[5]),
+            // Do not loop sent frames back in half duplex.
+            Op::Write(reg::MIREGADR, phy::PHCON2),
+            Op::Write(reg::MIWRL, 0),
+            Op::Write(reg::MIWRH, bits::HDLDIS),
+            Op::Poll(reg::MISTAT, bits::BUSY, 0),
+            // Interrupt on link changes.
+            Op::Write(reg::MIREGADR, phy::PHIE),
+            Op::Write(reg::MIWRL, bits::PLNKIE | bits::PGEIE),
+            Op::Write(reg::MIWRH, 0),
+            Op::Poll(reg::MISTAT, bits::BUSY, 0),
+            Op::Write(
+                reg::EIE,
+                bits::INTIE | bits::PKTIE | bits::LINKIF | bits::TXIF | bits::TXERIF | bits::RXERIF,
+            ),
+            Op::Set(reg::ECON2, bits::AUTOINC),
+            Op::Set(reg::ECON1, bits::RXEN),
+        ]
+    }
+
+    fn op(&self, step: usize) -> Option<Op> {
+        let program = self.program.extract()?;
+        match program {
+            Program::Init => self.init_ops().get(step).copied(),
+            Program::Link => [
+                // Reading PHIR acknowledges the link change.
+                Op::Write(reg::MIREGADR, phy::PHIR),
+                Op::Write(reg::MICMD, bits::MIIRD),
+                Op::Poll(reg::MISTAT, bits::BUSY, 0),
+                Op::Write(reg::MICMD, 0),
+                Op::Write(reg::MIREGADR, phy::PHSTAT2),
+                Op::Write(reg::MICMD, bits::MIIRD),
+                Op::Poll(reg::MISTAT, bits::BUSY, 0),
+                Op::Write(reg::MICMD, 0),
+                Op::Read(reg::MIRDH, 0),
+            ]
+            .get(step)
+            .copied(),
+            Program::Filter => {
+                let filter = self.filter.get();
+                let hash = self.multicast_hash.get().to_le_bytes();
+                // With every filter off, every frame is received.
+                let filters = if filter.promiscuous {
+                    bits::CRCEN
+                } else {
+                    bits::UCEN
+                        | bits::CRCEN
+                        | if filter.broadcast { bits::BCEN } else { 0 }
+                        | if filter.all_multicast { bits::MCEN } else { 0 }
+                        | if hash != [0; 8] { bits::HTEN } else { 0 }
+                };
+                [
+                    Op::Write(reg::EHT0, hash[0]),
+                    Op::Write(reg::EHT1, hash[1]),
+                    Op::Write(reg::EHT2, hash[2]),
+                    Op::Write(reg::EHT3, hash[3]),
+                    Op::Write(reg::EHT4, hash[4]),
+                    Op::Write(reg::EHT5, hash[5]),
+                    Op::Write(reg::EHT6, hash[6]),
+                    Op::Write(reg::EHT7, hash[7]),
+                    Op::Write(reg::ERXFCON, filters),
+                ]
+                .get(step)
+                .copied()
+            }
+            Program::Transmit => {
+                let len = self.tx_len.get();
+                let end = TX_START + len as u16;
+                [
+                    Op::Write(reg::EWRPTL, TX_START as u8),
+                    Op::Write(reg::EWRPTH, (TX_START >> 8) as u8),
+                    Op::WriteFrame(len),
+                    Op::Write(reg::ETXSTL, TX_START as u8),
+                    Op::Write(reg::ETXSTH, (TX_START >> 8) as u8),
+                    Op::Write(reg::ETXNDL, end as u8),
+                    Op::Write(reg::ETXNDH, (end >> 8) as u8),
+                    // The transmit logic can stall after an error unless
+                    // it is reset before each frame.
+                    Op::Set(reg::ECON1, bits::TXRST),
+                    Op::Clear(reg::ECON1, bits::TXRST),
+                    Op::Clear(reg::EIR, bits::TXIF | bits::TXERIF),
+                    Op::Set(reg::ECON1, bits::TXRTS),
+                ]
+                .get(step)
+                .copied()
+            }
+            Program::Disable => [Op::Clear(reg::ECON1, bits::RXEN), Op::Write(reg::EIE, 0)]
+                .get(step)
+                .copied(),
+            // PKTIF is not reliable, the number of received frames is.
+            Program::Interrupt => [
+                Op::Clear(reg::EIE, bits::INTIE),
+                Op::Read(reg::EIR, 0),
+                Op::Read(reg::EPKTCNT, 1),
+            ]
+            .get(step)
+            .copied(),
+            Program::TransmitDone => [Op::Clear(reg::EIR, bits::TXIF | bits::TXERIF)]
+                .get(step)
+                .copied(),
+            Program::ReceiveHeader => {
+                let next = self.next_packet.get();
+                [
+                    Op::Write(reg::ERDPTL, next as u8),
+                    Op::Write(reg::ERDPTH, (next >> 8) as u8),
+                    Op::ReadBuffer(RX_HEADER_LEN),
+                ]
+                .get(step)
+                .copied()
+            }
+            Program::ReceiveFrame => [Op::ReadBuffer(self.rx_len.get())].get(step).copied(),
+            Program::ReceiveRelease => {
+                // The read pointer must be odd, so it is set to just before
+                // the next frame.
+                let next = self.next_packet.get();
+                let read = if next == RX_START { RX_END } else { next - 1 };
+                [
+                    Op::Write(reg::ERXRDPTL, read as u8),
+                    Op::Write(reg::ERXRDPTH, (read >> 8) as u8),
+                    Op::Set(reg::ECON2, bits::PKTDEC),
+                    Op::Read(reg::EPKTCNT, 1),
+                ]
+                .get(step)
+                .copied()
+            }
+            Program::ReceiveError => [Op::Clear(reg::EIR, bits::RXERIF)].get(step).copied(),
+            Program::EndInterrupt => [Op::Set(reg::EIE, bits::INTIE)].get(step).copied(),
+        }
+    }
+
+    fn start(&self, program: Program) {
+        self.program.set(program);
+        self.step.set(0);
+        self.run();
+    }
+
+    /// Send the next command of the program, selecting its bank first if
+    /// needed.
+    fn run(&self) {
+        let op = match self.op(self.step.get()) {
+            Some(op) => op,
+            None => return self.program_done(),
+        };
+
+        if let Some(bank) = op.bank() {
+            let current = self.bank.get();
+            if bank != current {
+                // Clear the bits that differ, then set the missing ones.
+                let (command, mask, selected) = if current == BANK_UNKNOWN || current & !bank != 0 {
+                    (BFC, bits::BSEL, 0)
+                } else {
+                    (BFS, bank, bank)
+                };
+                self.bank.set(selected);
+                self.selecting_bank.set(true);
+                self.transfer(|wbuf| {
+                    wbuf[0] = command | reg::ECON1.address;
+                    wbuf[1] = mask;
+                    2
+                });
+                return;
+            }
+        }
+
+        match op {
+            Op::Write(reg, value) => self.transfer(|wbuf| {
+                wbuf[0] = WCR | reg.address;
+                wbuf[1] = value;
+                2
+            }),
+            Op::Set(reg, mask) => self.transfer(|wbuf| {
+                wbuf[0] = BFS | reg.address;
+                wbuf[1] = mask;
+                2
+            }),
+            Op::Clear(reg, mask) => self.transfer(|wbuf| {
+                wbuf[0] = BFC | reg.address;
+                wbuf[1] = mask;
+                2
+            }),
+            Op::Read(reg, _) | Op::Poll(reg, _, _) => self.transfer(|wbuf| {
+                wbuf[0] = RCR | reg.address;
+                wbuf[1] = 0;
+                wbuf[2] = 0;
+                if reg.mac {
+                    3
+                } else {
+                    2
+                }
+            }),
+            Op::WriteFrame(len) => {
+                let tx_buffer = &self.tx_buffer;
+                self.transfer(|wbuf| {
+                    wbuf[0] = WBM;
+                    // The control byte: use the settings of MACON3.
+                    wbuf[1] = 0;
+                    tx_buffer.map(|frame| wbuf[2..2 + len].copy_from_slice(&frame[..len]));
+                    2 + len
+                })
+            }
+            Op::ReadBuffer(len) => self.transfer(|wbuf| {
+                wbuf[0] = RBM;
+                1 + len
+            }),
+        }
+    }
+
+    fn transfer<F: FnOnce(&mut [u8]) -> usize>(&self, fill: F) {
+        self.spi_buf.take().map(|wbuf| {
+            let len = fill(wbuf);
+            let _ = self
+                .spi
+                .read_write_bytes(wbuf, self.spi_read_buf.take(), len);
+        });
+    }
+
+    /// Handle the result of the command that was sent. Returns whether the
+    /// program moves on to its next command.
+    fn command_done(&self, op: Op, rbuf: &[u8]) -> bool {
+        let value = |reg: Reg| if reg.mac { rbuf[2] } else { rbuf[1] };
+        match op {
+            Op::Read(reg, index) => {
+                let mut values = self.values.get();
+                values[index] = value(reg);
+                self.values.set(values);
+                true
+            }
+            Op::Poll(reg, mask, expected) => value(reg) & mask == expected,
+            _ => true,
+        }
+    }
+
+    fn program_done(&self) {
+        let program = match self.program.take() {
+            Some(program) => program,
+            None => return,
+        };
+        let values = self.values.get();
+        match program {
+            Program::Init => {
+                self.on.set(true);
+                self.next_packet.set(RX_START);
+                self.link_pending.set(true);
+                self.filter_pending.set(true);
+                self.idle();
+            }
+            Program::Link => {
+                let status = if values[0] & bits::LSTAT != 0 {
+                    LinkStatus::Up {
+                        speed: 10,
+                        full_duplex: values[0] & bits::DPXSTAT != 0,
+                    }
+                } else {
+                    LinkStatus::Down
+                };
+                if status != self.link.get() {
+                    self.link.set(status);
+                    self.client.map(|client| client.link_status_changed(status));
+                }
+                if self.servicing.get() {
+                    self.flags.set(self.flags.get() & !bits::LINKIF);
+                    self.service();
+                } else {
+                    self.idle();
+                }
+            }
+            Program::Filter | Program::Transmit => self.idle(),
+            Program::Disable => {
+                self.on.set(false);
+                self.link.set(LinkStatus::Down);
+                self.link_pending.set(false);
+                self.filter_pending.set(false);
+                self.interrupt_pending.set(false);
+            }
+            Program::Interrupt => {
+                self.flags.set(values[0]);
+                self.packets.set(values[1]);
+                self.service();
+            }
+            Program::TransmitDone => {
+                let flags = self.flags.get();
+                self.flags.set(flags & !(bits::TXIF | bits::TXERIF));
+                let result = if flags & bits::TXERIF != 0 {
+                    Err(ErrorCode::FAIL)
+                } else {
+                    Ok(())
+                };
+                self.tx_started.set(false);
+                self.tx_buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.transmit_done(buffer, result));
+                });
+                self.service();
+            }
+            Program::ReceiveHeader => {
+                let header = self.spi_read_buf.map_or([0; RX_HEADER_LEN], |rbuf| {
+                    let mut header = [0; RX_HEADER_LEN];
+                    header.copy_from_slice(&rbuf[1..=RX_HEADER_LEN]);
+                    header
+                });
+                let next = u16::from_le_bytes([header[0], header[1]]);
+                let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+                let received_ok = header[4] & 0x80 != 0;
+                let frame_len = len.saturating_sub(FCS_LEN);
+                self.next_packet
+                    .set(if next <= RX_END { next } else { RX_START });
+                if received_ok
+                    && frame_len >= ethernet::HEADER_LEN
+                    && frame_len <= ethernet::MAX_FRAME_LEN
+                {
+                    self.rx_len.set(frame_len);
+                    self.start(Program::ReceiveFrame);
+                } else {
+                    self.start(Program::ReceiveRelease);
+                }
+            }
+            Program::ReceiveFrame => {
+                let len = self.rx_len.get();
+                self.spi_read_buf.map(|rbuf| {
+                    self.client
+                        .map(|client| client.frame_received(&rbuf[1..=len]));
+                });
+                self.start(Program::ReceiveRelease);
+            }
+            Program::ReceiveRelease => {
+                self.packets.set(values[1]);
+                self.service();
+            }
+            Program::ReceiveError => {
+                self.flags.set(self.flags.get() & !bits::RXERIF);
+                self.service();
+            }
+            Program::EndInterrupt => {
+                self.servicing.set(false);
+                self.idle();
+            }
+        }
+    }
+
+    /// Handle the next cause of the interrupt.
+    fn service(&self) {
+        self.servicing.set(true);
+        let flags = self.flags.get();
+        if flags & bits::LINKIF != 0 {
+            self.start(Program::Link);
+        } else if flags & (bits::TXIF | bits::TXERIF) != 0 {
+            self.start(Program::TransmitDone);
+        } else if self.packets.get() > 0 {
+            self.start(Program::ReceiveHeader);
+        } else if flags & bits::RXERIF != 0 {
+            // The receive buffer was full, and frames were dropped.
+            self.start(Program::ReceiveError);
+        } else {
+            self.start(Program::EndInterrupt);
+        }
+    }
+
+    /// Start the work that waited for the last program to finish.
+    fn idle(&self) {
+        if !self.on.get() || self.program.is_some() {
+            return;
+        }
+        if self.interrupt_pending.replace(false) {
+            self.start(Program::Interrupt);
+        } else if self.disable_pending.replace(false) {
+            self.start(Program::Disable);
+        } else if self.link_pending.replace(false) {
+            self.start(Program::Link);
+        } else if self.filter_pending.replace(false) {
+            self.start(Program::Filter);
+        } else if self.tx_buffer.is_some() && !self.tx_started.get() {
+            self.tx_started.set(true);
+            self.start(Program::Transmit);
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> ethernet::Mac<'a> for Enc28j60<'a, S> {
+    fn set_client(&self, client: &'a dyn ethernet::Client) {
+        self.client.set(client);
+    }
+
+    fn enable(&self, address: MacAddress) -> Result<(), ErrorCode> {
+        if self.on.get() || self.program.is_some() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.address.set(address);
+        self.bank.set(BANK_UNKNOWN);
+        self.start(Program::Init);
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        if !self.on.get() || self.disable_pending.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        if self.tx_buffer.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.disable_pending.set(true);
+        self.idle();
+        Ok(())
+    }
+
+    fn set_filter(&self, filter: Filter) -> Result<(), ErrorCode> {
+        self.filter.set(filter);
+        self.filter_pending.set(true);
+        self.idle();
+        Ok(())
+    }
+
+    fn set_multicast_addresses(&self, addresses: &[MacAddress]) -> Result<(), ErrorCode> {
+        if addresses.iter().any(|address| !address.is_multicast()) {
+            return Err(ErrorCode::INVAL);
+        }
+        // Bits 28 to 23 of the CRC index the table.
+        self.multicast_hash
+            .set(ethernet::multicast_hash_table(addresses, |crc| crc >> 23));
+        self.filter_pending.set(true);
+        self.idle();
+        Ok(())
+    }
+
+    fn link_status(&self) -> LinkStatus {
+        self.link.get()
+    }
+
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.on.get() {
+            return Err((ErrorCode::OFF, buffer));
+        }
+        if self.tx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if len < ethernet::HEADER_LEN || len > ethernet::MAX_FRAME_LEN || len > buffer.len() {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+        self.tx_buffer.replace(buffer);
+        self.tx_len.set(len);
+        self.tx_started.set(false);
+        self.idle();
+        Ok(())
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for Enc28j60<'a, S> {
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        _len: usize,
+    ) {
+        self.spi_buf.replace(write_buffer);
+        read_buffer.map(|rbuf| self.spi_read_buf.replace(rbuf));
+
+        if self.selecting_bank.replace(false) {
+            self.run();
+            return;
+        }
+        let step = self.step.get();
+        if let Some(op) = self.op(step) {
+            let next = self
+                .spi_read_buf
+                .map_or(true, |rbuf| self.command_done(op, rbuf));
+            if next {
+                self.step.set(step + 1);
+            }
+        }
+        self.run();
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> gpio::Client for Enc28j60<'a, S> {
+    fn fired(&self) {
+        if !self.on.get() {
+            return;
+        }
+        if self.program.is_some() {
+            self.interrupt_pending.set(true);
+        } else {
+            self.start(Program::Interrupt);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Continuous health tests for an entropy source.
+//!
+//! `EntropyHealthTest` sits between a 32-bit entropy source and its client,
+//! and runs the health tests of NIST SP 800-90B, section 4.4, on every
+//! sample. Each byte of the entropy is a sample.
+//!
+//! - The repetition count test fails if a sample repeats too many times in a
+//!   row.
+//! - The adaptive proportion test fails if the first sample of a window of
+//!   512 samples occurs too many times in the window.
+//!
+//! The cutoffs of both tests follow from the min-entropy per sample claimed
+//! for the source, with a false positive probability of 2^-20. The first
+//! 1024 samples are tested and discarded as startup tests before any entropy
+//! is delivered.
+//!
+//! When a test fails, the source enters an error state: the client receives
+//! an `entropy_available` callback with `FAIL` and no entropy, and `get()`
+//! fails, until the tests are reset. Entropy is only delivered in batches
+//! that passed the tests in full.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::hil::entropy::Entropy32;
+//! # use kernel::static_init;
+//!
+//! let health = static_init!(
+//!     capsules::entropy_health::EntropyHealthTest<'static>,
+//!     capsules::entropy_health::EntropyHealthTest::new(&sam4l::trng::TRNG, 8)
+//! );
+//! sam4l::trng::TRNG.set_client(health);
+//! health.set_client(entropy_client);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::entropy::{self, Entropy32};
+use kernel::ErrorCode;
+
+/// Window of the adaptive proportion test, in samples.
+const APT_WINDOW: usize = 512;
+
+/// Samples tested and discarded before the first entropy is delivered.
+const STARTUP_SAMPLES: usize = 1024;
+
+/// Words of entropy tested before they are delivered together.
+const BATCH_WORDS: usize = 8;
+
+/// Cutoff of the repetition count test for 1 to 8 bits of min-entropy per
+/// sample: `1 + ceil(20 / H)`.
+const RCT_CUTOFFS: [usize; 8] = [21, 11, 8, 6, 5, 5, 4, 4];
+
+/// Cutoff of the adaptive proportion test for 1 to 8 bits of min-entropy per
+/// sample: `1 + CRITBINOM(512, 2^-H, 1 - 2^-20)`.
+const APT_CUTOFFS: [usize; 8] = [311, 177, 103, 62, 39, 25, 18, 13];
+
+pub struct EntropyHealthTest<'a> {
+    source: &'a dyn Entropy32<'a>,
+    client: OptionalCell<&'a dyn entropy::Client32>,
+    rct_cutoff: usize,
+    apt_cutoff: usize,
+
+    failed: Cell<bool>,
+    startup_remaining: Cell<usize>,
+
+    // Repetition count test: the last sample and how often it repeated.
+    last_sample: OptionalCell<u8>,
+    repetitions: Cell<usize>,
+
+    // Adaptive proportion test: the first sample of the window, how often it
+    // occurred and the number of samples in the window so far.
+    window_sample: Cell<u8>,
+    window_count: Cell<usize>,
+    window_len: Cell<usize>,
+}
+
+impl<'a> EntropyHealthTest<'a> {
+    /// Test `source`, which claims `min_entropy` bits of min-entropy per
+    /// byte, from 1 to 8.
+    pub fn new(source: &'a dyn Entropy32<'a>, min_entropy: usize) -> EntropyHealthTest<'a> {
+        let index = core::cmp::min(core::cmp::max(min_entropy, 1), 8) - 1;
+        EntropyHealthTest {
+            source: source,
+            client: OptionalCell::empty(),
+            rct_cutoff: RCT_CUTOFFS[index],
+            apt_cutoff: APT_CUTOFFS[index],
+            failed: Cell::new(false),
+            startup_remaining: Cell::new(STARTUP_SAMPLES),
+            last_sample: OptionalCell::empty(),
+            repetitions: Cell::new(0),
+            window_sample: Cell::new(0),
+            window_count: Cell::new(0),
+            window_len: Cell::new(0),
+        }
+    }
+
+    /// Returns whether a health test failed.
+    pub fn failed(&self) -> bool {
+        self.failed.get()
+    }
+
+    /// Leave the error state, and run the startup tests again.
+    pub fn reset(&self) {
+        self.failed.set(false);
+        self.startup_remaining.set(STARTUP_SAMPLES);
+        self.last_sample.clear();
+        self.repetitions.set(0);
+        self.window_len.set(0);
+    }
+
+    /// Run both tests on `sample`. Returns `false` if either failed.
+    fn test_sample(&self, sample: u8) -> bool {
+        if self.last_sample.contains(&sample) {
+            self.repetitions.set(self.repetitions.get() + 1);
+        } else {
+            self.last_sample.set(sample);
+            self.repetitions.set(1);
+        }
+        if self.repetitions.get() >= self.rct_cutoff {
+            return false;
+        }
+
+        if self.window_len.get() == 0 {
+            self.window_sample.set(sample);
+            self.window_count.set(1);
+        } else if sample == self.window_sample.get() {
+            self.window_count.set(self.window_count.get() + 1);
+            if self.window_count.get() >= self.apt_cutoff {
+                return false;
+            }
+        }
+        self.window_len
+            .set((self.window_len.get() + 1) % APT_WINDOW);
+        true
+    }
+
+    fn test_word(&self, word: u32) -> bool {
+        word.to_le_bytes()
+            .iter()
+            .all(|sample| self.test_sample(*sample))
+    }
+}
+
+impl<'a> Entropy32<'a> for EntropyHealthTest<'a> {
+    fn get(&self) -> Result<(), ErrorCode> {
+        if self.failed.get() {
+            return Err(ErrorCode::FAIL);
+        }
+        self.source.get()
+    }
+
+    fn cancel(&self) -> Result<(), ErrorCode> {
+        self.source.cancel()
+    }
+
+    fn set_client(&'a self, client: &'a dyn entropy::Client32) {
+        self.client.set(client);
+    }
+}
+
+impl entropy::Client32 for EntropyHealthTest<'_> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> entropy::Continue {
+        if self.failed.get() {
+            return entropy::Continue::Done;
+        }
+        if error.is_err() {
+            return self.client.map_or(entropy::Continue::Done, |client| {
+                client.entropy_available(&mut core::iter::empty(), error)
+            });
+        }
+
+        // Run the startup tests on samples that are never delivered.
+        while self.startup_remaining.get() > 0 {
+            let word = match entropy.next() {
+                Some(word) => word,
+                None => return entropy::Continue::More,
+            };
+            if !self.test_word(word) {
+                self.failed.set(true);
+                break;
+            }
+            self.startup_remaining
+                .set(self.startup_remaining.get().saturating_sub(4));
+        }
+
+        let mut words = [0; BATCH_WORDS];
+        let mut len = 0;
+        while len < BATCH_WORDS && !self.failed.get() {
+            match entropy.next() {
+                Some(word) => {
+                    if !self.test_word(word) {
+                        self.failed.set(true);
+                    }
+                    words[len] = word;
+                    len += 1;
+                }
+                None => break,
+            }
+        }
+
+        if self.failed.get() {
+            self.client.map(|client| {
+                client.entropy_available(&mut core::iter::empty(), Err(ErrorCode::FAIL))
+            });
+            return entropy::Continue::Done;
+        }
+        if len == 0 {
+            return entropy::Continue::More;
+        }
+        self.client.map_or(entropy::Continue::Done, |client| {
+            client.entropy_available(&mut words[..len].iter().copied(), Ok(()))
+        })
+    }
+}
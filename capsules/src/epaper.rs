@@ -0,0 +1,830 @@
+//! E-paper screens with SSD1680 and IL0373 controllers
+//!
+//! - <https://www.solomon-systech.com/product/ssd1680/>
+//! - <https://www.good-display.com/companyfile/IL0373.pdf>
+//!
+//! The panels are monochrome, with one bit per pixel and a set bit for a
+//! white pixel. Write frames must start and end on a multiple of 8 pixels
+//! horizontally, as each byte holds 8 horizontal pixels.
+//!
+//! Writing the last byte of the write frame refreshes that part of the
+//! panel, and the write completes once the refresh is done, which can take a
+//! few seconds. Between refreshes, the controller is in deep sleep, which
+//! keeps its RAM. It is woken up by a reset before the next write frame.
+//!
+//! Refresh Modes
+//! -------------
+//!
+//! A full refresh uses the waveform of the panel: the pixels flash between
+//! black and white until they settle, which clears any ghosting. A partial
+//! refresh uses a short waveform loaded by the driver, which only drives the
+//! pixels to their new color, without flashing. Partial refreshes leave some
+//! ghosting, so every `full_refresh_interval` partial refreshes, a full
+//! refresh is done instead.
+//!
+//! ```rust
+//! epaper.set_refresh_mode(capsules::epaper::RefreshMode::Partial, 10);
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! SPI example
+//!
+//! ```rust
+//! let epaper = components::epaper::EPaperComponent::new(mux_alarm).finalize(
+//!     components::epaper_component_helper!(
+//!         // screen
+//!         &capsules::epaper::SSD1680,
+//!         // bus type
+//!         capsules::bus::SpiMasterBus<
+//!             'static,
+//!             VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>,
+//!         >,
+//!         // bus
+//!         &bus,
+//!         // timer type
+//!         nrf52840::rtc::Rtc,
+//!         // pin type
+//!         nrf52::gpio::GPIOPin<'static>,
+//!         // dc
+//!         &nrf52840::gpio::PORT[GPIO_D3],
+//!         // reset
+//!         &nrf52840::gpio::PORT[GPIO_D2],
+//!         // busy
+//!         &nrf52840::gpio::PORT[GPIO_D5]
+//!     ),
+//! );
+//! epaper.init();
+//! ```
+
+use crate::bus::{self, Bus, BusWidth};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::gpio::Pin;
+use kernel::hil::screen::{self, ScreenClient, ScreenPixelFormat, ScreenRotation};
+use kernel::hil::time::{self, Alarm};
+use kernel::ErrorCode;
+
+/// Size of the command parameters buffer, which holds the longest waveform.
+pub const BUFFER_SIZE: usize = 160;
+
+pub const SEQUENCE_BUFFER_SIZE: usize = 40;
+
+/// Interval of the busy line checks.
+const BUSY_POLL_MS: u32 = 10;
+
+/// Longest time the controller may be busy, a full refresh in the cold
+/// takes a few seconds.
+const BUSY_TIMEOUT_MS: u32 = 10000;
+
+// Positions of the parameters computed at run time.
+const DRIVER_OUTPUT: usize = 0;
+const X_WINDOW: usize = 3;
+const Y_WINDOW: usize = 5;
+const X_COUNTER: usize = 9;
+const Y_COUNTER: usize = 10;
+const PARTIAL_WINDOW: usize = 12;
+const RESOLUTION: usize = 19;
+const PARAMETERS_LEN: usize = 22;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Controller {
+    SSD1680,
+    IL0373,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum RefreshMode {
+    Full,
+    Partial,
+}
+
+/// One step of a command sequence.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Step {
+    Nop,
+    /// Pulse the reset line.
+    Reset,
+    /// Wait for the controller to release the busy line.
+    WaitBusy,
+    /// Wait for the given number of milliseconds.
+    Delay(u32),
+    /// A command with fixed parameters.
+    Command(u8, &'static [u8]),
+    /// A command with the parameters computed at run time, at the given
+    /// position and length.
+    Parameters(u8, usize, usize),
+    /// The given number of bytes of the write buffer, after a command if any.
+    Data(Option<u8>, usize),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Status {
+    Idle,
+    ResetLow,
+    ResetHigh,
+    WaitBusy(u32),
+    Delay,
+    SendCommand(usize),
+    SendParameters,
+    SendDataCommand(usize),
+    SendData,
+}
+
+/// What to report to the client at the end of a sequence.
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Init,
+    Command,
+    Write,
+}
+
+/************ SSD1680 **************/
+
+const SSD1680_INIT: [Step; 10] = [
+    Step::Reset,
+    Step::WaitBusy,
+    // software reset
+    Step::Command(0x12, &[]),
+    Step::WaitBusy,
+    // driver output control
+    Step::Parameters(0x01, DRIVER_OUTPUT, 3),
+    // data entry mode: x and y increment
+    Step::Command(0x11, &[0x03]),
+    // border waveform
+    Step::Command(0x3C, &[0x05]),
+    // display update control 1: normal black and white RAM
+    Step::Command(0x21, &[0x00, 0x80]),
+    // internal temperature sensor
+    Step::Command(0x18, &[0x80]),
+    Step::WaitBusy,
+];
+
+const SSD1680_WINDOW: [Step; 2] = [
+    Step::Parameters(0x44, X_WINDOW, 2),
+    Step::Parameters(0x45, Y_WINDOW, 4),
+];
+
+const SSD1680_FULL_REFRESH: [Step; 4] = [
+    Step::Command(0x3C, &[0x05]),
+    // display update control 2: load the waveform of the panel, mode 1
+    Step::Command(0x22, &[0xF7]),
+    Step::Command(0x20, &[]),
+    Step::WaitBusy,
+];
+
+/// Partial refresh waveform: voltages of the 5 transitions for 12 phases,
+/// phase timings, and frame rates.
+const SSD1680_PARTIAL_WAVEFORM: [u8; 153] = [
+    0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x80, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x40, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x00, 0x00, 0x00,
+];
+
+const SSD1680_PARTIAL_REFRESH: [Step; 10] = [
+    Step::Command(0x32, &SSD1680_PARTIAL_WAVEFORM),
+    // end option, gate, source and VCOM voltages of the waveform
+    Step::Command(0x3F, &[0x22]),
+    Step::Command(0x03, &[0x17]),
+    Step::Command(0x04, &[0x41, 0x00, 0x32]),
+    Step::Command(0x2C, &[0x36]),
+    // display option: the previous image follows each update
+    Step::Command(
+        0x37,
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00],
+    ),
+    Step::Command(0x3C, &[0x80]),
+    // display update control 2: keep the loaded waveform, mode 2
+    Step::Command(0x22, &[0xCF]),
+    Step::Command(0x20, &[]),
+    Step::WaitBusy,
+];
+
+const SSD1680_SLEEP: [Step; 1] = [
+    // deep sleep mode 1, which keeps the RAM
+    Step::Command(0x10, &[0x01]),
+];
+
+/************ IL0373 **************/
+
+const IL0373_INIT: [Step; 9] = [
+    Step::Reset,
+    Step::WaitBusy,
+    // power setting
+    Step::Command(0x01, &[0x03, 0x00, 0x2B, 0x2B, 0x03]),
+    // booster soft start
+    Step::Command(0x06, &[0x17, 0x17, 0x17]),
+    // power on
+    Step::Command(0x04, &[]),
+    Step::WaitBusy,
+    // panel setting: black and white, waveform of the panel
+    Step::Command(0x00, &[0x1F]),
+    Step::Parameters(0x61, RESOLUTION, 3),
+    Step::Command(0x50, &[0x97]),
+];
+
+const IL0373_WINDOW: [Step; 2] = [
+    // partial in, so the data and the refresh only cover the window
+    Step::Command(0x91, &[]),
+    Step::Parameters(0x90, PARTIAL_WINDOW, 7),
+];
+
+const IL0373_FULL_REFRESH: [Step; 5] = [
+    Step::Command(0x00, &[0x1F]),
+    Step::Command(0x50, &[0x97]),
+    Step::Command(0x12, &[]),
+    Step::WaitBusy,
+    // partial out
+    Step::Command(0x92, &[]),
+];
+
+/// Partial refresh waveforms. Each pixel is driven to its new color,
+/// whatever its previous color, for 25 frames.
+const IL0373_PARTIAL_VCOM: [u8; 44] = [
+    0x00, 0x19, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const IL0373_PARTIAL_TO_WHITE: [u8; 42] = [
+    0x80, 0x19, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const IL0373_PARTIAL_TO_BLACK: [u8; 42] = [
+    0x40, 0x19, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+const IL0373_PARTIAL_REFRESH: [Step; 11] = [
+    // panel setting: black and white, waveform from the registers
+    Step::Command(0x00, &[0x3F]),
+    Step::Command(0x82, &[0x08]),
+    Step::Command(0x50, &[0x17]),
+    Step::Command(0x20, &IL0373_PARTIAL_VCOM),
+    // white to white, black to white, white to black, black to black
+    Step::Command(0x21, &IL0373_PARTIAL_TO_WHITE),
+    Step::Command(0x22, &IL0373_PARTIAL_TO_WHITE),
+    Step::Command(0x23, &IL0373_PARTIAL_TO_BLACK),
+    Step::Command(0x24, &IL0373_PARTIAL_TO_BLACK),
+    Step::Command(0x12, &[]),
+    Step::WaitBusy,
+    // partial out
+    Step::Command(0x92, &[]),
+];
+
+const IL0373_SLEEP: [Step; 4] = [
+    Step::Command(0x50, &[0xF7]),
+    // power off
+    Step::Command(0x02, &[]),
+    Step::WaitBusy,
+    Step::Command(0x07, &[0xA5]),
+];
+
+pub struct EPaperScreen {
+    controller: Controller,
+    width: usize,
+    height: usize,
+}
+
+/// 2.13" 122x250 panel, with the 6 columns that follow in RAM.
+pub const SSD1680: EPaperScreen = EPaperScreen {
+    controller: Controller::SSD1680,
+    width: 128,
+    height: 250,
+};
+
+/// 2.13" 104x212 panel.
+pub const IL0373: EPaperScreen = EPaperScreen {
+    controller: Controller::IL0373,
+    width: 104,
+    height: 212,
+};
+
+pub struct EPaper<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> {
+    bus: &'a B,
+    alarm: &'a A,
+    dc: &'a P,
+    reset: &'a P,
+    busy: &'a P,
+    screen: &'static EPaperScreen,
+    client: OptionalCell<&'static dyn screen::ScreenClient>,
+
+    status: Cell<Status>,
+    operation: Cell<Operation>,
+    sequence_buffer: TakeCell<'static, [Step]>,
+    position_in_sequence: Cell<usize>,
+    sequence_len: Cell<usize>,
+    buffer: TakeCell<'static, [u8]>,
+    parameters: Cell<[u8; PARAMETERS_LEN]>,
+    write_buffer: TakeCell<'static, [u8]>,
+
+    asleep: Cell<bool>,
+    inverted: Cell<bool>,
+    refresh_mode: Cell<RefreshMode>,
+    full_refresh_interval: Cell<usize>,
+    partial_refreshes: Cell<usize>,
+    // Whether the refresh of the current write frame is a full one.
+    full_refresh: Cell<bool>,
+
+    frame: Cell<(usize, usize, usize, usize)>,
+    written: Cell<usize>,
+}
+
+impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> EPaper<'a, A, B, P> {
+    pub fn new(
+        bus: &'a B,
+        alarm: &'a A,
+        dc: &'a P,
+        reset: &'a P,
+        busy: &'a P,
+        buffer: &'static mut [u8],
+        sequence_buffer: &'static mut [Step],
+        screen: &'static EPaperScreen,
+    ) -> EPaper<'a, A, B, P> {
+        dc.make_output();
+        reset.make_output();
+        reset.set();
+        busy.make_input();
+
+        let mut parameters = [0; PARAMETERS_LEN];
+        let last_gate = screen.height - 1;
+        parameters[DRIVER_OUTPUT] = (last_gate & 0xFF) as u8;
+        parameters[DRIVER_OUTPUT + 1] = (last_gate >> 8) as u8;
+        parameters[RESOLUTION] = screen.width as u8;
+        parameters[RESOLUTION + 1] = (screen.height >> 8) as u8;
+        parameters[RESOLUTION + 2] = (screen.height & 0xFF) as u8;
+
+        EPaper {
+            bus: bus,
+            alarm: alarm,
+            dc: dc,
+            reset: reset,
+            busy: busy,
+            screen: screen,
+            client: OptionalCell::empty(),
+
+            status: Cell::new(Status::Idle),
+            operation: Cell::new(Operation::Init),
+            sequence_buffer: TakeCell::new(sequence_buffer),
+            position_in_sequence: Cell::new(0),
+            sequence_len: Cell::new(0),
+            buffer: TakeCell::new(buffer),
+            parameters: Cell::new(parameters),
+            write_buffer: TakeCell::empty(),
+
+            asleep: Cell::new(true),
+            inverted: Cell::new(false),
+            refresh_mode: Cell::new(RefreshMode::Full),
+            full_refresh_interval: Cell::new(0),
+            partial_refreshes: Cell::new(0),
+            full_refresh: Cell::new(true),
+
+            frame: Cell::new((0, 0, screen.width, screen.height)),
+            written: Cell::new(0),
+        }
+    }
+
+    /// Reset and initialize the controller, and put it in deep sleep. The
+    /// client gets a `screen_is_ready()` callback once done.
+    pub fn init(&self) -> Result<(), ErrorCode> {
+        let (init, sleep): (&[Step], &[Step]) = match self.screen.controller {
+            Controller::SSD1680 => (&SSD1680_INIT, &SSD1680_SLEEP),
+            Controller::IL0373 => (&IL0373_INIT, &IL0373_SLEEP),
+        };
+        self.asleep.set(true);
+        self.start(Operation::Init, &[init, sleep])
+    }
+
+    /// Use `mode` for the following refreshes. In the partial mode, every
+    /// `full_refresh_interval` refreshes is a full one, or none if it is 0.
+    pub fn set_refresh_mode(&self, mode: RefreshMode, full_refresh_interval: usize) {
+        self.refresh_mode.set(mode);
+        self.full_refresh_interval.set(full_refresh_interval);
+        self.partial_refreshes.set(0);
+    }
+
+    fn is_busy(&self) -> bool {
+        match self.screen.controller {
+            Controller::SSD1680 => self.busy.read(),
+            // the busy line is active low
+            Controller::IL0373 => !self.busy.read(),
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        let (_, _, width, height) = self.frame.get();
+        width / 8 * height
+    }
+
+    /// Run the concatenation of `steps`.
+    fn start(&self, operation: Operation, steps: &[&[Step]]) -> Result<(), ErrorCode> {
+        if self.status.get() != Status::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.sequence_buffer
+            .map_or(Err(ErrorCode::NOMEM), |sequence_buffer| {
+                let mut len = 0;
+                for step in steps.iter().flat_map(|steps| steps.iter()) {
+                    if len == sequence_buffer.len() {
+                        return Err(ErrorCode::NOMEM);
+                    }
+                    sequence_buffer[len] = *step;
+                    len += 1;
+                }
+                self.sequence_len.set(len);
+                Ok(())
+            })?;
+        self.operation.set(operation);
+        self.position_in_sequence.set(0);
+        self.next_step();
+        Ok(())
+    }
+
+    /// The steps that wake the controller up, if it is in deep sleep, and
+    /// select the write frame.
+    fn wake_steps(&self) -> (&'static [Step], &'static [Step]) {
+        let init: &'static [Step] = match self.screen.controller {
+            Controller::SSD1680 => &SSD1680_INIT,
+            Controller::IL0373 => &IL0373_INIT,
+        };
+        let window: &'static [Step] = match self.screen.controller {
+            Controller::SSD1680 => &SSD1680_WINDOW,
+            Controller::IL0373 => &IL0373_WINDOW,
+        };
+        if self.asleep.get() {
+            self.asleep.set(false);
+            (init, window)
+        } else {
+            (&[], window)
+        }
+    }
+
+    /// Send `len` bytes of the write buffer to the frame, followed by the
+    /// refresh and deep sleep once the frame is complete.
+    fn write_data(&self, buffer: &'static mut [u8], len: usize) -> Result<(), ErrorCode> {
+        if self.status.get() != Status::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let written = self.written.get();
+        let len = cmp::min(cmp::min(len, buffer.len()), self.frame_len() - written);
+        if len == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.inverted.get() {
+            for byte in buffer[..len].iter_mut() {
+                *byte = !*byte;
+            }
+        }
+        self.write_buffer.replace(buffer);
+        self.written.set(written + len);
+
+        let (wake, window) = if self.asleep.get() || written == 0 {
+            self.wake_steps()
+        } else {
+            (&[][..], &[][..])
+        };
+
+        let data: [Step; 6] = match self.screen.controller {
+            Controller::SSD1680 => {
+                // each chunk starts at the RAM address of its first byte
+                let (x, y, width, _) = self.frame.get();
+                let row_len = width / 8;
+                let mut parameters = self.parameters.get();
+                parameters[X_COUNTER] = (x / 8 + written % row_len) as u8;
+                let row = y + written / row_len;
+                parameters[Y_COUNTER] = (row & 0xFF) as u8;
+                parameters[Y_COUNTER + 1] = (row >> 8) as u8;
+                self.parameters.set(parameters);
+                let counters = [
+                    Step::Parameters(0x4E, X_COUNTER, 1),
+                    Step::Parameters(0x4F, Y_COUNTER, 2),
+                ];
+                if self.full_refresh.get() {
+                    // the previous image of the next partial refresh
+                    [
+                        counters[0],
+                        counters[1],
+                        Step::Data(Some(0x24), len),
+                        counters[0],
+                        counters[1],
+                        Step::Data(Some(0x26), len),
+                    ]
+                } else {
+                    [
+                        counters[0],
+                        counters[1],
+                        Step::Data(Some(0x24), len),
+                        Step::Nop,
+                        Step::Nop,
+                        Step::Nop,
+                    ]
+                }
+            }
+            Controller::IL0373 => {
+                let command = if written == 0 { Some(0x13) } else { None };
+                [
+                    Step::Data(command, len),
+                    Step::Nop,
+                    Step::Nop,
+                    Step::Nop,
+                    Step::Nop,
+                    Step::Nop,
+                ]
+            }
+        };
+
+        let (refresh, sleep): (&[Step], &[Step]) = if written + len < self.frame_len() {
+            (&[], &[])
+        } else {
+            self.asleep.set(true);
+            match (self.screen.controller, self.full_refresh.get()) {
+                (Controller::SSD1680, true) => (&SSD1680_FULL_REFRESH, &SSD1680_SLEEP),
+                (Controller::SSD1680, false) => (&SSD1680_PARTIAL_REFRESH, &SSD1680_SLEEP),
+                (Controller::IL0373, true) => (&IL0373_FULL_REFRESH, &IL0373_SLEEP),
+                (Controller::IL0373, false) => (&IL0373_PARTIAL_REFRESH, &IL0373_SLEEP),
+            }
+        };
+
+        let res = self.start(Operation::Write, &[wake, window, &data, refresh, sleep]);
+        if res.is_err() {
+            self.written.set(written);
+        }
+        res
+    }
+
+    fn next_step(&self) {
+        let position = self.position_in_sequence.get();
+        if position >= self.sequence_len.get() {
+            self.finish();
+            return;
+        }
+        self.position_in_sequence.set(position + 1);
+        let step = self
+            .sequence_buffer
+            .map_or(Step::Nop, |sequence| sequence[position]);
+        match step {
+            Step::Nop => self.next_step(),
+            Step::Reset => {
+                self.reset.clear();
+                self.set_delay(10, Status::ResetLow);
+            }
+            Step::WaitBusy => {
+                if self.is_busy() {
+                    self.set_delay(BUSY_POLL_MS, Status::WaitBusy(BUSY_POLL_MS));
+                } else {
+                    self.next_step();
+                }
+            }
+            Step::Delay(ms) => self.set_delay(ms, Status::Delay),
+            Step::Command(command, parameters) => {
+                let len = self.buffer.map_or(0, |buffer| {
+                    let len = cmp::min(parameters.len(), buffer.len());
+                    buffer[..len].copy_from_slice(&parameters[..len]);
+                    len
+                });
+                self.send_command(command, len);
+            }
+            Step::Parameters(command, position, len) => {
+                let parameters = self.parameters.get();
+                self.buffer.map(|buffer| {
+                    buffer[..len].copy_from_slice(&parameters[position..position + len]);
+                });
+                self.send_command(command, len);
+            }
+            Step::Data(Some(command), len) => {
+                self.status.set(Status::SendDataCommand(len));
+                self.dc.clear();
+                let _ = self.bus.set_addr(BusWidth::Bits8, command as usize);
+            }
+            Step::Data(None, len) => self.send_data(len),
+        }
+    }
+
+    fn send_command(&self, command: u8, parameters_len: usize) {
+        self.status.set(Status::SendCommand(parameters_len));
+        self.dc.clear();
+        let _ = self.bus.set_addr(BusWidth::Bits8, command as usize);
+    }
+
+    fn send_data(&self, len: usize) {
+        self.write_buffer.take().map(|buffer| {
+            self.status.set(Status::SendData);
+            self.dc.set();
+            let _ = self.bus.write(BusWidth::Bits8, buffer, len);
+        });
+    }
+
+    /// Report the end of the sequence to the client.
+    fn finish(&self) {
+        self.status.set(Status::Idle);
+        self.client.map(|client| match self.operation.get() {
+            Operation::Init => client.screen_is_ready(),
+            Operation::Command => client.command_complete(Ok(())),
+            Operation::Write => {
+                if self.written.get() >= self.frame_len() {
+                    self.written.set(0);
+                }
+                self.write_buffer
+                    .take()
+                    .map(|buffer| client.write_complete(buffer, Ok(())));
+            }
+        });
+    }
+
+    /// End the sequence with an error, leaving the controller to be reset
+    /// before the next write frame.
+    fn fail(&self, error: ErrorCode) {
+        self.status.set(Status::Idle);
+        self.asleep.set(true);
+        self.written.set(0);
+        self.client.map(|client| match self.operation.get() {
+            Operation::Init => client.screen_is_ready(),
+            Operation::Command => client.command_complete(Err(error)),
+            Operation::Write => {
+                self.write_buffer
+                    .take()
+                    .map(|buffer| client.write_complete(buffer, Err(error)));
+            }
+        });
+    }
+
+    fn set_delay(&self, ms: u32, next_status: Status) {
+        self.status.set(next_status);
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+}
+
+impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> screen::Screen for EPaper<'a, A, B, P> {
+    fn get_resolution(&self) -> (usize, usize) {
+        (self.screen.width, self.screen.height)
+    }
+
+    fn get_pixel_format(&self) -> ScreenPixelFormat {
+        ScreenPixelFormat::Mono
+    }
+
+    fn get_rotation(&self) -> ScreenRotation {
+        ScreenRotation::Normal
+    }
+
+    fn set_write_frame(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.status.get() != Status::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        if x % 8 != 0
+            || width % 8 != 0
+            || width == 0
+            || height == 0
+            || x + width > self.screen.width
+            || y + height > self.screen.height
+        {
+            return Err(ErrorCode::INVAL);
+        }
+
+        let (x_end, y_end) = (x + width - 1, y + height - 1);
+        let mut parameters = self.parameters.get();
+        parameters[X_WINDOW] = (x / 8) as u8;
+        parameters[X_WINDOW + 1] = (x_end / 8) as u8;
+        parameters[Y_WINDOW] = (y & 0xFF) as u8;
+        parameters[Y_WINDOW + 1] = (y >> 8) as u8;
+        parameters[Y_WINDOW + 2] = (y_end & 0xFF) as u8;
+        parameters[Y_WINDOW + 3] = (y_end >> 8) as u8;
+        parameters[PARTIAL_WINDOW] = x as u8;
+        parameters[PARTIAL_WINDOW + 1] = (x_end as u8) | 0x07;
+        parameters[PARTIAL_WINDOW + 2] = (y >> 8) as u8;
+        parameters[PARTIAL_WINDOW + 3] = (y & 0xFF) as u8;
+        parameters[PARTIAL_WINDOW + 4] = (y_end >> 8) as u8;
+        parameters[PARTIAL_WINDOW + 5] = (y_end & 0xFF) as u8;
+        parameters[PARTIAL_WINDOW + 6] = 0x01;
+        self.parameters.set(parameters);
+        self.frame.set((x, y, width, height));
+        self.written.set(0);
+
+        let full_refresh = match self.refresh_mode.get() {
+            RefreshMode::Full => true,
+            RefreshMode::Partial => {
+                let interval = self.full_refresh_interval.get();
+                if interval > 0 && self.partial_refreshes.get() >= interval {
+                    self.partial_refreshes.set(0);
+                    true
+                } else {
+                    self.partial_refreshes.set(self.partial_refreshes.get() + 1);
+                    false
+                }
+            }
+        };
+        self.full_refresh.set(full_refresh);
+
+        let (wake, window) = self.wake_steps();
+        self.start(Operation::Command, &[wake, window])
+    }
+
+    fn write(&self, buffer: &'static mut [u8], len: usize) -> Result<(), ErrorCode> {
+        self.written.set(0);
+        self.write_data(buffer, len)
+    }
+
+    fn write_continue(&self, buffer: &'static mut [u8], len: usize) -> Result<(), ErrorCode> {
+        self.write_data(buffer, len)
+    }
+
+    fn set_client(&self, client: Option<&'static dyn ScreenClient>) {
+        if let Some(client) = client {
+            self.client.set(client);
+        } else {
+            self.client.clear();
+        }
+    }
+
+    /// The panel keeps its image without power, and the controller is in
+    /// deep sleep between refreshes, so any brightness is accepted.
+    fn set_brightness(&self, _brightness: usize) -> Result<(), ErrorCode> {
+        self.start(Operation::Command, &[&[Step::Delay(1)]])
+    }
+
+    /// Inverts the colors of the following writes.
+    fn invert_on(&self) -> Result<(), ErrorCode> {
+        self.inverted.set(true);
+        self.start(Operation::Command, &[&[Step::Delay(1)]])
+    }
+
+    fn invert_off(&self) -> Result<(), ErrorCode> {
+        self.inverted.set(false);
+        self.start(Operation::Command, &[&[Step::Delay(1)]])
+    }
+}
+
+impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> time::AlarmClient for EPaper<'a, A, B, P> {
+    fn alarm(&self) {
+        match self.status.get() {
+            Status::ResetLow => {
+                self.reset.set();
+                self.set_delay(10, Status::ResetHigh);
+            }
+            Status::WaitBusy(waited) => {
+                if !self.is_busy() {
+                    self.next_step();
+                } else if waited >= BUSY_TIMEOUT_MS {
+                    self.fail(ErrorCode::FAIL);
+                } else {
+                    self.set_delay(BUSY_POLL_MS, Status::WaitBusy(waited + BUSY_POLL_MS));
+                }
+            }
+            Status::ResetHigh | Status::Delay => self.next_step(),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> bus::Client for EPaper<'a, A, B, P> {
+    fn command_complete(&self, buffer: Option<&'static mut [u8]>, _len: usize) {
+        if let Some(buffer) = buffer {
+            if self.status.get() == Status::SendData {
+                self.write_buffer.replace(buffer);
+            } else {
+                self.buffer.replace(buffer);
+            }
+        }
+
+        match self.status.get() {
+            Status::SendCommand(len) => {
+                if len > 0 {
+                    self.buffer.take().map(|buffer| {
+                        self.status.set(Status::SendParameters);
+                        self.dc.set();
+                        let _ = self.bus.write(BusWidth::Bits8, buffer, len);
+                    });
+                } else {
+                    self.next_step();
+                }
+            }
+            Status::SendDataCommand(len) => self.send_data(len),
+            Status::SendParameters | Status::SendData => self.next_step(),
+            _ => {}
+        }
+    }
+}
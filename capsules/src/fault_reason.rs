@@ -0,0 +1,211 @@
+//! Tells processes why a process faulted.
+//!
+//! With a fault policy that restarts processes, a process that crashed only
+//! sees that it started again. This capsule wraps the board's fault policy to
+//! deliver the fault reason, as reported by the architecture, to userspace:
+//!
+//! - a restarted process gets a "you crashed" upcall with the reason of its
+//!   last fault, so that it can log it or recover its state;
+//! - supervisor processes get an upcall whenever any other process faults,
+//!   to log diagnostics or decide what to do with it.
+//!
+//! On Cortex-M the reason is the CFSR, the HFSR and the faulting address from
+//! the MMFAR or BFAR, for example the address of an MPU violation. On RISC-V
+//! it is `mcause` and `mtval`. Faults forced by capsules have a zero reason.
+//!
+//! Usage
+//! -----
+//!
+//! The capsule is created before the processes are loaded, and passed to
+//! `load_processes()` as their fault policy.
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct FaultReasonCapability;
+//! unsafe impl capabilities::ProcessManagementCapability for FaultReasonCapability {}
+//!
+//! let fault_reason = static_init!(
+//!     capsules::fault_reason::FaultReason<'static, FaultReasonCapability>,
+//!     capsules::fault_reason::FaultReason::new(
+//!         board_kernel,
+//!         FaultReasonCapability,
+//!         board_kernel.create_grant(&memory_allocation_capability),
+//!         &RESTART_FAULT_POLICY
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Reasons are passed as three values: the fault status, the hard fault
+//! status, and the faulting address, or `0` if there is none.
+//!
+//! ### Subscribe
+//!
+//! - `0`: "You crashed" upcall, with the reason of the last fault of the
+//!   process. If the process restarted after a fault, it is scheduled as soon
+//!   as it subscribes, once per restart.
+//! - `1`: Supervisor upcall, called when another process faults, with the
+//!   identifier of the faulting process, its fault status and its faulting
+//!   address. Command `2` gets the hard fault status.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get the reason of the last fault of this process. Returns `FAIL` if
+//!   it did not restart after a fault.
+//! - `2`: Get the reason of the last fault of the process `data1`. Returns
+//!   `INVAL` if there is no such process, and `FAIL` if it has not faulted.
+
+use core::cell::Cell;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::procs::{FaultAction, FaultInfo, Process, ProcessFaultPolicy};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::FaultReason as usize;
+
+#[derive(Default)]
+pub struct App {
+    crashed: Upcall,
+    supervisor: Upcall,
+    /// Whether the "you crashed" upcall was scheduled since the process
+    /// started.
+    notified: bool,
+}
+
+pub struct FaultReason<'a, C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    capability: C,
+    apps: Grant<App>,
+    policy: &'a dyn ProcessFaultPolicy,
+}
+
+impl<'a, C: ProcessManagementCapability> FaultReason<'a, C> {
+    /// `policy` decides what to do with faulting processes.
+    pub fn new(
+        kernel: &'static Kernel,
+        capability: C,
+        grant: Grant<App>,
+        policy: &'a dyn ProcessFaultPolicy,
+    ) -> FaultReason<'a, C> {
+        FaultReason {
+            kernel: kernel,
+            capability: capability,
+            apps: grant,
+            policy: policy,
+        }
+    }
+
+    /// The last fault of the process `identifier`, or `None` if there is no
+    /// such process.
+    fn last_fault(&self, identifier: usize) -> Option<Option<FaultInfo>> {
+        let fault = Cell::new(None);
+        self.kernel
+            .process_each_capability(&self.capability, |proc| {
+                if proc.processid().id() == identifier {
+                    fault.set(Some(proc.get_last_fault()));
+                }
+            });
+        fault.get()
+    }
+
+    fn fault_return(fault: Option<FaultInfo>) -> CommandReturn {
+        match fault {
+            Some(fault) => CommandReturn::success_u32_u32_u32(
+                fault.status,
+                fault.hard_fault_status,
+                fault.address.unwrap_or(0),
+            ),
+            None => CommandReturn::failure(ErrorCode::FAIL),
+        }
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> ProcessFaultPolicy for FaultReason<'a, C> {
+    fn action(&self, process: &dyn Process) -> FaultAction {
+        let faulted = process.processid();
+        let fault = process.get_last_fault().unwrap_or_default();
+        self.apps.each(|processid, app| {
+            if processid != faulted {
+                app.supervisor.schedule(
+                    faulted.id(),
+                    fault.status as usize,
+                    fault.address.unwrap_or(0) as usize,
+                );
+            }
+        });
+        self.policy.action(process)
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> Driver for FaultReason<'a, C> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let fault = match self.last_fault(appid.id()) {
+            Some(fault) => fault,
+            None => return Err((callback, ErrorCode::INVAL)),
+        };
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    core::mem::swap(&mut app.crashed, &mut callback);
+                    if let Some(fault) = fault {
+                        if !app.notified {
+                            app.notified = app.crashed.schedule(
+                                fault.status as usize,
+                                fault.hard_fault_status as usize,
+                                fault.address.unwrap_or(0) as usize,
+                            );
+                        }
+                    }
+                    Ok(())
+                }
+                1 => {
+                    core::mem::swap(&mut app.supervisor, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // own last fault
+            1 => match self.last_fault(appid.id()) {
+                Some(fault) => Self::fault_return(fault),
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+
+            // last fault of another process
+            2 => match self.last_fault(data1) {
+                Some(fault) => Self::fault_return(fault),
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
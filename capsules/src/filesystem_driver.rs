@@ -0,0 +1,349 @@
+//! Provides userspace with access to the files of a filesystem.
+//!
+//! Each process opens one file at a time by name, and then reads it, writes
+//! it as a whole or deletes it. Processes can also list the files. There is
+//! no isolation between processes: they all share the files of the
+//! filesystem. One operation runs at a time, and others fail with `BUSY`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! let fs_driver = static_init!(
+//!     capsules::filesystem_driver::FileSystemDriver<'static>,
+//!     capsules::filesystem_driver::FileSystemDriver::new(
+//!         fs,
+//!         &mut capsules::filesystem_driver::BUFFER,
+//!         board_kernel.create_grant(&grant_cap),
+//!     )
+//! );
+//! hil::filesystem::FileSystem::set_client(fs, fs_driver);
+//! ```
+
+use core::cmp;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::filesystem::{self, FileName, MAX_NAME_LEN};
+use kernel::ErrorCode;
+use kernel::{
+    CommandReturn, Driver, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice,
+    Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::FileSystem as usize;
+
+/// Buffer for the content of reads and writes, which bounds the size of the
+/// files userspace can write.
+pub static mut BUFFER: [u8; 1024] = [0; 1024];
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    /// Name and length of the name of the open file.
+    file: Option<(FileName, usize)>,
+    name: ReadOnlyAppSlice,
+    buffer_write: ReadOnlyAppSlice,
+    buffer_read: ReadWriteAppSlice,
+}
+
+impl App {
+    fn file_name(&self) -> Result<(FileName, usize), ErrorCode> {
+        self.file.ok_or(ErrorCode::RESERVE)
+    }
+}
+
+pub struct FileSystemDriver<'a> {
+    fs: &'a dyn filesystem::FileSystem<'a>,
+    apps: Grant<App>,
+    buffer: TakeCell<'static, [u8]>,
+    /// The process whose operation is running.
+    current: OptionalCell<ProcessId>,
+}
+
+impl<'a> FileSystemDriver<'a> {
+    pub fn new(
+        fs: &'a dyn filesystem::FileSystem<'a>,
+        buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> FileSystemDriver<'a> {
+        FileSystemDriver {
+            fs: fs,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            current: OptionalCell::empty(),
+        }
+    }
+
+    /// Open the file named in the name buffer of `appid`.
+    fn open(&self, appid: ProcessId, create: bool) -> Result<usize, ErrorCode> {
+        self.apps
+            .enter(appid, |app| {
+                let mut name = [0; MAX_NAME_LEN];
+                let len = app.name.map_or(0, |slice| {
+                    // The name ends at the first zero, if any.
+                    let len = slice.iter().position(|b| *b == 0).unwrap_or(slice.len());
+                    if len <= MAX_NAME_LEN {
+                        name[..len].copy_from_slice(&slice[..len]);
+                    }
+                    len
+                });
+                if len == 0 || len > MAX_NAME_LEN {
+                    return Err(ErrorCode::SIZE);
+                }
+                let file_len = match self.fs.file_len(&name[..len]) {
+                    Ok(file_len) => file_len,
+                    Err(ErrorCode::INVAL) if create => 0,
+                    Err(e) => return Err(e),
+                };
+                app.file = Some((name, len));
+                Ok(file_len)
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    /// Start an operation of `appid` on its open file.
+    fn start<F>(&self, appid: ProcessId, operation: F) -> Result<(), ErrorCode>
+    where
+        F: FnOnce(&mut App, &[u8]) -> Result<(), ErrorCode>,
+    {
+        if self.current.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.apps
+            .enter(appid, |app| {
+                let (name, len) = app.file_name()?;
+                operation(app, &name[..len])
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+            .map(|()| self.current.set(appid))
+    }
+
+    /// Signal the end of the operation of the current process.
+    fn done(&self, result: Result<(), ErrorCode>, length: usize) {
+        self.current.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback
+                    .schedule(kernel::into_statuscode(result), length, 0);
+            });
+        });
+    }
+}
+
+impl filesystem::Client for FileSystemDriver<'_> {
+    fn mount_done(&self, _result: Result<(), ErrorCode>) {}
+
+    fn read_done(&self, result: Result<(), ErrorCode>, buffer: &'static mut [u8], length: usize) {
+        self.current.map(|appid| {
+            let _ = self.apps.enter(*appid, |app| {
+                app.buffer_read.mut_map_or((), |slice| {
+                    let len = cmp::min(length, slice.len());
+                    slice[..len].copy_from_slice(&buffer[..len]);
+                });
+            });
+        });
+        self.buffer.replace(buffer);
+        self.done(result, length);
+    }
+
+    fn write_done(&self, result: Result<(), ErrorCode>, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.done(result, length);
+    }
+
+    fn delete_done(&self, result: Result<(), ErrorCode>) {
+        self.done(result, 0);
+    }
+}
+
+impl Driver for FileSystemDriver<'_> {
+    /// Setup shared kernel-writable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer to read files and file names in to.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut slice, &mut app.buffer_read);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup shared kernel-readable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Name of the file to open, which ends at the first zero byte or
+    ///        at the end of the buffer.
+    /// - `1`: Content to write to the open file.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.name);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut slice, &mut app.buffer_write);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: A read, write or deletion is done. The callback signature is
+    ///        `fn(status: StatusCode, length: usize)`, with the number of
+    ///        bytes read or written.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Open the file named in read-only buffer 0, and return its
+    ///        length. Fails with `INVAL` if the file does not exist, unless
+    ///        the first argument is 1: the file is then created by the next
+    ///        write.
+    /// - `2`: Read the number of bytes in the second argument from the
+    ///        offset in the first argument of the open file, in to
+    ///        read-write buffer 0.
+    /// - `3`: Replace the content of the open file with the number of bytes
+    ///        in the first argument of read-only buffer 1.
+    /// - `4`: Delete the open file.
+    /// - `5`: Write the name of the file at the index in the first argument
+    ///        of the list of files to read-write buffer 0, padded with
+    ///        zeros, and return the length of the file. Fails with `INVAL`
+    ///        if there are fewer files.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // open
+            1 => match self.open(appid, data1 == 1) {
+                Ok(len) => CommandReturn::success_u32(len as u32),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // read
+            2 => self
+                .start(appid, |app, name| {
+                    let length = cmp::min(data2, app.buffer_read.len());
+                    self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
+                        self.fs
+                            .read(name, data1, buffer, length)
+                            .map_err(|(e, buffer)| {
+                                self.buffer.replace(buffer);
+                                e
+                            })
+                    })
+                })
+                .into(),
+
+            // write
+            3 => self
+                .start(appid, |app, name| {
+                    if data1 > app.buffer_write.len() {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
+                        if data1 > buffer.len() {
+                            self.buffer.replace(buffer);
+                            return Err(ErrorCode::SIZE);
+                        }
+                        app.buffer_write.map_or((), |slice| {
+                            buffer[..data1].copy_from_slice(&slice[..data1]);
+                        });
+                        self.fs.write(name, buffer, data1).map_err(|(e, buffer)| {
+                            self.buffer.replace(buffer);
+                            e
+                        })
+                    })
+                })
+                .into(),
+
+            // delete
+            4 => self.start(appid, |_app, name| self.fs.delete(name)).into(),
+
+            // list
+            5 => match self.fs.file(data1) {
+                Some((name, len)) => self
+                    .apps
+                    .enter(appid, |app| {
+                        app.buffer_read.mut_map_or((), |slice| {
+                            for (i, b) in slice.iter_mut().enumerate() {
+                                *b = name.get(i).copied().unwrap_or(0);
+                            }
+                        });
+                        CommandReturn::success_u32(len as u32)
+                    })
+                    .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
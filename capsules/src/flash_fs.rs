@@ -0,0 +1,836 @@
+//! Power-loss safe filesystem over flash pages.
+//!
+//! This capsule implements `hil::filesystem::FileSystem` on a region of
+//! flash pages accessed with `hil::flash::Flash`, in the spirit of littlefs:
+//! data is never overwritten in place, so an interrupted write or deletion
+//! leaves the previous state of the filesystem intact.
+//!
+//! ```text
+//! hil::filesystem::FileSystem
+//!        ┌─────────────┐
+//!        │             │
+//!        │ This module │
+//!        │             │
+//!        └─────────────┘
+//!       hil::flash::Flash
+//! ```
+//!
+//! Layout
+//! ------
+//!
+//! Every page of the region holds one chunk of one file, after a header:
+//!
+//! ```text
+//! | magic (4) | seq (4) | name (16) | chunk (2) | count (2) | len (2) | 0xffff (2) | crc (4) |
+//! ```
+//!
+//! Writing a file writes all of its chunks to free pages, with a sequence
+//! number larger than any other in the filesystem. The chunks form a new
+//! generation of the file, which replaces the previous one once all `count`
+//! chunks are on flash. A deleted file has a generation of a single page
+//! with a `count` of zero. The CRC-32 covers the header and the data of the
+//! page, so pages of interrupted writes are detected. When the filesystem is
+//! mounted, the newest complete generation of each file is kept, and every
+//! other page is reclaimed when free pages run out.
+//!
+//! Pages are used in turn, starting after the last written page, and pages
+//! are only erased right before they are reused. This spreads erases over
+//! the whole region.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! pub static mut PAGEBUFFER: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//! let fs = static_init!(
+//!     capsules::flash_fs::FlashFs<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::flash_fs::FlashFs::new(
+//!         &sam4l::flashcalw::FLASH_CONTROLLER,
+//!         &mut PAGEBUFFER,
+//!         0x60000 / 512, // First page of the region.
+//!         static_init!(
+//!             [capsules::flash_fs::PageInfo; 128],
+//!             [capsules::flash_fs::PageInfo::EMPTY; 128]
+//!         ),
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, fs);
+//! hil::filesystem::FileSystem::mount(fs).unwrap();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::filesystem::{self, FileName, MAX_NAME_LEN};
+use kernel::hil::flash::{self, Flash};
+use kernel::ErrorCode;
+
+/// Most files the filesystem can hold, counting the deleted files whose
+/// pages are not reclaimed yet.
+pub const MAX_FILES: usize = 16;
+
+const MAGIC: u32 = 0x5346_6b54;
+const HEADER_LEN: usize = 36;
+
+#[derive(Clone, Copy, PartialEq)]
+enum PageState {
+    /// Erased, ready to be written.
+    Erased,
+    /// A valid chunk of a file.
+    Used,
+    /// Neither erased nor valid.
+    Dirty,
+}
+
+/// What the filesystem knows of a page. The board provides one for every
+/// page of the region.
+#[derive(Clone, Copy)]
+pub struct PageInfo {
+    state: PageState,
+    slot: usize,
+    seq: u32,
+    chunk: u16,
+    count: u16,
+    len: u16,
+}
+
+impl PageInfo {
+    pub const EMPTY: PageInfo = PageInfo {
+        state: PageState::Dirty,
+        slot: 0,
+        seq: 0,
+        chunk: 0,
+        count: 0,
+        len: 0,
+    };
+}
+
+#[derive(Clone, Copy)]
+struct FileEntry {
+    used: bool,
+    name: FileName,
+    /// Sequence number of the current generation, or 0 if there is none.
+    seq: u32,
+    len: usize,
+    deleted: bool,
+}
+
+const NO_FILE: FileEntry = FileEntry {
+    used: false,
+    name: [0; MAX_NAME_LEN],
+    seq: 0,
+    len: 0,
+    deleted: true,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Unmounted,
+    /// Reading the header of the page at this index.
+    Mount(usize),
+    Idle,
+    Read,
+    Write,
+    Delete,
+}
+
+/// CRC-32 (IEEE 802.3) of `data`, continuing from `crc`.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in data.iter() {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// `name` padded with zeros, if it is a valid file name.
+fn file_name(name: &[u8]) -> Option<FileName> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains(&0) {
+        None
+    } else {
+        let mut padded = [0; MAX_NAME_LEN];
+        padded[..name.len()].copy_from_slice(name);
+        Some(padded)
+    }
+}
+
+pub struct FlashFs<'a, F: Flash + 'static> {
+    flash: &'a F,
+    pagebuffer: TakeCell<'static, F::Page>,
+    /// Bytes of file data a page holds.
+    capacity: usize,
+    /// First flash page of the region.
+    region_start: usize,
+    pages: TakeCell<'static, [PageInfo]>,
+    num_pages: usize,
+    files: Cell<[FileEntry; MAX_FILES]>,
+    /// Sequence number of the next generation.
+    next_seq: Cell<u32>,
+    /// Page to try first for the next write.
+    cursor: Cell<usize>,
+
+    state: Cell<State>,
+    client: OptionalCell<&'a dyn filesystem::Client>,
+    buffer: TakeCell<'static, [u8]>,
+    /// File, sequence number and chunk count of the current operation.
+    slot: Cell<usize>,
+    seq: Cell<u32>,
+    count: Cell<u16>,
+    /// Next chunk to write.
+    chunk: Cell<usize>,
+    /// Page being erased or written.
+    page: Cell<usize>,
+    /// Offset in the file, length and progress of the current operation.
+    offset: Cell<usize>,
+    length: Cell<usize>,
+    done: Cell<usize>,
+}
+
+impl<'a, F: Flash> FlashFs<'a, F> {
+    /// Create a filesystem on the pages from `region_start`, one for each
+    /// entry of `pages`.
+    pub fn new(
+        flash: &'a F,
+        pagebuffer: &'static mut F::Page,
+        region_start: usize,
+        pages: &'static mut [PageInfo],
+    ) -> FlashFs<'a, F> {
+        FlashFs {
+            flash: flash,
+            capacity: pagebuffer.as_mut().len().saturating_sub(HEADER_LEN),
+            pagebuffer: TakeCell::new(pagebuffer),
+            region_start: region_start,
+            num_pages: pages.len(),
+            pages: TakeCell::new(pages),
+            files: Cell::new([NO_FILE; MAX_FILES]),
+            next_seq: Cell::new(1),
+            cursor: Cell::new(0),
+            state: Cell::new(State::Unmounted),
+            client: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            slot: Cell::new(0),
+            seq: Cell::new(0),
+            count: Cell::new(0),
+            chunk: Cell::new(0),
+            page: Cell::new(0),
+            offset: Cell::new(0),
+            length: Cell::new(0),
+            done: Cell::new(0),
+        }
+    }
+
+    fn files(&self) -> &[Cell<FileEntry>] {
+        let files: &Cell<[FileEntry]> = &self.files;
+        files.as_slice_of_cells()
+    }
+
+    fn page_info(&self, page: usize) -> PageInfo {
+        self.pages.map_or(PageInfo::EMPTY, |pages| pages[page])
+    }
+
+    fn set_page_info(&self, page: usize, info: PageInfo) {
+        self.pages.map(|pages| pages[page] = info);
+    }
+
+    /// The slot of the file `name`, which may be deleted.
+    fn find_slot(&self, name: &FileName) -> Option<usize> {
+        self.files()
+            .iter()
+            .position(|file| file.get().used && file.get().name == *name)
+    }
+
+    /// The slot of an existing file `name`.
+    fn find_file(&self, name: &[u8]) -> Result<usize, ErrorCode> {
+        let name = file_name(name).ok_or(ErrorCode::SIZE)?;
+        self.find_slot(&name)
+            .filter(|slot| !self.files()[*slot].get().deleted)
+            .ok_or(ErrorCode::INVAL)
+    }
+
+    /// The slot of the file `name`, allocated if the file is not known.
+    fn find_or_alloc_slot(&self, name: &FileName) -> Option<usize> {
+        self.find_slot(name).or_else(|| {
+            let slot = self.files().iter().position(|file| !file.get().used)?;
+            self.files()[slot].set(FileEntry {
+                used: true,
+                name: *name,
+                ..NO_FILE
+            });
+            Some(slot)
+        })
+    }
+
+    /// Whether a generation of the file in `slot` is being written.
+    fn writing(&self, slot: usize) -> bool {
+        match self.state.get() {
+            State::Write | State::Delete => slot == self.slot.get(),
+            _ => false,
+        }
+    }
+
+    /// Free the slots of deleted files that have no pages left.
+    fn release_slots(&self) {
+        self.pages.map(|pages| {
+            for (slot, cell) in self.files().iter().enumerate() {
+                let file = cell.get();
+                if file.used
+                    && !self.writing(slot)
+                    && file.deleted
+                    && !pages
+                        .iter()
+                        .any(|info| info.state == PageState::Used && info.slot == slot)
+                {
+                    cell.set(NO_FILE);
+                }
+            }
+        });
+    }
+
+    /// Whether `page` can be erased without losing a file.
+    fn reclaimable(&self, page: usize) -> bool {
+        let info = self.page_info(page);
+        match info.state {
+            PageState::Erased => false,
+            PageState::Dirty => true,
+            PageState::Used => {
+                let file = self.files()[info.slot].get();
+                if self.writing(info.slot) && info.seq == self.seq.get() {
+                    // A chunk of the generation being written.
+                    false
+                } else if info.seq != file.seq {
+                    true
+                } else {
+                    // A deletion must stay on flash as long as older
+                    // generations of the file do.
+                    file.deleted
+                        && !self.pages.map_or(false, |pages| {
+                            pages.iter().any(|other| {
+                                other.state == PageState::Used
+                                    && other.slot == info.slot
+                                    && other.seq < info.seq
+                            })
+                        })
+                }
+            }
+        }
+    }
+
+    /// Pages that can be written, after an erase for some.
+    fn free_pages(&self) -> usize {
+        (0..self.num_pages)
+            .filter(|page| {
+                self.page_info(*page).state == PageState::Erased || self.reclaimable(*page)
+            })
+            .count()
+    }
+
+    /// The next page to write, and whether it must be erased first.
+    fn alloc_page(&self) -> Option<(usize, bool)> {
+        let start = self.cursor.get();
+        let candidates = (0..self.num_pages).map(|i| (start + i) % self.num_pages);
+        let page = candidates
+            .clone()
+            .find(|page| self.page_info(*page).state == PageState::Erased)
+            .map(|page| (page, false))
+            .or_else(|| {
+                candidates
+                    .clone()
+                    .find(|page| self.reclaimable(*page))
+                    .map(|page| (page, true))
+            });
+        page.map(|(page, _)| self.cursor.set((page + 1) % self.num_pages));
+        page
+    }
+
+    /// Read the next page of the region while mounting.
+    fn mount_next(&self, page: usize) {
+        if page >= self.num_pages {
+            self.mount_finish();
+            return;
+        }
+        self.state.set(State::Mount(page));
+        if let Some(buffer) = self.pagebuffer.take() {
+            if let Err((_, buffer)) = self.flash.read_page(self.region_start + page, buffer) {
+                self.pagebuffer.replace(buffer);
+                self.state.set(State::Unmounted);
+                self.client
+                    .map(|client| client.mount_done(Err(ErrorCode::FAIL)));
+            }
+        }
+    }
+
+    /// What the page in `buffer` holds. Fails if a new file cannot be
+    /// tracked.
+    fn parse_page(&self, buffer: &[u8]) -> Result<PageInfo, ErrorCode> {
+        if buffer.iter().all(|b| *b == 0xff) {
+            return Ok(PageInfo {
+                state: PageState::Erased,
+                ..PageInfo::EMPTY
+            });
+        }
+
+        let word =
+            |i: usize| u32::from_le_bytes([buffer[i], buffer[i + 1], buffer[i + 2], buffer[i + 3]]);
+        let half = |i: usize| u16::from_le_bytes([buffer[i], buffer[i + 1]]);
+        let mut name = [0; MAX_NAME_LEN];
+        name.copy_from_slice(&buffer[8..24]);
+        let (seq, chunk, count, len) = (word(4), half(24), half(26), half(28));
+
+        let valid_layout = word(0) == MAGIC
+            && seq != 0
+            && name[0] != 0
+            && (len as usize) <= buffer.len() - HEADER_LEN
+            && if count == 0 {
+                chunk == 0 && len == 0
+            } else {
+                chunk < count
+            };
+        if !valid_layout
+            || crc32(
+                crc32(0, &buffer[..32]),
+                &buffer[HEADER_LEN..HEADER_LEN + len as usize],
+            ) != word(32)
+        {
+            return Ok(PageInfo::EMPTY);
+        }
+
+        let slot = self.find_or_alloc_slot(&name).ok_or(ErrorCode::NOMEM)?;
+        Ok(PageInfo {
+            state: PageState::Used,
+            slot: slot,
+            seq: seq,
+            chunk: chunk,
+            count: count,
+            len: len,
+        })
+    }
+
+    /// Pick the newest complete generation of every file.
+    fn mount_finish(&self) {
+        self.pages.map(|pages| {
+            let mut last = None;
+            for (slot, cell) in self.files().iter().enumerate() {
+                if !cell.get().used {
+                    continue;
+                }
+                let used = || {
+                    pages
+                        .iter()
+                        .filter(move |info| info.state == PageState::Used && info.slot == slot)
+                };
+                // A generation is complete once all of its chunks are here.
+                let current = used()
+                    .filter(|info| {
+                        info.count == 0
+                            || used().filter(|other| other.seq == info.seq).count()
+                                == info.count as usize
+                    })
+                    .max_by_key(|info| info.seq);
+                cell.set(FileEntry {
+                    seq: current.map_or(0, |info| info.seq),
+                    len: current.map_or(0, |info| {
+                        used()
+                            .filter(|other| other.seq == info.seq)
+                            .map(|other| other.len as usize)
+                            .sum()
+                    }),
+                    deleted: current.map_or(true, |info| info.count == 0),
+                    ..cell.get()
+                });
+            }
+            for (page, info) in pages.iter().enumerate() {
+                if info.state == PageState::Used && last.map_or(true, |(_, seq)| info.seq > seq) {
+                    last = Some((page, info.seq));
+                }
+            }
+            if let Some((page, seq)) = last {
+                self.next_seq.set(seq + 1);
+                self.cursor.set((page + 1) % self.num_pages);
+            }
+        });
+        self.state.set(State::Idle);
+        self.client.map(|client| client.mount_done(Ok(())));
+    }
+
+    /// Check that a new operation can start.
+    fn check_idle(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Idle => Ok(()),
+            State::Unmounted => Err(ErrorCode::OFF),
+            _ => Err(ErrorCode::BUSY),
+        }
+    }
+
+    /// Start writing a generation of `count` chunks of the file `name`.
+    fn start_generation(&self, name: &[u8], count: usize) -> Result<(), ErrorCode> {
+        let name = file_name(name).ok_or(ErrorCode::SIZE)?;
+        if count > u16::MAX as usize {
+            return Err(ErrorCode::SIZE);
+        }
+        let slot = self.find_or_alloc_slot(&name).ok_or(ErrorCode::NOMEM)?;
+        if self.free_pages() < cmp::max(count, 1) {
+            self.release_slots();
+            return Err(ErrorCode::NOMEM);
+        }
+        self.slot.set(slot);
+        self.seq.set(self.next_seq.get());
+        self.next_seq.set(self.next_seq.get() + 1);
+        self.count.set(count as u16);
+        self.chunk.set(0);
+        Ok(())
+    }
+
+    /// Write the next chunk of the generation, or finish it.
+    fn write_next(&self) {
+        if self.chunk.get() >= cmp::max(self.count.get() as usize, 1) {
+            self.finish_generation();
+            return;
+        }
+        match self.alloc_page() {
+            None => self.fail(ErrorCode::NOMEM),
+            Some((page, erase)) => {
+                self.page.set(page);
+                if erase {
+                    self.set_page_info(page, PageInfo::EMPTY);
+                    if let Err(e) = self.flash.erase_page(self.region_start + page) {
+                        self.fail(e);
+                    }
+                } else {
+                    self.write_chunk();
+                }
+            }
+        }
+    }
+
+    /// Write the next chunk to the erased page `self.page`.
+    fn write_chunk(&self) {
+        let capacity = self.capacity;
+        let chunk = self.chunk.get();
+        let start = cmp::min(chunk * capacity, self.length.get());
+        let len = cmp::min(capacity, self.length.get() - start);
+        let name = self.files()[self.slot.get()].get().name;
+
+        let result = self
+            .pagebuffer
+            .take()
+            .map_or(Err(ErrorCode::BUSY), |pagebuffer| {
+                {
+                    let page = pagebuffer.as_mut();
+                    for b in page.iter_mut() {
+                        *b = 0xff;
+                    }
+                    page[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+                    page[4..8].copy_from_slice(&self.seq.get().to_le_bytes());
+                    page[8..24].copy_from_slice(&name);
+                    page[24..26].copy_from_slice(&(chunk as u16).to_le_bytes());
+                    page[26..28].copy_from_slice(&self.count.get().to_le_bytes());
+                    page[28..30].copy_from_slice(&(len as u16).to_le_bytes());
+                    self.buffer.map(|buffer| {
+                        page[HEADER_LEN..HEADER_LEN + len]
+                            .copy_from_slice(&buffer[start..start + len])
+                    });
+                    let crc = crc32(crc32(0, &page[..32]), &page[HEADER_LEN..HEADER_LEN + len]);
+                    page[32..36].copy_from_slice(&crc.to_le_bytes());
+                }
+                // An interrupted write leaves the page dirty.
+                self.set_page_info(self.page.get(), PageInfo::EMPTY);
+                self.flash
+                    .write_page(self.region_start + self.page.get(), pagebuffer)
+                    .map_err(|(e, pagebuffer)| {
+                        self.pagebuffer.replace(pagebuffer);
+                        e
+                    })
+            });
+        if let Err(e) = result {
+            self.fail(e);
+        }
+    }
+
+    /// All chunks are written, the generation replaces the previous one.
+    fn finish_generation(&self) {
+        let deleting = self.state.get() == State::Delete;
+        let slot = &self.files()[self.slot.get()];
+        slot.set(FileEntry {
+            seq: self.seq.get(),
+            len: self.length.get(),
+            deleted: deleting,
+            ..slot.get()
+        });
+        self.state.set(State::Idle);
+        if deleting {
+            self.client.map(|client| client.delete_done(Ok(())));
+        } else {
+            let length = self.length.get();
+            self.buffer.take().map(|buffer| {
+                self.client
+                    .map(move |client| client.write_done(Ok(()), buffer, length));
+            });
+        }
+    }
+
+    /// End the current operation with an error.
+    fn fail(&self, error: ErrorCode) {
+        let state = self.state.replace(State::Idle);
+        self.release_slots();
+        match state {
+            State::Read | State::Write => {
+                self.buffer.take().map(|buffer| {
+                    self.client.map(move |client| {
+                        if state == State::Read {
+                            client.read_done(Err(error), buffer, 0)
+                        } else {
+                            client.write_done(Err(error), buffer, 0)
+                        }
+                    });
+                });
+            }
+            State::Delete => {
+                self.client.map(|client| client.delete_done(Err(error)));
+            }
+            State::Unmounted | State::Mount(_) | State::Idle => {}
+        }
+    }
+
+    /// Read the page of the next chunk of a read.
+    fn read_next(&self) {
+        let position = self.offset.get() + self.done.get();
+        let chunk = position / self.capacity;
+        let file = self.files()[self.slot.get()].get();
+        let page = self.pages.map_or(None, |pages| {
+            pages.iter().position(|info| {
+                info.state == PageState::Used
+                    && info.slot == self.slot.get()
+                    && info.seq == file.seq
+                    && info.chunk as usize == chunk
+            })
+        });
+        let result = page.map_or(Err(ErrorCode::FAIL), |page| {
+            self.pagebuffer
+                .take()
+                .map_or(Err(ErrorCode::BUSY), |pagebuffer| {
+                    self.flash
+                        .read_page(self.region_start + page, pagebuffer)
+                        .map_err(|(e, pagebuffer)| {
+                            self.pagebuffer.replace(pagebuffer);
+                            e
+                        })
+                })
+        });
+        if let Err(e) = result {
+            self.fail(e);
+        }
+    }
+}
+
+impl<'a, F: Flash> filesystem::FileSystem<'a> for FlashFs<'a, F> {
+    fn set_client(&self, client: &'a dyn filesystem::Client) {
+        self.client.set(client);
+    }
+
+    fn mount(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Unmounted | State::Idle => {}
+            _ => return Err(ErrorCode::BUSY),
+        }
+        if self.num_pages == 0 || self.capacity == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        self.files.set([NO_FILE; MAX_FILES]);
+        self.pages.map(|pages| {
+            for info in pages.iter_mut() {
+                *info = PageInfo::EMPTY;
+            }
+        });
+        self.next_seq.set(1);
+        self.cursor.set(0);
+        self.mount_next(0);
+        Ok(())
+    }
+
+    fn file_len(&self, name: &[u8]) -> Result<usize, ErrorCode> {
+        self.find_file(name)
+            .map(|slot| self.files()[slot].get().len)
+    }
+
+    fn file(&self, index: usize) -> Option<(FileName, usize)> {
+        self.files()
+            .iter()
+            .map(|file| file.get())
+            .filter(|file| file.used && !file.deleted)
+            .nth(index)
+            .map(|file| (file.name, file.len))
+    }
+
+    fn read(
+        &self,
+        name: &[u8],
+        offset: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(e) = self.check_idle() {
+            return Err((e, buffer));
+        }
+        let slot = match self.find_file(name) {
+            Ok(slot) => slot,
+            Err(e) => return Err((e, buffer)),
+        };
+        let file_len = self.files()[slot].get().len;
+        let length = cmp::min(
+            cmp::min(length, buffer.len()),
+            file_len.saturating_sub(offset),
+        );
+        if length == 0 {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+
+        self.state.set(State::Read);
+        self.slot.set(slot);
+        self.offset.set(offset);
+        self.length.set(length);
+        self.done.set(0);
+        self.buffer.replace(buffer);
+        self.read_next();
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        name: &[u8],
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(e) = self.check_idle() {
+            return Err((e, buffer));
+        }
+        if length > buffer.len() {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+        let capacity = self.capacity;
+        let count = cmp::max((length + capacity - 1) / capacity, 1);
+        if let Err(e) = self.start_generation(name, count) {
+            return Err((e, buffer));
+        }
+
+        self.state.set(State::Write);
+        self.length.set(length);
+        self.buffer.replace(buffer);
+        self.write_next();
+        Ok(())
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), ErrorCode> {
+        self.check_idle()?;
+        self.find_file(name)?;
+        self.start_generation(name, 0)?;
+
+        self.state.set(State::Delete);
+        self.length.set(0);
+        self.write_next();
+        Ok(())
+    }
+}
+
+impl<'a, F: Flash> flash::Client<F> for FlashFs<'a, F> {
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, error: flash::Error) {
+        match self.state.get() {
+            State::Mount(page) => {
+                let info = match error {
+                    flash::Error::CommandComplete => self.parse_page(pagebuffer.as_mut()),
+                    flash::Error::FlashError => Ok(PageInfo::EMPTY),
+                };
+                self.pagebuffer.replace(pagebuffer);
+                match info {
+                    Ok(info) => {
+                        self.set_page_info(page, info);
+                        self.mount_next(page + 1);
+                    }
+                    Err(e) => {
+                        self.state.set(State::Unmounted);
+                        self.client.map(|client| client.mount_done(Err(e)));
+                    }
+                }
+            }
+            State::Read => {
+                let capacity = self.capacity;
+                let position = self.offset.get() + self.done.get();
+                let start = HEADER_LEN + position % capacity;
+                let len = cmp::min(
+                    capacity - position % capacity,
+                    self.length.get() - self.done.get(),
+                );
+                let done = self.done.get();
+                self.buffer.map(|buffer| {
+                    buffer[done..done + len]
+                        .copy_from_slice(&pagebuffer.as_mut()[start..start + len])
+                });
+                self.pagebuffer.replace(pagebuffer);
+
+                if error != flash::Error::CommandComplete {
+                    self.fail(ErrorCode::FAIL);
+                } else if done + len < self.length.get() {
+                    self.done.set(done + len);
+                    self.read_next();
+                } else {
+                    self.state.set(State::Idle);
+                    let length = self.length.get();
+                    self.buffer.take().map(|buffer| {
+                        self.client
+                            .map(move |client| client.read_done(Ok(()), buffer, length));
+                    });
+                }
+            }
+            State::Unmounted | State::Idle | State::Write | State::Delete => {
+                self.pagebuffer.replace(pagebuffer);
+            }
+        }
+    }
+
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: flash::Error) {
+        self.pagebuffer.replace(pagebuffer);
+        match error {
+            flash::Error::CommandComplete => {
+                let chunk = self.chunk.get();
+                self.set_page_info(
+                    self.page.get(),
+                    PageInfo {
+                        state: PageState::Used,
+                        slot: self.slot.get(),
+                        seq: self.seq.get(),
+                        chunk: chunk as u16,
+                        count: self.count.get(),
+                        len: cmp::min(
+                            self.capacity,
+                            self.length.get() - cmp::min(chunk * self.capacity, self.length.get()),
+                        ) as u16,
+                    },
+                );
+                self.chunk.set(chunk + 1);
+                self.write_next();
+            }
+            flash::Error::FlashError => self.fail(ErrorCode::FAIL),
+        }
+    }
+
+    fn erase_complete(&self, error: flash::Error) {
+        match error {
+            flash::Error::CommandComplete => {
+                self.set_page_info(
+                    self.page.get(),
+                    PageInfo {
+                        state: PageState::Erased,
+                        ..PageInfo::EMPTY
+                    },
+                );
+                self.release_slots();
+                self.write_chunk();
+            }
+            flash::Error::FlashError => self.fail(ErrorCode::FAIL),
+        }
+    }
+}
@@ -46,25 +46,154 @@
 //!
 //! The GPIO interface provides only one callback, which is used for pins that
 //! have had interrupts enabled.
+//!
+//! ### Shift Out
+//!
+//! For simple shift-register chains (such as the 74HC595) on boards without a
+//! spare SPI peripheral, the capsule can clock bytes out on a data pin,
+//! toggling a clock pin once per bit and pulsing a latch pin once all bits
+//! have been shifted. The bits are clocked out in the kernel so that every
+//! byte of a transfer is shifted with the same timing. As the kernel does
+//! nothing else meanwhile, a transfer is at most `MAX_SHIFT_OUT_LEN` bytes
+//! long.
+//!
+//! By default the pins toggle as fast as the CPU can drive them. For slower
+//! shift registers, or long wires, an app can set a half-period that the
+//! capsule waits for between edges. This busy-waits in the kernel, so it
+//! needs a `ShiftOutDelay` from the board, and the half-period is bounded by
+//! `MAX_SHIFT_OUT_HALF_PERIOD_US`:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let shift_out_delay = static_init!(
+//!     capsules::gpio::TimeDelay<'static, nrf52840::rtc::Rtc<'static>>,
+//!     capsules::gpio::TimeDelay::new(&base_peripherals.rtc)
+//! );
+//! gpio.set_shift_out_delay(shift_out_delay);
+//! ```
 
 /// Syscall driver number.
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Gpio as usize;
 
 use core::mem;
+use kernel::common::cells::OptionalCell;
 use kernel::hil::gpio;
 use kernel::hil::gpio::{Configure, Input, InterruptWithValue, Output};
-use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::hil::time::{Frequency, Ticks, Time};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, Upcall};
+
+/// Longest half-period of the shift out clock, in microseconds.
+pub const MAX_SHIFT_OUT_HALF_PERIOD_US: usize = 100;
+
+/// Most bytes shifted out by a single command. With the longest half-period
+/// this keeps the kernel busy for about 100 ms.
+pub const MAX_SHIFT_OUT_LEN: usize = 64;
+
+/// Order in which the bits of each byte are shifted out.
+#[derive(Copy, Clone, PartialEq)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+/// Pins used to shift bytes out to a shift register, and how fast.
+#[derive(Copy, Clone)]
+pub struct ShiftOutConfig {
+    data: usize,
+    clock: usize,
+    latch: usize,
+    order: BitOrder,
+    half_period_us: u32,
+}
+
+/// Waits between the edges of the shift out clock.
+pub trait ShiftOutDelay {
+    /// Busy-wait for at least `us` microseconds.
+    fn delay_us(&self, us: u32);
+}
+
+/// A `ShiftOutDelay` that busy-waits on a `Time`. Delays are rounded up to
+/// whole ticks of `T`.
+pub struct TimeDelay<'a, T: Time> {
+    time: &'a T,
+}
+
+impl<'a, T: Time> TimeDelay<'a, T> {
+    pub fn new(time: &'a T) -> TimeDelay<'a, T> {
+        TimeDelay { time: time }
+    }
+}
+
+impl<'a, T: Time> ShiftOutDelay for TimeDelay<'a, T> {
+    fn delay_us(&self, us: u32) {
+        let ticks = (us as u64 * T::Frequency::frequency() as u64 + 999_999) / 1_000_000;
+        let start = self.time.now();
+        // Wait for one more tick, as `start` can be almost a tick old.
+        let end = start.wrapping_add(T::Ticks::from(ticks as u32 + 1));
+        while self.time.now().within_range(start, end) {}
+    }
+}
+
+/// Clock `bytes` out on `data` and then pulse `latch`. The data pin is set
+/// up while the clock is low and sampled by the shift register on the rising
+/// clock edge. With a `delay`, each level of the clock and latch pins is
+/// held for at least `half_period_us`.
+fn clock_out<I: Iterator<Item = u8>>(
+    data: &dyn Output,
+    clock: &dyn Output,
+    latch: &dyn Output,
+    order: BitOrder,
+    half_period_us: u32,
+    delay: Option<&dyn ShiftOutDelay>,
+    bytes: I,
+) {
+    let wait = || {
+        if half_period_us > 0 {
+            delay.map(|delay| delay.delay_us(half_period_us));
+        }
+    };
+    for byte in bytes {
+        for bit in 0..8 {
+            let shift = match order {
+                BitOrder::MsbFirst => 7 - bit,
+                BitOrder::LsbFirst => bit,
+            };
+            if (byte >> shift) & 1 == 1 {
+                data.set();
+            } else {
+                data.clear();
+            }
+            wait();
+            clock.set();
+            wait();
+            clock.clear();
+        }
+    }
+    wait();
+    latch.set();
+    wait();
+    latch.clear();
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    shift_out: Option<ShiftOutConfig>,
+    shift_out_buffer: ReadOnlyAppSlice,
+}
 
 pub struct GPIO<'a, IP: gpio::InterruptPin<'a>> {
     pins: &'a [Option<&'a gpio::InterruptValueWrapper<'a, IP>>],
-    apps: Grant<Upcall>,
+    apps: Grant<App>,
+    shift_out_delay: OptionalCell<&'a dyn ShiftOutDelay>,
 }
 
 impl<'a, IP: gpio::InterruptPin<'a>> GPIO<'a, IP> {
     pub fn new(
         pins: &'a [Option<&'a gpio::InterruptValueWrapper<'a, IP>>],
-        grant: Grant<Upcall>,
+        grant: Grant<App>,
     ) -> Self {
         for (i, maybe_pin) in pins.iter().enumerate() {
             if let Some(pin) = maybe_pin {
@@ -74,9 +203,15 @@ impl<'a, IP: gpio::InterruptPin<'a>> GPIO<'a, IP> {
         Self {
             pins: pins,
             apps: grant,
+            shift_out_delay: OptionalCell::empty(),
         }
     }
 
+    /// Let apps slow down the shift out clock by waiting with `delay`.
+    pub fn set_shift_out_delay(&self, delay: &'a dyn ShiftOutDelay) {
+        self.shift_out_delay.set(delay);
+    }
+
     fn configure_input_pin(&self, pin_num: u32, config: usize) -> CommandReturn {
         let maybe_pin = self.pins[pin_num as usize];
         if let Some(pin) = maybe_pin {
@@ -127,6 +262,120 @@ impl<'a, IP: gpio::InterruptPin<'a>> GPIO<'a, IP> {
             CommandReturn::failure(ErrorCode::NODEVICE)
         }
     }
+
+    fn configure_shift_out(&self, pins: usize, order: usize, appid: ProcessId) -> CommandReturn {
+        let data = pins & 0xff;
+        let clock = (pins >> 8) & 0xff;
+        let latch = (pins >> 16) & 0xff;
+        let order = match order {
+            0 => BitOrder::MsbFirst,
+            1 => BitOrder::LsbFirst,
+            _ => return CommandReturn::failure(ErrorCode::INVAL),
+        };
+        if pins >> 24 != 0 || data == clock || data == latch || clock == latch {
+            return CommandReturn::failure(ErrorCode::INVAL);
+        }
+        for &index in [data, clock, latch].iter() {
+            match self.pins.get(index) {
+                Some(Some(_)) => {}
+                Some(None) => return CommandReturn::failure(ErrorCode::NODEVICE),
+                None => return CommandReturn::failure(ErrorCode::INVAL),
+            }
+        }
+
+        let res = self.apps.enter(appid, |app| {
+            app.shift_out = Some(ShiftOutConfig {
+                data: data,
+                clock: clock,
+                latch: latch,
+                order: order,
+                half_period_us: 0,
+            });
+        });
+        match res {
+            Ok(()) => {
+                for &index in [data, clock, latch].iter() {
+                    self.pins[index].map(|pin| {
+                        pin.make_output();
+                        pin.clear();
+                    });
+                }
+                CommandReturn::success()
+            }
+            Err(e) => CommandReturn::failure(e.into()),
+        }
+    }
+
+    fn set_shift_out_half_period(&self, us: usize, appid: ProcessId) -> CommandReturn {
+        if us > MAX_SHIFT_OUT_HALF_PERIOD_US {
+            return CommandReturn::failure(ErrorCode::INVAL);
+        }
+        if us > 0 && self.shift_out_delay.is_none() {
+            return CommandReturn::failure(ErrorCode::NOSUPPORT);
+        }
+        self.apps
+            .enter(appid, |app| match app.shift_out.as_mut() {
+                Some(config) => {
+                    config.half_period_us = us as u32;
+                    CommandReturn::success()
+                }
+                None => CommandReturn::failure(ErrorCode::RESERVE),
+            })
+            .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+    }
+
+    /// Clock `bytes` out on the configured data pin and then pulse the latch
+    /// pin.
+    fn shift_out<I: Iterator<Item = u8>>(&self, config: ShiftOutConfig, bytes: I) {
+        if let (Some(data), Some(clock), Some(latch)) = (
+            self.pins[config.data],
+            self.pins[config.clock],
+            self.pins[config.latch],
+        ) {
+            clock_out(
+                data,
+                clock,
+                latch,
+                config.order,
+                config.half_period_us,
+                self.shift_out_delay.extract(),
+                bytes,
+            );
+        }
+    }
+
+    fn shift_out_byte(&self, byte: usize, appid: ProcessId) -> CommandReturn {
+        if byte > 0xff {
+            return CommandReturn::failure(ErrorCode::INVAL);
+        }
+        self.apps
+            .enter(appid, |app| {
+                app.shift_out
+                    .map_or(CommandReturn::failure(ErrorCode::RESERVE), |config| {
+                        self.shift_out(config, core::iter::once(byte as u8));
+                        CommandReturn::success()
+                    })
+            })
+            .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+    }
+
+    fn shift_out_buffer(&self, len: usize, appid: ProcessId) -> CommandReturn {
+        self.apps
+            .enter(appid, |app| {
+                let config = match app.shift_out {
+                    Some(config) => config,
+                    None => return CommandReturn::failure(ErrorCode::RESERVE),
+                };
+                if len == 0 || len > MAX_SHIFT_OUT_LEN || app.shift_out_buffer.len() < len {
+                    return CommandReturn::failure(ErrorCode::SIZE);
+                }
+                app.shift_out_buffer.map_or((), |buffer| {
+                    self.shift_out(config, buffer[..len].iter().copied());
+                });
+                CommandReturn::success()
+            })
+            .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+    }
 }
 
 impl<'a, IP: gpio::InterruptPin<'a>> gpio::ClientWithValue for GPIO<'a, IP> {
@@ -137,8 +386,9 @@ impl<'a, IP: gpio::InterruptPin<'a>> gpio::ClientWithValue for GPIO<'a, IP> {
             let pin_state = pin.read();
 
             // schedule callback with the pin number and value
-            self.apps.each(|_, callback| {
-                callback.schedule(pin_num as usize, pin_state as usize, 0);
+            self.apps.each(|_, app| {
+                app.callback
+                    .schedule(pin_num as usize, pin_state as usize, 0);
             });
         }
     }
@@ -163,7 +413,7 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
             0 => self
                 .apps
                 .enter(app_id, |app| {
-                    mem::swap(&mut **app, &mut callback);
+                    mem::swap(&mut app.callback, &mut callback);
                 })
                 .map_err(ErrorCode::from),
             // default
@@ -176,6 +426,32 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
         }
     }
 
+    /// Share a buffer of bytes to shift out.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Bytes shifted out by command `12`.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.shift_out_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
     /// Query and control pin values and states.
     ///
     /// Each byte of the `data` argument is treated as its own field.
@@ -193,6 +469,9 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
     ///                   Set to `0` to interrupt on either edge.
     ///                   Set to `1` for rising edge.
     ///                   Set to `2` for falling edge.
+    ///   - `shift_pins`: Pins used to shift out bytes. The lowest byte is the
+    ///                   data pin, the second byte the clock pin and the
+    ///                   third byte the latch pin.
     ///
     /// ### `command_num`
     ///
@@ -206,12 +485,20 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
     /// - `7`: Configure interrupt on `pin` with `irq_config` in 0x00XX00000
     /// - `8`: Disable interrupt on `pin`.
     /// - `9`: Disable `pin`.
+    /// - `10`: Configure shift out on `shift_pins`, with the bit order in
+    ///         `data2`: `0` for MSB first, `1` for LSB first.
+    /// - `11`: Shift out the byte in `data1` and pulse the latch pin.
+    /// - `12`: Shift out the first `data1` bytes of the allowed buffer, at
+    ///         most `MAX_SHIFT_OUT_LEN`, and pulse the latch pin.
+    /// - `13`: Set the half-period of the shift out clock to `data1`
+    ///         microseconds, at most `MAX_SHIFT_OUT_HALF_PERIOD_US`. `0`, the
+    ///         default, toggles the pins as fast as possible.
     fn command(
         &self,
         command_num: usize,
         data1: usize,
         data2: usize,
-        _: ProcessId,
+        appid: ProcessId,
     ) -> CommandReturn {
         let pins = self.pins.as_ref();
         let pin_index = data1;
@@ -349,8 +636,303 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
                 }
             }
 
+            // configure shift out
+            10 => self.configure_shift_out(data1, data2, appid),
+
+            // shift out a byte
+            11 => self.shift_out_byte(data1, appid),
+
+            // shift out the allowed buffer
+            12 => self.shift_out_buffer(data1, appid),
+
+            // set the shift out clock half-period
+            13 => self.set_shift_out_half_period(data1, appid),
+
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{clock_out, BitOrder, ShiftOutDelay, GPIO, MAX_SHIFT_OUT_LEN};
+    use crate::test::mock_process;
+    use core::cell::Cell;
+    use kernel::hil::gpio::{
+        self, Configuration, FloatingState, InterruptEdge, InterruptValueWrapper, Output,
+    };
+    use kernel::{Driver, ErrorCode};
+
+    const DATA: u8 = 0;
+    const CLOCK: u8 = 1;
+    const LATCH: u8 = 2;
+
+    /// Records the level changes of the pins, with a fake time in
+    /// microseconds advanced by the delay.
+    struct Log {
+        now: Cell<u32>,
+        // (pin, level, time)
+        events: [Cell<(u8, bool, u32)>; 32],
+        len: Cell<usize>,
+    }
+
+    impl Log {
+        fn new() -> Log {
+            Log {
+                now: Cell::new(0),
+                events: Default::default(),
+                len: Cell::new(0),
+            }
+        }
+
+        fn record(&self, pin: u8, level: bool) {
+            self.events[self.len.get()].set((pin, level, self.now.get()));
+            self.len.set(self.len.get() + 1);
+        }
+
+        /// The events of `pin`.
+        fn of(&self, pin: u8) -> impl Iterator<Item = (bool, u32)> + '_ {
+            self.events[..self.len.get()]
+                .iter()
+                .map(|event| event.get())
+                .filter(move |event| event.0 == pin)
+                .map(|(_, level, time)| (level, time))
+        }
+    }
+
+    struct MockPin<'a> {
+        id: u8,
+        log: &'a Log,
+    }
+
+    impl Output for MockPin<'_> {
+        fn set(&self) {
+            self.log.record(self.id, true);
+        }
+
+        fn clear(&self) {
+            self.log.record(self.id, false);
+        }
+
+        fn toggle(&self) -> bool {
+            unimplemented!()
+        }
+    }
+
+    impl ShiftOutDelay for Log {
+        fn delay_us(&self, us: u32) {
+            self.now.set(self.now.get() + us);
+        }
+    }
+
+    /// The data levels sampled on the rising clock edges, in order.
+    fn sampled_bits(log: &Log) -> [bool; 8] {
+        let mut bits = [false; 8];
+        let mut data = false;
+        let mut n = 0;
+        for event in log.events[..log.len.get()].iter().map(|e| e.get()) {
+            match event {
+                (DATA, level, _) => data = level,
+                (CLOCK, true, _) => {
+                    bits[n] = data;
+                    n += 1;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(n, 8);
+        bits
+    }
+
+    fn run(log: &Log, byte: u8, order: BitOrder, half_period_us: u32) {
+        let data = MockPin { id: DATA, log: log };
+        let clock = MockPin {
+            id: CLOCK,
+            log: log,
+        };
+        let latch = MockPin {
+            id: LATCH,
+            log: log,
+        };
+        clock_out(
+            &data,
+            &clock,
+            &latch,
+            order,
+            half_period_us,
+            Some(log),
+            core::iter::once(byte),
+        );
+    }
+
+    #[test]
+    fn test_bit_order() {
+        let log = Log::new();
+        run(&log, 0b1000_0110, BitOrder::MsbFirst, 0);
+        assert_eq!(
+            sampled_bits(&log),
+            [true, false, false, false, false, true, true, false]
+        );
+
+        let log = Log::new();
+        run(&log, 0b1000_0110, BitOrder::LsbFirst, 0);
+        assert_eq!(
+            sampled_bits(&log),
+            [false, true, true, false, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_no_delay() {
+        let log = Log::new();
+        run(&log, 0xa5, BitOrder::MsbFirst, 0);
+        assert!(log.of(CLOCK).all(|(_, time)| time == 0));
+        assert_eq!(log.of(LATCH).count(), 2);
+    }
+
+    #[test]
+    fn test_half_period() {
+        let log = Log::new();
+        run(&log, 0xa5, BitOrder::MsbFirst, 10);
+
+        // Each clock level lasts one half-period.
+        let mut previous: Option<u32> = None;
+        let mut edges = 0;
+        for (_, time) in log.of(CLOCK) {
+            if let Some(previous) = previous {
+                assert_eq!(time - previous, 10);
+            }
+            previous = Some(time);
+            edges += 1;
+        }
+        assert_eq!(edges, 16);
+
+        // The data is set up a half-period before each rising edge.
+        let data: [(bool, u32); 8] = {
+            let mut data = [(false, 0); 8];
+            for (i, event) in log.of(DATA).enumerate() {
+                data[i] = event;
+            }
+            data
+        };
+        for (i, (level, time)) in log.of(CLOCK).enumerate() {
+            if level {
+                assert_eq!(time - data[i / 2].1, 10);
+            }
+        }
+
+        // The latch is pulsed a half-period after the last falling edge,
+        // and held high for a half-period.
+        let last_clock = previous.unwrap();
+        let mut latch = log.of(LATCH);
+        assert_eq!(latch.next(), Some((true, last_clock + 10)));
+        assert_eq!(latch.next(), Some((false, last_clock + 20)));
+    }
+
+    /// A pin of the driver, which counts how often it was set.
+    #[derive(Default)]
+    struct CountingPin {
+        sets: Cell<usize>,
+    }
+
+    impl gpio::Configure for CountingPin {
+        fn configuration(&self) -> Configuration {
+            Configuration::Output
+        }
+
+        fn make_output(&self) -> Configuration {
+            Configuration::Output
+        }
+
+        fn disable_output(&self) -> Configuration {
+            Configuration::Output
+        }
+
+        fn make_input(&self) -> Configuration {
+            Configuration::Output
+        }
+
+        fn disable_input(&self) -> Configuration {
+            Configuration::Output
+        }
+
+        fn deactivate_to_low_power(&self) {}
+
+        fn set_floating_state(&self, _state: FloatingState) {}
+
+        fn floating_state(&self) -> FloatingState {
+            FloatingState::PullNone
+        }
+    }
+
+    impl Output for CountingPin {
+        fn set(&self) {
+            self.sets.set(self.sets.get() + 1);
+        }
+
+        fn clear(&self) {}
+
+        fn toggle(&self) -> bool {
+            unimplemented!()
+        }
+    }
+
+    impl gpio::Input for CountingPin {
+        fn read(&self) -> bool {
+            false
+        }
+    }
+
+    impl<'a> gpio::Interrupt<'a> for CountingPin {
+        fn set_client(&self, _client: &'a dyn gpio::Client) {}
+
+        fn enable_interrupts(&self, _mode: InterruptEdge) {}
+
+        fn disable_interrupts(&self) {}
+
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Pin for CountingPin {}
+    impl<'a> gpio::InterruptPin<'a> for CountingPin {}
+
+    #[test]
+    fn test_shift_out_length_is_bounded() {
+        let (kernel, processes) = mock_process::kernel(&["app"]);
+        let id = processes[0].processid();
+        let pins = [
+            CountingPin::default(),
+            CountingPin::default(),
+            CountingPin::default(),
+        ];
+        let wrappers = [
+            InterruptValueWrapper::new(&pins[0]),
+            InterruptValueWrapper::new(&pins[1]),
+            InterruptValueWrapper::new(&pins[2]),
+        ];
+        let driver_pins = [Some(&wrappers[0]), Some(&wrappers[1]), Some(&wrappers[2])];
+        let gpio = GPIO::new(&driver_pins, mock_process::grant(kernel));
+
+        // Data on pin 0, clock on pin 1 and latch on pin 2.
+        assert!(gpio.command(10, 0x02_01_00, 0, id).is_success());
+        let buffer = [0xff; MAX_SHIFT_OUT_LEN + 1];
+        assert!(gpio
+            .allow_readonly(id, 0, processes[0].readonly_slice(&buffer))
+            .is_ok());
+
+        assert_eq!(
+            gpio.command(12, MAX_SHIFT_OUT_LEN + 1, 0, id).get_failure(),
+            Some(ErrorCode::SIZE)
+        );
+        assert_eq!(pins[1].sets.get(), 0);
+        assert_eq!(pins[2].sets.get(), 0);
+
+        assert!(gpio.command(12, MAX_SHIFT_OUT_LEN, 0, id).is_success());
+        assert_eq!(pins[0].sets.get(), MAX_SHIFT_OUT_LEN * 8);
+        assert_eq!(pins[1].sets.get(), MAX_SHIFT_OUT_LEN * 8);
+        assert_eq!(pins[2].sets.get(), 1);
+    }
+}
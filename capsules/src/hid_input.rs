@@ -0,0 +1,286 @@
+//! Provides userspace with a USB keyboard and mouse.
+//!
+//! Processes send key presses and mouse movements, which are turned into
+//! reports of a `hil::usb_hid::UsbHid` device with the report format of
+//! `capsules::usb::keyboard_mouse`. Reports of different processes are sent
+//! one at a time, and each process has at most one report waiting.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let hid = static_init!(
+//!     capsules::usb::keyboard_mouse::KeyboardMouseHid<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::keyboard_mouse::KeyboardMouseHid::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x521f,
+//!         strings,
+//!     )
+//! );
+//! nrf52840::usbd::USBD.set_client(hid);
+//!
+//! let hid_input = static_init!(
+//!     capsules::hid_input::HidInput<
+//!         'static,
+//!         capsules::usb::keyboard_mouse::KeyboardMouseHid<'static, nrf52840::usbd::Usbd<'static>>,
+//!     >,
+//!     capsules::hid_input::HidInput::new(
+//!         hid,
+//!         static_init!([u8; 8], [0; 8]),
+//!         board_kernel.create_grant(&grant_cap),
+//!     )
+//! );
+//! hid.set_client(hid_input);
+//!
+//! hid.enable();
+//! hid.attach();
+//! ```
+
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::usb_hid;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+use crate::usb::keyboard_mouse::{REPORT_KEYBOARD, REPORT_MOUSE};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::HidInput as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    /// The report waiting to be sent, and whether the keys it presses must
+    /// be released after it.
+    pending: Option<([u8; 8], bool)>,
+}
+
+pub struct HidInput<'a, H: usb_hid::UsbHid<'a, [u8; 8]>> {
+    hid: &'a H,
+    apps: Grant<App>,
+    /// The process whose report is being sent.
+    current: OptionalCell<ProcessId>,
+    /// Whether a report releasing all keys must follow the one being sent.
+    release: Cell<bool>,
+    buffer: TakeCell<'static, [u8; 8]>,
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 8]>> HidInput<'a, H> {
+    pub fn new(hid: &'a H, buffer: &'static mut [u8; 8], grant: Grant<App>) -> HidInput<'a, H> {
+        HidInput {
+            hid: hid,
+            apps: grant,
+            current: OptionalCell::empty(),
+            release: Cell::new(false),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    /// Queue a report of `appid`.
+    fn queue(&self, appid: ProcessId, report: [u8; 8], release: bool) -> CommandReturn {
+        let res = self
+            .apps
+            .enter(appid, |app| {
+                if app.pending.is_some() {
+                    Err(ErrorCode::BUSY)
+                } else {
+                    app.pending = Some((report, release));
+                    Ok(())
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        if res.is_ok() {
+            self.send_next();
+        }
+        res.into()
+    }
+
+    /// Send the next waiting report, unless one is being sent.
+    fn send_next(&self) {
+        while let Some(buf) = self.buffer.take() {
+            let mut next = None;
+            for cntr in self.apps.iter() {
+                let appid = cntr.processid();
+                cntr.enter(|app| {
+                    if next.is_none() {
+                        next = app.pending.take().map(|pending| (appid, pending));
+                    }
+                });
+            }
+            match next {
+                None => {
+                    self.buffer.replace(buf);
+                    return;
+                }
+                Some((appid, (report, release))) => {
+                    buf.copy_from_slice(&report);
+                    match self.hid.send_buffer(buf) {
+                        Ok(_) => {
+                            self.current.set(appid);
+                            self.release.set(release);
+                        }
+                        Err((err, buf)) => {
+                            self.buffer.replace(buf);
+                            let _ = self.apps.enter(appid, |app| {
+                                app.callback
+                                    .schedule(kernel::into_statuscode(Err(err)), 0, 0);
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 8]>> usb_hid::Client<'a, [u8; 8]> for HidInput<'a, H> {
+    fn packet_received(
+        &'a self,
+        _result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 8],
+        _endpoint: usize,
+    ) {
+        // Nothing is received, but keep the buffer anyway.
+        self.buffer.replace(buffer);
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 8],
+        _endpoint: usize,
+    ) {
+        if result.is_ok() && self.release.replace(false) {
+            // Release every key, keeping the report ID.
+            for b in buffer[1..].iter_mut() {
+                *b = 0;
+            }
+            match self.hid.send_buffer(buffer) {
+                Ok(_) => return,
+                Err((_, buffer)) => self.buffer.replace(buffer),
+            };
+        } else {
+            self.buffer.replace(buffer);
+        }
+
+        self.current.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback.schedule(kernel::into_statuscode(result), 0, 0);
+            });
+        });
+        self.send_next();
+    }
+
+    fn can_receive(&'a self) -> bool {
+        false
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 8]>> Driver for HidInput<'a, H> {
+    /// Subscribe to HID input events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: A report of the process was sent, or could not be. The
+    ///        callback signature is `fn(status: StatusCode)`.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(callback),
+            Ok(Err(e)) => Err((callback, e)),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// Keyboard and mouse control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Press the keys of `data2`, one usage ID per byte with 0 for
+    ///        none, with the modifiers of `data1`. They stay pressed until
+    ///        the next command 1 or 2.
+    /// - `2`: Type the key of `data2` with the modifiers of `data1`: press
+    ///        it and release all keys.
+    /// - `3`: Move the mouse by the signed bytes X in bits 0-7, Y in bits
+    ///        8-15 and the wheel in bits 16-23 of `data2`, with the buttons
+    ///        of `data1` pressed.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // set pressed keys
+            1 => {
+                let keys = (data2 as u32).to_le_bytes();
+                self.queue(
+                    appid,
+                    [
+                        REPORT_KEYBOARD,
+                        data1 as u8,
+                        keys[0],
+                        keys[1],
+                        keys[2],
+                        keys[3],
+                        0,
+                        0,
+                    ],
+                    false,
+                )
+            }
+
+            // type a key
+            2 => self.queue(
+                appid,
+                [REPORT_KEYBOARD, data1 as u8, data2 as u8, 0, 0, 0, 0, 0],
+                true,
+            ),
+
+            // move the mouse
+            3 => {
+                let movement = (data2 as u32).to_le_bytes();
+                self.queue(
+                    appid,
+                    [
+                        REPORT_MOUSE,
+                        data1 as u8 & 0x07,
+                        movement[0],
+                        movement[1],
+                        movement[2],
+                        0,
+                        0,
+                        0,
+                    ],
+                    false,
+                )
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
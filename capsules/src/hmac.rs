@@ -13,6 +13,7 @@
 //!     VirtualMuxHmac<'static, lowrisc::hmac::Hmac>,
 //!     VirtualMuxHmac::new(mux_hmac)
 //! );
+//! virtual_hmac_user.setup();
 //! let hmac = static_init!(
 //!     capsules::hmac::HmacDriver<'static, VirtualMuxHmac<'static, lowrisc::hmac::Hmac>>,
 //!     capsules::hmac::HmacDriver::new(
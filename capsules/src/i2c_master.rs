@@ -1,4 +1,18 @@
 //! Driver for an I2C Master interface.
+//!
+//! General Call
+//! ------------
+//!
+//! Besides transfers to a single device, the driver can transmit to the I2C
+//! general-call address (`0x00`). A general call is a broadcast: every device
+//! on the bus that supports general call acknowledges it and interprets the
+//! first byte of the payload as a command. There is no way to tell which or
+//! how many devices acted on it. Per the I2C specification the command byte
+//! must not be `0x00`, and a command byte with its least significant bit set
+//! is a hardware general call that carries the address of the transmitting
+//! master. The command byte `0x06` asks devices to reset and latch the
+//! programmable part of their address, which the driver exposes as a software
+//! reset of the bus.
 
 use enum_primitive::enum_from_primitive;
 use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
@@ -19,6 +33,26 @@ pub struct App {
 
 pub static mut BUF: [u8; 64] = [0; 64];
 
+/// The address used to broadcast to all devices on the bus.
+pub const GENERAL_CALL_ADDRESS: u8 = 0x00;
+
+/// General-call command byte to reset devices and have them latch the
+/// programmable part of their address.
+pub const GENERAL_CALL_RESET: u8 = 0x06;
+
+/// Check that `payload` is valid to transmit as a general call.
+fn validate_general_call(payload: &[u8]) -> Result<(), ErrorCode> {
+    match payload.first() {
+        // The command byte must be present and `0x00` is reserved.
+        None => Err(ErrorCode::SIZE),
+        Some(0x00) => Err(ErrorCode::INVAL),
+        // A hardware general call carries the master address in the command
+        // byte and must be followed by at least one data byte.
+        Some(command) if command & 0x01 == 0x01 && payload.len() < 2 => Err(ErrorCode::SIZE),
+        Some(_) => Ok(()),
+    }
+}
+
 struct Transaction {
     /// The buffer containing the bytes to transmit as it should be returned to
     /// the client
@@ -77,6 +111,8 @@ impl<'a, I: 'a + i2c::I2CMaster> I2CMasterDriver<'a, I> {
                             Cmd::Write => self.i2c.write(addr, buffer, wlen),
                             Cmd::Read => self.i2c.read(addr, buffer, rlen),
                             Cmd::WriteRead => self.i2c.write_read(addr, buffer, wlen, rlen),
+                            // General calls are started by `general_call()`.
+                            Cmd::GeneralCall | Cmd::GeneralCallReset => (),
                         }
                     });
                     // TODO(alevy): if buf.take() returned None, the I2C hadn't returned the
@@ -85,6 +121,63 @@ impl<'a, I: 'a + i2c::I2CMaster> I2CMasterDriver<'a, I> {
             })
             .expect("Appid does not map to app");
     }
+
+    /// Transmit the first `wlen` bytes of the app's buffer to the
+    /// general-call address.
+    fn general_call(&self, app_id: ProcessId, wlen: usize) -> CommandReturn {
+        if wlen > u8::MAX as usize {
+            return CommandReturn::failure(ErrorCode::SIZE);
+        }
+        let buffer = match self.buf.take() {
+            Some(buffer) => buffer,
+            None => return CommandReturn::failure(ErrorCode::BUSY),
+        };
+        let res = self
+            .apps
+            .enter(app_id, |app| {
+                app.slice.map_or(Err(ErrorCode::RESERVE), |app_buffer| {
+                    if wlen > app_buffer.len() || wlen > buffer.len() {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    validate_general_call(&app_buffer[..wlen])?;
+                    buffer[..wlen].copy_from_slice(&app_buffer[..wlen]);
+                    Ok(())
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        match res {
+            Ok(()) => {
+                self.tx.put(Transaction {
+                    app_id,
+                    read_len: OptionalCell::empty(),
+                });
+                self.i2c.write(GENERAL_CALL_ADDRESS, buffer, wlen as u8);
+                CommandReturn::success()
+            }
+            Err(e) => {
+                self.buf.replace(buffer);
+                CommandReturn::failure(e)
+            }
+        }
+    }
+
+    /// Issue the general-call software reset sequence.
+    fn general_call_reset(&self, app_id: ProcessId) -> CommandReturn {
+        if let Err(e) = self.apps.enter(app_id, |_| ()) {
+            return CommandReturn::failure(e.into());
+        }
+        self.buf
+            .take()
+            .map_or(CommandReturn::failure(ErrorCode::BUSY), |buffer| {
+                buffer[0] = GENERAL_CALL_RESET;
+                self.tx.put(Transaction {
+                    app_id,
+                    read_len: OptionalCell::empty(),
+                });
+                self.i2c.write(GENERAL_CALL_ADDRESS, buffer, 1);
+                CommandReturn::success()
+            })
+    }
 }
 
 use enum_primitive::cast::FromPrimitive;
@@ -96,6 +189,8 @@ pub enum Cmd {
     Write = 1,
     Read = 2,
     WriteRead = 3,
+    GeneralCall = 4,
+    GeneralCallReset = 5,
 }
 }
 
@@ -154,6 +249,18 @@ impl<'a, I: 'a + i2c::I2CMaster> Driver for I2CMasterDriver<'a, I> {
     }
 
     /// Initiate transfers
+    ///
+    /// ### `cmd_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Write `arg2` bytes to the device at address `arg1`.
+    /// - `2`: Read `arg2` bytes from the device at address `arg1`.
+    /// - `3`: Write `arg1 >> 8` bytes then read `arg2` bytes from the device
+    ///        at address `arg1 & 0xff`.
+    /// - `4`: Broadcast the first `arg1` bytes of the buffer to the
+    ///        general-call address. The first byte is the general-call
+    ///        command byte and must not be `0x00`.
+    /// - `5`: Broadcast the general-call software reset (`0x06`).
     fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
         if let Some(cmd) = Cmd::from_usize(cmd_num) {
             match cmd {
@@ -194,6 +301,9 @@ impl<'a, I: 'a + i2c::I2CMaster> Driver for I2CMasterDriver<'a, I> {
                         })
                         .unwrap_or_else(|err| err.into())
                 }
+                // Broadcast the first `arg1` bytes of the buffer.
+                Cmd::GeneralCall => self.general_call(appid, arg1),
+                Cmd::GeneralCallReset => self.general_call_reset(appid),
             }
         } else {
             CommandReturn::failure(ErrorCode::NOSUPPORT)
@@ -220,3 +330,148 @@ impl<'a, I: 'a + i2c::I2CMaster> i2c::I2CHwMasterClient for I2CMasterDriver<'a,
         self.buf.put(Some(buffer));
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{
+        validate_general_call, I2CMasterDriver, DRIVER_NUM, GENERAL_CALL_ADDRESS,
+        GENERAL_CALL_RESET,
+    };
+    use crate::test::mock_process::{self, MockProcess};
+    use core::cell::RefCell;
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::i2c::{self, I2CHwMasterClient};
+    use kernel::{Driver, ErrorCode};
+    use std::vec::Vec;
+
+    /// A bus that records the address and bytes of each write, and holds
+    /// the buffer until the test completes it.
+    struct MockI2C {
+        writes: RefCell<Vec<(u8, Vec<u8>)>>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl MockI2C {
+        fn new() -> MockI2C {
+            MockI2C {
+                writes: RefCell::new(Vec::new()),
+                buffer: TakeCell::empty(),
+            }
+        }
+    }
+
+    impl i2c::I2CMaster for MockI2C {
+        fn set_master_client(&self, _master_client: &'static dyn I2CHwMasterClient) {}
+
+        fn enable(&self) {}
+
+        fn disable(&self) {}
+
+        fn write_read(&self, _addr: u8, data: &'static mut [u8], _write_len: u8, _read_len: u8) {
+            self.buffer.replace(data);
+        }
+
+        fn write(&self, addr: u8, data: &'static mut [u8], len: u8) {
+            self.writes
+                .borrow_mut()
+                .push((addr, data[..len as usize].to_vec()));
+            self.buffer.replace(data);
+        }
+
+        fn read(&self, _addr: u8, buffer: &'static mut [u8], _len: u8) {
+            self.buffer.replace(buffer);
+        }
+    }
+
+    type Master = I2CMasterDriver<'static, MockI2C>;
+
+    /// A driver on a mock bus, and a process that allowed `data` and
+    /// subscribed to completions.
+    fn master(data: &[u8]) -> (&'static Master, &'static MockI2C, &'static MockProcess) {
+        let (kernel, processes) = mock_process::kernel(&["bus"]);
+        let bus = mock_process::leak(MockI2C::new());
+        let driver = mock_process::leak(I2CMasterDriver::new(
+            bus,
+            mock_process::buffer(8),
+            mock_process::grant(kernel),
+        ));
+        let app = processes[0];
+        let id = app.processid();
+        assert!(driver
+            .allow_readwrite(id, 1, app.readwrite_slice(data))
+            .is_ok());
+        assert!(driver.subscribe(1, app.upcall(DRIVER_NUM, 1), id).is_ok());
+        (driver, bus, app)
+    }
+
+    /// Complete the write in progress on `bus`.
+    fn complete(driver: &Master, bus: &MockI2C) {
+        let buffer = bus.buffer.take().expect("no transfer in progress");
+        driver.command_complete(buffer, i2c::Error::CommandComplete);
+    }
+
+    #[test]
+    fn test_general_call_writes_to_address_zero() {
+        let (driver, bus, app) = master(&[0x04, 0x12, 0x34, 0x56]);
+        let id = app.processid();
+        assert!(driver.command(4, 3, 0, id).is_success());
+        // Only one transfer may be in progress.
+        assert_eq!(
+            driver.command(4, 3, 0, id).get_failure(),
+            Some(ErrorCode::BUSY)
+        );
+        complete(driver, bus);
+        assert_eq!(app.take_upcalls(), [(1, 0, 0, 0)]);
+        assert_eq!(
+            *bus.writes.borrow(),
+            [(GENERAL_CALL_ADDRESS, std::vec![0x04, 0x12, 0x34])]
+        );
+    }
+
+    #[test]
+    fn test_software_reset_sends_reset_byte() {
+        // The reset does not need the process to share a buffer.
+        let (driver, bus, app) = master(&[]);
+        assert!(driver.command(5, 0, 0, app.processid()).is_success());
+        complete(driver, bus);
+        assert_eq!(app.take_upcalls(), [(1, 0, 0, 0)]);
+        assert_eq!(
+            *bus.writes.borrow(),
+            [(GENERAL_CALL_ADDRESS, std::vec![GENERAL_CALL_RESET])]
+        );
+    }
+
+    #[test]
+    fn test_invalid_general_calls_are_rejected() {
+        assert_eq!(validate_general_call(&[]), Err(ErrorCode::SIZE));
+        assert_eq!(validate_general_call(&[0x00, 0x12]), Err(ErrorCode::INVAL));
+        assert_eq!(validate_general_call(&[0x21]), Err(ErrorCode::SIZE));
+        assert_eq!(validate_general_call(&[0x21, 0x12]), Ok(()));
+        assert_eq!(validate_general_call(&[GENERAL_CALL_RESET]), Ok(()));
+
+        // Nothing reaches the bus, and the driver stays free.
+        let (driver, bus, app) = master(&[0x00, 0x12]);
+        let id = app.processid();
+        assert_eq!(
+            driver.command(4, 2, 0, id).get_failure(),
+            Some(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            driver.command(4, 0, 0, id).get_failure(),
+            Some(ErrorCode::SIZE)
+        );
+        assert_eq!(
+            driver.command(4, 3, 0, id).get_failure(),
+            Some(ErrorCode::SIZE)
+        );
+        assert_eq!(
+            driver.command(4, 256, 0, id).get_failure(),
+            Some(ErrorCode::SIZE)
+        );
+        assert!(bus.writes.borrow().is_empty());
+        assert!(app.take_upcalls().is_empty());
+        assert!(driver.command(5, 0, 0, id).is_success());
+    }
+}
@@ -83,6 +83,8 @@ impl hil::i2c::I2CHwMasterClient for I2CMasterSlaveDriver<'_> {
             hil::i2c::Error::ArbitrationLost => -3,
             hil::i2c::Error::Overrun => -4,
             hil::i2c::Error::NotSupported => -5,
+            hil::i2c::Error::Timeout => -6,
+            hil::i2c::Error::PecMismatch => -7,
             hil::i2c::Error::CommandComplete => 0,
         };
 
@@ -0,0 +1,413 @@
+//! Provides userspace with streaming audio over an I2S interface.
+//!
+//! An application plays audio from a read-only allowed buffer and records
+//! into a read-write allowed buffer, or both at the same time. Each buffer is
+//! used as a ring: the driver streams through it continuously, and notifies
+//! the application whenever it is done with one half, so that the application
+//! refills (or reads) that half while the other one is streamed.
+//!
+//! Between the application and the peripheral, samples go through two pairs
+//! of kernel buffers used in turn, so that the peripheral always has the next
+//! one while the driver copies the last one.
+//!
+//! Only one application can stream at a time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let tx_buffers = static_init!([[u32; 128]; 2], [[0; 128]; 2]);
+//! let rx_buffers = static_init!([[u32; 128]; 2], [[0; 128]; 2]);
+//! let (tx0, tx1) = tx_buffers.split_at_mut(1);
+//! let (rx0, rx1) = rx_buffers.split_at_mut(1);
+//! let i2s = static_init!(
+//!     capsules::i2s::I2sDriver<'static, nrf52::i2s::I2S>,
+//!     capsules::i2s::I2sDriver::new(
+//!         &base_peripherals.i2s,
+//!         [&mut tx0[0], &mut tx1[0]],
+//!         [&mut rx0[0], &mut rx1[0]],
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! base_peripherals.i2s.set_client(i2s);
+//! ```
+
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::i2s;
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::I2s as usize;
+
+#[derive(Default)]
+pub struct App {
+    play_callback: Upcall,
+    record_callback: Upcall,
+    play_data: ReadOnlyAppSlice,
+    record_data: ReadWriteAppSlice,
+}
+
+pub struct I2sDriver<'a, I: i2s::I2s<'a>> {
+    i2s: &'a I,
+    apps: Grant<App>,
+    /// The application streaming, until the peripheral stopped.
+    owner: OptionalCell<ProcessId>,
+    stopping: Cell<bool>,
+    /// Kernel buffers not handed to the peripheral.
+    tx_buffers: [TakeCell<'static, [u32]>; 2],
+    rx_buffers: [TakeCell<'static, [u32]>; 2],
+    /// Byte offsets of the next samples in the application buffers.
+    play_offset: Cell<usize>,
+    record_offset: Cell<usize>,
+}
+
+/// Advance a ring offset by `amount` bytes, less than half of `len`. Returns
+/// the new offset, and the half of the ring that was passed, if any.
+fn advance(offset: usize, amount: usize, len: usize) -> (usize, Option<usize>) {
+    let half = len / 2;
+    let end = offset + amount;
+    if offset < half && end >= half {
+        (end, Some(0))
+    } else if end >= len {
+        (end - len, Some(1))
+    } else {
+        (end, None)
+    }
+}
+
+impl<'a, I: i2s::I2s<'a>> I2sDriver<'a, I> {
+    pub fn new(
+        i2s: &'a I,
+        tx_buffers: [&'static mut [u32]; 2],
+        rx_buffers: [&'static mut [u32]; 2],
+        grant: Grant<App>,
+    ) -> I2sDriver<'a, I> {
+        let [tx0, tx1] = tx_buffers;
+        let [rx0, rx1] = rx_buffers;
+        I2sDriver {
+            i2s: i2s,
+            apps: grant,
+            owner: OptionalCell::empty(),
+            stopping: Cell::new(false),
+            tx_buffers: [TakeCell::new(tx0), TakeCell::new(tx1)],
+            rx_buffers: [TakeCell::new(rx0), TakeCell::new(rx1)],
+            play_offset: Cell::new(0),
+            record_offset: Cell::new(0),
+        }
+    }
+
+    /// Number of bytes in a kernel buffer.
+    fn chunk_len(&self) -> usize {
+        self.tx_buffers[0].map_or(0, |buffer| buffer.len() * 4)
+    }
+
+    /// Keep a kernel buffer until streaming starts again.
+    fn stash(&self, buffers: i2s::BufferSet) {
+        if let Some(tx) = buffers.0 {
+            if let Some(cell) = self.tx_buffers.iter().find(|cell| cell.is_none()) {
+                cell.replace(tx);
+            }
+        }
+        if let Some(rx) = buffers.1 {
+            if let Some(cell) = self.rx_buffers.iter().find(|cell| cell.is_none()) {
+                cell.replace(rx);
+            }
+        }
+    }
+
+    /// Fill `words` from the play buffer of `app` and advance through it,
+    /// notifying the application when a half is consumed.
+    fn fill(&self, words: &mut [u32], app: &mut App) {
+        let len = app.play_data.len();
+        if len < 2 * words.len() * 4 {
+            // The application replaced its buffer by a too small one.
+            return;
+        }
+        let offset = self.play_offset.get() % len;
+        app.play_data.map_or((), |data| {
+            for (i, word) in words.iter_mut().enumerate() {
+                let mut bytes = [0; 4];
+                for (j, byte) in bytes.iter_mut().enumerate() {
+                    *byte = data[(offset + i * 4 + j) % len];
+                }
+                *word = u32::from_le_bytes(bytes);
+            }
+        });
+        let (offset, half) = advance(offset, words.len() * 4, len);
+        self.play_offset.set(offset);
+        if let Some(half) = half {
+            app.play_callback.schedule(half, 0, 0);
+        }
+    }
+
+    /// Copy `words` into the record buffer of `app` and advance through it,
+    /// notifying the application when a half is filled.
+    fn drain(&self, words: &[u32], app: &mut App) {
+        let len = app.record_data.len();
+        if len < 2 * words.len() * 4 {
+            return;
+        }
+        let offset = self.record_offset.get() % len;
+        app.record_data.mut_map_or((), |data| {
+            for (i, word) in words.iter().enumerate() {
+                for (j, byte) in word.to_le_bytes().iter().enumerate() {
+                    data[(offset + i * 4 + j) % len] = *byte;
+                }
+            }
+        });
+        let (offset, half) = advance(offset, words.len() * 4, len);
+        self.record_offset.set(offset);
+        if let Some(half) = half {
+            app.record_callback.schedule(half, 0, 0);
+        }
+    }
+
+    /// Take a set of kernel buffers for the enabled directions, filling the
+    /// transmit buffer from `app`.
+    fn next_set(&self, index: usize, play: bool, record: bool, app: &mut App) -> i2s::BufferSet {
+        let tx = if play {
+            self.tx_buffers[index].take().map(|buffer| {
+                self.fill(buffer, app);
+                buffer
+            })
+        } else {
+            None
+        };
+        let rx = if record {
+            self.rx_buffers[index].take()
+        } else {
+            None
+        };
+        (tx, rx)
+    }
+
+    fn start(&self, appid: ProcessId, play: bool, record: bool) -> Result<(), ErrorCode> {
+        if self.owner.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if !play && !record {
+            return Err(ErrorCode::INVAL);
+        }
+        let chunk = self.chunk_len();
+        self.apps
+            .enter(appid, |app| {
+                // Each half of a ring must hold at least a kernel buffer.
+                if (play && app.play_data.len() < 2 * chunk)
+                    || (record && app.record_data.len() < 2 * chunk)
+                {
+                    return Err(ErrorCode::SIZE);
+                }
+                self.play_offset.set(0);
+                self.record_offset.set(0);
+
+                let first = self.next_set(0, play, record, app);
+                if let Err((e, buffers)) = self.i2s.start(first) {
+                    self.stash(buffers);
+                    return Err(e);
+                }
+                self.owner.set(appid);
+                let second = self.next_set(1, play, record, app);
+                if let Err((_, buffers)) = self.i2s.queue(second) {
+                    self.stash(buffers);
+                }
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn stop(&self) -> Result<(), ErrorCode> {
+        self.stopping.set(true);
+        self.i2s.stop()
+    }
+}
+
+impl<'a, I: i2s::I2s<'a>> Driver for I2sDriver<'a, I> {
+    /// ### `allow_num`
+    ///
+    /// - `0`: Ring buffer to record into.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.record_data, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// ### `allow_num`
+    ///
+    /// - `0`: Ring buffer to play from.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.play_data, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: A half of the play buffer was consumed and can be refilled.
+    ///        Upcall arguments: (half, 0, 0).
+    /// - `1`: A half of the record buffer was filled. Upcall arguments:
+    ///        (half, 0, 0).
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.play_callback, &mut callback);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.record_callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(callback),
+            Ok(Err(e)) => Err((callback, e)),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Configure the stream. `data1` is the sample rate in Hz, the low
+    ///        byte of `data2` the sample width in bits (8, 16 or 24), and the
+    ///        next byte the channels (0 stereo, 1 left, 2 right). Returns the
+    ///        sample rate used.
+    /// - `2`: Start streaming. Bit 0 of `data1` plays the play buffer, bit 1
+    ///        records into the record buffer.
+    /// - `3`: Stop streaming.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // configure
+            1 => {
+                if self.owner.is_some() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                let width = match data2 & 0xFF {
+                    8 => i2s::SampleWidth::Bits8,
+                    16 => i2s::SampleWidth::Bits16,
+                    24 => i2s::SampleWidth::Bits24,
+                    _ => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                let channels = match (data2 >> 8) & 0xFF {
+                    0 => i2s::Channels::Stereo,
+                    1 => i2s::Channels::Left,
+                    2 => i2s::Channels::Right,
+                    _ => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                match self.i2s.configure(data1 as u32, width, channels) {
+                    Ok(rate) => CommandReturn::success_u32(rate),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            // start
+            2 => self.start(appid, data1 & 1 != 0, data1 & 2 != 0).into(),
+
+            // stop
+            3 => {
+                if !self.owner.contains(&appid) || self.stopping.get() {
+                    return CommandReturn::failure(ErrorCode::OFF);
+                }
+                self.stop().into()
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+impl<'a, I: i2s::I2s<'a>> i2s::Client for I2sDriver<'a, I> {
+    fn buffers_done(&self, buffers: i2s::BufferSet) {
+        if self.stopping.get() {
+            self.stash(buffers);
+            return;
+        }
+        let (mut tx, mut rx) = buffers;
+        let res = self.owner.map_or(Err(ErrorCode::FAIL), |appid| {
+            self.apps
+                .enter(*appid, |app| {
+                    if let Some(rx) = rx.as_mut() {
+                        self.drain(rx, app);
+                    }
+                    if let Some(tx) = tx.as_mut() {
+                        self.fill(tx, app);
+                    }
+                })
+                .map_err(ErrorCode::from)
+        });
+        match res {
+            Ok(()) => {
+                if let Err((_, buffers)) = self.i2s.queue((tx, rx)) {
+                    self.stash(buffers);
+                }
+            }
+            Err(_) => {
+                // The application is gone.
+                self.stash((tx, rx));
+                let _ = self.stop();
+            }
+        }
+    }
+
+    fn stopped(&self) {
+        self.stopping.set(false);
+        self.owner.clear();
+    }
+}
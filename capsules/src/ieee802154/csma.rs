@@ -0,0 +1,515 @@
+//! Unslotted CSMA-CA and automatic retransmission for IEEE 802.15.4.
+//!
+//! `CsmaMac` is a `Mac` implementation that, like `AwakeMac`, keeps the
+//! radio on at all times, but also performs channel access and
+//! acknowledgements in software (IEEE 802.15.4-2015, 6.2.5 and 6.7.4), so
+//! that radios without hardware support for them can still send reliably on
+//! a congested channel:
+//!
+//!   * Before every transmission attempt, the layer waits a random number of
+//!     unit backoff periods, between 0 and 2^BE - 1. The radio performs the
+//!     clear channel assessment as part of its transmission, and reports a
+//!     busy channel with `BUSY`. The backoff exponent BE is then increased,
+//!     up to `MAX_BE`, and the frame is attempted again, at most
+//!     `max_csma_backoffs` more times before `BUSY` is reported to the client.
+//!   * Frames that request an acknowledgement are retransmitted, with a new
+//!     CSMA-CA backoff, if no ACK with their sequence number is received
+//!     within the ACK wait duration. After `max_frame_retries`
+//!     retransmissions, `NOACK` is reported to the client. ACKs reported by
+//!     the radio itself are used as is.
+//!   * With `set_send_acks(true)`, ACKs are sent for received unicast frames
+//!     that request one. This should only be enabled for radios that do not
+//!     generate ACKs in hardware.
+//!
+//! The client of the layer receives, in `send_done`, whether the frame was
+//! acknowledged and the result of the last attempt, which the layers above
+//! pass up to their own clients.
+//!
+//! Backoffs use a simple pseudo-random generator, seeded from the extended
+//! address of the radio and the time of the first transmission, which is
+//! enough to keep neighbors from backing off in lockstep.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let csma_mac = static_init!(
+//!     capsules::ieee802154::csma::CsmaMac<'static, RadioDevice, Alarm>,
+//!     capsules::ieee802154::csma::CsmaMac::new(radio, alarm, deferred_caller)
+//! );
+//! radio.set_transmit_client(csma_mac);
+//! radio.set_receive_client(csma_mac, &mut RADIO_RX_BUF);
+//! alarm.set_alarm_client(csma_mac);
+//! csma_mac.initialize_callback_handle(
+//!     deferred_caller
+//!         .register(csma_mac)
+//!         .expect("no deferred call slot available for CSMA MAC"),
+//! );
+//! csma_mac.initialize(&mut CSMA_ACK_BUF);
+//! // For radios that do not send ACKs themselves:
+//! csma_mac.set_send_acks(true);
+//!
+//! // The framer is then built on `csma_mac` instead of an `AwakeMac`.
+//! ```
+
+use crate::ieee802154::mac::Mac;
+use crate::net::ieee802154::{FrameType, FrameVersion, Header, MacAddress};
+use core::cell::Cell;
+use core::cmp::min;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::radio;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ErrorCode;
+
+/// Duration of a unit backoff period (20 symbols at 2.4 GHz).
+pub const UNIT_BACKOFF_PERIOD_US: u32 = 320;
+
+/// Time to wait for an ACK after a transmission (54 symbols at 2.4 GHz).
+pub const ACK_WAIT_DURATION_US: u32 = 864;
+
+/// Initial and maximum backoff exponents (macMinBe, macMaxBe).
+pub const MIN_BE: u8 = 3;
+pub const MAX_BE: u8 = 5;
+
+/// Default number of further backoffs after a busy channel
+/// (macMaxCsmaBackoffs).
+pub const DEFAULT_MAX_CSMA_BACKOFFS: u8 = 4;
+
+/// Default number of retransmissions of an unacknowledged frame
+/// (macMaxFrameRetries).
+pub const DEFAULT_MAX_FRAME_RETRIES: u8 = 3;
+
+/// Short address of frames sent to every device.
+const BROADCAST_ADDR: u16 = 0xffff;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum CsmaState {
+    /// No frame is being sent.
+    Idle,
+    /// Waiting for the backoff period before the next attempt.
+    Backoff,
+    /// The frame is being transmitted by the radio.
+    Transmitting,
+    /// The frame was sent, and the ACK has not been received yet.
+    WaitingForAck,
+}
+
+pub struct CsmaMac<'a, R: radio::Radio, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+
+    tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    rx_client: OptionalCell<&'static dyn radio::RxClient>,
+
+    state: Cell<CsmaState>,
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    /// Sequence number of the frame, if it waits for an ACK.
+    tx_ack_seq: Cell<Option<u8>>,
+    /// Number of busy channel assessments for the current attempt (NB).
+    backoffs: Cell<u8>,
+    /// Backoff exponent of the current attempt (BE).
+    backoff_exponent: Cell<u8>,
+    /// Number of retransmissions of the current frame.
+    retries: Cell<u8>,
+    max_csma_backoffs: Cell<u8>,
+    max_frame_retries: Cell<u8>,
+    ack_wait_us: Cell<u32>,
+    rng_state: Cell<u32>,
+
+    send_acks: Cell<bool>,
+    ack_frame_pending: Cell<bool>,
+    ack_buf: TakeCell<'static, [u8]>,
+    /// Sequence number of the received frame to acknowledge.
+    ack_pending: OptionalCell<u8>,
+    /// The radio is transmitting an ACK from `ack_buf`.
+    sending_ack: Cell<bool>,
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> CsmaMac<'a, R, A> {
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> CsmaMac<'a, R, A> {
+        CsmaMac {
+            radio: radio,
+            alarm: alarm,
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            state: Cell::new(CsmaState::Idle),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_ack_seq: Cell::new(None),
+            backoffs: Cell::new(0),
+            backoff_exponent: Cell::new(MIN_BE),
+            retries: Cell::new(0),
+            max_csma_backoffs: Cell::new(DEFAULT_MAX_CSMA_BACKOFFS),
+            max_frame_retries: Cell::new(DEFAULT_MAX_FRAME_RETRIES),
+            ack_wait_us: Cell::new(ACK_WAIT_DURATION_US),
+            rng_state: Cell::new(0),
+            send_acks: Cell::new(false),
+            ack_frame_pending: Cell::new(false),
+            ack_buf: TakeCell::empty(),
+            ack_pending: OptionalCell::empty(),
+            sending_ack: Cell::new(false),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Sets how many more times a frame is attempted after the channel is
+    /// found busy.
+    pub fn set_max_csma_backoffs(&self, max_csma_backoffs: u8) {
+        self.max_csma_backoffs.set(max_csma_backoffs);
+    }
+
+    /// Sets how many times an unacknowledged frame is retransmitted. Radios
+    /// that retransmit frames in hardware may use 0.
+    pub fn set_max_frame_retries(&self, max_frame_retries: u8) {
+        self.max_frame_retries.set(max_frame_retries);
+    }
+
+    /// Sets how long to wait for an ACK. Neighbors that send ACKs in software
+    /// may need longer than the standard `ACK_WAIT_DURATION_US`.
+    pub fn set_ack_wait_us(&self, ack_wait_us: u32) {
+        self.ack_wait_us.set(ack_wait_us);
+    }
+
+    /// Sends ACKs for received frames that request one. Requires the buffer
+    /// passed to `initialize()`.
+    pub fn set_send_acks(&self, send_acks: bool) {
+        self.send_acks.set(send_acks);
+    }
+
+    /// Xorshift pseudo-random generator, only used to pick backoffs.
+    fn random(&self) -> u32 {
+        let mut x = self.rng_state.get();
+        if x == 0 {
+            let addr = self.radio.get_address_long();
+            x = (u32::from_be_bytes([addr[4], addr[5], addr[6], addr[7]])
+                ^ self.alarm.now().into_u32())
+                | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state.set(x);
+        x
+    }
+
+    fn set_timer_us(&self, us: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_us(us));
+    }
+
+    /// Starts the CSMA-CA procedure for a new attempt of the frame.
+    fn start_csma(&self) {
+        self.backoffs.set(0);
+        self.backoff_exponent.set(MIN_BE);
+        self.backoff();
+    }
+
+    fn backoff(&self) {
+        let periods = self.random() & ((1 << self.backoff_exponent.get()) - 1);
+        self.state.set(CsmaState::Backoff);
+        self.set_timer_us(periods * UNIT_BACKOFF_PERIOD_US);
+    }
+
+    fn attempt(&self) {
+        self.tx_buf.take().map(|buf| {
+            // An ACK being sent keeps the channel busy.
+            if self.sending_ack.get() {
+                self.channel_busy(buf);
+                return;
+            }
+            self.state.set(CsmaState::Transmitting);
+            match self.radio.transmit(buf, self.tx_len.get()) {
+                Ok(()) => {}
+                Err((ErrorCode::BUSY, buf)) => self.channel_busy(buf),
+                Err((ecode, buf)) => self.finish(buf, false, Err(ecode)),
+            }
+        });
+    }
+
+    fn channel_busy(&self, buf: &'static mut [u8]) {
+        let backoffs = self.backoffs.get() + 1;
+        if backoffs > self.max_csma_backoffs.get() {
+            self.finish(buf, false, Err(ErrorCode::BUSY));
+        } else {
+            self.tx_buf.replace(buf);
+            self.backoffs.set(backoffs);
+            self.backoff_exponent
+                .set(min(self.backoff_exponent.get() + 1, MAX_BE));
+            self.backoff();
+        }
+    }
+
+    fn ack_timeout(&self) {
+        let retries = self.retries.get();
+        if retries < self.max_frame_retries.get() {
+            self.retries.set(retries + 1);
+            self.start_csma();
+        } else {
+            self.tx_buf.take().map(|buf| {
+                self.finish(buf, false, Err(ErrorCode::NOACK));
+            });
+        }
+    }
+
+    fn finish(&self, buf: &'static mut [u8], acked: bool, result: Result<(), ErrorCode>) {
+        self.state.set(CsmaState::Idle);
+        self.tx_client.map(move |c| {
+            c.send_done(buf, acked, result);
+        });
+        self.send_pending_ack();
+    }
+
+    /// Sends the pending ACK, unless the radio is transmitting a frame.
+    fn send_pending_ack(&self) {
+        if self.state.get() == CsmaState::Transmitting || self.sending_ack.get() {
+            return;
+        }
+        let seq = match self.ack_pending.take() {
+            Some(seq) => seq,
+            None => return,
+        };
+        let header = Header {
+            frame_type: FrameType::Acknowledgement,
+            frame_pending: self.ack_frame_pending.get(),
+            ack_requested: false,
+            version: FrameVersion::V2006,
+            seq: Some(seq),
+            dst_pan: None,
+            dst_addr: None,
+            src_pan: None,
+            src_addr: None,
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        self.ack_buf.take().map(|buf| {
+            let frame_len = match header.encode(&mut buf[radio::PSDU_OFFSET..], true).done() {
+                Some((frame_len, _)) => frame_len,
+                None => {
+                    self.ack_buf.replace(buf);
+                    return;
+                }
+            };
+            match self.radio.transmit(buf, frame_len) {
+                Ok(()) => self.sending_ack.set(true),
+                Err((_, buf)) => {
+                    self.ack_buf.replace(buf);
+                }
+            }
+        });
+    }
+
+    fn addressed_to_us(&self, dst_addr: Option<MacAddress>) -> bool {
+        match dst_addr {
+            Some(MacAddress::Short(addr)) => {
+                addr == self.radio.get_address() || addr == BROADCAST_ADDR
+            }
+            Some(MacAddress::Long(addr)) => addr == self.radio.get_address_long(),
+            None => false,
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> Mac for CsmaMac<'a, R, A> {
+    /// `mac_buf` holds the ACKs sent with `set_send_acks(true)`.
+    fn initialize(&self, mac_buf: &'static mut [u8]) -> Result<(), ErrorCode> {
+        self.ack_buf.replace(mac_buf);
+        Ok(())
+    }
+
+    fn is_on(&self) -> bool {
+        self.radio.is_on()
+    }
+
+    fn set_config_client(&self, client: &'static dyn radio::ConfigClient) {
+        self.radio.set_config_client(client)
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.radio.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.radio.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.radio.set_pan(id)
+    }
+
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.ack_frame_pending.set(pending);
+        self.radio.set_ack_frame_pending(pending)
+    }
+
+    fn get_address(&self) -> u16 {
+        self.radio.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.radio.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.radio.get_pan()
+    }
+
+    fn config_commit(&self) {
+        self.radio.config_commit()
+    }
+
+    fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static dyn radio::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.radio.set_receive_buffer(buffer);
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.state.get() != CsmaState::Idle {
+            return Err((ErrorCode::BUSY, full_mac_frame));
+        }
+        let header = match Header::decode(&full_mac_frame[radio::PSDU_OFFSET..], false).done() {
+            Some((_, (header, _))) => header,
+            None => return Err((ErrorCode::INVAL, full_mac_frame)),
+        };
+        // Frames to every device are never acknowledged.
+        let ack_seq =
+            if header.ack_requested && header.dst_addr != Some(MacAddress::Short(BROADCAST_ADDR)) {
+                header.seq
+            } else {
+                None
+            };
+
+        self.tx_ack_seq.set(ack_seq);
+        self.tx_len.set(frame_len);
+        self.tx_buf.replace(full_mac_frame);
+        self.retries.set(0);
+        self.start_csma();
+        Ok(())
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> time::AlarmClient for CsmaMac<'a, R, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            CsmaState::Backoff => self.attempt(),
+            CsmaState::WaitingForAck => self.ack_timeout(),
+            CsmaState::Idle | CsmaState::Transmitting => {}
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> DynamicDeferredCallClient for CsmaMac<'a, R, A> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.send_pending_ack();
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> radio::TxClient for CsmaMac<'a, R, A> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: Result<(), ErrorCode>) {
+        if self.sending_ack.get() {
+            self.sending_ack.set(false);
+            self.ack_buf.replace(buf);
+            if self.state.get() == CsmaState::Idle {
+                self.send_pending_ack();
+            }
+            return;
+        }
+
+        match result {
+            // The radio found the channel busy.
+            Err(ErrorCode::BUSY) => self.channel_busy(buf),
+            Err(ecode) => self.finish(buf, false, Err(ecode)),
+            Ok(()) => {
+                if self.tx_ack_seq.get().is_none() || acked {
+                    self.finish(buf, acked, Ok(()));
+                } else {
+                    self.tx_buf.replace(buf);
+                    self.state.set(CsmaState::WaitingForAck);
+                    self.set_timer_us(self.ack_wait_us.get());
+                    self.send_pending_ack();
+                }
+            }
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> radio::RxClient for CsmaMac<'a, R, A> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        result: Result<(), ErrorCode>,
+    ) {
+        let header = match Header::decode(&buf[radio::PSDU_OFFSET..], false).done() {
+            Some((_, (header, _))) if crc_valid => header,
+            _ => {
+                self.radio.set_receive_buffer(buf);
+                return;
+            }
+        };
+
+        if header.frame_type == FrameType::Acknowledgement {
+            if self.state.get() == CsmaState::WaitingForAck
+                && header.seq.is_some()
+                && header.seq == self.tx_ack_seq.get()
+            {
+                let _ = self.alarm.disarm();
+                self.tx_buf.take().map(|tx_buf| {
+                    self.finish(tx_buf, true, Ok(()));
+                });
+            }
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        if !self.addressed_to_us(header.dst_addr) {
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        // The ACK is sent once the radio is done with the reception.
+        if self.send_acks.get()
+            && header.ack_requested
+            && header.dst_addr != Some(MacAddress::Short(BROADCAST_ADDR))
+        {
+            if let Some(seq) = header.seq {
+                self.ack_pending.set(seq);
+                self.handle.map(|handle| self.deferred_caller.set(*handle));
+            }
+        }
+
+        self.rx_client.map(move |c| {
+            c.receive(buf, frame_len, crc_valid, result);
+        });
+    }
+}
@@ -3,6 +3,11 @@
 //! Implements a userspace interface for sending and receiving IEEE 802.15.4
 //! frames. Also provides a minimal list-based interface for managing keys and
 //! known link neighbors, which is needed for 802.15.4 security.
+//!
+//! If the board provides the radio's `RadioEnergy` interface with
+//! `set_energy_scanner()`, processes can also survey the channels, measuring
+//! the energy on each of them, and read how often the CCAs before
+//! transmitting found a channel busy, so that they can pick a quiet channel.
 
 use crate::ieee802154::{device, framer};
 use crate::net::ieee802154::{AddressMode, Header, KeyId, MacAddress, PanID, SecurityLevel};
@@ -14,6 +19,7 @@ use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::common::dynamic_deferred_call::{
     DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
 };
+use kernel::hil::radio;
 use kernel::{
     CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
     ReadWriteAppSlice, Upcall,
@@ -22,6 +28,11 @@ use kernel::{
 const MAX_NEIGHBORS: usize = 4;
 const MAX_KEYS: usize = 4;
 
+/// Channels of the 2.4 GHz O-QPSK PHY, which a channel survey can scan.
+const FIRST_CHANNEL: u8 = 11;
+const NUM_CHANNELS: usize = 16;
+const CHANNEL_MASK: u32 = 0xFFFF << FIRST_CHANNEL;
+
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Ieee802154 as usize;
 
@@ -155,6 +166,7 @@ impl KeyDescriptor {
 pub struct App {
     rx_callback: Upcall,
     tx_callback: Upcall,
+    survey_callback: Upcall,
     app_read: ReadWriteAppSlice,
     app_write: ReadOnlyAppSlice,
     app_cfg: ReadWriteAppSlice,
@@ -196,6 +208,16 @@ pub struct RadioDriver<'a> {
 
     /// Used to save result for passing a callback from a deferred call.
     saved_result: OptionalCell<Result<(), ErrorCode>>,
+
+    /// Energy detection and CCA statistics of the radio, if supported.
+    energy: OptionalCell<&'a dyn radio::RadioEnergy>,
+    /// ID of the app running a channel survey.
+    survey_app: OptionalCell<ProcessId>,
+    /// Channels left to scan in the survey, as a bit mask.
+    survey_channels: Cell<u32>,
+    survey_duration_us: Cell<u32>,
+    /// Peak energy measured on each channel, in dBm.
+    survey_energy: Cell<[i8; NUM_CHANNELS]>,
 }
 
 impl<'a> RadioDriver<'a> {
@@ -218,9 +240,20 @@ impl<'a> RadioDriver<'a> {
             saved_appid: OptionalCell::empty(),
             saved_result: OptionalCell::empty(),
             handle: OptionalCell::empty(),
+            energy: OptionalCell::empty(),
+            survey_app: OptionalCell::empty(),
+            survey_channels: Cell::new(0),
+            survey_duration_us: Cell::new(0),
+            survey_energy: Cell::new([i8::MIN; NUM_CHANNELS]),
         }
     }
 
+    /// Enable the channel survey and CCA statistics commands, which use the
+    /// energy detection of `energy`.
+    pub fn set_energy_scanner(&self, energy: &'a dyn radio::RadioEnergy) {
+        self.energy.set(energy);
+    }
+
     pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
         self.handle.replace(handle);
     }
@@ -440,6 +473,78 @@ impl<'a> RadioDriver<'a> {
     /// Schedule the next transmission if there is one pending. Performs the
     /// transmission asynchronously, returning any errors via callbacks.
     #[inline]
+    // Channel survey functions
+
+    /// Start a survey of the channels in `channels` for `app`, scanning each
+    /// of them for `duration_us` microseconds.
+    fn start_survey(
+        &self,
+        app: ProcessId,
+        channels: u32,
+        duration_us: u32,
+    ) -> Result<(), ErrorCode> {
+        if self.energy.is_none() {
+            return Err(ErrorCode::NOSUPPORT);
+        } else if self.survey_app.is_some() {
+            return Err(ErrorCode::BUSY);
+        } else if channels & !CHANNEL_MASK != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        self.apps.enter(app, |app| {
+            app.app_cfg.map_or(Err(ErrorCode::INVAL), |cfg| {
+                if cfg.len() < NUM_CHANNELS {
+                    Err(ErrorCode::SIZE)
+                } else {
+                    Ok(())
+                }
+            })
+        })??;
+
+        self.survey_channels.set(if channels == 0 {
+            CHANNEL_MASK
+        } else {
+            channels
+        });
+        self.survey_duration_us.set(duration_us);
+        self.survey_energy.set([i8::MIN; NUM_CHANNELS]);
+        self.scan_next_channel().map(|()| {
+            self.survey_app.set(app);
+        })
+    }
+
+    /// Scan the next channel of the survey. At least one must be left.
+    fn scan_next_channel(&self) -> Result<(), ErrorCode> {
+        let channels = self.survey_channels.get();
+        let channel = channels.trailing_zeros();
+        self.survey_channels.set(channels & !(1 << channel));
+        self.energy.map_or(Err(ErrorCode::NOSUPPORT), |energy| {
+            energy.energy_scan(channel as u8, self.survey_duration_us.get())
+        })
+    }
+
+    /// Report the survey to its app: the energy of each channel goes into
+    /// the config buffer, and the quietest channel into the callback.
+    fn finish_survey(&self, result: Result<(), ErrorCode>) {
+        let survey_energy = self.survey_energy.get();
+        let quietest = (0..NUM_CHANNELS)
+            .filter(|&i| survey_energy[i] != i8::MIN)
+            .min_by_key(|&i| survey_energy[i])
+            .map_or(0, |i| i + FIRST_CHANNEL as usize);
+        self.survey_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.app_cfg.mut_map_or((), |cfg| {
+                    let cfg = cfg.as_mut();
+                    let len = min(cfg.len(), NUM_CHANNELS);
+                    for (dst, energy) in cfg[..len].iter_mut().zip(survey_energy.iter()) {
+                        *dst = *energy as u8;
+                    }
+                });
+                app.survey_callback
+                    .schedule(kernel::into_statuscode(result), quietest, 0);
+            });
+        });
+    }
+
     fn do_next_tx_async(&self) {
         self.get_next_tx_if_idle()
             .map(|appid| self.perform_tx_async(appid));
@@ -565,6 +670,8 @@ impl Driver for RadioDriver<'_> {
     ///
     /// - `0`: Setup callback for when frame is received.
     /// - `1`: Setup callback for when frame is transmitted.
+    /// - `2`: Setup callback for when a channel survey is done. The arguments
+    ///        are the status and the quietest channel scanned.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -581,6 +688,10 @@ impl Driver for RadioDriver<'_> {
                     mem::swap(&mut app.tx_callback, &mut callback);
                     Ok(callback)
                 }
+                2 => {
+                    mem::swap(&mut app.survey_callback, &mut callback);
+                    Ok(callback)
+                }
                 _ => Err((callback, ErrorCode::NOSUPPORT)),
             })
             .unwrap_or_else(|err| Err((callback, err.into())))
@@ -635,11 +746,22 @@ impl Driver for RadioDriver<'_> {
     ///                      9 bytes: the key ID (might not use all bytes) +
     ///                      16 bytes: the key.
     /// - `25`: Remove the key at an index.
+    /// - `26`: Transmit a frame.
+    /// - `27`: Survey the channels whose bits are set in the first argument
+    ///        (bits 11 to 26, or 0 for all of them), measuring the energy on
+    ///        each one for the number of milliseconds in the second
+    ///        argument. Frames are neither sent nor received during the
+    ///        survey. Returns NOSUPPORT if the radio cannot measure energy.
+    ///        app_cfg (out): 16 bytes: the peak energy measured on channels
+    ///                       11 to 26, in dBm as i8, -128 if not scanned.
+    /// - `28`: Get the number of CCAs run on a channel and the number of
+    ///        them that found it busy, since the last reset.
+    /// - `29`: Reset the CCA counts of all channels.
     fn command(
         &self,
         command_number: usize,
         arg1: usize,
-        _: usize,
+        arg2: usize,
         appid: ProcessId,
     ) -> CommandReturn {
         match command_number {
@@ -873,11 +995,55 @@ impl Driver for RadioDriver<'_> {
                         },
                     )
             }
+            27 => self
+                .start_survey(appid, arg1 as u32, (arg2 as u32).saturating_mul(1000))
+                .into(),
+            28 => self
+                .energy
+                .map_or(
+                    CommandReturn::failure(ErrorCode::NOSUPPORT),
+                    |energy| match energy.cca_stats(arg1 as u8) {
+                        Ok(stats) => CommandReturn::success_u32_u32(stats.attempts, stats.busy),
+                        Err(e) => CommandReturn::failure(e),
+                    },
+                ),
+            29 => self
+                .energy
+                .map_or(CommandReturn::failure(ErrorCode::NOSUPPORT), |energy| {
+                    energy.reset_cca_stats();
+                    CommandReturn::success()
+                }),
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
 }
 
+impl radio::EnergyScanClient for RadioDriver<'_> {
+    fn scan_done(&self, channel: u8, energy: Result<i8, ErrorCode>) {
+        if self.survey_app.is_none() {
+            return;
+        }
+        let result = energy.and_then(|energy| {
+            let mut survey_energy = self.survey_energy.get();
+            if let Some(entry) = survey_energy.get_mut(channel.wrapping_sub(FIRST_CHANNEL) as usize)
+            {
+                *entry = energy;
+            }
+            self.survey_energy.set(survey_energy);
+            if self.survey_channels.get() == 0 {
+                Ok(false)
+            } else {
+                self.scan_next_channel().map(|()| true)
+            }
+        });
+        match result {
+            Ok(true) => {}
+            Ok(false) => self.finish_survey(Ok(())),
+            Err(e) => self.finish_survey(Err(e)),
+        }
+    }
+}
+
 impl device::TxClient for RadioDriver<'_> {
     fn send_done(&self, spi_buf: &'static mut [u8], acked: bool, result: Result<(), ErrorCode>) {
         self.kernel_tx.replace(spi_buf);
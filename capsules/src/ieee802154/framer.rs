@@ -1,6 +1,6 @@
 //! Implements IEEE 802.15.4 MAC device abstraction over a 802.15.4 MAC interface.
 //! Allows its users to prepare and send frames in plaintext, handling 802.15.4
-//! encoding and security procedures (in the future) transparently.
+//! encoding and security procedures transparently.
 //!
 //! However, certain IEEE 802.15.4 MAC device concepts are not implemented in
 //! this layer of abstraction and instead handled in hardware for performance
@@ -64,15 +64,35 @@
 //! mac_device.set_transmit_client(radio_capsule);
 //! mac_device.set_receive_client(radio_capsule);
 //! ```
+//!
+//! Security
+//! --------
+//!
+//! Frames prepared with a security level and key ID are encrypted and
+//! authenticated with AES-CCM* before transmission, using the key returned by
+//! the `KeyProcedure` (the keys provisioned through the radio driver, above).
+//! The CCM* nonce is built from the extended address of the sender, its frame
+//! counter and the security level. The outgoing frame counter is incremented
+//! for every secured frame, and no secured frames are sent once it is
+//! exhausted. Since a nonce must never be reused with the same key, boards
+//! that keep keys across reboots should save the counter with
+//! `get_frame_counter()` and restore it with `set_frame_counter()`.
+//!
+//! Secured incoming frames are decrypted and their MIC is checked before they
+//! are passed to the receive client; frames that fail authentication are
+//! dropped. The sender must be a neighbor known to the `DeviceProcedure`, and
+//! frames whose frame counter is not greater than that of the last
+//! authenticated frame from the same device are dropped as replays. The
+//! counters of the last `RX_FRAME_COUNTERS_LEN` devices are kept.
 
 //
-// TODO: Encryption/decryption
 // TODO: Sending beacon frames
 // TODO: Channel scanning
 //
 
 use crate::ieee802154::device::{MacDevice, RxClient, TxClient};
 use crate::ieee802154::mac::Mac;
+use crate::net::buffer::PacketBuffer;
 use crate::net::ieee802154::{
     FrameType, FrameVersion, Header, KeyId, MacAddress, PanID, Security, SecurityLevel,
 };
@@ -140,6 +160,24 @@ impl Frame {
 
         Ok(())
     }
+
+    /// Builds payload bytes in place at the end of the frame. `build` gets a
+    /// `PacketBuffer` over the space the frame has left, with `headroom`
+    /// bytes of it reserved for a header that is only known once the bytes
+    /// after it are written. What `build` leaves in the buffer is appended
+    /// to the payload, moved over any headroom it did not use.
+    pub fn build_payload<F, R>(&mut self, headroom: usize, build: F) -> R
+    where
+        F: FnOnce(&mut PacketBuffer) -> R,
+    {
+        let begin = radio::PSDU_OFFSET + self.info.unsecured_length();
+        let end = begin + self.remaining_data_capacity();
+        let mut packet = PacketBuffer::new(&mut self.buf[begin..end], headroom);
+        let result = build(&mut packet);
+        packet.reclaim_headroom();
+        self.info.data_len += packet.len();
+        result
+    }
 }
 
 impl FrameInfo {
@@ -159,7 +197,6 @@ impl FrameInfo {
     /// frame type and security levels. Returns the (offset, len) of the m data
     /// fields, not including the MIC. The a data is always the remaining prefix
     /// of the header, so it can be determined implicitly.
-    #[allow(dead_code)]
     fn ccm_encrypt_ranges(&self) -> (usize, usize) {
         // IEEE 802.15.4-2015: Table 9-1. Exceptions to Private Payload field
         // The boundary between open and private payload fields depends
@@ -191,7 +228,7 @@ impl FrameInfo {
             // m data is the private payload field
             (
                 private_payload_offset,
-                self.unsecured_length() | private_payload_offset,
+                self.unsecured_length() - private_payload_offset,
             )
         }
     }
@@ -222,6 +259,13 @@ fn get_ccm_nonce(device_addr: &[u8; 8], frame_counter: u32, level: SecurityLevel
 /// - pads the m data to 16-byte blocks
 pub const CRYPT_BUF_SIZE: usize = radio::MAX_MTU + 3 * 16;
 
+/// Number of devices whose incoming frame counters are kept for replay
+/// protection. When the table is full, the oldest device is forgotten.
+pub const RX_FRAME_COUNTERS_LEN: usize = 8;
+
+/// Largest frame counter; frames with this counter are never sent or accepted.
+const FRAME_COUNTER_MAX: u32 = 0xffffffff;
+
 /// IEEE 802.15.4-2015, 9.2.2, KeyDescriptor lookup procedure.
 /// Trait to be implemented by an upper layer that manages the list of 802.15.4
 /// key descriptors. This trait interface enables the lookup procedure to be
@@ -298,6 +342,17 @@ pub struct Framer<'a, M: Mac, A: AES128CCM<'a>> {
     aes_ccm: &'a A,
     data_sequence: Cell<u8>,
 
+    /// Frame counter of the next secured frame to be sent
+    tx_frame_counter: Cell<u32>,
+    /// Smallest frame counter accepted from each known device, by extended
+    /// address
+    rx_frame_counters: Cell<[Option<([u8; 8], u32)>; RX_FRAME_COUNTERS_LEN]>,
+    /// Entry of `rx_frame_counters` replaced by the next new device
+    rx_frame_counters_next: Cell<usize>,
+    /// Source device and frame counter of the frame being decrypted, recorded
+    /// once the frame is authenticated
+    rx_frame_counter: OptionalCell<([u8; 8], u32)>,
+
     /// KeyDescriptor lookup procedure
     key_procedure: OptionalCell<&'a dyn KeyProcedure>,
     /// DeviceDescriptor lookup procedure
@@ -322,6 +377,10 @@ impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
             mac: mac,
             aes_ccm: aes_ccm,
             data_sequence: Cell::new(0),
+            tx_frame_counter: Cell::new(0),
+            rx_frame_counters: Cell::new([None; RX_FRAME_COUNTERS_LEN]),
+            rx_frame_counters_next: Cell::new(0),
+            rx_frame_counter: OptionalCell::empty(),
             key_procedure: OptionalCell::empty(),
             device_procedure: OptionalCell::empty(),
             tx_state: MapCell::new(TxState::Idle),
@@ -341,6 +400,49 @@ impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
         self.device_procedure.set(device_procedure);
     }
 
+    /// Sets the frame counter used for the next secured frame.
+    pub fn set_frame_counter(&self, frame_counter: u32) {
+        self.tx_frame_counter.set(frame_counter);
+    }
+
+    /// Gets the frame counter used for the next secured frame.
+    pub fn get_frame_counter(&self) -> u32 {
+        self.tx_frame_counter.get()
+    }
+
+    /// IEEE 802.15.4-2015, 9.2.3, step h: checks that `frame_counter` is not
+    /// smaller than the next counter expected from `device_addr`.
+    fn frame_counter_valid(&self, device_addr: &[u8; 8], frame_counter: u32) -> bool {
+        self.rx_frame_counters
+            .get()
+            .iter()
+            .flatten()
+            .find(|(addr, _)| addr == device_addr)
+            .map_or(true, |(_, next)| frame_counter >= *next)
+    }
+
+    /// IEEE 802.15.4-2015, 9.2.3, step o: records the frame counter of an
+    /// authenticated frame from `device_addr`, so that it is not accepted
+    /// again.
+    fn record_frame_counter(&self, device_addr: [u8; 8], frame_counter: u32) {
+        let mut counters = self.rx_frame_counters.get();
+        let next = frame_counter + 1;
+        match counters
+            .iter_mut()
+            .flatten()
+            .find(|(addr, _)| *addr == device_addr)
+        {
+            Some(entry) => entry.1 = next,
+            None => {
+                let index = self.rx_frame_counters_next.get();
+                counters[index] = Some((device_addr, next));
+                self.rx_frame_counters_next
+                    .set((index + 1) % RX_FRAME_COUNTERS_LEN);
+            }
+        }
+        self.rx_frame_counters.set(counters);
+    }
+
     /// Look up the key using the IEEE 802.15.4 KeyDescriptor lookup prodecure
     /// implemented elsewhere.
     fn lookup_key(&self, level: SecurityLevel, key_id: KeyId) -> Option<[u8; 16]> {
@@ -430,11 +532,12 @@ impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                         // Step g, h: Check frame counter
                         let frame_counter = match security.frame_counter {
                             Some(frame_counter) => {
-                                if frame_counter == 0xffffffff {
+                                if frame_counter == FRAME_COUNTER_MAX
+                                    || !self.frame_counter_valid(&device_addr, frame_counter)
+                                {
                                     // Counter error
                                     return None;
                                 }
-                                // TODO: Check frame counter against source device
                                 frame_counter
                             }
                             // TSCH mode, where ASN is used instead, not supported
@@ -445,6 +548,7 @@ impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
 
                         // Compute ccm nonce
                         let nonce = get_ccm_nonce(&device_addr, frame_counter, security.level);
+                        self.rx_frame_counter.set((device_addr, frame_counter));
 
                         Some(FrameInfo {
                             frame_type: header.frame_type,
@@ -569,7 +673,7 @@ impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                                     m_len,
                                     info.mic_len,
                                     level.encryption_needed(),
-                                    true,
+                                    false,
                                 );
                                 match res {
                                     Ok(()) => (RxState::Decrypting(info), None),
@@ -685,9 +789,15 @@ impl<'a, M: Mac, A: AES128CCM<'a>> MacDevice<'a> for Framer<'a, M, A> {
         // specification.
         let src_addr_long = self.get_address_long();
         let security_desc = security_needed.and_then(|(level, key_id)| {
+            let frame_counter = self.tx_frame_counter.get();
+            if frame_counter == FRAME_COUNTER_MAX {
+                // Counter error
+                return None;
+            }
             self.lookup_key(level, key_id).map(|key| {
-                // TODO: lookup frame counter for device
-                let frame_counter = 0;
+                // Every secured frame uses a new counter, even if it is
+                // never sent, so that nonces are never reused.
+                self.tx_frame_counter.set(frame_counter + 1);
                 let nonce = get_ccm_nonce(&src_addr_long, frame_counter, level);
                 (
                     Security {
@@ -769,7 +879,8 @@ impl<'a, M: Mac, A: AES128CCM<'a>> MacDevice<'a> for Framer<'a, M, A> {
 
 impl<'a, M: Mac, A: AES128CCM<'a>> radio::TxClient for Framer<'a, M, A> {
     fn send_done(&self, buf: &'static mut [u8], acked: bool, result: Result<(), ErrorCode>) {
-        self.data_sequence.set(self.data_sequence.get() + 1);
+        self.data_sequence
+            .set(self.data_sequence.get().wrapping_add(1));
         self.tx_client.map(move |client| {
             client.send_done(buf, acked, result);
         });
@@ -873,7 +984,11 @@ impl<'a, M: Mac, A: AES128CCM<'a>> CCMClient for Framer<'a, M, A> {
                 let buf = buf;
                 match state {
                     RxState::Decrypting(info) => {
+                        let frame_counter = self.rx_frame_counter.take();
                         let next_state = if tag_is_valid {
+                            if let Some((device_addr, frame_counter)) = frame_counter {
+                                self.record_frame_counter(device_addr, frame_counter);
+                            }
                             RxState::ReadyToYield(info, buf)
                         } else {
                             RxState::ReadyToReturn(buf)
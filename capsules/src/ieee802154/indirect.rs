@@ -0,0 +1,773 @@
+//! Indirect transmission for sleepy 802.15.4 devices.
+//!
+//! A sleepy end device keeps its radio off most of the time and periodically
+//! polls its parent (the coordinator) with a MAC data request command. Frames
+//! for such a device cannot be sent whenever they are ready, as the device
+//! will usually not be listening. Instead, the coordinator holds them as
+//! "indirect" frames and sends them only in response to a data request from
+//! the device.
+//!
+//! `IndirectMac` implements this on the coordinator as a layer between a
+//! `MacDevice` (such as the `Framer`) and another `Mac` implementation:
+//!
+//!   * Frames destined for a device marked sleepy with `set_sleepy()` are
+//!     copied into a small queue and their transmission is reported complete
+//!     (without an ACK). Frames for other devices pass straight through.
+//!   * When a data request arrives from a sleepy device, its oldest queued
+//!     frame is sent. The frame pending bit of that frame is set if more
+//!     frames remain queued for the device, so that it keeps polling.
+//!   * While any frame is queued, ACKs sent by the radio have the frame
+//!     pending bit set, so devices polling with a data request stay awake to
+//!     receive their frame.
+//!   * Queued frames that are not requested within the expiry time are
+//!     dropped.
+//!
+//! The frame pending bit cannot be changed in a secured frame without
+//! invalidating its MIC, so it is left as the framer set it for secured
+//! frames.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! static mut INDIRECT_BUFS: [[u8; radio::MAX_BUF_SIZE]; 4] = [[0; radio::MAX_BUF_SIZE]; 4];
+//!
+//! let indirect_frames = static_init!(
+//!     [capsules::ieee802154::indirect::IndirectFrame; 4],
+//!     [
+//!         IndirectFrame::new(&mut INDIRECT_BUFS[0]),
+//!         IndirectFrame::new(&mut INDIRECT_BUFS[1]),
+//!         IndirectFrame::new(&mut INDIRECT_BUFS[2]),
+//!         IndirectFrame::new(&mut INDIRECT_BUFS[3]),
+//!     ]
+//! );
+//! let indirect_mac = static_init!(
+//!     capsules::ieee802154::indirect::IndirectMac<'static, AwakeMacDevice, Alarm>,
+//!     capsules::ieee802154::indirect::IndirectMac::new(awake_mac, alarm, indirect_frames)
+//! );
+//! awake_mac.set_transmit_client(indirect_mac);
+//! awake_mac.set_receive_client(indirect_mac);
+//! alarm.set_alarm_client(indirect_mac);
+//!
+//! // The framer is then built on `indirect_mac` instead of `awake_mac`.
+//! indirect_mac.set_sleepy(MacAddress::Short(0x0002), true);
+//! ```
+
+use crate::ieee802154::mac::Mac;
+use crate::net::ieee802154::{FrameType, Header, MacAddress};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::time::{Alarm, AlarmClient, Ticks};
+use kernel::ErrorCode;
+
+/// Number of devices that can be marked sleepy.
+pub const MAX_SLEEPY_DEVICES: usize = 8;
+
+/// Default time a frame is held for a sleepy device.
+pub const DEFAULT_EXPIRY_MS: u32 = 7000;
+
+/// MAC command identifier of a data request.
+const DATA_REQUEST_COMMAND: u8 = 0x04;
+
+/// Frame control bits in the first byte of the frame.
+const FCF_SECURITY_ENABLED: u8 = 1 << 3;
+const FCF_FRAME_PENDING: u8 = 1 << 4;
+
+/// A queue slot holding one frame for a sleepy device.
+pub struct IndirectFrame {
+    buf: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+    dst: Cell<Option<MacAddress>>,
+    expiry: Cell<u32>,
+    // Order the frame was queued in, so frames are sent oldest first.
+    order: Cell<usize>,
+}
+
+impl IndirectFrame {
+    pub fn new(buf: &'static mut [u8]) -> IndirectFrame {
+        IndirectFrame {
+            buf: TakeCell::new(buf),
+            len: Cell::new(0),
+            dst: Cell::new(None),
+            expiry: Cell::new(0),
+            order: Cell::new(0),
+        }
+    }
+}
+
+pub struct IndirectMac<'a, M: Mac, A: Alarm<'a>> {
+    mac: &'a M,
+    alarm: &'a A,
+    frames: &'a [IndirectFrame],
+    sleepy: [Cell<Option<MacAddress>>; MAX_SLEEPY_DEVICES],
+    expiry_ms: Cell<u32>,
+    next_order: Cell<usize>,
+    dropped: Cell<usize>,
+
+    /// Index of the queued frame being sent.
+    sending: OptionalCell<usize>,
+    /// Device that requested a frame while the radio was busy.
+    requested: OptionalCell<MacAddress>,
+    /// Buffer of a queued frame, returned to the client from the alarm.
+    queued_done: TakeCell<'static, [u8]>,
+
+    tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    rx_client: OptionalCell<&'static dyn radio::RxClient>,
+}
+
+impl<'a, M: Mac, A: Alarm<'a>> IndirectMac<'a, M, A> {
+    pub fn new(mac: &'a M, alarm: &'a A, frames: &'a [IndirectFrame]) -> IndirectMac<'a, M, A> {
+        IndirectMac {
+            mac: mac,
+            alarm: alarm,
+            frames: frames,
+            sleepy: Default::default(),
+            expiry_ms: Cell::new(DEFAULT_EXPIRY_MS),
+            next_order: Cell::new(0),
+            dropped: Cell::new(0),
+            sending: OptionalCell::empty(),
+            requested: OptionalCell::empty(),
+            queued_done: TakeCell::empty(),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Mark whether the device with the given address is sleepy, so frames
+    /// for it are held until it requests them. Unmarking a device drops any
+    /// frames still queued for it.
+    pub fn set_sleepy(&self, addr: MacAddress, sleepy: bool) -> Result<(), ErrorCode> {
+        if sleepy {
+            if self.is_sleepy(addr) {
+                return Ok(());
+            }
+            let slot = self.sleepy.iter().find(|s| s.get().is_none());
+            slot.map_or(Err(ErrorCode::NOMEM), |slot| {
+                slot.set(Some(addr));
+                Ok(())
+            })
+        } else {
+            for slot in self.sleepy.iter() {
+                if slot.get() == Some(addr) {
+                    slot.set(None);
+                }
+            }
+            for (i, frame) in self.frames.iter().enumerate() {
+                if frame.dst.get() == Some(addr) && !self.sending.contains(&i) {
+                    frame.dst.set(None);
+                }
+            }
+            self.update_ack_frame_pending();
+            Ok(())
+        }
+    }
+
+    /// Set how long frames are held for a sleepy device before being dropped.
+    pub fn set_expiry_ms(&self, expiry_ms: u32) {
+        self.expiry_ms.set(expiry_ms);
+    }
+
+    /// Returns the number of queued frames for `addr`.
+    pub fn queued_frames(&self, addr: MacAddress) -> usize {
+        self.frames
+            .iter()
+            .filter(|frame| frame.dst.get() == Some(addr))
+            .count()
+    }
+
+    /// Returns the number of frames dropped because they expired or the queue
+    /// was full.
+    pub fn dropped_frames(&self) -> usize {
+        self.dropped.get()
+    }
+
+    fn is_sleepy(&self, addr: MacAddress) -> bool {
+        self.sleepy.iter().any(|s| s.get() == Some(addr))
+    }
+
+    fn any_queued(&self) -> bool {
+        self.frames.iter().any(|frame| frame.dst.get().is_some())
+    }
+
+    fn update_ack_frame_pending(&self) {
+        self.mac.set_ack_frame_pending(self.any_queued());
+        self.mac.config_commit();
+    }
+
+    /// Returns the index of the oldest frame queued for `addr` that is not
+    /// being sent.
+    fn oldest_frame(&self, addr: MacAddress) -> Option<usize> {
+        self.frames
+            .iter()
+            .enumerate()
+            .filter(|(i, frame)| frame.dst.get() == Some(addr) && !self.sending.contains(i))
+            .min_by_key(|(_, frame)| frame.order.get())
+            .map(|(i, _)| i)
+    }
+
+    /// Copy a frame for a sleepy device into the queue.
+    fn enqueue(&self, dst: MacAddress, buf: &[u8], len: usize) -> Result<(), ErrorCode> {
+        let index = self
+            .frames
+            .iter()
+            .position(|frame| frame.dst.get().is_none() && frame.buf.is_some())
+            .ok_or(ErrorCode::NOMEM)?;
+        let frame = &self.frames[index];
+        frame.buf.map_or(Err(ErrorCode::NOMEM), |qbuf| {
+            let total = radio::PSDU_OFFSET + len;
+            if total > qbuf.len() || total > buf.len() {
+                return Err(ErrorCode::SIZE);
+            }
+            qbuf[..total].copy_from_slice(&buf[..total]);
+            Ok(())
+        })?;
+        frame.len.set(len);
+        frame.dst.set(Some(dst));
+        frame.order.set(self.next_order.get());
+        self.next_order.set(self.next_order.get().wrapping_add(1));
+        let now = self.alarm.now();
+        let expiry = now.wrapping_add(A::ticks_from_ms(self.expiry_ms.get()));
+        frame.expiry.set(expiry.into_u32());
+        Ok(())
+    }
+
+    /// Send the oldest frame queued for `addr`, if there is one.
+    fn send_queued(&self, addr: MacAddress) {
+        if self.sending.is_some() {
+            self.requested.set(addr);
+            return;
+        }
+        let index = match self.oldest_frame(addr) {
+            Some(index) => index,
+            None => return,
+        };
+        let frame = &self.frames[index];
+        let more = self.queued_frames(addr) > 1;
+        if let Some(buf) = frame.buf.take() {
+            let fcf = &mut buf[radio::PSDU_OFFSET];
+            if *fcf & FCF_SECURITY_ENABLED == 0 {
+                if more {
+                    *fcf |= FCF_FRAME_PENDING;
+                } else {
+                    *fcf &= !FCF_FRAME_PENDING;
+                }
+            }
+            self.sending.set(index);
+            match self.mac.transmit(buf, frame.len.get()) {
+                Ok(()) => {}
+                Err((ErrorCode::BUSY, buf)) => {
+                    // Try again once the current transmission completes.
+                    frame.buf.replace(buf);
+                    self.sending.clear();
+                    self.requested.set(addr);
+                }
+                Err((_, buf)) => {
+                    frame.buf.replace(buf);
+                    self.sending.clear();
+                }
+            }
+        }
+    }
+
+    /// Drop frames that have expired and arm the alarm for the next expiry.
+    fn expire_frames(&self) {
+        let now = self.alarm.now();
+        let mut next: Option<A::Ticks> = None;
+        for (i, frame) in self.frames.iter().enumerate() {
+            if frame.dst.get().is_none() || self.sending.contains(&i) {
+                continue;
+            }
+            let expiry = A::Ticks::from(frame.expiry.get());
+            let remaining = expiry.wrapping_sub(now);
+            // A frame has expired once its expiry is no longer ahead of now
+            // by at most the expiry time.
+            if remaining > A::ticks_from_ms(self.expiry_ms.get()) || remaining.into_u32() == 0 {
+                frame.dst.set(None);
+                self.dropped.set(self.dropped.get() + 1);
+            } else if next.map_or(true, |n| remaining < n) {
+                next = Some(remaining);
+            }
+        }
+        if let Some(dt) = next {
+            self.alarm.set_alarm(now, dt);
+        }
+        self.update_ack_frame_pending();
+    }
+
+    /// If `buf` holds a data request from a sleepy device, returns the
+    /// device's address.
+    fn data_request_source(&self, buf: &[u8]) -> Option<MacAddress> {
+        let (data_offset, (header, _)) =
+            Header::decode(&buf[radio::PSDU_OFFSET..], false).done()?;
+        if header.frame_type != FrameType::MACCommand {
+            return None;
+        }
+        let command = buf.get(radio::PSDU_OFFSET + data_offset)?;
+        match header.src_addr {
+            Some(src) if *command == DATA_REQUEST_COMMAND && self.is_sleepy(src) => Some(src),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, M: Mac, A: Alarm<'a>> Mac for IndirectMac<'a, M, A> {
+    fn initialize(&self, mac_buf: &'static mut [u8]) -> Result<(), ErrorCode> {
+        self.mac.initialize(mac_buf)
+    }
+
+    fn is_on(&self) -> bool {
+        self.mac.is_on()
+    }
+
+    fn set_config_client(&self, client: &'static dyn radio::ConfigClient) {
+        self.mac.set_config_client(client)
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.mac.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.mac.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.mac.set_pan(id)
+    }
+
+    fn set_ack_frame_pending(&self, pending: bool) {
+        // Frames queued here always keep the frame pending bit set.
+        self.mac.set_ack_frame_pending(pending || self.any_queued())
+    }
+
+    fn get_address(&self) -> u16 {
+        self.mac.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.mac.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.mac.get_pan()
+    }
+
+    fn config_commit(&self) {
+        self.mac.config_commit()
+    }
+
+    fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static dyn radio::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.mac.set_receive_buffer(buffer);
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let dst = Header::decode(&full_mac_frame[radio::PSDU_OFFSET..], false)
+            .done()
+            .and_then(|(_, (header, _))| header.dst_addr);
+        match dst {
+            Some(dst) if self.is_sleepy(dst) => {
+                if self.queued_done.is_some() {
+                    return Err((ErrorCode::BUSY, full_mac_frame));
+                }
+                if let Err(e) = self.enqueue(dst, full_mac_frame, frame_len) {
+                    self.dropped.set(self.dropped.get() + 1);
+                    return Err((e, full_mac_frame));
+                }
+                // Report completion from the alarm rather than from within
+                // this call, which also sets the expiry of the new frame.
+                self.queued_done.replace(full_mac_frame);
+                self.alarm.set_alarm(self.alarm.now(), A::Ticks::from(1));
+                self.update_ack_frame_pending();
+                Ok(())
+            }
+            _ => self.mac.transmit(full_mac_frame, frame_len),
+        }
+    }
+}
+
+impl<'a, M: Mac, A: Alarm<'a>> radio::TxClient for IndirectMac<'a, M, A> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: Result<(), ErrorCode>) {
+        match self.sending.take() {
+            Some(index) => {
+                let frame = &self.frames[index];
+                frame.buf.replace(buf);
+                // A frame that was not received stays queued until the
+                // device polls again or the frame expires.
+                if result.is_ok() && acked {
+                    frame.dst.set(None);
+                    self.update_ack_frame_pending();
+                }
+            }
+            None => {
+                self.tx_client.map(move |c| {
+                    c.send_done(buf, acked, result);
+                });
+            }
+        }
+
+        if let Some(addr) = self.requested.take() {
+            self.send_queued(addr);
+        }
+    }
+}
+
+impl<'a, M: Mac, A: Alarm<'a>> radio::RxClient for IndirectMac<'a, M, A> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        result: Result<(), ErrorCode>,
+    ) {
+        let requester = if crc_valid {
+            self.data_request_source(buf)
+        } else {
+            None
+        };
+        match requester {
+            Some(addr) => {
+                self.mac.set_receive_buffer(buf);
+                self.send_queued(addr);
+            }
+            None => {
+                self.rx_client.map(move |c| {
+                    c.receive(buf, frame_len, crc_valid, result);
+                });
+            }
+        }
+    }
+}
+
+impl<'a, M: Mac, A: Alarm<'a>> AlarmClient for IndirectMac<'a, M, A> {
+    fn alarm(&self) {
+        if let Some(buf) = self.queued_done.take() {
+            self.tx_client.map(move |c| {
+                c.send_done(buf, false, Ok(()));
+            });
+        }
+        self.expire_frames();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::net::ieee802154::FrameVersion;
+    use crate::test::mock_alarm::MockAlarm;
+    use crate::test::mock_process;
+    use core::cell::RefCell;
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    const PARENT: MacAddress = MacAddress::Short(0x0001);
+    const CHILD: MacAddress = MacAddress::Short(0x0002);
+    const AWAKE: MacAddress = MacAddress::Short(0x0003);
+
+    /// A MAC that records the frames it transmits and holds each until the
+    /// test completes it.
+    struct MockMac {
+        sent: RefCell<Vec<Vec<u8>>>,
+        tx: TakeCell<'static, [u8]>,
+        ack_frame_pending: Cell<bool>,
+        tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    }
+
+    impl MockMac {
+        fn new() -> MockMac {
+            MockMac {
+                sent: RefCell::new(Vec::new()),
+                tx: TakeCell::empty(),
+                ack_frame_pending: Cell::new(false),
+                tx_client: OptionalCell::empty(),
+            }
+        }
+
+        /// Finish the transmission in progress.
+        fn complete(&self, acked: bool) {
+            let buf = self.tx.take().expect("no transmission in progress");
+            self.tx_client
+                .map(move |client| client.send_done(buf, acked, Ok(())));
+        }
+    }
+
+    impl Mac for MockMac {
+        fn initialize(&self, _mac_buf: &'static mut [u8]) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn set_config_client(&self, _client: &'static dyn radio::ConfigClient) {}
+
+        fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+            self.tx_client.set(client);
+        }
+
+        fn set_receive_client(&self, _client: &'static dyn radio::RxClient) {}
+
+        fn set_receive_buffer(&self, _buffer: &'static mut [u8]) {}
+
+        fn get_address(&self) -> u16 {
+            0x0001
+        }
+
+        fn get_address_long(&self) -> [u8; 8] {
+            [0; 8]
+        }
+
+        fn get_pan(&self) -> u16 {
+            0xABCD
+        }
+
+        fn set_address(&self, _addr: u16) {}
+
+        fn set_address_long(&self, _addr: [u8; 8]) {}
+
+        fn set_pan(&self, _id: u16) {}
+
+        fn set_ack_frame_pending(&self, pending: bool) {
+            self.ack_frame_pending.set(pending);
+        }
+
+        fn config_commit(&self) {}
+
+        fn is_on(&self) -> bool {
+            true
+        }
+
+        fn transmit(
+            &self,
+            full_mac_frame: &'static mut [u8],
+            frame_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            if self.tx.is_some() {
+                return Err((ErrorCode::BUSY, full_mac_frame));
+            }
+            let frame = &full_mac_frame[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len];
+            self.sent.borrow_mut().push(frame.to_vec());
+            self.tx.replace(full_mac_frame);
+            Ok(())
+        }
+    }
+
+    /// Records the transmissions reported to the MAC device.
+    #[derive(Default)]
+    struct Client {
+        done: RefCell<Vec<(bool, Result<(), ErrorCode>)>>,
+    }
+
+    impl radio::TxClient for Client {
+        fn send_done(&self, _buf: &'static mut [u8], acked: bool, result: Result<(), ErrorCode>) {
+            self.done.borrow_mut().push((acked, result));
+        }
+    }
+
+    type Indirect = IndirectMac<'static, MockMac, MockAlarm<'static>>;
+
+    struct IndirectTest {
+        indirect: &'static Indirect,
+        mac: &'static MockMac,
+        alarm: &'static MockAlarm<'static>,
+        client: &'static Client,
+    }
+
+    impl IndirectTest {
+        /// A coordinator with room for `slots` indirect frames and `CHILD`
+        /// marked sleepy.
+        fn new(slots: usize) -> IndirectTest {
+            let mac = mock_process::leak(MockMac::new());
+            let alarm: &MockAlarm = mock_process::leak(MockAlarm::new());
+            let frames: Vec<IndirectFrame> = (0..slots)
+                .map(|_| IndirectFrame::new(mock_process::buffer(radio::MAX_BUF_SIZE)))
+                .collect();
+            let indirect = mock_process::leak(IndirectMac::new(
+                mac,
+                alarm,
+                Box::leak(frames.into_boxed_slice()),
+            ));
+            let client = mock_process::leak(Client::default());
+            mac.set_transmit_client(indirect);
+            alarm.set_alarm_client(indirect);
+            indirect.set_transmit_client(client);
+            indirect.set_sleepy(CHILD, true).unwrap();
+            IndirectTest {
+                indirect: indirect,
+                mac: mac,
+                alarm: alarm,
+                client: client,
+            }
+        }
+
+        /// Encode a frame from `src` to `dst` with `payload` into a new
+        /// buffer, returning it and the frame length.
+        fn frame(
+            frame_type: FrameType,
+            src: MacAddress,
+            dst: MacAddress,
+            payload: &[u8],
+        ) -> (&'static mut [u8], usize) {
+            let buf = mock_process::buffer(radio::MAX_BUF_SIZE);
+            let header = Header {
+                frame_type: frame_type,
+                frame_pending: false,
+                ack_requested: true,
+                version: FrameVersion::V2006,
+                seq: Some(0),
+                dst_pan: Some(0xABCD),
+                dst_addr: Some(dst),
+                src_pan: Some(0xABCD),
+                src_addr: Some(src),
+                security: None,
+                header_ies: Default::default(),
+                header_ies_len: 0,
+                payload_ies: Default::default(),
+                payload_ies_len: 0,
+            };
+            let (offset, _) = header
+                .encode(&mut buf[radio::PSDU_OFFSET..], true)
+                .done()
+                .unwrap();
+            let start = radio::PSDU_OFFSET + offset;
+            buf[start..start + payload.len()].copy_from_slice(payload);
+            (buf, offset + payload.len())
+        }
+
+        /// Send a data frame carrying `marker` to `dst` through the
+        /// coordinator, and let a queued frame be reported sent.
+        fn send(&self, dst: MacAddress, marker: u8) -> Result<(), ErrorCode> {
+            let (buf, len) = Self::frame(FrameType::Data, PARENT, dst, &[marker]);
+            let result = self.indirect.transmit(buf, len).map_err(|(e, _)| e);
+            if result.is_ok() && dst == CHILD {
+                assert_eq!(self.alarm.fire(), Some(1));
+            }
+            result
+        }
+
+        /// Receive a data request from `CHILD`.
+        fn data_request(&self) {
+            let (buf, len) = Self::frame(
+                FrameType::MACCommand,
+                CHILD,
+                PARENT,
+                &[DATA_REQUEST_COMMAND],
+            );
+            radio::RxClient::receive(self.indirect, buf, len, true, Ok(()));
+        }
+
+        /// The marker and frame pending bit of each frame transmitted.
+        fn sent(&self) -> Vec<(u8, bool)> {
+            self.mac
+                .sent
+                .borrow_mut()
+                .drain(..)
+                .map(|frame| {
+                    let pending = frame[0] & FCF_FRAME_PENDING != 0;
+                    (*frame.last().unwrap(), pending)
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_queued_frame_sent_on_data_request() {
+        let test = IndirectTest::new(2);
+
+        // Frames for awake devices go straight out.
+        assert_eq!(test.send(AWAKE, 1), Ok(()));
+        assert_eq!(test.sent(), vec![(1, false)]);
+        test.mac.complete(true);
+        assert_eq!(*test.client.done.borrow(), vec![(true, Ok(()))]);
+
+        // Frames for the sleepy child wait for its data request.
+        assert_eq!(test.send(CHILD, 2), Ok(()));
+        assert_eq!(
+            *test.client.done.borrow(),
+            vec![(true, Ok(())), (false, Ok(()))]
+        );
+        assert_eq!(test.sent(), vec![]);
+        assert_eq!(test.indirect.queued_frames(CHILD), 1);
+        assert!(test.mac.ack_frame_pending.get());
+
+        test.data_request();
+        assert_eq!(test.sent(), vec![(2, false)]);
+
+        // A frame the child did not acknowledge is sent again on its next
+        // request.
+        test.mac.complete(false);
+        assert_eq!(test.indirect.queued_frames(CHILD), 1);
+        test.data_request();
+        assert_eq!(test.sent(), vec![(2, false)]);
+        test.mac.complete(true);
+
+        assert_eq!(test.indirect.queued_frames(CHILD), 0);
+        assert!(!test.mac.ack_frame_pending.get());
+        test.data_request();
+        assert_eq!(test.sent(), vec![]);
+        assert_eq!(test.client.done.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_frame_pending_set_while_more_frames_queued() {
+        let test = IndirectTest::new(3);
+        assert_eq!(test.send(CHILD, 1), Ok(()));
+        assert_eq!(test.send(CHILD, 2), Ok(()));
+        assert_eq!(test.send(CHILD, 3), Ok(()));
+        assert_eq!(test.send(CHILD, 4), Err(ErrorCode::NOMEM));
+        assert_eq!(test.indirect.dropped_frames(), 1);
+
+        // Frames go out oldest first, with the frame pending bit set until
+        // the last one.
+        test.data_request();
+        assert_eq!(test.sent(), vec![(1, true)]);
+        test.mac.complete(true);
+        test.data_request();
+        assert_eq!(test.sent(), vec![(2, true)]);
+        test.mac.complete(true);
+        assert!(test.mac.ack_frame_pending.get());
+        test.data_request();
+        assert_eq!(test.sent(), vec![(3, false)]);
+        test.mac.complete(true);
+        assert!(!test.mac.ack_frame_pending.get());
+    }
+
+    #[test]
+    fn test_expired_frames_dropped() {
+        let test = IndirectTest::new(2);
+        test.indirect.set_expiry_ms(100);
+        assert_eq!(test.send(CHILD, 1), Ok(()));
+        test.alarm.advance(50_000);
+        assert_eq!(test.send(CHILD, 2), Ok(()));
+
+        // Each frame expires 100 ms after it was queued. Reporting the first
+        // frame sent took a tick, so the second was queued 50001 ticks later.
+        assert_eq!(test.alarm.fire(), Some(50_000 - 2));
+        assert_eq!(test.indirect.dropped_frames(), 1);
+        assert_eq!(test.indirect.queued_frames(CHILD), 1);
+        assert!(test.mac.ack_frame_pending.get());
+
+        assert_eq!(test.alarm.fire(), Some(50_001));
+        assert_eq!(test.indirect.dropped_frames(), 2);
+        assert_eq!(test.indirect.queued_frames(CHILD), 0);
+        assert!(!test.mac.ack_frame_pending.get());
+        assert_eq!(test.alarm.until_alarm(), None);
+
+        test.data_request();
+        assert_eq!(test.sent(), vec![]);
+    }
+}
@@ -42,6 +42,8 @@ pub trait Mac {
     fn set_address_long(&self, addr: [u8; 8]);
     /// Sets the 16-bit PAN id of the radio
     fn set_pan(&self, id: u16);
+    /// Sets whether ACKs sent by the radio have the frame pending bit set
+    fn set_ack_frame_pending(&self, pending: bool);
 
     /// Must be called after one or more calls to `set_*`. If
     /// `set_*` is called without calling `config_commit`, there is no guarantee
@@ -110,6 +112,10 @@ impl<R: radio::Radio> Mac for AwakeMac<'_, R> {
         self.radio.set_pan(id)
     }
 
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.radio.set_ack_frame_pending(pending)
+    }
+
     fn get_address(&self) -> u16 {
         self.radio.get_address()
     }
@@ -1,7 +1,9 @@
 //! Support for IEEE 802.15.4.
 
+pub mod csma;
 pub mod device;
 pub mod framer;
+pub mod indirect;
 pub mod mac;
 pub mod virtual_mac;
 pub mod xmac;
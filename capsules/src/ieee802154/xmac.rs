@@ -376,6 +376,10 @@ impl<'a, R: radio::Radio, A: Alarm<'a>> Mac for XMac<'a, R, A> {
         self.radio.set_pan(id)
     }
 
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.radio.set_ack_frame_pending(pending)
+    }
+
     fn get_address(&self) -> u16 {
         self.radio.get_address()
     }
@@ -0,0 +1,670 @@
+//! Provides userspace with a key-value store with optional entry expiry.
+//!
+//! This capsule sits on top of a `hil::kv_system` implementation (for
+//! example TicKV) and exposes set/get/delete operations on unhashed keys to
+//! applications.
+//!
+//! Every value is stored with a small header holding the value length and an
+//! expiry timestamp. When an app sets a key with a time-to-live (TTL), the
+//! expiry is taken from the alarm passed to `set_clock()`. A get of a key
+//! whose TTL has passed reports the key as not found, and the expired entry
+//! is then deleted from the store. Entries are only deleted lazily in this
+//! way; there is no background expiry.
+//!
+//! The ticks of the alarm are extended to 64 bits with an `OverflowTracker`,
+//! which the capsule updates from an alarm every half counter period so that
+//! no wrap is missed while the store is idle.
+//!
+//! The expiry is stored in milliseconds since the kernel booted, and that
+//! clock starts again from zero at every boot. An entry that survives a
+//! reset therefore expires once the new uptime reaches its stored expiry:
+//! its lifetime is extended by the uptime before the reset. Apps that need
+//! entries to expire across resets must delete them after a reset.
+//!
+//! Keys are shared between all apps.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let kv_key = static_init!(capsules::tickv::TicKVKeyType, [0; 8]);
+//! let kv_data = static_init!([u8; 64], [0; 64]);
+//! let kv_store = static_init!(
+//!     capsules::kv_store::KVStoreDriver<
+//!         'static,
+//!         capsules::tickv::TicKVStore<'static, FlashUser<'static, FlashCtrl<'static>>>,
+//!         VirtualMuxAlarm<'static, Rtc<'static>>,
+//!     >,
+//!     capsules::kv_store::KVStoreDriver::new(
+//!         tickv,
+//!         kv_key,
+//!         kv_data,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! tickv.set_client(kv_store);
+//! kv_alarm.set_alarm_client(kv_store);
+//! kv_store.set_clock(kv_alarm);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: The key.
+//! - Read-only `1`: The value to set.
+//! - Read-write `0`: Buffer the value of a get is copied into.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Operation complete. Called with the status and, for a get, the
+//!   length of the stored value. A missing or expired key is reported as
+//!   `NOSUPPORT`.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Set the key to the first `data1` bytes of the value buffer,
+//!   replacing any existing value. `data2` is the TTL in milliseconds, or `0`
+//!   for an entry that never expires. Returns `NOSUPPORT` for a non-zero TTL
+//!   if there is no clock, and `SIZE` if the value is too large.
+//! - `2`: Get the value of the key.
+//! - `3`: Delete the key.
+//! - `4`: Returns the largest value that can be stored, in bytes.
+//!
+//! The store handles one operation at a time. Operations of other apps are
+//! queued and run in the order they were issued, and each app can have one
+//! operation running or queued; further operations return `BUSY`. A queued
+//! operation uses the buffers allowed when it runs, so they must stay
+//! allowed until its callback.
+
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::kv_system::{self, KVSystem};
+use kernel::hil::time::{self, Alarm, Frequency, OverflowTracker, Ticks};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::KVStore as usize;
+
+/// Bytes of the stored value used for the header: the value length (`u16`)
+/// followed by the expiry in milliseconds since boot (`u64`, `0` for none).
+pub const HEADER_LEN: usize = 10;
+
+/// Write the header of a value of `len` bytes expiring at `expiry`.
+fn encode_header(buf: &mut [u8], len: usize, expiry: u64) {
+    buf[0..2].copy_from_slice(&(len as u16).to_le_bytes());
+    buf[2..HEADER_LEN].copy_from_slice(&expiry.to_le_bytes());
+}
+
+/// The value length and expiry in the header of a stored value.
+fn decode_header(data: &[u8]) -> (usize, u64) {
+    let mut len_bytes = [0; 2];
+    let mut expiry_bytes = [0; 8];
+    len_bytes.copy_from_slice(&data[0..2]);
+    expiry_bytes.copy_from_slice(&data[2..HEADER_LEN]);
+    (
+        u16::from_le_bytes(len_bytes) as usize,
+        u64::from_le_bytes(expiry_bytes),
+    )
+}
+
+/// Whether a value with `expiry` has expired at `now_ms`, the time if there
+/// is a clock. Entries stored with a TTL are treated as expired if the clock
+/// has since been removed.
+fn is_expired(expiry: u64, now_ms: Option<u64>) -> bool {
+    expiry != 0 && now_ms.map_or(true, |now| now >= expiry)
+}
+
+/// Convert 64-bit ticks of a clock running at `frequency` to milliseconds.
+fn ticks_to_ms(ticks: u64, frequency: u32) -> u64 {
+    (ticks as u128 * 1000 / frequency as u128) as u64
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    None,
+    /// Removing the old value before a set.
+    SetInvalidate,
+    Set,
+    Get,
+    /// Removing an expired value found by a get.
+    Expire,
+    Delete,
+}
+
+/// Hash an unhashed key into `key` using FNV-1a.
+fn hash_key(unhashed_key: &[u8], key: &mut [u8]) {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in unhashed_key.iter() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    for (i, k) in key.iter_mut().enumerate() {
+        *k = hash.to_le_bytes()[i % 8];
+    }
+}
+
+/// An operation waiting for the store.
+#[derive(Clone, Copy)]
+enum Pending {
+    /// Value length and TTL.
+    Set(usize, usize),
+    Get,
+    Delete,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    /// The queued operation, and its position in the queue.
+    pending: Option<(Pending, u32)>,
+    key: ReadOnlyAppSlice,
+    value: ReadOnlyAppSlice,
+    out: ReadWriteAppSlice,
+}
+
+pub struct KVStoreDriver<'a, S: KVSystem<'a>, A: Alarm<'a>> {
+    kv: &'a S,
+    clock: OptionalCell<&'a A>,
+    // Software extension of the clock to 64 bits.
+    ticks64: OverflowTracker,
+    apps: Grant<App>,
+    current_app: OptionalCell<ProcessId>,
+    operation: Cell<Operation>,
+    /// Position in the queue of the next operation to queue.
+    next_ticket: Cell<u32>,
+    key_buffer: TakeCell<'static, S::K>,
+    data_buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, S: KVSystem<'a>, A: Alarm<'a>> KVStoreDriver<'a, S, A> {
+    pub fn new(
+        kv: &'a S,
+        key_buffer: &'static mut S::K,
+        data_buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> KVStoreDriver<'a, S, A> {
+        KVStoreDriver {
+            kv: kv,
+            clock: OptionalCell::empty(),
+            ticks64: OverflowTracker::new(),
+            apps: grant,
+            current_app: OptionalCell::empty(),
+            operation: Cell::new(Operation::None),
+            next_ticket: Cell::new(0),
+            key_buffer: TakeCell::new(key_buffer),
+            data_buffer: TakeCell::new(data_buffer),
+        }
+    }
+
+    /// Set the clock used to timestamp entries with a TTL, whose alarm
+    /// client must be this driver. Without a clock entries can only be
+    /// stored without expiry.
+    pub fn set_clock(&self, clock: &'a A) {
+        self.clock.set(clock);
+        self.track_clock(clock);
+    }
+
+    /// Extend the ticks of `clock`, and set the alarm to do it again before
+    /// the counter can wrap.
+    fn track_clock(&self, clock: &A) -> u64 {
+        let now = clock.now();
+        let ticks = self.ticks64.extend(now);
+        let half_period = A::Ticks::from(A::Ticks::max_value().into_u32() / 2);
+        clock.set_alarm(now, half_period);
+        ticks
+    }
+
+    /// The current time in milliseconds, if there is a clock.
+    fn now_ms(&self) -> Option<u64> {
+        self.clock.map(|clock| {
+            let ticks = self.ticks64.extend(clock.now());
+            ticks_to_ms(ticks, A::Frequency::frequency())
+        })
+    }
+
+    fn max_value_len(&self) -> usize {
+        self.data_buffer
+            .map_or(0, |buf| buf.len().saturating_sub(HEADER_LEN))
+    }
+
+    /// Hash the key shared by `appid` into the key buffer.
+    fn load_key(&self, appid: ProcessId) -> Result<&'static mut S::K, ErrorCode> {
+        let key = self.key_buffer.take().ok_or(ErrorCode::BUSY)?;
+        let res = self
+            .apps
+            .enter(appid, |app| {
+                app.key.map_or(Err(ErrorCode::RESERVE), |unhashed_key| {
+                    if unhashed_key.is_empty() {
+                        Err(ErrorCode::INVAL)
+                    } else {
+                        hash_key(unhashed_key, key.as_mut());
+                        Ok(())
+                    }
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        match res {
+            Ok(()) => Ok(key),
+            Err(e) => {
+                self.key_buffer.replace(key);
+                Err(e)
+            }
+        }
+    }
+
+    fn set(&self, len: usize, ttl_ms: usize, appid: ProcessId) -> Result<(), ErrorCode> {
+        let expiry = if ttl_ms == 0 {
+            0
+        } else {
+            self.now_ms().ok_or(ErrorCode::NOSUPPORT)? + ttl_ms as u64
+        };
+        if len > self.max_value_len() || len > u16::MAX as usize {
+            return Err(ErrorCode::SIZE);
+        }
+
+        self.data_buffer.map_or(Err(ErrorCode::BUSY), |buf| {
+            self.apps
+                .enter(appid, |app| {
+                    app.value.map_or(Err(ErrorCode::RESERVE), |value| {
+                        if value.len() < len {
+                            return Err(ErrorCode::SIZE);
+                        }
+                        encode_header(buf, len, expiry);
+                        buf[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&value[0..len]);
+                        for b in buf[HEADER_LEN + len..].iter_mut() {
+                            *b = 0;
+                        }
+                        Ok(())
+                    })
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        })?;
+
+        // Remove any existing value first, as the store won't replace it.
+        let key = self.load_key(appid)?;
+        self.kv.invalidate_key(key).map_err(|(key, res)| {
+            self.key_buffer.replace(key);
+            res.err().unwrap_or(ErrorCode::FAIL)
+        })?;
+        self.operation.set(Operation::SetInvalidate);
+        Ok(())
+    }
+
+    fn get(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let key = self.load_key(appid)?;
+        let buf = match self.data_buffer.take() {
+            Some(buf) => buf,
+            None => {
+                self.key_buffer.replace(key);
+                return Err(ErrorCode::BUSY);
+            }
+        };
+        self.kv.get_value(key, buf).map_err(|(key, buf, res)| {
+            self.key_buffer.replace(key);
+            self.data_buffer.replace(buf);
+            res.err().unwrap_or(ErrorCode::FAIL)
+        })?;
+        self.operation.set(Operation::Get);
+        Ok(())
+    }
+
+    fn delete(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let key = self.load_key(appid)?;
+        self.kv.invalidate_key(key).map_err(|(key, res)| {
+            self.key_buffer.replace(key);
+            res.err().unwrap_or(ErrorCode::FAIL)
+        })?;
+        self.operation.set(Operation::Delete);
+        Ok(())
+    }
+
+    fn start(&self, operation: Pending, appid: ProcessId) -> Result<(), ErrorCode> {
+        let res = match operation {
+            Pending::Set(len, ttl_ms) => self.set(len, ttl_ms, appid),
+            Pending::Get => self.get(appid),
+            Pending::Delete => self.delete(appid),
+        };
+        if res.is_ok() {
+            self.current_app.set(appid);
+        }
+        res
+    }
+
+    /// Run the operation of `appid` now, or queue it if the store is busy.
+    fn issue(&self, operation: Pending, appid: ProcessId) -> Result<(), ErrorCode> {
+        if self.current_app.contains(&appid) {
+            return Err(ErrorCode::BUSY);
+        }
+        if self.operation.get() == Operation::None {
+            return self.start(operation, appid);
+        }
+        let ticket = self.next_ticket.get();
+        self.apps
+            .enter(appid, |app| {
+                if app.pending.is_some() {
+                    Err(ErrorCode::BUSY)
+                } else {
+                    app.pending = Some((operation, ticket));
+                    Ok(())
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()))?;
+        self.next_ticket.set(ticket.wrapping_add(1));
+        Ok(())
+    }
+
+    /// Start the oldest queued operation, if the store is idle.
+    fn run_next(&self) {
+        while self.operation.get() == Operation::None {
+            // Tickets are compared relative to the next one, so that the
+            // order survives the counter wrapping around.
+            let next_ticket = self.next_ticket.get();
+            let mut oldest: Option<(ProcessId, Pending, u32)> = None;
+            for cntr in self.apps.iter() {
+                let appid = cntr.processid();
+                cntr.enter(|app| {
+                    if let Some((operation, ticket)) = app.pending {
+                        let age = next_ticket.wrapping_sub(ticket);
+                        if oldest.map_or(true, |(_, _, oldest_ticket)| {
+                            age > next_ticket.wrapping_sub(oldest_ticket)
+                        }) {
+                            oldest = Some((appid, operation, ticket));
+                        }
+                    }
+                });
+            }
+
+            match oldest {
+                None => return,
+                Some((appid, operation, _)) => {
+                    let _ = self.apps.enter(appid, |app| app.pending = None);
+                    if let Err(e) = self.start(operation, appid) {
+                        let _ = self.apps.enter(appid, |app| {
+                            app.callback.schedule(kernel::into_statuscode(Err(e)), 0, 0);
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Finish the current operation and notify the app.
+    fn complete(&self, result: Result<(), ErrorCode>, len: usize) {
+        self.operation.set(Operation::None);
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback
+                    .schedule(kernel::into_statuscode(result), len, 0);
+            });
+        });
+    }
+
+    /// Copy a value read from the store to the current app, unless it has
+    /// expired. Returns `true` if the value has expired.
+    fn deliver_value(&self, data: &[u8]) -> bool {
+        let (len, expiry) = decode_header(data);
+        if is_expired(expiry, self.now_ms()) {
+            return true;
+        }
+
+        let len = core::cmp::min(len, data.len() - HEADER_LEN);
+        self.current_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app| {
+                app.out.mut_map_or((), |out| {
+                    let copy_len = core::cmp::min(len, out.len());
+                    out[0..copy_len].copy_from_slice(&data[HEADER_LEN..HEADER_LEN + copy_len]);
+                });
+            });
+        });
+        self.complete(Ok(()), len);
+        false
+    }
+}
+
+impl<'a, S: KVSystem<'a>, A: Alarm<'a>> kv_system::Client<S::K> for KVStoreDriver<'a, S, A> {
+    fn generate_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        _unhashed_key: &'static [u8],
+        _key_buf: &'static S::K,
+    ) {
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut S::K,
+        value: &'static mut [u8],
+    ) {
+        self.key_buffer.replace(key);
+        self.data_buffer.replace(value);
+        self.complete(result, 0);
+        self.run_next();
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut S::K,
+        ret_buf: &'static mut [u8],
+    ) {
+        let expired = match result {
+            Ok(()) if ret_buf.len() >= HEADER_LEN => self.deliver_value(ret_buf),
+            Ok(()) => {
+                self.complete(Err(ErrorCode::FAIL), 0);
+                false
+            }
+            Err(e) => {
+                self.complete(Err(e), 0);
+                false
+            }
+        };
+        self.data_buffer.replace(ret_buf);
+
+        if expired {
+            match self.kv.invalidate_key(key) {
+                Ok(()) => self.operation.set(Operation::Expire),
+                Err((key, _)) => {
+                    self.key_buffer.replace(key);
+                    self.complete(Err(ErrorCode::NOSUPPORT), 0);
+                }
+            }
+        } else {
+            self.key_buffer.replace(key);
+        }
+        self.run_next();
+    }
+
+    fn invalidate_key_complete(&self, result: Result<(), ErrorCode>, key: &'static mut S::K) {
+        match self.operation.get() {
+            Operation::SetInvalidate => {
+                // The key not existing yet is expected, so the result is
+                // ignored.
+                let value = self.data_buffer.take().unwrap();
+                match self.kv.append_key(key, value) {
+                    Ok(()) => self.operation.set(Operation::Set),
+                    Err((key, value, res)) => {
+                        self.key_buffer.replace(key);
+                        self.data_buffer.replace(value);
+                        self.complete(Err(res.err().unwrap_or(ErrorCode::FAIL)), 0);
+                    }
+                }
+            }
+            Operation::Expire => {
+                self.key_buffer.replace(key);
+                self.complete(Err(ErrorCode::NOSUPPORT), 0);
+            }
+            _ => {
+                self.key_buffer.replace(key);
+                self.complete(result, 0);
+            }
+        }
+        self.run_next();
+    }
+
+    fn garbage_collect_complete(&self, _result: Result<(), ErrorCode>) {}
+}
+
+impl<'a, S: KVSystem<'a>, A: Alarm<'a>> time::AlarmClient for KVStoreDriver<'a, S, A> {
+    fn alarm(&self) {
+        self.clock.map(|clock| self.track_clock(clock));
+    }
+}
+
+impl<'a, S: KVSystem<'a>, A: Alarm<'a>> Driver for KVStoreDriver<'a, S, A> {
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| mem::swap(&mut app.key, &mut slice))
+                .map_err(ErrorCode::from),
+            1 => self
+                .apps
+                .enter(appid, |app| mem::swap(&mut app.value, &mut slice))
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| mem::swap(&mut app.out, &mut slice))
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // set
+            1 => self.issue(Pending::Set(data1, data2), appid).into(),
+
+            // get
+            2 => self.issue(Pending::Get, appid).into(),
+
+            // delete
+            3 => self.issue(Pending::Delete, appid).into(),
+
+            // maximum value length
+            4 => CommandReturn::success_u32(self.max_value_len() as u32),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_header, encode_header, is_expired, ticks_to_ms, HEADER_LEN};
+    use kernel::hil::time::{OverflowTracker, Ticks, Ticks24};
+
+    #[test]
+    fn test_header() {
+        let mut buf = [0; HEADER_LEN];
+        encode_header(&mut buf, 300, 0x1234_5678_9abc);
+        assert_eq!(decode_header(&buf), (300, 0x1234_5678_9abc));
+    }
+
+    #[test]
+    fn test_expiry() {
+        // Set at 1000 ms with a TTL of 500 ms.
+        let expiry = 1500;
+        assert!(!is_expired(expiry, Some(1000)));
+        assert!(!is_expired(expiry, Some(1499)));
+        assert!(is_expired(expiry, Some(1500)));
+        assert!(is_expired(expiry, Some(10_000)));
+        // Without a clock, entries with a TTL are expired.
+        assert!(is_expired(expiry, None));
+    }
+
+    #[test]
+    fn test_no_expiry() {
+        assert!(!is_expired(0, Some(u64::MAX)));
+        assert!(!is_expired(0, None));
+    }
+
+    #[test]
+    fn test_clock_wrap() {
+        // The alarm extends the ticks every half period, so wraps of the
+        // counter are seen even if the store is idle.
+        let ticks64 = OverflowTracker::new();
+        let half = Ticks24::max_value().into_u32() / 2;
+        let mut now = 0;
+        for _ in 0..8 {
+            now = (now + half) & 0xFF_FFFF;
+            ticks64.extend(Ticks24::from(now));
+        }
+        assert_eq!(ticks64.extend(Ticks24::from(now)), 8 * half as u64);
+    }
+
+    #[test]
+    fn test_ticks_to_ms() {
+        assert_eq!(ticks_to_ms(32768, 32768), 1000);
+        assert_eq!(ticks_to_ms(16_000_000, 16_000_000), 1000);
+        // Past a wrap of a 32-bit counter at 16 MHz.
+        assert_eq!(ticks_to_ms(1 << 32, 16_000_000), 268_435);
+        assert_eq!(ticks_to_ms(u64::MAX, 1000), u64::MAX);
+    }
+}
@@ -7,7 +7,8 @@
 //! This capsule takes an array of pins and the polarity of the LED (active high
 //! or active low). This allows the board to configure how the underlying GPIO
 //! must be controlled to turn on and off LEDs, such that the syscall driver
-//! interface can be agnostic to the LED polarity.
+//! interface can be agnostic to the LED polarity. At most `MAX_LEDS` LEDs are
+//! supported.
 //!
 //! Usage
 //! -----
@@ -49,7 +50,52 @@
 //! - `3`: Toggle the on/off state of the LED.
 //!   - `data`: The index of the LED. Starts at 0.
 //!   - Return: `Ok(())` if the LED index was valid, `INVAL` otherwise.
+//! - `4`: Persist the LED states set by this app across app restarts.
+//!   - `data`: `1` to enable persistence, `0` to disable it and forget any
+//!     saved state.
+//!   - Return: When enabling, a bitmask of the LEDs whose state was restored
+//!     from before the app restarted (0 on first use). `NOMEM` if the driver
+//!     cannot track any more apps.
+//! - `5`: Push a prioritized "on" state for the LED.
+//!   - `data`: The index of the LED. Starts at 0.
+//!   - `data2`: The priority (0-255).
+//!   - Return: `Ok(())`, `INVAL` if the LED index or priority is not valid,
+//!     or `NOMEM` if no more states can be tracked.
+//! - `6`: Push a prioritized "off" state for the LED. Arguments and return
+//!   values are the same as for `5`.
+//! - `7`: Clear this app's state for the LED at a priority.
+//!   - `data`: The index of the LED. Starts at 0.
+//!   - `data2`: The priority of the state to clear.
+//!   - Return: `Ok(())` if the state was cleared, `INVAL` if the app had no
+//!     such state.
+//!
+//! Status Arbitration
+//! ------------------
+//!
+//! Several apps may want to show their status on the same LED. Instead of
+//! turning it on and off directly, an app can push a state for the LED with
+//! a priority. The LED shows the state with the highest priority among all
+//! apps (the most recently pushed one if several share that priority). When
+//! that state is cleared, the LED falls back to the next highest one. An app
+//! pushing a second state for the same LED and priority replaces its first.
+//!
+//! The on, off and toggle commands set the LED's base state, which is shown
+//! only while no prioritized state is active. States of apps that have exited
+//! are dropped the next time the LED is updated.
+//!
+//! Persistence
+//! -----------
+//!
+//! A process's grant is cleared when it restarts, so the LED states an app
+//! set cannot be stored there. Instead, the driver keeps a small table of
+//! saved states keyed by the app's index in the kernel's processes array and
+//! the start of its flash region, which both stay the same across restarts.
+//! When a restarted app enables persistence again, the LEDs it had set are
+//! restored and the app is told which ones, so it can skip re-initializing
+//! them. Once another app is loaded in its place, the saved states of the
+//! app it replaced are released.
 
+use core::cell::Cell;
 use kernel::common::cells::TakeCell;
 use kernel::hil::led;
 use kernel::{CommandReturn, Driver, ErrorCode, ProcessId};
@@ -58,14 +104,65 @@ use kernel::{CommandReturn, Driver, ErrorCode, ProcessId};
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Led as usize;
 
+/// Maximum number of LEDs the driver can control, as LED states are kept in
+/// `u32` bitmasks.
+pub const MAX_LEDS: usize = 32;
+
+/// Number of apps whose LED states can be persisted across restarts.
+pub const MAX_PERSISTENT_APPS: usize = 4;
+
+/// Number of prioritized states that can be active across all LEDs.
+pub const MAX_STATUS_STATES: usize = 16;
+
+/// A prioritized state pushed by an app.
+#[derive(Copy, Clone)]
+struct StatusState {
+    owner: ProcessId,
+    led: usize,
+    priority: u8,
+    on: bool,
+    /// Order the state was pushed in, to prefer the most recent of equal
+    /// priorities.
+    seq: u32,
+}
+
+/// LED states saved on behalf of one app.
+#[derive(Copy, Clone)]
+struct PersistedState {
+    /// Index of the app in the processes array, and start of its flash
+    /// region, used to recognize the app after it restarts.
+    index: usize,
+    app_flash_start: usize,
+    /// Bit `i` is set if the app has set the state of LED `i`.
+    set: u32,
+    /// Bit `i` is set if the app last left LED `i` on.
+    on: u32,
+}
+
 /// Holds the array of LEDs and implements a `Driver` interface to
 /// control them.
 pub struct LedDriver<'a, L: led::Led> {
     leds: TakeCell<'a, [&'a L]>,
+    persisted: [Cell<Option<PersistedState>>; MAX_PERSISTENT_APPS],
+    states: [Cell<Option<StatusState>>; MAX_STATUS_STATES],
+    next_seq: Cell<u32>,
+    /// Bit `i` is set if the base state of LED `i` is on.
+    base_on: Cell<u32>,
 }
 
 impl<'a, L: led::Led> LedDriver<'a, L> {
+    /// Create a driver for `leds`, initializing them and turning them off.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the board has more than `MAX_LEDS` LEDs.
     pub fn new(leds: &'a mut [&'a L]) -> Self {
+        assert!(
+            leds.len() <= MAX_LEDS,
+            "LedDriver supports at most {} LEDs",
+            MAX_LEDS
+        );
+
         // Initialize all LEDs and turn them off
         for led in leds.iter_mut() {
             led.init();
@@ -74,6 +171,184 @@ impl<'a, L: led::Led> LedDriver<'a, L> {
 
         Self {
             leds: TakeCell::new(leds),
+            persisted: Default::default(),
+            states: Default::default(),
+            next_seq: Cell::new(0),
+            base_on: Cell::new(0),
+        }
+    }
+
+    /// Show the highest priority state of LED `index`, or its base state if
+    /// it has none. Drops the states of apps that no longer exist.
+    fn update(&self, leds: &[&'a L], index: usize) {
+        let mut shown: Option<StatusState> = None;
+        for slot in self.states.iter() {
+            if let Some(state) = slot.get() {
+                if state.owner.index().is_none() {
+                    slot.set(None);
+                } else if state.led == index {
+                    let higher =
+                        shown.map_or(true, |s| (state.priority, state.seq) > (s.priority, s.seq));
+                    if higher {
+                        shown = Some(state);
+                    }
+                }
+            }
+        }
+        let on = shown.map_or(self.base_on.get() & (1 << index) != 0, |s| s.on);
+        if on {
+            leds[index].on();
+        } else {
+            leds[index].off();
+        }
+    }
+
+    /// Set the base state of LED `index` and show it unless a prioritized
+    /// state is active.
+    fn set_base(&self, leds: &[&'a L], index: usize, on: bool) {
+        if on {
+            self.base_on.set(self.base_on.get() | (1 << index));
+        } else {
+            self.base_on.set(self.base_on.get() & !(1 << index));
+        }
+        self.update(leds, index);
+    }
+
+    fn find_state(
+        &self,
+        appid: ProcessId,
+        index: usize,
+        priority: u8,
+    ) -> Option<&Cell<Option<StatusState>>> {
+        self.states.iter().find(|slot| {
+            slot.get().map_or(false, |state| {
+                state.owner == appid && state.led == index && state.priority == priority
+            })
+        })
+    }
+
+    /// Push a prioritized state for LED `index` on behalf of the app.
+    fn push_state(
+        &self,
+        leds: &[&'a L],
+        appid: ProcessId,
+        index: usize,
+        priority: usize,
+        on: bool,
+    ) -> CommandReturn {
+        if index >= leds.len() || priority > u8::MAX as usize {
+            return CommandReturn::failure(ErrorCode::INVAL);
+        }
+        let priority = priority as u8;
+        // Reclaim the states of exited apps before looking for a free slot.
+        self.update(leds, index);
+        let slot = self
+            .find_state(appid, index, priority)
+            .or_else(|| self.states.iter().find(|slot| slot.get().is_none()));
+        match slot {
+            Some(slot) => {
+                let seq = self.next_seq.get();
+                self.next_seq.set(seq.wrapping_add(1));
+                slot.set(Some(StatusState {
+                    owner: appid,
+                    led: index,
+                    priority: priority,
+                    on: on,
+                    seq: seq,
+                }));
+                self.update(leds, index);
+                CommandReturn::success()
+            }
+            None => CommandReturn::failure(ErrorCode::NOMEM),
+        }
+    }
+
+    /// Clear the app's state for LED `index` at `priority`.
+    fn clear_state(
+        &self,
+        leds: &[&'a L],
+        appid: ProcessId,
+        index: usize,
+        priority: usize,
+    ) -> CommandReturn {
+        if index >= leds.len() || priority > u8::MAX as usize {
+            return CommandReturn::failure(ErrorCode::INVAL);
+        }
+        match self.find_state(appid, index, priority as u8) {
+            Some(slot) => {
+                slot.set(None);
+                self.update(leds, index);
+                CommandReturn::success()
+            }
+            None => CommandReturn::failure(ErrorCode::INVAL),
+        }
+    }
+
+    /// Find the persistence slot belonging to the app, if it has one.
+    ///
+    /// A slot saved by another app loaded at the same index has been
+    /// replaced by this app, so it is released.
+    fn persisted_slot(&self, appid: ProcessId) -> Option<&Cell<Option<PersistedState>>> {
+        let index = appid.index()?;
+        let app_flash_start = appid.get_editable_flash_range().0;
+        let mut found = None;
+        for slot in self.persisted.iter() {
+            if let Some(state) = slot.get() {
+                if state.index != index {
+                    continue;
+                }
+                if state.app_flash_start == app_flash_start {
+                    found = Some(slot);
+                } else {
+                    slot.set(None);
+                }
+            }
+        }
+        found
+    }
+
+    /// Record the new state of LED `index` if the app persists its LEDs.
+    fn record(&self, appid: ProcessId, index: usize, on: bool) {
+        self.persisted_slot(appid).map(|slot| {
+            slot.get().map(|mut state| {
+                state.set |= 1 << index;
+                if on {
+                    state.on |= 1 << index;
+                } else {
+                    state.on &= !(1 << index);
+                }
+                slot.set(Some(state));
+            });
+        });
+    }
+
+    /// Enable persistence for the app, restoring any LED states saved before
+    /// it restarted. Returns the mask of restored LEDs.
+    fn enable_persistence(&self, leds: &[&'a L], appid: ProcessId) -> CommandReturn {
+        if let Some(state) = self.persisted_slot(appid).and_then(|slot| slot.get()) {
+            for i in 0..leds.len() {
+                if state.set & (1 << i) != 0 {
+                    self.set_base(leds, i, state.on & (1 << i) != 0);
+                }
+            }
+            return CommandReturn::success_u32(state.set);
+        }
+
+        let index = match appid.index() {
+            Some(index) => index,
+            None => return CommandReturn::failure(ErrorCode::FAIL),
+        };
+        match self.persisted.iter().find(|slot| slot.get().is_none()) {
+            Some(slot) => {
+                slot.set(Some(PersistedState {
+                    index: index,
+                    app_flash_start: appid.get_editable_flash_range().0,
+                    set: 0,
+                    on: 0,
+                }));
+                CommandReturn::success_u32(0)
+            }
+            None => CommandReturn::failure(ErrorCode::NOMEM),
         }
     }
 }
@@ -91,7 +366,22 @@ impl<L: led::Led> Driver for LedDriver<'_, L> {
     ///        if the LED index is not valid.
     /// - `3`: Toggle the LED at index specified by `data` on or off. Returns
     ///        `INVAL` if the LED index is not valid.
-    fn command(&self, command_num: usize, data: usize, _: usize, _: ProcessId) -> CommandReturn {
+    /// - `4`: Enable (`data` is 1) or disable (`data` is 0) persisting this
+    ///        app's LED states across restarts. Enabling returns the mask of
+    ///        LEDs restored from before a restart.
+    /// - `5`: Push an "on" state with priority `data2` for the LED at index
+    ///        `data`.
+    /// - `6`: Push an "off" state with priority `data2` for the LED at index
+    ///        `data`.
+    /// - `7`: Clear this app's state with priority `data2` for the LED at
+    ///        index `data`.
+    fn command(
+        &self,
+        command_num: usize,
+        data: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
         self.leds
             .map(|leds| {
                 match command_num {
@@ -102,7 +392,8 @@ impl<L: led::Led> Driver for LedDriver<'_, L> {
                         if data >= leds.len() {
                             CommandReturn::failure(ErrorCode::INVAL) /* led out of range */
                         } else {
-                            leds[data].on();
+                            self.set_base(leds, data, true);
+                            self.record(appid, data, true);
                             CommandReturn::success()
                         }
                     }
@@ -112,7 +403,8 @@ impl<L: led::Led> Driver for LedDriver<'_, L> {
                         if data >= leds.len() {
                             CommandReturn::failure(ErrorCode::INVAL) /* led out of range */
                         } else {
-                            leds[data].off();
+                            self.set_base(leds, data, false);
+                            self.record(appid, data, false);
                             CommandReturn::success()
                         }
                     }
@@ -122,11 +414,32 @@ impl<L: led::Led> Driver for LedDriver<'_, L> {
                         if data >= leds.len() {
                             CommandReturn::failure(ErrorCode::INVAL) /* led out of range */
                         } else {
-                            leds[data].toggle();
+                            let on = self.base_on.get() & (1 << data) == 0;
+                            self.set_base(leds, data, on);
+                            self.record(appid, data, on);
                             CommandReturn::success()
                         }
                     }
 
+                    // persist
+                    4 => match data {
+                        0 => {
+                            self.persisted_slot(appid).map(|slot| slot.set(None));
+                            CommandReturn::success()
+                        }
+                        1 => self.enable_persistence(leds, appid),
+                        _ => CommandReturn::failure(ErrorCode::INVAL),
+                    },
+
+                    // push prioritized on state
+                    5 => self.push_state(leds, appid, data, data2, true),
+
+                    // push prioritized off state
+                    6 => self.push_state(leds, appid, data, data2, false),
+
+                    // clear prioritized state
+                    7 => self.clear_state(leds, appid, data, data2),
+
                     // default
                     _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
                 }
@@ -134,3 +447,203 @@ impl<L: led::Led> Driver for LedDriver<'_, L> {
             .expect("LEDs slice taken")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{LedDriver, MAX_LEDS, MAX_PERSISTENT_APPS};
+    use crate::test::mock_process;
+    use core::cell::Cell;
+    use kernel::hil::led::Led;
+    use kernel::{Driver, ErrorCode};
+
+    #[derive(Default)]
+    struct MockLed {
+        on: Cell<bool>,
+    }
+
+    impl Led for MockLed {
+        fn init(&self) {}
+
+        fn on(&self) {
+            self.on.set(true);
+        }
+
+        fn off(&self) {
+            self.on.set(false);
+        }
+
+        fn toggle(&self) {
+            self.on.set(!self.on.get());
+        }
+
+        fn read(&self) -> bool {
+            self.on.get()
+        }
+    }
+
+    #[test]
+    fn all_leds_up_to_max_are_controlled() {
+        let mocks: [MockLed; MAX_LEDS] = Default::default();
+        let mut leds: [&MockLed; MAX_LEDS] = [&mocks[0]; MAX_LEDS];
+        for (i, led) in leds.iter_mut().enumerate() {
+            *led = &mocks[i];
+        }
+        let all: [&MockLed; MAX_LEDS] = leds;
+        let driver = LedDriver::new(&mut leds);
+
+        driver.set_base(&all, MAX_LEDS - 1, true);
+        assert!(mocks[MAX_LEDS - 1].read());
+        assert_eq!(driver.base_on.get(), 1 << (MAX_LEDS - 1));
+        assert!(mocks[..MAX_LEDS - 1].iter().all(|led| !led.read()));
+
+        driver.set_base(&all, 0, true);
+        driver.set_base(&all, MAX_LEDS - 1, false);
+        assert!(!mocks[MAX_LEDS - 1].read());
+        assert!(mocks[0].read());
+        assert_eq!(driver.base_on.get(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_many_leds_are_rejected() {
+        let mock = MockLed::default();
+        let mut leds = [&mock; MAX_LEDS + 1];
+        let _ = LedDriver::new(&mut leds);
+    }
+
+    #[test]
+    fn test_restart_restores_persisted_leds() {
+        let (_, processes) = mock_process::kernel(&["blink", "other"]);
+        let (blink, other) = (processes[0], processes[1]);
+        let mocks: [MockLed; 3] = Default::default();
+        let mut leds = [&mocks[0], &mocks[1], &mocks[2]];
+        let driver = LedDriver::new(&mut leds);
+        let on = |mocks: &[MockLed; 3]| [mocks[0].read(), mocks[1].read(), mocks[2].read()];
+
+        // Nothing is restored the first time persistence is enabled.
+        let id = blink.processid();
+        assert_eq!(driver.command(4, 1, 0, id).get_success_u32(), Some(0));
+        assert!(driver.command(1, 0, 0, id).is_success());
+        assert!(driver.command(2, 1, 0, id).is_success());
+        assert!(driver.command(3, 2, 0, id).is_success());
+        assert_eq!(on(&mocks), [true, false, true]);
+
+        // While the app restarts, another one changes the LEDs.
+        blink.restart();
+        let other_id = other.processid();
+        assert!(driver.command(2, 0, 0, other_id).is_success());
+        assert!(driver.command(1, 1, 0, other_id).is_success());
+        assert!(driver.command(2, 2, 0, other_id).is_success());
+        assert_eq!(on(&mocks), [false, true, false]);
+
+        // The restarted app gets its LEDs back, and is told which.
+        let id = blink.processid();
+        assert_eq!(driver.command(4, 1, 0, id).get_success_u32(), Some(0b111));
+        assert_eq!(on(&mocks), [true, false, true]);
+
+        // Once it stops persisting, a restart restores nothing.
+        assert!(driver.command(4, 0, 0, id).is_success());
+        blink.restart();
+        let id = blink.processid();
+        assert_eq!(driver.command(4, 1, 0, id).get_success_u32(), Some(0));
+    }
+
+    #[test]
+    fn test_replaced_app_releases_persisted_leds() {
+        let (_, processes) = mock_process::kernel(&["a", "b", "c", "d", "e"]);
+        let mock = MockLed::default();
+        let mut leds = [&mock];
+        let driver = LedDriver::new(&mut leds);
+        let persist = |index: usize| driver.command(4, 1, 0, processes[index].processid());
+
+        for index in 0..MAX_PERSISTENT_APPS {
+            assert_eq!(persist(index).get_success_u32(), Some(0));
+            assert!(driver
+                .command(1, 0, 0, processes[index].processid())
+                .is_success());
+        }
+        assert_eq!(persist(4).get_failure(), Some(ErrorCode::NOMEM));
+
+        // A restarted app keeps its slot.
+        processes[0].restart();
+        assert_eq!(persist(0).get_success_u32(), Some(1));
+        assert_eq!(persist(4).get_failure(), Some(ErrorCode::NOMEM));
+
+        // Another app loaded in its place gets nothing of it.
+        processes[0].replace("f");
+        assert_eq!(persist(0).get_success_u32(), Some(0));
+
+        // The slot of a replaced app is released once the app in its place
+        // uses the driver, even without persisting its own LEDs.
+        processes[1].replace("g");
+        assert!(driver
+            .command(2, 0, 0, processes[1].processid())
+            .is_success());
+        assert_eq!(persist(4).get_success_u32(), Some(0));
+    }
+
+    #[test]
+    fn test_highest_priority_state_is_shown() {
+        let (_, processes) = mock_process::kernel(&["a", "b"]);
+        let (a, b) = (processes[0].processid(), processes[1].processid());
+        let mocks: [MockLed; 2] = Default::default();
+        let mut leds = [&mocks[0], &mocks[1]];
+        let driver = LedDriver::new(&mut leds);
+
+        // The base state shows while no state is pushed.
+        assert!(driver.command(1, 0, 0, a).is_success());
+        assert!(mocks[0].read());
+
+        // Each higher priority state takes over.
+        assert!(driver.command(6, 0, 1, a).is_success());
+        assert!(!mocks[0].read());
+        assert!(driver.command(5, 0, 5, b).is_success());
+        assert!(mocks[0].read());
+        // Of equal priorities, the most recent is shown.
+        assert!(driver.command(6, 0, 5, a).is_success());
+        assert!(!mocks[0].read());
+        // A lower priority state does not change the LED.
+        assert!(driver.command(5, 0, 2, a).is_success());
+        assert!(!mocks[0].read());
+        // States of one LED leave the others alone.
+        assert!(!mocks[1].read());
+
+        // Clearing falls back to the next highest state, then to the base.
+        assert!(driver.command(7, 0, 5, a).is_success());
+        assert!(mocks[0].read());
+        assert!(driver.command(7, 0, 5, b).is_success());
+        assert!(mocks[0].read());
+        assert!(driver.command(7, 0, 2, a).is_success());
+        assert!(!mocks[0].read());
+        assert!(driver.command(7, 0, 1, a).is_success());
+        assert!(mocks[0].read());
+
+        // While a state is active, changing the base state does not show.
+        assert!(driver.command(6, 0, 0, b).is_success());
+        assert!(driver.command(1, 0, 0, a).is_success());
+        assert!(!mocks[0].read());
+        assert!(driver.command(7, 0, 0, b).is_success());
+        assert!(mocks[0].read());
+
+        // An app can only clear its own states.
+        assert!(driver.command(6, 0, 3, b).is_success());
+        assert_eq!(
+            driver.command(7, 0, 3, a).get_failure(),
+            Some(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            driver.command(5, 0, 256, a).get_failure(),
+            Some(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            driver.command(5, 2, 1, a).get_failure(),
+            Some(ErrorCode::INVAL)
+        );
+        assert!(!mocks[0].read());
+
+        // The states of an app that restarted are dropped.
+        processes[1].restart();
+        assert!(driver.command(1, 0, 0, a).is_success());
+        assert!(mocks[0].read());
+    }
+}
@@ -1,5 +1,6 @@
 #![feature(const_fn)]
-#![forbid(unsafe_code)]
+#![cfg_attr(not(test), forbid(unsafe_code))]
+#![cfg_attr(test, deny(unsafe_code))]
 #![no_std]
 
 pub mod test;
@@ -9,6 +10,8 @@ pub mod net;
 
 pub mod adc;
 pub mod adc_microphone;
+pub mod aes;
+pub mod aes_gcm;
 pub mod alarm;
 pub mod ambient_light;
 pub mod analog_comparator;
@@ -16,32 +19,51 @@ pub mod analog_sensor;
 pub mod apds9960;
 pub mod app_flash_driver;
 pub mod ble_advertising_driver;
+pub mod ble_gatt;
 pub mod bus;
 pub mod button;
 pub mod buzzer_driver;
+pub mod can;
 pub mod console;
+pub mod console_log;
+pub mod crash_dump;
 pub mod crc;
 pub mod ctap;
+pub mod ctr_drbg;
 pub mod dac;
+pub mod date_time;
+pub mod deadline;
 pub mod debug_process_restart;
 pub mod driver;
+pub mod ds18b20;
+pub mod ecdsa_p256;
+pub mod enc28j60;
+pub mod entropy_health;
+pub mod epaper;
+pub mod fault_reason;
+pub mod filesystem_driver;
+pub mod flash_fs;
 pub mod fm25cl;
 pub mod ft6x06;
 pub mod fxos8700cq;
 pub mod gpio;
 pub mod gpio_async;
 pub mod hd44780;
+pub mod hid_input;
 pub mod hmac;
 pub mod hts221;
 pub mod humidity;
 pub mod i2c_master;
 pub mod i2c_master_slave_driver;
+pub mod i2s;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod kv_store;
 pub mod l3gd20;
 pub mod led;
 pub mod led_matrix;
 pub mod log;
+pub mod lorawan;
 pub mod low_level_debug;
 pub mod lps25hb;
 pub mod lsm303agr;
@@ -50,33 +72,50 @@ pub mod lsm303xx;
 pub mod ltc294x;
 pub mod max17205;
 pub mod mcp230xx;
+pub mod mcp2515;
 pub mod mlx90614;
+pub mod modbus;
+pub mod moisture;
 pub mod mx25r6435f;
 pub mod ninedof;
+pub mod nmea;
 pub mod nonvolatile_storage_driver;
 pub mod nonvolatile_to_pages;
 pub mod nrf51822_serialization;
+pub mod one_wire;
+pub mod ota;
 pub mod panic_button;
 pub mod pca9544a;
 pub mod process_console;
+pub mod process_stats;
 pub mod proximity;
+pub mod pwm_capture;
+pub mod pwm_input;
+pub mod qdec;
+pub mod qspi_flash;
 pub mod rf233;
 pub mod rf233_const;
 pub mod rng;
+pub mod rs485;
 pub mod screen;
 pub mod sdcard;
 pub mod segger_rtt;
+pub mod sha256;
 pub mod sht3x;
 pub mod si7021;
+pub mod smbus;
 pub mod sound_pressure;
 pub mod spi_controller;
 pub mod spi_peripheral;
 pub mod st77xx;
+pub mod sx126x;
+pub mod sx127x;
 pub mod temperature;
 pub mod temperature_stm;
 pub mod text_screen;
 pub mod tickv;
 pub mod touch;
+pub mod touch_gesture;
 pub mod tsl2561;
 pub mod usb;
 pub mod virtual_adc;
@@ -91,3 +130,4 @@ pub mod virtual_rng;
 pub mod virtual_spi;
 pub mod virtual_timer;
 pub mod virtual_uart;
+pub mod virtual_watchdog;
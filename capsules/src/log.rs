@@ -65,6 +65,37 @@
 //!     log.set_read_client(log_storage_read_client);
 //!     log.set_append_client(log_storage_append_client);
 //! ```
+//!
+//! Sub-logs
+//! --------
+//!
+//! A single storage volume can be partitioned into several independent sub-logs (for example
+//! separate error, telemetry, and audit logs), each with its own read and append positions. Each
+//! sub-log is a `Log` over a page-aligned part of the volume returned by `partition_volume()`, and
+//! the sub-logs are grouped in a `SubLogs`, which routes flash callbacks to the sub-log that
+//! issued the flash operation and lets clients select a sub-log by index. Only one sub-log can
+//! have an operation in progress at a time, as they share the flash.
+//!
+//! ```
+//!     storage_volume!(VOLUME, 8);
+//!     const SUB_LOG_SIZES: [usize; 2] = [2048, 6144];
+//!
+//!     let error_log = static_init!(
+//!         capsules::log::Log,
+//!         capsules::log::Log::new(
+//!             capsules::log::partition_volume(&VOLUME, &SUB_LOG_SIZES, 512, 0).unwrap(),
+//!             &mut sam4l::flashcalw::FLASH_CONTROLLER,
+//!             error_log_pagebuffer,
+//!             dynamic_deferred_caller,
+//!             true
+//!         )
+//!     );
+//!     // ... and likewise for the telemetry log with index 1.
+//!
+//!     let logs = static_init!([&'static capsules::log::Log; 2], [error_log, telemetry_log]);
+//!     let sub_logs = static_init!(capsules::log::SubLogs, capsules::log::SubLogs::new(logs));
+//!     kernel::hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, sub_logs);
+//! ```
 
 use core::cell::Cell;
 use core::convert::TryFrom;
@@ -457,9 +488,11 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
 
         // Get flash page to write to and log page being overwritten. Subtract page_size since
         // padding pointer points to start of the page following the one we want to flush after the
-        // padding operation.
+        // padding operation. Before the log first wraps around the volume no page is overwritten,
+        // and the wrapped subtraction yields a page number past any entry.
         let page_number = self.page_number(pad_ptr - self.page_size);
-        let overwritten_page = (pad_ptr - self.volume.len() - self.page_size) / self.page_size;
+        let overwritten_page =
+            pad_ptr.wrapping_sub(self.volume.len() + self.page_size) / self.page_size;
 
         // Advance read and oldest entry IDs, if within flash page being overwritten.
         let read_entry_id = self.read_entry_id.get();
@@ -871,7 +904,7 @@ impl<'a, F: Flash + 'static> flash::Client<F> for Log<'a, F> {
         match error {
             flash::Error::CommandComplete => {
                 let oldest_entry_id = self.oldest_entry_id.get();
-                if oldest_entry_id >= self.append_entry_id.get() - self.page_size {
+                if oldest_entry_id >= self.append_entry_id.get().saturating_sub(self.page_size) {
                     // Erased all pages. Reset state and callback client.
                     if self.reset() {
                         self.error.set(Ok(()));
@@ -906,3 +939,407 @@ impl<'a, F: Flash + 'static> DynamicDeferredCallClient for Log<'a, F> {
         self.client_callback();
     }
 }
+
+/// Returns the part of `volume` used by sub-log `log_id`, when the volume is partitioned into
+/// consecutive sub-logs of the sizes in `sizes`. Returns `None` if `log_id` is not a valid
+/// sub-log, if a size is not a non-zero multiple of `page_size`, or if the sub-logs do not fit
+/// within the volume.
+pub fn partition_volume(
+    volume: &'static [u8],
+    sizes: &[usize],
+    page_size: usize,
+    log_id: usize,
+) -> Option<&'static [u8]> {
+    if log_id >= sizes.len() || page_size == 0 {
+        return None;
+    }
+    let mut start = 0;
+    for (id, size) in sizes.iter().enumerate() {
+        if *size == 0 || size % page_size != 0 || start + size > volume.len() {
+            return None;
+        }
+        if id == log_id {
+            return Some(&volume[start..start + size]);
+        }
+        start += size;
+    }
+    None
+}
+
+/// Several independent logs sharing one flash device, selected by sub-log index.
+pub struct SubLogs<'a, F: Flash + 'static> {
+    logs: &'a [&'a Log<'a, F>],
+}
+
+impl<'a, F: Flash + 'static> SubLogs<'a, F> {
+    pub fn new(logs: &'a [&'a Log<'a, F>]) -> SubLogs<'a, F> {
+        SubLogs { logs }
+    }
+
+    /// Returns the number of sub-logs.
+    pub fn num_logs(&self) -> usize {
+        self.logs.len()
+    }
+
+    /// Returns the sub-log with the given index.
+    /// Result<(), ErrorCode>s used:
+    ///     * INVAL: no sub-log with that index.
+    pub fn log(&self, log_id: usize) -> Result<&'a Log<'a, F>, ErrorCode> {
+        self.logs.get(log_id).copied().ok_or(ErrorCode::INVAL)
+    }
+
+    /// Returns the sub-log with the given index if no sub-log has an operation in progress.
+    /// Result<(), ErrorCode>s used:
+    ///     * INVAL: no sub-log with that index.
+    ///     * BUSY: a sub-log is busy with another operation, try again later.
+    fn idle_log(&self, log_id: usize) -> Result<&'a Log<'a, F>, ErrorCode> {
+        let log = self.log(log_id)?;
+        if self.logs.iter().any(|log| log.state.get() != State::Idle) {
+            Err(ErrorCode::BUSY)
+        } else {
+            Ok(log)
+        }
+    }
+
+    /// Read the next entry of a sub-log into a buffer. See `LogRead::read()`; also fails with
+    /// INVAL if `log_id` is not a valid sub-log.
+    pub fn read(
+        &self,
+        log_id: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        match self.idle_log(log_id) {
+            Ok(log) => log.read(buffer, length),
+            Err(e) => Err((e, buffer)),
+        }
+    }
+
+    /// Append an entry onto the end of a sub-log. See `LogWrite::append()`; also fails with INVAL
+    /// if `log_id` is not a valid sub-log.
+    pub fn append(
+        &self,
+        log_id: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        match self.idle_log(log_id) {
+            Ok(log) => log.append(buffer, length),
+            Err(e) => Err((e, buffer)),
+        }
+    }
+
+    /// Seek to a new read entry ID within a sub-log. See `LogRead::seek()`.
+    pub fn seek(&self, log_id: usize, entry_id: EntryID) -> Result<(), ErrorCode> {
+        self.idle_log(log_id)?.seek(entry_id)
+    }
+
+    /// Sync a sub-log to storage. See `LogWrite::sync()`.
+    pub fn sync(&self, log_id: usize) -> Result<(), ErrorCode> {
+        self.idle_log(log_id)?.sync()
+    }
+
+    /// Erase a sub-log in its entirety, leaving the other sub-logs intact. See `LogWrite::erase()`.
+    pub fn erase(&self, log_id: usize) -> Result<(), ErrorCode> {
+        self.idle_log(log_id)?.erase()
+    }
+
+    /// Returns the sub-log with a flash operation in progress.
+    fn flash_log(&self) -> Option<&'a Log<'a, F>> {
+        self.logs.iter().copied().find(|log| match log.state.get() {
+            State::Append | State::Sync | State::Erase => true,
+            State::Idle | State::Read | State::Seek => false,
+        })
+    }
+}
+
+impl<'a, F: Flash + 'static> flash::Client<F> for SubLogs<'a, F> {
+    fn read_complete(&self, _read_buffer: &'static mut F::Page, _error: flash::Error) {
+        // Reads are made directly from the storage volume, not through the flash interface.
+        unreachable!();
+    }
+
+    /// Forward to the sub-log that started the write.
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: flash::Error) {
+        self.flash_log()
+            .map(move |log| log.write_complete(pagebuffer, error))
+            .unwrap();
+    }
+
+    /// Forward to the sub-log that started the erase.
+    fn erase_complete(&self, error: flash::Error) {
+        self.flash_log()
+            .map(move |log| log.erase_complete(error))
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{partition_volume, Log, SubLogs};
+    use crate::test::mock_process;
+    use core::cell::{Cell, RefCell};
+    use kernel::common::cells::TakeCell;
+    use kernel::common::dynamic_deferred_call::{
+        DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+        DynamicDeferredCallClientState,
+    };
+    use kernel::hil::flash::{self, Flash};
+    use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+    use kernel::ErrorCode;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const PAGE_SIZE: usize = 64;
+    const SUB_LOG_SIZES: [usize; 2] = [2 * PAGE_SIZE, 2 * PAGE_SIZE];
+
+    struct MockPage([u8; PAGE_SIZE]);
+
+    impl Default for MockPage {
+        fn default() -> MockPage {
+            MockPage([0; PAGE_SIZE])
+        }
+    }
+
+    impl AsMut<[u8]> for MockPage {
+        fn as_mut(&mut self) -> &mut [u8] {
+            &mut self.0
+        }
+    }
+
+    /// A flash that holds each write or erase until the test completes it.
+    /// Written pages are not stored, as the logs only read flash pages they
+    /// filled.
+    struct MockFlash {
+        page: TakeCell<'static, MockPage>,
+        erasing: Cell<bool>,
+    }
+
+    impl MockFlash {
+        fn new() -> MockFlash {
+            MockFlash {
+                page: TakeCell::empty(),
+                erasing: Cell::new(false),
+            }
+        }
+
+        /// Finish the operation in progress.
+        fn complete(&self, client: &dyn flash::Client<MockFlash>) {
+            if let Some(page) = self.page.take() {
+                client.write_complete(page, flash::Error::CommandComplete);
+            } else if self.erasing.replace(false) {
+                client.erase_complete(flash::Error::CommandComplete);
+            } else {
+                panic!("no flash operation in progress");
+            }
+        }
+    }
+
+    impl Flash for MockFlash {
+        type Page = MockPage;
+
+        fn read_page(
+            &self,
+            _page_number: usize,
+            buf: &'static mut MockPage,
+        ) -> Result<(), (ErrorCode, &'static mut MockPage)> {
+            Err((ErrorCode::NOSUPPORT, buf))
+        }
+
+        fn write_page(
+            &self,
+            _page_number: usize,
+            buf: &'static mut MockPage,
+        ) -> Result<(), (ErrorCode, &'static mut MockPage)> {
+            self.page.replace(buf);
+            Ok(())
+        }
+
+        fn erase_page(&self, _page_number: usize) -> Result<(), ErrorCode> {
+            self.erasing.set(true);
+            Ok(())
+        }
+    }
+
+    /// Records what one sub-log reports to its clients.
+    #[derive(Default)]
+    struct Client {
+        read: RefCell<Vec<Vec<u8>>>,
+        appended: RefCell<Vec<Vec<u8>>>,
+        done: RefCell<Vec<Result<(), ErrorCode>>>,
+    }
+
+    impl LogReadClient for Client {
+        fn read_done(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+            error: Result<(), ErrorCode>,
+        ) {
+            assert_eq!(error, Ok(()));
+            self.read.borrow_mut().push(buffer[..length].to_vec());
+        }
+
+        fn seek_done(&self, error: Result<(), ErrorCode>) {
+            self.done.borrow_mut().push(error);
+        }
+    }
+
+    impl LogWriteClient for Client {
+        fn append_done(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+            records_lost: bool,
+            error: Result<(), ErrorCode>,
+        ) {
+            assert_eq!(error, Ok(()));
+            assert!(!records_lost);
+            self.appended.borrow_mut().push(buffer[..length].to_vec());
+        }
+
+        fn sync_done(&self, error: Result<(), ErrorCode>) {
+            self.done.borrow_mut().push(error);
+        }
+
+        fn erase_done(&self, error: Result<(), ErrorCode>) {
+            self.done.borrow_mut().push(error);
+        }
+    }
+
+    type TestLog = Log<'static, MockFlash>;
+
+    struct SubLogsTest {
+        sub_logs: &'static SubLogs<'static, MockFlash>,
+        logs: [&'static TestLog; 2],
+        handles: [DeferredCallHandle; 2],
+        clients: [&'static Client; 2],
+        flash: &'static MockFlash,
+    }
+
+    impl SubLogsTest {
+        /// Two sub-logs over an erased volume.
+        fn new() -> SubLogsTest {
+            let volume: &'static [u8] =
+                Box::leak(std::vec![0xFF; 4 * PAGE_SIZE].into_boxed_slice());
+            let flash = mock_process::leak(MockFlash::new());
+            let states: &'static [DynamicDeferredCallClientState] = Box::leak(
+                std::vec![
+                    DynamicDeferredCallClientState::default(),
+                    DynamicDeferredCallClientState::default(),
+                ]
+                .into_boxed_slice(),
+            );
+            let deferred_caller = mock_process::leak(DynamicDeferredCall::new(states));
+            let log = |log_id| -> &'static TestLog {
+                mock_process::leak(Log::new(
+                    partition_volume(volume, &SUB_LOG_SIZES, PAGE_SIZE, log_id).unwrap(),
+                    flash,
+                    Box::leak(Box::new(MockPage::default())),
+                    deferred_caller,
+                    false,
+                ))
+            };
+            let logs = [log(0), log(1)];
+            let clients = [
+                mock_process::leak(Client::default()),
+                mock_process::leak(Client::default()),
+            ];
+            let mut handles = [None, None];
+            for i in 0..2 {
+                logs[i].set_read_client(clients[i]);
+                logs[i].set_append_client(clients[i]);
+                let handle = deferred_caller.register(logs[i]).unwrap();
+                logs[i].initialize_callback_handle(handle);
+                handles[i] = Some(handle);
+            }
+            SubLogsTest {
+                sub_logs: mock_process::leak(SubLogs::new(mock_process::leak(logs))),
+                logs: logs,
+                handles: [handles[0].unwrap(), handles[1].unwrap()],
+                clients: clients,
+                flash: flash,
+            }
+        }
+
+        fn append(&self, log_id: usize, data: &[u8]) -> Result<(), ErrorCode> {
+            let buffer = mock_process::buffer(data.len());
+            buffer.copy_from_slice(data);
+            self.sub_logs
+                .append(log_id, buffer, data.len())
+                .map_err(|(e, _)| e)
+        }
+
+        /// Read the next entry of a sub-log and deliver the deferred
+        /// callback.
+        fn read(&self, log_id: usize) -> Result<(), ErrorCode> {
+            self.sub_logs
+                .read(log_id, mock_process::buffer(16), 16)
+                .map_err(|(e, _)| e)?;
+            self.logs[log_id].call(self.handles[log_id]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sub_logs_are_independent() {
+        let test = SubLogsTest::new();
+        assert_eq!(test.sub_logs.num_logs(), 2);
+        assert_eq!(test.append(0, b"error-1"), Ok(()));
+        assert_eq!(test.append(1, b"telemetry"), Ok(()));
+        assert_eq!(test.append(0, b"error-2"), Ok(()));
+        assert_eq!(*test.clients[0].appended.borrow(), [b"error-1", b"error-2"]);
+        assert_eq!(*test.clients[1].appended.borrow(), [b"telemetry"]);
+
+        // Each sub-log reads its own entries, from its own read position.
+        assert_eq!(test.read(1), Ok(()));
+        assert_eq!(test.read(0), Ok(()));
+        assert_eq!(test.read(1), Err(ErrorCode::FAIL));
+        assert_eq!(test.read(0), Ok(()));
+        assert_eq!(test.read(0), Err(ErrorCode::FAIL));
+        assert_eq!(*test.clients[0].read.borrow(), [b"error-1", b"error-2"]);
+        assert_eq!(*test.clients[1].read.borrow(), [b"telemetry"]);
+
+        // Erasing one sub-log leaves the other intact.
+        assert_eq!(test.sub_logs.erase(1), Ok(()));
+        // The sub-logs share the flash, so the other one waits.
+        assert_eq!(test.append(0, b"error-3"), Err(ErrorCode::BUSY));
+        test.flash.complete(test.sub_logs);
+        assert_eq!(*test.clients[1].done.borrow(), [Ok(())]);
+        assert!(test.clients[0].done.borrow().is_empty());
+        assert_eq!(test.read(1), Err(ErrorCode::FAIL));
+        let start = test.logs[0].log_start();
+        assert_eq!(test.sub_logs.seek(0, start), Ok(()));
+        test.logs[0].call(test.handles[0]);
+        assert_eq!(test.read(0), Ok(()));
+        assert_eq!(
+            test.clients[0].read.borrow().last().unwrap().as_slice(),
+            b"error-1"
+        );
+
+        // A sync reports back to the sub-log that started it.
+        assert_eq!(test.sub_logs.sync(0), Ok(()));
+        test.flash.complete(test.sub_logs);
+        assert_eq!(*test.clients[0].done.borrow(), [Ok(()), Ok(())]);
+        assert_eq!(*test.clients[1].done.borrow(), [Ok(())]);
+    }
+
+    #[test]
+    fn test_invalid_sub_log_is_rejected() {
+        let test = SubLogsTest::new();
+        assert!(test.sub_logs.log(1).is_ok());
+        assert_eq!(test.sub_logs.log(2).err(), Some(ErrorCode::INVAL));
+        assert_eq!(test.append(2, b"lost"), Err(ErrorCode::INVAL));
+        assert_eq!(test.read(2).err(), Some(ErrorCode::INVAL));
+        assert_eq!(test.sub_logs.seek(2, 0), Err(ErrorCode::INVAL));
+        assert_eq!(test.sub_logs.sync(2), Err(ErrorCode::INVAL));
+        assert_eq!(test.sub_logs.erase(2), Err(ErrorCode::INVAL));
+
+        // Nothing was written to the valid sub-logs.
+        assert_eq!(test.read(0), Err(ErrorCode::FAIL));
+        assert_eq!(test.read(1), Err(ErrorCode::FAIL));
+        assert!(partition_volume(&[0; 256], &SUB_LOG_SIZES, PAGE_SIZE, 2).is_none());
+    }
+}
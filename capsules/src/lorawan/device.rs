@@ -0,0 +1,58 @@
+//! The interface of a LoRaWAN end device, as provided by a MAC layer.
+//!
+//! A device first joins a network, and can then send uplinks to it. Class A
+//! devices only receive downlinks right after an uplink, so an uplink
+//! completes only once its receive windows are closed, and downlinks are
+//! delivered before that.
+
+use kernel::ErrorCode;
+
+pub trait MacDevice<'a> {
+    fn set_client(&self, client: &'a dyn MacClient);
+
+    /// Whether the device is part of a network, having joined it now or
+    /// before a reboot.
+    fn is_joined(&self) -> bool;
+
+    /// The address of the device in the network it joined.
+    fn dev_addr(&self) -> Option<u32>;
+
+    /// Whether a join or an uplink is in progress.
+    fn is_busy(&self) -> bool;
+
+    /// Join a network with over-the-air activation, leaving any network the
+    /// device is part of. EUIs are given most significant byte first, as
+    /// they are usually written.
+    fn join(&self, dev_eui: [u8; 8], join_eui: [u8; 8], app_key: [u8; 16])
+        -> Result<(), ErrorCode>;
+
+    /// Send `payload` to application port `port`, from 1 to 223. Confirmed
+    /// uplinks must be acknowledged by the network.
+    ///
+    /// Returns `OFF` if the device has not joined a network, and `SIZE` if
+    /// the payload is longer than `max_payload_len()`.
+    fn send(&self, port: u8, confirmed: bool, payload: &[u8]) -> Result<(), ErrorCode>;
+
+    fn data_rate(&self) -> u8;
+
+    /// Set the data rate of uplinks. Lower data rates reach further, but
+    /// carry less data and occupy the channel longer.
+    fn set_data_rate(&self, data_rate: u8) -> Result<(), ErrorCode>;
+
+    /// The longest payload that can be sent at the current data rate.
+    fn max_payload_len(&self) -> usize;
+}
+
+pub trait MacClient {
+    /// The join finished. The result is `NOACK` if the network did not
+    /// accept the device.
+    fn join_done(&self, result: Result<(), ErrorCode>);
+
+    /// The uplink finished, and its receive windows are closed. `acked` is
+    /// whether the network acknowledged a confirmed uplink, which otherwise
+    /// fails with `NOACK`.
+    fn send_done(&self, result: Result<(), ErrorCode>, acked: bool);
+
+    /// A downlink with `payload` for application port `port` arrived.
+    fn receive(&self, port: u8, payload: &[u8]);
+}
@@ -0,0 +1,300 @@
+//! LoRaWAN userspace interface.
+//!
+//! Processes share the network the device joined. Any process can make the
+//! device join a network, with the credentials in its join buffer, and then
+//! send uplinks. Joins and uplinks are handled one at a time.
+//!
+//! Class A devices only receive downlinks in the windows following an
+//! uplink, so downlinks are written to the downlink buffer of the process
+//! that sent the uplink, which is notified before the uplink completes.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let lorawan_driver = static_init!(
+//!     capsules::lorawan::LoRaWanDriver<'static>,
+//!     capsules::lorawan::LoRaWanDriver::new(lorawan, board_kernel.create_grant(&grant_cap))
+//! );
+//! lorawan.set_client(lorawan_driver);
+//! ```
+
+use crate::lorawan::device::{MacClient, MacDevice};
+use core::{cmp, mem};
+use kernel::common::cells::OptionalCell;
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::LoRaWan as usize;
+
+/// Bit of the send command marking a confirmed uplink.
+const CONFIRMED_FLAG: usize = 1 << 8;
+
+/// Length of the join buffer: DevEUI, JoinEUI and AppKey.
+const JOIN_PARAMS_LEN: usize = 8 + 8 + 16;
+
+#[derive(Default)]
+pub struct App {
+    join_callback: Upcall,
+    send_callback: Upcall,
+    downlink_callback: Upcall,
+    join_params: ReadOnlyAppSlice,
+    uplink: ReadOnlyAppSlice,
+    downlink: ReadWriteAppSlice,
+}
+
+pub struct LoRaWanDriver<'a> {
+    mac: &'a dyn MacDevice<'a>,
+    apps: Grant<App>,
+    /// The process whose join or uplink is in progress.
+    requester: OptionalCell<ProcessId>,
+}
+
+impl<'a> LoRaWanDriver<'a> {
+    pub fn new(mac: &'a dyn MacDevice<'a>, grant: Grant<App>) -> LoRaWanDriver<'a> {
+        LoRaWanDriver {
+            mac: mac,
+            apps: grant,
+            requester: OptionalCell::empty(),
+        }
+    }
+
+    fn join(&self, app: &mut App) -> Result<(), ErrorCode> {
+        app.join_params.map_or(Err(ErrorCode::INVAL), |params| {
+            if params.len() < JOIN_PARAMS_LEN {
+                return Err(ErrorCode::INVAL);
+            }
+            let mut dev_eui = [0; 8];
+            let mut join_eui = [0; 8];
+            let mut app_key = [0; 16];
+            dev_eui.copy_from_slice(&params[0..8]);
+            join_eui.copy_from_slice(&params[8..16]);
+            app_key.copy_from_slice(&params[16..JOIN_PARAMS_LEN]);
+            self.mac.join(dev_eui, join_eui, app_key)
+        })
+    }
+
+    fn send(&self, app: &mut App, port: u8, confirmed: bool, len: usize) -> Result<(), ErrorCode> {
+        if len == 0 {
+            return self.mac.send(port, confirmed, &[]);
+        }
+        app.uplink.map_or(Err(ErrorCode::INVAL), |payload| {
+            if len > payload.len() {
+                return Err(ErrorCode::SIZE);
+            }
+            self.mac.send(port, confirmed, &payload[..len])
+        })
+    }
+}
+
+impl Driver for LoRaWanDriver<'_> {
+    /// ### `allow_num`
+    ///
+    /// - `0`: Downlink buffer. The payload of downlinks is written to it.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.downlink, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(slice),
+            Ok(Err(e)) => Err((slice, e)),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// ### `allow_num`
+    ///
+    /// - `0`: Join buffer: the DevEUI (8 bytes) and JoinEUI (8 bytes), most
+    ///        significant byte first, followed by the AppKey (16 bytes).
+    /// - `1`: Uplink buffer.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.join_params, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.uplink, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(slice),
+            Ok(Err(e)) => Err((slice, e)),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Subscribe to LoRaWAN events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: The join finished. Upcall arguments: (status, device address).
+    ///        The status is `NOACK` if the network did not accept the device.
+    /// - `1`: The uplink finished. Upcall arguments: (status, acked). The
+    ///        status is `NOACK` if a confirmed uplink was not acknowledged.
+    /// - `2`: A downlink was written to the downlink buffer. Upcall
+    ///        arguments: (port, length).
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.join_callback, &mut callback);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.send_callback, &mut callback);
+                    Ok(())
+                }
+                2 => {
+                    mem::swap(&mut app.downlink_callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(callback),
+            Ok(Err(e)) => Err((callback, e)),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// LoRaWAN control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Join a network with the credentials in the join buffer.
+    /// - `2`: Send the first `data2` bytes of the uplink buffer to port
+    ///        `data1 & 0xff`, as a confirmed uplink if bit 8 of `data1` is
+    ///        set.
+    /// - `3`: Whether the device joined a network. Returns its address if
+    ///        it did, and `OFF` otherwise.
+    /// - `4`: Set the data rate of uplinks to `data1`.
+    /// - `5`: Returns the longest uplink payload at the current data rate.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // join, send
+            1 | 2 => {
+                if self.requester.is_some() || self.mac.is_busy() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                let res = self
+                    .apps
+                    .enter(appid, |app| {
+                        if command_num == 1 {
+                            self.join(app)
+                        } else {
+                            let port = (data1 & 0xff) as u8;
+                            self.send(app, port, data1 & CONFIRMED_FLAG != 0, data2)
+                        }
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                if res.is_ok() {
+                    self.requester.set(appid);
+                }
+                res.into()
+            }
+
+            // joined status
+            3 => match self.mac.dev_addr() {
+                Some(dev_addr) => CommandReturn::success_u32(dev_addr),
+                None => CommandReturn::failure(ErrorCode::OFF),
+            },
+
+            // set data rate
+            4 => {
+                if self.mac.is_busy() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                self.mac
+                    .set_data_rate(cmp::min(data1, u8::MAX as usize) as u8)
+                    .into()
+            }
+
+            // max payload length
+            5 => CommandReturn::success_u32(self.mac.max_payload_len() as u32),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+impl MacClient for LoRaWanDriver<'_> {
+    fn join_done(&self, result: Result<(), ErrorCode>) {
+        if let Some(appid) = self.requester.extract() {
+            let dev_addr = self.mac.dev_addr().unwrap_or(0);
+            let _ = self.apps.enter(appid, |app| {
+                app.join_callback
+                    .schedule(kernel::into_statuscode(result), dev_addr as usize, 0);
+            });
+        }
+    }
+
+    fn send_done(&self, result: Result<(), ErrorCode>, acked: bool) {
+        if let Some(appid) = self.requester.extract() {
+            let _ = self.apps.enter(appid, |app| {
+                app.send_callback
+                    .schedule(kernel::into_statuscode(result), acked as usize, 0);
+            });
+        }
+    }
+
+    fn receive(&self, port: u8, payload: &[u8]) {
+        self.requester.map(|appid| {
+            let _ = self.apps.enter(*appid, |app| {
+                let len = app.downlink.mut_map_or(0, |buffer| {
+                    let len = cmp::min(payload.len(), buffer.len());
+                    buffer[..len].copy_from_slice(&payload[..len]);
+                    len
+                });
+                app.downlink_callback.schedule(port as usize, len, 0);
+            });
+        });
+    }
+}
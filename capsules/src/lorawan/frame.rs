@@ -0,0 +1,169 @@
+//! LoRaWAN 1.0.x frame formats, and the regional parameters of the EU868
+//! band.
+//!
+//! Multi-byte fields of LoRaWAN frames are little-endian. Every frame starts
+//! with a one-byte MAC header (MHDR), giving its type, and ends with a
+//! 4-byte message integrity code (MIC), the start of the AES-CMAC of the
+//! frame.
+//!
+//! ```text
+//! Join request:  | MHDR | JoinEUI (8) | DevEUI (8) | DevNonce (2) | MIC |
+//! Join accept:   | MHDR | AppNonce (3) | NetID (3) | DevAddr (4) |
+//!                  DLSettings | RxDelay | [CFList (16)] | MIC |
+//! Data frame:    | MHDR | DevAddr (4) | FCtrl | FCnt (2) | FOpts (0-15) |
+//!                  [FPort | FRMPayload] | MIC |
+//! ```
+
+use kernel::hil::lora::{Bandwidth, CodingRate, Modulation};
+use kernel::hil::symmetric_encryption::AES128_BLOCK_SIZE;
+
+pub const MIC_LEN: usize = 4;
+
+/// Frame types, in the top 3 bits of the MHDR. The bottom bits hold the
+/// major version of the protocol, which is always 0.
+pub mod mtype {
+    pub const JOIN_REQUEST: u8 = 0x00;
+    pub const JOIN_ACCEPT: u8 = 0x20;
+    pub const UNCONFIRMED_UP: u8 = 0x40;
+    pub const UNCONFIRMED_DOWN: u8 = 0x60;
+    pub const CONFIRMED_UP: u8 = 0x80;
+    pub const CONFIRMED_DOWN: u8 = 0xa0;
+    pub const MASK: u8 = 0xe0;
+}
+
+/// Bits of the FCtrl field of data frames.
+pub mod fctrl {
+    pub const ADR: u8 = 0x80;
+    pub const ACK: u8 = 0x20;
+    pub const FPENDING: u8 = 0x10;
+    pub const FOPTS_LEN_MASK: u8 = 0x0f;
+}
+
+/// Length of a join request, MIC included.
+pub const JOIN_REQUEST_LEN: usize = 1 + 8 + 8 + 2 + MIC_LEN;
+
+/// Length of a join accept without and with a list of channels.
+pub const JOIN_ACCEPT_LEN: usize = 1 + 3 + 3 + 4 + 1 + 1 + MIC_LEN;
+pub const JOIN_ACCEPT_CFLIST_LEN: usize = JOIN_ACCEPT_LEN + 16;
+
+/// Offset of the FOpts field of data frames, after the MHDR and the start
+/// of the frame header.
+pub const FOPTS_OFFSET: usize = 1 + 4 + 1 + 2;
+
+/// Largest difference between the frame counter of a downlink and the
+/// expected one.
+pub const MAX_FCNT_GAP: u32 = 16384;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    Uplink = 0,
+    Downlink = 1,
+}
+
+/// A block of the form used for the MIC (`B0`) and the encryption (`Ai`) of
+/// data frames.
+fn frame_block(first: u8, dir: Direction, dev_addr: u32, fcnt: u32, last: u8) -> [u8; 16] {
+    let mut block = [0; AES128_BLOCK_SIZE];
+    block[0] = first;
+    block[5] = dir as u8;
+    block[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+    block[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    block[15] = last;
+    block
+}
+
+/// The block `B0` prepended to a data frame of `len` bytes, MIC excluded,
+/// to compute its MIC.
+pub fn mic_block(dir: Direction, dev_addr: u32, fcnt: u32, len: usize) -> [u8; 16] {
+    frame_block(0x49, dir, dev_addr, fcnt, len as u8)
+}
+
+/// The block `Ai` whose encryption is XORed with the `i`th block of the
+/// payload of a data frame. The payload is thus encrypted with AES-CTR,
+/// starting from `A1`.
+pub fn encryption_block(dir: Direction, dev_addr: u32, fcnt: u32, i: u8) -> [u8; 16] {
+    frame_block(0x01, dir, dev_addr, fcnt, i)
+}
+
+/// The block whose encryption with the AppKey is a session key: the NwkSKey
+/// if `kind` is 1, and the AppSKey if it is 2.
+pub fn session_key_block(kind: u8, join_accept: &[u8], dev_nonce: u16) -> [u8; 16] {
+    let mut block = [0; AES128_BLOCK_SIZE];
+    block[0] = kind;
+    // AppNonce and NetID.
+    block[1..7].copy_from_slice(&join_accept[1..7]);
+    block[7..9].copy_from_slice(&dev_nonce.to_le_bytes());
+    block
+}
+
+/// Derive a CMAC subkey from the encryption of the zero block, or from the
+/// previous subkey (RFC 4493, section 2.3).
+pub fn cmac_subkey(key: &[u8]) -> [u8; 16] {
+    let mut subkey = [0; AES128_BLOCK_SIZE];
+    for i in 0..AES128_BLOCK_SIZE {
+        let carry = key.get(i + 1).map_or(0, |next| next >> 7);
+        subkey[i] = key[i] << 1 | carry;
+    }
+    if key[0] & 0x80 != 0 {
+        subkey[AES128_BLOCK_SIZE - 1] ^= 0x87;
+    }
+    subkey
+}
+
+/// Time on air of a packet of `len` bytes with a payload CRC, in
+/// microseconds (SX1276 datasheet, section 4.1.1.7).
+pub fn time_on_air_us(m: &Modulation, len: usize) -> u32 {
+    let symbol_us = m.symbol_time_us();
+    let sf = m.spreading_factor as i32;
+    let de = m.low_data_rate() as i32;
+    let bits = 8 * len as i32 - 4 * sf + 28 + 16;
+    let blocks = (bits + 4 * (sf - 2 * de) - 1) / (4 * (sf - 2 * de));
+    let payload_symbols = 8 + if bits > 0 {
+        blocks as u32 * (m.coding_rate as u32 + 4)
+    } else {
+        0
+    };
+    // An 8-symbol preamble, and 4.25 symbols of sync word.
+    (8 * 4 + 17) * symbol_us / 4 + payload_symbols * symbol_us
+}
+
+/// Regional parameters of the 863-870 MHz band.
+pub mod eu868 {
+    use super::{Bandwidth, CodingRate, Modulation};
+
+    /// Channels every device must support.
+    pub const DEFAULT_CHANNELS: [u32; 3] = [868_100_000, 868_300_000, 868_500_000];
+    /// Number of channels, including those the network adds in the CFList
+    /// of the join accept.
+    pub const MAX_CHANNELS: usize = DEFAULT_CHANNELS.len() + 5;
+
+    pub const RX2_FREQUENCY: u32 = 869_525_000;
+    pub const RX2_DATA_RATE: u8 = 0;
+
+    /// Data rates 0 to 5 use spreading factors 12 to 7 at 125 kHz.
+    pub const MAX_DATA_RATE: u8 = 5;
+    pub const DEFAULT_DATA_RATE: u8 = 0;
+    pub const TX_POWER: i8 = 14;
+
+    /// Largest application payload for each data rate, without FOpts.
+    pub const MAX_PAYLOAD_LEN: [usize; MAX_DATA_RATE as usize + 1] = [51, 51, 51, 115, 222, 222];
+
+    /// The transmitter may be on for at most 1 / `DUTY_CYCLE` of the time.
+    pub const DUTY_CYCLE: u32 = 100;
+
+    pub const RECEIVE_DELAY1_MS: u32 = 1000;
+    pub const JOIN_ACCEPT_DELAY1_MS: u32 = 5000;
+    /// The second receive window opens one second after the first.
+    pub const RX2_DELAY_MS: u32 = 1000;
+
+    pub fn modulation(data_rate: u8, frequency: u32, downlink: bool) -> Modulation {
+        Modulation {
+            frequency: frequency,
+            spreading_factor: 12 - data_rate,
+            bandwidth: Bandwidth::Bw125kHz,
+            coding_rate: CodingRate::Cr4_5,
+            invert_iq: downlink,
+            public_network: true,
+        }
+    }
+}
@@ -0,0 +1,1045 @@
+//! Class A LoRaWAN 1.0.x MAC layer for end devices, in the EU868 band.
+//!
+//! The MAC joins networks with over-the-air activation, encrypts and
+//! authenticates uplinks and downlinks, and opens the two receive windows
+//! that follow each uplink.
+//!
+//! Cryptography relies on an AES-128 engine with the CBC and CTR modes.
+//! Payloads are encrypted with CTR. The AES-CMAC used for MICs is CBC with a
+//! zero IV once the last block is masked with a subkey (RFC 4493). The
+//! single block encryptions used to decrypt join accepts and to derive
+//! session keys are CBC operations over one block.
+//!
+//! The session (device address, session keys, frame counters, DevNonce and
+//! the settings the network sent in its join accept) is kept in nonvolatile
+//! storage, so that a device resumes it after a reboot and never reuses a
+//! frame counter or a DevNonce. It is written before every join request and
+//! uplink, with the counters already incremented.
+//!
+//! The MAC delays transmissions to respect the 1% duty cycle of the band.
+//! It ignores MAC commands from the network, and does not use adaptive data
+//! rate.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let lorawan = static_init!(
+//!     capsules::lorawan::mac::LoRaWanMac<
+//!         'static,
+//!         capsules::sx126x::SX126x<'static, VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>>,
+//!         VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!         nrf52840::aes::AesECB<'static>,
+//!     >,
+//!     capsules::lorawan::mac::LoRaWanMac::new(
+//!         sx1262,
+//!         lorawan_alarm,
+//!         &nrf52840::aes::AESECB,
+//!         nonvolatile_storage,
+//!         LORAWAN_STORAGE_ADDRESS,
+//!         &mut capsules::lorawan::mac::FRAME_BUF,
+//!         &mut capsules::lorawan::mac::CRYPT_BUF,
+//!         &mut capsules::lorawan::mac::STORAGE_BUF,
+//!     )
+//! );
+//! sx1262.set_transmit_client(lorawan);
+//! sx1262.set_receive_client(lorawan);
+//! lorawan_alarm.set_alarm_client(lorawan);
+//! nrf52840::aes::AESECB.set_client(lorawan);
+//! nonvolatile_storage.set_client(lorawan);
+//! lorawan.initialize();
+//! ```
+
+use crate::lorawan::device::{MacClient, MacDevice};
+use crate::lorawan::frame::{self, eu868, fctrl, mtype, Direction, MIC_LEN};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::lora::{self, Modulation};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::symmetric_encryption::{
+    self, AES128Ctr, AES128, AES128CBC, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ErrorCode;
+
+pub static mut FRAME_BUF: [u8; lora::MAX_PAYLOAD_LEN] = [0; lora::MAX_PAYLOAD_LEN];
+
+/// Length of the work buffer for cryptography, which must hold a block, the
+/// `B0` block and a padded frame.
+pub const CRYPT_BUF_LEN: usize = 2 * AES128_BLOCK_SIZE + lora::MAX_PAYLOAD_LEN + 1;
+pub static mut CRYPT_BUF: [u8; CRYPT_BUF_LEN] = [0; CRYPT_BUF_LEN];
+
+/// Length of the session record in nonvolatile storage.
+pub const RECORD_LEN: usize = 54 + 4 * eu868::MAX_CHANNELS;
+pub static mut STORAGE_BUF: [u8; RECORD_LEN] = [0; RECORD_LEN];
+
+/// Marks a valid session record, and its version.
+const RECORD_MAGIC: u32 = 0x4c52_5701;
+
+/// Receive windows open this much earlier than they should, to make up for
+/// the time the radio takes to start listening.
+const RX_MARGIN_MS: u32 = 20;
+
+/// Number of preamble symbols the radio must hear to detect a packet.
+const RX_PREAMBLE_SYMBOLS: u32 = 8;
+
+/// Messages are authenticated at this offset in the work buffer. The block
+/// before it receives the encryption of the zero block, from which the CMAC
+/// subkeys are derived.
+const CMAC_OFFSET: usize = AES128_BLOCK_SIZE;
+
+const ZERO_BLOCK: [u8; AES128_BLOCK_SIZE] = [0; AES128_BLOCK_SIZE];
+
+type Key = [u8; AES128_KEY_SIZE];
+
+/// The state of the device in the network it joined, which is persisted.
+#[derive(Copy, Clone)]
+struct Session {
+    joined: bool,
+    /// Last DevNonce sent in a join request.
+    dev_nonce: u16,
+    dev_addr: u32,
+    /// Frame counter of the next uplink.
+    fcnt_up: u32,
+    /// Smallest frame counter accepted for the next downlink.
+    fcnt_down: u32,
+    nwk_s_key: Key,
+    app_s_key: Key,
+    rx1_dr_offset: u8,
+    rx2_data_rate: u8,
+    /// Delay of the first receive window, in seconds.
+    rx1_delay: u8,
+    /// Frequency of each channel, in Hz, or 0 if the channel is unused.
+    channels: [u32; eu868::MAX_CHANNELS],
+}
+
+impl Session {
+    fn new() -> Session {
+        let mut channels = [0; eu868::MAX_CHANNELS];
+        channels[..eu868::DEFAULT_CHANNELS.len()].copy_from_slice(&eu868::DEFAULT_CHANNELS);
+        Session {
+            joined: false,
+            dev_nonce: 0,
+            dev_addr: 0,
+            fcnt_up: 0,
+            fcnt_down: 0,
+            nwk_s_key: [0; AES128_KEY_SIZE],
+            app_s_key: [0; AES128_KEY_SIZE],
+            rx1_dr_offset: 0,
+            rx2_data_rate: eu868::RX2_DATA_RATE,
+            rx1_delay: (eu868::RECEIVE_DELAY1_MS / 1000) as u8,
+            channels: channels,
+        }
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        let mut off = 0;
+        let mut put = |bytes: &[u8]| {
+            buf[off..off + bytes.len()].copy_from_slice(bytes);
+            off += bytes.len();
+        };
+        put(&RECORD_MAGIC.to_le_bytes());
+        put(&[self.joined as u8]);
+        put(&self.dev_nonce.to_le_bytes());
+        put(&self.dev_addr.to_le_bytes());
+        put(&self.fcnt_up.to_le_bytes());
+        put(&self.fcnt_down.to_le_bytes());
+        put(&self.nwk_s_key);
+        put(&self.app_s_key);
+        put(&[self.rx1_dr_offset, self.rx2_data_rate, self.rx1_delay]);
+        for channel in self.channels.iter() {
+            put(&channel.to_le_bytes());
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Option<Session> {
+        if buf.len() < RECORD_LEN || read_u32(buf, 0) != RECORD_MAGIC {
+            return None;
+        }
+        let mut session = Session::new();
+        session.joined = buf[4] != 0;
+        session.dev_nonce = u16::from_le_bytes([buf[5], buf[6]]);
+        session.dev_addr = read_u32(buf, 7);
+        session.fcnt_up = read_u32(buf, 11);
+        session.fcnt_down = read_u32(buf, 15);
+        session.nwk_s_key.copy_from_slice(&buf[19..35]);
+        session.app_s_key.copy_from_slice(&buf[35..51]);
+        session.rx1_dr_offset = buf[51];
+        session.rx2_data_rate = cmp::min(buf[52], eu868::MAX_DATA_RATE);
+        session.rx1_delay = buf[53];
+        for (i, channel) in session.channels.iter_mut().enumerate() {
+            *channel = read_u32(buf, 54 + 4 * i);
+        }
+        Some(session)
+    }
+}
+
+fn read_u32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    /// Reading the session from storage.
+    Loading,
+    Idle,
+    JoinRequestMic,
+    UplinkEncrypt,
+    UplinkMic,
+    /// Writing the session to storage before transmitting.
+    Persisting,
+    /// Waiting for the duty cycle to allow transmitting.
+    DutyCycleWait,
+    Transmitting,
+    WaitRx1,
+    Rx1,
+    WaitRx2,
+    Rx2,
+    JoinAcceptDecrypt,
+    JoinAcceptMic,
+    SessionKeys,
+    /// Writing the session of the network that was joined to storage.
+    SavingSession,
+    DownlinkMic,
+    DownlinkDecrypt,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Crypto {
+    Idle,
+    /// Encrypting the zero block to derive the CMAC subkeys.
+    CmacSubkey,
+    /// Running CBC over the masked message, padded to the given length.
+    Cmac(usize),
+    Ctr,
+    /// Encrypting the first block of a given number, one at a time.
+    Blocks(usize, usize),
+}
+
+pub struct LoRaWanMac<'a, R: lora::Radio<'a>, A: Alarm<'a>, E: AES128<'a> + AES128Ctr + AES128CBC> {
+    radio: &'a R,
+    alarm: &'a A,
+    aes: &'a E,
+    storage: &'a dyn NonvolatileStorage<'a>,
+    storage_address: usize,
+    client: OptionalCell<&'a dyn MacClient>,
+    state: Cell<State>,
+    crypto: Cell<Crypto>,
+    cmac_len: Cell<usize>,
+    mic: Cell<[u8; MIC_LEN]>,
+    session: Cell<Session>,
+    app_key: Cell<Key>,
+    data_rate: Cell<u8>,
+    channel: Cell<usize>,
+    joining: Cell<bool>,
+    confirmed: Cell<bool>,
+    acked: Cell<bool>,
+    /// Whether the last downlink was confirmed, and must be acknowledged by
+    /// the next uplink.
+    ack_pending: Cell<bool>,
+    /// Frame counter of the frame being sent or received.
+    fcnt: Cell<u32>,
+    frame_len: Cell<usize>,
+    second_window: Cell<bool>,
+    uplink: OptionalCell<Modulation>,
+    tx_end: Cell<A::Ticks>,
+    duty_cycle_start: Cell<A::Ticks>,
+    duty_cycle_wait: Cell<A::Ticks>,
+    frame: TakeCell<'static, [u8]>,
+    crypt_buf: TakeCell<'a, [u8]>,
+    storage_buf: TakeCell<'a, [u8]>,
+}
+
+impl<'a, R: lora::Radio<'a>, A: Alarm<'a>, E: AES128<'a> + AES128Ctr + AES128CBC>
+    LoRaWanMac<'a, R, A, E>
+{
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        aes: &'a E,
+        storage: &'a dyn NonvolatileStorage<'a>,
+        storage_address: usize,
+        frame: &'static mut [u8],
+        crypt_buf: &'a mut [u8],
+        storage_buf: &'a mut [u8],
+    ) -> LoRaWanMac<'a, R, A, E> {
+        LoRaWanMac {
+            radio: radio,
+            alarm: alarm,
+            aes: aes,
+            storage: storage,
+            storage_address: storage_address,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            crypto: Cell::new(Crypto::Idle),
+            cmac_len: Cell::new(0),
+            mic: Cell::new([0; MIC_LEN]),
+            session: Cell::new(Session::new()),
+            app_key: Cell::new([0; AES128_KEY_SIZE]),
+            data_rate: Cell::new(eu868::DEFAULT_DATA_RATE),
+            channel: Cell::new(0),
+            joining: Cell::new(false),
+            confirmed: Cell::new(false),
+            acked: Cell::new(false),
+            ack_pending: Cell::new(false),
+            fcnt: Cell::new(0),
+            frame_len: Cell::new(0),
+            second_window: Cell::new(false),
+            uplink: OptionalCell::empty(),
+            tx_end: Cell::new(A::Ticks::from(0)),
+            duty_cycle_start: Cell::new(A::Ticks::from(0)),
+            duty_cycle_wait: Cell::new(A::Ticks::from(0)),
+            frame: TakeCell::new(frame),
+            crypt_buf: TakeCell::new(crypt_buf),
+            storage_buf: TakeCell::new(storage_buf),
+        }
+    }
+
+    /// Enable the AES engine and restore the session from storage. Joins
+    /// and uplinks fail with `BUSY` until the session is read.
+    pub fn initialize(&self) {
+        self.aes.enable();
+        self.state.set(State::Loading);
+        let res = self
+            .storage_buf
+            .take()
+            .map_or(Err(ErrorCode::NOMEM), |buf| {
+                self.storage.read(buf, self.storage_address, RECORD_LEN)
+            });
+        if res.is_err() {
+            self.state.set(State::Idle);
+        }
+    }
+
+    fn finish(&self, result: Result<(), ErrorCode>) {
+        self.state.set(State::Idle);
+        self.client.map(|client| {
+            if self.joining.get() {
+                client.join_done(result);
+            } else {
+                client.send_done(result, self.acked.get());
+            }
+        });
+    }
+
+    fn check(&self, result: Result<(), ErrorCode>) {
+        if let Err(e) = result {
+            self.finish(Err(e));
+        }
+    }
+
+    /// Start an operation of the AES engine over the work buffer, once its
+    /// mode, key and IV are set.
+    fn crypt(&self, start: usize, stop: usize) -> Result<(), ErrorCode> {
+        self.crypt_buf.take().map_or(Err(ErrorCode::NOMEM), |buf| {
+            self.aes.start_message();
+            match self.aes.crypt(None, buf, start, stop) {
+                None => Ok(()),
+                Some((res, _, buf)) => {
+                    self.crypt_buf.replace(buf);
+                    res.and(Err(ErrorCode::FAIL))
+                }
+            }
+        })
+    }
+
+    /// Compute the CMAC with `key` of the `len` bytes at `CMAC_OFFSET` in the
+    /// work buffer, which must have room for padding.
+    fn start_cmac(&self, key: &Key, len: usize) -> Result<(), ErrorCode> {
+        self.crypt_buf
+            .map(|buf| buf[..CMAC_OFFSET].copy_from_slice(&ZERO_BLOCK));
+        self.cmac_len.set(len);
+        self.crypto.set(Crypto::CmacSubkey);
+        self.aes.set_mode_aes128cbc(true);
+        self.aes.set_key(key)?;
+        self.aes.set_iv(&ZERO_BLOCK)?;
+        self.crypt(0, AES128_BLOCK_SIZE)
+    }
+
+    /// Encrypt or decrypt the `len` bytes at the start of the work buffer
+    /// with CTR.
+    fn start_ctr(&self, key: &Key, iv: &[u8], len: usize) -> Result<(), ErrorCode> {
+        let padded = (len + AES128_BLOCK_SIZE - 1) / AES128_BLOCK_SIZE * AES128_BLOCK_SIZE;
+        self.crypt_buf.map(|buf| {
+            for byte in buf[len..padded].iter_mut() {
+                *byte = 0;
+            }
+        });
+        self.crypto.set(Crypto::Ctr);
+        self.aes.set_mode_aes128ctr(true);
+        self.aes.set_key(key)?;
+        self.aes.set_iv(iv)?;
+        self.crypt(0, padded)
+    }
+
+    /// Encrypt each of the `count` blocks at the start of the work buffer on
+    /// its own.
+    fn start_blocks(&self, key: &Key, count: usize) -> Result<(), ErrorCode> {
+        self.crypto.set(Crypto::Blocks(0, count));
+        self.aes.set_mode_aes128cbc(true);
+        self.aes.set_key(key)?;
+        self.aes.set_iv(&ZERO_BLOCK)?;
+        self.crypt(0, AES128_BLOCK_SIZE)
+    }
+
+    /// Mask the last block of the message being authenticated with a CMAC
+    /// subkey, padding it if it is incomplete, and start running CBC over it.
+    fn cmac_mask(&self) -> Result<(), ErrorCode> {
+        let len = self.cmac_len.get();
+        let padded = self.crypt_buf.map_or(0, |buf| {
+            let k1 = frame::cmac_subkey(&buf[..AES128_BLOCK_SIZE]);
+            let (subkey, padded) = if len > 0 && len % AES128_BLOCK_SIZE == 0 {
+                (k1, len)
+            } else {
+                let padded = (len / AES128_BLOCK_SIZE + 1) * AES128_BLOCK_SIZE;
+                buf[CMAC_OFFSET + len] = 0x80;
+                for byte in buf[CMAC_OFFSET + len + 1..CMAC_OFFSET + padded].iter_mut() {
+                    *byte = 0;
+                }
+                (frame::cmac_subkey(&k1), padded)
+            };
+            let last = CMAC_OFFSET + padded - AES128_BLOCK_SIZE;
+            for (byte, mask) in buf[last..last + AES128_BLOCK_SIZE]
+                .iter_mut()
+                .zip(subkey.iter())
+            {
+                *byte ^= mask;
+            }
+            padded
+        });
+        self.crypto.set(Crypto::Cmac(padded));
+        self.aes.set_iv(&ZERO_BLOCK)?;
+        self.crypt(CMAC_OFFSET, CMAC_OFFSET + padded)
+    }
+
+    /// Called once a cryptographic operation of the current state is done.
+    fn crypto_done(&self, result: Result<(), ErrorCode>) {
+        if let Err(e) = result {
+            self.finish(Err(e));
+            return;
+        }
+        match self.state.get() {
+            State::JoinRequestMic | State::UplinkMic => {
+                self.append_mic();
+                self.persist(State::Persisting);
+            }
+            State::UplinkEncrypt => {
+                let payload_len = self.frame_len.get() - (frame::FOPTS_OFFSET + 1);
+                self.frame.map(|frame| {
+                    self.crypt_buf.map(|buf| {
+                        frame[frame::FOPTS_OFFSET + 1..][..payload_len]
+                            .copy_from_slice(&buf[..payload_len])
+                    });
+                });
+                self.check(self.authenticate_uplink());
+            }
+            State::JoinAcceptDecrypt => {
+                let len = self.frame_len.get();
+                self.frame.map(|frame| {
+                    self.crypt_buf.map(|buf| {
+                        frame[1..len].copy_from_slice(&buf[..len - 1]);
+                        buf[CMAC_OFFSET..CMAC_OFFSET + len - MIC_LEN]
+                            .copy_from_slice(&frame[..len - MIC_LEN]);
+                    });
+                });
+                self.state.set(State::JoinAcceptMic);
+                if self.start_cmac(&self.app_key.get(), len - MIC_LEN).is_err() {
+                    self.window_failed();
+                }
+            }
+            State::JoinAcceptMic => {
+                if !self.mic_matches() {
+                    self.window_failed();
+                    return;
+                }
+                let dev_nonce = self.session.get().dev_nonce;
+                self.frame.map(|frame| {
+                    self.crypt_buf.map(|buf| {
+                        buf[..AES128_BLOCK_SIZE]
+                            .copy_from_slice(&frame::session_key_block(1, frame, dev_nonce));
+                        buf[AES128_BLOCK_SIZE..2 * AES128_BLOCK_SIZE]
+                            .copy_from_slice(&frame::session_key_block(2, frame, dev_nonce));
+                    });
+                });
+                self.state.set(State::SessionKeys);
+                self.check(self.start_blocks(&self.app_key.get(), 2));
+            }
+            State::SessionKeys => self.joined(),
+            State::DownlinkMic => {
+                if !self.mic_matches() {
+                    self.window_failed();
+                    return;
+                }
+                match self.downlink_payload() {
+                    Some((port, start, end)) => {
+                        let session = self.session.get();
+                        self.frame.map(|frame| {
+                            self.crypt_buf
+                                .map(|buf| buf[..end - start].copy_from_slice(&frame[start..end]))
+                        });
+                        let key = if port == 0 {
+                            session.nwk_s_key
+                        } else {
+                            session.app_s_key
+                        };
+                        let iv = frame::encryption_block(
+                            Direction::Downlink,
+                            session.dev_addr,
+                            self.fcnt.get(),
+                            1,
+                        );
+                        self.state.set(State::DownlinkDecrypt);
+                        self.check(self.start_ctr(&key, &iv, end - start));
+                    }
+                    None => self.downlink_done(),
+                }
+            }
+            State::DownlinkDecrypt => {
+                // Port 0 carries MAC commands, which are not supported.
+                if let Some((port, start, end)) = self.downlink_payload() {
+                    if port != 0 {
+                        self.crypt_buf.map(|buf| {
+                            self.client
+                                .map(|client| client.receive(port, &buf[..end - start]));
+                        });
+                    }
+                }
+                self.downlink_done();
+            }
+            _ => {}
+        }
+    }
+
+    fn append_mic(&self) {
+        let len = self.frame_len.get();
+        let mic = self.mic.get();
+        self.frame
+            .map(|frame| frame[len..len + MIC_LEN].copy_from_slice(&mic));
+        self.frame_len.set(len + MIC_LEN);
+    }
+
+    fn mic_matches(&self) -> bool {
+        let len = self.frame_len.get();
+        let mic = self.mic.get();
+        self.frame
+            .map_or(false, |frame| frame[len - MIC_LEN..len] == mic)
+    }
+
+    fn authenticate_uplink(&self) -> Result<(), ErrorCode> {
+        let len = self.frame_len.get();
+        let session = self.session.get();
+        let b0 = frame::mic_block(Direction::Uplink, session.dev_addr, self.fcnt.get(), len);
+        self.frame.map(|frame| {
+            self.crypt_buf.map(|buf| {
+                buf[CMAC_OFFSET..CMAC_OFFSET + AES128_BLOCK_SIZE].copy_from_slice(&b0);
+                buf[CMAC_OFFSET + AES128_BLOCK_SIZE..][..len].copy_from_slice(&frame[..len]);
+            });
+        });
+        self.state.set(State::UplinkMic);
+        self.start_cmac(&session.nwk_s_key, AES128_BLOCK_SIZE + len)
+    }
+
+    fn persist(&self, next: State) {
+        self.state.set(next);
+        let session = self.session.get();
+        let res = self
+            .storage_buf
+            .take()
+            .map_or(Err(ErrorCode::NOMEM), |buf| {
+                session.encode(buf);
+                self.storage.write(buf, self.storage_address, RECORD_LEN)
+            });
+        self.check(res);
+    }
+
+    fn transmit_when_allowed(&self) {
+        let start = self.duty_cycle_start.get();
+        let wait = self.duty_cycle_wait.get();
+        if self
+            .alarm
+            .now()
+            .within_range(start, start.wrapping_add(wait))
+        {
+            self.state.set(State::DutyCycleWait);
+            self.alarm.set_alarm(start, wait);
+        } else {
+            self.transmit();
+        }
+    }
+
+    fn transmit(&self) {
+        // Hop to the next channel in use.
+        let channels = self.session.get().channels;
+        let mut channel = self.channel.get();
+        for _ in 0..channels.len() {
+            channel = (channel + 1) % channels.len();
+            if channels[channel] != 0 {
+                break;
+            }
+        }
+        self.channel.set(channel);
+
+        let modulation = eu868::modulation(self.data_rate.get(), channels[channel], false);
+        self.uplink.set(modulation);
+        self.state.set(State::Transmitting);
+        let len = self.frame_len.get();
+        let res = self.frame.take().map_or(Err(ErrorCode::NOMEM), |frame| {
+            self.radio
+                .transmit(modulation, eu868::TX_POWER, frame, len)
+                .map_err(|(e, frame)| {
+                    self.frame.replace(frame);
+                    e
+                })
+        });
+        self.check(res);
+    }
+
+    /// Delay of the first receive window after the end of the uplink.
+    fn rx1_delay_ms(&self) -> u32 {
+        if self.joining.get() {
+            eu868::JOIN_ACCEPT_DELAY1_MS
+        } else {
+            match self.session.get().rx1_delay {
+                0 => eu868::RECEIVE_DELAY1_MS,
+                delay => delay as u32 * 1000,
+            }
+        }
+    }
+
+    fn open_window(&self, second: bool) {
+        let session = self.session.get();
+        let modulation = if second {
+            eu868::modulation(session.rx2_data_rate, eu868::RX2_FREQUENCY, true)
+        } else {
+            let frequency = self
+                .uplink
+                .map_or(eu868::DEFAULT_CHANNELS[0], |m| m.frequency);
+            let data_rate = self.data_rate.get().saturating_sub(session.rx1_dr_offset);
+            eu868::modulation(data_rate, frequency, true)
+        };
+        let timeout_ms =
+            2 * RX_MARGIN_MS + RX_PREAMBLE_SYMBOLS * modulation.symbol_time_us() / 1000;
+        self.second_window.set(second);
+        self.state.set(if second { State::Rx2 } else { State::Rx1 });
+        let res = self.frame.take().map_or(Err(ErrorCode::NOMEM), |frame| {
+            self.radio
+                .receive(modulation, frame, timeout_ms)
+                .map_err(|(e, frame)| {
+                    self.frame.replace(frame);
+                    e
+                })
+        });
+        if res.is_err() {
+            self.window_failed();
+        }
+    }
+
+    /// Nothing valid was received in the current window: wait for the next
+    /// one, or give up.
+    fn window_failed(&self) {
+        if !self.second_window.get() {
+            self.state.set(State::WaitRx2);
+            self.alarm.set_alarm(
+                self.tx_end.get(),
+                A::ticks_from_ms(self.rx1_delay_ms() + eu868::RX2_DELAY_MS - RX_MARGIN_MS),
+            );
+        } else if self.joining.get() || self.confirmed.get() {
+            self.finish(Err(ErrorCode::NOACK));
+        } else {
+            self.finish(Ok(()));
+        }
+    }
+
+    fn process_join_accept(&self) -> Result<(), ErrorCode> {
+        let len = self.frame_len.get();
+        if len != frame::JOIN_ACCEPT_LEN && len != frame::JOIN_ACCEPT_CFLIST_LEN {
+            return Err(ErrorCode::INVAL);
+        }
+        let valid = self.frame.map_or(false, |frame| {
+            self.crypt_buf
+                .map(|buf| buf[..len - 1].copy_from_slice(&frame[1..len]));
+            frame[0] & mtype::MASK == mtype::JOIN_ACCEPT
+        });
+        if !valid {
+            return Err(ErrorCode::INVAL);
+        }
+        // Join accepts are encrypted with AES decryption, so that devices
+        // decrypt them with AES encryption.
+        self.state.set(State::JoinAcceptDecrypt);
+        self.start_blocks(&self.app_key.get(), (len - 1) / AES128_BLOCK_SIZE)
+    }
+
+    /// Set up the session of the network that was joined, from the join
+    /// accept and the session keys in the work buffer.
+    fn joined(&self) {
+        let len = self.frame_len.get();
+        let mut session = self.session.get();
+        self.frame.map(|frame| {
+            session.dev_addr = read_u32(frame, 7);
+            session.rx1_dr_offset = (frame[11] >> 4) & 0x7;
+            session.rx2_data_rate = cmp::min(frame[11] & 0xf, eu868::MAX_DATA_RATE);
+            session.rx1_delay = frame[12] & 0xf;
+            if len == frame::JOIN_ACCEPT_CFLIST_LEN {
+                // Frequencies of the extra channels, in steps of 100 Hz.
+                for (i, channel) in session.channels[eu868::DEFAULT_CHANNELS.len()..]
+                    .iter_mut()
+                    .enumerate()
+                {
+                    let f = &frame[13 + 3 * i..16 + 3 * i];
+                    *channel = u32::from_le_bytes([f[0], f[1], f[2], 0]) * 100;
+                }
+            }
+        });
+        self.crypt_buf.map(|buf| {
+            session.nwk_s_key.copy_from_slice(&buf[..AES128_BLOCK_SIZE]);
+            session
+                .app_s_key
+                .copy_from_slice(&buf[AES128_BLOCK_SIZE..2 * AES128_BLOCK_SIZE]);
+        });
+        session.joined = true;
+        session.fcnt_up = 0;
+        session.fcnt_down = 0;
+        self.session.set(session);
+        self.ack_pending.set(false);
+        self.persist(State::SavingSession);
+    }
+
+    fn process_downlink(&self) -> Result<(), ErrorCode> {
+        let len = self.frame_len.get();
+        let session = self.session.get();
+        let fcnt = self
+            .frame
+            .map_or(None, |frame| {
+                if len < frame::FOPTS_OFFSET + MIC_LEN {
+                    return None;
+                }
+                let frame_type = frame[0] & mtype::MASK;
+                let fopts_len = (frame[5] & fctrl::FOPTS_LEN_MASK) as usize;
+                if (frame_type != mtype::UNCONFIRMED_DOWN && frame_type != mtype::CONFIRMED_DOWN)
+                    || read_u32(frame, 1) != session.dev_addr
+                    || frame::FOPTS_OFFSET + fopts_len + MIC_LEN > len
+                {
+                    return None;
+                }
+                Some(u16::from_le_bytes([frame[6], frame[7]]))
+            })
+            .ok_or(ErrorCode::INVAL)?;
+
+        // Only the low 16 bits of the frame counter are sent.
+        let expected = session.fcnt_down;
+        let mut fcnt = (expected & !0xffff) | fcnt as u32;
+        if fcnt < expected {
+            fcnt = fcnt.wrapping_add(0x10000);
+        }
+        if fcnt.wrapping_sub(expected) >= frame::MAX_FCNT_GAP {
+            return Err(ErrorCode::INVAL);
+        }
+        self.fcnt.set(fcnt);
+
+        let b0 = frame::mic_block(Direction::Downlink, session.dev_addr, fcnt, len - MIC_LEN);
+        self.frame.map(|frame| {
+            self.crypt_buf.map(|buf| {
+                buf[CMAC_OFFSET..CMAC_OFFSET + AES128_BLOCK_SIZE].copy_from_slice(&b0);
+                buf[CMAC_OFFSET + AES128_BLOCK_SIZE..][..len - MIC_LEN]
+                    .copy_from_slice(&frame[..len - MIC_LEN]);
+            });
+        });
+        self.state.set(State::DownlinkMic);
+        self.start_cmac(&session.nwk_s_key, AES128_BLOCK_SIZE + len - MIC_LEN)
+    }
+
+    /// The port of the downlink, and the bounds of its payload in the frame,
+    /// if it has any.
+    fn downlink_payload(&self) -> Option<(u8, usize, usize)> {
+        let len = self.frame_len.get();
+        self.frame.map_or(None, |frame| {
+            let port = frame::FOPTS_OFFSET + (frame[5] & fctrl::FOPTS_LEN_MASK) as usize;
+            if port + 1 > len - MIC_LEN {
+                None
+            } else {
+                Some((frame[port], port + 1, len - MIC_LEN))
+            }
+        })
+    }
+
+    fn downlink_done(&self) {
+        let mut session = self.session.get();
+        session.fcnt_down = self.fcnt.get().wrapping_add(1);
+        self.session.set(session);
+        let (frame_type, frame_ctrl) = self
+            .frame
+            .map_or((0, 0), |frame| (frame[0] & mtype::MASK, frame[5]));
+        self.ack_pending.set(frame_type == mtype::CONFIRMED_DOWN);
+        self.acked
+            .set(self.confirmed.get() && frame_ctrl & fctrl::ACK != 0);
+        if self.confirmed.get() && !self.acked.get() {
+            self.finish(Err(ErrorCode::NOACK));
+        } else {
+            self.finish(Ok(()));
+        }
+    }
+}
+
+impl<'a, R: lora::Radio<'a>, A: Alarm<'a>, E: AES128<'a> + AES128Ctr + AES128CBC> MacDevice<'a>
+    for LoRaWanMac<'a, R, A, E>
+{
+    fn set_client(&self, client: &'a dyn MacClient) {
+        self.client.set(client);
+    }
+
+    fn is_joined(&self) -> bool {
+        self.session.get().joined
+    }
+
+    fn dev_addr(&self) -> Option<u32> {
+        let session = self.session.get();
+        if session.joined {
+            Some(session.dev_addr)
+        } else {
+            None
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        self.state.get() != State::Idle
+    }
+
+    fn join(
+        &self,
+        dev_eui: [u8; 8],
+        join_eui: [u8; 8],
+        app_key: [u8; 16],
+    ) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle || self.frame.is_none() {
+            return Err(ErrorCode::BUSY);
+        }
+        // Start afresh, but never reuse a DevNonce.
+        let dev_nonce = self.session.get().dev_nonce.wrapping_add(1);
+        let mut session = Session::new();
+        session.dev_nonce = dev_nonce;
+        self.session.set(session);
+        self.app_key.set(app_key);
+        self.joining.set(true);
+        self.confirmed.set(false);
+        self.acked.set(false);
+        self.ack_pending.set(false);
+
+        let len = frame::JOIN_REQUEST_LEN - MIC_LEN;
+        self.frame.map(|frame| {
+            frame[0] = mtype::JOIN_REQUEST;
+            for i in 0..8 {
+                frame[1 + i] = join_eui[7 - i];
+                frame[9 + i] = dev_eui[7 - i];
+            }
+            frame[17..19].copy_from_slice(&dev_nonce.to_le_bytes());
+            self.crypt_buf
+                .map(|buf| buf[CMAC_OFFSET..CMAC_OFFSET + len].copy_from_slice(&frame[..len]));
+        });
+        self.frame_len.set(len);
+        self.state.set(State::JoinRequestMic);
+        let res = self.start_cmac(&app_key, len);
+        if res.is_err() {
+            self.state.set(State::Idle);
+        }
+        res
+    }
+
+    fn send(&self, port: u8, confirmed: bool, payload: &[u8]) -> Result<(), ErrorCode> {
+        let mut session = self.session.get();
+        if self.state.get() != State::Idle || self.frame.is_none() {
+            return Err(ErrorCode::BUSY);
+        } else if !session.joined {
+            return Err(ErrorCode::OFF);
+        } else if port == 0 || port > 223 {
+            return Err(ErrorCode::INVAL);
+        } else if payload.len() > self.max_payload_len() {
+            return Err(ErrorCode::SIZE);
+        }
+        let fcnt = session.fcnt_up;
+        session.fcnt_up = fcnt.wrapping_add(1);
+        self.session.set(session);
+        self.fcnt.set(fcnt);
+        self.joining.set(false);
+        self.confirmed.set(confirmed);
+        self.acked.set(false);
+        let frame_ctrl = if self.ack_pending.take() {
+            fctrl::ACK
+        } else {
+            0
+        };
+
+        self.frame.map(|frame| {
+            frame[0] = if confirmed {
+                mtype::CONFIRMED_UP
+            } else {
+                mtype::UNCONFIRMED_UP
+            };
+            frame[1..5].copy_from_slice(&session.dev_addr.to_le_bytes());
+            frame[5] = frame_ctrl;
+            frame[6..8].copy_from_slice(&(fcnt as u16).to_le_bytes());
+            frame[8] = port;
+        });
+        self.crypt_buf
+            .map(|buf| buf[..payload.len()].copy_from_slice(payload));
+        self.frame_len.set(frame::FOPTS_OFFSET + 1 + payload.len());
+
+        let res = if payload.is_empty() {
+            self.authenticate_uplink()
+        } else {
+            let iv = frame::encryption_block(Direction::Uplink, session.dev_addr, fcnt, 1);
+            self.state.set(State::UplinkEncrypt);
+            self.start_ctr(&session.app_s_key, &iv, payload.len())
+        };
+        if res.is_err() {
+            self.state.set(State::Idle);
+        }
+        res
+    }
+
+    fn data_rate(&self) -> u8 {
+        self.data_rate.get()
+    }
+
+    fn set_data_rate(&self, data_rate: u8) -> Result<(), ErrorCode> {
+        if data_rate > eu868::MAX_DATA_RATE {
+            return Err(ErrorCode::INVAL);
+        }
+        self.data_rate.set(data_rate);
+        Ok(())
+    }
+
+    fn max_payload_len(&self) -> usize {
+        eu868::MAX_PAYLOAD_LEN[self.data_rate.get() as usize]
+    }
+}
+
+impl<'a, R: lora::Radio<'a>, A: Alarm<'a>, E: AES128<'a> + AES128Ctr + AES128CBC>
+    symmetric_encryption::Client<'a> for LoRaWanMac<'a, R, A, E>
+{
+    fn crypt_done(&'a self, _source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+        self.crypt_buf.replace(dest);
+        let result = match self.crypto.get() {
+            Crypto::Idle => return,
+            Crypto::CmacSubkey => match self.cmac_mask() {
+                Ok(()) => return,
+                Err(e) => Err(e),
+            },
+            Crypto::Cmac(padded) => {
+                self.crypt_buf.map(|buf| {
+                    let mut mic = [0; MIC_LEN];
+                    mic.copy_from_slice(
+                        &buf[CMAC_OFFSET + padded - AES128_BLOCK_SIZE..][..MIC_LEN],
+                    );
+                    self.mic.set(mic);
+                });
+                Ok(())
+            }
+            Crypto::Ctr => Ok(()),
+            Crypto::Blocks(block, count) => {
+                if block + 1 < count {
+                    self.crypto.set(Crypto::Blocks(block + 1, count));
+                    let next = (block + 1) * AES128_BLOCK_SIZE;
+                    match self
+                        .aes
+                        .set_iv(&ZERO_BLOCK)
+                        .and_then(|()| self.crypt(next, next + AES128_BLOCK_SIZE))
+                    {
+                        Ok(()) => return,
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    Ok(())
+                }
+            }
+        };
+        self.crypto.set(Crypto::Idle);
+        self.crypto_done(result);
+    }
+}
+
+impl<'a, R: lora::Radio<'a>, A: Alarm<'a>, E: AES128<'a> + AES128Ctr + AES128CBC>
+    lora::TransmitClient for LoRaWanMac<'a, R, A, E>
+{
+    fn transmit_done(&self, buf: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.frame.replace(buf);
+        if let Err(e) = result {
+            self.finish(Err(e));
+            return;
+        }
+        let now = self.alarm.now();
+        self.tx_end.set(now);
+        let airtime_ms = self.uplink.map_or(0, |m| {
+            frame::time_on_air_us(m, self.frame_len.get()) / 1000 + 1
+        });
+        self.duty_cycle_start.set(now);
+        self.duty_cycle_wait
+            .set(A::ticks_from_ms(airtime_ms * (eu868::DUTY_CYCLE - 1)));
+        self.second_window.set(false);
+        self.state.set(State::WaitRx1);
+        self.alarm
+            .set_alarm(now, A::ticks_from_ms(self.rx1_delay_ms() - RX_MARGIN_MS));
+    }
+}
+
+impl<'a, R: lora::Radio<'a>, A: Alarm<'a>, E: AES128<'a> + AES128Ctr + AES128CBC>
+    lora::ReceiveClient for LoRaWanMac<'a, R, A, E>
+{
+    fn receive_done(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        _rssi: i16,
+        _snr: i8,
+        result: Result<(), ErrorCode>,
+    ) {
+        self.frame.replace(buf);
+        self.frame_len.set(len);
+        let res = result.and_then(|()| {
+            if self.joining.get() {
+                self.process_join_accept()
+            } else {
+                self.process_downlink()
+            }
+        });
+        if res.is_err() {
+            self.window_failed();
+        }
+    }
+}
+
+impl<'a, R: lora::Radio<'a>, A: Alarm<'a>, E: AES128<'a> + AES128Ctr + AES128CBC> time::AlarmClient
+    for LoRaWanMac<'a, R, A, E>
+{
+    fn alarm(&self) {
+        match self.state.get() {
+            State::DutyCycleWait => self.transmit(),
+            State::WaitRx1 => self.open_window(false),
+            State::WaitRx2 => self.open_window(true),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, R: lora::Radio<'a>, A: Alarm<'a>, E: AES128<'a> + AES128Ctr + AES128CBC>
+    NonvolatileStorageClient<'a> for LoRaWanMac<'a, R, A, E>
+{
+    fn read_done(&self, buffer: &'a mut [u8], _length: usize) {
+        if let Some(session) = Session::decode(buffer) {
+            self.session.set(session);
+        }
+        self.storage_buf.replace(buffer);
+        self.state.set(State::Idle);
+    }
+
+    fn write_done(&self, buffer: &'a mut [u8], _length: usize) {
+        self.storage_buf.replace(buffer);
+        match self.state.get() {
+            State::Persisting => self.transmit_when_allowed(),
+            State::SavingSession => self.finish(Ok(())),
+            _ => {}
+        }
+    }
+}
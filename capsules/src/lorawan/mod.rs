@@ -0,0 +1,9 @@
+pub mod device;
+pub mod driver;
+pub mod frame;
+pub mod mac;
+
+pub use self::device::{MacClient, MacDevice};
+pub use self::driver::LoRaWanDriver;
+pub use self::driver::DRIVER_NUM;
+pub use self::mac::LoRaWanMac;
@@ -0,0 +1,1236 @@
+//! Driver for the Microchip MCP2515 SPI CAN controller.
+//!
+//! <https://www.microchip.com/wwwproducts/en/MCP2515>
+//!
+//! The controller has three transmit buffers and two receive buffers.
+//! Frames are sent one at a time from the first transmit buffer, and
+//! received frames roll over from the first receive buffer into the second
+//! when it is full. It is driven over SPI, one command at a time. The
+//! controller pulls its INT pin low while it holds a received frame, has
+//! sent one, or has seen an error; the pin must be connected to an
+//! interrupt-capable GPIO pin.
+//!
+//! Each receive buffer has one acceptance mask, shared by its filters, so
+//! the driver offers two acceptance filters: the first one sets the mask and
+//! both filters of the first receive buffer, the second one the mask and the
+//! four filters of the second. The controller cannot turn a filter off, so a
+//! cleared filter accepts standard identifier 0 only, and the driver checks
+//! every received frame against the filters again before passing it on.
+//!
+//! Filters can only be written in configuration mode, so setting one while
+//! the controller is on the bus takes it off the bus briefly, once no frame
+//! is being sent. The controller rejoins the bus on its own after a bus-off,
+//! so the driver takes it off the bus until it is enabled again.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let mcp2515_spi = static_init!(
+//!     capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>,
+//!     capsules::virtual_spi::VirtualSpiMasterDevice::new(mux_spi, &nrf52840::gpio::PORT[CS]));
+//! let mcp2515 = static_init!(
+//!     capsules::mcp2515::Mcp2515<'static,
+//!         capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>>,
+//!     capsules::mcp2515::Mcp2515::new(
+//!         mcp2515_spi,
+//!         &nrf52840::gpio::PORT[INT],
+//!         8_000_000,
+//!         &mut capsules::mcp2515::SPI_BUF,
+//!         &mut capsules::mcp2515::SPI_READ_BUF,
+//!     ));
+//! mcp2515_spi.set_client(mcp2515);
+//! nrf52840::gpio::PORT[INT].set_client(mcp2515);
+//! mcp2515.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::can::{self, Filter, Id};
+use kernel::hil::gpio;
+use kernel::hil::spi;
+use kernel::ErrorCode;
+
+/// Number of registers of a transmit or receive buffer from its identifier
+/// to its last data byte: four identifier registers, the data length code
+/// and eight data bytes.
+const BUFFER_REGS_LEN: usize = 5 + can::MAX_DATA_LEN;
+
+/// Length of the SPI buffers: a command, and the registers of a transmit or
+/// receive buffer.
+pub const SPI_BUF_LEN: usize = 1 + BUFFER_REGS_LEN;
+
+pub static mut SPI_BUF: [u8; SPI_BUF_LEN] = [0; SPI_BUF_LEN];
+pub static mut SPI_READ_BUF: [u8; SPI_BUF_LEN] = [0; SPI_BUF_LEN];
+
+/// The fastest SPI clock the controller accepts.
+const SPI_SPEED: u32 = 10_000_000;
+
+/// Number of acceptance filters: one per receive buffer.
+const FILTER_COUNT: usize = 2;
+
+/// SPI commands.
+const RESET: u8 = 0xC0;
+const READ: u8 = 0x03;
+const WRITE: u8 = 0x02;
+const BIT_MODIFY: u8 = 0x05;
+/// Load transmit buffer 0 from its identifier.
+const LOAD_TX_BUFFER: u8 = 0x40;
+/// Request to send transmit buffer 0.
+const RTS_TXB0: u8 = 0x81;
+/// Read receive buffer 0 or 1 from its identifier, which also clears its
+/// interrupt flag.
+const READ_RX_BUFFER: [u8; 2] = [0x90, 0x94];
+
+mod reg {
+    pub const RXF0: u8 = 0x00;
+    pub const RXF1: u8 = 0x04;
+    pub const RXF2: u8 = 0x08;
+    pub const CANSTAT: u8 = 0x0E;
+    pub const CANCTRL: u8 = 0x0F;
+    pub const RXF3: u8 = 0x10;
+    pub const RXF4: u8 = 0x14;
+    pub const RXF5: u8 = 0x18;
+    pub const RXM0: u8 = 0x20;
+    pub const RXM1: u8 = 0x24;
+    pub const CNF3: u8 = 0x28;
+    pub const CNF2: u8 = 0x29;
+    pub const CNF1: u8 = 0x2A;
+    pub const CANINTE: u8 = 0x2B;
+    pub const CANINTF: u8 = 0x2C;
+    pub const EFLG: u8 = 0x2D;
+    pub const RXB0CTRL: u8 = 0x60;
+}
+
+mod bits {
+    // REQOP in CANCTRL, and OPMOD in CANSTAT
+    pub const MODE: u8 = 0b111 << 5;
+    pub const MODE_NORMAL: u8 = 0b000 << 5;
+    pub const MODE_CONFIG: u8 = 0b100 << 5;
+
+    // CANCTRL
+    pub const ABAT: u8 = 1 << 4;
+
+    // CANINTE and CANINTF
+    pub const ERRIF: u8 = 1 << 5;
+    pub const TX0IF: u8 = 1 << 2;
+    pub const RX1IF: u8 = 1 << 1;
+    pub const RX0IF: u8 = 1 << 0;
+
+    // EFLG
+    pub const TXBO: u8 = 1 << 5;
+
+    // RXB0CTRL
+    pub const BUKT: u8 = 1 << 2;
+
+    // CNF2: the length of the second phase segment is set in CNF3.
+    pub const BTLMODE: u8 = 1 << 7;
+
+    // Second identifier register
+    pub const SRR: u8 = 1 << 4;
+    pub const EXIDE: u8 = 1 << 3;
+
+    // Data length code register
+    pub const RTR: u8 = 1 << 6;
+    pub const DLC: u8 = 0x0F;
+}
+
+/// Split a bit of `bitrate` into time quanta of two oscillator periods
+/// times the prescaler: the synchronization quantum, the propagation and
+/// first phase segments, and the second phase segment after the sample
+/// point, which is placed at about 87.5% of the bit when there are enough
+/// quanta. Returns the values of CNF1, CNF2 and CNF3, or `None` if the
+/// bitrate cannot be reached exactly.
+fn bit_timing(oscillator: u32, bitrate: u32) -> Option<[u8; 3]> {
+    (8..=25u32).rev().find_map(|quanta| {
+        let ticks = bitrate.checked_mul(2 * quanta)?;
+        if ticks == 0 || oscillator % ticks != 0 {
+            return None;
+        }
+        let prescaler = oscillator / ticks;
+        let ps2 = cmp::max(2, (quanta + 4) / 8);
+        let prop = (quanta - 1 - ps2) / 2;
+        let ps1 = quanta - 1 - ps2 - prop;
+        if prescaler <= 64 && ps1 <= 8 && ps2 <= 8 {
+            Some([
+                (prescaler - 1) as u8,
+                bits::BTLMODE | ((ps1 - 1) << 3) as u8 | (prop - 1) as u8,
+                (ps2 - 1) as u8,
+            ])
+        } else {
+            None
+        }
+    })
+}
+
+/// The four identifier registers of `id`, laid out as in the transmit and
+/// receive buffers and the filters.
+fn id_registers(id: Id) -> [u8; 4] {
+    match id {
+        Id::Standard(id) => [(id >> 3) as u8, (id << 5) as u8, 0, 0],
+        Id::Extended(id) => [
+            (id >> 21) as u8,
+            ((id >> 13) & 0xE0) as u8 | bits::EXIDE | ((id >> 16) & 0x3) as u8,
+            (id >> 8) as u8,
+            id as u8,
+        ],
+    }
+}
+
+/// A command of a program.
+#[derive(Copy, Clone, PartialEq)]
+enum Op {
+    Reset,
+    Write(u8, u8),
+    /// Write the four registers of an identifier, a filter or a mask.
+    WriteId(u8, [u8; 4]),
+    /// Set the bits of a register selected by the first byte to those of
+    /// the second.
+    Modify(u8, u8, u8),
+    /// Read a register into one of the read values.
+    Read(u8, usize),
+    /// Read a register until its value masked by the first byte equals the
+    /// second.
+    Poll(u8, u8, u8),
+    /// Load the frame to send into transmit buffer 0.
+    LoadTx,
+    RequestToSend,
+    /// Read receive buffer 0 or 1 into the SPI read buffer.
+    ReadRx(usize),
+}
+
+/// The programs the driver runs.
+#[derive(Copy, Clone, PartialEq)]
+enum Program {
+    /// Reset and set up the controller, and join the bus.
+    Init,
+    Filter,
+    Transmit,
+    Disable,
+    /// Leave the bus after a bus-off.
+    BusOff,
+    /// Read why the controller interrupted.
+    Interrupt,
+    TransmitDone,
+    Receive(usize),
+    ErrorDone,
+}
+
+pub struct Mcp2515<'a, S: spi::SpiMasterDevice> {
+    spi: &'a S,
+    int_pin: &'a dyn gpio::InterruptPin<'a>,
+    /// Frequency of the oscillator of the controller.
+    oscillator: u32,
+    controller_client: OptionalCell<&'a dyn can::ControllerClient>,
+    transmit_client: OptionalCell<&'a dyn can::TransmitClient>,
+    receive_client: OptionalCell<&'a dyn can::ReceiveClient>,
+    spi_buf: TakeCell<'static, [u8]>,
+    spi_read_buf: TakeCell<'static, [u8]>,
+
+    /// The program running, and its next command.
+    program: OptionalCell<Program>,
+    step: Cell<usize>,
+    values: Cell<[u8; 2]>,
+
+    on: Cell<bool>,
+    /// CNF1, CNF2 and CNF3 for the bitrate.
+    timing: Cell<[u8; 3]>,
+    filters: [Cell<Option<Filter>>; FILTER_COUNT],
+
+    /// Work waiting for the running program to finish.
+    interrupt_pending: Cell<bool>,
+    filter_pending: Cell<bool>,
+    disable_pending: Cell<bool>,
+
+    /// The interrupt and error flags read when the controller interrupted.
+    flags: Cell<u8>,
+    errors: Cell<u8>,
+
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_id: Cell<Id>,
+    tx_len: Cell<usize>,
+    tx_started: Cell<bool>,
+}
+
+impl<'a, S: spi::SpiMasterDevice> Mcp2515<'a, S> {
+    pub fn new(
+        spi: &'a S,
+        int_pin: &'a dyn gpio::InterruptPin<'a>,
+        oscillator: u32,
+        spi_buf: &'static mut [u8],
+        spi_read_buf: &'static mut [u8],
+    ) -> Mcp2515<'a, S> {
+        Mcp2515 {
+            spi: spi,
+            int_pin: int_pin,
+            oscillator: oscillator,
+            controller_client: OptionalCell::empty(),
+            transmit_client: OptionalCell::empty(),
+            receive_client: OptionalCell::empty(),
+            spi_buf: TakeCell::new(spi_buf),
+            spi_read_buf: TakeCell::new(spi_read_buf),
+            program: OptionalCell::empty(),
+            step: Cell::new(0),
+            values: Cell::new([0; 2]),
+            on: Cell::new(false),
+            timing: Cell::new([0; 3]),
+            filters: [Cell::new(None), Cell::new(None)],
+            interrupt_pending: Cell::new(false),
+            filter_pending: Cell::new(false),
+            disable_pending: Cell::new(false),
+            flags: Cell::new(0),
+            errors: Cell::new(0),
+            tx_buffer: TakeCell::empty(),
+            tx_id: Cell::new(Id::Standard(0)),
+            tx_len: Cell::new(0),
+            tx_started: Cell::new(false),
+        }
+    }
+
+    /// Configure the SPI bus and the interrupt pin.
+    pub fn initialize(&self) {
+        self.spi.configure(
+            spi::ClockPolarity::IdleLow,
+            spi::ClockPhase::SampleLeading,
+            SPI_SPEED,
+        );
+        self.int_pin.make_input();
+        self.int_pin
+            .enable_interrupts(gpio::InterruptEdge::FallingEdge);
+    }
+
+    /// The identifier and mask registers of filter `index`.
+    fn filter_registers(&self, index: usize) -> ([u8; 4], [u8; 4]) {
+        let filter = self.filters[index].get().unwrap_or(Filter {
+            id: Id::Standard(0),
+            mask: can::STANDARD_ID_MAX,
+        });
+        // The filter only matches frames of its kind, whatever the mask.
+        let mask = match filter.id {
+            Id::Standard(_) => Id::Standard((filter.mask & can::STANDARD_ID_MAX) as u16),
+            Id::Extended(_) => Id::Extended(filter.mask & can::EXTENDED_ID_MAX),
+        };
+        let mut mask_registers = id_registers(mask);
+        mask_registers[1] &= !bits::EXIDE;
+        (id_registers(filter.id), mask_registers)
+    }
+
+    fn filter_ops(&self) -> [Op; 8] {
+        let (filter0, mask0) = self.filter_registers(0);
+        let (filter1, mask1) = self.filter_registers(1);
+        [
+            Op::WriteId(reg::RXM0, mask0),
+            Op::WriteId(reg::RXF0, filter0),
+            Op::WriteId(reg::RXF1, filter0),
+            Op::WriteId(reg::RXM1, mask1),
+            Op::WriteId(reg::RXF2, filter1),
+            Op::WriteId(reg::RXF3, filter1),
+            Op::WriteId(reg::RXF4, filter1),
+            Op::WriteId(reg::RXF5, filter1),
+        ]
+    }
+
+    fn init_ops(&self) -> [Op; 17] {
+        let timing = self.timing.get();
+        let f = self.filter_ops();
+        [
+            Op::Reset,
+            // The controller is in configuration mode once it is out of
+            // reset.
+            Op::Poll(reg::CANSTAT, bits::MODE, bits::MODE_CONFIG),
+            Op::Write(reg::CNF1, timing[0]),
+            Op::Write(reg::CNF2, timing[1]),
+            Op::Write(reg::CNF3, timing[2]),
+            f[0],
+            f[1],
+            f[2],
+            f[3],
+            f[4],
+            f[5],
+            f[6],
+            f[7],
+            Op::Write(reg::RXB0CTRL, bits::BUKT),
+            Op::Write(
+                reg::CANINTE,
+                bits::ERRIF | bits::TX0IF | bits::RX1IF | bits::RX0IF,
+            ),
+            Op::Modify(reg::CANCTRL, bits::MODE, bits::MODE_NORMAL),
+            Op::Poll(reg::CANSTAT, bits::MODE, bits::MODE_NORMAL),
+        ]
+    }
+
+    fn filter_program_ops(&self) -> [Op; 12] {
+        let f = self.filter_ops();
+        [
+            Op::Modify(reg::CANCTRL, bits::MODE, bits::MODE_CONFIG),
+            Op::Poll(reg::CANSTAT, bits::MODE, bits::MODE_CONFIG),
+            f[0],
+            f[1],
+            f[2],
+            f[3],
+            f[4],
+            f[5],
+            f[6],
+            f[7],
+            Op::Modify(reg::CANCTRL, bits::MODE, bits::MODE_NORMAL),
+            Op::Poll(reg::CANSTAT, bits::MODE, bits::MODE_NORMAL),
+        ]
+    }
+
+    fn op(&self, step: usize) -> Option<Op> {
+        let program = self.program.extract()?;
+        match program {
+            Program::Init => self.init_ops().get(step).copied(),
+            Program::Filter => self.filter_program_ops().get(step).copied(),
+            Program::Transmit => [Op::LoadTx, Op::RequestToSend].get(step).copied(),
+            // Configuration mode takes the controller off the bus, and
+            // aborting clears the request to send.
+            Program::Disable | Program::BusOff => [
+                Op::Write(reg::CANCTRL, bits::MODE_CONFIG | bits::ABAT),
+                Op::Write(reg::CANINTE, 0),
+                Op::Write(reg::CANINTF, 0),
+            ]
+            .get(step)
+            .copied(),
+            Program::Interrupt => [Op::Read(reg::CANINTF, 0), Op::Read(reg::EFLG, 1)]
+                .get(step)
+                .copied(),
+            Program::TransmitDone => [Op::Modify(reg::CANINTF, bits::TX0IF, 0)]
+                .get(step)
+                .copied(),
+            Program::Receive(buffer) => [Op::ReadRx(buffer)].get(step).copied(),
+            Program::ErrorDone => [Op::Modify(reg::CANINTF, bits::ERRIF, 0)]
+                .get(step)
+                .copied(),
+        }
+    }
+
+    fn start(&self, program: Program) {
+        self.program.set(program);
+        self.step.set(0);
+        self.run();
+    }
+
+    /// Send the next command of the program.
+    fn run(&self) {
+        let op = match self.op(self.step.get()) {
+            Some(op) => op,
+            None => return self.program_done(),
+        };
+
+        match op {
+            Op::Reset => self.transfer(|wbuf| {
+                wbuf[0] = RESET;
+                1
+            }),
+            Op::Write(address, value) => self.transfer(|wbuf| {
+                wbuf[0] = WRITE;
+                wbuf[1] = address;
+                wbuf[2] = value;
+                3
+            }),
+            Op::WriteId(address, registers) => self.transfer(|wbuf| {
+                wbuf[0] = WRITE;
+                wbuf[1] = address;
+                wbuf[2..6].copy_from_slice(&registers);
+                6
+            }),
+            Op::Modify(address, mask, value) => self.transfer(|wbuf| {
+                wbuf[0] = BIT_MODIFY;
+                wbuf[1] = address;
+                wbuf[2] = mask;
+                wbuf[3] = value;
+                4
+            }),
+            Op::Read(address, _) | Op::Poll(address, _, _) => self.transfer(|wbuf| {
+                wbuf[0] = READ;
+                wbuf[1] = address;
+                wbuf[2] = 0;
+                3
+            }),
+            Op::LoadTx => {
+                let id = self.tx_id.get();
+                let len = self.tx_len.get();
+                let tx_buffer = &self.tx_buffer;
+                self.transfer(|wbuf| {
+                    wbuf[0] = LOAD_TX_BUFFER;
+                    wbuf[1..5].copy_from_slice(&id_registers(id));
+                    wbuf[5] = len as u8;
+                    tx_buffer.map(|frame| wbuf[6..6 + len].copy_from_slice(&frame[..len]));
+                    6 + len
+                })
+            }
+            Op::RequestToSend => self.transfer(|wbuf| {
+                wbuf[0] = RTS_TXB0;
+                1
+            }),
+            Op::ReadRx(buffer) => self.transfer(|wbuf| {
+                wbuf[0] = READ_RX_BUFFER[buffer];
+                1 + BUFFER_REGS_LEN
+            }),
+        }
+    }
+
+    fn transfer<F: FnOnce(&mut [u8]) -> usize>(&self, fill: F) {
+        self.spi_buf.take().map(|wbuf| {
+            let len = fill(wbuf);
+            let _ = self
+                .spi
+                .read_write_bytes(wbuf, self.spi_read_buf.take(), len);
+        });
+    }
+
+    /// Handle the result of the command that was sent. Returns whether the
+    /// program moves on to its next command.
+    fn command_done(&self, op: Op, rbuf: &[u8]) -> bool {
+        match op {
+            Op::Read(_, index) => {
+                let mut values = self.values.get();
+                values[index] = rbuf[2];
+                self.values.set(values);
+                true
+            }
+            Op::Poll(_, mask, expected) => rbuf[2] & mask == expected,
+            _ => true,
+        }
+    }
+
+    fn program_done(&self) {
+        let program = match self.program.take() {
+            Some(program) => program,
+            None => return,
+        };
+        match program {
+            Program::Init => {
+                self.on.set(true);
+                self.idle();
+            }
+            Program::Filter | Program::Transmit => self.idle(),
+            Program::Disable => self.stopped(),
+            Program::BusOff => {
+                self.stopped();
+                self.controller_client.map(|client| client.bus_off());
+            }
+            Program::Interrupt => {
+                let values = self.values.get();
+                self.flags.set(values[0]);
+                self.errors.set(values[1]);
+                self.service();
+            }
+            Program::TransmitDone => {
+                self.tx_started.set(false);
+                self.tx_buffer.take().map(|buffer| {
+                    self.transmit_client
+                        .map(move |client| client.transmit_complete(Ok(()), buffer));
+                });
+                self.start(Program::Interrupt);
+            }
+            Program::Receive(_) => {
+                let mut registers = [0; BUFFER_REGS_LEN];
+                self.spi_read_buf
+                    .map(|rbuf| registers.copy_from_slice(&rbuf[1..=BUFFER_REGS_LEN]));
+                self.frame_received(&registers);
+                self.start(Program::Interrupt);
+            }
+            Program::ErrorDone => self.start(Program::Interrupt),
+        }
+    }
+
+    /// Handle the next cause of the interrupt. The interrupt flags are read
+    /// again after each one, until none is left.
+    fn service(&self) {
+        let flags = self.flags.get();
+        if flags & bits::RX0IF != 0 {
+            self.start(Program::Receive(0));
+        } else if flags & bits::RX1IF != 0 {
+            self.start(Program::Receive(1));
+        } else if flags & bits::TX0IF != 0 {
+            self.start(Program::TransmitDone);
+        } else if flags & bits::ERRIF != 0 {
+            if self.errors.get() & bits::TXBO != 0 {
+                self.start(Program::BusOff);
+            } else {
+                self.start(Program::ErrorDone);
+            }
+        } else {
+            self.idle();
+        }
+    }
+
+    /// Pass on a received frame that matches one of the filters.
+    fn frame_received(&self, registers: &[u8; BUFFER_REGS_LEN]) {
+        let sidh = registers[0] as u32;
+        let sidl = registers[1] as u32;
+        let dlc = registers[4];
+        let (id, remote) = if registers[1] & bits::EXIDE != 0 {
+            let id = sidh << 21
+                | (sidl >> 5) << 18
+                | (sidl & 0x3) << 16
+                | (registers[2] as u32) << 8
+                | registers[3] as u32;
+            (Id::Extended(id), dlc & bits::RTR != 0)
+        } else {
+            let id = sidh << 3 | sidl >> 5;
+            (Id::Standard(id as u16), registers[1] & bits::SRR != 0)
+        };
+        // Remote frames carry no data, and are not passed on.
+        if remote {
+            return;
+        }
+        let accepted = self
+            .filters
+            .iter()
+            .any(|filter| filter.get().map_or(false, |filter| filter.matches(id)));
+        if accepted {
+            let len = cmp::min((dlc & bits::DLC) as usize, can::MAX_DATA_LEN);
+            self.receive_client
+                .map(|client| client.frame_received(id, &registers[5..5 + len]));
+        }
+    }
+
+    /// The controller left the bus: return the frame that was not sent.
+    fn stopped(&self) {
+        self.on.set(false);
+        self.interrupt_pending.set(false);
+        self.filter_pending.set(false);
+        self.disable_pending.set(false);
+        self.tx_started.set(false);
+        self.tx_buffer.take().map(|buffer| {
+            self.transmit_client
+                .map(move |client| client.transmit_complete(Err(ErrorCode::FAIL), buffer));
+        });
+    }
+
+    /// Start the work that waited for the last program to finish.
+    fn idle(&self) {
+        if !self.on.get() || self.program.is_some() {
+            return;
+        }
+        if self.interrupt_pending.replace(false) {
+            self.start(Program::Interrupt);
+        } else if self.disable_pending.replace(false) {
+            self.start(Program::Disable);
+        } else if self.tx_buffer.is_none() && self.filter_pending.replace(false) {
+            self.start(Program::Filter);
+        } else if self.tx_buffer.is_some() && !self.tx_started.get() {
+            self.tx_started.set(true);
+            self.start(Program::Transmit);
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> can::Controller<'a> for Mcp2515<'a, S> {
+    fn set_controller_client(&self, client: &'a dyn can::ControllerClient) {
+        self.controller_client.set(client);
+    }
+
+    fn enable(&self, bitrate: u32) -> Result<(), ErrorCode> {
+        if self.on.get() || self.program.is_some() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let timing = bit_timing(self.oscillator, bitrate).ok_or(ErrorCode::INVAL)?;
+        self.timing.set(timing);
+        self.start(Program::Init);
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        if !self.on.get() || self.disable_pending.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.disable_pending.set(true);
+        self.idle();
+        Ok(())
+    }
+
+    fn filter_count(&self) -> usize {
+        FILTER_COUNT
+    }
+
+    fn set_filter(&self, index: usize, filter: Option<Filter>) -> Result<(), ErrorCode> {
+        if index >= FILTER_COUNT {
+            return Err(ErrorCode::INVAL);
+        }
+        self.filters[index].set(filter);
+        // The filters are written when the controller is set up, so they
+        // only need writing again once it is.
+        if self.on.get() {
+            self.filter_pending.set(true);
+            self.idle();
+        }
+        Ok(())
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> can::Transmit<'a> for Mcp2515<'a, S> {
+    fn set_transmit_client(&self, client: &'a dyn can::TransmitClient) {
+        self.transmit_client.set(client);
+    }
+
+    fn send(
+        &self,
+        id: Id,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.on.get() {
+            return Err((ErrorCode::OFF, buffer));
+        }
+        if self.tx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if len > can::MAX_DATA_LEN || len > buffer.len() {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+        self.tx_buffer.replace(buffer);
+        self.tx_id.set(id);
+        self.tx_len.set(len);
+        self.tx_started.set(false);
+        self.idle();
+        Ok(())
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> can::Receive<'a> for Mcp2515<'a, S> {
+    fn set_receive_client(&self, client: &'a dyn can::ReceiveClient) {
+        self.receive_client.set(client);
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> can::Can<'a> for Mcp2515<'a, S> {}
+
+impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for Mcp2515<'a, S> {
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        _len: usize,
+    ) {
+        self.spi_buf.replace(write_buffer);
+        read_buffer.map(|rbuf| self.spi_read_buf.replace(rbuf));
+
+        let step = self.step.get();
+        if let Some(op) = self.op(step) {
+            let next = self
+                .spi_read_buf
+                .map_or(true, |rbuf| self.command_done(op, rbuf));
+            if next {
+                self.step.set(step + 1);
+            }
+        }
+        self.run();
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> gpio::Client for Mcp2515<'a, S> {
+    fn fired(&self) {
+        if !self.on.get() {
+            return;
+        }
+        if self.program.is_some() {
+            self.interrupt_pending.set(true);
+        } else {
+            self.start(Program::Interrupt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{bit_timing, bits, reg, Mcp2515, BUFFER_REGS_LEN, SPI_BUF_LEN};
+    use crate::test::mock_process;
+    use core::cell::{Cell, RefCell};
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::can::{self, Controller, Filter, Id, Receive, Transmit};
+    use kernel::hil::gpio::{self, Client, Configuration, FloatingState, InterruptEdge};
+    use kernel::hil::spi::{ClockPhase, ClockPolarity, SpiMasterClient, SpiMasterDevice};
+    use kernel::ErrorCode;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const TXB0SIDH: usize = 0x31;
+    const RXB0SIDH: usize = 0x61;
+    const RXB1SIDH: usize = 0x71;
+    const TXREQ: u8 = 1 << 3;
+
+    /// A controller behind a bus: it runs each command on its registers as
+    /// the command is sent, and holds the buffers until the test completes
+    /// the transfer. Mode changes take effect at once.
+    struct MockSpi {
+        write: TakeCell<'static, [u8]>,
+        read: TakeCell<'static, [u8]>,
+        len: Cell<usize>,
+        registers: RefCell<[u8; 128]>,
+    }
+
+    impl MockSpi {
+        fn new() -> MockSpi {
+            MockSpi {
+                write: TakeCell::empty(),
+                read: TakeCell::empty(),
+                len: Cell::new(0),
+                registers: RefCell::new([0; 128]),
+            }
+        }
+
+        /// Finish transfers until the driver stops sending commands.
+        fn run(&self, client: &dyn SpiMasterClient) {
+            while let Some(write) = self.write.take() {
+                client.read_write_done(write, self.read.take(), self.len.get());
+            }
+        }
+
+        fn register(&self, address: u8) -> u8 {
+            self.registers.borrow()[address as usize]
+        }
+
+        fn registers(&self, address: u8, len: usize) -> Vec<u8> {
+            let start = address as usize;
+            self.registers.borrow()[start..start + len].to_vec()
+        }
+
+        fn set_flags(&self, flags: u8) {
+            let mut registers = self.registers.borrow_mut();
+            if registers[reg::CANINTE as usize] & flags != 0 {
+                registers[reg::CANINTF as usize] |= flags;
+            }
+        }
+
+        /// The identifier, length and data of the frame the driver asked to
+        /// send, which the controller then sends.
+        fn transmit(&self) -> Option<Vec<u8>> {
+            if self.registers.borrow()[TXB0SIDH - 1] & TXREQ == 0 {
+                return None;
+            }
+            let frame = self.registers(TXB0SIDH as u8, BUFFER_REGS_LEN);
+            self.registers.borrow_mut()[TXB0SIDH - 1] &= !TXREQ;
+            self.set_flags(bits::TX0IF);
+            Some(frame)
+        }
+
+        /// Put a frame into receive buffer 0 or 1.
+        fn receive(&self, buffer: usize, frame: &[u8]) {
+            let start = [RXB0SIDH, RXB1SIDH][buffer];
+            self.registers.borrow_mut()[start..start + frame.len()].copy_from_slice(frame);
+            self.set_flags([bits::RX0IF, bits::RX1IF][buffer]);
+        }
+
+        fn bus_off(&self) {
+            self.registers.borrow_mut()[reg::EFLG as usize] |= bits::TXBO;
+            self.set_flags(bits::ERRIF);
+        }
+
+        fn execute(&self, command: &[u8], response: &mut [u8]) {
+            let mut registers = self.registers.borrow_mut();
+            let address = command.get(1).copied().unwrap_or(0) as usize;
+            match command[0] {
+                super::RESET => {
+                    *registers = [0; 128];
+                    registers[reg::CANCTRL as usize] = 0x87;
+                }
+                super::READ => {
+                    for (i, byte) in response[2..].iter_mut().enumerate() {
+                        *byte = registers[address + i];
+                    }
+                }
+                super::WRITE => {
+                    for (i, byte) in command[2..].iter().enumerate() {
+                        registers[address + i] = *byte;
+                    }
+                }
+                super::BIT_MODIFY => {
+                    let (mask, value) = (command[2], command[3]);
+                    registers[address] = registers[address] & !mask | value & mask;
+                }
+                super::LOAD_TX_BUFFER => {
+                    registers[TXB0SIDH..TXB0SIDH + command.len() - 1]
+                        .copy_from_slice(&command[1..]);
+                }
+                super::RTS_TXB0 => registers[TXB0SIDH - 1] |= TXREQ,
+                0x90 | 0x94 => {
+                    let (start, flag) = if command[0] == 0x90 {
+                        (RXB0SIDH, bits::RX0IF)
+                    } else {
+                        (RXB1SIDH, bits::RX1IF)
+                    };
+                    let len = response.len() - 1;
+                    response[1..].copy_from_slice(&registers[start..start + len]);
+                    registers[reg::CANINTF as usize] &= !flag;
+                }
+                other => panic!("unknown command {:#x}", other),
+            }
+            let mode = registers[reg::CANCTRL as usize] & bits::MODE;
+            let status = registers[reg::CANSTAT as usize] & !bits::MODE;
+            registers[reg::CANSTAT as usize] = status | mode;
+        }
+    }
+
+    impl SpiMasterDevice for MockSpi {
+        fn configure(&self, _cpol: ClockPolarity, _cpal: ClockPhase, _rate: u32) {}
+
+        fn read_write_bytes(
+            &self,
+            write_buffer: &'static mut [u8],
+            mut read_buffer: Option<&'static mut [u8]>,
+            len: usize,
+        ) -> Result<(), ErrorCode> {
+            let mut response = [0; SPI_BUF_LEN];
+            self.execute(&write_buffer[..len], &mut response[..len]);
+            if let Some(rbuf) = read_buffer.as_mut() {
+                rbuf[..len].copy_from_slice(&response[..len]);
+            }
+            self.write.replace(write_buffer);
+            self.read.put(read_buffer);
+            self.len.set(len);
+            Ok(())
+        }
+
+        fn set_polarity(&self, _cpol: ClockPolarity) {}
+        fn set_phase(&self, _cpal: ClockPhase) {}
+        fn set_rate(&self, _rate: u32) {}
+
+        fn get_polarity(&self) -> ClockPolarity {
+            ClockPolarity::IdleLow
+        }
+        fn get_phase(&self) -> ClockPhase {
+            ClockPhase::SampleLeading
+        }
+        fn get_rate(&self) -> u32 {
+            0
+        }
+
+        fn hold_low(&self) {}
+        fn release_low(&self) {}
+    }
+
+    struct MockPin;
+
+    impl gpio::Configure for MockPin {
+        fn configuration(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn make_output(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn disable_output(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn make_input(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn disable_input(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn deactivate_to_low_power(&self) {}
+
+        fn set_floating_state(&self, _state: FloatingState) {}
+
+        fn floating_state(&self) -> FloatingState {
+            FloatingState::PullNone
+        }
+    }
+
+    impl gpio::Output for MockPin {
+        fn set(&self) {}
+
+        fn clear(&self) {}
+
+        fn toggle(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Input for MockPin {
+        fn read(&self) -> bool {
+            true
+        }
+    }
+
+    impl<'a> gpio::Interrupt<'a> for MockPin {
+        fn set_client(&self, _client: &'a dyn gpio::Client) {}
+
+        fn enable_interrupts(&self, _mode: InterruptEdge) {}
+
+        fn disable_interrupts(&self) {}
+
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Pin for MockPin {}
+    impl<'a> gpio::InterruptPin<'a> for MockPin {}
+
+    #[derive(Default)]
+    struct TestClient {
+        transmitted: RefCell<Vec<Result<(), ErrorCode>>>,
+        received: RefCell<Vec<(Id, Vec<u8>)>>,
+        bus_offs: Cell<usize>,
+    }
+
+    impl can::ControllerClient for TestClient {
+        fn bus_off(&self) {
+            self.bus_offs.set(self.bus_offs.get() + 1);
+        }
+    }
+
+    impl can::TransmitClient for TestClient {
+        fn transmit_complete(&self, status: Result<(), ErrorCode>, _buffer: &'static mut [u8]) {
+            self.transmitted.borrow_mut().push(status);
+        }
+    }
+
+    impl can::ReceiveClient for TestClient {
+        fn frame_received(&self, id: Id, data: &[u8]) {
+            self.received.borrow_mut().push((id, data.to_vec()));
+        }
+    }
+
+    type Driver = Mcp2515<'static, MockSpi>;
+
+    /// A controller with an 8 MHz oscillator, on the bus at 500 kbit/s
+    /// with `filters`.
+    fn setup(
+        filters: [Option<Filter>; 2],
+    ) -> (&'static Driver, &'static MockSpi, &'static TestClient) {
+        let spi = mock_process::leak(MockSpi::new());
+        let pin = mock_process::leak(MockPin);
+        let client = mock_process::leak(TestClient::default());
+        let mcp = mock_process::leak(Mcp2515::new(
+            spi,
+            pin,
+            8_000_000,
+            Box::leak(Box::new([0; SPI_BUF_LEN])),
+            Box::leak(Box::new([0; SPI_BUF_LEN])),
+        ));
+        mcp.set_controller_client(client);
+        mcp.set_transmit_client(client);
+        mcp.set_receive_client(client);
+        mcp.initialize();
+        for (index, filter) in filters.iter().enumerate() {
+            assert_eq!(mcp.set_filter(index, *filter), Ok(()));
+        }
+        assert_eq!(mcp.enable(500_000), Ok(()));
+        spi.run(mcp);
+        (mcp, spi, client)
+    }
+
+    fn accept_all() -> [Option<Filter>; 2] {
+        [
+            Some(Filter {
+                id: Id::Standard(0),
+                mask: 0,
+            }),
+            Some(Filter {
+                id: Id::Extended(0),
+                mask: 0,
+            }),
+        ]
+    }
+
+    fn buffer(data: &[u8]) -> &'static mut [u8] {
+        let buffer = Box::leak(Box::new([0; 8]));
+        buffer[..data.len()].copy_from_slice(data);
+        buffer
+    }
+
+    #[test]
+    fn test_bit_timing() {
+        // 16 quanta, sampled after 14 of them.
+        assert_eq!(bit_timing(8_000_000, 250_000), Some([0x00, 0xB5, 0x01]));
+        // 8 quanta, sampled after 6 of them.
+        assert_eq!(bit_timing(8_000_000, 500_000), Some([0x00, 0x91, 0x01]));
+        assert_eq!(bit_timing(8_000_000, 300_000), None);
+        assert_eq!(bit_timing(8_000_000, 0), None);
+    }
+
+    #[test]
+    fn test_enable_sets_up_controller() {
+        let (mcp, spi, _) = setup(accept_all());
+
+        assert_eq!(spi.register(reg::CANSTAT) & bits::MODE, bits::MODE_NORMAL);
+        assert_eq!(spi.registers(reg::CNF3, 3), [0x01, 0x91, 0x00]);
+        assert_eq!(spi.register(reg::RXB0CTRL), bits::BUKT);
+        assert_eq!(spi.registers(reg::RXM0, 4), [0, 0, 0, 0]);
+        assert_eq!(spi.registers(reg::RXF1, 4), [0, 0, 0, 0]);
+        assert_eq!(spi.registers(reg::RXM1, 4), [0, 0, 0, 0]);
+        assert_eq!(spi.registers(reg::RXF5, 4), [0, bits::EXIDE, 0, 0]);
+        assert_eq!(mcp.enable(500_000), Err(ErrorCode::ALREADY));
+    }
+
+    #[test]
+    fn test_enable_rejects_unreachable_bitrate() {
+        let spi = mock_process::leak(MockSpi::new());
+        let mcp = mock_process::leak(Mcp2515::new(
+            spi,
+            mock_process::leak(MockPin),
+            8_000_000,
+            Box::leak(Box::new([0; SPI_BUF_LEN])),
+            Box::leak(Box::new([0; SPI_BUF_LEN])),
+        ));
+
+        assert_eq!(mcp.enable(300_000), Err(ErrorCode::INVAL));
+        assert!(spi.write.is_none());
+    }
+
+    #[test]
+    fn test_send() {
+        let (mcp, spi, client) = setup(accept_all());
+
+        assert!(mcp
+            .send(Id::Extended(0x1234567), buffer(&[1, 2, 3]), 3)
+            .is_ok());
+        assert_eq!(
+            mcp.send(Id::Standard(1), buffer(&[]), 0)
+                .map_err(|(e, _)| e),
+            Err(ErrorCode::BUSY)
+        );
+        spi.run(mcp);
+
+        let frame = spi.transmit().expect("frame not sent");
+        assert_eq!(
+            frame[..8],
+            [0x09, 0x03 | bits::EXIDE, 0x45, 0x67, 3, 1, 2, 3]
+        );
+        assert!(client.transmitted.borrow().is_empty());
+
+        mcp.fired();
+        spi.run(mcp);
+        assert_eq!(*client.transmitted.borrow(), [Ok(())]);
+        assert_eq!(spi.register(reg::CANINTF), 0);
+
+        assert!(mcp.send(Id::Standard(0x123), buffer(&[9]), 1).is_ok());
+        spi.run(mcp);
+        let frame = spi.transmit().expect("frame not sent");
+        assert_eq!(frame[..6], [0x24, 0x60, 0, 0, 1, 9]);
+    }
+
+    #[test]
+    fn test_send_rejects_long_frame() {
+        let (mcp, _, _) = setup(accept_all());
+
+        let result = mcp.send(Id::Standard(1), Box::leak(Box::new([0; 9])), 9);
+        assert_eq!(result.map_err(|(e, _)| e), Err(ErrorCode::SIZE));
+    }
+
+    #[test]
+    fn test_receive_from_both_buffers() {
+        let (mcp, spi, client) = setup(accept_all());
+
+        spi.receive(0, &[0x24, 0x60, 0, 0, 2, 0xAA, 0xBB]);
+        spi.receive(1, &[0x09, 0x03 | bits::EXIDE, 0x45, 0x67, 1, 0xCC]);
+        mcp.fired();
+        spi.run(mcp);
+
+        assert_eq!(
+            *client.received.borrow(),
+            [
+                (Id::Standard(0x123), std::vec![0xAA, 0xBB]),
+                (Id::Extended(0x1234567), std::vec![0xCC]),
+            ]
+        );
+        assert_eq!(spi.register(reg::CANINTF), 0);
+    }
+
+    #[test]
+    fn test_receive_drops_frames_filters_reject() {
+        let (mcp, spi, client) = setup([
+            Some(Filter {
+                id: Id::Standard(0x100),
+                mask: 0x700,
+            }),
+            None,
+        ]);
+        // The cleared filter only accepts standard identifier 0.
+        assert_eq!(spi.registers(reg::RXM1, 4), [0xFF, 0xE0, 0, 0]);
+        assert_eq!(spi.registers(reg::RXF2, 4), [0, 0, 0, 0]);
+
+        // Standard identifiers 0x123, 0x223 and 0, and a remote frame.
+        for frame in &[
+            [0x24, 0x60, 0, 0, 1, 1],
+            [0x44, 0x60, 0, 0, 1, 2],
+            [0, 0, 0, 0, 1, 3],
+            [0x24, 0x60 | bits::SRR, 0, 0, 0, 0],
+        ] {
+            spi.receive(0, frame);
+            mcp.fired();
+            spi.run(mcp);
+        }
+
+        assert_eq!(
+            *client.received.borrow(),
+            [(Id::Standard(0x123), std::vec![1])]
+        );
+    }
+
+    #[test]
+    fn test_set_filter_waits_for_transmission() {
+        let (mcp, spi, _) = setup(accept_all());
+        assert!(mcp.send(Id::Standard(1), buffer(&[]), 0).is_ok());
+        spi.run(mcp);
+
+        let filter = Filter {
+            id: Id::Standard(0x7FF),
+            mask: can::STANDARD_ID_MAX,
+        };
+        assert_eq!(mcp.set_filter(0, Some(filter)), Ok(()));
+        spi.run(mcp);
+        assert_eq!(spi.registers(reg::RXM0, 4), [0, 0, 0, 0]);
+
+        assert!(spi.transmit().is_some());
+        mcp.fired();
+        spi.run(mcp);
+        assert_eq!(spi.registers(reg::RXM0, 4), [0xFF, 0xE0, 0, 0]);
+        assert_eq!(spi.registers(reg::RXF0, 4), [0xFF, 0xE0, 0, 0]);
+        assert_eq!(spi.register(reg::CANSTAT) & bits::MODE, bits::MODE_NORMAL);
+        assert_eq!(mcp.set_filter(2, None), Err(ErrorCode::INVAL));
+    }
+
+    #[test]
+    fn test_bus_off_leaves_bus() {
+        let (mcp, spi, client) = setup(accept_all());
+        assert!(mcp.send(Id::Standard(1), buffer(&[]), 0).is_ok());
+        spi.run(mcp);
+
+        spi.bus_off();
+        mcp.fired();
+        spi.run(mcp);
+
+        assert_eq!(client.bus_offs.get(), 1);
+        assert_eq!(*client.transmitted.borrow(), [Err(ErrorCode::FAIL)]);
+        assert_eq!(spi.register(reg::CANSTAT) & bits::MODE, bits::MODE_CONFIG);
+        assert_eq!(
+            mcp.send(Id::Standard(1), buffer(&[]), 0)
+                .map_err(|(e, _)| e),
+            Err(ErrorCode::OFF)
+        );
+        assert_eq!(mcp.disable(), Err(ErrorCode::ALREADY));
+
+        assert_eq!(mcp.enable(500_000), Ok(()));
+        spi.run(mcp);
+        assert_eq!(spi.register(reg::CANSTAT) & bits::MODE, bits::MODE_NORMAL);
+    }
+
+    #[test]
+    fn test_disable() {
+        let (mcp, spi, client) = setup(accept_all());
+
+        assert_eq!(mcp.disable(), Ok(()));
+        spi.run(mcp);
+
+        assert_eq!(spi.register(reg::CANSTAT) & bits::MODE, bits::MODE_CONFIG);
+        assert_eq!(spi.register(reg::CANINTE), 0);
+        assert_eq!(client.bus_offs.get(), 0);
+        assert_eq!(mcp.disable(), Err(ErrorCode::ALREADY));
+    }
+}
@@ -0,0 +1,610 @@
+//! Modbus RTU master.
+//!
+//! <https://modbus.org/docs/Modbus_over_serial_line_V1_02.pdf>
+//!
+//! Modbus RTU frames are sent over a UART, usually through an RS-485
+//! transceiver (see `rs485`). A frame holds the address of a slave, a
+//! function code, its data, and a CRC-16. Frames are delimited by silence:
+//! the master waits at least 3.5 character times after the previous frame
+//! before transmitting, which the capsule times with the alarm.
+//!
+//! The master transmits a request and waits for the response of the slave
+//! for up to `RESPONSE_TIMEOUT_MS`. A slave may answer with an exception
+//! instead, which is shorter than a normal response. The capsule therefore
+//! receives the first `EXCEPTION_LENGTH` bytes of a response, and then the
+//! rest of it if it is not an exception. Requests to the broadcast address
+//! `0` get no response; the capsule waits `TURNAROUND_MS` before the next
+//! request so slaves have time to process them.
+//!
+//! The UART must be configured by the board, and used by nothing else.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let modbus_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, stm32f412g::tim2::Tim2>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let modbus = static_init!(
+//!     capsules::modbus::ModbusMaster<
+//!         'static,
+//!         capsules::rs485::Rs485<'static, ...>,
+//!         VirtualMuxAlarm<'static, stm32f412g::tim2::Tim2>,
+//!     >,
+//!     capsules::modbus::ModbusMaster::new(
+//!         rs485,
+//!         modbus_alarm,
+//!         &mut capsules::modbus::TX_BUF,
+//!         &mut capsules::modbus::RX_BUF,
+//!         board_kernel.create_grant(&memory_allocation_capability)
+//!     )
+//! );
+//! hil::uart::Transmit::set_transmit_client(rs485, modbus);
+//! hil::uart::Receive::set_receive_client(rs485, modbus);
+//! modbus_alarm.set_alarm_client(modbus);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! The data of requests and responses is shared in the allowed buffer as it
+//! is on the wire: registers are big-endian, and coils are packed eight per
+//! byte, starting at the least significant bit.
+//!
+//! ### Allow
+//!
+//! - `0`: Values to write, and values read.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Request completed. The first argument is the status, the second
+//!   the number of bytes read into the buffer, and the third the exception
+//!   code the slave answered with, or `0`. The status is `NOACK` if the
+//!   slave did not respond, `FAIL` if it answered with an exception or a
+//!   corrupted frame, and `SIZE` if the buffer was too short for the values
+//!   read.
+//!
+//! ### Command
+//!
+//! For commands `1`-`6`, `arg1` holds the slave address in bits 0-7 and the
+//! first register or coil in bits 8-23.
+//!
+//! - `0`: Driver check.
+//! - `1`: Read `arg2` holding registers.
+//! - `2`: Write `arg2` to a single holding register.
+//! - `3`: Write `arg2` holding registers from the buffer.
+//! - `4`: Read `arg2` coils.
+//! - `5`: Set a single coil, off if `arg2` is `0` and on otherwise.
+//! - `6`: Write `arg2` coils from the buffer.
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::hil::uart;
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadWrite, ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::ModbusRtu as usize;
+
+/// The longest Modbus RTU frame.
+pub const MAX_FRAME_LENGTH: usize = 256;
+
+pub static mut TX_BUF: [u8; MAX_FRAME_LENGTH] = [0; MAX_FRAME_LENGTH];
+pub static mut RX_BUF: [u8; MAX_FRAME_LENGTH] = [0; MAX_FRAME_LENGTH];
+
+/// Time a slave has to start responding.
+pub const RESPONSE_TIMEOUT_MS: u32 = 1000;
+
+/// Time slaves have to process a broadcast request.
+pub const TURNAROUND_MS: u32 = 100;
+
+/// The length of an exception response, which no other response is
+/// shorter than.
+pub const EXCEPTION_LENGTH: usize = 5;
+
+/// The address requests are broadcast to.
+pub const BROADCAST_ADDRESS: u8 = 0;
+
+// Function codes.
+const READ_COILS: u8 = 0x01;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+/// Set in the function code of exception responses.
+const EXCEPTION_FLAG: u8 = 0x80;
+
+/// The Modbus CRC-16 (polynomial 0xA001 reflected, initial value 0xFFFF).
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for the bus to be silent before transmitting.
+    Silence,
+    Transmitting,
+    /// Waiting for the first `EXCEPTION_LENGTH` bytes of the response.
+    ReceivingHead,
+    /// Waiting for the rest of the response.
+    ReceivingRest,
+    /// Waiting for slaves to process a broadcast.
+    Turnaround,
+}
+
+#[derive(Copy, Clone)]
+struct Request {
+    app_id: ProcessId,
+    slave: u8,
+    function: u8,
+    /// The length of the request frame.
+    length: usize,
+    /// The length of a normal response.
+    response_length: usize,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    buffer: ReadWriteAppSlice,
+}
+
+pub struct ModbusMaster<'a, U: uart::Uart<'a>, A: Alarm<'a>> {
+    uart: &'a U,
+    alarm: &'a A,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    state: Cell<State>,
+    request: OptionalCell<Request>,
+    /// The first bytes of the response, while the rest is received.
+    head: Cell<[u8; EXCEPTION_LENGTH]>,
+    /// When the last frame on the bus ended.
+    last_frame: OptionalCell<A::Ticks>,
+    baud_rate: Cell<u32>,
+}
+
+impl<'a, U: uart::Uart<'a>, A: Alarm<'a>> ModbusMaster<'a, U, A> {
+    pub fn new(
+        uart: &'a U,
+        alarm: &'a A,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> ModbusMaster<'a, U, A> {
+        ModbusMaster {
+            uart: uart,
+            alarm: alarm,
+            tx_buffer: TakeCell::new(tx_buffer),
+            rx_buffer: TakeCell::new(rx_buffer),
+            apps: grant,
+            state: Cell::new(State::Idle),
+            request: OptionalCell::empty(),
+            head: Cell::new([0; EXCEPTION_LENGTH]),
+            last_frame: OptionalCell::empty(),
+            baud_rate: Cell::new(19200),
+        }
+    }
+
+    /// Set the baud rate the board configured the UART with, which the
+    /// silent interval between frames depends on.
+    pub fn set_baud_rate(&self, baud_rate: u32) {
+        self.baud_rate.set(baud_rate);
+    }
+
+    /// The silent interval between frames: 3.5 characters of 11 bits, fixed
+    /// at 1750 us above 19200 baud.
+    fn silent_interval_us(&self) -> u32 {
+        let baud_rate = self.baud_rate.get();
+        if baud_rate > 19200 {
+            1750
+        } else {
+            (35 * 11 * 100_000 + baud_rate - 1) / baud_rate
+        }
+    }
+
+    /// Build the request frame for command `command` in `frame`. Returns the
+    /// request, without its CRC.
+    fn build(
+        &self,
+        app: &mut App,
+        frame: &mut [u8],
+        command: usize,
+        arg1: usize,
+        arg2: usize,
+    ) -> Result<(u8, usize, usize), ErrorCode> {
+        let quantity = arg2;
+        frame[0] = arg1 as u8;
+        frame[2..4].copy_from_slice(&((arg1 >> 8) as u16).to_be_bytes());
+        match command {
+            1 | 4 => {
+                let (function, max, data_length) = if command == 1 {
+                    (READ_HOLDING_REGISTERS, 125, quantity * 2)
+                } else {
+                    (READ_COILS, 2000, (quantity + 7) / 8)
+                };
+                if quantity == 0 || quantity > max {
+                    return Err(ErrorCode::INVAL);
+                }
+                frame[4..6].copy_from_slice(&(quantity as u16).to_be_bytes());
+                Ok((function, 6, 3 + data_length + 2))
+            }
+            2 | 5 => {
+                let (function, value) = if command == 2 {
+                    (WRITE_SINGLE_REGISTER, arg2 as u16)
+                } else if arg2 == 0 {
+                    (WRITE_SINGLE_COIL, 0x0000)
+                } else {
+                    (WRITE_SINGLE_COIL, 0xFF00)
+                };
+                frame[4..6].copy_from_slice(&value.to_be_bytes());
+                // The slave echoes the request.
+                Ok((function, 6, 8))
+            }
+            3 | 6 => {
+                let (function, max, data_length) = if command == 3 {
+                    (WRITE_MULTIPLE_REGISTERS, 123, quantity * 2)
+                } else {
+                    (WRITE_MULTIPLE_COILS, 1968, (quantity + 7) / 8)
+                };
+                if quantity == 0 || quantity > max {
+                    return Err(ErrorCode::INVAL);
+                }
+                app.buffer.map_or(Err(ErrorCode::RESERVE), |values| {
+                    if values.len() < data_length {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    frame[4..6].copy_from_slice(&(quantity as u16).to_be_bytes());
+                    frame[6] = data_length as u8;
+                    frame[7..(7 + data_length)].copy_from_slice(&values[..data_length]);
+                    Ok((function, 7 + data_length, 8))
+                })
+            }
+            _ => Err(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    /// Start the request for command `command`.
+    fn start(&self, app_id: ProcessId, command: usize, arg1: usize, arg2: usize) -> CommandReturn {
+        if self.state.get() != State::Idle {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+        let res = self.tx_buffer.map_or(Err(ErrorCode::BUSY), |frame| {
+            self.apps
+                .enter(app_id, |app| {
+                    let (function, length, response_length) =
+                        self.build(app, frame, command, arg1, arg2)?;
+                    frame[1] = function;
+                    let crc = crc16(&frame[..length]);
+                    frame[length..(length + 2)].copy_from_slice(&crc.to_le_bytes());
+                    Ok(Request {
+                        app_id: app_id,
+                        slave: frame[0],
+                        function: function,
+                        length: length + 2,
+                        response_length: response_length,
+                    })
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        });
+
+        match res {
+            Ok(request) => {
+                self.request.set(request);
+                self.wait_for_silence();
+                CommandReturn::success()
+            }
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+
+    /// Transmit the request once the bus has been silent for long enough.
+    fn wait_for_silence(&self) {
+        let now = self.alarm.now();
+        let interval = A::ticks_from_us(self.silent_interval_us());
+        let elapsed = self
+            .last_frame
+            .map_or(interval, |last_frame| now.wrapping_sub(*last_frame));
+        if elapsed >= interval {
+            self.transmit();
+        } else {
+            self.state.set(State::Silence);
+            self.alarm.set_alarm(now, interval.wrapping_sub(elapsed));
+        }
+    }
+
+    fn transmit(&self) {
+        let request = match self.request.extract() {
+            Some(request) => request,
+            None => return,
+        };
+        // Listen before transmitting, so that no byte of a quick response
+        // is missed.
+        if request.slave != BROADCAST_ADDRESS {
+            if let Err(e) = self.receive(EXCEPTION_LENGTH) {
+                self.complete(Err(e), 0, 0);
+                return;
+            }
+        }
+        self.state.set(State::Transmitting);
+        let res = self.tx_buffer.take().map_or(Err(ErrorCode::FAIL), |frame| {
+            self.uart
+                .transmit_buffer(frame, request.length)
+                .map_err(|(e, frame)| {
+                    self.tx_buffer.replace(frame);
+                    e
+                })
+        });
+        if let Err(e) = res {
+            if request.slave != BROADCAST_ADDRESS {
+                self.state.set(State::Idle);
+                let _ = self.uart.receive_abort();
+            }
+            self.complete(Err(e), 0, 0);
+        }
+    }
+
+    fn receive(&self, len: usize) -> Result<(), ErrorCode> {
+        self.rx_buffer
+            .take()
+            .map_or(Err(ErrorCode::FAIL), |buffer| {
+                self.uart
+                    .receive_buffer(buffer, len)
+                    .map_err(|(e, buffer)| {
+                        self.rx_buffer.replace(buffer);
+                        e
+                    })
+            })
+    }
+
+    /// Check the response to `request`, and copy the values read to the
+    /// process. Returns the result, the number of bytes copied, and the
+    /// exception code.
+    fn check_response(
+        &self,
+        request: Request,
+        response: &[u8],
+    ) -> (Result<(), ErrorCode>, usize, usize) {
+        let (data, crc) = response.split_at(response.len() - 2);
+        if crc16(data) != u16::from_le_bytes([crc[0], crc[1]])
+            || data[0] != request.slave
+            || data[1] & !EXCEPTION_FLAG != request.function
+        {
+            return (Err(ErrorCode::FAIL), 0, 0);
+        }
+        if data[1] & EXCEPTION_FLAG == EXCEPTION_FLAG {
+            return (Err(ErrorCode::FAIL), 0, data[2] as usize);
+        }
+        match request.function {
+            READ_HOLDING_REGISTERS | READ_COILS => {
+                let count = data[2] as usize;
+                if count + 3 != data.len() {
+                    return (Err(ErrorCode::FAIL), 0, 0);
+                }
+                self.apps
+                    .enter(request.app_id, |app| {
+                        app.buffer
+                            .mut_map_or((Err(ErrorCode::RESERVE), 0, 0), |values| {
+                                let len = cmp::min(count, values.len());
+                                values[..len].copy_from_slice(&data[3..(3 + len)]);
+                                if len < count {
+                                    (Err(ErrorCode::SIZE), len, 0)
+                                } else {
+                                    (Ok(()), len, 0)
+                                }
+                            })
+                    })
+                    .unwrap_or_else(|err| (Err(err.into()), 0, 0))
+            }
+            _ => (Ok(()), 0, 0),
+        }
+    }
+
+    /// Finish the request, and tell the process.
+    fn complete(&self, result: Result<(), ErrorCode>, len: usize, exception: usize) {
+        self.last_frame.set(self.alarm.now());
+        self.state.set(State::Idle);
+        self.request.take().map(|request| {
+            let _ = self.apps.enter(request.app_id, |app| {
+                app.callback
+                    .schedule(kernel::into_statuscode(result), len, exception);
+            });
+        });
+    }
+}
+
+impl<'a, U: uart::Uart<'a>, A: Alarm<'a>> time::AlarmClient for ModbusMaster<'a, U, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Silence => self.transmit(),
+            State::Turnaround => self.complete(Ok(()), 0, 0),
+            // The slave did not respond in time. If the reception could not
+            // be cancelled synchronously, its callback completes the request.
+            State::ReceivingHead | State::ReceivingRest => {
+                if self.uart.receive_abort() == Ok(()) {
+                    self.complete(Err(ErrorCode::NOACK), 0, 0);
+                }
+            }
+            State::Idle | State::Transmitting => {}
+        }
+    }
+}
+
+impl<'a, U: uart::Uart<'a>, A: Alarm<'a>> uart::TransmitClient for ModbusMaster<'a, U, A> {
+    fn transmitted_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        _tx_len: usize,
+        rval: Result<(), ErrorCode>,
+    ) {
+        self.tx_buffer.replace(tx_buffer);
+        let broadcast = self
+            .request
+            .map_or(false, |request| request.slave == BROADCAST_ADDRESS);
+        if let Err(e) = rval {
+            if !broadcast {
+                self.state.set(State::Idle);
+                let _ = self.uart.receive_abort();
+            }
+            self.complete(Err(e), 0, 0);
+        } else if self.state.get() != State::Idle {
+            self.last_frame.set(self.alarm.now());
+            let timeout = if broadcast {
+                self.state.set(State::Turnaround);
+                TURNAROUND_MS
+            } else {
+                // A quick slave may already have sent the head of its
+                // response.
+                if self.state.get() == State::Transmitting {
+                    self.state.set(State::ReceivingHead);
+                }
+                RESPONSE_TIMEOUT_MS
+            };
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(timeout));
+        }
+    }
+}
+
+impl<'a, U: uart::Uart<'a>, A: Alarm<'a>> uart::ReceiveClient for ModbusMaster<'a, U, A> {
+    fn received_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: uart::Error,
+    ) {
+        let state = self.state.get();
+        let request = match self.request.extract() {
+            Some(request) if state != State::Idle => request,
+            _ => {
+                self.rx_buffer.replace(rx_buffer);
+                return;
+            }
+        };
+
+        if rval.is_err() {
+            self.rx_buffer.replace(rx_buffer);
+            let _ = self.alarm.disarm();
+            // A slave that never started responding is missing, while a
+            // partial response is corrupted.
+            let e = if state != State::ReceivingRest && rx_len == 0 {
+                ErrorCode::NOACK
+            } else {
+                ErrorCode::FAIL
+            };
+            self.complete(Err(e), 0, 0);
+            return;
+        }
+
+        let response_length = if state != State::ReceivingRest {
+            if rx_buffer[1] & EXCEPTION_FLAG == EXCEPTION_FLAG {
+                EXCEPTION_LENGTH
+            } else {
+                // Keep the head aside and receive the rest of the response.
+                let mut head = [0; EXCEPTION_LENGTH];
+                head.copy_from_slice(&rx_buffer[..EXCEPTION_LENGTH]);
+                self.head.set(head);
+                self.rx_buffer.replace(rx_buffer);
+                self.state.set(State::ReceivingRest);
+                if let Err(e) = self.receive(request.response_length - EXCEPTION_LENGTH) {
+                    let _ = self.alarm.disarm();
+                    self.complete(Err(e), 0, 0);
+                }
+                return;
+            }
+        } else {
+            let rest = request.response_length - EXCEPTION_LENGTH;
+            rx_buffer.copy_within(..rest, EXCEPTION_LENGTH);
+            rx_buffer[..EXCEPTION_LENGTH].copy_from_slice(&self.head.get());
+            request.response_length
+        };
+
+        let _ = self.alarm.disarm();
+        let (result, len, exception) = self.check_response(request, &rx_buffer[..response_length]);
+        self.rx_buffer.replace(rx_buffer);
+        self.complete(result, len, exception);
+    }
+}
+
+impl<'a, U: uart::Uart<'a>, A: Alarm<'a>> Driver for ModbusMaster<'a, U, A> {
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        arg2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+            1..=6 => self.start(appid, command_num, arg1, arg2),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
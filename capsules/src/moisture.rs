@@ -0,0 +1,275 @@
+//! Provides userspace with readings from an analog soil-moisture sensor.
+//!
+//! The sensor is read through an ADC channel. Each app calibrates the sensor
+//! by recording the raw ADC value with the probe in dry soil (or air) and in
+//! wet soil (or water). Readings are then linearly mapped between the two
+//! references to a moisture percentage, where the dry reference is 0% and the
+//! wet reference is 100%. Readings beyond either reference are clamped.
+//! Either reference may be the larger one, so both resistive sensors (whose
+//! value rises with moisture) and capacitive sensors (whose value falls with
+//! moisture) are supported.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let moisture = static_init!(
+//!     capsules::moisture::MoistureSensor<'static>,
+//!     capsules::moisture::MoistureSensor::new(
+//!         adc_channel,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! adc_channel.set_client(moisture);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: Reading upcall. Called with the moisture in percent (0-100) and
+//!   the raw ADC value.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Set the dry reference to the raw ADC value in `data1`.
+//! - `2`: Set the wet reference to the raw ADC value in `data1`.
+//! - `3`: Read the moisture. Returns `RESERVE` if the app has not set both
+//!   references.
+//! - `4`: Read the raw ADC value, for calibration. Delivered through the
+//!   reading upcall with a moisture of 0.
+
+use core::cell::Cell;
+use core::mem;
+use kernel::hil::adc;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Moisture as usize;
+
+/// Map a raw ADC reading to a moisture percentage between the `dry` (0%) and
+/// `wet` (100%) references, clamping readings beyond either reference.
+pub fn moisture_percent(raw: u16, dry: u16, wet: u16) -> u8 {
+    if dry == wet {
+        return 0;
+    }
+    let (raw, dry, wet) = (raw as i32, dry as i32, wet as i32);
+    let span = wet - dry;
+    let offset = raw - dry;
+    // Round to the nearest percent.
+    let percent = (offset * 100 + span / 2) / span;
+    if percent < 0 {
+        0
+    } else if percent > 100 {
+        100
+    } else {
+        percent as u8
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Request {
+    None,
+    Moisture,
+    Raw,
+}
+
+impl Default for Request {
+    fn default() -> Request {
+        Request::None
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    dry: Option<u16>,
+    wet: Option<u16>,
+    request: Request,
+}
+
+pub struct MoistureSensor<'a> {
+    adc: &'a dyn adc::AdcChannel,
+    apps: Grant<App>,
+    busy: Cell<bool>,
+}
+
+impl<'a> MoistureSensor<'a> {
+    pub fn new(adc: &'a dyn adc::AdcChannel, grant: Grant<App>) -> MoistureSensor<'a> {
+        MoistureSensor {
+            adc: adc,
+            apps: grant,
+            busy: Cell::new(false),
+        }
+    }
+
+    fn read(&self, request: Request, appid: ProcessId) -> CommandReturn {
+        let res = self
+            .apps
+            .enter(appid, |app| {
+                if app.request != Request::None {
+                    return Err(ErrorCode::BUSY);
+                }
+                if request == Request::Moisture && (app.dry.is_none() || app.wet.is_none()) {
+                    return Err(ErrorCode::RESERVE);
+                }
+                app.request = request;
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        if let Err(e) = res {
+            return CommandReturn::failure(e);
+        }
+
+        // Apps waiting for a sample already in progress share it.
+        if !self.busy.get() {
+            if let Err(e) = self.adc.sample() {
+                let _ = self.apps.enter(appid, |app| app.request = Request::None);
+                return CommandReturn::failure(e);
+            }
+            self.busy.set(true);
+        }
+        CommandReturn::success()
+    }
+
+    fn set_reference(&self, wet: bool, raw: usize, appid: ProcessId) -> CommandReturn {
+        if raw > u16::MAX as usize {
+            return CommandReturn::failure(ErrorCode::INVAL);
+        }
+        self.apps
+            .enter(appid, |app| {
+                let other = if wet { app.dry } else { app.wet };
+                if other == Some(raw as u16) {
+                    // The references must differ to map between them.
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                if wet {
+                    app.wet = Some(raw as u16);
+                } else {
+                    app.dry = Some(raw as u16);
+                }
+                CommandReturn::success()
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl adc::Client for MoistureSensor<'_> {
+    fn sample_ready(&self, sample: u16) {
+        self.busy.set(false);
+        self.apps.each(|_, app| {
+            let percent = match app.request {
+                Request::None => return,
+                Request::Raw => 0,
+                Request::Moisture => match (app.dry, app.wet) {
+                    (Some(dry), Some(wet)) => moisture_percent(sample, dry, wet),
+                    _ => 0,
+                },
+            };
+            app.request = Request::None;
+            app.callback.schedule(percent as usize, sample as usize, 0);
+        });
+    }
+}
+
+impl Driver for MoistureSensor<'_> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        data: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // set dry reference
+            1 => self.set_reference(false, data, appid),
+
+            // set wet reference
+            2 => self.set_reference(true, data, appid),
+
+            // read moisture
+            3 => self.read(Request::Moisture, appid),
+
+            // read raw value
+            4 => self.read(Request::Raw, appid),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::moisture_percent;
+
+    /// A resistive sensor on a 12-bit ADC, calibrated in air and in water.
+    const DRY: u16 = 800;
+    const WET: u16 = 3200;
+
+    #[test]
+    fn test_maps_between_references() {
+        assert_eq!(moisture_percent(DRY, DRY, WET), 0);
+        assert_eq!(moisture_percent(WET, DRY, WET), 100);
+        assert_eq!(moisture_percent(2000, DRY, WET), 50);
+        assert_eq!(moisture_percent(1400, DRY, WET), 25);
+        // 24 steps of the ADC per percent, rounded to the nearest.
+        assert_eq!(moisture_percent(DRY + 11, DRY, WET), 0);
+        assert_eq!(moisture_percent(DRY + 12, DRY, WET), 1);
+    }
+
+    #[test]
+    fn test_clamps_beyond_references() {
+        assert_eq!(moisture_percent(DRY - 1, DRY, WET), 0);
+        assert_eq!(moisture_percent(0, DRY, WET), 0);
+        assert_eq!(moisture_percent(WET + 1, DRY, WET), 100);
+        assert_eq!(moisture_percent(u16::MAX, DRY, WET), 100);
+    }
+
+    #[test]
+    fn test_inverted_calibration() {
+        // A capacitive sensor reads lower the wetter the soil.
+        let (dry, wet) = (WET, DRY);
+        assert_eq!(moisture_percent(dry, dry, wet), 0);
+        assert_eq!(moisture_percent(wet, dry, wet), 100);
+        assert_eq!(moisture_percent(2000, dry, wet), 50);
+        assert_eq!(moisture_percent(2600, dry, wet), 25);
+        assert_eq!(moisture_percent(dry + 1, dry, wet), 0);
+        assert_eq!(moisture_percent(u16::MAX, dry, wet), 0);
+        assert_eq!(moisture_percent(wet - 1, dry, wet), 100);
+        assert_eq!(moisture_percent(0, dry, wet), 100);
+    }
+
+    #[test]
+    fn test_equal_references() {
+        assert_eq!(moisture_percent(1000, 1000, 1000), 0);
+    }
+}
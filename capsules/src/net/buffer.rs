@@ -0,0 +1,123 @@
+//! A packet being built in place in a buffer.
+//!
+//! A `PacketBuffer` holds the bytes of a packet in a window of a larger
+//! buffer. The space in front of the window is headroom, and the space after
+//! it is tailroom. A layer appends its payload into the tailroom and
+//! prepends its header into the headroom, so a packet is assembled in the
+//! buffer it is sent from, rather than copied into a new buffer at each
+//! layer.
+//!
+//! A layer that only knows whether it needs a header once it has written
+//! what follows it reserves headroom for the header up front, and gives the
+//! headroom back with `reclaim_headroom` if it ends up not writing it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let mut packet = PacketBuffer::new(&mut buf, HEADER_LEN);
+//! packet.append_slice(&payload)?;
+//! packet.prepend(HEADER_LEN)?.copy_from_slice(&header);
+//! ```
+
+use kernel::ErrorCode;
+
+pub struct PacketBuffer<'a> {
+    buf: &'a mut [u8],
+    head: usize,
+    tail: usize,
+}
+
+impl<'a> PacketBuffer<'a> {
+    /// Creates an empty packet in `buf`, with `headroom` bytes in front of
+    /// it for headers to be prepended.
+    pub fn new(buf: &'a mut [u8], headroom: usize) -> PacketBuffer<'a> {
+        let head = core::cmp::min(headroom, buf.len());
+        PacketBuffer {
+            buf: buf,
+            head: head,
+            tail: head,
+        }
+    }
+
+    /// Space left in front of the packet.
+    pub fn headroom(&self) -> usize {
+        self.head
+    }
+
+    /// Space left after the packet.
+    pub fn tailroom(&self) -> usize {
+        self.buf.len() - self.tail
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail - self.head
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[self.head..self.tail]
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.head..self.tail]
+    }
+
+    /// Grows the packet by `len` bytes at its front, and returns them to be
+    /// written. Fails with `SIZE` if there is not enough headroom.
+    pub fn prepend(&mut self, len: usize) -> Result<&mut [u8], ErrorCode> {
+        if len > self.head {
+            return Err(ErrorCode::SIZE);
+        }
+        self.head -= len;
+        Ok(&mut self.buf[self.head..self.head + len])
+    }
+
+    /// Grows the packet by `len` bytes at its end, and returns them to be
+    /// written. Fails with `SIZE` if there is not enough tailroom.
+    pub fn append(&mut self, len: usize) -> Result<&mut [u8], ErrorCode> {
+        let start = self.tail;
+        self.extend(len)?;
+        Ok(&mut self.buf[start..self.tail])
+    }
+
+    pub fn prepend_slice(&mut self, bytes: &[u8]) -> Result<(), ErrorCode> {
+        self.prepend(bytes.len())
+            .map(|dst| dst.copy_from_slice(bytes))
+    }
+
+    pub fn append_slice(&mut self, bytes: &[u8]) -> Result<(), ErrorCode> {
+        self.append(bytes.len())
+            .map(|dst| dst.copy_from_slice(bytes))
+    }
+
+    /// The tailroom, for an encoder that only knows how many bytes it
+    /// writes once it is done. The bytes written become part of the packet
+    /// with `extend`.
+    pub fn tailroom_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.tail..]
+    }
+
+    /// Grows the packet by `len` bytes of its tailroom, which have already
+    /// been written. Fails with `SIZE` if there is not enough tailroom.
+    pub fn extend(&mut self, len: usize) -> Result<(), ErrorCode> {
+        if len > self.tailroom() {
+            return Err(ErrorCode::SIZE);
+        }
+        self.tail += len;
+        Ok(())
+    }
+
+    /// Moves the packet to the front of the buffer, giving back headroom
+    /// that was reserved but not used.
+    pub fn reclaim_headroom(&mut self) {
+        if self.head > 0 {
+            self.buf.copy_within(self.head..self.tail, 0);
+            self.tail -= self.head;
+            self.head = 0;
+        }
+    }
+}
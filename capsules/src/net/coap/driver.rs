@@ -0,0 +1,460 @@
+//! CoAP userspace interface.
+//!
+//! Processes use this driver both to send requests to CoAP servers and to
+//! expose resources of their own, served by the shared `CoapEndpoint`.
+//!
+//! Requests are sent one at a time, by any process. The body of the
+//! response, fetched block-wise if needed, is written to the response buffer
+//! of the process before it is notified.
+//!
+//! Each process can register up to `MAX_RESOURCES` resources, each
+//! identified by its path. GET requests to a resource are answered with the
+//! representation buffer the process allowed for it. The payload of PUT and
+//! POST requests, possibly sent block-wise, is written to the resource
+//! buffer of the process, which is notified once the whole payload arrived.
+//! Other methods are not allowed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let coap_driver = static_init!(
+//!     capsules::net::coap::CoapDriver<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::net::coap::CoapDriver::new(coap, board_kernel.create_grant(&grant_cap))
+//! );
+//! coap.set_client(coap_driver);
+//! coap.set_server(coap_driver);
+//! ```
+
+use crate::net::coap::endpoint::{CoapClient, CoapEndpoint, CoapServer, Request, MAX_PATH_LEN};
+use crate::net::coap::message::code;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::util::host_slice_to_u16;
+use core::{cmp, mem};
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::Alarm;
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Coap as usize;
+
+/// Number of resources each process can register.
+pub const MAX_RESOURCES: usize = 4;
+
+/// Bit of the request command marking a confirmable request.
+const CONFIRMABLE_FLAG: usize = 1 << 8;
+
+/// Length of the address and port at the start of the request config buffer.
+const ENDPOINT_LEN: usize = 18;
+
+#[derive(Copy, Clone)]
+struct Resource {
+    path: [u8; MAX_PATH_LEN],
+    len: usize,
+}
+
+impl Resource {
+    fn path(&self) -> &[u8] {
+        &self.path[..self.len]
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    response_callback: Upcall,
+    resource_callback: Upcall,
+    request_cfg: ReadOnlyAppSlice,
+    request_payload: ReadOnlyAppSlice,
+    resource_path: ReadOnlyAppSlice,
+    representations: [ReadOnlyAppSlice; MAX_RESOURCES],
+    response_data: ReadWriteAppSlice,
+    resource_data: ReadWriteAppSlice,
+    resources: [Option<Resource>; MAX_RESOURCES],
+    /// Number of bytes of the response body written so far.
+    response_len: usize,
+}
+
+pub struct CoapDriver<'a, A: Alarm<'a>> {
+    endpoint: &'a CoapEndpoint<'a, A>,
+    apps: Grant<App>,
+    /// The process whose request is outstanding.
+    requester: OptionalCell<ProcessId>,
+}
+
+impl<'a, A: Alarm<'a>> CoapDriver<'a, A> {
+    pub fn new(endpoint: &'a CoapEndpoint<'a, A>, grant: Grant<App>) -> CoapDriver<'a, A> {
+        CoapDriver {
+            endpoint: endpoint,
+            apps: grant,
+            requester: OptionalCell::empty(),
+        }
+    }
+
+    /// Send the request described by the buffers of `app`.
+    fn send_request(
+        &self,
+        app: &mut App,
+        request_code: u8,
+        confirmable: bool,
+        len: usize,
+    ) -> Result<(), ErrorCode> {
+        if len > app.request_payload.len() {
+            return Err(ErrorCode::SIZE);
+        }
+        let request_payload = &app.request_payload;
+        app.request_cfg.map_or(Err(ErrorCode::INVAL), |cfg| {
+            if cfg.len() < ENDPOINT_LEN {
+                return Err(ErrorCode::INVAL);
+            }
+            let mut addr = IPAddr::new();
+            addr.0.copy_from_slice(&cfg[..16]);
+            let port = host_slice_to_u16(&cfg[16..ENDPOINT_LEN]);
+            let path = &cfg[ENDPOINT_LEN..];
+            let send = |payload: &[u8]| {
+                self.endpoint
+                    .request(addr, port, confirmable, request_code, path, payload)
+            };
+            if len == 0 {
+                send(&[])
+            } else {
+                request_payload.map_or(Err(ErrorCode::INVAL), |payload| send(&payload[..len]))
+            }
+        })
+    }
+
+    /// Register the resource whose path is in the resource path buffer of
+    /// `appid`. Returns its index.
+    fn register(&self, appid: ProcessId) -> Result<u32, ErrorCode> {
+        let mut resource = Resource {
+            path: [0; MAX_PATH_LEN],
+            len: 0,
+        };
+        self.apps
+            .enter(appid, |app| {
+                app.resource_path.map_or(Err(ErrorCode::INVAL), |path| {
+                    if path.is_empty() || path.len() > MAX_PATH_LEN {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    resource.path[..path.len()].copy_from_slice(path);
+                    resource.len = path.len();
+                    Ok(())
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()))?;
+
+        let taken = self.apps.iter().any(|cntr| {
+            cntr.enter(|app| {
+                app.resources
+                    .iter()
+                    .flatten()
+                    .any(|other| other.path() == resource.path())
+            })
+        });
+        if taken {
+            return Err(ErrorCode::ALREADY);
+        }
+
+        self.apps
+            .enter(appid, |app| {
+                match app.resources.iter().position(|r| r.is_none()) {
+                    Some(index) => {
+                        app.resources[index] = Some(resource);
+                        Ok(index as u32)
+                    }
+                    None => Err(ErrorCode::NOMEM),
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for CoapDriver<'a, A> {
+    /// Setup buffers to read/write from.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Response buffer. The body of responses is written to it.
+    /// - `1`: Resource buffer. The payload of PUT and POST requests to the
+    ///        resources of the process is written to it.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.response_data, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.resource_data, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(slice),
+            Ok(Err(e)) => Err((slice, e)),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// ### `allow_num`
+    ///
+    /// - `0`: Request config buffer: the server address (16 bytes) and port
+    ///        (2 bytes), followed by the path of the resource, with its
+    ///        segments separated by `/`.
+    /// - `1`: Request payload buffer.
+    /// - `2`: Resource path buffer, with the path of the resource to register.
+    /// - `3` to `3 + MAX_RESOURCES - 1`: Representation of the resource with
+    ///        index `allow_num - 3`, returned by GET requests.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| {
+                let target = match allow_num {
+                    0 => &mut app.request_cfg,
+                    1 => &mut app.request_payload,
+                    2 => &mut app.resource_path,
+                    n => match app.representations.get_mut(n.wrapping_sub(3)) {
+                        Some(representation) => representation,
+                        None => return Err(ErrorCode::NOSUPPORT),
+                    },
+                };
+                mem::swap(target, &mut slice);
+                Ok(())
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(slice),
+            Ok(Err(e)) => Err((slice, e)),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Subscribe to CoAP events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: The response to a request arrived, or the request failed.
+    ///        Upcall arguments: (status, response code, body length). The
+    ///        status is `NOACK` if no response arrived in time.
+    /// - `1`: The payload of a PUT or POST request to a resource was written
+    ///        to the resource buffer. Upcall arguments: (resource index,
+    ///        method code, payload length).
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.response_callback, &mut callback);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.resource_callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(callback),
+            Ok(Err(e)) => Err((callback, e)),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// CoAP control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Send a request with method code `data1 & 0xff` (1 GET, 2 POST,
+    ///        3 PUT, 4 DELETE), confirmable if bit 8 of `data1` is set, to the
+    ///        server and path in the request config buffer. The payload is
+    ///        the first `data2` bytes of the request payload buffer.
+    /// - `2`: Register a resource with the path in the resource path buffer.
+    ///        Returns the index of the resource.
+    /// - `3`: Unregister the resource with index `data1`.
+    /// - `4`: Cancel the outstanding request of the process.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // request
+            1 => {
+                if self.requester.is_some() || self.endpoint.is_busy() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                let request_code = (data1 & 0xff) as u8;
+                let confirmable = data1 & CONFIRMABLE_FLAG != 0;
+                let res = self
+                    .apps
+                    .enter(appid, |app| {
+                        let res = self.send_request(app, request_code, confirmable, data2);
+                        if res.is_ok() {
+                            app.response_len = 0;
+                        }
+                        res
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                if res.is_ok() {
+                    self.requester.set(appid);
+                }
+                res.into()
+            }
+
+            // register resource
+            2 => match self.register(appid) {
+                Ok(index) => CommandReturn::success_u32(index),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // unregister resource
+            3 => self
+                .apps
+                .enter(appid, |app| match app.resources.get_mut(data1) {
+                    Some(resource) if resource.is_some() => {
+                        *resource = None;
+                        CommandReturn::success()
+                    }
+                    _ => CommandReturn::failure(ErrorCode::INVAL),
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // cancel request
+            4 => {
+                if self.requester.contains(&appid) {
+                    self.endpoint.cancel();
+                    self.requester.clear();
+                    CommandReturn::success()
+                } else {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                }
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> CoapClient for CoapDriver<'a, A> {
+    fn response(
+        &self,
+        result: Result<(), ErrorCode>,
+        response_code: u8,
+        offset: usize,
+        payload: &[u8],
+        more: bool,
+    ) {
+        if let Some(appid) = self.requester.extract() {
+            let _ = self.apps.enter(appid, |app| {
+                if result.is_ok() {
+                    let copied = app.response_data.mut_map_or(0, |buffer| {
+                        let len = cmp::min(payload.len(), buffer.len().saturating_sub(offset));
+                        buffer[offset..offset + len].copy_from_slice(&payload[..len]);
+                        len
+                    });
+                    if copied > 0 {
+                        app.response_len = cmp::max(app.response_len, offset + copied);
+                    }
+                }
+                if !more {
+                    let len = app.response_len;
+                    app.response_callback.schedule(
+                        kernel::into_statuscode(result),
+                        response_code as usize,
+                        len,
+                    );
+                }
+            });
+        }
+        if !more {
+            self.requester.clear();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> CoapServer for CoapDriver<'a, A> {
+    fn request(&self, request: &Request, offset: usize, response: &mut [u8]) -> (u8, usize, bool) {
+        for cntr in self.apps.iter() {
+            let result = cntr.enter(|app| {
+                let index = app.resources.iter().position(|resource| {
+                    resource.map_or(false, |resource| resource.path() == request.path)
+                })?;
+                Some(match request.code {
+                    code::GET => app.representations[index].map_or(
+                        (code::CONTENT, 0, false),
+                        |representation| {
+                            if offset > representation.len() {
+                                return (code::BAD_OPTION, 0, false);
+                            }
+                            let len = cmp::min(response.len(), representation.len() - offset);
+                            response[..len].copy_from_slice(&representation[offset..offset + len]);
+                            (code::CONTENT, len, offset + len < representation.len())
+                        },
+                    ),
+                    code::PUT | code::POST => {
+                        let start = request.block1.map_or(0, |block1| block1.offset());
+                        let more = request.block1.map_or(false, |block1| block1.more);
+                        let end = start + request.payload.len();
+                        let written = app.resource_data.mut_map_or(false, |buffer| {
+                            if end > buffer.len() {
+                                return false;
+                            }
+                            buffer[start..end].copy_from_slice(request.payload);
+                            true
+                        });
+                        if !written {
+                            (code::REQUEST_ENTITY_TOO_LARGE, 0, false)
+                        } else if more {
+                            (code::CONTINUE, 0, false)
+                        } else {
+                            app.resource_callback
+                                .schedule(index, request.code as usize, end);
+                            if request.code == code::PUT {
+                                (code::CHANGED, 0, false)
+                            } else {
+                                (code::CREATED, 0, false)
+                            }
+                        }
+                    }
+                    _ => (code::METHOD_NOT_ALLOWED, 0, false),
+                })
+            });
+            if let Some(result) = result {
+                return result;
+            }
+        }
+        (code::NOT_FOUND, 0, false)
+    }
+}
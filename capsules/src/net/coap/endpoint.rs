@@ -0,0 +1,746 @@
+//! CoAP message layer, on top of UDP.
+//!
+//! A `CoapEndpoint` binds to a UDP port and both sends requests, on behalf of
+//! a single `CoapClient`, and answers requests, through a single
+//! `CoapServer`.
+//!
+//! Client
+//! ------
+//!
+//! One request is outstanding at a time. Confirmable requests are
+//! retransmitted with exponential backoff until they are acknowledged, and
+//! the response may be piggybacked on the acknowledgment or sent separately;
+//! separate confirmable responses are acknowledged. Responses are matched to
+//! the request by their token.
+//!
+//! Request payloads larger than `BLOCK_SIZE` are sent block-wise with the
+//! Block1 option, and responses carrying a Block2 option are fetched block
+//! by block, each block being passed to the client as it arrives.
+//!
+//! Server
+//! ------
+//!
+//! Requests are answered immediately, with the response piggybacked on the
+//! acknowledgment of confirmable requests. The server writes the part of the
+//! representation of a resource starting at a given offset, so that large
+//! representations are served block-wise with the Block2 option. Block1
+//! options of requests are passed to the server and echoed in the response.
+//! Duplicate confirmable requests are answered again rather than
+//! deduplicated, so servers should only expose idempotent operations.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let coap = static_init!(
+//!     capsules::net::coap::CoapEndpoint<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::net::coap::CoapEndpoint::new(
+//!         coap_alarm,
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         LeasableBuffer::new(coap_tx_buffer),
+//!         coap_request_buffer,
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(coap);
+//! udp_recv.set_client(coap);
+//! coap_alarm.set_alarm_client(coap);
+//! coap.bind(capsules::net::coap::COAP_PORT);
+//! ```
+
+use crate::net::coap::message::{code, option, Block, CoapHeader, MessageType, Options};
+use crate::net::coap::message::{encode_option, PAYLOAD_MARKER};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ErrorCode;
+
+/// Default UDP port of CoAP.
+pub const COAP_PORT: u16 = 5683;
+
+/// Block size exponent used for block-wise transfers.
+pub const BLOCK_SZX: u8 = 2;
+
+/// Size of the blocks of block-wise transfers.
+pub const BLOCK_SIZE: usize = 16 << BLOCK_SZX;
+
+/// Maximum length of the path of a resource, with its segments separated by
+/// `/`.
+pub const MAX_PATH_LEN: usize = 32;
+
+/// Time before the first retransmission of a confirmable message, in
+/// milliseconds.
+const ACK_TIMEOUT_MS: u32 = 2000;
+
+/// Number of retransmissions of a confirmable message.
+const MAX_RETRANSMIT: u8 = 4;
+
+/// Time to wait for a response to a non-confirmable request or for a
+/// separate response, in milliseconds.
+const RESPONSE_TIMEOUT_MS: u32 = 10000;
+
+/// Space reserved in front of the payload of responses for the header and
+/// options: a header with a full token, two block options and the payload
+/// marker. The transmit buffer must hold it and a block, or requests are
+/// dropped.
+const RESPONSE_HDR_SPACE: usize = 4 + 8 + 2 * 5 + 1;
+
+pub trait CoapClient {
+    /// A response to the request sent with `CoapEndpoint::request()`, or one
+    /// block of it, was received. `offset` is the position of `payload` in
+    /// the whole response body, and `more` is true if further blocks follow.
+    /// On error, no further blocks follow: `NOACK` means no response was
+    /// received in time, and `FAIL` that the request was rejected with a
+    /// reset message.
+    fn response(
+        &self,
+        result: Result<(), ErrorCode>,
+        code: u8,
+        offset: usize,
+        payload: &[u8],
+        more: bool,
+    );
+}
+
+/// A request received by a `CoapEndpoint`.
+pub struct Request<'b> {
+    pub code: u8,
+    /// The Uri-Path options, joined by `/`.
+    pub path: &'b [u8],
+    pub payload: &'b [u8],
+    /// The Block1 option, if `payload` is one block of a larger body.
+    pub block1: Option<Block>,
+}
+
+pub trait CoapServer {
+    /// Handle a request. The response payload is the part of the
+    /// representation starting at `offset`, written into `response`.
+    /// Returns the response code, the number of bytes written and whether the
+    /// representation continues beyond them.
+    fn request(&self, request: &Request, offset: usize, response: &mut [u8]) -> (u8, usize, bool);
+}
+
+/// The request of the client.
+#[derive(Copy, Clone)]
+struct Exchange {
+    dest: IPAddr,
+    port: u16,
+    confirmable: bool,
+    code: u8,
+    path: [u8; MAX_PATH_LEN],
+    path_len: usize,
+    payload_len: usize,
+    token: u16,
+    message_id: u16,
+    /// Block of the request payload being sent.
+    block1: u32,
+    /// Block of the response being requested.
+    block2: u32,
+    /// Block size exponent of the response, which the server may choose
+    /// smaller than ours.
+    block2_szx: u8,
+    retries: u8,
+    /// Whether the request was acknowledged, and a separate response is
+    /// awaited.
+    acked: bool,
+}
+
+pub struct CoapEndpoint<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    /// Payload of the request of the client.
+    request_buffer: TakeCell<'static, [u8]>,
+    exchange: OptionalCell<Exchange>,
+    /// Whether the request could not be sent because the transmit buffer was
+    /// in use.
+    request_pending: Cell<bool>,
+    message_id: Cell<u16>,
+    token: Cell<u16>,
+    client: OptionalCell<&'a dyn CoapClient>,
+    server: OptionalCell<&'a dyn CoapServer>,
+    net_cap: &'static NetworkCapability,
+}
+
+impl<'a, A: Alarm<'a>> CoapEndpoint<'a, A> {
+    pub fn new(
+        alarm: &'a A,
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        tx_buffer: LeasableBuffer<'static, u8>,
+        request_buffer: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> CoapEndpoint<'a, A> {
+        CoapEndpoint {
+            alarm: alarm,
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            tx_buffer: MapCell::new(tx_buffer),
+            request_buffer: TakeCell::new(request_buffer),
+            exchange: OptionalCell::empty(),
+            request_pending: Cell::new(false),
+            message_id: Cell::new(0),
+            token: Cell::new(0),
+            client: OptionalCell::empty(),
+            server: OptionalCell::empty(),
+            net_cap: net_cap,
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn CoapClient) {
+        self.client.set(client);
+    }
+
+    pub fn set_server(&self, server: &'a dyn CoapServer) {
+        self.server.set(server);
+    }
+
+    /// Bind to UDP port `port`, on which requests are received and from
+    /// which requests are sent.
+    pub fn bind(&self, port: u16) -> Result<(), ErrorCode> {
+        if self.udp_sender.is_bound() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let socket = self
+            .port_table
+            .create_socket()
+            .map_err(|_| ErrorCode::NOMEM)?;
+        match self.port_table.bind(socket, port, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                self.udp_sender.set_binding(send_binding);
+                self.udp_receiver.set_binding(recv_binding);
+                // Start from an arbitrary message ID, so that a rebooted
+                // device does not reuse recent ones.
+                let seed = self.alarm.now().into_u32();
+                self.message_id.set(seed as u16);
+                self.token.set((seed >> 16) as u16);
+                Ok(())
+            }
+            // Dropping the socket frees it.
+            Err(_socket) => Err(ErrorCode::INVAL),
+        }
+    }
+
+    /// Whether a request is outstanding.
+    pub fn is_busy(&self) -> bool {
+        self.exchange.is_some()
+    }
+
+    /// Send a request with method `code` to the resource at `path` (segments
+    /// separated by `/`) on `dest`, port `port`. The response is passed to
+    /// the client.
+    pub fn request(
+        &self,
+        dest: IPAddr,
+        port: u16,
+        confirmable: bool,
+        code: u8,
+        path: &[u8],
+        payload: &[u8],
+    ) -> Result<(), ErrorCode> {
+        if self.exchange.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if !code::is_request(code) || path.len() > MAX_PATH_LEN {
+            return Err(ErrorCode::INVAL);
+        }
+        self.request_buffer
+            .map_or(Err(ErrorCode::NOMEM), |buffer| {
+                if payload.len() > buffer.len() {
+                    return Err(ErrorCode::SIZE);
+                }
+                buffer[..payload.len()].copy_from_slice(payload);
+                Ok(())
+            })?;
+
+        let mut exchange = Exchange {
+            dest: dest,
+            port: port,
+            confirmable: confirmable,
+            code: code,
+            path: [0; MAX_PATH_LEN],
+            path_len: path.len(),
+            payload_len: payload.len(),
+            token: self.token.get().wrapping_add(1),
+            message_id: self.next_message_id(),
+            block1: 0,
+            block2: 0,
+            block2_szx: BLOCK_SZX,
+            retries: 0,
+            acked: false,
+        };
+        exchange.path[..path.len()].copy_from_slice(path);
+        self.token.set(exchange.token);
+        self.exchange.set(exchange);
+        self.send_request()
+    }
+
+    /// Give up waiting for the response to the outstanding request. The
+    /// client is not called.
+    pub fn cancel(&self) {
+        self.exchange.clear();
+        self.request_pending.set(false);
+        let _ = self.alarm.disarm();
+    }
+
+    fn next_message_id(&self) -> u16 {
+        let id = self.message_id.get().wrapping_add(1);
+        self.message_id.set(id);
+        id
+    }
+
+    fn start_timer(&self, ms: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    /// Encode the current request, or the current block of it.
+    fn encode_request(&self, exchange: &Exchange, buf: &mut [u8]) -> Option<usize> {
+        let msg_type = if exchange.confirmable {
+            MessageType::Confirmable
+        } else {
+            MessageType::NonConfirmable
+        };
+        let mut header = CoapHeader::new(msg_type, exchange.code, exchange.message_id);
+        header.set_token(&exchange.token.to_be_bytes());
+        let (mut off, _) = header.encode(buf, 0).done()?;
+
+        let mut prev = 0;
+        for segment in exchange.path[..exchange.path_len]
+            .split(|&b| b == b'/')
+            .filter(|segment| !segment.is_empty())
+        {
+            off = encode_option(buf, off, prev, option::URI_PATH, segment)
+                .done()?
+                .0;
+            prev = option::URI_PATH;
+        }
+
+        let mut block_value = [0; 3];
+        if exchange.block2 > 0 {
+            let block2 = Block {
+                num: exchange.block2,
+                more: false,
+                szx: exchange.block2_szx,
+            };
+            let len = block2.encode(&mut block_value);
+            off = encode_option(buf, off, prev, option::BLOCK2, &block_value[..len])
+                .done()?
+                .0;
+            prev = option::BLOCK2;
+        }
+
+        // The payload is only sent with the first request of a block-wise
+        // response.
+        let (start, end) = if exchange.block2 > 0 {
+            (0, 0)
+        } else if exchange.payload_len > BLOCK_SIZE {
+            let block1 = Block {
+                num: exchange.block1,
+                more: (exchange.block1 as usize + 1) * BLOCK_SIZE < exchange.payload_len,
+                szx: BLOCK_SZX,
+            };
+            let len = block1.encode(&mut block_value);
+            off = encode_option(buf, off, prev, option::BLOCK1, &block_value[..len])
+                .done()?
+                .0;
+            let start = block1.offset();
+            (start, cmp::min(start + BLOCK_SIZE, exchange.payload_len))
+        } else {
+            (0, exchange.payload_len)
+        };
+
+        if end > start {
+            let len = end - start;
+            if off + 1 + len > buf.len() {
+                return None;
+            }
+            buf[off] = PAYLOAD_MARKER;
+            self.request_buffer.map(|payload| {
+                buf[off + 1..off + 1 + len].copy_from_slice(&payload[start..end]);
+            });
+            off += 1 + len;
+        }
+        Some(off)
+    }
+
+    /// Send the current request, and start waiting for its acknowledgment or
+    /// response. If it cannot be encoded, the exchange ends and `SIZE` is
+    /// returned.
+    fn send_request(&self) -> Result<(), ErrorCode> {
+        let exchange = match self.exchange.extract() {
+            Some(exchange) => exchange,
+            None => return Ok(()),
+        };
+        let timeout = if exchange.confirmable {
+            ACK_TIMEOUT_MS << exchange.retries
+        } else {
+            RESPONSE_TIMEOUT_MS
+        };
+        self.start_timer(timeout);
+
+        match self.tx_buffer.take() {
+            Some(mut buffer) => {
+                buffer.reset();
+                match self.encode_request(&exchange, &mut buffer[..]) {
+                    Some(len) => {
+                        buffer.slice(0..len);
+                        if let Err(mut buffer) = self.udp_sender.send_to(
+                            exchange.dest,
+                            exchange.port,
+                            buffer,
+                            self.net_cap,
+                        ) {
+                            // Confirmable requests are retransmitted when the
+                            // timer expires.
+                            buffer.reset();
+                            self.tx_buffer.replace(buffer);
+                        }
+                    }
+                    None => {
+                        self.tx_buffer.replace(buffer);
+                        self.exchange.clear();
+                        let _ = self.alarm.disarm();
+                        return Err(ErrorCode::SIZE);
+                    }
+                }
+            }
+            None => self.request_pending.set(true),
+        }
+        Ok(())
+    }
+
+    /// Send the current request again or its next block, ending the exchange
+    /// with an error if that fails.
+    fn continue_request(&self) {
+        if let Err(e) = self.send_request() {
+            self.client
+                .map(|client| client.response(Err(e), code::EMPTY, 0, &[], false));
+        }
+    }
+
+    /// End the exchange and pass its result to the client.
+    fn finish(
+        &self,
+        result: Result<(), ErrorCode>,
+        code: u8,
+        offset: usize,
+        payload: &[u8],
+        more: bool,
+    ) {
+        if !more {
+            self.exchange.clear();
+            let _ = self.alarm.disarm();
+        }
+        self.client
+            .map(|client| client.response(result, code, offset, payload, more));
+    }
+
+    /// Send a message without payload nor options.
+    fn send_empty(&self, dest: IPAddr, port: u16, msg_type: MessageType, message_id: u16) {
+        self.tx_buffer.take().map(|mut buffer| {
+            buffer.reset();
+            let header = CoapHeader::new(msg_type, code::EMPTY, message_id);
+            match header.encode(&mut buffer[..], 0).done() {
+                Some((len, _)) => {
+                    buffer.slice(0..len);
+                    if let Err(mut buffer) =
+                        self.udp_sender.send_to(dest, port, buffer, self.net_cap)
+                    {
+                        buffer.reset();
+                        self.tx_buffer.replace(buffer);
+                    }
+                }
+                None => {
+                    self.tx_buffer.replace(buffer);
+                }
+            }
+        });
+    }
+
+    /// Handle a response, or an empty acknowledgment or reset, to the
+    /// request of the client.
+    fn handle_response(
+        &self,
+        src_addr: IPAddr,
+        src_port: u16,
+        header: &CoapHeader,
+        options: &[u8],
+    ) {
+        let mut exchange = match self.exchange.extract() {
+            Some(exchange) => exchange,
+            None => {
+                if header.msg_type == MessageType::Confirmable {
+                    self.send_empty(src_addr, src_port, MessageType::Reset, header.message_id);
+                }
+                return;
+            }
+        };
+        if src_addr != exchange.dest || src_port != exchange.port {
+            return;
+        }
+
+        if header.code == code::EMPTY {
+            if header.message_id != exchange.message_id {
+                return;
+            }
+            match header.msg_type {
+                MessageType::Acknowledgement => {
+                    exchange.acked = true;
+                    self.exchange.set(exchange);
+                    self.start_timer(RESPONSE_TIMEOUT_MS);
+                }
+                MessageType::Reset => {
+                    self.finish(Err(ErrorCode::FAIL), code::EMPTY, 0, &[], false);
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        if header.get_token() != exchange.token.to_be_bytes() {
+            return;
+        }
+        if header.msg_type == MessageType::Acknowledgement
+            && header.message_id != exchange.message_id
+        {
+            return;
+        }
+        if header.msg_type == MessageType::Confirmable {
+            self.send_empty(
+                src_addr,
+                src_port,
+                MessageType::Acknowledgement,
+                header.message_id,
+            );
+        }
+
+        let mut block2 = None;
+        for (number, value) in Options::new(options) {
+            if number == option::BLOCK2 {
+                block2 = Block::decode(value);
+            }
+        }
+        let payload = Options::new(options).payload();
+
+        // Send the next block of the request payload.
+        let sent = (exchange.block1 as usize + 1) * BLOCK_SIZE;
+        if header.code == code::CONTINUE && exchange.block2 == 0 && sent < exchange.payload_len {
+            exchange.block1 += 1;
+            exchange.message_id = self.next_message_id();
+            exchange.retries = 0;
+            exchange.acked = false;
+            self.exchange.set(exchange);
+            self.continue_request();
+            return;
+        }
+
+        match block2 {
+            Some(block2) if block2.more => {
+                // Further blocks are requested with the size the server
+                // chose.
+                self.finish(Ok(()), header.code, block2.offset(), payload, true);
+                exchange.block2 = block2.num + 1;
+                exchange.block2_szx = block2.szx;
+                exchange.message_id = self.next_message_id();
+                exchange.retries = 0;
+                exchange.acked = false;
+                self.exchange.set(exchange);
+                self.continue_request();
+            }
+            Some(block2) => {
+                self.finish(Ok(()), header.code, block2.offset(), payload, false);
+            }
+            None => {
+                self.finish(Ok(()), header.code, 0, payload, false);
+            }
+        }
+    }
+
+    /// Answer a request with the response of the server.
+    fn handle_request(&self, src_addr: IPAddr, src_port: u16, header: &CoapHeader, options: &[u8]) {
+        let mut path = [0; MAX_PATH_LEN];
+        let mut path_len = 0;
+        let mut path_too_long = false;
+        let mut block1 = None;
+        let mut block2 = None;
+        for (number, value) in Options::new(options) {
+            match number {
+                option::URI_PATH => {
+                    let sep = if path_len > 0 { 1 } else { 0 };
+                    if path_len + sep + value.len() > MAX_PATH_LEN {
+                        path_too_long = true;
+                    } else {
+                        if sep > 0 {
+                            path[path_len] = b'/';
+                        }
+                        path[path_len + sep..path_len + sep + value.len()].copy_from_slice(value);
+                        path_len += sep + value.len();
+                    }
+                }
+                option::BLOCK1 => block1 = Block::decode(value),
+                option::BLOCK2 => block2 = Block::decode(value),
+                _ => {}
+            }
+        }
+        let request = Request {
+            code: header.code,
+            path: &path[..path_len],
+            payload: Options::new(options).payload(),
+            block1: block1,
+        };
+
+        self.tx_buffer.take().map(|mut buffer| {
+            buffer.reset();
+            if buffer.len() < RESPONSE_HDR_SPACE + BLOCK_SIZE {
+                self.tx_buffer.replace(buffer);
+                return;
+            }
+
+            // Serve blocks no larger than ours.
+            let (offset, szx) = match block2 {
+                Some(block2) => (block2.offset(), cmp::min(block2.szx, BLOCK_SZX)),
+                None => (0, BLOCK_SZX),
+            };
+            let size = 16 << szx;
+            let (response_code, len, more) = if path_too_long {
+                (code::NOT_FOUND, 0, false)
+            } else {
+                let response = &mut buffer[RESPONSE_HDR_SPACE..RESPONSE_HDR_SPACE + size];
+                self.server.map_or((code::NOT_FOUND, 0, false), |server| {
+                    server.request(&request, offset, response)
+                })
+            };
+            let len = cmp::min(len, size);
+
+            let (msg_type, message_id) = if header.msg_type == MessageType::Confirmable {
+                (MessageType::Acknowledgement, header.message_id)
+            } else {
+                (MessageType::NonConfirmable, self.next_message_id())
+            };
+            let mut response_header = CoapHeader::new(msg_type, response_code, message_id);
+            response_header.set_token(header.get_token());
+
+            let mut encode = || -> Option<usize> {
+                let (mut off, _) = response_header.encode(&mut buffer[..], 0).done()?;
+                let mut block_value = [0; 3];
+                if more || offset > 0 {
+                    let block2 = Block {
+                        num: (offset / size) as u32,
+                        more: more,
+                        szx: szx,
+                    };
+                    let n = block2.encode(&mut block_value);
+                    off = encode_option(&mut buffer[..], off, 0, option::BLOCK2, &block_value[..n])
+                        .done()?
+                        .0;
+                }
+                if let Some(block1) = block1 {
+                    let n = block1.encode(&mut block_value);
+                    off = encode_option(
+                        &mut buffer[..],
+                        off,
+                        if more || offset > 0 {
+                            option::BLOCK2
+                        } else {
+                            0
+                        },
+                        option::BLOCK1,
+                        &block_value[..n],
+                    )
+                    .done()?
+                    .0;
+                }
+                if len > 0 {
+                    buffer[off] = PAYLOAD_MARKER;
+                    buffer[..].copy_within(RESPONSE_HDR_SPACE..RESPONSE_HDR_SPACE + len, off + 1);
+                    off += 1 + len;
+                }
+                Some(off)
+            };
+
+            match encode() {
+                Some(total) => {
+                    buffer.slice(0..total);
+                    if let Err(mut buffer) =
+                        self.udp_sender
+                            .send_to(src_addr, src_port, buffer, self.net_cap)
+                    {
+                        buffer.reset();
+                        self.tx_buffer.replace(buffer);
+                    }
+                }
+                None => {
+                    self.tx_buffer.replace(buffer);
+                }
+            }
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for CoapEndpoint<'a, A> {
+    fn send_done(&self, _result: Result<(), ErrorCode>, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buffer.replace(dgram);
+        if self.request_pending.take() {
+            self.continue_request();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for CoapEndpoint<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        let (offset, header) = match CoapHeader::decode(payload).done() {
+            Some(decoded) => decoded,
+            None => return,
+        };
+        let options = &payload[offset..];
+        if code::is_request(header.code) {
+            self.handle_request(src_addr, src_port, &header, options);
+        } else if code::is_response(header.code) || header.code == code::EMPTY {
+            if header.code == code::EMPTY && header.msg_type == MessageType::Confirmable {
+                // A ping.
+                self.send_empty(src_addr, src_port, MessageType::Reset, header.message_id);
+            } else {
+                self.handle_response(src_addr, src_port, &header, options);
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for CoapEndpoint<'a, A> {
+    fn alarm(&self) {
+        let mut exchange = match self.exchange.extract() {
+            Some(exchange) => exchange,
+            None => return,
+        };
+        if exchange.confirmable && !exchange.acked && exchange.retries < MAX_RETRANSMIT {
+            exchange.retries += 1;
+            self.exchange.set(exchange);
+            self.continue_request();
+        } else {
+            self.finish(Err(ErrorCode::NOACK), code::EMPTY, 0, &[], false);
+        }
+    }
+}
@@ -0,0 +1,356 @@
+//! This file contains the structs and methods associated with CoAP messages
+//! (RFC 7252): the fixed header and token, the options that follow it, and
+//! the Block1/Block2 option values used for block-wise transfers (RFC 7959).
+//!
+//! A message is laid out as the header, followed by the options in
+//! increasing option number, and then by the payload, separated from the
+//! options by a `0xff` marker if it is not empty.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+/// Version of CoAP implemented.
+pub const COAP_VERSION: u8 = 1;
+
+/// Size of the header, not including the token.
+pub const COAP_HDR_LEN: usize = 4;
+
+/// Maximum length of a token.
+pub const MAX_TOKEN_LEN: usize = 8;
+
+/// Marker separating the options from the payload.
+pub const PAYLOAD_MARKER: u8 = 0xff;
+
+/// Message codes, as `class << 5 | detail`.
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+    pub const GET: u8 = 0x01;
+    pub const POST: u8 = 0x02;
+    pub const PUT: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+    pub const CREATED: u8 = 0x41;
+    pub const DELETED: u8 = 0x42;
+    pub const VALID: u8 = 0x43;
+    pub const CHANGED: u8 = 0x44;
+    pub const CONTENT: u8 = 0x45;
+    pub const CONTINUE: u8 = 0x5f;
+    pub const BAD_REQUEST: u8 = 0x80;
+    pub const BAD_OPTION: u8 = 0x82;
+    pub const NOT_FOUND: u8 = 0x84;
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    pub const REQUEST_ENTITY_INCOMPLETE: u8 = 0x88;
+    pub const REQUEST_ENTITY_TOO_LARGE: u8 = 0x8d;
+    pub const INTERNAL_SERVER_ERROR: u8 = 0xa0;
+
+    /// Whether `code` is a request method.
+    pub fn is_request(code: u8) -> bool {
+        code != EMPTY && code >> 5 == 0
+    }
+
+    /// Whether `code` is a response code.
+    pub fn is_response(code: u8) -> bool {
+        (2..=5).contains(&(code >> 5))
+    }
+}
+
+/// Option numbers.
+pub mod option {
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl MessageType {
+    fn from_bits(bits: u8) -> MessageType {
+        match bits & 0x3 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        }
+    }
+}
+
+/// The `CoapHeader` struct holds the fixed header of a CoAP message and its
+/// token.
+#[derive(Copy, Clone, Debug)]
+pub struct CoapHeader {
+    pub msg_type: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    token: [u8; MAX_TOKEN_LEN],
+    token_len: usize,
+}
+
+impl CoapHeader {
+    pub fn new(msg_type: MessageType, code: u8, message_id: u16) -> CoapHeader {
+        CoapHeader {
+            msg_type: msg_type,
+            code: code,
+            message_id: message_id,
+            token: [0; MAX_TOKEN_LEN],
+            token_len: 0,
+        }
+    }
+
+    /// Set the token, truncated to `MAX_TOKEN_LEN` bytes.
+    pub fn set_token(&mut self, token: &[u8]) {
+        self.token_len = token.len().min(MAX_TOKEN_LEN);
+        self.token[..self.token_len].copy_from_slice(&token[..self.token_len]);
+    }
+
+    pub fn get_token(&self) -> &[u8] {
+        &self.token[..self.token_len]
+    }
+
+    pub fn get_hdr_size(&self) -> usize {
+        COAP_HDR_LEN + self.token_len
+    }
+
+    /// This function serializes the `CoapHeader` into the provided buffer.
+    ///
+    /// # Arguments
+    ///
+    /// `buf` - A mutable buffer to serialize the `CoapHeader` into
+    /// `offset` - The current offset into the provided buffer
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the new offset into the buffer wrapped in an
+    /// SResult.
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, self.get_hdr_size() + offset);
+
+        let first = COAP_VERSION << 6 | (self.msg_type as u8) << 4 | self.token_len as u8;
+        let mut off = offset;
+        off = enc_consume!(buf, off; encode_u8, first);
+        off = enc_consume!(buf, off; encode_u8, self.code);
+        off = enc_consume!(buf, off; encode_u16, self.message_id);
+        off = enc_consume!(buf, off; encode_bytes, self.get_token());
+        stream_done!(off, off);
+    }
+
+    /// This function deserializes the `CoapHeader` from the provided buffer.
+    ///
+    /// # Arguments
+    ///
+    /// `buf` - The byte array corresponding to a serialized CoAP message
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the offset of the options, after the token, and
+    /// a `CoapHeader` struct wrapped in an SResult
+    pub fn decode(buf: &[u8]) -> SResult<CoapHeader> {
+        stream_len_cond!(buf, COAP_HDR_LEN);
+        let off = 0;
+        let (off, first) = dec_try!(buf, off; decode_u8);
+        let (off, code) = dec_try!(buf, off; decode_u8);
+        let (off, message_id) = dec_try!(buf, off; decode_u16);
+        let token_len = (first & 0xf) as usize;
+        stream_cond!(first >> 6 == COAP_VERSION && token_len <= MAX_TOKEN_LEN);
+        stream_len_cond!(buf, off + token_len);
+
+        let mut header = CoapHeader::new(MessageType::from_bits(first >> 4), code, message_id);
+        header.set_token(&buf[off..off + token_len]);
+        stream_done!(off + token_len, header);
+    }
+}
+
+/// Split a delta or length of an option into its 4-bit field and extended
+/// value.
+fn option_nibble(value: usize) -> (u8, usize, usize) {
+    if value < 13 {
+        (value as u8, 0, 0)
+    } else if value < 269 {
+        (13, value - 13, 1)
+    } else {
+        (14, value - 269, 2)
+    }
+}
+
+/// Encode option `number` with value `value` at `offset`, following the
+/// option numbered `prev` (0 for the first option). Options must be encoded
+/// in increasing order of number.
+///
+/// # Return Value
+///
+/// This function returns the new offset into the buffer wrapped in an
+/// SResult.
+pub fn encode_option(
+    buf: &mut [u8],
+    offset: usize,
+    prev: u16,
+    number: u16,
+    value: &[u8],
+) -> SResult<usize> {
+    stream_cond!(number >= prev);
+    let (delta, delta_ext, delta_ext_len) = option_nibble((number - prev) as usize);
+    let (len, len_ext, len_ext_len) = option_nibble(value.len());
+    stream_len_cond!(buf, offset + 1 + delta_ext_len + len_ext_len + value.len());
+
+    let mut off = offset;
+    off = enc_consume!(buf, off; encode_u8, delta << 4 | len);
+    for &(ext, ext_len) in [(delta_ext, delta_ext_len), (len_ext, len_ext_len)].iter() {
+        if ext_len == 1 {
+            off = enc_consume!(buf, off; encode_u8, ext as u8);
+        } else if ext_len == 2 {
+            off = enc_consume!(buf, off; encode_u16, ext as u16);
+        }
+    }
+    off = enc_consume!(buf, off; encode_bytes, value);
+    stream_done!(off, off);
+}
+
+/// An iterator over the options of a message, yielding their numbers and
+/// values. Iteration stops at the payload marker, or at the first malformed
+/// option.
+pub struct Options<'b> {
+    buf: &'b [u8],
+    number: u16,
+    malformed: bool,
+}
+
+impl<'b> Options<'b> {
+    /// Iterate over the options in `buf`, the part of a message after the
+    /// header and token.
+    pub fn new(buf: &'b [u8]) -> Options<'b> {
+        Options {
+            buf: buf,
+            number: 0,
+            malformed: false,
+        }
+    }
+
+    /// Skip the remaining options and return the payload, which is empty if
+    /// the message has none or the options are malformed.
+    pub fn payload(mut self) -> &'b [u8] {
+        while self.next().is_some() {}
+        if self.malformed || self.buf.is_empty() {
+            &[]
+        } else {
+            &self.buf[1..]
+        }
+    }
+
+    /// Decode an extended delta or length at the start of `self.buf`.
+    fn extended(&mut self, nibble: u8) -> Option<usize> {
+        match nibble {
+            13 => {
+                let value = *self.buf.get(0)? as usize + 13;
+                self.buf = &self.buf[1..];
+                Some(value)
+            }
+            14 => {
+                if self.buf.len() < 2 {
+                    return None;
+                }
+                let value = ((self.buf[0] as usize) << 8 | self.buf[1] as usize) + 269;
+                self.buf = &self.buf[2..];
+                Some(value)
+            }
+            15 => None,
+            _ => Some(nibble as usize),
+        }
+    }
+}
+
+impl<'b> Iterator for Options<'b> {
+    type Item = (u16, &'b [u8]);
+
+    fn next(&mut self) -> Option<(u16, &'b [u8])> {
+        if self.malformed {
+            return None;
+        }
+        let first = *self.buf.get(0)?;
+        if first == PAYLOAD_MARKER {
+            return None;
+        }
+        self.buf = &self.buf[1..];
+        let option = self.extended(first >> 4).and_then(|delta| {
+            let len = self.extended(first & 0xf)?;
+            if len > self.buf.len() {
+                return None;
+            }
+            let (value, rest) = self.buf.split_at(len);
+            self.buf = rest;
+            Some((delta, value))
+        });
+        match option {
+            Some((delta, value)) => {
+                self.number = self.number.saturating_add(delta as u16);
+                Some((self.number, value))
+            }
+            None => {
+                self.malformed = true;
+                None
+            }
+        }
+    }
+}
+
+/// The value of a Block1 or Block2 option: the number of a block, whether
+/// more blocks follow it, and the block size exponent.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Block {
+    pub num: u32,
+    pub more: bool,
+    /// Block size exponent: blocks are `16 << szx` bytes long.
+    pub szx: u8,
+}
+
+impl Block {
+    /// Largest block size exponent, for 1024-byte blocks.
+    pub const MAX_SZX: u8 = 6;
+
+    pub fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    /// Offset of the block in the whole body.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    /// Decode an option value, or `None` if it is invalid.
+    pub fn decode(value: &[u8]) -> Option<Block> {
+        if value.len() > 3 {
+            return None;
+        }
+        let value = value.iter().fold(0u32, |v, &b| v << 8 | b as u32);
+        let szx = (value & 0x7) as u8;
+        if szx > Block::MAX_SZX {
+            return None;
+        }
+        Some(Block {
+            num: value >> 4,
+            more: value & 0x8 != 0,
+            szx: szx,
+        })
+    }
+
+    /// Encode the option value into `buf`, with as few bytes as possible.
+    /// Returns the number of bytes used.
+    pub fn encode(&self, buf: &mut [u8; 3]) -> usize {
+        let value = self.num << 4 | (self.more as u32) << 3 | self.szx as u32;
+        let len = match value {
+            0 => 0,
+            1..=0xff => 1,
+            0x100..=0xffff => 2,
+            _ => 3,
+        };
+        for i in 0..len {
+            buf[i] = (value >> (8 * (len - 1 - i))) as u8;
+        }
+        len
+    }
+}
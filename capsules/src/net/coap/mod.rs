@@ -0,0 +1,7 @@
+pub mod driver;
+pub mod endpoint;
+pub mod message;
+
+pub use self::driver::CoapDriver;
+pub use self::driver::DRIVER_NUM;
+pub use self::endpoint::{CoapClient, CoapEndpoint, CoapServer, Request, COAP_PORT};
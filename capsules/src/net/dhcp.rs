@@ -0,0 +1,675 @@
+//! DHCP client that configures an IPv4 interface, on top of UDP.
+//!
+//! The client acquires a lease (RFC 2131) by broadcasting a DHCPDISCOVER and
+//! requesting the address of the first offer, and sets the address, netmask
+//! and router of the interface from the acknowledgment. A lost or refused
+//! lease clears the address of the interface and starts over. Discovery is
+//! retried with an exponential backoff up to `MAX_TIMEOUT_MS`, forever.
+//!
+//! Once bound, the lease is renewed from its server at T1 (half of the lease
+//! by default), and from any server at T2 (seven eighths of the lease), with
+//! requests sent again every half of the time left, but at least every
+//! `MIN_RETRANSMIT_S`. The lease is lost when it expires.
+//!
+//! Processes are notified when the address changes.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let dhcp = static_init!(
+//!     capsules::net::dhcp::DhcpClient<'static, VirtualMuxAlarm<'static, Tim2>>,
+//!     capsules::net::dhcp::DhcpClient::new(
+//!         dhcp_alarm,
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         LeasableBuffer::new(dhcp_tx_buffer),
+//!         ip4,
+//!         board_kernel.create_grant(&grant_cap),
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(dhcp);
+//! udp_recv.set_client(dhcp);
+//! dhcp_alarm.set_alarm_client(dhcp);
+//! dhcp.start();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: Address upcall, when the address of the interface changes, with
+//!   `1` if a lease was acquired or `0` if it was lost, the new address and
+//!   the new netmask (as big-endian 32-bit values).
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get the address and netmask of the lease. Returns `OFF` if there is
+//!   no lease.
+//! - `2`: Get the router, and the seconds left before the lease expires.
+//!   Returns `OFF` if there is no lease.
+//! - `3`: Renew the lease now, or acquire one if the client was not started.
+//!   Returns `BUSY` while a lease is being acquired.
+
+use crate::net::ipv4::ip4_interface::{IP4Config, IP4Configuration};
+use crate::net::ipv4::ip4_utils::IP4Addr;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use core::{cmp, mem};
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Dhcp as usize;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+/// Length of the messages sent, the minimum length of a BOOTP message.
+pub const PACKET_LEN: usize = 300;
+
+/// First time to wait for an offer or an acknowledgment, doubled after each
+/// attempt up to `MAX_TIMEOUT_MS`.
+const MIN_TIMEOUT_MS: u32 = 4000;
+const MAX_TIMEOUT_MS: u32 = 64000;
+
+/// Number of times a request for an offered address is sent before
+/// discovering again.
+const MAX_REQUEST_RETRIES: u8 = 3;
+
+/// Shortest time between requests while renewing or rebinding, in seconds.
+const MIN_RETRANSMIT_S: u32 = 60;
+
+/// Longest single alarm used while waiting for the next step of the lease,
+/// in seconds, so that it fits the alarm's counter even at high frequencies.
+const WAIT_STEP_S: u32 = 60;
+
+/// Offset of the options, after the fixed fields and the magic cookie.
+const OPTIONS_OFFSET: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+/// Asks servers to broadcast their replies, since the interface has no
+/// address yet.
+const FLAG_BROADCAST: u16 = 0x8000;
+
+mod option {
+    pub const PAD: u8 = 0;
+    pub const SUBNET_MASK: u8 = 1;
+    pub const ROUTER: u8 = 3;
+    pub const REQUESTED_ADDR: u8 = 50;
+    pub const LEASE_TIME: u8 = 51;
+    pub const MESSAGE_TYPE: u8 = 53;
+    pub const SERVER_ID: u8 = 54;
+    pub const PARAMETER_LIST: u8 = 55;
+    pub const RENEWAL_TIME: u8 = 58;
+    pub const REBINDING_TIME: u8 = 59;
+    pub const END: u8 = 255;
+}
+
+mod message {
+    pub const DISCOVER: u8 = 1;
+    pub const OFFER: u8 = 2;
+    pub const REQUEST: u8 = 3;
+    pub const ACK: u8 = 5;
+    pub const NAK: u8 = 6;
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Stopped,
+    /// Waiting for an offer to a DHCPDISCOVER.
+    Selecting {
+        retries: u8,
+    },
+    /// Waiting for the acknowledgment of the offered address.
+    Requesting {
+        retries: u8,
+        addr: IP4Addr,
+        server: IP4Addr,
+    },
+    Bound,
+    /// Renewing the lease from its server.
+    Renewing,
+    /// Renewing the lease from any server.
+    Rebinding,
+}
+
+/// A lease, with its times in seconds from when it was acquired.
+#[derive(Copy, Clone)]
+struct Lease {
+    addr: IP4Addr,
+    netmask: IP4Addr,
+    router: IP4Addr,
+    server: IP4Addr,
+    t1: u32,
+    t2: u32,
+    expiry: u32,
+}
+
+/// The options of a reply that the client uses.
+struct Reply {
+    msg_type: u8,
+    addr: IP4Addr,
+    netmask: Option<IP4Addr>,
+    router: Option<IP4Addr>,
+    server: Option<IP4Addr>,
+    lease_time: Option<u32>,
+    t1: Option<u32>,
+    t2: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+}
+
+pub struct DhcpClient<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    interface: &'a dyn IP4Configuration,
+    state: Cell<State>,
+    xid: Cell<u32>,
+    lease: OptionalCell<Lease>,
+    /// Seconds since the lease was acquired, counted by the alarm, and the
+    /// seconds waited by the current alarm.
+    elapsed: Cell<u32>,
+    step: Cell<u32>,
+    /// When to send the next request while renewing or rebinding.
+    next_send: Cell<u32>,
+    apps: Grant<App>,
+    net_cap: &'static NetworkCapability,
+}
+
+impl<'a, A: Alarm<'a>> DhcpClient<'a, A> {
+    pub fn new(
+        alarm: &'a A,
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        tx_buffer: LeasableBuffer<'static, u8>,
+        interface: &'a dyn IP4Configuration,
+        grant: Grant<App>,
+        net_cap: &'static NetworkCapability,
+    ) -> DhcpClient<'a, A> {
+        DhcpClient {
+            alarm: alarm,
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            tx_buffer: MapCell::new(tx_buffer),
+            interface: interface,
+            state: Cell::new(State::Stopped),
+            xid: Cell::new(0),
+            lease: OptionalCell::empty(),
+            elapsed: Cell::new(0),
+            step: Cell::new(0),
+            next_send: Cell::new(0),
+            apps: grant,
+            net_cap: net_cap,
+        }
+    }
+
+    /// Bind to the DHCP client port, and acquire a lease.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Stopped {
+            return Err(ErrorCode::ALREADY);
+        }
+        if !self.udp_sender.is_bound() {
+            let socket = self
+                .port_table
+                .create_socket()
+                .map_err(|_| ErrorCode::NOMEM)?;
+            match self.port_table.bind(socket, CLIENT_PORT, self.net_cap) {
+                Ok((send_binding, recv_binding)) => {
+                    self.udp_sender.set_binding(send_binding);
+                    self.udp_receiver.set_binding(recv_binding);
+                }
+                // Dropping the socket frees it.
+                Err(_socket) => return Err(ErrorCode::INVAL),
+            }
+        }
+        self.discover(0);
+        Ok(())
+    }
+
+    /// A new transaction identifier, from the MAC address and the time.
+    fn new_xid(&self) {
+        let mac = self.interface.mac_address().0;
+        let seed = u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]);
+        self.xid
+            .set(seed ^ self.alarm.now().into_u32().wrapping_mul(2_654_435_761));
+    }
+
+    /// Wait `ms` for a reply while acquiring a lease.
+    fn timeout(&self, ms: u32) {
+        self.step.set(0);
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    /// Wait `seconds` before the next step of the lease.
+    fn wait(&self, seconds: u32) {
+        let step = cmp::min(seconds, WAIT_STEP_S);
+        self.step.set(step);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(step * 1000));
+    }
+
+    fn backoff_ms(retries: u8) -> u32 {
+        cmp::min(MIN_TIMEOUT_MS << cmp::min(retries, 4), MAX_TIMEOUT_MS)
+    }
+
+    /// Broadcast a DHCPDISCOVER, attempt `retries`.
+    fn discover(&self, retries: u8) {
+        if retries == 0 {
+            self.new_xid();
+        }
+        self.state.set(State::Selecting { retries: retries });
+        self.timeout(Self::backoff_ms(retries));
+        self.send(message::DISCOVER, IP4Addr::BROADCAST, None, None);
+    }
+
+    /// Broadcast a request for an offered address, attempt `retries`.
+    fn request(&self, retries: u8, addr: IP4Addr, server: IP4Addr) {
+        self.state.set(State::Requesting {
+            retries: retries,
+            addr: addr,
+            server: server,
+        });
+        self.timeout(Self::backoff_ms(retries));
+        self.send(
+            message::REQUEST,
+            IP4Addr::BROADCAST,
+            None,
+            Some((addr, server)),
+        );
+    }
+
+    /// Send a request to extend the lease, until `limit`, and wait for the
+    /// next attempt.
+    fn renew(&self, limit: u32) {
+        let lease = match self.lease.extract() {
+            Some(lease) => lease,
+            None => return,
+        };
+        let dst = if self.state.get() == State::Renewing {
+            lease.server
+        } else {
+            IP4Addr::BROADCAST
+        };
+        self.new_xid();
+        self.send(message::REQUEST, dst, Some(lease.addr), None);
+
+        let elapsed = self.elapsed.get();
+        let left = limit.saturating_sub(elapsed);
+        let next = elapsed.saturating_add(cmp::min(cmp::max(left / 2, MIN_RETRANSMIT_S), left));
+        self.next_send.set(next);
+        self.wait(next - elapsed);
+    }
+
+    /// Write a message of type `msg_type` and send it to `dst`. `ciaddr` is
+    /// the address of the lease being extended, and `requested` the offered
+    /// address and the server that offered it. If the buffer is busy, the
+    /// message is sent again when the timeout expires.
+    fn send(
+        &self,
+        msg_type: u8,
+        dst: IP4Addr,
+        ciaddr: Option<IP4Addr>,
+        requested: Option<(IP4Addr, IP4Addr)>,
+    ) {
+        let mut buffer = match self.tx_buffer.take() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        buffer.reset();
+        if buffer.len() < PACKET_LEN {
+            self.tx_buffer.replace(buffer);
+            return;
+        }
+        for byte in buffer[..PACKET_LEN].iter_mut() {
+            *byte = 0;
+        }
+        buffer[0] = OP_REQUEST;
+        buffer[1] = 1; // Ethernet
+        buffer[2] = 6;
+        buffer[4..8].copy_from_slice(&self.xid.get().to_be_bytes());
+        if let Some(ciaddr) = ciaddr {
+            buffer[12..16].copy_from_slice(&ciaddr.0);
+        } else {
+            buffer[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+        }
+        buffer[28..34].copy_from_slice(&self.interface.mac_address().0);
+        buffer[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut off = OPTIONS_OFFSET;
+        buffer[off..off + 3].copy_from_slice(&[option::MESSAGE_TYPE, 1, msg_type]);
+        off += 3;
+        if let Some((addr, server)) = requested {
+            buffer[off..off + 2].copy_from_slice(&[option::REQUESTED_ADDR, 4]);
+            buffer[off + 2..off + 6].copy_from_slice(&addr.0);
+            buffer[off + 6..off + 8].copy_from_slice(&[option::SERVER_ID, 4]);
+            buffer[off + 8..off + 12].copy_from_slice(&server.0);
+            off += 12;
+        }
+        let parameters = [
+            option::SUBNET_MASK,
+            option::ROUTER,
+            option::LEASE_TIME,
+            option::RENEWAL_TIME,
+            option::REBINDING_TIME,
+        ];
+        buffer[off] = option::PARAMETER_LIST;
+        buffer[off + 1] = parameters.len() as u8;
+        buffer[off + 2..off + 2 + parameters.len()].copy_from_slice(&parameters);
+        off += 2 + parameters.len();
+        buffer[off] = option::END;
+
+        buffer.slice(0..PACKET_LEN);
+        if let Err(mut buffer) =
+            self.udp_sender
+                .send_to(dst.to_mapped(), SERVER_PORT, buffer, self.net_cap)
+        {
+            buffer.reset();
+            self.tx_buffer.replace(buffer);
+        }
+    }
+
+    /// Parse a reply to the current transaction.
+    fn parse(&self, payload: &[u8]) -> Option<Reply> {
+        if payload.len() < OPTIONS_OFFSET
+            || payload[0] != OP_REPLY
+            || payload[4..8] != self.xid.get().to_be_bytes()
+            || payload[28..34] != self.interface.mac_address().0
+            || payload[236..240] != MAGIC_COOKIE
+        {
+            return None;
+        }
+        let mut reply = Reply {
+            msg_type: 0,
+            addr: IP4Addr::UNSPECIFIED,
+            netmask: None,
+            router: None,
+            server: None,
+            lease_time: None,
+            t1: None,
+            t2: None,
+        };
+        reply.addr.0.copy_from_slice(&payload[16..20]);
+
+        let addr = |value: &[u8]| {
+            let mut addr = IP4Addr::UNSPECIFIED;
+            addr.0.copy_from_slice(&value[..4]);
+            addr
+        };
+        let seconds = |value: &[u8]| u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+        let mut off = OPTIONS_OFFSET;
+        while off < payload.len() {
+            let code = payload[off];
+            if code == option::END {
+                break;
+            }
+            if code == option::PAD {
+                off += 1;
+                continue;
+            }
+            let len = *payload.get(off + 1)? as usize;
+            let value = payload.get(off + 2..off + 2 + len)?;
+            match code {
+                option::MESSAGE_TYPE if len == 1 => reply.msg_type = value[0],
+                option::SUBNET_MASK if len == 4 => reply.netmask = Some(addr(value)),
+                // The first router is used.
+                option::ROUTER if len >= 4 => reply.router = Some(addr(value)),
+                option::SERVER_ID if len == 4 => reply.server = Some(addr(value)),
+                option::LEASE_TIME if len == 4 => reply.lease_time = Some(seconds(value)),
+                option::RENEWAL_TIME if len == 4 => reply.t1 = Some(seconds(value)),
+                option::REBINDING_TIME if len == 4 => reply.t2 = Some(seconds(value)),
+                _ => {}
+            }
+            off += 2 + len;
+        }
+        Some(reply)
+    }
+
+    /// Configure the interface from an acknowledgment.
+    fn bind(&self, reply: &Reply, server: IP4Addr) {
+        let lease_time = match reply.lease_time {
+            Some(lease_time) if !reply.addr.is_unspecified() => lease_time,
+            _ => return,
+        };
+        let t2 = reply
+            .t2
+            .unwrap_or((lease_time as u64 * 7 / 8) as u32)
+            .min(lease_time);
+        let t1 = reply.t1.unwrap_or(lease_time / 2).min(t2);
+        let lease = Lease {
+            addr: reply.addr,
+            // Without a netmask, every destination is reached through the
+            // router.
+            netmask: reply.netmask.unwrap_or(IP4Addr::BROADCAST),
+            router: reply.router.unwrap_or(IP4Addr::UNSPECIFIED),
+            server: reply.server.unwrap_or(server),
+            t1: t1,
+            t2: t2,
+            expiry: lease_time,
+        };
+        let previous = self.interface.get_config();
+        let config = IP4Config {
+            addr: lease.addr,
+            netmask: lease.netmask,
+            gateway: lease.router,
+        };
+        if config != previous {
+            self.interface.set_config(config);
+        }
+        self.lease.set(lease);
+        self.elapsed.set(0);
+        self.state.set(State::Bound);
+        let _ = self.alarm.disarm();
+        self.wait(lease.t1);
+        if config.addr != previous.addr || config.netmask != previous.netmask {
+            self.notify(true, config);
+        }
+    }
+
+    /// Forget the lease, clear the address of the interface, and acquire a
+    /// new lease.
+    fn restart(&self) {
+        let _ = self.alarm.disarm();
+        if self.lease.take().is_some() {
+            let config = IP4Config {
+                addr: IP4Addr::UNSPECIFIED,
+                netmask: IP4Addr::UNSPECIFIED,
+                gateway: IP4Addr::UNSPECIFIED,
+            };
+            self.interface.set_config(config);
+            self.notify(false, config);
+        }
+        self.discover(0);
+    }
+
+    fn notify(&self, bound: bool, config: IP4Config) {
+        let addr = u32::from_be_bytes(config.addr.0) as usize;
+        let netmask = u32::from_be_bytes(config.netmask.0) as usize;
+        self.apps.each(|_, app| {
+            app.callback.schedule(bound as usize, addr, netmask);
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for DhcpClient<'a, A> {
+    fn send_done(&self, _result: Result<(), ErrorCode>, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buffer.replace(dgram);
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for DhcpClient<'a, A> {
+    fn receive(
+        &self,
+        _src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        if src_port != SERVER_PORT {
+            return;
+        }
+        let reply = match self.parse(payload) {
+            Some(reply) => reply,
+            None => return,
+        };
+        match (self.state.get(), reply.msg_type) {
+            (State::Selecting { .. }, message::OFFER) => {
+                if let Some(server) = reply.server {
+                    if !reply.addr.is_unspecified() {
+                        let _ = self.alarm.disarm();
+                        self.request(0, reply.addr, server);
+                    }
+                }
+            }
+            (State::Requesting { server, .. }, message::ACK) => self.bind(&reply, server),
+            (State::Renewing, message::ACK) | (State::Rebinding, message::ACK) => {
+                let server = self
+                    .lease
+                    .map_or(IP4Addr::UNSPECIFIED, |lease| lease.server);
+                self.bind(&reply, server);
+            }
+            (State::Requesting { .. }, message::NAK)
+            | (State::Renewing, message::NAK)
+            | (State::Rebinding, message::NAK) => self.restart(),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for DhcpClient<'a, A> {
+    fn alarm(&self) {
+        let elapsed = self.elapsed.get().saturating_add(self.step.get());
+        self.elapsed.set(elapsed);
+        let lease = self.lease.extract();
+        match (self.state.get(), lease) {
+            (State::Stopped, _) => {}
+            (State::Selecting { retries }, _) => self.discover(retries.saturating_add(1)),
+            (
+                State::Requesting {
+                    retries,
+                    addr,
+                    server,
+                },
+                _,
+            ) => {
+                if retries + 1 < MAX_REQUEST_RETRIES {
+                    self.request(retries + 1, addr, server);
+                } else {
+                    self.discover(0);
+                }
+            }
+            (State::Bound, Some(lease)) => {
+                if elapsed >= lease.t1 {
+                    self.state.set(State::Renewing);
+                    self.renew(lease.t2);
+                } else {
+                    self.wait(lease.t1 - elapsed);
+                }
+            }
+            (State::Renewing, Some(lease)) | (State::Rebinding, Some(lease)) => {
+                let renewing = self.state.get() == State::Renewing;
+                let limit = if renewing { lease.t2 } else { lease.expiry };
+                if elapsed >= lease.expiry {
+                    self.restart();
+                } else if renewing && elapsed >= lease.t2 {
+                    self.state.set(State::Rebinding);
+                    self.renew(lease.expiry);
+                } else if elapsed >= self.next_send.get() {
+                    self.renew(limit);
+                } else {
+                    self.wait(cmp::min(self.next_send.get(), limit) - elapsed);
+                }
+            }
+            // A lease state without a lease.
+            _ => self.restart(),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for DhcpClient<'a, A> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(&self, command_num: usize, _: usize, _: usize, _: ProcessId) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // address and netmask
+            1 => match self.lease.extract() {
+                Some(lease) => CommandReturn::success_u32_u32(
+                    u32::from_be_bytes(lease.addr.0),
+                    u32::from_be_bytes(lease.netmask.0),
+                ),
+                None => CommandReturn::failure(ErrorCode::OFF),
+            },
+
+            // router and time left
+            2 => match self.lease.extract() {
+                Some(lease) => CommandReturn::success_u32_u32(
+                    u32::from_be_bytes(lease.router.0),
+                    lease.expiry.saturating_sub(self.elapsed.get()),
+                ),
+                None => CommandReturn::failure(ErrorCode::OFF),
+            },
+
+            // renew now
+            3 => match (self.state.get(), self.lease.extract()) {
+                (State::Stopped, _) => self.start().into(),
+                (State::Bound, Some(lease)) => {
+                    let _ = self.alarm.disarm();
+                    self.state.set(State::Renewing);
+                    self.renew(lease.t2);
+                    CommandReturn::success()
+                }
+                (State::Renewing, _) | (State::Rebinding, _) => CommandReturn::success(),
+                _ => CommandReturn::failure(ErrorCode::BUSY),
+            },
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
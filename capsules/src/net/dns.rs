@@ -0,0 +1,977 @@
+//! DNS resolver and mDNS responder, on top of UDP.
+//!
+//! The resolver looks up the IPv4 (A) or IPv6 (AAAA) addresses of a name for
+//! processes. Names ending in `.local` are looked up with multicast DNS (RFC
+//! 6762) on the local network, and other names with a query to the DNS
+//! server (RFC 1035) set by the board, which must do recursion. A query is
+//! sent again every `QUERY_TIMEOUT_MS` up to `MAX_RETRIES` more times, and
+//! the lookup fails with `NOACK` if no answer arrives. One lookup runs at a
+//! time.
+//!
+//! Answers are kept in a cache of `CACHE_LEN` names for the time to live of
+//! their records, but at most `MAX_TTL_S`, and lookups of a cached name are
+//! answered from it. While the cache is not empty, the alarm fires every
+//! `CACHE_SWEEP_S` to count time and drop expired entries.
+//!
+//! The responder answers A and AAAA queries for `hostname.local`, so that
+//! the board can be found on the local network, with the addresses in the
+//! interface list. If an IPv4 interface is set with `set_ip4_interface()`,
+//! its current address is used instead of the IPv4-mapped addresses of the
+//! list, so that addresses from DHCP are announced. Probing and announcing
+//! are not implemented, and legacy unicast queries (from a port other than
+//! 5353) are not answered.
+//!
+//! IPv4 addresses are IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`), as in
+//! the UDP layer. To receive mDNS over IPv4, the interface must join the
+//! `MDNS_GROUP_IP4` multicast group.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let dns = static_init!(
+//!     capsules::net::dns::DnsResolver<'static, VirtualMuxAlarm<'static, Tim2>>,
+//!     capsules::net::dns::DnsResolver::new(
+//!         dns_alarm,
+//!         dns_send,
+//!         dns_recv,
+//!         mdns_send,
+//!         mdns_recv,
+//!         udp_port_table,
+//!         LeasableBuffer::new(dns_tx_buffer),
+//!         IP4Addr([192, 168, 1, 1]).to_mapped(),
+//!         "tock",
+//!         interface_list,
+//!         board_kernel.create_grant(&grant_cap),
+//!         net_cap,
+//!     )
+//! );
+//! dns_send.set_client(dns);
+//! dns_recv.set_client(dns);
+//! mdns_send.set_client(dns);
+//! mdns_recv.set_client(dns);
+//! dns_alarm.set_alarm_client(dns);
+//! dns.set_ip4_interface(ip4);
+//! ip4.join_multicast(capsules::net::dns::MDNS_GROUP_IP4);
+//! dns.start(53000);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: The name to look up, in ASCII, optionally terminated by
+//!   a null byte or a dot.
+//! - Read-write `0`: Buffer receiving the addresses found, 16 bytes each.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Lookup upcall, with the status, the number of addresses written
+//!   and their time to live in seconds. A name without addresses of the
+//!   type requested succeeds with no address, and an error from the server
+//!   fails with `FAIL`.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Look up the name, for IPv4 addresses if the argument is `1` or
+//!   IPv6 addresses if it is `28`. Returns `INVAL` if the name is invalid or
+//!   longer than `MAX_NAME_LEN`, and `BUSY` while another lookup runs.
+
+use crate::net::ipv4::ip4_interface::IP4Configuration;
+use crate::net::ipv4::ip4_utils::IP4Addr;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use core::{cmp, mem};
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Dns as usize;
+
+pub const SERVER_PORT: u16 = 53;
+pub const MDNS_PORT: u16 = 5353;
+
+/// The mDNS multicast groups, 224.0.0.251 and ff02::fb.
+pub const MDNS_GROUP_IP4: IP4Addr = IP4Addr([224, 0, 0, 251]);
+pub const MDNS_GROUP_IP6: IPAddr =
+    IPAddr([0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFB]);
+
+/// Length of the buffer needed for the longest message sent.
+pub const PACKET_LEN: usize = 256;
+
+/// Longest name looked up, without the final dot.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Most addresses kept for a name.
+pub const MAX_ADDRS: usize = 4;
+
+/// Number of names kept in the cache.
+pub const CACHE_LEN: usize = 4;
+
+/// Time to wait for an answer before sending the query again.
+pub const QUERY_TIMEOUT_MS: u32 = 2000;
+
+/// Number of times a query is sent again before the lookup fails.
+pub const MAX_RETRIES: u8 = 2;
+
+/// Longest time an answer is cached, in seconds.
+pub const MAX_TTL_S: u32 = 3600;
+
+/// Time to live of the records sent by the responder, in seconds.
+pub const MDNS_TTL_S: u32 = 120;
+
+/// Time between two sweeps of the cache, in seconds, short enough for the
+/// alarm's counter not to wrap between them even at high frequencies.
+const CACHE_SWEEP_S: u32 = 60;
+
+const HEADER_LEN: usize = 12;
+const MAX_LABEL_LEN: usize = 63;
+/// Most compression pointers followed in a name, against loops.
+const MAX_POINTERS: usize = 8;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_OPCODE: u16 = 0x7800;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_RD: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000F;
+
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+/// The top bit of the class, the cache-flush bit in mDNS answers and the
+/// unicast-response bit in mDNS questions.
+const CLASS_MASK: u16 = 0x7FFF;
+const CACHE_FLUSH: u16 = 0x8000;
+
+mod rtype {
+    pub const A: u16 = 1;
+    pub const AAAA: u16 = 28;
+    pub const ANY: u16 = 255;
+}
+
+/// A name, in ASCII with dots between its labels.
+#[derive(Copy, Clone)]
+struct Name {
+    bytes: [u8; MAX_NAME_LEN],
+    len: usize,
+}
+
+impl Name {
+    /// The name in `bytes`, up to a null byte and without a final dot, or
+    /// `None` if it is empty, too long, or has an empty or too long label.
+    fn from_bytes(bytes: &[u8]) -> Option<Name> {
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        let mut bytes = &bytes[..len];
+        if bytes.last() == Some(&b'.') {
+            bytes = &bytes[..bytes.len() - 1];
+        }
+        if bytes.is_empty()
+            || bytes.len() > MAX_NAME_LEN
+            || bytes
+                .split(|&b| b == b'.')
+                .any(|label| label.is_empty() || label.len() > MAX_LABEL_LEN)
+        {
+            return None;
+        }
+        let mut name = Name {
+            bytes: [0; MAX_NAME_LEN],
+            len: bytes.len(),
+        };
+        name.bytes[..bytes.len()].copy_from_slice(bytes);
+        Some(name)
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn eq_ignore_case(&self, other: &Name) -> bool {
+        self.as_bytes().eq_ignore_ascii_case(other.as_bytes())
+    }
+
+    fn is_local(&self) -> bool {
+        let bytes = self.as_bytes();
+        bytes.len() > 6 && bytes[bytes.len() - 6..].eq_ignore_ascii_case(b".local")
+    }
+
+    /// Length of the name in a message, without compression.
+    fn encoded_len(&self) -> usize {
+        self.len + 2
+    }
+
+    /// Write the labels of the name at `off`, and return the offset after
+    /// them.
+    fn encode(&self, buf: &mut [u8], mut off: usize) -> usize {
+        for label in self.as_bytes().split(|&b| b == b'.') {
+            buf[off] = label.len() as u8;
+            buf[off + 1..off + 1 + label.len()].copy_from_slice(label);
+            off += 1 + label.len();
+        }
+        buf[off] = 0;
+        off + 1
+    }
+}
+
+fn read_u16(msg: &[u8], off: usize) -> Option<u16> {
+    msg.get(off..off + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(msg: &[u8], off: usize) -> Option<u32> {
+    msg.get(off..off + 4)
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// The offset after the name at `off` in `msg`.
+fn skip_name(msg: &[u8], mut off: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(off)? as usize;
+        if len == 0 {
+            return Some(off + 1);
+        } else if len & 0xC0 == 0xC0 {
+            return Some(off + 2);
+        } else if len & 0xC0 != 0 {
+            return None;
+        }
+        off += 1 + len;
+    }
+}
+
+/// Whether the name at `off` in `msg` is `name`, ignoring case.
+fn name_matches(msg: &[u8], mut off: usize, name: &Name) -> Option<bool> {
+    let name = name.as_bytes();
+    let mut pos = 0;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(off)? as usize;
+        if len & 0xC0 == 0xC0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            off = (len & 0x3F) << 8 | *msg.get(off + 1)? as usize;
+            continue;
+        } else if len & 0xC0 != 0 {
+            return None;
+        } else if len == 0 {
+            return Some(pos == name.len());
+        }
+        if pos > 0 {
+            if name.get(pos) != Some(&b'.') {
+                return Some(false);
+            }
+            pos += 1;
+        }
+        let label = msg.get(off + 1..off + 1 + len)?;
+        match name.get(pos..pos + len) {
+            Some(part) if part.eq_ignore_ascii_case(label) => {}
+            _ => return Some(false),
+        }
+        pos += len;
+        off += 1 + len;
+    }
+}
+
+/// The addresses found for a name.
+#[derive(Copy, Clone)]
+struct Answer {
+    addrs: [IPAddr; MAX_ADDRS],
+    count: usize,
+    ttl: u32,
+}
+
+#[derive(Copy, Clone)]
+struct CacheEntry {
+    name: Name,
+    qtype: u16,
+    answer: Answer,
+    /// When the entry expires, in seconds of the resolver's clock.
+    expiry: u32,
+}
+
+/// The lookup running.
+#[derive(Copy, Clone)]
+struct Query {
+    appid: ProcessId,
+    name: Name,
+    qtype: u16,
+    id: u16,
+    retries: u8,
+    mdns: bool,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    name: ReadOnlyAppSlice,
+    result: ReadWriteAppSlice,
+}
+
+pub struct DnsResolver<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    dns_sender: &'a dyn UDPSender<'a>,
+    dns_receiver: &'a UDPReceiver<'a>,
+    mdns_sender: &'a dyn UDPSender<'a>,
+    mdns_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    server: Cell<IPAddr>,
+    /// `hostname.local`, or an empty name if the hostname is too long.
+    local_name: Name,
+    interface_list: &'static [IPAddr],
+    ip4: OptionalCell<&'a dyn IP4Configuration>,
+    query: OptionalCell<Query>,
+    cache: MapCell<[Option<CacheEntry>; CACHE_LEN]>,
+    /// Seconds counted since the resolver started, and the time of the
+    /// last second counted.
+    clock: Cell<u32>,
+    clock_ref: Cell<A::Ticks>,
+    apps: Grant<App>,
+    net_cap: &'static NetworkCapability,
+}
+
+impl<'a, A: Alarm<'a>> DnsResolver<'a, A> {
+    /// `server` is the DNS server, and `hostname` the name announced with
+    /// mDNS, without `.local`. The responder is disabled if `hostname` is
+    /// longer than `MAX_NAME_LEN - 6` bytes.
+    pub fn new(
+        alarm: &'a A,
+        dns_sender: &'a dyn UDPSender<'a>,
+        dns_receiver: &'a UDPReceiver<'a>,
+        mdns_sender: &'a dyn UDPSender<'a>,
+        mdns_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        tx_buffer: LeasableBuffer<'static, u8>,
+        server: IPAddr,
+        hostname: &'static str,
+        interface_list: &'static [IPAddr],
+        grant: Grant<App>,
+        net_cap: &'static NetworkCapability,
+    ) -> DnsResolver<'a, A> {
+        let mut local_name = [0; MAX_NAME_LEN];
+        let hostname = hostname.as_bytes();
+        let len = hostname.len() + 6;
+        let local_name = if len <= MAX_NAME_LEN {
+            local_name[..hostname.len()].copy_from_slice(hostname);
+            local_name[hostname.len()..len].copy_from_slice(b".local");
+            Name::from_bytes(&local_name[..len])
+        } else {
+            None
+        };
+        DnsResolver {
+            alarm: alarm,
+            dns_sender: dns_sender,
+            dns_receiver: dns_receiver,
+            mdns_sender: mdns_sender,
+            mdns_receiver: mdns_receiver,
+            port_table: port_table,
+            tx_buffer: MapCell::new(tx_buffer),
+            server: Cell::new(server),
+            local_name: local_name.unwrap_or(Name {
+                bytes: [0; MAX_NAME_LEN],
+                len: 0,
+            }),
+            interface_list: interface_list,
+            ip4: OptionalCell::empty(),
+            query: OptionalCell::empty(),
+            cache: MapCell::new([None; CACHE_LEN]),
+            clock: Cell::new(0),
+            clock_ref: Cell::new(alarm.now()),
+            apps: grant,
+            net_cap: net_cap,
+        }
+    }
+
+    /// Announce the current address of `ip4` with mDNS, in place of the
+    /// IPv4-mapped addresses of the interface list.
+    pub fn set_ip4_interface(&self, ip4: &'a dyn IP4Configuration) {
+        self.ip4.set(ip4);
+    }
+
+    /// Change the DNS server, for example to one given by DHCP. This clears
+    /// the cache.
+    pub fn set_server(&self, server: IPAddr) {
+        self.server.set(server);
+        self.cache.map(|cache| *cache = [None; CACHE_LEN]);
+    }
+
+    /// Bind the resolver to `client_port` and the responder to the mDNS
+    /// port.
+    pub fn start(&self, client_port: u16) -> Result<(), ErrorCode> {
+        if self.dns_sender.is_bound() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.bind(self.dns_sender, self.dns_receiver, client_port)?;
+        self.bind(self.mdns_sender, self.mdns_receiver, MDNS_PORT)
+    }
+
+    fn bind(
+        &self,
+        sender: &'a dyn UDPSender<'a>,
+        receiver: &'a UDPReceiver<'a>,
+        port: u16,
+    ) -> Result<(), ErrorCode> {
+        let socket = self
+            .port_table
+            .create_socket()
+            .map_err(|_| ErrorCode::NOMEM)?;
+        match self.port_table.bind(socket, port, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                sender.set_binding(send_binding);
+                receiver.set_binding(recv_binding);
+                Ok(())
+            }
+            // Dropping the socket frees it.
+            Err(_socket) => Err(ErrorCode::INVAL),
+        }
+    }
+
+    /// Count the whole seconds passed since the last call.
+    fn update_clock(&self) -> u32 {
+        let second = A::ticks_from_seconds(1).into_u32();
+        let elapsed = self
+            .alarm
+            .now()
+            .wrapping_sub(self.clock_ref.get())
+            .into_u32()
+            / second;
+        self.clock_ref.set(
+            self.clock_ref
+                .get()
+                .wrapping_add(A::ticks_from_seconds(elapsed)),
+        );
+        self.clock.set(self.clock.get().saturating_add(elapsed));
+        self.clock.get()
+    }
+
+    /// The multicast group of mDNS on the transport of the interfaces.
+    fn mdns_group(&self) -> IPAddr {
+        if self.ip4.is_some() || IP4Addr::from_mapped(self.server.get()).is_some() {
+            MDNS_GROUP_IP4.to_mapped()
+        } else {
+            MDNS_GROUP_IP6
+        }
+    }
+
+    /// The addresses of the board.
+    fn local_addrs(&self) -> ([IPAddr; MAX_ADDRS], usize) {
+        let mut addrs = [IPAddr::new(); MAX_ADDRS];
+        let mut count = 0;
+        let ip4_addr = self
+            .ip4
+            .map(|ip4| ip4.get_config().addr)
+            .filter(|addr| !addr.is_unspecified());
+        if let Some(addr) = ip4_addr {
+            addrs[count] = addr.to_mapped();
+            count += 1;
+        }
+        for addr in self.interface_list.iter() {
+            let mapped = IP4Addr::from_mapped(*addr).is_some();
+            if count < MAX_ADDRS && !(mapped && self.ip4.is_some()) {
+                addrs[count] = *addr;
+                count += 1;
+            }
+        }
+        (addrs, count)
+    }
+
+    /// The cached answer for `name`, with the time it has left.
+    fn cache_lookup(&self, name: &Name, qtype: u16) -> Option<Answer> {
+        let now = self.update_clock();
+        self.cache.map_or(None, |cache| {
+            cache
+                .iter()
+                .flatten()
+                .find(|entry| {
+                    entry.qtype == qtype && entry.expiry > now && entry.name.eq_ignore_case(name)
+                })
+                .map(|entry| {
+                    let mut answer = entry.answer;
+                    answer.ttl = entry.expiry - now;
+                    answer
+                })
+        })
+    }
+
+    /// Cache `answer`, in place of the entry for the same name, or else of
+    /// the entry expiring first.
+    fn cache_insert(&self, name: &Name, qtype: u16, answer: &Answer) {
+        let now = self.update_clock();
+        let entry = CacheEntry {
+            name: *name,
+            qtype: qtype,
+            answer: *answer,
+            expiry: now.saturating_add(answer.ttl),
+        };
+        self.cache.map(|cache| {
+            let index = cache
+                .iter()
+                .position(|slot| {
+                    slot.map_or(true, |old| {
+                        old.expiry <= now || (old.qtype == qtype && old.name.eq_ignore_case(name))
+                    })
+                })
+                .or_else(|| (0..CACHE_LEN).min_by_key(|&i| cache[i].map_or(0, |old| old.expiry)))
+                .unwrap_or(0);
+            cache[index] = Some(entry);
+        });
+    }
+
+    /// Drop the expired entries, and wait for the next sweep if the cache is
+    /// not empty.
+    fn sweep(&self) {
+        let now = self.update_clock();
+        let empty = self.cache.map_or(true, |cache| {
+            for slot in cache.iter_mut() {
+                if slot.map_or(false, |entry| entry.expiry <= now) {
+                    *slot = None;
+                }
+            }
+            cache.iter().all(|slot| slot.is_none())
+        });
+        if empty {
+            let _ = self.alarm.disarm();
+        } else {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_seconds(CACHE_SWEEP_S));
+        }
+    }
+
+    /// Look up `name` for `appid`, from the cache or with a query.
+    fn lookup(&self, appid: ProcessId, name: Name, qtype: u16) -> Result<(), ErrorCode> {
+        if self.query.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if let Some(answer) = self.cache_lookup(&name, qtype) {
+            self.finish(appid, Ok(answer));
+            return Ok(());
+        }
+        let query = Query {
+            appid: appid,
+            name: name,
+            qtype: qtype,
+            id: self.alarm.now().into_u32().wrapping_mul(2_654_435_761) as u16,
+            retries: 0,
+            mdns: name.is_local(),
+        };
+        self.query.set(query);
+        self.send_query(&query);
+        Ok(())
+    }
+
+    /// Send `query`, and wait for its answer. If the buffer is busy, the
+    /// query is sent again when the timeout expires.
+    fn send_query(&self, query: &Query) {
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(QUERY_TIMEOUT_MS));
+        let mut buffer = match self.tx_buffer.take() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        buffer.reset();
+        let len = HEADER_LEN + query.name.encoded_len() + 4;
+        if buffer.len() < len {
+            self.tx_buffer.replace(buffer);
+            return;
+        }
+        // mDNS queries have no identifier, and ask for no recursion.
+        let (id, flags) = if query.mdns {
+            (0, 0)
+        } else {
+            (query.id, FLAG_RD)
+        };
+        buffer[0..2].copy_from_slice(&id.to_be_bytes());
+        buffer[2..4].copy_from_slice(&flags.to_be_bytes());
+        buffer[4..6].copy_from_slice(&1u16.to_be_bytes());
+        for byte in buffer[6..HEADER_LEN].iter_mut() {
+            *byte = 0;
+        }
+        let off = query.name.encode(&mut buffer[..], HEADER_LEN);
+        buffer[off..off + 2].copy_from_slice(&query.qtype.to_be_bytes());
+        buffer[off + 2..off + 4].copy_from_slice(&CLASS_IN.to_be_bytes());
+
+        buffer.slice(0..len);
+        let result = if query.mdns {
+            self.mdns_sender
+                .send_to(self.mdns_group(), MDNS_PORT, buffer, self.net_cap)
+        } else {
+            self.dns_sender
+                .send_to(self.server.get(), SERVER_PORT, buffer, self.net_cap)
+        };
+        if let Err(mut buffer) = result {
+            buffer.reset();
+            self.tx_buffer.replace(buffer);
+        }
+    }
+
+    /// Parse a response to `query`. Returns `None` if the response is not
+    /// for it.
+    fn parse_response(&self, msg: &[u8], query: &Query) -> Option<Result<Answer, ErrorCode>> {
+        let id = read_u16(msg, 0)?;
+        let flags = read_u16(msg, 2)?;
+        let qdcount = read_u16(msg, 4)?;
+        let ancount = read_u16(msg, 6)?;
+        if flags & FLAG_QR == 0 || flags & FLAG_OPCODE != 0 {
+            return None;
+        }
+        if !query.mdns {
+            // The server must repeat the question.
+            if id != query.id
+                || qdcount != 1
+                || name_matches(msg, HEADER_LEN, &query.name) != Some(true)
+            {
+                return None;
+            }
+            if flags & RCODE_MASK != 0 {
+                return Some(Err(ErrorCode::FAIL));
+            }
+        }
+
+        let mut off = HEADER_LEN;
+        for _ in 0..qdcount {
+            off = skip_name(msg, off)? + 4;
+        }
+        let mut answer = Answer {
+            addrs: [IPAddr::new(); MAX_ADDRS],
+            count: 0,
+            ttl: MAX_TTL_S,
+        };
+        for _ in 0..ancount {
+            let name_off = off;
+            off = skip_name(msg, off)?;
+            let rtype = read_u16(msg, off)?;
+            let class = read_u16(msg, off + 2)?;
+            let ttl = read_u32(msg, off + 4)?;
+            let rdlen = read_u16(msg, off + 8)? as usize;
+            let rdata = msg.get(off + 10..off + 10 + rdlen)?;
+            off += 10 + rdlen;
+
+            // Answers from a server may be for the target of an alias, but
+            // mDNS responders may answer other questions at the same time.
+            if rtype != query.qtype
+                || class & CLASS_MASK != CLASS_IN
+                || answer.count == MAX_ADDRS
+                || (query.mdns && name_matches(msg, name_off, &query.name) != Some(true))
+            {
+                continue;
+            }
+            let addr = match (rtype, rdlen) {
+                (rtype::A, 4) => {
+                    let mut addr = IP4Addr::UNSPECIFIED;
+                    addr.0.copy_from_slice(rdata);
+                    addr.to_mapped()
+                }
+                (rtype::AAAA, 16) => {
+                    let mut addr = IPAddr::new();
+                    addr.0.copy_from_slice(rdata);
+                    addr
+                }
+                _ => continue,
+            };
+            answer.addrs[answer.count] = addr;
+            answer.count += 1;
+            answer.ttl = cmp::min(answer.ttl, ttl);
+        }
+
+        if query.mdns && answer.count == 0 {
+            return None;
+        }
+        if answer.count == 0 {
+            answer.ttl = 0;
+        }
+        Some(Ok(answer))
+    }
+
+    fn receive_response(&self, msg: &[u8], mdns: bool) {
+        let query = match self.query.extract() {
+            Some(query) if query.mdns == mdns => query,
+            _ => return,
+        };
+        let result = match self.parse_response(msg, &query) {
+            Some(result) => result,
+            None => return,
+        };
+        self.query.clear();
+        if let Ok(answer) = result {
+            if answer.count > 0 && answer.ttl > 0 {
+                self.cache_insert(&query.name, query.qtype, &answer);
+            }
+        }
+        self.finish(query.appid, result);
+        self.sweep();
+    }
+
+    /// Answer the questions of an mDNS query for the local name.
+    fn respond(&self, src_addr: IPAddr, src_port: u16, msg: &[u8]) {
+        if src_port != MDNS_PORT || self.local_name.len == 0 {
+            return;
+        }
+        let (want_a, want_aaaa) = match self.parse_query(msg) {
+            Some(wanted) => wanted,
+            None => return,
+        };
+        let (addrs, count) = self.local_addrs();
+        let mut buffer = match self.tx_buffer.take() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        buffer.reset();
+
+        let mut off = HEADER_LEN;
+        let mut ancount: u16 = 0;
+        for addr in addrs[..count].iter() {
+            let (rtype, rdata) = match IP4Addr::from_mapped(*addr) {
+                Some(_) if want_a => (rtype::A, &addr.0[12..16]),
+                None if want_aaaa => (rtype::AAAA, &addr.0[..]),
+                _ => continue,
+            };
+            // Answers after the first point to its name.
+            let name_len = if ancount == 0 {
+                self.local_name.encoded_len()
+            } else {
+                2
+            };
+            if off + name_len + 10 + rdata.len() > buffer.len() {
+                break;
+            }
+            off = if ancount == 0 {
+                self.local_name.encode(&mut buffer[..], off)
+            } else {
+                buffer[off..off + 2].copy_from_slice(&[0xC0, HEADER_LEN as u8]);
+                off + 2
+            };
+            buffer[off..off + 2].copy_from_slice(&rtype.to_be_bytes());
+            buffer[off + 2..off + 4].copy_from_slice(&(CLASS_IN | CACHE_FLUSH).to_be_bytes());
+            buffer[off + 4..off + 8].copy_from_slice(&MDNS_TTL_S.to_be_bytes());
+            buffer[off + 8..off + 10].copy_from_slice(&(rdata.len() as u16).to_be_bytes());
+            buffer[off + 10..off + 10 + rdata.len()].copy_from_slice(rdata);
+            off += 10 + rdata.len();
+            ancount += 1;
+        }
+        if ancount == 0 {
+            self.tx_buffer.replace(buffer);
+            return;
+        }
+        for byte in buffer[0..HEADER_LEN].iter_mut() {
+            *byte = 0;
+        }
+        buffer[2..4].copy_from_slice(&(FLAG_QR | FLAG_AA).to_be_bytes());
+        buffer[6..8].copy_from_slice(&ancount.to_be_bytes());
+
+        // Answer on the transport of the query.
+        let dst = if IP4Addr::from_mapped(src_addr).is_some() {
+            MDNS_GROUP_IP4.to_mapped()
+        } else {
+            MDNS_GROUP_IP6
+        };
+        buffer.slice(0..off);
+        if let Err(mut buffer) = self
+            .mdns_sender
+            .send_to(dst, MDNS_PORT, buffer, self.net_cap)
+        {
+            buffer.reset();
+            self.tx_buffer.replace(buffer);
+        }
+    }
+
+    /// Whether an mDNS query asks for the A or AAAA records of the local
+    /// name.
+    fn parse_query(&self, msg: &[u8]) -> Option<(bool, bool)> {
+        let flags = read_u16(msg, 2)?;
+        let qdcount = read_u16(msg, 4)?;
+        if flags & (FLAG_QR | FLAG_OPCODE) != 0 {
+            return None;
+        }
+        let mut want_a = false;
+        let mut want_aaaa = false;
+        let mut off = HEADER_LEN;
+        for _ in 0..qdcount {
+            let name_off = off;
+            off = skip_name(msg, off)?;
+            let qtype = read_u16(msg, off)?;
+            let class = read_u16(msg, off + 2)? & CLASS_MASK;
+            off += 4;
+            if (class == CLASS_IN || class == CLASS_ANY)
+                && name_matches(msg, name_off, &self.local_name) == Some(true)
+            {
+                want_a |= qtype == rtype::A || qtype == rtype::ANY;
+                want_aaaa |= qtype == rtype::AAAA || qtype == rtype::ANY;
+            }
+        }
+        Some((want_a, want_aaaa))
+    }
+
+    /// Give the result of a lookup to `appid`.
+    fn finish(&self, appid: ProcessId, result: Result<Answer, ErrorCode>) {
+        let _ = self.apps.enter(appid, |app| match result {
+            Ok(answer) => {
+                let count = app.result.mut_map_or(0, |buf| {
+                    let count = cmp::min(answer.count, buf.len() / 16);
+                    for (i, addr) in answer.addrs[..count].iter().enumerate() {
+                        buf[i * 16..(i + 1) * 16].copy_from_slice(&addr.0);
+                    }
+                    count
+                });
+                app.callback
+                    .schedule(kernel::into_statuscode(Ok(())), count, answer.ttl as usize);
+            }
+            Err(e) => {
+                app.callback.schedule(kernel::into_statuscode(Err(e)), 0, 0);
+            }
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for DnsResolver<'a, A> {
+    fn send_done(&self, _result: Result<(), ErrorCode>, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buffer.replace(dgram);
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for DnsResolver<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        dst_port: u16,
+        payload: &[u8],
+    ) {
+        if payload.len() < HEADER_LEN {
+            return;
+        }
+        let response = payload[2] & 0x80 != 0;
+        if dst_port == MDNS_PORT {
+            if response {
+                self.receive_response(payload, true);
+            } else {
+                self.respond(src_addr, src_port, payload);
+            }
+        } else if response && src_port == SERVER_PORT {
+            self.receive_response(payload, false);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for DnsResolver<'a, A> {
+    fn alarm(&self) {
+        if let Some(mut query) = self.query.extract() {
+            if query.retries < MAX_RETRIES {
+                query.retries += 1;
+                self.query.set(query);
+                self.send_query(&query);
+                return;
+            }
+            self.query.clear();
+            self.finish(query.appid, Err(ErrorCode::NOACK));
+        }
+        self.sweep();
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for DnsResolver<'a, A> {
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| mem::swap(&mut app.name, &mut slice))
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| mem::swap(&mut app.result, &mut slice))
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| mem::swap(&mut app.callback, &mut callback))
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        qtype: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // look up
+            1 => {
+                if qtype != rtype::A as usize && qtype != rtype::AAAA as usize {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                let name = self
+                    .apps
+                    .enter(appid, |app| {
+                        app.name.map_or(None, |name| Name::from_bytes(name))
+                    })
+                    .unwrap_or(None);
+                match name {
+                    Some(name) => self.lookup(appid, name, qtype as u16).into(),
+                    None => CommandReturn::failure(ErrorCode::INVAL),
+                }
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,1464 @@
+//! DTLS 1.2 client (RFC 6347) with pre-shared keys, on top of UDP.
+//!
+//! `DtlsSession` secures the datagrams exchanged with a single server with
+//! the `TLS_PSK_WITH_AES_128_GCM_SHA256` cipher suite (RFC 5487). It sits
+//! between a UDP user and the UDP stack: it implements `UDPSender` for the
+//! user and is the client of the `UDPSender` and `UDPReceiver` below it, so
+//! an existing user such as `CoapEndpoint` opts into DTLS by being given the
+//! session instead of its `UDPSender`, and by receiving through the session.
+//! Processes using the CoAP syscall driver of such an endpoint then talk to
+//! the server over DTLS too. Processes using the UDP syscall driver reach
+//! the session through the secure sockets of the driver, once the board
+//! gives it the session with `UDPDriver::set_secure_sender()`.
+//!
+//! Datagrams sent to the server address and port set with `set_server()`
+//! are encrypted as application data records. The first one starts the
+//! handshake if no session is established, and is sent once it completes.
+//! One datagram is sent at a time. Datagrams to other destinations are
+//! refused, and records from other sources are dropped.
+//!
+//! The handshake follows RFC 6347, Section 4.2: a cookie requested with a
+//! HelloVerifyRequest is echoed in a second ClientHello, and flights are
+//! retransmitted with a timeout starting at `RETRANSMIT_TIMEOUT_MS` and
+//! doubling `MAX_RETRANSMITS` times, after which the handshake fails with
+//! `NOACK`. Received application data records are checked against a replay
+//! window of 64 records. Once the session is established, records of epoch 0
+//! are dropped (RFC 6347, Section 4.1.2.7), so only the encrypted alerts of
+//! the server can close it.
+//!
+//! Records are encrypted with the `AES128GCM` HIL, and the PRF and the hashes
+//! of the handshake transcript are computed with a `digest` engine in SHA-256
+//! mode, one hash at a time. The HMACs of the PRF are built on the plain
+//! digests, since their keys are longer than `HMACSha256` supports. Boards
+//! without a digest engine use `crate::sha256::Sha256Software`.
+//!
+//! Limitations:
+//!
+//! - Only pre-shared keys, and only AES-128-GCM; no certificates.
+//! - Handshake messages must not be fragmented, and only the first
+//!   encrypted record of each datagram is processed.
+//! - No session resumption or renegotiation, and no extensions are sent.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let dtls = static_init!(
+//!     capsules::net::dtls::DtlsSession<
+//!         'static,
+//!         VirtualMuxAlarm<'static, Rtc>,
+//!         capsules::sha256::Sha256Software<'static>,
+//!     >,
+//!     capsules::net::dtls::DtlsSession::new(
+//!         dtls_alarm,
+//!         aes_gcm,
+//!         sha,
+//!         rng,
+//!         udp_send,
+//!         LeasableBuffer::new(dtls_tx_buffer),
+//!         dtls_crypt_buffer,
+//!         dtls_hash_buffer,
+//!         dtls_transcript_buffer,
+//!         dtls_digest_buffer,
+//!         net_cap,
+//!     )
+//! );
+//! let coap = static_init!(
+//!     capsules::net::coap::CoapEndpoint<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::net::coap::CoapEndpoint::new(
+//!         coap_alarm,
+//!         dtls,
+//!         udp_recv,
+//!         udp_port_table,
+//!         LeasableBuffer::new(coap_tx_buffer),
+//!         coap_request_buffer,
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(dtls);
+//! udp_recv.set_client(dtls);
+//! dtls_alarm.set_alarm_client(dtls);
+//! AES128GCM::set_client(aes_gcm, dtls);
+//! hil::digest::Digest::set_client(sha, dtls);
+//! rng.set_client(dtls);
+//! dtls.set_client(coap);
+//! dtls.set_receive_client(coap);
+//! dtls.set_server(server_addr, capsules::net::dtls::COAPS_PORT);
+//! dtls.set_psk(b"identity", &PSK);
+//! coap_alarm.set_alarm_client(coap);
+//! coap.bind(capsules::net::coap::COAP_PORT);
+//! ```
+
+use crate::aes::tags_equal;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortBindingTx;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use crate::net::udp::UDPHeader;
+use core::cell::Cell;
+use core::cmp;
+use kernel::capabilities::UdpDriverCapability;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::hil::rng;
+use kernel::hil::symmetric_encryption::{
+    GCMClient, AES128GCM, AES128_KEY_SIZE, GCM_NONCE_LENGTH, GCM_TAG_LENGTH,
+};
+use kernel::hil::time::{self, Alarm};
+use kernel::ErrorCode;
+
+/// Default UDP port of CoAP over DTLS.
+pub const COAPS_PORT: u16 = 5684;
+
+/// Longest PSK identity and PSK supported.
+pub const MAX_PSK_IDENTITY_LEN: usize = 64;
+pub const MAX_PSK_LEN: usize = 64;
+
+/// Longest cookie accepted from a HelloVerifyRequest.
+pub const MAX_COOKIE_LEN: usize = 32;
+
+pub const RECORD_HEADER_LEN: usize = 13;
+
+/// Bytes added to the payload of an encrypted record: its header, the
+/// explicit part of the nonce and the tag. The transmit buffer must hold
+/// the longest datagram sent plus this overhead, and the crypt buffer the
+/// longest datagram sent or received plus `CRYPT_OVERHEAD`.
+pub const RECORD_OVERHEAD: usize = RECORD_HEADER_LEN + EXPLICIT_NONCE_LEN + GCM_TAG_LENGTH;
+pub const CRYPT_OVERHEAD: usize = AAD_LEN + GCM_TAG_LENGTH;
+
+/// Length of the hash buffer: the padded key of an HMAC followed by the
+/// longest message the PRF authenticates, A(i) and the label and seeds.
+pub const HASH_BUFFER_LEN: usize = HMAC_BLOCK_LEN + 32 + MAX_SEED_LEN;
+
+/// Length of the transcript buffer, which holds the handshake messages
+/// hashed into the Finished messages. Servers sending long extensions or
+/// identity hints need a longer one.
+pub const TRANSCRIPT_BUFFER_LEN: usize = 512;
+
+/// Time before the first retransmission of a flight, in milliseconds.
+const RETRANSMIT_TIMEOUT_MS: u32 = 1000;
+
+/// Number of retransmissions of a flight.
+const MAX_RETRANSMITS: u8 = 5;
+
+const VERSION: [u8; 2] = [0xFE, 0xFD];
+const TLS_PSK_WITH_AES_128_GCM_SHA256: [u8; 2] = [0x00, 0xA8];
+
+const HANDSHAKE_HEADER_LEN: usize = 12;
+const EXPLICIT_NONCE_LEN: usize = 8;
+const AAD_LEN: usize = 13;
+const RANDOM_LEN: usize = 32;
+const MASTER_SECRET_LEN: usize = 48;
+const IV_LEN: usize = GCM_NONCE_LENGTH - EXPLICIT_NONCE_LEN;
+const VERIFY_DATA_LEN: usize = 12;
+const FINISHED_LEN: usize = HANDSHAKE_HEADER_LEN + VERIFY_DATA_LEN;
+const KEY_BLOCK_LEN: usize = 2 * AES128_KEY_SIZE + 2 * IV_LEN;
+
+const HMAC_BLOCK_LEN: usize = 64;
+const HMAC_IPAD: u8 = 0x36;
+const HMAC_OPAD: u8 = 0x5c;
+/// Longest label of the PRF and its seeds: a label such as "client
+/// finished" with the two randoms is the longest.
+const MAX_SEED_LEN: usize = 15 + 2 * RANDOM_LEN;
+
+mod content_type {
+    pub const CHANGE_CIPHER_SPEC: u8 = 20;
+    pub const ALERT: u8 = 21;
+    pub const HANDSHAKE: u8 = 22;
+    pub const APPLICATION_DATA: u8 = 23;
+}
+
+mod handshake_type {
+    pub const CLIENT_HELLO: u8 = 1;
+    pub const SERVER_HELLO: u8 = 2;
+    pub const HELLO_VERIFY_REQUEST: u8 = 3;
+    pub const SERVER_KEY_EXCHANGE: u8 = 12;
+    pub const SERVER_HELLO_DONE: u8 = 14;
+    pub const CLIENT_KEY_EXCHANGE: u8 = 16;
+    pub const FINISHED: u8 = 20;
+}
+
+const ALERT_LEVEL_FATAL: u8 = 2;
+const ALERT_CLOSE_NOTIFY: u8 = 0;
+
+pub trait DtlsClient {
+    /// The handshake started by `connect()` or by the first datagram sent
+    /// completed. `NOACK` means the server did not answer, and `FAIL` that
+    /// it rejected the handshake.
+    fn connect_done(&self, result: Result<(), ErrorCode>);
+
+    /// The server closed the session, or an alert ended it.
+    fn disconnected(&self);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum State {
+    Closed,
+    /// Waiting for the client random.
+    Random,
+    /// Sent the ClientHello, waiting for a HelloVerifyRequest or a
+    /// ServerHello.
+    ClientHello,
+    /// Received the ServerHello, waiting for the ServerHelloDone.
+    ServerHello,
+    /// Deriving the keys and the Finished messages.
+    Deriving,
+    /// Sent the client Finished, waiting for the server's.
+    Finished,
+    Connected,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum AppTx {
+    None,
+    /// The datagram of the client waits for the session or the buffers.
+    Queued,
+    /// The datagram of the client is being encrypted or sent.
+    Sending,
+}
+
+#[derive(Copy, Clone)]
+enum CryptOp {
+    Idle,
+    Encrypt {
+        content_type: u8,
+        seq: u64,
+        m_len: usize,
+    },
+    Decrypt {
+        content_type: u8,
+        seq: u64,
+        m_len: usize,
+    },
+}
+
+/// The value the PRF being computed derives.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Derive {
+    MasterSecret,
+    KeyBlock,
+    /// The verify data of the Finished messages, from the hash of the
+    /// transcript.
+    ClientFinished,
+    ServerFinished,
+}
+
+/// The hash the digest engine computes.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum HashOp {
+    Idle,
+    /// A PRF secret longer than an HMAC block.
+    KeyHash,
+    /// Inner and outer hashes of the HMAC giving A(i) of the PRF.
+    AInner,
+    AOuter,
+    /// Inner and outer hashes of the HMAC giving an output block of the PRF.
+    BlockInner,
+    BlockOuter,
+    Transcript,
+}
+
+#[derive(Copy, Clone)]
+struct Keys {
+    client_key: [u8; AES128_KEY_SIZE],
+    server_key: [u8; AES128_KEY_SIZE],
+    client_iv: [u8; IV_LEN],
+    server_iv: [u8; IV_LEN],
+}
+
+fn write_record_header(buf: &mut [u8], content_type: u8, epoch: u16, seq: u64, len: usize) {
+    buf[0] = content_type;
+    buf[1..3].copy_from_slice(&VERSION);
+    buf[3..5].copy_from_slice(&epoch.to_be_bytes());
+    buf[5..11].copy_from_slice(&seq.to_be_bytes()[2..]);
+    buf[11..13].copy_from_slice(&(len as u16).to_be_bytes());
+}
+
+/// Write the header of an unfragmented handshake message.
+fn write_handshake_header(buf: &mut [u8], msg_type: u8, len: usize, message_seq: u16) {
+    buf[0] = msg_type;
+    buf[1..4].copy_from_slice(&(len as u32).to_be_bytes()[1..]);
+    buf[4..6].copy_from_slice(&message_seq.to_be_bytes());
+    buf[6..9].copy_from_slice(&[0; 3]);
+    buf[9..12].copy_from_slice(&(len as u32).to_be_bytes()[1..]);
+}
+
+fn read_u24(buf: &[u8]) -> usize {
+    (buf[0] as usize) << 16 | (buf[1] as usize) << 8 | buf[2] as usize
+}
+
+pub struct DtlsSession<'a, A: Alarm<'a>, D: digest::Digest<'a, [u8; 32]> + digest::Sha256> {
+    alarm: &'a A,
+    aes_gcm: &'a dyn AES128GCM<'a>,
+    digest: &'a D,
+    rng: &'a dyn rng::Rng<'a>,
+    udp_sender: &'a dyn UDPSender<'a>,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    /// Transmit buffer holding the start of a datagram whose last record is
+    /// being encrypted, and the length of that start.
+    tx_pending: MapCell<LeasableBuffer<'static, u8>>,
+    tx_pending_len: Cell<usize>,
+    crypt_buffer: TakeCell<'static, [u8]>,
+    crypt_op: Cell<CryptOp>,
+    /// Message hashed by the digest engine for the PRF, and the digest.
+    hash_buffer: TakeCell<'static, [u8]>,
+    digest_buffer: TakeCell<'static, [u8; 32]>,
+    hash_op: Cell<HashOp>,
+    net_cap: &'static NetworkCapability,
+
+    send_client: OptionalCell<&'a dyn UDPSendClient>,
+    recv_client: OptionalCell<&'a dyn UDPRecvClient>,
+    client: OptionalCell<&'a dyn DtlsClient>,
+    /// Datagram of the send client.
+    app_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    app_tx: Cell<AppTx>,
+
+    server: Cell<(IPAddr, u16)>,
+    /// Local address and port of the last datagram from the server.
+    local: Cell<(IPAddr, u16)>,
+    psk_identity: OptionalCell<&'static [u8]>,
+    psk: OptionalCell<&'static [u8]>,
+
+    state: Cell<State>,
+    retransmits: Cell<u8>,
+    client_random: Cell<[u8; RANDOM_LEN]>,
+    server_random: Cell<[u8; RANDOM_LEN]>,
+    cookie: Cell<([u8; MAX_COOKIE_LEN], usize)>,
+    /// Handshake messages so far, and their length, or `None` once they
+    /// overflowed the buffer.
+    transcript: TakeCell<'static, [u8]>,
+    transcript_len: Cell<Option<usize>>,
+    derive: Cell<Derive>,
+    /// Key of the PRF padded to an HMAC block, its label and seeds, the
+    /// last A(i), and the output so far and its length.
+    prf_key: Cell<[u8; HMAC_BLOCK_LEN]>,
+    prf_seed: Cell<([u8; MAX_SEED_LEN], usize)>,
+    prf_a: Cell<[u8; 32]>,
+    prf_out: Cell<([u8; MASTER_SECRET_LEN], usize)>,
+    prf_len: Cell<usize>,
+    master_secret: Cell<[u8; MASTER_SECRET_LEN]>,
+    client_verify_data: Cell<[u8; VERIFY_DATA_LEN]>,
+    server_verify_data: Cell<[u8; VERIFY_DATA_LEN]>,
+    keys: OptionalCell<Keys>,
+    /// message_seq of the ClientHello, which the next client messages
+    /// follow.
+    tx_message_seq: Cell<u16>,
+    /// message_seq of the next handshake message expected from the server.
+    rx_message_seq: Cell<u16>,
+    /// Sequence numbers of the next records sent in epochs 0 and 1.
+    tx_seq: Cell<[u64; 2]>,
+    /// Highest sequence number received in epoch 1, and the bitmap of the
+    /// 64 numbers up to it already received.
+    rx_window: Cell<Option<(u64, u64)>>,
+}
+
+impl<'a, A: Alarm<'a>, D: digest::Digest<'a, [u8; 32]> + digest::Sha256> DtlsSession<'a, A, D> {
+    pub fn new(
+        alarm: &'a A,
+        aes_gcm: &'a dyn AES128GCM<'a>,
+        digest: &'a D,
+        rng: &'a dyn rng::Rng<'a>,
+        udp_sender: &'a dyn UDPSender<'a>,
+        tx_buffer: LeasableBuffer<'static, u8>,
+        crypt_buffer: &'static mut [u8],
+        hash_buffer: &'static mut [u8],
+        transcript_buffer: &'static mut [u8],
+        digest_buffer: &'static mut [u8; 32],
+        net_cap: &'static NetworkCapability,
+    ) -> DtlsSession<'a, A, D> {
+        DtlsSession {
+            alarm: alarm,
+            aes_gcm: aes_gcm,
+            digest: digest,
+            rng: rng,
+            udp_sender: udp_sender,
+            tx_buffer: MapCell::new(tx_buffer),
+            tx_pending: MapCell::empty(),
+            tx_pending_len: Cell::new(0),
+            crypt_buffer: TakeCell::new(crypt_buffer),
+            crypt_op: Cell::new(CryptOp::Idle),
+            hash_buffer: TakeCell::new(hash_buffer),
+            digest_buffer: TakeCell::new(digest_buffer),
+            hash_op: Cell::new(HashOp::Idle),
+            net_cap: net_cap,
+            send_client: OptionalCell::empty(),
+            recv_client: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            app_buffer: MapCell::empty(),
+            app_tx: Cell::new(AppTx::None),
+            server: Cell::new((IPAddr::new(), COAPS_PORT)),
+            local: Cell::new((IPAddr::new(), 0)),
+            psk_identity: OptionalCell::empty(),
+            psk: OptionalCell::empty(),
+            state: Cell::new(State::Closed),
+            retransmits: Cell::new(0),
+            client_random: Cell::new([0; RANDOM_LEN]),
+            server_random: Cell::new([0; RANDOM_LEN]),
+            cookie: Cell::new(([0; MAX_COOKIE_LEN], 0)),
+            transcript: TakeCell::new(transcript_buffer),
+            transcript_len: Cell::new(Some(0)),
+            derive: Cell::new(Derive::MasterSecret),
+            prf_key: Cell::new([0; HMAC_BLOCK_LEN]),
+            prf_seed: Cell::new(([0; MAX_SEED_LEN], 0)),
+            prf_a: Cell::new([0; 32]),
+            prf_out: Cell::new(([0; MASTER_SECRET_LEN], 0)),
+            prf_len: Cell::new(0),
+            master_secret: Cell::new([0; MASTER_SECRET_LEN]),
+            client_verify_data: Cell::new([0; VERIFY_DATA_LEN]),
+            server_verify_data: Cell::new([0; VERIFY_DATA_LEN]),
+            keys: OptionalCell::empty(),
+            tx_message_seq: Cell::new(0),
+            rx_message_seq: Cell::new(0),
+            tx_seq: Cell::new([0; 2]),
+            rx_window: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn DtlsClient) {
+        self.client.set(client);
+    }
+
+    /// Set the client receiving the decrypted datagrams from the server.
+    pub fn set_receive_client(&self, client: &'a dyn UDPRecvClient) {
+        self.recv_client.set(client);
+    }
+
+    /// Set the server, which takes effect at the next handshake.
+    pub fn set_server(&self, addr: IPAddr, port: u16) {
+        self.server.set((addr, port));
+    }
+
+    /// Set the PSK and its identity. Returns `SIZE` if either is too long.
+    pub fn set_psk(&self, identity: &'static [u8], psk: &'static [u8]) -> Result<(), ErrorCode> {
+        if identity.len() > MAX_PSK_IDENTITY_LEN || psk.len() > MAX_PSK_LEN || psk.is_empty() {
+            return Err(ErrorCode::SIZE);
+        }
+        self.psk_identity.set(identity);
+        self.psk.set(psk);
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.get() == State::Connected
+    }
+
+    /// Start the handshake with the server. Returns `ALREADY` if a session
+    /// is established or being established, and `RESERVE` if no PSK is set.
+    pub fn connect(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Closed {
+            return Err(ErrorCode::ALREADY);
+        }
+        if self.psk.is_none() {
+            return Err(ErrorCode::RESERVE);
+        }
+        self.rng.get()?;
+        self.state.set(State::Random);
+        Ok(())
+    }
+
+    /// Close the session, notifying the server if the buffers are free.
+    pub fn close(&self) {
+        if self.state.get() == State::Connected {
+            let _ = self.encrypt_record(content_type::ALERT, &[1, ALERT_CLOSE_NOTIFY], None);
+        }
+        self.end_session(Err(ErrorCode::CANCEL));
+    }
+
+    /// Forget the session, ending a handshake in progress or the datagram
+    /// of the client with `error`.
+    fn end_session(&self, error: Result<(), ErrorCode>) {
+        let state = self.state.replace(State::Closed);
+        let _ = self.alarm.disarm();
+        self.keys.clear();
+        self.rx_window.set(None);
+        self.master_secret.set([0; MASTER_SECRET_LEN]);
+        self.prf_key.set([0; HMAC_BLOCK_LEN]);
+        if self.app_tx.get() == AppTx::Queued {
+            self.app_tx.set(AppTx::None);
+            if let Some(buffer) = self.app_buffer.take() {
+                self.send_client
+                    .map(move |client| client.send_done(error, buffer));
+            }
+        }
+        match state {
+            State::Closed => {}
+            State::Connected => {
+                self.client.map(|client| client.disconnected());
+            }
+            _ => {
+                self.client.map(|client| client.connect_done(error));
+            }
+        }
+    }
+
+    fn set_retransmit_timer(&self) {
+        let timeout = RETRANSMIT_TIMEOUT_MS << self.retransmits.get();
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(timeout));
+    }
+
+    fn next_seq(&self, epoch: u16) -> u64 {
+        let mut seqs = self.tx_seq.get();
+        let seq = seqs[epoch as usize];
+        seqs[epoch as usize] += 1;
+        self.tx_seq.set(seqs);
+        seq
+    }
+
+    /// Add a handshake message to the transcript.
+    fn hash_message(&self, msg: &[u8]) {
+        let len = self.transcript_len.get().and_then(|len| {
+            self.transcript.map_or(None, |transcript| {
+                transcript.get_mut(len..len + msg.len()).map(|dst| {
+                    dst.copy_from_slice(msg);
+                    len + msg.len()
+                })
+            })
+        });
+        self.transcript_len.set(len);
+    }
+
+    /// Start a handshake with a new client random.
+    fn start_handshake(&self, random: [u8; RANDOM_LEN]) {
+        self.client_random.set(random);
+        self.cookie.set(([0; MAX_COOKIE_LEN], 0));
+        self.tx_message_seq.set(0);
+        self.rx_message_seq.set(0);
+        self.tx_seq.set([self.tx_seq.get()[0], 0]);
+        self.rx_window.set(None);
+        self.retransmits.set(0);
+        self.state.set(State::ClientHello);
+        self.new_client_hello();
+    }
+
+    /// Hash a ClientHello with the current cookie into a new transcript, and
+    /// send it.
+    fn new_client_hello(&self) {
+        self.transcript_len.set(Some(0));
+        let mut msg = [0; HANDSHAKE_HEADER_LEN + 42 + MAX_COOKIE_LEN];
+        let len = self.client_hello(&mut msg);
+        self.hash_message(&msg[..len]);
+        self.set_retransmit_timer();
+        self.send_plaintext(&msg[..len]);
+    }
+
+    /// Write the ClientHello into `msg`, and return its length.
+    fn client_hello(&self, msg: &mut [u8]) -> usize {
+        let (cookie, cookie_len) = self.cookie.get();
+        let body_len = 42 + cookie_len;
+        write_handshake_header(
+            msg,
+            handshake_type::CLIENT_HELLO,
+            body_len,
+            self.tx_message_seq.get(),
+        );
+        let body = &mut msg[HANDSHAKE_HEADER_LEN..];
+        body[0..2].copy_from_slice(&VERSION);
+        body[2..34].copy_from_slice(&self.client_random.get());
+        // No session ID.
+        body[34] = 0;
+        body[35] = cookie_len as u8;
+        body[36..36 + cookie_len].copy_from_slice(&cookie[..cookie_len]);
+        let off = 36 + cookie_len;
+        body[off..off + 2].copy_from_slice(&2u16.to_be_bytes());
+        body[off + 2..off + 4].copy_from_slice(&TLS_PSK_WITH_AES_128_GCM_SHA256);
+        // Only the null compression method.
+        body[off + 4] = 1;
+        body[off + 5] = 0;
+        HANDSHAKE_HEADER_LEN + body_len
+    }
+
+    /// Send `msg` in a handshake record of epoch 0.
+    fn send_plaintext(&self, msg: &[u8]) {
+        let mut tx = match self.tx_buffer.take() {
+            Some(tx) => tx,
+            // Sent again when the retransmission timer expires.
+            None => return,
+        };
+        tx.reset();
+        let len = RECORD_HEADER_LEN + msg.len();
+        if tx.len() < len {
+            self.tx_buffer.replace(tx);
+            return;
+        }
+        let seq = self.next_seq(0);
+        write_record_header(&mut tx[..], content_type::HANDSHAKE, 0, seq, msg.len());
+        tx[RECORD_HEADER_LEN..len].copy_from_slice(msg);
+        tx.slice(0..len);
+        self.send_datagram(tx);
+    }
+
+    fn send_datagram(&self, tx: LeasableBuffer<'static, u8>) {
+        let (addr, port) = self.server.get();
+        if let Err(mut tx) = self.udp_sender.send_to(addr, port, tx, self.net_cap) {
+            tx.reset();
+            self.tx_buffer.replace(tx);
+        }
+    }
+
+    /// Write the ClientKeyExchange into `msg`, and return its length.
+    fn client_key_exchange(&self, msg: &mut [u8]) -> usize {
+        let identity = self.psk_identity.extract().unwrap_or(&[]);
+        let body_len = 2 + identity.len();
+        write_handshake_header(
+            msg,
+            handshake_type::CLIENT_KEY_EXCHANGE,
+            body_len,
+            self.tx_message_seq.get() + 1,
+        );
+        msg[HANDSHAKE_HEADER_LEN..HANDSHAKE_HEADER_LEN + 2]
+            .copy_from_slice(&(identity.len() as u16).to_be_bytes());
+        msg[HANDSHAKE_HEADER_LEN + 2..HANDSHAKE_HEADER_LEN + body_len].copy_from_slice(identity);
+        HANDSHAKE_HEADER_LEN + body_len
+    }
+
+    fn client_finished(&self) -> [u8; FINISHED_LEN] {
+        let mut msg = [0; FINISHED_LEN];
+        write_handshake_header(
+            &mut msg,
+            handshake_type::FINISHED,
+            VERIFY_DATA_LEN,
+            self.tx_message_seq.get() + 2,
+        );
+        msg[HANDSHAKE_HEADER_LEN..].copy_from_slice(&self.client_verify_data.get());
+        msg
+    }
+
+    /// Answer the server flight: derive the master secret from the PSK and
+    /// the randoms, and continue in `prf_done()` once it is computed.
+    fn finish_handshake(&self) {
+        if self.digest.set_mode_sha256().is_err() {
+            self.end_session(Err(ErrorCode::FAIL));
+            return;
+        }
+        self.state.set(State::Deriving);
+        let psk = self.psk.extract().unwrap_or(&[]);
+        // The premaster secret of plain PSK (RFC 4279, Section 2) is the PSK
+        // preceded by as many zeros, each with their length.
+        let mut premaster = [0; 4 + 2 * MAX_PSK_LEN];
+        let n = psk.len();
+        premaster[0..2].copy_from_slice(&(n as u16).to_be_bytes());
+        premaster[2 + n..4 + n].copy_from_slice(&(n as u16).to_be_bytes());
+        premaster[4 + n..4 + 2 * n].copy_from_slice(psk);
+        self.derive.set(Derive::MasterSecret);
+        self.start_prf(
+            &premaster[..4 + 2 * n],
+            b"master secret",
+            &self.client_random.get(),
+            &self.server_random.get(),
+            MASTER_SECRET_LEN,
+        );
+    }
+
+    /// Start the TLS 1.2 PRF with SHA-256 (RFC 5246, Section 5), whose
+    /// first `len` bytes go to `prf_done()`.
+    fn start_prf(&self, secret: &[u8], label: &[u8], seed1: &[u8], seed2: &[u8], len: usize) {
+        let mut seed = [0; MAX_SEED_LEN];
+        let seed_len = label.len() + seed1.len() + seed2.len();
+        seed[..label.len()].copy_from_slice(label);
+        seed[label.len()..label.len() + seed1.len()].copy_from_slice(seed1);
+        seed[label.len() + seed1.len()..seed_len].copy_from_slice(seed2);
+        self.prf_seed.set((seed, seed_len));
+        self.prf_out.set(([0; MASTER_SECRET_LEN], 0));
+        self.prf_len.set(len);
+        if secret.len() > HMAC_BLOCK_LEN {
+            // HMAC keys longer than a block are hashed first.
+            self.hash(HashOp::KeyHash, None, &[secret]);
+        } else {
+            let mut key = [0; HMAC_BLOCK_LEN];
+            key[..secret.len()].copy_from_slice(secret);
+            self.prf_key.set(key);
+            // A(1) is the HMAC of the seed.
+            self.hash(HashOp::AInner, Some(HMAC_IPAD), &[&seed[..seed_len]]);
+        }
+    }
+
+    /// Start hashing `parts` for `op`, preceded by the PRF key xored with
+    /// `pad` if given.
+    fn hash(&self, op: HashOp, pad: Option<u8>, parts: &[&[u8]]) {
+        let buf = match self.hash_buffer.take() {
+            Some(buf) => buf,
+            None => {
+                self.end_session(Err(ErrorCode::BUSY));
+                return;
+            }
+        };
+        let mut len = 0;
+        if let Some(pad) = pad {
+            for (dst, key) in buf.iter_mut().zip(self.prf_key.get().iter()) {
+                *dst = key ^ pad;
+            }
+            len = HMAC_BLOCK_LEN;
+        }
+        for part in parts {
+            if buf.len() < len + part.len() {
+                self.hash_buffer.replace(buf);
+                self.end_session(Err(ErrorCode::SIZE));
+                return;
+            }
+            buf[len..len + part.len()].copy_from_slice(part);
+            len += part.len();
+        }
+        let mut data = LeasableBuffer::new(buf);
+        data.slice(0..len);
+        self.add_data(op, data);
+    }
+
+    /// Start hashing the transcript.
+    fn hash_transcript(&self) {
+        let len = match self.transcript_len.get() {
+            Some(len) => len,
+            None => {
+                self.end_session(Err(ErrorCode::SIZE));
+                return;
+            }
+        };
+        match self.transcript.take() {
+            Some(buf) => {
+                let mut data = LeasableBuffer::new(buf);
+                data.slice(0..len);
+                self.add_data(HashOp::Transcript, data);
+            }
+            None => self.end_session(Err(ErrorCode::BUSY)),
+        }
+    }
+
+    fn add_data(&self, op: HashOp, data: LeasableBuffer<'static, u8>) {
+        self.hash_op.set(op);
+        if let Err((_, buf)) = self.digest.add_data(data) {
+            self.hash_op.set(HashOp::Idle);
+            self.return_hash_buffer(op, buf);
+            self.end_session(Err(ErrorCode::FAIL));
+        }
+    }
+
+    fn return_hash_buffer(&self, op: HashOp, buf: &'static mut [u8]) {
+        if op == HashOp::Transcript {
+            self.transcript.replace(buf);
+        } else {
+            self.hash_buffer.replace(buf);
+        }
+    }
+
+    /// Continue the PRF or the handshake with the digest of `op`.
+    fn hash_computed(&self, op: HashOp, digest: [u8; 32]) {
+        let (seed, seed_len) = self.prf_seed.get();
+        match op {
+            HashOp::Idle => {}
+            HashOp::KeyHash => {
+                let mut key = [0; HMAC_BLOCK_LEN];
+                key[..32].copy_from_slice(&digest);
+                self.prf_key.set(key);
+                self.hash(HashOp::AInner, Some(HMAC_IPAD), &[&seed[..seed_len]]);
+            }
+            HashOp::AInner => self.hash(HashOp::AOuter, Some(HMAC_OPAD), &[&digest]),
+            HashOp::AOuter => {
+                self.prf_a.set(digest);
+                self.hash(
+                    HashOp::BlockInner,
+                    Some(HMAC_IPAD),
+                    &[&digest, &seed[..seed_len]],
+                );
+            }
+            HashOp::BlockInner => self.hash(HashOp::BlockOuter, Some(HMAC_OPAD), &[&digest]),
+            HashOp::BlockOuter => {
+                let (mut out, len) = self.prf_out.get();
+                let n = cmp::min(digest.len(), self.prf_len.get() - len);
+                out[len..len + n].copy_from_slice(&digest[..n]);
+                self.prf_out.set((out, len + n));
+                if len + n == self.prf_len.get() {
+                    self.prf_done(&out[..len + n]);
+                } else {
+                    // A(i + 1) is the HMAC of A(i).
+                    self.hash(HashOp::AInner, Some(HMAC_IPAD), &[&self.prf_a.get()]);
+                }
+            }
+            HashOp::Transcript => {
+                let label = if self.derive.get() == Derive::ClientFinished {
+                    b"client finished"
+                } else {
+                    b"server finished"
+                };
+                self.start_prf(
+                    &self.master_secret.get(),
+                    label,
+                    &digest,
+                    &[],
+                    VERIFY_DATA_LEN,
+                );
+            }
+        }
+    }
+
+    /// Continue the handshake with the output of the PRF: after the master
+    /// secret come the keys, then the verify data of the client Finished and
+    /// of the server Finished, each over the transcript so far, and then the
+    /// client flight is sent.
+    fn prf_done(&self, out: &[u8]) {
+        match self.derive.get() {
+            Derive::MasterSecret => {
+                let mut master_secret = [0; MASTER_SECRET_LEN];
+                master_secret.copy_from_slice(out);
+                self.master_secret.set(master_secret);
+                self.derive.set(Derive::KeyBlock);
+                self.start_prf(
+                    &master_secret,
+                    b"key expansion",
+                    &self.server_random.get(),
+                    &self.client_random.get(),
+                    KEY_BLOCK_LEN,
+                );
+            }
+            Derive::KeyBlock => {
+                let mut keys = Keys {
+                    client_key: [0; AES128_KEY_SIZE],
+                    server_key: [0; AES128_KEY_SIZE],
+                    client_iv: [0; IV_LEN],
+                    server_iv: [0; IV_LEN],
+                };
+                keys.client_key.copy_from_slice(&out[0..16]);
+                keys.server_key.copy_from_slice(&out[16..32]);
+                keys.client_iv.copy_from_slice(&out[32..36]);
+                keys.server_iv.copy_from_slice(&out[36..40]);
+                self.keys.set(keys);
+
+                let mut msg = [0; HANDSHAKE_HEADER_LEN + 2 + MAX_PSK_IDENTITY_LEN];
+                let len = self.client_key_exchange(&mut msg);
+                self.hash_message(&msg[..len]);
+                self.derive.set(Derive::ClientFinished);
+                self.hash_transcript();
+            }
+            Derive::ClientFinished => {
+                let mut verify_data = [0; VERIFY_DATA_LEN];
+                verify_data.copy_from_slice(out);
+                self.client_verify_data.set(verify_data);
+                self.hash_message(&self.client_finished());
+                self.derive.set(Derive::ServerFinished);
+                self.hash_transcript();
+            }
+            Derive::ServerFinished => {
+                let mut verify_data = [0; VERIFY_DATA_LEN];
+                verify_data.copy_from_slice(out);
+                self.server_verify_data.set(verify_data);
+                self.prf_key.set([0; HMAC_BLOCK_LEN]);
+                self.digest.clear_data();
+
+                self.state.set(State::Finished);
+                self.retransmits.set(0);
+                self.set_retransmit_timer();
+                self.send_finished_flight();
+            }
+        }
+    }
+
+    /// Send the ClientKeyExchange, the ChangeCipherSpec and the encrypted
+    /// Finished in one datagram.
+    fn send_finished_flight(&self) {
+        if !matches!(self.crypt_op.get(), CryptOp::Idle) || self.crypt_buffer.is_none() {
+            return;
+        }
+        let mut tx = match self.tx_buffer.take() {
+            Some(tx) => tx,
+            None => return,
+        };
+        tx.reset();
+        let mut msg = [0; HANDSHAKE_HEADER_LEN + 2 + MAX_PSK_IDENTITY_LEN];
+        let msg_len = self.client_key_exchange(&mut msg);
+        let len = 2 * RECORD_HEADER_LEN + msg_len + 1;
+        if tx.len() < len + RECORD_OVERHEAD + FINISHED_LEN {
+            self.tx_buffer.replace(tx);
+            return;
+        }
+        let seq = self.next_seq(0);
+        write_record_header(&mut tx[..], content_type::HANDSHAKE, 0, seq, msg_len);
+        tx[RECORD_HEADER_LEN..RECORD_HEADER_LEN + msg_len].copy_from_slice(&msg[..msg_len]);
+        let off = RECORD_HEADER_LEN + msg_len;
+        let seq = self.next_seq(0);
+        write_record_header(&mut tx[off..], content_type::CHANGE_CIPHER_SPEC, 0, seq, 1);
+        tx[off + RECORD_HEADER_LEN] = 1;
+        let _ = self.encrypt_record(
+            content_type::HANDSHAKE,
+            &self.client_finished(),
+            Some((tx, len)),
+        );
+    }
+
+    /// Encrypt `payload` as a record of epoch 1, to be sent after the first
+    /// `prefix_len` bytes of `tx`, or alone if no buffer is given.
+    fn encrypt_record(
+        &self,
+        content_type: u8,
+        payload: &[u8],
+        tx: Option<(LeasableBuffer<'static, u8>, usize)>,
+    ) -> Result<(), ErrorCode> {
+        let (tx, prefix_len) = match tx {
+            Some(tx) => tx,
+            None => (self.tx_buffer.take().ok_or(ErrorCode::BUSY)?, 0),
+        };
+        let keys = match self.keys.extract() {
+            Some(keys) if matches!(self.crypt_op.get(), CryptOp::Idle) => keys,
+            _ => {
+                self.tx_buffer.replace(tx);
+                return Err(ErrorCode::BUSY);
+            }
+        };
+        let buf = match self.crypt_buffer.take() {
+            Some(buf)
+                if buf.len() >= AAD_LEN + payload.len() + GCM_TAG_LENGTH
+                    && tx.len() >= prefix_len + RECORD_OVERHEAD + payload.len() =>
+            {
+                buf
+            }
+            Some(buf) => {
+                self.crypt_buffer.replace(buf);
+                self.tx_buffer.replace(tx);
+                return Err(ErrorCode::SIZE);
+            }
+            // A decrypted record is being processed.
+            None => {
+                self.tx_buffer.replace(tx);
+                return Err(ErrorCode::BUSY);
+            }
+        };
+
+        let seq = self.next_seq(1);
+        let seq_num = (1u64 << 48 | seq).to_be_bytes();
+        buf[0..8].copy_from_slice(&seq_num);
+        buf[8] = content_type;
+        buf[9..11].copy_from_slice(&VERSION);
+        buf[11..13].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        buf[AAD_LEN..AAD_LEN + payload.len()].copy_from_slice(payload);
+
+        let mut nonce = [0; GCM_NONCE_LENGTH];
+        nonce[..IV_LEN].copy_from_slice(&keys.client_iv);
+        nonce[IV_LEN..].copy_from_slice(&seq_num);
+        self.tx_pending.replace(tx);
+        self.tx_pending_len.set(prefix_len);
+        let result = if self.aes_gcm.set_key(&keys.client_key).is_err()
+            || self.aes_gcm.set_nonce(&nonce).is_err()
+        {
+            Err((ErrorCode::FAIL, buf))
+        } else {
+            self.aes_gcm.crypt(buf, 0, AAD_LEN, payload.len(), true)
+        };
+        match result {
+            Ok(()) => {
+                self.crypt_op.set(CryptOp::Encrypt {
+                    content_type: content_type,
+                    seq: seq,
+                    m_len: payload.len(),
+                });
+                Ok(())
+            }
+            Err((err, buf)) => {
+                self.crypt_buffer.replace(buf);
+                if let Some(tx) = self.tx_pending.take() {
+                    self.tx_buffer.replace(tx);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Send the datagram of the send client, if the session and the buffers
+    /// allow it.
+    fn send_app_data(&self) {
+        if self.state.get() != State::Connected || self.app_tx.get() != AppTx::Queued {
+            return;
+        }
+        let result = self
+            .app_buffer
+            .map(|app| self.encrypt_record(content_type::APPLICATION_DATA, &app[..], None));
+        match result {
+            Some(Ok(())) => self.app_tx.set(AppTx::Sending),
+            // Retried once the buffers are returned.
+            Some(Err(ErrorCode::BUSY)) | None => {}
+            Some(Err(err)) => {
+                self.app_tx.set(AppTx::None);
+                if let Some(buffer) = self.app_buffer.take() {
+                    self.send_client
+                        .map(move |client| client.send_done(Err(err), buffer));
+                }
+            }
+        }
+    }
+
+    /// Process the handshake messages of a record. Only the Finished of the
+    /// server is accepted in an encrypted record.
+    fn receive_handshake(&self, mut body: &[u8], encrypted: bool) {
+        while body.len() >= HANDSHAKE_HEADER_LEN {
+            let msg_type = body[0];
+            let len = read_u24(&body[1..4]);
+            let message_seq = u16::from_be_bytes([body[4], body[5]]);
+            let frag_off = read_u24(&body[6..9]);
+            let frag_len = read_u24(&body[9..12]);
+            if frag_off != 0 || frag_len != len || body.len() < HANDSHAKE_HEADER_LEN + len {
+                return;
+            }
+            let (msg, rest) = body.split_at(HANDSHAKE_HEADER_LEN + len);
+            body = rest;
+            // Retransmitted and early messages are dropped.
+            if message_seq != self.rx_message_seq.get() {
+                continue;
+            }
+            if encrypted != (msg_type == handshake_type::FINISHED) {
+                continue;
+            }
+            if !self.receive_handshake_message(msg_type, msg) {
+                continue;
+            }
+            self.rx_message_seq.set(message_seq.wrapping_add(1));
+            if self.state.get() == State::Closed {
+                return;
+            }
+        }
+    }
+
+    /// Process a handshake message, and return whether it was expected.
+    fn receive_handshake_message(&self, msg_type: u8, msg: &[u8]) -> bool {
+        let body = &msg[HANDSHAKE_HEADER_LEN..];
+        match (self.state.get(), msg_type) {
+            (State::ClientHello, handshake_type::HELLO_VERIFY_REQUEST) => {
+                if body.len() < 3 || body.len() < 3 + body[2] as usize {
+                    return false;
+                }
+                let cookie_len = body[2] as usize;
+                if cookie_len > MAX_COOKIE_LEN {
+                    self.end_session(Err(ErrorCode::FAIL));
+                    return true;
+                }
+                let mut cookie = [0; MAX_COOKIE_LEN];
+                cookie[..cookie_len].copy_from_slice(&body[3..3 + cookie_len]);
+                self.cookie.set((cookie, cookie_len));
+                // The ClientHello and HelloVerifyRequest are not part of the
+                // transcript, and the ServerHello follows the new ClientHello.
+                self.tx_message_seq.set(self.tx_message_seq.get() + 1);
+                self.retransmits.set(0);
+                self.new_client_hello();
+                true
+            }
+            (State::ClientHello, handshake_type::SERVER_HELLO) => {
+                // Version, random and session ID.
+                if body.len() < 35 || body.len() < 35 + body[34] as usize + 3 {
+                    return false;
+                }
+                let off = 35 + body[34] as usize;
+                if body[off..off + 2] != TLS_PSK_WITH_AES_128_GCM_SHA256 || body[off + 2] != 0 {
+                    self.end_session(Err(ErrorCode::FAIL));
+                    return true;
+                }
+                let mut server_random = [0; RANDOM_LEN];
+                server_random.copy_from_slice(&body[2..34]);
+                self.server_random.set(server_random);
+                self.hash_message(msg);
+                self.state.set(State::ServerHello);
+                true
+            }
+            (State::ServerHello, handshake_type::SERVER_KEY_EXCHANGE) => {
+                // The PSK identity hint is not used.
+                self.hash_message(msg);
+                true
+            }
+            (State::ServerHello, handshake_type::SERVER_HELLO_DONE) => {
+                self.hash_message(msg);
+                self.finish_handshake();
+                true
+            }
+            (State::Finished, handshake_type::FINISHED) => {
+                if !tags_equal(body, &self.server_verify_data.get()) {
+                    self.end_session(Err(ErrorCode::FAIL));
+                    return true;
+                }
+                let _ = self.alarm.disarm();
+                self.state.set(State::Connected);
+                self.client.map(|client| client.connect_done(Ok(())));
+                self.send_app_data();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn receive_alert(&self, body: &[u8]) {
+        if body.len() >= 2 && (body[0] == ALERT_LEVEL_FATAL || body[1] == ALERT_CLOSE_NOTIFY) {
+            self.end_session(Err(ErrorCode::FAIL));
+        }
+    }
+
+    /// Whether the record `seq` of epoch 1 was already received.
+    fn is_replay(&self, seq: u64) -> bool {
+        match self.rx_window.get() {
+            None => false,
+            Some((highest, bitmap)) => {
+                seq <= highest && (highest - seq >= 64 || bitmap & (1 << (highest - seq)) != 0)
+            }
+        }
+    }
+
+    fn record_received(&self, seq: u64) {
+        let window = match self.rx_window.get() {
+            None => (seq, 1),
+            Some((highest, bitmap)) if seq > highest => {
+                let shift = seq - highest;
+                let bitmap = if shift >= 64 { 0 } else { bitmap << shift };
+                (seq, bitmap | 1)
+            }
+            Some((highest, bitmap)) => (highest, bitmap | 1 << (highest - seq)),
+        };
+        self.rx_window.set(Some(window));
+    }
+
+    /// Start decrypting a record of epoch 1.
+    fn decrypt_record(&self, header: &[u8], body: &[u8]) {
+        let keys = match self.keys.extract() {
+            Some(keys) if matches!(self.crypt_op.get(), CryptOp::Idle) => keys,
+            _ => return,
+        };
+        if body.len() < EXPLICIT_NONCE_LEN + GCM_TAG_LENGTH {
+            return;
+        }
+        let mut seq_num = [0; 8];
+        seq_num.copy_from_slice(&header[3..11]);
+        let seq = u64::from_be_bytes(seq_num) & 0xFFFF_FFFF_FFFF;
+        if self.is_replay(seq) {
+            return;
+        }
+        let m_len = body.len() - EXPLICIT_NONCE_LEN - GCM_TAG_LENGTH;
+        let buf = match self.crypt_buffer.take() {
+            Some(buf) if buf.len() >= AAD_LEN + m_len + GCM_TAG_LENGTH => buf,
+            Some(buf) => {
+                self.crypt_buffer.replace(buf);
+                return;
+            }
+            None => return,
+        };
+        buf[0..8].copy_from_slice(&seq_num);
+        buf[8..11].copy_from_slice(&header[0..3]);
+        buf[11..13].copy_from_slice(&(m_len as u16).to_be_bytes());
+        buf[AAD_LEN..AAD_LEN + m_len + GCM_TAG_LENGTH].copy_from_slice(&body[EXPLICIT_NONCE_LEN..]);
+
+        let mut nonce = [0; GCM_NONCE_LENGTH];
+        nonce[..IV_LEN].copy_from_slice(&keys.server_iv);
+        nonce[IV_LEN..].copy_from_slice(&body[..EXPLICIT_NONCE_LEN]);
+        let result = if self.aes_gcm.set_key(&keys.server_key).is_err()
+            || self.aes_gcm.set_nonce(&nonce).is_err()
+        {
+            Err((ErrorCode::FAIL, buf))
+        } else {
+            self.aes_gcm.crypt(buf, 0, AAD_LEN, m_len, false)
+        };
+        match result {
+            Ok(()) => self.crypt_op.set(CryptOp::Decrypt {
+                content_type: header[0],
+                seq: seq,
+                m_len: m_len,
+            }),
+            Err((_, buf)) => {
+                self.crypt_buffer.replace(buf);
+            }
+        }
+    }
+
+    /// Handle a decrypted record.
+    fn receive_decrypted(&self, content_type: u8, payload: &[u8]) {
+        match content_type {
+            content_type::HANDSHAKE => self.receive_handshake(payload, true),
+            content_type::ALERT => self.receive_alert(payload),
+            content_type::APPLICATION_DATA if self.state.get() == State::Connected => {
+                let (server_addr, server_port) = self.server.get();
+                let (local_addr, local_port) = self.local.get();
+                self.recv_client.map(|client| {
+                    client.receive(server_addr, local_addr, server_port, local_port, payload)
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: digest::Digest<'a, [u8; 32]> + digest::Sha256> UDPSender<'a>
+    for DtlsSession<'a, A, D>
+{
+    fn set_client(&self, client: &'a dyn UDPSendClient) {
+        self.send_client.set(client);
+    }
+
+    fn send_to(
+        &'a self,
+        dest: IPAddr,
+        dst_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        if (dest, dst_port) != self.server.get() || self.app_tx.get() != AppTx::None {
+            return Err(buf);
+        }
+        if self.state.get() == State::Closed && self.connect().is_err() {
+            return Err(buf);
+        }
+        self.app_buffer.replace(buf);
+        self.app_tx.set(AppTx::Queued);
+        self.send_app_data();
+        Ok(())
+    }
+
+    /// Not supported: datagrams are only sent to the server.
+    fn driver_send_to(
+        &'a self,
+        _dest: IPAddr,
+        _dst_port: u16,
+        _src_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        _driver_send_cap: &dyn UdpDriverCapability,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        Err(buf)
+    }
+
+    /// Not supported: datagrams are only sent to the server.
+    fn send(
+        &'a self,
+        _dest: IPAddr,
+        _udp_header: UDPHeader,
+        buf: LeasableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        Err(buf)
+    }
+
+    fn get_binding(&self) -> Option<UdpPortBindingTx> {
+        self.udp_sender.get_binding()
+    }
+
+    fn is_bound(&self) -> bool {
+        self.udp_sender.is_bound()
+    }
+
+    fn set_binding(&self, binding: UdpPortBindingTx) -> Option<UdpPortBindingTx> {
+        self.udp_sender.set_binding(binding)
+    }
+
+    fn set_traffic_class(&self, traffic_class: u8) {
+        self.udp_sender.set_traffic_class(traffic_class);
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: digest::Digest<'a, [u8; 32]> + digest::Sha256> UDPSendClient
+    for DtlsSession<'a, A, D>
+{
+    fn send_done(&self, result: Result<(), ErrorCode>, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buffer.replace(dgram);
+        if self.app_tx.get() == AppTx::Sending {
+            self.app_tx.set(AppTx::None);
+            if let Some(buffer) = self.app_buffer.take() {
+                self.send_client
+                    .map(move |client| client.send_done(result, buffer));
+            }
+        }
+        self.send_app_data();
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: digest::Digest<'a, [u8; 32]> + digest::Sha256> UDPRecvClient
+    for DtlsSession<'a, A, D>
+{
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        dst_addr: IPAddr,
+        src_port: u16,
+        dst_port: u16,
+        payload: &[u8],
+    ) {
+        if (src_addr, src_port) != self.server.get() {
+            return;
+        }
+        self.local.set((dst_addr, dst_port));
+        let mut records = payload;
+        while records.len() >= RECORD_HEADER_LEN && self.state.get() != State::Closed {
+            let header = &records[..RECORD_HEADER_LEN];
+            let len = u16::from_be_bytes([header[11], header[12]]) as usize;
+            if header[1] != VERSION[0] || records.len() < RECORD_HEADER_LEN + len {
+                return;
+            }
+            let body = &records[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len];
+            records = &records[RECORD_HEADER_LEN + len..];
+            let epoch = u16::from_be_bytes([header[3], header[4]]);
+            match (epoch, header[0]) {
+                // Plaintext records, alerts included, are only accepted
+                // during the handshake.
+                (0, _) if self.state.get() == State::Connected => {}
+                (0, content_type::HANDSHAKE) => self.receive_handshake(body, false),
+                (0, content_type::ALERT) => self.receive_alert(body),
+                // The keys are already derived when the server changes its
+                // cipher spec, so the record is not needed.
+                (0, content_type::CHANGE_CIPHER_SPEC) => {}
+                (1, _) => {
+                    self.decrypt_record(header, body);
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: digest::Digest<'a, [u8; 32]> + digest::Sha256> GCMClient
+    for DtlsSession<'a, A, D>
+{
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        match self.crypt_op.replace(CryptOp::Idle) {
+            CryptOp::Idle => {
+                self.crypt_buffer.replace(buf);
+            }
+            CryptOp::Encrypt {
+                content_type,
+                seq,
+                m_len,
+            } => {
+                let mut tx = match self.tx_pending.take() {
+                    Some(tx) => tx,
+                    None => {
+                        self.crypt_buffer.replace(buf);
+                        return;
+                    }
+                };
+                let off = self.tx_pending_len.get();
+                let len = EXPLICIT_NONCE_LEN + m_len + GCM_TAG_LENGTH;
+                write_record_header(&mut tx[off..], content_type, 1, seq, len);
+                let off = off + RECORD_HEADER_LEN;
+                tx[off..off + EXPLICIT_NONCE_LEN].copy_from_slice(&buf[0..8]);
+                tx[off + EXPLICIT_NONCE_LEN..off + len]
+                    .copy_from_slice(&buf[AAD_LEN..AAD_LEN + m_len + GCM_TAG_LENGTH]);
+                self.crypt_buffer.replace(buf);
+                if res.is_err() {
+                    tx.reset();
+                    self.tx_buffer.replace(tx);
+                    return;
+                }
+                tx.slice(0..off + len);
+                self.send_datagram(tx);
+            }
+            CryptOp::Decrypt {
+                content_type,
+                seq,
+                m_len,
+            } => {
+                if res.is_ok() && tag_is_valid {
+                    self.record_received(seq);
+                    self.receive_decrypted(content_type, &buf[AAD_LEN..AAD_LEN + m_len]);
+                    self.crypt_buffer.replace(buf);
+                } else {
+                    self.crypt_buffer.replace(buf);
+                }
+            }
+        }
+        self.send_app_data();
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: digest::Digest<'a, [u8; 32]> + digest::Sha256> time::AlarmClient
+    for DtlsSession<'a, A, D>
+{
+    fn alarm(&self) {
+        let state = self.state.get();
+        if !matches!(
+            state,
+            State::ClientHello | State::ServerHello | State::Finished
+        ) {
+            return;
+        }
+        if self.retransmits.get() >= MAX_RETRANSMITS {
+            self.end_session(Err(ErrorCode::NOACK));
+            return;
+        }
+        self.retransmits.set(self.retransmits.get() + 1);
+        self.set_retransmit_timer();
+        if state == State::Finished {
+            self.send_finished_flight();
+        } else {
+            let mut msg = [0; HANDSHAKE_HEADER_LEN + 42 + MAX_COOKIE_LEN];
+            let len = self.client_hello(&mut msg);
+            self.send_plaintext(&msg[..len]);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: digest::Digest<'a, [u8; 32]> + digest::Sha256> rng::Client
+    for DtlsSession<'a, A, D>
+{
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> rng::Continue {
+        if self.state.get() != State::Random {
+            return rng::Continue::Done;
+        }
+        if error.is_err() {
+            self.end_session(Err(ErrorCode::FAIL));
+            return rng::Continue::Done;
+        }
+        let mut random = [0; RANDOM_LEN];
+        for chunk in random.chunks_mut(4) {
+            match randomness.next() {
+                Some(word) => chunk.copy_from_slice(&word.to_be_bytes()),
+                None => return rng::Continue::More,
+            }
+        }
+        self.start_handshake(random);
+        rng::Continue::Done
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: digest::Digest<'a, [u8; 32]> + digest::Sha256>
+    digest::Client<'a, [u8; 32]> for DtlsSession<'a, A, D>
+{
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        let op = self.hash_op.get();
+        self.return_hash_buffer(op, data);
+        if self.state.get() != State::Deriving {
+            self.hash_op.set(HashOp::Idle);
+            return;
+        }
+        let digest = match self.digest_buffer.take() {
+            Some(digest) if result.is_ok() => digest,
+            Some(digest) => {
+                self.digest_buffer.replace(digest);
+                self.hash_op.set(HashOp::Idle);
+                self.end_session(Err(ErrorCode::FAIL));
+                return;
+            }
+            None => {
+                self.hash_op.set(HashOp::Idle);
+                self.end_session(Err(ErrorCode::FAIL));
+                return;
+            }
+        };
+        if let Err((_, digest)) = self.digest.run(digest) {
+            self.digest_buffer.replace(digest);
+            self.hash_op.set(HashOp::Idle);
+            self.end_session(Err(ErrorCode::FAIL));
+        }
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut [u8; 32]) {
+        let value = *digest;
+        self.digest_buffer.replace(digest);
+        let op = self.hash_op.replace(HashOp::Idle);
+        // The session ended while the digest was computed.
+        if self.state.get() != State::Deriving {
+            return;
+        }
+        if result.is_err() {
+            self.end_session(Err(ErrorCode::FAIL));
+            return;
+        }
+        self.hash_computed(op, value);
+    }
+}
@@ -66,6 +66,13 @@ impl Bitmap {
         }
     }
 
+    // Returns true if every bit from start_idx (inclusive) to end_idx
+    // (exclusive) is set, and the range is not empty.
+    pub fn bits_all_set(&self, start_idx: usize, end_idx: usize) -> bool {
+        start_idx < end_idx
+            && (start_idx..end_idx).all(|idx| self.map[idx / 8] & (1 << (idx % 8)) != 0)
+    }
+
     pub fn is_complete(&self, total_length: usize) -> bool {
         let mut result = true;
         for i in 0..total_length / 8 {
@@ -0,0 +1,180 @@
+//! This file contains the ARP packet format (RFC 826) for IPv4 over Ethernet,
+//! and the cache of the MAC addresses of neighbors used by the IPv4 layer.
+//!
+//! The cache has a fixed number of entries, replaced in round-robin order.
+//! Entries do not expire; they are updated by every ARP packet from the
+//! neighbor, and the whole cache is cleared when the link goes down.
+
+use crate::net::ipv4::ip4_utils::IP4Addr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+use core::cell::Cell;
+use kernel::hil::ethernet::MacAddress;
+
+/// EtherType of ARP packets.
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Length of an ARP packet for IPv4 over Ethernet.
+pub const ARP_PKT_LEN: usize = 28;
+
+/// Number of neighbors kept in the cache.
+pub const ARP_CACHE_LEN: usize = 8;
+
+const HTYPE_ETHERNET: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ArpOp {
+    Request = 1,
+    Reply = 2,
+}
+
+/// An ARP packet for IPv4 over Ethernet.
+#[derive(Copy, Clone, Debug)]
+pub struct ArpPacket {
+    pub op: ArpOp,
+    pub sender_mac: MacAddress,
+    pub sender_ip: IP4Addr,
+    pub target_mac: MacAddress,
+    pub target_ip: IP4Addr,
+}
+
+impl ArpPacket {
+    /// A request for the MAC address of `target_ip`.
+    pub fn request(sender_mac: MacAddress, sender_ip: IP4Addr, target_ip: IP4Addr) -> ArpPacket {
+        ArpPacket {
+            op: ArpOp::Request,
+            sender_mac: sender_mac,
+            sender_ip: sender_ip,
+            target_mac: MacAddress([0; 6]),
+            target_ip: target_ip,
+        }
+    }
+
+    /// The reply to `request`, from `sender_mac`.
+    pub fn reply(request: &ArpPacket, sender_mac: MacAddress) -> ArpPacket {
+        ArpPacket {
+            op: ArpOp::Reply,
+            sender_mac: sender_mac,
+            sender_ip: request.target_ip,
+            target_mac: request.sender_mac,
+            target_ip: request.sender_ip,
+        }
+    }
+
+    /// This function serializes the `ArpPacket` into the provided buffer.
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the new offset into the buffer wrapped in an
+    /// SResult.
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, ARP_PKT_LEN + offset);
+
+        let mut off = offset;
+        off = enc_consume!(buf, off; encode_u16, HTYPE_ETHERNET);
+        off = enc_consume!(buf, off; encode_u16, PTYPE_IPV4);
+        off = enc_consume!(buf, off; encode_u8, 6);
+        off = enc_consume!(buf, off; encode_u8, 4);
+        off = enc_consume!(buf, off; encode_u16, self.op as u16);
+        off = enc_consume!(buf, off; encode_bytes, &self.sender_mac.0);
+        off = enc_consume!(buf, off; encode_bytes, &self.sender_ip.0);
+        off = enc_consume!(buf, off; encode_bytes, &self.target_mac.0);
+        off = enc_consume!(buf, off; encode_bytes, &self.target_ip.0);
+        stream_done!(off, off);
+    }
+
+    /// This function deserializes an `ArpPacket` from the provided buffer.
+    /// Packets for other hardware or protocol types are an error.
+    pub fn decode(buf: &[u8]) -> SResult<ArpPacket> {
+        stream_len_cond!(buf, ARP_PKT_LEN);
+        let (off, htype) = dec_try!(buf, 0; decode_u16);
+        let (off, ptype) = dec_try!(buf, off; decode_u16);
+        let (off, hlen) = dec_try!(buf, off; decode_u8);
+        let (off, plen) = dec_try!(buf, off; decode_u8);
+        stream_cond!(htype == HTYPE_ETHERNET && ptype == PTYPE_IPV4 && hlen == 6 && plen == 4);
+        let (off, op) = dec_try!(buf, off; decode_u16);
+        let op = match op {
+            1 => ArpOp::Request,
+            2 => ArpOp::Reply,
+            _ => stream_err!(),
+        };
+
+        let mut packet = ArpPacket::request(
+            MacAddress([0; 6]),
+            IP4Addr::UNSPECIFIED,
+            IP4Addr::UNSPECIFIED,
+        );
+        packet.op = op;
+        let off = dec_consume!(buf, off; decode_bytes, &mut packet.sender_mac.0);
+        let off = dec_consume!(buf, off; decode_bytes, &mut packet.sender_ip.0);
+        let off = dec_consume!(buf, off; decode_bytes, &mut packet.target_mac.0);
+        let off = dec_consume!(buf, off; decode_bytes, &mut packet.target_ip.0);
+        stream_done!(off, packet);
+    }
+}
+
+#[derive(Copy, Clone)]
+struct ArpEntry {
+    ip_addr: IP4Addr,
+    mac_addr: MacAddress,
+}
+
+pub struct ArpCache {
+    entries: Cell<[Option<ArpEntry>; ARP_CACHE_LEN]>,
+    /// The entry replaced by the next new neighbor.
+    next: Cell<usize>,
+}
+
+impl ArpCache {
+    pub fn new() -> ArpCache {
+        ArpCache {
+            entries: Cell::new([None; ARP_CACHE_LEN]),
+            next: Cell::new(0),
+        }
+    }
+
+    pub fn lookup(&self, ip_addr: IP4Addr) -> Option<MacAddress> {
+        self.entries
+            .get()
+            .iter()
+            .flatten()
+            .find(|entry| entry.ip_addr == ip_addr)
+            .map(|entry| entry.mac_addr)
+    }
+
+    /// Update the MAC address of `ip_addr` if it is in the cache. Returns
+    /// whether it was.
+    pub fn update(&self, ip_addr: IP4Addr, mac_addr: MacAddress) -> bool {
+        let mut entries = self.entries.get();
+        let found = entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.ip_addr == ip_addr)
+            .map(|entry| entry.mac_addr = mac_addr)
+            .is_some();
+        self.entries.set(entries);
+        found
+    }
+
+    /// Add or update the MAC address of `ip_addr`.
+    pub fn insert(&self, ip_addr: IP4Addr, mac_addr: MacAddress) {
+        if self.update(ip_addr, mac_addr) {
+            return;
+        }
+        let mut entries = self.entries.get();
+        let index = self.next.get();
+        entries[index] = Some(ArpEntry {
+            ip_addr: ip_addr,
+            mac_addr: mac_addr,
+        });
+        self.entries.set(entries);
+        self.next.set((index + 1) % ARP_CACHE_LEN);
+    }
+
+    pub fn clear(&self) {
+        self.entries.set([None; ARP_CACHE_LEN]);
+        self.next.set(0);
+    }
+}
@@ -0,0 +1,646 @@
+//! This file contains an IPv4 interface on top of an Ethernet MAC, with
+//! ARP, replies to ICMP echo requests (ping), and UDP.
+//!
+//! The interface implements the `IP6Sender` trait, and passes received UDP
+//! datagrams to an `IP6RecvClient`, so that the UDP layer (`MuxUdpSender`,
+//! `MuxUdpReceiver`) and the UDP userspace driver run on top of it unchanged.
+//! Addresses cross these interfaces as IPv4-mapped IPv6 addresses
+//! (`::ffff:a.b.c.d`), including in the interface list of the UDP driver,
+//! so processes use the same system calls on either transport. Received
+//! datagrams are passed up with a synthesized IPv6 header; datagrams sent to
+//! a broadcast or multicast address appear as sent to the address of the
+//! interface, so that processes bound to it receive them.
+//!
+//! One datagram is sent at a time. The MAC address of the next hop, which is
+//! the destination itself if it is in the subnet and the gateway otherwise,
+//! is looked up in the ARP cache. If it is not there, the datagram waits
+//! while an ARP request is sent, up to `ARP_RETRIES` more times every
+//! `ARP_TIMEOUT_MS`, and fails with `NOACK` if no reply arrives. ARP replies
+//! and ICMP echo replies are sent from a second buffer, and dropped if it is
+//! busy.
+//!
+//! The interface receives datagrams to the multicast groups it joins with
+//! `join_multicast()`, and sends datagrams to any group. IGMP is not
+//! implemented, so groups outside of the local network control block
+//! (224.0.0.0/24) may not be forwarded to the interface by switches that
+//! snoop IGMP.
+//!
+//! Fragmented packets and IP options are not supported: fragments are
+//! dropped, options are skipped, and datagrams are sent with the "Don't
+//! Fragment" flag, so they must fit in one Ethernet frame.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ip4 = static_init!(
+//!     capsules::net::ipv4::ip4_interface::IP4Interface<
+//!         'static,
+//!         stm32f429zi::ethernet::Ethernet<'static>,
+//!         VirtualMuxAlarm<'static, stm32f429zi::tim2::Tim2>,
+//!     >,
+//!     capsules::net::ipv4::ip4_interface::IP4Interface::new(
+//!         &base_peripherals.ethernet,
+//!         ip4_alarm,
+//!         MAC_ADDR,
+//!         IP4Config {
+//!             addr: IP4Addr([192, 168, 1, 50]),
+//!             netmask: IP4Addr([255, 255, 255, 0]),
+//!             gateway: IP4Addr([192, 168, 1, 1]),
+//!         },
+//!         &mut capsules::net::ipv4::ip4_interface::TX_BUF,
+//!         &mut capsules::net::ipv4::ip4_interface::CTL_BUF,
+//!         ip_vis,
+//!     )
+//! );
+//! base_peripherals.ethernet.set_client(ip4);
+//! ip4_alarm.set_alarm_client(ip4);
+//! ip4.set_client(udp_mux);
+//! ip4.set_udp_client(udp_recv_mux);
+//! base_peripherals.ethernet.enable(MAC_ADDR);
+//! ```
+
+use crate::net::ipv4::arp::{ArpCache, ArpOp, ArpPacket, ARP_PKT_LEN, ETHERTYPE_ARP};
+use crate::net::ipv4::ip4_utils::{self, ip4_proto, IP4Addr, IP4Header, IP4_HDR_LEN};
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, TransportHeader, UDP_HDR_LEN};
+use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
+use crate::net::stream::SResult;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::ethernet::{self, LinkStatus, MacAddress};
+use kernel::hil::time::{self, Alarm};
+use kernel::ErrorCode;
+
+pub static mut TX_BUF: [u8; ethernet::MAX_FRAME_LEN] = [0; ethernet::MAX_FRAME_LEN];
+pub static mut CTL_BUF: [u8; ethernet::MAX_FRAME_LEN] = [0; ethernet::MAX_FRAME_LEN];
+
+/// EtherType of IPv4 packets.
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+
+/// Time to wait for an ARP reply before asking again.
+pub const ARP_TIMEOUT_MS: u32 = 1000;
+
+/// Number of times an ARP request is sent again before a datagram fails.
+pub const ARP_RETRIES: u8 = 2;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// Number of multicast groups the interface can join.
+pub const MAX_MULTICAST_GROUPS: usize = 4;
+
+/// Offset of the IPv4 header in a frame.
+const IP4_OFFSET: usize = ethernet::HEADER_LEN;
+
+/// The addresses of the interface.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IP4Config {
+    pub addr: IP4Addr,
+    pub netmask: IP4Addr,
+    /// The router for destinations outside the subnet, or the unspecified
+    /// address if there is none.
+    pub gateway: IP4Addr,
+}
+
+/// Access to the addresses of an IPv4 interface, for the capsules that
+/// configure it, such as the DHCP client.
+pub trait IP4Configuration {
+    fn mac_address(&self) -> MacAddress;
+
+    fn get_config(&self) -> IP4Config;
+
+    /// Change the addresses of the interface. This clears the ARP cache.
+    fn set_config(&self, config: IP4Config);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum TxState {
+    Idle,
+    /// The datagram waits for the MAC address of the next hop.
+    Resolving {
+        next_hop: IP4Addr,
+        retries: u8,
+    },
+    /// The datagram waits for the MAC to be done with a control frame.
+    Ready,
+    Sending,
+}
+
+pub struct IP4Interface<'a, M: ethernet::Mac<'a>, A: Alarm<'a>> {
+    mac: &'a M,
+    alarm: &'a A,
+    mac_addr: MacAddress,
+    config: Cell<IP4Config>,
+    arp_cache: ArpCache,
+    /// Joined multicast groups, unspecified addresses are free slots.
+    multicast_groups: Cell<[IP4Addr; MAX_MULTICAST_GROUPS]>,
+
+    /// The datagram being sent, as an Ethernet frame.
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_state: Cell<TxState>,
+    next_id: Cell<u16>,
+    tos: Cell<u8>,
+
+    /// ARP packets and ICMP echo replies.
+    ctl_buf: TakeCell<'static, [u8]>,
+    ctl_len: Cell<usize>,
+    ctl_sending: Cell<bool>,
+
+    send_client: OptionalCell<&'a dyn IP6SendClient>,
+    udp_client: OptionalCell<&'a dyn IP6RecvClient>,
+    ip_vis: &'static IpVisibilityCapability,
+}
+
+impl<'a, M: ethernet::Mac<'a>, A: Alarm<'a>> IP4Interface<'a, M, A> {
+    pub fn new(
+        mac: &'a M,
+        alarm: &'a A,
+        mac_addr: MacAddress,
+        config: IP4Config,
+        tx_buf: &'static mut [u8],
+        ctl_buf: &'static mut [u8],
+        ip_vis: &'static IpVisibilityCapability,
+    ) -> IP4Interface<'a, M, A> {
+        IP4Interface {
+            mac: mac,
+            alarm: alarm,
+            mac_addr: mac_addr,
+            config: Cell::new(config),
+            arp_cache: ArpCache::new(),
+            multicast_groups: Cell::new([IP4Addr::UNSPECIFIED; MAX_MULTICAST_GROUPS]),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_len: Cell::new(0),
+            tx_state: Cell::new(TxState::Idle),
+            next_id: Cell::new(0),
+            tos: Cell::new(0),
+            ctl_buf: TakeCell::new(ctl_buf),
+            ctl_len: Cell::new(0),
+            ctl_sending: Cell::new(false),
+            send_client: OptionalCell::empty(),
+            udp_client: OptionalCell::empty(),
+            ip_vis: ip_vis,
+        }
+    }
+
+    /// Set the client receiving UDP datagrams, usually a `MuxUdpReceiver`.
+    pub fn set_udp_client(&self, client: &'a dyn IP6RecvClient) {
+        self.udp_client.set(client);
+    }
+
+    /// Receive datagrams sent to the multicast group `group`. Fails with
+    /// `INVAL` if `group` is not a multicast address, and `NOMEM` if
+    /// `MAX_MULTICAST_GROUPS` are already joined.
+    pub fn join_multicast(&self, group: IP4Addr) -> Result<(), ErrorCode> {
+        if !group.is_multicast() {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut groups = self.multicast_groups.get();
+        if groups.contains(&group) {
+            return Ok(());
+        }
+        let slot = groups
+            .iter_mut()
+            .find(|slot| slot.is_unspecified())
+            .ok_or(ErrorCode::NOMEM)?;
+        *slot = group;
+        self.set_multicast_filter(&groups)?;
+        self.multicast_groups.set(groups);
+        Ok(())
+    }
+
+    /// Stop receiving datagrams sent to the multicast group `group`.
+    pub fn leave_multicast(&self, group: IP4Addr) -> Result<(), ErrorCode> {
+        let mut groups = self.multicast_groups.get();
+        let slot = groups
+            .iter_mut()
+            .find(|slot| **slot == group && !group.is_unspecified())
+            .ok_or(ErrorCode::INVAL)?;
+        *slot = IP4Addr::UNSPECIFIED;
+        self.multicast_groups.set(groups);
+        self.set_multicast_filter(&groups)
+    }
+
+    fn set_multicast_filter(
+        &self,
+        groups: &[IP4Addr; MAX_MULTICAST_GROUPS],
+    ) -> Result<(), ErrorCode> {
+        let mut addresses = [MacAddress([0; 6]); MAX_MULTICAST_GROUPS];
+        let mut count = 0;
+        for group in groups.iter().filter(|group| !group.is_unspecified()) {
+            addresses[count] = group.multicast_mac();
+            count += 1;
+        }
+        self.mac.set_multicast_addresses(&addresses[..count])
+    }
+
+    /// Whether a packet to `dst` is for this interface.
+    fn is_for_us(&self, dst: IP4Addr) -> bool {
+        let config = self.config.get();
+        dst == config.addr
+            || dst == IP4Addr::BROADCAST
+            || dst == config.addr.subnet_broadcast(config.netmask)
+            || (dst.is_multicast() && self.multicast_groups.get().contains(&dst))
+    }
+
+    fn next_hop(&self, dst: IP4Addr) -> IP4Addr {
+        let config = self.config.get();
+        if dst.same_subnet(config.addr, config.netmask) || config.gateway.is_unspecified() {
+            dst
+        } else {
+            config.gateway
+        }
+    }
+
+    fn write_eth_header(&self, frame: &mut [u8], dst: MacAddress, ethertype: u16) {
+        frame[0..6].copy_from_slice(&dst.0);
+        frame[6..12].copy_from_slice(&self.mac_addr.0);
+        frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+    }
+
+    /// Queue a control frame of `len` bytes written by `fill`, unless one is
+    /// already queued or being sent.
+    fn send_control<F: FnOnce(&mut [u8]) -> Option<usize>>(&self, fill: F) {
+        if self.ctl_len.get() > 0 {
+            return;
+        }
+        let len = self.ctl_buf.map_or(None, |frame| fill(frame));
+        if let Some(len) = len {
+            self.ctl_len.set(len);
+            self.transmit_next();
+        }
+    }
+
+    fn send_arp(&self, packet: ArpPacket, dst: MacAddress) {
+        self.send_control(|frame| {
+            self.write_eth_header(frame, dst, ETHERTYPE_ARP);
+            packet
+                .encode(frame, ethernet::HEADER_LEN)
+                .done()
+                .map(|(off, _)| off)
+        });
+    }
+
+    /// Ask for the MAC address of the next hop of the datagram waiting.
+    fn request_next_hop(&self, next_hop: IP4Addr) {
+        let request = ArpPacket::request(self.mac_addr, self.config.get().addr, next_hop);
+        self.send_arp(request, MacAddress::BROADCAST);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(ARP_TIMEOUT_MS));
+    }
+
+    /// Address the datagram waiting to `dst`, and send it once the MAC is
+    /// free.
+    fn resolved(&self, dst: MacAddress) -> Result<(), ErrorCode> {
+        self.tx_buf.map(|frame| frame[0..6].copy_from_slice(&dst.0));
+        self.tx_state.set(TxState::Ready);
+        if self.ctl_sending.get() {
+            Ok(())
+        } else {
+            self.transmit_datagram()
+        }
+    }
+
+    fn transmit_datagram(&self) -> Result<(), ErrorCode> {
+        let frame = self.tx_buf.take().ok_or(ErrorCode::FAIL)?;
+        match self.mac.transmit(frame, self.tx_len.get()) {
+            Ok(()) => {
+                self.tx_state.set(TxState::Sending);
+                Ok(())
+            }
+            Err((e, frame)) => {
+                self.tx_buf.replace(frame);
+                self.tx_state.set(TxState::Idle);
+                Err(e)
+            }
+        }
+    }
+
+    /// Send the next frame waiting, if the MAC is free. Control frames go
+    /// first.
+    fn transmit_next(&self) {
+        if self.ctl_sending.get() || self.tx_state.get() == TxState::Sending {
+            return;
+        }
+        let len = self.ctl_len.get();
+        if len > 0 {
+            self.ctl_buf
+                .take()
+                .map(|frame| match self.mac.transmit(frame, len) {
+                    Ok(()) => self.ctl_sending.set(true),
+                    Err((_, frame)) => {
+                        // The control frame is dropped.
+                        self.ctl_buf.replace(frame);
+                        self.ctl_len.set(0);
+                    }
+                });
+        }
+        if !self.ctl_sending.get() && self.tx_state.get() == TxState::Ready {
+            if let Err(e) = self.transmit_datagram() {
+                self.send_client.map(|client| client.send_done(Err(e)));
+            }
+        }
+    }
+
+    fn receive_arp(&self, payload: &[u8]) {
+        let packet = match ArpPacket::decode(payload).done() {
+            Some((_, packet)) => packet,
+            None => return,
+        };
+        let our_addr = self.config.get().addr;
+        if packet.sender_ip.is_unspecified() || our_addr.is_unspecified() {
+            return;
+        }
+
+        // Only neighbors that talk to us are added to the cache, but any
+        // neighbor already in it is updated.
+        if packet.target_ip == our_addr {
+            self.arp_cache.insert(packet.sender_ip, packet.sender_mac);
+        } else {
+            self.arp_cache.update(packet.sender_ip, packet.sender_mac);
+        }
+
+        if packet.op == ArpOp::Request && packet.target_ip == our_addr {
+            self.send_arp(ArpPacket::reply(&packet, self.mac_addr), packet.sender_mac);
+        }
+
+        if let TxState::Resolving { next_hop, .. } = self.tx_state.get() {
+            if next_hop == packet.sender_ip {
+                let _ = self.alarm.disarm();
+                if let Err(e) = self.resolved(packet.sender_mac) {
+                    self.send_client.map(|client| client.send_done(Err(e)));
+                }
+            }
+        }
+    }
+
+    fn receive_ip(&self, src_mac: MacAddress, packet: &[u8]) {
+        let (hdr_len, header) = match IP4Header::decode(packet).done() {
+            Some(decoded) => decoded,
+            None => return,
+        };
+        let total_len = header.total_len as usize;
+        if total_len > packet.len() || header.is_fragment() || !self.is_for_us(header.dst_addr) {
+            return;
+        }
+        let payload = &packet[hdr_len..total_len];
+        match header.protocol {
+            ip4_proto::ICMP => self.receive_icmp(src_mac, &header, payload),
+            ip4_proto::UDP => self.receive_udp(&header, payload),
+            _ => {}
+        }
+    }
+
+    /// Answer echo requests addressed to this interface.
+    fn receive_icmp(&self, src_mac: MacAddress, header: &IP4Header, message: &[u8]) {
+        if message.len() < 8
+            || message[0] != ICMP_ECHO_REQUEST
+            || message[1] != 0
+            || header.dst_addr != self.config.get().addr
+            || ip4_utils::compute_checksum(message) != 0
+        {
+            return;
+        }
+        self.send_control(|frame| {
+            let len = IP4_OFFSET + IP4_HDR_LEN + message.len();
+            if len > frame.len() {
+                return None;
+            }
+            self.write_eth_header(frame, src_mac, ETHERTYPE_IPV4);
+            let mut reply_header =
+                IP4Header::new(ip4_proto::ICMP, header.dst_addr, header.src_addr);
+            reply_header.total_len = (IP4_HDR_LEN + message.len()) as u16;
+            reply_header.id = self.next_id.get();
+            self.next_id.set(self.next_id.get().wrapping_add(1));
+            let off = match reply_header.encode(frame, IP4_OFFSET) {
+                SResult::Done(off, _) => off,
+                _ => return None,
+            };
+
+            let reply = &mut frame[off..len];
+            reply.copy_from_slice(message);
+            reply[0] = ICMP_ECHO_REPLY;
+            reply[2..4].copy_from_slice(&[0, 0]);
+            let cksum = ip4_utils::compute_checksum(reply);
+            reply[2..4].copy_from_slice(&cksum.to_be_bytes());
+            Some(len)
+        });
+    }
+
+    fn receive_udp(&self, header: &IP4Header, datagram: &[u8]) {
+        if datagram.len() < UDP_HDR_LEN {
+            return;
+        }
+        let udp_len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+        if udp_len < UDP_HDR_LEN || udp_len > datagram.len() {
+            return;
+        }
+        let datagram = &datagram[..udp_len];
+        // A zero checksum means the sender did not compute one.
+        let cksum = u16::from_be_bytes([datagram[6], datagram[7]]);
+        if cksum != 0
+            && ip4_utils::compute_udp_checksum(header.src_addr, header.dst_addr, datagram) != 0
+        {
+            return;
+        }
+
+        let mut ip6_header = IP6Header::new();
+        ip6_header.src_addr = header.src_addr.to_mapped();
+        ip6_header.dst_addr = self.config.get().addr.to_mapped();
+        ip6_header.set_next_header(ip6_nh::UDP);
+        ip6_header.set_payload_len(udp_len as u16);
+        ip6_header.set_hop_limit(header.ttl);
+        ip6_header.set_traffic_class(header.tos);
+        self.udp_client
+            .map(|client| client.receive(ip6_header, datagram));
+    }
+
+    /// Write the datagram to the tx buffer, as a frame without its
+    /// destination MAC address. Returns the length of the frame.
+    fn write_datagram(
+        &self,
+        frame: &mut [u8],
+        dst: IP4Addr,
+        transport_header: TransportHeader,
+        payload: &LeasableBuffer<'static, u8>,
+    ) -> Result<usize, ErrorCode> {
+        let udp_header = match transport_header {
+            TransportHeader::UDP(udp_header) => udp_header,
+            _ => return Err(ErrorCode::NOSUPPORT),
+        };
+        let udp_len = UDP_HDR_LEN + payload.len();
+        let len = IP4_OFFSET + IP4_HDR_LEN + udp_len;
+        if len > frame.len() || len > ethernet::MAX_FRAME_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+
+        self.write_eth_header(frame, MacAddress([0; 6]), ETHERTYPE_IPV4);
+        let src = self.config.get().addr;
+        let mut header = IP4Header::new(ip4_proto::UDP, src, dst);
+        header.tos = self.tos.get();
+        header.total_len = (IP4_HDR_LEN + udp_len) as u16;
+        header.id = self.next_id.get();
+        self.next_id.set(self.next_id.get().wrapping_add(1));
+        let off = header
+            .encode(frame, IP4_OFFSET)
+            .done()
+            .map(|(off, _)| off)
+            .ok_or(ErrorCode::SIZE)?;
+
+        let datagram = &mut frame[off..len];
+        datagram[0..2].copy_from_slice(&udp_header.get_src_port().to_be_bytes());
+        datagram[2..4].copy_from_slice(&udp_header.get_dst_port().to_be_bytes());
+        datagram[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+        datagram[6..8].copy_from_slice(&[0, 0]);
+        datagram[UDP_HDR_LEN..].copy_from_slice(&payload[..]);
+        let cksum = match ip4_utils::compute_udp_checksum(src, dst, datagram) {
+            // A computed checksum of zero is sent as all ones.
+            0 => 0xFFFF,
+            cksum => cksum,
+        };
+        datagram[6..8].copy_from_slice(&cksum.to_be_bytes());
+        Ok(len)
+    }
+}
+
+impl<'a, M: ethernet::Mac<'a>, A: Alarm<'a>> IP4Configuration for IP4Interface<'a, M, A> {
+    fn mac_address(&self) -> MacAddress {
+        self.mac_addr
+    }
+
+    fn get_config(&self) -> IP4Config {
+        self.config.get()
+    }
+
+    fn set_config(&self, config: IP4Config) {
+        self.config.set(config);
+        self.arp_cache.clear();
+    }
+}
+
+/// The IPv4 interface takes the place of the IPv6 layer below the UDP layer.
+/// Addresses must be IPv4-mapped, and only UDP is supported.
+impl<'a, M: ethernet::Mac<'a>, A: Alarm<'a>> IP6Sender<'a> for IP4Interface<'a, M, A> {
+    fn set_client(&self, client: &'a dyn IP6SendClient) {
+        self.send_client.set(client);
+    }
+
+    /// Sets the address of the interface, if `src_addr` is IPv4-mapped.
+    fn set_addr(&self, src_addr: IPAddr) {
+        if let Some(addr) = IP4Addr::from_mapped(src_addr) {
+            let mut config = self.config.get();
+            config.addr = addr;
+            self.set_config(config);
+        }
+    }
+
+    /// The gateway is an IPv4 address, set with `set_config()`.
+    fn set_gateway(&self, _gateway: crate::net::ieee802154::MacAddress) {}
+
+    /// IPv4 headers are built for each datagram.
+    fn set_header(&mut self, _ip6_header: IP6Header) {}
+
+    /// Sets the type of service byte, which has the same layout as the
+    /// traffic class.
+    fn set_traffic_class(&self, traffic_class: u8) {
+        self.tos.set(traffic_class);
+    }
+
+    fn send_to(
+        &self,
+        dst: IPAddr,
+        transport_header: TransportHeader,
+        payload: &LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), ErrorCode> {
+        if !net_cap.remote_addr_valid(dst, self.ip_vis) {
+            return Err(ErrorCode::FAIL);
+        }
+        let dst = IP4Addr::from_mapped(dst).ok_or(ErrorCode::INVAL)?;
+        if self.tx_state.get() != TxState::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let len = self.tx_buf.map_or(Err(ErrorCode::BUSY), |frame| {
+            self.write_datagram(frame, dst, transport_header, payload)
+        })?;
+        self.tx_len.set(len);
+
+        if dst.is_multicast() {
+            return self.resolved(dst.multicast_mac());
+        }
+        if self.is_for_us(dst) && dst != self.config.get().addr {
+            return self.resolved(MacAddress::BROADCAST);
+        }
+        let next_hop = self.next_hop(dst);
+        match self.arp_cache.lookup(next_hop) {
+            Some(dst_mac) => self.resolved(dst_mac),
+            None => {
+                self.tx_state.set(TxState::Resolving {
+                    next_hop: next_hop,
+                    retries: 0,
+                });
+                self.request_next_hop(next_hop);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a, M: ethernet::Mac<'a>, A: Alarm<'a>> ethernet::Client for IP4Interface<'a, M, A> {
+    fn transmit_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        if self.ctl_sending.get() {
+            self.ctl_sending.set(false);
+            self.ctl_len.set(0);
+            self.ctl_buf.replace(buffer);
+        } else {
+            self.tx_buf.replace(buffer);
+            self.tx_state.set(TxState::Idle);
+            self.send_client.map(|client| client.send_done(result));
+        }
+        self.transmit_next();
+    }
+
+    fn frame_received(&self, frame: &[u8]) {
+        if frame.len() < ethernet::HEADER_LEN {
+            return;
+        }
+        let mut src_mac = MacAddress([0; 6]);
+        src_mac.0.copy_from_slice(&frame[6..12]);
+        let payload = &frame[ethernet::HEADER_LEN..];
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP if payload.len() >= ARP_PKT_LEN => self.receive_arp(payload),
+            ETHERTYPE_IPV4 => self.receive_ip(src_mac, payload),
+            _ => {}
+        }
+    }
+
+    fn link_status_changed(&self, status: LinkStatus) {
+        if status == LinkStatus::Down {
+            self.arp_cache.clear();
+        }
+    }
+}
+
+impl<'a, M: ethernet::Mac<'a>, A: Alarm<'a>> time::AlarmClient for IP4Interface<'a, M, A> {
+    fn alarm(&self) {
+        if let TxState::Resolving { next_hop, retries } = self.tx_state.get() {
+            if retries < ARP_RETRIES {
+                self.tx_state.set(TxState::Resolving {
+                    next_hop: next_hop,
+                    retries: retries + 1,
+                });
+                self.request_next_hop(next_hop);
+            } else {
+                self.tx_state.set(TxState::Idle);
+                self.send_client
+                    .map(|client| client.send_done(Err(ErrorCode::NOACK)));
+            }
+        }
+    }
+}
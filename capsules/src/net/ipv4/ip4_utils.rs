@@ -0,0 +1,219 @@
+//! This file contains the definition of the [IP4Addr](struct.IP4Addr.html)
+//! and [IP4Header](struct.IP4Header.html) structs, and the Internet checksum
+//! used by the IPv4 layer.
+//!
+//! The UDP layer and the UDP userspace driver only handle 16-byte `IPAddr`
+//! addresses. IPv4 addresses are passed through them as IPv4-mapped IPv6
+//! addresses (`::ffff:a.b.c.d`, RFC 4291 section 2.5.5.2).
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+use kernel::hil::ethernet::MacAddress;
+
+pub mod ip4_proto {
+    pub const ICMP: u8 = 1;
+    pub const UDP: u8 = 17;
+}
+
+/// Length of an IPv4 header without options.
+pub const IP4_HDR_LEN: usize = 20;
+
+/// Time to live of the packets sent.
+pub const DEFAULT_TTL: u8 = 64;
+
+/// The "Don't Fragment" flag, in the flags and fragment offset field.
+const FLAG_DF: u16 = 0x4000;
+/// The "More Fragments" flag and the fragment offset.
+const FRAGMENT_MASK: u16 = 0x3FFF;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IP4Addr(pub [u8; 4]);
+
+impl IP4Addr {
+    pub const UNSPECIFIED: IP4Addr = IP4Addr([0; 4]);
+    pub const BROADCAST: IP4Addr = IP4Addr([0xFF; 4]);
+
+    pub fn is_unspecified(&self) -> bool {
+        *self == IP4Addr::UNSPECIFIED
+    }
+
+    /// Whether `self` is a multicast group address (224.0.0.0/4).
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0xF0 == 0xE0
+    }
+
+    /// The Ethernet address of the multicast group `self` (RFC 1112 section
+    /// 6.4): the low 23 bits of the group after `01:00:5e`.
+    pub fn multicast_mac(&self) -> MacAddress {
+        MacAddress([0x01, 0x00, 0x5E, self.0[1] & 0x7F, self.0[2], self.0[3]])
+    }
+
+    /// Whether `self` and `other` are in the same subnet.
+    pub fn same_subnet(&self, other: IP4Addr, netmask: IP4Addr) -> bool {
+        (0..4).all(|i| self.0[i] & netmask.0[i] == other.0[i] & netmask.0[i])
+    }
+
+    /// The broadcast address of the subnet of `self`.
+    pub fn subnet_broadcast(&self, netmask: IP4Addr) -> IP4Addr {
+        let mut addr = *self;
+        for i in 0..4 {
+            addr.0[i] |= !netmask.0[i];
+        }
+        addr
+    }
+
+    /// The IPv4-mapped IPv6 address of `self`.
+    pub fn to_mapped(&self) -> IPAddr {
+        let mut addr = IPAddr::new();
+        addr.0[10] = 0xFF;
+        addr.0[11] = 0xFF;
+        addr.0[12..16].copy_from_slice(&self.0);
+        addr
+    }
+
+    /// The IPv4 address `addr` is mapped from, or `None` if it is not an
+    /// IPv4-mapped address.
+    pub fn from_mapped(addr: IPAddr) -> Option<IP4Addr> {
+        if addr.0[..10].iter().all(|&b| b == 0) && addr.0[10] == 0xFF && addr.0[11] == 0xFF {
+            let mut ip4_addr = IP4Addr::UNSPECIFIED;
+            ip4_addr.0.copy_from_slice(&addr.0[12..16]);
+            Some(ip4_addr)
+        } else {
+            None
+        }
+    }
+}
+
+/// Adds the 16-bit big-endian words of `buf` to `sum`, padding an odd last
+/// byte with zero.
+pub fn add_to_sum(mut sum: u32, buf: &[u8]) -> u32 {
+    for chunk in buf.chunks(2) {
+        let lsb = if chunk.len() == 2 { chunk[1] } else { 0 };
+        sum += (chunk[0] as u32) << 8 | lsb as u32;
+    }
+    sum
+}
+
+/// Folds `sum` into the one's complement checksum. Over data that includes
+/// a correct checksum, this returns 0.
+pub fn fold_checksum(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+    !sum as u16
+}
+
+/// The Internet checksum (RFC 1071) of `buf`.
+pub fn compute_checksum(buf: &[u8]) -> u16 {
+    fold_checksum(add_to_sum(0, buf))
+}
+
+/// The checksum of a UDP datagram, header included, with the IPv4
+/// pseudo-header.
+pub fn compute_udp_checksum(src_addr: IP4Addr, dst_addr: IP4Addr, datagram: &[u8]) -> u16 {
+    let mut sum = add_to_sum(0, &src_addr.0);
+    sum = add_to_sum(sum, &dst_addr.0);
+    sum += ip4_proto::UDP as u32;
+    sum += datagram.len() as u32;
+    fold_checksum(add_to_sum(sum, datagram))
+}
+
+/// The fields of an IPv4 header. Options are skipped when decoding, and
+/// never sent.
+#[derive(Copy, Clone, Debug)]
+pub struct IP4Header {
+    pub tos: u8,
+    pub total_len: u16,
+    pub id: u16,
+    pub flags_fragment: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub src_addr: IP4Addr,
+    pub dst_addr: IP4Addr,
+}
+
+impl IP4Header {
+    /// A header for a packet that must not be fragmented.
+    pub fn new(protocol: u8, src_addr: IP4Addr, dst_addr: IP4Addr) -> IP4Header {
+        IP4Header {
+            tos: 0,
+            total_len: IP4_HDR_LEN as u16,
+            id: 0,
+            flags_fragment: FLAG_DF,
+            ttl: DEFAULT_TTL,
+            protocol: protocol,
+            src_addr: src_addr,
+            dst_addr: dst_addr,
+        }
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.flags_fragment & FRAGMENT_MASK != 0
+    }
+
+    /// This function serializes the `IP4Header` into the provided buffer,
+    /// with its checksum.
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the new offset into the buffer wrapped in an
+    /// SResult.
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, IP4_HDR_LEN + offset);
+
+        let mut off = offset;
+        off = enc_consume!(buf, off; encode_u8, 0x45);
+        off = enc_consume!(buf, off; encode_u8, self.tos);
+        off = enc_consume!(buf, off; encode_u16, self.total_len);
+        off = enc_consume!(buf, off; encode_u16, self.id);
+        off = enc_consume!(buf, off; encode_u16, self.flags_fragment);
+        off = enc_consume!(buf, off; encode_u8, self.ttl);
+        off = enc_consume!(buf, off; encode_u8, self.protocol);
+        let cksum_off = off;
+        off = enc_consume!(buf, off; encode_u16, 0);
+        off = enc_consume!(buf, off; encode_bytes, &self.src_addr.0);
+        off = enc_consume!(buf, off; encode_bytes, &self.dst_addr.0);
+
+        let cksum = compute_checksum(&buf[offset..off]);
+        enc_consume!(buf, cksum_off; encode_u16, cksum);
+        stream_done!(off, off);
+    }
+
+    /// This function deserializes an `IP4Header` from the provided buffer,
+    /// checking its version, length and checksum.
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the offset of the payload and the header
+    /// wrapped in an SResult.
+    pub fn decode(buf: &[u8]) -> SResult<IP4Header> {
+        stream_len_cond!(buf, IP4_HDR_LEN);
+        let (off, version_ihl) = dec_try!(buf, 0; decode_u8);
+        stream_cond!(version_ihl >> 4 == 4);
+        let hdr_len = (version_ihl & 0x0F) as usize * 4;
+        stream_cond!(hdr_len >= IP4_HDR_LEN);
+        stream_len_cond!(buf, hdr_len);
+        stream_cond!(compute_checksum(&buf[..hdr_len]) == 0);
+
+        let (off, tos) = dec_try!(buf, off; decode_u8);
+        let (off, total_len) = dec_try!(buf, off; decode_u16);
+        let (off, id) = dec_try!(buf, off; decode_u16);
+        let (off, flags_fragment) = dec_try!(buf, off; decode_u16);
+        let (off, ttl) = dec_try!(buf, off; decode_u8);
+        let (off, protocol) = dec_try!(buf, off; decode_u8);
+        let (off, _cksum) = dec_try!(buf, off; decode_u16);
+        let mut header = IP4Header::new(protocol, IP4Addr::UNSPECIFIED, IP4Addr::UNSPECIFIED);
+        let off = dec_consume!(buf, off; decode_bytes, &mut header.src_addr.0);
+        dec_consume!(buf, off; decode_bytes, &mut header.dst_addr.0);
+        stream_cond!(total_len as usize >= hdr_len);
+
+        header.tos = tos;
+        header.total_len = total_len;
+        header.id = id;
+        header.flags_fragment = flags_fragment;
+        header.ttl = ttl;
+        stream_done!(hdr_len, header);
+    }
+}
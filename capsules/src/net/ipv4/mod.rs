@@ -0,0 +1,3 @@
+pub mod arp;
+pub mod ip4_interface;
+pub mod ip4_utils;
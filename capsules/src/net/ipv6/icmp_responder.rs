@@ -0,0 +1,268 @@
+//! This file contains a responder to ICMPv6 messages for the IPv6 layer over
+//! 6LoWPAN.
+//!
+//! The responder answers echo requests (ping) sent to an address of the
+//! interface or to the all-nodes group, and Neighbor Solicitations (RFC 4861)
+//! for an address of the interface with a Neighbor Advertisement carrying
+//! the MAC address of the board. The link-layer addresses of the neighbors
+//! that send solicitations are stored in a `NeighborCache`, and updated by
+//! their advertisements. The `IP6SendStruct` given the same cache with
+//! `set_neighbor_cache()` sends packets to a neighbor in the cache directly,
+//! rather than to the gateway.
+//!
+//! Echo replies can be turned off with `set_echo_enabled(false)`, so that
+//! deployments that must not reveal the board do not answer pings. Neighbor
+//! advertisements are always sent, as neighbors cannot reach the board
+//! without them.
+//!
+//! One reply is sent at a time, and messages that arrive meanwhile are
+//! dropped.
+//!
+//! Usage
+//! -----
+//!
+//! The responder needs its own `IP6Sender`, and receives ICMPv6 messages
+//! from the IPv6 receiver:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let neighbors = static_init!(
+//!     capsules::net::ipv6::ndp::NeighborCache,
+//!     capsules::net::ipv6::ndp::NeighborCache::new()
+//! );
+//! let icmp_responder = static_init!(
+//!     capsules::net::ipv6::icmp_responder::ICMP6Responder<'static>,
+//!     capsules::net::ipv6::icmp_responder::ICMP6Responder::new(
+//!         icmp_send,
+//!         src_mac_addr,
+//!         interface_list,
+//!         neighbors,
+//!         LeasableBuffer::new(icmp_buffer),
+//!         net_cap,
+//!     )
+//! );
+//! icmp_send.set_client(icmp_responder);
+//! ip_receive.set_icmp_client(icmp_responder);
+//! ip_send.set_neighbor_cache(neighbors);
+//! ```
+
+use crate::net::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::ndp::{self, na_flags, nd_option, NeighborCache};
+use crate::net::ipv6::{IP6Header, TransportHeader, ICMP_HDR_LEN};
+use crate::net::network_capabilities::NetworkCapability;
+use core::cell::Cell;
+use kernel::common::cells::MapCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::ErrorCode;
+
+/// The all-nodes multicast address, `ff02::1`.
+pub const ALL_NODES_ADDR: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+pub struct ICMP6Responder<'a> {
+    sender: &'a dyn IP6Sender<'a>,
+    mac_addr: MacAddress,
+    interface_list: &'static [IPAddr],
+    neighbors: &'a NeighborCache,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    sending: Cell<bool>,
+    echo_enabled: Cell<bool>,
+    net_cap: &'static NetworkCapability,
+}
+
+impl<'a> ICMP6Responder<'a> {
+    pub fn new(
+        sender: &'a dyn IP6Sender<'a>,
+        mac_addr: MacAddress,
+        interface_list: &'static [IPAddr],
+        neighbors: &'a NeighborCache,
+        tx_buffer: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> ICMP6Responder<'a> {
+        ICMP6Responder {
+            sender: sender,
+            mac_addr: mac_addr,
+            interface_list: interface_list,
+            neighbors: neighbors,
+            tx_buffer: MapCell::new(tx_buffer),
+            sending: Cell::new(false),
+            echo_enabled: Cell::new(true),
+            net_cap: net_cap,
+        }
+    }
+
+    /// Answer echo requests or not. They are answered by default.
+    pub fn set_echo_enabled(&self, enabled: bool) {
+        self.echo_enabled.set(enabled);
+    }
+
+    fn is_local(&self, addr: IPAddr) -> bool {
+        self.interface_list.contains(&addr)
+    }
+
+    fn receive_echo_request(&self, ip6_header: &IP6Header, id: u16, seqno: u16, data: &[u8]) {
+        let dst = ip6_header.get_dst_addr();
+        let src = ip6_header.get_src_addr();
+        if !self.echo_enabled.get()
+            || src.is_multicast()
+            || src.is_unspecified()
+            || !(self.is_local(dst) || dst == ALL_NODES_ADDR)
+        {
+            return;
+        }
+        // Requests to the all-nodes group are answered from the first
+        // address of the interface.
+        let reply_src = if dst.is_multicast() {
+            match self.interface_list.first() {
+                Some(addr) => *addr,
+                None => return,
+            }
+        } else {
+            dst
+        };
+        let mut icmp_header = ICMP6Header::new(ICMP6Type::Type129);
+        icmp_header.set_options(ICMP6HeaderOptions::Type129 {
+            id: id,
+            seqno: seqno,
+        });
+        self.send(reply_src, src, icmp_header, |buf| {
+            let reply = buf.get_mut(..data.len())?;
+            reply.copy_from_slice(data);
+            Some(data.len())
+        });
+    }
+
+    fn receive_solicitation(&self, ip6_header: &IP6Header, body: &[u8]) {
+        let src = ip6_header.get_src_addr();
+        let dst = ip6_header.get_dst_addr();
+        let mut target = IPAddr::new();
+        target.0.copy_from_slice(&body[..ndp::ND_TARGET_LEN]);
+        if target.is_multicast() {
+            return;
+        }
+        let src_mac =
+            match ndp::find_ll_option(&body[ndp::ND_TARGET_LEN..], nd_option::SOURCE_LL_ADDR) {
+                Ok(src_mac) => src_mac,
+                Err(()) => return,
+            };
+        // Duplicate address detection, from the unspecified address, must
+        // be to the solicited-node group and without a link-layer address.
+        if src.is_unspecified() && (dst != ndp::solicited_node_addr(target) || src_mac.is_some()) {
+            return;
+        }
+        if let Some(src_mac) = src_mac {
+            self.neighbors.insert(src, src_mac);
+        }
+        if !self.is_local(target) {
+            return;
+        }
+
+        let (reply_dst, flags) = if src.is_unspecified() {
+            (ALL_NODES_ADDR, na_flags::OVERRIDE)
+        } else {
+            (src, na_flags::OVERRIDE | na_flags::SOLICITED)
+        };
+        let mut icmp_header = ICMP6Header::new(ICMP6Type::Type136);
+        icmp_header.set_options(ICMP6HeaderOptions::Type136 { flags: flags });
+        let mac_addr = self.mac_addr;
+        self.send(target, reply_dst, icmp_header, |buf| {
+            buf.get_mut(..ndp::ND_TARGET_LEN)?
+                .copy_from_slice(&target.0);
+            let len = ndp::encode_ll_option(
+                &mut buf[ndp::ND_TARGET_LEN..],
+                nd_option::TARGET_LL_ADDR,
+                mac_addr,
+            )?;
+            Some(ndp::ND_TARGET_LEN + len)
+        });
+    }
+
+    fn receive_advertisement(&self, body: &[u8]) {
+        let mut target = IPAddr::new();
+        target.0.copy_from_slice(&body[..ndp::ND_TARGET_LEN]);
+        if target.is_multicast() {
+            return;
+        }
+        // Solicitations are never sent, so advertisements only update the
+        // neighbors already in the cache.
+        if let Ok(Some(target_mac)) =
+            ndp::find_ll_option(&body[ndp::ND_TARGET_LEN..], nd_option::TARGET_LL_ADDR)
+        {
+            self.neighbors.update(target, target_mac);
+        }
+    }
+
+    /// Send a message from `src` to `dst`, with the body written by `fill`,
+    /// unless a message is being sent.
+    fn send<F: FnOnce(&mut [u8]) -> Option<usize>>(
+        &self,
+        src: IPAddr,
+        dst: IPAddr,
+        icmp_header: ICMP6Header,
+        fill: F,
+    ) {
+        if self.sending.get() {
+            return;
+        }
+        self.tx_buffer.take().map(|mut buffer| {
+            buffer.reset();
+            if let Some(len) = fill(&mut buffer[..]) {
+                buffer.slice(0..len);
+                self.sender.set_addr(src);
+                // The sender copies the message, and may be done before it
+                // returns.
+                self.sending.set(true);
+                if self
+                    .sender
+                    .send_to(
+                        dst,
+                        TransportHeader::ICMP(icmp_header),
+                        &buffer,
+                        self.net_cap,
+                    )
+                    .is_err()
+                {
+                    self.sending.set(false);
+                }
+            }
+            buffer.reset();
+            self.tx_buffer.replace(buffer);
+        });
+    }
+}
+
+impl<'a> IP6RecvClient for ICMP6Responder<'a> {
+    fn receive(&self, ip6_header: IP6Header, message: &[u8]) {
+        // Other types of messages are ignored.
+        let icmp_header = match ICMP6Header::decode(message).done() {
+            Some((_, icmp_header)) => icmp_header,
+            None => return,
+        };
+        let body = &message[ICMP_HDR_LEN..];
+        let neighbor_discovery = ip6_header.get_hop_limit() == ndp::ND_HOP_LIMIT
+            && icmp_header.get_code() == 0
+            && body.len() >= ndp::ND_TARGET_LEN;
+        match icmp_header.get_options() {
+            ICMP6HeaderOptions::Type128 { id, seqno } => {
+                self.receive_echo_request(&ip6_header, id, seqno, body)
+            }
+            ICMP6HeaderOptions::Type135 { .. } if neighbor_discovery => {
+                self.receive_solicitation(&ip6_header, body)
+            }
+            ICMP6HeaderOptions::Type136 { .. } if neighbor_discovery => {
+                self.receive_advertisement(body)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a> IP6SendClient for ICMP6Responder<'a> {
+    fn send_done(&self, _result: Result<(), ErrorCode>) {
+        self.sending.set(false);
+    }
+}
@@ -5,6 +5,8 @@
 use crate::net::icmpv6::{ICMP6Header, ICMP6HeaderOptions};
 use crate::net::ieee802154::MacAddress;
 use crate::net::ipv6::IP6Header;
+use crate::net::tcp::tcp::TCP_HDR_LEN;
+use crate::net::tcp::TCPHeader;
 use crate::net::udp::UDPHeader;
 
 #[derive(Copy, Clone, PartialEq)]
@@ -197,7 +199,10 @@ pub fn compute_icmp_checksum(
 
     // add options
     match icmp_header.get_options() {
-        ICMP6HeaderOptions::Type1 { unused } | ICMP6HeaderOptions::Type3 { unused } => {
+        ICMP6HeaderOptions::Type1 { unused }
+        | ICMP6HeaderOptions::Type3 { unused }
+        | ICMP6HeaderOptions::Type135 { reserved: unused }
+        | ICMP6HeaderOptions::Type136 { flags: unused } => {
             sum += unused >> 16; // upper 16 bits
             sum += unused & 0xffff; // lower 16 bits
         }
@@ -215,7 +220,7 @@ pub fn compute_icmp_checksum(
     while sum > 0xffff {
         let sum_upper = sum >> 16;
         let sum_lower = sum & 0xffff;
-        sum += sum_upper + sum_lower;
+        sum = sum_upper + sum_lower;
     }
 
     sum = !sum;
@@ -224,6 +229,74 @@ pub fn compute_icmp_checksum(
     sum as u16
 }
 
+/// Computes the checksum of a whole ICMPv6 message, header included, of any
+/// type. The checksum field is included in the sum, so this returns 0 when
+/// verifying a received message with a correct checksum.
+pub fn compute_icmp_message_checksum(ip6_header: &IP6Header, message: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+
+    // add ipv6 pseudo-header, with the message length as upper-layer length
+    let mut i = 0;
+    while i < 16 {
+        sum += (ip6_header.src_addr.0[i] as u32) << 8 | ip6_header.src_addr.0[i + 1] as u32;
+        sum += (ip6_header.dst_addr.0[i] as u32) << 8 | ip6_header.dst_addr.0[i + 1] as u32;
+        i += 2;
+    }
+    sum += message.len() as u32 >> 16;
+    sum += message.len() as u32 & 0xffff;
+    sum += ip6_nh::ICMP as u32;
+
+    sum += compute_sum(message, message.len() as u16);
+
+    // carry overflow
+    while sum > 0xffff {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+
+    !sum as u16
+}
+
+/// Computes the checksum of a TCP segment made of `tcp_header` followed by
+/// `payload`, which holds any options and the data. The checksum field of
+/// `tcp_header` is included in the sum, so this returns 0 when verifying a
+/// received segment with a correct checksum.
+pub fn compute_tcp_checksum(ip6_header: &IP6Header, tcp_header: &TCPHeader, payload: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+
+    // add ipv6 pseudo-header, with the segment length as upper-layer length
+    let mut i = 0;
+    while i < 16 {
+        sum += (ip6_header.src_addr.0[i] as u32) << 8 | ip6_header.src_addr.0[i + 1] as u32;
+        sum += (ip6_header.dst_addr.0[i] as u32) << 8 | ip6_header.dst_addr.0[i + 1] as u32;
+        i += 2;
+    }
+    let tcp_length = (TCP_HDR_LEN + payload.len()) as u32;
+    sum += tcp_length >> 16;
+    sum += tcp_length & 0xffff;
+    sum += ip6_nh::TCP as u32;
+
+    // add tcp header
+    let mut header = [0; TCP_HDR_LEN];
+    let _ = tcp_header.encode(&mut header, 0);
+    sum += compute_sum(&header, TCP_HDR_LEN as u16);
+
+    // add payload, padding an odd last byte with zero
+    let even_len = payload.len() & !1;
+    for chunk in payload[..even_len].chunks(2) {
+        sum += (chunk[0] as u32) << 8 | chunk[1] as u32;
+    }
+    if even_len < payload.len() {
+        sum += (payload[even_len] as u32) << 8;
+    }
+
+    // carry overflow
+    while sum > 0xffff {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+
+    !sum as u16
+}
+
 pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
     let mut sum: u32 = 0;
 
@@ -241,7 +314,7 @@ pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
         i += 2;
     }
 
-    sum += ip6_header.payload_len as u32;
+    sum += ip6_header.get_payload_len() as u32;
     sum += ip6_header.next_header as u32;
 
     sum
@@ -250,12 +323,11 @@ pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
 pub fn compute_sum(buf: &[u8], len: u16) -> u32 {
     let mut sum: u32 = 0;
 
-    let mut i: usize = 0;
-    while i < (len as usize) {
-        let msb = (buf[i] as u32) << 8;
-        let lsb = buf[i + 1] as u32;
+    // an odd last byte is padded with zero
+    for chunk in buf[..len as usize].chunks(2) {
+        let msb = (chunk[0] as u32) << 8;
+        let lsb = chunk.get(1).map_or(0, |&b| b as u32);
         sum += msb + lsb;
-        i += 2;
     }
 
     sum
@@ -1,3 +1,4 @@
+use crate::net::ipv6::ip_utils::ip6_nh;
 use crate::net::ipv6::IP6Header;
 use crate::net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
 use kernel::common::cells::OptionalCell;
@@ -25,6 +26,9 @@ use kernel::ErrorCode;
   udp_recv, a `UDPReceive` struct.
 - The UDPReceive struct is a field of the UDPDriver, which ultimately passes the
   packets up to userland.
+- TCP segments are instead passed to a separate TCP client, the `TCPDriver`,
+  if one is set, and ICMPv6 messages to a separate ICMP client, the
+  `ICMP6Responder`, if one is set.
 */
 
 pub trait IP6RecvClient {
@@ -38,22 +42,42 @@ pub trait IP6RecvClient {
 /// that are not among the local addresses of this device.
 pub trait IP6Receiver<'a> {
     fn set_client(&self, client: &'a dyn IP6RecvClient);
+
+    /// Set the client receiving TCP segments, which are otherwise passed to
+    /// the client set with `set_client()`.
+    fn set_tcp_client(&self, client: &'a dyn IP6RecvClient);
+
+    /// Set the client receiving ICMPv6 messages, which are otherwise passed
+    /// to the client set with `set_client()`.
+    fn set_icmp_client(&self, client: &'a dyn IP6RecvClient);
 }
 
 pub struct IP6RecvStruct<'a> {
     client: OptionalCell<&'a dyn IP6RecvClient>,
+    tcp_client: OptionalCell<&'a dyn IP6RecvClient>,
+    icmp_client: OptionalCell<&'a dyn IP6RecvClient>,
 }
 
 impl<'a> IP6Receiver<'a> for IP6RecvStruct<'a> {
     fn set_client(&self, client: &'a dyn IP6RecvClient) {
         self.client.set(client);
     }
+
+    fn set_tcp_client(&self, client: &'a dyn IP6RecvClient) {
+        self.tcp_client.set(client);
+    }
+
+    fn set_icmp_client(&self, client: &'a dyn IP6RecvClient) {
+        self.icmp_client.set(client);
+    }
 }
 
 impl<'a> IP6RecvStruct<'a> {
     pub fn new() -> IP6RecvStruct<'a> {
         IP6RecvStruct {
             client: OptionalCell::empty(),
+            tcp_client: OptionalCell::empty(),
+            icmp_client: OptionalCell::empty(),
         }
     }
 }
@@ -71,11 +95,15 @@ impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
                     debug!("cksum fail!: {:?}", checksum_result);
                     return; //Dropped.
                 }
-                // Note: Protocols for which checksum verification is not implemented (TCP, etc.)
+                // Note: Protocols for which checksum verification is not implemented
                 // are automatically assumed as fine, rather than dropped
 
-                self.client
-                    .map(|client| client.receive(ip6_header, &buf[offset..len]));
+                let client = match ip6_header.get_next_header() {
+                    ip6_nh::TCP if self.tcp_client.is_some() => &self.tcp_client,
+                    ip6_nh::ICMP if self.icmp_client.is_some() => &self.icmp_client,
+                    _ => &self.client,
+                };
+                client.map(|client| client.receive(ip6_header, &buf[offset..len]));
             }
             None => {
                 debug!("failed to decode ipv6 header");
@@ -5,7 +5,11 @@
 //! when a transmission has completed.
 //!
 //! This file also includes an implementation of the `IP6Sender` trait, which
-//! sends an IPv6 packet using 6LoWPAN.
+//! sends an IPv6 packet using 6LoWPAN. Packets are sent to the MAC address of
+//! the destination if it is in the neighbor cache set with
+//! `set_neighbor_cache()`, and to the gateway otherwise. The frames are
+//! secured by the 802.15.4 framer with the security level and key set with
+//! `set_security()`, or sent unsecured if none is set.
 
 // Additional Work and Known Problems
 // ----------------------------------
@@ -17,8 +21,9 @@
 // interface.
 
 use crate::ieee802154::device::{MacDevice, TxClient};
-use crate::net::ieee802154::MacAddress;
+use crate::net::ieee802154::{KeyId, MacAddress, SecurityLevel};
 use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ndp::NeighborCache;
 use crate::net::ipv6::{IP6Header, IP6Packet, TransportHeader};
 use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
 use crate::net::sixlowpan::sixlowpan_state::TxState;
@@ -72,6 +77,13 @@ pub trait IP6Sender<'a> {
     /// `IP6Sender` instance will use
     fn set_header(&mut self, ip6_header: IP6Header);
 
+    /// This method sets the traffic class (DSCP and ECN) of packets sent
+    /// from this `IP6Sender` instance. The default is 0 (best effort).
+    ///
+    /// # Arguments
+    /// `traffic_class` - Traffic class byte placed in the IPv6 header
+    fn set_traffic_class(&self, traffic_class: u8);
+
     /// This method sends the provided transport header and payload to the
     /// given destination IP address
     ///
@@ -97,11 +109,13 @@ pub struct IP6SendStruct<'a, A: time::Alarm<'a>> {
     // successful reception on receivers with slow copies out of the radio buffer
     // (imix)
     src_addr: Cell<IPAddr>,
+    traffic_class: Cell<u8>,
     gateway: Cell<MacAddress>,
+    neighbors: OptionalCell<&'a NeighborCache>,
+    security: Cell<Option<(SecurityLevel, KeyId)>>,
     tx_buf: TakeCell<'static, [u8]>,
     sixlowpan: TxState<'a>,
     radio: &'a dyn MacDevice<'a>,
-    dst_mac_addr: MacAddress,
     src_mac_addr: MacAddress,
     client: OptionalCell<&'a dyn IP6SendClient>,
     ip_vis: &'static IpVisibilityCapability,
@@ -125,6 +139,10 @@ impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
             .map(|ip6_packet| ip6_packet.header = ip6_header);
     }
 
+    fn set_traffic_class(&self, traffic_class: u8) {
+        self.traffic_class.set(traffic_class);
+    }
+
     fn send_to(
         &self,
         dst: IPAddr,
@@ -135,11 +153,15 @@ impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
         if !net_cap.remote_addr_valid(dst, self.ip_vis) {
             return Err(ErrorCode::FAIL);
         }
+        let dst_mac_addr = self
+            .neighbors
+            .map_or(None, |neighbors| neighbors.lookup(dst))
+            .unwrap_or(self.gateway.get());
         let _ = self.sixlowpan.init(
             self.src_mac_addr,
-            self.dst_mac_addr,
+            dst_mac_addr,
             self.radio.get_pan(),
-            None,
+            self.security.get(),
         );
         self.init_packet(dst, transport_header, payload);
         let ret = self.send_next_fragment();
@@ -162,17 +184,31 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
             ip6_packet: TakeCell::new(ip6_packet),
             alarm: alarm,
             src_addr: Cell::new(IPAddr::new()),
+            traffic_class: Cell::new(0),
             gateway: Cell::new(dst_mac_addr),
+            neighbors: OptionalCell::empty(),
+            security: Cell::new(None),
             tx_buf: TakeCell::new(tx_buf),
             sixlowpan: sixlowpan,
             radio: radio,
-            dst_mac_addr: dst_mac_addr,
             src_mac_addr: src_mac_addr,
             client: OptionalCell::empty(),
             ip_vis: ip_vis,
         }
     }
 
+    /// Send packets to the neighbors in `neighbors` directly.
+    pub fn set_neighbor_cache(&self, neighbors: &'a NeighborCache) {
+        self.neighbors.set(neighbors);
+    }
+
+    /// Secure the frames of the packets sent with the key matching `level`
+    /// and `key_id`, or send them unsecured if `security` is `None`. Packets
+    /// are not sent if the key is not provisioned.
+    pub fn set_security(&self, security: Option<(SecurityLevel, KeyId)>) {
+        self.security.set(security);
+    }
+
     fn init_packet(
         &self,
         dst_addr: IPAddr,
@@ -187,6 +223,9 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
                 ip6_packet.header = IP6Header::default();
                 ip6_packet.header.src_addr = self.src_addr.get();
                 ip6_packet.header.dst_addr = dst_addr;
+                ip6_packet
+                    .header
+                    .set_traffic_class(self.traffic_class.get());
                 ip6_packet.set_payload(transport_header, payload);
                 ip6_packet.set_transport_checksum();
             },
@@ -1,6 +1,8 @@
+pub mod icmp_responder;
 pub mod ip_utils;
 pub mod ipv6_recv;
 pub mod ipv6_send;
+pub mod ndp;
 
 // Reexport the exports of the [`ipv6`] module, to avoid redundant
 // module paths (e.g. `capsules::net::ipv6::ipv6::IP6Header`)
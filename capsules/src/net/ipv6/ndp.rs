@@ -0,0 +1,167 @@
+//! This file contains the parts of Neighbor Discovery (RFC 4861) used over
+//! IEEE 802.15.4: the link-layer address options (RFC 4944 section 8), and
+//! the cache of the MAC addresses of neighbors used by the IPv6 layer.
+//!
+//! The cache has a fixed number of entries, replaced in round-robin order.
+//! Entries do not expire; they are updated by every Neighbor Discovery
+//! message from the neighbor that carries its link-layer address. Neighbor
+//! unreachability detection and the address registrations of 6LoWPAN
+//! Neighbor Discovery (RFC 6775) are not implemented.
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use core::cell::Cell;
+
+/// Number of neighbors kept in the cache.
+pub const NEIGHBOR_CACHE_LEN: usize = 8;
+
+/// Hop limit of Neighbor Discovery messages. Received messages with another
+/// hop limit did not come from the link, and are dropped.
+pub const ND_HOP_LIMIT: u8 = 255;
+
+/// Length of the target address in solicitations and advertisements.
+pub const ND_TARGET_LEN: usize = 16;
+
+pub mod nd_option {
+    pub const SOURCE_LL_ADDR: u8 = 1;
+    pub const TARGET_LL_ADDR: u8 = 2;
+}
+
+/// Flags of neighbor advertisements.
+pub mod na_flags {
+    pub const ROUTER: u32 = 0x8000_0000;
+    pub const SOLICITED: u32 = 0x4000_0000;
+    pub const OVERRIDE: u32 = 0x2000_0000;
+}
+
+/// Length of the link-layer address option of `addr`, padded to a multiple
+/// of 8 bytes.
+pub fn ll_option_len(addr: MacAddress) -> usize {
+    match addr {
+        MacAddress::Short(_) => 8,
+        MacAddress::Long(_) => 16,
+    }
+}
+
+/// Writes a link-layer address option of type `opt_type` for `addr` at the
+/// start of `buf`. Returns the length of the option, or `None` if `buf` is
+/// too short.
+pub fn encode_ll_option(buf: &mut [u8], opt_type: u8, addr: MacAddress) -> Option<usize> {
+    let len = ll_option_len(addr);
+    let option = buf.get_mut(..len)?;
+    for byte in option.iter_mut() {
+        *byte = 0;
+    }
+    option[0] = opt_type;
+    option[1] = (len / 8) as u8;
+    match addr {
+        MacAddress::Short(short_addr) => option[2..4].copy_from_slice(&short_addr.to_be_bytes()),
+        MacAddress::Long(long_addr) => option[2..10].copy_from_slice(&long_addr),
+    }
+    Some(len)
+}
+
+/// Finds the link-layer address option of type `opt_type` in `options`.
+/// Returns `Err(())` if the options are malformed, which makes the whole
+/// message invalid.
+pub fn find_ll_option(options: &[u8], opt_type: u8) -> Result<Option<MacAddress>, ()> {
+    let mut found = None;
+    let mut off = 0;
+    while off < options.len() {
+        let len = *options.get(off + 1).ok_or(())? as usize * 8;
+        if len == 0 || off + len > options.len() {
+            return Err(());
+        }
+        if options[off] == opt_type {
+            found = match len {
+                8 => Some(MacAddress::Short(u16::from_be_bytes([
+                    options[off + 2],
+                    options[off + 3],
+                ]))),
+                16 => {
+                    let mut long_addr = [0; 8];
+                    long_addr.copy_from_slice(&options[off + 2..off + 10]);
+                    Some(MacAddress::Long(long_addr))
+                }
+                _ => found,
+            };
+        }
+        off += len;
+    }
+    Ok(found)
+}
+
+/// The solicited-node multicast address of `addr` (`ff02::1:ffXX:XXXX`).
+pub fn solicited_node_addr(addr: IPAddr) -> IPAddr {
+    let mut group = IPAddr([0; 16]);
+    group.0[0] = 0xff;
+    group.0[1] = 0x02;
+    group.0[11] = 0x01;
+    group.0[12] = 0xff;
+    group.0[13..16].copy_from_slice(&addr.0[13..16]);
+    group
+}
+
+#[derive(Copy, Clone)]
+struct NeighborEntry {
+    ip_addr: IPAddr,
+    mac_addr: MacAddress,
+}
+
+pub struct NeighborCache {
+    entries: Cell<[Option<NeighborEntry>; NEIGHBOR_CACHE_LEN]>,
+    /// The entry replaced by the next new neighbor.
+    next: Cell<usize>,
+}
+
+impl NeighborCache {
+    pub fn new() -> NeighborCache {
+        NeighborCache {
+            entries: Cell::new([None; NEIGHBOR_CACHE_LEN]),
+            next: Cell::new(0),
+        }
+    }
+
+    pub fn lookup(&self, ip_addr: IPAddr) -> Option<MacAddress> {
+        self.entries
+            .get()
+            .iter()
+            .flatten()
+            .find(|entry| entry.ip_addr == ip_addr)
+            .map(|entry| entry.mac_addr)
+    }
+
+    /// Update the MAC address of `ip_addr` if it is in the cache. Returns
+    /// whether it was.
+    pub fn update(&self, ip_addr: IPAddr, mac_addr: MacAddress) -> bool {
+        let mut entries = self.entries.get();
+        let found = entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.ip_addr == ip_addr)
+            .map(|entry| entry.mac_addr = mac_addr)
+            .is_some();
+        self.entries.set(entries);
+        found
+    }
+
+    /// Add or update the MAC address of `ip_addr`.
+    pub fn insert(&self, ip_addr: IPAddr, mac_addr: MacAddress) {
+        if self.update(ip_addr, mac_addr) {
+            return;
+        }
+        let mut entries = self.entries.get();
+        let index = self.next.get();
+        entries[index] = Some(NeighborEntry {
+            ip_addr: ip_addr,
+            mac_addr: mac_addr,
+        });
+        self.entries.set(entries);
+        self.next.set((index + 1) % NEIGHBOR_CACHE_LEN);
+    }
+
+    pub fn clear(&self) {
+        self.entries.set([None; NEIGHBOR_CACHE_LEN]);
+        self.next.set(0);
+    }
+}
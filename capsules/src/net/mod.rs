@@ -1,12 +1,19 @@
-//! Modules for IPv6 over 6LoWPAN stack
+//! Modules for the IPv6 over 6LoWPAN and IPv4 over Ethernet stacks
 
+pub mod buffer;
 pub mod frag_utils;
 pub mod sixlowpan;
+pub mod sntp;
 pub mod util;
 #[macro_use]
 pub mod stream;
+pub mod coap;
+pub mod dhcp;
+pub mod dns;
+pub mod dtls;
 pub mod icmpv6;
 pub mod ieee802154;
+pub mod ipv4;
 pub mod ipv6;
 pub mod network_capabilities;
 pub mod tcp;
@@ -236,9 +236,8 @@ use core::cell::Cell;
 use core::cmp::min;
 use kernel::common::cells::{MapCell, TakeCell};
 use kernel::common::list::{List, ListLink, ListNode};
-use kernel::hil::radio;
 use kernel::hil::time;
-use kernel::hil::time::{Frequency, Ticks};
+use kernel::hil::time::Ticks;
 use kernel::ErrorCode;
 
 // Reassembly timeout in seconds
@@ -470,42 +469,51 @@ impl<'a> TxState<'a> {
         mut frame: Frame,
         ctx_store: &dyn ContextStore,
     ) -> Result<Frame, (Result<(), ErrorCode>, &'static mut [u8])> {
-        // Here, we assume that the compressed headers fit in the first MTU
-        // fragment. This is consistent with RFC 6282.
-        let mut lowpan_packet = [0 as u8; radio::MAX_FRAME_SIZE as usize];
-        let (consumed, written) = {
-            match sixlowpan_compression::compress(
+        // TODO: This -2 is added to account for the FCS; this should be changed
+        // in the MAC code
+        let capacity = frame.remaining_data_capacity() - 2;
+
+        // The compressed headers are written straight into the frame, after
+        // room for the fragment header, which is only known to be needed
+        // once their length is. Here, we assume that the compressed headers
+        // fit in the first MTU fragment. This is consistent with RFC 6282.
+        let lowpan = frame.build_payload(lowpan_frag::FRAG1_HDR_SIZE, |packet| {
+            let (consumed, written) = sixlowpan_compression::compress(
                 ctx_store,
                 ip6_packet,
                 self.src_mac_addr.get(),
                 self.dst_mac_addr.get(),
-                &mut lowpan_packet,
-            ) {
-                Err(_) => return Err((Err(ErrorCode::FAIL), frame.into_buf())),
-                Ok(result) => result,
-            }
-        };
-
-        let remaining_payload = ip6_packet.get_total_len() as usize - consumed;
-        let lowpan_len = written + remaining_payload;
+                packet.tailroom_mut(),
+            )
+            .map_err(|_| ErrorCode::FAIL)?;
+            packet.extend(written)?;
 
-        // TODO: This -2 is added to account for the FCS; this should be changed
-        // in the MAC code
-        let mut remaining_capacity = frame.remaining_data_capacity() - 2;
+            let remaining_payload = ip6_packet.get_total_len() as usize - consumed;
+            let mut remaining_capacity = capacity;
 
-        // Need to fragment
-        if lowpan_len > remaining_capacity {
-            remaining_capacity -= self.write_frag_hdr(&mut frame, true);
-        }
+            // Need to fragment
+            if written + remaining_payload > remaining_capacity {
+                let frag_header = packet.prepend(lowpan_frag::FRAG1_HDR_SIZE)?;
+                set_frag_hdr(
+                    self.dgram_size.get(),
+                    self.dgram_tag.get(),
+                    /*offset = */
+                    0,
+                    frag_header,
+                    true,
+                );
+                remaining_capacity -= lowpan_frag::FRAG1_HDR_SIZE;
+            }
 
-        // Write the 6lowpan header
-        if written <= remaining_capacity {
-            // TODO: Check success
-            let _ = frame.append_payload(&lowpan_packet[0..written]);
-            remaining_capacity -= written;
-        } else {
-            return Err((Err(ErrorCode::SIZE), frame.into_buf()));
-        }
+            if written > remaining_capacity {
+                return Err(ErrorCode::SIZE);
+            }
+            Ok((consumed, remaining_payload, remaining_capacity - written))
+        });
+        let (consumed, remaining_payload, remaining_capacity) = match lowpan {
+            Ok(lowpan) => lowpan,
+            Err(e) => return Err((Err(e), frame.into_buf())),
+        };
 
         // Write the remainder of the payload, rounding down to a multiple
         // of 8 if the entire payload won't fit
@@ -530,7 +538,7 @@ impl<'a> TxState<'a> {
     ) -> Result<Frame, (Result<(), ErrorCode>, &'static mut [u8])> {
         let dgram_offset = self.dgram_offset.get();
         let mut remaining_capacity = frame.remaining_data_capacity();
-        remaining_capacity -= self.write_frag_hdr(&mut frame, false);
+        remaining_capacity -= self.write_frag_hdr(&mut frame);
 
         // This rounds payload_len down to the nearest multiple of 8 if it
         // is not the last fragment (per RFC 4944)
@@ -583,33 +591,23 @@ impl<'a> TxState<'a> {
         (payload_len, dgram_offset)
     }
 
-    fn write_frag_hdr(&self, frame: &mut Frame, first_frag: bool) -> usize {
-        if first_frag {
-            let mut frag_header = [0 as u8; lowpan_frag::FRAG1_HDR_SIZE];
-            set_frag_hdr(
-                self.dgram_size.get(),
-                self.dgram_tag.get(),
-                /*offset = */
-                0,
-                &mut frag_header,
-                true,
-            );
-            // TODO: Check success
-            let _ = frame.append_payload(&frag_header);
-            lowpan_frag::FRAG1_HDR_SIZE
-        } else {
-            let mut frag_header = [0 as u8; lowpan_frag::FRAGN_HDR_SIZE];
-            set_frag_hdr(
-                self.dgram_size.get(),
-                self.dgram_tag.get(),
-                self.dgram_offset.get(),
-                &mut frag_header,
-                first_frag,
-            );
-            // TODO: Check success
-            let _ = frame.append_payload(&frag_header);
-            lowpan_frag::FRAGN_HDR_SIZE
-        }
+    // Writes the header of a subsequent fragment in place in the frame
+    fn write_frag_hdr(&self, frame: &mut Frame) -> usize {
+        // TODO: Check success
+        let _ = frame.build_payload(0, |packet| {
+            packet
+                .append(lowpan_frag::FRAGN_HDR_SIZE)
+                .map(|frag_header| {
+                    set_frag_hdr(
+                        self.dgram_size.get(),
+                        self.dgram_tag.get(),
+                        self.dgram_offset.get(),
+                        frag_header,
+                        false,
+                    )
+                })
+        });
+        lowpan_frag::FRAGN_HDR_SIZE
     }
 
     fn end_transmit(&self) {
@@ -633,7 +631,8 @@ pub struct RxState<'a> {
     // Marks if this instance is being used for a packet reassembly or if it is
     // free to use for a new packet.
     busy: Cell<bool>,
-    // The time when packet reassembly started for the current packet.
+    // The time when packet reassembly started for the current packet, as the
+    // `Ticks` of the `Sixlowpan`'s clock.
     start_time: Cell<u32>,
 
     next: ListLink<'a, RxState<'a>>,
@@ -682,21 +681,24 @@ impl<'a> RxState<'a> {
 
     // Checks if a given RxState is free or expired (and thus, can be freed).
     // This function implements the reassembly timeout for 6LoWPAN lazily.
-    fn is_busy(&self, frequency: u32, current_time: u32) -> bool {
-        let expired = current_time >= (self.start_time.get() + FRAG_TIMEOUT * frequency);
-        if expired {
+    // Each reassembly expires `timeout` ticks after its first fragment,
+    // independently of the others. The elapsed time is computed with the
+    // width of the clock's counter, so it is correct across a wrap.
+    fn is_busy<T: Ticks>(&self, current_time: T, timeout: T) -> bool {
+        let start_time = T::from(self.start_time.get());
+        if self.busy.get() && current_time.wrapping_sub(start_time) >= timeout {
             self.end_receive(None, Err(ErrorCode::FAIL));
         }
         self.busy.get()
     }
 
-    fn start_receive(
+    fn start_receive<T: Ticks>(
         &self,
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
         dgram_size: u16,
         dgram_tag: u16,
-        current_tics: u32,
+        current_tics: T,
     ) {
         self.dst_mac_addr.set(dst_mac_addr);
         self.src_mac_addr.set(src_mac_addr);
@@ -704,7 +706,7 @@ impl<'a> RxState<'a> {
         self.dgram_size.set(dgram_size);
         self.busy.set(true);
         self.bitmap.map(|bitmap| bitmap.clear());
-        self.start_time.set(current_tics);
+        self.start_time.set(current_tics.into_u32());
     }
 
     // This function assumes that the payload is a slice starting from the
@@ -740,9 +742,19 @@ impl<'a> RxState<'a> {
             payload_len
         };
         self.packet.replace(packet);
-        if !self.bitmap.map_or(false, |bitmap| {
-            bitmap.set_bits(dgram_offset / 8, (dgram_offset + uncompressed_len) / 8)
-        }) {
+        // A fragment received twice, for instance because its ACK was lost
+        // and the sender retransmitted it, is ignored.
+        let (start, end) = (dgram_offset / 8, (dgram_offset + uncompressed_len) / 8);
+        if self
+            .bitmap
+            .map_or(false, |bitmap| bitmap.bits_all_set(start, end))
+        {
+            return Ok(false);
+        }
+        if !self
+            .bitmap
+            .map_or(false, |bitmap| bitmap.set_bits(start, end))
+        {
             // If this fails, we received an overlapping fragment. We can simply
             // drop the packet in this case.
             Err(Err(ErrorCode::FAIL))
@@ -787,11 +799,16 @@ impl<'a> RxState<'a> {
 ///
 /// Finally, `set_client` controls the client that will receive transmission
 /// completion and reception callbacks.
+///
+/// Frames secured with 802.15.4 security reach `Sixlowpan` only once the
+/// framer has authenticated and decrypted them. To keep unsecured frames
+/// from being accepted, call `set_require_security(true)`.
 pub struct Sixlowpan<'a, A: time::Alarm<'a>, C: ContextStore> {
     pub ctx_store: C,
     clock: &'a A,
     tx_dgram_tag: Cell<u16>,
     rx_client: Cell<Option<&'a dyn SixlowpanRxClient>>,
+    require_security: Cell<bool>,
 
     // Receive state
     rx_states: List<'a, RxState<'a>>,
@@ -804,6 +821,9 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> RxClient for Sixlowpan<'a, A, C> {
         // a callback for an invalid frame reception
         // TODO: Handle the case where the addresses are None/elided - they
         // should not default to the zero address
+        if self.require_security.get() && header.security.is_none() {
+            return;
+        }
         let src_mac_addr = header.src_addr.unwrap_or(MacAddress::Short(0));
         let dst_mac_addr = header.dst_addr.unwrap_or(MacAddress::Short(0));
 
@@ -870,11 +890,21 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
             clock: clock,
             tx_dgram_tag: Cell::new(0),
             rx_client: Cell::new(None),
+            require_security: Cell::new(false),
 
             rx_states: List::new(),
         }
     }
 
+    fn frag_timeout_ticks(&self) -> A::Ticks {
+        A::ticks_from_seconds(FRAG_TIMEOUT)
+    }
+
+    /// Drop received frames that were not secured with 802.15.4 security.
+    pub fn set_require_security(&self, require_security: bool) {
+        self.require_security.set(require_security);
+    }
+
     fn receive_frame(
         &self,
         packet: &[u8],
@@ -913,14 +943,14 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         let rx_state = self
             .rx_states
             .iter()
-            .find(|state| !state.is_busy(self.clock.now().into_u32(), A::Frequency::frequency()));
+            .find(|state| !state.is_busy(self.clock.now(), self.frag_timeout_ticks()));
         rx_state.map_or((None, Err(ErrorCode::NOMEM)), |state| {
             state.start_receive(
                 src_mac_addr,
                 dst_mac_addr,
                 payload_len as u16,
                 0,
-                self.clock.now().into_u32(),
+                self.clock.now(),
             );
             // The packet buffer should *always* be there; in particular,
             // since this state is not busy, it must have the packet buffer.
@@ -980,9 +1010,10 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
 
         // Else find a free state
         if rx_state.is_none() {
-            rx_state = self.rx_states.iter().find(|state| {
-                !state.is_busy(self.clock.now().into_u32(), A::Frequency::frequency())
-            });
+            rx_state = self
+                .rx_states
+                .iter()
+                .find(|state| !state.is_busy(self.clock.now(), self.frag_timeout_ticks()));
             // Initialize new state
             rx_state.map(|state| {
                 state.start_receive(
@@ -990,7 +1021,7 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
                     dst_mac_addr,
                     dgram_size,
                     dgram_tag,
-                    self.clock.now().into_u32(),
+                    self.clock.now(),
                 )
             });
             if rx_state.is_none() {
@@ -1034,3 +1065,122 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         // TODO: Need to get buffer back from Mac layer on disassociation
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::net::sixlowpan::sixlowpan_compression::Context;
+    use crate::test::mock_alarm::MockAlarm;
+    use crate::test::mock_process;
+    use kernel::hil::time::{Freq32KHz, Ticks24, Time};
+    use std::vec;
+    use std::vec::Vec;
+
+    type Clock = MockAlarm<'static, Ticks24, Freq32KHz>;
+
+    const SENDER: MacAddress = MacAddress::Short(0x0001);
+    const OTHER_SENDER: MacAddress = MacAddress::Short(0x0002);
+    const RECEIVER: MacAddress = MacAddress::Short(0x0003);
+
+    /// The uncompressed size of the test datagram: a 40-byte IPv6 header and
+    /// 40 bytes of payload.
+    const DGRAM_SIZE: u16 = 80;
+
+    /// Just before the 24-bit counter wraps.
+    const BEFORE_WRAP: u32 = 0xff_ff00;
+
+    fn setup() -> (&'static Clock, &'static Sixlowpan<'static, Clock, Context>) {
+        let clock: &Clock = mock_process::leak(MockAlarm::new());
+        let context = Context {
+            prefix: [0; 16],
+            prefix_len: 0,
+            id: 0,
+            compress: false,
+        };
+        let sixlowpan = mock_process::leak(Sixlowpan::new(context, clock));
+        // A single reassembly buffer, so a second datagram only fits once the
+        // first one has completed or expired.
+        sixlowpan.add_rx_state(mock_process::leak(RxState::new(mock_process::buffer(1280))));
+        (clock, sixlowpan)
+    }
+
+    /// The first fragment: a compressed header that only carries the next
+    /// header inline, followed by the first 16 bytes of payload.
+    fn frag1(tag: u16) -> Vec<u8> {
+        let mut frame = vec![
+            lowpan_frag::FRAG1_HDR | (DGRAM_SIZE >> 8) as u8,
+            DGRAM_SIZE as u8,
+            (tag >> 8) as u8,
+            tag as u8,
+        ];
+        // IPHC: traffic class and flow label elided, hop limit 255, both
+        // addresses derived from the MAC addresses, no next header.
+        frame.extend_from_slice(&[0x7b, 0x33, 0x3b]);
+        frame.extend_from_slice(&[0xaa; 16]);
+        frame
+    }
+
+    /// The second and last fragment: the remaining 24 bytes of payload.
+    fn fragn(tag: u16) -> Vec<u8> {
+        let mut frame = vec![
+            lowpan_frag::FRAGN_HDR | (DGRAM_SIZE >> 8) as u8,
+            DGRAM_SIZE as u8,
+            (tag >> 8) as u8,
+            tag as u8,
+            56 / 8,
+        ];
+        frame.extend_from_slice(&[0xbb; 24]);
+        frame
+    }
+
+    /// Deliver `frame` from `src`, and return whether it completed a datagram.
+    fn receive(
+        sixlowpan: &Sixlowpan<'static, Clock, Context>,
+        frame: &[u8],
+        src: MacAddress,
+    ) -> Result<bool, ErrorCode> {
+        let (state, result) = sixlowpan.receive_frame(frame, frame.len(), src, RECEIVER);
+        state.map(|state| state.end_receive(None, result));
+        result.map(|()| state.is_some())
+    }
+
+    #[test]
+    fn test_reassembly_across_24_bit_wrap() {
+        let (clock, sixlowpan) = setup();
+        clock.set_now(BEFORE_WRAP);
+        assert_eq!(receive(sixlowpan, &frag1(1), SENDER), Ok(false));
+
+        clock.advance(0x200);
+        assert!(clock.now().into_u32() < BEFORE_WRAP);
+        // The reassembly started just before the wrap is still live.
+        assert_eq!(
+            receive(sixlowpan, &frag1(2), OTHER_SENDER),
+            Err(ErrorCode::NOMEM)
+        );
+        assert_eq!(receive(sixlowpan, &fragn(1), SENDER), Ok(true));
+
+        // Completing it released the buffer.
+        assert_eq!(receive(sixlowpan, &frag1(2), OTHER_SENDER), Ok(false));
+    }
+
+    #[test]
+    fn test_reassembly_expires_across_24_bit_wrap() {
+        let (clock, sixlowpan) = setup();
+        let timeout = Clock::ticks_from_seconds(FRAG_TIMEOUT).into_u32();
+        clock.set_now(BEFORE_WRAP);
+        assert_eq!(receive(sixlowpan, &frag1(1), SENDER), Ok(false));
+
+        clock.advance(timeout - 1);
+        assert_eq!(
+            receive(sixlowpan, &frag1(2), OTHER_SENDER),
+            Err(ErrorCode::NOMEM)
+        );
+
+        clock.advance(1);
+        assert_eq!(receive(sixlowpan, &frag1(2), OTHER_SENDER), Ok(false));
+        // The expired datagram can no longer complete.
+        assert_eq!(receive(sixlowpan, &fragn(1), SENDER), Err(ErrorCode::NOMEM));
+    }
+}
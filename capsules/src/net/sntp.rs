@@ -0,0 +1,391 @@
+//! SNTP client that keeps a real-time clock synchronized, on top of UDP.
+//!
+//! The client queries a single server (RFC 4330) every `poll_interval`
+//! seconds. A query is sent again if no response arrives within
+//! `RESPONSE_TIMEOUT_MS`, up to `MAX_RETRIES` times. The transmit timestamp
+//! of a valid response, corrected by half of the round trip, is compared to
+//! the `DateTime` clock, which is set when it is off by a second or more.
+//!
+//! Processes can read the status of the last synchronization and ask for one
+//! immediately.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sntp = static_init!(
+//!     capsules::net::sntp::SntpClient<'static, VirtualMuxAlarm<'static, Rtc>, Rtc>,
+//!     capsules::net::sntp::SntpClient::new(
+//!         sntp_alarm,
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         LeasableBuffer::new(sntp_tx_buffer),
+//!         &peripherals.rtc,
+//!         SERVER_ADDR,
+//!         3600,
+//!         board_kernel.create_grant(&grant_cap),
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(sntp);
+//! udp_recv.set_client(sntp);
+//! sntp_alarm.set_alarm_client(sntp);
+//! sntp.bind(SNTP_LOCAL_PORT);
+//! sntp.start();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: Synchronization upcall, after each synchronization attempt, with
+//!   its status and the offset applied to the clock in seconds (as a signed
+//!   32-bit value). The status is `NOACK` if the server did not answer.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get whether the clock was ever synchronized, and the stratum of
+//!   the server at the last synchronization.
+//! - `2`: Get the offset applied to the clock at the last synchronization,
+//!   and the Unix time of that synchronization.
+//! - `3`: Synchronize now. Returns `BUSY` while a query is outstanding.
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::date_time::{DateTime, DateTimeValues};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Sntp as usize;
+
+/// UDP port of NTP servers.
+pub const NTP_PORT: u16 = 123;
+
+/// Length of an SNTP message without authentication.
+pub const PACKET_LEN: usize = 48;
+
+/// Time to wait for a response, in milliseconds.
+const RESPONSE_TIMEOUT_MS: u32 = 2000;
+
+/// Number of times a query is sent again before the attempt fails.
+const MAX_RETRIES: u8 = 3;
+
+/// Longest single alarm used while waiting for the next poll, in seconds, so
+/// that it fits the alarm's counter even at high frequencies.
+const WAIT_STEP_S: u32 = 60;
+
+/// Seconds from the NTP epoch, 1900, to the Unix epoch, 1970.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Leap indicator 0, version 4, client mode.
+const CLIENT_HEADER: u8 = 0b00_100_011;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// Waiting for the next poll, with the seconds left after the current
+    /// alarm.
+    Waiting(u32),
+    /// A query was sent at `sent`, in alarm ticks, which is also its
+    /// transmit timestamp.
+    Querying { retries: u8, sent: u32 },
+}
+
+#[derive(Copy, Clone, Default)]
+struct SyncStatus {
+    stratum: u8,
+    offset: i32,
+    unix_time: u32,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+}
+
+pub struct SntpClient<'a, A: Alarm<'a>, D: DateTime<'a>> {
+    alarm: &'a A,
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    clock: &'a D,
+    server: IPAddr,
+    poll_interval: u32,
+    state: Cell<State>,
+    last_sync: OptionalCell<SyncStatus>,
+    apps: Grant<App>,
+    net_cap: &'static NetworkCapability,
+}
+
+impl<'a, A: Alarm<'a>, D: DateTime<'a>> SntpClient<'a, A, D> {
+    pub fn new(
+        alarm: &'a A,
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        tx_buffer: LeasableBuffer<'static, u8>,
+        clock: &'a D,
+        server: IPAddr,
+        poll_interval: u32,
+        grant: Grant<App>,
+        net_cap: &'static NetworkCapability,
+    ) -> SntpClient<'a, A, D> {
+        SntpClient {
+            alarm: alarm,
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            tx_buffer: MapCell::new(tx_buffer),
+            clock: clock,
+            server: server,
+            poll_interval: poll_interval,
+            state: Cell::new(State::Waiting(0)),
+            last_sync: OptionalCell::empty(),
+            apps: grant,
+            net_cap: net_cap,
+        }
+    }
+
+    /// Bind to the local UDP port `port`, from which queries are sent.
+    pub fn bind(&self, port: u16) -> Result<(), ErrorCode> {
+        if self.udp_sender.is_bound() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let socket = self
+            .port_table
+            .create_socket()
+            .map_err(|_| ErrorCode::NOMEM)?;
+        match self.port_table.bind(socket, port, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                self.udp_sender.set_binding(send_binding);
+                self.udp_receiver.set_binding(recv_binding);
+                Ok(())
+            }
+            // Dropping the socket frees it.
+            Err(_socket) => Err(ErrorCode::INVAL),
+        }
+    }
+
+    /// Synchronize now, and then every poll interval.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if let State::Querying { .. } = self.state.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.query(0)
+    }
+
+    /// Send a query, attempt `retries` of the current synchronization.
+    fn query(&self, retries: u8) -> Result<(), ErrorCode> {
+        let now = self.alarm.now();
+        // The server echoes the transmit timestamp, which identifies its
+        // response; the clock may not be set yet, so use the alarm's.
+        let sent = now.into_u32();
+        self.state.set(State::Querying {
+            retries: retries,
+            sent: sent,
+        });
+        self.alarm
+            .set_alarm(now, A::ticks_from_ms(RESPONSE_TIMEOUT_MS));
+
+        // Without the buffer, the query is sent again when it times out.
+        let mut buffer = match self.tx_buffer.take() {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        };
+        buffer.reset();
+        if buffer.len() < PACKET_LEN {
+            self.tx_buffer.replace(buffer);
+            return Err(ErrorCode::SIZE);
+        }
+        for byte in buffer[..PACKET_LEN].iter_mut() {
+            *byte = 0;
+        }
+        buffer[0] = CLIENT_HEADER;
+        buffer[40..44].copy_from_slice(&sent.to_be_bytes());
+        buffer.slice(0..PACKET_LEN);
+        if let Err(mut buffer) =
+            self.udp_sender
+                .send_to(self.server, NTP_PORT, buffer, self.net_cap)
+        {
+            buffer.reset();
+            self.tx_buffer.replace(buffer);
+        }
+        Ok(())
+    }
+
+    /// Wait `seconds` before the next poll.
+    fn wait(&self, seconds: u32) {
+        let step = core::cmp::min(seconds, WAIT_STEP_S);
+        self.state.set(State::Waiting(seconds - step));
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(step * 1000));
+    }
+
+    /// End a synchronization attempt, and notify processes.
+    fn finish(&self, result: Result<(), ErrorCode>) {
+        self.wait(self.poll_interval);
+        let offset = self.last_sync.map_or(0, |sync| sync.offset);
+        let status = kernel::into_statuscode(result);
+        self.apps.each(|_, app| {
+            app.callback.schedule(status, offset as usize, 0);
+        });
+    }
+
+    /// Set the clock from a response to the query sent at `sent`.
+    fn synchronize(&self, response: &[u8], sent: u32) -> Result<(), ErrorCode> {
+        let stratum = response[1];
+        // Seconds and fraction of the server's transmit timestamp. Timestamps
+        // with the top bit clear are in the era starting in 2036.
+        let seconds = u32::from_be_bytes([response[40], response[41], response[42], response[43]]);
+        let fraction = u32::from_be_bytes([response[44], response[45], response[46], response[47]]);
+        let seconds = if seconds & 0x8000_0000 != 0 {
+            seconds as u64
+        } else {
+            seconds as u64 + (1 << 32)
+        };
+
+        // Correct by half of the round trip, and round to the second.
+        let round_trip = self.alarm.now().wrapping_sub(A::Ticks::from(sent));
+        let frequency = <A::Frequency as time::Frequency>::frequency() as u64;
+        let half_trip_ms = round_trip.into_u32() as u64 * 1000 / frequency / 2;
+        let fraction_ms = (fraction as u64 * 1000) >> 32;
+        let ms = fraction_ms + half_trip_ms + 500;
+        let unix_time = seconds - NTP_UNIX_OFFSET + ms / 1000;
+
+        // No offset is known if the clock was never set.
+        let offset = match self.clock.get_date_time() {
+            Ok(now) => Some(unix_time as i64 - now.unix_time() as i64),
+            Err(_) => None,
+        };
+        if offset != Some(0) {
+            self.clock
+                .set_date_time(DateTimeValues::from_unix_time(unix_time))?;
+        }
+        let offset = offset.unwrap_or(0);
+        self.last_sync.set(SyncStatus {
+            stratum: stratum,
+            offset: offset.clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+            unix_time: unix_time as u32,
+        });
+        Ok(())
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: DateTime<'a>> UDPSendClient for SntpClient<'a, A, D> {
+    fn send_done(&self, _result: Result<(), ErrorCode>, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buffer.replace(dgram);
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: DateTime<'a>> UDPRecvClient for SntpClient<'a, A, D> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        let sent = match self.state.get() {
+            State::Querying { sent, .. } => sent,
+            State::Waiting(_) => return,
+        };
+        if src_addr.0 != self.server.0 || src_port != NTP_PORT || payload.len() < PACKET_LEN {
+            return;
+        }
+        // The originate timestamp echoes the query's transmit timestamp.
+        if payload[24..28] != sent.to_be_bytes() || payload[0] & 0b111 != MODE_SERVER {
+            return;
+        }
+        let leap = payload[0] >> 6;
+        let stratum = payload[1];
+        let result = if leap == LEAP_UNSYNCHRONIZED || stratum == 0 || stratum > 15 {
+            // The server is unsynchronized, or asks us to stop (a
+            // kiss-o'-death message).
+            Err(ErrorCode::FAIL)
+        } else {
+            self.synchronize(payload, sent)
+        };
+        let _ = self.alarm.disarm();
+        self.finish(result);
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: DateTime<'a>> time::AlarmClient for SntpClient<'a, A, D> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Waiting(0) => {
+                let _ = self.query(0);
+            }
+            State::Waiting(seconds) => self.wait(seconds),
+            State::Querying { retries, .. } if retries < MAX_RETRIES => {
+                let _ = self.query(retries + 1);
+            }
+            State::Querying { .. } => self.finish(Err(ErrorCode::NOACK)),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, D: DateTime<'a>> Driver for SntpClient<'a, A, D> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(&self, command_num: usize, _: usize, _: usize, _: ProcessId) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // synchronization status
+            1 => match self.last_sync.extract() {
+                Some(sync) => CommandReturn::success_u32_u32(1, sync.stratum as u32),
+                None => CommandReturn::success_u32_u32(0, 0),
+            },
+
+            // last synchronization
+            2 => match self.last_sync.extract() {
+                Some(sync) => CommandReturn::success_u32_u32(sync.offset as u32, sync.unix_time),
+                None => CommandReturn::failure(ErrorCode::OFF),
+            },
+
+            // synchronize now
+            3 => self.start().into(),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
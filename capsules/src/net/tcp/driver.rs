@@ -0,0 +1,891 @@
+//! TCP userspace interface.
+//!
+//! Implements a minimal TCP on top of the IPv6 layer, so that processes can
+//! open a connection to a remote endpoint and exchange a stream of bytes with
+//! it. Each process has at most one connection at a time, which it opens
+//! actively: listening for incoming connections is not supported.
+//!
+//! Segments are sent one at a time. A new segment is only sent once the
+//! previous one was acknowledged, and is retransmitted with exponential
+//! backoff if no acknowledgment arrives in time. The connection is aborted
+//! after `MAX_RETRIES` retransmissions of the same segment. Received data is
+//! written to the read buffer of the process, and the receive window
+//! advertised to the peer is the free space left in that buffer, so the peer
+//! stops sending when the process does not consume the data.
+//!
+//! Out-of-order segments are dropped rather than buffered, and TCP options
+//! are ignored.
+//!
+//! Usage
+//! -----
+//!
+//! The driver needs its own `IP6Sender` and a virtual alarm, and receives
+//! segments from the IPv6 receiver:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let tcp_driver = static_init!(
+//!     capsules::net::tcp::TCPDriver<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::net::tcp::TCPDriver::new(
+//!         tcp_send,
+//!         tcp_alarm,
+//!         board_kernel.create_grant(&grant_cap),
+//!         LeasableBuffer::new(tcp_buffer),
+//!         net_cap,
+//!     )
+//! );
+//! tcp_send.set_client(tcp_driver);
+//! tcp_alarm.set_alarm_client(tcp_driver);
+//! ip_receive.set_tcp_client(tcp_driver);
+//! ```
+
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, TransportHeader};
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::tcp::tcp::tcp_flags;
+use crate::net::tcp::TCPHeader;
+use crate::net::util::host_slice_to_u16;
+use core::cell::Cell;
+use core::{cmp, mem};
+use kernel::common::cells::MapCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Tcp as usize;
+
+/// Initial retransmission timeout, in milliseconds.
+const INITIAL_RTO_MS: u32 = 1000;
+
+/// Largest retransmission timeout, in milliseconds.
+const MAX_RTO_MS: u32 = 32000;
+
+/// Number of retransmissions of a segment before the connection is aborted.
+pub const MAX_RETRIES: u8 = 6;
+
+/// Time spent in the TIME-WAIT state, in milliseconds. This is much shorter
+/// than the 2 MSL of the specification, so that processes can reconnect
+/// quickly.
+const TIME_WAIT_MS: u32 = 4000;
+
+/// First port used for connections.
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Events reported through the upcall, as its first argument.
+mod event {
+    /// The connection was established.
+    pub const CONNECTED: usize = 0;
+    /// All data passed to the last send command was acknowledged. The second
+    /// argument is the number of bytes sent.
+    pub const SENT: usize = 1;
+    /// Data was received. The second argument is the number of bytes in the
+    /// read buffer that were not consumed yet.
+    pub const RECEIVED: usize = 2;
+    /// The peer closed its side of the connection.
+    pub const PEER_CLOSED: usize = 3;
+    /// The connection was closed by both sides.
+    pub const CLOSED: usize = 4;
+    /// The connection was reset or refused by the peer.
+    pub const RESET: usize = 5;
+    /// The connection was aborted after too many retransmissions.
+    pub const TIMEOUT: usize = 6;
+}
+
+/// The state of a connection, as in RFC 793. There is no LISTEN or
+/// SYN-RECEIVED state, as connections are only opened actively.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TCPState {
+    Closed = 0,
+    SynSent = 1,
+    Established = 2,
+    FinWait1 = 3,
+    FinWait2 = 4,
+    CloseWait = 5,
+    Closing = 6,
+    LastAck = 7,
+    TimeWait = 8,
+}
+
+/// A segment to send, as chosen by `Connection::next_segment()`.
+struct Segment {
+    flags: u16,
+    seq: u32,
+    /// Offset of the data in the write buffer.
+    offset: usize,
+    len: usize,
+}
+
+#[derive(Copy, Clone)]
+struct Connection {
+    state: TCPState,
+    remote_addr: IPAddr,
+    remote_port: u16,
+    local_port: u16,
+    /// Oldest unacknowledged sequence number.
+    snd_una: u32,
+    /// Next sequence number to send.
+    snd_nxt: u32,
+    /// Window advertised by the peer.
+    snd_wnd: u16,
+    /// Sequence number of the first byte of the write buffer.
+    tx_seq: u32,
+    /// Number of bytes of the write buffer to send.
+    tx_len: usize,
+    /// Next sequence number expected from the peer.
+    rcv_nxt: u32,
+    /// Number of bytes in the read buffer not consumed yet.
+    rx_len: usize,
+    ack_pending: bool,
+    rst_pending: bool,
+    /// Retransmission timeout, in milliseconds.
+    rto: u32,
+    retries: u8,
+    /// Running timer, as a reference and a duration in alarm ticks.
+    timer: Option<(u32, u32)>,
+}
+
+impl Default for Connection {
+    fn default() -> Connection {
+        Connection {
+            state: TCPState::Closed,
+            remote_addr: IPAddr::new(),
+            remote_port: 0,
+            local_port: 0,
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_wnd: 0,
+            tx_seq: 0,
+            tx_len: 0,
+            rcv_nxt: 0,
+            rx_len: 0,
+            ack_pending: false,
+            rst_pending: false,
+            rto: INITIAL_RTO_MS,
+            retries: 0,
+            timer: None,
+        }
+    }
+}
+
+/// Whether sequence number `a` comes before `b`, modulo 2^32.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+impl Connection {
+    /// Choose the next segment to send, and advance `snd_nxt` past it.
+    fn next_segment(&mut self, mss: usize) -> Option<Segment> {
+        if self.rst_pending {
+            self.rst_pending = false;
+            return Some(Segment {
+                flags: tcp_flags::RST,
+                seq: self.snd_nxt,
+                offset: 0,
+                len: 0,
+            });
+        }
+        match self.state {
+            TCPState::SynSent => {
+                if self.snd_nxt == self.snd_una {
+                    self.snd_nxt = self.snd_una.wrapping_add(1);
+                    return Some(Segment {
+                        flags: tcp_flags::SYN,
+                        seq: self.snd_una,
+                        offset: 0,
+                        len: 0,
+                    });
+                }
+                return None;
+            }
+            TCPState::Established
+            | TCPState::CloseWait
+            | TCPState::FinWait1
+            | TCPState::Closing
+            | TCPState::LastAck => {
+                // Only one segment is in flight at a time.
+                if self.snd_nxt == self.snd_una {
+                    let sent = self.snd_nxt.wrapping_sub(self.tx_seq) as usize;
+                    if sent < self.tx_len {
+                        // A zero window is probed with a single byte, which
+                        // is retransmitted until the window opens.
+                        let window = cmp::max(self.snd_wnd as usize, 1);
+                        let len = cmp::min(cmp::min(mss, self.tx_len - sent), window);
+                        self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+                        return Some(Segment {
+                            flags: tcp_flags::ACK | tcp_flags::PSH,
+                            seq: self.snd_una,
+                            offset: sent,
+                            len: len,
+                        });
+                    }
+                    let fin_pending = match self.state {
+                        TCPState::FinWait1 | TCPState::Closing | TCPState::LastAck => {
+                            sent == self.tx_len
+                        }
+                        _ => false,
+                    };
+                    if fin_pending {
+                        self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                        return Some(Segment {
+                            flags: tcp_flags::ACK | tcp_flags::FIN,
+                            seq: self.snd_una,
+                            offset: 0,
+                            len: 0,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+        if self.ack_pending {
+            return Some(Segment {
+                flags: tcp_flags::ACK,
+                seq: self.snd_nxt,
+                offset: 0,
+                len: 0,
+            });
+        }
+        None
+    }
+
+    /// Whether our FIN was sent and acknowledged.
+    fn fin_acked(&self) -> bool {
+        self.snd_una == self.tx_seq.wrapping_add(self.tx_len as u32 + 1)
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    app_read: ReadWriteAppSlice,
+    app_write: ReadOnlyAppSlice,
+    app_cfg: ReadOnlyAppSlice,
+    conn: Connection,
+}
+
+impl App {
+    /// The receive window: the free space in the read buffer.
+    fn window(&self) -> u16 {
+        cmp::min(
+            self.app_read.len().saturating_sub(self.conn.rx_len),
+            u16::MAX as usize,
+        ) as u16
+    }
+
+    /// Close the connection and report `event`.
+    fn close(&mut self, event: usize) {
+        self.conn.state = TCPState::Closed;
+        self.conn.timer = None;
+        self.conn.ack_pending = false;
+        self.callback.schedule(event, 0, 0);
+    }
+}
+
+pub struct TCPDriver<'a, A: Alarm<'a>> {
+    /// IPv6 sender, used by this driver only
+    sender: &'a dyn IP6Sender<'a>,
+
+    /// Alarm for the retransmission and TIME-WAIT timers
+    alarm: &'a A,
+
+    /// Grant of apps that use this driver, each with its connection
+    apps: Grant<App>,
+
+    /// Whether a segment is being sent
+    sending: Cell<bool>,
+
+    /// Next port to try for a new connection
+    next_port: Cell<u16>,
+
+    /// Maximum amount of data in a segment
+    mss: usize,
+
+    kernel_buffer: MapCell<LeasableBuffer<'static, u8>>,
+
+    net_cap: &'static NetworkCapability,
+}
+
+impl<'a, A: Alarm<'a>> TCPDriver<'a, A> {
+    pub fn new(
+        sender: &'a dyn IP6Sender<'a>,
+        alarm: &'a A,
+        grant: Grant<App>,
+        kernel_buffer: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> TCPDriver<'a, A> {
+        TCPDriver {
+            sender: sender,
+            alarm: alarm,
+            apps: grant,
+            sending: Cell::new(false),
+            next_port: Cell::new(EPHEMERAL_PORT_START),
+            mss: kernel_buffer.len(),
+            kernel_buffer: MapCell::new(kernel_buffer),
+            net_cap: net_cap,
+        }
+    }
+
+    /// Start the timer of `app` to expire after `ms` milliseconds.
+    fn start_timer(&self, app: &mut App, ms: u32) {
+        let now = self.alarm.now().into_u32();
+        app.conn.timer = Some((now, A::ticks_from_ms(ms).into_u32()));
+    }
+
+    /// Set the alarm for the earliest timer of all connections.
+    fn update_alarm(&self) {
+        let now = self.alarm.now();
+        let mut earliest: Option<A::Ticks> = None;
+        for cntr in self.apps.iter() {
+            if let Some((reference, dt)) = cntr.enter(|app| app.conn.timer) {
+                let elapsed = now.wrapping_sub(A::Ticks::from(reference));
+                let dt = A::Ticks::from(dt);
+                let remaining = if elapsed < dt {
+                    dt.wrapping_sub(elapsed)
+                } else {
+                    A::Ticks::from(0)
+                };
+                if earliest.map_or(true, |earliest| remaining < earliest) {
+                    earliest = Some(remaining);
+                }
+            }
+        }
+        match earliest {
+            Some(remaining) => self.alarm.set_alarm(now, remaining),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+
+    /// A timer of `app` expired.
+    fn timer_fired(&self, app: &mut App) {
+        if app.conn.state == TCPState::TimeWait {
+            app.close(event::CLOSED);
+            return;
+        }
+        app.conn.retries += 1;
+        if app.conn.retries > MAX_RETRIES {
+            app.close(event::TIMEOUT);
+            return;
+        }
+        // Go back to the oldest unacknowledged segment and send it again.
+        app.conn.rto = cmp::min(app.conn.rto * 2, MAX_RTO_MS);
+        app.conn.snd_nxt = app.conn.snd_una;
+        let rto = app.conn.rto;
+        self.start_timer(app, rto);
+    }
+
+    /// Send the next segment of `app`, if it has one. Returns whether a
+    /// segment is being sent.
+    fn send_segment(&self, app: &mut App) -> bool {
+        let segment = match app.conn.next_segment(self.mss) {
+            Some(segment) => segment,
+            None => return false,
+        };
+
+        let mut tcp_header = TCPHeader::new();
+        tcp_header.set_src_port(app.conn.local_port);
+        tcp_header.set_dst_port(app.conn.remote_port);
+        tcp_header.set_seq_num(segment.seq);
+        if segment.flags & tcp_flags::ACK != 0 {
+            tcp_header.set_ack_num(app.conn.rcv_nxt);
+            app.conn.ack_pending = false;
+        }
+        tcp_header.set_flags(segment.flags);
+        tcp_header.set_window(app.window());
+
+        let result = self
+            .kernel_buffer
+            .take()
+            .map_or(Err(ErrorCode::BUSY), |mut buffer| {
+                buffer.reset();
+                let end = segment.offset + segment.len;
+                app.app_write.map_or((), |data| {
+                    buffer[..segment.len].copy_from_slice(&data[segment.offset..end]);
+                });
+                buffer.slice(0..segment.len);
+                let result = self.sender.send_to(
+                    app.conn.remote_addr,
+                    TransportHeader::TCP(tcp_header),
+                    &buffer,
+                    self.net_cap,
+                );
+                self.kernel_buffer.replace(buffer);
+                result
+            });
+
+        // Segments using sequence space are retransmitted until acknowledged,
+        // including if they could not be sent at all.
+        if app.conn.snd_nxt != app.conn.snd_una && app.conn.timer.is_none() {
+            let rto = app.conn.rto;
+            self.start_timer(app, rto);
+        }
+        if result.is_ok() {
+            self.sending.set(true);
+        }
+        result.is_ok()
+    }
+
+    /// Send the next segment of any connection, unless one is being sent.
+    fn do_output(&self) {
+        if self.sending.get() {
+            return;
+        }
+        for cntr in self.apps.iter() {
+            if cntr.enter(|app| self.send_segment(app)) {
+                break;
+            }
+        }
+    }
+
+    /// Pick a local port not used by any other connection.
+    fn allocate_port(&self) -> u16 {
+        loop {
+            let port = self.next_port.get();
+            self.next_port
+                .set(port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START));
+            let in_use = self.apps.iter().any(|cntr| {
+                cntr.enter(|app| app.conn.state != TCPState::Closed && app.conn.local_port == port)
+            });
+            if !in_use {
+                return port;
+            }
+        }
+    }
+
+    /// Process a segment received on the connection of `app`.
+    fn segment_received(&self, app: &mut App, tcp_header: &TCPHeader, data: &[u8]) {
+        let seq = tcp_header.get_seq_num();
+        let ack = tcp_header.get_ack_num();
+
+        if tcp_header.has_flags(tcp_flags::RST) {
+            // Only accept resets that could not be left over from an older
+            // connection.
+            let acceptable = match app.conn.state {
+                TCPState::SynSent => {
+                    tcp_header.has_flags(tcp_flags::ACK) && ack == app.conn.snd_nxt
+                }
+                _ => seq == app.conn.rcv_nxt,
+            };
+            if acceptable {
+                app.close(event::RESET);
+            }
+            return;
+        }
+
+        if app.conn.state == TCPState::SynSent {
+            if tcp_header.has_flags(tcp_flags::SYN | tcp_flags::ACK) && ack == app.conn.snd_nxt {
+                app.conn.state = TCPState::Established;
+                app.conn.rcv_nxt = seq.wrapping_add(1);
+                app.conn.snd_una = ack;
+                app.conn.snd_wnd = tcp_header.get_window();
+                app.conn.tx_seq = ack;
+                app.conn.tx_len = 0;
+                app.conn.rto = INITIAL_RTO_MS;
+                app.conn.retries = 0;
+                app.conn.timer = None;
+                app.conn.ack_pending = true;
+                app.callback.schedule(event::CONNECTED, 0, 0);
+            }
+            return;
+        }
+
+        if seq != app.conn.rcv_nxt {
+            // Out of order or duplicate: acknowledge what was received so far.
+            app.conn.ack_pending = true;
+            return;
+        }
+
+        if tcp_header.has_flags(tcp_flags::ACK) {
+            if seq_lt(app.conn.snd_una, ack) && !seq_lt(app.conn.snd_nxt, ack) {
+                app.conn.snd_una = ack;
+                app.conn.rto = INITIAL_RTO_MS;
+                app.conn.retries = 0;
+                app.conn.timer = None;
+                if app.conn.snd_nxt != app.conn.snd_una {
+                    // Part of the segment in flight was acknowledged.
+                    self.start_timer(app, INITIAL_RTO_MS);
+                }
+
+                if app.conn.tx_len > 0
+                    && ack == app.conn.tx_seq.wrapping_add(app.conn.tx_len as u32)
+                {
+                    let sent = app.conn.tx_len;
+                    app.conn.tx_seq = ack;
+                    app.conn.tx_len = 0;
+                    app.callback.schedule(event::SENT, sent, 0);
+                }
+
+                if app.conn.fin_acked() {
+                    match app.conn.state {
+                        TCPState::FinWait1 => app.conn.state = TCPState::FinWait2,
+                        TCPState::Closing => {
+                            app.conn.state = TCPState::TimeWait;
+                            self.start_timer(app, TIME_WAIT_MS);
+                        }
+                        TCPState::LastAck => {
+                            app.close(event::CLOSED);
+                            return;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            app.conn.snd_wnd = tcp_header.get_window();
+        }
+
+        let receiving = match app.conn.state {
+            TCPState::Established | TCPState::FinWait1 | TCPState::FinWait2 => true,
+            _ => false,
+        };
+        if !receiving {
+            if !data.is_empty() || tcp_header.has_flags(tcp_flags::FIN) {
+                app.conn.ack_pending = true;
+            }
+            return;
+        }
+
+        if !data.is_empty() {
+            let rx_len = app.conn.rx_len;
+            let accepted = app.app_read.mut_map_or(0, |buffer| {
+                let len = cmp::min(buffer.len().saturating_sub(rx_len), data.len());
+                buffer[rx_len..rx_len + len].copy_from_slice(&data[..len]);
+                len
+            });
+            app.conn.rx_len += accepted;
+            app.conn.rcv_nxt = app.conn.rcv_nxt.wrapping_add(accepted as u32);
+            app.conn.ack_pending = true;
+            if accepted > 0 {
+                app.callback.schedule(event::RECEIVED, app.conn.rx_len, 0);
+            }
+            if accepted < data.len() {
+                // The rest, and any FIN, will be retransmitted by the peer
+                // once the window opens.
+                return;
+            }
+        }
+
+        if tcp_header.has_flags(tcp_flags::FIN) {
+            app.conn.rcv_nxt = app.conn.rcv_nxt.wrapping_add(1);
+            app.conn.ack_pending = true;
+            match app.conn.state {
+                TCPState::Established => app.conn.state = TCPState::CloseWait,
+                TCPState::FinWait1 => app.conn.state = TCPState::Closing,
+                _ => {
+                    app.conn.state = TCPState::TimeWait;
+                    self.start_timer(app, TIME_WAIT_MS);
+                }
+            }
+            app.callback.schedule(event::PEER_CLOSED, 0, 0);
+        }
+    }
+
+    #[inline]
+    fn parse_ip_port_pair(&self, buf: &[u8]) -> Option<(IPAddr, u16)> {
+        if buf.len() != mem::size_of::<IPAddr>() + mem::size_of::<u16>() {
+            None
+        } else {
+            let (a, p) = buf.split_at(mem::size_of::<IPAddr>());
+            let mut addr = IPAddr::new();
+            addr.0.copy_from_slice(a);
+            Some((addr, host_slice_to_u16(p)))
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for TCPDriver<'a, A> {
+    /// Setup buffers to read/write from.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Read buffer. Received data is appended to it, and the free space
+    ///        left in it is the receive window.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.app_read, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// ### `allow_num`
+    ///
+    /// - `0`: Write buffer, with the data to send. It cannot be changed until
+    ///        the data passed to the last send command was acknowledged.
+    /// - `1`: Config buffer, with the remote address (16 bytes) and port (2
+    ///        bytes) to connect to.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    if app.conn.tx_len > 0 && app.conn.state != TCPState::Closed {
+                        Err(ErrorCode::BUSY)
+                    } else {
+                        mem::swap(&mut app.app_write, &mut slice);
+                        Ok(())
+                    }
+                }
+                1 => {
+                    mem::swap(&mut app.app_cfg, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .map_err(ErrorCode::from);
+
+        match res {
+            Ok(Ok(())) => Ok(slice),
+            Ok(Err(e)) => Err((slice, e)),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Subscribe to connection events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Connection events. Upcall arguments: (event, value, 0), with
+    ///        event `0` connected, `1` data sent (value: number of bytes),
+    ///        `2` data received (value: number of unconsumed bytes in the
+    ///        read buffer), `3` closed by the peer, `4` closed, `5` reset by
+    ///        the peer and `6` aborted after too many retransmissions.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// TCP control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Connect to the remote endpoint in the config buffer. Returns the
+    ///        local port of the connection.
+    /// - `2`: Send the first `data1` bytes of the write buffer.
+    /// - `3`: Consume the first `data1` bytes of the read buffer. The
+    ///        remaining bytes are moved to its start.
+    /// - `4`: Close the connection, after all data was sent.
+    /// - `5`: Abort the connection, resetting it.
+    /// - `6`: Get the state of the connection.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        let res = match command_num {
+            0 => return CommandReturn::success(),
+
+            // connect
+            1 => {
+                // Picked outside of the grant, as it looks at all connections.
+                let local_port = self.allocate_port();
+                self.apps
+                    .enter(appid, |app| {
+                        if app.conn.state != TCPState::Closed || app.conn.rst_pending {
+                            return Err(ErrorCode::BUSY);
+                        }
+                        let remote = app.app_cfg.map_or(None, |cfg| self.parse_ip_port_pair(cfg));
+                        let (addr, port) = match remote {
+                            Some((addr, port)) if !addr.is_unspecified() && port != 0 => {
+                                (addr, port)
+                            }
+                            _ => return Err(ErrorCode::INVAL),
+                        };
+                        // The alarm is the only source of variation available
+                        // for the initial sequence number.
+                        let iss = self.alarm.now().into_u32().wrapping_mul(2654435761);
+                        app.conn = Connection {
+                            state: TCPState::SynSent,
+                            remote_addr: addr,
+                            remote_port: port,
+                            local_port: local_port,
+                            snd_una: iss,
+                            snd_nxt: iss,
+                            tx_seq: iss,
+                            ..Connection::default()
+                        };
+                        Ok(local_port as u32)
+                    })
+                    .unwrap_or_else(|err| Err(err.into()))
+            }
+
+            // send
+            2 => self
+                .apps
+                .enter(appid, |app| match app.conn.state {
+                    TCPState::Established | TCPState::CloseWait => {
+                        if app.conn.tx_len > 0 {
+                            Err(ErrorCode::BUSY)
+                        } else if data1 == 0 || data1 > app.app_write.len() {
+                            Err(ErrorCode::SIZE)
+                        } else {
+                            app.conn.tx_len = data1;
+                            Ok(0)
+                        }
+                    }
+                    _ => Err(ErrorCode::INVAL),
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+
+            // consume
+            3 => self
+                .apps
+                .enter(appid, |app| {
+                    let rx_len = cmp::min(app.conn.rx_len, app.app_read.len());
+                    if data1 > rx_len {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    let was_full = (app.window() as usize) < self.mss;
+                    app.app_read.mut_map_or((), |buffer| {
+                        buffer.copy_within(data1..rx_len, 0);
+                    });
+                    app.conn.rx_len = rx_len - data1;
+                    // Let the peer know the window opened.
+                    if was_full && app.conn.state != TCPState::Closed {
+                        app.conn.ack_pending = true;
+                    }
+                    Ok(0)
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+
+            // close
+            4 => self
+                .apps
+                .enter(appid, |app| {
+                    match app.conn.state {
+                        TCPState::SynSent => app.conn.state = TCPState::Closed,
+                        TCPState::Established => app.conn.state = TCPState::FinWait1,
+                        TCPState::CloseWait => app.conn.state = TCPState::LastAck,
+                        _ => return Err(ErrorCode::INVAL),
+                    }
+                    Ok(0)
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+
+            // abort
+            5 => self
+                .apps
+                .enter(appid, |app| match app.conn.state {
+                    TCPState::Closed => Err(ErrorCode::INVAL),
+                    state => {
+                        app.conn.rst_pending = state != TCPState::SynSent;
+                        app.conn.state = TCPState::Closed;
+                        app.conn.timer = None;
+                        Ok(0)
+                    }
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+
+            // state
+            6 => self
+                .apps
+                .enter(appid, |app| Ok(app.conn.state as u32))
+                .unwrap_or_else(|err| Err(err.into())),
+
+            _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+
+        self.do_output();
+        self.update_alarm();
+        match res {
+            Ok(value) => CommandReturn::success_u32(value),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6SendClient for TCPDriver<'a, A> {
+    fn send_done(&self, _result: Result<(), ErrorCode>) {
+        // Lost segments are recovered by retransmission.
+        self.sending.set(false);
+        self.do_output();
+        self.update_alarm();
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6RecvClient for TCPDriver<'a, A> {
+    fn receive(&self, ip6_header: IP6Header, payload: &[u8]) {
+        if ip6_header.get_next_header() != ip6_nh::TCP {
+            return;
+        }
+        let (offset, tcp_header) = match TCPHeader::decode(payload).done() {
+            Some(decoded) => decoded,
+            None => return,
+        };
+        let src_addr = ip6_header.get_src_addr();
+        self.apps.each(|_, app| {
+            if app.conn.state != TCPState::Closed
+                && app.conn.remote_addr == src_addr
+                && app.conn.remote_port == tcp_header.get_src_port()
+                && app.conn.local_port == tcp_header.get_dst_port()
+            {
+                self.segment_received(app, &tcp_header, &payload[offset..]);
+            }
+        });
+        self.do_output();
+        self.update_alarm();
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for TCPDriver<'a, A> {
+    fn alarm(&self) {
+        let now = self.alarm.now();
+        self.apps.each(|_, app| {
+            if let Some((reference, dt)) = app.conn.timer {
+                if now.wrapping_sub(A::Ticks::from(reference)) >= A::Ticks::from(dt) {
+                    app.conn.timer = None;
+                    self.timer_fired(app);
+                }
+            }
+        });
+        self.do_output();
+        self.update_alarm();
+    }
+}
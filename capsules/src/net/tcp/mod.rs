@@ -0,0 +1,9 @@
+pub mod driver;
+
+pub use self::driver::TCPDriver;
+pub use self::driver::DRIVER_NUM;
+
+// Reexport the exports of the [`tcp`] module, to avoid redundant
+// module paths (e.g. `capsules::net::tcp::tcp::TCPHeader`)
+pub mod tcp;
+pub use tcp::TCPHeader;
@@ -0,0 +1,206 @@
+//! This file contains the structs and methods associated with the TCP header.
+//! This includes getters and setters for the various header fields, as well
+//! as the standard encode/decode functionality required for serializing
+//! the struct for transmission.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u32};
+use crate::net::stream::{encode_u16, encode_u32};
+
+// Note: Unlike the UDP header, all TCP header fields are stored in host byte
+// order, and converted when encoding and decoding.
+
+/// Size of a TCP header without options.
+pub const TCP_HDR_LEN: usize = 20;
+
+/// Control flags of a TCP segment.
+pub mod tcp_flags {
+    pub const FIN: u16 = 0x01;
+    pub const SYN: u16 = 0x02;
+    pub const RST: u16 = 0x04;
+    pub const PSH: u16 = 0x08;
+    pub const ACK: u16 = 0x10;
+    pub const URG: u16 = 0x20;
+}
+
+/// The `TCPHeader` struct follows the layout for the TCP segment header.
+/// Options are skipped when decoding, and never encoded.
+#[derive(Copy, Clone, Debug)]
+pub struct TCPHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq_num: u32,
+    pub ack_num: u32,
+    pub offset_and_control: u16,
+    pub window: u16,
+    pub cksum: u16,
+    pub urg_ptr: u16,
+    /// Length of the whole segment, header included. This is not part of the
+    /// header on the wire, but the IP layer needs it to serialize the payload.
+    pub len: u16,
+}
+
+impl Default for TCPHeader {
+    fn default() -> TCPHeader {
+        TCPHeader {
+            src_port: 0,
+            dst_port: 0,
+            seq_num: 0,
+            ack_num: 0,
+            offset_and_control: ((TCP_HDR_LEN / 4) as u16) << 12,
+            window: 0,
+            cksum: 0,
+            urg_ptr: 0,
+            len: TCP_HDR_LEN as u16,
+        }
+    }
+}
+
+impl TCPHeader {
+    pub fn new() -> TCPHeader {
+        TCPHeader::default()
+    }
+
+    pub fn set_src_port(&mut self, port: u16) {
+        self.src_port = port;
+    }
+
+    pub fn set_dst_port(&mut self, port: u16) {
+        self.dst_port = port;
+    }
+
+    pub fn set_seq_num(&mut self, seq_num: u32) {
+        self.seq_num = seq_num;
+    }
+
+    pub fn set_ack_num(&mut self, ack_num: u32) {
+        self.ack_num = ack_num;
+    }
+
+    /// Set the control flags, a combination of the `tcp_flags` constants.
+    pub fn set_flags(&mut self, flags: u16) {
+        self.offset_and_control = (self.offset_and_control & !0x3f) | (flags & 0x3f);
+    }
+
+    pub fn set_window(&mut self, window: u16) {
+        self.window = window;
+    }
+
+    pub fn set_cksum(&mut self, cksum: u16) {
+        self.cksum = cksum;
+    }
+
+    pub fn set_len(&mut self, len: u16) {
+        self.len = len;
+    }
+
+    pub fn get_src_port(&self) -> u16 {
+        self.src_port
+    }
+
+    pub fn get_dst_port(&self) -> u16 {
+        self.dst_port
+    }
+
+    pub fn get_seq_num(&self) -> u32 {
+        self.seq_num
+    }
+
+    pub fn get_ack_num(&self) -> u32 {
+        self.ack_num
+    }
+
+    pub fn get_flags(&self) -> u16 {
+        self.offset_and_control & 0x3f
+    }
+
+    pub fn has_flags(&self, flags: u16) -> bool {
+        self.get_flags() & flags == flags
+    }
+
+    pub fn get_window(&self) -> u16 {
+        self.window
+    }
+
+    pub fn get_cksum(&self) -> u16 {
+        self.cksum
+    }
+
+    pub fn get_len(&self) -> u16 {
+        self.len
+    }
+
+    /// Offset of the data from the start of the segment, in bytes, as given
+    /// by the data offset field. This includes any options.
+    pub fn get_data_offset(&self) -> usize {
+        ((self.offset_and_control >> 12) as usize) * 4
+    }
+
+    pub fn get_hdr_size(&self) -> usize {
+        TCP_HDR_LEN
+    }
+
+    /// This function serializes the `TCPHeader` into the provided buffer.
+    ///
+    /// # Arguments
+    ///
+    /// `buf` - A mutable buffer to serialize the `TCPHeader` into
+    /// `offset` - The current offset into the provided buffer
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the new offset into the buffer wrapped in an
+    /// SResult.
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, self.get_hdr_size() + offset);
+
+        let mut off = offset;
+        off = enc_consume!(buf, off; encode_u16, self.src_port);
+        off = enc_consume!(buf, off; encode_u16, self.dst_port);
+        off = enc_consume!(buf, off; encode_u32, self.seq_num);
+        off = enc_consume!(buf, off; encode_u32, self.ack_num);
+        off = enc_consume!(buf, off; encode_u16, self.offset_and_control);
+        off = enc_consume!(buf, off; encode_u16, self.window);
+        off = enc_consume!(buf, off; encode_u16, self.cksum);
+        off = enc_consume!(buf, off; encode_u16, self.urg_ptr);
+        stream_done!(off, off);
+    }
+
+    /// This function deserializes the `TCPHeader` from the provided buffer,
+    /// which must contain the whole segment.
+    ///
+    /// # Arguments
+    ///
+    /// `buf` - The byte array corresponding to a serialized TCP segment
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the offset of the segment data, after any
+    /// options, and a `TCPHeader` struct wrapped in an SResult
+    pub fn decode(buf: &[u8]) -> SResult<TCPHeader> {
+        stream_len_cond!(buf, TCP_HDR_LEN);
+        let mut tcp_header = Self::new();
+        let off = 0;
+        let (off, src_port) = dec_try!(buf, off; decode_u16);
+        tcp_header.src_port = src_port;
+        let (off, dst_port) = dec_try!(buf, off; decode_u16);
+        tcp_header.dst_port = dst_port;
+        let (off, seq_num) = dec_try!(buf, off; decode_u32);
+        tcp_header.seq_num = seq_num;
+        let (off, ack_num) = dec_try!(buf, off; decode_u32);
+        tcp_header.ack_num = ack_num;
+        let (off, offset_and_control) = dec_try!(buf, off; decode_u16);
+        tcp_header.offset_and_control = offset_and_control;
+        let (off, window) = dec_try!(buf, off; decode_u16);
+        tcp_header.window = window;
+        let (off, cksum) = dec_try!(buf, off; decode_u16);
+        tcp_header.cksum = cksum;
+        let (off, urg_ptr) = dec_try!(buf, off; decode_u16);
+        tcp_header.urg_ptr = urg_ptr;
+        tcp_header.len = buf.len() as u16;
+
+        let data_offset = tcp_header.get_data_offset();
+        stream_cond!(data_offset >= off && data_offset <= buf.len());
+        stream_done!(data_offset, tcp_header);
+    }
+}
@@ -0,0 +1,55 @@
+//! Derivation of the keys used by a Thread network from its network (master)
+//! key, as outlined in Section 7.1.4 of the Thread 1.1.1 Specification.
+//!
+//! Every key sequence number gives a pair of keys:
+//!
+//! ```text
+//! HMAC-SHA256(network key, key sequence || "Thread") = MLE key || MAC key
+//! ```
+//!
+//! where the key sequence is a 32-bit big-endian number, the MLE key secures
+//! MLE messages and the MAC key secures 802.15.4 frames. Both sides of a
+//! frame identify the key sequence with the key index of the auxiliary
+//! security header, `(key sequence & 0x7f) + 1`, and MLE messages also carry
+//! the full key sequence as their key source.
+
+use crate::sha256::hmac_sha256;
+
+/// Length of the network key and of the derived keys.
+pub const KEY_LEN: usize = 16;
+
+const KEY_STRING: &[u8] = b"Thread";
+
+/// The MLE and MAC keys of one key sequence.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct ThreadKeys {
+    pub key_sequence: u32,
+    pub mle_key: [u8; KEY_LEN],
+    pub mac_key: [u8; KEY_LEN],
+}
+
+impl ThreadKeys {
+    /// Derive the keys of `key_sequence` from `network_key`.
+    pub fn derive(network_key: &[u8; KEY_LEN], key_sequence: u32) -> ThreadKeys {
+        let hash = hmac_sha256(network_key, &[&key_sequence.to_be_bytes(), KEY_STRING]);
+
+        let mut keys = ThreadKeys {
+            key_sequence: key_sequence,
+            mle_key: [0; KEY_LEN],
+            mac_key: [0; KEY_LEN],
+        };
+        keys.mle_key.copy_from_slice(&hash[..KEY_LEN]);
+        keys.mac_key.copy_from_slice(&hash[KEY_LEN..]);
+        keys
+    }
+
+    /// The key index that identifies these keys in security headers.
+    pub fn key_index(&self) -> u8 {
+        key_index(self.key_sequence)
+    }
+}
+
+/// The key index of `key_sequence`.
+pub fn key_index(key_sequence: u32) -> u8 {
+    (key_sequence & 0x7f) as u8 + 1
+}
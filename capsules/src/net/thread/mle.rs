@@ -0,0 +1,913 @@
+//! Mesh Link Establishment (MLE), as outlined in Chapter 4 of the Thread
+//! 1.1.1 Specification, for attaching to a Thread network as a Minimal End
+//! Device (MED).
+//!
+//! MLE messages are UDP datagrams on port 19788 between link-local
+//! addresses. They consist of a command type and a series of TLV parameters
+//! (see the `tlv` module), secured with AES-CCM* under the MLE key derived
+//! from the network key (see the `key` module).
+//!
+//! Attaching to a parent comprises a four-step handshake:
+//!
+//! 1. The child multicasts a Parent Request to all routers (ff02::2),
+//!    first asking only routers to answer and, if none does within 750 ms,
+//!    routers and router-eligible end devices within 1250 ms.
+//! 2. Each potential parent unicasts a Parent Response, which echoes the
+//!    challenge of the request.
+//! 3. The child selects the parent with the best link margin, then the
+//!    highest priority and number of good links, and unicasts a Child ID
+//!    Request.
+//! 4. The parent unicasts a Child ID Response with the 16-bit short address
+//!    (RLOC16) of the child, which is then set on the MAC device.
+//!
+//! Once attached, the child sends a Child Update Request every half of its
+//! timeout to keep the parent from removing it, and attaches again if the
+//! parent stops answering.
+//!
+//! `ThreadMle` also implements the 802.15.4 key and device lookup
+//! procedures of the framer, so that data frames can be secured with the MAC
+//! key of the current key sequence. MLE messages themselves are sent in
+//! frames without MAC security, so the 6LoWPAN layer must not be set to
+//! require security. Once `attach_changed()` reports a short address,
+//! frames should be secured with `mac_security()`.
+//!
+//! Limitations:
+//!
+//! - Only the link-local address is used: mesh-local and RLOC addresses are
+//!   not configured, and no Address Registration is sent, so the node can
+//!   only talk to its neighbors.
+//! - Only MLE messages of the current key sequence are accepted: the node
+//!   does not switch keys when the network rotates them.
+//! - The PAN ID, channel, network key and key sequence are provisioned by
+//!   the board; network discovery and commissioning are not implemented.
+//! - The link-layer frame counter is announced as 0, which lets the parent
+//!   accept any frame counter from the node.
+//! - Challenges come from a simple pseudo-random generator seeded from the
+//!   extended address and the alarm, not from an entropy source.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let thread_mle = static_init!(
+//!     capsules::net::thread::mle::ThreadMle<'static, VirtualMuxAlarm<'static, Rtc>,
+//!         VirtualAES128CCM<'static, nrf52840::aes::AesECB<'static>>>,
+//!     capsules::net::thread::mle::ThreadMle::new(
+//!         mac_device,
+//!         mle_aes_ccm,
+//!         mle_alarm,
+//!         mle_send,
+//!         mle_recv,
+//!         udp_port_table,
+//!         LeasableBuffer::new(&mut MLE_TX_BUF),
+//!         &mut MLE_CRYPT_BUF,
+//!         net_cap,
+//!     )
+//! );
+//! mle_aes_ccm.set_client(thread_mle);
+//! mle_alarm.set_alarm_client(thread_mle);
+//! mle_send.set_client(thread_mle);
+//! mle_recv.set_client(thread_mle);
+//! mac_device.set_key_procedure(thread_mle);
+//! mac_device.set_device_procedure(thread_mle);
+//! thread_mle.set_network_key(&NETWORK_KEY, 0);
+//! thread_mle.start();
+//! ```
+//!
+//! The MAC device must be configured with the PAN ID and channel of the
+//! network, and the interface list must contain the link-local address
+//! generated from the extended address of the MAC device.
+
+use crate::ieee802154::device::MacDevice;
+use crate::ieee802154::framer::{DeviceProcedure, KeyProcedure};
+use crate::net::ieee802154::{KeyId, MacAddress, SecurityLevel};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::thread::key::{ThreadKeys, KEY_LEN};
+use crate::net::thread::tlv::{LinkMode, MulticastResponder, Tlv, TlvType};
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::symmetric_encryption::{self, AES128CCM, CCM_NONCE_LENGTH};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ErrorCode;
+
+pub const MLE_PORT: u16 = 19788;
+
+/// The link-local all-routers multicast address, ff02::2.
+pub const ALL_ROUTERS: IPAddr = IPAddr([0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+
+/// Length of the longest MLE message (command and TLVs) received.
+pub const MAX_MESSAGE_LEN: usize = 256;
+
+/// Length of the buffer needed for encryption and decryption.
+pub const CRYPT_BUF_LEN: usize = M_OFF + MAX_MESSAGE_LEN + MIC_LEN;
+
+/// Length of the buffer needed for the longest message sent.
+pub const TX_BUF_LEN: usize = 128;
+
+/// Thread version announced in the Version TLV.
+pub const THREAD_VERSION: u16 = 2;
+
+/// Timeout after which the parent removes the child if it has not heard
+/// from it, in seconds.
+pub const CHILD_TIMEOUT_S: u32 = 240;
+
+/// Time to wait for Parent Responses to a request to routers only, and to
+/// routers and router-eligible end devices.
+const PARENT_REQUEST_ROUTERS_MS: u32 = 750;
+const PARENT_REQUEST_ALL_MS: u32 = 1250;
+
+/// Time to wait before starting over when no parent answered.
+const ATTACH_BACKOFF_MS: u32 = 5000;
+
+/// Time to wait for a Child ID Response or a Child Update Response.
+const RESPONSE_TIMEOUT_MS: u32 = 1250;
+
+/// Number of times a Child ID Request or a Child Update Request is sent
+/// before giving up on the parent.
+const MAX_REQUESTS: u8 = 3;
+
+/// Mode of a Minimal End Device: the receiver stays on, and the device does
+/// not route nor keep the full network data.
+const MODE: u8 = LinkMode::ReceiverOnWhenIdle as u8 | LinkMode::SecureDataRequests as u8;
+
+/// TLVs requested in the Child ID Request.
+const CHILD_ID_TLV_REQUEST: [u8; 2] = [TlvType::Address16 as u8, TlvType::NetworkData as u8];
+
+/// Security suite byte of secured messages.
+const SECURITY_SUITE_154: u8 = 0;
+
+/// Security control of the auxiliary header: level ENC-MIC-32 and a key
+/// identified by a 4-byte source (the key sequence) and an index.
+const SECURITY_CONTROL: u8 = SecurityLevel::EncMic32 as u8 | 0x10;
+const AUX_HEADER_LEN: usize = 10;
+const MIC_LEN: usize = 4;
+
+/// Layout of the crypt buffer: the source and destination IPv6 addresses
+/// and the auxiliary header are authenticated, followed by the command and
+/// TLVs and the MIC.
+const A_OFF: usize = 0;
+const AUX_OFF: usize = 32;
+const M_OFF: usize = AUX_OFF + AUX_HEADER_LEN;
+
+/// Short address of a device that does not have one.
+const NO_SHORT_ADDRESS: u16 = 0xFFFE;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Command {
+    LinkRequest = 0,
+    LinkAccept = 1,
+    LinkAcceptAndRequest = 2,
+    LinkReject = 3,
+    Advertisement = 4,
+    Update = 5,
+    UpdateRequest = 6,
+    DataRequest = 7,
+    DataResponse = 8,
+    ParentRequest = 9,
+    ParentResponse = 10,
+    ChildIdRequest = 11,
+    ChildIdResponse = 12,
+    ChildUpdateRequest = 13,
+    ChildUpdateResponse = 14,
+    Announce = 15,
+    DiscoveryRequest = 16,
+    DiscoveryResponse = 17,
+}
+
+/// Clients are told when the node attaches, with its short address, and
+/// when it detaches.
+pub trait MleClient {
+    fn attach_changed(&self, rloc16: Option<u16>);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum State {
+    Idle,
+    /// Waiting for Parent Responses, with the scan of routers only (0) or
+    /// of routers and end devices (1).
+    ParentRequest(u8),
+    /// Waiting before attaching again.
+    Backoff,
+    /// Waiting for the Child ID Response, with the number of requests sent.
+    ChildIdRequest(u8),
+    Attached,
+    /// Waiting for the Child Update Response, with the number of requests
+    /// sent.
+    ChildUpdateRequest(u8),
+}
+
+#[derive(Copy, Clone)]
+enum CryptOp {
+    Idle,
+    Encrypt {
+        dst: IPAddr,
+        m_len: usize,
+    },
+    Decrypt {
+        src: IPAddr,
+        m_len: usize,
+        frame_counter: u32,
+    },
+}
+
+#[derive(Copy, Clone, Debug)]
+struct LeaderData {
+    partition_id: u32,
+    weighting: u8,
+    data_version: u8,
+    stable_data_version: u8,
+    leader_router_id: u8,
+}
+
+/// A parent, or a candidate parent while Parent Responses are collected.
+#[derive(Copy, Clone, Debug)]
+struct Parent {
+    ip: IPAddr,
+    ext_addr: [u8; 8],
+    rloc16: u16,
+    /// Challenge of the Parent Response, echoed in the Child ID Request
+    challenge: [u8; 8],
+    /// Smallest MLE frame counter accepted from the parent
+    next_frame_counter: u32,
+    /// Link quality, priority and number of links of quality 3, compared
+    /// in this order
+    rank: (u8, u8, u8),
+}
+
+/// The TLVs of a received message used by the attach process.
+#[derive(Default)]
+struct Message {
+    command: u8,
+    source_address: Option<u16>,
+    challenge: Option<[u8; 8]>,
+    response: Option<[u8; 8]>,
+    link_margin: Option<u8>,
+    /// Parent priority and number of links of quality 3
+    connectivity: Option<(u8, u8)>,
+    address16: Option<u16>,
+    leader_data: Option<LeaderData>,
+    status: Option<u8>,
+}
+
+impl Message {
+    fn parse(buf: &[u8]) -> Option<Message> {
+        let mut msg = Message::default();
+        msg.command = *buf.first()?;
+        let mut off = 1;
+        while off < buf.len() {
+            // TLVs that cannot be decoded, such as those not needed by an
+            // end device, are skipped.
+            let end = off + 2 + *buf.get(off + 1)? as usize;
+            if end > buf.len() {
+                return None;
+            }
+            if let Some((_, tlv)) = Tlv::decode(&buf[off..end]).done() {
+                match tlv {
+                    Tlv::SourceAddress(addr) => msg.source_address = Some(addr),
+                    Tlv::Challenge(challenge) => msg.challenge = Some(challenge),
+                    Tlv::Response(response) => msg.response = Some(response),
+                    Tlv::LinkMargin(margin) => msg.link_margin = Some(margin),
+                    Tlv::Connectivity {
+                        parent_priority,
+                        link_quality_3,
+                        ..
+                    } => msg.connectivity = Some((parent_priority, link_quality_3)),
+                    Tlv::Address16(addr) => msg.address16 = Some(addr),
+                    Tlv::LeaderData {
+                        partition_id,
+                        weighting,
+                        data_version,
+                        stable_data_version,
+                        leader_router_id,
+                    } => {
+                        msg.leader_data = Some(LeaderData {
+                            partition_id: partition_id,
+                            weighting: weighting,
+                            data_version: data_version,
+                            stable_data_version: stable_data_version,
+                            leader_router_id: leader_router_id,
+                        })
+                    }
+                    Tlv::Status(status) => msg.status = Some(status),
+                    _ => {}
+                }
+            }
+            off = end;
+        }
+        Some(msg)
+    }
+}
+
+/// Link quality of a link margin in dB (Section 4.4.1.1.1).
+fn link_quality(link_margin: u8) -> u8 {
+    if link_margin > 20 {
+        3
+    } else if link_margin > 10 {
+        2
+    } else if link_margin > 2 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Extended address of the device using the link-local address `ip`.
+fn ext_addr_from_ip(ip: &IPAddr) -> [u8; 8] {
+    let mut ext_addr = [0; 8];
+    ext_addr.copy_from_slice(&ip.0[8..16]);
+    ext_addr[0] ^= 0x02;
+    ext_addr
+}
+
+pub struct ThreadMle<'a, A: Alarm<'a>, C: AES128CCM<'a>> {
+    mac: &'a dyn MacDevice<'a>,
+    aes_ccm: &'a C,
+    alarm: &'a A,
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    crypt_buffer: TakeCell<'static, [u8]>,
+    crypt_op: Cell<CryptOp>,
+    net_cap: &'static NetworkCapability,
+    client: OptionalCell<&'a dyn MleClient>,
+
+    keys: OptionalCell<ThreadKeys>,
+    /// Frame counter of the next MLE message sent
+    frame_counter: Cell<u32>,
+    rng_state: Cell<u32>,
+
+    state: Cell<State>,
+    /// Challenge of the last request sent
+    challenge: Cell<[u8; 8]>,
+    candidate: OptionalCell<Parent>,
+    parent: OptionalCell<Parent>,
+    leader_data: OptionalCell<LeaderData>,
+    rloc16: OptionalCell<u16>,
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> ThreadMle<'a, A, C> {
+    /// `tx_buffer` should be `TX_BUF_LEN` bytes long, and `crypt_buffer`
+    /// `CRYPT_BUF_LEN` bytes long.
+    pub fn new(
+        mac: &'a dyn MacDevice<'a>,
+        aes_ccm: &'a C,
+        alarm: &'a A,
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        tx_buffer: LeasableBuffer<'static, u8>,
+        crypt_buffer: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> ThreadMle<'a, A, C> {
+        ThreadMle {
+            mac: mac,
+            aes_ccm: aes_ccm,
+            alarm: alarm,
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            tx_buffer: MapCell::new(tx_buffer),
+            crypt_buffer: TakeCell::new(crypt_buffer),
+            crypt_op: Cell::new(CryptOp::Idle),
+            net_cap: net_cap,
+            client: OptionalCell::empty(),
+            keys: OptionalCell::empty(),
+            frame_counter: Cell::new(0),
+            rng_state: Cell::new(0),
+            state: Cell::new(State::Idle),
+            challenge: Cell::new([0; 8]),
+            candidate: OptionalCell::empty(),
+            parent: OptionalCell::empty(),
+            leader_data: OptionalCell::empty(),
+            rloc16: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn MleClient) {
+        self.client.set(client);
+    }
+
+    /// Derive the MLE and MAC keys of `key_sequence` from the network key.
+    pub fn set_network_key(&self, network_key: &[u8; KEY_LEN], key_sequence: u32) {
+        self.keys.set(ThreadKeys::derive(network_key, key_sequence));
+    }
+
+    /// Bind the MLE port and start attaching. Returns `INVAL` if the network
+    /// key is not set.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if self.keys.is_none() {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.udp_sender.is_bound() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let socket = self
+            .port_table
+            .create_socket()
+            .map_err(|_| ErrorCode::NOMEM)?;
+        match self.port_table.bind(socket, MLE_PORT, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                self.udp_sender.set_binding(send_binding);
+                self.udp_receiver.set_binding(recv_binding);
+            }
+            // Dropping the socket frees it.
+            Err(_socket) => return Err(ErrorCode::INVAL),
+        }
+        self.attach();
+        Ok(())
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.rloc16.is_some()
+    }
+
+    /// The security level and key ID with which data frames must be sent.
+    pub fn mac_security(&self) -> Option<(SecurityLevel, KeyId)> {
+        self.keys
+            .map(|keys| (SecurityLevel::EncMic32, KeyId::Index(keys.key_index())))
+    }
+
+    fn own_ip(&self) -> IPAddr {
+        IPAddr::generate_from_mac(MacAddress::Long(self.mac.get_address_long()))
+    }
+
+    /// Xorshift pseudo-random generator, only used for challenges.
+    fn random(&self) -> u32 {
+        let mut x = self.rng_state.get();
+        if x == 0 {
+            let addr = self.mac.get_address_long();
+            x = (u32::from_be_bytes([addr[4], addr[5], addr[6], addr[7]])
+                ^ self.alarm.now().into_u32())
+                | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state.set(x);
+        x
+    }
+
+    fn new_challenge(&self) -> [u8; 8] {
+        let mut challenge = [0; 8];
+        challenge[..4].copy_from_slice(&self.random().to_be_bytes());
+        challenge[4..].copy_from_slice(&self.random().to_be_bytes());
+        self.challenge.set(challenge);
+        challenge
+    }
+
+    fn set_timer_ms(&self, ms: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    /// Forget the parent and the short address.
+    fn detach(&self) {
+        let _ = self.alarm.disarm();
+        self.state.set(State::Idle);
+        self.candidate.clear();
+        self.parent.clear();
+        self.leader_data.clear();
+        if self.rloc16.take().is_some() {
+            self.client.map(|client| client.attach_changed(None));
+        }
+        self.mac.set_address(NO_SHORT_ADDRESS);
+        self.mac.config_commit();
+    }
+
+    fn attach(&self) {
+        self.detach();
+        self.send_parent_request(0);
+    }
+
+    fn send_parent_request(&self, scan: u8) {
+        let (scan_mask, timeout) = if scan == 0 {
+            (MulticastResponder::Router as u8, PARENT_REQUEST_ROUTERS_MS)
+        } else {
+            (
+                MulticastResponder::Router as u8 | MulticastResponder::EndDevice as u8,
+                PARENT_REQUEST_ALL_MS,
+            )
+        };
+        let challenge = self.new_challenge();
+        self.state.set(State::ParentRequest(scan));
+        self.set_timer_ms(timeout);
+        // A request that cannot be sent is handled as if no parent answered.
+        let _ = self.send(
+            ALL_ROUTERS,
+            Command::ParentRequest,
+            &[
+                Tlv::Mode(MODE),
+                Tlv::Challenge(challenge),
+                Tlv::ScanMask(scan_mask),
+                Tlv::Version(THREAD_VERSION),
+            ],
+        );
+    }
+
+    fn send_child_id_request(&self, parent: &Parent, requests: u8) {
+        self.state.set(State::ChildIdRequest(requests));
+        self.set_timer_ms(RESPONSE_TIMEOUT_MS);
+        let _ = self.send(
+            parent.ip,
+            Command::ChildIdRequest,
+            &[
+                Tlv::Response(parent.challenge),
+                Tlv::LinkLayerFrameCounter(0),
+                Tlv::MleFrameCounter(self.frame_counter.get()),
+                Tlv::Mode(MODE),
+                Tlv::Timeout(CHILD_TIMEOUT_S),
+                Tlv::Version(THREAD_VERSION),
+                Tlv::TlvRequest(&CHILD_ID_TLV_REQUEST),
+            ],
+        );
+    }
+
+    fn send_child_update_request(&self, parent: &Parent, rloc16: u16, requests: u8) {
+        let challenge = self.new_challenge();
+        self.state.set(State::ChildUpdateRequest(requests));
+        self.set_timer_ms(RESPONSE_TIMEOUT_MS);
+        let source = Tlv::SourceAddress(rloc16);
+        let mode = Tlv::Mode(MODE);
+        let challenge = Tlv::Challenge(challenge);
+        let timeout = Tlv::Timeout(CHILD_TIMEOUT_S);
+        let _ = match self.leader_data.extract() {
+            Some(leader) => self.send(
+                parent.ip,
+                Command::ChildUpdateRequest,
+                &[
+                    source,
+                    Tlv::LeaderData {
+                        partition_id: leader.partition_id,
+                        weighting: leader.weighting,
+                        data_version: leader.data_version,
+                        stable_data_version: leader.stable_data_version,
+                        leader_router_id: leader.leader_router_id,
+                    },
+                    mode,
+                    challenge,
+                    timeout,
+                ],
+            ),
+            None => self.send(
+                parent.ip,
+                Command::ChildUpdateRequest,
+                &[source, mode, challenge, timeout],
+            ),
+        };
+    }
+
+    /// Encrypt a message to `dst`, which is sent once encrypted.
+    fn send(&self, dst: IPAddr, command: Command, tlvs: &[Tlv]) -> Result<(), ErrorCode> {
+        let keys = self.keys.extract().ok_or(ErrorCode::OFF)?;
+        if self.tx_buffer.is_none() {
+            return Err(ErrorCode::BUSY);
+        }
+        let buf = self.crypt_buffer.take().ok_or(ErrorCode::BUSY)?;
+
+        // The message must fit both buffers once encrypted.
+        let end = core::cmp::min(buf.len(), M_OFF + TX_BUF_LEN - 1 - AUX_HEADER_LEN);
+        if end < M_OFF + 1 + MIC_LEN {
+            self.crypt_buffer.replace(buf);
+            return Err(ErrorCode::SIZE);
+        }
+        let end = end - MIC_LEN;
+        buf[M_OFF] = command as u8;
+        let mut m_len = 1;
+        for tlv in tlvs {
+            match tlv.encode(&mut buf[M_OFF + m_len..end]).done() {
+                Some((len, ())) => m_len += len,
+                None => {
+                    self.crypt_buffer.replace(buf);
+                    return Err(ErrorCode::SIZE);
+                }
+            }
+        }
+
+        let src = self.own_ip();
+        let frame_counter = self.frame_counter.get();
+        self.frame_counter.set(frame_counter.wrapping_add(1));
+        buf[A_OFF..A_OFF + 16].copy_from_slice(&src.0);
+        buf[A_OFF + 16..AUX_OFF].copy_from_slice(&dst.0);
+        buf[AUX_OFF] = SECURITY_CONTROL;
+        buf[AUX_OFF + 1..AUX_OFF + 5].copy_from_slice(&frame_counter.to_le_bytes());
+        buf[AUX_OFF + 5..AUX_OFF + 9].copy_from_slice(&keys.key_sequence.to_be_bytes());
+        buf[AUX_OFF + 9] = keys.key_index();
+
+        let nonce = Self::nonce(&self.mac.get_address_long(), frame_counter);
+        self.crypt(buf, &keys, &nonce, m_len, true)?;
+        self.crypt_op.set(CryptOp::Encrypt {
+            dst: dst,
+            m_len: m_len,
+        });
+        Ok(())
+    }
+
+    fn nonce(ext_addr: &[u8; 8], frame_counter: u32) -> [u8; CCM_NONCE_LENGTH] {
+        let mut nonce = [0; CCM_NONCE_LENGTH];
+        nonce[..8].copy_from_slice(ext_addr);
+        nonce[8..12].copy_from_slice(&frame_counter.to_be_bytes());
+        nonce[12] = SecurityLevel::EncMic32 as u8;
+        nonce
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        keys: &ThreadKeys,
+        nonce: &[u8; CCM_NONCE_LENGTH],
+        m_len: usize,
+        encrypting: bool,
+    ) -> Result<(), ErrorCode> {
+        if self.aes_ccm.set_key(&keys.mle_key).is_err() || self.aes_ccm.set_nonce(nonce).is_err() {
+            self.crypt_buffer.replace(buf);
+            return Err(ErrorCode::FAIL);
+        }
+        self.aes_ccm
+            .crypt(buf, A_OFF, M_OFF, m_len, MIC_LEN, true, encrypting)
+            .map_err(|(err, buf)| {
+                self.crypt_buffer.replace(buf);
+                err
+            })
+    }
+
+    /// Send the message encrypted in `buf`.
+    fn send_encrypted(&self, buf: &[u8], dst: IPAddr, m_len: usize) {
+        self.tx_buffer.take().map(|mut tx| {
+            tx.reset();
+            let len = 1 + AUX_HEADER_LEN + m_len + MIC_LEN;
+            if tx.len() < len {
+                self.tx_buffer.replace(tx);
+                return;
+            }
+            tx[0] = SECURITY_SUITE_154;
+            tx[1..len].copy_from_slice(&buf[AUX_OFF..M_OFF + m_len + MIC_LEN]);
+            tx.slice(0..len);
+            if let Err(mut tx) = self.udp_sender.send_to(dst, MLE_PORT, tx, self.net_cap) {
+                tx.reset();
+                self.tx_buffer.replace(tx);
+            }
+        });
+    }
+
+    /// Handle an authenticated message from `src`, secured with
+    /// `frame_counter`.
+    fn receive_message(&self, src: IPAddr, frame_counter: u32, msg: Message) {
+        // Drop replayed messages from the parent.
+        if let Some(mut parent) = self.parent.extract() {
+            if parent.ip == src {
+                if frame_counter < parent.next_frame_counter {
+                    return;
+                }
+                parent.next_frame_counter = frame_counter.wrapping_add(1);
+                self.parent.set(parent);
+            }
+        }
+
+        match self.state.get() {
+            State::ParentRequest(_) if msg.command == Command::ParentResponse as u8 => {
+                self.receive_parent_response(src, frame_counter, &msg)
+            }
+            State::ChildIdRequest(_) if msg.command == Command::ChildIdResponse as u8 => {
+                self.receive_child_id_response(src, &msg)
+            }
+            State::ChildUpdateRequest(_) if msg.command == Command::ChildUpdateResponse as u8 => {
+                self.receive_child_update_response(src, &msg)
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_parent_response(&self, src: IPAddr, frame_counter: u32, msg: &Message) {
+        if msg.response != Some(self.challenge.get()) {
+            return;
+        }
+        let (rloc16, challenge, link_margin) =
+            match (msg.source_address, msg.challenge, msg.link_margin) {
+                (Some(rloc16), Some(challenge), Some(link_margin)) => {
+                    (rloc16, challenge, link_margin)
+                }
+                _ => return,
+            };
+        let (priority, links) = msg.connectivity.unwrap_or((0, 0));
+        // The parent priority is a signed 2-bit value in the top bits.
+        let priority = ((priority as i8 >> 6) + 1) as u8;
+        let candidate = Parent {
+            ip: src,
+            ext_addr: ext_addr_from_ip(&src),
+            rloc16: rloc16,
+            challenge: challenge,
+            next_frame_counter: frame_counter.wrapping_add(1),
+            rank: (link_quality(link_margin), priority, links),
+        };
+        if self
+            .candidate
+            .map_or(true, |best| candidate.rank > best.rank)
+        {
+            self.candidate.set(candidate);
+        }
+    }
+
+    fn receive_child_id_response(&self, src: IPAddr, msg: &Message) {
+        let parent = match self.parent.extract() {
+            Some(parent) if parent.ip == src => parent,
+            _ => return,
+        };
+        let rloc16 = match msg.address16 {
+            // The child's short address is derived from its parent's.
+            Some(rloc16) if rloc16 & 0xFC00 == parent.rloc16 & 0xFC00 => rloc16,
+            _ => return,
+        };
+        if let Some(leader) = msg.leader_data {
+            self.leader_data.set(leader);
+        }
+        self.mac.set_address(rloc16);
+        self.mac.config_commit();
+        self.rloc16.set(rloc16);
+        self.keep_alive();
+        self.client
+            .map(|client| client.attach_changed(Some(rloc16)));
+    }
+
+    fn receive_child_update_response(&self, src: IPAddr, msg: &Message) {
+        if !self.parent.map_or(false, |parent| parent.ip == src) {
+            return;
+        }
+        if msg.status.is_some() {
+            // The parent no longer knows this child.
+            self.attach();
+        } else if msg.response == Some(self.challenge.get()) {
+            if let Some(leader) = msg.leader_data {
+                self.leader_data.set(leader);
+            }
+            self.keep_alive();
+        }
+    }
+
+    fn keep_alive(&self) {
+        self.state.set(State::Attached);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_seconds(CHILD_TIMEOUT_S / 2));
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> time::AlarmClient for ThreadMle<'a, A, C> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Idle => {}
+            State::ParentRequest(scan) => match self.candidate.take() {
+                Some(parent) => {
+                    self.parent.set(parent);
+                    self.send_child_id_request(&parent, 1);
+                }
+                None if scan == 0 => self.send_parent_request(1),
+                None => {
+                    self.state.set(State::Backoff);
+                    self.set_timer_ms(ATTACH_BACKOFF_MS);
+                }
+            },
+            State::Backoff => self.send_parent_request(0),
+            State::ChildIdRequest(requests) => match self.parent.extract() {
+                Some(parent) if requests < MAX_REQUESTS => {
+                    self.send_child_id_request(&parent, requests + 1)
+                }
+                _ => self.attach(),
+            },
+            State::Attached => match (self.parent.extract(), self.rloc16.extract()) {
+                (Some(parent), Some(rloc16)) => self.send_child_update_request(&parent, rloc16, 1),
+                _ => self.attach(),
+            },
+            State::ChildUpdateRequest(requests) => {
+                match (self.parent.extract(), self.rloc16.extract()) {
+                    (Some(parent), Some(rloc16)) if requests < MAX_REQUESTS => {
+                        self.send_child_update_request(&parent, rloc16, requests + 1)
+                    }
+                    _ => self.attach(),
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> symmetric_encryption::CCMClient for ThreadMle<'a, A, C> {
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        let op = self.crypt_op.replace(CryptOp::Idle);
+        match op {
+            CryptOp::Idle => {}
+            CryptOp::Encrypt { dst, m_len } => {
+                if res.is_ok() {
+                    self.send_encrypted(buf, dst, m_len);
+                }
+            }
+            CryptOp::Decrypt {
+                src,
+                m_len,
+                frame_counter,
+            } => {
+                if res.is_ok() && tag_is_valid {
+                    let msg = Message::parse(&buf[M_OFF..M_OFF + m_len]);
+                    self.crypt_buffer.replace(buf);
+                    if let Some(msg) = msg {
+                        self.receive_message(src, frame_counter, msg);
+                    }
+                    return;
+                }
+            }
+        }
+        self.crypt_buffer.replace(buf);
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> UDPSendClient for ThreadMle<'a, A, C> {
+    fn send_done(&self, _result: Result<(), ErrorCode>, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buffer.replace(dgram);
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> UDPRecvClient for ThreadMle<'a, A, C> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        dst_addr: IPAddr,
+        src_port: u16,
+        dst_port: u16,
+        payload: &[u8],
+    ) {
+        // Unsecured messages, only used for discovery, are dropped.
+        if src_port != MLE_PORT
+            || dst_port != MLE_PORT
+            || payload.len() < 1 + AUX_HEADER_LEN + 1 + MIC_LEN
+            || payload[0] != SECURITY_SUITE_154
+            || payload[1] != SECURITY_CONTROL
+            || !src_addr.is_unicast_link_local()
+        {
+            return;
+        }
+        let keys = match self.keys.extract() {
+            Some(keys) => keys,
+            None => return,
+        };
+        let aux = &payload[1..1 + AUX_HEADER_LEN];
+        let key_sequence = u32::from_be_bytes([aux[5], aux[6], aux[7], aux[8]]);
+        if key_sequence != keys.key_sequence || aux[9] != keys.key_index() {
+            return;
+        }
+        let frame_counter = u32::from_le_bytes([aux[1], aux[2], aux[3], aux[4]]);
+
+        let m_len = payload.len() - 1 - AUX_HEADER_LEN - MIC_LEN;
+        // Messages received while another one is being encrypted or
+        // decrypted are dropped, and sent again by their sender.
+        let buf = match self.crypt_buffer.take() {
+            Some(buf) if buf.len() >= M_OFF + m_len + MIC_LEN => buf,
+            Some(buf) => {
+                self.crypt_buffer.replace(buf);
+                return;
+            }
+            None => return,
+        };
+        buf[A_OFF..A_OFF + 16].copy_from_slice(&src_addr.0);
+        buf[A_OFF + 16..AUX_OFF].copy_from_slice(&dst_addr.0);
+        buf[AUX_OFF..M_OFF + m_len + MIC_LEN].copy_from_slice(&payload[1..]);
+
+        let nonce = Self::nonce(&ext_addr_from_ip(&src_addr), frame_counter);
+        if self.crypt(buf, &keys, &nonce, m_len, false).is_ok() {
+            self.crypt_op.set(CryptOp::Decrypt {
+                src: src_addr,
+                m_len: m_len,
+                frame_counter: frame_counter,
+            });
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> KeyProcedure for ThreadMle<'a, A, C> {
+    fn lookup_key(&self, _level: SecurityLevel, key_id: KeyId) -> Option<[u8; 16]> {
+        self.keys.extract().and_then(|keys| {
+            if key_id == KeyId::Index(keys.key_index()) {
+                Some(keys.mac_key)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> DeviceProcedure for ThreadMle<'a, A, C> {
+    fn lookup_addr_long(&self, addr: MacAddress) -> Option<[u8; 8]> {
+        self.parent
+            .extract()
+            .filter(|parent| match addr {
+                MacAddress::Short(short_addr) => short_addr == parent.rloc16,
+                MacAddress::Long(long_addr) => long_addr == parent.ext_addr,
+            })
+            .map(|parent| parent.ext_addr)
+    }
+}
@@ -1 +1,3 @@
+pub mod key;
+pub mod mle;
 pub mod tlv;
@@ -5,6 +5,24 @@
 //! and bind to UDP ports for receiving packets.
 //! Also exposes a list of interface addresses to the application (currently
 //! hard-coded).
+//! On top of the IPv4 interface (`net::ipv4::ip4_interface`), addresses in
+//! the endpoints and the interface list are IPv4-mapped IPv6 addresses
+//! (`::ffff:a.b.c.d`).
+//!
+//! Each process has a table of `MAX_SOCKETS` sockets, each bound to a local
+//! endpoint and optionally connected to a remote one, in which case it only
+//! receives datagrams from that endpoint. Sockets bound to port 0 get an
+//! ephemeral port from 49152 to 65535 that no app or capsule uses. Socket 0
+//! is the one used by the bind (`3`) and transmit (`2`) commands of the
+//! original single-port interface.
+//!
+//! If the board gives the driver a DTLS session (`net::dtls`) with
+//! `set_secure_sender()`, a socket connected to the server of the session
+//! can be made secure (`10`). Its datagrams are then sent through the
+//! session, and it receives the datagrams the session decrypts instead of
+//! those sent to its own port. The board binds the session to its own port,
+//! passes that port to `set_secure_sender()`, and sets the driver as the
+//! send and receive client of the session.
 
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::network_capabilities::NetworkCapability;
@@ -21,7 +39,7 @@ use core::convert::TryInto;
 use core::mem::size_of;
 use core::{cmp, mem};
 use kernel::capabilities::UdpDriverCapability;
-use kernel::common::cells::MapCell;
+use kernel::common::cells::{MapCell, OptionalCell};
 use kernel::common::leasable_buffer::LeasableBuffer;
 use kernel::{
     debug, CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
@@ -31,6 +49,13 @@ use kernel::{
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Udp as usize;
 
+/// Number of sockets each process can have bound at a time.
+pub const MAX_SOCKETS: usize = 4;
+
+/// First port of the dynamic range (RFC 6335), from which ephemeral ports
+/// are allocated.
+pub const EPHEMERAL_PORT_MIN: u16 = 49152;
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct UDPEndpoint {
     addr: IPAddr,
@@ -66,6 +91,28 @@ impl UDPEndpoint {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+struct Socket {
+    local: UDPEndpoint,
+    /// Only endpoint datagrams are received from, if connected
+    remote: Option<UDPEndpoint>,
+    /// Whether the datagrams go through the DTLS session
+    secure: bool,
+}
+
+impl Socket {
+    /// Whether the socket receives a datagram from `src` to `dst`, where
+    /// `secure_port` is the port of the DTLS session, if any.
+    fn accepts(&self, src: &UDPEndpoint, dst: &UDPEndpoint, secure_port: Option<u16>) -> bool {
+        let local = if self.secure {
+            secure_port == Some(dst.port)
+        } else {
+            self.local == *dst
+        };
+        local && self.remote.map_or(true, |remote| remote == *src)
+    }
+}
+
 #[derive(Default)]
 pub struct App {
     rx_callback: Upcall,
@@ -75,7 +122,24 @@ pub struct App {
     app_cfg: ReadWriteAppSlice,
     app_rx_cfg: ReadWriteAppSlice,
     pending_tx: Option<[UDPEndpoint; 2]>,
-    bound_port: Option<UDPEndpoint>,
+    /// Whether the pending datagram goes through the DTLS session
+    pending_secure: bool,
+    sockets: [Option<Socket>; MAX_SOCKETS],
+    traffic_class: u8,
+}
+
+impl App {
+    fn is_bound(&self) -> bool {
+        self.sockets.iter().any(|socket| socket.is_some())
+    }
+
+    fn socket(&self, socket: usize) -> Result<Socket, ErrorCode> {
+        self.sockets
+            .get(socket)
+            .copied()
+            .flatten()
+            .ok_or(ErrorCode::INVAL)
+    }
 }
 
 #[allow(dead_code)]
@@ -83,6 +147,10 @@ pub struct UDPDriver<'a> {
     /// UDP sender
     sender: &'a dyn UDPSender<'a>,
 
+    /// DTLS session of secure sockets, and the local port it is bound to
+    secure_sender: OptionalCell<&'a dyn UDPSender<'a>>,
+    secure_port: OptionalCell<u16>,
+
     /// Grant of apps that use this radio driver.
     apps: Grant<App>,
     /// ID of app whose transmission request is being processed.
@@ -99,6 +167,9 @@ pub struct UDPDriver<'a> {
 
     kernel_buffer: MapCell<LeasableBuffer<'static, u8>>,
 
+    /// Next ephemeral port tried
+    ephemeral_port: Cell<u16>,
+
     driver_send_cap: &'static dyn UdpDriverCapability,
 
     net_cap: &'static NetworkCapability,
@@ -117,17 +188,28 @@ impl<'a> UDPDriver<'a> {
     ) -> UDPDriver<'a> {
         UDPDriver {
             sender: sender,
+            secure_sender: OptionalCell::empty(),
+            secure_port: OptionalCell::empty(),
             apps: grant,
             current_app: Cell::new(None),
             interface_list: interface_list,
             max_tx_pyld_len: max_tx_pyld_len,
             port_table: port_table,
             kernel_buffer: MapCell::new(kernel_buffer),
+            ephemeral_port: Cell::new(EPHEMERAL_PORT_MIN),
             driver_send_cap: driver_send_cap,
             net_cap: net_cap,
         }
     }
 
+    /// Send the datagrams of secure sockets through `sender`, a DTLS session
+    /// bound to local port `port`. The driver must be the send and receive
+    /// client of the session.
+    pub fn set_secure_sender(&self, sender: &'a dyn UDPSender<'a>, port: u16) {
+        self.secure_sender.set(sender);
+        self.secure_port.set(port);
+    }
+
     /// Utility function to perform an action on an app in a system call.
     #[inline]
     fn do_with_app<F>(&self, appid: ProcessId, closure: F) -> Result<(), ErrorCode>
@@ -203,14 +285,27 @@ impl<'a> UDPDriver<'a> {
                         }
                         kernel_buffer[0..payload.len()].copy_from_slice(payload.as_ref());
                         kernel_buffer.slice(0..payload.len());
-                        match self.sender.driver_send_to(
-                            dst_addr,
-                            dst_port,
-                            src_port,
-                            kernel_buffer,
-                            self.driver_send_cap,
-                            self.net_cap,
-                        ) {
+                        let result = if app.pending_secure {
+                            // The session sends from its own port.
+                            match self.secure_sender.extract() {
+                                Some(sender) => {
+                                    sender.set_traffic_class(app.traffic_class);
+                                    sender.send_to(dst_addr, dst_port, kernel_buffer, self.net_cap)
+                                }
+                                None => Err(kernel_buffer),
+                            }
+                        } else {
+                            self.sender.set_traffic_class(app.traffic_class);
+                            self.sender.driver_send_to(
+                                dst_addr,
+                                dst_port,
+                                src_port,
+                                kernel_buffer,
+                                self.driver_send_cap,
+                                self.net_cap,
+                            )
+                        };
+                        match result {
                             Ok(_) => Ok(()),
                             Err(mut buf) => {
                                 buf.reset();
@@ -279,6 +374,109 @@ impl<'a> UDPDriver<'a> {
             Some(pair)
         }
     }
+
+    /// The destination endpoint in the second half of `app_cfg`.
+    fn cfg_destination(&self, app: &App) -> Option<UDPEndpoint> {
+        app.app_cfg.map_or(None, |cfg| {
+            if cfg.len() != 2 * size_of::<UDPEndpoint>() {
+                None
+            } else {
+                self.parse_ip_port_pair(&cfg.as_ref()[size_of::<UDPEndpoint>()..])
+            }
+        })
+    }
+
+    /// The local endpoint in the second half of `app_rx_cfg`, which must be
+    /// on a local interface.
+    fn rx_cfg_local(&self, app: &App) -> Result<UDPEndpoint, ErrorCode> {
+        let local = app
+            .app_rx_cfg
+            .map_or(None, |cfg| {
+                if cfg.len() != 2 * size_of::<UDPEndpoint>() {
+                    None
+                } else {
+                    self.parse_ip_port_pair(&cfg.as_ref()[size_of::<UDPEndpoint>()..])
+                }
+            })
+            .ok_or(ErrorCode::INVAL)?;
+        if self.interface_list.iter().any(|addr| *addr == local.addr) {
+            Ok(local)
+        } else {
+            Err(ErrorCode::INVAL)
+        }
+    }
+
+    /// Find a port of the dynamic range that no app or capsule is bound to.
+    fn allocate_ephemeral_port(&self) -> Result<u16, ErrorCode> {
+        for _ in EPHEMERAL_PORT_MIN..=u16::MAX {
+            let port = self.ephemeral_port.get();
+            self.ephemeral_port.set(if port == u16::MAX {
+                EPHEMERAL_PORT_MIN
+            } else {
+                port + 1
+            });
+            match self.port_table.is_bound(port) {
+                Ok(false) => return Ok(port),
+                Ok(true) => {}
+                Err(_) => return Err(ErrorCode::FAIL),
+            }
+        }
+        Err(ErrorCode::NOMEM)
+    }
+
+    /// Bind a new socket of `appid` to the local endpoint in `app_rx_cfg`,
+    /// with an ephemeral port if its port is 0, and return its index and
+    /// port.
+    fn bind_socket(&self, appid: ProcessId) -> Result<(usize, u16), ErrorCode> {
+        let mut local = self
+            .apps
+            .enter(appid, |app| self.rx_cfg_local(app))
+            .unwrap_or_else(|err| Err(err.into()))?;
+        // The port table queries the sockets of all apps, so it must be
+        // checked outside of the grant.
+        if local.port == 0 {
+            local.port = self.allocate_ephemeral_port()?;
+        } else {
+            match self.port_table.is_bound(local.port) {
+                Ok(false) => {}
+                Ok(true) => return Err(ErrorCode::BUSY),
+                Err(_) => return Err(ErrorCode::FAIL),
+            }
+        }
+        self.apps
+            .enter(appid, |app| {
+                let index = app
+                    .sockets
+                    .iter()
+                    .position(|socket| socket.is_none())
+                    .ok_or(ErrorCode::NOMEM)?;
+                app.sockets[index] = Some(Socket {
+                    local: local,
+                    remote: None,
+                    secure: false,
+                });
+                Ok((index, local.port))
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    /// Queue the payload of `appid` for transmission on `socket`, to the
+    /// endpoint it is connected to or else to the destination in `app_cfg`.
+    fn send_on_socket(&self, appid: ProcessId, socket: usize) -> Result<(), ErrorCode> {
+        self.do_with_app(appid, |app| {
+            if app.pending_tx.is_some() {
+                return Err(ErrorCode::BUSY);
+            }
+            let sock = app.socket(socket)?;
+            let dst = match sock.remote {
+                Some(remote) => remote,
+                None => self.cfg_destination(app).ok_or(ErrorCode::INVAL)?,
+            };
+            app.pending_tx = Some([sock.local, dst]);
+            app.pending_secure = sock.secure;
+            Ok(())
+        })
+    }
 }
 
 impl<'a> Driver for UDPDriver<'a> {
@@ -365,7 +563,8 @@ impl<'a> Driver for UDPDriver<'a> {
     ///
     /// - `0`: Setup callback for when packet is received. If no port has
     ///        been bound, return RESERVE to indicate that port binding is
-    ///        is a prerequisite to reception.
+    ///        is a prerequisite to reception. The callback receives the length
+    ///        of the payload and the socket it was received on.
     /// - `1`: Setup callback for when packet is transmitted. Notably,
     ///        this callback receives the result of the send_done callback
     ///        from udp_send.rs, which does not currently pass information
@@ -379,7 +578,7 @@ impl<'a> Driver for UDPDriver<'a> {
         match subscribe_num {
             0 => {
                 let res = self.apps.enter(app_id, |app| {
-                    if app.bound_port.is_some() {
+                    if app.is_bound() {
                         mem::swap(&mut app.rx_callback, &mut callback);
                         Ok(())
                     } else {
@@ -450,15 +649,35 @@ impl<'a> Driver for UDPDriver<'a> {
     ///        the current implementation of this only allows for each app to bind to a single
     ///        port at a time, as such an implementation conserves memory (and is similar
     ///        to the approach applied by TinyOS and Riot).
-    ///        /// - `4`: Returns the maximum payload that can be transmitted by apps using this driver.
+    /// - `4`: Returns the maximum payload that can be transmitted by apps using this driver.
     ///        This represents the size of the payload buffer in the kernel. Apps can use this
     ///        syscall to ensure they do not attempt to send too-large messages.
+    /// - `5`: Set the IPv6 traffic class byte (DSCP in the upper six bits, ECN in the lower
+    ///        two) of datagrams this app sends, or the type of service byte over IPv4. Returns INVAL if `arg1` does not fit in a
+    ///        byte. Defaults to 0 (best effort).
+    /// - `6`: Bind a new socket to the local address and port in the second half of rx_cfg.
+    ///        If the port is 0, an unused ephemeral port is allocated. Returns the index
+    ///        of the socket and its port. Returns INVAL if the address is not a local
+    ///        interface, BUSY if the port is already bound, and NOMEM if all `MAX_SOCKETS`
+    ///        sockets of the app are bound or no ephemeral port is free.
+    /// - `7`: Connect socket `arg1` to the address and port in the second half of cfg, so
+    ///        that it only receives datagrams from that endpoint and sends to it by default.
+    ///        The zero endpoint disconnects the socket, which makes a secure socket plain.
+    ///        Returns INVAL if the socket is not bound or the endpoint cannot be parsed.
+    /// - `8`: Transmit the payload on socket `arg1`, to the endpoint it is connected to or
+    ///        else to the destination in the second half of cfg. Returns like `2`.
+    /// - `9`: Close socket `arg1`. Returns INVAL if the socket is not bound.
+    /// - `10`: Make socket `arg1` secure if `arg2` is not 0, or plain again if it is 0.
+    ///        A secure socket sends and receives through the DTLS session of the board,
+    ///        and must be connected to its server: datagrams to other endpoints fail.
+    ///        Returns NOSUPPORT if the board has no session, and INVAL if the socket is
+    ///        not bound, or not connected when it is made secure.
 
     fn command(
         &self,
         command_num: usize,
         arg1: usize,
-        _: usize,
+        arg2: usize,
         appid: ProcessId,
     ) -> CommandReturn {
         match command_num {
@@ -496,7 +715,7 @@ impl<'a> Driver for UDPDriver<'a> {
                             // Cannot support more than one pending tx per process.
                             return Err(ErrorCode::BUSY);
                         }
-                        if app.bound_port.is_none() {
+                        if !app.is_bound() {
                             // Currently, apps need to bind to a port before they can send from said port
                             return Err(ErrorCode::RESERVE);
                         }
@@ -509,7 +728,11 @@ impl<'a> Driver for UDPDriver<'a> {
                                 self.parse_ip_port_pair(&cfg.as_ref()[size_of::<UDPEndpoint>()..]),
                                 self.parse_ip_port_pair(&cfg.as_ref()[..size_of::<UDPEndpoint>()]),
                             ) {
-                                if Some(src.clone()) == app.bound_port {
+                                if app
+                                    .sockets
+                                    .iter()
+                                    .any(|socket| socket.map_or(false, |s| s.local == src))
+                                {
                                     Some([src, dst])
                                 } else {
                                     None
@@ -522,6 +745,7 @@ impl<'a> Driver for UDPDriver<'a> {
                             return Err(ErrorCode::INVAL);
                         }
                         app.pending_tx = next_tx;
+                        app.pending_secure = false;
                         Ok(())
                     })
                     .unwrap_or_else(|err| Err(err.into()));
@@ -552,7 +776,7 @@ impl<'a> Driver for UDPDriver<'a> {
                         requested_addr_opt.map_or(Err(Err(ErrorCode::INVAL)), |requested_addr| {
                             // If zero address, close any already bound socket
                             if requested_addr.is_zero() {
-                                app.bound_port = None;
+                                app.sockets[0] = None;
                                 return Ok(None);
                             }
                             // Check that requested addr is a local interface
@@ -581,7 +805,11 @@ impl<'a> Driver for UDPDriver<'a> {
                                         self.apps
                                             .enter(appid, |app| {
                                                 // The requested addr is free and valid
-                                                app.bound_port = Some(requested_addr);
+                                                app.sockets[0] = Some(Socket {
+                                                    local: requested_addr,
+                                                    remote: None,
+                                                    secure: false,
+                                                });
                                                 CommandReturn::success()
                                             })
                                             .unwrap_or_else(|err| {
@@ -597,6 +825,61 @@ impl<'a> Driver for UDPDriver<'a> {
                 }
             }
             4 => CommandReturn::success_u32(self.max_tx_pyld_len as u32),
+            5 => {
+                if arg1 > u8::MAX as usize {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                self.apps
+                    .enter(appid, |app| {
+                        app.traffic_class = arg1 as u8;
+                        CommandReturn::success()
+                    })
+                    .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+            }
+            6 => match self.bind_socket(appid) {
+                Ok((socket, port)) => CommandReturn::success_u32_u32(socket as u32, port as u32),
+                Err(e) => CommandReturn::failure(e),
+            },
+            7 => self
+                .do_with_app(appid, |app| {
+                    let mut socket = app.socket(arg1)?;
+                    let remote = self.cfg_destination(app).ok_or(ErrorCode::INVAL)?;
+                    socket.remote = if remote.is_zero() { None } else { Some(remote) };
+                    // Secure sockets only talk to the server of the session.
+                    socket.secure = socket.secure && socket.remote.is_some();
+                    app.sockets[arg1] = Some(socket);
+                    Ok(())
+                })
+                .into(),
+            8 => match self.send_on_socket(appid, arg1) {
+                Ok(()) => self.do_next_tx_immediate(appid).map_or_else(
+                    |err| CommandReturn::failure(err.into()),
+                    |v| CommandReturn::success_u32(v),
+                ),
+                Err(e) => CommandReturn::failure(e),
+            },
+            9 => self
+                .do_with_app(appid, |app| {
+                    app.socket(arg1)?;
+                    app.sockets[arg1] = None;
+                    Ok(())
+                })
+                .into(),
+            10 => {
+                if self.secure_sender.is_none() {
+                    return CommandReturn::failure(ErrorCode::NOSUPPORT);
+                }
+                self.do_with_app(appid, |app| {
+                    let mut socket = app.socket(arg1)?;
+                    if arg2 != 0 && socket.remote.is_none() {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    socket.secure = arg2 != 0;
+                    app.sockets[arg1] = Some(socket);
+                    Ok(())
+                })
+                .into()
+            }
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -627,41 +910,44 @@ impl<'a> UDPRecvClient for UDPDriver<'a> {
         dst_port: u16,
         payload: &[u8],
     ) {
+        let src = UDPEndpoint {
+            addr: src_addr,
+            port: src_port,
+        };
+        let dst = UDPEndpoint {
+            addr: dst_addr,
+            port: dst_port,
+        };
+        let secure_port = self.secure_port.extract();
         self.apps.each(|_, app| {
-            if app.bound_port.is_some() {
-                let mut for_me = false;
-                app.bound_port.as_ref().map(|requested_addr| {
-                    if requested_addr.addr == dst_addr && requested_addr.port == dst_port {
-                        for_me = true;
+            let socket = match app
+                .sockets
+                .iter()
+                .position(|socket| socket.map_or(false, |s| s.accepts(&src, &dst, secure_port)))
+            {
+                Some(socket) => socket,
+                None => return,
+            };
+            let len = payload.len();
+            let res = app.app_read.mut_map_or(Ok(()), |rbuf| {
+                if rbuf.len() >= len {
+                    rbuf[..len].copy_from_slice(&payload[..len]);
+                    Ok(())
+                } else {
+                    Err(ErrorCode::SIZE) //packet does not fit
+                }
+            });
+            if res.is_ok() {
+                // Write address of sender into rx_cfg so it can be read by client
+                app.rx_callback.schedule(len, socket, 0);
+                let cfg_len = 2 * size_of::<UDPEndpoint>();
+                let _ = app.app_rx_cfg.mut_map_or(Err(ErrorCode::INVAL), |cfg| {
+                    if cfg.len() != cfg_len {
+                        return Err(ErrorCode::INVAL);
                     }
+                    src.encode(cfg, 0);
+                    Ok(())
                 });
-                if for_me {
-                    let len = payload.len();
-                    let res = app.app_read.mut_map_or(Ok(()), |rbuf| {
-                        if rbuf.len() >= len {
-                            rbuf[..len].copy_from_slice(&payload[..len]);
-                            Ok(())
-                        } else {
-                            Err(ErrorCode::SIZE) //packet does not fit
-                        }
-                    });
-                    if res.is_ok() {
-                        // Write address of sender into rx_cfg so it can be read by client
-                        let sender_addr = UDPEndpoint {
-                            addr: src_addr,
-                            port: src_port,
-                        };
-                        app.rx_callback.schedule(len, 0, 0);
-                        let cfg_len = 2 * size_of::<UDPEndpoint>();
-                        let _ = app.app_rx_cfg.mut_map_or(Err(ErrorCode::INVAL), |cfg| {
-                            if cfg.len() != cfg_len {
-                                return Err(ErrorCode::INVAL);
-                            }
-                            sender_addr.encode(cfg, 0);
-                            Ok(())
-                        });
-                    }
-                }
             }
         });
     }
@@ -673,15 +959,340 @@ impl<'a> PortQuery for UDPDriver<'a> {
         let mut port_bound = false;
         for app in self.apps.iter() {
             app.enter(|other_app| {
-                if other_app.bound_port.is_some() {
-                    let other_addr_opt = other_app.bound_port.clone();
-                    let other_addr = other_addr_opt.expect("Missing other_addr");
-                    if other_addr.port == port {
-                        port_bound = true;
-                    }
+                if other_app
+                    .sockets
+                    .iter()
+                    .any(|socket| socket.map_or(false, |s| s.local.port == port))
+                {
+                    port_bound = true;
                 }
             });
+            if port_bound {
+                break;
+            }
         }
         port_bound
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::ieee802154::device::MacDevice;
+    use crate::ieee802154::framer::Framer;
+    use crate::ieee802154::mac::Mac;
+    use crate::net::ieee802154::{Header, MacAddress};
+    use crate::net::ipv6::ipv6_send::{IP6SendStruct, IP6Sender};
+    use crate::net::ipv6::{IP6Header, IP6Packet, IPPayload, TransportHeader};
+    use crate::net::network_capabilities::{
+        AddrRange, IpVisibilityCapability, PortRange, UdpVisibilityCapability,
+    };
+    use crate::net::sixlowpan::sixlowpan_compression::{self, Context};
+    use crate::net::sixlowpan::sixlowpan_state::{Sixlowpan, SixlowpanState, TxState};
+    use crate::net::udp::udp_send::{MuxUdpSender, UDPSendStruct};
+    use crate::net::udp::UDPHeader;
+    use crate::test::mock_alarm::MockAlarm;
+    use crate::test::mock_process::{self, Capability, MockProcess};
+    use core::cell::RefCell;
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::radio;
+    use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM};
+    use kernel::hil::time::Alarm;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const SRC_MAC: MacAddress = MacAddress::Short(0x0001);
+    const DST_MAC: MacAddress = MacAddress::Short(0x0002);
+
+    /// A MAC that records the frames it transmits and holds each until the
+    /// test completes it.
+    struct MockMac {
+        sent: RefCell<Vec<Vec<u8>>>,
+        tx: TakeCell<'static, [u8]>,
+        tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    }
+
+    impl MockMac {
+        fn new() -> MockMac {
+            MockMac {
+                sent: RefCell::new(Vec::new()),
+                tx: TakeCell::empty(),
+                tx_client: OptionalCell::empty(),
+            }
+        }
+
+        /// Finish the transmission in progress.
+        fn complete(&self) {
+            let buf = self.tx.take().expect("no transmission in progress");
+            self.tx_client
+                .map(move |client| client.send_done(buf, true, Ok(())));
+        }
+    }
+
+    impl Mac for MockMac {
+        fn initialize(&self, _mac_buf: &'static mut [u8]) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn set_config_client(&self, _client: &'static dyn radio::ConfigClient) {}
+
+        fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+            self.tx_client.set(client);
+        }
+
+        fn set_receive_client(&self, _client: &'static dyn radio::RxClient) {}
+
+        fn set_receive_buffer(&self, _buffer: &'static mut [u8]) {}
+
+        fn get_address(&self) -> u16 {
+            0x0001
+        }
+
+        fn get_address_long(&self) -> [u8; 8] {
+            [0; 8]
+        }
+
+        fn get_pan(&self) -> u16 {
+            0xABCD
+        }
+
+        fn set_address(&self, _addr: u16) {}
+
+        fn set_address_long(&self, _addr: [u8; 8]) {}
+
+        fn set_pan(&self, _id: u16) {}
+
+        fn set_ack_frame_pending(&self, _pending: bool) {}
+
+        fn config_commit(&self) {}
+
+        fn is_on(&self) -> bool {
+            true
+        }
+
+        fn transmit(
+            &self,
+            full_mac_frame: &'static mut [u8],
+            frame_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            let frame = &full_mac_frame[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len];
+            self.sent.borrow_mut().push(frame.to_vec());
+            self.tx.replace(full_mac_frame);
+            Ok(())
+        }
+    }
+
+    /// Encryption is never used, since frames are sent unsecured.
+    struct MockCcm;
+
+    impl<'a> AES128CCM<'a> for MockCcm {
+        fn set_client(&'a self, _client: &'a dyn CCMClient) {}
+
+        fn set_key(&self, _key: &[u8]) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn set_nonce(&self, _nonce: &[u8]) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn crypt(
+            &self,
+            buf: &'static mut [u8],
+            _a_off: usize,
+            _m_off: usize,
+            _m_len: usize,
+            _mic_len: usize,
+            _confidential: bool,
+            _encrypting: bool,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            Err((ErrorCode::NOSUPPORT, buf))
+        }
+    }
+
+    struct UdpTest {
+        driver: &'static UDPDriver<'static>,
+        mac: &'static MockMac,
+        alarm: &'static MockAlarm<'static>,
+        ctx: Context,
+        interfaces: &'static [IPAddr],
+        apps: Vec<&'static MockProcess>,
+    }
+
+    impl UdpTest {
+        /// A driver for processes `names` sending over 6LoWPAN to a mock
+        /// MAC.
+        fn new(names: &[&'static str]) -> UdpTest {
+            let (kernel, apps) = mock_process::kernel(names);
+            let mac = mock_process::leak(MockMac::new());
+            let alarm: &MockAlarm = mock_process::leak(MockAlarm::new());
+            let framer = mock_process::leak(Framer::new(mac, mock_process::leak(MockCcm)));
+            mac.set_transmit_client(framer);
+
+            let ctx = Context {
+                prefix: [0; 16],
+                prefix_len: 0,
+                id: 0,
+                compress: false,
+            };
+            let sixlowpan = mock_process::leak(Sixlowpan::new(ctx, alarm));
+            let ip_vis = mock_process::leak(IpVisibilityCapability::new(&Capability));
+            let udp_vis = mock_process::leak(UdpVisibilityCapability::new(&Capability));
+            let ip6_packet = Box::leak(Box::new(IP6Packet::new(IPPayload::new(
+                TransportHeader::UDP(UDPHeader::new()),
+                mock_process::buffer(200),
+            ))));
+            let ip_send = mock_process::leak(IP6SendStruct::new(
+                ip6_packet,
+                alarm,
+                mock_process::buffer(radio::MAX_BUF_SIZE),
+                TxState::new(sixlowpan as &dyn SixlowpanState),
+                framer,
+                DST_MAC,
+                SRC_MAC,
+                ip_vis,
+            ));
+            alarm.set_alarm_client(ip_send);
+            framer.set_transmit_client(ip_send);
+
+            let mut local = IPAddr::new();
+            local.0 = [0xFE, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01];
+            let interfaces = mock_process::leak([local]);
+            ip_send.set_addr(local);
+
+            let udp_mux: &MuxUdpSender<IP6SendStruct<MockAlarm>> =
+                mock_process::leak(MuxUdpSender::new(ip_send));
+            ip_send.set_client(udp_mux);
+            let udp_send = mock_process::leak(UDPSendStruct::new(udp_mux, udp_vis));
+            let port_table = mock_process::leak(UdpPortManager::new(
+                &Capability,
+                Box::leak(Box::new([None; 16])),
+                udp_vis,
+            ));
+            let net_cap = mock_process::leak(NetworkCapability::new(
+                AddrRange::Any,
+                PortRange::Any,
+                PortRange::Any,
+                &Capability,
+            ));
+            let driver = mock_process::leak(UDPDriver::new(
+                udp_send,
+                mock_process::grant(kernel),
+                interfaces,
+                200,
+                port_table,
+                LeasableBuffer::new(mock_process::buffer(200)),
+                &Capability,
+                net_cap,
+            ));
+            udp_send.set_client(driver);
+            port_table.set_user_ports(driver, &Capability);
+
+            UdpTest {
+                driver: driver,
+                mac: mac,
+                alarm: alarm,
+                ctx: ctx,
+                interfaces: interfaces,
+                apps: apps,
+            }
+        }
+
+        /// Encode `addr` and `port` as an endpoint in a configuration
+        /// buffer.
+        fn endpoint(addr: IPAddr, port: u16) -> Vec<u8> {
+            let mut endpoint = addr.0.to_vec();
+            endpoint.extend_from_slice(&port.to_le_bytes());
+            endpoint
+        }
+
+        /// Bind app `index` to `port` of the local interface and send a
+        /// datagram from it, returning the IPv6 header the MAC was given.
+        fn send(&self, index: usize, port: u16) -> IP6Header {
+            let app = self.apps[index];
+            let id = app.processid();
+            let local = Self::endpoint(self.interfaces[0], port);
+            let mut remote_addr = self.interfaces[0];
+            remote_addr.0[15] = 0x02;
+            let remote = Self::endpoint(remote_addr, 5683);
+
+            let mut rx_cfg = local.clone();
+            rx_cfg.extend_from_slice(&local);
+            let mut cfg = local.clone();
+            cfg.extend_from_slice(&remote);
+            assert!(self
+                .driver
+                .allow_readwrite(id, 2, app.readwrite_slice(&rx_cfg))
+                .is_ok());
+            assert!(self.driver.command(3, 0, 0, id).is_success());
+            assert!(self
+                .driver
+                .allow_readwrite(id, 1, app.readwrite_slice(&cfg))
+                .is_ok());
+            assert!(self
+                .driver
+                .allow_readonly(id, 0, app.readonly_slice(b"hello"))
+                .is_ok());
+            let _ = self.driver.subscribe(1, app.upcall(DRIVER_NUM, 1), id);
+
+            assert_eq!(self.driver.command(2, 0, 0, id).get_success_u32(), Some(1));
+            let header = self.sent_header();
+
+            // Let the send complete so the next datagram can go out.
+            self.mac.complete();
+            assert!(self.alarm.fire().is_some());
+            assert_eq!(app.take_upcalls(), [(1, 0, 0, 0)]);
+            header
+        }
+
+        /// Decompress the IPv6 header of the one frame the MAC sent.
+        fn sent_header(&self) -> IP6Header {
+            let frames: Vec<Vec<u8>> = self.mac.sent.borrow_mut().drain(..).collect();
+            assert_eq!(frames.len(), 1);
+            let frame = &frames[0];
+            let (data_offset, _) = Header::decode(frame, false).done().unwrap();
+            let mut packet = [0; 200];
+            sixlowpan_compression::decompress(
+                &self.ctx,
+                &frame[data_offset..],
+                SRC_MAC,
+                DST_MAC,
+                &mut packet,
+                0,
+                false,
+            )
+            .unwrap();
+            IP6Header::decode(&packet).done().unwrap().1
+        }
+    }
+
+    #[test]
+    fn test_traffic_class_defaults_to_zero() {
+        let test = UdpTest::new(&["app"]);
+        assert_eq!(test.send(0, 1000).get_traffic_class(), 0);
+    }
+
+    #[test]
+    fn test_traffic_class_set_per_app() {
+        let test = UdpTest::new(&["voice", "bulk"]);
+        let voice = test.apps[0].processid();
+
+        // Expedited forwarding (DSCP 46) with ECN capable transport.
+        assert!(test.driver.command(5, 0xBA, 0, voice).is_success());
+        assert_eq!(
+            test.driver.command(5, 0x100, 0, voice).get_failure(),
+            Some(ErrorCode::INVAL)
+        );
+
+        let header = test.send(0, 1000);
+        assert_eq!(header.get_traffic_class(), 0xBA);
+        assert_eq!(header.get_dscp(), 46);
+        assert_eq!(header.get_ecn(), 0b10);
+
+        // The other app still sends with the default class.
+        assert_eq!(test.send(1, 1001).get_traffic_class(), 0);
+        assert_eq!(test.send(0, 1002).get_traffic_class(), 0xBA);
+    }
+}
@@ -59,6 +59,7 @@ impl<'a, T: IP6Sender<'a>> MuxUdpSender<'a, T> {
         if list_empty {
             ret = match caller.tx_buffer.take() {
                 Some(buf) => {
+                    self.ip_sender.set_traffic_class(caller.traffic_class.get());
                     let ret = self
                         .ip_sender
                         .send_to(dest, transport_header, &buf, net_cap);
@@ -110,6 +111,8 @@ impl<'a, T: IP6Sender<'a>> IP6SendClient for MuxUdpSender<'a, T> {
                     Some(buf) => match next_sender.next_th.take() {
                         Some(th) => match next_sender.net_cap.take() {
                             Some(net_cap) => {
+                                self.ip_sender
+                                    .set_traffic_class(next_sender.traffic_class.get());
                                 let ret = self.ip_sender.send_to(
                                     next_sender.next_dest.get(),
                                     th,
@@ -232,6 +235,14 @@ pub trait UDPSender<'a> {
     fn is_bound(&self) -> bool;
 
     fn set_binding(&self, binding: UdpPortBindingTx) -> Option<UdpPortBindingTx>;
+
+    /// This function sets the IPv6 traffic class (DSCP and ECN) of datagrams
+    /// sent by this `UDPSender`. It should not be changed while a datagram is
+    /// queued. The default is 0 (best effort).
+    ///
+    /// # Arguments
+    /// `traffic_class` - Traffic class byte placed in the IPv6 header
+    fn set_traffic_class(&self, traffic_class: u8);
 }
 
 /// This is a specific instantiation of the `UDPSender` trait. Note
@@ -247,6 +258,7 @@ pub struct UDPSendStruct<'a, T: IP6Sender<'a>> {
     binding: MapCell<UdpPortBindingTx>,
     udp_vis: &'static UdpVisibilityCapability,
     net_cap: OptionalCell<&'static NetworkCapability>,
+    traffic_class: Cell<u8>,
 }
 
 impl<'a, T: IP6Sender<'a>> ListNode<'a, UDPSendStruct<'a, T>> for UDPSendStruct<'a, T> {
@@ -339,6 +351,10 @@ impl<'a, T: IP6Sender<'a>> UDPSender<'a> for UDPSendStruct<'a, T> {
     fn set_binding(&self, binding: UdpPortBindingTx) -> Option<UdpPortBindingTx> {
         self.binding.replace(binding)
     }
+
+    fn set_traffic_class(&self, traffic_class: u8) {
+        self.traffic_class.set(traffic_class);
+    }
 }
 
 impl<'a, T: IP6Sender<'a>> UDPSendStruct<'a, T> {
@@ -356,6 +372,7 @@ impl<'a, T: IP6Sender<'a>> UDPSendStruct<'a, T> {
             binding: MapCell::empty(),
             udp_vis: udp_vis,
             net_cap: OptionalCell::empty(),
+            traffic_class: Cell::new(0),
         }
     }
 }
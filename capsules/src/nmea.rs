@@ -0,0 +1,339 @@
+//! Position from a GNSS module that reports NMEA 0183 sentences.
+//!
+//! GNSS (GPS, Galileo, GLONASS, ...) modules usually stream NMEA sentences
+//! over a UART, several times a second. This capsule reads that stream,
+//! checks each sentence, and parses RMC and GGA sentences from any talker,
+//! so that processes get their position without handling every character.
+//!
+//! - RMC gives the UTC date and time, whether the fix is valid, and the
+//!   latitude and longitude.
+//! - GGA gives the UTC time, the quality of the fix, the number of
+//!   satellites used, the latitude and longitude, and the altitude above
+//!   mean sea level.
+//!
+//! Latitudes and longitudes are in millionths of a degree, positive to the
+//! north and east. Altitudes are in centimeters.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let gnss_uart = static_init!(UartDevice, UartDevice::new(uart_mux, false));
+//! gnss_uart.setup();
+//! let nmea = static_init!(
+//!     capsules::nmea::Nmea<'static, UartDevice>,
+//!     capsules::nmea::Nmea::new(
+//!         gnss_uart,
+//!         &mut capsules::nmea::RX_BUF,
+//!         &mut capsules::nmea::SENTENCE_BUF,
+//!         board_kernel.create_grant(&memory_allocation_capability)
+//!     )
+//! );
+//! hil::uart::Receive::set_receive_client(gnss_uart, nmea);
+//! nmea.start();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: Position update, after each RMC or GGA sentence. The arguments are
+//!   the fix quality, the latitude, and the longitude (as signed 32-bit
+//!   values).
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get the fix quality and the number of satellites used. The fix
+//!   quality is `0` without a fix, `1` with a GNSS fix, `2` with a
+//!   differential fix, and higher values as in GGA sentences.
+//! - `2`: Get the latitude and the longitude.
+//! - `3`: Get the altitude.
+//! - `4`: Get the UTC time as `hhmmss`, and the date as `ddmmyy`, in
+//!   decimal.
+
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::TakeCell;
+use kernel::hil::uart;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Gnss as usize;
+
+/// The longest NMEA sentence, including the line ending.
+pub const MAX_SENTENCE_LENGTH: usize = 82;
+
+pub static mut RX_BUF: [u8; 1] = [0; 1];
+pub static mut SENTENCE_BUF: [u8; MAX_SENTENCE_LENGTH] = [0; MAX_SENTENCE_LENGTH];
+
+#[derive(Copy, Clone, Default)]
+struct Position {
+    fix: u8,
+    satellites: u8,
+    latitude: i32,
+    longitude: i32,
+    altitude: i32,
+    time: u32,
+    date: u32,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+}
+
+pub struct Nmea<'a, U: uart::Receive<'a>> {
+    uart: &'a U,
+    rx_buffer: TakeCell<'static, [u8]>,
+    sentence: TakeCell<'static, [u8]>,
+    sentence_len: Cell<usize>,
+    position: Cell<Position>,
+    apps: Grant<App>,
+}
+
+/// Parse a decimal number with an optional sign and fraction, as an integer
+/// in units of `10^-decimals`. Extra decimals are truncated.
+fn parse_fixed(field: &[u8], decimals: u32) -> Option<i64> {
+    let (negative, digits) = match field.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        Some(_) => (false, field),
+        None => return None,
+    };
+    let mut value: i64 = 0;
+    let mut fraction = None;
+    for c in digits {
+        match (c, fraction) {
+            (b'.', None) => fraction = Some(0),
+            (b'0'..=b'9', Some(n)) if n >= decimals => {}
+            (b'0'..=b'9', _) => {
+                value = value.checked_mul(10)? + (c - b'0') as i64;
+                fraction = fraction.map(|n| n + 1);
+            }
+            _ => return None,
+        }
+    }
+    for _ in fraction.unwrap_or(0)..decimals {
+        value = value.checked_mul(10)?;
+    }
+    Some(if negative { -value } else { value })
+}
+
+/// Parse a latitude or longitude of the form `dddmm.mmmm`, with
+/// `degree_digits` digits of degrees, and its hemisphere, in millionths of a
+/// degree.
+fn parse_coordinate(field: &[u8], hemisphere: &[u8], degree_digits: usize) -> Option<i32> {
+    if field.len() <= degree_digits {
+        return None;
+    }
+    let degrees = parse_fixed(&field[..degree_digits], 0)?;
+    let minutes = parse_fixed(&field[degree_digits..], 6)?;
+    let value = (degrees * 1_000_000 + minutes / 60) as i32;
+    match hemisphere {
+        b"N" | b"E" => Some(value),
+        b"S" | b"W" => Some(-value),
+        _ => None,
+    }
+}
+
+/// Check the checksum of `sentence`, `$...*hh` without the line ending, and
+/// return its body between `$` and `*`.
+fn checked_body(sentence: &[u8]) -> Option<&[u8]> {
+    let star = sentence.iter().position(|c| *c == b'*')?;
+    if sentence.first() != Some(&b'$') || sentence.len() != star + 3 {
+        return None;
+    }
+    let body = &sentence[1..star];
+    let checksum = body.iter().fold(0, |sum, c| sum ^ c);
+    let hex = core::str::from_utf8(&sentence[(star + 1)..]).ok()?;
+    if u8::from_str_radix(hex, 16).ok()? == checksum {
+        Some(body)
+    } else {
+        None
+    }
+}
+
+impl<'a, U: uart::Receive<'a>> Nmea<'a, U> {
+    pub fn new(
+        uart: &'a U,
+        rx_buffer: &'static mut [u8],
+        sentence: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> Nmea<'a, U> {
+        Nmea {
+            uart: uart,
+            rx_buffer: TakeCell::new(rx_buffer),
+            sentence: TakeCell::new(sentence),
+            sentence_len: Cell::new(0),
+            position: Cell::new(Position::default()),
+            apps: grant,
+        }
+    }
+
+    /// Start reading sentences from the module.
+    pub fn start(&self) {
+        self.rx_buffer.take().map(|buffer| {
+            if let Err((_, buffer)) = self.uart.receive_buffer(buffer, 1) {
+                self.rx_buffer.replace(buffer);
+            }
+        });
+    }
+
+    /// Add a received character to the sentence, and handle the sentence once
+    /// its line ends.
+    fn received(&self, c: u8) {
+        self.sentence.map(|sentence| {
+            let len = self.sentence_len.get();
+            match c {
+                // Every sentence starts with `$`, so resynchronize on it.
+                b'$' => {
+                    sentence[0] = c;
+                    self.sentence_len.set(1);
+                }
+                b'\r' | b'\n' => {
+                    if len > 0 {
+                        self.parse(&sentence[..len]);
+                    }
+                    self.sentence_len.set(0);
+                }
+                _ if len > 0 && len < sentence.len() => {
+                    sentence[len] = c;
+                    self.sentence_len.set(len + 1);
+                }
+                // Drop characters outside sentences, and sentences that are
+                // too long.
+                _ => self.sentence_len.set(0),
+            }
+        });
+    }
+
+    fn parse(&self, sentence: &[u8]) {
+        let body = match checked_body(sentence) {
+            Some(body) => body,
+            None => return,
+        };
+        let mut fields = [&body[..0]; 15];
+        for (field, value) in fields.iter_mut().zip(body.split(|c| *c == b',')) {
+            *field = value;
+        }
+        // The talker, e.g. `GP` or `GN`, does not matter.
+        if fields[0].len() != 5 {
+            return;
+        }
+        let mut position = self.position.get();
+        let updated = match &fields[0][2..] {
+            b"RMC" => self.parse_rmc(&fields, &mut position),
+            b"GGA" => self.parse_gga(&fields, &mut position),
+            _ => None,
+        };
+        if updated.is_some() {
+            self.position.set(position);
+            self.apps.each(|_, app| {
+                app.callback.schedule(
+                    position.fix as usize,
+                    position.latitude as usize,
+                    position.longitude as usize,
+                );
+            });
+        }
+    }
+
+    /// `$--RMC,hhmmss.ss,A,llll.ll,a,yyyyy.yy,a,x.x,x.x,ddmmyy,x.x,a*hh`
+    fn parse_rmc(&self, fields: &[&[u8]; 15], position: &mut Position) -> Option<()> {
+        let time = parse_fixed(fields[1], 0)? as u32;
+        let date = parse_fixed(fields[9], 0)? as u32;
+        if fields[2] != b"A" {
+            position.fix = 0;
+            position.time = time;
+            position.date = date;
+            return Some(());
+        }
+        position.latitude = parse_coordinate(fields[3], fields[4], 2)?;
+        position.longitude = parse_coordinate(fields[5], fields[6], 3)?;
+        // Keep the fix quality from GGA, which is more precise.
+        if position.fix == 0 {
+            position.fix = 1;
+        }
+        position.time = time;
+        position.date = date;
+        Some(())
+    }
+
+    /// `$--GGA,hhmmss.ss,llll.ll,a,yyyyy.yy,a,x,xx,x.x,x.x,M,x.x,M,x.x,xxxx*hh`
+    fn parse_gga(&self, fields: &[&[u8]; 15], position: &mut Position) -> Option<()> {
+        let time = parse_fixed(fields[1], 0)? as u32;
+        let fix = parse_fixed(fields[6], 0)? as u8;
+        position.time = time;
+        position.fix = fix;
+        position.satellites = parse_fixed(fields[7], 0).unwrap_or(0) as u8;
+        if fix == 0 {
+            return Some(());
+        }
+        position.latitude = parse_coordinate(fields[2], fields[3], 2)?;
+        position.longitude = parse_coordinate(fields[4], fields[5], 3)?;
+        position.altitude = parse_fixed(fields[9], 2)? as i32;
+        Some(())
+    }
+}
+
+impl<'a, U: uart::Receive<'a>> uart::ReceiveClient for Nmea<'a, U> {
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: uart::Error,
+    ) {
+        if rval.is_ok() && rx_len == 1 {
+            self.received(buffer[0]);
+        } else {
+            // Drop the sentence a character may be missing from.
+            self.sentence_len.set(0);
+        }
+        if let Err((_, buffer)) = self.uart.receive_buffer(buffer, 1) {
+            self.rx_buffer.replace(buffer);
+        }
+    }
+}
+
+impl<'a, U: uart::Receive<'a>> Driver for Nmea<'a, U> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(&self, command_num: usize, _: usize, _: usize, _: ProcessId) -> CommandReturn {
+        let position = self.position.get();
+        match command_num {
+            0 => CommandReturn::success(),
+            1 => CommandReturn::success_u32_u32(position.fix as u32, position.satellites as u32),
+            2 => {
+                CommandReturn::success_u32_u32(position.latitude as u32, position.longitude as u32)
+            }
+            3 => CommandReturn::success_u32(position.altitude as u32),
+            4 => CommandReturn::success_u32_u32(position.time, position.date),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -1,9 +1,8 @@
 //! This provides kernel and userspace access to nonvolatile memory.
 //!
-//! This is an initial implementation that does not provide safety for
-//! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! By default, each application has full access to the entire memory space
+//! that has been provided to userland. The board can instead give each
+//! application a region of its own, see "Per-process regions" below.
 //!
 //! However, the kernel accessible memory does not have to be the same range
 //! as the userspace accessible address space. The kernel memory can overlap
@@ -53,6 +52,45 @@
 //!         &mut capsules::nonvolatile_storage_driver::BUFFER));
 //! hil::nonvolatile_storage::NonvolatileStorage::set_client(fm25cl, nonvolatile_storage);
 //! ```
+//!
+//! Masked updates
+//! --------------
+//!
+//! To change a few bits of stored data, userspace can issue a masked update
+//! instead of reading the data, modifying it, and writing it back. The app
+//! shares a write buffer holding the new values of the bytes to update,
+//! followed by as many mask bytes. The capsule reads the affected bytes,
+//! replaces the bits selected by the mask with the given values, and writes
+//! the bytes back. No other read or write runs between the read and the
+//! write, so the update is atomic from the point of view of applications.
+//!
+//! The bytes are written back with a plain `write()`, which may set bits from
+//! 0 to 1. As for any write, the underlying storage driver must handle that,
+//! for example by erasing and rewriting whole pages as
+//! `nonvolatile_to_pages` does on flash.
+//!
+//! Per-process regions
+//! -------------------
+//!
+//! If the board calls `enable_regions()`, applications no longer share the
+//! userspace memory. Each application instead allocates a region of its own,
+//! and reads, writes and masked updates use offsets within that region and
+//! cannot reach past it. Regions are keyed by the short ID of the application,
+//! so an application finds its region again after a reboot or an update.
+//! Applications without a fixed short ID cannot allocate a region.
+//!
+//! The regions are recorded in a header of `REGIONS_HEADER_LEN` bytes at the
+//! start of the userspace memory: a magic number, then the short ID, offset
+//! and length of up to `MAX_REGIONS` regions, all little-endian `u32`s. If the
+//! magic number does not match, there are no regions yet. Regions are
+//! allocated one after the other and are never freed, but an application can
+//! erase the content of its own region.
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! nonvolatile_storage.enable_regions();
+//! ```
 
 use core::cell::Cell;
 use core::cmp;
@@ -62,7 +100,7 @@ use kernel::hil;
 use kernel::ErrorCode;
 use kernel::{
     CommandReturn, Driver, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice,
-    Upcall,
+    ShortID, Upcall,
 };
 
 /// Syscall driver number.
@@ -71,18 +109,60 @@ pub const DRIVER_NUM: usize = driver::NUM::NvmStorage as usize;
 
 pub static mut BUFFER: [u8; 512] = [0; 512];
 
+/// The most regions the header can record.
+pub const MAX_REGIONS: usize = 8;
+
+/// Length of the header at the start of the userspace memory that records
+/// the regions of applications.
+pub const REGIONS_HEADER_LEN: usize = 4 + MAX_REGIONS * 12;
+
+/// Marks a valid regions header, "NVRG".
+const REGIONS_MAGIC: u32 = 0x4752_564e;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
     UserspaceWrite,
+    UserspaceMaskedUpdate,
+    UserspaceAllocate,
+    UserspaceErase,
     KernelRead,
     KernelWrite,
 }
 
+/// Replace the bits of `bytes` selected by `mask` with the bits of `value`.
+/// `value[i]` and `mask[i]` apply to `bytes[i]`.
+pub fn apply_mask(bytes: &mut [u8], value: &[u8], mask: &[u8]) {
+    for ((byte, value), mask) in bytes.iter_mut().zip(value).zip(mask) {
+        *byte = (*byte & !mask) | (value & mask);
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum NonvolatileUser {
-    App { app_id: ProcessId },
+    App {
+        app_id: ProcessId,
+    },
     Kernel,
+    /// The capsule itself, reading the regions header.
+    Regions,
+}
+
+/// Whether applications share the userspace memory or each have a region.
+#[derive(Clone, Copy, PartialEq)]
+enum RegionsState {
+    Shared,
+    Loading,
+    Ready,
+}
+
+/// The region of one application. A short ID of 0 marks an unused entry.
+/// The offset is from the end of the regions header.
+#[derive(Clone, Copy, Default)]
+struct Region {
+    short_id: u32,
+    offset: usize,
+    length: usize,
 }
 
 pub struct App {
@@ -121,6 +201,14 @@ pub struct NonvolatileStorage<'a> {
     buffer: TakeCell<'static, [u8]>,
     // What issued the currently executing call. This can be an app or the kernel.
     current_user: OptionalCell<NonvolatileUser>,
+    // The physical address of the masked update whose bytes are being read.
+    current_masked_update: OptionalCell<usize>,
+    // The next offset to erase and the end of the erase in progress.
+    current_erase: OptionalCell<(usize, usize)>,
+
+    // Whether applications have regions of their own, and the regions.
+    regions_state: Cell<RegionsState>,
+    regions: Cell<[Region; MAX_REGIONS]>,
 
     // The first byte that is accessible from userspace.
     userspace_start_address: usize,
@@ -162,6 +250,10 @@ impl<'a> NonvolatileStorage<'a> {
             apps: grant,
             buffer: TakeCell::new(buffer),
             current_user: OptionalCell::empty(),
+            current_masked_update: OptionalCell::empty(),
+            current_erase: OptionalCell::empty(),
+            regions_state: Cell::new(RegionsState::Shared),
+            regions: Cell::new([Region::default(); MAX_REGIONS]),
             userspace_start_address: userspace_start_address,
             userspace_length: userspace_length,
             kernel_start_address: kernel_start_address,
@@ -175,6 +267,129 @@ impl<'a> NonvolatileStorage<'a> {
         }
     }
 
+    /// Give each application a region of its own instead of sharing the
+    /// userspace memory. This reads the regions header; applications get
+    /// `BUSY` until it is read.
+    pub fn enable_regions(&self) -> Result<(), ErrorCode> {
+        if self.userspace_length < REGIONS_HEADER_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        if self.current_user.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                if buffer.len() < REGIONS_HEADER_LEN {
+                    self.buffer.replace(buffer);
+                    return Err(ErrorCode::SIZE);
+                }
+                self.current_user.set(NonvolatileUser::Regions);
+                self.regions_state.set(RegionsState::Loading);
+                self.driver
+                    .read(buffer, self.userspace_start_address, REGIONS_HEADER_LEN)
+                    .map_err(|e| {
+                        self.current_user.clear();
+                        self.regions_state.set(RegionsState::Shared);
+                        e
+                    })
+            })
+    }
+
+    /// Fill the table of regions from the header in `buffer`.
+    fn load_regions(&self, buffer: &[u8]) {
+        let word = |i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&buffer[i * 4..i * 4 + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let mut regions = [Region::default(); MAX_REGIONS];
+        if buffer.len() >= REGIONS_HEADER_LEN && word(0) == REGIONS_MAGIC {
+            let space = self.userspace_length - REGIONS_HEADER_LEN;
+            for (i, region) in regions.iter_mut().enumerate() {
+                let offset = word(1 + i * 3 + 1) as usize;
+                let length = word(1 + i * 3 + 2) as usize;
+                // Ignore entries that do not fit, in case the userspace
+                // memory shrank.
+                if offset <= space && length <= space - offset {
+                    region.short_id = word(1 + i * 3);
+                    region.offset = offset;
+                    region.length = length;
+                }
+            }
+        }
+        self.regions.set(regions);
+    }
+
+    /// Write the header recording the table of regions to `buffer`.
+    fn store_regions(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(&REGIONS_MAGIC.to_le_bytes());
+        for (i, region) in self.regions.get().iter().enumerate() {
+            let entry = &mut buffer[4 + i * 12..4 + i * 12 + 12];
+            entry[0..4].copy_from_slice(&region.short_id.to_le_bytes());
+            entry[4..8].copy_from_slice(&(region.offset as u32).to_le_bytes());
+            entry[8..12].copy_from_slice(&(region.length as u32).to_le_bytes());
+        }
+    }
+
+    /// Returns the index and region of `appid`, if it has one.
+    fn region_of(&self, appid: ProcessId) -> Option<(usize, Region)> {
+        match appid.short_app_id() {
+            ShortID::Fixed(id) => self
+                .regions
+                .get()
+                .iter()
+                .position(|region| region.short_id == id.get())
+                .map(|index| (index, self.regions.get()[index])),
+            ShortID::LocallyUnique => None,
+        }
+    }
+
+    /// Add a region of `length` bytes for `appid` after the existing ones,
+    /// and return its index.
+    fn allocate_region(&self, appid: ProcessId, length: usize) -> Result<usize, ErrorCode> {
+        let short_id = match appid.short_app_id() {
+            ShortID::Fixed(id) => id.get(),
+            ShortID::LocallyUnique => return Err(ErrorCode::NOSUPPORT),
+        };
+        if self.region_of(appid).is_some() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let mut regions = self.regions.get();
+        let index = regions
+            .iter()
+            .position(|region| region.short_id == 0)
+            .ok_or(ErrorCode::NOMEM)?;
+        let offset = regions
+            .iter()
+            .map(|region| region.offset + region.length)
+            .max()
+            .unwrap_or(0);
+        if length > self.userspace_length - REGIONS_HEADER_LEN - offset {
+            return Err(ErrorCode::NOMEM);
+        }
+        regions[index] = Region {
+            short_id: short_id,
+            offset: offset,
+            length: length,
+        };
+        self.regions.set(regions);
+        Ok(index)
+    }
+
+    /// Returns the offset in the userspace memory and the length of the
+    /// memory that `app_id` can access.
+    fn userspace_bounds(&self, app_id: Option<ProcessId>) -> Result<(usize, usize), ErrorCode> {
+        match self.regions_state.get() {
+            RegionsState::Shared => Ok((0, self.userspace_length)),
+            RegionsState::Loading => Err(ErrorCode::BUSY),
+            RegionsState::Ready => app_id
+                .and_then(|appid| self.region_of(appid))
+                .map(|(_, region)| (REGIONS_HEADER_LEN + region.offset, region.length))
+                .ok_or(ErrorCode::RESERVE),
+        }
+    }
+
     // Check so see if we are doing something. If not, go ahead and do this
     // command. If so, this is queued and will be run when the pending
     // command completes.
@@ -186,17 +401,21 @@ impl<'a> NonvolatileStorage<'a> {
         app_id: Option<ProcessId>,
     ) -> Result<(), ErrorCode> {
         // Do bounds check.
-        match command {
-            NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
+        let offset = match command {
+            NonvolatileCommand::UserspaceRead
+            | NonvolatileCommand::UserspaceWrite
+            | NonvolatileCommand::UserspaceMaskedUpdate => {
                 // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length
-                    || length > self.userspace_length
-                    || offset + length > self.userspace_length
-                {
+                // is offset in the physical memory. With per-process
+                // regions, address 0 is the start of the app's region.
+                let (base, limit) = self.userspace_bounds(app_id)?;
+                if offset >= limit || length > limit || offset + length > limit {
                     return Err(ErrorCode::INVAL);
                 }
+                base + offset
             }
+            // The region was checked when the command was issued.
+            NonvolatileCommand::UserspaceAllocate | NonvolatileCommand::UserspaceErase => offset,
             NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
                 // Because the kernel uses the NonvolatileStorage interface,
                 // its calls are absolute addresses.
@@ -207,20 +426,33 @@ impl<'a> NonvolatileStorage<'a> {
                 {
                     return Err(ErrorCode::INVAL);
                 }
+                offset
             }
-        }
+        };
 
         // Do very different actions if this is a call from userspace
         // or from the kernel.
         match command {
-            NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
+            NonvolatileCommand::UserspaceRead
+            | NonvolatileCommand::UserspaceWrite
+            | NonvolatileCommand::UserspaceMaskedUpdate
+            | NonvolatileCommand::UserspaceAllocate
+            | NonvolatileCommand::UserspaceErase => {
                 app_id.map_or(Err(ErrorCode::FAIL), |appid| {
                     self.apps
                         .enter(appid, |app| {
                             // Get the length of the correct allowed buffer.
+                            // Masked updates take their values and masks from
+                            // the write buffer. Allocations and erases do not
+                            // use an allowed buffer.
                             let allow_buf_len = match command {
                                 NonvolatileCommand::UserspaceRead => app.buffer_read.len(),
                                 NonvolatileCommand::UserspaceWrite => app.buffer_write.len(),
+                                NonvolatileCommand::UserspaceMaskedUpdate => {
+                                    app.buffer_write.len() / 2
+                                }
+                                NonvolatileCommand::UserspaceAllocate
+                                | NonvolatileCommand::UserspaceErase => length,
                                 _ => 0,
                             };
 
@@ -229,6 +461,13 @@ impl<'a> NonvolatileStorage<'a> {
                                 return Err(ErrorCode::RESERVE);
                             }
 
+                            // A masked update cannot be shortened.
+                            if command == NonvolatileCommand::UserspaceMaskedUpdate
+                                && length > allow_buf_len
+                            {
+                                return Err(ErrorCode::SIZE);
+                            }
+
                             // Shorten the length if the application gave us nowhere to
                             // put it.
                             let active_len = cmp::min(length, allow_buf_len);
@@ -261,6 +500,10 @@ impl<'a> NonvolatileStorage<'a> {
                                 }
 
                                 self.userspace_call_driver(command, offset, active_len)
+                                    .map_err(|e| {
+                                        self.current_user.clear();
+                                        e
+                                    })
                             } else {
                                 // Some app is using the storage, we must wait.
                                 if app.pending_command == true {
@@ -342,6 +585,57 @@ impl<'a> NonvolatileStorage<'a> {
                     NonvolatileCommand::UserspaceWrite => {
                         self.driver.write(buffer, physical_address, active_len)
                     }
+                    NonvolatileCommand::UserspaceMaskedUpdate => {
+                        // Read the affected bytes first, the update is
+                        // applied and written back in `read_done()`.
+                        if active_len < length {
+                            self.buffer.replace(buffer);
+                            Err(ErrorCode::SIZE)
+                        } else {
+                            self.current_masked_update.set(physical_address);
+                            self.driver.read(buffer, physical_address, active_len)
+                        }
+                    }
+                    NonvolatileCommand::UserspaceAllocate => {
+                        // Add the region to the table, and write the
+                        // header. `length` is the size of the region.
+                        let allocated =
+                            self.current_user
+                                .extract()
+                                .map_or(Err(ErrorCode::FAIL), |user| match user {
+                                    NonvolatileUser::App { app_id } => {
+                                        self.allocate_region(app_id, length)
+                                    }
+                                    _ => Err(ErrorCode::FAIL),
+                                });
+                        match allocated {
+                            Ok(index) => {
+                                self.store_regions(buffer);
+                                self.driver
+                                    .write(buffer, self.userspace_start_address, REGIONS_HEADER_LEN)
+                                    .map_err(|e| {
+                                        let mut regions = self.regions.get();
+                                        regions[index] = Region::default();
+                                        self.regions.set(regions);
+                                        e
+                                    })
+                            }
+                            Err(e) => {
+                                self.buffer.replace(buffer);
+                                Err(e)
+                            }
+                        }
+                    }
+                    NonvolatileCommand::UserspaceErase => {
+                        // Write the first chunk of erased bytes, the others
+                        // are written in `write_done()`.
+                        for byte in buffer.iter_mut() {
+                            *byte = 0xff;
+                        }
+                        self.current_erase
+                            .set((offset + active_len, offset + length));
+                        self.driver.write(buffer, physical_address, active_len)
+                    }
                     _ => Err(ErrorCode::FAIL),
                 }
             })
@@ -382,6 +676,8 @@ impl<'a> NonvolatileStorage<'a> {
                         {
                             true
                         } else {
+                            // Let the next app run instead.
+                            self.current_user.clear();
                             false
                         }
                     } else {
@@ -399,6 +695,52 @@ impl<'a> NonvolatileStorage<'a> {
 /// This is the callback client for the underlying physical storage driver.
 impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for NonvolatileStorage<'_> {
     fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        // If this read is the first half of a masked update, apply the
+        // update and write the bytes back. The current user is kept so that
+        // nothing else can access the storage until the write completes.
+        if let Some(address) = self.current_masked_update.take() {
+            let applied = match self.current_user.extract() {
+                Some(NonvolatileUser::App { app_id }) => self
+                    .apps
+                    .enter(app_id, |app| {
+                        // The values are followed by the masks.
+                        app.buffer_write.map_or(Err(ErrorCode::RESERVE), |data| {
+                            if data.len() < 2 * length {
+                                Err(ErrorCode::RESERVE)
+                            } else {
+                                apply_mask(
+                                    &mut buffer[..length],
+                                    &data[..length],
+                                    &data[length..2 * length],
+                                );
+                                Ok(())
+                            }
+                        })
+                    })
+                    .unwrap_or_else(|err| Err(err.into())),
+                _ => Err(ErrorCode::FAIL),
+            };
+            let res = match applied {
+                Ok(()) => self.driver.write(buffer, address, length),
+                Err(e) => {
+                    self.buffer.replace(buffer);
+                    Err(e)
+                }
+            };
+            if let Err(e) = res {
+                self.current_user.take().map(|user| {
+                    if let NonvolatileUser::App { app_id } = user {
+                        let _ = self.apps.enter(app_id, |app| {
+                            app.callback_write
+                                .schedule(kernel::into_statuscode(Err(e)), 0, 0);
+                        });
+                    }
+                });
+                self.check_queue();
+            }
+            return;
+        }
+
         // Switch on which user of this capsule generated this callback.
         self.current_user.take().map(|user| {
             match user {
@@ -407,6 +749,11 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
                         client.read_done(buffer, length);
                     });
                 }
+                NonvolatileUser::Regions => {
+                    self.load_regions(&buffer[..length]);
+                    self.buffer.replace(buffer);
+                    self.regions_state.set(RegionsState::Ready);
+                }
                 NonvolatileUser::App { app_id } => {
                     let _ = self.apps.enter(app_id, move |app| {
                         // Need to copy in the contents of the buffer
@@ -433,6 +780,37 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
     }
 
     fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        // If an erase is in progress, write the next chunk of erased bytes.
+        // The buffer still holds them.
+        if let Some((next, end)) = self.current_erase.take() {
+            if next < end {
+                let active_len = cmp::min(end - next, buffer.len());
+                self.current_erase.set((next + active_len, end));
+                match self
+                    .driver
+                    .write(buffer, self.userspace_start_address + next, active_len)
+                {
+                    Ok(()) => return,
+                    Err(e) => {
+                        self.current_erase.clear();
+                        self.current_user.take().map(|user| {
+                            if let NonvolatileUser::App { app_id } = user {
+                                let _ = self.apps.enter(app_id, |app| {
+                                    app.callback_write.schedule(
+                                        kernel::into_statuscode(Err(e)),
+                                        0,
+                                        0,
+                                    );
+                                });
+                            }
+                        });
+                        self.check_queue();
+                        return;
+                    }
+                }
+            }
+        }
+
         // Switch on which user of this capsule generated this callback.
         self.current_user.take().map(|user| {
             match user {
@@ -441,6 +819,9 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
                         client.write_done(buffer, length);
                     });
                 }
+                NonvolatileUser::Regions => {
+                    self.buffer.replace(buffer);
+                }
                 NonvolatileUser::App { app_id } => {
                     let _ = self.apps.enter(app_id, move |app| {
                         // Replace the buffer we used to do this write.
@@ -583,9 +964,23 @@ impl Driver for NonvolatileStorage<'_> {
     /// ### `command_num`
     ///
     /// - `0`: Return Ok(()) if this driver is included on the platform.
-    /// - `1`: Return the number of bytes available to userspace.
+    /// - `1`: Return the number of bytes available to userspace, or to this
+    ///        app if it has its own region.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
+    /// - `4`: Start a masked update of the number of bytes in the second
+    ///        argument at the offset in the first argument. The write buffer
+    ///        holds the new values of the bytes followed by their masks, so it
+    ///        must be at least twice as long as the update. Completion is
+    ///        signaled through the write done callback.
+    /// - `5`: Return the size of the region of this app, or 0 if it has
+    ///        none. Fails with `NOSUPPORT` if regions are not enabled.
+    /// - `6`: Allocate a region of the number of bytes in the first argument
+    ///        for this app. Fails with `ALREADY` if the app has a region,
+    ///        `NOSUPPORT` if it has no fixed short ID, and `NOMEM` if there is
+    ///        no room. Completion is signaled through the write done callback.
+    /// - `7`: Erase the region of this app, setting all its bytes to 0xFF.
+    ///        Completion is signaled through the write done callback.
     fn command(
         &self,
         command_num: usize,
@@ -600,7 +995,12 @@ impl Driver for NonvolatileStorage<'_> {
 
             1 /* How many bytes are accessible from userspace */ => {
                 // TODO: Would break on 64-bit platforms
-                CommandReturn::success_u32(self.userspace_length as u32)
+                match self.regions_state.get() {
+                    RegionsState::Shared => {
+                        CommandReturn::success_u32(self.userspace_length as u32)
+                    }
+                    _ => self.command(5, 0, 0, appid),
+                }
             },
 
             2 /* Issue a read command */ => {
@@ -633,7 +1033,337 @@ impl Driver for NonvolatileStorage<'_> {
                 }
             }
 
+            4 /* Issue a masked update */ => {
+                if length == 0 {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                let res = self.enqueue_command(
+                    NonvolatileCommand::UserspaceMaskedUpdate,
+                    offset,
+                    length,
+                    Some(appid),
+                );
+
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            5 /* Size of the region of this app */ => {
+                match self.regions_state.get() {
+                    RegionsState::Shared => CommandReturn::failure(ErrorCode::NOSUPPORT),
+                    RegionsState::Loading => CommandReturn::failure(ErrorCode::BUSY),
+                    RegionsState::Ready => CommandReturn::success_u32(
+                        self.region_of(appid).map_or(0, |(_, region)| region.length as u32),
+                    ),
+                }
+            }
+
+            6 /* Allocate a region for this app */ => {
+                let res = match self.regions_state.get() {
+                    RegionsState::Shared => Err(ErrorCode::NOSUPPORT),
+                    RegionsState::Loading => Err(ErrorCode::BUSY),
+                    RegionsState::Ready if offset == 0 => Err(ErrorCode::INVAL),
+                    RegionsState::Ready if appid.short_app_id() == ShortID::LocallyUnique => {
+                        Err(ErrorCode::NOSUPPORT)
+                    }
+                    RegionsState::Ready if self.region_of(appid).is_some() => {
+                        Err(ErrorCode::ALREADY)
+                    }
+                    RegionsState::Ready => self.enqueue_command(
+                        NonvolatileCommand::UserspaceAllocate,
+                        0,
+                        offset,
+                        Some(appid),
+                    ),
+                };
+
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            7 /* Erase the region of this app */ => {
+                let res = self.userspace_bounds(Some(appid)).and_then(|(base, limit)| {
+                    if self.regions_state.get() == RegionsState::Shared {
+                        Err(ErrorCode::NOSUPPORT)
+                    } else {
+                        self.enqueue_command(
+                            NonvolatileCommand::UserspaceErase,
+                            base,
+                            limit,
+                            Some(appid),
+                        )
+                    }
+                });
+
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{apply_mask, NonvolatileStorage, DRIVER_NUM};
+    use crate::test::mock_process::{self, MockProcess};
+    use core::cell::{Cell, RefCell};
+    use kernel::common::cells::{OptionalCell, TakeCell};
+    use kernel::hil::nonvolatile_storage::{self as hil_nv, NonvolatileStorageClient};
+    use kernel::{Driver, ErrorCode};
+
+    /// Physical address of the userspace memory.
+    const USERSPACE_START: usize = 0x100;
+    /// Page size of the mock storage, only used to place updates across a
+    /// page boundary.
+    const PAGE_SIZE: usize = 64;
+
+    /// Storage that completes an operation when the test asks it to, and
+    /// logs the operations.
+    struct MockStorage {
+        memory: RefCell<[u8; 0x200]>,
+        client: OptionalCell<&'static dyn NonvolatileStorageClient<'static>>,
+        pending: TakeCell<'static, [u8]>,
+        // (write, address, length) of the pending operation.
+        operation: Cell<(bool, usize, usize)>,
+        log: RefCell<[(bool, usize, usize); 8]>,
+        logged: Cell<usize>,
+    }
+
+    impl MockStorage {
+        fn new() -> MockStorage {
+            let mut memory = [0; 0x200];
+            for (i, byte) in memory.iter_mut().enumerate() {
+                *byte = (i * 7) as u8;
+            }
+            MockStorage {
+                memory: RefCell::new(memory),
+                client: OptionalCell::empty(),
+                pending: TakeCell::empty(),
+                operation: Cell::new((false, 0, 0)),
+                log: RefCell::new([(false, 0, 0); 8]),
+                logged: Cell::new(0),
+            }
+        }
+
+        /// Complete the pending operation. Returns whether one was pending.
+        fn complete(&self) -> bool {
+            let buffer = match self.pending.take() {
+                Some(buffer) => buffer,
+                None => return false,
+            };
+            let (write, address, length) = self.operation.get();
+            let client = self.client.extract().unwrap();
+            if write {
+                self.memory.borrow_mut()[address..address + length]
+                    .copy_from_slice(&buffer[..length]);
+                client.write_done(buffer, length);
+            } else {
+                buffer[..length].copy_from_slice(&self.memory.borrow()[address..address + length]);
+                client.read_done(buffer, length);
+            }
+            true
+        }
+
+        fn operations(&self) -> std::vec::Vec<(bool, usize, usize)> {
+            self.log.borrow()[..self.logged.get()].to_vec()
+        }
+
+        fn start(
+            &self,
+            write: bool,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            self.pending.replace(buffer);
+            self.operation.set((write, address, length));
+            self.log.borrow_mut()[self.logged.get()] = (write, address, length);
+            self.logged.set(self.logged.get() + 1);
+            Ok(())
+        }
+    }
+
+    impl hil_nv::NonvolatileStorage<'static> for MockStorage {
+        fn set_client(&self, client: &'static dyn NonvolatileStorageClient<'static>) {
+            self.client.set(client);
+        }
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            self.start(false, buffer, address, length)
+        }
+
+        fn write(
+            &self,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            self.start(true, buffer, address, length)
+        }
+    }
+
+    fn driver(
+        names: &[&'static str],
+    ) -> (
+        &'static MockStorage,
+        &'static NonvolatileStorage<'static>,
+        std::vec::Vec<&'static MockProcess>,
+    ) {
+        let (kernel, processes) = mock_process::kernel(names);
+        let storage = mock_process::leak(MockStorage::new());
+        let driver = mock_process::leak(NonvolatileStorage::new(
+            storage,
+            mock_process::grant(kernel),
+            USERSPACE_START,
+            0x100,
+            0,
+            USERSPACE_START,
+            mock_process::buffer(32),
+        ));
+        hil_nv::NonvolatileStorage::set_client(storage, driver);
+        for app in processes.iter() {
+            let id = app.processid();
+            assert!(driver.subscribe(0, app.upcall(DRIVER_NUM, 0), id).is_ok());
+            assert!(driver.subscribe(1, app.upcall(DRIVER_NUM, 1), id).is_ok());
+        }
+        (storage, driver, processes)
+    }
+
+    /// Start a masked update of `values` under `masks` at `offset`.
+    fn masked_update(
+        driver: &NonvolatileStorage,
+        app: &MockProcess,
+        offset: usize,
+        values: &[u8],
+        masks: &[u8],
+    ) -> Result<(), ErrorCode> {
+        let mut data = values.to_vec();
+        data.extend_from_slice(masks);
+        let id = app.processid();
+        assert!(driver
+            .allow_readonly(id, 0, app.readonly_slice(&data))
+            .is_ok());
+        match driver.command(4, offset, values.len(), id).get_failure() {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
+    }
+
+    #[test]
+    fn test_masked_update_across_page_boundary() {
+        let (storage, driver, processes) = driver(&["app"]);
+        let app = processes[0];
+        let before = *storage.memory.borrow();
+
+        // Six bytes, three on each side of a page boundary.
+        let offset = PAGE_SIZE - 3;
+        let address = USERSPACE_START + offset;
+        assert_eq!((address + 3) % PAGE_SIZE, 0);
+        let values = [0xff, 0x00, 0xa5, 0x5a, 0xff, 0x00];
+        let masks = [0x0f, 0xf0, 0xff, 0x00, 0x81, 0x7e];
+        assert_eq!(masked_update(driver, app, offset, &values, &masks), Ok(()));
+        while storage.complete() {}
+
+        // The bytes were read, and written back at the same place.
+        assert_eq!(
+            storage.operations(),
+            [(false, address, 6), (true, address, 6)]
+        );
+        assert_eq!(app.take_upcalls(), [(1, 6, 0, 0)]);
+
+        let mut expected = before;
+        apply_mask(&mut expected[address..address + 6], &values, &masks);
+        let memory = storage.memory.borrow();
+        for i in 0..memory.len() {
+            assert_eq!(memory[i], expected[i], "byte {:#x}", i);
+        }
+        // Bits outside the mask kept their stored value.
+        assert_eq!(memory[address + 3], before[address + 3]);
+        assert_eq!(memory[address] & 0xf0, before[address] & 0xf0);
+    }
+
+    #[test]
+    fn test_masked_update_is_atomic() {
+        let (storage, driver, processes) = driver(&["app"]);
+        let app = processes[0];
+        assert_eq!(
+            masked_update(driver, app, 0, &[0x00, 0x00], &[0xff, 0xff]),
+            Ok(())
+        );
+
+        // A kernel write issued between the read and the write back of the
+        // update waits until the update is done.
+        assert!(storage.complete());
+        let buffer = mock_process::buffer(2);
+        assert_eq!(
+            hil_nv::NonvolatileStorage::write(driver, buffer, 0x10, 2),
+            Ok(())
+        );
+        while storage.complete() {}
+
+        let start = USERSPACE_START;
+        assert_eq!(
+            storage.operations(),
+            [(false, start, 2), (true, start, 2), (true, 0x10, 2)]
+        );
+        assert_eq!(app.take_upcalls(), [(1, 2, 0, 0)]);
+        assert_eq!(storage.memory.borrow()[start..start + 2], [0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_masked_update_needs_masks() {
+        let (storage, driver, processes) = driver(&["app"]);
+        let app = processes[0];
+        let id = app.processid();
+        assert!(driver
+            .allow_readonly(id, 0, app.readonly_slice(&[0; 6]))
+            .is_ok());
+        assert_eq!(
+            driver.command(4, 0, 4, id).get_failure(),
+            Some(ErrorCode::SIZE)
+        );
+        assert_eq!(
+            driver.command(4, 0, 0, id).get_failure(),
+            Some(ErrorCode::INVAL)
+        );
+        assert!(storage.operations().is_empty());
+    }
+
+    #[test]
+    fn test_apply_mask() {
+        let mut bytes = [0b1010_1010, 0xff, 0x00];
+        apply_mask(&mut bytes, &[0b0101_0000, 0x00, 0xff], &[0xf0, 0x0f, 0xff]);
+        assert_eq!(bytes, [0b0101_1010, 0xf0, 0xff]);
+    }
+
+    #[test]
+    fn test_apply_mask_empty_mask() {
+        let mut bytes = [0x12, 0x34];
+        apply_mask(&mut bytes, &[0xff, 0xff], &[0x00, 0x00]);
+        assert_eq!(bytes, [0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_apply_mask_longer_than_two_bytes() {
+        let mut bytes = [0u8; 8];
+        let value = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mask = [0xff; 8];
+        apply_mask(&mut bytes, &value, &mask);
+        assert_eq!(bytes, value);
+    }
+}
@@ -0,0 +1,1087 @@
+//! Software 1-Wire bus master on a GPIO pin.
+//!
+//! The 1-Wire protocol is bit-banged over a single GPIO pin with an external
+//! pull-up resistor. The pin is driven low to pull the bus down and switched
+//! to an input to release it. Every time slot is timed by alarm callbacks,
+//! some only a few microseconds apart, so the alarm must run at 1 MHz or
+//! faster, and should not be shared with slow clients through a virtual
+//! alarm. A callback that comes more than a few microseconds late can
+//! corrupt a slot; corrupted transfers are caught by the CRCs of ROM codes
+//! and device memory.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let one_wire_alarm = static_init!(
+//!     capsules::virtual_alarm::VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let one_wire = static_init!(
+//!     capsules::one_wire::GpioOneWire<
+//!         'static,
+//!         sam4l::gpio::GPIOPin,
+//!         capsules::virtual_alarm::VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     >,
+//!     capsules::one_wire::GpioOneWire::new(&sam4l::gpio::PA[16], one_wire_alarm)
+//! );
+//! one_wire_alarm.set_alarm_client(one_wire);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::gpio;
+use kernel::hil::one_wire::{self, OneWire};
+use kernel::hil::time;
+use kernel::ErrorCode;
+
+/// Standard speed slot timings, in microseconds.
+const RESET_LOW_US: u32 = 480;
+const PRESENCE_SAMPLE_US: u32 = 70;
+const PRESENCE_END_US: u32 = 410;
+const WRITE_ONE_LOW_US: u32 = 6;
+const WRITE_ONE_RELEASE_US: u32 = 64;
+const WRITE_ZERO_LOW_US: u32 = 60;
+const WRITE_ZERO_RELEASE_US: u32 = 10;
+const READ_LOW_US: u32 = 6;
+const READ_SAMPLE_US: u32 = 9;
+const READ_END_US: u32 = 55;
+
+/// The kinds of time slot.
+#[derive(Copy, Clone, PartialEq)]
+enum Slot {
+    Reset,
+    Write(bool),
+    Read,
+}
+
+/// Where the running slot is, up to the next alarm.
+#[derive(Copy, Clone, PartialEq)]
+enum Phase {
+    Idle,
+    ResetLow,
+    PresenceSample,
+    /// Waiting for the end of the presence window, with whether a device
+    /// answered.
+    PresenceEnd(bool),
+    WriteLow(bool),
+    WriteEnd,
+    ReadLow,
+    ReadSample,
+    ReadEnd(bool),
+}
+
+/// The operation the slots are for.
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Idle,
+    Reset,
+    WriteBit,
+    ReadBit,
+    WriteByte,
+    ReadByte,
+    Select,
+    Search,
+}
+
+/// A byte being written or read, one slot per bit.
+#[derive(Copy, Clone, PartialEq)]
+struct Transfer {
+    write: bool,
+    value: u8,
+    bits: usize,
+}
+
+/// Where the ROM search is in a pass over the bus.
+#[derive(Copy, Clone, PartialEq)]
+enum Search {
+    Reset,
+    Command,
+    IdBit,
+    /// Reading the complement of the ROM bit, after the bit itself.
+    Complement(bool),
+    Direction,
+}
+
+pub struct GpioOneWire<'a, P: gpio::Pin, A: time::Alarm<'a>> {
+    pin: &'a P,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn one_wire::Client>,
+    phase: Cell<Phase>,
+    operation: Cell<Operation>,
+    transfer: Cell<Option<Transfer>>,
+
+    /// The device being selected, and the next byte of its ROM code to
+    /// send.
+    select_rom: Cell<Option<[u8; 8]>>,
+    select_byte: Cell<usize>,
+
+    roms: TakeCell<'static, [[u8; 8]]>,
+    found: Cell<usize>,
+    search: Cell<Search>,
+    search_rom: Cell<[u8; 8]>,
+    /// 1-based index of the ROM bit being searched.
+    search_bit: Cell<usize>,
+    /// 1-based index of the last bit where both a 0 and a 1 were seen and
+    /// the 0 branch was taken, or 0 once every branch has been explored.
+    last_discrepancy: Cell<usize>,
+    /// The same in the current pass.
+    last_zero: Cell<usize>,
+}
+
+impl<'a, P: gpio::Pin, A: time::Alarm<'a>> GpioOneWire<'a, P, A> {
+    pub fn new(pin: &'a P, alarm: &'a A) -> GpioOneWire<'a, P, A> {
+        pin.make_input();
+        GpioOneWire {
+            pin: pin,
+            alarm: alarm,
+            client: OptionalCell::empty(),
+            phase: Cell::new(Phase::Idle),
+            operation: Cell::new(Operation::Idle),
+            transfer: Cell::new(None),
+            select_rom: Cell::new(None),
+            select_byte: Cell::new(0),
+            roms: TakeCell::empty(),
+            found: Cell::new(0),
+            search: Cell::new(Search::Reset),
+            search_rom: Cell::new([0; 8]),
+            search_bit: Cell::new(1),
+            last_discrepancy: Cell::new(0),
+            last_zero: Cell::new(0),
+        }
+    }
+
+    /// Move on to `phase` in `us` microseconds.
+    fn wait_us(&self, phase: Phase, us: u32) {
+        self.phase.set(phase);
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_us(us));
+    }
+
+    fn drive_low(&self) {
+        self.pin.clear();
+        self.pin.make_output();
+    }
+
+    fn release(&self) {
+        self.pin.make_input();
+    }
+
+    /// Claim the bus for `operation`.
+    fn begin(&self, operation: Operation) -> Result<(), ErrorCode> {
+        if self.operation.get() != Operation::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.operation.set(operation);
+        Ok(())
+    }
+
+    /// Free the bus before calling the client, which may start the next
+    /// operation.
+    fn end(&self) {
+        self.operation.set(Operation::Idle);
+    }
+
+    fn start_slot(&self, slot: Slot) {
+        self.drive_low();
+        match slot {
+            Slot::Reset => self.wait_us(Phase::ResetLow, RESET_LOW_US),
+            Slot::Write(true) => self.wait_us(Phase::WriteLow(true), WRITE_ONE_LOW_US),
+            Slot::Write(false) => self.wait_us(Phase::WriteLow(false), WRITE_ZERO_LOW_US),
+            Slot::Read => self.wait_us(Phase::ReadLow, READ_LOW_US),
+        }
+    }
+
+    fn start_transfer(&self, write: bool, value: u8) {
+        self.transfer.set(Some(Transfer {
+            write: write,
+            value: if write { value } else { 0 },
+            bits: 0,
+        }));
+        self.transfer_slot(write, value);
+    }
+
+    /// Start the slot of the next bit of a byte transfer.
+    fn transfer_slot(&self, write: bool, value: u8) {
+        if write {
+            self.start_slot(Slot::Write(value & 1 != 0));
+        } else {
+            self.start_slot(Slot::Read);
+        }
+    }
+
+    /// A slot is over. `value` is the bit read, or whether a device was
+    /// present after a reset.
+    fn slot_done(&self, value: bool) {
+        if let Some(mut transfer) = self.transfer.get() {
+            if !transfer.write && value {
+                transfer.value |= 1 << transfer.bits;
+            }
+            transfer.bits += 1;
+            if transfer.bits < 8 {
+                self.transfer.set(Some(transfer));
+                self.transfer_slot(transfer.write, transfer.value >> transfer.bits);
+            } else {
+                self.transfer.set(None);
+                self.byte_done(transfer.value);
+            }
+            return;
+        }
+
+        match self.operation.get() {
+            Operation::Reset => {
+                self.end();
+                self.client.map(|client| client.reset_done(value));
+            }
+            Operation::WriteBit => {
+                self.end();
+                self.client.map(|client| client.write_done());
+            }
+            Operation::ReadBit => {
+                self.end();
+                self.client.map(|client| client.read_bit_done(value));
+            }
+            Operation::Select => {
+                if !value {
+                    self.end();
+                    self.client
+                        .map(|client| client.select_done(Err(ErrorCode::NODEVICE)));
+                } else if self.select_rom.get().is_some() {
+                    self.start_transfer(true, one_wire::MATCH_ROM);
+                } else {
+                    self.start_transfer(true, one_wire::SKIP_ROM);
+                }
+            }
+            Operation::Search => self.search_slot_done(value),
+            Operation::Idle | Operation::WriteByte | Operation::ReadByte => {}
+        }
+    }
+
+    fn byte_done(&self, value: u8) {
+        match self.operation.get() {
+            Operation::WriteByte => {
+                self.end();
+                self.client.map(|client| client.write_done());
+            }
+            Operation::ReadByte => {
+                self.end();
+                self.client.map(|client| client.read_byte_done(value));
+            }
+            Operation::Select => {
+                let index = self.select_byte.get();
+                match self.select_rom.get() {
+                    Some(rom) if index < rom.len() => {
+                        self.select_byte.set(index + 1);
+                        self.start_transfer(true, rom[index]);
+                    }
+                    _ => {
+                        self.end();
+                        self.client.map(|client| client.select_done(Ok(())));
+                    }
+                }
+            }
+            Operation::Search => {
+                // SEARCH ROM has been sent.
+                self.search.set(Search::IdBit);
+                self.start_slot(Slot::Read);
+            }
+            Operation::Idle | Operation::Reset | Operation::WriteBit | Operation::ReadBit => {}
+        }
+    }
+
+    /// Start a pass of the ROM search, unless the buffer is full.
+    fn search_pass(&self) {
+        let full = self
+            .roms
+            .map_or(true, |roms| self.found.get() >= roms.len());
+        if full {
+            self.search_done(Ok(self.found.get()));
+        } else {
+            self.search.set(Search::Reset);
+            self.start_slot(Slot::Reset);
+        }
+    }
+
+    fn search_slot_done(&self, value: bool) {
+        match self.search.get() {
+            Search::Reset => {
+                if !value {
+                    let found = self.found.get();
+                    self.search_done(if found == 0 {
+                        Err(ErrorCode::NODEVICE)
+                    } else {
+                        Ok(found)
+                    });
+                } else {
+                    self.search.set(Search::Command);
+                    self.search_bit.set(1);
+                    self.last_zero.set(0);
+                    self.start_transfer(true, one_wire::SEARCH_ROM);
+                }
+            }
+            Search::Command => {}
+            Search::IdBit => {
+                self.search.set(Search::Complement(value));
+                self.start_slot(Slot::Read);
+            }
+            Search::Complement(id_bit) => {
+                let bit = self.search_bit.get();
+                let mut rom = self.search_rom.get();
+                let (byte, mask) = ((bit - 1) / 8, 1 << ((bit - 1) % 8));
+                let direction = if id_bit && value {
+                    // No device took part in the search.
+                    return self.search_done(Err(ErrorCode::FAIL));
+                } else if id_bit != value {
+                    id_bit
+                } else {
+                    // Devices differ in this bit.
+                    let last_discrepancy = self.last_discrepancy.get();
+                    let direction = if bit < last_discrepancy {
+                        rom[byte] & mask != 0
+                    } else {
+                        bit == last_discrepancy
+                    };
+                    if !direction {
+                        self.last_zero.set(bit);
+                    }
+                    direction
+                };
+                if direction {
+                    rom[byte] |= mask;
+                } else {
+                    rom[byte] &= !mask;
+                }
+                self.search_rom.set(rom);
+                self.search.set(Search::Direction);
+                self.start_slot(Slot::Write(direction));
+            }
+            Search::Direction => {
+                let bit = self.search_bit.get();
+                if bit < 64 {
+                    self.search_bit.set(bit + 1);
+                    self.search.set(Search::IdBit);
+                    self.start_slot(Slot::Read);
+                    return;
+                }
+
+                let rom = self.search_rom.get();
+                if one_wire::crc8(&rom[..7]) != rom[7] {
+                    return self.search_done(Err(ErrorCode::FAIL));
+                }
+                let found = self.found.get();
+                self.roms.map(|roms| roms[found] = rom);
+                self.found.set(found + 1);
+
+                self.last_discrepancy.set(self.last_zero.get());
+                if self.last_discrepancy.get() == 0 {
+                    self.search_done(Ok(found + 1));
+                } else {
+                    self.search_pass();
+                }
+            }
+        }
+    }
+
+    fn search_done(&self, result: Result<usize, ErrorCode>) {
+        self.end();
+        self.roms.take().map(|roms| {
+            self.client
+                .map(move |client| client.search_done(roms, result));
+        });
+    }
+}
+
+impl<'a, P: gpio::Pin, A: time::Alarm<'a>> time::AlarmClient for GpioOneWire<'a, P, A> {
+    fn alarm(&self) {
+        match self.phase.get() {
+            Phase::Idle => {}
+            Phase::ResetLow => {
+                self.release();
+                self.wait_us(Phase::PresenceSample, PRESENCE_SAMPLE_US);
+            }
+            Phase::PresenceSample => {
+                let present = !self.pin.read();
+                self.wait_us(Phase::PresenceEnd(present), PRESENCE_END_US);
+            }
+            Phase::WriteLow(bit) => {
+                self.release();
+                if bit {
+                    self.wait_us(Phase::WriteEnd, WRITE_ONE_RELEASE_US);
+                } else {
+                    self.wait_us(Phase::WriteEnd, WRITE_ZERO_RELEASE_US);
+                }
+            }
+            Phase::ReadLow => {
+                self.release();
+                self.wait_us(Phase::ReadSample, READ_SAMPLE_US);
+            }
+            Phase::ReadSample => {
+                let bit = self.pin.read();
+                self.wait_us(Phase::ReadEnd(bit), READ_END_US);
+            }
+            Phase::PresenceEnd(value) | Phase::ReadEnd(value) => {
+                self.phase.set(Phase::Idle);
+                self.slot_done(value);
+            }
+            Phase::WriteEnd => {
+                self.phase.set(Phase::Idle);
+                self.slot_done(false);
+            }
+        }
+    }
+}
+
+impl<'a, P: gpio::Pin, A: time::Alarm<'a>> OneWire<'a> for GpioOneWire<'a, P, A> {
+    fn set_client(&self, client: &'a dyn one_wire::Client) {
+        self.client.set(client);
+    }
+
+    fn reset(&self) -> Result<(), ErrorCode> {
+        self.begin(Operation::Reset)?;
+        self.start_slot(Slot::Reset);
+        Ok(())
+    }
+
+    fn write_bit(&self, bit: bool) -> Result<(), ErrorCode> {
+        self.begin(Operation::WriteBit)?;
+        self.start_slot(Slot::Write(bit));
+        Ok(())
+    }
+
+    fn read_bit(&self) -> Result<(), ErrorCode> {
+        self.begin(Operation::ReadBit)?;
+        self.start_slot(Slot::Read);
+        Ok(())
+    }
+
+    fn write_byte(&self, byte: u8) -> Result<(), ErrorCode> {
+        self.begin(Operation::WriteByte)?;
+        self.start_transfer(true, byte);
+        Ok(())
+    }
+
+    fn read_byte(&self) -> Result<(), ErrorCode> {
+        self.begin(Operation::ReadByte)?;
+        self.start_transfer(false, 0);
+        Ok(())
+    }
+
+    fn select(&self, rom: Option<[u8; 8]>) -> Result<(), ErrorCode> {
+        self.begin(Operation::Select)?;
+        self.select_rom.set(rom);
+        self.select_byte.set(0);
+        self.start_slot(Slot::Reset);
+        Ok(())
+    }
+
+    fn search(
+        &self,
+        roms: &'static mut [[u8; 8]],
+    ) -> Result<(), (ErrorCode, &'static mut [[u8; 8]])> {
+        if roms.is_empty() {
+            return Err((ErrorCode::SIZE, roms));
+        }
+        if let Err(e) = self.begin(Operation::Search) {
+            return Err((e, roms));
+        }
+        self.roms.replace(roms);
+        self.found.set(0);
+        self.search_rom.set([0; 8]);
+        self.last_discrepancy.set(0);
+        self.search_pass();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::ds18b20::{self, Ds18b20};
+    use crate::test::mock_alarm::MockAlarm;
+    use crate::test::mock_process;
+    use core::cell::RefCell;
+    use core::cmp;
+    use kernel::hil::gpio::{Configuration, FloatingState};
+    use kernel::hil::one_wire::{crc8, Client, MATCH_ROM, SEARCH_ROM, SKIP_ROM};
+    use kernel::hil::sensors::{TemperatureClient, TemperatureDriver};
+    use kernel::hil::time::{Alarm, Ticks, Time};
+    use kernel::Driver;
+    use std::boxed::Box;
+    use std::collections::VecDeque;
+    use std::vec;
+    use std::vec::Vec;
+
+    // DS18B20 function commands.
+    const CONVERT_T: u8 = 0x44;
+    const READ_SCRATCHPAD: u8 = 0xBE;
+
+    /// Where a device is in a transaction.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Phase {
+        /// Waiting for a ROM command after a reset.
+        RomCommand,
+        /// Comparing ROM bits from the master after MATCH ROM.
+        Match(usize),
+        /// Taking part in a ROM search: sending a ROM bit, then its
+        /// complement, then reading the master's choice.
+        Search(usize, u8),
+        /// Selected, waiting for a function command.
+        Function,
+        /// Not selected until the next reset.
+        Idle,
+    }
+
+    /// A simulated DS18B20 on the bus.
+    struct Device {
+        rom: [u8; 8],
+        scratchpad: Cell<[u8; 9]>,
+        /// Microseconds after the reset pulse ends that its presence pulse
+        /// starts and ends.
+        presence: (u32, u32),
+        phase: Cell<Phase>,
+        byte: Cell<(u8, usize)>,
+        tx: RefCell<VecDeque<bool>>,
+        /// Whether the device sent in the current slot.
+        sent: Cell<bool>,
+        conversions: Cell<usize>,
+    }
+
+    impl Device {
+        /// A DS18B20 with serial number `serial` reading `raw` sixteenths of
+        /// a degree.
+        fn new(serial: u8, raw: i16) -> Device {
+            let mut rom = [ds18b20::DS18B20_FAMILY, serial, 0, 0, 0, 0, 0, 0];
+            rom[7] = crc8(&rom[..7]);
+            let [lsb, msb] = raw.to_le_bytes();
+            let mut scratchpad = [lsb, msb, 0x4B, 0x46, 0x7F, 0xFF, 0x0C, 0x10, 0];
+            scratchpad[8] = crc8(&scratchpad[..8]);
+            Device {
+                rom: rom,
+                scratchpad: Cell::new(scratchpad),
+                presence: (15, 135),
+                phase: Cell::new(Phase::Idle),
+                byte: Cell::new((0, 0)),
+                tx: RefCell::new(VecDeque::new()),
+                sent: Cell::new(false),
+                conversions: Cell::new(0),
+            }
+        }
+
+        fn rom_bit(&self, bit: usize) -> bool {
+            self.rom[bit / 8] & (1 << (bit % 8)) != 0
+        }
+
+        fn reset(&self) {
+            self.phase.set(Phase::RomCommand);
+            self.byte.set((0, 0));
+            self.tx.borrow_mut().clear();
+            self.sent.set(false);
+        }
+
+        /// The bit the device sends in the slot starting now, if it is
+        /// sending.
+        fn send(&self) -> Option<bool> {
+            match self.phase.get() {
+                Phase::Search(bit, 0) => {
+                    self.phase.set(Phase::Search(bit, 1));
+                    Some(self.rom_bit(bit))
+                }
+                Phase::Search(bit, 1) => {
+                    self.phase.set(Phase::Search(bit, 2));
+                    Some(!self.rom_bit(bit))
+                }
+                Phase::Function => self.tx.borrow_mut().pop_front(),
+                _ => None,
+            }
+        }
+
+        /// Take a bit written by the master, unless the device sent in
+        /// this slot.
+        fn receive(&self, value: bool) {
+            match self.phase.get() {
+                Phase::Match(bit) => {
+                    if value != self.rom_bit(bit) {
+                        self.phase.set(Phase::Idle);
+                    } else if bit == 63 {
+                        self.phase.set(Phase::Function);
+                    } else {
+                        self.phase.set(Phase::Match(bit + 1));
+                    }
+                }
+                Phase::Search(bit, 2) => {
+                    if value != self.rom_bit(bit) || bit == 63 {
+                        self.phase.set(Phase::Idle);
+                    } else {
+                        self.phase.set(Phase::Search(bit + 1, 0));
+                    }
+                }
+                Phase::RomCommand | Phase::Function => {
+                    let (mut byte, count) = self.byte.get();
+                    if value {
+                        byte |= 1 << count;
+                    }
+                    if count < 7 {
+                        self.byte.set((byte, count + 1));
+                    } else {
+                        self.byte.set((0, 0));
+                        self.command(byte);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        fn command(&self, command: u8) {
+            match (self.phase.get(), command) {
+                (Phase::RomCommand, SEARCH_ROM) => self.phase.set(Phase::Search(0, 0)),
+                (Phase::RomCommand, MATCH_ROM) => self.phase.set(Phase::Match(0)),
+                (Phase::RomCommand, SKIP_ROM) => self.phase.set(Phase::Function),
+                (Phase::Function, CONVERT_T) => self.conversions.set(self.conversions.get() + 1),
+                (Phase::Function, READ_SCRATCHPAD) => {
+                    let mut tx = self.tx.borrow_mut();
+                    for byte in self.scratchpad.get().iter() {
+                        tx.extend((0..8).map(|i| byte & (1 << i) != 0));
+                    }
+                }
+                _ => self.phase.set(Phase::Idle),
+            }
+        }
+    }
+
+    /// A bus line with a pull-up, seen from the master's pin, and the
+    /// 1 MHz alarm that times the slots.
+    struct Bus {
+        clock: &'static MockAlarm<'static>,
+        output: Cell<bool>,
+        level: Cell<bool>,
+        /// When the master last pulled the line low.
+        fell: Cell<u32>,
+        /// When devices hold the line low.
+        held: Cell<(u32, u32)>,
+        devices: Vec<Device>,
+    }
+
+    impl Bus {
+        fn new(devices: Vec<Device>) -> Bus {
+            Bus {
+                clock: mock_process::leak(MockAlarm::new()),
+                output: Cell::new(false),
+                level: Cell::new(true),
+                fell: Cell::new(0),
+                held: Cell::new((0, 0)),
+                devices: devices,
+            }
+        }
+
+        fn now(&self) -> u32 {
+            self.clock.now().into_u32()
+        }
+
+        /// Let the slots run until the bus master is done.
+        fn run(&self) {
+            while self.clock.fire().is_some() {}
+        }
+
+        fn master_low(&self) -> bool {
+            self.output.get() && !self.level.get()
+        }
+
+        /// Run `change` on the pin and let the devices see the edge it
+        /// makes.
+        fn change<F: FnOnce()>(&self, change: F) {
+            let was_low = self.master_low();
+            change();
+            let now = self.now();
+            match (was_low, self.master_low()) {
+                (false, true) => {
+                    // Devices sending a 0 hold the line through the slot.
+                    self.fell.set(now);
+                    let mut zero = false;
+                    for device in self.devices.iter() {
+                        let bit = device.send();
+                        device.sent.set(bit.is_some());
+                        zero |= bit == Some(false);
+                    }
+                    if zero {
+                        self.held.set((now, now + 30));
+                    }
+                }
+                (true, false) => {
+                    let low_us = now - self.fell.get();
+                    if low_us >= 480 {
+                        let mut presence: Option<(u32, u32)> = None;
+                        for device in self.devices.iter() {
+                            device.reset();
+                            let (start, end) = device.presence;
+                            presence = Some(presence.map_or((start, end), |(s, e)| {
+                                (cmp::min(s, start), cmp::max(e, end))
+                            }));
+                        }
+                        if let Some((start, end)) = presence {
+                            self.held.set((now + start, now + end));
+                        }
+                    } else {
+                        // Devices read master writes 15 us into the slot.
+                        for device in self.devices.iter() {
+                            if !device.sent.replace(false) {
+                                device.receive(low_us < 15);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    impl gpio::Configure for Bus {
+        fn configuration(&self) -> Configuration {
+            if self.output.get() {
+                Configuration::Output
+            } else {
+                Configuration::Input
+            }
+        }
+
+        fn make_output(&self) -> Configuration {
+            self.change(|| self.output.set(true));
+            Configuration::Output
+        }
+
+        fn disable_output(&self) -> Configuration {
+            self.change(|| self.output.set(false));
+            Configuration::Input
+        }
+
+        fn make_input(&self) -> Configuration {
+            self.change(|| self.output.set(false));
+            Configuration::Input
+        }
+
+        fn disable_input(&self) -> Configuration {
+            self.configuration()
+        }
+
+        fn deactivate_to_low_power(&self) {}
+
+        fn set_floating_state(&self, _state: FloatingState) {}
+
+        fn floating_state(&self) -> FloatingState {
+            FloatingState::PullNone
+        }
+    }
+
+    impl gpio::Output for Bus {
+        fn set(&self) {
+            self.change(|| self.level.set(true));
+        }
+
+        fn clear(&self) {
+            self.change(|| self.level.set(false));
+        }
+
+        fn toggle(&self) -> bool {
+            self.change(|| self.level.set(!self.level.get()));
+            self.level.get()
+        }
+    }
+
+    impl gpio::Input for Bus {
+        fn read(&self) -> bool {
+            let now = self.now();
+            let (from, until) = self.held.get();
+            !self.master_low() && !(from <= now && now < until)
+        }
+    }
+
+    impl gpio::Pin for Bus {}
+
+    /// Records what the bus master calls back with.
+    #[derive(Default)]
+    struct TestClient {
+        present: Cell<Option<bool>>,
+        bytes: RefCell<Vec<u8>>,
+        writes: Cell<usize>,
+        search: Cell<Option<Result<usize, ErrorCode>>>,
+        roms: RefCell<Vec<[u8; 8]>>,
+    }
+
+    impl Client for TestClient {
+        fn reset_done(&self, present: bool) {
+            self.present.set(Some(present));
+        }
+
+        fn write_done(&self) {
+            self.writes.set(self.writes.get() + 1);
+        }
+
+        fn read_bit_done(&self, bit: bool) {
+            self.bytes.borrow_mut().push(bit as u8);
+        }
+
+        fn read_byte_done(&self, byte: u8) {
+            self.bytes.borrow_mut().push(byte);
+        }
+
+        fn select_done(&self, _result: Result<(), ErrorCode>) {}
+
+        fn search_done(&self, roms: &'static mut [[u8; 8]], result: Result<usize, ErrorCode>) {
+            self.search.set(Some(result));
+            *self.roms.borrow_mut() = roms[..result.unwrap_or(0)].to_vec();
+        }
+    }
+
+    type Master = GpioOneWire<'static, Bus, MockAlarm<'static>>;
+
+    fn bus_master(devices: Vec<Device>) -> (&'static Master, &'static Bus) {
+        let bus = mock_process::leak(Bus::new(devices));
+        let one_wire = mock_process::leak(GpioOneWire::new(bus, bus.clock));
+        bus.clock.set_alarm_client(one_wire);
+        (one_wire, bus)
+    }
+
+    fn test_client(one_wire: &'static Master) -> &'static TestClient {
+        let client = mock_process::leak(TestClient::default());
+        one_wire.set_client(client);
+        client
+    }
+
+    fn roms(len: usize) -> &'static mut [[u8; 8]] {
+        Box::leak(vec![[0u8; 8]; len].into_boxed_slice())
+    }
+
+    #[test]
+    fn test_presence_detection() {
+        let (one_wire, bus) = bus_master(Vec::new());
+        let client = test_client(one_wire);
+        assert_eq!(one_wire.reset(), Ok(()));
+        bus.run();
+        assert_eq!(client.present.get(), Some(false));
+
+        let (one_wire, bus) = bus_master(vec![Device::new(1, 0)]);
+        let client = test_client(one_wire);
+        assert_eq!(one_wire.reset(), Ok(()));
+        assert_eq!(one_wire.reset(), Err(ErrorCode::BUSY));
+        assert_eq!(client.present.get(), None);
+        bus.run();
+        assert_eq!(client.present.get(), Some(true));
+        // The reset takes the reset pulse and the presence window.
+        assert!(bus.now() >= RESET_LOW_US + PRESENCE_SAMPLE_US + PRESENCE_END_US);
+
+        // A presence pulse that is over before the sample point is missed.
+        let mut late = Device::new(1, 0);
+        late.presence = (15, PRESENCE_SAMPLE_US - 10);
+        let (one_wire, bus) = bus_master(vec![late]);
+        let client = test_client(one_wire);
+        assert_eq!(one_wire.reset(), Ok(()));
+        bus.run();
+        assert_eq!(client.present.get(), Some(false));
+    }
+
+    #[test]
+    fn test_bytes() {
+        let device = Device::new(1, 0x0191);
+        let scratchpad = device.scratchpad.get();
+        let (one_wire, bus) = bus_master(vec![device]);
+        let client = test_client(one_wire);
+
+        assert_eq!(one_wire.select(None), Ok(()));
+        bus.run();
+        assert_eq!(one_wire.write_byte(READ_SCRATCHPAD), Ok(()));
+        bus.run();
+        assert_eq!(client.writes.get(), 1);
+        assert_eq!(bus.devices[0].phase.get(), Phase::Function);
+
+        assert_eq!(one_wire.read_byte(), Ok(()));
+        bus.run();
+        for _ in 0..8 {
+            assert_eq!(one_wire.read_bit(), Ok(()));
+            bus.run();
+        }
+        let lsb = scratchpad[1];
+        let bits: Vec<u8> = (0..8).map(|i| lsb >> i & 1).collect();
+        assert_eq!(client.bytes.borrow()[0], scratchpad[0]);
+        assert_eq!(client.bytes.borrow()[1..], bits[..]);
+    }
+
+    #[test]
+    fn test_rom_search() {
+        let devices = vec![
+            Device::new(0x35, 0),
+            Device::new(0x12, 0),
+            Device::new(0x34, 0),
+        ];
+        let mut expected: Vec<[u8; 8]> = devices.iter().map(|d| d.rom).collect();
+        expected.sort_by_key(|rom| u64::from_le_bytes(*rom).reverse_bits());
+        let (one_wire, bus) = bus_master(devices);
+        let client = test_client(one_wire);
+
+        assert!(one_wire.search(roms(4)).is_ok());
+        bus.run();
+        assert_eq!(client.search.get(), Some(Ok(3)));
+        assert_eq!(*client.roms.borrow(), expected);
+
+        // The search stops when the table is full.
+        assert!(one_wire.search(roms(2)).is_ok());
+        bus.run();
+        assert_eq!(client.search.get(), Some(Ok(2)));
+        assert_eq!(*client.roms.borrow(), expected[..2].to_vec());
+
+        let result = one_wire.search(roms(0));
+        assert_eq!(result.map_err(|(e, _)| e), Err(ErrorCode::SIZE));
+
+        let (one_wire, bus) = bus_master(Vec::new());
+        let client = test_client(one_wire);
+        assert!(one_wire.search(roms(2)).is_ok());
+        bus.run();
+        assert_eq!(client.search.get(), Some(Err(ErrorCode::NODEVICE)));
+    }
+
+    type Sensors = Ds18b20<'static, Master, MockAlarm<'static>>;
+
+    fn sensors(
+        devices: Vec<Device>,
+    ) -> (
+        &'static Sensors,
+        &'static Bus,
+        &'static MockAlarm<'static>,
+        &'static mock_process::MockProcess,
+    ) {
+        let (one_wire, bus) = bus_master(devices);
+        let (kernel, processes) = mock_process::kernel(&["thermo"]);
+        let alarm = mock_process::leak(MockAlarm::new());
+        let driver = mock_process::leak(Ds18b20::new(
+            one_wire,
+            alarm,
+            roms(ds18b20::MAX_DEVICES),
+            mock_process::grant(kernel),
+        ));
+        one_wire.set_client(driver);
+        alarm.set_alarm_client(driver);
+        let app = processes[0];
+        for subscribe_num in 0..2 {
+            assert!(driver
+                .subscribe(
+                    subscribe_num,
+                    app.upcall(ds18b20::DRIVER_NUM, subscribe_num),
+                    app.processid()
+                )
+                .is_ok());
+        }
+        (driver, bus, alarm, app)
+    }
+
+    #[test]
+    fn test_temperature_read() {
+        // 25.0625 and -10.125 degrees.
+        let (driver, bus, alarm, app) = sensors(vec![Device::new(1, 0x0191), Device::new(2, -162)]);
+        let id = app.processid();
+        assert!(driver.command(1, 0, 0, id).is_success());
+        assert_eq!(
+            driver.command(1, 0, 0, id).get_failure(),
+            Some(ErrorCode::BUSY)
+        );
+        bus.run();
+        assert_eq!(app.take_upcalls(), [(1, 0, 2, 0)]);
+
+        assert!(driver.command(3, 0, 0, id).is_success());
+        bus.run();
+        assert_eq!(
+            bus.devices
+                .iter()
+                .map(|d| d.conversions.get())
+                .collect::<Vec<_>>(),
+            vec![1, 1]
+        );
+        // Readings wait for the 750 ms conversion.
+        assert_eq!(alarm.until_alarm(), Some(750_000));
+        assert_eq!(app.take_upcalls(), []);
+
+        // The search finds the sensor with serial 2 first, as the lowest bit
+        // where the ROM codes differ is 0 in its code.
+        let low_word = |index| {
+            driver
+                .command(2, index, 0, id)
+                .get_success_u32_u32()
+                .unwrap()
+                .0
+        };
+        assert_eq!(low_word(0) >> 8 & 0xFF, 2);
+        assert_eq!(low_word(1) >> 8 & 0xFF, 1);
+
+        alarm.fire();
+        bus.run();
+        assert_eq!(
+            app.take_upcalls(),
+            [(0, 0, 0, -1012i32 as usize), (0, 0, 1, 2506)]
+        );
+
+        // A single sensor is matched by its ROM code.
+        assert!(driver.command(4, 0, 0, id).is_success());
+        bus.run();
+        assert_eq!(bus.devices[0].conversions.get(), 1);
+        assert_eq!(bus.devices[1].conversions.get(), 2);
+        alarm.fire();
+        bus.run();
+        assert_eq!(app.take_upcalls(), [(0, 0, 0, -1012i32 as usize)]);
+    }
+
+    #[test]
+    fn test_scratchpad_crc_failure() {
+        let device = Device::new(1, 0x0191);
+        let mut scratchpad = device.scratchpad.get();
+        scratchpad[8] ^= 0x01;
+        device.scratchpad.set(scratchpad);
+        let (driver, bus, alarm, app) = sensors(vec![device]);
+        let id = app.processid();
+        assert!(driver.command(1, 0, 0, id).is_success());
+        bus.run();
+        assert_eq!(app.take_upcalls(), [(1, 0, 1, 0)]);
+
+        assert!(driver.command(3, 0, 0, id).is_success());
+        bus.run();
+        alarm.fire();
+        bus.run();
+        assert_eq!(
+            app.take_upcalls(),
+            [(0, kernel::into_statuscode(Err(ErrorCode::FAIL)), 0, 0)]
+        );
+    }
+
+    #[derive(Default)]
+    struct Thermometer {
+        readings: RefCell<Vec<usize>>,
+    }
+
+    impl TemperatureClient for Thermometer {
+        fn callback(&self, value: usize) {
+            self.readings.borrow_mut().push(value);
+        }
+    }
+
+    #[test]
+    fn test_temperature_client() {
+        let (driver, bus, alarm, _) = sensors(vec![Device::new(1, 0x0191)]);
+        let thermometer = mock_process::leak(Thermometer::default());
+        driver.set_client(thermometer);
+
+        // The first reading searches the bus for the sensor.
+        assert_eq!(driver.read_temperature(), Ok(()));
+        assert_eq!(driver.read_temperature(), Err(ErrorCode::BUSY));
+        bus.run();
+        assert_eq!(bus.devices[0].conversions.get(), 1);
+        alarm.fire();
+        bus.run();
+        assert_eq!(*thermometer.readings.borrow(), [2506]);
+
+        assert_eq!(driver.read_temperature(), Ok(()));
+        bus.run();
+        alarm.fire();
+        bus.run();
+        assert_eq!(*thermometer.readings.borrow(), [2506, 2506]);
+        assert_eq!(bus.devices[0].conversions.get(), 2);
+    }
+}
@@ -0,0 +1,425 @@
+//! Over-the-air update of applications.
+//!
+//! `Ota` receives an application image from a transport, writes it to the
+//! inactive slot of the A/B update slots also used by `app_flash_driver`,
+//! verifies its CRC-32, and then rewrites the update metadata to make that
+//! slot active. Boards load processes from the active slot at boot, so the
+//! new image replaces the old one on the next restart. Switching slots is a
+//! single write of the metadata record, so an interrupted or corrupt update
+//! leaves the old image in use.
+//!
+//! Images arrive through an `OtaTransport`, so the same capsule can receive
+//! them over serial, UDP or BLE. `UartOtaTransport` receives them over a UART.
+//!
+//! Protocol
+//! --------
+//!
+//! The sender first sends a header of `HEADER_LEN` bytes: the magic `OTA1`,
+//! then the length of the image and its CRC-32 (IEEE 802.3), as little-endian
+//! `u32`s. It then sends the image, in messages of at most the length of the
+//! buffer given to the capsule. Once the image is committed, or if it is
+//! rejected, the capsule reports the result to the sender and waits for the
+//! next header. A header is rejected with `INVAL` if its magic is wrong, and
+//! with `SIZE` if the image does not fit in a slot; a corrupt image is
+//! rejected with `FAIL`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ota_transport = static_init!(
+//!     capsules::ota::UartOtaTransport<'static>,
+//!     capsules::ota::UartOtaTransport::new(uart, &mut capsules::ota::REPORT_BUFFER)
+//! );
+//! hil::uart::Transmit::set_transmit_client(uart, ota_transport);
+//! hil::uart::Receive::set_receive_client(uart, ota_transport);
+//! let ota = static_init!(
+//!     capsules::ota::Ota<'static>,
+//!     capsules::ota::Ota::new(
+//!         nv_to_page,
+//!         ota_transport,
+//!         capsules::app_flash_driver::UpdateSlots::new(
+//!             0x7F000, // metadata record
+//!             0x40000, // slot A
+//!             0x5F000, // slot B
+//!             0x1F000, // slot length
+//!         ),
+//!         &mut capsules::ota::BUFFER,
+//!     )
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, ota);
+//! ota_transport.set_client(ota);
+//! ota.start();
+//! ```
+//!
+//! At boot, the board loads processes from the active slot:
+//!
+//! ```rust
+//! let slot = capsules::app_flash_driver::active_slot(metadata_record);
+//! let app_flash = if slot == 0 { slot_a_flash } else { slot_b_flash };
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ErrorCode;
+
+use crate::app_flash_driver::{crc32_update, SlotMetadata, UpdateSlots, METADATA_LENGTH};
+
+/// Buffer for receiving images and writing them to flash.
+pub static mut BUFFER: [u8; 512] = [0; 512];
+
+/// Buffer for the reports of `UartOtaTransport`.
+pub static mut REPORT_BUFFER: [u8; 1] = [0; 1];
+
+/// Length of the header that starts an image.
+pub const HEADER_LEN: usize = 12;
+
+/// Magic bytes at the start of the header.
+const HEADER_MAGIC: [u8; 4] = *b"OTA1";
+
+/// Receives images from the sender of updates.
+pub trait OtaTransport<'a> {
+    /// Set the client that receives the messages.
+    fn set_client(&self, client: &'a dyn OtaTransportClient);
+
+    /// Receive the next message, of at most `length` bytes, in to `buffer`.
+    fn receive(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Tell the sender whether the image it sent was committed.
+    fn report(&self, result: Result<(), ErrorCode>);
+}
+
+/// Receive callbacks from `OtaTransport`.
+pub trait OtaTransportClient {
+    /// A message of `length` bytes was received in to `buffer`, or the
+    /// reception failed.
+    fn received(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Reading the update metadata record.
+    LoadMetadata,
+    /// Waiting for the header of an image.
+    Header,
+    /// Receiving the image and writing it to the inactive slot.
+    Data,
+    /// Reading back the inactive slot to verify it.
+    Verify,
+    /// Writing updated metadata to commit the image.
+    Commit,
+}
+
+pub struct Ota<'a> {
+    driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    transport: &'a dyn OtaTransport<'a>,
+    slots: UpdateSlots,
+    metadata: OptionalCell<SlotMetadata>,
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    // Length and CRC-32 of the image from its header.
+    image_length: Cell<usize>,
+    image_crc: Cell<u32>,
+    // Bytes of the image written or verified so far, and their CRC-32.
+    offset: Cell<usize>,
+    crc: Cell<u32>,
+}
+
+impl<'a> Ota<'a> {
+    pub fn new(
+        driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        transport: &'a dyn OtaTransport<'a>,
+        slots: UpdateSlots,
+        buffer: &'static mut [u8],
+    ) -> Ota<'a> {
+        Ota {
+            driver: driver,
+            transport: transport,
+            slots: slots,
+            metadata: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            image_length: Cell::new(0),
+            image_crc: Cell::new(0),
+            offset: Cell::new(0),
+            crc: Cell::new(0),
+        }
+    }
+
+    /// Read the update metadata and start waiting for images.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                if buffer.len() < HEADER_LEN {
+                    self.buffer.replace(buffer);
+                    return Err(ErrorCode::SIZE);
+                }
+                self.state.set(State::LoadMetadata);
+                self.driver
+                    .read(buffer, self.slots.metadata_address, METADATA_LENGTH)
+                    .map_err(|e| {
+                        self.state.set(State::Idle);
+                        e
+                    })
+            })
+    }
+
+    /// Wait for the header of the next image.
+    fn receive_header(&self, buffer: &'static mut [u8]) {
+        self.state.set(State::Header);
+        if let Err((_, buffer)) = self.transport.receive(buffer, HEADER_LEN) {
+            self.buffer.replace(buffer);
+            self.state.set(State::Idle);
+        }
+    }
+
+    /// Receive the next part of the image.
+    fn receive_data(&self, buffer: &'static mut [u8]) {
+        let length = cmp::min(buffer.len(), self.image_length.get() - self.offset.get());
+        self.state.set(State::Data);
+        if let Err((e, buffer)) = self.transport.receive(buffer, length) {
+            self.abort(buffer, e);
+        }
+    }
+
+    /// Read the next chunk of the inactive slot to verify.
+    fn verify_next(&self, buffer: &'static mut [u8]) -> Result<(), ErrorCode> {
+        let inactive = self.metadata.extract().map_or(1, |m| m.inactive());
+        let offset = self.offset.get();
+        let length = cmp::min(buffer.len(), self.image_length.get() - offset);
+        self.state.set(State::Verify);
+        self.driver
+            .read(buffer, self.slots.slot_addresses[inactive] + offset, length)
+    }
+
+    /// Reject the current image and wait for the next one.
+    fn abort(&self, buffer: &'static mut [u8], error: ErrorCode) {
+        self.transport.report(Err(error));
+        self.receive_header(buffer);
+    }
+
+    /// A flash operation failed and took the buffer with it, so no more
+    /// images can be received.
+    fn fail(&self, error: ErrorCode) {
+        self.transport.report(Err(error));
+        self.state.set(State::Idle);
+    }
+
+    fn header_received(&self, buffer: &'static mut [u8], length: usize) {
+        let word = |i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&buffer[i..i + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        if length < HEADER_LEN || buffer[0..4] != HEADER_MAGIC {
+            return self.abort(buffer, ErrorCode::INVAL);
+        }
+        let image_length = word(4) as usize;
+        let image_crc = word(8);
+        if image_length == 0 || image_length > self.slots.slot_length {
+            return self.abort(buffer, ErrorCode::SIZE);
+        }
+
+        // The inactive slot no longer holds a valid image.
+        self.metadata.map(|metadata| {
+            metadata.valid &= !(1 << metadata.inactive());
+        });
+        self.image_length.set(image_length);
+        self.image_crc.set(image_crc);
+        self.offset.set(0);
+        self.receive_data(buffer);
+    }
+
+    fn data_received(&self, buffer: &'static mut [u8], length: usize) {
+        let inactive = self.metadata.extract().map_or(1, |m| m.inactive());
+        let offset = self.offset.get();
+        let length = cmp::min(length, self.image_length.get() - offset);
+        if length == 0 {
+            return self.receive_data(buffer);
+        }
+        if let Err(e) =
+            self.driver
+                .write(buffer, self.slots.slot_addresses[inactive] + offset, length)
+        {
+            self.fail(e);
+        }
+    }
+
+    fn verified(&self, buffer: &'static mut [u8]) {
+        if self.crc.get() != self.image_crc.get() {
+            // The image is corrupt, keep the active slot.
+            return self.abort(buffer, ErrorCode::FAIL);
+        }
+
+        // Switch to the verified slot.
+        let committed = self.metadata.extract().map_or(
+            SlotMetadata {
+                active: 0,
+                valid: 0b01,
+            },
+            |metadata| SlotMetadata {
+                active: metadata.inactive(),
+                valid: metadata.valid | (1 << metadata.inactive()),
+            },
+        );
+        committed.serialize(buffer);
+        self.state.set(State::Commit);
+        if let Err(e) = self
+            .driver
+            .write(buffer, self.slots.metadata_address, METADATA_LENGTH)
+        {
+            self.fail(e);
+        }
+    }
+}
+
+impl OtaTransportClient for Ota<'_> {
+    fn received(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        if let Err(e) = result {
+            return self.abort(buffer, e);
+        }
+        match self.state.get() {
+            State::Header => self.header_received(buffer, length),
+            State::Data => self.data_received(buffer, length),
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+}
+
+impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Ota<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        match self.state.get() {
+            State::LoadMetadata => {
+                self.metadata.set(SlotMetadata::parse(&buffer[..length]));
+                self.receive_header(buffer);
+            }
+            State::Verify => {
+                self.crc
+                    .set(crc32_update(self.crc.get(), &buffer[..length]));
+                self.offset.set(self.offset.get() + length);
+                if self.offset.get() < self.image_length.get() {
+                    if let Err(e) = self.verify_next(buffer) {
+                        self.fail(e);
+                    }
+                } else {
+                    self.verified(buffer);
+                }
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        match self.state.get() {
+            State::Data => {
+                self.offset.set(self.offset.get() + length);
+                if self.offset.get() < self.image_length.get() {
+                    self.receive_data(buffer);
+                } else {
+                    // The whole image is written, read it back to verify it.
+                    self.offset.set(0);
+                    self.crc.set(0);
+                    if let Err(e) = self.verify_next(buffer) {
+                        self.fail(e);
+                    }
+                }
+            }
+            State::Commit => {
+                // The metadata record now names the updated slot as active,
+                // and the image runs after the next restart.
+                self.metadata.set(SlotMetadata::parse(buffer));
+                self.transport.report(Ok(()));
+                self.receive_header(buffer);
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+}
+
+/// Receives images over a UART. Reports are a single byte: 0 if the image
+/// was committed, or the error code otherwise.
+pub struct UartOtaTransport<'a> {
+    uart: &'a dyn hil::uart::UartData<'a>,
+    client: OptionalCell<&'a dyn OtaTransportClient>,
+    report_buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a> UartOtaTransport<'a> {
+    pub fn new(
+        uart: &'a dyn hil::uart::UartData<'a>,
+        report_buffer: &'static mut [u8],
+    ) -> UartOtaTransport<'a> {
+        UartOtaTransport {
+            uart: uart,
+            client: OptionalCell::empty(),
+            report_buffer: TakeCell::new(report_buffer),
+        }
+    }
+}
+
+impl<'a> OtaTransport<'a> for UartOtaTransport<'a> {
+    fn set_client(&self, client: &'a dyn OtaTransportClient) {
+        self.client.set(client);
+    }
+
+    fn receive(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.uart.receive_buffer(buffer, length)
+    }
+
+    fn report(&self, result: Result<(), ErrorCode>) {
+        // A report is dropped if the previous one is still being sent.
+        self.report_buffer.take().map(|buffer| {
+            buffer[0] = kernel::into_statuscode(result) as u8;
+            if let Err((_, buffer)) = self.uart.transmit_buffer(buffer, 1) {
+                self.report_buffer.replace(buffer);
+            }
+        });
+    }
+}
+
+impl hil::uart::TransmitClient for UartOtaTransport<'_> {
+    fn transmitted_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        _tx_len: usize,
+        _rval: Result<(), ErrorCode>,
+    ) {
+        self.report_buffer.replace(tx_buffer);
+    }
+}
+
+impl hil::uart::ReceiveClient for UartOtaTransport<'_> {
+    fn received_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: hil::uart::Error,
+    ) {
+        self.client
+            .map(move |client| client.received(rx_buffer, rx_len, rval));
+    }
+}
@@ -9,9 +9,25 @@
 //!  - 'help' prints the available commands and arguments
 //!  - 'status' prints the current system status
 //!  - 'list' lists the current processes with their IDs and running state
+//!  - 'stats' lists how much CPU time and how many syscalls each process used
 //!  - 'stop n' stops the process with name n
 //!  - 'start n' starts the stopped process with name n
 //!  - 'fault n' forces the process with name n into a fault state
+//!  - 'trace n [off]' logs the system calls of the process with name n, or
+//!    stops logging them
+//!  - 'memory n' prints the flash and RAM layout of the process with name n
+//!  - 'grants n' prints how much memory each grant of the process with name n
+//!    uses
+//!  - 'gpio n [set|clear|in]' reads pin n, or sets, clears it or makes it an
+//!    input
+//!  - 'i2c a [b ...] [rn]' writes bytes b to the I2C device at address a and
+//!    reads n bytes back
+//!  - 'spi b [b ...]' writes bytes b over SPI and prints the bytes read back
+//!  - 'adc n' samples ADC channel n once
+//!  - 'script [continue|stop]' runs the script set with `set_script()`,
+//!    optionally setting whether it continues or stops when a command fails
+//!  - 'crash [clear]' prints the last crash record saved by the crash dump
+//!    set with `set_crash_dump()`, or erases it
 //!  - 'panic' causes the kernel to run the panic handler
 //!
 //! ### `list` Command Fields:
@@ -30,6 +46,17 @@
 //! - `Grants`: The number of grants that have been initialized for the process
 //!   out of the total number of grants defined by the kernel.
 //!
+//! ### `stats` Command Fields:
+//!
+//! - `PID`: The identifier for the process.
+//! - `Name`: The process name.
+//! - `Runs`: How many times the scheduler has run the process.
+//! - `CPU ms`: How long the process has run, in milliseconds. The scheduler
+//!   timer only measures processes that run with a timeslice, so this is zero
+//!   with cooperative schedulers.
+//! - `CPU %`: The share of the CPU time of all processes.
+//! - `Syscalls`: The number of system calls the process has made to the kernel.
+//!
 //! Setup
 //! -----
 //!
@@ -103,6 +130,7 @@
 //! Total processes: 2
 //! Active processes: 2
 //! Timeslice expirations: 0
+//! Debug buffer: 212/959 bytes at peak, 0 bytes dropped
 //! ```
 //!
 //! and you can control processes with the `start` and `stop` commands:
@@ -111,18 +139,126 @@
 //! stop blink
 //! Process blink stopped
 //! ```
+//!
+//! ### `memory` Command Fields:
+//!
+//! - `Flash`: The flash of the process, and its size in bytes.
+//! - `RAM`: The memory of the process, and its size in bytes.
+//! - `Break`: The end of the memory the process can access, and how much is
+//!   left between it and the grants.
+//! - `Grants`: The start of the grant region, and its size in bytes.
+//! - `Stack`: The most stack the process used, as seen at system calls, if
+//!   the kernel knows where its stack starts.
+//!
+//! ### `grants` Command Fields:
+//!
+//! Grants are numbered in the order the board created them, which is the
+//! order their capsules were created in. Each allocated grant is listed with
+//! the bytes it uses, including alignment padding:
+//!
+//! ```text
+//! grants blink
+//! Grant  Bytes
+//!     0     76
+//!     4     12
+//! 2/12 grants allocated, 88 bytes
+//! ```
+//!
+//! Tracing system calls
+//! --------------------
+//!
+//! To debug how a process uses the kernel without a JTAG probe, `trace`
+//! logs its system calls to the debug console: the driver and command or
+//! subscribe numbers, the arguments, and what the kernel returned. Each run
+//! of the process starts with a line that gives how many times it ran and
+//! how much CPU time it used so far. Only the first 16 system calls of each
+//! run are logged, and the next run reports how many were left out:
+//!
+//! ```text
+//! trace blink
+//! Tracing system calls of process blink
+//! [0] run 57, 1250 us of CPU time, 0 syscalls not traced
+//! [0] cmd(0x2, 1, 0x0, 0x0) = Success
+//! [0] yield. which: 1
+//! trace blink off
+//! ```
+//!
+//! Scripts
+//! -------
+//!
+//! To make a debugging session repeatable, a board can give the console a
+//! script of newline-separated commands with `set_script()`, which the
+//! `script` command then runs in order. Each command is echoed before its
+//! output, and a summary is printed at the end:
+//!
+//! ```text
+//! script
+//! > stop blink
+//! Process blink stopped
+//! > list
+//! PID    Name    Quanta  Syscalls  Dropped Upcalls  Restarts    State  Grants
+//! 00     blink        0       113                0         0  StoppedYielded    1/12
+//! Script finished: 2 commands, 0 failed
+//! ```
+//!
+//! Scripts can also be run directly from the kernel with `run_script()`.
+//! Since each command runs as soon as the one before it returns, scripts
+//! cannot use the `i2c`, `spi` and `adc` commands, whose results come later.
+//!
+//! Peripheral Commands
+//! -------------------
+//!
+//! To bring up a board before any app exists, the console can drive GPIO
+//! pins, run one-shot I2C and SPI transactions and sample ADC channels. The
+//! board enables each with a `PeripheralAccessCapability`, since these
+//! accesses go around the capsules that normally use the peripherals, and
+//! makes the console the client of the buses and channels it gives it:
+//!
+//! ```rust
+//! # use kernel::{capabilities, hil};
+//!
+//! struct PeripheralAccess;
+//! unsafe impl capabilities::PeripheralAccessCapability for PeripheralAccess {}
+//!
+//! pconsole.set_gpio_pins(gpio_pins, &Capability);
+//! pconsole.set_i2c(&peripherals.i2c0, &mut capsules::process_console::I2C_BUF, &Capability);
+//! hil::i2c::I2CMaster::set_master_client(&peripherals.i2c0, pconsole);
+//! ```
+//!
+//! Numbers are decimal, or hexadecimal with a `0x` prefix. I2C and SPI
+//! transactions are at most 16 bytes:
+//!
+//! ```text
+//! gpio 3 set
+//! GPIO 3: 1
+//! i2c 0x48 0x00 r2
+//! I2C 0x48: [0C, 80]
+//! adc 0
+//! ADC 0: 2048
+//! ```
+//!
+//! Crash Records
+//! -------------
+//!
+//! When the board gives the console its `crash_dump::CrashDump` with
+//! `set_crash_dump()`, the `crash` command prints the last panic or process
+//! fault saved to flash, and `crash clear` erases it. Records are printed
+//! through the debug buffer, so records longer than it are cut short.
 
 use core::cell::Cell;
 use core::cmp;
 use core::str;
-use kernel::capabilities::ProcessManagementCapability;
-use kernel::common::cells::TakeCell;
+use kernel::capabilities::{PeripheralAccessCapability, ProcessManagementCapability};
+use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::debug;
-use kernel::hil::uart;
+use kernel::hil::{adc, gpio, i2c, spi, uart};
 use kernel::introspection::KernelInfo;
+use kernel::procs::Process;
 use kernel::ErrorCode;
 use kernel::Kernel;
 
+use crate::crash_dump::{CrashKind, CrashRecords};
+
 // Since writes are character echoes, we do not need more than 4 bytes:
 // the longest write is 3 bytes for a backspace (backspace, space, backspace).
 pub static mut WRITE_BUF: [u8; 4] = [0; 4];
@@ -132,6 +268,20 @@ pub static mut READ_BUF: [u8; 4] = [0; 4];
 // Commands can be up to 32 bytes long: since commands themselves are 4-5
 // characters, limiting arguments to 25 bytes or so seems fine for now.
 pub static mut COMMAND_BUF: [u8; 32] = [0; 32];
+// Buffers for the one-shot I2C and SPI transactions of the peripheral
+// commands.
+pub static mut I2C_BUF: [u8; 16] = [0; 16];
+pub static mut SPI_WRITE_BUF: [u8; 16] = [0; 16];
+pub static mut SPI_READ_BUF: [u8; 16] = [0; 16];
+
+/// What a script does when one of its commands fails.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ScriptErrorPolicy {
+    /// Report the failure and run the remaining commands.
+    Continue,
+    /// Report the failure and skip the remaining commands.
+    Stop,
+}
 
 pub struct ProcessConsole<'a, C: ProcessManagementCapability> {
     uart: &'a dyn uart::UartData<'a>,
@@ -149,6 +299,27 @@ pub struct ProcessConsole<'a, C: ProcessManagementCapability> {
     /// Internal flag that the process console should parse the command it just
     /// received after finishing echoing the last newline character.
     execute: Cell<bool>,
+
+    /// Script run by the `script` command, and whether it stops at the first
+    /// failing command.
+    script: Cell<Option<&'static str>>,
+    script_policy: Cell<ScriptErrorPolicy>,
+
+    /// Crash records printed by the `crash` command.
+    crash_dump: OptionalCell<&'a dyn CrashRecords>,
+
+    /// Peripherals driven by the peripheral commands, and their state.
+    gpio_pins: Cell<&'a [&'a dyn gpio::Pin]>,
+    i2c: OptionalCell<&'a dyn i2c::I2CMaster>,
+    i2c_buffer: TakeCell<'static, [u8]>,
+    i2c_address: Cell<u8>,
+    i2c_read_len: Cell<usize>,
+    spi: OptionalCell<&'a dyn spi::SpiMasterDevice>,
+    spi_write_buffer: TakeCell<'static, [u8]>,
+    spi_read_buffer: TakeCell<'static, [u8]>,
+    adc_channels: Cell<&'a [&'a dyn adc::AdcChannel]>,
+    adc_channel: OptionalCell<usize>,
+
     kernel: &'static Kernel,
     capability: C,
 }
@@ -172,6 +343,19 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
             command_index: Cell::new(0),
             running: Cell::new(false),
             execute: Cell::new(false),
+            script: Cell::new(None),
+            script_policy: Cell::new(ScriptErrorPolicy::Continue),
+            crash_dump: OptionalCell::empty(),
+            gpio_pins: Cell::new(&[]),
+            i2c: OptionalCell::empty(),
+            i2c_buffer: TakeCell::empty(),
+            i2c_address: Cell::new(0),
+            i2c_read_len: Cell::new(0),
+            spi: OptionalCell::empty(),
+            spi_write_buffer: TakeCell::empty(),
+            spi_read_buffer: TakeCell::empty(),
+            adc_channels: Cell::new(&[]),
+            adc_channel: OptionalCell::empty(),
             kernel: kernel,
             capability: capability,
         }
@@ -189,6 +373,287 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
         Ok(())
     }
 
+    /// Set the script run by the `script` command. The script holds one
+    /// command per line.
+    pub fn set_script(&self, script: &'static str) {
+        self.script.set(Some(script));
+    }
+
+    /// Set whether a script keeps running after a command fails.
+    pub fn set_script_error_policy(&self, policy: ScriptErrorPolicy) {
+        self.script_policy.set(policy);
+    }
+
+    /// Set the crash records printed by the `crash` command.
+    pub fn set_crash_dump(&self, crash_dump: &'a dyn CrashRecords) {
+        self.crash_dump.set(crash_dump);
+    }
+
+    /// Set the pins driven by the `gpio` command, numbered by their index.
+    pub fn set_gpio_pins(
+        &self,
+        pins: &'a [&'a dyn gpio::Pin],
+        _capability: &dyn PeripheralAccessCapability,
+    ) {
+        self.gpio_pins.set(pins);
+    }
+
+    /// Set the bus used by the `i2c` command. The console must be the client
+    /// of `i2c`.
+    pub fn set_i2c(
+        &self,
+        i2c: &'a dyn i2c::I2CMaster,
+        buffer: &'static mut [u8],
+        _capability: &dyn PeripheralAccessCapability,
+    ) {
+        self.i2c.set(i2c);
+        self.i2c_buffer.replace(buffer);
+    }
+
+    /// Set the device used by the `spi` command. The console must be the
+    /// client of `spi`.
+    pub fn set_spi(
+        &self,
+        spi: &'a dyn spi::SpiMasterDevice,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+        _capability: &dyn PeripheralAccessCapability,
+    ) {
+        self.spi.set(spi);
+        self.spi_write_buffer.replace(write_buffer);
+        self.spi_read_buffer.replace(read_buffer);
+    }
+
+    /// Set the channels sampled by the `adc` command, numbered by their
+    /// index. The console must be the client of each channel.
+    pub fn set_adc_channels(
+        &self,
+        channels: &'a [&'a dyn adc::AdcChannel],
+        _capability: &dyn PeripheralAccessCapability,
+    ) {
+        self.adc_channels.set(channels);
+    }
+
+    /// Parse a decimal number, or a hexadecimal one with a `0x` prefix.
+    fn parse_number(argument: Option<&str>) -> Result<usize, ErrorCode> {
+        let argument = argument.ok_or(ErrorCode::INVAL)?;
+        let parsed = if argument.starts_with("0x") {
+            usize::from_str_radix(&argument[2..], 16)
+        } else {
+            argument.parse::<usize>()
+        };
+        parsed.map_err(|_| {
+            debug!("Invalid number: {}", argument);
+            ErrorCode::INVAL
+        })
+    }
+
+    /// Read, set or clear a GPIO pin.
+    fn gpio_command<'b, I: Iterator<Item = &'b str>>(
+        &self,
+        mut arguments: I,
+    ) -> Result<(), ErrorCode> {
+        let index = Self::parse_number(arguments.next())?;
+        let pin = self.gpio_pins.get().get(index).ok_or_else(|| {
+            debug!("No GPIO pin {}", index);
+            ErrorCode::NODEVICE
+        })?;
+        match arguments.next() {
+            None => {}
+            Some("set") => {
+                pin.make_output();
+                pin.set();
+            }
+            Some("clear") => {
+                pin.make_output();
+                pin.clear();
+            }
+            Some("in") => {
+                pin.make_input();
+            }
+            Some(_) => {
+                debug!("Usage: gpio <pin> [set|clear|in]");
+                return Err(ErrorCode::INVAL);
+            }
+        }
+        debug!("GPIO {}: {}", index, pin.read() as u8);
+        Ok(())
+    }
+
+    /// Start a write and read transaction with an I2C device. The result is
+    /// printed when it completes.
+    fn i2c_command<'b, I: Iterator<Item = &'b str>>(
+        &self,
+        mut arguments: I,
+    ) -> Result<(), ErrorCode> {
+        let i2c = self.i2c.extract().ok_or_else(|| {
+            debug!("No I2C bus");
+            ErrorCode::NOSUPPORT
+        })?;
+        let address = Self::parse_number(arguments.next())?;
+        let buffer = self.i2c_buffer.take().ok_or(ErrorCode::BUSY)?;
+        let mut write_len = 0;
+        let mut read_len = 0;
+        for argument in arguments {
+            let parsed = if argument.starts_with('r') {
+                Self::parse_number(Some(&argument[1..])).map(|len| read_len = len)
+            } else {
+                Self::parse_number(Some(argument)).map(|byte| {
+                    if write_len < buffer.len() {
+                        buffer[write_len] = byte as u8;
+                    }
+                    write_len += 1;
+                })
+            };
+            if let Err(e) = parsed {
+                self.i2c_buffer.replace(buffer);
+                return Err(e);
+            }
+        }
+        if address > 0x7F
+            || write_len > buffer.len()
+            || read_len > buffer.len()
+            || write_len + read_len == 0
+        {
+            debug!("Usage: i2c <address> [byte ...] [r<length>]");
+            self.i2c_buffer.replace(buffer);
+            return Err(ErrorCode::INVAL);
+        }
+
+        self.i2c_address.set(address as u8);
+        self.i2c_read_len.set(read_len);
+        i2c.enable();
+        if read_len == 0 {
+            i2c.write(address as u8, buffer, write_len as u8);
+        } else if write_len == 0 {
+            i2c.read(address as u8, buffer, read_len as u8);
+        } else {
+            i2c.write_read(address as u8, buffer, write_len as u8, read_len as u8);
+        }
+        Ok(())
+    }
+
+    /// Start an SPI transaction. The bytes read are printed when it
+    /// completes.
+    fn spi_command<'b, I: Iterator<Item = &'b str>>(&self, arguments: I) -> Result<(), ErrorCode> {
+        let spi = self.spi.extract().ok_or_else(|| {
+            debug!("No SPI device");
+            ErrorCode::NOSUPPORT
+        })?;
+        let write_buffer = self.spi_write_buffer.take().ok_or(ErrorCode::BUSY)?;
+        let mut len = 0;
+        for argument in arguments {
+            match Self::parse_number(Some(argument)) {
+                Ok(byte) if len < write_buffer.len() => {
+                    write_buffer[len] = byte as u8;
+                    len += 1;
+                }
+                Ok(_) => {
+                    self.spi_write_buffer.replace(write_buffer);
+                    return Err(ErrorCode::SIZE);
+                }
+                Err(e) => {
+                    self.spi_write_buffer.replace(write_buffer);
+                    return Err(e);
+                }
+            }
+        }
+        if len == 0 {
+            debug!("Usage: spi <byte> [byte ...]");
+            self.spi_write_buffer.replace(write_buffer);
+            return Err(ErrorCode::INVAL);
+        }
+        spi.read_write_bytes(write_buffer, self.spi_read_buffer.take(), len)
+    }
+
+    /// Sample an ADC channel once. The sample is printed when it is ready.
+    fn adc_command(&self, argument: Option<&str>) -> Result<(), ErrorCode> {
+        let index = Self::parse_number(argument)?;
+        let channel = self.adc_channels.get().get(index).ok_or_else(|| {
+            debug!("No ADC channel {}", index);
+            ErrorCode::NODEVICE
+        })?;
+        if self.adc_channel.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        channel.sample()?;
+        self.adc_channel.set(index);
+        Ok(())
+    }
+
+    /// Print the last crash record, or erase it if `argument` is `clear`.
+    fn crash_command(&self, argument: Option<&str>) -> Result<(), ErrorCode> {
+        let crash_dump = self.crash_dump.extract().ok_or_else(|| {
+            debug!("No crash dump");
+            ErrorCode::NOSUPPORT
+        })?;
+        if argument == Some("clear") {
+            return crash_dump.clear().map(|()| debug!("Crash record erased"));
+        }
+        let found = crash_dump.last_record(&mut |kind, text| {
+            match kind {
+                CrashKind::Panic => debug!("Last crash: kernel panic"),
+                CrashKind::ProcessFault => debug!("Last crash: process fault"),
+            }
+            for line in text.split(|&byte| byte == b'\n') {
+                match str::from_utf8(line) {
+                    Ok(line) => debug!("{}", line.trim_end_matches('\r')),
+                    Err(_) => debug!("<invalid text>"),
+                }
+            }
+        });
+        if !found {
+            debug!("No crash record");
+        }
+        Ok(())
+    }
+
+    /// Run each newline-separated command of `script` in sequence, as if it
+    /// had been typed into the console. Each command is echoed before its
+    /// output so the output of a script can be followed. Blank lines are
+    /// skipped, and `script`, `i2c`, `spi` and `adc` commands fail, since
+    /// they could not finish before the next line. Returns the number of commands run and the number that
+    /// failed.
+    pub fn run_script(&self, script: &str) -> (usize, usize) {
+        let mut commands = 0;
+        let mut failures = 0;
+        for line in script.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            commands += 1;
+            debug!("> {}", line);
+            // Scripts cannot start other scripts, nor peripheral commands
+            // that finish after they return, since the next command would
+            // find the peripheral still busy.
+            let result = if line.starts_with("script") {
+                debug!("Scripts cannot run scripts");
+                Err(ErrorCode::INVAL)
+            } else if ["i2c", "spi", "adc"]
+                .iter()
+                .any(|command| line.starts_with(command))
+            {
+                debug!("Scripts cannot run i2c, spi or adc commands");
+                Err(ErrorCode::INVAL)
+            } else {
+                self.execute_command(line)
+            };
+            if result.is_err() {
+                failures += 1;
+                if self.script_policy.get() == ScriptErrorPolicy::Stop {
+                    debug!("Script stopped at line: {}", line);
+                    break;
+                }
+            }
+        }
+        debug!(
+            "Script finished: {} commands, {} failed",
+            commands, failures
+        );
+        (commands, failures)
+    }
+
     // Process the command in the command buffer and clear the buffer.
     fn read_command(&self) {
         self.command_buffer.map(|command| {
@@ -209,92 +674,22 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                 match cmd_str {
                     Ok(s) => {
                         let clean_str = s.trim();
-                        if clean_str.starts_with("help") {
-                            debug!("Welcome to the process console.");
-                            debug!("Valid commands are: help status list stop start fault panic");
-                        } else if clean_str.starts_with("start") {
-                            let argument = clean_str.split_whitespace().nth(1);
-                            argument.map(|name| {
-                                self.kernel.process_each_capability(
-                                    &self.capability,
-                                    |proc| {
-                                        let proc_name = proc.get_process_name();
-                                        if proc_name == name {
-                                            proc.resume();
-                                            debug!("Process {} resumed.", name);
-                                        }
-                                    },
-                                );
-                            });
-                        } else if clean_str.starts_with("stop") {
-                            let argument = clean_str.split_whitespace().nth(1);
-                            argument.map(|name| {
-                                self.kernel.process_each_capability(
-                                    &self.capability,
-                                    |proc| {
-                                        let proc_name = proc.get_process_name();
-                                        if proc_name == name {
-                                            proc.stop();
-                                            debug!("Process {} stopped", proc_name);
-                                        }
-                                    },
-                                );
-                            });
-                        } else if clean_str.starts_with("fault") {
-                            let argument = clean_str.split_whitespace().nth(1);
-                            argument.map(|name| {
-                                self.kernel.process_each_capability(
-                                    &self.capability,
-                                    |proc| {
-                                        let proc_name = proc.get_process_name();
-                                        if proc_name == name {
-                                            proc.set_fault_state();
-                                            debug!("Process {} now faulted", proc_name);
-                                        }
-                                    },
-                                );
-                            });
-                        } else if clean_str.starts_with("list") {
-                            debug!(" PID    Name                Quanta  Syscalls  Dropped Upcalls  Restarts    State  Grants");
-                            self.kernel
-                                .process_each_capability(&self.capability, |proc| {
-                                    let info: KernelInfo = KernelInfo::new(self.kernel);
-
-                                    let pname = proc.get_process_name();
-                                    let appid = proc.processid();
-                                    let (grants_used, grants_total) = info.number_app_grant_uses(appid, &self.capability);
-
-                                    debug!(
-                                        "  {:?}\t{:<20}{:6}{:10}{:17}{:10}  {:?}{:5}/{}",
-                                        appid,
-                                        pname,
-                                        proc.debug_timeslice_expiration_count(),
-                                        proc.debug_syscall_count(),
-                                        proc.debug_dropped_upcall_count(),
-                                        proc.get_restart_count(),
-                                        proc.get_state(),
-                                        grants_used,
-                                        grants_total
-                                    );
-                                });
-                        } else if clean_str.starts_with("status") {
-                            let info: KernelInfo = KernelInfo::new(self.kernel);
-                            debug!(
-                                "Total processes: {}",
-                                info.number_loaded_processes(&self.capability)
-                            );
-                            debug!(
-                                "Active processes: {}",
-                                info.number_active_processes(&self.capability)
-                            );
-                            debug!(
-                                "Timeslice expirations: {}",
-                                info.timeslice_expirations(&self.capability)
-                            );
-                        } else if clean_str.starts_with("panic") {
-                            panic!("ProcessConsole forced a kernel panic.");
+                        if clean_str.starts_with("script") {
+                            match clean_str.split_whitespace().nth(1) {
+                                Some("continue") => {
+                                    self.script_policy.set(ScriptErrorPolicy::Continue)
+                                }
+                                Some("stop") => self.script_policy.set(ScriptErrorPolicy::Stop),
+                                _ => {}
+                            }
+                            match self.script.get() {
+                                Some(script) => {
+                                    let _ = self.run_script(script);
+                                }
+                                None => debug!("No script set"),
+                            }
                         } else {
-                            debug!("Valid commands are: help status list stop start fault");
+                            let _ = self.execute_command(clean_str);
                         }
                     }
                     Err(_e) => debug!("Invalid command: {:?}", command),
@@ -307,6 +702,220 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
         self.command_index.set(0);
     }
 
+    /// Run a process management command on the processes named `argument`.
+    /// Fails if no name is given or no process has that name.
+    fn process_command<F: Fn(&dyn Process)>(
+        &self,
+        argument: Option<&str>,
+        action: F,
+    ) -> Result<(), ErrorCode> {
+        let name = argument.ok_or(ErrorCode::INVAL)?;
+        let found = Cell::new(false);
+        self.kernel
+            .process_each_capability(&self.capability, |proc| {
+                if proc.get_process_name() == name {
+                    found.set(true);
+                    action(proc);
+                }
+            });
+        if found.get() {
+            Ok(())
+        } else {
+            debug!("No process named {}", name);
+            Err(ErrorCode::FAIL)
+        }
+    }
+
+    // Execute a single command, returning an error if it is not a valid
+    // command or could not be carried out.
+    fn execute_command(&self, clean_str: &str) -> Result<(), ErrorCode> {
+        if clean_str.starts_with("help") {
+            debug!("Welcome to the process console.");
+            debug!(
+                "Valid commands are: help status list stats stop start fault trace memory grants gpio i2c spi adc script crash panic"
+            );
+        } else if clean_str.starts_with("start") {
+            let argument = clean_str.split_whitespace().nth(1);
+            self.process_command(argument, |proc| {
+                proc.resume();
+                debug!("Process {} resumed.", proc.get_process_name());
+            })?;
+        } else if clean_str.starts_with("stop") {
+            let argument = clean_str.split_whitespace().nth(1);
+            self.process_command(argument, |proc| {
+                proc.stop();
+                debug!("Process {} stopped", proc.get_process_name());
+            })?;
+        } else if clean_str.starts_with("fault") {
+            let argument = clean_str.split_whitespace().nth(1);
+            self.process_command(argument, |proc| {
+                proc.set_fault_state();
+                debug!("Process {} now faulted", proc.get_process_name());
+            })?;
+        } else if clean_str.starts_with("trace") {
+            let mut arguments = clean_str.split_whitespace().skip(1);
+            let name = arguments.next();
+            let enable = match arguments.next() {
+                None => true,
+                Some("off") => false,
+                Some(_) => {
+                    debug!("Usage: trace <process name> [off]");
+                    return Err(ErrorCode::INVAL);
+                }
+            };
+            self.process_command(name, |proc| {
+                proc.debug_set_syscall_tracing(enable);
+                if enable {
+                    debug!(
+                        "Tracing system calls of process {}",
+                        proc.get_process_name()
+                    );
+                } else {
+                    debug!("Stopped tracing process {}", proc.get_process_name());
+                }
+            })?;
+        } else if clean_str.starts_with("memory") {
+            let argument = clean_str.split_whitespace().nth(1);
+            self.process_command(argument, |proc| {
+                let flash_start = proc.flash_start() as usize;
+                let flash_end = proc.flash_end() as usize;
+                let mem_start = proc.mem_start() as usize;
+                let mem_end = proc.mem_end() as usize;
+                let app_break = proc.app_memory_break() as usize;
+                let kernel_break = proc.kernel_memory_break() as usize;
+                debug!(
+                    "Flash   {:#010X}-{:#010X} {:7} bytes",
+                    flash_start,
+                    flash_end,
+                    flash_end - flash_start
+                );
+                debug!(
+                    "RAM     {:#010X}-{:#010X} {:7} bytes",
+                    mem_start,
+                    mem_end,
+                    mem_end - mem_start
+                );
+                debug!(
+                    "Break   {:#010X}            {:7} bytes free",
+                    app_break,
+                    kernel_break.saturating_sub(app_break)
+                );
+                debug!(
+                    "Grants  {:#010X}            {:7} bytes",
+                    kernel_break,
+                    mem_end - kernel_break
+                );
+                match proc.debug_stack_high_water_mark() {
+                    Some(stack) => debug!("Stack                           {:7} bytes", stack),
+                    None => debug!("Stack                                 unknown"),
+                }
+            })?;
+        } else if clean_str.starts_with("grants") {
+            let argument = clean_str.split_whitespace().nth(1);
+            self.process_command(argument, |proc| {
+                let info: KernelInfo = KernelInfo::new(self.kernel);
+                let (grants_used, grants_total) =
+                    info.number_app_grant_uses(proc.processid(), &self.capability);
+                let mut total = 0;
+                debug!("Grant  Bytes");
+                for grant_num in 0..grants_total {
+                    if let Some(size) = proc.grant_allocated_size(grant_num) {
+                        debug!("{:5}  {:5}", grant_num, size);
+                        total += size;
+                    }
+                }
+                debug!(
+                    "{}/{} grants allocated, {} bytes",
+                    grants_used, grants_total, total
+                );
+            })?;
+        } else if clean_str.starts_with("gpio") {
+            self.gpio_command(clean_str.split_whitespace().skip(1))?;
+        } else if clean_str.starts_with("i2c") {
+            self.i2c_command(clean_str.split_whitespace().skip(1))?;
+        } else if clean_str.starts_with("spi") {
+            self.spi_command(clean_str.split_whitespace().skip(1))?;
+        } else if clean_str.starts_with("adc") {
+            self.adc_command(clean_str.split_whitespace().nth(1))?;
+        } else if clean_str.starts_with("list") {
+            debug!(" PID    Name                Quanta  Syscalls  Dropped Upcalls  Restarts    State  Grants");
+            self.kernel
+                .process_each_capability(&self.capability, |proc| {
+                    let info: KernelInfo = KernelInfo::new(self.kernel);
+
+                    let pname = proc.get_process_name();
+                    let appid = proc.processid();
+                    let (grants_used, grants_total) =
+                        info.number_app_grant_uses(appid, &self.capability);
+
+                    debug!(
+                        "  {:?}\t{:<20}{:6}{:10}{:17}{:10}  {:?}{:5}/{}",
+                        appid,
+                        pname,
+                        proc.debug_timeslice_expiration_count(),
+                        proc.debug_syscall_count(),
+                        proc.debug_dropped_upcall_count(),
+                        proc.get_restart_count(),
+                        proc.get_state(),
+                        grants_used,
+                        grants_total
+                    );
+                });
+        } else if clean_str.starts_with("stats") {
+            let info: KernelInfo = KernelInfo::new(self.kernel);
+            let total_us = info.execution_time_us(&self.capability);
+            debug!(" PID    Name                  Runs    CPU ms  CPU %  Syscalls");
+            self.kernel
+                .process_each_capability(&self.capability, |proc| {
+                    let time_us = proc.debug_execution_time_us();
+                    let percent = if total_us == 0 {
+                        0
+                    } else {
+                        time_us * 100 / total_us
+                    };
+                    debug!(
+                        "  {:?}\t{:<20}{:6}{:10}{:7}{:10}",
+                        proc.processid(),
+                        proc.get_process_name(),
+                        proc.debug_run_count(),
+                        time_us / 1000,
+                        percent,
+                        proc.debug_syscall_count(),
+                    );
+                });
+        } else if clean_str.starts_with("status") {
+            let info: KernelInfo = KernelInfo::new(self.kernel);
+            debug!(
+                "Total processes: {}",
+                info.number_loaded_processes(&self.capability)
+            );
+            debug!(
+                "Active processes: {}",
+                info.number_active_processes(&self.capability)
+            );
+            debug!(
+                "Timeslice expirations: {}",
+                info.timeslice_expirations(&self.capability)
+            );
+            if let Some(statistics) = kernel::debug::debug_statistics() {
+                debug!(
+                    "Debug buffer: {}/{} bytes at peak, {} bytes dropped",
+                    statistics.peak_buffered, statistics.capacity, statistics.dropped_bytes
+                );
+            }
+        } else if clean_str.starts_with("crash") {
+            self.crash_command(clean_str.split_whitespace().nth(1))?;
+        } else if clean_str.starts_with("panic") {
+            panic!("ProcessConsole forced a kernel panic.");
+        } else {
+            debug!(
+                "Valid commands are: help status list stats stop start fault trace memory grants gpio i2c spi adc script crash"
+            );
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        Ok(())
+    }
+
     fn write_byte(&self, byte: u8) -> Result<(), ErrorCode> {
         if self.tx_in_progress.get() {
             Err(ErrorCode::BUSY)
@@ -399,3 +1008,255 @@ impl<'a, C: ProcessManagementCapability> uart::ReceiveClient for ProcessConsole<
         let _ = self.uart.receive_buffer(read_buf, 1);
     }
 }
+
+impl<'a, C: ProcessManagementCapability> i2c::I2CHwMasterClient for ProcessConsole<'a, C> {
+    fn command_complete(&self, buffer: &'static mut [u8], error: i2c::Error) {
+        if let Some(i2c) = self.i2c.extract() {
+            i2c.disable();
+        }
+        let address = self.i2c_address.get();
+        let read_len = self.i2c_read_len.get();
+        if error != i2c::Error::CommandComplete {
+            debug!("I2C {:#04X}: {}", address, error);
+        } else if read_len == 0 {
+            debug!("I2C {:#04X}: written", address);
+        } else {
+            debug!("I2C {:#04X}: {:02X?}", address, &buffer[..read_len]);
+        }
+        self.i2c_buffer.replace(buffer);
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> spi::SpiMasterClient for ProcessConsole<'a, C> {
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        len: usize,
+    ) {
+        if let Some(read_buffer) = read_buffer {
+            debug!("SPI: {:02X?}", &read_buffer[..len]);
+            self.spi_read_buffer.replace(read_buffer);
+        }
+        self.spi_write_buffer.replace(write_buffer);
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> adc::Client for ProcessConsole<'a, C> {
+    fn sample_ready(&self, sample: u16) {
+        if let Some(index) = self.adc_channel.extract() {
+            self.adc_channel.clear();
+            debug!("ADC {}: {}", index, sample);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test::mock_debug;
+    use crate::test::mock_process::{self, Capability};
+    use core::cell::RefCell;
+    use kernel::hil::gpio::{Configuration, FloatingState};
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// A UART the console never gets to use, since scripts do not echo.
+    struct MockUart;
+
+    impl<'a> uart::Transmit<'a> for MockUart {
+        fn set_transmit_client(&self, _client: &'a dyn uart::TransmitClient) {}
+
+        fn transmit_buffer(
+            &self,
+            tx_buffer: &'static mut [u8],
+            _tx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            Err((ErrorCode::OFF, tx_buffer))
+        }
+
+        fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+            Err(ErrorCode::OFF)
+        }
+
+        fn transmit_abort(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::OFF)
+        }
+    }
+
+    impl<'a> uart::Receive<'a> for MockUart {
+        fn set_receive_client(&self, _client: &'a dyn uart::ReceiveClient) {}
+
+        fn receive_buffer(
+            &self,
+            rx_buffer: &'static mut [u8],
+            _rx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            Err((ErrorCode::OFF, rx_buffer))
+        }
+
+        fn receive_word(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::OFF)
+        }
+
+        fn receive_abort(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::OFF)
+        }
+    }
+
+    impl<'a> uart::UartData<'a> for MockUart {}
+
+    /// An output pin that records each level it is driven to, with its
+    /// number, in a log shared by all pins.
+    struct MockPin {
+        index: usize,
+        level: Cell<bool>,
+        log: &'static RefCell<Vec<(usize, bool)>>,
+    }
+
+    impl gpio::Configure for MockPin {
+        fn configuration(&self) -> Configuration {
+            Configuration::Output
+        }
+
+        fn make_output(&self) -> Configuration {
+            Configuration::Output
+        }
+
+        fn disable_output(&self) -> Configuration {
+            Configuration::Output
+        }
+
+        fn make_input(&self) -> Configuration {
+            Configuration::Output
+        }
+
+        fn disable_input(&self) -> Configuration {
+            Configuration::Output
+        }
+
+        fn deactivate_to_low_power(&self) {}
+
+        fn set_floating_state(&self, _state: FloatingState) {}
+
+        fn floating_state(&self) -> FloatingState {
+            FloatingState::PullNone
+        }
+    }
+
+    impl gpio::Output for MockPin {
+        fn set(&self) {
+            self.level.set(true);
+            self.log.borrow_mut().push((self.index, true));
+        }
+
+        fn clear(&self) {
+            self.level.set(false);
+            self.log.borrow_mut().push((self.index, false));
+        }
+
+        fn toggle(&self) -> bool {
+            self.level.set(!self.level.get());
+            self.level.get()
+        }
+    }
+
+    impl gpio::Input for MockPin {
+        fn read(&self) -> bool {
+            self.level.get()
+        }
+    }
+
+    impl gpio::Pin for MockPin {}
+
+    type Console = ProcessConsole<'static, Capability>;
+
+    /// A console driving `pins` GPIO pins, and the log of the levels they
+    /// were driven to.
+    fn console(pins: usize) -> (&'static Console, &'static RefCell<Vec<(usize, bool)>>) {
+        let (kernel, _processes) = mock_process::kernel(&[]);
+        let log = mock_process::leak(RefCell::new(Vec::new()));
+        let pins: Vec<&'static dyn gpio::Pin> = (0..pins)
+            .map(|index| {
+                mock_process::leak(MockPin {
+                    index: index,
+                    level: Cell::new(false),
+                    log: log,
+                }) as &'static dyn gpio::Pin
+            })
+            .collect();
+        let console = mock_process::leak(ProcessConsole::new(
+            mock_process::leak(MockUart),
+            mock_process::buffer(4),
+            mock_process::buffer(4),
+            mock_process::buffer(32),
+            kernel,
+            Capability,
+        ));
+        console.set_gpio_pins(Box::leak(pins.into_boxed_slice()), &Capability);
+        (console, log)
+    }
+
+    const SCRIPT: &str = "gpio 0 set\n\ngpio 1 set\nbogus\ngpio 0 clear\ngpio 2 set\n";
+
+    #[test]
+    fn test_script_continues_past_failures() {
+        let _debug = mock_debug::lock();
+        let (console, log) = console(3);
+        console.set_script_error_policy(ScriptErrorPolicy::Continue);
+
+        assert_eq!(console.run_script(SCRIPT), (5, 1));
+        assert_eq!(
+            *log.borrow(),
+            vec![(0, true), (1, true), (0, false), (2, true)]
+        );
+    }
+
+    #[test]
+    fn test_script_stops_at_first_failure() {
+        let _debug = mock_debug::lock();
+        let (console, log) = console(3);
+        console.set_script_error_policy(ScriptErrorPolicy::Stop);
+
+        assert_eq!(console.run_script(SCRIPT), (3, 1));
+        assert_eq!(*log.borrow(), vec![(0, true), (1, true)]);
+
+        // A failing pin number fails the same way as an unknown command.
+        assert_eq!(
+            console.run_script("gpio 1 clear\ngpio 7 set\ngpio 2 set"),
+            (2, 1)
+        );
+        assert_eq!(*log.borrow(), vec![(0, true), (1, true), (1, false)]);
+    }
+
+    #[test]
+    fn test_script_cannot_run_scripts() {
+        let _debug = mock_debug::lock();
+        let (console, log) = console(1);
+        console.set_script("gpio 0 set");
+
+        assert_eq!(console.run_script("script\ngpio 0 set"), (2, 1));
+        assert_eq!(*log.borrow(), vec![(0, true)]);
+    }
+
+    #[test]
+    fn test_script_rejects_peripheral_transactions() {
+        let _debug = mock_debug::lock();
+        let (console, log) = console(1);
+        console.set_script_error_policy(ScriptErrorPolicy::Continue);
+
+        let script = "i2c 0x48 0x00 r2\nspi 0x9F 0x00\nadc 0\ngpio 0 set";
+        assert_eq!(console.run_script(script), (4, 3));
+        assert_eq!(*log.borrow(), vec![(0, true)]);
+
+        console.set_script_error_policy(ScriptErrorPolicy::Stop);
+        assert_eq!(
+            console.run_script("gpio 0 clear\nadc 0\ngpio 0 set"),
+            (2, 1)
+        );
+        assert_eq!(*log.borrow(), vec![(0, true), (0, false)]);
+    }
+}
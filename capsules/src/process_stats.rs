@@ -0,0 +1,137 @@
+//! Reports how much CPU time and how many syscalls each process used.
+//!
+//! To find which process drains the battery, a process (for example a
+//! monitoring app that logs or sends the results) can read, for every
+//! process, how many times it ran, how long it ran, how many system calls it
+//! made, and an estimate of the charge it drew.
+//!
+//! CPU time is measured with the scheduler timer, so only runs with a
+//! timeslice are counted: with cooperative schedulers it stays zero. The
+//! charge is estimated from the CPU time and the current the board draws
+//! while the CPU is active, given by the board; it does not include
+//! peripherals.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct ProcessStatsCapability;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessStatsCapability {}
+//!
+//! let process_stats = static_init!(
+//!     capsules::process_stats::ProcessStats<ProcessStatsCapability>,
+//!     capsules::process_stats::ProcessStats::new(
+//!         board_kernel,
+//!         ProcessStatsCapability,
+//!         // 5 mA while the CPU runs
+//!         5000
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Processes are identified by their identifier, which changes when they
+//! restart.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get the identifier of the process at index `data1`, from `0`.
+//!   Returns `INVAL` past the last process, so that processes can be listed
+//!   by incrementing the index until it fails.
+//! - `2`: Get the CPU time of the process `data1`, in microseconds, as a
+//!   64-bit value.
+//! - `3`: Get the number of system calls of the process `data1`, and the
+//!   number of times it ran.
+//! - `4`: Get the estimated charge drawn by the process `data1`, in
+//!   microcoulombs.
+
+use core::cell::Cell;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::procs::Process;
+use kernel::{CommandReturn, Driver, ErrorCode, Kernel, ProcessId};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::ProcessStats as usize;
+
+pub struct ProcessStats<C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    capability: C,
+    active_current_ua: u32,
+}
+
+impl<C: ProcessManagementCapability> ProcessStats<C> {
+    /// `active_current_ua` is the current the board draws while the CPU
+    /// runs, in microamperes.
+    pub fn new(kernel: &'static Kernel, capability: C, active_current_ua: u32) -> Self {
+        ProcessStats {
+            kernel: kernel,
+            capability: capability,
+            active_current_ua: active_current_ua,
+        }
+    }
+
+    /// Call `f` with the process whose identifier is `identifier`.
+    fn with_process<F>(&self, identifier: usize, f: F) -> CommandReturn
+    where
+        F: Fn(&dyn Process) -> CommandReturn,
+    {
+        let ret = Cell::new(CommandReturn::failure(ErrorCode::INVAL));
+        self.kernel
+            .process_each_capability(&self.capability, |proc| {
+                if proc.processid().id() == identifier {
+                    ret.set(f(proc));
+                }
+            });
+        ret.into_inner()
+    }
+}
+
+impl<C: ProcessManagementCapability> Driver for ProcessStats<C> {
+    fn command(&self, command_num: usize, data1: usize, _: usize, _: ProcessId) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // identifier of the process at an index
+            1 => {
+                let index = Cell::new(0);
+                let ret = Cell::new(CommandReturn::failure(ErrorCode::INVAL));
+                self.kernel
+                    .process_each_capability(&self.capability, |proc| {
+                        if index.get() == data1 {
+                            ret.set(CommandReturn::success_u32(proc.processid().id() as u32));
+                        }
+                        index.set(index.get() + 1);
+                    });
+                ret.into_inner()
+            }
+
+            // CPU time
+            2 => self.with_process(data1, |proc| {
+                CommandReturn::success_u64(proc.debug_execution_time_us())
+            }),
+
+            // syscall and run counts
+            3 => self.with_process(data1, |proc| {
+                CommandReturn::success_u32_u32(
+                    proc.debug_syscall_count() as u32,
+                    proc.debug_run_count() as u32,
+                )
+            }),
+
+            // estimated charge
+            4 => self.with_process(data1, |proc| {
+                let charge_uc =
+                    proc.debug_execution_time_us() * self.active_current_ua as u64 / 1_000_000;
+                CommandReturn::success_u32(charge_uc as u32)
+            }),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Measures the frequency and duty cycle of a PWM signal with the input
+//! capture of a timer.
+//!
+//! This provides the same syscall interface as the
+//! [PWM input](../pwm_input/index.html) capsule, but the edges of the signal
+//! are timestamped by the timer in hardware through `hil::pwm_capture`
+//! rather than by GPIO interrupts, so the measurement does not depend on
+//! interrupt latency and follows faster signals. It suits fan tachometers,
+//! RC receiver signals and sensors with a frequency output.
+//!
+//! The capsule averages a configurable number of periods before reporting a
+//! measurement. If no period completes within the signal-loss timeout, the
+//! signal is reported as lost.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use kernel::hil::pwm_capture::PwmCapture;
+//!
+//! let pwm_capture_alarm = static_init!(
+//!     capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+//!     capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let pwm_capture = static_init!(
+//!     capsules::pwm_capture::PwmCapture<
+//!         'static,
+//!         nrf52::pwm_capture::PwmCapture<'static>,
+//!         capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+//!     >,
+//!     capsules::pwm_capture::PwmCapture::new(
+//!         nrf52_capture,
+//!         pwm_capture_alarm,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! nrf52_capture.set_client(pwm_capture);
+//! pwm_capture_alarm.set_alarm_client(pwm_capture);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: Measurement upcall. Called with the frequency in millihertz, the
+//!   duty cycle in hundredths of a percent (0-10000), and `1` if the
+//!   measurement is valid or `0` if the signal was lost.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Start measuring. Upcalls are delivered to every app that has
+//!   started measuring.
+//! - `2`: Stop measuring for this app.
+//! - `3`: Read the last measurement. Returns the frequency (mHz) and duty
+//!   cycle (1/100 %), or `FAIL` if there is no valid measurement.
+//! - `4`: Set the number of periods to average over (1 to
+//!   `MAX_AVERAGING`).
+//! - `5`: Set the signal-loss timeout in milliseconds (at least 1).
+
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::pwm_capture;
+use kernel::hil::time;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+use crate::pwm_input::{compute_measurement, App, Measurement};
+pub use crate::pwm_input::{DEFAULT_TIMEOUT_MS, MAX_AVERAGING};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::PwmInput as usize;
+
+pub struct PwmCapture<'a, P: pwm_capture::PwmCapture<'a>, A: time::Alarm<'a>> {
+    capture: &'a P,
+    alarm: &'a A,
+    apps: Grant<App>,
+
+    averaging: Cell<u32>,
+    timeout_ms: Cell<u32>,
+
+    // Accumulated over the periods of the current measurement.
+    periods: Cell<u32>,
+    period_ticks: Cell<u64>,
+    high_ticks: Cell<u64>,
+
+    last_measurement: OptionalCell<Measurement>,
+}
+
+impl<'a, P: pwm_capture::PwmCapture<'a>, A: time::Alarm<'a>> PwmCapture<'a, P, A> {
+    pub fn new(capture: &'a P, alarm: &'a A, grant: Grant<App>) -> PwmCapture<'a, P, A> {
+        PwmCapture {
+            capture: capture,
+            alarm: alarm,
+            apps: grant,
+            averaging: Cell::new(1),
+            timeout_ms: Cell::new(DEFAULT_TIMEOUT_MS),
+            periods: Cell::new(0),
+            period_ticks: Cell::new(0),
+            high_ticks: Cell::new(0),
+            last_measurement: OptionalCell::empty(),
+        }
+    }
+
+    fn any_app_measuring(&self) -> bool {
+        let mut measuring = false;
+        for app in self.apps.iter() {
+            measuring |= app.enter(|app| app.measuring);
+        }
+        measuring
+    }
+
+    fn reset_accumulators(&self) {
+        self.periods.set(0);
+        self.period_ticks.set(0);
+        self.high_ticks.set(0);
+    }
+
+    fn start(&self) -> Result<(), ErrorCode> {
+        self.reset_accumulators();
+        self.capture.start()?;
+        self.arm_timeout();
+        Ok(())
+    }
+
+    fn stop(&self) {
+        let _ = self.capture.stop();
+        let _ = self.alarm.disarm();
+        self.reset_accumulators();
+        self.last_measurement.clear();
+    }
+
+    fn arm_timeout(&self) {
+        let dt = A::ticks_from_ms(self.timeout_ms.get());
+        self.alarm.set_alarm(self.alarm.now(), dt);
+    }
+
+    fn report(&self, measurement: Option<Measurement>) {
+        match measurement {
+            Some(m) => self.last_measurement.set(m),
+            None => self.last_measurement.clear(),
+        }
+        let (frequency, duty, valid) = measurement.map_or((0, 0, 0), |m| {
+            (m.frequency_mhz as usize, m.duty as usize, 1)
+        });
+        self.apps.each(|_, app| {
+            if app.measuring {
+                app.callback.schedule(frequency, duty, valid);
+            }
+        });
+    }
+}
+
+impl<'a, P: pwm_capture::PwmCapture<'a>, A: time::Alarm<'a>> pwm_capture::Client
+    for PwmCapture<'a, P, A>
+{
+    fn captured(&self, period: u32, high: u32) {
+        self.periods.set(self.periods.get() + 1);
+        self.period_ticks
+            .set(self.period_ticks.get() + period as u64);
+        self.high_ticks.set(self.high_ticks.get() + high as u64);
+
+        if self.periods.get() >= self.averaging.get() {
+            let measurement = compute_measurement(
+                self.capture.get_frequency(),
+                self.periods.get(),
+                self.period_ticks.get(),
+                self.high_ticks.get(),
+            );
+            self.reset_accumulators();
+            if measurement.is_some() {
+                self.report(measurement);
+            }
+        }
+        self.arm_timeout();
+    }
+}
+
+impl<'a, P: pwm_capture::PwmCapture<'a>, A: time::Alarm<'a>> time::AlarmClient
+    for PwmCapture<'a, P, A>
+{
+    /// No period completed within the timeout: the signal has been lost.
+    fn alarm(&self) {
+        self.reset_accumulators();
+        self.report(None);
+        if self.any_app_measuring() {
+            self.arm_timeout();
+        }
+    }
+}
+
+impl<'a, P: pwm_capture::PwmCapture<'a>, A: time::Alarm<'a>> Driver for PwmCapture<'a, P, A> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        data: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // start
+            1 => {
+                let already_running = self.any_app_measuring();
+                if !already_running {
+                    if let Err(e) = self.start() {
+                        return CommandReturn::failure(e);
+                    }
+                }
+                let res = self.apps.enter(appid, |app| app.measuring = true);
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => {
+                        if !already_running {
+                            self.stop();
+                        }
+                        e.into()
+                    }
+                }
+            }
+
+            // stop
+            2 => {
+                let res = self.apps.enter(appid, |app| app.measuring = false);
+                match res {
+                    Ok(()) => {
+                        if !self.any_app_measuring() {
+                            self.stop();
+                        }
+                        CommandReturn::success()
+                    }
+                    Err(e) => e.into(),
+                }
+            }
+
+            // read
+            3 => self
+                .last_measurement
+                .map_or(CommandReturn::failure(ErrorCode::FAIL), |m| {
+                    CommandReturn::success_u32_u32(m.frequency_mhz, m.duty)
+                }),
+
+            // set averaging
+            4 => {
+                if data == 0 || data > MAX_AVERAGING {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    self.averaging.set(data as u32);
+                    CommandReturn::success()
+                }
+            }
+
+            // set signal-loss timeout
+            5 => {
+                if data == 0 || data > u32::MAX as usize {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    self.timeout_ms.set(data as u32);
+                    CommandReturn::success()
+                }
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
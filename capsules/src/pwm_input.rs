@@ -0,0 +1,578 @@
+//! Measures the frequency and duty cycle of a PWM signal on a GPIO pin.
+//!
+//! This is the inverse of PWM output: the capsule timestamps rising and
+//! falling edges of an incoming signal (for example a fan tachometer or a
+//! sensor with a PWM output) and computes the signal's frequency and duty
+//! cycle from the time between edges.
+//!
+//! Each rising edge completes one period. The capsule averages a
+//! configurable number of periods before reporting a measurement. If no edge
+//! arrives within the signal-loss timeout, the signal is reported as lost.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let pwm_input_alarm = static_init!(
+//!     capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+//!     capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let pwm_input = static_init!(
+//!     capsules::pwm_input::PwmInput<
+//!         'static,
+//!         nrf52840::gpio::GPIOPin,
+//!         capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+//!     >,
+//!     capsules::pwm_input::PwmInput::new(
+//!         &nrf52840::gpio::PORT[Pin::P0_03],
+//!         pwm_input_alarm,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! nrf52840::gpio::PORT[Pin::P0_03].set_client(pwm_input);
+//! pwm_input_alarm.set_alarm_client(pwm_input);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: Measurement upcall. Called with the frequency in millihertz, the
+//!   duty cycle in hundredths of a percent (0-10000), and `1` if the
+//!   measurement is valid or `0` if the signal was lost.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Start measuring. Upcalls are delivered to every app that has
+//!   started measuring.
+//! - `2`: Stop measuring for this app.
+//! - `3`: Read the last measurement. Returns the frequency (mHz) and duty
+//!   cycle (1/100 %), or `FAIL` if there is no valid measurement.
+//! - `4`: Set the number of periods to average over (1 to
+//!   `MAX_AVERAGING`).
+//! - `5`: Set the signal-loss timeout in milliseconds (at least 1).
+
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::gpio;
+use kernel::hil::time::{self, Frequency, Ticks};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::PwmInput as usize;
+
+/// Maximum number of periods that can be averaged per measurement.
+pub const MAX_AVERAGING: usize = 64;
+
+/// Default signal-loss timeout.
+pub const DEFAULT_TIMEOUT_MS: u32 = 1000;
+
+/// Duty cycle reported for a full-on signal, in hundredths of a percent.
+const DUTY_SCALE: u64 = 10_000;
+
+/// A frequency and duty cycle measurement.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Measurement {
+    pub frequency_mhz: u32,
+    pub duty: u32,
+}
+
+/// Compute the frequency and duty cycle of `periods` signal periods that
+/// lasted `period_ticks` in total, with the signal high for `high_ticks`,
+/// measured with a clock running at `ticks_per_second`.
+pub fn compute_measurement(
+    ticks_per_second: u32,
+    periods: u32,
+    period_ticks: u64,
+    high_ticks: u64,
+) -> Option<Measurement> {
+    if periods == 0 || period_ticks == 0 {
+        return None;
+    }
+    let frequency_mhz = ticks_per_second as u64 * 1000 * periods as u64 / period_ticks;
+    let duty = core::cmp::min(high_ticks, period_ticks) * DUTY_SCALE / period_ticks;
+    Some(Measurement {
+        frequency_mhz: core::cmp::min(frequency_mhz, u32::MAX as u64) as u32,
+        duty: duty as u32,
+    })
+}
+
+#[derive(Default)]
+pub struct App {
+    pub(crate) callback: Upcall,
+    pub(crate) measuring: bool,
+}
+
+pub struct PwmInput<'a, P: gpio::InterruptPin<'a>, A: time::Alarm<'a>> {
+    pin: &'a P,
+    alarm: &'a A,
+    apps: Grant<App>,
+
+    averaging: Cell<u32>,
+    timeout_ms: Cell<u32>,
+
+    /// Whether the pin interrupt is enabled for the rising edge rather than
+    /// the falling one. The edge is known from how the interrupt was set up,
+    /// as the pin may already have changed again when a short pulse is
+    /// handled.
+    rising: Cell<bool>,
+    last_rise: OptionalCell<A::Ticks>,
+    last_fall: OptionalCell<A::Ticks>,
+    // Accumulated over the periods of the current measurement.
+    periods: Cell<u32>,
+    period_ticks: Cell<u64>,
+    high_ticks: Cell<u64>,
+
+    last_measurement: OptionalCell<Measurement>,
+}
+
+impl<'a, P: gpio::InterruptPin<'a>, A: time::Alarm<'a>> PwmInput<'a, P, A> {
+    pub fn new(pin: &'a P, alarm: &'a A, grant: Grant<App>) -> PwmInput<'a, P, A> {
+        pin.make_input();
+        PwmInput {
+            pin: pin,
+            alarm: alarm,
+            apps: grant,
+            averaging: Cell::new(1),
+            timeout_ms: Cell::new(DEFAULT_TIMEOUT_MS),
+            rising: Cell::new(true),
+            last_rise: OptionalCell::empty(),
+            last_fall: OptionalCell::empty(),
+            periods: Cell::new(0),
+            period_ticks: Cell::new(0),
+            high_ticks: Cell::new(0),
+            last_measurement: OptionalCell::empty(),
+        }
+    }
+
+    fn any_app_measuring(&self) -> bool {
+        let mut measuring = false;
+        for app in self.apps.iter() {
+            measuring |= app.enter(|app| app.measuring);
+        }
+        measuring
+    }
+
+    fn reset_accumulators(&self) {
+        self.last_rise.clear();
+        self.last_fall.clear();
+        self.periods.set(0);
+        self.period_ticks.set(0);
+        self.high_ticks.set(0);
+    }
+
+    fn start(&self) {
+        self.reset_accumulators();
+        self.wait_for_edge(true);
+        self.arm_timeout();
+    }
+
+    /// Enable the pin interrupt for the next rising or falling edge.
+    fn wait_for_edge(&self, rising: bool) {
+        self.rising.set(rising);
+        self.pin.enable_interrupts(if rising {
+            gpio::InterruptEdge::RisingEdge
+        } else {
+            gpio::InterruptEdge::FallingEdge
+        });
+    }
+
+    fn stop(&self) {
+        self.pin.disable_interrupts();
+        let _ = self.alarm.disarm();
+        self.reset_accumulators();
+        self.last_measurement.clear();
+    }
+
+    fn arm_timeout(&self) {
+        let dt = A::ticks_from_ms(self.timeout_ms.get());
+        self.alarm.set_alarm(self.alarm.now(), dt);
+    }
+
+    fn report(&self, measurement: Option<Measurement>) {
+        match measurement {
+            Some(m) => self.last_measurement.set(m),
+            None => self.last_measurement.clear(),
+        }
+        let (frequency, duty, valid) = measurement.map_or((0, 0, 0), |m| {
+            (m.frequency_mhz as usize, m.duty as usize, 1)
+        });
+        self.apps.each(|_, app| {
+            if app.measuring {
+                app.callback.schedule(frequency, duty, valid);
+            }
+        });
+    }
+
+    fn rising_edge(&self, now: A::Ticks) {
+        // A rising edge ends the period that began with the previous one.
+        if let Some(rise) = self.last_rise.extract() {
+            let period = now.wrapping_sub(rise).into_u32() as u64;
+            let high = self.last_fall.extract().map_or(0, |fall| {
+                if fall.within_range(rise, now) {
+                    fall.wrapping_sub(rise).into_u32() as u64
+                } else {
+                    0
+                }
+            });
+            self.periods.set(self.periods.get() + 1);
+            self.period_ticks.set(self.period_ticks.get() + period);
+            self.high_ticks.set(self.high_ticks.get() + high);
+
+            if self.periods.get() >= self.averaging.get() {
+                let measurement = compute_measurement(
+                    A::Frequency::frequency(),
+                    self.periods.get(),
+                    self.period_ticks.get(),
+                    self.high_ticks.get(),
+                );
+                self.periods.set(0);
+                self.period_ticks.set(0);
+                self.high_ticks.set(0);
+                if measurement.is_some() {
+                    self.report(measurement);
+                }
+            }
+        }
+        self.last_rise.set(now);
+        self.last_fall.clear();
+    }
+}
+
+impl<'a, P: gpio::InterruptPin<'a>, A: time::Alarm<'a>> gpio::Client for PwmInput<'a, P, A> {
+    fn fired(&self) {
+        let now = self.alarm.now();
+        let rising = self.rising.get();
+        if rising {
+            self.rising_edge(now);
+        } else {
+            self.last_fall.set(now);
+        }
+        self.wait_for_edge(!rising);
+        self.arm_timeout();
+    }
+}
+
+impl<'a, P: gpio::InterruptPin<'a>, A: time::Alarm<'a>> time::AlarmClient for PwmInput<'a, P, A> {
+    /// No edge arrived within the timeout: the signal has been lost.
+    fn alarm(&self) {
+        self.reset_accumulators();
+        self.report(None);
+        if self.any_app_measuring() {
+            self.arm_timeout();
+        }
+    }
+}
+
+impl<'a, P: gpio::InterruptPin<'a>, A: time::Alarm<'a>> Driver for PwmInput<'a, P, A> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        data: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // start
+            1 => {
+                let already_running = self.any_app_measuring();
+                let res = self.apps.enter(appid, |app| app.measuring = true);
+                match res {
+                    Ok(()) => {
+                        if !already_running {
+                            self.start();
+                        }
+                        CommandReturn::success()
+                    }
+                    Err(e) => e.into(),
+                }
+            }
+
+            // stop
+            2 => {
+                let res = self.apps.enter(appid, |app| app.measuring = false);
+                match res {
+                    Ok(()) => {
+                        if !self.any_app_measuring() {
+                            self.stop();
+                        }
+                        CommandReturn::success()
+                    }
+                    Err(e) => e.into(),
+                }
+            }
+
+            // read
+            3 => self
+                .last_measurement
+                .map_or(CommandReturn::failure(ErrorCode::FAIL), |m| {
+                    CommandReturn::success_u32_u32(m.frequency_mhz, m.duty)
+                }),
+
+            // set averaging
+            4 => {
+                if data == 0 || data > MAX_AVERAGING {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    self.averaging.set(data as u32);
+                    CommandReturn::success()
+                }
+            }
+
+            // set signal-loss timeout
+            5 => {
+                if data == 0 || data > u32::MAX as usize {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    self.timeout_ms.set(data as u32);
+                    CommandReturn::success()
+                }
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compute_measurement, Measurement, PwmInput, DRIVER_NUM};
+    use crate::test::mock_alarm::MockAlarm;
+    use crate::test::mock_process::{self, MockProcess};
+    use core::cell::Cell;
+    use kernel::hil::gpio::{self, Configuration, FloatingState, InterruptEdge};
+    use kernel::hil::time::Alarm;
+    use kernel::{Driver, ErrorCode};
+
+    #[test]
+    fn test_compute_known_signal() {
+        // 1 kHz at 25% duty, timed with a 1 MHz clock.
+        assert_eq!(
+            compute_measurement(1_000_000, 1, 1000, 250),
+            Some(Measurement {
+                frequency_mhz: 1_000_000,
+                duty: 2500,
+            })
+        );
+        // 60 Hz at 50% duty, timed with a 32 kHz clock.
+        assert_eq!(
+            compute_measurement(32768, 60, 32768, 16384),
+            Some(Measurement {
+                frequency_mhz: 60_000,
+                duty: 5000,
+            })
+        );
+        // Time high beyond the period is capped at full duty.
+        assert_eq!(
+            compute_measurement(1_000_000, 1, 1000, 1001).map(|m| m.duty),
+            Some(10_000)
+        );
+        assert_eq!(compute_measurement(1_000_000, 0, 1000, 0), None);
+        assert_eq!(compute_measurement(1_000_000, 1, 0, 0), None);
+    }
+
+    #[test]
+    fn test_compute_averages_periods() {
+        // 4 periods of 900 and 1100 us, high for 200 and 300 us.
+        assert_eq!(
+            compute_measurement(1_000_000, 4, 4000, 1000),
+            Some(Measurement {
+                frequency_mhz: 1_000_000,
+                duty: 2500,
+            })
+        );
+    }
+
+    /// An input pin that records which edge its interrupt is enabled for.
+    #[derive(Default)]
+    struct MockPin {
+        level: Cell<bool>,
+        rising: Cell<Option<bool>>,
+    }
+
+    impl gpio::Configure for MockPin {
+        fn configuration(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn make_output(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn disable_output(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn make_input(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn disable_input(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn deactivate_to_low_power(&self) {}
+
+        fn set_floating_state(&self, _state: FloatingState) {}
+
+        fn floating_state(&self) -> FloatingState {
+            FloatingState::PullNone
+        }
+    }
+
+    impl gpio::Output for MockPin {
+        fn set(&self) {}
+
+        fn clear(&self) {}
+
+        fn toggle(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Input for MockPin {
+        fn read(&self) -> bool {
+            self.level.get()
+        }
+    }
+
+    impl<'a> gpio::Interrupt<'a> for MockPin {
+        fn set_client(&self, _client: &'a dyn gpio::Client) {}
+
+        fn enable_interrupts(&self, mode: InterruptEdge) {
+            self.rising.set(match mode {
+                InterruptEdge::RisingEdge => Some(true),
+                InterruptEdge::FallingEdge => Some(false),
+                InterruptEdge::EitherEdge => None,
+            });
+        }
+
+        fn disable_interrupts(&self) {
+            self.rising.set(None);
+        }
+
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Pin for MockPin {}
+    impl<'a> gpio::InterruptPin<'a> for MockPin {}
+
+    type Input = PwmInput<'static, MockPin, MockAlarm<'static>>;
+
+    /// A PWM input measuring for one process.
+    fn measuring() -> (
+        &'static Input,
+        &'static MockPin,
+        &'static MockAlarm<'static>,
+        &'static MockProcess,
+    ) {
+        let (kernel, processes) = mock_process::kernel(&["fan"]);
+        let pin = mock_process::leak(MockPin::default());
+        let alarm: &MockAlarm = mock_process::leak(MockAlarm::new());
+        let input = mock_process::leak(PwmInput::new(pin, alarm, mock_process::grant(kernel)));
+        alarm.set_alarm_client(input);
+        let app = processes[0];
+        let id = app.processid();
+        assert!(input.subscribe(0, app.upcall(DRIVER_NUM, 0), id).is_ok());
+        assert!(input.command(1, 0, 0, id).is_success());
+        (input, pin, alarm, app)
+    }
+
+    /// Run `periods` periods of `high` then `low` microseconds. The pin is
+    /// read low when each edge is handled, as if every pulse had already
+    /// ended.
+    fn pulse(input: &Input, pin: &MockPin, alarm: &MockAlarm, high: u32, low: u32, periods: usize) {
+        for _ in 0..periods {
+            pin.level.set(false);
+            assert_eq!(pin.rising.get(), Some(true));
+            gpio::Client::fired(input);
+            alarm.advance(high);
+            assert_eq!(pin.rising.get(), Some(false));
+            gpio::Client::fired(input);
+            alarm.advance(low);
+        }
+    }
+
+    #[test]
+    fn test_measures_short_pulses() {
+        let (input, pin, alarm, app) = measuring();
+        pulse(input, pin, alarm, 10, 990, 3);
+        // The first rising edge only starts the first period.
+        assert_eq!(
+            app.take_upcalls(),
+            [(0, 1_000_000, 100, 1), (0, 1_000_000, 100, 1)]
+        );
+        assert_eq!(
+            input
+                .command(3, 0, 0, app.processid())
+                .get_success_u32_u32(),
+            Some((1_000_000, 100))
+        );
+    }
+
+    #[test]
+    fn test_averages_then_loses_signal() {
+        let (input, pin, alarm, app) = measuring();
+        let id = app.processid();
+        assert!(input.command(4, 4, 0, id).is_success());
+        assert!(input.command(5, 10, 0, id).is_success());
+
+        pulse(input, pin, alarm, 200, 700, 1);
+        pulse(input, pin, alarm, 300, 800, 1);
+        pulse(input, pin, alarm, 200, 700, 1);
+        pulse(input, pin, alarm, 300, 800, 1);
+        assert!(app.take_upcalls().is_empty());
+        // The rising edge closing the fourth period completes the average.
+        pulse(input, pin, alarm, 200, 700, 1);
+        assert_eq!(app.take_upcalls(), [(0, 1_000_000, 2500, 1)]);
+
+        // Without edges for the timeout, the signal is reported lost.
+        alarm.advance(10_000 - 700 - 1);
+        assert!(app.take_upcalls().is_empty());
+        alarm.advance(1);
+        assert_eq!(app.take_upcalls(), [(0, 0, 0, 0)]);
+        assert_eq!(
+            input.command(3, 0, 0, id).get_failure(),
+            Some(ErrorCode::FAIL)
+        );
+        // Loss is reported again each timeout while the app measures.
+        alarm.advance(10_000);
+        assert_eq!(app.take_upcalls(), [(0, 0, 0, 0)]);
+
+        assert!(input.command(2, 0, 0, id).is_success());
+        assert_eq!(pin.rising.get(), None);
+        assert_eq!(alarm.until_alarm(), None);
+    }
+}
@@ -0,0 +1,367 @@
+//! Provides userspace with the position and velocity of a rotary encoder.
+//!
+//! The steps counted by a quadrature decoder, through `hil::qdec`, are
+//! accumulated into a 64-bit position, which does not overflow however long
+//! the encoder turns. The velocity is the number of steps over the last
+//! velocity window, in steps per second.
+//!
+//! Each app has its own origin, which it can move to the current position,
+//! and can set a threshold on its position. The app is called back when its
+//! position crosses the threshold, in either direction.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use kernel::hil::qdec::Qdec;
+//!
+//! let qdec_alarm = static_init!(
+//!     capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+//!     capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let qdec = static_init!(
+//!     capsules::qdec::Qdec<
+//!         'static,
+//!         nrf52::qdec::Qdec<'static>,
+//!         capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+//!     >,
+//!     capsules::qdec::Qdec::new(
+//!         &base_peripherals.qdec,
+//!         qdec_alarm,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! base_peripherals.qdec.set_client(qdec);
+//! qdec_alarm.set_alarm_client(qdec);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: Event upcall. Called with the event, and the low and high 32 bits
+//!   of the position of the app. The event is `0` if the position crossed
+//!   the threshold of the app, or `1` if the decoder lost steps, and the
+//!   position is no longer exact.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Start counting. Upcalls are delivered to every app that has
+//!   started counting.
+//! - `2`: Stop counting for this app.
+//! - `3`: Read the position of the app, as a 64-bit value.
+//! - `4`: Read the velocity in steps per second, as a signed 32-bit value.
+//! - `5`: Set the threshold of the app to the position with `data` as its
+//!   low and `data2` as its high 32 bits.
+//! - `6`: Clear the threshold of the app.
+//! - `7`: Move the origin of the app to the current position.
+
+use core::cell::Cell;
+use core::mem;
+use kernel::hil::qdec;
+use kernel::hil::time;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Qdec as usize;
+
+/// Length of the window the velocity is measured over.
+pub const VELOCITY_WINDOW_MS: u32 = 100;
+
+/// Upcall event: the position crossed the threshold.
+const EVENT_THRESHOLD: usize = 0;
+/// Upcall event: the decoder lost steps.
+const EVENT_OVERFLOW: usize = 1;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    counting: bool,
+    /// The absolute position the position of the app is relative to.
+    origin: i64,
+    threshold: Option<i64>,
+    /// Whether the position of the app was at or above its threshold.
+    above: bool,
+}
+
+impl App {
+    fn set_threshold(&mut self, threshold: i64, position: i64) {
+        self.threshold = Some(threshold);
+        self.above = position - self.origin >= threshold;
+    }
+}
+
+pub struct Qdec<'a, Q: qdec::Qdec<'a>, A: time::Alarm<'a>> {
+    qdec: &'a Q,
+    alarm: &'a A,
+    apps: Grant<App>,
+
+    /// Steps accumulated since the decoder was enabled.
+    position: Cell<i64>,
+    /// The position at the start of the velocity window.
+    window_start: Cell<i64>,
+    /// Steps per second over the last velocity window.
+    velocity: Cell<i32>,
+}
+
+impl<'a, Q: qdec::Qdec<'a>, A: time::Alarm<'a>> Qdec<'a, Q, A> {
+    pub fn new(qdec: &'a Q, alarm: &'a A, grant: Grant<App>) -> Qdec<'a, Q, A> {
+        Qdec {
+            qdec: qdec,
+            alarm: alarm,
+            apps: grant,
+            position: Cell::new(0),
+            window_start: Cell::new(0),
+            velocity: Cell::new(0),
+        }
+    }
+
+    fn any_app_counting(&self) -> bool {
+        let mut counting = false;
+        for app in self.apps.iter() {
+            counting |= app.enter(|app| app.counting);
+        }
+        counting
+    }
+
+    fn start(&self) -> Result<(), ErrorCode> {
+        self.qdec.enable()?;
+        self.position.set(0);
+        self.window_start.set(0);
+        self.velocity.set(0);
+        self.arm_window();
+        Ok(())
+    }
+
+    fn stop(&self) {
+        let _ = self.qdec.disable();
+        let _ = self.alarm.disarm();
+        self.velocity.set(0);
+    }
+
+    fn arm_window(&self) {
+        let dt = A::ticks_from_ms(VELOCITY_WINDOW_MS);
+        self.alarm.set_alarm(self.alarm.now(), dt);
+    }
+
+    /// Take the steps waiting in the decoder, and call back the apps whose
+    /// position crossed their threshold.
+    fn update_position(&self) {
+        let steps = self.qdec.take_steps();
+        if steps == 0 {
+            return;
+        }
+        let position = self.position.get() + steps as i64;
+        self.position.set(position);
+
+        self.apps.each(|_, app| {
+            if let Some(threshold) = app.threshold {
+                let relative = position - app.origin;
+                let above = relative >= threshold;
+                if above != app.above {
+                    app.above = above;
+                    if app.counting {
+                        app.callback.schedule(
+                            EVENT_THRESHOLD,
+                            relative as u32 as usize,
+                            (relative >> 32) as u32 as usize,
+                        );
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl<'a, Q: qdec::Qdec<'a>, A: time::Alarm<'a>> qdec::Client for Qdec<'a, Q, A> {
+    fn steps_ready(&self) {
+        self.update_position();
+    }
+
+    fn overflow(&self) {
+        self.update_position();
+        let position = self.position.get();
+        self.apps.each(|_, app| {
+            if app.counting {
+                let relative = position - app.origin;
+                app.callback.schedule(
+                    EVENT_OVERFLOW,
+                    relative as u32 as usize,
+                    (relative >> 32) as u32 as usize,
+                );
+            }
+        });
+    }
+}
+
+impl<'a, Q: qdec::Qdec<'a>, A: time::Alarm<'a>> time::AlarmClient for Qdec<'a, Q, A> {
+    /// The velocity window ended.
+    fn alarm(&self) {
+        self.update_position();
+        let position = self.position.get();
+        let steps = position - self.window_start.get();
+        self.window_start.set(position);
+        let velocity = steps * 1000 / VELOCITY_WINDOW_MS as i64;
+        self.velocity
+            .set(velocity.clamp(i32::MIN as i64, i32::MAX as i64) as i32);
+        if self.any_app_counting() {
+            self.arm_window();
+        }
+    }
+}
+
+impl<'a, Q: qdec::Qdec<'a>, A: time::Alarm<'a>> Driver for Qdec<'a, Q, A> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        data: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // start
+            1 => {
+                let already_running = self.any_app_counting();
+                if !already_running {
+                    if let Err(e) = self.start() {
+                        return CommandReturn::failure(e);
+                    }
+                }
+                let position = self.position.get();
+                let res = self.apps.enter(appid, |app| {
+                    if !app.counting {
+                        app.counting = true;
+                        app.origin = position;
+                        if let Some(threshold) = app.threshold {
+                            app.set_threshold(threshold, position);
+                        }
+                    }
+                });
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => {
+                        if !already_running {
+                            self.stop();
+                        }
+                        e.into()
+                    }
+                }
+            }
+
+            // stop
+            2 => {
+                let res = self.apps.enter(appid, |app| app.counting = false);
+                match res {
+                    Ok(()) => {
+                        if !self.any_app_counting() {
+                            self.stop();
+                        }
+                        CommandReturn::success()
+                    }
+                    Err(e) => e.into(),
+                }
+            }
+
+            // read position
+            3 => {
+                if self.qdec.is_enabled() {
+                    self.update_position();
+                }
+                let position = self.position.get();
+                self.apps
+                    .enter(appid, |app| {
+                        if app.counting {
+                            CommandReturn::success_u64((position - app.origin) as u64)
+                        } else {
+                            CommandReturn::failure(ErrorCode::OFF)
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            // read velocity
+            4 => self
+                .apps
+                .enter(appid, |app| {
+                    if app.counting {
+                        CommandReturn::success_u32(self.velocity.get() as u32)
+                    } else {
+                        CommandReturn::failure(ErrorCode::OFF)
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // set threshold
+            5 => {
+                let threshold = ((data2 as u32 as u64) << 32 | data as u32 as u64) as i64;
+                let position = self.position.get();
+                self.apps
+                    .enter(appid, |app| {
+                        app.set_threshold(threshold, position);
+                        CommandReturn::success()
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            // clear threshold
+            6 => self
+                .apps
+                .enter(appid, |app| {
+                    app.threshold = None;
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // zero position
+            7 => {
+                if self.qdec.is_enabled() {
+                    self.update_position();
+                }
+                let position = self.position.get();
+                self.apps
+                    .enter(appid, |app| {
+                        if app.counting {
+                            app.origin = position;
+                            if let Some(threshold) = app.threshold {
+                                app.set_threshold(threshold, position);
+                            }
+                            CommandReturn::success()
+                        } else {
+                            CommandReturn::failure(ErrorCode::OFF)
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
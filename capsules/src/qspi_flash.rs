@@ -0,0 +1,298 @@
+//! Flash pages of an external NOR flash behind a QSPI controller.
+//!
+//! `QspiFlash` implements `hil::flash::Flash` over `hil::qspi::Qspi`, so that
+//! an external flash backs nonvolatile storage, logs or the filesystem like
+//! the internal flash does. A page is a 4 kB sector, the smallest area the
+//! flash erases, and writing a page erases it first.
+//!
+//! Pages move through a word-aligned buffer of one program page, as QSPI
+//! controllers need, in as many reads or programs as the sector has program
+//! pages.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::hil::qspi::Qspi;
+//! # use kernel::hil::flash::HasClient;
+//! # use kernel::static_init;
+//!
+//! base_peripherals.qspi.set_pins(sck, csn, [io0, io1, io2, io3]);
+//! base_peripherals
+//!     .qspi
+//!     .configure(kernel::hil::qspi::Config {
+//!         frequency: 16_000_000,
+//!         size: 8 * 1024 * 1024,
+//!         address_width: kernel::hil::qspi::AddressWidth::Bits24,
+//!         read_mode: kernel::hil::qspi::ReadMode::Fast,
+//!         write_mode: kernel::hil::qspi::WriteMode::Single,
+//!     })
+//!     .unwrap();
+//! let qspi_flash = static_init!(
+//!     capsules::qspi_flash::QspiFlash<'static, nrf52840::qspi::Qspi<'static>>,
+//!     capsules::qspi_flash::QspiFlash::new(
+//!         &base_peripherals.qspi,
+//!         &mut capsules::qspi_flash::BUFFER.0
+//!     )
+//! );
+//! base_peripherals.qspi.set_client(qspi_flash);
+//! let sector = static_init!(
+//!     capsules::qspi_flash::QspiFlashSector,
+//!     capsules::qspi_flash::QspiFlashSector::default()
+//! );
+//! let nv_to_page = static_init!(
+//!     capsules::nonvolatile_to_pages::NonvolatileToPages<
+//!         'static,
+//!         capsules::qspi_flash::QspiFlash<'static, nrf52840::qspi::Qspi<'static>>,
+//!     >,
+//!     capsules::nonvolatile_to_pages::NonvolatileToPages::new(qspi_flash, sector)
+//! );
+//! qspi_flash.set_client(nv_to_page);
+//! ```
+
+use core::cell::Cell;
+use core::ops::{Index, IndexMut};
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::flash;
+use kernel::hil::qspi::{self, PROGRAM_PAGE_SIZE};
+use kernel::ErrorCode;
+
+/// Smallest area the flash erases, and the size of a page.
+pub const SECTOR_SIZE: usize = 4096;
+
+/// Buffer of one program page, aligned as QSPI controllers need.
+#[repr(align(4))]
+pub struct QspiBuffer(pub [u8; PROGRAM_PAGE_SIZE]);
+
+pub static mut BUFFER: QspiBuffer = QspiBuffer([0; PROGRAM_PAGE_SIZE]);
+
+/// A page of the flash, which is a sector of `SECTOR_SIZE` bytes.
+pub struct QspiFlashSector(pub [u8; SECTOR_SIZE]);
+
+impl Default for QspiFlashSector {
+    fn default() -> Self {
+        Self {
+            0: [0; SECTOR_SIZE],
+        }
+    }
+}
+
+impl Index<usize> for QspiFlashSector {
+    type Output = u8;
+
+    fn index(&self, idx: usize) -> &u8 {
+        &self.0[idx]
+    }
+}
+
+impl IndexMut<usize> for QspiFlashSector {
+    fn index_mut(&mut self, idx: usize) -> &mut u8 {
+        &mut self.0[idx]
+    }
+}
+
+impl AsMut<[u8]> for QspiFlashSector {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// Number of reads or programs of a sector.
+const CHUNKS: usize = SECTOR_SIZE / PROGRAM_PAGE_SIZE;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Read,
+    /// Erasing the sector before writing it.
+    EraseForWrite,
+    Write,
+    Erase,
+}
+
+pub struct QspiFlash<'a, Q: qspi::Qspi<'a>> {
+    qspi: &'a Q,
+    client: OptionalCell<&'a dyn flash::Client<QspiFlash<'a, Q>>>,
+    state: Cell<State>,
+    buffer: TakeCell<'static, [u8]>,
+    client_sector: TakeCell<'static, QspiFlashSector>,
+    sector_index: Cell<usize>,
+    chunk: Cell<usize>,
+}
+
+impl<'a, Q: qspi::Qspi<'a>> QspiFlash<'a, Q> {
+    pub fn new(qspi: &'a Q, buffer: &'static mut [u8]) -> QspiFlash<'a, Q> {
+        QspiFlash {
+            qspi: qspi,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            buffer: TakeCell::new(buffer),
+            client_sector: TakeCell::empty(),
+            sector_index: Cell::new(0),
+            chunk: Cell::new(0),
+        }
+    }
+
+    fn chunk_address(&self) -> usize {
+        self.sector_index.get() * SECTOR_SIZE + self.chunk.get() * PROGRAM_PAGE_SIZE
+    }
+
+    /// Read or program the current chunk of the sector.
+    fn transfer_chunk(&self) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::RESERVE)?;
+        let address = self.chunk_address();
+        let result = if self.state.get() == State::Read {
+            self.qspi.read(address, buffer, PROGRAM_PAGE_SIZE)
+        } else {
+            let start = self.chunk.get() * PROGRAM_PAGE_SIZE;
+            self.client_sector.map(|sector| {
+                buffer[..PROGRAM_PAGE_SIZE]
+                    .copy_from_slice(&sector.0[start..start + PROGRAM_PAGE_SIZE]);
+            });
+            self.qspi.write(address, buffer, PROGRAM_PAGE_SIZE)
+        };
+        result.map_err(|(e, buffer)| {
+            self.buffer.replace(buffer);
+            e
+        })
+    }
+
+    /// Start an operation on a sector.
+    fn start(&self, state: State, sector_index: usize) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.state.set(state);
+        self.sector_index.set(sector_index);
+        self.chunk.set(0);
+
+        let result = match state {
+            State::Read => self.transfer_chunk(),
+            _ => self
+                .qspi
+                .erase(sector_index * SECTOR_SIZE, qspi::EraseSize::Sector4K),
+        };
+        if result.is_err() {
+            self.state.set(State::Idle);
+        }
+        result
+    }
+
+    /// End the operation in progress, and report it to the client.
+    fn complete(&self, error: flash::Error) {
+        let state = self.state.get();
+        self.state.set(State::Idle);
+        match state {
+            State::Read => {
+                self.client_sector.take().map(|sector| {
+                    self.client
+                        .map(move |client| client.read_complete(sector, error));
+                });
+            }
+            State::EraseForWrite | State::Write => {
+                self.client_sector.take().map(|sector| {
+                    self.client
+                        .map(move |client| client.write_complete(sector, error));
+                });
+            }
+            State::Erase => {
+                self.client.map(|client| client.erase_complete(error));
+            }
+            State::Idle => {}
+        }
+    }
+
+    /// Move on to the next chunk, or complete the operation after the last.
+    fn next_chunk(&self) {
+        self.chunk.set(self.chunk.get() + 1);
+        if self.chunk.get() == CHUNKS {
+            self.complete(flash::Error::CommandComplete);
+        } else if self.transfer_chunk().is_err() {
+            self.complete(flash::Error::FlashError);
+        }
+    }
+}
+
+impl<'a, Q: qspi::Qspi<'a>> qspi::Client for QspiFlash<'a, Q> {
+    fn read_complete(&self, buffer: &'static mut [u8], len: usize, result: Result<(), ErrorCode>) {
+        let start = self.chunk.get() * PROGRAM_PAGE_SIZE;
+        if result.is_ok() {
+            self.client_sector.map(|sector| {
+                sector.0[start..start + len].copy_from_slice(&buffer[..len]);
+            });
+        }
+        self.buffer.replace(buffer);
+        match result {
+            Ok(()) => self.next_chunk(),
+            Err(_) => self.complete(flash::Error::FlashError),
+        }
+    }
+
+    fn write_complete(
+        &self,
+        buffer: &'static mut [u8],
+        _len: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        self.buffer.replace(buffer);
+        match result {
+            Ok(()) => self.next_chunk(),
+            Err(_) => self.complete(flash::Error::FlashError),
+        }
+    }
+
+    fn erase_complete(&self, result: Result<(), ErrorCode>) {
+        match (self.state.get(), result) {
+            (State::EraseForWrite, Ok(())) => {
+                self.state.set(State::Write);
+                if self.transfer_chunk().is_err() {
+                    self.complete(flash::Error::FlashError);
+                }
+            }
+            (_, Ok(())) => self.complete(flash::Error::CommandComplete),
+            (_, Err(_)) => self.complete(flash::Error::FlashError),
+        }
+    }
+}
+
+impl<'a, Q: qspi::Qspi<'a>, C: flash::Client<Self>> flash::HasClient<'a, C> for QspiFlash<'a, Q> {
+    fn set_client(&self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl<'a, Q: qspi::Qspi<'a>> flash::Flash for QspiFlash<'a, Q> {
+    type Page = QspiFlashSector;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        match self.start(State::Read, page_number) {
+            Ok(()) => {
+                self.client_sector.replace(buf);
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        match self.start(State::EraseForWrite, page_number) {
+            Ok(()) => {
+                self.client_sector.replace(buf);
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        self.start(State::Erase, page_number)
+    }
+}
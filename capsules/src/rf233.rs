@@ -21,6 +21,7 @@ use kernel::hil::radio;
 use kernel::hil::spi;
 use kernel::ErrorCode;
 
+use crate::rf233_const::AACK_SET_PD;
 use crate::rf233_const::CSMA_SEED_1;
 use crate::rf233_const::IRQ_MASK;
 use crate::rf233_const::PHY_CC_CCA_MODE_CS_OR_ED;
@@ -124,6 +125,7 @@ enum InternalState {
     CONFIG_IEEE6_SET,
     CONFIG_IEEE7_SET,
     CONFIG_POWER_SET,
+    CONFIG_CHANNEL_SET,
     CONFIG_DONE,
 
     // RX is a short-lived state for when software has detected
@@ -212,6 +214,7 @@ pub struct RF233<'a, S: spi::SpiMasterDevice> {
     addr: Cell<u16>,
     addr_long: Cell<[u8; 8]>,
     pan: Cell<u16>,
+    ack_frame_pending: Cell<bool>,
     tx_power: Cell<i8>,
     channel: Cell<u8>,
     spi_rx: TakeCell<'static, [u8]>,
@@ -1003,6 +1006,17 @@ impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
                 self.state_transition_write(
                     RF233Register::PHY_CC_CCA,
                     val,
+                    InternalState::CONFIG_CHANNEL_SET,
+                );
+            }
+            InternalState::CONFIG_CHANNEL_SET => {
+                let mut val = CSMA_SEED_1;
+                if self.ack_frame_pending.get() {
+                    val |= AACK_SET_PD;
+                }
+                self.state_transition_write(
+                    RF233Register::CSMA_SEED_1,
+                    val,
                     InternalState::CONFIG_DONE,
                 );
             }
@@ -1058,6 +1072,7 @@ impl<'a, S: spi::SpiMasterDevice> RF233<'a, S> {
             addr: Cell::new(0),
             addr_long: Cell::new([0x00; 8]),
             pan: Cell::new(0),
+            ack_frame_pending: Cell::new(false),
             tx_power: Cell::new(setting_to_power(PHY_TX_PWR)),
             channel: Cell::new(channel),
             spi_rx: TakeCell::empty(),
@@ -1267,6 +1282,10 @@ impl<S: spi::SpiMasterDevice> radio::RadioConfig for RF233<'_, S> {
         self.pan.set(id);
     }
 
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.ack_frame_pending.set(pending);
+    }
+
     fn set_tx_power(&self, power: i8) -> Result<(), ErrorCode> {
         if (power > 4 || power < -17) {
             Err(ErrorCode::INVAL)
@@ -79,6 +79,7 @@ pub const XAH_CTRL_1_AACK_PROM_MODE: u8 = 1 << 1;
 pub const XAH_CTRL_1_AACK_UPLD_RES_FT: u8 = 1 << 4;
 pub const XAH_CTRL_1_AACK_FLTR_RES_FT: u8 = 1 << 5;
 pub const AACK_FVN_MODE: u8 = 3 << 6;
+pub const AACK_SET_PD: u8 = 1 << 5;
 
 // Flag combinations that are used in initialization.
 pub const TRX_CTRL_1: u8 =
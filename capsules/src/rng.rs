@@ -7,6 +7,10 @@
 //! randomness. A single command starts the RNG, the callback is called when the
 //! requested amount of randomness is received, or the buffer is filled.
 //!
+//! The driver can also shuffle an array of fixed-size elements in the buffer
+//! in place, with a Fisher-Yates shuffle. Random indices are drawn by
+//! rejection sampling, so every permutation is equally likely.
+//!
 //! Usage
 //! -----
 //!
@@ -28,7 +32,7 @@ use kernel::hil::entropy::{Entropy32, Entropy8};
 use kernel::hil::rng;
 use kernel::hil::rng::{Client, Continue, Random, Rng};
 use kernel::{
-    CommandReturn, Driver, ErrorCode, Grant, ProcessId, ReadWrite, ReadWriteAppSlice, Upcall,
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadWrite, ReadWriteAppSlice, Upcall,
 };
 
 /// Syscall driver number.
@@ -41,6 +45,58 @@ pub struct App {
     buffer: ReadWriteAppSlice,
     remaining: usize,
     idx: usize,
+    // Element size and count of the array being shuffled, and the index of
+    // the next element to swap, which counts down to 0.
+    shuffle_size: usize,
+    shuffle_count: usize,
+    shuffle_idx: usize,
+}
+
+/// Map a random word to an index uniformly distributed in `0..n`, or return
+/// `None` if the word must be rejected to avoid biasing the result.
+pub fn uniform_index(random: u32, n: u32) -> Option<u32> {
+    if n == 0 {
+        return None;
+    }
+    // The largest multiple of `n` that fits in a u32 word, as a u64 so that
+    // it can be 2^32 when `n` is a power of two.
+    let zone = (1u64 << 32) / n as u64 * n as u64;
+    if (random as u64) < zone {
+        Some(random % n)
+    } else {
+        None
+    }
+}
+
+/// Swap elements `i` and `j` of `size` bytes in `buffer`.
+fn swap_elements(buffer: &mut [u8], size: usize, i: usize, j: usize) {
+    if i != j {
+        for k in 0..size {
+            buffer.swap(i * size + k, j * size + k);
+        }
+    }
+}
+
+/// Run Fisher-Yates steps on the elements of `size` bytes in `buffer`,
+/// starting at element `i` and counting down, until it is shuffled or
+/// `randomness` runs out. Returns the index to resume from, 0 when done.
+fn shuffle(
+    buffer: &mut [u8],
+    size: usize,
+    mut i: usize,
+    randomness: &mut dyn Iterator<Item = u32>,
+) -> usize {
+    while i > 0 {
+        let j = match randomness.next() {
+            Some(random) => uniform_index(random, i as u32 + 1),
+            None => break,
+        };
+        if let Some(j) = j {
+            swap_elements(buffer, size, i, j as usize);
+            i -= 1;
+        }
+    }
+    i
 }
 
 pub struct RngDriver<'a> {
@@ -134,6 +190,29 @@ impl rng::Client for RngDriver<'_> {
                     } else {
                         app.callback.schedule(0, newidx, 0);
                     }
+                } else if app.shuffle_idx > 0 {
+                    let (size, count) = (app.shuffle_size, app.shuffle_count);
+                    let mut i = app.shuffle_idx;
+                    let res = app.buffer.mut_map_or(Err(ErrorCode::NOMEM), |buffer| {
+                        if buffer.len() < size * count {
+                            // The app swapped buffers.
+                            return Err(ErrorCode::SIZE);
+                        }
+                        i = shuffle(buffer.as_mut(), size, i, randomness);
+                        Ok(())
+                    });
+
+                    match res {
+                        Ok(()) if i > 0 => {
+                            app.shuffle_idx = i;
+                            done = false;
+                        }
+                        _ => {
+                            app.shuffle_idx = 0;
+                            app.callback
+                                .schedule(kernel::into_statuscode(res), count, 0);
+                        }
+                    }
                 }
             });
 
@@ -206,7 +285,7 @@ impl<'a> Driver for RngDriver<'a> {
         &self,
         command_num: usize,
         data: usize,
-        _: usize,
+        data2: usize,
         appid: ProcessId,
     ) -> CommandReturn {
         match command_num {
@@ -232,6 +311,36 @@ impl<'a> Driver for RngDriver<'a> {
                     CommandReturn::success()
                 })
                 .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+
+            2 /* Shuffle an array of `data2` elements of `data` bytes */ => self
+                .apps
+                .enter(appid, |app| {
+                    if app.remaining > 0 || app.shuffle_idx > 0 {
+                        return CommandReturn::failure(ErrorCode::BUSY);
+                    }
+                    let len = match data.checked_mul(data2) {
+                        Some(len) if data > 0 && data2 <= u32::MAX as usize => len,
+                        _ => return CommandReturn::failure(ErrorCode::INVAL),
+                    };
+                    if len > app.buffer.len() {
+                        return CommandReturn::failure(ErrorCode::SIZE);
+                    }
+                    if data2 < 2 {
+                        // Nothing to shuffle.
+                        app.callback.schedule(0, data2, 0);
+                        return CommandReturn::success();
+                    }
+
+                    app.shuffle_size = data;
+                    app.shuffle_count = data2;
+                    app.shuffle_idx = data2 - 1;
+                    if !self.getting_randomness.get() {
+                        self.getting_randomness.set(true);
+                        let _ = self.rng.get();
+                    }
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| CommandReturn::failure(err.into())),
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -536,3 +645,94 @@ impl Client for SynchronousRandom<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{shuffle, uniform_index};
+    use core::cell::Cell;
+
+    /// A fake random source: a xorshift generator.
+    struct FakeRng(u32);
+
+    impl Iterator for FakeRng {
+        type Item = u32;
+
+        fn next(&mut self) -> Option<u32> {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn uniform_index_rejects_zero_range() {
+        assert_eq!(uniform_index(0, 0), None);
+        assert_eq!(uniform_index(u32::MAX, 0), None);
+    }
+
+    #[test]
+    fn uniform_index_power_of_two_accepts_all() {
+        assert_eq!(uniform_index(0, 1), Some(0));
+        assert_eq!(uniform_index(u32::MAX, 1), Some(0));
+        assert_eq!(uniform_index(u32::MAX, 4), Some(3));
+        assert_eq!(uniform_index(u32::MAX, 1 << 31), Some((1 << 31) - 1));
+    }
+
+    #[test]
+    fn uniform_index_rejection_bounds() {
+        // 2^32 = 3 * 1431655765 + 1, so only the last word is rejected.
+        let zone = 3 * 1431655765u32;
+        assert_eq!(uniform_index(zone - 1, 3), Some((zone - 1) % 3));
+        assert_eq!(uniform_index(zone, 3), None);
+        assert_eq!(uniform_index(u32::MAX, 3), None);
+
+        // 2^32 = 10 * 429496729 + 6, so the last 6 words are rejected.
+        let zone = 10 * 429496729u32;
+        assert_eq!(uniform_index(zone - 1, 10), Some(9));
+        for random in zone..=u32::MAX {
+            assert_eq!(uniform_index(random, 10), None);
+        }
+
+        // The largest range rejects everything from n up.
+        let n = (1 << 31) + 1;
+        assert_eq!(uniform_index(n - 1, n), Some(n - 1));
+        assert_eq!(uniform_index(n, n), None);
+    }
+
+    #[test]
+    fn shuffle_resumes_when_randomness_runs_out() {
+        let mut buffer = [0, 1, 2, 3, 10, 11];
+        // Only a rejected word and one valid word: one step is taken.
+        let mut randomness = [u32::MAX, 0].iter().cloned();
+        assert_eq!(shuffle(&mut buffer, 2, 2, &mut randomness), 1);
+        assert_eq!(buffer, [10, 11, 2, 3, 0, 1]);
+
+        let mut randomness = FakeRng(1);
+        assert_eq!(shuffle(&mut buffer, 2, 1, &mut randomness), 0);
+        let mut sorted = buffer;
+        sorted.sort_unstable();
+        assert_eq!(sorted, [0, 1, 2, 3, 10, 11]);
+    }
+
+    #[test]
+    fn shuffle_permutations_are_uniform() {
+        const TRIALS: usize = 60000;
+        let counts: [Cell<usize>; 6] = Default::default();
+        let mut randomness = FakeRng(0x1234_5678);
+        for _ in 0..TRIALS {
+            let mut buffer = [0u8, 1, 2];
+            assert_eq!(shuffle(&mut buffer, 1, 2, &mut randomness), 0);
+            // Lehmer code of the permutation.
+            let first = buffer[0] as usize;
+            let second = if buffer[1] > buffer[2] { 1 } else { 0 };
+            let cell = &counts[first * 2 + second];
+            cell.set(cell.get() + 1);
+        }
+        let expected = TRIALS / 6;
+        for count in counts.iter() {
+            let count = count.get();
+            assert!(count > expected * 95 / 100 && count < expected * 105 / 100);
+        }
+    }
+}
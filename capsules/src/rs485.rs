@@ -0,0 +1,339 @@
+//! RS-485 direction control for a UART.
+//!
+//! An RS-485 transceiver shares one differential pair between transmitting
+//! and receiving, so it only drives the bus while its driver enable (DE)
+//! input is asserted. Usually the receiver enable (RE) input is tied to it,
+//! so the transceiver does not hear its own transmission. This capsule wraps
+//! a UART and asserts the DE/RE pin for the duration of each transmission.
+//!
+//! The turnaround is timed with an alarm. DE is asserted `setup_us`
+//! microseconds before the first character, for transceivers that need time
+//! to enable their driver. After the UART reports the transmission complete,
+//! DE is held for `hold_bits` more bit periods: many UARTs report completion
+//! when the last character is handed to the shift register, and releasing
+//! the bus early would cut it off. The hold also keeps the bus driven for
+//! the stop bit, so the other end does not see a framing error.
+//!
+//! The wrapper implements the UART HIL traits, so consoles and other UART
+//! capsules can run over RS-485 unchanged, and userspace talks to the bus
+//! through them.
+//!
+//! Multidrop
+//! ---------
+//!
+//! With 9-bit words (`Width::Nine`), the ninth bit distinguishes address
+//! characters from data characters, so that the nodes on the bus only
+//! listen to the frames addressed to them. `transmit_address` sends an
+//! address character. Buffers are transmitted as data characters. The
+//! address bit of received words is bit 8 of `received_word`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let rs485_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, stm32f412g::tim2::Tim2>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let rs485 = static_init!(
+//!     capsules::rs485::Rs485<
+//!         'static,
+//!         stm32f412g::usart::Usart,
+//!         stm32f412g::gpio::Pin,
+//!         VirtualMuxAlarm<'static, stm32f412g::tim2::Tim2>,
+//!     >,
+//!     capsules::rs485::Rs485::new(
+//!         &base_peripherals.usart3,
+//!         gpio_ports.get_pin(PinId::PB14).unwrap(),
+//!         kernel::hil::gpio::ActivationMode::ActiveHigh,
+//!         rs485_alarm
+//!     )
+//! );
+//! base_peripherals.usart3.set_transmit_client(rs485);
+//! rs485_alarm.set_alarm_client(rs485);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::gpio;
+use kernel::hil::time::{self, Alarm};
+use kernel::hil::uart;
+use kernel::ErrorCode;
+
+/// Default time between asserting DE and starting a transmission.
+pub const DEFAULT_SETUP_US: u32 = 0;
+
+/// Default number of bit periods DE is held after a transmission completes,
+/// enough for a whole 8N1 character.
+pub const DEFAULT_HOLD_BITS: u32 = 10;
+
+/// Address bit of a 9-bit word.
+pub const ADDRESS_BIT: u32 = 1 << 8;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Transmission {
+    Buffer(usize),
+    Word(u32),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Completion {
+    Buffer(usize, Result<(), ErrorCode>),
+    Word(Result<(), ErrorCode>),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// DE is asserted, waiting for the transceiver before transmitting.
+    Enabling(Transmission),
+    Transmitting,
+    /// The transmission completed, waiting for the hold time before
+    /// releasing DE and telling the client.
+    Releasing(Completion),
+}
+
+pub struct Rs485<'a, U: uart::Uart<'a>, P: gpio::Pin, A: Alarm<'a>> {
+    uart: &'a U,
+    de_pin: &'a P,
+    de_mode: gpio::ActivationMode,
+    alarm: &'a A,
+    tx_client: OptionalCell<&'a dyn uart::TransmitClient>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    baud_rate: Cell<u32>,
+    setup_us: Cell<u32>,
+    hold_bits: Cell<u32>,
+}
+
+impl<'a, U: uart::Uart<'a>, P: gpio::Pin, A: Alarm<'a>> Rs485<'a, U, P, A> {
+    pub fn new(
+        uart: &'a U,
+        de_pin: &'a P,
+        de_mode: gpio::ActivationMode,
+        alarm: &'a A,
+    ) -> Rs485<'a, U, P, A> {
+        de_pin.make_output();
+        de_pin.write_activation(gpio::ActivationState::Inactive, de_mode);
+        Rs485 {
+            uart: uart,
+            de_pin: de_pin,
+            de_mode: de_mode,
+            alarm: alarm,
+            tx_client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            state: Cell::new(State::Idle),
+            baud_rate: Cell::new(115200),
+            setup_us: Cell::new(DEFAULT_SETUP_US),
+            hold_bits: Cell::new(DEFAULT_HOLD_BITS),
+        }
+    }
+
+    /// Set the time between asserting DE and transmitting, and the number
+    /// of bit periods DE is held after a transmission completes.
+    pub fn set_turnaround(&self, setup_us: u32, hold_bits: u32) {
+        self.setup_us.set(setup_us);
+        self.hold_bits.set(hold_bits);
+    }
+
+    /// Transmit `address` as a 9-bit address character. The UART must be
+    /// configured with `Width::Nine`. Completes with `transmitted_word`.
+    pub fn transmit_address(&self, address: u8) -> Result<(), ErrorCode> {
+        uart::Transmit::transmit_word(self, ADDRESS_BIT | address as u32)
+    }
+
+    fn set_de(&self, state: gpio::ActivationState) {
+        self.de_pin.write_activation(state, self.de_mode);
+    }
+
+    /// Assert DE and start `transmission`, after the setup time if any.
+    fn enable(&self, transmission: Transmission) -> Result<(), ErrorCode> {
+        self.set_de(gpio::ActivationState::Active);
+        let setup_us = self.setup_us.get();
+        if setup_us == 0 {
+            self.start(transmission)
+        } else {
+            self.state.set(State::Enabling(transmission));
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_us(setup_us));
+            Ok(())
+        }
+    }
+
+    /// Hand `transmission` to the UART.
+    fn start(&self, transmission: Transmission) -> Result<(), ErrorCode> {
+        self.state.set(State::Transmitting);
+        let res = match transmission {
+            Transmission::Buffer(len) => {
+                self.tx_buffer
+                    .take()
+                    .map_or(Err(ErrorCode::FAIL), |buffer| {
+                        match self.uart.transmit_buffer(buffer, len) {
+                            Ok(()) => Ok(()),
+                            Err((e, buffer)) => {
+                                self.tx_buffer.replace(buffer);
+                                Err(e)
+                            }
+                        }
+                    })
+            }
+            Transmission::Word(word) => self.uart.transmit_word(word),
+        };
+        if res.is_err() {
+            self.set_de(gpio::ActivationState::Inactive);
+            self.state.set(State::Idle);
+        }
+        res
+    }
+
+    /// Hold DE for the hold time, then release it and tell the client about
+    /// `completion`.
+    fn release(&self, completion: Completion) {
+        self.state.set(State::Releasing(completion));
+        // Round up, so that the whole last bit period is covered.
+        let baud_rate = self.baud_rate.get();
+        let hold_us = (self.hold_bits.get() * 1_000_000 + baud_rate - 1) / baud_rate;
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_us(hold_us));
+    }
+}
+
+impl<'a, U: uart::Uart<'a>, P: gpio::Pin, A: Alarm<'a>> time::AlarmClient for Rs485<'a, U, P, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Enabling(transmission) => {
+                if let Err(e) = self.start(transmission) {
+                    // The transmission was accepted when DE was asserted, so
+                    // it must complete with a callback.
+                    let completion = match transmission {
+                        Transmission::Buffer(_) => Completion::Buffer(0, Err(e)),
+                        Transmission::Word(_) => Completion::Word(Err(e)),
+                    };
+                    self.release(completion);
+                }
+            }
+            State::Releasing(completion) => {
+                self.set_de(gpio::ActivationState::Inactive);
+                self.state.set(State::Idle);
+                self.tx_client.map(|client| match completion {
+                    Completion::Buffer(len, rval) => {
+                        self.tx_buffer
+                            .take()
+                            .map(|buffer| client.transmitted_buffer(buffer, len, rval));
+                    }
+                    Completion::Word(rval) => client.transmitted_word(rval),
+                });
+            }
+            State::Idle | State::Transmitting => {}
+        }
+    }
+}
+
+impl<'a, U: uart::Uart<'a>, P: gpio::Pin, A: Alarm<'a>> uart::TransmitClient
+    for Rs485<'a, U, P, A>
+{
+    fn transmitted_word(&self, rval: Result<(), ErrorCode>) {
+        self.release(Completion::Word(rval));
+    }
+
+    fn transmitted_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+        rval: Result<(), ErrorCode>,
+    ) {
+        self.tx_buffer.replace(tx_buffer);
+        self.release(Completion::Buffer(tx_len, rval));
+    }
+}
+
+impl<'a, U: uart::Uart<'a>, P: gpio::Pin, A: Alarm<'a>> uart::Transmit<'a> for Rs485<'a, U, P, A> {
+    fn set_transmit_client(&self, client: &'a dyn uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.state.get() != State::Idle {
+            return Err((ErrorCode::BUSY, tx_buffer));
+        }
+        if tx_len > tx_buffer.len() {
+            return Err((ErrorCode::SIZE, tx_buffer));
+        }
+        self.tx_buffer.replace(tx_buffer);
+        self.enable(Transmission::Buffer(tx_len)).map_err(|e| {
+            // `start` put the buffer back when the UART refused it.
+            (e, self.tx_buffer.take().unwrap_or(&mut []))
+        })
+    }
+
+    fn transmit_word(&self, word: u32) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.enable(Transmission::Word(word))
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Idle => Ok(()),
+            State::Enabling(transmission) => {
+                let _ = self.alarm.disarm();
+                let completion = match transmission {
+                    Transmission::Buffer(_) => Completion::Buffer(0, Err(ErrorCode::CANCEL)),
+                    Transmission::Word(_) => Completion::Word(Err(ErrorCode::CANCEL)),
+                };
+                self.release(completion);
+                Err(ErrorCode::BUSY)
+            }
+            // The completion callback of the UART releases DE.
+            State::Transmitting => self.uart.transmit_abort(),
+            State::Releasing(_) => Err(ErrorCode::FAIL),
+        }
+    }
+}
+
+impl<'a, U: uart::Uart<'a>, P: gpio::Pin, A: Alarm<'a>> uart::Receive<'a> for Rs485<'a, U, P, A> {
+    fn set_receive_client(&self, client: &'a dyn uart::ReceiveClient) {
+        self.uart.set_receive_client(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.uart.receive_buffer(rx_buffer, rx_len)
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        self.uart.receive_word()
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        self.uart.receive_abort()
+    }
+}
+
+impl<'a, U: uart::Uart<'a>, P: gpio::Pin, A: Alarm<'a>> uart::Configure for Rs485<'a, U, P, A> {
+    fn configure(&self, params: uart::Parameters) -> Result<(), ErrorCode> {
+        if params.baud_rate == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        // RS-485 has no flow control lines.
+        if params.hw_flow_control {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        self.uart.configure(params)?;
+        self.baud_rate.set(params.baud_rate);
+        Ok(())
+    }
+}
+
+impl<'a, U: uart::Uart<'a>, P: gpio::Pin, A: Alarm<'a>> uart::UartData<'a> for Rs485<'a, U, P, A> {}
+impl<'a, U: uart::Uart<'a>, P: gpio::Pin, A: Alarm<'a>> uart::Uart<'a> for Rs485<'a, U, P, A> {}
@@ -9,19 +9,103 @@
 //! let screen =
 //!     components::screen::ScreenComponent::new(board_kernel, tft).finalize();
 //! ```
+//!
+//! Tearing Effect Synchronization
+//! ------------------------------
+//!
+//! Many panels drive a tearing effect (TE) line high while they are in their
+//! vertical blanking interval. If the TE line is wired to an interrupt-capable
+//! GPIO, the board can hand it to the capsule:
+//!
+//! ```rust
+//! screen.set_tearing_effect_pin(te_pin);
+//! te_pin.set_client(screen);
+//! ```
+//!
+//! Screens that signal their vertical blank themselves implement
+//! `hil::screen::ScreenVsync` instead, like the ST77xx driver given the TE
+//! line of the panel:
+//!
+//! ```rust
+//! tft.set_tearing_effect_pin(te_pin);
+//! te_pin.set_client(tft);
+//! screen.set_vsync(tft);
+//! kernel::hil::screen::ScreenVsync::set_vsync_client(tft, Some(screen));
+//! ```
+//!
+//! Apps can then ask for their frame writes to start only on the next TE
+//! edge (command 400), and can subscribe to an upcall on every vertical
+//! blank for frame pacing (subscribe 1, enabled with command 401). Without a
+//! TE line, TE-synchronized writes start immediately and vertical blank
+//! notifications are not supported.
+//!
+//! So that a write does not wait forever if the TE line never fires, for
+//! example because the panel's TE output is disabled, a deferred write
+//! starts anyway after `VERTICAL_BLANK_TIMEOUT_MS`. Boards with a TE line or
+//! a `ScreenVsync` screen must also give the capsule a
+//! `ScreenVerticalBlankTimer`; without one, apps cannot enable TE-synchronized
+//! writes.
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::screen::ScreenVerticalBlankTimer;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//!
+//! let vblank_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm));
+//! let vblank_timer = static_init!(
+//!     ScreenVerticalBlankTimer<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     ScreenVerticalBlankTimer::new(screen, vblank_alarm));
+//! vblank_alarm.set_alarm_client(vblank_timer);
+//! screen.set_vertical_blank_timer(vblank_timer);
+//! ```
+//!
+//! Back Buffer
+//! -----------
+//!
+//! The board can give the capsule a back buffer that holds a whole frame:
+//!
+//! ```rust
+//! screen.set_back_buffer(components::screen_buffer_size!(115200));
+//! ```
+//!
+//! Apps that enable it (command 500) write and fill their write frame in the
+//! back buffer instead of the screen. The capsule keeps, for each app, the
+//! rectangle that covers all its changes since its last present, and only
+//! sends that rectangle to the screen when the app presents the frame
+//! (command 501).
+//! Presents follow tearing effect synchronization like other writes. The
+//! back buffer is only supported for pixel formats of whole bytes.
 
 use core::cell::Cell;
+use core::cmp;
 use core::convert::From;
 use core::mem;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil;
+use kernel::hil::gpio;
 use kernel::hil::screen::{ScreenPixelFormat, ScreenRotation};
+use kernel::hil::time::{self, Alarm};
 use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, Upcall};
 
 /// Syscall driver number.
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Screen as usize;
 
+/// Time after which a write waiting for the tearing effect line starts
+/// anyway.
+pub const VERTICAL_BLANK_TIMEOUT_MS: u32 = 100;
+
+/// Times out writes waiting for a vertical blank on behalf of a `Screen`.
+pub trait VerticalBlankTimer {
+    /// Call `Screen::vertical_blank_timeout()` after `timeout_ms`
+    /// milliseconds.
+    fn start(&self, timeout_ms: u32);
+    /// Stop a running timeout.
+    fn cancel(&self);
+}
+
 fn screen_rotation_from(screen_rotation: usize) -> Option<ScreenRotation> {
     match screen_rotation {
         0 => Some(ScreenRotation::Normal),
@@ -62,6 +146,7 @@ enum ScreenCommand {
     SetWriteFrame,
     Write,
     Fill,
+    Present,
 }
 
 fn pixels_in_bytes(pixels: usize, bits_per_pixel: usize) -> usize {
@@ -73,6 +158,54 @@ fn pixels_in_bytes(pixels: usize, bits_per_pixel: usize) -> usize {
     }
 }
 
+/// A frame write waiting for the next vertical blank, with the timer that
+/// bounds the wait.
+struct DeferredWrite<'a> {
+    write: OptionalCell<(ScreenCommand, usize)>,
+    timer: OptionalCell<&'a dyn VerticalBlankTimer>,
+}
+
+impl<'a> DeferredWrite<'a> {
+    fn new() -> DeferredWrite<'a> {
+        DeferredWrite {
+            write: OptionalCell::empty(),
+            timer: OptionalCell::empty(),
+        }
+    }
+
+    fn is_some(&self) -> bool {
+        self.write.is_some()
+    }
+
+    fn has_timer(&self) -> bool {
+        self.timer.is_some()
+    }
+
+    /// Wait for the next vertical blank to start the write. Fails with
+    /// `NOSUPPORT` if there is no timer to bound the wait.
+    fn defer(&self, command: ScreenCommand, data1: usize) -> Result<(), ErrorCode> {
+        self.timer.map_or(Err(ErrorCode::NOSUPPORT), |timer| {
+            self.write.set((command, data1));
+            timer.start(VERTICAL_BLANK_TIMEOUT_MS);
+            Ok(())
+        })
+    }
+
+    /// The vertical blank came: take the write to start.
+    fn vertical_blank(&self) -> Option<(ScreenCommand, usize)> {
+        let write = self.write.take();
+        if write.is_some() {
+            self.timer.map(|timer| timer.cancel());
+        }
+        write
+    }
+
+    /// The wait timed out: take the write to start.
+    fn timeout(&self) -> Option<(ScreenCommand, usize)> {
+        self.write.take()
+    }
+}
+
 pub struct App {
     callback: Upcall,
     pending_command: bool,
@@ -86,6 +219,13 @@ pub struct App {
     height: usize,
     data1: usize,
     data2: usize,
+    tearing_effect_sync: bool,
+    vblank_callback: Upcall,
+    vblank_notify: bool,
+    back_buffered: bool,
+    // The rectangle (x, y, width, height) the app changed in the back buffer
+    // since its last present.
+    dirty: Option<(usize, usize, usize, usize)>,
 }
 
 impl Default for App {
@@ -103,6 +243,11 @@ impl Default for App {
             height: 0,
             write_len: 0,
             write_position: 0,
+            tearing_effect_sync: false,
+            vblank_callback: Upcall::default(),
+            vblank_notify: false,
+            back_buffered: false,
+            dirty: None,
         }
     }
 }
@@ -115,6 +260,13 @@ pub struct Screen<'a> {
     current_app: OptionalCell<ProcessId>,
     pixel_format: Cell<ScreenPixelFormat>,
     buffer: TakeCell<'static, [u8]>,
+    tearing_effect_pin: OptionalCell<&'a dyn gpio::InterruptPin<'a>>,
+    vsync: OptionalCell<&'a dyn hil::screen::ScreenVsync>,
+    deferred_write: DeferredWrite<'a>,
+    back_buffer: TakeCell<'static, [u8]>,
+    // The rectangle being presented, and the bytes of it sent so far.
+    presenting: OptionalCell<(usize, usize, usize, usize)>,
+    present_position: Cell<usize>,
 }
 
 impl<'a> Screen<'a> {
@@ -132,6 +284,264 @@ impl<'a> Screen<'a> {
             screen_ready: Cell::new(false),
             pixel_format: Cell::new(screen.get_pixel_format()),
             buffer: TakeCell::new(buffer),
+            tearing_effect_pin: OptionalCell::empty(),
+            vsync: OptionalCell::empty(),
+            deferred_write: DeferredWrite::new(),
+            back_buffer: TakeCell::empty(),
+            presenting: OptionalCell::empty(),
+            present_position: Cell::new(0),
+        }
+    }
+
+    /// Use `pin` as the panel's tearing effect line. The screen must also be
+    /// set as the pin's client.
+    pub fn set_tearing_effect_pin(&self, pin: &'a dyn gpio::InterruptPin<'a>) {
+        pin.make_input();
+        self.tearing_effect_pin.set(pin);
+    }
+
+    /// Use the vertical blank signal of the screen. The capsule must also be
+    /// set as its vsync client.
+    pub fn set_vsync(&self, vsync: &'a dyn hil::screen::ScreenVsync) {
+        self.vsync.set(vsync);
+    }
+
+    /// Use `timer` to start writes waiting for a vertical blank that does not
+    /// come. It is needed for apps to enable synchronized writes.
+    pub fn set_vertical_blank_timer(&self, timer: &'a dyn VerticalBlankTimer) {
+        self.deferred_write.timer.set(timer);
+    }
+
+    /// Use `buffer` as the back buffer. It must hold a whole frame at the
+    /// largest resolution and pixel format apps use.
+    pub fn set_back_buffer(&self, buffer: &'static mut [u8]) {
+        self.back_buffer.replace(buffer);
+    }
+
+    fn has_vertical_blank(&self) -> bool {
+        self.tearing_effect_pin.is_some() || self.vsync.is_some()
+    }
+
+    fn enable_vertical_blank(&self) {
+        self.tearing_effect_pin
+            .map(|pin| pin.enable_interrupts(gpio::InterruptEdge::RisingEdge));
+        self.vsync.map(|vsync| vsync.enable_vsync());
+    }
+
+    /// Enable the tearing effect interrupt only while a write is waiting for
+    /// it or an app wants vertical blank notifications.
+    fn update_tearing_effect_interrupt(&self) {
+        let mut needed = self.deferred_write.is_some();
+        for app in self.apps.iter() {
+            needed |= app.enter(|app| app.vblank_notify);
+        }
+        if needed {
+            self.enable_vertical_blank();
+        } else {
+            self.tearing_effect_pin.map(|pin| pin.disable_interrupts());
+            self.vsync.map(|vsync| vsync.disable_vsync());
+        }
+    }
+
+    /// Start a write that was waiting for a vertical blank.
+    fn start_deferred_write(&self, write: Option<(ScreenCommand, usize)>) {
+        write.map(|(command, data1)| {
+            self.current_app.map(|appid| {
+                if let Err(e) = self.start_write(command, data1, *appid) {
+                    self.run_next_command(kernel::into_statuscode(Err(e)), 0, 0);
+                }
+            });
+        });
+    }
+
+    /// Start the deferred write, if any, and notify the apps.
+    fn vertical_blank(&self) {
+        self.start_deferred_write(self.deferred_write.vertical_blank());
+
+        self.apps.each(|_, app| {
+            if app.vblank_notify {
+                app.vblank_callback.schedule(0, 0, 0);
+            }
+        });
+
+        self.update_tearing_effect_interrupt();
+    }
+
+    /// Returns the bytes per pixel and per row of the back buffer, if it can
+    /// hold a frame.
+    fn back_buffer_layout(&self, back_buffer: &[u8]) -> Result<(usize, usize), ErrorCode> {
+        let bits_per_pixel = self.pixel_format.get().get_bits_per_pixel();
+        if bits_per_pixel % 8 != 0 {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        let (width, height) = self.screen.get_resolution();
+        let bytes_per_pixel = bits_per_pixel / 8;
+        if width * height * bytes_per_pixel > back_buffer.len() {
+            return Err(ErrorCode::SIZE);
+        }
+        Ok((bytes_per_pixel, width * bytes_per_pixel))
+    }
+
+    /// Add the rectangle to the part of the back buffer the app presents
+    /// next.
+    fn mark_dirty(&self, appid: ProcessId, x: usize, y: usize, width: usize, height: usize) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let _ = self.apps.enter(appid, |app| {
+            app.dirty = Some(match app.dirty {
+                Some((dx, dy, dwidth, dheight)) => {
+                    let left = cmp::min(x, dx);
+                    let top = cmp::min(y, dy);
+                    let right = cmp::max(x + width, dx + dwidth);
+                    let bottom = cmp::max(y + height, dy + dheight);
+                    (left, top, right - left, bottom - top)
+                }
+                None => (x, y, width, height),
+            });
+        });
+    }
+
+    /// Write or fill the write frame of the app in the back buffer.
+    fn draw_to_back_buffer(
+        &self,
+        command: ScreenCommand,
+        data1: usize,
+        appid: ProcessId,
+    ) -> Result<(), ErrorCode> {
+        let (x, y, width, height) = self
+            .apps
+            .enter(appid, |app| (app.x, app.y, app.width, app.height))
+            .map_err(ErrorCode::from)?;
+        let (screen_width, screen_height) = self.screen.get_resolution();
+        if x + width > screen_width || y + height > screen_height {
+            return Err(ErrorCode::INVAL);
+        }
+
+        self.back_buffer
+            .map_or(Err(ErrorCode::NOSUPPORT), |back_buffer| {
+                let (bytes_per_pixel, stride) = self.back_buffer_layout(back_buffer)?;
+                let row_len = width * bytes_per_pixel;
+                self.apps
+                    .enter(appid, |app| {
+                        app.shared.map_or(Err(ErrorCode::NOMEM), |shared| {
+                            let len = if command == ScreenCommand::Write {
+                                cmp::min(shared.len(), data1)
+                            } else {
+                                row_len * height
+                            };
+                            if shared.len() < bytes_per_pixel || len == 0 {
+                                return Err(ErrorCode::NOMEM);
+                            }
+                            for row in 0..height {
+                                let start = (y + row) * stride + x * bytes_per_pixel;
+                                let dest = &mut back_buffer[start..start + row_len];
+                                if command == ScreenCommand::Write {
+                                    let offset = row * row_len;
+                                    if offset >= len {
+                                        break;
+                                    }
+                                    let n = cmp::min(row_len, len - offset);
+                                    dest[..n].copy_from_slice(&shared[offset..offset + n]);
+                                } else {
+                                    for pixel in dest.chunks_mut(bytes_per_pixel) {
+                                        pixel.copy_from_slice(&shared[..bytes_per_pixel]);
+                                    }
+                                }
+                            }
+                            Ok(())
+                        })
+                    })
+                    .unwrap_or_else(|err| err.into())
+            })?;
+
+        self.mark_dirty(appid, x, y, width, height);
+        self.run_next_command(kernel::into_statuscode(Ok(())), 0, 0);
+        Ok(())
+    }
+
+    /// Send the rectangle of the back buffer the app changed to the screen.
+    fn start_present(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let dirty = self
+            .apps
+            .enter(appid, |app| app.dirty.take())
+            .map_err(ErrorCode::from)?;
+        match dirty {
+            Some((x, y, width, height)) => {
+                self.present_position.set(0);
+                self.presenting.set((x, y, width, height));
+                self.screen
+                    .set_write_frame(x, y, width, height)
+                    .map_err(|e| {
+                        self.presenting.clear();
+                        self.mark_dirty(appid, x, y, width, height);
+                        e
+                    })
+            }
+            None => {
+                self.run_next_command(kernel::into_statuscode(Ok(())), 0, 0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Copy the next bytes of the rectangle being presented to `buffer`.
+    fn fill_next_buffer_for_present(&self, buffer: &mut [u8]) -> usize {
+        let (x, y, width, height) = match self.presenting.extract() {
+            Some(rect) => rect,
+            None => return 0,
+        };
+        self.back_buffer.map_or(0, |back_buffer| {
+            let (bytes_per_pixel, stride) = match self.back_buffer_layout(back_buffer) {
+                Ok(layout) => layout,
+                Err(_) => return 0,
+            };
+            let row_len = width * bytes_per_pixel;
+            let mut position = self.present_position.get();
+            let mut len = 0;
+            while len < buffer.len() && position < row_len * height {
+                let (row, column) = (position / row_len, position % row_len);
+                let n = cmp::min(row_len - column, buffer.len() - len);
+                let start = (y + row) * stride + x * bytes_per_pixel + column;
+                buffer[len..len + n].copy_from_slice(&back_buffer[start..start + n]);
+                len += n;
+                position += n;
+            }
+            self.present_position.set(position);
+            len
+        })
+    }
+
+    /// End the running present. The rectangle is presented again after an
+    /// error.
+    fn present_done(&self, r: Result<(), ErrorCode>) {
+        self.presenting.take().map(|(x, y, width, height)| {
+            if r.is_err() {
+                self.current_app
+                    .map(|appid| self.mark_dirty(*appid, x, y, width, height));
+            }
+        });
+        self.run_next_command(kernel::into_statuscode(r), 0, 0);
+    }
+
+    /// Start a frame write now, or defer it to the next tearing effect edge
+    /// if the app asked for synchronized writes and a TE line is available.
+    fn write_or_defer(
+        &self,
+        command: ScreenCommand,
+        data1: usize,
+        appid: ProcessId,
+    ) -> Result<(), ErrorCode> {
+        let sync = self
+            .apps
+            .enter(appid, |app| app.tearing_effect_sync)
+            .unwrap_or(false);
+        if sync && self.has_vertical_blank() {
+            self.deferred_write.defer(command, data1)?;
+            self.enable_vertical_blank();
+            Ok(())
+        } else {
+            self.start_write(command, data1, appid)
         }
     }
 
@@ -151,28 +561,33 @@ impl<'a> Screen<'a> {
                 if self.screen_ready.get() && self.current_app.is_none() {
                     self.current_app.set(appid);
                     app.command = command;
-                    let r = self.call_screen(command, data1, data2, appid);
-                    if r != Ok(()) {
-                        self.current_app.clear();
-                    }
-                    CommandReturn::from(r)
+                    None
                 } else {
                     if app.pending_command == true {
-                        CommandReturn::failure(ErrorCode::BUSY)
+                        Some(CommandReturn::failure(ErrorCode::BUSY))
                     } else {
                         app.pending_command = true;
                         app.command = command;
                         app.write_position = 0;
                         app.data1 = data1;
                         app.data2 = data2;
-                        CommandReturn::success()
+                        Some(CommandReturn::success())
                     }
                 }
             })
             .map_err(ErrorCode::from);
         match res {
             Err(e) => CommandReturn::failure(e),
-            Ok(r) => r,
+            Ok(Some(r)) => r,
+            // The screen runs the command outside of the grant, as it may
+            // complete right away.
+            Ok(None) => {
+                let r = self.call_screen(command, data1, data2, appid);
+                if r != Ok(()) {
+                    self.current_app.clear();
+                }
+                CommandReturn::from(r)
+            }
         }
     }
 
@@ -282,44 +697,62 @@ impl<'a> Screen<'a> {
                     Err(ErrorCode::NOSUPPORT)
                 }
             }
-            ScreenCommand::Fill => {
-                let res = self
+            ScreenCommand::Fill | ScreenCommand::Write => {
+                let back_buffered = self
                     .apps
-                    .enter(appid, |app| {
-                        // if it is larger than 0, we know it fits
-                        // the size has been verified by subscribe
-                        if app.shared.len() > 0 {
-                            app.write_position = 0;
-                            app.write_len = pixels_in_bytes(
-                                app.width * app.height,
-                                self.pixel_format.get().get_bits_per_pixel(),
-                            );
-
-                            self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
-                                let len = self.fill_next_buffer_for_write(buffer);
-
-                                if len > 0 {
-                                    self.screen.write(buffer, len)
-                                } else {
-                                    self.buffer.replace(buffer);
-                                    self.run_next_command(kernel::into_statuscode(Ok(())), 0, 0);
-                                    Ok(())
-                                }
-                            })
-                        } else {
-                            Err(ErrorCode::NOMEM)
-                        }
-                    })
-                    .map_err(|err| err.into());
-
-                match res {
-                    Err(e) => e,
-                    Ok(r) => r,
+                    .enter(appid, |app| app.back_buffered)
+                    .unwrap_or(false);
+                if back_buffered {
+                    self.draw_to_back_buffer(command, data1, appid)
+                } else {
+                    self.write_or_defer(command, data1, appid)
                 }
             }
-            ScreenCommand::Write => self
+            ScreenCommand::Present => self.write_or_defer(command, data1, appid),
+            ScreenCommand::SetWriteFrame => self
                 .apps
                 .enter(appid, |app| {
+                    app.write_position = 0;
+                    app.x = (data1 >> 16) & 0xFFFF;
+                    app.y = data1 & 0xFFFF;
+                    app.width = (data2 >> 16) & 0xFFFF;
+                    app.height = data2 & 0xFFFF;
+                    self.screen
+                        .set_write_frame(app.x, app.y, app.width, app.height)
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => Err(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn start_write(
+        &self,
+        command: ScreenCommand,
+        data1: usize,
+        appid: ProcessId,
+    ) -> Result<(), ErrorCode> {
+        match command {
+            ScreenCommand::Fill => {
+                // if it is larger than 0, we know it fits
+                // the size has been verified by subscribe
+                let res = self.apps.enter(appid, |app| {
+                    if app.shared.len() > 0 {
+                        app.write_position = 0;
+                        app.write_len = pixels_in_bytes(
+                            app.width * app.height,
+                            self.pixel_format.get().get_bits_per_pixel(),
+                        );
+                        Ok(())
+                    } else {
+                        Err(ErrorCode::NOMEM)
+                    }
+                })?;
+                // The buffer is filled from the grant, so this is done
+                // after leaving it.
+                res.and_then(|()| self.write_next_buffer(ErrorCode::NOMEM))
+            }
+            ScreenCommand::Write => {
+                let res = self.apps.enter(appid, |app| {
                     let len = if app.shared.len() < data1 {
                         app.shared.len()
                     } else {
@@ -328,37 +761,33 @@ impl<'a> Screen<'a> {
                     if len > 0 {
                         app.write_position = 0;
                         app.write_len = len;
-                        self.buffer.take().map_or(Err(ErrorCode::FAIL), |buffer| {
-                            let len = self.fill_next_buffer_for_write(buffer);
-                            if len > 0 {
-                                self.screen.write(buffer, len)
-                            } else {
-                                self.buffer.replace(buffer);
-                                self.run_next_command(kernel::into_statuscode(Ok(())), 0, 0);
-                                Ok(())
-                            }
-                        })
+                        Ok(())
                     } else {
                         Err(ErrorCode::NOMEM)
                     }
-                })
-                .unwrap_or_else(|err| err.into()),
-            ScreenCommand::SetWriteFrame => self
-                .apps
-                .enter(appid, |app| {
-                    app.write_position = 0;
-                    app.x = (data1 >> 16) & 0xFFFF;
-                    app.y = data1 & 0xFFFF;
-                    app.width = (data2 >> 16) & 0xFFFF;
-                    app.height = data2 & 0xFFFF;
-                    self.screen
-                        .set_write_frame(app.x, app.y, app.width, app.height)
-                })
-                .unwrap_or_else(|err| err.into()),
+                })?;
+                res.and_then(|()| self.write_next_buffer(ErrorCode::FAIL))
+            }
+            ScreenCommand::Present => self.start_present(appid),
             _ => Err(ErrorCode::NOSUPPORT),
         }
     }
 
+    /// Send the next part of the write of the current app to the screen.
+    /// Fails with `no_buffer` if the buffer is in use.
+    fn write_next_buffer(&self, no_buffer: ErrorCode) -> Result<(), ErrorCode> {
+        self.buffer.take().map_or(Err(no_buffer), |buffer| {
+            let len = self.fill_next_buffer_for_write(buffer);
+            if len > 0 {
+                self.screen.write(buffer, len)
+            } else {
+                self.buffer.replace(buffer);
+                self.run_next_command(kernel::into_statuscode(Ok(())), 0, 0);
+                Ok(())
+            }
+        })
+    }
+
     fn run_next_command(&self, data1: usize, data2: usize, data3: usize) {
         if !self.screen_ready.get() {
             self.screen_ready.set(true);
@@ -374,21 +803,20 @@ impl<'a> Screen<'a> {
         // Check if there are any pending events.
         for app in self.apps.iter() {
             let appid = app.processid();
-            let started_command = app.enter(|app| {
+            let pending_command = app.enter(|app| {
                 if app.pending_command {
                     app.pending_command = false;
-                    self.current_app.set(appid);
-                    let r = self.call_screen(app.command, app.data1, app.data2, appid);
-                    if r != Ok(()) {
-                        self.current_app.clear();
-                    }
-                    r == Ok(())
+                    Some((app.command, app.data1, app.data2))
                 } else {
-                    false
+                    None
                 }
             });
-            if started_command {
-                break;
+            if let Some((command, data1, data2)) = pending_command {
+                self.current_app.set(appid);
+                if self.call_screen(command, data1, data2, appid) == Ok(()) {
+                    break;
+                }
+                self.current_app.clear();
             }
         }
     }
@@ -474,10 +902,39 @@ impl<'a> Screen<'a> {
 
 impl<'a> hil::screen::ScreenClient for Screen<'a> {
     fn command_complete(&self, r: Result<(), ErrorCode>) {
+        if self.presenting.is_some() {
+            // The write frame of the present is set.
+            let res = r.and_then(|()| {
+                self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
+                    let len = self.fill_next_buffer_for_present(buffer);
+                    if len > 0 {
+                        self.screen.write(buffer, len)
+                    } else {
+                        self.buffer.replace(buffer);
+                        Err(ErrorCode::FAIL)
+                    }
+                })
+            });
+            if res.is_err() {
+                self.present_done(res);
+            }
+            return;
+        }
         self.run_next_command(kernel::into_statuscode(r), 0, 0);
     }
 
     fn write_complete(&self, buffer: &'static mut [u8], r: Result<(), ErrorCode>) {
+        if self.presenting.is_some() {
+            let len = self.fill_next_buffer_for_present(buffer);
+            if r == Ok(()) && len > 0 {
+                let _ = self.screen.write_continue(buffer, len);
+            } else {
+                self.buffer.replace(buffer);
+                self.present_done(r);
+            }
+            return;
+        }
+
         let len = self.fill_next_buffer_for_write(buffer);
 
         if r == Ok(()) && len > 0 {
@@ -499,6 +956,27 @@ impl<'a> hil::screen::ScreenSetupClient for Screen<'a> {
     }
 }
 
+impl<'a> gpio::Client for Screen<'a> {
+    /// The tearing effect line went high: the panel is in vertical blank.
+    fn fired(&self) {
+        self.vertical_blank();
+    }
+}
+
+impl<'a> Screen<'a> {
+    /// No vertical blank came in time: start the deferred write anyway.
+    pub fn vertical_blank_timeout(&self) {
+        self.start_deferred_write(self.deferred_write.timeout());
+        self.update_tearing_effect_interrupt();
+    }
+}
+
+impl<'a> hil::screen::ScreenVsyncClient for Screen<'a> {
+    fn vsync(&self) {
+        self.vertical_blank();
+    }
+}
+
 impl<'a> Driver for Screen<'a> {
     fn subscribe(
         &self,
@@ -513,6 +991,13 @@ impl<'a> Driver for Screen<'a> {
                     mem::swap(&mut app.callback, &mut callback);
                 })
                 .map_err(ErrorCode::from),
+            // Vertical blank notifications
+            1 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.vblank_callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
             _ => Err(ErrorCode::NOSUPPORT),
         };
         if let Err(e) = res {
@@ -576,6 +1061,55 @@ impl<'a> Driver for Screen<'a> {
             // Fill
             300 => self.enqueue_command(ScreenCommand::Fill, data1, data2, appid),
 
+            // Synchronize writes to the tearing effect line
+            400 => {
+                if self.has_vertical_blank() && !self.deferred_write.has_timer() {
+                    return CommandReturn::failure(ErrorCode::NOSUPPORT);
+                }
+                self.apps
+                    .enter(appid, |app| {
+                        app.tearing_effect_sync = data1 != 0;
+                        CommandReturn::success_u32(self.has_vertical_blank() as u32)
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            // Enable or disable vertical blank upcalls
+            401 => {
+                if !self.has_vertical_blank() {
+                    return CommandReturn::failure(ErrorCode::NOSUPPORT);
+                }
+                let res = self.apps.enter(appid, |app| {
+                    app.vblank_notify = data1 != 0;
+                });
+                match res {
+                    Ok(()) => {
+                        self.update_tearing_effect_interrupt();
+                        CommandReturn::success()
+                    }
+                    Err(e) => e.into(),
+                }
+            }
+
+            // Draw in the back buffer instead of the screen
+            500 => {
+                if self.back_buffer.is_none() {
+                    return CommandReturn::failure(ErrorCode::NOSUPPORT);
+                }
+                self.apps
+                    .enter(appid, |app| {
+                        app.back_buffered = data1 != 0;
+                        CommandReturn::success()
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            // Present the changes in the back buffer
+            501 => {
+                if self.back_buffer.is_none() {
+                    return CommandReturn::failure(ErrorCode::NOSUPPORT);
+                }
+                self.enqueue_command(ScreenCommand::Present, 0, 0, appid)
+            }
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -614,3 +1148,399 @@ impl<'a> Driver for Screen<'a> {
         }
     }
 }
+
+/// Times out writes of a `Screen` waiting for a vertical blank with an alarm.
+pub struct ScreenVerticalBlankTimer<'a, A: Alarm<'a>> {
+    screen: &'a Screen<'a>,
+    alarm: &'a A,
+}
+
+impl<'a, A: Alarm<'a>> ScreenVerticalBlankTimer<'a, A> {
+    pub fn new(screen: &'a Screen<'a>, alarm: &'a A) -> ScreenVerticalBlankTimer<'a, A> {
+        ScreenVerticalBlankTimer {
+            screen: screen,
+            alarm: alarm,
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> VerticalBlankTimer for ScreenVerticalBlankTimer<'a, A> {
+    fn start(&self, timeout_ms: u32) {
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(timeout_ms));
+    }
+
+    fn cancel(&self) {
+        let _ = self.alarm.disarm();
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for ScreenVerticalBlankTimer<'a, A> {
+    fn alarm(&self) {
+        self.screen.vertical_blank_timeout();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        DeferredWrite, Screen, ScreenCommand, VerticalBlankTimer, DRIVER_NUM,
+        VERTICAL_BLANK_TIMEOUT_MS,
+    };
+    use crate::test::mock_process;
+    use core::cell::Cell;
+    use kernel::common::cells::TakeCell;
+    use kernel::hil;
+    use kernel::hil::gpio::{self, Configuration, FloatingState, InterruptEdge};
+    use kernel::hil::screen::{ScreenClient, ScreenPixelFormat, ScreenRotation};
+    use kernel::{Driver, ErrorCode};
+
+    #[derive(Default)]
+    struct MockTimer {
+        running: Cell<Option<u32>>,
+    }
+
+    impl VerticalBlankTimer for MockTimer {
+        fn start(&self, timeout_ms: u32) {
+            self.running.set(Some(timeout_ms));
+        }
+
+        fn cancel(&self) {
+            self.running.set(None);
+        }
+    }
+
+    /// A panel that keeps the buffer of the write in progress.
+    struct MockPanel {
+        writing: TakeCell<'static, [u8]>,
+        written: Cell<usize>,
+        frame: Cell<(usize, usize, usize, usize)>,
+    }
+
+    impl Default for MockPanel {
+        fn default() -> MockPanel {
+            MockPanel {
+                writing: TakeCell::empty(),
+                written: Cell::new(0),
+                frame: Cell::new((0, 0, 0, 0)),
+            }
+        }
+    }
+
+    impl hil::screen::Screen for MockPanel {
+        fn get_resolution(&self) -> (usize, usize) {
+            (16, 16)
+        }
+
+        fn get_pixel_format(&self) -> ScreenPixelFormat {
+            ScreenPixelFormat::RGB_565
+        }
+
+        fn get_rotation(&self) -> ScreenRotation {
+            ScreenRotation::Normal
+        }
+
+        fn set_write_frame(
+            &self,
+            x: usize,
+            y: usize,
+            width: usize,
+            height: usize,
+        ) -> Result<(), ErrorCode> {
+            self.frame.set((x, y, width, height));
+            Ok(())
+        }
+
+        fn write(&self, buffer: &'static mut [u8], len: usize) -> Result<(), ErrorCode> {
+            self.written.set(self.written.get() + len);
+            self.writing.replace(buffer);
+            Ok(())
+        }
+
+        fn write_continue(&self, buffer: &'static mut [u8], len: usize) -> Result<(), ErrorCode> {
+            self.write(buffer, len)
+        }
+
+        fn set_client(&self, _client: Option<&'static dyn hil::screen::ScreenClient>) {}
+
+        fn set_brightness(&self, _brightness: usize) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn invert_on(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn invert_off(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    /// A tearing effect line.
+    #[derive(Default)]
+    struct MockPin {
+        interrupts: Cell<bool>,
+    }
+
+    impl gpio::Configure for MockPin {
+        fn configuration(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn make_output(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn disable_output(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn make_input(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn disable_input(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn deactivate_to_low_power(&self) {}
+
+        fn set_floating_state(&self, _state: FloatingState) {}
+
+        fn floating_state(&self) -> FloatingState {
+            FloatingState::PullNone
+        }
+    }
+
+    impl gpio::Output for MockPin {
+        fn set(&self) {}
+
+        fn clear(&self) {}
+
+        fn toggle(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Input for MockPin {
+        fn read(&self) -> bool {
+            false
+        }
+    }
+
+    impl<'a> gpio::Interrupt<'a> for MockPin {
+        fn set_client(&self, _client: &'a dyn gpio::Client) {}
+
+        fn enable_interrupts(&self, _mode: InterruptEdge) {
+            self.interrupts.set(true);
+        }
+
+        fn disable_interrupts(&self) {
+            self.interrupts.set(false);
+        }
+
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Pin for MockPin {}
+    impl<'a> gpio::InterruptPin<'a> for MockPin {}
+
+    /// A ready screen with a tearing effect line, used by one app that
+    /// allowed a frame of 4 bytes.
+    fn ready_screen<'a>(
+        panel: &'a MockPanel,
+        pin: &'a MockPin,
+    ) -> (Screen<'a>, &'static mock_process::MockProcess) {
+        let (kernel, processes) = mock_process::kernel(&["app"]);
+        let app = processes[0];
+        let screen = Screen::new(
+            panel,
+            None,
+            mock_process::buffer(16),
+            mock_process::grant(kernel),
+        );
+        screen.set_tearing_effect_pin(pin);
+        screen.screen_is_ready();
+        assert!(screen
+            .subscribe(0, app.upcall(DRIVER_NUM, 0), app.processid())
+            .is_ok());
+        assert!(screen
+            .allow_readonly(app.processid(), 0, app.readonly_slice(&[1, 2, 3, 4]))
+            .is_ok());
+        (screen, app)
+    }
+
+    #[test]
+    fn write_starts_now_without_timer() {
+        let deferred = DeferredWrite::new();
+        assert_eq!(
+            deferred.defer(ScreenCommand::Write, 4),
+            Err(ErrorCode::NOSUPPORT)
+        );
+        assert!(!deferred.is_some());
+        assert!(deferred.vertical_blank().is_none());
+    }
+
+    #[test]
+    fn vertical_blank_starts_write_and_cancels_timeout() {
+        let timer = MockTimer::default();
+        let deferred = DeferredWrite::new();
+        deferred.timer.set(&timer);
+
+        assert_eq!(deferred.defer(ScreenCommand::Write, 4), Ok(()));
+        assert!(deferred.is_some());
+        assert_eq!(timer.running.get(), Some(VERTICAL_BLANK_TIMEOUT_MS));
+
+        assert!(deferred.vertical_blank() == Some((ScreenCommand::Write, 4)));
+        assert_eq!(timer.running.get(), None);
+        assert!(!deferred.is_some());
+        // A late timeout finds nothing to start.
+        assert!(deferred.timeout().is_none());
+    }
+
+    #[test]
+    fn timeout_starts_write_without_vertical_blank() {
+        let timer = MockTimer::default();
+        let deferred = DeferredWrite::new();
+        deferred.timer.set(&timer);
+
+        assert_eq!(deferred.defer(ScreenCommand::Fill, 0), Ok(()));
+        assert!(deferred.timeout() == Some((ScreenCommand::Fill, 0)));
+        assert!(!deferred.is_some());
+        // A vertical blank after the timeout does not start it again.
+        assert!(deferred.vertical_blank().is_none());
+    }
+
+    #[test]
+    fn synchronized_write_waits_for_tearing_effect_edge() {
+        let (panel, pin, timer) = (
+            MockPanel::default(),
+            MockPin::default(),
+            MockTimer::default(),
+        );
+        let (screen, app) = ready_screen(&panel, &pin);
+        screen.set_vertical_blank_timer(&timer);
+
+        assert_eq!(
+            screen.command(400, 1, 0, app.processid()).get_success_u32(),
+            Some(1)
+        );
+        assert!(screen.command(200, 4, 0, app.processid()).is_success());
+        assert_eq!(panel.written.get(), 0);
+        assert!(pin.interrupts.get());
+        assert_eq!(timer.running.get(), Some(VERTICAL_BLANK_TIMEOUT_MS));
+
+        // The synthetic TE edge starts the write.
+        gpio::Client::fired(&screen);
+        assert_eq!(panel.written.get(), 4);
+        assert_eq!(timer.running.get(), None);
+        assert!(!pin.interrupts.get());
+
+        assert!(app.take_upcalls().is_empty());
+        screen.write_complete(panel.writing.take().unwrap(), Ok(()));
+        assert_eq!(app.take_upcalls(), [(0, 0, 0, 0)]);
+    }
+
+    #[test]
+    fn synchronized_write_starts_after_timeout() {
+        let (panel, pin, timer) = (
+            MockPanel::default(),
+            MockPin::default(),
+            MockTimer::default(),
+        );
+        let (screen, app) = ready_screen(&panel, &pin);
+        screen.set_vertical_blank_timer(&timer);
+
+        assert!(screen.command(400, 1, 0, app.processid()).get_success_u32() == Some(1));
+        assert!(screen.command(200, 4, 0, app.processid()).is_success());
+        assert_eq!(panel.written.get(), 0);
+
+        screen.vertical_blank_timeout();
+        assert_eq!(panel.written.get(), 4);
+        assert!(!pin.interrupts.get());
+
+        // A TE edge afterwards does not start the write again.
+        gpio::Client::fired(&screen);
+        assert_eq!(panel.written.get(), 4);
+    }
+
+    #[test]
+    fn unsynchronized_write_starts_now() {
+        let (panel, pin) = (MockPanel::default(), MockPin::default());
+        let (screen, app) = ready_screen(&panel, &pin);
+
+        assert!(screen.command(200, 4, 0, app.processid()).is_success());
+        assert_eq!(panel.written.get(), 4);
+    }
+
+    #[test]
+    fn synchronization_needs_timer() {
+        let (panel, pin) = (MockPanel::default(), MockPin::default());
+        let (screen, app) = ready_screen(&panel, &pin);
+
+        assert_eq!(
+            screen.command(400, 1, 0, app.processid()).get_failure(),
+            Some(ErrorCode::NOSUPPORT)
+        );
+    }
+
+    /// Fill the rectangle of the back buffer with the pixel allowed by the
+    /// app.
+    fn fill_back_buffer(
+        screen: &Screen,
+        app: &mock_process::MockProcess,
+        (x, y, width, height): (usize, usize, usize, usize),
+    ) {
+        let id = app.processid();
+        assert!(screen
+            .allow_readonly(id, 0, app.readonly_slice(&[0xab, 0xcd]))
+            .is_ok());
+        assert!(screen.command(500, 1, 0, id).is_success());
+        let frame = screen.command(100, x << 16 | y, width << 16 | height, id);
+        assert!(frame.is_success());
+        ScreenClient::command_complete(screen, Ok(()));
+        assert!(screen.command(300, 0, 0, id).is_success());
+    }
+
+    #[test]
+    fn present_sends_only_the_changes_of_the_app() {
+        let panel = MockPanel::default();
+        let (kernel, processes) = mock_process::kernel(&["a", "b"]);
+        let (a, b) = (processes[0], processes[1]);
+        let screen = Screen::new(
+            &panel,
+            None,
+            mock_process::buffer(16),
+            mock_process::grant(kernel),
+        );
+        screen.set_back_buffer(mock_process::buffer(16 * 16 * 2));
+        screen.screen_is_ready();
+
+        fill_back_buffer(&screen, a, (0, 0, 2, 2));
+        fill_back_buffer(&screen, a, (1, 1, 2, 1));
+        fill_back_buffer(&screen, b, (8, 8, 4, 4));
+        assert_eq!(panel.written.get(), 0);
+
+        // The present of `a` only covers the changes of `a`.
+        assert!(screen.command(501, 0, 0, a.processid()).is_success());
+        assert_eq!(panel.frame.get(), (0, 0, 3, 2));
+        ScreenClient::command_complete(&screen, Ok(()));
+        assert_eq!(panel.written.get(), 3 * 2 * 2);
+        screen.write_complete(panel.writing.take().unwrap(), Ok(()));
+
+        assert!(screen.command(501, 0, 0, b.processid()).is_success());
+        assert_eq!(panel.frame.get(), (8, 8, 4, 4));
+        ScreenClient::command_complete(&screen, Ok(()));
+        while let Some(buffer) = panel.writing.take() {
+            screen.write_complete(buffer, Ok(()));
+        }
+        assert_eq!(panel.written.get(), 3 * 2 * 2 + 4 * 4 * 2);
+
+        // Nothing changed since the last present of `a`.
+        panel.frame.set((0, 0, 0, 0));
+        assert!(screen.command(501, 0, 0, a.processid()).is_success());
+        assert_eq!(panel.frame.get(), (0, 0, 0, 0));
+    }
+}
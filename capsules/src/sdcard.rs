@@ -32,7 +32,22 @@
 //!     capsules::sdcard::SDCardDriver<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
 //!     capsules::sdcard::SDCardDriver::new(sdcard, &mut capsules::sdcard::KERNEL_BUFFER));
 //! sdcard.set_client(sdcard_driver);
+//!
+//! // Optional: watch the card detect pin, and initialize cards as they are
+//! // inserted.
+//! sdcard.set_auto_initialize(true);
+//! sdcard.detect_changes();
 //! ```
+//!
+//! Card Detection
+//! --------------
+//!
+//! If a card detect pin is wired, removing the card invalidates the card's
+//! initialization state: any transaction in progress is aborted with a
+//! `CardStateChanged` error, and reads and writes fail with `UNINSTALLED`
+//! until a card is inserted again. Once a card is inserted it must be
+//! initialized again, either explicitly or, if `set_auto_initialize()` is
+//! enabled, automatically once the card has settled.
 
 // Resources for SD Card API:
 //  * elm-chan.org/docs/mmc/mmc_e.html
@@ -74,6 +89,7 @@ pub struct SDCard<'a, A: hil::time::Alarm<'a>> {
 
     is_initialized: Cell<bool>,
     card_type: Cell<SDCardType>,
+    auto_initialize: Cell<bool>,
 
     detect_pin: Cell<Option<&'a dyn hil::gpio::InterruptPin<'a>>>,
 
@@ -238,6 +254,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
             alarm_count: Cell::new(0),
             is_initialized: Cell::new(false),
             card_type: Cell::new(SDCardType::Uninitialized),
+            auto_initialize: Cell::new(false),
             detect_pin: Cell::new(pin),
             txbuffer: TakeCell::new(txbuffer),
             rxbuffer: TakeCell::new(rxbuffer),
@@ -1078,6 +1095,15 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                 self.detect_changes();
                 self.alarm_count.set(0);
                 self.alarm_state.set(AlarmState::Idle);
+
+                // initialize a newly inserted card, if configured to
+                if self.auto_initialize.get() && self.is_installed() {
+                    if self.initialize().is_err() {
+                        self.client.map(move |client| {
+                            client.error(SdCardError::InitializationFailure as u32);
+                        });
+                    }
+                }
             }
 
             AlarmState::RepeatHCSInit => {
@@ -1201,6 +1227,12 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         self.is_initialized.get()
     }
 
+    /// sets whether a card is initialized automatically when it is inserted,
+    /// rather than waiting for an explicit call to `initialize()`
+    pub fn set_auto_initialize(&self, auto_initialize: bool) {
+        self.auto_initialize.set(auto_initialize);
+    }
+
     /// watches SD card detect pin for changes, sends callback on change
     pub fn detect_changes(&self) {
         self.detect_pin.get().map(|pin| {
@@ -1386,6 +1418,7 @@ impl<'a, A: hil::time::Alarm<'a>> hil::gpio::Client for SDCard<'a, A> {
 
         // either the card is new or gone, in either case it isn't initialized
         self.is_initialized.set(false);
+        self.card_type.set(SDCardType::Uninitialized);
 
         // disable additional interrupts
         self.detect_pin.get().map(|pin| {
@@ -1414,6 +1447,7 @@ pub struct SDCardDriver<'a, A: hil::time::Alarm<'a>> {
 #[derive(Default)]
 pub struct App {
     callback: Upcall,
+    detect_callback: Upcall,
     write_buffer: ReadOnlyAppSlice,
     read_buffer: ReadWriteAppSlice,
 }
@@ -1451,6 +1485,11 @@ impl<'a, A: hil::time::Alarm<'a>> SDCardClient for SDCardDriver<'a, A> {
                 app.callback.schedule(0, installed as usize, 0);
             });
         });
+
+        // notify every app watching for insertion and removal
+        self.grants.each(|_, app| {
+            app.detect_callback.schedule(installed as usize, 0, 0);
+        });
     }
 
     fn init_done(&self, block_size: u32, total_size: u64) {
@@ -1503,6 +1542,11 @@ impl<'a, A: hil::time::Alarm<'a>> SDCardClient for SDCardDriver<'a, A> {
     }
 
     fn error(&self, error: u32) {
+        // an aborted transaction may still hold the kernel buffer
+        self.sdcard.client_buffer.take().map(|buffer| {
+            self.kernel_buf.replace(buffer);
+        });
+
         self.current_process.map(|process_id| {
             let _ = self.grants.enter(*process_id, |app| {
                 app.callback.schedule(4, error as usize, 0);
@@ -1580,6 +1624,11 @@ impl<'a, A: hil::time::Alarm<'a>> Driver for SDCardDriver<'a, A> {
                         mem::swap(&mut grant.callback, &mut callback);
                         Ok(())
                     }
+                    // Set card detection callback
+                    1 => {
+                        mem::swap(&mut grant.detect_callback, &mut callback);
+                        Ok(())
+                    }
                     _ => Err(ErrorCode::NOSUPPORT),
                 }
             })
@@ -1615,6 +1664,16 @@ impl<'a, A: hil::time::Alarm<'a>> Driver for SDCardDriver<'a, A> {
             return CommandReturn::failure(ErrorCode::NOMEM);
         }
 
+        // reject block operations without an initialized card before taking
+        // the kernel buffer
+        if command_num == 3 || command_num == 4 {
+            if !self.sdcard.is_installed() {
+                return CommandReturn::failure(ErrorCode::UNINSTALLED);
+            } else if !self.sdcard.is_initialized() {
+                return CommandReturn::failure(ErrorCode::RESERVE);
+            }
+        }
+
         match command_num {
             // is_installed
             1 => {
@@ -1668,3 +1727,276 @@ impl<'a, A: hil::time::Alarm<'a>> Driver for SDCardDriver<'a, A> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{SDCard, SDCardDriver, SDCardType, SdCardError, DRIVER_NUM};
+    use crate::test::mock_alarm::MockAlarm;
+    use crate::test::mock_process::{self, MockProcess};
+    use core::cell::{Cell, RefCell};
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::gpio::{self, Configuration, FloatingState, InterruptEdge};
+    use kernel::hil::spi::{ClockPhase, ClockPolarity, SpiMasterClient, SpiMasterDevice};
+    use kernel::hil::time::Alarm;
+    use kernel::{Driver, ErrorCode};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// A bus that records the command of each transfer and holds the
+    /// buffers until the test completes it.
+    struct MockSpi {
+        write: TakeCell<'static, [u8]>,
+        read: TakeCell<'static, [u8]>,
+        len: Cell<usize>,
+        commands: RefCell<Vec<u8>>,
+    }
+
+    impl MockSpi {
+        fn new() -> MockSpi {
+            MockSpi {
+                write: TakeCell::empty(),
+                read: TakeCell::empty(),
+                len: Cell::new(0),
+                commands: RefCell::new(Vec::new()),
+            }
+        }
+
+        /// Finish the transfer in progress.
+        fn complete(&self, client: &dyn SpiMasterClient) {
+            let write = self.write.take().expect("no transfer in progress");
+            client.read_write_done(write, self.read.take(), self.len.get());
+        }
+    }
+
+    impl SpiMasterDevice for MockSpi {
+        fn configure(&self, _cpol: ClockPolarity, _cpal: ClockPhase, _rate: u32) {}
+
+        fn read_write_bytes(
+            &self,
+            write_buffer: &'static mut [u8],
+            read_buffer: Option<&'static mut [u8]>,
+            len: usize,
+        ) -> Result<(), ErrorCode> {
+            // Commands are sent after two dummy bytes.
+            if write_buffer[2] & 0xC0 == 0x40 {
+                self.commands.borrow_mut().push(write_buffer[2] & 0x3F);
+            }
+            self.write.replace(write_buffer);
+            self.read.put(read_buffer);
+            self.len.set(len);
+            Ok(())
+        }
+
+        fn set_polarity(&self, _cpol: ClockPolarity) {}
+        fn set_phase(&self, _cpal: ClockPhase) {}
+        fn set_rate(&self, _rate: u32) {}
+
+        fn get_polarity(&self) -> ClockPolarity {
+            ClockPolarity::IdleLow
+        }
+        fn get_phase(&self) -> ClockPhase {
+            ClockPhase::SampleLeading
+        }
+        fn get_rate(&self) -> u32 {
+            0
+        }
+
+        fn hold_low(&self) {}
+        fn release_low(&self) {}
+    }
+
+    /// An active low card detect pin.
+    #[derive(Default)]
+    struct MockPin {
+        removed: Cell<bool>,
+        interrupts: Cell<bool>,
+    }
+
+    impl gpio::Configure for MockPin {
+        fn configuration(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn make_output(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn disable_output(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn make_input(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn disable_input(&self) -> Configuration {
+            Configuration::Input
+        }
+
+        fn deactivate_to_low_power(&self) {}
+
+        fn set_floating_state(&self, _state: FloatingState) {}
+
+        fn floating_state(&self) -> FloatingState {
+            FloatingState::PullNone
+        }
+    }
+
+    impl gpio::Output for MockPin {
+        fn set(&self) {}
+
+        fn clear(&self) {}
+
+        fn toggle(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Input for MockPin {
+        fn read(&self) -> bool {
+            self.removed.get()
+        }
+    }
+
+    impl<'a> gpio::Interrupt<'a> for MockPin {
+        fn set_client(&self, _client: &'a dyn gpio::Client) {}
+
+        fn enable_interrupts(&self, _mode: InterruptEdge) {
+            self.interrupts.set(true);
+        }
+
+        fn disable_interrupts(&self) {
+            self.interrupts.set(false);
+        }
+
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Pin for MockPin {}
+    impl<'a> gpio::InterruptPin<'a> for MockPin {}
+
+    type Card = SDCard<'static, MockAlarm<'static>>;
+
+    struct CardTest {
+        sdcard: &'static Card,
+        driver: &'static SDCardDriver<'static, MockAlarm<'static>>,
+        spi: &'static MockSpi,
+        alarm: &'static MockAlarm<'static>,
+        pin: &'static MockPin,
+        app: &'static MockProcess,
+    }
+
+    impl CardTest {
+        /// An initialized card watched by a process.
+        fn new() -> CardTest {
+            let (kernel, processes) = mock_process::kernel(&["files"]);
+            let spi = mock_process::leak(MockSpi::new());
+            let alarm: &MockAlarm = mock_process::leak(MockAlarm::new());
+            let pin = mock_process::leak(MockPin::default());
+            let sdcard = mock_process::leak(SDCard::new(
+                spi,
+                alarm,
+                Some(pin),
+                Box::leak(Box::new([0; 515])),
+                Box::leak(Box::new([0; 515])),
+            ));
+            let driver = mock_process::leak(SDCardDriver::new(
+                sdcard,
+                Box::leak(Box::new([0; 512])),
+                mock_process::grant(kernel),
+            ));
+            sdcard.set_client(driver);
+            alarm.set_alarm_client(sdcard);
+            sdcard.detect_changes();
+            // As if the card had been initialized.
+            sdcard.is_initialized.set(true);
+            sdcard.card_type.set(SDCardType::SDv2BlockAddressable);
+
+            let app = processes[0];
+            let id = app.processid();
+            assert!(driver.subscribe(0, app.upcall(DRIVER_NUM, 0), id).is_ok());
+            assert!(driver.subscribe(1, app.upcall(DRIVER_NUM, 1), id).is_ok());
+            CardTest {
+                sdcard: sdcard,
+                driver: driver,
+                spi: spi,
+                alarm: alarm,
+                pin: pin,
+                app: app,
+            }
+        }
+
+        fn command(&self, command_num: usize, data: usize) -> Result<(), ErrorCode> {
+            let ret = self
+                .driver
+                .command(command_num, data, 0, self.app.processid());
+            match ret.get_failure() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+
+        /// Move the card and let the detection settle.
+        fn set_removed(&self, removed: bool) {
+            self.pin.removed.set(removed);
+            gpio::Client::fired(self.sdcard);
+            assert!(!self.pin.interrupts.get());
+            assert_eq!(self.alarm.fire(), Some(500_000));
+            assert!(self.pin.interrupts.get());
+        }
+    }
+
+    #[test]
+    fn test_removal_aborts_and_invalidates() {
+        let test = CardTest::new();
+        assert_eq!(test.command(3, 7), Ok(()));
+        assert_eq!(*test.spi.commands.borrow(), [17]);
+
+        test.pin.removed.set(true);
+        gpio::Client::fired(test.sdcard);
+        // The read in progress fails, and the card must be initialized
+        // again.
+        assert_eq!(
+            test.app.take_upcalls(),
+            [(0, 4, SdCardError::CardStateChanged as u32 as usize, 0)]
+        );
+        assert!(!test.sdcard.is_initialized());
+        test.spi.complete(test.sdcard);
+        assert!(test.app.take_upcalls().is_empty());
+        assert_eq!(test.command(3, 7), Err(ErrorCode::UNINSTALLED));
+
+        // Once the card has settled, every watching process is told.
+        assert_eq!(test.alarm.fire(), Some(500_000));
+        assert_eq!(test.app.take_upcalls(), [(0, 0, 0, 0), (1, 0, 0, 0)]);
+        assert_eq!(test.command(4, 7), Err(ErrorCode::UNINSTALLED));
+        assert_eq!(test.command(2, 0), Err(ErrorCode::UNINSTALLED));
+        assert_eq!(test.spi.commands.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_reinsertion_initializes_again() {
+        let test = CardTest::new();
+        test.sdcard.set_auto_initialize(true);
+        test.set_removed(true);
+        // Only the detection upcall is delivered to a process that has not
+        // used the card.
+        assert_eq!(test.app.take_upcalls(), [(1, 0, 0, 0)]);
+        assert!(test.spi.commands.borrow().is_empty());
+
+        // A new card is reported, and initialization starts with a reset.
+        test.set_removed(false);
+        assert_eq!(test.app.take_upcalls(), [(1, 1, 0, 0)]);
+        assert_eq!(*test.spi.commands.borrow(), [0]);
+        assert!(test
+            .driver
+            .command(1, 0, 0, test.app.processid())
+            .get_success_u32()
+            .map_or(false, |installed| installed == 1));
+        // Until then, blocks cannot be read.
+        assert_eq!(test.command(3, 7), Err(ErrorCode::RESERVE));
+    }
+}
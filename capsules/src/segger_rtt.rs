@@ -30,6 +30,15 @@
 //! if there is support for software interrupts or deferred calls in capsules,
 //! this timer should be removed.
 //!
+//! Tracing
+//! -------
+//!
+//! The RTT memory also has a second up channel, named "Trace", for kernel
+//! trace records (see `kernel::trace`). It has no buffer unless the board
+//! gives it one with `SeggerRtt::set_trace_buffer()`; `SeggerRtt` is then a
+//! `TraceSink` that writes records to it. Read it with `JLinkRTTLogger`,
+//! choosing channel 1.
+//!
 //! Todo
 //! ----
 //!
@@ -90,6 +99,7 @@ use core::marker::PhantomData;
 use kernel::common::cells::{OptionalCell, TakeCell, VolatileCell};
 use kernel::hil;
 use kernel::hil::uart;
+use kernel::trace::{TraceSink, RECORD_LEN};
 use kernel::ErrorCode;
 
 /// Suggested length for the up buffer to pass to the Segger RTT capsule.
@@ -107,6 +117,7 @@ pub struct SeggerRttMemory<'a> {
     number_up_buffers: VolatileCell<u32>,
     number_down_buffers: VolatileCell<u32>,
     up_buffer: SeggerRttBuffer<'a>,
+    trace_buffer: SeggerRttBuffer<'a>,
     down_buffer: SeggerRttBuffer<'a>,
 }
 
@@ -140,7 +151,7 @@ impl<'a> SeggerRttMemory<'a> {
             // known problem so far. If needed, this ID could be scrambled here, with the real magic
             // value being written only when this object is fully initialized.
             id: VolatileCell::new(*b"SEGGER RTT\0\0\0\0\0\0"),
-            number_up_buffers: VolatileCell::new(1),
+            number_up_buffers: VolatileCell::new(2),
             number_down_buffers: VolatileCell::new(1),
             up_buffer: SeggerRttBuffer {
                 name: VolatileCell::new(up_buffer_name.as_ptr()),
@@ -151,6 +162,16 @@ impl<'a> SeggerRttMemory<'a> {
                 flags: VolatileCell::new(0),
                 _lifetime: PhantomData,
             },
+            // Empty until `set_trace_buffer()`.
+            trace_buffer: SeggerRttBuffer {
+                name: VolatileCell::new(b"Trace\0".as_ptr()),
+                buffer: VolatileCell::new(core::ptr::null()),
+                length: VolatileCell::new(0),
+                write_position: VolatileCell::new(0),
+                read_position: VolatileCell::new(0),
+                flags: VolatileCell::new(0),
+                _lifetime: PhantomData,
+            },
             down_buffer: SeggerRttBuffer {
                 name: VolatileCell::new(down_buffer_name.as_ptr()),
                 buffer: VolatileCell::new(down_buffer_ptr),
@@ -176,6 +197,7 @@ pub struct SeggerRtt<'a, A: hil::time::Alarm<'a>> {
     config: TakeCell<'a, SeggerRttMemory<'a>>,
     up_buffer: TakeCell<'a, [u8]>,
     _down_buffer: TakeCell<'a, [u8]>,
+    trace_buffer: TakeCell<'a, [u8]>,
     client: OptionalCell<&'a dyn uart::TransmitClient>,
     client_buffer: TakeCell<'static, [u8]>,
     tx_len: Cell<usize>,
@@ -193,11 +215,50 @@ impl<'a, A: hil::time::Alarm<'a>> SeggerRtt<'a, A> {
             config: TakeCell::new(config),
             up_buffer: TakeCell::new(up_buffer),
             _down_buffer: TakeCell::new(down_buffer),
+            trace_buffer: TakeCell::empty(),
             client: OptionalCell::empty(),
             client_buffer: TakeCell::empty(),
             tx_len: Cell::new(0),
         }
     }
+
+    /// Give the trace channel a buffer, so that kernel trace records can be
+    /// written to it.
+    pub fn set_trace_buffer(&self, buffer: &'a mut [u8]) {
+        self.config.map(|config| {
+            config.trace_buffer.buffer.set(buffer.as_ptr());
+            config.trace_buffer.length.set(buffer.len() as u32);
+        });
+        self.trace_buffer.replace(buffer);
+    }
+}
+
+impl<'a, A: hil::time::Alarm<'a>> TraceSink for SeggerRtt<'a, A> {
+    fn write(&self, record: &[u8; RECORD_LEN]) {
+        self.trace_buffer.map(|buffer| {
+            self.config.map(|config| {
+                let channel = &config.trace_buffer;
+                let length = buffer.len();
+                let write = channel.write_position.get() as usize;
+                let read = channel.read_position.get() as usize;
+                if length <= RECORD_LEN {
+                    return;
+                }
+                // Drop records while the host has not read enough. One byte
+                // stays free, to tell a full channel from an empty one.
+                let free = (read + length - write - 1) % length;
+                if free < RECORD_LEN {
+                    return;
+                }
+                for (i, &byte) in record.iter().enumerate() {
+                    buffer[(write + i) % length] = byte;
+                }
+                channel
+                    .write_position
+                    .set(((write + RECORD_LEN) % length) as u32);
+            });
+        });
+    }
 }
 
 impl<'a, A: hil::time::Alarm<'a>> uart::Uart<'a> for SeggerRtt<'a, A> {}
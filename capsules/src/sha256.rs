@@ -0,0 +1,291 @@
+//! Software implementation of SHA-256.
+//!
+//! This is a synchronous implementation for boards without a hardware digest
+//! engine, such as for checking the signatures of apps while they are loaded,
+//! or for protocols that need many small digests in a row. `hmac_sha256()`
+//! computes HMAC-SHA256 (RFC 2104) with it.
+//!
+//! `Sha256Software` provides it through the `digest` HIL, so that capsules
+//! written against a digest engine also run on boards without one. It hashes
+//! the data when it is added and issues the callbacks from a deferred call.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let mut sha = capsules::sha256::Sha256::new();
+//! sha.update(b"abc");
+//! let digest: [u8; 32] = sha.finish();
+//! ```
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sha = static_init!(
+//!     capsules::sha256::Sha256Software<'static>,
+//!     capsules::sha256::Sha256Software::new(dynamic_deferred_caller)
+//! );
+//! sha.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(sha)
+//!         .expect("no deferred call slot available for sha256"),
+//! );
+//! ```
+
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::ErrorCode;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// A SHA-256 computation in progress.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    // Bytes in `block`.
+    block_len: usize,
+    // Bytes hashed so far.
+    length: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 {
+            state: H0,
+            block: [0; 64],
+            block_len: 0,
+            length: 0,
+        }
+    }
+
+    /// Add `data` to the message.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        while data.len() > 0 {
+            let take = core::cmp::min(64 - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+            if self.block_len == 64 {
+                self.compress();
+            }
+        }
+    }
+
+    /// Pad the message and return its digest.
+    pub fn finish(mut self) -> [u8; 32] {
+        let bit_length = self.length * 8;
+        self.block[self.block_len] = 0x80;
+        self.block_len += 1;
+        if self.block_len > 56 {
+            for byte in self.block[self.block_len..].iter_mut() {
+                *byte = 0;
+            }
+            self.compress();
+        }
+        for byte in self.block[self.block_len..56].iter_mut() {
+            *byte = 0;
+        }
+        self.block[56..].copy_from_slice(&bit_length.to_be_bytes());
+        self.compress();
+
+        let mut digest = [0; 32];
+        for (i, word) in self.state.iter().enumerate() {
+            digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    /// Hash the full block.
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([
+                self.block[i * 4],
+                self.block[i * 4 + 1],
+                self.block[i * 4 + 2],
+                self.block[i * 4 + 3],
+            ]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *state = state.wrapping_add(*value);
+        }
+        self.block_len = 0;
+    }
+}
+
+const HMAC_BLOCK_LEN: usize = 64;
+
+/// HMAC-SHA256 of the concatenation of `message`.
+pub fn hmac_sha256(key: &[u8], message: &[&[u8]]) -> [u8; 32] {
+    let mut pad = [0; HMAC_BLOCK_LEN];
+    if key.len() > HMAC_BLOCK_LEN {
+        let mut sha = Sha256::new();
+        sha.update(key);
+        pad[..32].copy_from_slice(&sha.finish());
+    } else {
+        pad[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    for byte in pad.iter_mut() {
+        *byte ^= 0x36;
+    }
+    inner.update(&pad);
+    for part in message {
+        inner.update(part);
+    }
+    let inner_hash = inner.finish();
+
+    let mut outer = Sha256::new();
+    // Turn the inner padding into the outer one.
+    for byte in pad.iter_mut() {
+        *byte ^= 0x36 ^ 0x5c;
+    }
+    outer.update(&pad);
+    outer.update(&inner_hash);
+    outer.finish()
+}
+
+/// SHA-256 in software behind the `digest` HIL. One `add_data()` or `run()`
+/// is outstanding at a time; others fail with `BUSY` until its callback.
+pub struct Sha256Software<'a> {
+    client: OptionalCell<&'a dyn digest::Client<'a, [u8; 32]>>,
+    sha: MapCell<Sha256>,
+    /// Data added and digest computed, returned by the deferred call.
+    data: TakeCell<'static, [u8]>,
+    digest: TakeCell<'static, [u8; 32]>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> Sha256Software<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> Sha256Software<'a> {
+        Sha256Software {
+            client: OptionalCell::empty(),
+            sha: MapCell::new(Sha256::new()),
+            data: TakeCell::empty(),
+            digest: TakeCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn is_busy(&self) -> bool {
+        self.data.is_some() || self.digest.is_some()
+    }
+
+    fn schedule_callback(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+}
+
+impl<'a> digest::Digest<'a, [u8; 32]> for Sha256Software<'a> {
+    fn set_client(&'a self, client: &'a dyn digest::Client<'a, [u8; 32]>) {
+        self.client.set(client);
+    }
+
+    fn add_data(
+        &self,
+        data: LeasableBuffer<'static, u8>,
+    ) -> Result<usize, (ErrorCode, &'static mut [u8])> {
+        if self.is_busy() {
+            return Err((ErrorCode::BUSY, data.take()));
+        }
+        let len = data.len();
+        self.sha.map(|sha| sha.update(&data[..]));
+        self.data.replace(data.take());
+        self.schedule_callback();
+        Ok(len)
+    }
+
+    fn run(
+        &'a self,
+        digest: &'static mut [u8; 32],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; 32])> {
+        if self.is_busy() {
+            return Err((ErrorCode::BUSY, digest));
+        }
+        let sha = self.sha.replace(Sha256::new()).unwrap_or_else(Sha256::new);
+        *digest = sha.finish();
+        self.digest.replace(digest);
+        self.schedule_callback();
+        Ok(())
+    }
+
+    fn clear_data(&self) {
+        self.sha.replace(Sha256::new());
+    }
+}
+
+impl<'a> digest::Sha256 for Sha256Software<'a> {
+    /// SHA-256 is the only mode.
+    fn set_mode_sha256(&self) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for Sha256Software<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if let Some(data) = self.data.take() {
+            self.client
+                .map(move |client| client.add_data_done(Ok(()), data));
+        } else if let Some(digest) = self.digest.take() {
+            self.client
+                .map(move |client| client.hash_done(Ok(()), digest));
+        }
+    }
+}
@@ -0,0 +1,423 @@
+//! Driver for SMBus devices on an I2C master.
+//!
+//! SMBus is a subset of I2C with a fixed set of transaction formats. This
+//! driver implements those protocols on top of any I2C master, so userspace
+//! can talk to battery gauges, PMBus power supplies, and other SMBus devices
+//! by command code rather than building raw I2C transfers.
+//!
+//! Packet Error Checking
+//! ---------------------
+//!
+//! Each transaction can optionally carry a packet error code (PEC), a CRC-8
+//! over every byte of the transaction including the address bytes. When PEC
+//! is requested the driver appends it to writes, and checks the one the
+//! device appends to reads. A read whose PEC does not match fails with
+//! `FAIL`.
+//!
+//! Block Reads
+//! -----------
+//!
+//! In a block read the device sends a count byte followed by that many data
+//! bytes. The I2C master HIL reads a fixed number of bytes, so the driver
+//! always reads the maximum block length of 32 bytes, and ignores the bytes
+//! past the count the device sent.
+//!
+//! SMBALERT
+//! --------
+//!
+//! Devices signal that they need attention by pulling the shared, active low
+//! SMBALERT# line. If the board connects it to a pin, userspace is notified
+//! on the falling edge, and can read the Alert Response Address to learn the
+//! address of the device that raised the alert.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let smbus = static_init!(
+//!     capsules::smbus::SMBusDriver<'static, nrf52840::i2c::TWIM>,
+//!     capsules::smbus::SMBusDriver::new(
+//!         &base_peripherals.twim0,
+//!         &mut capsules::smbus::BUF,
+//!         board_kernel.create_grant(&memory_allocation_capability)
+//!     )
+//! );
+//! base_peripherals.twim0.set_master_client(smbus);
+//!
+//! // Optionally, the pin connected to SMBALERT#.
+//! smbus.set_alert_pin(&nrf52840_peripherals.gpio_port[ALERT_PIN]);
+//! nrf52840_peripherals.gpio_port[ALERT_PIN].set_client(smbus);
+//! ```
+
+use core::cmp;
+use enum_primitive::cast::FromPrimitive;
+use enum_primitive::enum_from_primitive;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::hil::gpio;
+use kernel::hil::i2c;
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadWrite, ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SMBus as usize;
+
+/// The address devices respond to with their own address when they have
+/// raised SMBALERT#.
+pub const ALERT_RESPONSE_ADDRESS: u8 = 0x0C;
+
+/// The maximum number of data bytes in a block read or write.
+pub const MAX_BLOCK_LENGTH: usize = 32;
+
+/// Large enough for a command code, a count byte, a full block and a PEC.
+pub static mut BUF: [u8; MAX_BLOCK_LENGTH + 3] = [0; MAX_BLOCK_LENGTH + 3];
+
+/// Bit of `arg1` that requests packet error checking.
+const PEC_FLAG: usize = 1 << 16;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    alert_callback: Upcall,
+    slice: ReadWriteAppSlice,
+}
+
+struct Transaction {
+    app_id: ProcessId,
+    protocol: Cmd,
+    /// Whether the device appends a PEC to the bytes read.
+    pec: bool,
+    /// The PEC computed over the transaction up to the bytes read.
+    crc: u8,
+}
+
+pub struct SMBusDriver<'a, I: 'a + i2c::I2CMaster> {
+    i2c: &'a I,
+    alert_pin: OptionalCell<&'a dyn gpio::InterruptPin<'a>>,
+    buf: TakeCell<'static, [u8]>,
+    tx: MapCell<Transaction>,
+    apps: Grant<App>,
+}
+
+impl<'a, I: 'a + i2c::I2CMaster> SMBusDriver<'a, I> {
+    pub fn new(i2c: &'a I, buf: &'static mut [u8], apps: Grant<App>) -> SMBusDriver<'a, I> {
+        SMBusDriver {
+            i2c: i2c,
+            alert_pin: OptionalCell::empty(),
+            buf: TakeCell::new(buf),
+            tx: MapCell::empty(),
+            apps: apps,
+        }
+    }
+
+    /// Set the pin connected to the SMBALERT# line.
+    pub fn set_alert_pin(&self, pin: &'a dyn gpio::InterruptPin<'a>) {
+        self.alert_pin.set(pin);
+    }
+
+    /// Start the SMBus protocol `protocol`.
+    ///
+    /// `arg1` holds the 7-bit device address in bits 0-6, the command code
+    /// in bits 8-15, and `PEC_FLAG`. `arg2` holds the byte or word to write,
+    /// or the length of a block write.
+    fn start(&self, app_id: ProcessId, protocol: Cmd, arg1: usize, arg2: usize) -> CommandReturn {
+        if self.tx.is_some() {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+        let buffer = match self.buf.take() {
+            Some(buffer) => buffer,
+            None => return CommandReturn::failure(ErrorCode::BUSY),
+        };
+
+        let addr = match protocol {
+            Cmd::AlertResponse => ALERT_RESPONSE_ADDRESS,
+            _ => (arg1 & 0x7f) as u8,
+        };
+        let command = (arg1 >> 8) as u8;
+        let pec = arg1 & PEC_FLAG == PEC_FLAG;
+
+        let lengths = self
+            .apps
+            .enter(app_id, |app| match protocol {
+                Cmd::SendByte => {
+                    buffer[0] = arg2 as u8;
+                    Ok((1, 0))
+                }
+                Cmd::ReceiveByte | Cmd::AlertResponse => Ok((0, 1)),
+                Cmd::WriteByte => {
+                    buffer[0] = command;
+                    buffer[1] = arg2 as u8;
+                    Ok((2, 0))
+                }
+                Cmd::ReadByte => {
+                    buffer[0] = command;
+                    Ok((1, 1))
+                }
+                Cmd::WriteWord | Cmd::ProcessCall => {
+                    buffer[0] = command;
+                    buffer[1] = arg2 as u8;
+                    buffer[2] = (arg2 >> 8) as u8;
+                    Ok((3, if protocol == Cmd::ProcessCall { 2 } else { 0 }))
+                }
+                Cmd::ReadWord => {
+                    buffer[0] = command;
+                    Ok((1, 2))
+                }
+                Cmd::BlockWrite => app.slice.map_or(Err(ErrorCode::RESERVE), |app_buffer| {
+                    if arg2 > MAX_BLOCK_LENGTH || arg2 > app_buffer.len() {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    buffer[0] = command;
+                    buffer[1] = arg2 as u8;
+                    buffer[2..(2 + arg2)].copy_from_slice(&app_buffer[..arg2]);
+                    Ok((2 + arg2, 0))
+                }),
+                Cmd::BlockRead => {
+                    buffer[0] = command;
+                    Ok((1, 1 + MAX_BLOCK_LENGTH))
+                }
+                Cmd::Ping | Cmd::EnableAlert | Cmd::DisableAlert => Err(ErrorCode::INVAL),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        let (mut wlen, mut rlen) = match lengths {
+            Ok(lengths) => lengths,
+            Err(e) => {
+                self.buf.replace(buffer);
+                return CommandReturn::failure(e);
+            }
+        };
+
+        // The PEC covers each address byte as it appears on the bus, with
+        // the read/write bit.
+        let mut crc = 0;
+        if wlen > 0 {
+            crc = i2c::smbus_pec(i2c::smbus_pec(0, &[addr << 1]), &buffer[..wlen]);
+            if pec && rlen == 0 {
+                buffer[wlen] = crc;
+                wlen += 1;
+            }
+        }
+        if rlen > 0 {
+            crc = i2c::smbus_pec(crc, &[(addr << 1) | 1]);
+            if pec {
+                rlen += 1;
+            }
+        }
+
+        self.tx.put(Transaction {
+            app_id: app_id,
+            protocol: protocol,
+            pec: pec,
+            crc: crc,
+        });
+        if wlen > 0 && rlen > 0 {
+            self.i2c.write_read(addr, buffer, wlen as u8, rlen as u8);
+        } else if wlen > 0 {
+            self.i2c.write(addr, buffer, wlen as u8);
+        } else {
+            self.i2c.read(addr, buffer, rlen as u8);
+        }
+        CommandReturn::success()
+    }
+
+    /// Check the bytes read by `tx` and extract the value to report to
+    /// userspace.
+    fn complete(&self, tx: &Transaction, app: &mut App, buffer: &[u8]) -> Result<usize, ErrorCode> {
+        let (value, len) = match tx.protocol {
+            Cmd::ReceiveByte | Cmd::ReadByte => (buffer[0] as usize, 1),
+            // The device responds with its own address, shifted as it would
+            // be on the bus.
+            Cmd::AlertResponse => ((buffer[0] >> 1) as usize, 1),
+            Cmd::ReadWord | Cmd::ProcessCall => (buffer[0] as usize | (buffer[1] as usize) << 8, 2),
+            Cmd::BlockRead => {
+                let count = buffer[0] as usize;
+                if count > MAX_BLOCK_LENGTH {
+                    return Err(ErrorCode::SIZE);
+                }
+                (count, 1 + count)
+            }
+            _ => return Ok(0),
+        };
+
+        if tx.pec && i2c::smbus_pec(tx.crc, &buffer[..len]) != buffer[len] {
+            return Err(ErrorCode::FAIL);
+        }
+
+        if tx.protocol == Cmd::BlockRead {
+            app.slice.mut_map_or((), |app_buffer| {
+                let count = cmp::min(value, app_buffer.len());
+                app_buffer[..count].copy_from_slice(&buffer[1..(1 + count)]);
+            });
+        }
+        Ok(value)
+    }
+}
+
+fn into_result(error: i2c::Error) -> Result<(), ErrorCode> {
+    match error {
+        i2c::Error::CommandComplete => Ok(()),
+        i2c::Error::AddressNak | i2c::Error::DataNak => Err(ErrorCode::NOACK),
+        i2c::Error::ArbitrationLost => Err(ErrorCode::BUSY),
+        i2c::Error::NotSupported => Err(ErrorCode::NOSUPPORT),
+        i2c::Error::Overrun | i2c::Error::Timeout | i2c::Error::PecMismatch => Err(ErrorCode::FAIL),
+    }
+}
+
+enum_from_primitive! {
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Cmd {
+    Ping = 0,
+    SendByte = 1,
+    ReceiveByte = 2,
+    WriteByte = 3,
+    ReadByte = 4,
+    WriteWord = 5,
+    ReadWord = 6,
+    BlockWrite = 7,
+    BlockRead = 8,
+    ProcessCall = 9,
+    AlertResponse = 10,
+    EnableAlert = 11,
+    DisableAlert = 12,
+}
+}
+
+impl<'a, I: 'a + i2c::I2CMaster> Driver for SMBusDriver<'a, I> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `1`: Data for block writes, and destination of block reads.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            1 => self
+                .apps
+                .enter(appid, |app| {
+                    core::mem::swap(&mut app.slice, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `1`: Transaction completed. The first argument is the status, and
+    ///        the second the byte or word read, the number of bytes in a
+    ///        block read, or the address of the alerting device.
+    /// - `2`: SMBALERT# asserted.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            1 => self
+                .apps
+                .enter(app_id, |app| {
+                    core::mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            2 => self
+                .apps
+                .enter(app_id, |app| {
+                    core::mem::swap(&mut app.alert_callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// Start transactions.
+    ///
+    /// For commands `1`-`9`, `arg1` holds the 7-bit device address in bits
+    /// 0-6 and the command code in bits 8-15. Setting bit 16 enables packet
+    /// error checking.
+    ///
+    /// ### `cmd_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Send byte `arg2`.
+    /// - `2`: Receive byte.
+    /// - `3`: Write byte `arg2`.
+    /// - `4`: Read byte.
+    /// - `5`: Write word `arg2`.
+    /// - `6`: Read word.
+    /// - `7`: Block write the first `arg2` bytes of the buffer.
+    /// - `8`: Block read into the buffer.
+    /// - `9`: Process call, writing word `arg2` and reading a word back.
+    /// - `10`: Read the Alert Response Address. Bit 16 of `arg1` enables
+    ///         packet error checking.
+    /// - `11`: Enable SMBALERT# notifications.
+    /// - `12`: Disable SMBALERT# notifications.
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
+        match Cmd::from_usize(cmd_num) {
+            Some(Cmd::Ping) => CommandReturn::success(),
+            Some(Cmd::EnableAlert) => {
+                self.alert_pin
+                    .map_or(CommandReturn::failure(ErrorCode::NOSUPPORT), |pin| {
+                        pin.make_input();
+                        pin.enable_interrupts(gpio::InterruptEdge::FallingEdge);
+                        CommandReturn::success()
+                    })
+            }
+            Some(Cmd::DisableAlert) => {
+                self.alert_pin
+                    .map_or(CommandReturn::failure(ErrorCode::NOSUPPORT), |pin| {
+                        pin.disable_interrupts();
+                        CommandReturn::success()
+                    })
+            }
+            Some(protocol) => self.start(appid, protocol, arg1, arg2),
+            None => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+impl<'a, I: 'a + i2c::I2CMaster> i2c::I2CHwMasterClient for SMBusDriver<'a, I> {
+    fn command_complete(&self, buffer: &'static mut [u8], error: i2c::Error) {
+        self.tx.take().map(|tx| {
+            let _ = self.apps.enter(tx.app_id, |app| {
+                let (result, value) =
+                    match into_result(error).and_then(|()| self.complete(&tx, app, buffer)) {
+                        Ok(value) => (Ok(()), value),
+                        Err(e) => (Err(e), 0),
+                    };
+                app.callback
+                    .schedule(kernel::into_statuscode(result), value, 0);
+            });
+        });
+
+        self.buf.replace(buffer);
+    }
+}
+
+impl<'a, I: 'a + i2c::I2CMaster> gpio::Client for SMBusDriver<'a, I> {
+    fn fired(&self) {
+        self.apps.each(|_, app| {
+            app.alert_callback.schedule(0, 0, 0);
+        });
+    }
+}
@@ -1,5 +1,21 @@
 //! Provides userspace applications with the ability to communicate over the SPI
 //! bus.
+//!
+//! Slow or timing-sensitive devices can require gaps on the bus. The driver
+//! supports two delays, both zero by default:
+//!
+//! - An inter-byte delay, between the last clock of a byte and the first
+//!   clock of the next byte.
+//! - A chip-select setup/hold delay, between asserting the chip select and
+//!   the first clock, and between the last clock and deasserting the chip
+//!   select.
+//!
+//! When either delay is non-zero, the driver holds the chip select low for
+//! the whole operation and times the delays with an alarm, transferring one
+//! byte at a time if there is an inter-byte delay. The setup delay relies on
+//! the controller asserting the chip select when it is held (see
+//! `SpiMaster::hold_low()`); controllers that only assert it when a transfer
+//! starts, such as the SAM4L SPI peripheral, cannot honor it.
 
 use core::cell::Cell;
 use core::{cmp, mem};
@@ -7,6 +23,7 @@ use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil::spi::ClockPhase;
 use kernel::hil::spi::ClockPolarity;
 use kernel::hil::spi::{SpiMasterClient, SpiMasterDevice};
+use kernel::hil::time::{self, Alarm};
 use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
 use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
 
@@ -18,6 +35,89 @@ pub const DRIVER_NUM: usize = driver::NUM::Spi as usize;
 pub const DEFAULT_READ_BUF_LENGTH: usize = 1024;
 pub const DEFAULT_WRITE_BUF_LENGTH: usize = 1024;
 
+/// Largest inter-byte or chip-select delay, in microseconds.
+pub const MAX_DELAY_US: usize = 10_000;
+
+/// What the driver is waiting for when its alarm fires.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum DelayState {
+    Idle,
+    /// Chip-select setup delay before the first transfer.
+    Setup,
+    /// Delay between two bytes.
+    InterByte,
+    /// Chip-select hold delay after the last transfer.
+    Hold,
+}
+
+/// What the driver does next in an operation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Step {
+    /// Issue the next transfer.
+    Transfer,
+    /// Wait for a delay, in microseconds, with the chip select held.
+    Wait(DelayState, u32),
+    /// Release the chip select and notify the process.
+    Finish,
+}
+
+/// The configured delays, which decide how an operation is sequenced.
+#[derive(Copy, Clone, Default)]
+struct Delays {
+    inter_byte_us: u32,
+    cs_us: u32,
+}
+
+impl Delays {
+    /// Whether the chip select is held low for the whole operation.
+    fn hold_chip_select(&self) -> bool {
+        self.inter_byte_us > 0 || self.cs_us > 0
+    }
+
+    /// The first step, once the chip select is held.
+    fn first_step(&self) -> Step {
+        if self.cs_us > 0 {
+            Step::Wait(DelayState::Setup, self.cs_us)
+        } else {
+            Step::Transfer
+        }
+    }
+
+    /// The step after a transfer completes with `remaining` bytes left.
+    fn next_step(&self, remaining: usize) -> Step {
+        if remaining == 0 {
+            if self.cs_us > 0 {
+                Step::Wait(DelayState::Hold, self.cs_us)
+            } else {
+                Step::Finish
+            }
+        } else if self.inter_byte_us > 0 {
+            Step::Wait(DelayState::InterByte, self.inter_byte_us)
+        } else {
+            Step::Transfer
+        }
+    }
+
+    /// The step after waiting for the delay of `state`.
+    fn after_wait(&self, state: DelayState) -> Step {
+        match state {
+            DelayState::Hold => Step::Finish,
+            _ => Step::Transfer,
+        }
+    }
+
+    /// The length of the next transfer with `remaining` bytes left and
+    /// kernel buffers of `buffer_len` bytes.
+    fn transfer_len(&self, remaining: usize, buffer_len: usize) -> usize {
+        let len = cmp::min(remaining, buffer_len);
+        if self.inter_byte_us > 0 {
+            cmp::min(len, 1)
+        } else {
+            len
+        }
+    }
+}
+
 // SPI operations are handled by coping into a kernel buffer for
 // writes and copying out of a kernel buffer for reads.
 //
@@ -36,27 +136,64 @@ pub struct App {
     index: usize,
 }
 
-pub struct Spi<'a, S: SpiMasterDevice> {
+pub struct Spi<'a, S: SpiMasterDevice, A: Alarm<'a>> {
     spi_master: &'a S,
+    alarm: &'a A,
     busy: Cell<bool>,
     kernel_read: TakeCell<'static, [u8]>,
     kernel_write: TakeCell<'static, [u8]>,
     kernel_len: Cell<usize>,
     grants: Grant<App>,
     current_process: OptionalCell<ProcessId>,
+    delays: Cell<Delays>,
+    delay_state: Cell<DelayState>,
 }
 
-impl<'a, S: SpiMasterDevice> Spi<'a, S> {
-    pub fn new(spi_master: &'a S, grants: Grant<App>) -> Spi<'a, S> {
+impl<'a, S: SpiMasterDevice, A: Alarm<'a>> Spi<'a, S, A> {
+    pub fn new(spi_master: &'a S, alarm: &'a A, grants: Grant<App>) -> Spi<'a, S, A> {
         Spi {
             spi_master: spi_master,
+            alarm: alarm,
             busy: Cell::new(false),
             kernel_len: Cell::new(0),
             kernel_read: TakeCell::empty(),
             kernel_write: TakeCell::empty(),
             grants,
             current_process: OptionalCell::empty(),
+            delays: Cell::new(Delays::default()),
+            delay_state: Cell::new(DelayState::Idle),
+        }
+    }
+
+    fn step(&self, step: Step, app: &mut App) {
+        match step {
+            Step::Transfer => self.do_next_read_write(app),
+            Step::Wait(state, us) => {
+                self.delay_state.set(state);
+                self.alarm.set_alarm(self.alarm.now(), A::ticks_from_us(us));
+            }
+            Step::Finish => self.finish_operation(app),
+        }
+    }
+
+    /// Start an operation whose length and index have been set up in `app`.
+    fn start_operation(&self, app: &mut App) {
+        let delays = self.delays.get();
+        if delays.hold_chip_select() {
+            self.spi_master.hold_low();
+        }
+        self.step(delays.first_step(), app);
+    }
+
+    fn finish_operation(&self, app: &mut App) {
+        if self.delays.get().hold_chip_select() {
+            self.spi_master.release_low();
         }
+        self.busy.set(false);
+        let len = app.len;
+        app.len = 0;
+        app.index = 0;
+        app.callback.schedule(len, 0, 0);
     }
 
     pub fn config_buffers(&mut self, read: &'static mut [u8], write: &'static mut [u8]) {
@@ -72,7 +209,10 @@ impl<'a, S: SpiMasterDevice> Spi<'a, S> {
         let write_len = self.kernel_write.map_or(0, |kwbuf| {
             let mut start = app.index;
             let tmp_len = app.app_write.map_or(0, |src| {
-                let len = cmp::min(app.len - start, self.kernel_len.get());
+                let len = self
+                    .delays
+                    .get()
+                    .transfer_len(app.len - start, self.kernel_len.get());
                 let end = cmp::min(start + len, src.len());
                 start = cmp::min(start, end);
 
@@ -92,7 +232,7 @@ impl<'a, S: SpiMasterDevice> Spi<'a, S> {
     }
 }
 
-impl<'a, S: SpiMasterDevice> Driver for Spi<'a, S> {
+impl<'a, S: SpiMasterDevice, A: Alarm<'a>> Driver for Spi<'a, S, A> {
     fn allow_readwrite(
         &self,
         process_id: ProcessId,
@@ -187,6 +327,16 @@ impl<'a, S: SpiMasterDevice> Driver for Spi<'a, S> {
     // 10: get clock polarity on current peripheral
     //   - 0 is idle low
     //   - non-zero is idle high
+    // 11: set inter-byte delay
+    //   - parameter in microseconds, at most MAX_DELAY_US
+    //   - 0 disables the delay
+    // 12: get inter-byte delay
+    //   - value in microseconds
+    // 13: set chip-select setup/hold delay
+    //   - parameter in microseconds, at most MAX_DELAY_US
+    //   - 0 disables the delay
+    // 14: get chip-select setup/hold delay
+    //   - value in microseconds
     //
     // x: lock spi
     //   - if you perform an operation without the lock,
@@ -242,7 +392,7 @@ impl<'a, S: SpiMasterDevice> Driver for Spi<'a, S> {
                         app.len = arg1;
                         app.index = 0;
                         self.busy.set(true);
-                        self.do_next_read_write(app);
+                        self.start_operation(app);
                         CommandReturn::success()
                     } else {
                         /* write buffer too small, or zero length write */
@@ -288,12 +438,42 @@ impl<'a, S: SpiMasterDevice> Driver for Spi<'a, S> {
             10 /* get polarity */ => {
                 CommandReturn::success_u32(self.spi_master.get_polarity() as u32)
             }
+            11 /* set inter-byte delay */ => {
+                if arg1 > MAX_DELAY_US {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else if self.busy.get() {
+                    CommandReturn::failure(ErrorCode::BUSY)
+                } else {
+                    let mut delays = self.delays.get();
+                    delays.inter_byte_us = arg1 as u32;
+                    self.delays.set(delays);
+                    CommandReturn::success()
+                }
+            }
+            12 /* get inter-byte delay */ => {
+                CommandReturn::success_u32(self.delays.get().inter_byte_us)
+            }
+            13 /* set chip-select setup/hold delay */ => {
+                if arg1 > MAX_DELAY_US {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else if self.busy.get() {
+                    CommandReturn::failure(ErrorCode::BUSY)
+                } else {
+                    let mut delays = self.delays.get();
+                    delays.cs_us = arg1 as u32;
+                    self.delays.set(delays);
+                    CommandReturn::success()
+                }
+            }
+            14 /* get chip-select setup/hold delay */ => {
+                CommandReturn::success_u32(self.delays.get().cs_us)
+            }
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT)
         }
     }
 }
 
-impl<S: SpiMasterDevice> SpiMasterClient for Spi<'_, S> {
+impl<'a, S: SpiMasterDevice, A: Alarm<'a>> SpiMasterClient for Spi<'a, S, A> {
     fn read_write_done(
         &self,
         writebuf: &'static mut [u8],
@@ -334,16 +514,270 @@ impl<S: SpiMasterDevice> SpiMasterClient for Spi<'_, S> {
                 self.kernel_read.put(rbuf);
                 self.kernel_write.replace(writebuf);
 
-                if app.index == app.len {
-                    self.busy.set(false);
-                    let len = app.len;
-                    app.len = 0;
-                    app.index = 0;
-                    app.callback.schedule(len, 0, 0);
-                } else {
-                    self.do_next_read_write(app);
+                let step = self.delays.get().next_step(app.len - app.index);
+                self.step(step, app);
+            });
+        });
+    }
+}
+
+impl<'a, S: SpiMasterDevice, A: Alarm<'a>> time::AlarmClient for Spi<'a, S, A> {
+    fn alarm(&self) {
+        let state = self.delay_state.replace(DelayState::Idle);
+        self.current_process.map(|process_id| {
+            let _ = self.grants.enter(*process_id, |app| {
+                if state != DelayState::Idle {
+                    self.step(self.delays.get().after_wait(state), app);
                 }
             });
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{DelayState, Delays, Spi, Step, DRIVER_NUM};
+    use crate::test::mock_alarm::MockAlarm;
+    use crate::test::mock_process::{self, MockProcess};
+    use core::cell::{Cell, RefCell};
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::spi::{ClockPhase, ClockPolarity, SpiMasterClient, SpiMasterDevice};
+    use kernel::hil::time::{Alarm, Ticks, Time};
+    use kernel::{Driver, ErrorCode};
+
+    /// Time, in microseconds, the mock bus takes to clock out a byte.
+    const BYTE_US: u32 = 8;
+
+    /// What the mock bus records about the chip select and the bytes.
+    #[derive(Default)]
+    struct BusLog {
+        held: bool,
+        selected: bool,
+        selected_at: [u32; 8],
+        selections: usize,
+        deselected_at: u32,
+        byte_at: [u32; 8],
+        bytes: usize,
+        transfers: usize,
+    }
+
+    /// A bus that clocks bytes out in mock alarm time, and records when the
+    /// chip select changes and when each byte is clocked out.
+    struct MockSpi {
+        alarm: &'static MockAlarm<'static>,
+        log: RefCell<BusLog>,
+        write: TakeCell<'static, [u8]>,
+        read: TakeCell<'static, [u8]>,
+        len: Cell<usize>,
+    }
+
+    impl MockSpi {
+        fn new(alarm: &'static MockAlarm<'static>) -> MockSpi {
+            MockSpi {
+                alarm: alarm,
+                log: RefCell::new(BusLog::default()),
+                write: TakeCell::empty(),
+                read: TakeCell::empty(),
+                len: Cell::new(0),
+            }
+        }
+
+        fn select(&self) {
+            let mut log = self.log.borrow_mut();
+            if !log.selected {
+                log.selected = true;
+                let n = log.selections;
+                log.selected_at[n] = self.alarm.now().into_u32();
+                log.selections += 1;
+            }
+        }
+
+        fn deselect(&self) {
+            let mut log = self.log.borrow_mut();
+            if log.selected {
+                log.selected = false;
+                log.deselected_at = self.alarm.now().into_u32();
+            }
+        }
+
+        /// Clock out the pending transfer and report it to `client`.
+        /// Returns whether a transfer was pending.
+        fn complete(&self, client: &dyn SpiMasterClient) -> bool {
+            let write = match self.write.take() {
+                Some(write) => write,
+                None => return false,
+            };
+            self.select();
+            for _ in 0..self.len.get() {
+                {
+                    let mut log = self.log.borrow_mut();
+                    let n = log.bytes;
+                    log.byte_at[n] = self.alarm.now().into_u32();
+                    log.bytes += 1;
+                }
+                self.alarm.advance(BYTE_US);
+            }
+            if !self.log.borrow().held {
+                self.deselect();
+            }
+            client.read_write_done(write, self.read.take(), self.len.get());
+            true
+        }
+    }
+
+    impl SpiMasterDevice for MockSpi {
+        fn configure(&self, _cpol: ClockPolarity, _cpal: ClockPhase, _rate: u32) {}
+
+        fn read_write_bytes(
+            &self,
+            write_buffer: &'static mut [u8],
+            read_buffer: Option<&'static mut [u8]>,
+            len: usize,
+        ) -> Result<(), ErrorCode> {
+            self.log.borrow_mut().transfers += 1;
+            self.write.replace(write_buffer);
+            self.read.put(read_buffer);
+            self.len.set(len);
+            Ok(())
+        }
+
+        fn set_polarity(&self, _cpol: ClockPolarity) {}
+        fn set_phase(&self, _cpal: ClockPhase) {}
+        fn set_rate(&self, _rate: u32) {}
+
+        fn get_polarity(&self) -> ClockPolarity {
+            ClockPolarity::IdleLow
+        }
+        fn get_phase(&self) -> ClockPhase {
+            ClockPhase::SampleLeading
+        }
+        fn get_rate(&self) -> u32 {
+            0
+        }
+
+        fn hold_low(&self) {
+            self.log.borrow_mut().held = true;
+            self.select();
+        }
+
+        fn release_low(&self) {
+            self.log.borrow_mut().held = false;
+            self.deselect();
+        }
+    }
+
+    type TestSpi = Spi<'static, MockSpi, MockAlarm<'static>>;
+
+    /// Run an operation of `len` bytes with the given delays through a
+    /// driver whose kernel buffers are `buffer_len` bytes long, and return
+    /// what the bus saw.
+    fn run(delays: Delays, len: usize, buffer_len: usize) -> BusLog {
+        let (kernel, processes) = mock_process::kernel(&["app"]);
+        let app: &MockProcess = processes[0];
+        let id = app.processid();
+        let alarm = mock_process::leak(MockAlarm::new());
+        let mock = mock_process::leak(MockSpi::new(alarm));
+        let mut spi: TestSpi = Spi::new(mock, alarm, mock_process::grant(kernel));
+        spi.config_buffers(
+            mock_process::buffer(buffer_len),
+            mock_process::buffer(buffer_len),
+        );
+        let spi = mock_process::leak(spi);
+        alarm.set_alarm_client(spi);
+
+        assert!(spi.subscribe(0, app.upcall(DRIVER_NUM, 0), id).is_ok());
+        assert!(spi
+            .allow_readonly(id, 0, app.readonly_slice(&[0x5a; 8][..len]))
+            .is_ok());
+        assert!(spi
+            .command(11, delays.inter_byte_us as usize, 0, id)
+            .is_success());
+        assert!(spi.command(13, delays.cs_us as usize, 0, id).is_success());
+        assert!(spi.command(2, len, 0, id).is_success());
+
+        // Complete transfers and fire the alarm until the operation is over.
+        while mock.complete(spi) || alarm.fire().is_some() {}
+
+        assert_eq!(app.take_upcalls(), [(0, len, 0, 0)]);
+        let log = mock.log.replace(BusLog::default());
+        assert!(!log.held && !log.selected);
+        assert_eq!(log.bytes, len);
+        log
+    }
+
+    #[test]
+    fn test_no_delays() {
+        let delays = Delays::default();
+        assert!(!delays.hold_chip_select());
+        assert_eq!(delays.first_step(), Step::Transfer);
+
+        let bus = run(delays, 4, 16);
+        assert_eq!(bus.transfers, 1);
+        assert_eq!(bus.selections, 1);
+        assert_eq!(bus.byte_at[..4], [0, 8, 16, 24]);
+        assert_eq!(bus.deselected_at, 32);
+    }
+
+    #[test]
+    fn test_inter_byte_delay() {
+        let delays = Delays {
+            inter_byte_us: 100,
+            cs_us: 0,
+        };
+        let bus = run(delays, 4, 16);
+        assert_eq!(bus.transfers, 4);
+        // The chip select stays low between bytes.
+        assert_eq!(bus.selections, 1);
+        for i in 1..4 {
+            assert_eq!(bus.byte_at[i] - bus.byte_at[i - 1], BYTE_US + 100);
+        }
+        assert_eq!(bus.deselected_at, bus.byte_at[3] + BYTE_US);
+    }
+
+    #[test]
+    fn test_chip_select_delay() {
+        let delays = Delays {
+            inter_byte_us: 0,
+            cs_us: 50,
+        };
+        let bus = run(delays, 4, 16);
+        assert_eq!(bus.transfers, 1);
+        assert_eq!(bus.selections, 1);
+        assert_eq!(bus.byte_at[0] - bus.selected_at[0], 50);
+        assert_eq!(bus.deselected_at - (bus.byte_at[3] + BYTE_US), 50);
+    }
+
+    #[test]
+    fn test_both_delays() {
+        let delays = Delays {
+            inter_byte_us: 20,
+            cs_us: 50,
+        };
+        assert_eq!(delays.first_step(), Step::Wait(DelayState::Setup, 50));
+        assert_eq!(delays.next_step(1), Step::Wait(DelayState::InterByte, 20));
+        assert_eq!(delays.next_step(0), Step::Wait(DelayState::Hold, 50));
+
+        let bus = run(delays, 3, 16);
+        assert_eq!(bus.selections, 1);
+        assert_eq!(bus.byte_at[..3], [50, 78, 106]);
+        assert_eq!(bus.deselected_at, 164);
+    }
+
+    #[test]
+    fn test_split_across_buffers() {
+        // Without delays, an operation longer than the kernel buffers is
+        // sent as several transfers, each selecting the device.
+        let bus = run(Delays::default(), 5, 2);
+        assert_eq!(bus.transfers, 3);
+        assert_eq!(bus.selections, 3);
+
+        // With a chip-select delay, it stays selected across them.
+        let delays = Delays {
+            inter_byte_us: 0,
+            cs_us: 10,
+        };
+        let bus = run(delays, 5, 2);
+        assert_eq!(bus.transfers, 3);
+        assert_eq!(bus.selections, 1);
+    }
+}
@@ -66,26 +66,28 @@ impl<'a, S: SpiSlaveDevice> SpiPeripheral<'a, S> {
     // Assumes checks for busy/etc. already done
     // Updates app.index to be index + length of op
     fn do_next_read_write(&self, app: &mut PeripheralApp) {
-        let write_len = self.kernel_write.map_or(0, |kwbuf| {
-            let mut start = app.index;
-            let tmp_len = app.app_write.map_or(0, |src| {
-                let len = cmp::min(app.len - start, self.kernel_len.get());
+        let start = app.index;
+        let len = cmp::min(app.len - start, self.kernel_len.get());
+        self.kernel_write.map(|kwbuf| {
+            let write_len = app.app_write.map_or(0, |src| {
                 let end = cmp::min(start + len, src.len());
-                start = cmp::min(start, end);
+                let start = cmp::min(start, end);
 
                 for (i, c) in src.as_ref()[start..end].iter().enumerate() {
                     kwbuf[i] = *c;
                 }
                 end - start
             });
-            app.index = start + tmp_len;
-            tmp_len
+            // Without (enough of) a write buffer, respond to the master with
+            // zeros rather than stale data.
+            for c in kwbuf[write_len..len].iter_mut() {
+                *c = 0;
+            }
         });
-        let _ = self.spi_slave.read_write_bytes(
-            self.kernel_write.take(),
-            self.kernel_read.take(),
-            write_len,
-        );
+        app.index = start + len;
+        let _ =
+            self.spi_slave
+                .read_write_bytes(self.kernel_write.take(), self.kernel_read.take(), len);
     }
 }
 
@@ -185,9 +187,10 @@ impl<S: SpiSlaveDevice> Driver for SpiPeripheral<'_, S> {
 
     /// - 0: check if present
     /// - 1: read/write buffers
-    ///   - read and write buffers optional
-    ///   - fails if arg1 (bytes to write) >
-    ///     write_buffer.len()
+    ///   - read and write buffers optional, but at least one is required
+    ///   - without a write buffer, zeros are sent to the master
+    ///   - fails if arg1 (bytes to transfer) is larger than
+    ///     either buffer
     /// - 2: get chip select
     ///   - returns current selected peripheral
     ///   - in slave mode, always returns 0
@@ -243,9 +246,14 @@ impl<S: SpiSlaveDevice> Driver for SpiPeripheral<'_, S> {
                     return CommandReturn::failure(ErrorCode::BUSY);
                 }
                 self.grants.enter(process_id, |app| {
-                    let mut mlen = app.app_write.map_or(0, |w| w.len());
-                    let rlen = app.app_read.map_or(mlen, |r| r.len());
-                    mlen = cmp::min(mlen, rlen);
+                    // Either buffer may be omitted, but not both.
+                    let wlen = app.app_write.map_or(None, |w| Some(w.len()));
+                    let rlen = app.app_read.map_or(None, |r| Some(r.len()));
+                    let mlen = match (wlen, rlen) {
+                        (Some(wlen), Some(rlen)) => cmp::min(wlen, rlen),
+                        (Some(len), None) | (None, Some(len)) => len,
+                        (None, None) => 0,
+                    };
                     if mlen >= arg1 && arg1 > 0 {
                         app.len = arg1;
                         app.index = 0;
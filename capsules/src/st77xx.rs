@@ -33,13 +33,27 @@
 //!     ),
 //! );
 //! ```
+//!
+//! Vertical Blank
+//! --------------
+//!
+//! Panels with a tearing effect (TE) output, like the ST7789, signal their
+//! vertical blanking interval on it. If it is wired to an interrupt-capable
+//! GPIO, the driver implements `hil::screen::ScreenVsync` with it. The pin
+//! must be set before `init()`, which turns on the TE output of the panel.
+//!
+//! ```rust
+//! tft.set_tearing_effect_pin(&nrf52840::gpio::PORT[GPIO_D4]);
+//! nrf52840::gpio::PORT[GPIO_D4].set_client(tft);
+//! tft.init();
+//! ```
 
 use crate::bus::{self, Bus, BusWidth};
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
-use kernel::hil::gpio::Pin;
+use kernel::hil::gpio::{self, Pin};
 use kernel::hil::screen::{
-    self, ScreenClient, ScreenPixelFormat, ScreenRotation, ScreenSetupClient,
+    self, ScreenClient, ScreenPixelFormat, ScreenRotation, ScreenSetupClient, ScreenVsyncClient,
 };
 use kernel::hil::time::{self, Alarm};
 use kernel::ErrorCode;
@@ -159,6 +173,13 @@ const COLMOD: Command = Command {
     delay: 0,
 };
 
+/// Turn on the tearing effect output, for the vertical blank only.
+const TEON: Command = Command {
+    id: 0x35,
+    parameters: Some(&[0x00]),
+    delay: 0,
+};
+
 const MADCTL: Command = Command {
     id: 0x36,
     /// Default Parameters:
@@ -230,6 +251,10 @@ pub struct ST77XX<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> {
 
     current_rotation: Cell<ScreenRotation>,
 
+    tearing_effect_pin: OptionalCell<&'a dyn gpio::InterruptPin<'a>>,
+    tearing_effect_on: Cell<bool>,
+    vsync_client: OptionalCell<&'static dyn ScreenVsyncClient>,
+
     screen: &'static ST77XXScreen,
 }
 
@@ -274,10 +299,22 @@ impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> ST77XX<'a, A, B, P> {
 
             current_rotation: Cell::new(ScreenRotation::Normal),
 
+            tearing_effect_pin: OptionalCell::empty(),
+            tearing_effect_on: Cell::new(false),
+            vsync_client: OptionalCell::empty(),
+
             screen: screen,
         }
     }
 
+    /// Use `pin` as the tearing effect output of the panel. This must be
+    /// called before `init()`, and the driver must be set as the pin's
+    /// client.
+    pub fn set_tearing_effect_pin(&self, pin: &'a dyn gpio::InterruptPin<'a>) {
+        pin.make_input();
+        self.tearing_effect_pin.set(pin);
+    }
+
     fn send_sequence(&self, sequence: CommandSequence) -> Result<(), ErrorCode> {
         if self.status.get() == Status::Idle {
             let error = self.sequence_buffer.map_or_else(
@@ -519,7 +556,15 @@ impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> ST77XX<'a, A, B, P> {
                     );
                 } else {
                     self.status.set(Status::Idle);
-                    if !self.power_on.get() {
+                    if !self.power_on.get()
+                        && self.tearing_effect_pin.is_some()
+                        && !self.tearing_effect_on.get()
+                    {
+                        // The panel is initialized, the TE output is the
+                        // last thing to turn on.
+                        self.tearing_effect_on.set(true);
+                        self.send_command_with_default_parameters(&TEON);
+                    } else if !self.power_on.get() {
                         self.client.map(|client| {
                             self.power_on.set(true);
 
@@ -652,6 +697,7 @@ impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> ST77XX<'a, A, B, P> {
 
     pub fn init(&self) -> Result<(), ErrorCode> {
         if self.status.get() == Status::Idle {
+            self.tearing_effect_on.set(false);
             self.status.set(Status::Reset1);
             self.do_next_op();
             Ok(())
@@ -849,6 +895,39 @@ impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> screen::Screen for ST77XX<'a, A, B, P
     }
 }
 
+impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> screen::ScreenVsync for ST77XX<'a, A, B, P> {
+    fn set_vsync_client(&self, client: Option<&'static dyn ScreenVsyncClient>) {
+        if let Some(client) = client {
+            self.vsync_client.set(client);
+        } else {
+            self.vsync_client.clear();
+        }
+    }
+
+    fn enable_vsync(&self) -> Result<(), ErrorCode> {
+        self.tearing_effect_pin
+            .map_or(Err(ErrorCode::NOSUPPORT), |pin| {
+                pin.enable_interrupts(gpio::InterruptEdge::RisingEdge);
+                Ok(())
+            })
+    }
+
+    fn disable_vsync(&self) -> Result<(), ErrorCode> {
+        self.tearing_effect_pin
+            .map_or(Err(ErrorCode::NOSUPPORT), |pin| {
+                pin.disable_interrupts();
+                Ok(())
+            })
+    }
+}
+
+impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> gpio::Client for ST77XX<'a, A, B, P> {
+    /// The tearing effect output went high: the panel is in vertical blank.
+    fn fired(&self) {
+        self.vsync_client.map(|client| client.vsync());
+    }
+}
+
 impl<'a, A: Alarm<'a>, B: Bus<'a>, P: Pin> time::AlarmClient for ST77XX<'a, A, B, P> {
     fn alarm(&self) {
         self.do_next_op();
@@ -0,0 +1,599 @@
+//! Driver for the Semtech SX1261/62 LoRa transceivers.
+//!
+//! <https://www.semtech.com/products/wireless-rf/lora-core/sx1262>
+//!
+//! Unlike the SX127x, the radio is driven with commands rather than
+//! register accesses. It raises its BUSY pin while it processes a command,
+//! and ignores commands sent in the meantime, so the driver waits for BUSY
+//! to fall before each SPI transfer. This takes at most a few hundred
+//! microseconds, when the radio wakes up from sleep. The end of a
+//! transmission or reception, and the timeout of a reception, are signaled
+//! on the DIO1 pin, which must be connected to an interrupt-capable GPIO pin.
+//!
+//! The radio sleeps between operations. Each transmission or reception
+//! wakes it up, sends every command that depends on the requested
+//! modulation, and starts the operation. Once the radio signals the end of
+//! the operation, the driver reads the interrupt status and any received
+//! packet, puts the radio back to sleep and then notifies the client.
+//!
+//! Many SX1262 modules drive their RF switch with the DIO2 pin of the radio;
+//! this is enabled with the `dio2_rf_switch` argument of the constructor.
+//! Transmissions use the high power amplifier of the SX1262, so the transmit
+//! power ranges from -9 to 22 dBm.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sx1262_spi = static_init!(
+//!     capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>,
+//!     capsules::virtual_spi::VirtualSpiMasterDevice::new(mux_spi, &nrf52840::gpio::PORT[CS]));
+//! let sx1262 = static_init!(
+//!     capsules::sx126x::SX126x<'static,
+//!         capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>>,
+//!     capsules::sx126x::SX126x::new(
+//!         sx1262_spi,
+//!         &nrf52840::gpio::PORT[BUSY],
+//!         &nrf52840::gpio::PORT[DIO1],
+//!         true,
+//!         &mut capsules::sx126x::SPI_BUF,
+//!         &mut capsules::sx126x::SPI_READ_BUF,
+//!     ));
+//! sx1262_spi.set_client(sx1262);
+//! nrf52840::gpio::PORT[DIO1].set_client(sx1262);
+//! sx1262.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::gpio;
+use kernel::hil::lora::{self, Bandwidth, Modulation};
+use kernel::hil::spi;
+use kernel::ErrorCode;
+
+/// Number of bytes sent before the data when reading the buffer of the
+/// radio: the opcode, the offset and a status byte.
+const READ_BUFFER_HDR_LEN: usize = 3;
+
+/// Length of the SPI buffers: a buffer read command followed by a whole
+/// packet.
+pub const SPI_BUF_LEN: usize = READ_BUFFER_HDR_LEN + lora::MAX_PAYLOAD_LEN;
+
+pub static mut SPI_BUF: [u8; SPI_BUF_LEN] = [0; SPI_BUF_LEN];
+pub static mut SPI_READ_BUF: [u8; SPI_BUF_LEN] = [0; SPI_BUF_LEN];
+
+const SPI_SPEED: u32 = 8_000_000;
+
+/// Frequency of the crystal oscillator, in Hz.
+const FXOSC: u64 = 32_000_000;
+
+/// Number of symbols of the preamble.
+const PREAMBLE_LEN: u16 = 8;
+
+#[allow(dead_code)]
+mod opcode {
+    pub const GET_STATUS: u8 = 0xc0;
+    pub const SET_SLEEP: u8 = 0x84;
+    pub const SET_STANDBY: u8 = 0x80;
+    pub const SET_TX: u8 = 0x83;
+    pub const SET_RX: u8 = 0x82;
+    pub const CALIBRATE_IMAGE: u8 = 0x98;
+    pub const SET_PA_CONFIG: u8 = 0x95;
+    pub const SET_DIO_IRQ_PARAMS: u8 = 0x08;
+    pub const GET_IRQ_STATUS: u8 = 0x12;
+    pub const CLEAR_IRQ_STATUS: u8 = 0x02;
+    pub const SET_DIO2_AS_RF_SWITCH_CTRL: u8 = 0x9d;
+    pub const SET_RF_FREQUENCY: u8 = 0x86;
+    pub const SET_PACKET_TYPE: u8 = 0x8a;
+    pub const SET_TX_PARAMS: u8 = 0x8e;
+    pub const SET_MODULATION_PARAMS: u8 = 0x8b;
+    pub const SET_PACKET_PARAMS: u8 = 0x8c;
+    pub const SET_BUFFER_BASE_ADDRESS: u8 = 0x8f;
+    pub const GET_RX_BUFFER_STATUS: u8 = 0x13;
+    pub const GET_PACKET_STATUS: u8 = 0x14;
+    pub const WRITE_REGISTER: u8 = 0x0d;
+    pub const WRITE_BUFFER: u8 = 0x0e;
+    pub const READ_BUFFER: u8 = 0x1e;
+}
+
+mod register {
+    pub const IQ_POLARITY_SETUP: u16 = 0x0736;
+    pub const LORA_SYNC_WORD: u16 = 0x0740;
+}
+
+#[allow(dead_code)]
+mod irq {
+    pub const TX_DONE: u16 = 0x0001;
+    pub const RX_DONE: u16 = 0x0002;
+    pub const HEADER_ERR: u16 = 0x0020;
+    pub const CRC_ERR: u16 = 0x0040;
+    pub const TIMEOUT: u16 = 0x0200;
+    pub const ALL: u16 = 0x03ff;
+}
+
+const PACKET_TYPE_LORA: u8 = 0x01;
+const STANDBY_RC: u8 = 0x00;
+/// Sleep with the configuration retained, which makes waking up faster.
+const SLEEP_WARM_START: u8 = 0x04;
+const RAMP_200_US: u8 = 0x04;
+
+/// Reset value of the IQ polarity register. Bit 2 must be cleared when the
+/// IQ are inverted, and set otherwise (datasheet, section 15.4).
+const IQ_POLARITY_DEFAULT: u8 = 0x0d;
+
+/// Number of commands sent before starting an operation.
+const SETUP_LEN: usize = 15;
+
+/// Index of the image calibration in the setup sequence.
+const CALIBRATE_STEP: usize = 3;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Idle,
+    /// Sending the command with the given index in the setup sequence.
+    Setup(usize),
+    WriteBuffer,
+    Transmitting,
+    Receiving,
+    GetIrqStatus,
+    ClearIrq,
+    GetRxBufferStatus,
+    GetPacketStatus,
+    ReadBuffer,
+    Sleep,
+}
+
+pub struct SX126x<'a, S: spi::SpiMasterDevice> {
+    spi: &'a S,
+    busy: &'a dyn gpio::Pin,
+    dio1: &'a dyn gpio::InterruptPin<'a>,
+    dio2_rf_switch: bool,
+    state: Cell<State>,
+    transmitting: Cell<bool>,
+    modulation: OptionalCell<Modulation>,
+    power: Cell<i8>,
+    /// Reception timeout, in steps of 15.625 µs.
+    timeout: Cell<u32>,
+    /// Frequency band the image rejection was last calibrated for.
+    calibrated_band: OptionalCell<(u8, u8)>,
+    /// Length of the packet being sent or received.
+    len: Cell<usize>,
+    rx_addr: Cell<u8>,
+    rssi: Cell<i16>,
+    snr: Cell<i8>,
+    result: Cell<Result<(), ErrorCode>>,
+    buf: TakeCell<'static, [u8]>,
+    spi_buf: TakeCell<'static, [u8]>,
+    spi_read_buf: TakeCell<'static, [u8]>,
+    tx_client: OptionalCell<&'a dyn lora::TransmitClient>,
+    rx_client: OptionalCell<&'a dyn lora::ReceiveClient>,
+}
+
+/// The frequencies bounding the image calibration for the band that
+/// contains `frequency`, in steps of 4 MHz.
+fn calibration_band(frequency: u32) -> (u8, u8) {
+    match frequency {
+        0..=440_000_000 => (0x6b, 0x6f),
+        440_000_001..=510_000_000 => (0x75, 0x81),
+        510_000_001..=787_000_000 => (0xc1, 0xc5),
+        787_000_001..=870_000_000 => (0xd7, 0xdb),
+        _ => (0xe1, 0xe9),
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> SX126x<'a, S> {
+    pub fn new(
+        spi: &'a S,
+        busy: &'a dyn gpio::Pin,
+        dio1: &'a dyn gpio::InterruptPin<'a>,
+        dio2_rf_switch: bool,
+        spi_buf: &'static mut [u8],
+        spi_read_buf: &'static mut [u8],
+    ) -> SX126x<'a, S> {
+        SX126x {
+            spi: spi,
+            busy: busy,
+            dio1: dio1,
+            dio2_rf_switch: dio2_rf_switch,
+            state: Cell::new(State::Idle),
+            transmitting: Cell::new(false),
+            modulation: OptionalCell::empty(),
+            power: Cell::new(0),
+            timeout: Cell::new(0),
+            calibrated_band: OptionalCell::empty(),
+            len: Cell::new(0),
+            rx_addr: Cell::new(0),
+            rssi: Cell::new(0),
+            snr: Cell::new(0),
+            result: Cell::new(Ok(())),
+            buf: TakeCell::empty(),
+            spi_buf: TakeCell::new(spi_buf),
+            spi_read_buf: TakeCell::new(spi_read_buf),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Configure the SPI bus and the BUSY and DIO1 pins.
+    pub fn initialize(&self) {
+        self.spi.configure(
+            spi::ClockPolarity::IdleLow,
+            spi::ClockPhase::SampleLeading,
+            SPI_SPEED,
+        );
+        self.busy.make_input();
+        self.dio1.make_input();
+        self.dio1.enable_interrupts(gpio::InterruptEdge::RisingEdge);
+    }
+
+    /// Write the command with index `step` of the setup sequence of the
+    /// pending operation into `cmd`, and return its length.
+    fn setup_command(&self, m: &Modulation, step: usize, cmd: &mut [u8]) -> usize {
+        let transmitting = self.transmitting.get();
+        let mut put = |command: &[u8]| {
+            cmd[..command.len()].copy_from_slice(command);
+            command.len()
+        };
+        match step {
+            // Any command wakes the radio up, but is then ignored.
+            0 => put(&[opcode::GET_STATUS, 0]),
+            1 => put(&[opcode::SET_STANDBY, STANDBY_RC]),
+            2 => put(&[
+                opcode::SET_DIO2_AS_RF_SWITCH_CTRL,
+                self.dio2_rf_switch as u8,
+            ]),
+            CALIBRATE_STEP => {
+                let band = calibration_band(m.frequency);
+                if self.calibrated_band.contains(&band) {
+                    put(&[opcode::GET_STATUS, 0])
+                } else {
+                    self.calibrated_band.set(band);
+                    put(&[opcode::CALIBRATE_IMAGE, band.0, band.1])
+                }
+            }
+            4 => put(&[opcode::SET_PACKET_TYPE, PACKET_TYPE_LORA]),
+            5 => {
+                let frf = ((((m.frequency as u64) << 25) / FXOSC) as u32).to_be_bytes();
+                put(&[opcode::SET_RF_FREQUENCY, frf[0], frf[1], frf[2], frf[3]])
+            }
+            // Duty cycle and size of the power amplifier for +22 dBm.
+            6 => put(&[opcode::SET_PA_CONFIG, 0x04, 0x07, 0x00, 0x01]),
+            7 => put(&[opcode::SET_TX_PARAMS, self.power.get() as u8, RAMP_200_US]),
+            8 => {
+                let bandwidth = match m.bandwidth {
+                    Bandwidth::Bw125kHz => 0x04,
+                    Bandwidth::Bw250kHz => 0x05,
+                    Bandwidth::Bw500kHz => 0x06,
+                };
+                put(&[
+                    opcode::SET_MODULATION_PARAMS,
+                    m.spreading_factor,
+                    bandwidth,
+                    m.coding_rate as u8,
+                    m.low_data_rate() as u8,
+                ])
+            }
+            9 => put(&[
+                opcode::SET_PACKET_PARAMS,
+                (PREAMBLE_LEN >> 8) as u8,
+                PREAMBLE_LEN as u8,
+                // Explicit header.
+                0x00,
+                self.len.get() as u8,
+                transmitting as u8,
+                m.invert_iq as u8,
+            ]),
+            10 => {
+                let sync_word: u16 = if m.public_network { 0x3444 } else { 0x1424 };
+                put(&[
+                    opcode::WRITE_REGISTER,
+                    (register::LORA_SYNC_WORD >> 8) as u8,
+                    register::LORA_SYNC_WORD as u8,
+                    (sync_word >> 8) as u8,
+                    sync_word as u8,
+                ])
+            }
+            11 => {
+                let polarity = if m.invert_iq {
+                    IQ_POLARITY_DEFAULT & !0x04
+                } else {
+                    IQ_POLARITY_DEFAULT | 0x04
+                };
+                put(&[
+                    opcode::WRITE_REGISTER,
+                    (register::IQ_POLARITY_SETUP >> 8) as u8,
+                    register::IQ_POLARITY_SETUP as u8,
+                    polarity,
+                ])
+            }
+            12 => put(&[opcode::SET_BUFFER_BASE_ADDRESS, 0, 0]),
+            13 => {
+                let mask = if transmitting {
+                    irq::TX_DONE | irq::TIMEOUT
+                } else {
+                    irq::RX_DONE | irq::HEADER_ERR | irq::CRC_ERR | irq::TIMEOUT
+                };
+                // Signal the interrupts on DIO1 only.
+                put(&[
+                    opcode::SET_DIO_IRQ_PARAMS,
+                    (mask >> 8) as u8,
+                    mask as u8,
+                    (mask >> 8) as u8,
+                    mask as u8,
+                    0,
+                    0,
+                    0,
+                    0,
+                ])
+            }
+            _ => put(&[
+                opcode::CLEAR_IRQ_STATUS,
+                (irq::ALL >> 8) as u8,
+                irq::ALL as u8,
+            ]),
+        }
+    }
+
+    fn setup_step(&self, step: usize) {
+        self.state.set(State::Setup(step));
+        self.spi_buf.take().map(|wbuf| {
+            let len = self
+                .modulation
+                .map_or(0, |m| self.setup_command(m, step, wbuf));
+            // The first command wakes the radio up, which holds BUSY high
+            // while asleep.
+            self.send(wbuf, len, step != 0);
+        });
+    }
+
+    fn start(&self) {
+        self.setup_step(0);
+    }
+
+    fn send(&self, wbuf: &'static mut [u8], len: usize, wait_busy: bool) {
+        if wait_busy {
+            while self.busy.read() {}
+        }
+        let _ = self
+            .spi
+            .read_write_bytes(wbuf, self.spi_read_buf.take(), len);
+    }
+
+    /// Send `command` with as many extra zero bytes as there are bytes to
+    /// read in the response.
+    fn command(&self, command: &[u8], response_len: usize) {
+        self.spi_buf.take().map(|wbuf| {
+            let len = command.len() + response_len;
+            wbuf[..command.len()].copy_from_slice(command);
+            for byte in wbuf[command.len()..len].iter_mut() {
+                *byte = 0;
+            }
+            self.send(wbuf, len, true);
+        });
+    }
+
+    fn write_buffer(&self) {
+        let len = self.len.get();
+        self.spi_buf.take().map(|wbuf| {
+            wbuf[0] = opcode::WRITE_BUFFER;
+            wbuf[1] = 0;
+            self.buf
+                .map(|buf| wbuf[2..2 + len].copy_from_slice(&buf[..len]));
+            self.send(wbuf, 2 + len, true);
+        });
+    }
+
+    fn sleep(&self) {
+        self.state.set(State::Sleep);
+        self.command(&[opcode::SET_SLEEP, SLEEP_WARM_START], 0);
+    }
+
+    fn done(&self) {
+        self.state.set(State::Idle);
+        let result = self.result.get();
+        self.buf.take().map(|buf| {
+            if self.transmitting.get() {
+                self.tx_client
+                    .map(move |client| client.transmit_done(buf, result));
+            } else {
+                let len = if result.is_ok() { self.len.get() } else { 0 };
+                self.rx_client.map(move |client| {
+                    client.receive_done(buf, len, self.rssi.get(), self.snr.get(), result)
+                });
+            }
+        });
+    }
+
+    fn check_operation(
+        &self,
+        modulation: &Modulation,
+        buf: &'static mut [u8],
+    ) -> Result<&'static mut [u8], (ErrorCode, &'static mut [u8])> {
+        if self.state.get() != State::Idle || self.spi_buf.is_none() {
+            Err((ErrorCode::BUSY, buf))
+        } else if modulation.spreading_factor < 7 || modulation.spreading_factor > 12 {
+            Err((ErrorCode::INVAL, buf))
+        } else {
+            Ok(buf)
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> lora::Radio<'a> for SX126x<'a, S> {
+    fn set_transmit_client(&self, client: &'a dyn lora::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn lora::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn transmit(
+        &self,
+        modulation: Modulation,
+        power: i8,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let buf = self.check_operation(&modulation, buf)?;
+        if len > buf.len() || len > lora::MAX_PAYLOAD_LEN {
+            return Err((ErrorCode::SIZE, buf));
+        }
+        self.transmitting.set(true);
+        self.modulation.set(modulation);
+        self.power.set(cmp::max(cmp::min(power, 22), -9));
+        self.len.set(len);
+        self.buf.replace(buf);
+        self.start();
+        Ok(())
+    }
+
+    fn receive(
+        &self,
+        modulation: Modulation,
+        buf: &'static mut [u8],
+        timeout_ms: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let buf = self.check_operation(&modulation, buf)?;
+        self.transmitting.set(false);
+        self.modulation.set(modulation);
+        self.power.set(0);
+        // The timeout is a 24-bit count of 15.625 µs steps, where 0 would
+        // disable it.
+        self.timeout.set(cmp::max(
+            cmp::min(timeout_ms.saturating_mul(64), 0xfffffe),
+            1,
+        ));
+        self.len.set(lora::MAX_PAYLOAD_LEN);
+        self.buf.replace(buf);
+        self.start();
+        Ok(())
+    }
+
+    fn is_busy(&self) -> bool {
+        self.state.get() != State::Idle
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for SX126x<'a, S> {
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        _len: usize,
+    ) {
+        self.spi_buf.replace(write_buffer);
+        read_buffer.map(|rbuf| self.spi_read_buf.replace(rbuf));
+
+        match self.state.get() {
+            State::Idle | State::Transmitting | State::Receiving => {}
+            State::Setup(step) => {
+                if step + 1 < SETUP_LEN {
+                    self.setup_step(step + 1);
+                } else if self.transmitting.get() {
+                    self.state.set(State::WriteBuffer);
+                    self.write_buffer();
+                } else {
+                    self.state.set(State::Receiving);
+                    let timeout = self.timeout.get().to_be_bytes();
+                    self.command(&[opcode::SET_RX, timeout[1], timeout[2], timeout[3]], 0);
+                }
+            }
+            State::WriteBuffer => {
+                self.state.set(State::Transmitting);
+                // Without timeout.
+                self.command(&[opcode::SET_TX, 0, 0, 0], 0);
+            }
+            State::GetIrqStatus => {
+                let status = self
+                    .spi_read_buf
+                    .map_or(0, |rbuf| (rbuf[2] as u16) << 8 | rbuf[3] as u16);
+                let result = if self.transmitting.get() {
+                    if status & irq::TX_DONE != 0 {
+                        Ok(())
+                    } else {
+                        Err(ErrorCode::FAIL)
+                    }
+                } else if status & irq::RX_DONE != 0 {
+                    if status & (irq::HEADER_ERR | irq::CRC_ERR) != 0 {
+                        Err(ErrorCode::FAIL)
+                    } else {
+                        Ok(())
+                    }
+                } else if status & irq::TIMEOUT != 0 {
+                    Err(ErrorCode::NOACK)
+                } else {
+                    Err(ErrorCode::FAIL)
+                };
+                self.result.set(result);
+                self.state.set(State::ClearIrq);
+                self.command(
+                    &[
+                        opcode::CLEAR_IRQ_STATUS,
+                        (irq::ALL >> 8) as u8,
+                        irq::ALL as u8,
+                    ],
+                    0,
+                );
+            }
+            State::ClearIrq => {
+                if !self.transmitting.get() && self.result.get().is_ok() {
+                    self.state.set(State::GetRxBufferStatus);
+                    self.command(&[opcode::GET_RX_BUFFER_STATUS], 3);
+                } else {
+                    self.sleep();
+                }
+            }
+            State::GetRxBufferStatus => {
+                let max_len = self.buf.map_or(0, |buf| buf.len());
+                self.spi_read_buf.map(|rbuf| {
+                    self.len.set(cmp::min(rbuf[2] as usize, max_len));
+                    self.rx_addr.set(rbuf[3]);
+                });
+                self.state.set(State::GetPacketStatus);
+                self.command(&[opcode::GET_PACKET_STATUS], 4);
+            }
+            State::GetPacketStatus => {
+                self.spi_read_buf.map(|rbuf| {
+                    self.rssi.set(-(rbuf[2] as i16) / 2);
+                    self.snr.set(rbuf[3] as i8 / 4);
+                });
+                if self.len.get() > 0 {
+                    self.state.set(State::ReadBuffer);
+                    self.command(
+                        &[opcode::READ_BUFFER, self.rx_addr.get(), 0],
+                        self.len.get(),
+                    );
+                } else {
+                    self.sleep();
+                }
+            }
+            State::ReadBuffer => {
+                let len = self.len.get();
+                self.spi_read_buf.map(|rbuf| {
+                    self.buf.map(|buf| {
+                        buf[..len]
+                            .copy_from_slice(&rbuf[READ_BUFFER_HDR_LEN..READ_BUFFER_HDR_LEN + len])
+                    });
+                });
+                self.sleep();
+            }
+            State::Sleep => self.done(),
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> gpio::Client for SX126x<'a, S> {
+    fn fired(&self) {
+        match self.state.get() {
+            State::Transmitting | State::Receiving => {
+                self.state.set(State::GetIrqStatus);
+                self.command(&[opcode::GET_IRQ_STATUS], 3);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,514 @@
+//! Driver for the Semtech SX1276/77/78/79 LoRa transceivers.
+//!
+//! <https://www.semtech.com/products/wireless-rf/lora-core/sx1276>
+//!
+//! The radio is driven over SPI, one register at a time. It signals the end
+//! of a transmission or reception on its DIO0 pin, and the timeout of a
+//! reception on its DIO1 pin; both must be connected to interrupt-capable
+//! GPIO pins.
+//!
+//! The radio sleeps between operations. Each transmission or reception
+//! wakes it up in LoRa mode, writes every register that depends on the
+//! requested modulation, and starts the operation. Once the radio signals
+//! the end of the operation, the driver reads the interrupt flags and any
+//! received packet, puts the radio back to sleep and then notifies the
+//! client.
+//!
+//! Transmissions use the PA_BOOST pin, as on most SX1276 modules, so the
+//! transmit power ranges from 2 to 17 dBm.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sx1276_spi = static_init!(
+//!     capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>,
+//!     capsules::virtual_spi::VirtualSpiMasterDevice::new(mux_spi, &nrf52840::gpio::PORT[CS]));
+//! let sx1276 = static_init!(
+//!     capsules::sx127x::SX127x<'static,
+//!         capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>>,
+//!     capsules::sx127x::SX127x::new(
+//!         sx1276_spi,
+//!         &nrf52840::gpio::PORT[DIO0],
+//!         &nrf52840::gpio::PORT[DIO1],
+//!         &mut capsules::sx127x::SPI_BUF,
+//!         &mut capsules::sx127x::SPI_READ_BUF,
+//!     ));
+//! sx1276_spi.set_client(sx1276);
+//! nrf52840::gpio::PORT[DIO0].set_client(sx1276);
+//! nrf52840::gpio::PORT[DIO1].set_client(sx1276);
+//! sx1276.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::gpio;
+use kernel::hil::lora::{self, Bandwidth, Modulation};
+use kernel::hil::spi;
+use kernel::ErrorCode;
+
+/// Length of the SPI buffers: a register address followed by a whole FIFO.
+pub const SPI_BUF_LEN: usize = 1 + lora::MAX_PAYLOAD_LEN;
+
+pub static mut SPI_BUF: [u8; SPI_BUF_LEN] = [0; SPI_BUF_LEN];
+pub static mut SPI_READ_BUF: [u8; SPI_BUF_LEN] = [0; SPI_BUF_LEN];
+
+const SPI_SPEED: u32 = 8_000_000;
+
+/// Frequency of the crystal oscillator, in Hz.
+const FXOSC: u64 = 32_000_000;
+
+/// Number of symbols of the preamble.
+const PREAMBLE_LEN: u8 = 8;
+
+/// Bit of the register address selecting a write.
+const WRITE: u8 = 0x80;
+
+#[allow(dead_code)]
+mod reg {
+    pub const FIFO: u8 = 0x00;
+    pub const OP_MODE: u8 = 0x01;
+    pub const FRF_MSB: u8 = 0x06;
+    pub const FRF_MID: u8 = 0x07;
+    pub const FRF_LSB: u8 = 0x08;
+    pub const PA_CONFIG: u8 = 0x09;
+    pub const FIFO_ADDR_PTR: u8 = 0x0d;
+    pub const FIFO_TX_BASE_ADDR: u8 = 0x0e;
+    pub const FIFO_RX_BASE_ADDR: u8 = 0x0f;
+    pub const FIFO_RX_CURRENT_ADDR: u8 = 0x10;
+    pub const IRQ_FLAGS: u8 = 0x12;
+    pub const RX_NB_BYTES: u8 = 0x13;
+    pub const PKT_SNR_VALUE: u8 = 0x19;
+    pub const PKT_RSSI_VALUE: u8 = 0x1a;
+    pub const MODEM_CONFIG_1: u8 = 0x1d;
+    pub const MODEM_CONFIG_2: u8 = 0x1e;
+    pub const SYMB_TIMEOUT_LSB: u8 = 0x1f;
+    pub const PREAMBLE_MSB: u8 = 0x20;
+    pub const PREAMBLE_LSB: u8 = 0x21;
+    pub const PAYLOAD_LENGTH: u8 = 0x22;
+    pub const MODEM_CONFIG_3: u8 = 0x26;
+    pub const INVERT_IQ: u8 = 0x33;
+    pub const SYNC_WORD: u8 = 0x39;
+    pub const INVERT_IQ_2: u8 = 0x3b;
+    pub const DIO_MAPPING_1: u8 = 0x40;
+    pub const VERSION: u8 = 0x42;
+}
+
+mod mode {
+    pub const LONG_RANGE: u8 = 0x80;
+    pub const SLEEP: u8 = 0x00;
+    pub const STANDBY: u8 = 0x01;
+    pub const TX: u8 = 0x03;
+    pub const RX_SINGLE: u8 = 0x06;
+}
+
+#[allow(dead_code)]
+mod irq {
+    pub const RX_TIMEOUT: u8 = 0x80;
+    pub const RX_DONE: u8 = 0x40;
+    pub const PAYLOAD_CRC_ERROR: u8 = 0x20;
+    pub const VALID_HEADER: u8 = 0x10;
+    pub const TX_DONE: u8 = 0x08;
+    pub const ALL: u8 = 0xff;
+}
+
+/// DIO0 signals TxDone when mapped to 01, and RxDone when mapped to 00.
+const DIO0_TX_DONE: u8 = 0x40;
+const DIO0_RX_DONE: u8 = 0x00;
+
+const PA_BOOST: u8 = 0x80;
+const AGC_AUTO_ON: u8 = 0x04;
+const RX_PAYLOAD_CRC_ON: u8 = 0x04;
+
+/// Number of registers written before starting an operation.
+const SETUP_LEN: usize = 22;
+
+/// Number of registers read after an operation, from `FIFO_RX_CURRENT_ADDR`
+/// to `PKT_RSSI_VALUE`.
+const STATUS_LEN: usize = (reg::PKT_RSSI_VALUE - reg::FIFO_RX_CURRENT_ADDR + 1) as usize;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Idle,
+    /// Writing the register with the given index in the setup sequence.
+    Setup(usize),
+    WriteFifo,
+    Transmitting,
+    Receiving,
+    ReadStatus,
+    ClearIrq,
+    SetFifoPtr,
+    ReadFifo,
+    Sleep,
+}
+
+pub struct SX127x<'a, S: spi::SpiMasterDevice> {
+    spi: &'a S,
+    dio0: &'a dyn gpio::InterruptPin<'a>,
+    dio1: &'a dyn gpio::InterruptPin<'a>,
+    state: Cell<State>,
+    transmitting: Cell<bool>,
+    modulation: OptionalCell<Modulation>,
+    power: Cell<i8>,
+    timeout_symbols: Cell<u16>,
+    /// Length of the packet being sent or received.
+    len: Cell<usize>,
+    rx_addr: Cell<u8>,
+    rssi: Cell<i16>,
+    snr: Cell<i8>,
+    result: Cell<Result<(), ErrorCode>>,
+    buf: TakeCell<'static, [u8]>,
+    spi_buf: TakeCell<'static, [u8]>,
+    spi_read_buf: TakeCell<'static, [u8]>,
+    tx_client: OptionalCell<&'a dyn lora::TransmitClient>,
+    rx_client: OptionalCell<&'a dyn lora::ReceiveClient>,
+}
+
+impl<'a, S: spi::SpiMasterDevice> SX127x<'a, S> {
+    pub fn new(
+        spi: &'a S,
+        dio0: &'a dyn gpio::InterruptPin<'a>,
+        dio1: &'a dyn gpio::InterruptPin<'a>,
+        spi_buf: &'static mut [u8],
+        spi_read_buf: &'static mut [u8],
+    ) -> SX127x<'a, S> {
+        SX127x {
+            spi: spi,
+            dio0: dio0,
+            dio1: dio1,
+            state: Cell::new(State::Idle),
+            transmitting: Cell::new(false),
+            modulation: OptionalCell::empty(),
+            power: Cell::new(0),
+            timeout_symbols: Cell::new(0),
+            len: Cell::new(0),
+            rx_addr: Cell::new(0),
+            rssi: Cell::new(0),
+            snr: Cell::new(0),
+            result: Cell::new(Ok(())),
+            buf: TakeCell::empty(),
+            spi_buf: TakeCell::new(spi_buf),
+            spi_read_buf: TakeCell::new(spi_read_buf),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Configure the SPI bus and the interrupt pins.
+    pub fn initialize(&self) {
+        self.spi.configure(
+            spi::ClockPolarity::IdleLow,
+            spi::ClockPhase::SampleLeading,
+            SPI_SPEED,
+        );
+        for pin in [self.dio0, self.dio1].iter() {
+            pin.make_input();
+            pin.enable_interrupts(gpio::InterruptEdge::RisingEdge);
+        }
+    }
+
+    /// The registers to write, in order, to set up the pending operation.
+    fn setup_registers(&self, m: &Modulation) -> [(u8, u8); SETUP_LEN] {
+        let transmitting = self.transmitting.get();
+        let frf = ((m.frequency as u64) << 19) / FXOSC;
+        let bandwidth = match m.bandwidth {
+            Bandwidth::Bw125kHz => 0x70,
+            Bandwidth::Bw250kHz => 0x80,
+            Bandwidth::Bw500kHz => 0x90,
+        };
+        let crc = if transmitting { RX_PAYLOAD_CRC_ON } else { 0 };
+        let timeout = self.timeout_symbols.get();
+        let (invert_iq, invert_iq_2) = match (m.invert_iq, transmitting) {
+            (false, _) => (0x27, 0x1d),
+            (true, true) => (0x26, 0x19),
+            (true, false) => (0x67, 0x19),
+        };
+        [
+            // The modem can only be switched to LoRa while asleep.
+            (reg::OP_MODE, mode::SLEEP),
+            (reg::OP_MODE, mode::LONG_RANGE | mode::SLEEP),
+            (reg::OP_MODE, mode::LONG_RANGE | mode::STANDBY),
+            (reg::FRF_MSB, (frf >> 16) as u8),
+            (reg::FRF_MID, (frf >> 8) as u8),
+            (reg::FRF_LSB, frf as u8),
+            (reg::PA_CONFIG, PA_BOOST | (self.power.get() - 2) as u8),
+            (reg::MODEM_CONFIG_1, bandwidth | (m.coding_rate as u8) << 1),
+            (
+                reg::MODEM_CONFIG_2,
+                m.spreading_factor << 4 | crc | (timeout >> 8) as u8,
+            ),
+            (
+                reg::MODEM_CONFIG_3,
+                (m.low_data_rate() as u8) << 3 | AGC_AUTO_ON,
+            ),
+            (reg::SYMB_TIMEOUT_LSB, timeout as u8),
+            (reg::PREAMBLE_MSB, 0),
+            (reg::PREAMBLE_LSB, PREAMBLE_LEN),
+            (reg::SYNC_WORD, if m.public_network { 0x34 } else { 0x12 }),
+            (reg::INVERT_IQ, invert_iq),
+            (reg::INVERT_IQ_2, invert_iq_2),
+            (reg::FIFO_TX_BASE_ADDR, 0),
+            (reg::FIFO_RX_BASE_ADDR, 0),
+            (reg::FIFO_ADDR_PTR, 0),
+            // Only used in implicit header mode, but must not be zero.
+            (reg::PAYLOAD_LENGTH, cmp::max(self.len.get(), 1) as u8),
+            (
+                reg::DIO_MAPPING_1,
+                if transmitting {
+                    DIO0_TX_DONE
+                } else {
+                    DIO0_RX_DONE
+                },
+            ),
+            (reg::IRQ_FLAGS, irq::ALL),
+        ]
+    }
+
+    fn setup_step(&self, step: usize) {
+        self.state.set(State::Setup(step));
+        let (reg, val) = self.modulation.map_or((reg::OP_MODE, mode::SLEEP), |m| {
+            self.setup_registers(m)[step]
+        });
+        self.register_write(reg, val);
+    }
+
+    fn start(&self) {
+        self.setup_step(0);
+    }
+
+    fn register_write(&self, reg: u8, val: u8) {
+        self.spi_buf.take().map(|wbuf| {
+            wbuf[0] = reg | WRITE;
+            wbuf[1] = val;
+            let _ = self.spi.read_write_bytes(wbuf, self.spi_read_buf.take(), 2);
+        });
+    }
+
+    /// Read `len` consecutive registers starting at `reg`. The FIFO can be
+    /// read the same way, as it does not auto-increment the address.
+    fn burst_read(&self, reg: u8, len: usize) {
+        self.spi_buf.take().map(|wbuf| {
+            wbuf[0] = reg;
+            for byte in wbuf[1..=len].iter_mut() {
+                *byte = 0;
+            }
+            let _ = self
+                .spi
+                .read_write_bytes(wbuf, self.spi_read_buf.take(), len + 1);
+        });
+    }
+
+    fn fifo_write(&self) {
+        let len = self.len.get();
+        self.spi_buf.take().map(|wbuf| {
+            wbuf[0] = reg::FIFO | WRITE;
+            self.buf
+                .map(|buf| wbuf[1..=len].copy_from_slice(&buf[..len]));
+            let _ = self
+                .spi
+                .read_write_bytes(wbuf, self.spi_read_buf.take(), len + 1);
+        });
+    }
+
+    /// Decode the registers read after an operation finished.
+    fn read_status(&self, status: &[u8]) {
+        let status_reg = |reg: u8| status[1 + (reg - reg::FIFO_RX_CURRENT_ADDR) as usize];
+        let flags = status_reg(reg::IRQ_FLAGS);
+        let result = if self.transmitting.get() {
+            if flags & irq::TX_DONE != 0 {
+                Ok(())
+            } else {
+                Err(ErrorCode::FAIL)
+            }
+        } else if flags & irq::RX_TIMEOUT != 0 {
+            Err(ErrorCode::NOACK)
+        } else if flags & irq::RX_DONE == 0 || flags & irq::PAYLOAD_CRC_ERROR != 0 {
+            Err(ErrorCode::FAIL)
+        } else {
+            Ok(())
+        };
+        self.result.set(result);
+        if self.transmitting.get() || result.is_err() {
+            return;
+        }
+
+        let max_len = self.buf.map_or(0, |buf| buf.len());
+        self.len
+            .set(cmp::min(status_reg(reg::RX_NB_BYTES) as usize, max_len));
+        self.rx_addr.set(status_reg(reg::FIFO_RX_CURRENT_ADDR));
+        let snr = status_reg(reg::PKT_SNR_VALUE) as i8 / 4;
+        let offset = self.modulation.map_or(-157, |m| {
+            if m.frequency > 525_000_000 {
+                -157
+            } else {
+                -164
+            }
+        });
+        let mut rssi = offset + status_reg(reg::PKT_RSSI_VALUE) as i16;
+        if snr < 0 {
+            rssi += snr as i16;
+        }
+        self.rssi.set(rssi);
+        self.snr.set(snr);
+    }
+
+    fn done(&self) {
+        self.state.set(State::Idle);
+        let result = self.result.get();
+        self.buf.take().map(|buf| {
+            if self.transmitting.get() {
+                self.tx_client
+                    .map(move |client| client.transmit_done(buf, result));
+            } else {
+                let len = if result.is_ok() { self.len.get() } else { 0 };
+                self.rx_client.map(move |client| {
+                    client.receive_done(buf, len, self.rssi.get(), self.snr.get(), result)
+                });
+            }
+        });
+    }
+
+    fn check_operation(
+        &self,
+        modulation: &Modulation,
+        buf: &'static mut [u8],
+    ) -> Result<&'static mut [u8], (ErrorCode, &'static mut [u8])> {
+        if self.state.get() != State::Idle || self.spi_buf.is_none() {
+            Err((ErrorCode::BUSY, buf))
+        } else if modulation.spreading_factor < 7 || modulation.spreading_factor > 12 {
+            Err((ErrorCode::INVAL, buf))
+        } else {
+            Ok(buf)
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> lora::Radio<'a> for SX127x<'a, S> {
+    fn set_transmit_client(&self, client: &'a dyn lora::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn lora::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn transmit(
+        &self,
+        modulation: Modulation,
+        power: i8,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let buf = self.check_operation(&modulation, buf)?;
+        if len > buf.len() || len > lora::MAX_PAYLOAD_LEN {
+            return Err((ErrorCode::SIZE, buf));
+        }
+        self.transmitting.set(true);
+        self.modulation.set(modulation);
+        self.power.set(cmp::max(cmp::min(power, 17), 2));
+        self.timeout_symbols.set(0);
+        self.len.set(len);
+        self.buf.replace(buf);
+        self.start();
+        Ok(())
+    }
+
+    fn receive(
+        &self,
+        modulation: Modulation,
+        buf: &'static mut [u8],
+        timeout_ms: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let buf = self.check_operation(&modulation, buf)?;
+        let symbols = timeout_ms as u64 * 1000 / modulation.symbol_time_us() as u64;
+        self.transmitting.set(false);
+        self.modulation.set(modulation);
+        self.power.set(2);
+        self.timeout_symbols
+            .set(cmp::max(cmp::min(symbols, 0x3ff), 4) as u16);
+        self.len.set(0);
+        self.buf.replace(buf);
+        self.start();
+        Ok(())
+    }
+
+    fn is_busy(&self) -> bool {
+        self.state.get() != State::Idle
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for SX127x<'a, S> {
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        _len: usize,
+    ) {
+        self.spi_buf.replace(write_buffer);
+        read_buffer.map(|rbuf| self.spi_read_buf.replace(rbuf));
+
+        match self.state.get() {
+            State::Idle | State::Transmitting | State::Receiving => {}
+            State::Setup(step) => {
+                if step + 1 < SETUP_LEN {
+                    self.setup_step(step + 1);
+                } else if self.transmitting.get() {
+                    self.state.set(State::WriteFifo);
+                    self.fifo_write();
+                } else {
+                    self.state.set(State::Receiving);
+                    self.register_write(reg::OP_MODE, mode::LONG_RANGE | mode::RX_SINGLE);
+                }
+            }
+            State::WriteFifo => {
+                self.state.set(State::Transmitting);
+                self.register_write(reg::OP_MODE, mode::LONG_RANGE | mode::TX);
+            }
+            State::ReadStatus => {
+                self.spi_read_buf.map(|status| self.read_status(status));
+                self.state.set(State::ClearIrq);
+                self.register_write(reg::IRQ_FLAGS, irq::ALL);
+            }
+            State::ClearIrq => {
+                if !self.transmitting.get() && self.result.get().is_ok() && self.len.get() > 0 {
+                    self.state.set(State::SetFifoPtr);
+                    self.register_write(reg::FIFO_ADDR_PTR, self.rx_addr.get());
+                } else {
+                    self.state.set(State::Sleep);
+                    self.register_write(reg::OP_MODE, mode::LONG_RANGE | mode::SLEEP);
+                }
+            }
+            State::SetFifoPtr => {
+                self.state.set(State::ReadFifo);
+                self.burst_read(reg::FIFO, self.len.get());
+            }
+            State::ReadFifo => {
+                let len = self.len.get();
+                self.spi_read_buf.map(|rbuf| {
+                    self.buf
+                        .map(|buf| buf[..len].copy_from_slice(&rbuf[1..=len]));
+                });
+                self.state.set(State::Sleep);
+                self.register_write(reg::OP_MODE, mode::LONG_RANGE | mode::SLEEP);
+            }
+            State::Sleep => self.done(),
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> gpio::Client for SX127x<'a, S> {
+    /// Called when either DIO0 or DIO1 rises: the interrupt flags tell which
+    /// event happened.
+    fn fired(&self) {
+        match self.state.get() {
+            State::Transmitting | State::Receiving => {
+                self.state.set(State::ReadStatus);
+                self.burst_read(reg::FIFO_RX_CURRENT_ADDR, STATUS_LEN);
+            }
+            _ => {}
+        }
+    }
+}
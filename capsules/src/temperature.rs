@@ -5,9 +5,13 @@
 //!
 //! ### `subscribe` System Call
 //!
-//! The `subscribe` system call supports the single `subscribe_number` zero,
-//! which is used to provide a callback that will return back the result of
-//! a temperature sensor reading.
+//! The `subscribe` system call supports two `subscribe_number`s:
+//!
+//! * `0`: callback for a single temperature sensor reading. The callback
+//!   receives the temperature and the index of the sensor that was read.
+//! * `1`: callback for the completion of a read of all sensors. The callback
+//!   receives the status and the number of readings written to the buffer.
+//!
 //! The `subscribe`call return codes indicate the following:
 //!
 //! * `Ok(())`: the callback been successfully been configured.
@@ -22,13 +26,24 @@
 //! operation, currently the following cmd's are supported:
 //!
 //! * `0`: check whether the driver exist
-//! * `1`: read the temperature
+//! * `1`: read the temperature of the sensor whose index is given in the
+//!   first argument
+//! * `2`: return the number of sensors
+//! * `3`: read all sensors in sequence into the buffer shared with `allow`
+//!
+//! ### `allow_readwrite` System Call
+//!
+//! * `0`: buffer that receives the readings of a read of all sensors. Each
+//!   reading is stored as a little-endian `i32` in hundredths of degrees
+//!   centigrade, in sensor index order.
 //!
 //!
 //! The possible return from the 'command' system call indicates the following:
 //!
 //! * `Ok(())`:    The operation has been successful.
 //! * `BUSY`:      The driver is busy.
+//! * `INVAL`:     The sensor index is not valid.
+//! * `SIZE`:      The buffer is too small to hold a reading of every sensor.
 //! * `ENOSUPPORT`: Invalid `cmd`.
 //! * `NOMEM`:     No sufficient memory available.
 //! * `INVAL`:     Invalid address of the buffer or other error.
@@ -51,12 +66,34 @@
 //!
 //! kernel::hil::sensors::TemperatureDriver::set_client(si7021, temp);
 //! ```
+//!
+//! Boards with several temperature sensors can expose all of them through a
+//! single driver. Sensor indices follow the order of the array:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sensors = static_init!(
+//!     [&'static dyn kernel::hil::sensors::TemperatureDriver<'static>; 2],
+//!     [&nrf52::temperature::TEMP, si7021]
+//! );
+//! let temp = static_init!(
+//!        capsules::temperature::TemperatureSensor<'static>,
+//!        capsules::temperature::TemperatureSensor::new_multiple(sensors,
+//!                                                 board_kernel.create_grant(&grant_cap)));
+//! for sensor in sensors.iter() {
+//!     sensor.set_client(temp);
+//! }
+//! ```
 
 use core::cell::Cell;
 use core::convert::TryFrom;
 use core::mem;
+use kernel::common::cells::OptionalCell;
 use kernel::hil;
-use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadWrite, ReadWriteAppSlice, Upcall,
+};
 
 /// Syscall driver number.
 use crate::driver;
@@ -66,12 +103,49 @@ pub const DRIVER_NUM: usize = driver::NUM::Temperature as usize;
 pub struct App {
     callback: Upcall,
     subscribed: bool,
+    read_all_callback: Upcall,
+    readings: ReadWriteAppSlice,
+}
+
+/// The temperature sensors managed by the driver.
+#[derive(Copy, Clone)]
+enum Sensors<'a> {
+    One(&'a dyn hil::sensors::TemperatureDriver<'a>),
+    Many(&'a [&'a dyn hil::sensors::TemperatureDriver<'a>]),
+}
+
+impl<'a> Sensors<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Sensors::One(_) => 1,
+            Sensors::Many(drivers) => drivers.len(),
+        }
+    }
+
+    fn get(&self, index: usize) -> Option<&'a dyn hil::sensors::TemperatureDriver<'a>> {
+        match self {
+            Sensors::One(driver) if index == 0 => Some(*driver),
+            Sensors::One(_) => None,
+            Sensors::Many(drivers) => drivers.get(index).copied(),
+        }
+    }
+}
+
+/// What the sensor currently being read is being read for.
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Idle,
+    Single,
+    ReadAll,
 }
 
 pub struct TemperatureSensor<'a> {
-    driver: &'a dyn hil::sensors::TemperatureDriver<'a>,
+    sensors: Sensors<'a>,
     apps: Grant<App>,
-    busy: Cell<bool>,
+    operation: Cell<Operation>,
+    // Index of the sensor with a reading in flight.
+    current: Cell<usize>,
+    read_all_app: OptionalCell<ProcessId>,
 }
 
 impl<'a> TemperatureSensor<'a> {
@@ -79,23 +153,52 @@ impl<'a> TemperatureSensor<'a> {
         driver: &'a dyn hil::sensors::TemperatureDriver<'a>,
         grant: Grant<App>,
     ) -> TemperatureSensor<'a> {
+        TemperatureSensor::with_sensors(Sensors::One(driver), grant)
+    }
+
+    /// Create a driver for several sensors, selected by their index in
+    /// `drivers`.
+    pub fn new_multiple(
+        drivers: &'a [&'a dyn hil::sensors::TemperatureDriver<'a>],
+        grant: Grant<App>,
+    ) -> TemperatureSensor<'a> {
+        TemperatureSensor::with_sensors(Sensors::Many(drivers), grant)
+    }
+
+    fn with_sensors(sensors: Sensors<'a>, grant: Grant<App>) -> TemperatureSensor<'a> {
         TemperatureSensor {
-            driver: driver,
+            sensors: sensors,
             apps: grant,
-            busy: Cell::new(false),
+            operation: Cell::new(Operation::Idle),
+            current: Cell::new(0),
+            read_all_app: OptionalCell::empty(),
         }
     }
 
-    fn enqueue_command(&self, appid: ProcessId) -> CommandReturn {
+    fn start_read(&self, index: usize) -> Result<(), ErrorCode> {
+        self.current.set(index);
+        self.sensors
+            .get(index)
+            .map_or(Err(ErrorCode::INVAL), |sensor| sensor.read_temperature())
+    }
+
+    fn enqueue_command(&self, index: usize, appid: ProcessId) -> CommandReturn {
+        if index >= self.sensors.len() {
+            return CommandReturn::failure(ErrorCode::INVAL);
+        }
         self.apps
             .enter(appid, |app| {
-                if !self.busy.get() {
+                if self.operation.get() == Operation::Idle {
                     app.subscribed = true;
-                    self.busy.set(true);
-                    let rcode = self.driver.read_temperature();
+                    self.operation.set(Operation::Single);
+                    let rcode = self.start_read(index);
                     let eres = ErrorCode::try_from(rcode);
                     match eres {
-                        Ok(ecode) => CommandReturn::failure(ecode),
+                        Ok(ecode) => {
+                            app.subscribed = false;
+                            self.operation.set(Operation::Idle);
+                            CommandReturn::failure(ecode)
+                        }
                         _ => CommandReturn::success(),
                     }
                 } else {
@@ -105,15 +208,86 @@ impl<'a> TemperatureSensor<'a> {
             .unwrap_or_else(|err| CommandReturn::failure(err.into()))
     }
 
+    fn read_all(&self, appid: ProcessId) -> CommandReturn {
+        if self.operation.get() != Operation::Idle {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+        let needed = self.sensors.len() * 4;
+        let res = self
+            .apps
+            .enter(appid, |app| {
+                if app.readings.len() < needed {
+                    Err(ErrorCode::SIZE)
+                } else {
+                    Ok(())
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        if let Err(e) = res {
+            return CommandReturn::failure(e);
+        }
+
+        self.operation.set(Operation::ReadAll);
+        self.read_all_app.set(appid);
+        match self.start_read(0) {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => {
+                self.operation.set(Operation::Idle);
+                self.read_all_app.clear();
+                CommandReturn::failure(e)
+            }
+        }
+    }
+
+    /// Store a reading from a read of all sensors and start reading the next
+    /// sensor, or notify the app once every sensor has been read.
+    fn read_all_reading(&self, temp_val: usize) {
+        let index = self.current.get();
+        self.read_all_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app| {
+                app.readings.mut_map_or((), |buffer| {
+                    let offset = index * 4;
+                    if buffer.len() >= offset + 4 {
+                        buffer[offset..offset + 4]
+                            .copy_from_slice(&(temp_val as i32).to_le_bytes());
+                    }
+                });
+            });
+        });
+
+        let next = index + 1;
+        let result = if next < self.sensors.len() {
+            match self.start_read(next) {
+                Ok(()) => return,
+                Err(e) => Err(e),
+            }
+        } else {
+            Ok(())
+        };
+
+        self.operation.set(Operation::Idle);
+        self.read_all_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.read_all_callback
+                    .schedule(kernel::into_statuscode(result), next, 0);
+            });
+        });
+    }
+
     fn configure_callback(
         &self,
+        subscribe_num: usize,
         mut callback: Upcall,
         app_id: ProcessId,
     ) -> Result<Upcall, (Upcall, ErrorCode)> {
         let res = self
             .apps
             .enter(app_id, |app| {
-                mem::swap(&mut app.callback, &mut callback);
+                if subscribe_num == 0 {
+                    mem::swap(&mut app.callback, &mut callback);
+                } else {
+                    mem::swap(&mut app.read_all_callback, &mut callback);
+                }
             })
             .map_err(ErrorCode::from);
         if let Err(e) = res {
@@ -126,14 +300,20 @@ impl<'a> TemperatureSensor<'a> {
 
 impl hil::sensors::TemperatureClient for TemperatureSensor<'_> {
     fn callback(&self, temp_val: usize) {
-        for cntr in self.apps.iter() {
-            cntr.enter(|app| {
-                if app.subscribed {
-                    self.busy.set(false);
-                    app.subscribed = false;
-                    app.callback.schedule(temp_val, 0, 0);
+        match self.operation.get() {
+            Operation::ReadAll => self.read_all_reading(temp_val),
+            _ => {
+                self.operation.set(Operation::Idle);
+                let index = self.current.get();
+                for cntr in self.apps.iter() {
+                    cntr.enter(|app| {
+                        if app.subscribed {
+                            app.subscribed = false;
+                            app.callback.schedule(temp_val, index, 0);
+                        }
+                    });
                 }
-            });
+            }
         }
     }
 }
@@ -147,19 +327,198 @@ impl Driver for TemperatureSensor<'_> {
     ) -> Result<Upcall, (Upcall, ErrorCode)> {
         match subscribe_num {
             // subscribe to temperature reading with callback
-            0 => self.configure_callback(callback, app_id),
+            0 => self.configure_callback(0, callback, app_id),
+            // subscribe to completion of a read of all sensors
+            1 => self.configure_callback(1, callback, app_id),
             _ => Err((callback, ErrorCode::NOSUPPORT)),
         }
     }
 
-    fn command(&self, command_num: usize, _: usize, _: usize, appid: ProcessId) -> CommandReturn {
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.readings, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        data: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
         match command_num {
             // check whether the driver exists!!
             0 => CommandReturn::success(),
 
             // read temperature
-            1 => self.enqueue_command(appid),
+            1 => self.enqueue_command(data, appid),
+
+            // number of sensors
+            2 => CommandReturn::success_u32(self.sensors.len() as u32),
+
+            // read all sensors
+            3 => self.read_all(appid),
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{TemperatureSensor, DRIVER_NUM};
+    use crate::test::mock_process::{self, MockProcess};
+    use core::cell::Cell;
+    use kernel::common::cells::OptionalCell;
+    use kernel::hil::sensors::{TemperatureClient, TemperatureDriver};
+    use kernel::{Driver, ErrorCode, Read, ReadWriteAppSlice};
+    use std::vec::Vec;
+
+    /// A sensor that reads `value` when the test completes the reading.
+    struct MockSensor {
+        value: i32,
+        pending: Cell<bool>,
+        client: OptionalCell<&'static dyn TemperatureClient>,
+    }
+
+    impl MockSensor {
+        fn new(value: i32) -> MockSensor {
+            MockSensor {
+                value: value,
+                pending: Cell::new(false),
+                client: OptionalCell::empty(),
+            }
+        }
+
+        /// Finish the reading in progress. Returns false if there is none.
+        fn complete(&self) -> bool {
+            if !self.pending.replace(false) {
+                return false;
+            }
+            self.client
+                .map(|client| client.callback(self.value as usize));
+            true
+        }
+    }
+
+    impl TemperatureDriver<'static> for MockSensor {
+        fn set_client(&self, client: &'static dyn TemperatureClient) {
+            self.client.set(client);
+        }
+
+        fn read_temperature(&self) -> Result<(), ErrorCode> {
+            assert!(!self.pending.replace(true), "read while busy");
+            Ok(())
+        }
+    }
+
+    /// A driver for sensors reading `values`, and a process that allowed a
+    /// buffer of `len` bytes for their readings.
+    fn sensors(
+        values: &[i32],
+        len: usize,
+    ) -> (
+        &'static TemperatureSensor<'static>,
+        Vec<&'static MockSensor>,
+        &'static MockProcess,
+    ) {
+        let (kernel, processes) = mock_process::kernel(&["thermo"]);
+        let mocks: Vec<&'static MockSensor> = values
+            .iter()
+            .map(|value| mock_process::leak(MockSensor::new(*value)))
+            .collect();
+        let drivers: Vec<&'static dyn TemperatureDriver<'static>> = mocks
+            .iter()
+            .map(|mock| *mock as &'static dyn TemperatureDriver<'static>)
+            .collect();
+        let drivers = mock_process::leak(drivers);
+        let driver = mock_process::leak(TemperatureSensor::new_multiple(
+            drivers,
+            mock_process::grant(kernel),
+        ));
+        for mock in mocks.iter() {
+            mock.set_client(driver);
+        }
+        let app = processes[0];
+        let id = app.processid();
+        assert!(driver
+            .allow_readwrite(id, 0, app.readwrite_slice(&std::vec![0xAA; len]))
+            .is_ok());
+        assert!(driver.subscribe(1, app.upcall(DRIVER_NUM, 1), id).is_ok());
+        (driver, mocks, app)
+    }
+
+    /// The contents of the readings buffer of `process`.
+    fn readings(driver: &TemperatureSensor, process: &MockProcess) -> Vec<u8> {
+        let slice = driver
+            .allow_readwrite(process.processid(), 0, ReadWriteAppSlice::default())
+            .ok()
+            .unwrap();
+        slice.map_or(Vec::new(), |data| data.to_vec())
+    }
+
+    #[test]
+    fn test_read_all_in_sensor_order() {
+        let (driver, mocks, app) = sensors(&[2150, -550, 10000], 16);
+        assert_eq!(
+            driver.command(2, 0, 0, app.processid()).get_success_u32(),
+            Some(3)
+        );
+        assert!(driver.command(3, 0, 0, app.processid()).is_success());
+
+        // The sensors are read one after the other.
+        for (index, mock) in mocks.iter().enumerate() {
+            assert!(mocks
+                .iter()
+                .enumerate()
+                .all(|(other, mock)| mock.pending.get() == (other == index)));
+            assert_eq!(
+                driver.command(3, 0, 0, app.processid()).get_failure(),
+                Some(ErrorCode::BUSY)
+            );
+            assert!(mock.complete());
+        }
+        assert_eq!(app.take_upcalls(), [(1, 0, 3, 0)]);
+
+        let mut expected = Vec::new();
+        for value in [2150i32, -550, 10000].iter() {
+            expected.extend_from_slice(&value.to_le_bytes());
+        }
+        // Bytes past the readings are left alone.
+        expected.extend_from_slice(&[0xAA; 4]);
+        assert_eq!(readings(driver, app), expected);
+    }
+
+    #[test]
+    fn test_read_all_buffer_too_small() {
+        let (driver, mocks, app) = sensors(&[2150, -550, 10000], 11);
+        assert_eq!(
+            driver.command(3, 0, 0, app.processid()).get_failure(),
+            Some(ErrorCode::SIZE)
+        );
+        assert!(mocks.iter().all(|mock| !mock.pending.get()));
+        assert!(app.take_upcalls().is_empty());
+        assert_eq!(readings(driver, app), [0xAA; 11]);
+
+        // Nothing was started, so a single reading can be taken.
+        assert!(driver.command(1, 2, 0, app.processid()).is_success());
+        assert!(mocks[2].pending.get());
+    }
+}
@@ -0,0 +1,112 @@
+//! A fake alarm for unit tests of capsules.
+//!
+//! `MockAlarm` counts at 1 MHz by default, so microseconds and ticks are the
+//! same, and only moves when the test advances it. Advancing past the armed alarm
+//! fires it at its exact expiration, and the client may set it again.
+//!
+//! ```rust,ignore
+//! let alarm = mock_process::leak(MockAlarm::new());
+//! let driver = mock_process::leak(Driver::new(alarm));
+//! alarm.set_alarm_client(driver);
+//! driver.start();
+//! alarm.advance(1000);
+//! ```
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{Alarm, AlarmClient, Freq1MHz, Frequency, Ticks, Ticks32, Time};
+use kernel::ErrorCode;
+
+pub struct MockAlarm<'a, T: Ticks = Ticks32, F: Frequency = Freq1MHz> {
+    now: Cell<T>,
+    // The reference and dt of the armed alarm.
+    alarm: Cell<Option<(T, T)>>,
+    client: OptionalCell<&'a dyn AlarmClient>,
+    frequency: PhantomData<F>,
+}
+
+impl<'a, T: Ticks, F: Frequency> MockAlarm<'a, T, F> {
+    pub fn new() -> MockAlarm<'a, T, F> {
+        MockAlarm {
+            now: Cell::new(T::from(0)),
+            alarm: Cell::new(None),
+            client: OptionalCell::empty(),
+            frequency: PhantomData,
+        }
+    }
+
+    /// Move the counter to `now` without firing the alarm.
+    pub fn set_now(&self, now: u32) {
+        self.now.set(T::from(0).wrapping_add(T::from(now)));
+    }
+
+    /// Let `ticks` pass, firing the alarm each time it expires on the way.
+    pub fn advance(&self, ticks: u32) {
+        let mut left = ticks;
+        while let Some(until) = self.until_alarm() {
+            if until > left {
+                break;
+            }
+            self.now.set(self.now.get().wrapping_add(T::from(until)));
+            left -= until;
+            self.alarm.set(None);
+            self.client.map(|client| client.alarm());
+        }
+        self.now.set(self.now.get().wrapping_add(T::from(left)));
+    }
+
+    /// Advance to the armed alarm and fire it. Returns the ticks that
+    /// passed, or `None` if the alarm is not armed.
+    pub fn fire(&self) -> Option<u32> {
+        let until = self.until_alarm()?;
+        self.advance(until);
+        Some(until)
+    }
+
+    /// The ticks until the armed alarm expires, `0` if it already has.
+    pub fn until_alarm(&self) -> Option<u32> {
+        self.alarm.get().map(|(reference, dt)| {
+            let elapsed = self.now.get().wrapping_sub(reference).into_u32();
+            dt.into_u32().saturating_sub(elapsed)
+        })
+    }
+}
+
+impl<T: Ticks, F: Frequency> Time for MockAlarm<'_, T, F> {
+    type Frequency = F;
+    type Ticks = T;
+
+    fn now(&self) -> T {
+        self.now.get()
+    }
+}
+
+impl<'a, T: Ticks, F: Frequency> Alarm<'a> for MockAlarm<'a, T, F> {
+    fn set_alarm_client(&'a self, client: &'a dyn AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: T, dt: T) {
+        self.alarm.set(Some((reference, dt)));
+    }
+
+    fn get_alarm(&self) -> T {
+        self.alarm
+            .get()
+            .map_or(T::from(0), |(reference, dt)| reference.wrapping_add(dt))
+    }
+
+    fn disarm(&self) -> Result<(), ErrorCode> {
+        self.alarm.set(None);
+        Ok(())
+    }
+
+    fn is_armed(&self) -> bool {
+        self.alarm.get().is_some()
+    }
+
+    fn minimum_dt(&self) -> T {
+        T::from(1)
+    }
+}
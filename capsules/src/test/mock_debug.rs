@@ -0,0 +1,70 @@
+//! A debug writer for unit tests of capsules that print with `debug!()`.
+//!
+//! `debug!()` goes through a single writer for the whole kernel, so a test
+//! that reaches it first takes the lock, which installs a writer that throws
+//! its output away and keeps other tests from printing at the same time.
+//!
+//! ```rust,ignore
+//! let _debug = mock_debug::lock();
+//! driver.command(1, 0, 0, app.processid());
+//! ```
+
+#![allow(unsafe_code)]
+
+extern crate std;
+
+use std::boxed::Box;
+use std::sync::{Mutex, MutexGuard, Once, PoisonError};
+
+use kernel::common::RingBuffer;
+use kernel::debug::{self, DebugWriter, DebugWriterWrapper};
+use kernel::hil::uart;
+use kernel::ErrorCode;
+
+use super::mock_process;
+
+static INIT: Once = Once::new();
+static mut LOCK: Option<Mutex<()>> = None;
+
+/// A UART that refuses every transmission, so the debug writer drops what
+/// it would have sent.
+struct DiscardUart;
+
+impl<'a> uart::Transmit<'a> for DiscardUart {
+    fn set_transmit_client(&self, _client: &'a dyn uart::TransmitClient) {}
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        _tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        Err((ErrorCode::OFF, tx_buffer))
+    }
+
+    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::OFF)
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::OFF)
+    }
+}
+
+/// Let the calling test use `debug!()` until the returned guard is dropped.
+pub fn lock() -> MutexGuard<'static, ()> {
+    INIT.call_once(|| unsafe {
+        LOCK = Some(Mutex::new(()));
+        let ring_buffer = Box::leak(Box::new(RingBuffer::new(mock_process::buffer(256))));
+        let writer = mock_process::leak(DebugWriter::new(
+            mock_process::leak(DiscardUart),
+            mock_process::buffer(64),
+            ring_buffer,
+        ));
+        debug::set_debug_writer_wrapper(Box::leak(Box::new(DebugWriterWrapper::new(writer))));
+    });
+    // A test that failed while holding the lock leaves nothing to clean up.
+    unsafe { LOCK.as_ref() }
+        .unwrap()
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,603 @@
+//! A fake process for unit tests of capsules.
+//!
+//! `MockProcess` implements just enough of `Process` for a capsule to use its
+//! grants, read and write buffers it allowed, and schedule its upcalls. The
+//! upcalls are recorded so tests can check them.
+//!
+//! ```rust,ignore
+//! let (kernel, processes) = mock_process::kernel(&["app"]);
+//! let driver = Driver::new(mock_process::grant(kernel));
+//! let app = processes[0];
+//! driver.subscribe(0, app.upcall(DRIVER_NUM, 0), app.processid());
+//! driver.command(1, 0, 0, app.processid());
+//! assert_eq!(app.take_upcalls(), [(0, 0, 0, 0)]);
+//! ```
+
+#![allow(unsafe_code)]
+
+extern crate std;
+
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+use core::ptr::NonNull;
+use std::boxed::Box;
+use std::vec;
+use std::vec::Vec;
+
+use kernel::capabilities;
+use kernel::common::cells::OptionalCell;
+use kernel::mpu;
+use kernel::procs::{
+    Error, FaultInfo, FunctionCall, FunctionCallSource, Process, ProcessCustomGrantIdentifer,
+    State, Task,
+};
+use kernel::syscall::{ContextSwitchReason, Syscall, SyscallReturn};
+use kernel::{ErrorCode, Grant, Kernel, ProcessId, ReadOnlyAppSlice, ReadWriteAppSlice};
+use kernel::{Upcall, UpcallId};
+
+/// The capabilities the tests need, to build mock processes and to hand to
+/// capsules.
+pub struct Capability;
+unsafe impl capabilities::CreatePortTableCapability for Capability {}
+unsafe impl capabilities::ExternalProcessCapability for Capability {}
+unsafe impl capabilities::MemoryAllocationCapability for Capability {}
+unsafe impl capabilities::NetworkCapabilityCreationCapability for Capability {}
+unsafe impl capabilities::PeripheralAccessCapability for Capability {}
+unsafe impl capabilities::ProcessManagementCapability for Capability {}
+unsafe impl capabilities::UdpDriverCapability for Capability {}
+
+/// Where the flash region of the first process starts. Each process gets
+/// `FLASH_LEN` bytes of flash, after those of the processes before it, and
+/// keeps them when it restarts.
+const FLASH_START: usize = 0x40000;
+const FLASH_LEN: usize = 0x10000;
+/// How much further into flash an app replacing a process is loaded, so
+/// that it never shares a flash region with an earlier app.
+const FLASH_REPLACED_OFFSET: usize = 0x100_0000;
+/// Length of the protected header at the start of the flash region.
+const FLASH_HEADER_LEN: usize = 0x100;
+
+/// A process identifier never used before by the tests of this thread, so
+/// that a restarted process gets a new `ProcessId`.
+fn next_identifier() -> usize {
+    std::thread_local! {
+        static NEXT: Cell<usize> = Cell::new(0);
+    }
+    NEXT.with(|next| {
+        let identifier = next.get();
+        next.set(identifier + 1);
+        identifier
+    })
+}
+
+/// A kernel with a process for each of `names`, in that order.
+pub fn kernel(names: &[&'static str]) -> (&'static Kernel, Vec<&'static MockProcess>) {
+    let processes: Vec<&'static MockProcess> = names
+        .iter()
+        .enumerate()
+        .map(|(index, name)| &*Box::leak(Box::new(MockProcess::new(index, name))))
+        .collect();
+    let table: Vec<Option<&'static dyn Process>> = processes
+        .iter()
+        .map(|process| Some(*process as &'static dyn Process))
+        .collect();
+    let kernel: &'static Kernel =
+        Box::leak(Box::new(Kernel::new(Box::leak(table.into_boxed_slice()))));
+    for process in processes.iter() {
+        process.kernel.set(kernel);
+    }
+    (kernel, processes)
+}
+
+/// A new grant of `kernel`.
+pub fn grant<T: Default>(kernel: &'static Kernel) -> Grant<T> {
+    kernel.create_grant(&Capability)
+}
+
+/// A buffer of `len` bytes for the kernel side of a capsule.
+pub fn buffer(len: usize) -> &'static mut [u8] {
+    Box::leak(vec![0; len].into_boxed_slice())
+}
+
+/// Keep `value` for the rest of the test, for capsules and mocks that need
+/// `'static` references to each other.
+pub fn leak<T>(value: T) -> &'static T {
+    Box::leak(Box::new(value))
+}
+
+struct GrantRegion {
+    ptr: NonNull<u8>,
+    entered: bool,
+}
+
+pub struct MockProcess {
+    kernel: OptionalCell<&'static Kernel>,
+    index: usize,
+    identifier: Cell<usize>,
+    name: Cell<&'static str>,
+    flash_start: Cell<usize>,
+    active: Cell<bool>,
+    grants: RefCell<Vec<Option<GrantRegion>>>,
+    upcalls: RefCell<Vec<(UpcallId, usize, usize, usize)>>,
+}
+
+impl MockProcess {
+    fn new(index: usize, name: &'static str) -> MockProcess {
+        MockProcess {
+            kernel: OptionalCell::empty(),
+            index: index,
+            identifier: Cell::new(next_identifier()),
+            name: Cell::new(name),
+            flash_start: Cell::new(FLASH_START + index * FLASH_LEN),
+            active: Cell::new(true),
+            grants: RefCell::new(Vec::new()),
+            upcalls: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// The `ProcessId` of the process, which changes when it restarts.
+    pub fn processid(&self) -> ProcessId {
+        Process::processid(self)
+    }
+
+    /// An upcall for subscribe `subscribe_num` of driver `driver_num`.
+    pub fn upcall(&self, driver_num: usize, subscribe_num: usize) -> Upcall {
+        Upcall::new_external(
+            self.processid(),
+            UpcallId {
+                driver_num: driver_num,
+                subscribe_num: subscribe_num,
+            },
+            0,
+            NonNull::dangling(),
+            &Capability,
+        )
+    }
+
+    /// A read-only buffer of the process holding `data`.
+    pub fn readonly_slice(&self, data: &[u8]) -> ReadOnlyAppSlice {
+        let memory: &'static mut [u8] = Box::leak(data.to_vec().into_boxed_slice());
+        // The leaked memory is only reachable through the slice.
+        unsafe {
+            ReadOnlyAppSlice::new_external(
+                memory.as_ptr(),
+                memory.len(),
+                self.processid(),
+                &Capability,
+            )
+        }
+    }
+
+    /// A read-write buffer of the process holding `data`.
+    pub fn readwrite_slice(&self, data: &[u8]) -> ReadWriteAppSlice {
+        let memory: &'static mut [u8] = Box::leak(data.to_vec().into_boxed_slice());
+        // The leaked memory is only reachable through the slice.
+        unsafe {
+            ReadWriteAppSlice::new_external(
+                memory.as_mut_ptr(),
+                memory.len(),
+                self.processid(),
+                &Capability,
+            )
+        }
+    }
+
+    /// The upcalls scheduled since the last call, as their subscribe number
+    /// and arguments.
+    pub fn take_upcalls(&self) -> Vec<(usize, usize, usize, usize)> {
+        self.upcalls
+            .borrow_mut()
+            .drain(..)
+            .map(|(id, r0, r1, r2)| (id.subscribe_num, r0, r1, r2))
+            .collect()
+    }
+
+    /// Restart the process: it gets a new `ProcessId`, and its grants and
+    /// upcalls are cleared.
+    pub fn restart(&self) {
+        self.identifier.set(next_identifier());
+        self.grants.borrow_mut().clear();
+        self.upcalls.borrow_mut().clear();
+        self.active.set(true);
+    }
+
+    /// Replace the app with the app `name`, loaded from another flash region
+    /// into the same place in the processes array. It starts like a
+    /// restarted process.
+    pub fn replace(&self, name: &'static str) {
+        self.name.set(name);
+        self.flash_start
+            .set(self.flash_start.get() + FLASH_REPLACED_OFFSET);
+        self.restart();
+    }
+}
+
+impl Process for MockProcess {
+    fn processid(&self) -> ProcessId {
+        ProcessId::new_external(
+            self.kernel.expect("process of no kernel"),
+            self.identifier.get(),
+            self.index,
+            &Capability,
+        )
+    }
+
+    fn enqueue_task(&self, task: Task) -> bool {
+        if !self.active.get() {
+            return false;
+        }
+        match task {
+            Task::FunctionCall(FunctionCall {
+                source: FunctionCallSource::Driver(id),
+                argument0,
+                argument1,
+                argument2,
+                ..
+            }) => {
+                self.upcalls
+                    .borrow_mut()
+                    .push((id, argument0, argument1, argument2));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ready(&self) -> bool {
+        false
+    }
+
+    fn has_tasks(&self) -> bool {
+        !self.upcalls.borrow().is_empty()
+    }
+
+    fn dequeue_task(&self) -> Option<Task> {
+        None
+    }
+
+    fn remove_pending_upcalls(&self, upcall_id: UpcallId) {
+        self.upcalls
+            .borrow_mut()
+            .retain(|(id, _, _, _)| *id != upcall_id);
+    }
+
+    fn get_state(&self) -> State {
+        if self.active.get() {
+            State::Yielded
+        } else {
+            State::Terminated
+        }
+    }
+
+    fn set_yielded_state(&self) {}
+
+    fn stop(&self) {}
+
+    fn resume(&self) {}
+
+    fn set_fault_state(&self) {
+        self.active.set(false);
+    }
+
+    fn record_fault(&self) {}
+
+    fn get_last_fault(&self) -> Option<FaultInfo> {
+        None
+    }
+
+    fn get_restart_count(&self) -> usize {
+        0
+    }
+
+    fn get_process_name(&self) -> &'static str {
+        self.name.get()
+    }
+
+    fn credentials_verified(&self) -> bool {
+        false
+    }
+
+    fn get_priority(&self) -> u32 {
+        u32::MAX
+    }
+
+    fn get_permitted_drivers(&self) -> Option<&[u32]> {
+        None
+    }
+
+    fn inherit_priority(&self, _priority: u32) {}
+
+    fn terminate(&self, _completion_code: u32) {
+        self.active.set(false);
+        self.grants.borrow_mut().clear();
+        self.upcalls.borrow_mut().clear();
+    }
+
+    fn try_restart(&self, _completion_code: u32) {
+        self.restart();
+    }
+
+    fn brk(&self, _new_break: *const u8) -> Result<*const u8, Error> {
+        Err(Error::KernelError)
+    }
+
+    fn sbrk(&self, _increment: isize) -> Result<*const u8, Error> {
+        Err(Error::KernelError)
+    }
+
+    fn mem_start(&self) -> *const u8 {
+        core::ptr::null()
+    }
+
+    fn mem_end(&self) -> *const u8 {
+        core::ptr::null()
+    }
+
+    fn flash_start(&self) -> *const u8 {
+        self.flash_start.get() as *const u8
+    }
+
+    fn flash_end(&self) -> *const u8 {
+        (self.flash_start.get() + FLASH_LEN) as *const u8
+    }
+
+    fn kernel_memory_break(&self) -> *const u8 {
+        core::ptr::null()
+    }
+
+    fn app_memory_break(&self) -> *const u8 {
+        core::ptr::null()
+    }
+
+    fn number_writeable_flash_regions(&self) -> usize {
+        0
+    }
+
+    fn get_writeable_flash_region(&self, _region_index: usize) -> (u32, u32) {
+        (0, 0)
+    }
+
+    fn update_stack_start_pointer(&self, _stack_pointer: *const u8) {}
+
+    fn update_heap_start_pointer(&self, _heap_pointer: *const u8) {}
+
+    fn build_readwrite_appslice(
+        &self,
+        _buf_start_addr: *mut u8,
+        _size: usize,
+    ) -> Result<ReadWriteAppSlice, ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn build_readonly_appslice(
+        &self,
+        _buf_start_addr: *const u8,
+        _size: usize,
+    ) -> Result<ReadOnlyAppSlice, ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    unsafe fn set_byte(&self, _addr: *mut u8, _value: u8) -> bool {
+        false
+    }
+
+    fn flash_non_protected_start(&self) -> *const u8 {
+        (self.flash_start.get() + FLASH_HEADER_LEN) as *const u8
+    }
+
+    fn setup_mpu(&self) {}
+
+    fn add_mpu_region(
+        &self,
+        _unallocated_memory_start: *const u8,
+        _unallocated_memory_size: usize,
+        _min_region_size: usize,
+    ) -> Option<mpu::Region> {
+        None
+    }
+
+    fn add_read_only_mpu_region(
+        &self,
+        _unallocated_memory_start: *const u8,
+        _unallocated_memory_size: usize,
+        _min_region_size: usize,
+    ) -> Option<mpu::Region> {
+        None
+    }
+
+    fn remove_mpu_region(&self, _region: mpu::Region) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn allocate_grant(&self, grant_num: usize, size: usize, align: usize) -> Option<NonNull<u8>> {
+        if !self.active.get() {
+            return None;
+        }
+        let mut grants = self.grants.borrow_mut();
+        if grants.len() <= grant_num {
+            grants.resize_with(grant_num + 1, || None);
+        }
+        if grants[grant_num].is_some() {
+            return None;
+        }
+        let memory: &'static mut [u8] = Box::leak(vec![0; size + align].into_boxed_slice());
+        let offset = memory.as_ptr().align_offset(align);
+        let ptr = NonNull::new(memory[offset..].as_mut_ptr())?;
+        grants[grant_num] = Some(GrantRegion {
+            ptr: ptr,
+            entered: false,
+        });
+        Some(ptr)
+    }
+
+    fn grant_is_allocated(&self, grant_num: usize) -> Option<bool> {
+        if !self.active.get() {
+            return None;
+        }
+        Some(
+            self.grants
+                .borrow()
+                .get(grant_num)
+                .map_or(false, |grant| grant.is_some()),
+        )
+    }
+
+    fn allocate_custom_grant(
+        &self,
+        _size: usize,
+        _align: usize,
+    ) -> Option<(ProcessCustomGrantIdentifer, NonNull<u8>)> {
+        None
+    }
+
+    fn enter_grant(&self, grant_num: usize) -> Result<*mut u8, Error> {
+        if !self.active.get() {
+            return Err(Error::InactiveApp);
+        }
+        match self.grants.borrow_mut().get_mut(grant_num) {
+            Some(Some(grant)) => {
+                if grant.entered {
+                    Err(Error::AlreadyInUse)
+                } else {
+                    grant.entered = true;
+                    Ok(grant.ptr.as_ptr())
+                }
+            }
+            _ => Err(Error::AddressOutOfBounds),
+        }
+    }
+
+    fn enter_custom_grant(
+        &self,
+        _identifier: ProcessCustomGrantIdentifer,
+    ) -> Result<*mut u8, Error> {
+        Err(Error::KernelError)
+    }
+
+    fn leave_grant(&self, grant_num: usize) {
+        if let Some(Some(grant)) = self.grants.borrow_mut().get_mut(grant_num) {
+            grant.entered = false;
+        }
+    }
+
+    fn grant_allocated_count(&self) -> Option<usize> {
+        Some(self.grants.borrow().iter().filter(|g| g.is_some()).count())
+    }
+
+    fn grant_allocated_size(&self, _grant_num: usize) -> Option<usize> {
+        None
+    }
+
+    fn set_syscall_return_value(&self, _return_value: SyscallReturn) {}
+
+    fn set_process_function(&self, _callback: FunctionCall) {}
+
+    fn switch_to(&self) -> Option<ContextSwitchReason> {
+        None
+    }
+
+    fn print_memory_map(&self, _writer: &mut dyn Write) {}
+
+    fn print_full_process(&self, _writer: &mut dyn Write) {}
+
+    fn debug_stack_snapshot(&self, _buffer: &mut [u8]) -> Option<(*const u8, usize)> {
+        None
+    }
+
+    fn debug_syscall_count(&self) -> usize {
+        0
+    }
+
+    fn debug_dropped_upcall_count(&self) -> usize {
+        0
+    }
+
+    fn debug_timeslice_expiration_count(&self) -> usize {
+        0
+    }
+
+    fn debug_timeslice_expired(&self) {}
+
+    fn debug_syscall_called(&self, _last_syscall: Syscall) {}
+
+    fn debug_run_count(&self) -> usize {
+        0
+    }
+
+    fn debug_stack_high_water_mark(&self) -> Option<usize> {
+        None
+    }
+
+    fn debug_execution_time_us(&self) -> u64 {
+        0
+    }
+
+    fn debug_executed(&self, _execution_time_us: Option<u32>) {}
+
+    fn debug_set_syscall_tracing(&self, _enabled: bool) {}
+
+    fn debug_syscall_tracing(&self) -> bool {
+        false
+    }
+
+    fn debug_trace_syscall(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use kernel::Read;
+
+    #[derive(Default)]
+    struct AppData {
+        upcall: Upcall,
+        entered: usize,
+    }
+
+    #[test]
+    fn test_grant_upcalls_and_slices() {
+        let (kernel, processes) = kernel(&["first", "second"]);
+        let grant: Grant<AppData> = grant(kernel);
+        let (first, second) = (processes[0], processes[1]);
+
+        for (process, subscribe_num) in [(first, 0), (second, 1)].iter() {
+            assert!(grant
+                .enter(process.processid(), |data| {
+                    data.upcall = process.upcall(7, *subscribe_num);
+                    data.entered += 1;
+                })
+                .is_ok());
+        }
+        assert!(grant
+            .enter(first.processid(), |data| {
+                assert_eq!(data.entered, 1);
+                data.upcall.schedule(1, 2, 3);
+            })
+            .is_ok());
+        assert_eq!(first.take_upcalls(), [(0, 1, 2, 3)]);
+        assert_eq!(first.take_upcalls(), []);
+        assert_eq!(second.take_upcalls(), []);
+
+        let slice = second.readonly_slice(&[4, 5, 6]);
+        assert_eq!(slice.len(), 3);
+        assert_eq!(slice.map_or(0, |data| data[2]), 6);
+        assert_eq!(buffer(4), [0; 4]);
+    }
+
+    #[test]
+    fn test_restart_clears_grants() {
+        let (kernel, processes) = kernel(&["app"]);
+        let grant: Grant<AppData> = grant(kernel);
+        let app = processes[0];
+        let before = app.processid();
+        assert!(grant.enter(before, |data| data.entered += 1).is_ok());
+
+        app.restart();
+        assert!(app.processid() != before);
+        assert!(grant
+            .enter(app.processid(), |data| assert_eq!(data.entered, 0))
+            .is_ok());
+    }
+}
@@ -10,3 +10,10 @@ pub mod rng;
 pub mod udp;
 pub mod virtual_rng;
 pub mod virtual_uart;
+
+#[cfg(test)]
+pub(crate) mod mock_alarm;
+#[cfg(test)]
+pub(crate) mod mock_debug;
+#[cfg(test)]
+pub(crate) mod mock_process;
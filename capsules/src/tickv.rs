@@ -120,7 +120,7 @@ pub struct TicKVStore<'a, F: Flash + 'static> {
     operation: Cell<Operation>,
     next_operation: Cell<Operation>,
 
-    value_buffer: Cell<Option<&'static [u8]>>,
+    value_buffer: TakeCell<'static, [u8]>,
     key_buffer: TakeCell<'static, [u8; 8]>,
     ret_buffer: TakeCell<'static, [u8]>,
 
@@ -145,7 +145,7 @@ impl<'a, F: Flash> TicKVStore<'a, F> {
             tickv,
             operation: Cell::new(Operation::None),
             next_operation: Cell::new(Operation::None),
-            value_buffer: Cell::new(None),
+            value_buffer: TakeCell::empty(),
             key_buffer: TakeCell::empty(),
             ret_buffer: TakeCell::empty(),
             client: OptionalCell::empty(),
@@ -261,14 +261,41 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
                     self.operation.set(Operation::None);
                 }
-                _ => {}
+                Err(tickv::error_codes::ErrorCode::ReadNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::WriteNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::EraseNotReady(_))
+                | Ok(_) => {}
+                Err(_) => {
+                    // For example the key already exists
+                    self.operation.set(Operation::None);
+                    self.client.map(|cb| {
+                        cb.append_key_complete(
+                            Err(ErrorCode::FAIL),
+                            self.key_buffer.take().unwrap(),
+                            self.tickv.get_stored_value_buffer().unwrap(),
+                        );
+                    });
+                }
             },
             Operation::InvalidateKey => match ret {
                 Ok(tickv::success_codes::SuccessCode::Complete)
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
                     self.operation.set(Operation::None);
                 }
-                _ => {}
+                Err(tickv::error_codes::ErrorCode::ReadNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::WriteNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::EraseNotReady(_))
+                | Ok(_) => {}
+                Err(_) => {
+                    // For example the key doesn't exist
+                    self.operation.set(Operation::None);
+                    self.client.map(|cb| {
+                        cb.invalidate_key_complete(
+                            Err(ErrorCode::FAIL),
+                            self.key_buffer.take().unwrap(),
+                        );
+                    });
+                }
             },
             Operation::GarbageCollect => match ret {
                 Ok(tickv::success_codes::SuccessCode::Complete)
@@ -370,8 +397,15 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
     fn append_key(
         &self,
         key: &'static mut Self::K,
-        value: &'static [u8],
-    ) -> Result<(), (&'static mut Self::K, &'static [u8], Result<(), ErrorCode>)> {
+        value: &'static mut [u8],
+    ) -> Result<
+        (),
+        (
+            &'static mut Self::K,
+            &'static mut [u8],
+            Result<(), ErrorCode>,
+        ),
+    > {
         match self.operation.get() {
             Operation::None => {
                 self.operation.set(Operation::AppendKey);
@@ -387,7 +421,11 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                             self.key_buffer.replace(key);
                             Ok(())
                         }
-                        _ => Err((key, value, Err(ErrorCode::FAIL))),
+                        _ => Err((
+                            key,
+                            self.tickv.get_stored_value_buffer().unwrap(),
+                            Err(ErrorCode::FAIL),
+                        )),
                     },
                 }
             }
@@ -396,7 +434,7 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                 // We can save this request and start it after init
                 self.next_operation.set(Operation::AppendKey);
                 self.key_buffer.replace(key);
-                self.value_buffer.replace(Some(value));
+                self.value_buffer.replace(value);
                 Ok(())
             }
             _ => {
@@ -10,9 +10,14 @@
 //! let touch =
 //!     components::touch::TouchComponent::new(board_kernel, ts, Some(ts), Some(screen)).finalize(());
 //! ```
+//!
+//! Gestures can also be recognized in the kernel from the touches, for
+//! panels that do not recognize them. The recognizer receives the touches
+//! after their rotation, see `touch_gesture`.
 
 use core::cell::Cell;
 use core::mem;
+use kernel::common::cells::OptionalCell;
 use kernel::hil;
 use kernel::hil::screen::ScreenRotation;
 use kernel::hil::touch::{GestureEvent, TouchEvent, TouchStatus};
@@ -20,6 +25,8 @@ use kernel::{
     CommandReturn, Driver, ErrorCode, Grant, ProcessId, ReadWrite, ReadWriteAppSlice, Upcall,
 };
 
+use crate::touch_gesture::{GestureRecognizerClient, SwipeDirection, TouchGesture};
+
 /// Syscall driver number.
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Touch as usize;
@@ -37,6 +44,7 @@ pub struct App {
     touch_callback: Upcall,
     gesture_callback: Upcall,
     multi_touch_callback: Upcall,
+    recognized_gesture_callback: Upcall,
     events_buffer: ReadWriteAppSlice,
     ack: bool,
     dropped_events: usize,
@@ -53,6 +61,7 @@ impl Default for App {
             touch_callback: Upcall::default(),
             gesture_callback: Upcall::default(),
             multi_touch_callback: Upcall::default(),
+            recognized_gesture_callback: Upcall::default(),
             events_buffer: ReadWriteAppSlice::default(),
             ack: true,
             dropped_events: 0,
@@ -76,6 +85,8 @@ pub struct Touch<'a> {
     screen: Option<&'a dyn hil::screen::Screen>,
     apps: Grant<App>,
     screen_rotation_offset: Cell<ScreenRotation>,
+    /// Recognizes gestures from the rotated touches
+    gesture_recognizer: OptionalCell<&'a dyn hil::touch::MultiTouchClient>,
 }
 
 impl<'a> Touch<'a> {
@@ -91,9 +102,16 @@ impl<'a> Touch<'a> {
             screen: screen,
             screen_rotation_offset: Cell::new(ScreenRotation::Normal),
             apps: grant,
+            gesture_recognizer: OptionalCell::empty(),
         }
     }
 
+    /// Feed the touches to `recognizer`, which reports the gestures it
+    /// recognizes back to this driver.
+    pub fn set_gesture_recognizer(&self, recognizer: &'a dyn hil::touch::MultiTouchClient) {
+        self.gesture_recognizer.set(recognizer);
+    }
+
     pub fn set_screen_rotation_offset(&self, screen_rotation_offset: ScreenRotation) {
         self.screen_rotation_offset.set(screen_rotation_offset);
     }
@@ -165,6 +183,8 @@ impl<'a> hil::touch::TouchClient for Touch<'a> {
     fn touch_event(&self, mut event: TouchEvent) {
         // update rotation if there is a screen attached
         self.update_rotation(&mut event);
+        self.gesture_recognizer
+            .map(|recognizer| recognizer.touch_events(&[event], 1));
         // debug!(
         //     "touch {:?} x {} y {} size {:?} pressure {:?}",
         //     event.status, event.x, event.y, event.size, event.pressure
@@ -202,6 +222,29 @@ impl<'a> hil::touch::MultiTouchClient for Touch<'a> {
         } else {
             num_events
         };
+        self.gesture_recognizer.map(|recognizer| {
+            // the recognizer only follows the first two touches
+            let mut events = [TouchEvent {
+                status: TouchStatus::Released,
+                x: 0,
+                y: 0,
+                id: 0,
+                size: None,
+                pressure: None,
+            }; 2];
+            let mut num = 0;
+            for event in touch_events[..len].iter() {
+                if num == events.len() {
+                    break;
+                }
+                if let TouchStatus::Pressed | TouchStatus::Moved = event.status {
+                    events[num] = *event;
+                    self.update_rotation(&mut events[num]);
+                    num += 1;
+                }
+            }
+            recognizer.touch_events(&events[..num], num);
+        });
         // debug!("{} touch(es)", len);
         for app in self.apps.iter() {
             app.enter(|app| {
@@ -287,6 +330,36 @@ impl<'a> hil::touch::GestureClient for Touch<'a> {
     }
 }
 
+impl<'a> GestureRecognizerClient for Touch<'a> {
+    fn gesture(&self, gesture: TouchGesture) {
+        let (kind, data1, data2) = match gesture {
+            TouchGesture::Tap { x, y } => (1, (x as usize) << 16 | y as usize, 0),
+            TouchGesture::DoubleTap { x, y } => (2, (x as usize) << 16 | y as usize, 0),
+            TouchGesture::Swipe {
+                direction,
+                distance,
+            } => {
+                let direction = match direction {
+                    SwipeDirection::Up => 1,
+                    SwipeDirection::Down => 2,
+                    SwipeDirection::Left => 3,
+                    SwipeDirection::Right => 4,
+                };
+                (3, direction, distance as usize)
+            }
+            TouchGesture::Pinch {
+                start_distance,
+                end_distance,
+            } => (4, start_distance as usize, end_distance as usize),
+        };
+        for app in self.apps.iter() {
+            app.enter(|app| {
+                app.recognized_gesture_callback.schedule(kind, data1, data2);
+            });
+        }
+    }
+}
+
 impl<'a> Driver for Touch<'a> {
     fn allow_readwrite(
         &self,
@@ -368,6 +441,23 @@ impl<'a> Driver for Touch<'a> {
                     Err(ErrorCode::NOSUPPORT)
                 }
             }
+
+            // subscribe to gestures recognized from the touches
+            3 => {
+                if self.gesture_recognizer.is_some() {
+                    let r = self
+                        .apps
+                        .enter(app_id, |app| {
+                            mem::swap(&mut app.recognized_gesture_callback, &mut callback);
+                        })
+                        .map_err(ErrorCode::from);
+                    let _ = self.touch_enable();
+                    let _ = self.multi_touch_enable();
+                    r
+                } else {
+                    Err(ErrorCode::NOSUPPORT)
+                }
+            }
             _ => Err(ErrorCode::NOSUPPORT),
         };
 
@@ -0,0 +1,279 @@
+//! Recognizes gestures from the touches of a touch panel.
+//!
+//! The recognizer follows each interaction with the panel, from the first
+//! touch until all touches are released, and then decodes it:
+//!
+//! - A tap is a short touch that does not move. A second tap near the first
+//!   one within `DOUBLE_TAP_MS` makes a double tap instead, so a tap is only
+//!   reported once that time has passed.
+//! - A swipe is a single touch that moves at least `SWIPE_MIN_DISTANCE`
+//!   pixels. Its direction is that of the larger of its horizontal and
+//!   vertical movements, with `y` growing downwards.
+//! - A pinch is an interaction with two touches, whose distance changed by
+//!   at least `PINCH_MIN_CHANGE` pixels.
+//!
+//! The touch driver feeds the recognizer with the touches after it rotated
+//! them for the screen, and reports the gestures to userspace.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let gesture_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, stm32f412g::tim2::Tim2>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let recognizer = static_init!(
+//!     capsules::touch_gesture::GestureRecognizer<'static, VirtualMuxAlarm<'static, stm32f412g::tim2::Tim2>>,
+//!     capsules::touch_gesture::GestureRecognizer::new(gesture_alarm)
+//! );
+//! gesture_alarm.set_alarm_client(recognizer);
+//! touch.set_gesture_recognizer(recognizer);
+//! recognizer.set_client(touch);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::hil::touch::{MultiTouchClient, TouchEvent, TouchStatus};
+
+/// Distance in pixels a tap may move.
+pub const TAP_SLOP: u32 = 16;
+/// Longest touch that is a tap.
+pub const TAP_MAX_MS: u32 = 300;
+/// Longest time between the taps of a double tap, and their largest distance
+/// in pixels.
+pub const DOUBLE_TAP_MS: u32 = 300;
+pub const DOUBLE_TAP_SLOP: u32 = 32;
+/// Shortest swipe in pixels.
+pub const SWIPE_MIN_DISTANCE: u32 = 40;
+/// Smallest change of the distance between the touches of a pinch, in pixels.
+pub const PINCH_MIN_CHANGE: u32 = 30;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SwipeDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// A recognized gesture, in screen coordinates.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TouchGesture {
+    Tap {
+        x: u16,
+        y: u16,
+    },
+    DoubleTap {
+        x: u16,
+        y: u16,
+    },
+    Swipe {
+        direction: SwipeDirection,
+        distance: u32,
+    },
+    /// The touches moved apart to zoom in, or together to zoom out.
+    Pinch {
+        start_distance: u32,
+        end_distance: u32,
+    },
+}
+
+pub trait GestureRecognizerClient {
+    fn gesture(&self, gesture: TouchGesture);
+}
+
+/// Integer square root of `n`.
+fn isqrt(n: u32) -> u32 {
+    let mut root = 0;
+    let mut bit = 1 << 30;
+    let mut n = n;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if n >= root + bit {
+            n -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+fn distance(a: (u16, u16), b: (u16, u16)) -> u32 {
+    let dx = (a.0 as i32 - b.0 as i32).abs() as u32;
+    let dy = (a.1 as i32 - b.1 as i32).abs() as u32;
+    isqrt(dx * dx + dy * dy)
+}
+
+fn is_active(event: &&TouchEvent) -> bool {
+    match event.status {
+        TouchStatus::Pressed | TouchStatus::Moved => true,
+        TouchStatus::Released | TouchStatus::Unstarted => false,
+    }
+}
+
+pub struct GestureRecognizer<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn GestureRecognizerClient>,
+
+    // The interaction in progress, if `active`.
+    active: Cell<bool>,
+    start: Cell<(u16, u16)>,
+    last: Cell<(u16, u16)>,
+    start_time: Cell<A::Ticks>,
+    max_touches: Cell<usize>,
+    moved: Cell<bool>,
+    // Distances between the first two touches, once there were two.
+    pinch_start: OptionalCell<u32>,
+    pinch_last: Cell<u32>,
+
+    // A tap that may become a double tap until the alarm fires.
+    pending_tap: OptionalCell<(u16, u16)>,
+}
+
+impl<'a, A: Alarm<'a>> GestureRecognizer<'a, A> {
+    pub fn new(alarm: &'a A) -> GestureRecognizer<'a, A> {
+        GestureRecognizer {
+            alarm: alarm,
+            client: OptionalCell::empty(),
+            active: Cell::new(false),
+            start: Cell::new((0, 0)),
+            last: Cell::new((0, 0)),
+            start_time: Cell::new(A::Ticks::from(0)),
+            max_touches: Cell::new(0),
+            moved: Cell::new(false),
+            pinch_start: OptionalCell::empty(),
+            pinch_last: Cell::new(0),
+            pending_tap: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn GestureRecognizerClient) {
+        self.client.set(client);
+    }
+
+    fn report(&self, gesture: TouchGesture) {
+        self.client.map(|client| client.gesture(gesture));
+    }
+
+    /// Decode the interaction that just ended.
+    fn end(&self) {
+        if self.max_touches.get() >= 2 {
+            self.pinch_start.map(|start| {
+                let end = self.pinch_last.get();
+                if (*start as i32 - end as i32).abs() as u32 >= PINCH_MIN_CHANGE {
+                    self.report(TouchGesture::Pinch {
+                        start_distance: *start,
+                        end_distance: end,
+                    });
+                }
+            });
+            return;
+        }
+
+        let (start, last) = (self.start.get(), self.last.get());
+        if self.moved.get() {
+            let dx = last.0 as i32 - start.0 as i32;
+            let dy = last.1 as i32 - start.1 as i32;
+            let distance = distance(start, last);
+            if distance >= SWIPE_MIN_DISTANCE {
+                let direction = if dx.abs() > dy.abs() {
+                    if dx > 0 {
+                        SwipeDirection::Right
+                    } else {
+                        SwipeDirection::Left
+                    }
+                } else if dy > 0 {
+                    SwipeDirection::Down
+                } else {
+                    SwipeDirection::Up
+                };
+                self.report(TouchGesture::Swipe {
+                    direction: direction,
+                    distance: distance,
+                });
+            }
+            return;
+        }
+
+        let now = self.alarm.now();
+        if now.wrapping_sub(self.start_time.get()) > A::ticks_from_ms(TAP_MAX_MS) {
+            // A long press.
+            return;
+        }
+        match self.pending_tap.take() {
+            Some(tap) if distance(tap, start) <= DOUBLE_TAP_SLOP => {
+                let _ = self.alarm.disarm();
+                self.report(TouchGesture::DoubleTap {
+                    x: start.0,
+                    y: start.1,
+                });
+            }
+            pending => {
+                if let Some((x, y)) = pending {
+                    self.report(TouchGesture::Tap { x: x, y: y });
+                }
+                self.pending_tap.set(start);
+                self.alarm.set_alarm(now, A::ticks_from_ms(DOUBLE_TAP_MS));
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> MultiTouchClient for GestureRecognizer<'a, A> {
+    fn touch_events(&self, touch_events: &[TouchEvent], len: usize) {
+        let mut touches = touch_events.iter().take(len).filter(is_active);
+        let first = match touches.next() {
+            Some(first) => first,
+            None => {
+                if self.active.get() {
+                    self.active.set(false);
+                    self.end();
+                }
+                return;
+            }
+        };
+        let second = touches.next();
+        let count = 1 + second.map_or(0, |_| 1 + touches.count());
+        let position = (first.x, first.y);
+
+        if !self.active.get() {
+            self.active.set(true);
+            self.start.set(position);
+            self.start_time.set(self.alarm.now());
+            self.max_touches.set(0);
+            self.moved.set(false);
+            self.pinch_start.clear();
+        }
+        if count > self.max_touches.get() {
+            self.max_touches.set(count);
+        }
+
+        self.last.set(position);
+        if distance(self.start.get(), position) > TAP_SLOP {
+            self.moved.set(true);
+        }
+        if let Some(second) = second {
+            let pinch = distance(position, (second.x, second.y));
+            if self.pinch_start.is_none() {
+                self.pinch_start.set(pinch);
+            }
+            self.pinch_last.set(pinch);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for GestureRecognizer<'a, A> {
+    fn alarm(&self) {
+        self.pending_tap
+            .take()
+            .map(|(x, y)| self.report(TouchGesture::Tap { x: x, y: y }));
+    }
+}
@@ -1,17 +1,24 @@
 //! Communications Class Device for USB
 //!
-//! This capsule allows Tock to support a serial port over USB.
+//! This capsule allows Tock to support a serial port over USB. A `CdcAcm`
+//! created with `new()` is a USB device on its own, while one created with
+//! `new_function()` is a function of a `UsbComposite` device, which lets a
+//! board expose several serial ports.
 
 use core::cell::Cell;
 use core::cmp;
 use kernel::ErrorCode;
 
+use super::composite::UsbFunction;
 use super::descriptors;
 use super::descriptors::Buffer64;
 use super::descriptors::CdcInterfaceDescriptor;
+use super::descriptors::Descriptor;
 use super::descriptors::EndpointAddress;
 use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceAssociationDescriptor;
 use super::descriptors::InterfaceDescriptor;
+use super::descriptors::SetupData;
 use super::descriptors::TransferDirection;
 use super::usbc_client_ctrl::ClientCtrl;
 
@@ -32,6 +39,8 @@ const ENDPOINT_IN_NUM: usize = 2;
 /// Identifying number for the endpoint when transferring data from the host to
 /// us.
 const ENDPOINT_OUT_NUM: usize = 3;
+/// Identifying number for the notification endpoint.
+const ENDPOINT_NOTIFY_NUM: usize = 4;
 
 static LANGUAGES: &'static [u16; 1] = &[
     0x0409, // English (United States)
@@ -48,7 +57,10 @@ pub const MAX_CTRL_PACKET_SIZE_EARLGREY: u8 = 64;
 /// if a debug output is not connected.
 pub const CDC_BUFFER_TIMEOUT_MS: u32 = 10000;
 
-const N_ENDPOINTS: usize = 3;
+/// Index of the buffer of the IN endpoint in `buffers`.
+const BUFFER_IN: usize = 0;
+/// Index of the buffer of the OUT endpoint in `buffers`.
+const BUFFER_OUT: usize = 1;
 
 /// States of the CDC driver.
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -103,11 +115,23 @@ impl From<u8> for CDCCntrlMessage {
 /// Implementation of the Abstract Control Model (ACM) for the Communications
 /// Class Device (CDC) over USB.
 pub struct CdcAcm<'a, U: 'a, A: 'a + Alarm<'a>> {
-    /// Helper USB client library for handling many USB operations.
-    client_ctrl: ClientCtrl<'a, 'static, U>,
+    /// The USB hardware controller.
+    controller: &'a U,
 
-    /// 64 byte buffers for each endpoint.
-    buffers: [Buffer64; N_ENDPOINTS],
+    /// Helper USB client library for handling many USB operations. This is
+    /// `None` if the CDC-ACM is a function of a composite device, which
+    /// handles them instead.
+    client_ctrl: Option<ClientCtrl<'a, 'static, U>>,
+
+    /// Endpoints for transferring data to and from the host, which may be
+    /// the same endpoint number.
+    endpoint_in: usize,
+    endpoint_out: usize,
+    /// Endpoint for notifications to the host, which are never sent.
+    endpoint_notify: usize,
+
+    /// 64 byte buffers for the IN and OUT endpoints.
+    buffers: [Buffer64; 2],
 
     /// Current state of the CDC driver. This helps us track if a CDC client is
     /// connected and listening or not.
@@ -184,74 +208,9 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
         deferred_caller: &'a DynamicDeferredCall,
         host_initiated_function: Option<&'a (dyn Fn() + 'a)>,
     ) -> Self {
-        let interfaces: &mut [InterfaceDescriptor] = &mut [
-            InterfaceDescriptor {
-                interface_number: 0,
-                interface_class: 0x02,    // CDC communication
-                interface_subclass: 0x02, // abstract control model (ACM)
-                interface_protocol: 0x01, // V.25ter (AT commands)
-                ..InterfaceDescriptor::default()
-            },
-            InterfaceDescriptor {
-                interface_number: 1,
-                interface_class: 0x0a,    // CDC data
-                interface_subclass: 0x00, // none
-                interface_protocol: 0x00, // none
-                ..InterfaceDescriptor::default()
-            },
-        ];
-
-        let cdc_descriptors: &mut [CdcInterfaceDescriptor] = &mut [
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::Header,
-                field1: 0x10, // CDC
-                field2: 0x11, // CDC
-            },
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::CallManagement,
-                field1: 0x00, // Capabilities
-                field2: 0x01, // Data interface 1
-            },
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::AbstractControlManagement,
-                field1: 0x06, // Capabilities
-                field2: 0x00, // unused
-            },
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::Union,
-                field1: 0x00, // Interface 0
-                field2: 0x01, // Interface 1
-            },
-        ];
-
-        let endpoints: &[&[EndpointDescriptor]] = &[
-            &[EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(4, TransferDirection::DeviceToHost),
-                transfer_type: TransferType::Interrupt,
-                max_packet_size: 8,
-                interval: 16,
-            }],
-            &[
-                EndpointDescriptor {
-                    endpoint_address: EndpointAddress::new_const(
-                        2,
-                        TransferDirection::DeviceToHost,
-                    ),
-                    transfer_type: TransferType::Bulk,
-                    max_packet_size: 64,
-                    interval: 0,
-                },
-                EndpointDescriptor {
-                    endpoint_address: EndpointAddress::new_const(
-                        3,
-                        TransferDirection::HostToDevice,
-                    ),
-                    transfer_type: TransferType::Bulk,
-                    max_packet_size: 64,
-                    interval: 0,
-                },
-            ],
-        ];
+        let (mut interfaces, cdc_descriptors, notify_endpoint, data_endpoints) =
+            Self::descriptors(0, ENDPOINT_NOTIFY_NUM, ENDPOINT_IN_NUM, ENDPOINT_OUT_NUM);
+        let endpoints: &[&[EndpointDescriptor]] = &[&notify_endpoint, &data_endpoints];
 
         let (device_descriptor_buffer, other_descriptor_buffer) =
             descriptors::create_descriptor_buffers(
@@ -268,14 +227,15 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
                 descriptors::ConfigurationDescriptor {
                     ..descriptors::ConfigurationDescriptor::default()
                 },
-                interfaces,
+                &mut interfaces,
                 endpoints,
                 None, // No HID descriptor
-                Some(cdc_descriptors),
+                Some(&cdc_descriptors),
             );
 
-        Self {
-            client_ctrl: ClientCtrl::new(
+        Self::create(
+            controller,
+            Some(ClientCtrl::new(
                 controller,
                 device_descriptor_buffer,
                 other_descriptor_buffer,
@@ -283,12 +243,56 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
                 None, // No report descriptor
                 LANGUAGES,
                 strings,
-            ),
-            buffers: [
-                Buffer64::default(),
-                Buffer64::default(),
-                Buffer64::default(),
-            ],
+            )),
+            ENDPOINT_IN_NUM,
+            ENDPOINT_OUT_NUM,
+            ENDPOINT_NOTIFY_NUM,
+            timeout_alarm,
+            deferred_caller,
+            host_initiated_function,
+        )
+    }
+
+    /// Create a CDC-ACM that is a function of a composite device, which
+    /// transfers data on `data_endpoint` in both directions, and advertises
+    /// `notify_endpoint` for notifications.
+    pub fn new_function(
+        controller: &'a U,
+        notify_endpoint: usize,
+        data_endpoint: usize,
+        timeout_alarm: &'a A,
+        deferred_caller: &'a DynamicDeferredCall,
+        host_initiated_function: Option<&'a (dyn Fn() + 'a)>,
+    ) -> Self {
+        Self::create(
+            controller,
+            None,
+            data_endpoint,
+            data_endpoint,
+            notify_endpoint,
+            timeout_alarm,
+            deferred_caller,
+            host_initiated_function,
+        )
+    }
+
+    fn create(
+        controller: &'a U,
+        client_ctrl: Option<ClientCtrl<'a, 'static, U>>,
+        endpoint_in: usize,
+        endpoint_out: usize,
+        endpoint_notify: usize,
+        timeout_alarm: &'a A,
+        deferred_caller: &'a DynamicDeferredCall,
+        host_initiated_function: Option<&'a (dyn Fn() + 'a)>,
+    ) -> Self {
+        Self {
+            controller: controller,
+            client_ctrl: client_ctrl,
+            endpoint_in: endpoint_in,
+            endpoint_out: endpoint_out,
+            endpoint_notify: endpoint_notify,
+            buffers: [Buffer64::default(), Buffer64::default()],
             state: Cell::new(State::Disabled),
             ctrl_state: Cell::new(CtrlState::Idle),
             tx_buffer: TakeCell::empty(),
@@ -309,18 +313,199 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
         }
     }
 
+    /// The interface, functional and endpoint descriptors of a CDC-ACM whose
+    /// interfaces are numbered from `first_interface`.
+    fn descriptors(
+        first_interface: u8,
+        endpoint_notify: usize,
+        endpoint_in: usize,
+        endpoint_out: usize,
+    ) -> (
+        [InterfaceDescriptor; 2],
+        [CdcInterfaceDescriptor; 4],
+        [EndpointDescriptor; 1],
+        [EndpointDescriptor; 2],
+    ) {
+        let interfaces = [
+            InterfaceDescriptor {
+                interface_number: first_interface,
+                interface_class: 0x02,    // CDC communication
+                interface_subclass: 0x02, // abstract control model (ACM)
+                interface_protocol: 0x01, // V.25ter (AT commands)
+                num_endpoints: 1,
+                ..InterfaceDescriptor::default()
+            },
+            InterfaceDescriptor {
+                interface_number: first_interface + 1,
+                interface_class: 0x0a,    // CDC data
+                interface_subclass: 0x00, // none
+                interface_protocol: 0x00, // none
+                num_endpoints: 2,
+                ..InterfaceDescriptor::default()
+            },
+        ];
+
+        let cdc_descriptors = [
+            CdcInterfaceDescriptor {
+                subtype: descriptors::CdcInterfaceDescriptorSubType::Header,
+                field1: 0x10, // CDC
+                field2: 0x11, // CDC
+            },
+            CdcInterfaceDescriptor {
+                subtype: descriptors::CdcInterfaceDescriptorSubType::CallManagement,
+                field1: 0x00,                // Capabilities
+                field2: first_interface + 1, // Data interface
+            },
+            CdcInterfaceDescriptor {
+                subtype: descriptors::CdcInterfaceDescriptorSubType::AbstractControlManagement,
+                field1: 0x06, // Capabilities
+                field2: 0x00, // unused
+            },
+            CdcInterfaceDescriptor {
+                subtype: descriptors::CdcInterfaceDescriptorSubType::Union,
+                field1: first_interface,     // Communication interface
+                field2: first_interface + 1, // Data interface
+            },
+        ];
+
+        let notify_endpoint = [EndpointDescriptor {
+            endpoint_address: EndpointAddress::new(
+                endpoint_notify,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Interrupt,
+            max_packet_size: 8,
+            interval: 16,
+        }];
+
+        let data_endpoints = [
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    endpoint_in,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    endpoint_out,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+        ];
+
+        (interfaces, cdc_descriptors, notify_endpoint, data_endpoints)
+    }
+
     pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
         self.handle.replace(handle);
     }
 
     #[inline]
     pub fn controller(&self) -> &'a U {
-        self.client_ctrl.controller()
+        self.controller
     }
 
     #[inline]
     fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 64] {
-        &self.buffers[i - 1].buf
+        &self.buffers[i].buf
+    }
+
+    /// Set up the buffers for IN and OUT data transfer, and start the boot
+    /// period.
+    fn enable_endpoints(&'a self) {
+        self.controller()
+            .endpoint_set_in_buffer(self.endpoint_in, self.buffer(BUFFER_IN));
+        self.controller()
+            .endpoint_set_out_buffer(self.endpoint_out, self.buffer(BUFFER_OUT));
+        if self.endpoint_in == self.endpoint_out {
+            self.controller()
+                .endpoint_in_out_enable(TransferType::Bulk, self.endpoint_in);
+        } else {
+            self.controller()
+                .endpoint_in_enable(TransferType::Bulk, self.endpoint_in);
+            self.controller()
+                .endpoint_out_enable(TransferType::Bulk, self.endpoint_out);
+        }
+
+        self.state.set(State::Enabled);
+
+        self.timeout_alarm.set_alarm(
+            self.timeout_alarm.now(),
+            A::ticks_from_ms(CDC_BUFFER_TIMEOUT_MS),
+        );
+    }
+
+    /// Track the CDC requests of the host, which tell us when a CDC client is
+    /// connected or not.
+    fn handle_setup(&self, setup_data: &SetupData) {
+        match CDCCntrlMessage::from(setup_data.request_code) {
+            CDCCntrlMessage::SetLineCoding => {
+                self.ctrl_state.set(CtrlState::SetLineCoding);
+            }
+            CDCCntrlMessage::SetControlLineState => {
+                // Bit 0 and 1 of the value (setup_data.value) can be set
+                // D0: Indicates to DCE if DTE is present or not.
+                //     - 0 -> Not present
+                //     - 1 -> Present
+                // D1: Carrier control for half duplex modems.
+                //     - 0 -> Deactivate carrier
+                //     - 1 -> Activate carrier
+                // Currently we don't care about the value
+            }
+            CDCCntrlMessage::SendBreak => {
+                // On Mac, we seem to get the SEND_BREAK to signal that a
+                // client disconnects.
+                self.state.set(State::Enumerated)
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle the data of a Control Out transaction, received in `buf`.
+    fn handle_out(&self, buf: &[VolatileCell<u8>]) {
+        // Check what state our Ctrl endpoint is in.
+        if self.ctrl_state.get() == CtrlState::SetLineCoding {
+            // We got a Ctrl SET_LINE_CODING setup, now we are getting the data.
+            // We can parse the data we got.
+            descriptors::CdcAcmSetLineCodingData::get(buf).map(|line_coding| {
+                // Check if we should switch our main state machine to
+                // connecting meaning that the host is connecting to the virtual
+                // serial port. We decide this based on if the host is
+                // configuring the baud rate to what we expect.
+                if self.state.get() == State::Enumerated && line_coding.baud_rate == 115200 {
+                    self.state.set(State::Connecting);
+                }
+
+                // Check if the baud rate we got matches the special flag
+                // value (1200 baud). If so, we run an optional function
+                // provided when the CDC stack was configured.
+                if line_coding.baud_rate == 1200 {
+                    self.host_initiated_function.map(|f| {
+                        f();
+                    });
+                }
+            });
+        }
+    }
+
+    /// Handle the completion of a Control transfer.
+    fn handle_status_complete(&self) {
+        self.ctrl_state.set(CtrlState::Idle);
+
+        // Here we check to see if we just got connected to a CDC client. If so,
+        // we can begin transmitting if needed.
+        if self.state.get() == State::Connecting {
+            self.state.set(State::Connected);
+            if self.tx_buffer.is_some() {
+                self.controller().endpoint_resume_in(self.endpoint_in);
+            }
+        }
     }
 
     /// This is a helper function used to indicate successful uart transmission to
@@ -343,29 +528,17 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 {
     fn enable(&'a self) {
         // Set up the default control endpoint
-        self.client_ctrl.enable();
-
-        // Setup buffers for IN and OUT data transfer.
-        self.controller()
-            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
-        self.controller()
-            .endpoint_in_enable(TransferType::Bulk, ENDPOINT_IN_NUM);
-
-        self.controller()
-            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, self.buffer(ENDPOINT_OUT_NUM));
-        self.controller()
-            .endpoint_out_enable(TransferType::Bulk, ENDPOINT_OUT_NUM);
+        self.client_ctrl
+            .as_ref()
+            .map(|client_ctrl| client_ctrl.enable());
 
-        self.state.set(State::Enabled);
-
-        self.timeout_alarm.set_alarm(
-            self.timeout_alarm.now(),
-            A::ticks_from_ms(CDC_BUFFER_TIMEOUT_MS),
-        );
+        self.enable_endpoints();
     }
 
     fn attach(&'a self) {
-        self.client_ctrl.attach();
+        self.client_ctrl
+            .as_ref()
+            .map(|client_ctrl| client_ctrl.attach());
         self.state.set(State::Attached);
     }
 
@@ -379,89 +552,48 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     /// CDC uses special values here, and we can use these to know when a CDC
     /// client is connected or not.
     fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
-        descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf).map(|setup_data| {
-            let b_request = setup_data.request_code;
-
-            match CDCCntrlMessage::from(b_request) {
-                CDCCntrlMessage::SetLineCoding => {
-                    self.ctrl_state.set(CtrlState::SetLineCoding);
-                }
-                CDCCntrlMessage::SetControlLineState => {
-                    // Bit 0 and 1 of the value (setup_data.value) can be set
-                    // D0: Indicates to DCE if DTE is present or not.
-                    //     - 0 -> Not present
-                    //     - 1 -> Present
-                    // D1: Carrier control for half duplex modems.
-                    //     - 0 -> Deactivate carrier
-                    //     - 1 -> Activate carrier
-                    // Currently we don't care about the value
-                }
-                CDCCntrlMessage::SendBreak => {
-                    // On Mac, we seem to get the SEND_BREAK to signal that a
-                    // client disconnects.
-                    self.state.set(State::Enumerated)
-                }
-                _ => {}
-            }
-        });
-
-        self.client_ctrl.ctrl_setup(endpoint)
+        self.client_ctrl
+            .as_ref()
+            .map_or(hil::usb::CtrlSetupResult::ErrGeneric, |client_ctrl| {
+                SetupData::get(&client_ctrl.ctrl_buffer.buf)
+                    .map(|setup_data| self.handle_setup(&setup_data));
+
+                client_ctrl.ctrl_setup(endpoint)
+            })
     }
 
     /// Handle a Control In transaction
     fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
-        self.client_ctrl.ctrl_in(endpoint)
+        self.client_ctrl
+            .as_ref()
+            .map_or(hil::usb::CtrlInResult::Error, |client_ctrl| {
+                client_ctrl.ctrl_in(endpoint)
+            })
     }
 
     /// Handle a Control Out transaction
     fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
-        // Check what state our Ctrl endpoint is in.
-        if self.ctrl_state.get() == CtrlState::SetLineCoding {
-            // We got a Ctrl SET_LINE_CODING setup, now we are getting the data.
-            // We can parse the data we got.
-            descriptors::CdcAcmSetLineCodingData::get(&self.client_ctrl.ctrl_buffer.buf).map(
-                |line_coding| {
-                    // Check if we should switch our main state machine to
-                    // connecting meaning that the host is connecting to the virtual
-                    // serial port. We decide this based on if the host is
-                    // configuring the baud rate to what we expect.
-                    if self.state.get() == State::Enumerated && line_coding.baud_rate == 115200 {
-                        self.state.set(State::Connecting);
-                    }
-
-                    // Check if the baud rate we got matches the special flag
-                    // value (1200 baud). If so, we run an optional function
-                    // provided when the CDC stack was configured.
-                    if line_coding.baud_rate == 1200 {
-                        self.host_initiated_function.map(|f| {
-                            f();
-                        });
-                    }
-                },
-            );
-        }
-
-        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+        self.client_ctrl
+            .as_ref()
+            .map_or(hil::usb::CtrlOutResult::Halted, |client_ctrl| {
+                self.handle_out(&client_ctrl.ctrl_buffer.buf);
+                client_ctrl.ctrl_out(endpoint, packet_bytes)
+            })
     }
 
     fn ctrl_status(&'a self, endpoint: usize) {
-        self.client_ctrl.ctrl_status(endpoint)
+        self.client_ctrl
+            .as_ref()
+            .map(|client_ctrl| client_ctrl.ctrl_status(endpoint));
     }
 
     /// Handle the completion of a Control transfer
     fn ctrl_status_complete(&'a self, endpoint: usize) {
-        self.ctrl_state.set(CtrlState::Idle);
-
-        // Here we check to see if we just got connected to a CDC client. If so,
-        // we can begin transmitting if needed.
-        if self.state.get() == State::Connecting {
-            self.state.set(State::Connected);
-            if self.tx_buffer.is_some() {
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
-            }
-        }
+        self.handle_status_complete();
 
-        self.client_ctrl.ctrl_status_complete(endpoint)
+        self.client_ctrl
+            .as_ref()
+            .map(|client_ctrl| client_ctrl.ctrl_status_complete(endpoint));
     }
 
     /// Handle a Bulk/Interrupt IN transaction.
@@ -472,7 +604,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     /// `hil::usb::InResult::Delay` from this function. That means we can use
     /// this as a callback to mean that the transmission finished by waiting
     /// until this function is called when we don't have anything left to send.
-    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
         match transfer_type {
             TransferType::Bulk => {
                 self.tx_buffer
@@ -486,7 +618,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 
                             // Get packet that we have shared with the underlying
                             // USB stack to copy the tx into.
-                            let packet = self.buffer(endpoint);
+                            let packet = self.buffer(BUFFER_IN);
 
                             // Calculate how much more we can send.
                             let to_send = cmp::min(packet.len(), remaining);
@@ -530,7 +662,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     fn packet_out(
         &'a self,
         transfer_type: TransferType,
-        endpoint: usize,
+        _endpoint: usize,
         packet_bytes: u32,
     ) -> hil::usb::OutResult {
         match transfer_type {
@@ -545,7 +677,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
                     let copy_length = cmp::min(packet_bytes as usize, available_bytes);
 
                     // Do the copy into the RX buffer.
-                    let packet = self.buffer(endpoint);
+                    let packet = self.buffer(BUFFER_OUT);
                     for i in 0..copy_length {
                         rx_buf[rx_offset + i] = packet[i].get();
                     }
@@ -591,7 +723,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
             if remaining > 0 {
                 // We do, so ask to send again.
                 self.tx_buffer.replace(tx_buf);
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                self.controller().endpoint_resume_in(self.endpoint_in);
             } else {
                 // We don't have anything to send, so that means we are
                 // ok to signal the callback.
@@ -605,6 +737,90 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     }
 }
 
+impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> UsbFunction<'a> for CdcAcm<'a, U, A> {
+    fn num_interfaces(&self) -> u8 {
+        2
+    }
+
+    fn has_endpoint(&self, endpoint: usize) -> bool {
+        endpoint == self.endpoint_in
+            || endpoint == self.endpoint_out
+            || endpoint == self.endpoint_notify
+    }
+
+    fn write_descriptors(&self, first_interface: u8, buf: &[Cell<u8>]) -> usize {
+        let (interfaces, cdc_descriptors, notify_endpoint, data_endpoints) = Self::descriptors(
+            first_interface,
+            self.endpoint_notify,
+            self.endpoint_in,
+            self.endpoint_out,
+        );
+        let association = InterfaceAssociationDescriptor {
+            first_interface: first_interface,
+            interface_count: 2,
+            function_class: 0x02,    // CDC communication
+            function_subclass: 0x02, // abstract control model (ACM)
+            function_protocol: 0x01, // V.25ter (AT commands)
+            string_index: 0,
+        };
+
+        let mut len = association.write_to(buf);
+        len += interfaces[0].write_to(&buf[len..]);
+        for d in cdc_descriptors.iter() {
+            len += d.write_to(&buf[len..]);
+        }
+        len += notify_endpoint[0].write_to(&buf[len..]);
+        len += interfaces[1].write_to(&buf[len..]);
+        for d in data_endpoints.iter() {
+            len += d.write_to(&buf[len..]);
+        }
+        len
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    fn attach(&'a self) {
+        self.state.set(State::Attached);
+    }
+
+    fn bus_reset(&'a self) {
+        hil::usb::Client::bus_reset(self)
+    }
+
+    fn ctrl_setup(&'a self, setup_data: &SetupData) -> hil::usb::CtrlSetupResult {
+        self.handle_setup(setup_data);
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    fn ctrl_out(&'a self, buf: &[VolatileCell<u8>], _packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.handle_out(buf);
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        self.handle_status_complete();
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
+
 impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> uart::Configure for CdcAcm<'a, U, A> {
     fn configure(&self, _parameters: uart::Parameters) -> Result<(), ErrorCode> {
         // Since this is not a real UART, we don't need to consider these
@@ -643,7 +859,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> uart::Transmit<'a>
             if self.state.get() == State::Connected {
                 // Then signal to the lower layer that we are ready to do a TX
                 // by putting data in the IN endpoint.
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                self.controller().endpoint_resume_in(self.endpoint_in);
                 Ok(())
             } else if self.boot_period.get() {
                 // indicate success because we will try to send it once a host connects
@@ -0,0 +1,518 @@
+//! Composite USB devices, whose functions share one USB controller.
+//!
+//! `UsbComposite` is the client of the USB controller. It answers the
+//! standard requests of the default control endpoint, and assembles the
+//! configuration descriptor from the descriptors of each of its functions.
+//! A function is a group of interfaces and the endpoints they use, such as
+//! the two interfaces of a CDC-ACM serial port. Class and vendor requests
+//! for an interface, and the transfers of each endpoint, are passed to the
+//! function they belong to.
+//!
+//! Interfaces are numbered in the order functions are added. Endpoints are
+//! chosen by the board when it creates each function, and a function must
+//! not use the endpoints of another.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let composite = static_init!(
+//!     capsules::usb::composite::UsbComposite<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::composite::UsbComposite::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x2341,
+//!         0x005a,
+//!         strings,
+//!     )
+//! );
+//! // CDC-ACM console on endpoints 1 and 2, CDC-ACM debug on endpoints 3
+//! // and 4.
+//! let console = static_init!(
+//!     capsules::usb::cdc::CdcAcm<'static, nrf52840::usbd::Usbd, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::usb::cdc::CdcAcm::new_function(
+//!         &nrf52840::usbd::USBD,
+//!         1,
+//!         2,
+//!         console_alarm,
+//!         dynamic_deferred_caller,
+//!         None,
+//!     )
+//! );
+//! let debug = static_init!(
+//!     capsules::usb::cdc::CdcAcm<'static, nrf52840::usbd::Usbd, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::usb::cdc::CdcAcm::new_function(
+//!         &nrf52840::usbd::USBD,
+//!         3,
+//!         4,
+//!         debug_alarm,
+//!         dynamic_deferred_caller,
+//!         None,
+//!     )
+//! );
+//! // Vendor bulk interface on endpoint 5.
+//! let bulk = static_init!(
+//!     capsules::usb::vendor_bulk::VendorBulk<'static, nrf52840::usbd::Usbd>,
+//!     capsules::usb::vendor_bulk::VendorBulk::new(
+//!         &nrf52840::usbd::USBD,
+//!         5,
+//!         dynamic_deferred_caller,
+//!     )
+//! );
+//! composite.add_function(console).unwrap();
+//! composite.add_function(debug).unwrap();
+//! composite.add_function(bulk).unwrap();
+//! nrf52840::usbd::USBD.set_client(composite);
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+
+use super::descriptors::Buffer64;
+use super::descriptors::ConfigurationDescriptor;
+use super::descriptors::Descriptor;
+use super::descriptors::DescriptorType;
+use super::descriptors::DeviceDescriptor;
+use super::descriptors::LanguagesDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::SetupData;
+use super::descriptors::StandardRequest;
+use super::descriptors::StringDescriptor;
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ErrorCode;
+
+/// Most functions a composite device can have.
+pub const MAX_FUNCTIONS: usize = 4;
+
+const DESCRIPTOR_BUFLEN: usize = 256;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// A function of a composite device.
+///
+/// Interfaces of a function are numbered from the first interface the
+/// composite device assigns to it. Requests and transfers are only passed
+/// to a function once the composite device has been enabled.
+pub trait UsbFunction<'a> {
+    /// Number of interfaces of the function.
+    fn num_interfaces(&self) -> u8;
+
+    /// Whether the function uses endpoint `endpoint`, in either direction.
+    fn has_endpoint(&self, endpoint: usize) -> bool;
+
+    /// Write the descriptors of the function to `buf`, with its interfaces
+    /// numbered from `first_interface`. Functions with several interfaces
+    /// start with an interface association descriptor. Returns the number
+    /// of bytes written.
+    fn write_descriptors(&self, first_interface: u8, buf: &[Cell<u8>]) -> usize;
+
+    /// Give the buffers of the endpoints of the function to the controller,
+    /// and enable them.
+    fn enable(&'a self);
+
+    fn attach(&'a self) {}
+
+    fn bus_reset(&'a self);
+
+    /// Handle a request for one of the interfaces of the function. The
+    /// function must return `Ok` for the data stage of the request, if any,
+    /// to be passed to `ctrl_in()` or `ctrl_out()`.
+    fn ctrl_setup(&'a self, setup_data: &SetupData) -> hil::usb::CtrlSetupResult;
+
+    /// Write the next packet of the data stage of a request to `buf`.
+    fn ctrl_in(&'a self, _buf: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        hil::usb::CtrlInResult::Packet(0, true)
+    }
+
+    /// A packet of the data stage of a request was received in `buf`.
+    fn ctrl_out(
+        &'a self,
+        _buf: &[VolatileCell<u8>],
+        _packet_bytes: u32,
+    ) -> hil::usb::CtrlOutResult {
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    /// A request the function handled completed.
+    fn ctrl_status_complete(&'a self) {}
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult;
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult;
+
+    fn packet_transmitted(&'a self, endpoint: usize);
+}
+
+/// State of the default control endpoint.
+#[derive(Copy, Clone)]
+enum State {
+    Init,
+
+    /// We are doing a Control In transfer of some data in
+    /// self.descriptor_storage, with the given extent remaining to send.
+    CtrlIn(usize, usize),
+
+    SetAddress,
+
+    /// A function is handling the request.
+    Function(usize),
+}
+
+pub struct UsbComposite<'a, U: 'a> {
+    /// The USB hardware controller.
+    controller: &'a U,
+
+    functions: [OptionalCell<&'a dyn UsbFunction<'a>>; MAX_FUNCTIONS],
+
+    state: Cell<State>,
+
+    /// A 64-byte buffer for the control endpoint to be passed to the USB
+    /// driver.
+    ctrl_buffer: Buffer64,
+
+    /// Storage for composing responses to device descriptor requests.
+    descriptor_storage: Cell<[u8; DESCRIPTOR_BUFLEN]>,
+
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+
+    /// Manufacturer, product and serial number strings.
+    strings: &'static [&'static str; 3],
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbComposite<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> Self {
+        UsbComposite {
+            controller: controller,
+            functions: [
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+            ],
+            state: Cell::new(State::Init),
+            ctrl_buffer: Buffer64::default(),
+            descriptor_storage: Cell::new([0; DESCRIPTOR_BUFLEN]),
+            max_ctrl_packet_size: max_ctrl_packet_size,
+            vendor_id: vendor_id,
+            product_id: product_id,
+            strings: strings,
+        }
+    }
+
+    /// Add a function to the device. Functions must all be added before the
+    /// device is enabled.
+    pub fn add_function(&self, function: &'a dyn UsbFunction<'a>) -> Result<(), ErrorCode> {
+        self.functions
+            .iter()
+            .find(|slot| slot.is_none())
+            .map_or(Err(ErrorCode::NOMEM), |slot| {
+                slot.set(function);
+                Ok(())
+            })
+    }
+
+    #[inline]
+    pub fn controller(&self) -> &'a U {
+        self.controller
+    }
+
+    fn descriptor_buf(&self) -> &[Cell<u8>] {
+        let storage: &Cell<[u8]> = &self.descriptor_storage;
+        storage.as_slice_of_cells()
+    }
+
+    fn function(&self, index: usize) -> Option<&'a dyn UsbFunction<'a>> {
+        self.functions.get(index).and_then(|slot| slot.extract())
+    }
+
+    /// The index of the function `interface` belongs to.
+    fn function_for_interface(&self, interface: u16) -> Option<usize> {
+        let mut first = 0;
+        for (index, slot) in self.functions.iter().enumerate() {
+            if let Some(function) = slot.extract() {
+                let count = function.num_interfaces() as u16;
+                if interface >= first && interface < first + count {
+                    return Some(index);
+                }
+                first += count;
+            }
+        }
+        None
+    }
+
+    fn function_for_endpoint(&self, endpoint: usize) -> Option<&'a dyn UsbFunction<'a>> {
+        self.functions
+            .iter()
+            .filter_map(|slot| slot.extract())
+            .find(|function| function.has_endpoint(endpoint))
+    }
+
+    /// Write the configuration descriptor, followed by the descriptors of
+    /// every function. Descriptors that do not fit are left out.
+    fn write_configuration(&self, buf: &[Cell<u8>]) -> usize {
+        let header = ConfigurationDescriptor::default().size();
+        let mut len = header;
+        let mut interfaces = 0;
+        for function in self.functions.iter().filter_map(|slot| slot.extract()) {
+            len += function.write_descriptors(interfaces, &buf[len..]);
+            interfaces += function.num_interfaces();
+        }
+        ConfigurationDescriptor {
+            num_interfaces: interfaces,
+            related_descriptor_length: len - header,
+            ..ConfigurationDescriptor::default()
+        }
+        .write_to(buf);
+        len
+    }
+
+    fn handle_standard_device_request(
+        &self,
+        request: StandardRequest,
+    ) -> hil::usb::CtrlSetupResult {
+        match request {
+            StandardRequest::GetDescriptor {
+                descriptor_type,
+                descriptor_index,
+                lang_id,
+                requested_length,
+            } => {
+                let buf = self.descriptor_buf();
+                let len = match descriptor_type {
+                    DescriptorType::Device => match descriptor_index {
+                        0 => DeviceDescriptor {
+                            vendor_id: self.vendor_id,
+                            product_id: self.product_id,
+                            manufacturer_string: 1,
+                            product_string: 2,
+                            serial_number_string: 3,
+                            // Functions are described by interface
+                            // association descriptors.
+                            class: 0xef,
+                            subclass: 0x02,
+                            protocol: 0x01,
+                            max_packet_size_ep0: self.max_ctrl_packet_size,
+                            ..DeviceDescriptor::default()
+                        }
+                        .write_to(buf),
+                        _ => return hil::usb::CtrlSetupResult::ErrInvalidDeviceIndex,
+                    },
+                    DescriptorType::Configuration => match descriptor_index {
+                        0 => self.write_configuration(buf),
+                        _ => return hil::usb::CtrlSetupResult::ErrInvalidConfigurationIndex,
+                    },
+                    DescriptorType::String => match descriptor_index {
+                        0 => LanguagesDescriptor { langs: LANGUAGES }.write_to(buf),
+                        i if i > 0
+                            && (i as usize) <= self.strings.len()
+                            && lang_id == LANGUAGES[0] =>
+                        {
+                            StringDescriptor {
+                                string: self.strings[i as usize - 1],
+                            }
+                            .write_to(buf)
+                        }
+                        _ => return hil::usb::CtrlSetupResult::ErrInvalidStringIndex,
+                    },
+                    DescriptorType::DeviceQualifier => {
+                        // We are full-speed only, so we must
+                        // respond with a request error
+                        return hil::usb::CtrlSetupResult::ErrNoDeviceQualifier;
+                    }
+                    _ => return hil::usb::CtrlSetupResult::ErrUnrecognizedDescriptorType,
+                };
+                let end = min(len, requested_length as usize);
+                self.state.set(State::CtrlIn(0, end));
+                hil::usb::CtrlSetupResult::Ok
+            }
+            StandardRequest::SetAddress { device_address } => {
+                // Load the address we've been assigned ...
+                self.controller.set_address(device_address);
+
+                // ... and when this request gets to the Status stage we will actually enable the
+                // address.
+                self.state.set(State::SetAddress);
+                hil::usb::CtrlSetupResult::OkSetAddress
+            }
+            StandardRequest::SetConfiguration { .. } => {
+                // We have been assigned a particular configuration: fine!
+                hil::usb::CtrlSetupResult::Ok
+            }
+            _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for UsbComposite<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.controller
+            .endpoint_set_ctrl_buffer(&self.ctrl_buffer.buf);
+        self.controller
+            .enable_as_device(hil::usb::DeviceSpeed::Full); // must be Full for Bulk transfers
+        self.controller
+            .endpoint_out_enable(TransferType::Control, 0);
+
+        for function in self.functions.iter().filter_map(|slot| slot.extract()) {
+            function.enable();
+        }
+    }
+
+    fn attach(&'a self) {
+        self.controller.attach();
+        for function in self.functions.iter().filter_map(|slot| slot.extract()) {
+            function.attach();
+        }
+    }
+
+    fn bus_reset(&'a self) {
+        for function in self.functions.iter().filter_map(|slot| slot.extract()) {
+            function.bus_reset();
+        }
+    }
+
+    /// Handle a Control Setup transaction
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        if endpoint != 0 {
+            // For now we only support the default Control endpoint
+            return hil::usb::CtrlSetupResult::ErrInvalidDeviceIndex;
+        }
+        self.state.set(State::Init);
+        SetupData::get(&self.ctrl_buffer.buf).map_or(
+            hil::usb::CtrlSetupResult::ErrNoParse,
+            |setup_data| match setup_data.request_type.recipient() {
+                Recipient::Device => setup_data.get_standard_request().map_or(
+                    hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+                    |request| self.handle_standard_device_request(request),
+                ),
+                Recipient::Interface => {
+                    self.function_for_interface(setup_data.index & 0xff).map_or(
+                        hil::usb::CtrlSetupResult::ErrInvalidInterfaceIndex,
+                        |index| {
+                            let result = self
+                                .function(index)
+                                .map_or(hil::usb::CtrlSetupResult::ErrGeneric, |function| {
+                                    function.ctrl_setup(&setup_data)
+                                });
+                            if let hil::usb::CtrlSetupResult::Ok = result {
+                                self.state.set(State::Function(index));
+                            }
+                            result
+                        },
+                    )
+                }
+                _ => hil::usb::CtrlSetupResult::ErrGeneric,
+            },
+        )
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, _endpoint: usize) -> hil::usb::CtrlInResult {
+        match self.state.get() {
+            State::CtrlIn(start, end) => {
+                let len = end.saturating_sub(start);
+                if len > 0 {
+                    let packet_bytes = min(self.ctrl_buffer.buf.len(), len);
+                    let packet = &self.descriptor_buf()[start..start + packet_bytes];
+                    let buf = &self.ctrl_buffer.buf;
+
+                    // Copy a packet into the endpoint buffer
+                    for (i, b) in packet.iter().enumerate() {
+                        buf[i].set(b.get());
+                    }
+
+                    let start = start + packet_bytes;
+                    let len = end.saturating_sub(start);
+                    let transfer_complete = len == 0;
+
+                    self.state.set(State::CtrlIn(start, end));
+
+                    hil::usb::CtrlInResult::Packet(packet_bytes, transfer_complete)
+                } else {
+                    hil::usb::CtrlInResult::Packet(0, true)
+                }
+            }
+            State::Function(index) => self
+                .function(index)
+                .map_or(hil::usb::CtrlInResult::Error, |function| {
+                    function.ctrl_in(&self.ctrl_buffer.buf)
+                }),
+            _ => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, _endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.state.get() {
+            State::Function(index) => self
+                .function(index)
+                .map_or(hil::usb::CtrlOutResult::Halted, |function| {
+                    function.ctrl_out(&self.ctrl_buffer.buf, packet_bytes)
+                }),
+            _ => {
+                // Bad state
+                hil::usb::CtrlOutResult::Halted
+            }
+        }
+    }
+
+    fn ctrl_status(&'a self, _endpoint: usize) {
+        // Entered Status stage
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, _endpoint: usize) {
+        match self.state.replace(State::Init) {
+            State::SetAddress => {
+                self.controller.enable_address();
+            }
+            State::Function(index) => {
+                self.function(index)
+                    .map(|function| function.ctrl_status_complete());
+            }
+            _ => {}
+        }
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        self.function_for_endpoint(endpoint)
+            .map_or(hil::usb::InResult::Error, |function| {
+                function.packet_in(transfer_type, endpoint)
+            })
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        self.function_for_endpoint(endpoint)
+            .map_or(hil::usb::OutResult::Error, |function| {
+                function.packet_out(transfer_type, endpoint, packet_bytes)
+            })
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.function_for_endpoint(endpoint)
+            .map(|function| function.packet_transmitted(endpoint));
+    }
+}
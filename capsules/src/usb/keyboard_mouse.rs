@@ -0,0 +1,362 @@
+//! Keyboard and mouse over USB HID
+//!
+//! This capsule is a USB device with a single HID interface, whose reports
+//! are those of a keyboard and of a mouse. Reports are sent on an interrupt
+//! IN endpoint, and start with their report ID:
+//!
+//! ```text
+//! Keyboard: | 1 | Modifiers | Key 1 | Key 2 | Key 3 | Key 4 | Key 5 | Key 6 |
+//! Mouse:    | 2 | Buttons | X | Y | Wheel |
+//! ```
+//!
+//! Keys are usage IDs of the Keyboard/Keypad usage page, and modifiers are
+//! a bitmask starting with left control in bit 0. Mouse buttons are a
+//! bitmask of the left, right and middle buttons, and the movements are
+//! signed bytes. Reports fit in 8 bytes, the largest packet of the
+//! interrupt endpoints of the SAM4L.
+//!
+//! Output reports of the host, such as the state of the keyboard LEDs, are
+//! ignored.
+
+use core::cell::Cell;
+
+use super::descriptors;
+use super::descriptors::Buffer8;
+use super::descriptors::DescriptorType;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::HIDCountryCode;
+use super::descriptors::HIDDescriptor;
+use super::descriptors::HIDSubordinateDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::ReportDescriptor;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ErrorCode;
+
+/// Interrupt IN endpoint of the reports.
+const ENDPOINT_NUM: usize = 1;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Report ID of keyboard reports.
+pub const REPORT_KEYBOARD: u8 = 1;
+/// Report ID of mouse reports.
+pub const REPORT_MOUSE: u8 = 2;
+
+/// Length of keyboard reports, report ID included.
+const KEYBOARD_REPORT_LEN: usize = 8;
+/// Length of mouse reports, report ID included.
+const MOUSE_REPORT_LEN: usize = 5;
+
+static REPORT_DESCRIPTOR: &'static [u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x85, 0x01, // Report ID (REPORT_KEYBOARD)
+    0x05, 0x07, // Usage Page (Keyboard/Keypad)
+    0x19, 0xE0, // Usage Minimum (Left Control)
+    0x29, 0xE7, // Usage Maximum (Right GUI)
+    0x15, 0x00, // Logical Minimum (0)
+    0x25, 0x01, // Logical Maximum (1)
+    0x75, 0x01, // Report Size (1)
+    0x95, 0x08, // Report Count (8)
+    0x81, 0x02, // Input (Data, Variable, Absolute)
+    0x19, 0x00, // Usage Minimum (0)
+    0x29, 0x65, // Usage Maximum (101)
+    0x15, 0x00, // Logical Minimum (0)
+    0x25, 0x65, // Logical Maximum (101)
+    0x75, 0x08, // Report Size (8)
+    0x95, 0x06, // Report Count (6)
+    0x81, 0x00, // Input (Data, Array)
+    0xC0, // End Collection
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xA1, 0x01, // Collection (Application)
+    0x85, 0x02, // Report ID (REPORT_MOUSE)
+    0x09, 0x01, // Usage (Pointer)
+    0xA1, 0x00, // Collection (Physical)
+    0x05, 0x09, // Usage Page (Button)
+    0x19, 0x01, // Usage Minimum (1)
+    0x29, 0x03, // Usage Maximum (3)
+    0x15, 0x00, // Logical Minimum (0)
+    0x25, 0x01, // Logical Maximum (1)
+    0x75, 0x01, // Report Size (1)
+    0x95, 0x03, // Report Count (3)
+    0x81, 0x02, // Input (Data, Variable, Absolute)
+    0x75, 0x05, // Report Size (5)
+    0x95, 0x01, // Report Count (1)
+    0x81, 0x03, // Input (Constant)
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x30, // Usage (X)
+    0x09, 0x31, // Usage (Y)
+    0x09, 0x38, // Usage (Wheel)
+    0x15, 0x81, // Logical Minimum (-127)
+    0x25, 0x7F, // Logical Maximum (127)
+    0x75, 0x08, // Report Size (8)
+    0x95, 0x03, // Report Count (3)
+    0x81, 0x06, // Input (Data, Variable, Relative)
+    0xC0, // End Collection
+    0xC0, // End Collection
+];
+
+static REPORT: ReportDescriptor<'static> = ReportDescriptor {
+    desc: REPORT_DESCRIPTOR,
+};
+
+static SUB_HID_DESCRIPTOR: &'static [HIDSubordinateDescriptor] = &[HIDSubordinateDescriptor {
+    typ: DescriptorType::Report,
+    len: REPORT_DESCRIPTOR.len() as u16,
+}];
+
+static HID_DESCRIPTOR: HIDDescriptor<'static> = HIDDescriptor {
+    hid_class: 0x0111,
+    country_code: HIDCountryCode::NotSupported,
+    sub_descriptors: SUB_HID_DESCRIPTOR,
+};
+
+/// Length of the report in `report`, from its report ID.
+fn report_len(report: &[u8; 8]) -> Option<usize> {
+    match report[0] {
+        REPORT_KEYBOARD => Some(KEYBOARD_REPORT_LEN),
+        REPORT_MOUSE => Some(MOUSE_REPORT_LEN),
+        _ => None,
+    }
+}
+
+/// Implementation of a USB HID keyboard and mouse
+pub struct KeyboardMouseHid<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// Buffer of the IN endpoint.
+    buffer: Buffer8,
+
+    client: OptionalCell<&'a dyn hil::usb_hid::Client<'a, [u8; 8]>>,
+
+    /// The report being sent.
+    send_buffer: TakeCell<'static, [u8; 8]>,
+    /// Whether the report was passed to the controller. Some controllers
+    /// do not signal transmitted packets, and only ask for the next one.
+    sent: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> KeyboardMouseHid<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0x03,    // HID
+            interface_subclass: 0x00, // No subclass
+            interface_protocol: 0x00, // No protocol
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                ENDPOINT_NUM,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Interrupt,
+            max_packet_size: 8,
+            interval: 10,
+        }]];
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                Some(&HID_DESCRIPTOR),
+                None,
+            );
+
+        KeyboardMouseHid {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                Some(&HID_DESCRIPTOR),
+                Some(&REPORT),
+                LANGUAGES,
+                strings,
+            ),
+            buffer: Buffer8::default(),
+            client: OptionalCell::empty(),
+            send_buffer: TakeCell::empty(),
+            sent: Cell::new(false),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    pub fn set_client(&'a self, client: &'a dyn hil::usb_hid::Client<'a, [u8; 8]>) {
+        self.client.set(client);
+    }
+
+    /// The report was sent: return it to the client.
+    fn report_sent(&self) {
+        self.sent.set(false);
+        self.send_buffer.take().map(|buf| {
+            self.client.map(move |client| {
+                client.packet_transmitted(Ok(()), buf, ENDPOINT_NUM);
+            });
+        });
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 8]>
+    for KeyboardMouseHid<'a, U>
+{
+    fn send_buffer(
+        &'a self,
+        send: &'static mut [u8; 8],
+    ) -> Result<usize, (ErrorCode, &'static mut [u8; 8])> {
+        if self.send_buffer.is_some() {
+            return Err((ErrorCode::BUSY, send));
+        }
+        match report_len(send) {
+            Some(len) => {
+                self.send_buffer.replace(send);
+                self.controller().endpoint_resume_in(ENDPOINT_NUM);
+                Ok(len)
+            }
+            None => Err((ErrorCode::INVAL, send)),
+        }
+    }
+
+    fn send_cancel(&'a self) -> Result<&'static mut [u8; 8], ErrorCode> {
+        if self.sent.get() {
+            Err(ErrorCode::BUSY)
+        } else {
+            self.send_buffer.take().ok_or(ErrorCode::INVAL)
+        }
+    }
+
+    fn receive_buffer(
+        &'a self,
+        recv: &'static mut [u8; 8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; 8])> {
+        // There is no OUT endpoint.
+        Err((ErrorCode::NOSUPPORT, recv))
+    }
+
+    fn receive_cancel(&'a self) -> Result<&'static mut [u8; 8], ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for KeyboardMouseHid<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup the buffer for IN data transfer.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_NUM, &self.buffer.buf);
+        self.controller()
+            .endpoint_in_enable(TransferType::Interrupt, ENDPOINT_NUM);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {}
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        self.client_ctrl.ctrl_setup(endpoint)
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        self.client_ctrl.ctrl_in(endpoint)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        if self.send_buffer.is_some() && !self.sent.get() {
+            self.controller().endpoint_resume_in(ENDPOINT_NUM);
+        }
+
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle an Interrupt IN transaction.
+    ///
+    /// If the report was already sent, being asked for the next packet means
+    /// it was transmitted.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                if self.sent.get() {
+                    self.report_sent();
+                }
+                self.send_buffer
+                    .map_or(hil::usb::InResult::Delay, |report| {
+                        let len = report_len(report).unwrap_or(0);
+                        for i in 0..len {
+                            self.buffer.buf[i].set(report[i]);
+                        }
+                        self.sent.set(true);
+                        hil::usb::InResult::Packet(len)
+                    })
+            }
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                hil::usb::InResult::Error
+            }
+        }
+    }
+
+    /// Handle a Bulk/Interrupt OUT transaction
+    fn packet_out(
+        &'a self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::OutResult::Error
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        if self.sent.get() {
+            self.report_sent();
+        }
+    }
+}
@@ -0,0 +1,722 @@
+//! USB mass storage function for composite devices.
+//!
+//! This capsule is a function of a `UsbComposite` device that presents a
+//! region of nonvolatile storage to the host as a disk. It implements the
+//! Bulk-Only Transport of the USB mass storage class, with the SCSI
+//! transparent command set, so hosts mount it with their own drivers and no
+//! special tooling. The disk has one logical unit, whose blocks are
+//! `BLOCK_SIZE` bytes long.
+//!
+//! Each command goes through three stages on a single bulk endpoint number:
+//!
+//! 1. The host sends a command block wrapper (CBW) on the OUT endpoint.
+//! 2. Data of the command, if any, is sent on the IN or the OUT endpoint.
+//!    Blocks of READ(10) and WRITE(10) commands are read from or written to
+//!    the storage one buffer at a time.
+//! 3. The device sends a command status wrapper (CSW) on the IN endpoint.
+//!
+//! The USB HIL cannot stall and clear endpoints, so when the host expects
+//! more data than a command has, the rest of the data stage is padded with
+//! zeros, or received and dropped, and the CSW reports the difference as its
+//! residue.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! // Expose the first megabyte of the external flash.
+//! let msc = static_init!(
+//!     capsules::usb::mass_storage::MassStorage<'static, nrf52840::usbd::Usbd>,
+//!     capsules::usb::mass_storage::MassStorage::new(
+//!         &nrf52840::usbd::USBD,
+//!         6,
+//!         nv_to_page,
+//!         0,
+//!         0x100000,
+//!         static_init!([u8; 512], [0; 512]),
+//!     )
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, msc);
+//! composite.add_function(msc).unwrap();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::UsbFunction;
+use super::descriptors::Buffer64;
+use super::descriptors::Descriptor;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+
+/// Size of the blocks of the disk.
+pub const BLOCK_SIZE: usize = 512;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_LEN: u32 = 31;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_LEN: usize = 13;
+
+/// Class requests of the Bulk-Only Transport.
+const REQUEST_GET_MAX_LUN: u8 = 0xfe;
+const REQUEST_RESET: u8 = 0xff;
+
+/// Values of the status field of a CSW.
+const STATUS_PASSED: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+const STATUS_PHASE_ERROR: u8 = 2;
+
+/// SCSI operation codes.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5a;
+
+/// SCSI sense keys and additional sense codes.
+const SENSE_NONE: (u8, u8) = (0x00, 0x00);
+const SENSE_MEDIUM_ERROR: (u8, u8) = (0x03, 0x11); // Unrecovered read error
+const SENSE_WRITE_ERROR: (u8, u8) = (0x03, 0x0c); // Write error
+const SENSE_HARDWARE_ERROR: (u8, u8) = (0x04, 0x44); // Internal target failure
+const SENSE_INVALID_COMMAND: (u8, u8) = (0x05, 0x20); // Invalid command operation code
+const SENSE_OUT_OF_RANGE: (u8, u8) = (0x05, 0x21); // Logical block address out of range
+
+/// Standard INQUIRY data: a removable direct access block device.
+static INQUIRY_DATA: [u8; 36] = [
+    0x00, // Direct access block device
+    0x80, // Removable
+    0x04, // SPC-2
+    0x02, // Response data format
+    31,   // Additional length
+    0x00, 0x00, 0x00, //
+    b'T', b'o', b'c', b'k', b' ', b' ', b' ', b' ', // Vendor
+    b'M', b'a', b's', b's', b' ', b's', b't', b'o', // Product
+    b'r', b'a', b'g', b'e', b' ', b' ', b' ', b' ', //
+    b'1', b'.', b'0', b' ', // Revision
+];
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// Waiting for a CBW.
+    Command,
+    /// Sending the data of a command.
+    DataIn,
+    /// Receiving the data of a command.
+    DataOut,
+    /// Sending the CSW of a command.
+    Status,
+}
+
+/// What a command transfers in its data stage.
+enum Data {
+    None,
+    /// A response of this length, in the buffer.
+    Response(usize),
+    /// Bytes read from the storage.
+    Read(usize),
+    /// Bytes written to the storage.
+    Write(usize),
+}
+
+pub struct MassStorage<'a, U: 'a> {
+    /// The USB hardware controller.
+    controller: &'a U,
+
+    /// Endpoint for transferring data in both directions.
+    endpoint: usize,
+
+    /// 64 byte buffers for the IN and OUT directions of the endpoint.
+    in_buffer: Buffer64,
+    out_buffer: Buffer64,
+
+    storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    /// Storage address of the first block of the disk.
+    start: usize,
+    num_blocks: usize,
+
+    /// Buffer for responses and for blocks read or written.
+    buffer: TakeCell<'static, [u8]>,
+    /// Bytes of data in `buffer`.
+    buffer_len: Cell<usize>,
+    /// Bytes of `buffer` already sent.
+    buffer_offset: Cell<usize>,
+    /// Whether the storage has `buffer`.
+    busy: Cell<bool>,
+
+    state: Cell<State>,
+
+    /// Tag of the current command, returned in its CSW.
+    tag: Cell<u32>,
+    /// Length of the data stage the host expects.
+    data_len: Cell<usize>,
+    /// Bytes of the data stage that belong to the command. The rest is
+    /// padding or dropped.
+    data_valid: Cell<usize>,
+    /// Bytes transferred so far in the data stage.
+    transferred: Cell<usize>,
+    /// Storage address of the next read or write.
+    address: Cell<usize>,
+    /// Bytes yet to be read from the storage.
+    to_read: Cell<usize>,
+    status: Cell<u8>,
+    /// Sense key and additional sense code of the last failed command.
+    sense: Cell<(u8, u8)>,
+
+    /// Whether the controller was told to wait for an IN or OUT packet.
+    in_delayed: Cell<bool>,
+    out_delayed: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> MassStorage<'a, U> {
+    /// Create a disk of the `length` bytes of `storage` from `start`.
+    /// `buffer` must hold a multiple of `BLOCK_SIZE` bytes.
+    pub fn new(
+        controller: &'a U,
+        endpoint: usize,
+        storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        start: usize,
+        length: usize,
+        buffer: &'static mut [u8],
+    ) -> MassStorage<'a, U> {
+        MassStorage {
+            controller: controller,
+            endpoint: endpoint,
+            in_buffer: Buffer64::default(),
+            out_buffer: Buffer64::default(),
+            storage: storage,
+            start: start,
+            num_blocks: length / BLOCK_SIZE,
+            buffer: TakeCell::new(buffer),
+            buffer_len: Cell::new(0),
+            buffer_offset: Cell::new(0),
+            busy: Cell::new(false),
+            state: Cell::new(State::Command),
+            tag: Cell::new(0),
+            data_len: Cell::new(0),
+            data_valid: Cell::new(0),
+            transferred: Cell::new(0),
+            address: Cell::new(0),
+            to_read: Cell::new(0),
+            status: Cell::new(STATUS_PASSED),
+            sense: Cell::new(SENSE_NONE),
+            in_delayed: Cell::new(false),
+            out_delayed: Cell::new(false),
+        }
+    }
+
+    /// Abort the current command and wait for the next CBW.
+    fn reset(&self) {
+        self.state.set(State::Command);
+        self.to_read.set(0);
+        self.buffer_len.set(0);
+        self.buffer_offset.set(0);
+        if !self.busy.get() && self.out_delayed.replace(false) {
+            self.controller.endpoint_resume_out(self.endpoint);
+        }
+    }
+
+    fn fail(&self, sense: (u8, u8)) -> Data {
+        self.status.set(STATUS_FAILED);
+        self.sense.set(sense);
+        Data::None
+    }
+
+    /// Put the first `allocation` bytes of `response` in the buffer.
+    fn respond(&self, response: &[u8], allocation: usize) -> Data {
+        let len = cmp::min(response.len(), allocation);
+        self.buffer
+            .map(|buffer| {
+                buffer[..len].copy_from_slice(&response[..len]);
+                Data::Response(len)
+            })
+            .unwrap_or_else(|| self.fail(SENSE_HARDWARE_ERROR))
+    }
+
+    /// The storage address and length of the blocks of a READ(10) or
+    /// WRITE(10) command.
+    fn blocks(&self, cb: &[u8; 16]) -> Option<(usize, usize)> {
+        let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as usize;
+        let count = u16::from_be_bytes([cb[7], cb[8]]) as usize;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.num_blocks => {
+                Some((self.start + lba * BLOCK_SIZE, count * BLOCK_SIZE))
+            }
+            _ => None,
+        }
+    }
+
+    /// Run the SCSI command in `cb`.
+    fn handle_command(&self, cb: &[u8; 16]) -> Data {
+        let last_block = (self.num_blocks as u32).saturating_sub(1).to_be_bytes();
+        let blocks = (self.num_blocks as u32).to_be_bytes();
+        let block_size = (BLOCK_SIZE as u32).to_be_bytes();
+
+        match cb[0] {
+            TEST_UNIT_READY
+            | START_STOP_UNIT
+            | PREVENT_ALLOW_MEDIUM_REMOVAL
+            | VERIFY_10
+            | SYNCHRONIZE_CACHE_10 => Data::None,
+            REQUEST_SENSE => {
+                let (key, asc) = self.sense.replace(SENSE_NONE);
+                let response = [0x70, 0, key, 0, 0, 0, 0, 10, 0, 0, 0, 0, asc, 0, 0, 0, 0, 0];
+                self.respond(&response, cb[4] as usize)
+            }
+            INQUIRY => self.respond(&INQUIRY_DATA, u16::from_be_bytes([cb[3], cb[4]]) as usize),
+            MODE_SENSE_6 => self.respond(&[3, 0, 0, 0], cb[4] as usize),
+            MODE_SENSE_10 => self.respond(
+                &[0, 6, 0, 0, 0, 0, 0, 0],
+                u16::from_be_bytes([cb[7], cb[8]]) as usize,
+            ),
+            READ_FORMAT_CAPACITIES => self.respond(
+                &[
+                    0,
+                    0,
+                    0,
+                    8,
+                    blocks[0],
+                    blocks[1],
+                    blocks[2],
+                    blocks[3],
+                    0x02, // Formatted media
+                    block_size[1],
+                    block_size[2],
+                    block_size[3],
+                ],
+                u16::from_be_bytes([cb[7], cb[8]]) as usize,
+            ),
+            READ_CAPACITY_10 => self.respond(
+                &[
+                    last_block[0],
+                    last_block[1],
+                    last_block[2],
+                    last_block[3],
+                    block_size[0],
+                    block_size[1],
+                    block_size[2],
+                    block_size[3],
+                ],
+                8,
+            ),
+            READ_10 => self.blocks(cb).map_or_else(
+                || self.fail(SENSE_OUT_OF_RANGE),
+                |(address, len)| {
+                    self.address.set(address);
+                    Data::Read(len)
+                },
+            ),
+            WRITE_10 => self.blocks(cb).map_or_else(
+                || self.fail(SENSE_OUT_OF_RANGE),
+                |(address, len)| {
+                    self.address.set(address);
+                    Data::Write(len)
+                },
+            ),
+            _ => self.fail(SENSE_INVALID_COMMAND),
+        }
+    }
+
+    /// Handle the CBW in the OUT buffer, and start its data stage.
+    fn handle_cbw(&self, packet_bytes: u32) {
+        let packet = &self.out_buffer.buf;
+        let word = |i: usize| {
+            u32::from_le_bytes([
+                packet[i].get(),
+                packet[i + 1].get(),
+                packet[i + 2].get(),
+                packet[i + 3].get(),
+            ])
+        };
+        if packet_bytes != CBW_LEN || word(0) != CBW_SIGNATURE {
+            // Not a valid CBW, ignore it.
+            return;
+        }
+        let host_in = packet[12].get() & 0x80 != 0;
+        let mut cb = [0; 16];
+        for (i, b) in cb.iter_mut().enumerate() {
+            *b = packet[15 + i].get();
+        }
+
+        self.tag.set(word(4));
+        self.data_len.set(word(8) as usize);
+        self.transferred.set(0);
+        self.to_read.set(0);
+        self.buffer_len.set(0);
+        self.buffer_offset.set(0);
+        self.status.set(STATUS_PASSED);
+
+        let (valid, device_in) = match self.handle_command(&cb) {
+            Data::None => (0, host_in),
+            Data::Response(len) => {
+                self.buffer_len.set(len);
+                (len, true)
+            }
+            Data::Read(len) => {
+                self.to_read.set(len);
+                (len, true)
+            }
+            Data::Write(len) => (len, false),
+        };
+
+        if valid > 0 && (device_in != host_in || valid > self.data_len.get()) {
+            // The host and the device disagree on the data stage.
+            self.status.set(STATUS_PHASE_ERROR);
+            self.to_read.set(0);
+            self.buffer_len.set(0);
+            self.data_valid.set(0);
+        } else {
+            self.data_valid.set(valid);
+        }
+
+        if self.data_len.get() == 0 {
+            self.state.set(State::Status);
+        } else if host_in {
+            self.state.set(State::DataIn);
+        } else {
+            self.state.set(State::DataOut);
+            return;
+        }
+        self.controller.endpoint_resume_in(self.endpoint);
+    }
+
+    /// Read the next blocks of a READ(10) command in to the buffer. Returns
+    /// whether the read started.
+    fn read_next(&self) -> bool {
+        let started = self.buffer.take().map_or(false, |buffer| {
+            let len = cmp::min(self.to_read.get(), buffer.len());
+            self.busy.set(true);
+            match self.storage.read(buffer, self.address.get(), len) {
+                Ok(()) => true,
+                Err(_) => {
+                    // The storage keeps the buffer.
+                    self.busy.set(false);
+                    false
+                }
+            }
+        });
+        if !started {
+            self.to_read.set(0);
+            self.fail(SENSE_MEDIUM_ERROR);
+        }
+        started
+    }
+
+    /// Write the received blocks of a WRITE(10) command. Returns whether the
+    /// write started.
+    fn write_buffer(&self) -> bool {
+        let len = self.buffer_len.replace(0);
+        let started = self.buffer.take().map_or(false, |buffer| {
+            self.busy.set(true);
+            match self.storage.write(buffer, self.address.get(), len) {
+                Ok(()) => true,
+                Err(_) => {
+                    self.busy.set(false);
+                    false
+                }
+            }
+        });
+        if !started {
+            self.fail(SENSE_WRITE_ERROR);
+        }
+        started
+    }
+
+    /// Move to the status stage once the data stage is over.
+    fn data_out_done(&self) {
+        if self.transferred.get() >= self.data_len.get() {
+            self.state.set(State::Status);
+            self.controller.endpoint_resume_in(self.endpoint);
+        } else if self.out_delayed.replace(false) {
+            self.controller.endpoint_resume_out(self.endpoint);
+        }
+    }
+
+    /// Write the next packet of the data stage to the IN buffer.
+    fn data_in_packet(&self) -> hil::usb::InResult {
+        let packet = &self.in_buffer.buf;
+        let offset = self.buffer_offset.get();
+        if offset >= self.buffer_len.get() && self.to_read.get() > 0 && self.read_next() {
+            self.in_delayed.set(true);
+            return hil::usb::InResult::Delay;
+        }
+
+        let remaining = self.data_len.get() - self.transferred.get();
+        let sent = if offset < self.buffer_len.get() {
+            let len = cmp::min(
+                cmp::min(packet.len(), self.buffer_len.get() - offset),
+                remaining,
+            );
+            self.buffer.map(|buffer| {
+                for i in 0..len {
+                    packet[i].set(buffer[offset + i]);
+                }
+            });
+            self.buffer_offset.set(offset + len);
+            len
+        } else {
+            // Nothing left of the command, pad the data stage.
+            let len = cmp::min(packet.len(), remaining);
+            for b in packet[..len].iter() {
+                b.set(0);
+            }
+            len
+        };
+        self.transferred.set(self.transferred.get() + sent);
+        if self.transferred.get() >= self.data_len.get() {
+            self.state.set(State::Status);
+        }
+        hil::usb::InResult::Packet(sent)
+    }
+
+    /// Write the CSW of the current command to the IN buffer.
+    fn status_packet(&self) -> hil::usb::InResult {
+        let packet = &self.in_buffer.buf;
+        let residue = self.data_len.get().saturating_sub(self.data_valid.get()) as u32;
+        let fields = [CSW_SIGNATURE, self.tag.get(), residue];
+        for (i, field) in fields.iter().enumerate() {
+            for (j, b) in field.to_le_bytes().iter().enumerate() {
+                packet[i * 4 + j].set(*b);
+            }
+        }
+        packet[12].set(self.status.get());
+
+        self.state.set(State::Command);
+        if self.out_delayed.replace(false) {
+            self.controller.endpoint_resume_out(self.endpoint);
+        }
+        hil::usb::InResult::Packet(CSW_LEN)
+    }
+
+    /// Take a packet of the data stage from the OUT buffer.
+    fn data_out_packet(&self, packet_bytes: usize) {
+        let transferred = self.transferred.get();
+        let len = cmp::min(
+            packet_bytes,
+            self.data_valid.get().saturating_sub(transferred),
+        );
+        let packet = &self.out_buffer.buf;
+        let offset = self.buffer_len.get();
+        let full = self
+            .buffer
+            .map(|buffer| {
+                let len = cmp::min(len, buffer.len() - offset);
+                for i in 0..len {
+                    buffer[offset + i] = packet[i].get();
+                }
+                self.buffer_len.set(offset + len);
+                offset + len == buffer.len()
+            })
+            .unwrap_or(false);
+        self.transferred.set(transferred + packet_bytes);
+
+        let last = self.transferred.get() >= self.data_valid.get();
+        if self.buffer_len.get() > 0 && (full || last) && self.write_buffer() {
+            // The data stage continues once the write is done.
+            return;
+        }
+        self.data_out_done();
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for MassStorage<'a, U> {
+    fn num_interfaces(&self) -> u8 {
+        1
+    }
+
+    fn has_endpoint(&self, endpoint: usize) -> bool {
+        endpoint == self.endpoint
+    }
+
+    fn write_descriptors(&self, first_interface: u8, buf: &[Cell<u8>]) -> usize {
+        let interface = InterfaceDescriptor {
+            interface_number: first_interface,
+            num_endpoints: 2,
+            interface_class: 0x08,    // Mass storage
+            interface_subclass: 0x06, // SCSI transparent command set
+            interface_protocol: 0x50, // Bulk-Only Transport
+            ..InterfaceDescriptor::default()
+        };
+        let endpoints = [
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+        ];
+
+        let mut len = interface.write_to(buf);
+        for d in endpoints.iter() {
+            len += d.write_to(&buf[len..]);
+        }
+        len
+    }
+
+    fn enable(&'a self) {
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint, &self.in_buffer.buf);
+        self.controller
+            .endpoint_set_out_buffer(self.endpoint, &self.out_buffer.buf);
+        self.controller
+            .endpoint_in_out_enable(TransferType::Bulk, self.endpoint);
+    }
+
+    fn bus_reset(&'a self) {
+        self.reset();
+    }
+
+    fn ctrl_setup(&'a self, setup_data: &SetupData) -> hil::usb::CtrlSetupResult {
+        match (
+            setup_data.request_type.request_type(),
+            setup_data.request_code,
+        ) {
+            (RequestType::Class, REQUEST_RESET) => {
+                self.reset();
+                hil::usb::CtrlSetupResult::Ok
+            }
+            (RequestType::Class, REQUEST_GET_MAX_LUN) => hil::usb::CtrlSetupResult::Ok,
+            _ => hil::usb::CtrlSetupResult::ErrGeneric,
+        }
+    }
+
+    fn ctrl_in(&'a self, buf: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        // The data stage of GET MAX LUN: there is only logical unit 0.
+        buf[0].set(0);
+        hil::usb::CtrlInResult::Packet(1, true)
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                if self.busy.get() {
+                    self.in_delayed.set(true);
+                    return hil::usb::InResult::Delay;
+                }
+                match self.state.get() {
+                    State::DataIn => self.data_in_packet(),
+                    State::Status => self.status_packet(),
+                    State::Command | State::DataOut => {
+                        self.in_delayed.set(true);
+                        hil::usb::InResult::Delay
+                    }
+                }
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                hil::usb::InResult::Error
+            }
+        }
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                if self.busy.get() {
+                    self.out_delayed.set(true);
+                    return hil::usb::OutResult::Delay;
+                }
+                match self.state.get() {
+                    State::Command => {
+                        self.handle_cbw(packet_bytes);
+                        hil::usb::OutResult::Ok
+                    }
+                    State::DataOut => {
+                        self.data_out_packet(packet_bytes as usize);
+                        hil::usb::OutResult::Ok
+                    }
+                    State::DataIn | State::Status => {
+                        self.out_delayed.set(true);
+                        hil::usb::OutResult::Delay
+                    }
+                }
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                hil::usb::OutResult::Error
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        match self.state.get() {
+            State::DataIn | State::Status => self.controller.endpoint_resume_in(self.endpoint),
+            State::Command | State::DataOut => {}
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::nonvolatile_storage::NonvolatileStorageClient<'static>
+    for MassStorage<'a, U>
+{
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.busy.set(false);
+        if self.state.get() == State::DataIn {
+            self.buffer_len.set(length);
+            self.buffer_offset.set(0);
+            self.address.set(self.address.get() + length);
+            self.to_read.set(self.to_read.get().saturating_sub(length));
+            if length == 0 {
+                // Nothing more will come, pad the rest.
+                self.to_read.set(0);
+                self.fail(SENSE_MEDIUM_ERROR);
+            }
+        }
+        if self.in_delayed.replace(false) {
+            self.controller.endpoint_resume_in(self.endpoint);
+        }
+        if self.state.get() == State::Command && self.out_delayed.replace(false) {
+            self.controller.endpoint_resume_out(self.endpoint);
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.busy.set(false);
+        match self.state.get() {
+            State::DataOut => {
+                self.address.set(self.address.get() + length);
+                self.data_out_done();
+            }
+            State::Command => {
+                if self.out_delayed.replace(false) {
+                    self.controller.endpoint_resume_out(self.endpoint);
+                }
+            }
+            State::DataIn | State::Status => {}
+        }
+    }
+}
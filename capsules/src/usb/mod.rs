@@ -1,6 +1,10 @@
 pub mod cdc;
+pub mod composite;
 pub mod ctap;
 pub mod descriptors;
+pub mod keyboard_mouse;
+pub mod mass_storage;
 pub mod usb_user;
 pub mod usbc_client;
 pub mod usbc_client_ctrl;
+pub mod vendor_bulk;
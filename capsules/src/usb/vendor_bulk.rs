@@ -0,0 +1,363 @@
+//! Vendor-specific bulk interface for USB composite devices.
+//!
+//! This capsule is a function of a `UsbComposite` device with one interface
+//! of the vendor-specific class, which transfers data in both directions on
+//! a single bulk endpoint number. Host software reaches it with a generic
+//! USB library, such as libusb, rather than a class driver. It provides the
+//! UART interfaces, so that it can carry the same protocols as a serial
+//! port.
+//!
+//! Unlike a CDC-ACM serial port, the host is asked to wait while there is no
+//! receive buffer, so no data is dropped. Received packets that do not fit
+//! in the rest of the receive buffer are truncated, so receive buffers
+//! should hold a multiple of 64 bytes.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let bulk = static_init!(
+//!     capsules::usb::vendor_bulk::VendorBulk<'static, nrf52840::usbd::Usbd>,
+//!     capsules::usb::vendor_bulk::VendorBulk::new(
+//!         &nrf52840::usbd::USBD,
+//!         5,
+//!         dynamic_deferred_caller,
+//!     )
+//! );
+//! bulk.initialize_callback_handle(
+//!     dynamic_deferred_caller.register(bulk).unwrap(), // Unwrap fail = no deferred call slot available for vendor bulk
+//! );
+//! composite.add_function(bulk).unwrap();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::UsbFunction;
+use super::descriptors::Buffer64;
+use super::descriptors::Descriptor;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil;
+use kernel::hil::uart;
+use kernel::hil::usb::TransferType;
+use kernel::ErrorCode;
+
+pub struct VendorBulk<'a, U: 'a> {
+    /// The USB hardware controller.
+    controller: &'a U,
+
+    /// Endpoint for transferring data in both directions.
+    endpoint: usize,
+
+    /// 64 byte buffers for the IN and OUT directions of the endpoint.
+    in_buffer: Buffer64,
+    out_buffer: Buffer64,
+
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    tx_client: OptionalCell<&'a dyn uart::TransmitClient>,
+
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_offset: Cell<usize>,
+    rx_client: OptionalCell<&'a dyn uart::ReceiveClient>,
+    /// Whether a packet from the host is waiting for a receive buffer.
+    rx_delayed: Cell<bool>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    /// Whether an aborted receive must be signaled in a deferred call.
+    deferred_call_pending_abortrx: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> VendorBulk<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        endpoint: usize,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> VendorBulk<'a, U> {
+        VendorBulk {
+            controller: controller,
+            endpoint: endpoint,
+            in_buffer: Buffer64::default(),
+            out_buffer: Buffer64::default(),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            tx_client: OptionalCell::empty(),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_offset: Cell::new(0),
+            rx_client: OptionalCell::empty(),
+            rx_delayed: Cell::new(false),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            deferred_call_pending_abortrx: Cell::new(false),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for VendorBulk<'a, U> {
+    fn num_interfaces(&self) -> u8 {
+        1
+    }
+
+    fn has_endpoint(&self, endpoint: usize) -> bool {
+        endpoint == self.endpoint
+    }
+
+    fn write_descriptors(&self, first_interface: u8, buf: &[Cell<u8>]) -> usize {
+        let interface = InterfaceDescriptor {
+            interface_number: first_interface,
+            num_endpoints: 2,
+            interface_class: 0xff, // vendor specific
+            interface_subclass: 0x00,
+            interface_protocol: 0x00,
+            ..InterfaceDescriptor::default()
+        };
+        let endpoints = [
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+        ];
+
+        let mut len = interface.write_to(buf);
+        for d in endpoints.iter() {
+            len += d.write_to(&buf[len..]);
+        }
+        len
+    }
+
+    fn enable(&'a self) {
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint, &self.in_buffer.buf);
+        self.controller
+            .endpoint_set_out_buffer(self.endpoint, &self.out_buffer.buf);
+        self.controller
+            .endpoint_in_out_enable(TransferType::Bulk, self.endpoint);
+    }
+
+    fn bus_reset(&'a self) {}
+
+    fn ctrl_setup(&'a self, _setup_data: &SetupData) -> hil::usb::CtrlSetupResult {
+        // There are no vendor requests.
+        hil::usb::CtrlSetupResult::ErrGeneric
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                self.tx_buffer
+                    .take()
+                    .map_or(hil::usb::InResult::Delay, |tx_buf| {
+                        let offset = self.tx_offset.get();
+                        let remaining = self.tx_len.get() - offset;
+                        if remaining > 0 {
+                            let packet = &self.in_buffer.buf;
+                            let to_send = cmp::min(packet.len(), remaining);
+                            for i in 0..to_send {
+                                packet[i].set(tx_buf[offset + i]);
+                            }
+                            self.tx_offset.set(offset + to_send);
+                            self.tx_buffer.replace(tx_buf);
+                            hil::usb::InResult::Packet(to_send)
+                        } else {
+                            self.tx_client.map(move |tx_client| {
+                                tx_client.transmitted_buffer(tx_buf, self.tx_len.get(), Ok(()))
+                            });
+                            hil::usb::InResult::Delay
+                        }
+                    })
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                hil::usb::InResult::Error
+            }
+        }
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => self.rx_buffer.take().map_or_else(
+                || {
+                    // Make the host wait until there is a receive buffer.
+                    self.rx_delayed.set(true);
+                    hil::usb::OutResult::Delay
+                },
+                |rx_buf| {
+                    let rx_offset = self.rx_offset.get();
+                    let copy_length = cmp::min(packet_bytes as usize, rx_buf.len() - rx_offset);
+                    let packet = &self.out_buffer.buf;
+                    for i in 0..copy_length {
+                        rx_buf[rx_offset + i] = packet[i].get();
+                    }
+
+                    let total_received_bytes = rx_offset + copy_length;
+                    self.rx_offset.set(total_received_bytes);
+                    if total_received_bytes >= self.rx_len.get() {
+                        self.rx_client.map(move |client| {
+                            client.received_buffer(
+                                rx_buf,
+                                total_received_bytes,
+                                Ok(()),
+                                uart::Error::None,
+                            );
+                        });
+                    } else {
+                        self.rx_buffer.replace(rx_buf);
+                    }
+                    hil::usb::OutResult::Ok
+                },
+            ),
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                hil::usb::OutResult::Error
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        self.tx_buffer.take().map(|tx_buf| {
+            if self.tx_len.get() > self.tx_offset.get() {
+                self.tx_buffer.replace(tx_buf);
+                self.controller.endpoint_resume_in(self.endpoint);
+            } else {
+                self.tx_client.map(move |tx_client| {
+                    tx_client.transmitted_buffer(tx_buf, self.tx_len.get(), Ok(()))
+                });
+            }
+        });
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> uart::Configure for VendorBulk<'a, U> {
+    fn configure(&self, _parameters: uart::Parameters) -> Result<(), ErrorCode> {
+        // There is no line to configure.
+        Ok(())
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> uart::Transmit<'a> for VendorBulk<'a, U> {
+    fn set_transmit_client(&self, client: &'a dyn uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.tx_buffer.is_some() {
+            Err((ErrorCode::BUSY, tx_buffer))
+        } else if tx_len > tx_buffer.len() {
+            Err((ErrorCode::SIZE, tx_buffer))
+        } else {
+            self.tx_len.set(tx_len);
+            self.tx_offset.set(0);
+            self.tx_buffer.replace(tx_buffer);
+            self.controller.endpoint_resume_in(self.endpoint);
+            Ok(())
+        }
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+
+    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> uart::Receive<'a> for VendorBulk<'a, U> {
+    fn set_receive_client(&self, client: &'a dyn uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.rx_buffer.is_some() {
+            Err((ErrorCode::BUSY, rx_buffer))
+        } else if rx_len > rx_buffer.len() {
+            Err((ErrorCode::SIZE, rx_buffer))
+        } else {
+            self.rx_buffer.replace(rx_buffer);
+            self.rx_offset.set(0);
+            self.rx_len.set(rx_len);
+            if self.rx_delayed.replace(false) {
+                self.controller.endpoint_resume_out(self.endpoint);
+            }
+            Ok(())
+        }
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        if self.rx_buffer.is_none() {
+            Ok(())
+        } else {
+            self.deferred_call_pending_abortrx.set(true);
+            self.handle.map(|handle| self.deferred_caller.set(*handle));
+            Err(ErrorCode::BUSY)
+        }
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> DynamicDeferredCallClient for VendorBulk<'a, U> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.deferred_call_pending_abortrx.replace(false) {
+            self.rx_buffer.take().map(|rx_buf| {
+                let received = self.rx_offset.get();
+                self.rx_client.map(move |client| {
+                    client.received_buffer(
+                        rx_buf,
+                        received,
+                        Err(ErrorCode::CANCEL),
+                        uart::Error::None,
+                    );
+                });
+            });
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> uart::Uart<'a> for VendorBulk<'a, U> {}
+impl<'a, U: hil::usb::UsbController<'a>> uart::UartData<'a> for VendorBulk<'a, U> {}
@@ -1,12 +1,35 @@
 //! Virtualize the Alarm interface to enable multiple users of an underlying
 //! alarm hardware peripheral.
+//!
+//! A virtual alarm can also be set for a deadline further away than one
+//! period of the underlying counter with
+//! [`VirtualMuxAlarm::set_alarm_periods`]. The deadline is split into
+//! segments of at most half a counter period, chained from one another, so
+//! that every comparison against the counter stays unambiguous; the client is
+//! only called once the final segment has elapsed.
 
 use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
 use kernel::common::{List, ListLink, ListNode};
 use kernel::hil::time::{self, Alarm, Ticks, Time};
+use kernel::power::PowerClient;
 use kernel::ErrorCode;
 
+/// Number of ticks in one period of a counter with ticks `T`. Counters wider than 32 bits are
+/// treated as having a 2^32 tick period, which only shortens the segments of long alarms.
+fn counter_period<T: Ticks>() -> u64 {
+    T::max_value().into_u32() as u64 + 1
+}
+
+/// Alarms that expire sooner than this keep the chip out of deep sleep,
+/// whose wake-up latency could make them late.
+const DEEP_SLEEP_MIN_MS: u32 = 5;
+
+/// Longest segment a long alarm with ticks `T` is split into.
+fn max_segment<T: Ticks>() -> u64 {
+    counter_period::<T>() / 2
+}
+
 /// An object to multiplex multiple "virtual" alarms over a single underlying alarm. A
 /// `VirtualMuxAlarm` is a node in a linked list of alarms that share the same underlying alarm.
 pub struct VirtualMuxAlarm<'a, A: Alarm<'a>> {
@@ -20,6 +43,9 @@ pub struct VirtualMuxAlarm<'a, A: Alarm<'a>> {
     /// Whether this alarm is currently armed, i.e. whether it should fire when the time has
     /// elapsed.
     armed: Cell<bool>,
+    /// Ticks still to elapse after `reference + dt` before the client is called, for alarms
+    /// longer than one counter period.
+    remaining: Cell<u64>,
     /// Next alarm in the list.
     next: ListLink<'a, VirtualMuxAlarm<'a, A>>,
     /// Alarm client for this node in the list.
@@ -40,10 +66,35 @@ impl<'a, A: Alarm<'a>> VirtualMuxAlarm<'a, A> {
             reference: Cell::new(zero),
             dt: Cell::new(zero),
             armed: Cell::new(false),
+            remaining: Cell::new(0),
             next: ListLink::empty(),
             client: OptionalCell::empty(),
         }
     }
+
+    /// Set an alarm that fires `periods` full periods of the underlying counter plus `dt` ticks
+    /// after `reference`. This allows deadlines longer than the counter period, such as
+    /// hour-scale alarms on a 32-bit microsecond counter.
+    pub fn set_alarm_periods(&self, reference: A::Ticks, periods: u32, dt: A::Ticks) {
+        let total = periods as u64 * counter_period::<A::Ticks>() + dt.into_u32() as u64;
+        let segment = core::cmp::min(total, max_segment::<A::Ticks>());
+        self.set_alarm(reference, A::Ticks::from(segment as u32));
+        self.remaining.set(total - segment);
+    }
+
+    /// Advance a long alarm whose current segment has elapsed to its next segment. Returns
+    /// `false` if there are no segments left and the alarm should fire.
+    fn next_segment(&self) -> bool {
+        let remaining = self.remaining.get();
+        if remaining == 0 {
+            return false;
+        }
+        let segment = core::cmp::min(remaining, max_segment::<A::Ticks>());
+        self.reference.set(self.get_alarm());
+        self.dt.set(A::Ticks::from(segment as u32));
+        self.remaining.set(remaining - segment);
+        true
+    }
 }
 
 impl<'a, A: Alarm<'a>> Time for VirtualMuxAlarm<'a, A> {
@@ -64,6 +115,7 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
         self.reference.set(A::Ticks::from(0 as u32));
         self.dt.set(A::Ticks::from(0 as u32));
         self.armed.set(false);
+        self.remaining.set(0);
         self.client.set(client);
     }
 
@@ -73,6 +125,7 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
         }
 
         self.armed.set(false);
+        self.remaining.set(0);
 
         let enabled = self.mux.enabled.get() - 1;
         self.mux.enabled.set(enabled);
@@ -93,6 +146,7 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
         let enabled = self.mux.enabled.get();
         self.reference.set(reference);
         self.dt.set(dt);
+        self.remaining.set(0);
 
         if !self.armed.get() {
             self.mux.enabled.set(enabled + 1);
@@ -202,6 +256,10 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
                     )
             })
             .for_each(|cur| {
+                // A long alarm stays armed until its last segment elapses.
+                if cur.next_segment() {
+                    return;
+                }
                 cur.armed.set(false);
                 self.enabled.set(self.enabled.get() - 1);
                 //debug!("  Virtualizer: {:?} outside {:?}-{:?}, fire!", now, cur.reference.get(), cur.reference.get().wrapping_add(cur.dt.get()));
@@ -230,3 +288,105 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
         }
     }
 }
+
+impl<'a, A: Alarm<'a>> PowerClient for MuxAlarm<'a, A> {
+    fn deep_sleep_ready(&self) -> bool {
+        self.next_tick_vals.get().map_or(true, |(reference, dt)| {
+            let now = self.alarm.now();
+            let end = reference.wrapping_add(dt);
+            // An alarm that already expired has an interrupt pending, which
+            // keeps the chip awake anyway.
+            !now.within_range(reference, end)
+                || end.wrapping_sub(now) >= A::ticks_from_ms(DEEP_SLEEP_MIN_MS)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MuxAlarm, VirtualMuxAlarm};
+    use crate::test::mock_alarm::MockAlarm;
+    use crate::test::mock_process;
+    use core::cell::Cell;
+    use kernel::hil::time::{Alarm, AlarmClient, Ticks, Ticks24, Time};
+
+    /// Ticks in one period of a 24-bit counter.
+    const PERIOD: u32 = 1 << 24;
+
+    #[derive(Default)]
+    struct Client {
+        fired: Cell<usize>,
+    }
+
+    impl AlarmClient for Client {
+        fn alarm(&self) {
+            self.fired.set(self.fired.get() + 1);
+        }
+    }
+
+    type Mock = MockAlarm<'static, Ticks24>;
+
+    /// A virtual alarm on a 24-bit mock counter, and its client.
+    fn virtual_alarm(
+        mux: &'static MuxAlarm<'static, Mock>,
+    ) -> (&'static VirtualMuxAlarm<'static, Mock>, &'static Client) {
+        let alarm = mock_process::leak(VirtualMuxAlarm::new(mux));
+        let client = mock_process::leak(Client::default());
+        alarm.set_alarm_client(client);
+        (alarm, client)
+    }
+
+    #[test]
+    fn test_alarm_over_several_counter_wraps() {
+        let alarm: &Mock = mock_process::leak(MockAlarm::new());
+        // Start close to a wrap of the counter.
+        alarm.set_now(PERIOD - 1000);
+        let mux = mock_process::leak(MuxAlarm::new(alarm));
+        alarm.set_alarm_client(mux);
+        let (long, long_client) = virtual_alarm(mux);
+        let (short, short_client) = virtual_alarm(mux);
+
+        let start = long.now();
+        long.set_alarm_periods(start, 3, Ticks24::from(5000));
+        let deadline = start.wrapping_add(Ticks24::from(5000));
+
+        // A short alarm set meanwhile fires on time, and does not disturb
+        // the long one.
+        short.set_alarm(short.now(), Ticks24::from(2000));
+        alarm.advance(2000);
+        assert_eq!(short_client.fired.get(), 1);
+        assert_eq!(long_client.fired.get(), 0);
+
+        // The long alarm stays armed through every wrap of the counter.
+        for _ in 0..3 {
+            alarm.advance(PERIOD - 1000);
+            assert!(long.is_armed());
+            alarm.advance(1000);
+        }
+        assert_eq!(long_client.fired.get(), 0);
+
+        // It fires once all periods and the remainder have elapsed.
+        alarm.advance(5000 - 2000 - 1);
+        assert_eq!(long_client.fired.get(), 0);
+        alarm.advance(1);
+        assert_eq!(long_client.fired.get(), 1);
+        assert_eq!(alarm.now(), deadline);
+        assert!(!long.is_armed());
+        assert_eq!(alarm.until_alarm(), None);
+    }
+
+    #[test]
+    fn test_periods_alarm_disarmed_midway() {
+        let alarm: &Mock = mock_process::leak(MockAlarm::new());
+        let mux = mock_process::leak(MuxAlarm::new(alarm));
+        alarm.set_alarm_client(mux);
+        let (long, client) = virtual_alarm(mux);
+
+        long.set_alarm_periods(long.now(), 2, Ticks24::from(0));
+        alarm.advance(PERIOD);
+        assert!(long.disarm().is_ok());
+        alarm.advance(2 * PERIOD);
+        assert_eq!(client.fired.get(), 0);
+        assert_eq!(alarm.until_alarm(), None);
+    }
+}
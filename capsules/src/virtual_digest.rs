@@ -1,31 +1,73 @@
 //! Virtualize the Digest interface to enable multiple users of an underlying
 //! Digest hardware peripheral.
+//!
+//! Each `VirtualMuxDigest` runs sessions on the shared engine. A session
+//! starts with the first `add_data()` or `run()` of a user and ends when its
+//! digest is done or it calls `clear_data()`. While a session runs, one
+//! operation of each other user is queued, and further operations fail with
+//! `BUSY`. The engine cannot save a partial hash, so the state saved for each
+//! user is its mode: before a session starts the engine is cleared and, if
+//! the user set an HMAC key, the key is loaded again.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! let mux_digest = static_init!(
+//!     MuxDigest<'static, lowrisc::hmac::Hmac, [u8; 32]>,
+//!     MuxDigest::new(&earlgrey::hmac::HMAC)
+//! );
+//! hil::digest::Digest::set_client(&earlgrey::hmac::HMAC, mux_digest);
+//!
+//! let virtual_digest_user = static_init!(
+//!     VirtualMuxDigest<'static, lowrisc::hmac::Hmac, [u8; 32]>,
+//!     VirtualMuxDigest::new(mux_digest)
+//! );
+//! virtual_digest_user.setup();
+//! ```
 
 use core::cell::Cell;
 use core::marker::PhantomData;
-use kernel::common::cells::OptionalCell;
+use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::leasable_buffer::LeasableBuffer;
-use kernel::common::{ListLink, ListNode};
+use kernel::common::{List, ListLink, ListNode};
 use kernel::hil::digest;
 use kernel::hil::digest::DigestType;
 use kernel::ErrorCode;
 
-pub struct VirtualMuxDigest<'a, A: digest::Digest<'a, T>, T: DigestType> {
+/// The mode a user set for its sessions.
+#[derive(Clone, Copy)]
+enum Mode {
+    Sha256,
+    HmacSha256([u8; 32]),
+}
+
+pub struct VirtualMuxDigest<
+    'a,
+    A: digest::Digest<'a, T> + digest::HMACSha256,
+    T: 'static + DigestType,
+> {
     mux: &'a MuxDigest<'a, A, T>,
     next: ListLink<'a, VirtualMuxDigest<'a, A, T>>,
     client: OptionalCell<&'a dyn digest::Client<'a, T>>,
     id: u32,
+    mode: Cell<Mode>,
+    /// Operations waiting for the session of another user to end.
+    pending_data: OptionalCell<LeasableBuffer<'static, u8>>,
+    pending_digest: TakeCell<'static, T>,
 }
 
-impl<'a, A: digest::Digest<'a, T>, T: DigestType> ListNode<'a, VirtualMuxDigest<'a, A, T>>
-    for VirtualMuxDigest<'a, A, T>
+impl<'a, A: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType>
+    ListNode<'a, VirtualMuxDigest<'a, A, T>> for VirtualMuxDigest<'a, A, T>
 {
     fn next(&self) -> &'a ListLink<VirtualMuxDigest<'a, A, T>> {
         &self.next
     }
 }
 
-impl<'a, A: digest::Digest<'a, T>, T: DigestType> VirtualMuxDigest<'a, A, T> {
+impl<'a, A: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> VirtualMuxDigest<'a, A, T> {
     pub fn new(mux_digest: &'a MuxDigest<'a, A, T>) -> VirtualMuxDigest<'a, A, T> {
         let id = mux_digest.next_id.get();
         mux_digest.next_id.set(id + 1);
@@ -35,11 +77,23 @@ impl<'a, A: digest::Digest<'a, T>, T: DigestType> VirtualMuxDigest<'a, A, T> {
             next: ListLink::empty(),
             client: OptionalCell::empty(),
             id: id,
+            mode: Cell::new(Mode::Sha256),
+            pending_data: OptionalCell::empty(),
+            pending_digest: TakeCell::empty(),
         }
     }
+
+    /// Register this user with the mux, so its queued operations run.
+    pub fn setup(&'a self) {
+        self.mux.users.push_head(self);
+    }
+
+    fn has_pending(&self) -> bool {
+        self.pending_data.is_some() || self.pending_digest.is_some()
+    }
 }
 
-impl<'a, A: digest::Digest<'a, T>, T: DigestType> digest::Digest<'a, T>
+impl<'a, A: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> digest::Digest<'a, T>
     for VirtualMuxDigest<'a, A, T>
 {
     /// Set the client instance which will receive `add_data_done()` and
@@ -49,21 +103,21 @@ impl<'a, A: digest::Digest<'a, T>, T: DigestType> digest::Digest<'a, T>
     }
 
     /// Add data to the digest IP.
-    /// All data passed in is fed to the Digest hardware block.
+    /// All data passed in is fed to the Digest hardware block, once the
+    /// session of any other user ends.
     /// Returns the number of bytes written on success
     fn add_data(
         &self,
         data: LeasableBuffer<'static, u8>,
     ) -> Result<usize, (ErrorCode, &'static mut [u8])> {
-        // Check if any mux is enabled. If it isn't we enable it for us.
-        if self.mux.running.get() == false {
-            self.mux.running.set(true);
-            self.mux.running_id.set(self.id);
+        if self.mux.acquire(self) {
             self.mux.digest.add_data(data)
-        } else if self.mux.running_id.get() == self.id {
-            self.mux.digest.add_data(data)
-        } else {
+        } else if self.has_pending() {
             Err((ErrorCode::BUSY, data.take()))
+        } else {
+            let len = data.len();
+            self.pending_data.set(data);
+            Ok(len)
         }
     }
 
@@ -71,79 +125,182 @@ impl<'a, A: digest::Digest<'a, T>, T: DigestType> digest::Digest<'a, T>
     /// This doesn't return anything, instead the client needs to have
     /// set a `hash_done` handler.
     fn run(&'a self, digest: &'static mut T) -> Result<(), (ErrorCode, &'static mut T)> {
-        // Check if any mux is enabled. If it isn't we enable it for us.
-        if self.mux.running.get() == false {
-            self.mux.running.set(true);
-            self.mux.running_id.set(self.id);
+        if self.mux.acquire(self) {
             self.mux.digest.run(digest)
-        } else if self.mux.running_id.get() == self.id {
-            self.mux.digest.run(digest)
-        } else {
+        } else if self.pending_digest.is_some() {
             Err((ErrorCode::BUSY, digest))
+        } else {
+            // Runs after the queued data, if any, is added.
+            self.pending_digest.replace(digest);
+            Ok(())
         }
     }
 
     /// Disable the Digest hardware and clear the keys and any other sensitive
     /// data
     fn clear_data(&self) {
-        if self.mux.running_id.get() == self.id {
-            self.mux.running.set(false);
-            self.mux.digest.clear_data()
+        self.mode.set(Mode::Sha256);
+        if self.mux.is_running(self) {
+            self.mux.release();
+            self.mux.do_next();
         }
     }
 }
 
-impl<'a, A: digest::Digest<'a, T>, T: DigestType> digest::Client<'a, T>
+impl<'a, A: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> digest::HMACSha256
     for VirtualMuxDigest<'a, A, T>
 {
-    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
-        self.client
-            .map(move |client| client.add_data_done(result, data));
-    }
-
-    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut T) {
-        self.client
-            .map(move |client| client.hash_done(result, digest));
+    /// Set the HMAC key of this user. It is loaded in to the hardware when
+    /// the next session of this user starts, or now if it is running.
+    fn set_mode_hmacsha256(&self, key: &[u8; 32]) -> Result<(), ErrorCode> {
+        self.mode.set(Mode::HmacSha256(*key));
+        if self.mux.is_running(self) {
+            self.mux.digest.set_mode_hmacsha256(key)
+        } else {
+            Ok(())
+        }
     }
 }
 
-impl<'a, A: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> digest::HMACSha256
-    for VirtualMuxDigest<'a, A, T>
+impl<'a, A: digest::Digest<'a, T> + digest::HMACSha256 + digest::Sha256, T: DigestType>
+    digest::Sha256 for VirtualMuxDigest<'a, A, T>
 {
-    fn set_mode_hmacsha256(&self, key: &[u8; 32]) -> Result<(), ErrorCode> {
-        // Check if any mux is enabled. If it isn't we enable it for us.
-        if self.mux.running.get() == false {
-            self.mux.running.set(true);
-            self.mux.running_id.set(self.id);
-            self.mux.digest.set_mode_hmacsha256(key)
-        } else if self.mux.running_id.get() == self.id {
-            self.mux.digest.set_mode_hmacsha256(key)
+    /// Forget the HMAC key of this user, so its next sessions compute plain
+    /// digests. If it is running, the engine changes mode now.
+    fn set_mode_sha256(&self) -> Result<(), ErrorCode> {
+        self.mode.set(Mode::Sha256);
+        if self.mux.is_running(self) {
+            self.mux.digest.set_mode_sha256()
         } else {
-            Err(ErrorCode::BUSY)
+            Ok(())
         }
     }
 }
 
-/// Calling a 'set_mode*()' function from a `VirtualMuxDigest` will mark that
-/// `VirtualMuxDigest` as the one that has been enabled and running. Until that
-/// Mux calls `clear_data()` it will be the only `VirtualMuxDigest` that can
-/// interact with the underlying device.
-pub struct MuxDigest<'a, A: digest::Digest<'a, T>, T: DigestType> {
+/// Multiplexes the sessions of `VirtualMuxDigest` users on one Digest
+/// peripheral. The mux must be the client of the peripheral.
+pub struct MuxDigest<'a, A: digest::Digest<'a, T> + digest::HMACSha256, T: 'static + DigestType> {
     digest: &'a A,
+    users: List<'a, VirtualMuxDigest<'a, A, T>>,
     running: Cell<bool>,
     running_id: Cell<u32>,
     next_id: Cell<u32>,
     phantom: PhantomData<&'a T>,
 }
 
-impl<'a, A: digest::Digest<'a, T>, T: DigestType> MuxDigest<'a, A, T> {
+impl<'a, A: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> MuxDigest<'a, A, T> {
     pub const fn new(digest: &'a A) -> MuxDigest<'a, A, T> {
         MuxDigest {
             digest: digest,
+            users: List::new(),
             running: Cell::new(false),
             running_id: Cell::new(0),
             next_id: Cell::new(0),
             phantom: PhantomData,
         }
     }
+
+    fn is_running(&self, user: &VirtualMuxDigest<'a, A, T>) -> bool {
+        self.running.get() && self.running_id.get() == user.id
+    }
+
+    /// Returns whether `user` has the engine, starting a session for it if
+    /// the engine is free.
+    fn acquire(&self, user: &VirtualMuxDigest<'a, A, T>) -> bool {
+        if self.running.get() {
+            return self.running_id.get() == user.id;
+        }
+        self.running.set(true);
+        self.running_id.set(user.id);
+        // Restore the mode of the user on a clean engine.
+        self.digest.clear_data();
+        if let Mode::HmacSha256(key) = user.mode.get() {
+            if self.digest.set_mode_hmacsha256(&key).is_err() {
+                self.release();
+                return false;
+            }
+        }
+        true
+    }
+
+    /// End the running session, clearing any key from the engine.
+    fn release(&self) {
+        self.running.set(false);
+        self.digest.clear_data();
+    }
+
+    fn running_user(&self) -> Option<&'a VirtualMuxDigest<'a, A, T>> {
+        if !self.running.get() {
+            return None;
+        }
+        self.users
+            .iter()
+            .find(|user| user.id == self.running_id.get())
+    }
+
+    /// Start a session for the next user with a queued operation.
+    fn do_next(&self) {
+        while !self.running.get() {
+            let user = match self.users.iter().find(|user| user.has_pending()) {
+                Some(user) => user,
+                None => return,
+            };
+            if !self.acquire(user) {
+                // The mode of the user could not be restored.
+                user.pending_data.take().map(|data| {
+                    user.client
+                        .map(|client| client.add_data_done(Err(ErrorCode::FAIL), data.take()));
+                });
+                user.pending_digest.take().map(|digest| {
+                    user.client
+                        .map(move |client| client.hash_done(Err(ErrorCode::FAIL), digest));
+                });
+                continue;
+            }
+            self.start_pending(user);
+        }
+    }
+
+    /// Start the queued operation of `user`, which has the engine.
+    fn start_pending(&self, user: &'a VirtualMuxDigest<'a, A, T>) {
+        if let Some(data) = user.pending_data.take() {
+            if let Err((e, data)) = self.digest.add_data(data) {
+                self.release();
+                user.client
+                    .map(move |client| client.add_data_done(Err(e), data));
+            }
+        } else if let Some(digest) = user.pending_digest.take() {
+            if let Err((e, digest)) = self.digest.run(digest) {
+                self.release();
+                user.client
+                    .map(move |client| client.hash_done(Err(e), digest));
+            }
+        }
+    }
+}
+
+impl<'a, A: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> digest::Client<'a, T>
+    for MuxDigest<'a, A, T>
+{
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        self.running_user().map(move |user| {
+            user.client
+                .map(move |client| client.add_data_done(result, data));
+            // A digest queued behind the data runs now.
+            if self.is_running(user) && user.pending_data.is_none() {
+                self.start_pending(user);
+            }
+        });
+        self.do_next();
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut T) {
+        let user = self.running_user();
+        self.release();
+        self.do_next();
+        user.map(move |user| {
+            user.client
+                .map(move |client| client.hash_done(result, digest));
+        });
+    }
 }
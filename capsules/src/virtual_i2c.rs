@@ -2,6 +2,26 @@
 //!
 //! `MuxI2C` provides shared access to a single I2C Master Bus for multiple
 //! users. `I2CDevice` provides access to a specific I2C address.
+//!
+//! A slave that holds the bus would block every user of the mux. With a
+//! `MuxI2CTimer`, an operation that does not complete within
+//! `TRANSACTION_TIMEOUT_MS` is aborted, its device gets `Error::Timeout`,
+//! and the mux moves on to the next operation:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//! # use capsules::virtual_i2c::MuxI2CTimer;
+//!
+//! let i2c_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm));
+//! let i2c_timer = static_init!(
+//!     MuxI2CTimer<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     MuxI2CTimer::new(i2c_mux, i2c_alarm));
+//! i2c_alarm.set_alarm_client(i2c_timer);
+//! i2c_mux.set_transaction_timer(i2c_timer);
+//! ```
 
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
@@ -10,6 +30,18 @@ use kernel::common::dynamic_deferred_call::{
 };
 use kernel::common::{List, ListLink, ListNode};
 use kernel::hil::i2c::{self, Error, I2CClient, I2CHwMasterClient};
+use kernel::hil::time::{self, Alarm};
+
+/// Time an operation has to complete before it is aborted.
+pub const TRANSACTION_TIMEOUT_MS: u32 = 100;
+
+/// Times out operations on behalf of a `MuxI2C`.
+pub trait TransactionTimer {
+    /// Call `MuxI2C::transaction_timeout()` after `timeout_ms` milliseconds.
+    fn start(&self, timeout_ms: u32);
+    /// Stop a running timeout.
+    fn cancel(&self);
+}
 
 pub struct MuxI2C<'a> {
     i2c: &'a dyn i2c::I2CMaster,
@@ -21,10 +53,12 @@ pub struct MuxI2C<'a> {
     smbus_inflight: OptionalCell<&'a SMBusDevice<'a>>,
     deferred_caller: &'a DynamicDeferredCall,
     handle: OptionalCell<DeferredCallHandle>,
+    timer: OptionalCell<&'a dyn TransactionTimer>,
 }
 
 impl I2CHwMasterClient for MuxI2C<'_> {
     fn command_complete(&self, buffer: &'static mut [u8], error: Error) {
+        self.timer.map(|timer| timer.cancel());
         if self.i2c_inflight.is_some() {
             self.i2c_inflight.take().map(move |device| {
                 device.command_complete(buffer, error);
@@ -54,6 +88,7 @@ impl<'a> MuxI2C<'a> {
             smbus_inflight: OptionalCell::empty(),
             deferred_caller: deferred_caller,
             handle: OptionalCell::empty(),
+            timer: OptionalCell::empty(),
         }
     }
 
@@ -61,6 +96,26 @@ impl<'a> MuxI2C<'a> {
         self.handle.replace(handle);
     }
 
+    pub fn set_transaction_timer(&self, timer: &'a dyn TransactionTimer) {
+        self.timer.set(timer);
+    }
+
+    fn start_timer(&self) {
+        self.timer.map(|timer| timer.start(TRANSACTION_TIMEOUT_MS));
+    }
+
+    /// The operation in flight did not complete in time. Abort it, recovering
+    /// the bus, and fail it with `Error::Timeout`.
+    pub fn transaction_timeout(&self) {
+        if self.i2c_inflight.is_none() && self.smbus_inflight.is_none() {
+            return;
+        }
+        // If the driver cannot abort, the operation completes as usual.
+        self.i2c.abort().map(|buffer| {
+            self.command_complete(buffer, Error::Timeout);
+        });
+    }
+
     fn enable(&self) {
         let enabled = self.enabled.get();
         self.enabled.set(enabled + 1);
@@ -89,10 +144,17 @@ impl<'a> MuxI2C<'a> {
             mnode.map(|node| {
                 node.buffer.take().map(|buf| {
                     match node.operation.get() {
-                        Op::Write(len) => self.i2c.write(node.addr, buf, len),
-                        Op::Read(len) => self.i2c.read(node.addr, buf, len),
+                        Op::Write(len) => {
+                            self.start_timer();
+                            self.i2c.write(node.addr, buf, len);
+                        }
+                        Op::Read(len) => {
+                            self.start_timer();
+                            self.i2c.read(node.addr, buf, len);
+                        }
                         Op::WriteRead(wlen, rlen) => {
-                            self.i2c.write_read(node.addr, buf, wlen, rlen)
+                            self.start_timer();
+                            self.i2c.write_read(node.addr, buf, wlen, rlen);
                         }
                         Op::CommandComplete(err) => {
                             self.command_complete(buf, err);
@@ -113,6 +175,7 @@ impl<'a> MuxI2C<'a> {
                 mnode.map(|node| {
                     node.buffer.take().map(|buf| match node.operation.get() {
                         Op::Write(len) => {
+                            self.start_timer();
                             match self.smbus.unwrap().smbus_write(node.addr, buf, len) {
                                 Ok(_) => {}
                                 Err(e) => {
@@ -123,6 +186,7 @@ impl<'a> MuxI2C<'a> {
                             };
                         }
                         Op::Read(len) => {
+                            self.start_timer();
                             match self.smbus.unwrap().smbus_read(node.addr, buf, len) {
                                 Ok(_) => {}
                                 Err(e) => {
@@ -133,6 +197,7 @@ impl<'a> MuxI2C<'a> {
                             };
                         }
                         Op::WriteRead(wlen, rlen) => {
+                            self.start_timer();
                             match self
                                 .smbus
                                 .unwrap()
@@ -177,6 +242,38 @@ impl<'a> DynamicDeferredCallClient for MuxI2C<'a> {
     }
 }
 
+/// Times out operations of a `MuxI2C` with an alarm.
+pub struct MuxI2CTimer<'a, A: Alarm<'a>> {
+    mux: &'a MuxI2C<'a>,
+    alarm: &'a A,
+}
+
+impl<'a, A: Alarm<'a>> MuxI2CTimer<'a, A> {
+    pub fn new(mux: &'a MuxI2C<'a>, alarm: &'a A) -> MuxI2CTimer<'a, A> {
+        MuxI2CTimer {
+            mux: mux,
+            alarm: alarm,
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> TransactionTimer for MuxI2CTimer<'a, A> {
+    fn start(&self, timeout_ms: u32) {
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(timeout_ms));
+    }
+
+    fn cancel(&self) {
+        let _ = self.alarm.disarm();
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for MuxI2CTimer<'a, A> {
+    fn alarm(&self) {
+        self.mux.transaction_timeout();
+    }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 enum Op {
     Idle,
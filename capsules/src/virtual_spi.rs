@@ -7,11 +7,13 @@ use kernel::hil;
 use kernel::ErrorCode;
 
 /// The Mux struct manages multiple Spi clients. Each client may have
-/// at most one outstanding Spi request.
+/// at most one outstanding Spi request. While a client holds its chip
+/// select low, only that client's requests are served.
 pub struct MuxSpiMaster<'a, Spi: hil::spi::SpiMaster> {
     spi: &'a Spi,
     devices: List<'a, VirtualSpiMasterDevice<'a, Spi>>,
     inflight: OptionalCell<&'a VirtualSpiMasterDevice<'a, Spi>>,
+    held: OptionalCell<&'a VirtualSpiMasterDevice<'a, Spi>>,
 }
 
 impl<Spi: hil::spi::SpiMaster> hil::spi::SpiMasterClient for MuxSpiMaster<'_, Spi> {
@@ -34,49 +36,65 @@ impl<'a, Spi: hil::spi::SpiMaster> MuxSpiMaster<'a, Spi> {
             spi: spi,
             devices: List::new(),
             inflight: OptionalCell::empty(),
+            held: OptionalCell::empty(),
         }
     }
 
     fn do_next_op(&self) {
-        if self.inflight.is_none() {
-            let mnode = self
-                .devices
-                .iter()
-                .find(|node| node.operation.get() != Op::Idle);
-            mnode.map(|node| {
-                self.spi.specify_chip_select(node.chip_select.get());
-                let op = node.operation.get();
-                // Need to set idle here in case callback changes state
-                node.operation.set(Op::Idle);
-                match op {
-                    Op::Configure(cpol, cpal, rate) => {
-                        // The `chip_select` type will be correct based on
-                        // what implemented `SpiMaster`.
-                        self.spi.set_clock(cpol);
-                        self.spi.set_phase(cpal);
-                        self.spi.set_rate(rate);
-                    }
-                    Op::ReadWriteBytes(len) => {
-                        // Only async operations want to block by setting
-                        // the devices as inflight.
-                        self.inflight.set(node);
-                        node.txbuffer.take().map(|txbuffer| {
-                            let rxbuffer = node.rxbuffer.take();
-                            let _ = self.spi.read_write_bytes(txbuffer, rxbuffer, len);
-                        });
-                    }
-                    Op::SetPolarity(pol) => {
-                        self.spi.set_clock(pol);
-                    }
-                    Op::SetPhase(pal) => {
-                        self.spi.set_phase(pal);
-                    }
-                    Op::SetRate(rate) => {
-                        self.spi.set_rate(rate);
-                    }
-                    Op::Idle => {} // Can't get here...
+        // Operations other than transfers complete synchronously, so keep
+        // going until a transfer is in flight or nothing is pending.
+        while self.inflight.is_none() {
+            let mnode = self.held.map_or_else(
+                || self.devices.iter().find(|node| node.is_pending()),
+                |node| Some(*node).filter(|node| node.is_pending()),
+            );
+            let node = match mnode {
+                Some(node) => node,
+                None => break,
+            };
+            self.spi.specify_chip_select(node.chip_select.get());
+            match node.hold.take() {
+                Some(true) => {
+                    self.spi.hold_low();
+                    self.held.set(node);
                 }
-            });
+                Some(false) => {
+                    self.spi.release_low();
+                    self.held.clear();
+                }
+                None => {}
+            }
+            let op = node.operation.get();
+            // Need to set idle here in case callback changes state
+            node.operation.set(Op::Idle);
+            match op {
+                Op::Configure(cpol, cpal, rate) => {
+                    // The `chip_select` type will be correct based on
+                    // what implemented `SpiMaster`.
+                    self.spi.set_clock(cpol);
+                    self.spi.set_phase(cpal);
+                    self.spi.set_rate(rate);
+                }
+                Op::ReadWriteBytes(len) => {
+                    // Only async operations want to block by setting
+                    // the devices as inflight.
+                    self.inflight.set(node);
+                    node.txbuffer.take().map(|txbuffer| {
+                        let rxbuffer = node.rxbuffer.take();
+                        let _ = self.spi.read_write_bytes(txbuffer, rxbuffer, len);
+                    });
+                }
+                Op::SetPolarity(pol) => {
+                    self.spi.set_clock(pol);
+                }
+                Op::SetPhase(pal) => {
+                    self.spi.set_phase(pal);
+                }
+                Op::SetRate(rate) => {
+                    self.spi.set_rate(rate);
+                }
+                Op::Idle => {} // Only the chip select hold changed
+            }
         }
     }
 }
@@ -97,6 +115,7 @@ pub struct VirtualSpiMasterDevice<'a, Spi: hil::spi::SpiMaster> {
     txbuffer: TakeCell<'static, [u8]>,
     rxbuffer: TakeCell<'static, [u8]>,
     operation: Cell<Op>,
+    hold: Cell<Option<bool>>,
     next: ListLink<'a, VirtualSpiMasterDevice<'a, Spi>>,
     client: OptionalCell<&'a dyn hil::spi::SpiMasterClient>,
 }
@@ -112,6 +131,7 @@ impl<'a, Spi: hil::spi::SpiMaster> VirtualSpiMasterDevice<'a, Spi> {
             txbuffer: TakeCell::empty(),
             rxbuffer: TakeCell::empty(),
             operation: Cell::new(Op::Idle),
+            hold: Cell::new(None),
             next: ListLink::empty(),
             client: OptionalCell::empty(),
         }
@@ -121,6 +141,10 @@ impl<'a, Spi: hil::spi::SpiMaster> VirtualSpiMasterDevice<'a, Spi> {
         self.mux.devices.push_head(self);
         self.client.set(client);
     }
+
+    fn is_pending(&self) -> bool {
+        self.operation.get() != Op::Idle || self.hold.get().is_some()
+    }
 }
 
 impl<Spi: hil::spi::SpiMaster> hil::spi::SpiMasterClient for VirtualSpiMasterDevice<'_, Spi> {
@@ -189,6 +213,16 @@ impl<Spi: hil::spi::SpiMaster> hil::spi::SpiMasterDevice for VirtualSpiMasterDev
     fn get_rate(&self) -> u32 {
         self.mux.spi.get_rate()
     }
+
+    fn hold_low(&self) {
+        self.hold.set(Some(true));
+        self.mux.do_next_op();
+    }
+
+    fn release_low(&self) {
+        self.hold.set(Some(false));
+        self.mux.do_next_op();
+    }
 }
 
 pub struct SpiSlaveDevice<'a, Spi: hil::spi::SpiSlave> {
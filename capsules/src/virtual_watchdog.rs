@@ -0,0 +1,219 @@
+//! Watchdog that kernel clients and processes must all check in with.
+//!
+//! The kernel loop tickles the chip's watchdog on every iteration, so the
+//! watchdog only catches a hung kernel: a process stuck in a loop is preempted
+//! and the kernel keeps running. This capsule sits between the kernel loop and
+//! the chip's watchdog, and only tickles it if every registered kernel client
+//! and every monitored process checked in within its window. A single hung
+//! client or process then lets the watchdog reset the board.
+//!
+//! Windows are measured in wall time, including while the chip sleeps and
+//! while processes wait in `yield`, so they must be longer than the longest a
+//! client waits between check ins.
+//!
+//! Usage
+//! -----
+//!
+//! The board returns the capsule from `Platform::watchdog()`, so that the
+//! kernel loop uses it instead of the chip's watchdog.
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let watchdog = static_init!(
+//!     capsules::virtual_watchdog::VirtualWatchdog<'static, sam4l::wdt::Wdt, sam4l::ast::Ast>,
+//!     capsules::virtual_watchdog::VirtualWatchdog::new(
+//!         &peripherals.wdt,
+//!         ast,
+//!         board_kernel.create_grant(&memory_allocation_capability)
+//!     )
+//! );
+//!
+//! // A kernel client that must check in every second.
+//! let radio_watchdog = static_init!(
+//!     capsules::virtual_watchdog::WatchdogUser<'static, sam4l::ast::Ast>,
+//!     capsules::virtual_watchdog::WatchdogUser::new(ast, 1000)
+//! );
+//! watchdog.register(radio_watchdog);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Start monitoring the process, which must then check in at least
+//!   every `data1` milliseconds. Counts as a check in.
+//! - `2`: Check in.
+//! - `3`: Stop monitoring the process.
+
+use core::cell::Cell;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::time::{Ticks, Time};
+use kernel::watchdog::WatchDog;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Watchdog as usize;
+
+/// A process monitored by the watchdog.
+pub struct App<K: Ticks> {
+    /// The window of the process, or `None` if it is not monitored.
+    window: Option<K>,
+    last_check_in: K,
+}
+
+impl<K: Ticks> Default for App<K> {
+    fn default() -> Self {
+        App {
+            window: None,
+            last_check_in: K::from(0),
+        }
+    }
+}
+
+/// A kernel client of the virtual watchdog.
+pub struct WatchdogUser<'a, T: Time> {
+    time: &'a T,
+    window: T::Ticks,
+    last_check_in: Cell<T::Ticks>,
+    next: ListLink<'a, WatchdogUser<'a, T>>,
+}
+
+impl<'a, T: Time> ListNode<'a, WatchdogUser<'a, T>> for WatchdogUser<'a, T> {
+    fn next(&'a self) -> &'a ListLink<'a, WatchdogUser<'a, T>> {
+        &self.next
+    }
+}
+
+impl<'a, T: Time> WatchdogUser<'a, T> {
+    /// The client must check in at least every `window_ms` milliseconds.
+    pub fn new(time: &'a T, window_ms: u32) -> WatchdogUser<'a, T> {
+        WatchdogUser {
+            time: time,
+            window: T::ticks_from_ms(window_ms),
+            last_check_in: Cell::new(time.now()),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Tell the watchdog that the client is alive.
+    pub fn check_in(&self) {
+        self.last_check_in.set(self.time.now());
+    }
+
+    fn expired(&self, now: T::Ticks) -> bool {
+        now.wrapping_sub(self.last_check_in.get()) > self.window
+    }
+}
+
+pub struct VirtualWatchdog<'a, W: WatchDog, T: Time> {
+    watchdog: &'a W,
+    time: &'a T,
+    users: List<'a, WatchdogUser<'a, T>>,
+    apps: Grant<App<T::Ticks>>,
+}
+
+impl<'a, W: WatchDog, T: Time> VirtualWatchdog<'a, W, T> {
+    pub fn new(
+        watchdog: &'a W,
+        time: &'a T,
+        grant: Grant<App<T::Ticks>>,
+    ) -> VirtualWatchdog<'a, W, T> {
+        VirtualWatchdog {
+            watchdog: watchdog,
+            time: time,
+            users: List::new(),
+            apps: grant,
+        }
+    }
+
+    /// Add a kernel client, which counts as a check in.
+    pub fn register(&self, user: &'a WatchdogUser<'a, T>) {
+        user.check_in();
+        self.users.push_head(user);
+    }
+
+    /// Whether every client and monitored process checked in within its
+    /// window.
+    fn all_checked_in(&self) -> bool {
+        let now = self.time.now();
+        if self.users.iter().any(|user| user.expired(now)) {
+            return false;
+        }
+        let expired = Cell::new(false);
+        self.apps.each(|_, app| {
+            if let Some(window) = app.window {
+                if now.wrapping_sub(app.last_check_in) > window {
+                    expired.set(true);
+                }
+            }
+        });
+        !expired.get()
+    }
+}
+
+impl<'a, W: WatchDog, T: Time> WatchDog for VirtualWatchdog<'a, W, T> {
+    fn setup(&self) {
+        self.watchdog.setup();
+    }
+
+    fn tickle(&self) {
+        if self.all_checked_in() {
+            self.watchdog.tickle();
+        }
+    }
+
+    fn suspend(&self) {
+        self.watchdog.suspend();
+    }
+
+    fn resume(&self) {
+        self.watchdog.resume();
+    }
+}
+
+impl<'a, W: WatchDog, T: Time> Driver for VirtualWatchdog<'a, W, T> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        let now = self.time.now();
+        let res = self.apps.enter(appid, |app| match command_num {
+            0 => CommandReturn::success(),
+
+            // start monitoring
+            1 => {
+                if data1 == 0 {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                app.window = Some(T::ticks_from_ms(data1 as u32));
+                app.last_check_in = now;
+                CommandReturn::success()
+            }
+
+            // check in
+            2 => {
+                if app.window.is_none() {
+                    return CommandReturn::failure(ErrorCode::OFF);
+                }
+                app.last_check_in = now;
+                CommandReturn::success()
+            }
+
+            // stop monitoring
+            3 => {
+                app.window = None;
+                CommandReturn::success()
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        });
+        res.unwrap_or_else(|err| CommandReturn::failure(err.into()))
+    }
+}
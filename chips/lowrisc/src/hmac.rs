@@ -267,3 +267,15 @@ impl hil::digest::HMACSha256 for Hmac<'_> {
         Ok(())
     }
 }
+
+impl hil::digest::Sha256 for Hmac<'_> {
+    fn set_mode_sha256(&self) -> Result<(), ErrorCode> {
+        let regs = self.registers;
+
+        // SHA-256 without the HMAC of the key
+        regs.cfg
+            .write(CFG::ENDIAN_SWAP::SET + CFG::SHA_EN::SET + CFG::DIGEST_SWAP::SET);
+
+        Ok(())
+    }
+}
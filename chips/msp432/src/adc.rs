@@ -6,7 +6,7 @@ use core::{mem, slice};
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::registers::interfaces::{ReadWriteable, Readable, Writeable};
 use kernel::common::registers::{
-    register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly,
+    register_bitfields, register_structs, FieldValue, ReadOnly, ReadWrite, WriteOnly,
 };
 use kernel::common::StaticRef;
 use kernel::hil;
@@ -496,6 +496,7 @@ pub struct Adc<'a> {
     resolution: AdcResolution,
     mode: Cell<AdcMode>,
     active_channel: Cell<Channel>,
+    reference: Cell<hil::adc::VoltageReference>,
     ref_module: OptionalCell<&'a dyn ref_module::AnalogReference>,
     timer: OptionalCell<&'a dyn timer::InternalTimer>,
     dma: OptionalCell<&'a dma::DmaChannel<'a>>,
@@ -513,6 +514,7 @@ impl Adc<'_> {
             resolution: DEFAULT_ADC_RESOLUTION,
             mode: Cell::new(AdcMode::Disabled),
             active_channel: Cell::new(Channel::Channel0),
+            reference: Cell::new(hil::adc::VoltageReference::Vdd),
             ref_module: OptionalCell::empty(),
             timer: OptionalCell::empty(), // must be TIMER_A3!
             dma: OptionalCell::empty(),
@@ -623,8 +625,8 @@ impl<'a> Adc<'a> {
             self.registers.mctl[i].modify(
                 // Set the input for the channel
                 MCTLx::INCHx.val(i as u32)
-                // Set Reference voltage to Internal AVCC for Vref+ and AVSS (GND) for Vref-
-                + MCTLx::VRSEL::AvccAvss
+                // Set the selected reference voltage for Vref+ and AVSS (GND) for Vref-
+                + self.vrsel()
                 // Configure the channel for single-ended mode
                 + MCTLx::DIF::SingleEnded
                 // Disable comparator window
@@ -669,6 +671,14 @@ impl<'a> Adc<'a> {
         self.registers.ctl0.modify(CTL0::ON::SET);
     }
 
+    fn vrsel(&self) -> FieldValue<u32, MCTLx::Register> {
+        match self.reference.get() {
+            hil::adc::VoltageReference::Internal => MCTLx::VRSEL::VRefBufferedAvss,
+            hil::adc::VoltageReference::External => MCTLx::VRSEL::VeRef,
+            hil::adc::VoltageReference::Vdd => MCTLx::VRSEL::AvccAvss,
+        }
+    }
+
     fn get_sample(&self, chan: Channel) -> u16 {
         // calculate the number of shifts which are necessary to align the sample to u16
         let shift = 8 - 2 * (self.resolution as usize);
@@ -881,7 +891,7 @@ impl hil::adc::Adc for Adc<'_> {
     }
 
     fn get_voltage_reference_mv(&self) -> Option<usize> {
-        self.ref_module.map(|ref_mod| ref_mod.ref_voltage_mv())
+        hil::adc::AdcReference::get_voltage_reference_uv(self).map(|uv| uv / 1000)
     }
 
     fn set_client(&self, _client: &'static dyn hil::adc::Client) {
@@ -986,3 +996,74 @@ impl hil::adc::AdcHighSpeed for Adc<'_> {
         }
     }
 }
+
+/// Samples sequences of a single channel, as the ADC samples one channel
+/// continuously.
+impl hil::adc::AdcReference for Adc<'_> {
+    /// Select the reference: the buffered reference of the REF module, which
+    /// the board sets up, VeREF+, or AVCC.
+    fn set_voltage_reference(
+        &self,
+        reference: hil::adc::VoltageReference,
+    ) -> Result<(), ErrorCode> {
+        if self.mode.get() != AdcMode::Disabled {
+            return Err(ErrorCode::BUSY);
+        }
+        self.reference.set(reference);
+        // The conversions are stopped, so the memory controls can be changed
+        for i in 0..AVAILABLE_ADC_CHANNELS {
+            self.registers.mctl[i].modify(self.vrsel());
+        }
+        Ok(())
+    }
+
+    fn get_voltage_reference(&self) -> hil::adc::VoltageReference {
+        self.reference.get()
+    }
+
+    /// AVCC is assumed to be 3.3 V.
+    fn get_voltage_reference_uv(&self) -> Option<usize> {
+        match self.reference.get() {
+            hil::adc::VoltageReference::Internal => self
+                .ref_module
+                .map(|ref_mod| ref_mod.ref_voltage_mv() * 1000),
+            hil::adc::VoltageReference::External => None,
+            hil::adc::VoltageReference::Vdd => Some(3_300_000),
+        }
+    }
+
+    fn calibrate(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn set_calibration_client(&self, _client: &'static dyn hil::adc::CalibrationClient) {}
+}
+
+impl hil::adc::AdcStreaming for Adc<'_> {
+    fn max_sequence_length(&self) -> usize {
+        1
+    }
+
+    fn sample_sequence(
+        &self,
+        channels: &[&Self::Channel],
+        frequency: u32,
+        buffer1: &'static mut [u16],
+        length1: usize,
+        buffer2: &'static mut [u16],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])> {
+        if channels.len() != 1 {
+            return Err((ErrorCode::NOSUPPORT, buffer1, buffer2));
+        }
+        hil::adc::AdcHighSpeed::sample_highspeed(
+            self,
+            channels[0],
+            frequency,
+            buffer1,
+            length1,
+            buffer2,
+            length2,
+        )
+    }
+}
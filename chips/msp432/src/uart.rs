@@ -164,6 +164,9 @@ impl<'a> hil::uart::Configure for Uart<'a> {
             hil::uart::Width::Six => {
                 panic!("UART: width of 6 bit is not supported by this hardware!")
             }
+            hil::uart::Width::Nine => {
+                panic!("UART: width of 9 bit is not supported by this hardware!")
+            }
         }
 
         // Setup stop bits
@@ -20,6 +20,7 @@
 //! - Single-pin capacitive sensor support
 //! - Event generation on output changes
 
+use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::interfaces::{Readable, Writeable};
 use kernel::common::registers::{
@@ -215,6 +216,7 @@ register_bitfields! [
 pub struct Comparator<'a> {
     registers: StaticRef<CompRegisters>,
     client: OptionalCell<&'a dyn analog_comparator::Client>,
+    mode: Cell<analog_comparator::InterruptMode>,
 }
 
 impl<'a> Comparator<'a> {
@@ -222,6 +224,7 @@ impl<'a> Comparator<'a> {
         Comparator {
             registers: ACOMP_BASE,
             client: OptionalCell::empty(),
+            mode: Cell::new(analog_comparator::InterruptMode::RisingEdge),
         }
     }
 
@@ -269,13 +272,17 @@ impl<'a> Comparator<'a> {
         self.registers.enable.write(Enable::ENABLE::Disabled);
     }
 
-    /// Handles upward crossing events (when VIN+ becomes greater than VIN-)
+    /// Handles crossing events of the enabled direction
     pub fn handle_interrupt(&self) {
-        // HIL only cares about upward crossing interrupts
-        // VIN+ crossed VIN-
-        if self.registers.events_up.get() == 1 {
+        // VIN+ crossed VIN-. Only the event of the selected mode is enabled.
+        let fired = match self.mode.get() {
+            analog_comparator::InterruptMode::RisingEdge => &self.registers.events_up,
+            analog_comparator::InterruptMode::FallingEdge => &self.registers.events_down,
+            analog_comparator::InterruptMode::Toggle => &self.registers.events_cross,
+        };
+        if fired.get() == 1 {
             // Clear event
-            self.registers.events_up.set(0);
+            fired.set(0);
             self.client.map(|client| {
                 // Only one channel (0)
                 client.fired(0);
@@ -292,8 +299,12 @@ impl<'a> analog_comparator::AnalogComparator<'a> for Comparator<'a> {
     fn start_comparing(&self, _: &Self::Channel) -> Result<(), ErrorCode> {
         self.enable();
 
-        // Enable only up interrupt (If VIN+ crosses VIN-)
-        self.registers.inten.write(InterruptEnable::UP::SET);
+        // Enable only the interrupt of the selected crossing
+        self.registers.inten.write(match self.mode.get() {
+            analog_comparator::InterruptMode::RisingEdge => InterruptEnable::UP::SET,
+            analog_comparator::InterruptMode::FallingEdge => InterruptEnable::DOWN::SET,
+            analog_comparator::InterruptMode::Toggle => InterruptEnable::CROSS::SET,
+        });
 
         Ok(())
     }
@@ -309,6 +320,15 @@ impl<'a> analog_comparator::AnalogComparator<'a> for Comparator<'a> {
         Ok(())
     }
 
+    fn set_interrupt_mode(
+        &self,
+        _: &Self::Channel,
+        mode: analog_comparator::InterruptMode,
+    ) -> Result<(), ErrorCode> {
+        self.mode.set(mode);
+        Ok(())
+    }
+
     /// Performs a single comparison between VIN+ and VIN-
     /// Returns true if vin+ > vin-
     /// Enables comparator if not enabled, to disable call stop comparing
@@ -322,6 +342,19 @@ impl<'a> analog_comparator::AnalogComparator<'a> for Comparator<'a> {
         self.registers.result.get() == 1
     }
 
+    fn window_comparison(&self, _: usize) -> Result<bool, ErrorCode> {
+        // The single comparator cannot bracket a window
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn start_window(&self, _: usize, _: analog_comparator::WindowEvent) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn stop_window(&self, _: usize) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
     fn set_client(&self, client: &'a dyn analog_comparator::Client) {
         self.client.set(client);
     }
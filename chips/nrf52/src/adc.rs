@@ -1,12 +1,30 @@
 //! ADC driver for the nRF52. Uses the SAADC peripheral.
-
-use kernel::common::cells::{OptionalCell, VolatileCell};
+//!
+//! Single samples are triggered in software. For high-speed sampling, the
+//! SAADC scans a sequence of up to eight channels on every compare event of
+//! a timer, connected to its SAMPLE task through a PPI channel, and EasyDMA
+//! stores the samples in the buffer being filled. The next buffer is set up
+//! as soon as the SAADC has started on the current one, and the SAADC is
+//! restarted on it when the current one is full.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! base_peripherals.adc.set_sample_timer(&base_peripherals.timer2, 1);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell, VolatileCell};
 use kernel::common::registers::interfaces::{Readable, Writeable};
-use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::registers::{register_bitfields, FieldValue, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil;
 use kernel::ErrorCode;
 
+use crate::ppi::{self, Ppi};
+use crate::timer::Timer;
+
 #[repr(C)]
 struct AdcRegisters {
     /// Start the ADC and prepare the result buffer in RAM
@@ -243,6 +261,23 @@ const SAADC_BASE: StaticRef<AdcRegisters> =
 // Buffer to save completed sample to.
 static mut SAMPLE: [u16; 1] = [0; 1];
 
+/// Number of channels the SAADC can scan.
+const NUM_CHANNELS: usize = 8;
+
+/// Highest total rate of samples, over all the channels of a sequence.
+const MAX_SAMPLES_PER_SECOND: u32 = 200_000;
+
+/// Frequency of the sample timer, which runs unprescaled.
+const TIMER_FREQUENCY: u32 = 16_000_000;
+
+#[derive(Copy, Clone, PartialEq)]
+enum AdcMode {
+    Idle,
+    Single,
+    Streaming,
+    Calibrating,
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug)]
 pub enum AdcChannelGain {
@@ -323,6 +358,28 @@ impl AdcChannelSetup {
 pub struct Adc {
     registers: StaticRef<AdcRegisters>,
     client: OptionalCell<&'static dyn hil::adc::Client>,
+    highspeed_client: OptionalCell<&'static dyn hil::adc::HighSpeedClient>,
+    calibration_client: OptionalCell<&'static dyn hil::adc::CalibrationClient>,
+    mode: Cell<AdcMode>,
+    reference: Cell<hil::adc::VoltageReference>,
+
+    // Triggering of high-speed samples
+    timer: OptionalCell<&'static Timer>,
+    ppi: Ppi,
+    ppi_channel: Cell<usize>,
+    sequence_length: Cell<usize>,
+
+    /// The buffer the SAADC is filling.
+    buffer: TakeCell<'static, [u16]>,
+    /// The buffer to fill next.
+    next_buffer: TakeCell<'static, [u16]>,
+    next_length: Cell<usize>,
+    /// Whether RESULT.PTR holds the next buffer already.
+    next_programmed: Cell<bool>,
+    /// Whether the SAADC was started, and has not reported STARTED yet.
+    starting: Cell<bool>,
+    /// A second buffer passed with a length of zero, held to be returned.
+    spare_buffer: TakeCell<'static, [u16]>,
 }
 
 impl Adc {
@@ -330,23 +387,130 @@ impl Adc {
         Self {
             registers: SAADC_BASE,
             client: OptionalCell::empty(),
+            highspeed_client: OptionalCell::empty(),
+            calibration_client: OptionalCell::empty(),
+            mode: Cell::new(AdcMode::Idle),
+            reference: Cell::new(hil::adc::VoltageReference::Vdd),
+            timer: OptionalCell::empty(),
+            ppi: Ppi::new(),
+            ppi_channel: Cell::new(0),
+            sequence_length: Cell::new(1),
+            buffer: TakeCell::empty(),
+            next_buffer: TakeCell::empty(),
+            next_length: Cell::new(0),
+            next_programmed: Cell::new(false),
+            starting: Cell::new(false),
+            spare_buffer: TakeCell::empty(),
         }
     }
 
-    pub fn calibrate(&self) {
-        // Enable the ADC
-        self.registers.enable.write(ENABLE::ENABLE::SET);
-        self.registers.inten.write(INTEN::CALIBRATEDONE::SET);
-        self.registers.tasks_calibrateoffset.write(TASK::TASK::SET);
+    pub fn set_highspeed_client(&self, client: &'static dyn hil::adc::HighSpeedClient) {
+        self.highspeed_client.set(client);
+    }
+
+    /// Trigger high-speed samples with `timer`, through PPI channel
+    /// `ppi_channel` (0 to 19). Neither must be used by anything else.
+    pub fn set_sample_timer(&self, timer: &'static Timer, ppi_channel: usize) {
+        self.timer.set(timer);
+        self.ppi_channel.set(ppi_channel);
+    }
+
+    fn ppi_channel(&self) -> FieldValue<u32, ppi::Channel::Register> {
+        FieldValue::<u32, ppi::Channel::Register>::new(1, self.ppi_channel.get(), 1)
+    }
+
+    fn configure_channel(&self, index: usize, channel: &AdcChannelSetup) {
+        // Positive goes to the channel passed in, negative not connected.
+        self.registers.ch[index]
+            .pselp
+            .write(PSEL::PSEL.val(channel.channel as u32));
+        self.registers.ch[index]
+            .pseln
+            .write(PSEL::PSEL::NotConnected);
+        let refsel = match self.reference.get() {
+            hil::adc::VoltageReference::Internal => CONFIG::REFSEL::Internal,
+            _ => CONFIG::REFSEL::VDD1_4,
+        };
+        self.registers.ch[index].config.write(
+            CONFIG::GAIN.val(channel.gain as u32)
+                + refsel
+                + CONFIG::TACQ.val(channel.sampling_time as u32)
+                + CONFIG::RESP.val(channel.resp as u32)
+                + CONFIG::RESN.val(channel.resn as u32)
+                + CONFIG::MODE::SE,
+        );
+    }
+
+    /// Disconnect the channels from `first` on, which the SAADC then skips.
+    fn disable_channels_from(&self, first: usize) {
+        for ch in self.registers.ch.iter().skip(first) {
+            ch.pselp.write(PSEL::PSEL::NotConnected);
+        }
+    }
+
+    fn set_result_buffer(&self, buf: &[u16], length: usize) {
+        self.registers.result_ptr.set(buf.as_ptr());
+        self.registers
+            .result_maxcnt
+            .write(RESULT_MAXCNT::MAXCNT.val(length as u32));
+    }
+
+    fn valid_length(&self, buf: &[u16], length: usize) -> bool {
+        length <= buf.len() && length % self.sequence_length.get() == 0
+    }
+
+    fn handle_streaming_interrupt(&self) {
+        if self.registers.events_started.is_set(EVENT::EVENT) {
+            self.registers.events_started.write(EVENT::EVENT::CLEAR);
+            self.starting.set(false);
+            // RESULT.PTR can take the next buffer once the SAADC started.
+            if !self.next_programmed.get() {
+                self.next_buffer.map(|buf| {
+                    self.set_result_buffer(buf, self.next_length.get());
+                    self.next_programmed.set(true);
+                });
+            }
+        }
+        if self.registers.events_end.is_set(EVENT::EVENT) {
+            self.registers.events_end.write(EVENT::EVENT::CLEAR);
+            let amount = self.registers.result_amount.read(RESULT_AMOUNT::AMOUNT) as usize;
+            let full = self.buffer.take();
+
+            if self.next_programmed.get() {
+                self.next_programmed.set(false);
+                self.next_buffer.take().map(|buf| self.buffer.replace(buf));
+                self.starting.set(true);
+                self.registers.tasks_start.write(TASK::TASK::SET);
+            }
+            // Otherwise samples are missed until the next buffer comes.
+
+            full.map(|buf| {
+                for sample in buf.iter_mut().take(amount) {
+                    // shift left to meet the ADC HIL requirement
+                    let val = *sample as i16;
+                    *sample = if val < 0 { 0 } else { (val as u16) << 4 };
+                }
+                self.highspeed_client
+                    .map(move |client| client.samples_ready(buf, amount));
+            });
+        }
     }
 
     pub fn handle_interrupt(&self) {
+        if self.mode.get() == AdcMode::Streaming {
+            self.handle_streaming_interrupt();
+            return;
+        }
+
         // Determine what event occurred.
         if self.registers.events_calibratedone.is_set(EVENT::EVENT) {
             self.registers
                 .events_calibratedone
                 .write(EVENT::EVENT::CLEAR);
             self.registers.enable.write(ENABLE::ENABLE::CLEAR);
+            self.mode.set(AdcMode::Idle);
+            self.calibration_client
+                .map(|client| client.calibration_done(Ok(())));
         } else if self.registers.events_started.is_set(EVENT::EVENT) {
             self.registers.events_started.write(EVENT::EVENT::CLEAR);
             // ADC has started, now issue the sample.
@@ -359,6 +523,7 @@ impl Adc {
             self.registers.events_stopped.write(EVENT::EVENT::CLEAR);
             // ADC is stopped. Disable and return value.
             self.registers.enable.write(ENABLE::ENABLE::CLEAR);
+            self.mode.set(AdcMode::Idle);
 
             let val = unsafe { SAMPLE[0] as i16 };
             self.client.map(|client| {
@@ -374,21 +539,14 @@ impl hil::adc::Adc for Adc {
     type Channel = AdcChannelSetup;
 
     fn sample(&self, channel: &Self::Channel) -> Result<(), ErrorCode> {
-        // Positive goes to the channel passed in, negative not connected.
-        self.registers.ch[0]
-            .pselp
-            .write(PSEL::PSEL.val(channel.channel as u32));
-        self.registers.ch[0].pseln.write(PSEL::PSEL::NotConnected);
+        if self.mode.get() != AdcMode::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.mode.set(AdcMode::Single);
 
         // Configure the ADC for a single read.
-        self.registers.ch[0].config.write(
-            CONFIG::GAIN.val(channel.gain as u32)
-                + CONFIG::REFSEL::VDD1_4
-                + CONFIG::TACQ.val(channel.sampling_time as u32)
-                + CONFIG::RESP.val(channel.resp as u32)
-                + CONFIG::RESN.val(channel.resn as u32)
-                + CONFIG::MODE::SE,
-        );
+        self.configure_channel(0, channel);
+        self.disable_channels_from(1);
 
         // Set max resolution (with oversampling).
         self.registers.resolution.write(RESOLUTION::VAL::bit12);
@@ -428,7 +586,19 @@ impl hil::adc::Adc for Adc {
     }
 
     fn stop_sampling(&self) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+        if self.mode.get() != AdcMode::Streaming {
+            return Err(ErrorCode::FAIL);
+        }
+        self.ppi.disable(self.ppi_channel());
+        self.timer.map(|timer| timer.stop_counter());
+        self.registers.inten.set(0);
+        self.registers.tasks_stop.write(TASK::TASK::SET);
+        self.registers.events_started.write(EVENT::EVENT::CLEAR);
+        self.registers.events_end.write(EVENT::EVENT::CLEAR);
+        self.next_programmed.set(false);
+        self.starting.set(false);
+        self.mode.set(AdcMode::Idle);
+        Ok(())
     }
 
     fn get_resolution_bits(&self) -> usize {
@@ -436,10 +606,206 @@ impl hil::adc::Adc for Adc {
     }
 
     fn get_voltage_reference_mv(&self) -> Option<usize> {
-        Some(3300)
+        hil::adc::AdcReference::get_voltage_reference_uv(self).map(|uv| uv / 1000)
     }
 
     fn set_client(&self, client: &'static dyn hil::adc::Client) {
         self.client.set(client);
     }
 }
+
+/// The SAADC samples against its internal 0.6 V reference or a quarter of
+/// VDD, scaled by the gain of the channel. Full scale is given for the
+/// default gain of 1/4, with VDD assumed to be 3.3 V.
+impl hil::adc::AdcReference for Adc {
+    fn set_voltage_reference(
+        &self,
+        reference: hil::adc::VoltageReference,
+    ) -> Result<(), ErrorCode> {
+        if self.mode.get() != AdcMode::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        match reference {
+            hil::adc::VoltageReference::External => Err(ErrorCode::NOSUPPORT),
+            _ => {
+                self.reference.set(reference);
+                Ok(())
+            }
+        }
+    }
+
+    fn get_voltage_reference(&self) -> hil::adc::VoltageReference {
+        self.reference.get()
+    }
+
+    fn get_voltage_reference_uv(&self) -> Option<usize> {
+        match self.reference.get() {
+            hil::adc::VoltageReference::Internal => Some(2_400_000),
+            hil::adc::VoltageReference::External => None,
+            hil::adc::VoltageReference::Vdd => Some(3_300_000),
+        }
+    }
+
+    /// Calibrates the offset of the SAADC, which drifts with temperature.
+    fn calibrate(&self) -> Result<(), ErrorCode> {
+        if self.mode.get() != AdcMode::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.mode.set(AdcMode::Calibrating);
+        // Enable the ADC
+        self.registers.enable.write(ENABLE::ENABLE::SET);
+        self.registers.inten.write(INTEN::CALIBRATEDONE::SET);
+        self.registers.tasks_calibrateoffset.write(TASK::TASK::SET);
+        Ok(())
+    }
+
+    fn set_calibration_client(&self, client: &'static dyn hil::adc::CalibrationClient) {
+        self.calibration_client.set(client);
+    }
+}
+
+/// Implements continuous sampling of one channel, as a sequence of one.
+impl hil::adc::AdcHighSpeed for Adc {
+    fn sample_highspeed(
+        &self,
+        channel: &Self::Channel,
+        frequency: u32,
+        buffer1: &'static mut [u16],
+        length1: usize,
+        buffer2: &'static mut [u16],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])> {
+        hil::adc::AdcStreaming::sample_sequence(
+            self,
+            &[channel],
+            frequency,
+            buffer1,
+            length1,
+            buffer2,
+            length2,
+        )
+    }
+
+    fn provide_buffer(
+        &self,
+        buf: &'static mut [u16],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u16])> {
+        if self.mode.get() != AdcMode::Streaming {
+            return Err((ErrorCode::OFF, buf));
+        }
+        if length == 0 || !self.valid_length(buf, length) {
+            return Err((ErrorCode::INVAL, buf));
+        }
+        if self.next_buffer.is_some() {
+            return Err((ErrorCode::BUSY, buf));
+        }
+
+        if self.buffer.is_none() {
+            // Sampling ran out of buffers: restart on this one.
+            self.set_result_buffer(buf, length);
+            self.buffer.replace(buf);
+            self.starting.set(true);
+            self.registers.tasks_start.write(TASK::TASK::SET);
+        } else {
+            self.next_length.set(length);
+            if !self.starting.get() {
+                self.set_result_buffer(buf, length);
+                self.next_programmed.set(true);
+            }
+            self.next_buffer.replace(buf);
+        }
+        Ok(())
+    }
+
+    fn retrieve_buffers(
+        &self,
+    ) -> Result<(Option<&'static mut [u16]>, Option<&'static mut [u16]>), ErrorCode> {
+        if self.mode.get() == AdcMode::Streaming {
+            // cannot return buffers while running
+            return Err(ErrorCode::INVAL);
+        }
+        let first = self.buffer.take().or_else(|| self.spare_buffer.take());
+        let second = self.next_buffer.take().or_else(|| self.spare_buffer.take());
+        Ok((first, second))
+    }
+}
+
+/// Implements scanning a sequence of channels into buffers.
+impl hil::adc::AdcStreaming for Adc {
+    fn max_sequence_length(&self) -> usize {
+        NUM_CHANNELS
+    }
+
+    fn sample_sequence(
+        &self,
+        channels: &[&Self::Channel],
+        frequency: u32,
+        buffer1: &'static mut [u16],
+        length1: usize,
+        buffer2: &'static mut [u16],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])> {
+        if self.mode.get() != AdcMode::Idle {
+            return Err((ErrorCode::BUSY, buffer1, buffer2));
+        }
+        let timer = match self.timer.extract() {
+            Some(timer) => timer,
+            None => return Err((ErrorCode::NODEVICE, buffer1, buffer2)),
+        };
+        let sequence_length = channels.len();
+        if sequence_length == 0 || sequence_length > NUM_CHANNELS {
+            return Err((ErrorCode::INVAL, buffer1, buffer2));
+        }
+        if frequency == 0
+            || frequency.saturating_mul(sequence_length as u32) > MAX_SAMPLES_PER_SECOND
+        {
+            return Err((ErrorCode::INVAL, buffer1, buffer2));
+        }
+        self.sequence_length.set(sequence_length);
+        if length1 == 0
+            || !self.valid_length(buffer1, length1)
+            || !self.valid_length(buffer2, length2)
+        {
+            return Err((ErrorCode::INVAL, buffer1, buffer2));
+        }
+
+        self.mode.set(AdcMode::Streaming);
+        for (index, channel) in channels.iter().enumerate() {
+            self.configure_channel(index, channel);
+        }
+        self.disable_channels_from(sequence_length);
+        self.registers.resolution.write(RESOLUTION::VAL::bit12);
+        // Samples are triggered by the timer through the PPI.
+        self.registers.samplerate.write(SAMPLERATE::MODE::Task);
+
+        self.set_result_buffer(buffer1, length1);
+        self.buffer.replace(buffer1);
+        self.next_programmed.set(false);
+        if length2 > 0 {
+            self.next_length.set(length2);
+            self.next_buffer.replace(buffer2);
+        } else {
+            self.spare_buffer.replace(buffer2);
+        }
+
+        self.registers.events_started.write(EVENT::EVENT::CLEAR);
+        self.registers.events_end.write(EVENT::EVENT::CLEAR);
+        self.registers.enable.write(ENABLE::ENABLE::SET);
+        self.registers
+            .inten
+            .write(INTEN::STARTED::SET + INTEN::END::SET);
+        self.starting.set(true);
+        self.registers.tasks_start.write(TASK::TASK::SET);
+
+        self.ppi.connect(
+            self.ppi_channel.get(),
+            timer.compare_event_address(0),
+            &self.registers.tasks_sample as *const _ as u32,
+            None,
+        );
+        self.ppi.enable(self.ppi_channel());
+        timer.start_periodic(0, TIMER_FREQUENCY / frequency);
+        Ok(())
+    }
+}
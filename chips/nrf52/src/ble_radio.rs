@@ -32,16 +32,31 @@
 //! * Payload - 2 to 255 bytes
 //!
 //! * CRC - 3 bytes
+//!
+//! ### Connections
+//!
+//! The radio also runs the packet exchanges of connectable peripherals and
+//! of centrals (`BleConnectionRadio`). Shortcuts chain the two packets of an
+//! exchange, and the radio switches between them `T_IFS` after the first
+//! one. The buffer of the second packet is swapped in when the interrupt
+//! for the end of the first one is handled, so those exchanges rely on the
+//! kernel handling the interrupt within `T_IFS`.
+//!
+//! While scanning, the radio gets ready to answer each packet received if
+//! the scan allows answers, and stops if the client does not answer it.
+//! Otherwise, it starts listening again once the packet is handled.
 
 use core::cell::Cell;
+use core::cmp;
 use core::convert::TryFrom;
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
-use kernel::common::registers::interfaces::{Readable, Writeable};
+use kernel::common::registers::interfaces::{ReadWriteable, Readable, Writeable};
 use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_connection;
 use kernel::ErrorCode;
 use nrf5x::constants::TxPower;
 
@@ -530,12 +545,46 @@ register_bitfields! [u32,
 static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
     [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
 
+/// Progress of the packet exchanges of connections.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum LinkState {
+    /// No exchange is in progress.
+    Idle,
+    /// Transmitting the first packet of an advertisement or of a connection
+    /// event of the central.
+    FirstTx,
+    /// Listening for the response to it.
+    ResponseRx,
+    EventRx,
+    EventTx,
+    ScanRx,
+    /// Transmitting the answer to a packet received while scanning.
+    ScanTx,
+    /// Waiting for the radio to be disabled to listen again.
+    ScanRestart,
+    /// Waiting for the radio to be disabled at the end of the exchange.
+    Finishing,
+    /// Disabling the radio at the end of a receive window.
+    Closing,
+}
+
 pub struct Radio<'a> {
     registers: StaticRef<RadioRegisters>,
     tx_power: Cell<TxPower>,
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
     tx_client: OptionalCell<&'a dyn ble_advertising::TxClient>,
     buffer: TakeCell<'static, [u8]>,
+    connection_client: OptionalCell<&'a dyn ble_connection::ConnectionClient>,
+    link_state: Cell<LinkState>,
+    /// Whether the receive window was closed before the first packet was
+    /// sent, or while a packet was received.
+    link_close_requested: Cell<bool>,
+    /// Whether packets received while scanning can be answered.
+    link_answer: Cell<bool>,
+    link_rx_len: Cell<usize>,
+    link_result: Cell<Result<(), ErrorCode>>,
+    link_tx: TakeCell<'static, [u8]>,
+    link_rx: TakeCell<'static, [u8]>,
 }
 
 impl<'a> Radio<'a> {
@@ -546,6 +595,14 @@ impl<'a> Radio<'a> {
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
+            connection_client: OptionalCell::empty(),
+            link_state: Cell::new(LinkState::Idle),
+            link_close_requested: Cell::new(false),
+            link_answer: Cell::new(false),
+            link_rx_len: Cell::new(0),
+            link_result: Cell::new(Ok(())),
+            link_tx: TakeCell::empty(),
+            link_rx: TakeCell::empty(),
         }
     }
 
@@ -597,6 +654,11 @@ impl<'a> Radio<'a> {
 
     #[inline(never)]
     pub fn handle_interrupt(&self) {
+        if self.link_state.get() != LinkState::Idle {
+            self.handle_link_interrupt();
+            return;
+        }
+
         self.disable_all_interrupts();
 
         if self.registers.event_ready.is_set(Event::READY) {
@@ -684,6 +746,211 @@ impl<'a> Radio<'a> {
         buf
     }
 
+    fn handle_link_interrupt(&self) {
+        if self.registers.event_end.is_set(Event::READY) {
+            self.registers.event_end.write(Event::READY::CLEAR);
+            match self.link_state.get() {
+                LinkState::FirstTx => {
+                    self.registers.event_address.write(Event::READY::CLEAR);
+                    self.link_rx
+                        .map(|rx| self.registers.packetptr.set(rx.as_ptr() as u32));
+                    if self.link_close_requested.take() {
+                        self.link_close();
+                    } else {
+                        self.registers
+                            .shorts
+                            .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                        self.link_state.set(LinkState::ResponseRx);
+                    }
+                }
+                LinkState::ResponseRx => {
+                    self.link_received();
+                    self.link_state.set(LinkState::Finishing);
+                }
+                LinkState::EventRx => {
+                    // The radio is already ramping up to transmit the
+                    // response.
+                    self.registers
+                        .shorts
+                        .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                    let crc_ok = self.link_received();
+                    let len = self.link_rx_len.get();
+                    self.link_rx.map(|rx| {
+                        self.link_tx.map(|tx| {
+                            self.connection_client
+                                .map(|client| client.respond(&rx[..len], crc_ok, tx));
+                            self.registers.packetptr.set(tx.as_ptr() as u32);
+                        });
+                    });
+                    self.link_state.set(LinkState::EventTx);
+                }
+                LinkState::EventTx | LinkState::ScanTx => self.link_state.set(LinkState::Finishing),
+                LinkState::ScanRx => self.link_scanned(),
+                _ => {}
+            }
+        }
+
+        if self.registers.event_disabled.is_set(Event::READY) {
+            self.registers.event_disabled.write(Event::READY::CLEAR);
+        }
+        match self.link_state.get() {
+            LinkState::Finishing | LinkState::Closing
+                if self.registers.state.matches_all(State::STATE::DISABLED) =>
+            {
+                self.link_done();
+            }
+            LinkState::ScanRestart if self.registers.state.matches_all(State::STATE::DISABLED) => {
+                if self.link_close_requested.take() {
+                    self.link_rx_len.set(0);
+                    self.link_result.set(Err(ErrorCode::NOACK));
+                    self.link_done();
+                } else {
+                    self.link_scan_start();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle a packet received while scanning.
+    fn link_scanned(&self) {
+        if self.link_answer.get() {
+            // The radio is already ramping up to transmit the answer.
+            self.registers
+                .shorts
+                .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+        }
+        let crc_ok = self.link_received();
+        let len = self.link_rx_len.get();
+        // The sample is the magnitude of the signal strength.
+        let rssi = -(self.registers.rssisample.read(RssiSample::RSSISAMPLE) as i8);
+        let answered = crc_ok
+            && self.link_rx.map_or(false, |rx| {
+                self.link_tx.map_or(false, |tx| {
+                    let answered = self
+                        .connection_client
+                        .map_or(false, |client| client.scanned(&rx[..len], rssi, tx));
+                    if answered && self.link_answer.get() {
+                        self.registers.packetptr.set(tx.as_ptr() as u32);
+                    }
+                    answered
+                })
+            });
+        if answered && self.link_answer.get() {
+            self.link_state.set(LinkState::ScanTx);
+        } else {
+            self.link_state.set(LinkState::ScanRestart);
+            if self.link_answer.get() {
+                self.registers.task_disable.write(Task::ENABLE::SET);
+            }
+        }
+    }
+
+    /// Listen for the next packet while scanning.
+    fn link_scan_start(&self) {
+        self.registers.event_address.write(Event::READY::CLEAR);
+        self.registers.event_end.write(Event::READY::CLEAR);
+        self.link_rx
+            .map(|rx| self.registers.packetptr.set(rx.as_ptr() as u32));
+        let shorts = Shortcut::READY_START::SET
+            + Shortcut::END_DISABLE::SET
+            + Shortcut::ADDRESS_RSSISTART::SET
+            + Shortcut::DISABLED_RSSISTOP::SET;
+        if self.link_answer.get() {
+            self.registers
+                .shorts
+                .write(shorts + Shortcut::DISABLED_TXEN::SET);
+        } else {
+            self.registers.shorts.write(shorts);
+        }
+        self.link_state.set(LinkState::ScanRx);
+        self.registers.task_rxen.write(Task::ENABLE::SET);
+    }
+
+    /// Record the length and CRC status of the packet received. Returns
+    /// whether its CRC is valid.
+    fn link_received(&self) -> bool {
+        let crc_ok = self.registers.crcstatus.is_set(Event::READY);
+        let len = self
+            .link_rx
+            .map_or(0, |rx| cmp::min(rx[1] as usize + 2, rx.len()));
+        self.link_rx_len.set(len);
+        self.link_result
+            .set(if crc_ok { Ok(()) } else { Err(ErrorCode::FAIL) });
+        crc_ok
+    }
+
+    fn link_close(&self) {
+        self.registers.shorts.set(0);
+        self.link_rx_len.set(0);
+        self.link_result.set(Err(ErrorCode::NOACK));
+        self.link_state.set(LinkState::Closing);
+        self.registers.task_disable.write(Task::ENABLE::SET);
+    }
+
+    fn link_done(&self) {
+        self.link_state.set(LinkState::Idle);
+        self.disable_all_interrupts();
+        self.registers.shorts.set(0);
+        self.radio_off();
+        let len = self.link_rx_len.get();
+        let result = self.link_result.get();
+        if let (Some(tx), Some(rx)) = (self.link_tx.take(), self.link_rx.take()) {
+            self.connection_client
+                .map(move |client| client.exchange_done(tx, rx, len, result));
+        }
+    }
+
+    /// Configure the radio for an exchange on `channel`, receiving packets
+    /// of up to `rx_len` bytes.
+    fn link_initialize(
+        &self,
+        channel: RadioChannel,
+        access_address: u32,
+        crc_init: u32,
+        rx_len: usize,
+    ) {
+        self.radio_on();
+        self.ble_set_tx_power();
+        self.ble_set_channel_rate();
+        self.ble_set_channel_freq(channel);
+        self.ble_set_data_whitening(channel);
+        self.set_tx_address();
+        self.set_rx_address();
+        self.ble_set_packet_config();
+        self.registers
+            .pcnf1
+            .modify(PacketConfiguration1::MAXLEN.val(cmp::min(rx_len - 2, 255) as u32));
+        self.ble_set_access_address(access_address);
+        self.ble_set_crc_config();
+        self.registers.crcinit.set(crc_init);
+        self.registers
+            .tifs
+            .write(InterFrameSpacing::TIFS.val(ble_connection::T_IFS_US));
+
+        self.registers.event_ready.write(Event::READY::CLEAR);
+        self.registers.event_address.write(Event::READY::CLEAR);
+        self.registers.event_end.write(Event::READY::CLEAR);
+        self.registers.event_disabled.write(Event::READY::CLEAR);
+        self.link_close_requested.set(false);
+        self.disable_all_interrupts();
+        self.registers
+            .intenset
+            .write(Interrupt::END::SET + Interrupt::DISABLED::SET);
+    }
+
+    /// Transmit `tx`, and then listen for the response in `rx`.
+    fn link_transmit_first(&self, tx: &'static mut [u8], rx: &'static mut [u8]) {
+        self.registers.packetptr.set(tx.as_ptr() as u32);
+        self.registers.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_RXEN::SET,
+        );
+        self.link_tx.replace(tx);
+        self.link_rx.replace(rx);
+        self.link_state.set(LinkState::FirstTx);
+        self.registers.task_txen.write(Task::ENABLE::SET);
+    }
+
     fn ble_initialize(&self, channel: RadioChannel) {
         self.radio_on();
 
@@ -725,6 +992,13 @@ impl<'a> Radio<'a> {
         self.registers.base0.set(0x89bed600);
     }
 
+    // The most significant byte of access addresses is the prefix, and the
+    // others the base address
+    fn ble_set_access_address(&self, access_address: u32) {
+        self.registers.prefix0.set(access_address >> 24);
+        self.registers.base0.set(access_address << 8);
+    }
+
     // Packet configuration
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1 Packet Format
     //
@@ -787,6 +1061,14 @@ impl<'a> Radio<'a> {
     }
 }
 
+impl<'a> kernel::power::PowerClient for Radio<'a> {
+    /// The radio needs the crystal oscillator while it is on.
+    fn deep_sleep_ready(&self) -> bool {
+        self.link_state.get() == LinkState::Idle
+            && self.registers.state.matches_all(State::STATE::DISABLED)
+    }
+}
+
 impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     fn transmit_advertisement(&self, buf: &'static mut [u8], _len: usize, channel: RadioChannel) {
         let res = self.replace_radio_buffer(buf);
@@ -811,6 +1093,115 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     }
 }
 
+impl<'a> ble_connection::BleConnectionRadio<'a> for Radio<'a> {
+    fn set_connection_client(&self, client: &'a dyn ble_connection::ConnectionClient) {
+        self.connection_client.set(client);
+    }
+
+    fn advertise(
+        &self,
+        channel: RadioChannel,
+        tx: &'static mut [u8],
+        rx: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [u8])> {
+        if self.link_state.get() != LinkState::Idle || self.buffer.is_some() {
+            return Err((ErrorCode::BUSY, tx, rx));
+        } else if tx.len() < 2 || rx.len() < 2 {
+            return Err((ErrorCode::SIZE, tx, rx));
+        }
+        self.link_initialize(
+            channel,
+            ble_connection::ADVERTISING_ACCESS_ADDRESS,
+            ble_connection::ADVERTISING_CRC_INIT,
+            rx.len(),
+        );
+        self.link_transmit_first(tx, rx);
+        Ok(())
+    }
+
+    fn connection_event(
+        &self,
+        channel: RadioChannel,
+        access_address: u32,
+        crc_init: u32,
+        tx: &'static mut [u8],
+        rx: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [u8])> {
+        if self.link_state.get() != LinkState::Idle || self.buffer.is_some() {
+            return Err((ErrorCode::BUSY, tx, rx));
+        } else if tx.len() < 2 || rx.len() < 2 {
+            return Err((ErrorCode::SIZE, tx, rx));
+        }
+        self.link_initialize(channel, access_address, crc_init, rx.len());
+        self.registers.packetptr.set(rx.as_ptr() as u32);
+        self.registers.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_TXEN::SET,
+        );
+        self.link_tx.replace(tx);
+        self.link_rx.replace(rx);
+        self.link_state.set(LinkState::EventRx);
+        self.registers.task_rxen.write(Task::ENABLE::SET);
+        Ok(())
+    }
+
+    fn scan(
+        &self,
+        channel: RadioChannel,
+        answer: bool,
+        tx: &'static mut [u8],
+        rx: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [u8])> {
+        if self.link_state.get() != LinkState::Idle || self.buffer.is_some() {
+            return Err((ErrorCode::BUSY, tx, rx));
+        } else if tx.len() < 2 || rx.len() < 2 {
+            return Err((ErrorCode::SIZE, tx, rx));
+        }
+        self.link_initialize(
+            channel,
+            ble_connection::ADVERTISING_ACCESS_ADDRESS,
+            ble_connection::ADVERTISING_CRC_INIT,
+            rx.len(),
+        );
+        self.link_answer.set(answer);
+        self.link_tx.replace(tx);
+        self.link_rx.replace(rx);
+        self.link_scan_start();
+        Ok(())
+    }
+
+    fn central_event(
+        &self,
+        channel: RadioChannel,
+        access_address: u32,
+        crc_init: u32,
+        tx: &'static mut [u8],
+        rx: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [u8])> {
+        if self.link_state.get() != LinkState::Idle || self.buffer.is_some() {
+            return Err((ErrorCode::BUSY, tx, rx));
+        } else if tx.len() < 2 || rx.len() < 2 {
+            return Err((ErrorCode::SIZE, tx, rx));
+        }
+        self.link_initialize(channel, access_address, crc_init, rx.len());
+        self.link_transmit_first(tx, rx);
+        Ok(())
+    }
+
+    fn close_receive_window(&self) {
+        match self.link_state.get() {
+            LinkState::FirstTx | LinkState::ScanRestart => self.link_close_requested.set(true),
+            LinkState::ResponseRx | LinkState::EventRx | LinkState::ScanRx
+                if !self.registers.event_address.is_set(Event::READY) =>
+            {
+                self.link_close();
+            }
+            // Stop once the packet being received is handled.
+            LinkState::ScanRx => self.link_close_requested.set(true),
+            _ => {}
+        }
+    }
+}
+
 impl ble_advertising::BleConfig for Radio<'_> {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
@@ -10,6 +10,10 @@ pub struct NRF52<'a, I: InterruptService<DeferredCallTask> + 'a> {
     userspace_kernel_boundary: cortexm4::syscall::SysCall,
     scheduler_timer: cortexm4::systick::SysTick,
     interrupt_service: &'a I,
+    // Only used to stop and restart the high frequency crystal around deep
+    // sleep.
+    clock: crate::clock::Clock,
+    power: crate::power::Power<'a>,
 }
 
 impl<'a, I: InterruptService<DeferredCallTask> + 'a> NRF52<'a, I> {
@@ -21,6 +25,8 @@ impl<'a, I: InterruptService<DeferredCallTask> + 'a> NRF52<'a, I> {
             // 64Mhz CPU clock.
             scheduler_timer: cortexm4::systick::SysTick::new_with_calibration(64000000),
             interrupt_service,
+            clock: crate::clock::Clock::new(),
+            power: crate::power::Power::new(),
         }
     }
 }
@@ -48,9 +54,11 @@ pub struct Nrf52DefaultPeripherals<'a> {
     pub twim1: crate::i2c::TWIM,
     pub spim2: crate::spi::SPIM,
     pub adc: crate::adc::Adc,
+    pub i2s: crate::i2s::I2S<'a>,
     pub nvmc: crate::nvmc::Nvmc,
     pub clock: crate::clock::Clock,
     pub pwm0: crate::pwm::Pwm,
+    pub qdec: crate::qdec::Qdec<'a>,
 }
 
 impl<'a> Nrf52DefaultPeripherals<'a> {
@@ -74,9 +82,11 @@ impl<'a> Nrf52DefaultPeripherals<'a> {
             twim1: crate::i2c::TWIM::new_twim1(),
             spim2: crate::spi::SPIM::new(2),
             adc: crate::adc::Adc::new(),
+            i2s: crate::i2s::I2S::new(),
             nvmc: crate::nvmc::Nvmc::new(),
             clock: crate::clock::Clock::new(),
             pwm0: crate::pwm::Pwm::new(),
+            qdec: crate::qdec::Qdec::new(),
         }
     }
     // Necessary for setting up circular dependencies
@@ -104,6 +114,7 @@ impl<'a> kernel::InterruptService<DeferredCallTask> for Nrf52DefaultPeripherals<
                     ),
                 }
             }
+            crate::peripheral_interrupts::QDEC => self.qdec.handle_interrupt(),
             crate::peripheral_interrupts::RNG => self.trng.handle_interrupt(),
             crate::peripheral_interrupts::RTC1 => self.rtc.handle_interrupt(),
             crate::peripheral_interrupts::TEMP => self.temp.handle_interrupt(),
@@ -141,6 +152,7 @@ impl<'a> kernel::InterruptService<DeferredCallTask> for Nrf52DefaultPeripherals<
             }
             crate::peripheral_interrupts::SPIM2_SPIS2_SPI2 => self.spim2.handle_interrupt(),
             crate::peripheral_interrupts::ADC => self.adc.handle_interrupt(),
+            crate::peripheral_interrupts::I2S => self.i2s.handle_interrupt(),
             _ => return false,
         }
         true
@@ -206,6 +218,28 @@ impl<'a, I: InterruptService<DeferredCallTask> + 'a> kernel::Chip for NRF52<'a,
         }
     }
 
+    fn deep_sleep(&self) {
+        // The RTC runs from the low frequency clock, which stays on in the
+        // low-power sub-mode of System ON. Stop the crystal oscillator, as
+        // peripherals that need a high frequency clock while the chip sleeps
+        // get the RC oscillator automatically, and restart it on wake-up.
+        let crystal = match self.clock.high_source() {
+            crate::clock::HighClockSource::XTAL => self.clock.high_running(),
+            crate::clock::HighClockSource::RC => false,
+        };
+        self.power.low_power_mode();
+        if crystal {
+            self.clock.high_stop();
+        }
+        unsafe {
+            cortexm4::support::wfi();
+        }
+        if crystal {
+            self.clock.high_start();
+            while !self.clock.high_running() {}
+        }
+    }
+
     unsafe fn atomic<F, R>(&self, f: F) -> R
     where
         F: FnOnce() -> R,
@@ -3,6 +3,10 @@
 //! This module supports nRF52's two I2C master (`TWIM`) peripherals,
 //! but not I2C slave (`TWIS`).
 //!
+//! An aborted transfer may leave a slave holding SDA low. To recover the
+//! bus, the `TWIM` clocks SCL by hand through the GPIO pins given to
+//! `set_recovery_pins`.
+//!
 //! - Author: Jay Kickliter
 //! - Author: Andrew Thompson
 //! - Date: Nov 4, 2017
@@ -32,6 +36,8 @@ pub struct TWIM {
     registers: StaticRef<TwimRegisters>,
     client: OptionalCell<&'static dyn hil::i2c::I2CHwMasterClient>,
     buf: TakeCell<'static, [u8]>,
+    /// The SCL and SDA pins, driven as GPIOs to recover the bus.
+    recovery_pins: OptionalCell<(&'static dyn hil::gpio::Pin, &'static dyn hil::gpio::Pin)>,
 }
 
 /// I2C bus speed.
@@ -48,6 +54,7 @@ impl TWIM {
             registers,
             client: OptionalCell::empty(),
             buf: TakeCell::empty(),
+            recovery_pins: OptionalCell::empty(),
         }
     }
 
@@ -70,6 +77,16 @@ impl TWIM {
         self.registers.psel_sda.set(sda);
     }
 
+    /// Sets the GPIO pins of SCL and SDA, which must be the pins passed to
+    /// `configure`, to recover the bus after an aborted transfer.
+    pub fn set_recovery_pins(
+        &self,
+        scl: &'static dyn hil::gpio::Pin,
+        sda: &'static dyn hil::gpio::Pin,
+    ) {
+        self.recovery_pins.set((scl, sda));
+    }
+
     /// Sets the I2C bus speed to one of three possible values
     /// enumerated in `Speed`.
     pub fn set_speed(&self, speed: Speed) {
@@ -128,6 +145,49 @@ impl TWIM {
     pub fn is_enabled(&self) -> bool {
         self.registers.enable.matches_all(ENABLE::ENABLE::Enable)
     }
+
+    /// Clock SCL until a slave holding SDA low releases it, then generate a
+    /// stop condition. The `TWIM` must be disabled, for the pins to be
+    /// driven as GPIOs.
+    fn recover_bus(&self) {
+        self.recovery_pins.map(|(scl, sda)| {
+            // The lines are open drain: a line is released by making it an
+            // input, for the pull-up to bring it high, and pulled low by
+            // making it an output.
+            scl.clear();
+            sda.clear();
+            scl.make_input();
+            sda.make_input();
+            bus_delay();
+
+            // A slave in the middle of a byte releases SDA within 9 clocks.
+            for _ in 0..9 {
+                if sda.read() {
+                    break;
+                }
+                scl.make_output();
+                bus_delay();
+                scl.make_input();
+                bus_delay();
+            }
+
+            // Stop condition: SDA rises while SCL is high.
+            scl.make_output();
+            sda.make_output();
+            bus_delay();
+            scl.make_input();
+            bus_delay();
+            sda.make_input();
+            bus_delay();
+        });
+    }
+}
+
+/// Wait half a clock period at 100 kHz, 5 µs at 64 MHz.
+fn bus_delay() {
+    for _ in 0..100 {
+        cortexm4::support::nop();
+    }
 }
 
 impl hil::i2c::I2CMaster for TWIM {
@@ -210,6 +270,27 @@ impl hil::i2c::I2CMaster for TWIM {
         self.registers.tasks_startrx.write(TASK::TASK::SET);
         self.buf.replace(buffer);
     }
+
+    fn abort(&self) -> Option<&'static mut [u8]> {
+        let buf = self.buf.take()?;
+        self.registers
+            .intenclr
+            .write(INTE::STOPPED::Enable + INTE::ERROR::Enable);
+        self.registers.shorts.set(0);
+        self.registers.tasks_stop.write(TASK::TASK::SET);
+
+        // Disabling the TWIM ends the transfer, and hands the pins back to
+        // the GPIOs.
+        self.disable();
+        self.recover_bus();
+        self.registers.events_stopped.write(EVENT::EVENT::CLEAR);
+        self.registers.events_error.write(EVENT::EVENT::CLEAR);
+        self.registers
+            .errorsrc
+            .write(ERRORSRC::ANACK::ErrorOccurred + ERRORSRC::DNACK::ErrorOccurred);
+        self.enable();
+        Some(buf)
+    }
 }
 
 // The SPI0_TWI0 and SPI1_TWI1 interrupts are dispatched to the
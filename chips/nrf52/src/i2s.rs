@@ -0,0 +1,407 @@
+//! I2S driver for nRF52.
+//!
+//! The peripheral runs as master, generating SCK and LRCK (and optionally
+//! MCK) from the 32 MHz clock. It fetches and stores samples by EasyDMA, and
+//! latches the RXD.PTR and TXD.PTR registers at the start of every transfer,
+//! signalled by the RXPTRUPD and TXPTRUPD events. The next pointers can be
+//! written from then on, which makes streaming without gaps possible.
+
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::registers::interfaces::{Readable, Writeable};
+use kernel::common::registers::{register_bitfields, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::i2s;
+use kernel::ErrorCode;
+use nrf5x::pinmux::Pinmux;
+
+const I2S_BASE: StaticRef<I2sRegisters> =
+    unsafe { StaticRef::new(0x40025000 as *const I2sRegisters) };
+
+/// Largest number of words in a transfer.
+const MAX_WORDS: usize = 0x3FFF;
+
+/// Pin select value of a disconnected signal.
+const PSEL_DISCONNECTED: u32 = 0xFFFF_FFFF;
+
+#[repr(C)]
+struct I2sRegisters {
+    /// Starts continuous I2S transfer
+    tasks_start: WriteOnly<u32, TASK::Register>,
+    /// Stops I2S transfer
+    tasks_stop: WriteOnly<u32, TASK::Register>,
+    _reserved0: [u8; 252],
+    /// The RXD.PTR register has been copied to internal double-buffers
+    events_rxptrupd: ReadWrite<u32, EVENT::Register>,
+    /// I2S transfer stopped
+    events_stopped: ReadWrite<u32, EVENT::Register>,
+    _reserved1: [u8; 8],
+    /// The TXD.PTR register has been copied to internal double-buffers
+    events_txptrupd: ReadWrite<u32, EVENT::Register>,
+    _reserved2: [u8; 488],
+    /// Enable or disable interrupt
+    inten: ReadWrite<u32, INTE::Register>,
+    /// Enable interrupt
+    intenset: ReadWrite<u32, INTE::Register>,
+    /// Disable interrupt
+    intenclr: ReadWrite<u32, INTE::Register>,
+    _reserved3: [u8; 500],
+    /// Enable I2S module
+    enable: ReadWrite<u32, ENABLE::Register>,
+    /// I2S mode
+    mode: ReadWrite<u32, MODE::Register>,
+    /// Reception (RX) enable
+    rxen: ReadWrite<u32, ENABLE::Register>,
+    /// Transmission (TX) enable
+    txen: ReadWrite<u32, ENABLE::Register>,
+    /// Master clock generator enable
+    mcken: ReadWrite<u32, ENABLE::Register>,
+    /// Master clock generator frequency
+    mckfreq: ReadWrite<u32>,
+    /// MCK / LRCK ratio
+    ratio: ReadWrite<u32>,
+    /// Sample width
+    swidth: ReadWrite<u32, SWIDTH::Register>,
+    /// Alignment of sample within a frame
+    align: ReadWrite<u32>,
+    /// Frame format
+    format: ReadWrite<u32>,
+    /// Enable channels
+    channels: ReadWrite<u32, CHANNELS::Register>,
+    _reserved4: [u8; 12],
+    /// Receive buffer RAM start address
+    rxd_ptr: ReadWrite<u32>,
+    _reserved5: [u8; 4],
+    /// Transmit buffer RAM start address
+    txd_ptr: ReadWrite<u32>,
+    _reserved6: [u8; 12],
+    /// Size of RXD and TXD buffers in 32-bit words
+    maxcnt: ReadWrite<u32>,
+    _reserved7: [u8; 12],
+    /// Pin select for MCK signal
+    psel_mck: ReadWrite<u32>,
+    /// Pin select for SCK signal
+    psel_sck: ReadWrite<u32>,
+    /// Pin select for LRCK signal
+    psel_lrck: ReadWrite<u32>,
+    /// Pin select for SDIN signal
+    psel_sdin: ReadWrite<u32>,
+    /// Pin select for SDOUT signal
+    psel_sdout: ReadWrite<u32>,
+}
+
+register_bitfields![u32,
+    TASK [
+        TASK 0
+    ],
+    EVENT [
+        EVENT 0
+    ],
+    INTE [
+        /// Interrupt on EVENTS_RXPTRUPD event
+        RXPTRUPD 1,
+        /// Interrupt on EVENTS_STOPPED event
+        STOPPED 2,
+        /// Interrupt on EVENTS_TXPTRUPD event
+        TXPTRUPD 5
+    ],
+    ENABLE [
+        ENABLE 0
+    ],
+    MODE [
+        MODE OFFSET(0) NUMBITS(1) [
+            Master = 0,
+            Slave = 1
+        ]
+    ],
+    SWIDTH [
+        SWIDTH OFFSET(0) NUMBITS(2) [
+            Bits8 = 0,
+            Bits16 = 1,
+            Bits24 = 2
+        ]
+    ],
+    CHANNELS [
+        CHANNELS OFFSET(0) NUMBITS(2) [
+            Stereo = 0,
+            Left = 1,
+            Right = 2
+        ]
+    ]
+];
+
+/// Dividers of the 32 MHz clock available for MCK, with their MCKFREQ value.
+const MCK_DIVIDERS: [(u32, u32); 18] = [
+    (2, 0x8000_0000),
+    (3, 0x5000_0000),
+    (4, 0x4000_0000),
+    (5, 0x3000_0000),
+    (6, 0x2800_0000),
+    (8, 0x2000_0000),
+    (10, 0x1800_0000),
+    (11, 0x1600_0000),
+    (15, 0x1100_0000),
+    (16, 0x1000_0000),
+    (21, 0x0C00_0000),
+    (23, 0x0B00_0000),
+    (30, 0x0880_0000),
+    (31, 0x0840_0000),
+    (32, 0x0800_0000),
+    (42, 0x0600_0000),
+    (63, 0x0410_0000),
+    (125, 0x020C_0000),
+];
+
+/// MCK / LRCK ratios, indexed by their RATIO value.
+const RATIOS: [u32; 9] = [32, 48, 64, 96, 128, 192, 256, 384, 512];
+
+pub struct I2S<'a> {
+    registers: StaticRef<I2sRegisters>,
+    client: OptionalCell<&'a dyn i2s::Client>,
+    running: Cell<bool>,
+    /// Buffers being transferred.
+    current: MapCell<i2s::BufferSet>,
+    /// Buffers written to RXD.PTR and TXD.PTR, not latched yet.
+    next: MapCell<i2s::BufferSet>,
+    /// Buffers queued behind `next`.
+    pending: MapCell<i2s::BufferSet>,
+}
+
+impl<'a> I2S<'a> {
+    pub fn new() -> I2S<'a> {
+        I2S {
+            registers: I2S_BASE,
+            client: OptionalCell::empty(),
+            running: Cell::new(false),
+            current: MapCell::empty(),
+            next: MapCell::empty(),
+            pending: MapCell::empty(),
+        }
+    }
+
+    /// Select the pins of the interface. SCK and LRCK are always used, MCK
+    /// only if the codec needs a master clock, and SDOUT and SDIN only to
+    /// transmit and receive respectively.
+    pub fn set_pins(
+        &self,
+        sck: Pinmux,
+        lrck: Pinmux,
+        mck: Option<Pinmux>,
+        sdout: Option<Pinmux>,
+        sdin: Option<Pinmux>,
+    ) {
+        let regs = &*self.registers;
+        regs.psel_sck.set(sck.into());
+        regs.psel_lrck.set(lrck.into());
+        regs.psel_mck
+            .set(mck.map_or(PSEL_DISCONNECTED, |pin| pin.into()));
+        regs.mcken.write(ENABLE::ENABLE.val(mck.is_some() as u32));
+        regs.psel_sdout
+            .set(sdout.map_or(PSEL_DISCONNECTED, |pin| pin.into()));
+        regs.psel_sdin
+            .set(sdin.map_or(PSEL_DISCONNECTED, |pin| pin.into()));
+    }
+
+    /// Number of words in a set, or an error if the buffers do not make a
+    /// valid set.
+    fn set_len(buffers: &i2s::BufferSet) -> Result<usize, ErrorCode> {
+        let len = match buffers {
+            (Some(tx), Some(rx)) if tx.len() != rx.len() => return Err(ErrorCode::SIZE),
+            (Some(tx), _) => tx.len(),
+            (None, Some(rx)) => rx.len(),
+            (None, None) => return Err(ErrorCode::INVAL),
+        };
+        if len == 0 || len > MAX_WORDS {
+            Err(ErrorCode::SIZE)
+        } else {
+            Ok(len)
+        }
+    }
+
+    /// Point the DMA at a set of buffers, to be latched at the start of the
+    /// next transfer.
+    fn set_pointers(&self, buffers: &mut i2s::BufferSet) {
+        let regs = &*self.registers;
+        if let Some(tx) = buffers.0.as_mut() {
+            regs.txd_ptr.set(tx.as_ptr() as u32);
+        }
+        if let Some(rx) = buffers.1.as_mut() {
+            regs.rxd_ptr.set(rx.as_mut_ptr() as u32);
+        }
+    }
+
+    /// Whether a set of buffers has the directions of the running stream.
+    fn same_directions(&self, buffers: &i2s::BufferSet) -> bool {
+        let regs = &*self.registers;
+        buffers.0.is_some() == regs.txen.is_set(ENABLE::ENABLE)
+            && buffers.1.is_some() == regs.rxen.is_set(ENABLE::ENABLE)
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = &*self.registers;
+
+        // In full duplex both pointers are latched together, so only follow
+        // the events of one direction.
+        let latched = if regs.txen.is_set(ENABLE::ENABLE) {
+            regs.events_txptrupd.is_set(EVENT::EVENT)
+        } else {
+            regs.events_rxptrupd.is_set(EVENT::EVENT)
+        };
+        regs.events_txptrupd.write(EVENT::EVENT::CLEAR);
+        regs.events_rxptrupd.write(EVENT::EVENT::CLEAR);
+
+        if latched && self.running.get() {
+            // If nothing was written to the pointers, the current buffers are
+            // transferred again.
+            if let Some(next) = self.next.take() {
+                if let Some(done) = self.current.replace(next) {
+                    self.client.map(|client| client.buffers_done(done));
+                }
+                if let Some(mut pending) = self.pending.take() {
+                    self.set_pointers(&mut pending);
+                    self.next.put(pending);
+                }
+            }
+        }
+
+        if regs.events_stopped.is_set(EVENT::EVENT) {
+            regs.events_stopped.write(EVENT::EVENT::CLEAR);
+            regs.intenclr
+                .write(INTE::RXPTRUPD::SET + INTE::TXPTRUPD::SET + INTE::STOPPED::SET);
+            regs.enable.write(ENABLE::ENABLE::CLEAR);
+            self.running.set(false);
+            for set in [&self.current, &self.next, &self.pending].iter() {
+                if let Some(buffers) = set.take() {
+                    self.client.map(|client| client.buffers_done(buffers));
+                }
+            }
+            self.client.map(|client| client.stopped());
+        }
+    }
+}
+
+impl<'a> i2s::I2s<'a> for I2S<'a> {
+    fn set_client(&self, client: &'a dyn i2s::Client) {
+        self.client.set(client);
+    }
+
+    fn configure(
+        &self,
+        sample_rate: u32,
+        width: i2s::SampleWidth,
+        channels: i2s::Channels,
+    ) -> Result<u32, ErrorCode> {
+        if self.running.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        if sample_rate == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        let regs = &*self.registers;
+
+        // A frame has to hold two samples.
+        let (swidth, min_ratio) = match width {
+            i2s::SampleWidth::Bits8 => (SWIDTH::SWIDTH::Bits8, 16),
+            i2s::SampleWidth::Bits16 => (SWIDTH::SWIDTH::Bits16, 32),
+            i2s::SampleWidth::Bits24 => (SWIDTH::SWIDTH::Bits24, 48),
+        };
+
+        // Find the clock setting closest to the requested rate.
+        let distance = |rate: u32| {
+            if rate > sample_rate {
+                rate - sample_rate
+            } else {
+                sample_rate - rate
+            }
+        };
+        let mut best: Option<(u32, u32, u32)> = None;
+        for &(divider, mckfreq) in MCK_DIVIDERS.iter() {
+            for (ratio, &mck_per_lrck) in RATIOS.iter().enumerate() {
+                if mck_per_lrck < min_ratio {
+                    continue;
+                }
+                let rate = 32_000_000 / divider / mck_per_lrck;
+                if best.map_or(true, |(best_rate, _, _)| {
+                    distance(rate) < distance(best_rate)
+                }) {
+                    best = Some((rate, mckfreq, ratio as u32));
+                }
+            }
+        }
+
+        best.map_or(Err(ErrorCode::INVAL), |(rate, mckfreq, ratio)| {
+            regs.mode.write(MODE::MODE::Master);
+            regs.mckfreq.set(mckfreq);
+            regs.ratio.set(ratio);
+            regs.swidth.write(swidth);
+            regs.channels.write(match channels {
+                i2s::Channels::Stereo => CHANNELS::CHANNELS::Stereo,
+                i2s::Channels::Left => CHANNELS::CHANNELS::Left,
+                i2s::Channels::Right => CHANNELS::CHANNELS::Right,
+            });
+            Ok(rate)
+        })
+    }
+
+    fn start(&self, mut buffers: i2s::BufferSet) -> Result<(), (ErrorCode, i2s::BufferSet)> {
+        if self.running.get() {
+            return Err((ErrorCode::BUSY, buffers));
+        }
+        let len = match Self::set_len(&buffers) {
+            Ok(len) => len,
+            Err(e) => return Err((e, buffers)),
+        };
+        let regs = &*self.registers;
+
+        regs.txen
+            .write(ENABLE::ENABLE.val(buffers.0.is_some() as u32));
+        regs.rxen
+            .write(ENABLE::ENABLE.val(buffers.1.is_some() as u32));
+        regs.maxcnt.set(len as u32);
+        self.set_pointers(&mut buffers);
+        self.next.put(buffers);
+        self.running.set(true);
+
+        regs.events_txptrupd.write(EVENT::EVENT::CLEAR);
+        regs.events_rxptrupd.write(EVENT::EVENT::CLEAR);
+        regs.events_stopped.write(EVENT::EVENT::CLEAR);
+        regs.intenset
+            .write(INTE::RXPTRUPD::SET + INTE::TXPTRUPD::SET + INTE::STOPPED::SET);
+        regs.enable.write(ENABLE::ENABLE::SET);
+        regs.tasks_start.write(TASK::TASK::SET);
+        Ok(())
+    }
+
+    fn queue(&self, mut buffers: i2s::BufferSet) -> Result<(), (ErrorCode, i2s::BufferSet)> {
+        if !self.running.get() {
+            return Err((ErrorCode::OFF, buffers));
+        }
+        match Self::set_len(&buffers) {
+            Ok(len) if len == self.registers.maxcnt.get() as usize => {}
+            Ok(_) => return Err((ErrorCode::SIZE, buffers)),
+            Err(e) => return Err((e, buffers)),
+        }
+        if !self.same_directions(&buffers) {
+            return Err((ErrorCode::INVAL, buffers));
+        }
+
+        if !self.next.is_some() {
+            self.set_pointers(&mut buffers);
+            self.next.put(buffers);
+            Ok(())
+        } else if !self.pending.is_some() {
+            self.pending.put(buffers);
+            Ok(())
+        } else {
+            Err((ErrorCode::BUSY, buffers))
+        }
+    }
+
+    fn stop(&self) -> Result<(), ErrorCode> {
+        if !self.running.get() {
+            return Err(ErrorCode::OFF);
+        }
+        self.registers.tasks_stop.write(TASK::TASK::SET);
+        Ok(())
+    }
+}
@@ -33,6 +33,14 @@ pub const RAM_LEN_BITS: usize = 8;
 pub const RAM_S1_BITS: usize = 0;
 pub const PREBUF_LEN_BYTES: usize = 2;
 
+// Duration of one energy detect sample, in microseconds (8 symbols).
+const ED_SAMPLE_TIME_US: u32 = 128;
+// Conversion of energy detect levels to dBm, from Section 6.20.12.6 of the
+// nRF52840 Product Specification: dBm = ED_RSSIOFFS + ED_RSSISCALE * level.
+const ED_RSSISCALE: i32 = 4;
+const ED_RSSIOFFS: i32 = -93;
+const NUM_CHANNELS: usize = 16;
+
 // artifact of entanglement with rf233 implementation, mac layer
 // places packet data starting PSDU_OFFSET=2 bytes after start of
 // buffer to make room for 1 byte spi header required when communicating
@@ -143,8 +151,12 @@ struct RadioRegisters {
     /// Stop the bit counter
     /// - Address: 0x020 - 0x024
     task_bcstop: WriteOnly<u32, Task::Register>,
-    /// Reserved
-    _reserved1: [u32; 2],
+    /// Start the energy detect measurement used in IEEE 802.15.4 mode
+    /// - Address: 0x024 - 0x028
+    task_edstart: WriteOnly<u32, Task::Register>,
+    /// Stop the energy detect measurement
+    /// - Address: 0x028 - 0x02c
+    task_edstop: WriteOnly<u32, Task::Register>,
     /// Stop the bit counter
     /// - Address: 0x02c - 0x030
     task_ccastart: WriteOnly<u32, Task::Register>,
@@ -193,8 +205,12 @@ struct RadioRegisters {
     /// IEEE 802.15.4 length field received
     /// - Address: 0x138 - 0x13c
     event_framestart: ReadWrite<u32, Event::Register>,
-    /// Reserved
-    _reserved5: [u32; 2],
+    /// Sampling of energy detection complete
+    /// - Address: 0x13c - 0x140
+    event_edend: ReadWrite<u32, Event::Register>,
+    /// The sampling of energy detection has stopped
+    /// - Address: 0x140 - 0x144
+    event_edstopped: ReadWrite<u32, Event::Register>,
     /// Wireless medium in idle - clear to send
     /// - Address: 0x144-0x148
     event_ccaidle: ReadWrite<u32, Event::Register>,
@@ -321,7 +337,13 @@ struct RadioRegisters {
     /// - Address: 0x650 - 0x654
     modecnf0: ReadWrite<u32, RadioModeConfig::Register>,
     /// Reserved
-    _reserved16: [u32; 6],
+    _reserved16: [u32; 4],
+    /// IEEE 802.15.4 energy detect loop count
+    /// - Address: 0x664 - 0x668
+    edcnt: ReadWrite<u32, EdCount::Register>,
+    /// IEEE 802.15.4 energy detect level
+    /// - Address: 0x668 - 0x66c
+    edsample: ReadOnly<u32, EdSample::Register>,
     /// Clear Channel Assesment (CCA) control register
     /// - Address: 0x66C - 0x670
     ccactrl: ReadWrite<u32, CCAControl::Register>,
@@ -388,6 +410,10 @@ register_bitfields! [u32,
         CRCERROR OFFSET(13) NUMBITS(1),
         /// CCAIDLE event
         FRAMESTART OFFSET(14) NUMBITS(1),
+        /// EDEND event
+        EDEND OFFSET(15) NUMBITS(1),
+        /// EDSTOPPED event
+        EDSTOPPED OFFSET(16) NUMBITS(1),
         /// CCAIDLE event
         CCAIDLE OFFSET(17) NUMBITS(1),
         /// CCABUSY event
@@ -627,6 +653,17 @@ register_bitfields! [u32,
     MACHeaderMask [
         PATTERN OFFSET(0) NUMBITS(32)
     ],
+    /// Energy detect loop count register
+    EdCount [
+        /// Number of extra 128 us energy detect samples, the peak of which
+        /// is reported
+        EDCNT OFFSET(0) NUMBITS(21)
+    ],
+    /// Energy detect level register
+    EdSample [
+        /// Energy detect level
+        EDLVL OFFSET(0) NUMBITS(8)
+    ],
     CCAControl [
         CCAMODE OFFSET(0) NUMBITS(3) [
             ED_MODE = 0,
@@ -674,6 +711,11 @@ pub struct Radio<'p> {
     channel: Cell<RadioChannel>,
     transmitting: Cell<bool>,
     timer0: OptionalCell<&'p crate::timer::TimerAlarm<'p>>,
+    energy_client: OptionalCell<&'static dyn radio::EnergyScanClient>,
+    // Channel being scanned, and the duration of the scan.
+    scan_channel: OptionalCell<RadioChannel>,
+    scan_duration_us: Cell<u32>,
+    cca_stats: Cell<[radio::CcaStats; NUM_CHANNELS]>,
 }
 
 impl<'a> AlarmClient for Radio<'a> {
@@ -700,6 +742,15 @@ impl<'p> Radio<'p> {
             channel: Cell::new(RadioChannel::DataChannel26),
             transmitting: Cell::new(false),
             timer0: OptionalCell::empty(),
+            energy_client: OptionalCell::empty(),
+            scan_channel: OptionalCell::empty(),
+            scan_duration_us: Cell::new(0),
+            cca_stats: Cell::new(
+                [radio::CcaStats {
+                    attempts: 0,
+                    busy: 0,
+                }; NUM_CHANNELS],
+            ),
         }
     }
 
@@ -777,7 +828,9 @@ impl<'p> Radio<'p> {
         if self.registers.event_ready.is_set(Event::READY) {
             self.registers.event_ready.write(Event::READY::CLEAR);
             self.registers.event_end.write(Event::READY::CLEAR);
-            if self.transmitting.get()
+            if self.scan_channel.is_some() {
+                self.registers.task_edstart.write(Task::ENABLE::SET);
+            } else if self.transmitting.get()
                 && self.registers.state.get() == nrf5x::constants::RADIO_STATE_RXIDLE
             {
                 self.registers.task_ccastart.write(Task::ENABLE::SET);
@@ -794,11 +847,13 @@ impl<'p> Radio<'p> {
         // THEN start the transmit part of the radio
         if self.registers.event_ccaidle.is_set(Event::READY) {
             self.registers.event_ccaidle.write(Event::READY::CLEAR);
+            self.count_cca(false);
             self.registers.task_txen.write(Task::ENABLE::SET)
         }
 
         if self.registers.event_ccabusy.is_set(Event::READY) {
             self.registers.event_ccabusy.write(Event::READY::CLEAR);
+            self.count_cca(true);
             self.registers.event_ready.write(Event::READY::CLEAR);
             self.registers.task_disable.write(Task::ENABLE::SET);
             while self.registers.event_disabled.get() == 0 {}
@@ -834,6 +889,22 @@ impl<'p> Radio<'p> {
             self.enable_interrupts();
         }
 
+        if self.registers.event_edend.is_set(Event::READY) {
+            self.registers.event_edend.write(Event::READY::CLEAR);
+            let level = self.registers.edsample.read(EdSample::EDLVL) as i32;
+            let energy = ED_RSSIOFFS + ED_RSSISCALE * level;
+            let energy = energy.max(i8::MIN as i32).min(i8::MAX as i32) as i8;
+            // Return to the configured channel before reporting the scan,
+            // so that the client can start another one.
+            let channel = self.scan_channel.take();
+            self.radio_off();
+            self.radio_initialize();
+            channel.map(|channel| {
+                self.energy_client
+                    .map(|client| client.scan_done(channel.get_channel_index(), Ok(energy)));
+            });
+        }
+
         // tx or rx finished!
         if self.registers.event_end.is_set(Event::READY) {
             self.registers.event_end.write(Event::READY::CLEAR);
@@ -893,7 +964,8 @@ impl<'p> Radio<'p> {
                 + Interrupt::CCAIDLE::SET
                 + Interrupt::CCABUSY::SET
                 + Interrupt::END::SET
-                + Interrupt::FRAMESTART::SET,
+                + Interrupt::FRAMESTART::SET
+                + Interrupt::EDEND::SET,
         );
     }
 
@@ -931,7 +1003,13 @@ impl<'p> Radio<'p> {
 
         self.ieee802154_set_tx_power();
 
-        self.ieee802154_set_channel_freq(self.channel.get());
+        self.ieee802154_set_channel_freq(
+            self.scan_channel
+                .extract()
+                .unwrap_or_else(|| self.channel.get()),
+        );
+
+        self.ieee802154_set_ed_count();
 
         self.set_tx_address();
         self.set_rx_address();
@@ -992,6 +1070,14 @@ impl<'p> Radio<'p> {
             .write(Frequency::FREQUENCY.val(channel as u32));
     }
 
+    // Run enough energy detect samples to cover the scan duration.
+    fn ieee802154_set_ed_count(&self) {
+        let samples = (self.scan_duration_us.get() + ED_SAMPLE_TIME_US - 1) / ED_SAMPLE_TIME_US;
+        self.registers
+            .edcnt
+            .write(EdCount::EDCNT.val(samples.saturating_sub(1).min(0x1F_FFFF)));
+    }
+
     fn ieee802154_set_tx_power(&self) {
         self.set_tx_power();
     }
@@ -1014,6 +1100,24 @@ impl<'p> Radio<'p> {
         self.random_nonce.set(next_nonce.0);
         self.random_nonce.get()
     }
+
+    // Count a CCA on the current channel.
+    fn count_cca(&self, busy: bool) {
+        let mut stats = self.cca_stats.get();
+        let channel = &mut stats[(self.channel.get().get_channel_index() - 11) as usize];
+        channel.attempts = channel.attempts.wrapping_add(1);
+        if busy {
+            channel.busy = channel.busy.wrapping_add(1);
+        }
+        self.cca_stats.set(stats);
+    }
+}
+
+impl<'p> kernel::power::PowerClient for Radio<'p> {
+    /// The radio needs the crystal oscillator while it is on.
+    fn deep_sleep_ready(&self) -> bool {
+        self.registers.state.matches_all(State::STATE::DISABLED)
+    }
 }
 
 impl<'p> kernel::hil::radio::Radio for Radio<'p> {}
@@ -1111,6 +1215,10 @@ impl<'p> kernel::hil::radio::RadioConfig for Radio<'p> {
         self.pan.set(id);
     }
 
+    fn set_ack_frame_pending(&self, _pending: bool) {
+        // ACKs are not supported by this driver yet.
+    }
+
     fn set_channel(&self, chan: u8) -> Result<(), ErrorCode> {
         match RadioChannel::try_from(chan) {
             Err(_) => Err(ErrorCode::NOSUPPORT),
@@ -1154,7 +1262,7 @@ impl<'p> kernel::hil::radio::RadioData for Radio<'p> {
         buf: &'static mut [u8],
         frame_len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
-        if self.tx_buf.is_some() || self.transmitting.get() {
+        if self.tx_buf.is_some() || self.transmitting.get() || self.scan_channel.is_some() {
             return Err((ErrorCode::BUSY, buf));
         } else if radio::PSDU_OFFSET + frame_len >= buf.len() {
             // Not enough room for CRC
@@ -1174,3 +1282,31 @@ impl<'p> kernel::hil::radio::RadioData for Radio<'p> {
         Ok(())
     }
 }
+
+impl<'p> kernel::hil::radio::RadioEnergy for Radio<'p> {
+    fn set_energy_scan_client(&self, client: &'static dyn radio::EnergyScanClient) {
+        self.energy_client.set(client);
+    }
+
+    fn energy_scan(&self, channel: u8, duration_us: u32) -> Result<(), ErrorCode> {
+        let channel = RadioChannel::try_from(channel).map_err(|_| ErrorCode::INVAL)?;
+        if self.tx_buf.is_some() || self.transmitting.get() || self.scan_channel.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.scan_channel.set(channel);
+        self.scan_duration_us.set(duration_us);
+        self.radio_off();
+        self.radio_initialize();
+        Ok(())
+    }
+
+    fn cca_stats(&self, channel: u8) -> Result<radio::CcaStats, ErrorCode> {
+        let channel = RadioChannel::try_from(channel).map_err(|_| ErrorCode::INVAL)?;
+        Ok(self.cca_stats.get()[(channel.get_channel_index() - 11) as usize])
+    }
+
+    fn reset_cca_stats(&self) {
+        self.cca_stats
+            .set([radio::CcaStats::default(); NUM_CHANNELS]);
+    }
+}
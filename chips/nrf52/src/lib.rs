@@ -12,11 +12,14 @@ pub mod crt1;
 pub mod deferred_call_tasks;
 pub mod ficr;
 pub mod i2c;
+pub mod i2s;
 pub mod ieee802154_radio;
 pub mod nvmc;
 pub mod power;
 pub mod ppi;
 pub mod pwm;
+pub mod pwm_capture;
+pub mod qdec;
 pub mod spi;
 pub mod uart;
 pub mod uicr;
@@ -323,6 +323,27 @@ impl<'a> Power<'a> {
         self.registers.intenclr.set(0xffffffff);
     }
 
+    /// Select the low-power sub-mode of System ON, in which the regulators
+    /// and clocks are only kept on while peripherals request them. This
+    /// undoes the constant-latency sub-mode.
+    pub fn low_power_mode(&self) {
+        self.registers.task_lowpwr.write(Task::ENABLE::SET);
+    }
+
+    /// Enter System OFF, the deepest sleep state. Only a reset, a GPIO
+    /// DETECT signal, LPCOMP or NFC can wake the chip, and it then restarts
+    /// from reset: the RAM and the RTC are not kept running, so this cannot
+    /// be used to sleep until the next alarm.
+    pub fn system_off(&self) -> ! {
+        self.registers.systemoff.write(Task::ENABLE::SET);
+        // Entering System OFF can take a few cycles.
+        loop {
+            unsafe {
+                cortexm4::support::wfi();
+            }
+        }
+    }
+
     pub fn get_main_supply_status(&self) -> MainVoltage {
         match self
             .registers
@@ -58,52 +58,20 @@ struct PpiRegisters {
     chen: ReadWrite<u32, Channel::Register>,
     chenset: ReadWrite<u32, Channel::Register>,
     chenclr: ReadWrite<u32, Channel::Register>,
-    ch0_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch0_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch1_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch1_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch2_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch2_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch3_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch3_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch4_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch4_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch5_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch5_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch6_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch6_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch7_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch7_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch8_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch8_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch9_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch9_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch10_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch10_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch11_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch11_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch12_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch12_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch13_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch13_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch14_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch14_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch15_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch15_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch16_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch16_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch17_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch17_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch18_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch18_tep: ReadWrite<u32, TaskEndPoint::Register>,
-    ch19_eep: ReadWrite<u32, EventEndPoint::Register>,
-    ch19_tep: ReadWrite<u32, TaskEndPoint::Register>,
+    ch: [ChannelEndPoints; 20],
     _reserved2: [u32; 148],
     chg: [ReadWrite<u32, Channel::Register>; 6],
     _reserved3: [u32; 62],
     fork_tep: [ReadWrite<u32, TaskEndPoint::Register>; 32],
 }
 
+/// The event end point and task end point of a programmable channel.
+#[repr(C)]
+struct ChannelEndPoints {
+    eep: ReadWrite<u32, EventEndPoint::Register>,
+    tep: ReadWrite<u32, TaskEndPoint::Register>,
+}
+
 register_bitfields! [u32,
     Control [
         ENABLE OFFSET(0) NUMBITS(1)
@@ -168,4 +136,17 @@ impl Ppi {
     pub fn disable(&self, channels: FieldValue<u32, Channel::Register>) {
         self.registers.chenclr.write(channels);
     }
+
+    /// Connect the event at address `event` to the task at address `task`
+    /// through programmable channel `channel` (0 to 19), and to the task at
+    /// address `fork` if there is one. The channel still has to be enabled.
+    pub fn connect(&self, channel: usize, event: u32, task: u32, fork: Option<u32>) {
+        self.registers.ch[channel]
+            .eep
+            .write(EventEndPoint::ADDRESS.val(event));
+        self.registers.ch[channel]
+            .tep
+            .write(TaskEndPoint::ADDRESS.val(task));
+        self.registers.fork_tep[channel].write(TaskEndPoint::ADDRESS.val(fork.unwrap_or(0)));
+    }
 }
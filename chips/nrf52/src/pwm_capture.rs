@@ -0,0 +1,149 @@
+//! Input capture of a PWM signal, nRF52
+//!
+//! The GPIOTE event of the pin, on either edge, is connected through a PPI
+//! channel to the CAPTURE\[0\] task of a timer, and forked to its CLEAR task.
+//! On every edge the timer latches the time since the previous edge, and
+//! restarts from zero. The GPIOTE interrupt then reads the latched time: a
+//! falling edge ends the time the signal was high, and a rising edge ends
+//! the time it was low, and with it a period.
+//!
+//! The latched time is overwritten by the next edge, so pulses shorter than
+//! the interrupt latency are not measured correctly.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use kernel::hil::gpio::Interrupt;
+//!
+//! let pwm_capture = static_init!(
+//!     nrf52::pwm_capture::PwmCapture<'static>,
+//!     nrf52::pwm_capture::PwmCapture::new(
+//!         &base_peripherals.timer2,
+//!         &nrf52840::gpio::PORT[Pin::P0_03],
+//!         0
+//!     )
+//! );
+//! nrf52840::gpio::PORT[Pin::P0_03].set_client(pwm_capture);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::common::registers::FieldValue;
+use kernel::hil::gpio::{self, Configure, Input, Interrupt};
+use kernel::hil::pwm_capture;
+use kernel::ErrorCode;
+
+use crate::gpio::GPIOPin;
+use crate::ppi::{Channel, Ppi};
+use crate::timer::Timer;
+
+/// The capture clock is the 16 MHz clock divided by `2^PRESCALER`.
+const PRESCALER: u32 = 4;
+
+const CC_CAPTURE: usize = 0;
+
+pub struct PwmCapture<'a> {
+    timer: &'a Timer,
+    pin: &'a GPIOPin<'a>,
+    ppi: Ppi,
+    ppi_channel: usize,
+    client: OptionalCell<&'a dyn pwm_capture::Client>,
+    running: Cell<bool>,
+    /// Whether an edge has been seen since the capture started, as the time
+    /// up to the first one is not a whole level of the signal.
+    synchronized: Cell<bool>,
+    /// The time the signal was high in the period in progress.
+    high: OptionalCell<u32>,
+}
+
+impl<'a> PwmCapture<'a> {
+    /// Capture the signal on `pin` with `timer`, through PPI channel
+    /// `ppi_channel` (0 to 19), which must not be used by anything else.
+    pub const fn new(timer: &'a Timer, pin: &'a GPIOPin<'a>, ppi_channel: usize) -> Self {
+        PwmCapture {
+            timer: timer,
+            pin: pin,
+            ppi: Ppi::new(),
+            ppi_channel: ppi_channel,
+            client: OptionalCell::empty(),
+            running: Cell::new(false),
+            synchronized: Cell::new(false),
+            high: OptionalCell::empty(),
+        }
+    }
+
+    fn ppi_channel(&self) -> FieldValue<u32, Channel::Register> {
+        FieldValue::<u32, Channel::Register>::new(1, self.ppi_channel, 1)
+    }
+}
+
+impl<'a> pwm_capture::PwmCapture<'a> for PwmCapture<'a> {
+    fn set_client(&self, client: &'a dyn pwm_capture::Client) {
+        self.client.set(client);
+    }
+
+    fn get_frequency(&self) -> u32 {
+        16_000_000 >> PRESCALER
+    }
+
+    fn start(&self) -> Result<(), ErrorCode> {
+        if self.running.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.pin.make_input();
+        self.pin.enable_interrupts(gpio::InterruptEdge::EitherEdge);
+        let event = match self.pin.gpiote_event_address() {
+            Some(event) => event,
+            None => {
+                // No GPIOTE channel was free for the pin.
+                self.pin.disable_interrupts();
+                return Err(ErrorCode::NOMEM);
+            }
+        };
+        self.ppi.connect(
+            self.ppi_channel,
+            event,
+            self.timer.capture_task_address(CC_CAPTURE),
+            Some(self.timer.clear_task_address()),
+        );
+        self.synchronized.set(false);
+        self.high.clear();
+        self.running.set(true);
+        self.timer.start_counter(PRESCALER);
+        self.ppi.enable(self.ppi_channel());
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), ErrorCode> {
+        self.ppi.disable(self.ppi_channel());
+        self.pin.disable_interrupts();
+        self.timer.stop_counter();
+        self.running.set(false);
+        Ok(())
+    }
+}
+
+impl gpio::Client for PwmCapture<'_> {
+    fn fired(&self) {
+        if !self.running.get() {
+            return;
+        }
+        // The time the level that just ended lasted.
+        let level = self.timer.get_capture(CC_CAPTURE);
+        if !self.synchronized.get() {
+            self.synchronized.set(true);
+            return;
+        }
+        if self.pin.read() {
+            // Rising edge: the low level ended, and with it the period.
+            if let Some(high) = self.high.take() {
+                self.client
+                    .map(|client| client.captured(high.wrapping_add(level), high));
+            }
+        } else {
+            self.high.set(level);
+        }
+    }
+}
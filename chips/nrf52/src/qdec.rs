@@ -0,0 +1,183 @@
+//! Quadrature decoder (QDEC), nRF52
+//!
+//! The QDEC samples the two phases of a rotary encoder every 128 µs, and
+//! accumulates the steps in its ACC register, which holds -1024 to 1023
+//! steps. It counts at most one step per sample, and reports every 10
+//! samples if the encoder moved, which tells the client to take the steps
+//! long before ACC overflows.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! base_peripherals.qdec.set_pins(
+//!     nrf5x::pinmux::Pinmux::new(Pin::P0_04 as u32),
+//!     nrf5x::pinmux::Pinmux::new(Pin::P0_05 as u32),
+//! );
+//! ```
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::registers::interfaces::{Readable, Writeable};
+use kernel::common::registers::{
+    register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly,
+};
+use kernel::common::StaticRef;
+use kernel::hil::qdec;
+use kernel::ErrorCode;
+use nrf5x::pinmux::Pinmux;
+
+register_structs! {
+    QdecRegisters {
+        (0x000 => tasks_start: WriteOnly<u32, Task::Register>),
+        (0x004 => tasks_stop: WriteOnly<u32, Task::Register>),
+        /// Read and clear ACC into ACCREAD
+        (0x008 => tasks_readclracc: WriteOnly<u32, Task::Register>),
+        (0x00C => _reserved0),
+        (0x100 => events_samplerdy: ReadWrite<u32, Event::Register>),
+        /// REPORTPER samples with movement were taken
+        (0x104 => events_reportrdy: ReadWrite<u32, Event::Register>),
+        /// ACC overflowed
+        (0x108 => events_accof: ReadWrite<u32, Event::Register>),
+        (0x10C => _reserved1),
+        (0x304 => intenset: ReadWrite<u32, Interrupt::Register>),
+        (0x308 => intenclr: ReadWrite<u32, Interrupt::Register>),
+        (0x30C => _reserved2),
+        (0x500 => enable: ReadWrite<u32, Enable::Register>),
+        (0x504 => ledpol: ReadWrite<u32>),
+        (0x508 => sampleper: ReadWrite<u32, SamplePeriod::Register>),
+        (0x50C => sample: ReadOnly<u32>),
+        (0x510 => reportper: ReadWrite<u32, ReportPeriod::Register>),
+        (0x514 => acc: ReadOnly<u32>),
+        /// Snapshot of ACC taken by READCLRACC
+        (0x518 => accread: ReadOnly<u32>),
+        (0x51C => psel_led: ReadWrite<u32, Psel::Register>),
+        (0x520 => psel_a: ReadWrite<u32, Psel::Register>),
+        (0x524 => psel_b: ReadWrite<u32, Psel::Register>),
+        /// Debounce input filters
+        (0x528 => dbfen: ReadWrite<u32, Enable::Register>),
+        (0x52C => @END),
+    }
+}
+
+register_bitfields![u32,
+    Task [
+        ENABLE 0
+    ],
+    Event [
+        READY 0
+    ],
+    Interrupt [
+        SAMPLERDY 0,
+        REPORTRDY 1,
+        ACCOF 2
+    ],
+    Enable [
+        ENABLE 0
+    ],
+    SamplePeriod [
+        SAMPLEPER OFFSET(0) NUMBITS(4) [
+            us128 = 0,
+            us256 = 1,
+            us512 = 2,
+            us1024 = 3,
+            us2048 = 4,
+            us4096 = 5,
+            us8192 = 6,
+            us16384 = 7
+        ]
+    ],
+    ReportPeriod [
+        REPORTPER OFFSET(0) NUMBITS(4) [
+            Samples10 = 0,
+            Samples40 = 1,
+            Samples80 = 2,
+            Samples120 = 3
+        ]
+    ],
+    Psel [
+        PIN OFFSET(0) NUMBITS(6) [],
+        CONNECT OFFSET(31) NUMBITS(1) [
+            Connected = 0,
+            Disconnected = 1
+        ]
+    ]
+];
+
+const QDEC_BASE: StaticRef<QdecRegisters> =
+    unsafe { StaticRef::new(0x40012000 as *const QdecRegisters) };
+
+pub struct Qdec<'a> {
+    registers: StaticRef<QdecRegisters>,
+    client: OptionalCell<&'a dyn qdec::Client>,
+}
+
+impl<'a> Qdec<'a> {
+    pub const fn new() -> Qdec<'a> {
+        Qdec {
+            registers: QDEC_BASE,
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Set the pins of phases A and B of the encoder.
+    pub fn set_pins(&self, a: Pinmux, b: Pinmux) {
+        let a: u32 = a.into();
+        let b: u32 = b.into();
+        self.registers.psel_a.write(Psel::PIN.val(a));
+        self.registers.psel_b.write(Psel::PIN.val(b));
+        self.registers.psel_led.write(Psel::CONNECT::Disconnected);
+    }
+
+    pub fn handle_interrupt(&self) {
+        if self.registers.events_accof.is_set(Event::READY) {
+            self.registers.events_accof.write(Event::READY::CLEAR);
+            self.client.map(|client| client.overflow());
+        }
+        if self.registers.events_reportrdy.is_set(Event::READY) {
+            self.registers.events_reportrdy.write(Event::READY::CLEAR);
+            self.client.map(|client| client.steps_ready());
+        }
+    }
+}
+
+impl<'a> qdec::Qdec<'a> for Qdec<'a> {
+    fn set_client(&self, client: &'a dyn qdec::Client) {
+        self.client.set(client);
+    }
+
+    fn enable(&self) -> Result<(), ErrorCode> {
+        if self.is_enabled() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let regs = &*self.registers;
+        regs.sampleper.write(SamplePeriod::SAMPLEPER::us128);
+        regs.reportper.write(ReportPeriod::REPORTPER::Samples10);
+        regs.dbfen.write(Enable::ENABLE::SET);
+        regs.events_reportrdy.write(Event::READY::CLEAR);
+        regs.events_accof.write(Event::READY::CLEAR);
+        regs.intenset
+            .write(Interrupt::REPORTRDY::SET + Interrupt::ACCOF::SET);
+        regs.enable.write(Enable::ENABLE::SET);
+        regs.tasks_readclracc.write(Task::ENABLE::SET);
+        regs.tasks_start.write(Task::ENABLE::SET);
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        let regs = &*self.registers;
+        regs.tasks_stop.write(Task::ENABLE::SET);
+        regs.intenclr
+            .write(Interrupt::SAMPLERDY::SET + Interrupt::REPORTRDY::SET + Interrupt::ACCOF::SET);
+        regs.enable.write(Enable::ENABLE::CLEAR);
+        Ok(())
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.registers.enable.is_set(Enable::ENABLE)
+    }
+
+    fn take_steps(&self) -> i32 {
+        self.registers.tasks_readclracc.write(Task::ENABLE::SET);
+        self.registers.accread.get() as i32
+    }
+}
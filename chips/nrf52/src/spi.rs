@@ -20,8 +20,8 @@
 //! * ✓ get_clock
 //! * ✓ set_phase
 //! * ✓ get_phase
-//! * hold_low
-//! * release_low
+//! * ✓ hold_low
+//! * ✓ release_low
 //!
 //! Author
 //! -------------------
@@ -239,6 +239,7 @@ pub struct SPIM {
     chip_select: OptionalCell<&'static dyn hil::gpio::Pin>,
     initialized: Cell<bool>,
     busy: Cell<bool>,
+    hold_cs: Cell<bool>,
     tx_buf: TakeCell<'static, [u8]>,
     rx_buf: TakeCell<'static, [u8]>,
     transfer_len: Cell<usize>,
@@ -252,6 +253,7 @@ impl SPIM {
             chip_select: OptionalCell::empty(),
             initialized: Cell::new(false),
             busy: Cell::new(false),
+            hold_cs: Cell::new(false),
             tx_buf: TakeCell::empty(),
             rx_buf: TakeCell::empty(),
             transfer_len: Cell::new(0),
@@ -268,7 +270,9 @@ impl SPIM {
                 return;
             }
 
-            self.chip_select.map(|cs| cs.set());
+            if !self.hold_cs.get() {
+                self.chip_select.map(|cs| cs.set());
+            }
             self.registers.events_end.write(EVENT::EVENT::CLEAR);
 
             self.busy.set(false);
@@ -470,14 +474,17 @@ impl hil::spi::SpiMaster for SPIM {
         }
     }
 
-    // The following two trait functions are not implemented for
-    // SAM4L, and appear to not provide much functionality. Let's not
-    // bother implementing them unless needed.
+    // The chip select is a GPIO pin, so it can be asserted and
+    // deasserted between transfers as well as around them.
     fn hold_low(&self) {
-        unimplemented!("SPI: Use `read_write_bytes()` instead.");
+        self.hold_cs.set(true);
+        self.chip_select.map(|cs| cs.clear());
     }
 
     fn release_low(&self) {
-        unimplemented!("SPI: Use `read_write_bytes()` instead.");
+        self.hold_cs.set(false);
+        if !self.busy.get() {
+            self.chip_select.map(|cs| cs.set());
+        }
     }
 }
@@ -6,5 +6,6 @@ pub use nrf52::{
 };
 pub mod gpio;
 pub mod interrupt_service;
+pub mod qspi;
 
 pub mod peripheral_interrupts;
@@ -0,0 +1,427 @@
+//! QSPI driver for nRF52840.
+//!
+//! The peripheral transfers between the flash and RAM by EasyDMA, and sends
+//! the write enable command before programs and erases by itself. It signals
+//! the end of every task with the READY event, which for programs and erases
+//! only means that the command was sent. To complete them once the flash is
+//! done, the driver then reads the status register with a custom instruction
+//! that waits until the flash is no longer busy.
+//!
+//! The flash is mapped into memory at `XIP_BASE` whenever the peripheral is
+//! active.
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::registers::interfaces::{Readable, Writeable};
+use kernel::common::registers::{register_bitfields, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::qspi;
+use kernel::ErrorCode;
+use nrf52::pinmux::Pinmux;
+
+const QSPI_BASE: StaticRef<QspiRegisters> =
+    unsafe { StaticRef::new(0x40029000 as *const QspiRegisters) };
+
+/// Address of the memory-mapped flash.
+pub const XIP_BASE: usize = 0x1200_0000;
+
+/// Largest number of bytes in a read or a program.
+const MAX_COUNT: usize = 0x3FFFF;
+
+/// Read Status Register instruction.
+const RDSR: u32 = 0x05;
+
+#[repr(C)]
+struct QspiRegisters {
+    /// Activate QSPI interface
+    tasks_activate: WriteOnly<u32, TASK::Register>,
+    /// Start transfer from external flash memory to internal RAM
+    tasks_readstart: WriteOnly<u32, TASK::Register>,
+    /// Start transfer from internal RAM to external flash memory
+    tasks_writestart: WriteOnly<u32, TASK::Register>,
+    /// Start external flash memory erase operation
+    tasks_erasestart: WriteOnly<u32, TASK::Register>,
+    /// Deactivate QSPI interface
+    tasks_deactivate: WriteOnly<u32, TASK::Register>,
+    _reserved0: [u8; 236],
+    /// QSPI peripheral is ready
+    events_ready: ReadWrite<u32, EVENT::Register>,
+    _reserved1: [u8; 512],
+    /// Enable interrupt
+    intenset: ReadWrite<u32, INTE::Register>,
+    /// Disable interrupt
+    intenclr: ReadWrite<u32, INTE::Register>,
+    _reserved2: [u8; 500],
+    /// Enable QSPI peripheral and acquire the pins selected in PSELn
+    enable: ReadWrite<u32, ENABLE::Register>,
+    /// Flash memory source address
+    read_src: ReadWrite<u32>,
+    /// RAM destination address
+    read_dst: ReadWrite<u32>,
+    /// Read transfer length
+    read_cnt: ReadWrite<u32>,
+    /// Flash destination address
+    write_dst: ReadWrite<u32>,
+    /// RAM source address
+    write_src: ReadWrite<u32>,
+    /// Write transfer length
+    write_cnt: ReadWrite<u32>,
+    /// Start address of flash block to be erased
+    erase_ptr: ReadWrite<u32>,
+    /// Size of block to be erased
+    erase_len: ReadWrite<u32, ERASE_LEN::Register>,
+    /// Pin select for serial clock SCK
+    psel_sck: ReadWrite<u32>,
+    /// Pin select for chip select signal CSN
+    psel_csn: ReadWrite<u32>,
+    _reserved3: [u8; 4],
+    /// Pin select for serial data IO0 to IO3
+    psel_io: [ReadWrite<u32>; 4],
+    /// Address offset into the external memory for Execute in Place
+    /// operation
+    xipoffset: ReadWrite<u32>,
+    /// Interface configuration
+    ifconfig0: ReadWrite<u32, IFCONFIG0::Register>,
+    _reserved4: [u8; 184],
+    /// Interface configuration
+    ifconfig1: ReadWrite<u32, IFCONFIG1::Register>,
+    _reserved5: [u8; 48],
+    /// Custom instruction configuration register
+    cinstrconf: ReadWrite<u32, CINSTRCONF::Register>,
+}
+
+register_bitfields![u32,
+    TASK [
+        TASK 0
+    ],
+    EVENT [
+        EVENT 0
+    ],
+    INTE [
+        /// Interrupt on EVENTS_READY event
+        READY 0
+    ],
+    ENABLE [
+        ENABLE 0
+    ],
+    ERASE_LEN [
+        LEN OFFSET(0) NUMBITS(2) [
+            Erase4KB = 0,
+            Erase64KB = 1,
+            All = 2
+        ]
+    ],
+    IFCONFIG0 [
+        /// Configure number of data lines and opcode used for reading
+        READOC OFFSET(0) NUMBITS(3) [
+            FastRead = 0,
+            Read2O = 1,
+            Read2IO = 2,
+            Read4O = 3,
+            Read4IO = 4
+        ],
+        /// Configure number of data lines and opcode used for writing
+        WRITEOC OFFSET(3) NUMBITS(3) [
+            PP = 0,
+            PP2O = 1,
+            PP4O = 2,
+            PP4IO = 3
+        ],
+        /// Addressing mode
+        ADDRMODE OFFSET(6) NUMBITS(1) [
+            Bit24 = 0,
+            Bit32 = 1
+        ],
+        /// Page size for commands PP, PP2O, PP4O and PP4IO
+        PPSIZE OFFSET(12) NUMBITS(1) [
+            Bytes256 = 0,
+            Bytes512 = 1
+        ]
+    ],
+    IFCONFIG1 [
+        /// Minimum amount of time that the CSN pin must stay high before it
+        /// can go low again, in 62.5 ns units
+        SCKDELAY OFFSET(0) NUMBITS(8) [],
+        /// Select SPI mode
+        SPIMODE OFFSET(25) NUMBITS(1) [
+            Mode0 = 0,
+            Mode3 = 1
+        ],
+        /// SCK frequency is 32 MHz / (SCKFREQ + 1)
+        SCKFREQ OFFSET(28) NUMBITS(4) []
+    ],
+    CINSTRCONF [
+        /// Opcode of Custom instruction
+        OPCODE OFFSET(0) NUMBITS(8) [],
+        /// Length of custom instruction in number of bytes, including the
+        /// opcode
+        LENGTH OFFSET(8) NUMBITS(4) [],
+        /// Level of the IO2 pin during the instruction
+        LIO2 OFFSET(12) NUMBITS(1) [],
+        /// Level of the IO3 pin during the instruction
+        LIO3 OFFSET(13) NUMBITS(1) [],
+        /// Wait until the flash is no longer busy before sending the
+        /// instruction
+        WIPWAIT OFFSET(14) NUMBITS(1) [],
+        /// Send WREN before the instruction
+        WREN OFFSET(15) NUMBITS(1) []
+    ]
+];
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Off,
+    Idle,
+    Read,
+    Write,
+    Erase,
+    /// Waiting until a program or an erase is done.
+    WriteWait,
+    EraseWait,
+}
+
+pub struct Qspi<'a> {
+    registers: StaticRef<QspiRegisters>,
+    client: OptionalCell<&'a dyn qspi::Client>,
+    state: Cell<State>,
+    mapped: Cell<bool>,
+    size: Cell<usize>,
+    buffer: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+}
+
+impl<'a> Qspi<'a> {
+    pub fn new() -> Qspi<'a> {
+        Qspi {
+            registers: QSPI_BASE,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Off),
+            mapped: Cell::new(false),
+            size: Cell::new(0),
+            buffer: TakeCell::empty(),
+            len: Cell::new(0),
+        }
+    }
+
+    /// Select the pins of the interface.
+    pub fn set_pins(&self, sck: Pinmux, csn: Pinmux, io: [Pinmux; 4]) {
+        let regs = &*self.registers;
+        regs.psel_sck.set(sck.into());
+        regs.psel_csn.set(csn.into());
+        for (psel, pin) in regs.psel_io.iter().zip(io.iter()) {
+            psel.set((*pin).into());
+        }
+    }
+
+    /// Check the area of a read or a program.
+    fn check_transfer(&self, address: usize, buffer: &[u8], len: usize) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle || self.mapped.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        if len == 0 || len > buffer.len() || len > MAX_COUNT {
+            return Err(ErrorCode::SIZE);
+        }
+        if address % 4 != 0 || len % 4 != 0 || buffer.as_ptr() as usize % 4 != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        if address + len > self.size.get() {
+            return Err(ErrorCode::INVAL);
+        }
+        Ok(())
+    }
+
+    /// Start a task that ends with the READY event.
+    fn start(&self, state: State, task: &WriteOnly<u32, TASK::Register>) {
+        let regs = &*self.registers;
+        self.state.set(state);
+        regs.events_ready.write(EVENT::EVENT::CLEAR);
+        regs.intenset.write(INTE::READY::SET);
+        task.write(TASK::TASK::SET);
+    }
+
+    /// Read the status register once the flash is done with a program or an
+    /// erase.
+    fn wait_while_busy(&self, state: State) {
+        let regs = &*self.registers;
+        self.state.set(state);
+        regs.events_ready.write(EVENT::EVENT::CLEAR);
+        regs.intenset.write(INTE::READY::SET);
+        regs.cinstrconf.write(
+            CINSTRCONF::OPCODE.val(RDSR)
+                + CINSTRCONF::LENGTH.val(2)
+                + CINSTRCONF::LIO2::SET
+                + CINSTRCONF::LIO3::SET
+                + CINSTRCONF::WIPWAIT::SET,
+        );
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = &*self.registers;
+        if !regs.events_ready.is_set(EVENT::EVENT) {
+            return;
+        }
+        regs.events_ready.write(EVENT::EVENT::CLEAR);
+        regs.intenclr.write(INTE::READY::SET);
+
+        match self.state.get() {
+            State::Read => {
+                self.state.set(State::Idle);
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.read_complete(buffer, self.len.get(), Ok(())));
+                });
+            }
+            State::Write => self.wait_while_busy(State::WriteWait),
+            State::Erase => self.wait_while_busy(State::EraseWait),
+            State::WriteWait => {
+                self.state.set(State::Idle);
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.write_complete(buffer, self.len.get(), Ok(())));
+                });
+            }
+            State::EraseWait => {
+                self.state.set(State::Idle);
+                self.client.map(|client| client.erase_complete(Ok(())));
+            }
+            State::Off | State::Idle => {}
+        }
+    }
+}
+
+impl<'a> qspi::Qspi<'a> for Qspi<'a> {
+    fn set_client(&self, client: &'a dyn qspi::Client) {
+        self.client.set(client);
+    }
+
+    fn configure(&self, config: qspi::Config) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Off | State::Idle if !self.mapped.get() => {}
+            _ => return Err(ErrorCode::BUSY),
+        }
+        if !config.size.is_power_of_two() || config.frequency < 2_000_000 {
+            return Err(ErrorCode::INVAL);
+        }
+        let regs = &*self.registers;
+
+        if self.state.get() == State::Idle {
+            regs.tasks_deactivate.write(TASK::TASK::SET);
+        }
+
+        // The closest frequency that is not higher.
+        let sckfreq =
+            core::cmp::min((32_000_000 + config.frequency - 1) / config.frequency, 16) - 1;
+        regs.ifconfig0.write(
+            match config.read_mode {
+                qspi::ReadMode::Fast => IFCONFIG0::READOC::FastRead,
+                qspi::ReadMode::DualOutput => IFCONFIG0::READOC::Read2O,
+                qspi::ReadMode::DualIo => IFCONFIG0::READOC::Read2IO,
+                qspi::ReadMode::QuadOutput => IFCONFIG0::READOC::Read4O,
+                qspi::ReadMode::QuadIo => IFCONFIG0::READOC::Read4IO,
+            } + match config.write_mode {
+                qspi::WriteMode::Single => IFCONFIG0::WRITEOC::PP,
+                qspi::WriteMode::QuadOutput => IFCONFIG0::WRITEOC::PP4O,
+            } + match config.address_width {
+                qspi::AddressWidth::Bits24 => IFCONFIG0::ADDRMODE::Bit24,
+                qspi::AddressWidth::Bits32 => IFCONFIG0::ADDRMODE::Bit32,
+            } + IFCONFIG0::PPSIZE::Bytes256,
+        );
+        regs.ifconfig1.write(
+            IFCONFIG1::SCKDELAY.val(1)
+                + IFCONFIG1::SPIMODE::Mode0
+                + IFCONFIG1::SCKFREQ.val(sckfreq),
+        );
+        regs.xipoffset.set(0);
+        self.size.set(config.size);
+
+        // Activation only takes as long as the flash needs to wake up, so
+        // wait for it rather than complete the configuration later.
+        regs.events_ready.write(EVENT::EVENT::CLEAR);
+        regs.enable.write(ENABLE::ENABLE::SET);
+        regs.tasks_activate.write(TASK::TASK::SET);
+        while !regs.events_ready.is_set(EVENT::EVENT) {}
+        regs.events_ready.write(EVENT::EVENT::CLEAR);
+        self.state.set(State::Idle);
+        Ok(())
+    }
+
+    fn read(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(e) = self.check_transfer(address, buffer, len) {
+            return Err((e, buffer));
+        }
+        let regs = &*self.registers;
+        regs.read_src.set(address as u32);
+        regs.read_dst.set(buffer.as_mut_ptr() as u32);
+        regs.read_cnt.set(len as u32);
+        self.buffer.replace(buffer);
+        self.len.set(len);
+        self.start(State::Read, &regs.tasks_readstart);
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(e) = self.check_transfer(address, buffer, len) {
+            return Err((e, buffer));
+        }
+        if address % qspi::PROGRAM_PAGE_SIZE + len > qspi::PROGRAM_PAGE_SIZE {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        let regs = &*self.registers;
+        regs.write_dst.set(address as u32);
+        regs.write_src.set(buffer.as_ptr() as u32);
+        regs.write_cnt.set(len as u32);
+        self.buffer.replace(buffer);
+        self.len.set(len);
+        self.start(State::Write, &regs.tasks_writestart);
+        Ok(())
+    }
+
+    fn erase(&self, address: usize, size: qspi::EraseSize) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle || self.mapped.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        let (len, alignment) = match size {
+            qspi::EraseSize::Sector4K => (ERASE_LEN::LEN::Erase4KB, 0x1000),
+            qspi::EraseSize::Block64K => (ERASE_LEN::LEN::Erase64KB, 0x10000),
+            qspi::EraseSize::Chip => (ERASE_LEN::LEN::All, 1),
+        };
+        if size != qspi::EraseSize::Chip && (address % alignment != 0 || address >= self.size.get())
+        {
+            return Err(ErrorCode::INVAL);
+        }
+        let regs = &*self.registers;
+        regs.erase_ptr.set(address as u32);
+        regs.erase_len.write(len);
+        self.start(State::Erase, &regs.tasks_erasestart);
+        Ok(())
+    }
+
+    fn enable_memory_mapped(&self) -> Result<usize, ErrorCode> {
+        match self.state.get() {
+            State::Off => Err(ErrorCode::OFF),
+            State::Idle => {
+                self.mapped.set(true);
+                Ok(XIP_BASE)
+            }
+            _ => Err(ErrorCode::BUSY),
+        }
+    }
+
+    fn disable_memory_mapped(&self) -> Result<(), ErrorCode> {
+        if !self.mapped.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.mapped.set(false);
+        Ok(())
+    }
+}
@@ -378,6 +378,14 @@ impl<'a> GPIOPin<'a> {
         }
     }
 
+    /// Address of the GPIOTE event of the pin, for the PPI to connect tasks
+    /// to. The pin only has one while its interrupts are enabled.
+    pub fn gpiote_event_address(&self) -> Option<u32> {
+        self.find_channel(self.pin)
+            .ok()
+            .map(|channel| &self.gpiote_registers.event_in[channel] as *const _ as u32)
+    }
+
     pub fn set_high_drive(&self, high_drive: bool) {
         self.gpio_registers.pin_cnf[self.pin as usize].modify(if high_drive {
             PinConfig::DRIVE::H0H1
@@ -255,6 +255,61 @@ impl Timer {
             client.compare(val as u8);
         });
     }
+
+    /// Run the timer as a 32-bit counter of the 16 MHz clock divided by
+    /// `2^prescaler`, from 0.
+    pub fn start_counter(&self, prescaler: u32) {
+        let regs = &*self.registers;
+        regs.tasks_stop.write(Task::ENABLE::SET);
+        // Timer mode
+        regs.mode.set(0);
+        regs.bitmode.write(Bitmode::BITMODE::Bit32);
+        regs.prescaler.set(prescaler);
+        regs.tasks_clear.write(Task::ENABLE::SET);
+        regs.tasks_start.write(Task::ENABLE::SET);
+    }
+
+    /// Run the timer on the 16 MHz clock divided by `2^prescaler`,
+    /// generating COMPARE\[0\] and restarting from 0 every `period` ticks.
+    pub fn start_periodic(&self, prescaler: u32, period: u32) {
+        let regs = &*self.registers;
+        regs.tasks_stop.write(Task::ENABLE::SET);
+        // Timer mode
+        regs.mode.set(0);
+        regs.bitmode.write(Bitmode::BITMODE::Bit32);
+        regs.prescaler.set(prescaler);
+        regs.cc[0].write(CC::CC.val(period));
+        regs.shorts.write(Shorts::COMPARE0_CLEAR::EnableShortcut);
+        regs.tasks_clear.write(Task::ENABLE::SET);
+        regs.tasks_start.write(Task::ENABLE::SET);
+    }
+
+    pub fn stop_counter(&self) {
+        self.registers.tasks_stop.write(Task::ENABLE::SET);
+        self.registers.tasks_clear.write(Task::ENABLE::SET);
+        self.registers.shorts.set(0);
+    }
+
+    /// The counter value last captured into CC[`cc`].
+    pub fn get_capture(&self, cc: usize) -> u32 {
+        self.registers.cc[cc].read(CC::CC)
+    }
+
+    /// Address of the task capturing the counter into CC[`cc`], for the PPI
+    /// to trigger.
+    pub fn capture_task_address(&self, cc: usize) -> u32 {
+        &self.registers.tasks_capture[cc] as *const _ as u32
+    }
+
+    /// Address of the COMPARE[`cc`] event, for the PPI to connect.
+    pub fn compare_event_address(&self, cc: usize) -> u32 {
+        &self.registers.events_compare[cc] as *const _ as u32
+    }
+
+    /// Address of the task clearing the counter, for the PPI to trigger.
+    pub fn clear_task_address(&self) -> u32 {
+        &self.registers.tasks_clear as *const _ as u32
+    }
 }
 
 pub struct TimerAlarm<'a> {
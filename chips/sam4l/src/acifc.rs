@@ -265,11 +265,15 @@ register_bitfields![u32,
     ]
 ];
 
+/// Number of windows formed by the pairs of the four available ACs.
+const WINDOWS: usize = 2;
+
 const ACIFC_BASE: StaticRef<AcifcRegisters> =
     unsafe { StaticRef::new(0x40040000 as *const AcifcRegisters) };
 
 pub struct Acifc<'a> {
     client: Cell<Option<&'a dyn analog_comparator::Client>>,
+    modes: [Cell<analog_comparator::InterruptMode>; 4],
 }
 
 /// Implement constructor for struct Acifc
@@ -277,6 +281,12 @@ impl<'a> Acifc<'a> {
     pub const fn new() -> Acifc<'a> {
         Acifc {
             client: Cell::new(None),
+            modes: [
+                Cell::new(analog_comparator::InterruptMode::RisingEdge),
+                Cell::new(analog_comparator::InterruptMode::RisingEdge),
+                Cell::new(analog_comparator::InterruptMode::RisingEdge),
+                Cell::new(analog_comparator::InterruptMode::RisingEdge),
+            ],
         }
     }
 
@@ -326,6 +336,21 @@ impl<'a> Acifc<'a> {
         regs.ctrl.write(Control::EN::CLEAR);
     }
 
+    /// Notify the client of a crossing on comparator `ac` if its interrupt
+    /// mode asks for crossings in this direction.
+    fn crossed(&self, ac: usize, rising: bool) {
+        let report = match self.modes[ac].get() {
+            analog_comparator::InterruptMode::RisingEdge => rising,
+            analog_comparator::InterruptMode::FallingEdge => !rising,
+            analog_comparator::InterruptMode::Toggle => true,
+        };
+        if report {
+            self.client.get().map(|client| {
+                client.fired(ac);
+            });
+        }
+    }
+
     /// Handling of interrupts. Currently set up so that an interrupt fires
     /// only once when the condition is true (e.g. Vinp > Vinn), and then
     /// doesn't fire anymore until the condition is false (e.g. Vinp < Vinn).
@@ -345,17 +370,16 @@ impl<'a> Acifc<'a> {
             // to IER
             regs.idr.write(Interrupt::ACINT0::SET);
 
-            // If Vinp > Vinn, throw an interrupt to the client and set the AC so
+            // If Vinp > Vinn, report the rising crossing and set the AC so
             // that it will throw an interrupt when Vinn < Vinp instead.
             if !regs.conf[0].is_set(ACConfiguration::IS) {
-                self.client.get().map(|client| {
-                    client.fired(0);
-                });
+                self.crossed(0, true);
                 regs.conf[0].modify(ACConfiguration::IS::WhenVinpLtVinn);
             }
-            // If Vinp < Vinn, set the AC so that it will throw an interrupt when
-            // Vinp > Vinn instead.
+            // If Vinp < Vinn, report the falling crossing and set the AC so
+            // that it will throw an interrupt when Vinp > Vinn instead.
             else {
+                self.crossed(0, false);
                 regs.conf[0].modify(ACConfiguration::IS::WhenVinpGtVinn);
             }
 
@@ -372,17 +396,16 @@ impl<'a> Acifc<'a> {
             // to IER
             regs.idr.write(Interrupt::ACINT1::SET);
 
-            // If Vinp > Vinn, throw an interrupt to the client and set the AC so
+            // If Vinp > Vinn, report the rising crossing and set the AC so
             // that it will throw an interrupt when Vinn < Vinp instead.
             if !regs.conf[1].is_set(ACConfiguration::IS) {
-                self.client.get().map(|client| {
-                    client.fired(1);
-                });
+                self.crossed(1, true);
                 regs.conf[1].modify(ACConfiguration::IS::WhenVinpLtVinn);
             }
-            // If Vinp < Vinn, set the AC so that it will throw an interrupt when
-            // Vinp > Vinn instead.
+            // If Vinp < Vinn, report the falling crossing and set the AC so
+            // that it will throw an interrupt when Vinp > Vinn instead.
             else {
+                self.crossed(1, false);
                 regs.conf[1].modify(ACConfiguration::IS::WhenVinpGtVinn);
             }
 
@@ -399,17 +422,16 @@ impl<'a> Acifc<'a> {
             // to IER
             regs.idr.write(Interrupt::ACINT2::SET);
 
-            // If Vinp > Vinn, throw an interrupt to the client and set the AC so
+            // If Vinp > Vinn, report the rising crossing and set the AC so
             // that it will throw an interrupt when Vinn < Vinp instead.
             if !regs.conf[2].is_set(ACConfiguration::IS) {
-                self.client.get().map(|client| {
-                    client.fired(2);
-                });
+                self.crossed(2, true);
                 regs.conf[2].modify(ACConfiguration::IS::WhenVinpLtVinn);
             }
-            // If Vinp < Vinn, set the AC so that it will throw an interrupt when
-            // Vinp > Vinn instead.
+            // If Vinp < Vinn, report the falling crossing and set the AC so
+            // that it will throw an interrupt when Vinp > Vinn instead.
             else {
+                self.crossed(2, false);
                 regs.conf[2].modify(ACConfiguration::IS::WhenVinpGtVinn);
             }
 
@@ -426,23 +448,37 @@ impl<'a> Acifc<'a> {
             // to IER
             regs.idr.write(Interrupt::ACINT3::SET);
 
-            // If Vinp > Vinn, throw an interrupt to the client and set the AC so
+            // If Vinp > Vinn, report the rising crossing and set the AC so
             // that it will throw an interrupt when Vinn < Vinp instead.
             if !regs.conf[3].is_set(ACConfiguration::IS) {
-                self.client.get().map(|client| {
-                    client.fired(3);
-                });
+                self.crossed(3, true);
                 regs.conf[3].modify(ACConfiguration::IS::WhenVinpLtVinn);
             }
-            // If Vinp < Vinn, set the AC so that it will throw an interrupt when
-            // Vinp > Vinn instead.
+            // If Vinp < Vinn, report the falling crossing and set the AC so
+            // that it will throw an interrupt when Vinp > Vinn instead.
             else {
+                self.crossed(3, false);
                 regs.conf[3].modify(ACConfiguration::IS::WhenVinpGtVinn);
             }
 
             // Clear the interrupt request
             regs.icr.write(Interrupt::ACINT3::SET);
             regs.ier.write(Interrupt::ACINT3::SET);
+        } else if regs.isr.is_set(Interrupt::WFINT0) {
+            // Window interrupts only fire on the selected event, so just clear
+            // the interrupt request and report the new window state
+            regs.icr.write(Interrupt::WFINT0::SET);
+            let inside = regs.sr.is_set(Status::WFCS0);
+            self.client.get().map(|client| {
+                client.window_fired(0, inside);
+            });
+        } else if regs.isr.is_set(Interrupt::WFINT1) {
+            // Repeat the same for window 1
+            regs.icr.write(Interrupt::WFINT1::SET);
+            let inside = regs.sr.is_set(Status::WFCS1);
+            self.client.get().map(|client| {
+                client.window_fired(1, inside);
+            });
         }
     }
 }
@@ -528,6 +564,88 @@ impl<'a> analog_comparator::AnalogComparator<'a> for Acifc<'a> {
         }
     }
 
+    /// Select the crossings reported for a channel
+    fn set_interrupt_mode(
+        &self,
+        channel: &Self::Channel,
+        mode: analog_comparator::InterruptMode,
+    ) -> Result<(), ErrorCode> {
+        match self.modes.get(channel.chan_num as usize) {
+            Some(m) => {
+                m.set(mode);
+                Ok(())
+            }
+            None => Err(ErrorCode::INVAL),
+        }
+    }
+
+    /// Do a single window comparison. Window x is formed by AC(2x) and
+    /// AC(2x+1), so only windows 0 and 1 are available.
+    fn window_comparison(&self, window: usize) -> Result<bool, ErrorCode> {
+        if window >= WINDOWS {
+            return Err(ErrorCode::INVAL);
+        }
+        self.enable();
+        let regs = ACIFC_BASE;
+
+        // The window status is only valid while window mode is enabled
+        let enabled = regs.confw[window].is_set(WindowConfiguration::WFEN);
+        regs.confw[window].modify(WindowConfiguration::WFEN::SET);
+        let result = if window == 0 {
+            regs.sr.is_set(Status::WFCS0)
+        } else {
+            regs.sr.is_set(Status::WFCS1)
+        };
+        if !enabled {
+            regs.confw[window].modify(WindowConfiguration::WFEN::CLEAR);
+        }
+        Ok(result)
+    }
+
+    /// Start interrupt-based window comparisons
+    fn start_window(
+        &self,
+        window: usize,
+        event: analog_comparator::WindowEvent,
+    ) -> Result<(), ErrorCode> {
+        if window >= WINDOWS {
+            return Err(ErrorCode::INVAL);
+        }
+        self.enable();
+        let regs = ACIFC_BASE;
+
+        let wis = match event {
+            analog_comparator::WindowEvent::Enter => WindowConfiguration::WIS::InterruptEnterWindow,
+            analog_comparator::WindowEvent::Leave => WindowConfiguration::WIS::InterruptLeaveWindow,
+            analog_comparator::WindowEvent::Toggle => {
+                WindowConfiguration::WIS::InterruptToggleAcwout
+            }
+        };
+        regs.confw[window].write(WindowConfiguration::WFEN::SET + wis);
+        if window == 0 {
+            regs.ier.write(Interrupt::WFINT0::SET);
+        } else {
+            regs.ier.write(Interrupt::WFINT1::SET);
+        }
+        Ok(())
+    }
+
+    /// Stop interrupt-based window comparisons
+    fn stop_window(&self, window: usize) -> Result<(), ErrorCode> {
+        if window >= WINDOWS {
+            return Err(ErrorCode::INVAL);
+        }
+        let regs = ACIFC_BASE;
+
+        if window == 0 {
+            regs.idr.write(Interrupt::WFINT0::SET);
+        } else {
+            regs.idr.write(Interrupt::WFINT1::SET);
+        }
+        regs.confw[window].write(WindowConfiguration::WFEN::CLEAR);
+        Ok(())
+    }
+
     fn set_client(&self, client: &'a dyn analog_comparator::Client) {
         self.client.set(Some(client));
     }
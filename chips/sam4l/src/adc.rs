@@ -88,6 +88,7 @@ pub struct Adc {
     // state tracking for the ADC
     enabled: Cell<bool>,
     adc_clk_freq: Cell<u32>,
+    reference: Cell<hil::adc::VoltageReference>,
     active: Cell<bool>,
     continuous: Cell<bool>,
     dma_running: Cell<bool>,
@@ -334,6 +335,7 @@ impl Adc {
             // status of the ADC peripheral
             enabled: Cell::new(false),
             adc_clk_freq: Cell::new(0),
+            reference: Cell::new(hil::adc::VoltageReference::Vdd),
             active: Cell::new(false),
             continuous: Cell::new(false),
             dma_running: Cell::new(false),
@@ -461,7 +463,12 @@ impl Adc {
             self.enabled.set(true);
 
             // configure the ADC max speed and reference select
-            let mut cfg_val = Configuration::SPEED::ksps300 + Configuration::REFSEL::VccX0p5;
+            let refsel = match self.reference.get() {
+                hil::adc::VoltageReference::Internal => Configuration::REFSEL::Internal1V,
+                hil::adc::VoltageReference::External => Configuration::REFSEL::ExternalRef1,
+                hil::adc::VoltageReference::Vdd => Configuration::REFSEL::VccX0p5,
+            };
+            let mut cfg_val = Configuration::SPEED::ksps300 + refsel;
 
             // First, enable the clocks
             // Both the ADCIFE clock and GCLK10 are needed,
@@ -806,10 +813,9 @@ impl hil::adc::Adc for Adc {
         12
     }
 
-    /// Voltage reference is VCC/2, we assume VCC is 3.3 V, and we use a gain
-    /// of 0.5.
+    /// Voltage reference is the selected one, we use a gain of 0.5.
     fn get_voltage_reference_mv(&self) -> Option<usize> {
-        Some(3300)
+        hil::adc::AdcReference::get_voltage_reference_uv(self).map(|uv| uv / 1000)
     }
 
     /// Sets the client for this driver.
@@ -820,6 +826,46 @@ impl hil::adc::Adc for Adc {
     }
 }
 
+/// Implements selecting the voltage reference of the ADC
+impl hil::adc::AdcReference for Adc {
+    /// Select the reference: the internal 1 V bandgap, the external reference
+    /// on ADCREFP, or VCC/2.
+    fn set_voltage_reference(
+        &self,
+        reference: hil::adc::VoltageReference,
+    ) -> Result<(), ErrorCode> {
+        if self.active.get() {
+            // disallow reconfiguration during sampling
+            return Err(ErrorCode::BUSY);
+        }
+        self.reference.set(reference);
+        // force the configuration to be written again on the next sample
+        self.adc_clk_freq.set(0);
+        Ok(())
+    }
+
+    fn get_voltage_reference(&self) -> hil::adc::VoltageReference {
+        self.reference.get()
+    }
+
+    /// With a gain of 0.5, full scale is twice the reference. We assume VCC
+    /// is 3.3 V.
+    fn get_voltage_reference_uv(&self) -> Option<usize> {
+        match self.reference.get() {
+            hil::adc::VoltageReference::Internal => Some(2_000_000),
+            hil::adc::VoltageReference::External => None,
+            hil::adc::VoltageReference::Vdd => Some(3_300_000),
+        }
+    }
+
+    /// The ADC is calibrated in the factory only.
+    fn calibrate(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn set_calibration_client(&self, _client: &'static dyn hil::adc::CalibrationClient) {}
+}
+
 /// Implements an ADC capable of continuous sampling
 impl hil::adc::AdcHighSpeed for Adc {
     /// Capture buffered samples from the ADC continuously at a given
@@ -976,6 +1022,37 @@ impl hil::adc::AdcHighSpeed for Adc {
     }
 }
 
+/// Samples sequences of a single channel, as the ADC samples one channel
+/// continuously.
+impl hil::adc::AdcStreaming for Adc {
+    fn max_sequence_length(&self) -> usize {
+        1
+    }
+
+    fn sample_sequence(
+        &self,
+        channels: &[&Self::Channel],
+        frequency: u32,
+        buffer1: &'static mut [u16],
+        length1: usize,
+        buffer2: &'static mut [u16],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])> {
+        if channels.len() != 1 {
+            return Err((ErrorCode::NOSUPPORT, buffer1, buffer2));
+        }
+        hil::adc::AdcHighSpeed::sample_highspeed(
+            self,
+            channels[0],
+            frequency,
+            buffer1,
+            length1,
+            buffer2,
+            length2,
+        )
+    }
+}
+
 /// Implements a client of a DMA.
 impl dma::DMAClient for Adc {
     /// Handler for DMA transfer completion.
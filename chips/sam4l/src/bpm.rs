@@ -173,6 +173,20 @@ pub unsafe fn set_ck32source(source: CK32Source) {
         .modify_no_read(control, PowerModeControl::CK32S.val(source as u32));
 }
 
+/// Select the RETENTION mode instead of WAIT for deep sleep. In RETENTION
+/// mode the core voltage is lowered and only the 32 kHz clocks keep running,
+/// so the chip still wakes up on the AST, the EIC and TWIS address matches.
+pub unsafe fn set_retention(retention: bool) {
+    let control = BPM.pmcon.extract();
+    let ret = if retention {
+        PowerModeControl::RET::PowerSave
+    } else {
+        PowerModeControl::RET::NoPowerSave
+    };
+    unlock_register(0x1c); // Control
+    BPM.pmcon.modify_no_read(control, ret);
+}
+
 unsafe fn unlock_register(register_offset: u32) {
     BPM.unlock
         .write(Unlock::KEY.val(BPM_UNLOCK_KEY) + Unlock::ADDR.val(register_offset));
@@ -1,5 +1,6 @@
 //! Interrupt mapping and DMA channel setup.
 
+use crate::bpm;
 use crate::deferred_call_tasks::Task;
 use crate::pm;
 
@@ -300,6 +301,19 @@ impl<I: InterruptService<Task> + 'static> Chip for Sam4l<I> {
         }
     }
 
+    fn deep_sleep(&self) {
+        if !pm::deep_sleep_ready() {
+            self.sleep();
+            return;
+        }
+        unsafe {
+            bpm::set_retention(true);
+            cortexm4::scb::set_sleepdeep();
+            cortexm4::support::wfi();
+            bpm::set_retention(false);
+        }
+    }
+
     unsafe fn atomic<F, R>(&self, f: F) -> R
     where
         F: FnOnce() -> R,
@@ -604,6 +604,8 @@ impl spi::SpiMaster for SpiHw {
         self.get_phase()
     }
 
+    // The chip select is driven by the peripheral, which only asserts
+    // it when a transfer starts.
     fn hold_low(&self) {
         let spi = &SpiRegisterManager::new(&self);
         let csr = self.get_active_csr(spi);
@@ -614,6 +616,10 @@ impl spi::SpiMaster for SpiHw {
         let spi = &SpiRegisterManager::new(&self);
         let csr = self.get_active_csr(spi);
         csr.modify(ChipSelectParams::CSAAT::InactiveAfterTransfer);
+        if self.transfers_in_progress.get() == 0 {
+            // Raise a chip select left active by the last transfer.
+            spi.registers.cr.write(Control::LASTXFER::SET);
+        }
     }
 
     fn specify_chip_select(&self, cs: Self::ChipSelect) {
@@ -394,6 +394,7 @@ pub struct USART<'a> {
     client: OptionalCell<UsartClient<'a>>,
 
     spi_chip_select: OptionalCell<&'a dyn hil::gpio::Pin>,
+    spi_hold_cs: Cell<bool>,
     pm: &'a pm::PowerManager,
 }
 
@@ -427,6 +428,7 @@ impl<'a> USART<'a> {
 
             // This is only used if the USART is in SPI mode.
             spi_chip_select: OptionalCell::empty(),
+            spi_hold_cs: Cell::new(false),
             pm,
         }
     }
@@ -659,16 +661,11 @@ impl<'a> USART<'a> {
                         // For the SPI case it is a little more complicated.
 
                         // First, it is now a valid time to de-assert the CS
-                        // line because we know the write and/or read is done.
-                        self.spi_chip_select.map_or_else(
-                            || {
-                                // Do "else" case first. Thanks, rust.
-                                self.rts_disable_spi_deassert_cs(usart);
-                            },
-                            |cs| {
-                                cs.set();
-                            },
-                        );
+                        // line because we know the write and/or read is done,
+                        // unless the client asked for it to be held.
+                        if !self.spi_hold_cs.get() {
+                            self.spi_deassert_cs(usart);
+                        }
 
                         // Get the RX buffer, and it is ok if we didn't use one,
                         // we can just return None.
@@ -734,6 +731,33 @@ impl<'a> USART<'a> {
         usart.registers.cr.write(Control::RTSDIS::SET);
     }
 
+    /// In SPI mode, asserts the chip select line. If no CS pin was
+    /// provided, the HW RTS pin is used as the CS line instead.
+    fn spi_assert_cs(&self, usart: &USARTRegManager) {
+        self.spi_chip_select.map_or_else(
+            || {
+                // Do the "else" case first.
+                self.rts_enable_spi_assert_cs(usart);
+            },
+            |cs| {
+                cs.clear();
+            },
+        );
+    }
+
+    /// In SPI mode, de-asserts the chip select line.
+    fn spi_deassert_cs(&self, usart: &USARTRegManager) {
+        self.spi_chip_select.map_or_else(
+            || {
+                // Do the "else" case first.
+                self.rts_disable_spi_deassert_cs(usart);
+            },
+            |cs| {
+                cs.set();
+            },
+        );
+    }
+
     fn enable_rx_timeout(&self, usart: &USARTRegManager, timeout: u8) {
         usart
             .registers
@@ -1064,16 +1088,7 @@ impl spi::SpiMaster for USART<'_> {
         self.tx_len.set(count);
 
         // Set !CS low
-        self.spi_chip_select.map_or_else(
-            || {
-                // Do the "else" case first. If a CS pin was provided as the
-                // CS line, we use the HW RTS pin as the CS line instead.
-                self.rts_enable_spi_assert_cs(usart);
-            },
-            |cs| {
-                cs.clear();
-            },
-        );
+        self.spi_assert_cs(usart);
 
         // Check if we should read and write or just write.
         if read_buffer.is_some() {
@@ -1223,11 +1238,22 @@ impl spi::SpiMaster for USART<'_> {
     // allow an application to manually control when the
     // CS line is high or low, such that it can issue multi-byte
     // requests with single byte operations.
+    //
+    // Controllers that can drive the chip select outside of a
+    // transfer also bring it low as soon as hold_low() is called,
+    // and bring a held chip select high as soon as release_low()
+    // is called if no transfer is in progress.
     fn hold_low(&self) {
-        unimplemented!("USART: SPI: Use `read_write_bytes()` instead.");
+        let usart = &USARTRegManager::new(&self);
+        self.spi_hold_cs.set(true);
+        self.spi_assert_cs(usart);
     }
 
     fn release_low(&self) {
-        unimplemented!("USART: SPI: Use `read_write_bytes()` instead.");
+        let usart = &USARTRegManager::new(&self);
+        self.spi_hold_cs.set(false);
+        if self.usart_tx_state.get() != USARTStateTX::DMA_Transmitting {
+            self.spi_deassert_cs(usart);
+        }
     }
 }
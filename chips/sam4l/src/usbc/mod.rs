@@ -684,7 +684,11 @@ impl<'a> Usbc<'a> {
         if config.matches_all(EndpointConfig::EPTYPE::Control) {
             endpoint_enable_interrupts(endpoint, EndpointControl::RXSTPE::SET);
             state.endpoint_states[endpoint] = EndpointState::Ctrl(CtrlState::Init);
-        } else if config.matches_all(EndpointConfig::EPTYPE::Bulk + EndpointConfig::EPDIR::In) {
+        } else if config.matches_all(EndpointConfig::EPTYPE::Bulk + EndpointConfig::EPDIR::In)
+            || config.matches_all(EndpointConfig::EPTYPE::Interrupt + EndpointConfig::EPDIR::In)
+        {
+            // Interrupt IN endpoints only differ from bulk ones in how often
+            // the host polls them.
             endpoint_enable_interrupts(endpoint, EndpointControl::TXINE::SET);
             state.endpoint_states[endpoint] = EndpointState::BulkIn(BulkInState::Init);
         } else if config.matches_all(EndpointConfig::EPTYPE::Bulk + EndpointConfig::EPDIR::Out) {
@@ -1323,9 +1327,16 @@ impl<'a> Usbc<'a> {
                     }
                     // A bank is free to write an IN packet
 
+                    let transfer_type = if usbc_regs().uecfg[endpoint]
+                        .matches_all(EndpointConfig::EPTYPE::Interrupt)
+                    {
+                        TransferType::Interrupt
+                    } else {
+                        TransferType::Bulk
+                    };
                     let result = self.client.map(|c| {
                         // Allow client to write a packet payload to the buffer
-                        c.packet_in(TransferType::Bulk, endpoint)
+                        c.packet_in(transfer_type, endpoint)
                     });
                     match result {
                         Some(hil::usb::InResult::Packet(packet_bytes)) => {
@@ -1530,7 +1541,13 @@ impl<'a> hil::usb::UsbController<'a> for Usbc<'a> {
                     + EndpointConfig::EPSIZE::Bytes8
                     + EndpointConfig::EPBK::Single,
             )),
-            TransferType::Interrupt | TransferType::Isochronous => unimplemented!(),
+            TransferType::Interrupt => LocalRegisterCopy::new(From::from(
+                EndpointConfig::EPTYPE::Interrupt
+                    + EndpointConfig::EPDIR::In
+                    + EndpointConfig::EPSIZE::Bytes8
+                    + EndpointConfig::EPBK::Single,
+            )),
+            TransferType::Isochronous => unimplemented!(),
         };
 
         self._endpoint_enable(endpoint, endpoint_cfg)
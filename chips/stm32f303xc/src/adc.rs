@@ -774,3 +774,51 @@ impl hil::adc::AdcHighSpeed for Adc<'_> {
         Err(ErrorCode::NOSUPPORT)
     }
 }
+
+/// Not yet supported
+impl hil::adc::AdcStreaming for Adc<'_> {
+    fn max_sequence_length(&self) -> usize {
+        1
+    }
+
+    fn sample_sequence(
+        &self,
+        _channels: &[&Self::Channel],
+        _frequency: u32,
+        buffer1: &'static mut [u16],
+        _length1: usize,
+        buffer2: &'static mut [u16],
+        _length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])> {
+        Err((ErrorCode::NOSUPPORT, buffer1, buffer2))
+    }
+}
+
+/// Samples are taken against VREF+, which is tied to VDDA, assumed to be
+/// 3.3 V.
+impl hil::adc::AdcReference for Adc<'_> {
+    fn set_voltage_reference(
+        &self,
+        reference: hil::adc::VoltageReference,
+    ) -> Result<(), ErrorCode> {
+        match reference {
+            hil::adc::VoltageReference::Vdd => Ok(()),
+            _ => Err(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn get_voltage_reference(&self) -> hil::adc::VoltageReference {
+        hil::adc::VoltageReference::Vdd
+    }
+
+    fn get_voltage_reference_uv(&self) -> Option<usize> {
+        Some(3_300_000)
+    }
+
+    /// The ADC calibrates itself whenever it is enabled.
+    fn calibrate(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn set_calibration_client(&self, _client: &'static dyn hil::adc::CalibrationClient) {}
+}
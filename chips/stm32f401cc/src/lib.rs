@@ -2,7 +2,9 @@
 
 use cortexm4::{generic_isr, unhandled_interrupt};
 
-pub use stm32f4xx::{adc, chip, dbg, dma1, exti, gpio, nvic, rcc, spi, syscfg, tim2, usart};
+pub use stm32f4xx::{
+    adc, chip, dbg, dma1, exti, gpio, nvic, rcc, spi, syscfg, tim2, tim3, tim5, usart,
+};
 
 pub mod interrupt_service;
 
@@ -6,6 +6,7 @@ pub struct Stm32f412gDefaultPeripherals<'a> {
     pub stm32f4: Stm32f4xxDefaultPeripherals<'a>,
     // Once implemented, place Stm32f412g specific peripherals here
     pub trng: stm32f4xx::trng::Trng<'a>,
+    pub quadspi: stm32f4xx::quadspi::Quadspi<'a>,
 }
 
 impl<'a> Stm32f412gDefaultPeripherals<'a> {
@@ -17,6 +18,7 @@ impl<'a> Stm32f412gDefaultPeripherals<'a> {
         Self {
             stm32f4: Stm32f4xxDefaultPeripherals::new(rcc, exti, dma),
             trng: stm32f4xx::trng::Trng::new(rcc),
+            quadspi: stm32f4xx::quadspi::Quadspi::new(rcc),
         }
     }
     // Necessary for setting up circular dependencies
@@ -32,6 +34,10 @@ impl<'a> kernel::InterruptService<DeferredCallTask> for Stm32f412gDefaultPeriphe
                 self.trng.handle_interrupt();
                 true
             }
+            stm32f412g_nvic::SQPI => {
+                self.quadspi.handle_interrupt();
+                true
+            }
             _ => self.stm32f4.service_interrupt(interrupt),
         }
     }
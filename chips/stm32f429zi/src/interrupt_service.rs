@@ -3,7 +3,8 @@ use stm32f4xx::deferred_calls::DeferredCallTask;
 
 pub struct Stm32f429ziDefaultPeripherals<'a> {
     pub stm32f4: Stm32f4xxDefaultPeripherals<'a>,
-    // Once implemented, place Stm32f429zi specific peripherals here
+    pub ethernet: stm32f4xx::ethernet::Ethernet<'a>,
+    pub can1: stm32f4xx::can::Can<'a>,
 }
 
 impl<'a> Stm32f429ziDefaultPeripherals<'a> {
@@ -11,9 +12,12 @@ impl<'a> Stm32f429ziDefaultPeripherals<'a> {
         rcc: &'a crate::rcc::Rcc,
         exti: &'a crate::exti::Exti<'a>,
         dma: &'a crate::dma1::Dma1<'a>,
+        syscfg: &'a crate::syscfg::Syscfg<'a>,
     ) -> Self {
         Self {
             stm32f4: Stm32f4xxDefaultPeripherals::new(rcc, exti, dma),
+            ethernet: stm32f4xx::ethernet::Ethernet::new(rcc, syscfg),
+            can1: stm32f4xx::can::Can::new(rcc),
         }
     }
     // Necessary for setting up circular dependencies
@@ -24,7 +28,22 @@ impl<'a> Stm32f429ziDefaultPeripherals<'a> {
 impl<'a> kernel::InterruptService<DeferredCallTask> for Stm32f429ziDefaultPeripherals<'a> {
     unsafe fn service_interrupt(&self, interrupt: u32) -> bool {
         match interrupt {
-            // put Stm32f429zi specific interrupts here
+            stm32f4xx::nvic::ETH => {
+                self.ethernet.handle_interrupt();
+                true
+            }
+            stm32f4xx::nvic::CAN1_TX => {
+                self.can1.handle_transmit_interrupt();
+                true
+            }
+            stm32f4xx::nvic::CAN1_RX0 => {
+                self.can1.handle_receive_interrupt();
+                true
+            }
+            stm32f4xx::nvic::CAN1_SCE => {
+                self.can1.handle_status_change_interrupt();
+                true
+            }
             _ => self.stm32f4.service_interrupt(interrupt),
         }
     }
@@ -2,7 +2,10 @@
 
 use cortexm4::generic_isr;
 
-pub use stm32f4xx::{adc, chip, dbg, dma1, exti, gpio, nvic, rcc, spi, syscfg, tim2, usart};
+pub use stm32f4xx::{
+    adc, can, chip, dbg, dma1, ethernet, exti, gpio, nvic, rcc, spi, syscfg, tim2, tim3, tim5,
+    usart,
+};
 
 pub mod interrupt_service;
 pub mod stm32f429zi_nvic;
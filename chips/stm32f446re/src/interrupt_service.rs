@@ -4,6 +4,7 @@ use stm32f4xx::deferred_calls::DeferredCallTask;
 pub struct Stm32f446reDefaultPeripherals<'a> {
     pub stm32f4: Stm32f4xxDefaultPeripherals<'a>,
     // Once implemented, place Stm32f446re specific peripherals here
+    pub can1: stm32f4xx::can::Can<'a>,
 }
 
 impl<'a> Stm32f446reDefaultPeripherals<'a> {
@@ -14,6 +15,7 @@ impl<'a> Stm32f446reDefaultPeripherals<'a> {
     ) -> Self {
         Self {
             stm32f4: Stm32f4xxDefaultPeripherals::new(rcc, exti, dma),
+            can1: stm32f4xx::can::Can::new(rcc),
         }
     }
     // Necessary for setting up circular dependencies
@@ -25,6 +27,18 @@ impl<'a> kernel::InterruptService<DeferredCallTask> for Stm32f446reDefaultPeriph
     unsafe fn service_interrupt(&self, interrupt: u32) -> bool {
         match interrupt {
             // put Stm32f446re specific interrupts here
+            stm32f4xx::nvic::CAN1_TX => {
+                self.can1.handle_transmit_interrupt();
+                true
+            }
+            stm32f4xx::nvic::CAN1_RX0 => {
+                self.can1.handle_receive_interrupt();
+                true
+            }
+            stm32f4xx::nvic::CAN1_SCE => {
+                self.can1.handle_status_change_interrupt();
+                true
+            }
             _ => self.stm32f4.service_interrupt(interrupt),
         }
     }
@@ -1,6 +1,8 @@
 #![no_std]
 
-pub use stm32f4xx::{chip, dbg, dma1, exti, gpio, nvic, rcc, spi, syscfg, tim2, usart};
+pub use stm32f4xx::{
+    can, chip, dbg, dma1, exti, gpio, nvic, rcc, spi, syscfg, tim2, tim3, tim5, usart,
+};
 
 pub mod interrupt_service;
 pub mod stm32f446re_nvic;
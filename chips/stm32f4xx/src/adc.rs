@@ -5,6 +5,7 @@ use kernel::common::registers::interfaces::{ReadWriteable, Readable};
 use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite};
 use kernel::common::StaticRef;
 use kernel::hil;
+use kernel::hil::power::{PowerControl, RefCountedClock};
 use kernel::ClockInterface;
 use kernel::ErrorCode;
 
@@ -307,7 +308,7 @@ enum ADCStatus {
 pub struct Adc<'a> {
     registers: StaticRef<AdcRegisters>,
     common_registers: StaticRef<AdcCommonRegisters>,
-    clock: AdcClock<'a>,
+    clock: RefCountedClock<AdcClock<'a>>,
     status: Cell<ADCStatus>,
     client: OptionalCell<&'static dyn hil::adc::Client>,
 }
@@ -317,50 +318,62 @@ impl<'a> Adc<'a> {
         Adc {
             registers: ADC1_BASE,
             common_registers: ADC_COMMON_BASE,
-            clock: AdcClock(rcc::PeripheralClock::new(
+            clock: RefCountedClock::new(AdcClock(rcc::PeripheralClock::new(
                 rcc::PeripheralClockType::APB2(rcc::PCLK2::ADC1),
                 rcc,
-            )),
+            ))),
             status: Cell::new(ADCStatus::Off),
             client: OptionalCell::empty(),
         }
     }
 
-    pub fn enable(&self) {
-        // Enable adc clock
-        self.enable_clock();
+    /// Request the clock and power up the ADC, for one conversion.
+    fn enable(&self) {
+        self.clock.request();
 
         // Enable ADC
         self.registers.cr2.modify(CR2::ADON::SET);
+        // Wait for the ADC to stabilize, 3 µs at up to 180 MHz.
+        for _ in 0..540 {
+            cortexm4::support::nop();
+        }
 
         // set idle state
         self.status.set(ADCStatus::Idle);
     }
 
+    /// Power down the ADC and release the clock requested by `enable()`.
+    fn disable(&self) {
+        self.registers.cr2.modify(CR2::ADON::CLEAR);
+        self.status.set(ADCStatus::Off);
+        self.clock.release();
+    }
+
     pub fn handle_interrupt(&self) {
         // Check if regular group conversion ended
         if self.registers.sr.is_set(SR::EOC) {
             // Clear interrupt
             self.registers.cr1.modify(CR1::EOCIE::CLEAR);
+            let sample = self.registers.dr.read(DR::DATA) as u16;
             if self.status.get() == ADCStatus::OneSample {
-                // set state
-                self.status.set(ADCStatus::Idle);
+                self.disable();
             }
-            self.client
-                .map(|client| client.sample_ready(self.registers.dr.read(DR::DATA) as u16));
+            self.client.map(|client| client.sample_ready(sample));
         }
     }
 
     pub fn is_enabled_clock(&self) -> bool {
-        self.clock.is_enabled()
+        self.clock.clock().is_enabled()
     }
 
+    /// Keep the ADC clock on, even while no conversion is in progress.
     pub fn enable_clock(&self) {
-        self.clock.enable();
+        self.clock.request();
     }
 
+    /// Undo `enable_clock()`.
     pub fn disable_clock(&self) {
-        self.clock.disable();
+        self.clock.release();
     }
 
     pub fn enable_temperature(&self) {
@@ -388,22 +401,19 @@ impl hil::adc::Adc for Adc<'_> {
     type Channel = Channel;
 
     fn sample(&self, channel: &Self::Channel) -> Result<(), ErrorCode> {
-        if self.status.get() == ADCStatus::Off {
-            self.enable();
+        if self.status.get() == ADCStatus::OneSample {
+            return Err(ErrorCode::BUSY);
         }
+        self.enable();
         if *channel as u32 == 18 {
             self.enable_temperature();
         }
-        if self.status.get() == ADCStatus::Idle {
-            self.status.set(ADCStatus::OneSample);
-            self.registers.sqr1.modify(SQR1::L.val(0b0000));
-            self.registers.sqr3.modify(SQR3::SQ1.val(*channel as u32));
-            self.registers.cr1.modify(CR1::EOCIE::SET);
-            self.registers.cr2.modify(CR2::SWSTART::SET);
-            Ok(())
-        } else {
-            Err(ErrorCode::BUSY)
-        }
+        self.status.set(ADCStatus::OneSample);
+        self.registers.sqr1.modify(SQR1::L.val(0b0000));
+        self.registers.sqr3.modify(SQR3::SQ1.val(*channel as u32));
+        self.registers.cr1.modify(CR1::EOCIE::SET);
+        self.registers.cr2.modify(CR2::SWSTART::SET);
+        Ok(())
     }
 
     fn sample_continuous(
@@ -479,3 +489,50 @@ impl hil::adc::AdcHighSpeed for Adc<'_> {
         Err(ErrorCode::NOSUPPORT)
     }
 }
+
+/// Not yet supported
+impl hil::adc::AdcStreaming for Adc<'_> {
+    fn max_sequence_length(&self) -> usize {
+        1
+    }
+
+    fn sample_sequence(
+        &self,
+        _channels: &[&Self::Channel],
+        _frequency: u32,
+        buffer1: &'static mut [u16],
+        _length1: usize,
+        buffer2: &'static mut [u16],
+        _length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])> {
+        Err((ErrorCode::NOSUPPORT, buffer1, buffer2))
+    }
+}
+
+/// Samples are taken against VREF+, which is tied to VDDA, assumed to be
+/// 3.3 V.
+impl hil::adc::AdcReference for Adc<'_> {
+    fn set_voltage_reference(
+        &self,
+        reference: hil::adc::VoltageReference,
+    ) -> Result<(), ErrorCode> {
+        match reference {
+            hil::adc::VoltageReference::Vdd => Ok(()),
+            _ => Err(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn get_voltage_reference(&self) -> hil::adc::VoltageReference {
+        hil::adc::VoltageReference::Vdd
+    }
+
+    fn get_voltage_reference_uv(&self) -> Option<usize> {
+        Some(3_300_000)
+    }
+
+    fn calibrate(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn set_calibration_client(&self, _client: &'static dyn hil::adc::CalibrationClient) {}
+}
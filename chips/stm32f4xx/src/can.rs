@@ -0,0 +1,559 @@
+//! bxCAN driver for CAN1 of the STM32F4 chips that have one, such as the
+//! STM32F429 and STM32F446.
+//!
+//! Frames are sent one at a time from transmit mailbox 0, and received
+//! frames are read from receive FIFO 0, into which every filter bank of
+//! CAN1 is assigned. Each acceptance filter of the HIL is a filter bank in
+//! 32-bit mask mode. CAN2 shares its filter banks with CAN1, and is not
+//! supported.
+//!
+//! The controller does not recover from bus-off on its own: it stays off
+//! the bus until it is enabled again.
+//!
+//! The bit timing is derived from the APB1 clock, the 16 MHz HSI. The board
+//! configures the RX and TX pins for their alternate function, and connects
+//! them to a transceiver.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let can1 = &peripherals.can1;
+//! can1.set_controller_client(can_driver);
+//! can1.set_transmit_client(can_driver);
+//! can1.set_receive_client(can_driver);
+//! can_driver.enable(500_000);
+//! ```
+
+use crate::rcc;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::registers::interfaces::{ReadWriteable, Readable, Writeable};
+use kernel::common::registers::{register_bitfields, ReadWrite};
+use kernel::common::StaticRef;
+use kernel::hil::can::{self, Filter, Id};
+use kernel::ClockInterface;
+use kernel::ErrorCode;
+
+const CAN1_BASE: StaticRef<CanRegisters> =
+    unsafe { StaticRef::new(0x4000_6400 as *const CanRegisters) };
+
+/// Frequency of the APB1 clock of the peripheral, the 16 MHz HSI.
+const PCLK1_FREQUENCY: u32 = 16_000_000;
+
+/// Number of filter banks of CAN1, with the banks split evenly with CAN2
+/// as they are after reset.
+const FILTER_COUNT: usize = 14;
+
+/// Iterations to wait for the controller to enter initialization mode
+/// before giving up.
+const TIMEOUT: usize = 100_000;
+
+#[repr(C)]
+struct TxMailbox {
+    /// CAN TX mailbox identifier register
+    tir: ReadWrite<u32, MIR::Register>,
+    /// CAN mailbox data length control and time stamp register
+    tdtr: ReadWrite<u32, MDTR::Register>,
+    /// CAN mailbox data low register
+    tdlr: ReadWrite<u32>,
+    /// CAN mailbox data high register
+    tdhr: ReadWrite<u32>,
+}
+
+#[repr(C)]
+struct RxMailbox {
+    /// CAN receive FIFO mailbox identifier register
+    rir: ReadWrite<u32, MIR::Register>,
+    /// CAN receive FIFO mailbox data length control and time stamp register
+    rdtr: ReadWrite<u32, MDTR::Register>,
+    /// CAN receive FIFO mailbox data low register
+    rdlr: ReadWrite<u32>,
+    /// CAN receive FIFO mailbox data high register
+    rdhr: ReadWrite<u32>,
+}
+
+#[repr(C)]
+struct FilterBank {
+    /// Filter bank register 1, the identifier in mask mode
+    fr1: ReadWrite<u32>,
+    /// Filter bank register 2, the mask in mask mode
+    fr2: ReadWrite<u32>,
+}
+
+#[repr(C)]
+struct CanRegisters {
+    /// CAN master control register
+    mcr: ReadWrite<u32, MCR::Register>,
+    /// CAN master status register
+    msr: ReadWrite<u32, MSR::Register>,
+    /// CAN transmit status register
+    tsr: ReadWrite<u32, TSR::Register>,
+    /// CAN receive FIFO 0 register
+    rf0r: ReadWrite<u32, RFR::Register>,
+    /// CAN receive FIFO 1 register
+    rf1r: ReadWrite<u32, RFR::Register>,
+    /// CAN interrupt enable register
+    ier: ReadWrite<u32, IER::Register>,
+    /// CAN error status register
+    esr: ReadWrite<u32, ESR::Register>,
+    /// CAN bit timing register
+    btr: ReadWrite<u32, BTR::Register>,
+    _reserved0: [u32; 88],
+    tx: [TxMailbox; 3],
+    rx: [RxMailbox; 2],
+    _reserved1: [u32; 12],
+    /// CAN filter master register
+    fmr: ReadWrite<u32, FMR::Register>,
+    /// CAN filter mode register
+    fm1r: ReadWrite<u32>,
+    _reserved2: u32,
+    /// CAN filter scale register
+    fs1r: ReadWrite<u32>,
+    _reserved3: u32,
+    /// CAN filter FIFO assignment register
+    ffa1r: ReadWrite<u32>,
+    _reserved4: u32,
+    /// CAN filter activation register
+    fa1r: ReadWrite<u32>,
+    _reserved5: [u32; 8],
+    filters: [FilterBank; 28],
+}
+
+register_bitfields![u32,
+    MCR [
+        /// Debug freeze
+        DBF OFFSET(16) NUMBITS(1) [],
+        /// bxCAN software master reset
+        RESET OFFSET(15) NUMBITS(1) [],
+        /// Automatic bus-off management
+        ABOM OFFSET(6) NUMBITS(1) [],
+        /// No automatic retransmission
+        NART OFFSET(4) NUMBITS(1) [],
+        /// Receive FIFO locked mode
+        RFLM OFFSET(3) NUMBITS(1) [],
+        /// Sleep mode request
+        SLEEP OFFSET(1) NUMBITS(1) [],
+        /// Initialization request
+        INRQ OFFSET(0) NUMBITS(1) []
+    ],
+    MSR [
+        /// Error interrupt
+        ERRI OFFSET(2) NUMBITS(1) [],
+        /// Sleep acknowledge
+        SLAK OFFSET(1) NUMBITS(1) [],
+        /// Initialization acknowledge
+        INAK OFFSET(0) NUMBITS(1) []
+    ],
+    TSR [
+        /// Abort request for mailbox 0
+        ABRQ0 OFFSET(7) NUMBITS(1) [],
+        /// Transmission error of mailbox 0
+        TERR0 OFFSET(3) NUMBITS(1) [],
+        /// Arbitration lost for mailbox 0
+        ALST0 OFFSET(2) NUMBITS(1) [],
+        /// Transmission OK of mailbox 0
+        TXOK0 OFFSET(1) NUMBITS(1) [],
+        /// Request completed mailbox 0
+        RQCP0 OFFSET(0) NUMBITS(1) []
+    ],
+    RFR [
+        /// Release FIFO output mailbox
+        RFOM OFFSET(5) NUMBITS(1) [],
+        /// FIFO overrun
+        FOVR OFFSET(4) NUMBITS(1) [],
+        /// FIFO full
+        FULL OFFSET(3) NUMBITS(1) [],
+        /// FIFO message pending
+        FMP OFFSET(0) NUMBITS(2) []
+    ],
+    IER [
+        /// Error interrupt enable
+        ERRIE OFFSET(15) NUMBITS(1) [],
+        /// Bus-off interrupt enable
+        BOFIE OFFSET(10) NUMBITS(1) [],
+        /// FIFO message pending interrupt enable
+        FMPIE0 OFFSET(1) NUMBITS(1) [],
+        /// Transmit mailbox empty interrupt enable
+        TMEIE OFFSET(0) NUMBITS(1) []
+    ],
+    ESR [
+        /// Receive error counter
+        REC OFFSET(24) NUMBITS(8) [],
+        /// Least significant byte of the 9-bit transmit error counter
+        TEC OFFSET(16) NUMBITS(8) [],
+        /// Last error code
+        LEC OFFSET(4) NUMBITS(3) [],
+        /// Bus-off flag
+        BOFF OFFSET(2) NUMBITS(1) [],
+        /// Error passive flag
+        EPVF OFFSET(1) NUMBITS(1) [],
+        /// Error warning flag
+        EWGF OFFSET(0) NUMBITS(1) []
+    ],
+    BTR [
+        /// Silent mode (debug)
+        SILM OFFSET(31) NUMBITS(1) [],
+        /// Loop back mode (debug)
+        LBKM OFFSET(30) NUMBITS(1) [],
+        /// Resynchronization jump width
+        SJW OFFSET(24) NUMBITS(2) [],
+        /// Time segment 2
+        TS2 OFFSET(20) NUMBITS(3) [],
+        /// Time segment 1
+        TS1 OFFSET(16) NUMBITS(4) [],
+        /// Baud rate prescaler
+        BRP OFFSET(0) NUMBITS(10) []
+    ],
+    /// Identifier register of the transmit and receive mailboxes, laid out
+    /// as the filter bank registers in 32-bit scale.
+    MIR [
+        /// Standard identifier, or the high bits of an extended identifier
+        STID OFFSET(21) NUMBITS(11) [],
+        /// Low bits of an extended identifier
+        EXID OFFSET(3) NUMBITS(18) [],
+        /// Identifier extension
+        IDE OFFSET(2) NUMBITS(1) [],
+        /// Remote transmission request
+        RTR OFFSET(1) NUMBITS(1) [],
+        /// Transmit mailbox request
+        TXRQ OFFSET(0) NUMBITS(1) []
+    ],
+    MDTR [
+        /// Message time stamp
+        TIME OFFSET(16) NUMBITS(16) [],
+        /// Filter match index
+        FMI OFFSET(8) NUMBITS(8) [],
+        /// Data length code
+        DLC OFFSET(0) NUMBITS(4) []
+    ],
+    FMR [
+        /// CAN2 start bank
+        CAN2SB OFFSET(8) NUMBITS(6) [],
+        /// Filter initialization mode
+        FINIT OFFSET(0) NUMBITS(1) []
+    ]
+];
+
+/// Split a bit of `bitrate` into time quanta of the clock: the
+/// synchronization quantum, and the two time segments around the sample
+/// point, which is placed at 87.5% of the bit. Returns the prescaler and the
+/// lengths of the segments, or `None` if the bitrate cannot be reached
+/// exactly.
+fn bit_timing(clock: u32, bitrate: u32) -> Option<(u32, u32, u32)> {
+    (8..=25u32).rev().find_map(|quanta| {
+        let ticks = bitrate.checked_mul(quanta)?;
+        if ticks == 0 || clock % ticks != 0 {
+            return None;
+        }
+        let prescaler = clock / ticks;
+        let ts2 = cmp::max(1, (quanta + 4) / 8);
+        let ts1 = quanta - 1 - ts2;
+        if prescaler <= 1024 && ts1 <= 16 && ts2 <= 8 {
+            Some((prescaler, ts1, ts2))
+        } else {
+            None
+        }
+    })
+}
+
+/// The value of an identifier register, and of the mask matching it.
+fn identifier_register(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => (id as u32) << 21,
+        Id::Extended(id) => id << 3 | MIR::IDE::SET.value,
+    }
+}
+
+pub struct Can<'a> {
+    registers: StaticRef<CanRegisters>,
+    clock: CanClock<'a>,
+    controller_client: OptionalCell<&'a dyn can::ControllerClient>,
+    transmit_client: OptionalCell<&'a dyn can::TransmitClient>,
+    receive_client: OptionalCell<&'a dyn can::ReceiveClient>,
+    enabled: Cell<bool>,
+    filters: [Cell<Option<Filter>>; FILTER_COUNT],
+    tx_buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a> Can<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc) -> Can<'a> {
+        Can {
+            registers: CAN1_BASE,
+            clock: CanClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::APB1(rcc::PCLK1::CAN1),
+                rcc,
+            )),
+            controller_client: OptionalCell::empty(),
+            transmit_client: OptionalCell::empty(),
+            receive_client: OptionalCell::empty(),
+            enabled: Cell::new(false),
+            filters: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            tx_buffer: TakeCell::empty(),
+        }
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled()
+    }
+
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+    }
+
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+    }
+
+    fn enter_init_mode(&self) -> Result<(), ErrorCode> {
+        self.registers
+            .mcr
+            .modify(MCR::SLEEP::CLEAR + MCR::INRQ::SET);
+        for _ in 0..TIMEOUT {
+            if self.registers.msr.is_set(MSR::INAK) {
+                return Ok(());
+            }
+        }
+        Err(ErrorCode::FAIL)
+    }
+
+    /// Write filter bank `index` from the filter stored for it.
+    fn apply_filter(&self, index: usize) {
+        let regs = &*self.registers;
+        let bit = 1 << index;
+        regs.fmr.modify(FMR::FINIT::SET);
+        regs.fa1r.set(regs.fa1r.get() & !bit);
+        if let Some(filter) = self.filters[index].get() {
+            // A mask of the kind of the filter, so that frames of the other
+            // kind never match.
+            let mask = match filter.id {
+                Id::Standard(_) => Id::Standard((filter.mask & can::STANDARD_ID_MAX) as u16),
+                Id::Extended(_) => Id::Extended(filter.mask & can::EXTENDED_ID_MAX),
+            };
+            regs.fm1r.set(regs.fm1r.get() & !bit);
+            regs.fs1r.set(regs.fs1r.get() | bit);
+            regs.ffa1r.set(regs.ffa1r.get() & !bit);
+            regs.filters[index].fr1.set(identifier_register(filter.id));
+            regs.filters[index]
+                .fr2
+                .set(identifier_register(mask) | MIR::IDE::SET.value);
+            regs.fa1r.set(regs.fa1r.get() | bit);
+        }
+        regs.fmr.modify(FMR::FINIT::CLEAR);
+    }
+
+    /// Leave the bus, aborting the frame being sent. The transmit
+    /// interrupt returns its buffer, and turns the clock off.
+    fn stop(&self) {
+        self.enabled.set(false);
+        self.registers.ier.write(IER::TMEIE::SET);
+        self.registers.mcr.modify(MCR::INRQ::SET);
+        if self.tx_buffer.is_some() {
+            self.registers.tsr.write(TSR::ABRQ0::SET);
+        } else {
+            self.clock.disable();
+        }
+    }
+
+    pub fn handle_transmit_interrupt(&self) {
+        let regs = &*self.registers;
+        if !regs.tsr.is_set(TSR::RQCP0) {
+            return;
+        }
+        // Frames are retransmitted until they are sent, so a request only
+        // completes without being sent if it was aborted.
+        let result = if regs.tsr.is_set(TSR::TXOK0) {
+            Ok(())
+        } else {
+            Err(ErrorCode::FAIL)
+        };
+        regs.tsr.write(TSR::RQCP0::SET);
+        if !self.enabled.get() {
+            self.clock.disable();
+        }
+        self.tx_buffer.take().map(|buffer| {
+            self.transmit_client
+                .map(move |client| client.transmit_complete(result, buffer));
+        });
+    }
+
+    pub fn handle_receive_interrupt(&self) {
+        let regs = &*self.registers;
+        while regs.rf0r.read(RFR::FMP) != 0 {
+            let mailbox = &regs.rx[0];
+            let identifier = mailbox.rir.extract();
+            let len = cmp::min(mailbox.rdtr.read(MDTR::DLC) as usize, can::MAX_DATA_LEN);
+            let mut data = [0; can::MAX_DATA_LEN];
+            data[..4].copy_from_slice(&mailbox.rdlr.get().to_le_bytes());
+            data[4..].copy_from_slice(&mailbox.rdhr.get().to_le_bytes());
+            // Releasing the mailbox also clears an overrun.
+            regs.rf0r.write(RFR::RFOM::SET + RFR::FOVR::SET);
+
+            // Remote frames carry no data, and are not passed on.
+            if identifier.is_set(MIR::RTR) {
+                continue;
+            }
+            let id = if identifier.is_set(MIR::IDE) {
+                Id::Extended(identifier.read(MIR::STID) << 18 | identifier.read(MIR::EXID))
+            } else {
+                Id::Standard(identifier.read(MIR::STID) as u16)
+            };
+            self.receive_client
+                .map(|client| client.frame_received(id, &data[..len]));
+        }
+    }
+
+    pub fn handle_status_change_interrupt(&self) {
+        self.registers.msr.write(MSR::ERRI::SET);
+        if self.enabled.get() && self.registers.esr.is_set(ESR::BOFF) {
+            self.stop();
+            self.controller_client.map(|client| client.bus_off());
+        }
+    }
+}
+
+struct CanClock<'a>(rcc::PeripheralClock<'a>);
+
+impl ClockInterface for CanClock<'_> {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
+
+impl<'a> can::Controller<'a> for Can<'a> {
+    fn set_controller_client(&self, client: &'a dyn can::ControllerClient) {
+        self.controller_client.set(client);
+    }
+
+    fn enable(&self, bitrate: u32) -> Result<(), ErrorCode> {
+        if self.enabled.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        // A frame aborted when the controller last left the bus has not
+        // been returned yet.
+        if self.tx_buffer.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let (prescaler, ts1, ts2) = bit_timing(PCLK1_FREQUENCY, bitrate).ok_or(ErrorCode::INVAL)?;
+
+        self.clock.enable();
+        if let Err(e) = self.enter_init_mode() {
+            self.clock.disable();
+            return Err(e);
+        }
+        let regs = &*self.registers;
+        regs.mcr
+            .modify(MCR::ABOM::CLEAR + MCR::NART::CLEAR + MCR::RFLM::CLEAR + MCR::DBF::CLEAR);
+        regs.btr.write(
+            BTR::BRP.val(prescaler - 1)
+                + BTR::TS1.val(ts1 - 1)
+                + BTR::TS2.val(ts2 - 1)
+                + BTR::SJW.val(0),
+        );
+        for index in 0..FILTER_COUNT {
+            self.apply_filter(index);
+        }
+        regs.tsr.write(TSR::RQCP0::SET);
+        regs.ier
+            .write(IER::TMEIE::SET + IER::FMPIE0::SET + IER::BOFIE::SET + IER::ERRIE::SET);
+        // The controller joins the bus once it has seen it idle.
+        regs.mcr.modify(MCR::INRQ::CLEAR);
+        self.enabled.set(true);
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        if !self.enabled.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.stop();
+        Ok(())
+    }
+
+    fn filter_count(&self) -> usize {
+        FILTER_COUNT
+    }
+
+    fn set_filter(&self, index: usize, filter: Option<Filter>) -> Result<(), ErrorCode> {
+        if index >= FILTER_COUNT {
+            return Err(ErrorCode::INVAL);
+        }
+        self.filters[index].set(filter);
+        // The filters are written when the controller is enabled, as the
+        // registers cannot be written without the clock.
+        if self.enabled.get() {
+            self.apply_filter(index);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> can::Transmit<'a> for Can<'a> {
+    fn set_transmit_client(&self, client: &'a dyn can::TransmitClient) {
+        self.transmit_client.set(client);
+    }
+
+    fn send(
+        &self,
+        id: Id,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.enabled.get() {
+            return Err((ErrorCode::OFF, buffer));
+        }
+        if self.tx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if len > can::MAX_DATA_LEN || len > buffer.len() {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+
+        let mut data = [0; can::MAX_DATA_LEN];
+        data[..len].copy_from_slice(&buffer[..len]);
+        let mailbox = &self.registers.tx[0];
+        mailbox.tir.set(identifier_register(id));
+        mailbox.tdtr.write(MDTR::DLC.val(len as u32));
+        mailbox
+            .tdlr
+            .set(u32::from_le_bytes([data[0], data[1], data[2], data[3]]));
+        mailbox
+            .tdhr
+            .set(u32::from_le_bytes([data[4], data[5], data[6], data[7]]));
+        self.tx_buffer.replace(buffer);
+        mailbox.tir.modify(MIR::TXRQ::SET);
+        Ok(())
+    }
+}
+
+impl<'a> can::Receive<'a> for Can<'a> {
+    fn set_receive_client(&self, client: &'a dyn can::ReceiveClient) {
+        self.receive_client.set(client);
+    }
+}
+
+impl<'a> can::Can<'a> for Can<'a> {}
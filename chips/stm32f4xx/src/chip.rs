@@ -23,8 +23,11 @@ pub struct Stm32f4xxDefaultPeripherals<'a> {
     pub dma_streams: [crate::dma1::Stream<'a>; 8],
     pub exti: &'a crate::exti::Exti<'a>,
     pub i2c1: crate::i2c::I2C<'a>,
+    pub rtc: crate::rtc::Rtc<'a>,
     pub spi3: crate::spi::Spi<'a>,
     pub tim2: crate::tim2::Tim2<'a>,
+    pub tim3: crate::tim3::Tim3<'a>,
+    pub tim5: crate::tim5::Tim5<'a>,
     pub usart2: crate::usart::Usart<'a>,
     pub usart3: crate::usart::Usart<'a>,
     pub gpio_ports: crate::gpio::GpioPorts<'a>,
@@ -42,6 +45,7 @@ impl<'a> Stm32f4xxDefaultPeripherals<'a> {
             dma_streams: crate::dma1::new_dma1_stream(dma),
             exti,
             i2c1: crate::i2c::I2C::new(rcc),
+            rtc: crate::rtc::Rtc::new(rcc, exti),
             spi3: crate::spi::Spi::new(
                 crate::spi::SPI3_BASE,
                 crate::spi::SpiClock(crate::rcc::PeripheralClock::new(
@@ -52,6 +56,8 @@ impl<'a> Stm32f4xxDefaultPeripherals<'a> {
                 crate::dma1::Dma1Peripheral::SPI3_RX,
             ),
             tim2: crate::tim2::Tim2::new(rcc),
+            tim3: crate::tim3::Tim3::new(rcc),
+            tim5: crate::tim5::Tim5::new(rcc),
             usart2: crate::usart::Usart::new_usart2(rcc),
             usart3: crate::usart::Usart::new_usart3(rcc),
             gpio_ports: crate::gpio::GpioPorts::new(rcc, exti),
@@ -104,6 +110,8 @@ impl<'a> InterruptService<DeferredCallTask> for Stm32f4xxDefaultPeripherals<'a>
 
             nvic::SPI3 => self.spi3.handle_interrupt(),
 
+            nvic::RTC_Alarm => self.rtc.handle_interrupt(),
+
             nvic::EXTI0 => self.exti.handle_interrupt(),
             nvic::EXTI1 => self.exti.handle_interrupt(),
             nvic::EXTI2 => self.exti.handle_interrupt(),
@@ -113,6 +121,8 @@ impl<'a> InterruptService<DeferredCallTask> for Stm32f4xxDefaultPeripherals<'a>
             nvic::EXTI15_10 => self.exti.handle_interrupt(),
 
             nvic::TIM2 => self.tim2.handle_interrupt(),
+            nvic::TIM3 => self.tim3.handle_interrupt(),
+            nvic::TIM5 => self.tim5.handle_interrupt(),
 
             _ => return false,
         }
@@ -0,0 +1,747 @@
+//! Ethernet MAC driver for the STM32F4 chips that have one, such as the
+//! STM32F429.
+//!
+//! The MAC is connected to an external PHY over MII or RMII, and manages the
+//! PHY over its MDIO interface. Its DMA moves frames between memory and the
+//! MAC, following descriptors:
+//!
+//! - a single transmit descriptor, which points at the buffer of the frame
+//!   being sent, so frames are sent without being copied;
+//! - a ring of `RX_DESCRIPTORS` receive descriptors, each pointing at a
+//!   buffer of `RX_BUFFER_LEN` bytes of the receive buffers given by the
+//!   board. Frames are passed to the client from there, and the buffer is
+//!   given back to the DMA once the client returns.
+//!
+//! The PHY negotiates the speed and duplex mode of the link. The MAC has no
+//! interrupt for link changes, so the board calls `check_link()` from the
+//! interrupt of the PHY or periodically, which configures the MAC for the
+//! negotiated link and tells the client.
+//!
+//! The MAC needs an AHB clock of at least 25 MHz to run at 100 Mbit/s, and
+//! the board configures the pins of the interface for their alternate
+//! function.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ethernet = &peripherals.ethernet;
+//! ethernet.set_receive_buffers(&mut stm32f4xx::ethernet::RX_BUFFERS.0);
+//! ethernet.configure(stm32f4xx::ethernet::Config {
+//!     rmii: true,
+//!     phy_address: 0,
+//!     hclk_frequency: 168_000_000,
+//! });
+//! ethernet.set_client(network_stack);
+//! ethernet.enable(MacAddress([0x02, 0x00, 0x00, 0x12, 0x34, 0x56]));
+//! ```
+
+use crate::rcc;
+use crate::syscfg;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell, VolatileCell};
+use kernel::common::registers::interfaces::{ReadWriteable, Readable, Writeable};
+use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::ethernet::{self, Filter, LinkStatus, MacAddress};
+use kernel::ClockInterface;
+use kernel::ErrorCode;
+
+const MAC_BASE: StaticRef<MacRegisters> =
+    unsafe { StaticRef::new(0x4002_8000 as *const MacRegisters) };
+const DMA_BASE: StaticRef<DmaRegisters> =
+    unsafe { StaticRef::new(0x4002_9000 as *const DmaRegisters) };
+
+/// Number of receive descriptors.
+pub const RX_DESCRIPTORS: usize = 4;
+
+/// Length of the buffer of a receive descriptor, which holds a whole frame
+/// and its frame check sequence.
+pub const RX_BUFFER_LEN: usize = 1536;
+
+/// Receive buffers, aligned for the DMA.
+#[repr(align(4))]
+pub struct ReceiveBuffers(pub [u8; RX_DESCRIPTORS * RX_BUFFER_LEN]);
+
+pub static mut RX_BUFFERS: ReceiveBuffers = ReceiveBuffers([0; RX_DESCRIPTORS * RX_BUFFER_LEN]);
+
+/// Iterations to wait for the MDIO interface or a reset before giving up.
+const TIMEOUT: usize = 100_000;
+
+/// Length of the frame check sequence.
+const FCS_LEN: usize = 4;
+
+/// PHY registers and their bits, from IEEE 802.3 clause 22.
+const PHY_BMCR: u32 = 0;
+const PHY_BMSR: u32 = 1;
+const PHY_ANAR: u32 = 4;
+const PHY_ANLPAR: u32 = 5;
+const BMCR_RESET: u16 = 1 << 15;
+const BMCR_AUTONEG_ENABLE: u16 = 1 << 12;
+const BMCR_AUTONEG_RESTART: u16 = 1 << 9;
+const BMSR_AUTONEG_COMPLETE: u16 = 1 << 5;
+const BMSR_LINK: u16 = 1 << 2;
+const AN_100_FULL: u16 = 1 << 8;
+const AN_100_HALF: u16 = 1 << 7;
+const AN_10_FULL: u16 = 1 << 6;
+
+#[repr(C)]
+struct MacRegisters {
+    /// Ethernet MAC configuration register
+    maccr: ReadWrite<u32, MACCR::Register>,
+    /// Ethernet MAC frame filter register
+    macffr: ReadWrite<u32, MACFFR::Register>,
+    /// Ethernet MAC hash table high register
+    machthr: ReadWrite<u32>,
+    /// Ethernet MAC hash table low register
+    machtlr: ReadWrite<u32>,
+    /// Ethernet MAC MII address register
+    macmiiar: ReadWrite<u32, MACMIIAR::Register>,
+    /// Ethernet MAC MII data register
+    macmiidr: ReadWrite<u32>,
+    /// Ethernet MAC flow control register
+    macfcr: ReadWrite<u32>,
+    /// Ethernet MAC VLAN tag register
+    macvlantr: ReadWrite<u32>,
+    _reserved0: [u32; 2],
+    /// Ethernet MAC remote wakeup frame filter register
+    macrwuffr: ReadWrite<u32>,
+    /// Ethernet MAC PMT control and status register
+    macpmtcsr: ReadWrite<u32>,
+    _reserved1: u32,
+    /// Ethernet MAC debug register
+    macdbgr: ReadOnly<u32>,
+    /// Ethernet MAC interrupt status register
+    macsr: ReadWrite<u32>,
+    /// Ethernet MAC interrupt mask register
+    macimr: ReadWrite<u32, MACIMR::Register>,
+    /// Ethernet MAC address 0 high register
+    maca0hr: ReadWrite<u32>,
+    /// Ethernet MAC address 0 low register
+    maca0lr: ReadWrite<u32>,
+}
+
+#[repr(C)]
+struct DmaRegisters {
+    /// Ethernet DMA bus mode register
+    dmabmr: ReadWrite<u32, DMABMR::Register>,
+    /// Ethernet DMA transmit poll demand register
+    dmatpdr: WriteOnly<u32>,
+    /// Ethernet DMA receive poll demand register
+    dmarpdr: WriteOnly<u32>,
+    /// Ethernet DMA receive descriptor list address register
+    dmardlar: ReadWrite<u32>,
+    /// Ethernet DMA transmit descriptor list address register
+    dmatdlar: ReadWrite<u32>,
+    /// Ethernet DMA status register
+    dmasr: ReadWrite<u32, DMASR::Register>,
+    /// Ethernet DMA operation mode register
+    dmaomr: ReadWrite<u32, DMAOMR::Register>,
+    /// Ethernet DMA interrupt enable register
+    dmaier: ReadWrite<u32, DMAIER::Register>,
+    /// Ethernet DMA missed frame and buffer overflow counter register
+    dmamfbocr: ReadOnly<u32>,
+}
+
+register_bitfields![u32,
+    MACCR [
+        /// Fast Ethernet speed
+        FES OFFSET(14) NUMBITS(1) [],
+        /// Duplex mode
+        DM OFFSET(11) NUMBITS(1) [],
+        /// Transmitter enable
+        TE OFFSET(3) NUMBITS(1) [],
+        /// Receiver enable
+        RE OFFSET(2) NUMBITS(1) []
+    ],
+    MACFFR [
+        /// Broadcast frames disable
+        BFD OFFSET(5) NUMBITS(1) [],
+        /// Pass all multicast
+        PAM OFFSET(4) NUMBITS(1) [],
+        /// Hash multicast
+        HM OFFSET(2) NUMBITS(1) [],
+        /// Promiscuous mode
+        PM OFFSET(0) NUMBITS(1) []
+    ],
+    MACMIIAR [
+        /// PHY address
+        PA OFFSET(11) NUMBITS(5) [],
+        /// MII register
+        MR OFFSET(6) NUMBITS(5) [],
+        /// Clock range
+        CR OFFSET(2) NUMBITS(3) [
+            Div42 = 0,
+            Div62 = 1,
+            Div16 = 2,
+            Div26 = 3,
+            Div102 = 4
+        ],
+        /// MII write
+        MW OFFSET(1) NUMBITS(1) [],
+        /// MII busy
+        MB OFFSET(0) NUMBITS(1) []
+    ],
+    MACIMR [
+        /// Time stamp trigger interrupt mask
+        TSTIM OFFSET(9) NUMBITS(1) [],
+        /// PMT interrupt mask
+        PMTIM OFFSET(3) NUMBITS(1) []
+    ],
+    DMABMR [
+        /// Address-aligned beats
+        AAB OFFSET(25) NUMBITS(1) [],
+        /// Use separate PBL
+        USP OFFSET(23) NUMBITS(1) [],
+        /// Rx DMA PBL
+        RDP OFFSET(17) NUMBITS(6) [],
+        /// Fixed burst
+        FB OFFSET(16) NUMBITS(1) [],
+        /// Programmable burst length
+        PBL OFFSET(8) NUMBITS(6) [],
+        /// Software reset
+        SR OFFSET(0) NUMBITS(1) []
+    ],
+    DMASR [
+        /// Normal interrupt summary
+        NIS OFFSET(16) NUMBITS(1) [],
+        /// Abnormal interrupt summary
+        AIS OFFSET(15) NUMBITS(1) [],
+        /// Fatal bus error status
+        FBES OFFSET(13) NUMBITS(1) [],
+        /// Receive buffer unavailable status
+        RBUS OFFSET(7) NUMBITS(1) [],
+        /// Receive status
+        RS OFFSET(6) NUMBITS(1) [],
+        /// Transmit status
+        TS OFFSET(0) NUMBITS(1) []
+    ],
+    DMAOMR [
+        /// Receive store and forward
+        RSF OFFSET(25) NUMBITS(1) [],
+        /// Transmit store and forward
+        TSF OFFSET(21) NUMBITS(1) [],
+        /// Flush transmit FIFO
+        FTF OFFSET(20) NUMBITS(1) [],
+        /// Start/stop transmission
+        ST OFFSET(13) NUMBITS(1) [],
+        /// Start/stop receive
+        SR OFFSET(1) NUMBITS(1) []
+    ],
+    DMAIER [
+        /// Normal interrupt summary enable
+        NISE OFFSET(16) NUMBITS(1) [],
+        /// Abnormal interrupt summary enable
+        AISE OFFSET(15) NUMBITS(1) [],
+        /// Fatal bus error interrupt enable
+        FBEIE OFFSET(13) NUMBITS(1) [],
+        /// Receive buffer unavailable interrupt enable
+        RBUIE OFFSET(7) NUMBITS(1) [],
+        /// Receive interrupt enable
+        RIE OFFSET(6) NUMBITS(1) [],
+        /// Transmit interrupt enable
+        TIE OFFSET(0) NUMBITS(1) []
+    ]
+];
+
+/// Bits of the first word of the descriptors.
+const DES0_OWN: u32 = 1 << 31;
+const TDES0_IC: u32 = 1 << 30;
+const TDES0_LS: u32 = 1 << 29;
+const TDES0_FS: u32 = 1 << 28;
+const TDES0_TER: u32 = 1 << 21;
+const RDES0_FS: u32 = 1 << 9;
+const RDES0_LS: u32 = 1 << 8;
+const DES0_ES: u32 = 1 << 15;
+
+/// Bits of the second word of the receive descriptors.
+const RDES1_RER: u32 = 1 << 15;
+
+/// A DMA descriptor, in the normal format.
+#[repr(C, align(4))]
+struct Descriptor {
+    status: VolatileCell<u32>,
+    control: VolatileCell<u32>,
+    buffer1: VolatileCell<u32>,
+    buffer2: VolatileCell<u32>,
+}
+
+impl Descriptor {
+    const fn new() -> Descriptor {
+        Descriptor {
+            status: VolatileCell::new(0),
+            control: VolatileCell::new(0),
+            buffer1: VolatileCell::new(0),
+            buffer2: VolatileCell::new(0),
+        }
+    }
+}
+
+/// How the MAC is connected to its PHY.
+#[derive(Copy, Clone)]
+pub struct Config {
+    /// Whether the PHY is connected over RMII rather than MII.
+    pub rmii: bool,
+    /// MDIO address of the PHY.
+    pub phy_address: u8,
+    /// Frequency of the AHB clock, from which the MDIO clock is derived.
+    pub hclk_frequency: u32,
+}
+
+pub struct Ethernet<'a> {
+    mac: StaticRef<MacRegisters>,
+    dma: StaticRef<DmaRegisters>,
+    clock: EthernetClock<'a>,
+    syscfg: &'a syscfg::Syscfg<'a>,
+    client: OptionalCell<&'a dyn ethernet::Client>,
+    config: Cell<Config>,
+    enabled: Cell<bool>,
+    link: Cell<LinkStatus>,
+    filter: Cell<Filter>,
+    multicast_hash: Cell<u64>,
+
+    tx_descriptor: Descriptor,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_descriptors: [Descriptor; RX_DESCRIPTORS],
+    rx_buffers: TakeCell<'static, [u8]>,
+    /// The receive descriptor the next frame is in.
+    rx_next: Cell<usize>,
+}
+
+impl<'a> Ethernet<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc, syscfg: &'a syscfg::Syscfg<'a>) -> Ethernet<'a> {
+        Ethernet {
+            mac: MAC_BASE,
+            dma: DMA_BASE,
+            clock: EthernetClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::AHB1(rcc::HCLK1::ETHMAC),
+                rcc,
+            )),
+            syscfg: syscfg,
+            client: OptionalCell::empty(),
+            config: Cell::new(Config {
+                rmii: true,
+                phy_address: 0,
+                hclk_frequency: 16_000_000,
+            }),
+            enabled: Cell::new(false),
+            link: Cell::new(LinkStatus::Down),
+            filter: Cell::new(Filter {
+                promiscuous: false,
+                all_multicast: false,
+                broadcast: true,
+            }),
+            multicast_hash: Cell::new(0),
+            tx_descriptor: Descriptor::new(),
+            tx_buffer: TakeCell::empty(),
+            rx_descriptors: [
+                Descriptor::new(),
+                Descriptor::new(),
+                Descriptor::new(),
+                Descriptor::new(),
+            ],
+            rx_buffers: TakeCell::empty(),
+            rx_next: Cell::new(0),
+        }
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled()
+    }
+
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+    }
+
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+    }
+
+    /// Set how the MAC is connected to its PHY. Takes effect the next time
+    /// the MAC is enabled.
+    pub fn configure(&self, config: Config) {
+        self.config.set(config);
+    }
+
+    /// Give the buffers frames are received into, which must be at least
+    /// `RX_DESCRIPTORS * RX_BUFFER_LEN` bytes long and word-aligned, as
+    /// `RX_BUFFERS` is.
+    pub fn set_receive_buffers(&self, buffers: &'static mut [u8]) {
+        self.rx_buffers.replace(buffers);
+    }
+
+    /// Read the link status from the PHY, configure the MAC for the
+    /// negotiated speed and duplex mode, and tell the client if the link
+    /// changed.
+    pub fn check_link(&self) {
+        if self.update_link() {
+            let status = self.link.get();
+            self.client.map(|client| client.link_status_changed(status));
+        }
+    }
+
+    /// Update the link status. Returns whether it changed.
+    fn update_link(&self) -> bool {
+        if !self.enabled.get() {
+            return false;
+        }
+        let status = self.read_link().unwrap_or(LinkStatus::Down);
+        if status == self.link.get() {
+            return false;
+        }
+        if let LinkStatus::Up { speed, full_duplex } = status {
+            self.mac
+                .maccr
+                .modify(MACCR::FES.val((speed == 100) as u32) + MACCR::DM.val(full_duplex as u32));
+        }
+        self.link.set(status);
+        true
+    }
+
+    fn read_link(&self) -> Result<LinkStatus, ErrorCode> {
+        // The link bit latches low, so read it twice for its current state.
+        self.phy_read(PHY_BMSR)?;
+        let bmsr = self.phy_read(PHY_BMSR)?;
+        if bmsr & BMSR_LINK == 0 || bmsr & BMSR_AUTONEG_COMPLETE == 0 {
+            return Ok(LinkStatus::Down);
+        }
+        let common = self.phy_read(PHY_ANAR)? & self.phy_read(PHY_ANLPAR)?;
+        let (speed, full_duplex) = if common & AN_100_FULL != 0 {
+            (100, true)
+        } else if common & AN_100_HALF != 0 {
+            (100, false)
+        } else if common & AN_10_FULL != 0 {
+            (10, true)
+        } else {
+            (10, false)
+        };
+        Ok(LinkStatus::Up {
+            speed: speed,
+            full_duplex: full_duplex,
+        })
+    }
+
+    fn wait_mdio(&self) -> Result<(), ErrorCode> {
+        for _ in 0..TIMEOUT {
+            if !self.mac.macmiiar.is_set(MACMIIAR::MB) {
+                return Ok(());
+            }
+        }
+        Err(ErrorCode::FAIL)
+    }
+
+    /// The MDIO clock must not be faster than 2.5 MHz.
+    fn mdio_clock_range(&self) -> u32 {
+        match self.config.get().hclk_frequency {
+            0..=34_999_999 => MACMIIAR::CR::Div16.value,
+            35_000_000..=59_999_999 => MACMIIAR::CR::Div26.value,
+            60_000_000..=99_999_999 => MACMIIAR::CR::Div42.value,
+            100_000_000..=149_999_999 => MACMIIAR::CR::Div62.value,
+            _ => MACMIIAR::CR::Div102.value,
+        }
+    }
+
+    fn phy_access(&self, reg: u32, write: bool) -> Result<(), ErrorCode> {
+        self.mac.macmiiar.write(
+            MACMIIAR::PA.val(self.config.get().phy_address as u32)
+                + MACMIIAR::MR.val(reg)
+                + MACMIIAR::CR.val(self.mdio_clock_range())
+                + MACMIIAR::MW.val(write as u32)
+                + MACMIIAR::MB::SET,
+        );
+        self.wait_mdio()
+    }
+
+    fn phy_read(&self, reg: u32) -> Result<u16, ErrorCode> {
+        self.wait_mdio()?;
+        self.phy_access(reg, false)?;
+        Ok(self.mac.macmiidr.get() as u16)
+    }
+
+    fn phy_write(&self, reg: u32, value: u16) -> Result<(), ErrorCode> {
+        self.wait_mdio()?;
+        self.mac.macmiidr.set(value as u32);
+        self.phy_access(reg, true)
+    }
+
+    /// Reset the PHY and start the negotiation of the link.
+    fn start_phy(&self) -> Result<(), ErrorCode> {
+        self.phy_write(PHY_BMCR, BMCR_RESET)?;
+        let mut reset = false;
+        for _ in 0..TIMEOUT {
+            if self.phy_read(PHY_BMCR)? & BMCR_RESET == 0 {
+                reset = true;
+                break;
+            }
+        }
+        if !reset {
+            return Err(ErrorCode::FAIL);
+        }
+        self.phy_write(PHY_BMCR, BMCR_AUTONEG_ENABLE | BMCR_AUTONEG_RESTART)
+    }
+
+    fn apply_filter(&self) {
+        let filter = self.filter.get();
+        let hash = self.multicast_hash.get();
+        self.mac.machthr.set((hash >> 32) as u32);
+        self.mac.machtlr.set(hash as u32);
+        self.mac.macffr.write(
+            MACFFR::PM.val(filter.promiscuous as u32)
+                + MACFFR::PAM.val(filter.all_multicast as u32)
+                + MACFFR::BFD.val(!filter.broadcast as u32)
+                + MACFFR::HM.val((hash != 0) as u32),
+        );
+    }
+
+    /// Give every receive buffer to the DMA, and point the DMA at the
+    /// descriptors.
+    fn setup_descriptors(&self) {
+        self.rx_buffers.map(|buffers| {
+            for (i, descriptor) in self.rx_descriptors.iter().enumerate() {
+                let end = if i == RX_DESCRIPTORS - 1 {
+                    RDES1_RER
+                } else {
+                    0
+                };
+                descriptor
+                    .buffer1
+                    .set(buffers[i * RX_BUFFER_LEN..].as_ptr() as u32);
+                descriptor.buffer2.set(0);
+                descriptor.control.set(end | RX_BUFFER_LEN as u32);
+                descriptor.status.set(DES0_OWN);
+            }
+        });
+        self.rx_next.set(0);
+        self.tx_descriptor.status.set(TDES0_TER);
+        self.dma
+            .dmardlar
+            .set(&self.rx_descriptors[0] as *const Descriptor as u32);
+        self.dma
+            .dmatdlar
+            .set(&self.tx_descriptor as *const Descriptor as u32);
+    }
+
+    fn start(&self, address: MacAddress) -> Result<(), ErrorCode> {
+        let dma = &*self.dma;
+        let mac = &*self.mac;
+
+        // The interface can only be selected while the MAC is in reset.
+        self.syscfg.enable_clock();
+        self.syscfg.select_ethernet_rmii(self.config.get().rmii);
+        self.enable_clock();
+        self.clock.0.reset_ethmac();
+
+        // The reset only completes once the PHY provides its clocks.
+        dma.dmabmr.modify(DMABMR::SR::SET);
+        let mut reset = false;
+        for _ in 0..TIMEOUT {
+            if !dma.dmabmr.is_set(DMABMR::SR) {
+                reset = true;
+                break;
+            }
+        }
+        if !reset {
+            return Err(ErrorCode::FAIL);
+        }
+        self.start_phy()?;
+
+        let a = address.0;
+        mac.maca0hr.set((a[5] as u32) << 8 | a[4] as u32);
+        mac.maca0lr
+            .set(u32::from_le_bytes([a[0], a[1], a[2], a[3]]));
+        self.apply_filter();
+        mac.macimr.write(MACIMR::TSTIM::SET + MACIMR::PMTIM::SET);
+
+        self.setup_descriptors();
+        dma.dmabmr.write(
+            DMABMR::AAB::SET
+                + DMABMR::USP::SET
+                + DMABMR::RDP.val(32)
+                + DMABMR::FB::SET
+                + DMABMR::PBL.val(32),
+        );
+        dma.dmaomr.write(DMAOMR::RSF::SET + DMAOMR::TSF::SET);
+        dma.dmaier.write(
+            DMAIER::NISE::SET
+                + DMAIER::AISE::SET
+                + DMAIER::FBEIE::SET
+                + DMAIER::RBUIE::SET
+                + DMAIER::RIE::SET
+                + DMAIER::TIE::SET,
+        );
+
+        mac.maccr.modify(MACCR::TE::SET + MACCR::RE::SET);
+        dma.dmaomr.modify(DMAOMR::FTF::SET);
+        dma.dmaomr.modify(DMAOMR::ST::SET + DMAOMR::SR::SET);
+        Ok(())
+    }
+
+    fn stop(&self) {
+        self.dma
+            .dmaomr
+            .modify(DMAOMR::ST::CLEAR + DMAOMR::SR::CLEAR);
+        self.mac.maccr.modify(MACCR::TE::CLEAR + MACCR::RE::CLEAR);
+        self.dma.dmaier.set(0);
+        self.disable_clock();
+    }
+
+    /// Pass the frames the DMA received to the client, and give their
+    /// buffers back to the DMA.
+    fn receive_frames(&self) {
+        for _ in 0..RX_DESCRIPTORS {
+            let index = self.rx_next.get();
+            let descriptor = &self.rx_descriptors[index];
+            let status = descriptor.status.get();
+            if status & DES0_OWN != 0 {
+                break;
+            }
+
+            // Frames that did not fit in a single buffer are dropped.
+            let len = ((status >> 16) & 0x3FFF) as usize;
+            let complete = status & (RDES0_FS | RDES0_LS) == RDES0_FS | RDES0_LS;
+            if complete && status & DES0_ES == 0 && len >= ethernet::HEADER_LEN + FCS_LEN {
+                self.rx_buffers.map(|buffers| {
+                    let start = index * RX_BUFFER_LEN;
+                    let frame = &buffers[start..start + len - FCS_LEN];
+                    self.client.map(|client| client.frame_received(frame));
+                });
+            }
+
+            descriptor.status.set(DES0_OWN);
+            self.rx_next.set((index + 1) % RX_DESCRIPTORS);
+        }
+    }
+
+    pub fn handle_interrupt(&self) {
+        let dma = &*self.dma;
+        let status = dma.dmasr.extract();
+        // Status bits are cleared by writing them back.
+        dma.dmasr.set(status.get() & 0x0001_FFFF);
+
+        if status.is_set(DMASR::RS) || status.is_set(DMASR::RBUS) {
+            self.receive_frames();
+            // Reception stops when the DMA runs out of buffers.
+            dma.dmarpdr.set(0);
+        }
+
+        // A bus error stops the DMA, so the frame being sent is lost.
+        let bus_error = status.is_set(DMASR::FBES);
+        if status.is_set(DMASR::TS) || bus_error {
+            let failed = bus_error || self.tx_descriptor.status.get() & DES0_ES != 0;
+            self.tx_buffer.take().map(|buffer| {
+                let result = if failed { Err(ErrorCode::FAIL) } else { Ok(()) };
+                self.client
+                    .map(move |client| client.transmit_done(buffer, result));
+            });
+        }
+    }
+}
+
+struct EthernetClock<'a>(rcc::PeripheralClock<'a>);
+
+impl ClockInterface for EthernetClock<'_> {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
+
+impl<'a> ethernet::Mac<'a> for Ethernet<'a> {
+    fn set_client(&self, client: &'a dyn ethernet::Client) {
+        self.client.set(client);
+    }
+
+    fn enable(&self, address: MacAddress) -> Result<(), ErrorCode> {
+        if self.enabled.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let buffers_ok = self.rx_buffers.map_or(false, |buffers| {
+            buffers.len() >= RX_DESCRIPTORS * RX_BUFFER_LEN && buffers.as_ptr() as usize % 4 == 0
+        });
+        if !buffers_ok {
+            return Err(ErrorCode::NOMEM);
+        }
+        if let Err(e) = self.start(address) {
+            self.stop();
+            return Err(e);
+        }
+        self.enabled.set(true);
+        self.link.set(LinkStatus::Down);
+        self.update_link();
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        if !self.enabled.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        if self.tx_buffer.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.stop();
+        self.enabled.set(false);
+        self.link.set(LinkStatus::Down);
+        Ok(())
+    }
+
+    fn set_filter(&self, filter: Filter) -> Result<(), ErrorCode> {
+        self.filter.set(filter);
+        if self.enabled.get() {
+            self.apply_filter();
+        }
+        Ok(())
+    }
+
+    fn set_multicast_addresses(&self, addresses: &[MacAddress]) -> Result<(), ErrorCode> {
+        if addresses.iter().any(|address| !address.is_multicast()) {
+            return Err(ErrorCode::INVAL);
+        }
+        // The upper six bits of the bit-reversed CRC index the table.
+        self.multicast_hash
+            .set(ethernet::multicast_hash_table(addresses, |crc| {
+                crc.reverse_bits() >> 26
+            }));
+        if self.enabled.get() {
+            self.apply_filter();
+        }
+        Ok(())
+    }
+
+    fn link_status(&self) -> LinkStatus {
+        self.link.get()
+    }
+
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.enabled.get() {
+            return Err((ErrorCode::OFF, buffer));
+        }
+        if self.tx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if len < ethernet::HEADER_LEN || len > ethernet::MAX_FRAME_LEN || len > buffer.len() {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+
+        let descriptor = &self.tx_descriptor;
+        descriptor.buffer1.set(buffer.as_ptr() as u32);
+        descriptor.control.set(len as u32);
+        // Handing the descriptor to the DMA comes last.
+        descriptor
+            .status
+            .set(DES0_OWN | TDES0_IC | TDES0_LS | TDES0_FS | TDES0_TER);
+        self.tx_buffer.replace(buffer);
+        self.dma.dmatpdr.set(0);
+        Ok(())
+    }
+}
@@ -609,6 +609,20 @@ impl<'a> Exti<'a> {
         }
     }
 
+    /// Route RTC alarm events, on line 17, to the `RTC_Alarm` interrupt.
+    pub fn enable_rtc_alarm_line(&self) {
+        self.registers.rtsr.modify(RTSR::TR17::SET);
+        self.registers.imr.modify(IMR::MR17::SET);
+    }
+
+    pub fn disable_rtc_alarm_line(&self) {
+        self.registers.imr.modify(IMR::MR17::CLEAR);
+    }
+
+    pub fn clear_rtc_alarm_pending(&self) {
+        self.registers.pr.write(PR::PR17::SET);
+    }
+
     pub fn handle_interrupt(&self) {
         let mut exti_pr: u32 = 0;
 
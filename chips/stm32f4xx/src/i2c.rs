@@ -5,9 +5,11 @@ use kernel::common::registers::interfaces::{ReadWriteable, Readable, Writeable};
 use kernel::common::registers::{register_bitfields, ReadWrite};
 use kernel::common::StaticRef;
 use kernel::hil;
+use kernel::hil::gpio::{Configure, Input, Output};
 use kernel::hil::i2c::{self, Error, I2CHwMasterClient, I2CMaster};
 use kernel::ClockInterface;
 
+use crate::gpio;
 use crate::rcc;
 
 pub enum I2CSpeed {
@@ -195,6 +197,9 @@ pub struct I2C<'a> {
     slave_address: Cell<u8>,
 
     status: Cell<I2CStatus>,
+
+    /// The SCL and SDA pins, driven as GPIOs to recover the bus.
+    recovery_pins: OptionalCell<(&'a gpio::Pin<'a>, &'a gpio::Pin<'a>)>,
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -226,9 +231,17 @@ impl<'a> I2C<'a> {
             rx_len: Cell::new(0),
 
             status: Cell::new(I2CStatus::Idle),
+
+            recovery_pins: OptionalCell::empty(),
         }
     }
 
+    /// Set the pins of SCL and SDA, in the alternate function of the I2C, to
+    /// recover the bus after an aborted transfer.
+    pub fn set_recovery_pins(&self, scl: &'a gpio::Pin<'a>, sda: &'a gpio::Pin<'a>) {
+        self.recovery_pins.set((scl, sda));
+    }
+
     pub fn set_speed(&self, speed: I2CSpeed, system_clock_in_mhz: usize) {
         self.disable();
         self.registers
@@ -390,6 +403,46 @@ impl<'a> I2C<'a> {
         self.status.set(I2CStatus::Idle);
     }
 
+    /// Clock SCL until a slave holding SDA low releases it, then generate a
+    /// stop condition, and give the pins back to the I2C.
+    fn recover_bus(&self) {
+        self.recovery_pins.map(|(scl, sda)| {
+            // The lines are open drain: a line is released by making it an
+            // input, for the pull-up to bring it high, and pulled low by
+            // making it an output.
+            scl.make_input();
+            sda.make_input();
+            scl.clear();
+            sda.clear();
+            bus_delay();
+
+            // A slave in the middle of a byte releases SDA within 9 clocks.
+            for _ in 0..9 {
+                if sda.read() {
+                    break;
+                }
+                scl.make_output();
+                bus_delay();
+                scl.make_input();
+                bus_delay();
+            }
+
+            // Stop condition: SDA rises while SCL is high.
+            scl.make_output();
+            sda.make_output();
+            bus_delay();
+            scl.make_input();
+            bus_delay();
+            sda.make_input();
+            bus_delay();
+
+            for pin in [scl, sda].iter() {
+                pin.set_mode_output_opendrain();
+                pin.set_mode(gpio::Mode::AlternateFunctionMode);
+            }
+        });
+    }
+
     fn start_read(&self) {
         self.rx_position.set(0);
         self.registers
@@ -441,6 +494,32 @@ impl i2c::I2CMaster for I2C<'_> {
             self.start_read();
         }
     }
+    fn abort(&self) -> Option<&'static mut [u8]> {
+        let buf = self.buffer.take()?;
+        self.stop();
+
+        // A software reset frees the I2C from a stuck bus, but clears its
+        // configuration.
+        let freq = self.registers.cr2.read(CR2::FREQ);
+        let ccr = self.registers.ccr.get();
+        let trise = self.registers.trise.get();
+        self.disable();
+        self.recover_bus();
+        self.registers.cr1.write(CR1::SWRST::SET);
+        self.registers.cr1.write(CR1::SWRST::CLEAR);
+        self.registers.cr2.write(CR2::FREQ.val(freq));
+        self.registers.ccr.set(ccr);
+        self.registers.trise.set(trise);
+        self.enable();
+        Some(buf)
+    }
+}
+
+/// Wait at least half a clock period at 100 kHz, 5 µs at up to 180 MHz.
+fn bus_delay() {
+    for _ in 0..300 {
+        cortexm4::support::nop();
+    }
 }
 
 struct I2CClock<'a>(rcc::PeripheralClock<'a>);
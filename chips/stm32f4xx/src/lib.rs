@@ -13,17 +13,23 @@ pub mod nvic;
 
 // Peripherals
 pub mod adc;
+pub mod can;
 pub mod dbg;
 pub mod deferred_calls;
 pub mod dma1;
+pub mod ethernet;
 pub mod exti;
 pub mod fsmc;
 pub mod gpio;
 pub mod i2c;
+pub mod quadspi;
 pub mod rcc;
+pub mod rtc;
 pub mod spi;
 pub mod syscfg;
 pub mod tim2;
+pub mod tim3;
+pub mod tim5;
 pub mod trng;
 pub mod usart;
 
@@ -63,6 +63,7 @@ pub const DMA2_Stream1: u32 = 57;
 pub const DMA2_Stream2: u32 = 58;
 pub const DMA2_Stream3: u32 = 59;
 pub const DMA2_Stream4: u32 = 60;
+pub const ETH: u32 = 61;
 pub const CAN2_TX: u32 = 63;
 pub const CAN2_RX0: u32 = 64;
 pub const CAN2_RX1: u32 = 65;
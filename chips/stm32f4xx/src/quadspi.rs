@@ -0,0 +1,616 @@
+//! QUADSPI driver for the STM32F4 chips that have one.
+//!
+//! Indirect operations move data through the 32-byte FIFO of the peripheral,
+//! one byte per access of the data register, from the FIFO threshold
+//! interrupt. Programs and erases are a sequence of commands: the write
+//! enable command, the program or erase command, and an automatic polling of
+//! the status register that stops once the flash is no longer busy.
+//!
+//! The flash is mapped into memory at `MEMORY_MAPPED_BASE` in the memory-mapped
+//! mode, which reads it with the read command of the configuration. The
+//! board configures the pins of the interface for their alternate function.
+
+use crate::rcc;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::registers::interfaces::{ReadWriteable, Readable, Writeable};
+use kernel::common::registers::{register_bitfields, FieldValue, ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::qspi;
+use kernel::ClockInterface;
+use kernel::ErrorCode;
+
+const QUADSPI_BASE: StaticRef<QuadspiRegisters> =
+    unsafe { StaticRef::new(0xA000_1000 as *const QuadspiRegisters) };
+
+/// Address of the memory-mapped flash.
+pub const MEMORY_MAPPED_BASE: usize = 0x9000_0000;
+
+/// Frequency of the AHB clock of the peripheral, the 16 MHz HSI.
+const HCLK_FREQUENCY: u32 = 16_000_000;
+
+/// Number of lines of a phase of a command.
+const NONE: u32 = 0b00;
+const SINGLE: u32 = 0b01;
+const DUAL: u32 = 0b10;
+const QUAD: u32 = 0b11;
+
+/// Flash instructions.
+const WREN: u32 = 0x06;
+const RDSR: u32 = 0x05;
+
+#[repr(C)]
+struct QuadspiRegisters {
+    /// Control register
+    cr: ReadWrite<u32, CR::Register>,
+    /// Device configuration register
+    dcr: ReadWrite<u32, DCR::Register>,
+    /// Status register
+    sr: ReadOnly<u32, SR::Register>,
+    /// Flag clear register
+    fcr: WriteOnly<u32, FCR::Register>,
+    /// Data length register
+    dlr: ReadWrite<u32>,
+    /// Communication configuration register
+    ccr: ReadWrite<u32, CCR::Register>,
+    /// Address register
+    ar: ReadWrite<u32>,
+    /// Alternate bytes register
+    abr: ReadWrite<u32>,
+    /// Data register, accessed by bytes so that every access moves a single
+    /// byte of the FIFO
+    dr: ReadWrite<u8>,
+    _reserved0: [u8; 3],
+    /// Polling status mask register
+    psmkr: ReadWrite<u32>,
+    /// Polling status match register
+    psmar: ReadWrite<u32>,
+    /// Polling interval register
+    pir: ReadWrite<u32>,
+}
+
+register_bitfields![u32,
+    CR [
+        /// Clock prescaler
+        PRESCALER OFFSET(24) NUMBITS(8) [],
+        /// Automatic poll mode stop
+        APMS OFFSET(22) NUMBITS(1) [],
+        /// Status match interrupt enable
+        SMIE OFFSET(19) NUMBITS(1) [],
+        /// FIFO threshold interrupt enable
+        FTIE OFFSET(18) NUMBITS(1) [],
+        /// Transfer complete interrupt enable
+        TCIE OFFSET(17) NUMBITS(1) [],
+        /// Transfer error interrupt enable
+        TEIE OFFSET(16) NUMBITS(1) [],
+        /// FIFO threshold level
+        FTHRES OFFSET(8) NUMBITS(5) [],
+        /// Sample shift
+        SSHIFT OFFSET(4) NUMBITS(1) [],
+        /// Abort request
+        ABORT OFFSET(1) NUMBITS(1) [],
+        /// Enable
+        EN OFFSET(0) NUMBITS(1) []
+    ],
+    DCR [
+        /// Flash memory size, 2^(FSIZE + 1) bytes
+        FSIZE OFFSET(16) NUMBITS(5) [],
+        /// Chip select high time, in cycles minus one
+        CSHT OFFSET(8) NUMBITS(3) [],
+        /// Mode 0 or mode 3
+        CKMODE OFFSET(0) NUMBITS(1) []
+    ],
+    SR [
+        /// FIFO level
+        FLEVEL OFFSET(8) NUMBITS(6) [],
+        /// Busy
+        BUSY OFFSET(5) NUMBITS(1) [],
+        /// Status match flag
+        SMF OFFSET(3) NUMBITS(1) [],
+        /// FIFO threshold flag
+        FTF OFFSET(2) NUMBITS(1) [],
+        /// Transfer complete flag
+        TCF OFFSET(1) NUMBITS(1) [],
+        /// Transfer error flag
+        TEF OFFSET(0) NUMBITS(1) []
+    ],
+    FCR [
+        /// Clear timeout flag
+        CTOF OFFSET(4) NUMBITS(1) [],
+        /// Clear status match flag
+        CSMF OFFSET(3) NUMBITS(1) [],
+        /// Clear transfer complete flag
+        CTCF OFFSET(1) NUMBITS(1) [],
+        /// Clear transfer error flag
+        CTEF OFFSET(0) NUMBITS(1) []
+    ],
+    CCR [
+        /// Functional mode
+        FMODE OFFSET(26) NUMBITS(2) [
+            IndirectWrite = 0,
+            IndirectRead = 1,
+            AutomaticPolling = 2,
+            MemoryMapped = 3
+        ],
+        /// Data mode
+        DMODE OFFSET(24) NUMBITS(2) [],
+        /// Number of dummy cycles
+        DCYC OFFSET(18) NUMBITS(5) [],
+        /// Alternate bytes size
+        ABSIZE OFFSET(16) NUMBITS(2) [],
+        /// Alternate bytes mode
+        ABMODE OFFSET(14) NUMBITS(2) [],
+        /// Address size
+        ADSIZE OFFSET(12) NUMBITS(2) [
+            Bits24 = 2,
+            Bits32 = 3
+        ],
+        /// Address mode
+        ADMODE OFFSET(10) NUMBITS(2) [],
+        /// Instruction mode
+        IMODE OFFSET(8) NUMBITS(2) [],
+        /// Instruction
+        INSTRUCTION OFFSET(0) NUMBITS(8) []
+    ]
+];
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    Read,
+    /// Sending the write enable command before a program or an erase.
+    WriteEnable,
+    Program,
+    Erase,
+    /// Polling the status register until the flash is done.
+    Poll,
+}
+
+pub struct Quadspi<'a> {
+    registers: StaticRef<QuadspiRegisters>,
+    clock: QuadspiClock<'a>,
+    client: OptionalCell<&'a dyn qspi::Client>,
+    config: OptionalCell<qspi::Config>,
+    state: Cell<State>,
+    mapped: Cell<bool>,
+
+    buffer: TakeCell<'static, [u8]>,
+    address: Cell<usize>,
+    len: Cell<usize>,
+    index: Cell<usize>,
+    // The erase in progress, if the operation is not a program.
+    erase: OptionalCell<qspi::EraseSize>,
+}
+
+impl<'a> Quadspi<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc) -> Quadspi<'a> {
+        Quadspi {
+            registers: QUADSPI_BASE,
+            clock: QuadspiClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::AHB3(rcc::HCLK3::QSPI),
+                rcc,
+            )),
+            client: OptionalCell::empty(),
+            config: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            mapped: Cell::new(false),
+            buffer: TakeCell::empty(),
+            address: Cell::new(0),
+            len: Cell::new(0),
+            index: Cell::new(0),
+            erase: OptionalCell::empty(),
+        }
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled()
+    }
+
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+    }
+
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+    }
+
+    fn address_size(config: &qspi::Config) -> FieldValue<u32, CCR::Register> {
+        match config.address_width {
+            qspi::AddressWidth::Bits24 => CCR::ADSIZE::Bits24,
+            qspi::AddressWidth::Bits32 => CCR::ADSIZE::Bits32,
+        }
+    }
+
+    /// The read command of the configuration, without its functional mode.
+    /// The alternate byte, if any, holds the mode bits of the flash, which
+    /// must not enter its continuous read mode.
+    fn read_command(config: &qspi::Config) -> FieldValue<u32, CCR::Register> {
+        let (instruction, address, alternate, dummy, data) = match config.read_mode {
+            qspi::ReadMode::Fast => (0x0B, SINGLE, NONE, 8, SINGLE),
+            qspi::ReadMode::DualOutput => (0x3B, SINGLE, NONE, 8, DUAL),
+            qspi::ReadMode::DualIo => (0xBB, DUAL, DUAL, 0, DUAL),
+            qspi::ReadMode::QuadOutput => (0x6B, SINGLE, NONE, 8, QUAD),
+            qspi::ReadMode::QuadIo => (0xEB, QUAD, QUAD, 4, QUAD),
+        };
+        CCR::INSTRUCTION.val(instruction)
+            + CCR::IMODE.val(SINGLE)
+            + CCR::ADMODE.val(address)
+            + Self::address_size(config)
+            + CCR::ABMODE.val(alternate)
+            + CCR::ABSIZE.val(0)
+            + CCR::DCYC.val(dummy)
+            + CCR::DMODE.val(data)
+    }
+
+    /// Check the area of a read or a program.
+    fn check_transfer(&self, address: usize, buffer: &[u8], len: usize) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle || self.mapped.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        let size = self.config.map_or(0, |config| config.size);
+        if size == 0 {
+            return Err(ErrorCode::OFF);
+        }
+        if len == 0 || len > buffer.len() {
+            return Err(ErrorCode::SIZE);
+        }
+        if address % 4 != 0 || len % 4 != 0 || buffer.as_ptr() as usize % 4 != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        if address + len > size {
+            return Err(ErrorCode::INVAL);
+        }
+        Ok(())
+    }
+
+    /// Start a command, which transfers `len` bytes if any. Writing the
+    /// address register starts the commands that have an address, and
+    /// writing the communication configuration register the others.
+    fn command(
+        &self,
+        state: State,
+        ccr: FieldValue<u32, CCR::Register>,
+        address: Option<usize>,
+        len: usize,
+        interrupts: FieldValue<u32, CR::Register>,
+    ) {
+        let regs = &*self.registers;
+        // A command can only start once the previous one released the bus.
+        while regs.sr.is_set(SR::BUSY) {}
+
+        self.state.set(state);
+        regs.fcr
+            .write(FCR::CTEF::SET + FCR::CTCF::SET + FCR::CSMF::SET + FCR::CTOF::SET);
+        if len > 0 {
+            regs.dlr.set(len as u32 - 1);
+        }
+        regs.abr.set(0xFF);
+        regs.cr.modify(CR::TEIE::SET + interrupts);
+        regs.ccr.write(ccr);
+        if let Some(address) = address {
+            regs.ar.set(address as u32);
+        }
+    }
+
+    fn write_enable(&self) {
+        self.command(
+            State::WriteEnable,
+            CCR::INSTRUCTION.val(WREN) + CCR::IMODE.val(SINGLE) + CCR::FMODE::IndirectWrite,
+            None,
+            0,
+            CR::TCIE::SET,
+        );
+    }
+
+    /// Send the program or erase command, after the write enable command.
+    fn program_or_erase(&self, config: &qspi::Config) {
+        let command = CCR::IMODE.val(SINGLE) + CCR::FMODE::IndirectWrite;
+        match self.erase.extract() {
+            None => {
+                let (instruction, data) = match config.write_mode {
+                    qspi::WriteMode::Single => (0x02, SINGLE),
+                    qspi::WriteMode::QuadOutput => (0x32, QUAD),
+                };
+                self.index.set(0);
+                self.command(
+                    State::Program,
+                    command
+                        + CCR::INSTRUCTION.val(instruction)
+                        + CCR::ADMODE.val(SINGLE)
+                        + Self::address_size(config)
+                        + CCR::DMODE.val(data),
+                    Some(self.address.get()),
+                    self.len.get(),
+                    CR::TCIE::SET + CR::FTIE::SET,
+                );
+            }
+            Some(qspi::EraseSize::Chip) => {
+                self.command(
+                    State::Erase,
+                    command + CCR::INSTRUCTION.val(0xC7),
+                    None,
+                    0,
+                    CR::TCIE::SET,
+                );
+            }
+            Some(size) => {
+                let instruction = if size == qspi::EraseSize::Sector4K {
+                    0x20
+                } else {
+                    0xD8
+                };
+                self.command(
+                    State::Erase,
+                    command
+                        + CCR::INSTRUCTION.val(instruction)
+                        + CCR::ADMODE.val(SINGLE)
+                        + Self::address_size(config),
+                    Some(self.address.get()),
+                    0,
+                    CR::TCIE::SET,
+                );
+            }
+        }
+    }
+
+    /// Poll the status register until the write in progress bit clears.
+    fn poll(&self) {
+        let regs = &*self.registers;
+        regs.psmkr.set(0x01);
+        regs.psmar.set(0x00);
+        regs.pir.set(0x10);
+        regs.cr.modify(CR::APMS::SET);
+        self.command(
+            State::Poll,
+            CCR::INSTRUCTION.val(RDSR)
+                + CCR::IMODE.val(SINGLE)
+                + CCR::DMODE.val(SINGLE)
+                + CCR::FMODE::AutomaticPolling,
+            None,
+            1,
+            CR::SMIE::SET,
+        );
+    }
+
+    /// End the operation in progress, and report it to the client.
+    fn complete(&self, result: Result<(), ErrorCode>) {
+        let regs = &*self.registers;
+        regs.cr
+            .modify(CR::TEIE::CLEAR + CR::TCIE::CLEAR + CR::FTIE::CLEAR + CR::SMIE::CLEAR);
+        let state = self.state.get();
+        self.state.set(State::Idle);
+
+        let len = self.len.get();
+        match state {
+            State::Read => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.read_complete(buffer, len, result));
+                });
+            }
+            _ if self.erase.is_some() => {
+                self.erase.clear();
+                self.client.map(|client| client.erase_complete(result));
+            }
+            _ => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.write_complete(buffer, len, result));
+                });
+            }
+        }
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = &*self.registers;
+        if regs.sr.is_set(SR::TEF) {
+            regs.fcr.write(FCR::CTEF::SET);
+            if self.state.get() != State::Idle {
+                regs.cr.modify(CR::ABORT::SET);
+                self.complete(Err(ErrorCode::FAIL));
+            }
+            return;
+        }
+
+        match self.state.get() {
+            State::Read => {
+                // Check for the end of the transfer before emptying the FIFO,
+                // so that no byte is left behind.
+                let complete = regs.sr.is_set(SR::TCF);
+                self.buffer.map(|buffer| {
+                    let mut index = self.index.get();
+                    while index < self.len.get() && regs.sr.read(SR::FLEVEL) > 0 {
+                        buffer[index] = regs.dr.get();
+                        index += 1;
+                    }
+                    self.index.set(index);
+                });
+                if complete {
+                    regs.fcr.write(FCR::CTCF::SET);
+                    self.complete(Ok(()));
+                }
+            }
+            State::Program => {
+                self.buffer.map(|buffer| {
+                    let mut index = self.index.get();
+                    while index < self.len.get() && regs.sr.is_set(SR::FTF) {
+                        regs.dr.set(buffer[index]);
+                        index += 1;
+                    }
+                    self.index.set(index);
+                });
+                if self.index.get() == self.len.get() {
+                    regs.cr.modify(CR::FTIE::CLEAR);
+                }
+                if regs.sr.is_set(SR::TCF) {
+                    regs.fcr.write(FCR::CTCF::SET);
+                    regs.cr.modify(CR::TCIE::CLEAR);
+                    self.poll();
+                }
+            }
+            State::WriteEnable => {
+                if regs.sr.is_set(SR::TCF) {
+                    regs.fcr.write(FCR::CTCF::SET);
+                    regs.cr.modify(CR::TCIE::CLEAR);
+                    self.config.map(|config| self.program_or_erase(config));
+                }
+            }
+            State::Erase => {
+                if regs.sr.is_set(SR::TCF) {
+                    regs.fcr.write(FCR::CTCF::SET);
+                    regs.cr.modify(CR::TCIE::CLEAR);
+                    self.poll();
+                }
+            }
+            State::Poll => {
+                if regs.sr.is_set(SR::SMF) {
+                    regs.fcr.write(FCR::CSMF::SET);
+                    regs.cr.modify(CR::APMS::CLEAR);
+                    self.complete(Ok(()));
+                }
+            }
+            State::Idle => {}
+        }
+    }
+}
+
+struct QuadspiClock<'a>(rcc::PeripheralClock<'a>);
+
+impl ClockInterface for QuadspiClock<'_> {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
+
+impl<'a> qspi::Qspi<'a> for Quadspi<'a> {
+    fn set_client(&self, client: &'a dyn qspi::Client) {
+        self.client.set(client);
+    }
+
+    fn configure(&self, config: qspi::Config) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle || self.mapped.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        if !config.size.is_power_of_two() || config.size < 2 || config.frequency == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        let regs = &*self.registers;
+        self.enable_clock();
+
+        // The closest frequency that is not higher.
+        let prescaler = core::cmp::min(
+            (HCLK_FREQUENCY + config.frequency - 1) / config.frequency,
+            256,
+        ) - 1;
+        regs.cr.write(CR::EN::CLEAR);
+        regs.dcr.write(
+            DCR::FSIZE.val(config.size.trailing_zeros() - 1)
+                + DCR::CSHT.val(1)
+                + DCR::CKMODE::CLEAR,
+        );
+        regs.cr.write(
+            CR::PRESCALER.val(prescaler) + CR::FTHRES.val(0) + CR::SSHIFT::SET + CR::EN::SET,
+        );
+        self.config.set(config);
+        Ok(())
+    }
+
+    fn read(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(e) = self.check_transfer(address, buffer, len) {
+            return Err((e, buffer));
+        }
+        self.buffer.replace(buffer);
+        self.len.set(len);
+        self.index.set(0);
+        self.config.map(|config| {
+            self.command(
+                State::Read,
+                Self::read_command(config) + CCR::FMODE::IndirectRead,
+                Some(address),
+                len,
+                CR::TCIE::SET + CR::FTIE::SET,
+            )
+        });
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(e) = self.check_transfer(address, buffer, len) {
+            return Err((e, buffer));
+        }
+        if address % qspi::PROGRAM_PAGE_SIZE + len > qspi::PROGRAM_PAGE_SIZE {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        self.buffer.replace(buffer);
+        self.address.set(address);
+        self.len.set(len);
+        self.erase.clear();
+        self.write_enable();
+        Ok(())
+    }
+
+    fn erase(&self, address: usize, size: qspi::EraseSize) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle || self.mapped.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        let flash_size = self.config.map_or(0, |config| config.size);
+        if flash_size == 0 {
+            return Err(ErrorCode::OFF);
+        }
+        let alignment = match size {
+            qspi::EraseSize::Sector4K => 0x1000,
+            qspi::EraseSize::Block64K => 0x10000,
+            qspi::EraseSize::Chip => 1,
+        };
+        if size != qspi::EraseSize::Chip && (address % alignment != 0 || address >= flash_size) {
+            return Err(ErrorCode::INVAL);
+        }
+        self.address.set(address);
+        self.erase.set(size);
+        self.write_enable();
+        Ok(())
+    }
+
+    fn enable_memory_mapped(&self) -> Result<usize, ErrorCode> {
+        if self.state.get() != State::Idle || self.mapped.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.config.map_or(Err(ErrorCode::OFF), |config| {
+            let regs = &*self.registers;
+            regs.abr.set(0xFF);
+            regs.ccr
+                .write(Self::read_command(config) + CCR::FMODE::MemoryMapped);
+            self.mapped.set(true);
+            Ok(MEMORY_MAPPED_BASE)
+        })
+    }
+
+    fn disable_memory_mapped(&self) -> Result<(), ErrorCode> {
+        if !self.mapped.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let regs = &*self.registers;
+        regs.cr.modify(CR::ABORT::SET);
+        while regs.cr.is_set(CR::ABORT) {}
+        self.mapped.set(false);
+        Ok(())
+    }
+}
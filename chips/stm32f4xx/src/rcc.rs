@@ -215,6 +215,8 @@ register_bitfields![u32,
     AHB1RSTR [
         /// USB OTG HS module reset
         OTGHSRST OFFSET(29) NUMBITS(1) [],
+        /// Ethernet MAC reset
+        ETHMACRST OFFSET(25) NUMBITS(1) [],
         /// DMA2 reset
         DMA2RST OFFSET(22) NUMBITS(1) [],
         /// DMA2 reset
@@ -339,6 +341,14 @@ register_bitfields![u32,
         OTGHSULPIEN OFFSET(30) NUMBITS(1) [],
         /// USB OTG HS clock enable
         OTGHSEN OFFSET(29) NUMBITS(1) [],
+        /// Ethernet PTP clock enable
+        ETHMACPTPEN OFFSET(28) NUMBITS(1) [],
+        /// Ethernet Reception clock enable
+        ETHMACRXEN OFFSET(27) NUMBITS(1) [],
+        /// Ethernet Transmission clock enable
+        ETHMACTXEN OFFSET(26) NUMBITS(1) [],
+        /// Ethernet MAC clock enable
+        ETHMACEN OFFSET(25) NUMBITS(1) [],
         /// DMA2 clock enable
         DMA2EN OFFSET(22) NUMBITS(1) [],
         /// DMA1 clock enable
@@ -783,6 +793,34 @@ impl Rcc {
         self.registers.apb1enr.modify(APB1ENR::TIM2EN::CLEAR)
     }
 
+    // TIM3 clock
+
+    fn is_enabled_tim3_clock(&self) -> bool {
+        self.registers.apb1enr.is_set(APB1ENR::TIM3EN)
+    }
+
+    fn enable_tim3_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::TIM3EN::SET)
+    }
+
+    fn disable_tim3_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::TIM3EN::CLEAR)
+    }
+
+    // TIM5 clock
+
+    fn is_enabled_tim5_clock(&self) -> bool {
+        self.registers.apb1enr.is_set(APB1ENR::TIM5EN)
+    }
+
+    fn enable_tim5_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::TIM5EN::SET)
+    }
+
+    fn disable_tim5_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::TIM5EN::CLEAR)
+    }
+
     // SYSCFG clock
 
     fn is_enabled_syscfg_clock(&self) -> bool {
@@ -923,6 +961,29 @@ impl Rcc {
         self.registers.ahb1enr.modify(AHB1ENR::GPIOAEN::CLEAR)
     }
 
+    // Ethernet MAC clocks
+
+    fn is_enabled_ethmac_clock(&self) -> bool {
+        self.registers.ahb1enr.is_set(AHB1ENR::ETHMACEN)
+    }
+
+    fn enable_ethmac_clock(&self) {
+        self.registers
+            .ahb1enr
+            .modify(AHB1ENR::ETHMACEN::SET + AHB1ENR::ETHMACTXEN::SET + AHB1ENR::ETHMACRXEN::SET)
+    }
+
+    fn disable_ethmac_clock(&self) {
+        self.registers.ahb1enr.modify(
+            AHB1ENR::ETHMACEN::CLEAR + AHB1ENR::ETHMACTXEN::CLEAR + AHB1ENR::ETHMACRXEN::CLEAR,
+        )
+    }
+
+    fn reset_ethmac(&self) {
+        self.registers.ahb1rstr.modify(AHB1RSTR::ETHMACRST::SET);
+        self.registers.ahb1rstr.modify(AHB1RSTR::ETHMACRST::CLEAR);
+    }
+
     // FMC
 
     fn is_enabled_fmc_clock(&self) -> bool {
@@ -937,6 +998,20 @@ impl Rcc {
         self.registers.ahb3enr.modify(AHB3ENR::FMCEN::CLEAR)
     }
 
+    // QSPI
+
+    fn is_enabled_qspi_clock(&self) -> bool {
+        self.registers.ahb3enr.is_set(AHB3ENR::QSPIEN)
+    }
+
+    fn enable_qspi_clock(&self) {
+        self.registers.ahb3enr.modify(AHB3ENR::QSPIEN::SET)
+    }
+
+    fn disable_qspi_clock(&self) {
+        self.registers.ahb3enr.modify(AHB3ENR::QSPIEN::CLEAR)
+    }
+
     // USART2 clock
 
     fn is_enabled_usart2_clock(&self) -> bool {
@@ -965,6 +1040,20 @@ impl Rcc {
         self.registers.apb1enr.modify(APB1ENR::USART3EN::CLEAR)
     }
 
+    // CAN1 clock
+
+    fn is_enabled_can1_clock(&self) -> bool {
+        self.registers.apb1enr.is_set(APB1ENR::CAN1EN)
+    }
+
+    fn enable_can1_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::CAN1EN::SET)
+    }
+
+    fn disable_can1_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::CAN1EN::CLEAR)
+    }
+
     // ADC1 clock
 
     fn is_enabled_adc1_clock(&self) -> bool {
@@ -1006,6 +1095,53 @@ impl Rcc {
     fn disable_otgfs_clock(&self) {
         self.registers.ahb2enr.modify(AHB2ENR::OTGFSEN::CLEAR);
     }
+
+    // PWR clock
+
+    fn is_enabled_pwr_clock(&self) -> bool {
+        self.registers.apb1enr.is_set(APB1ENR::PWREN)
+    }
+
+    fn enable_pwr_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::PWREN::SET);
+    }
+
+    fn disable_pwr_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::PWREN::CLEAR);
+    }
+
+    // RTC clock, in the backup domain
+
+    fn is_enabled_rtc_clock(&self) -> bool {
+        self.registers.bdcr.is_set(BDCR::RTCEN)
+    }
+
+    /// Start the oscillator for the RTC and enable its clock. The backup
+    /// domain must be writable.
+    fn configure_rtc_clock(&self, source: RtcClockSource) {
+        match source {
+            RtcClockSource::LSE => {
+                self.registers.bdcr.modify(BDCR::LSEON::SET);
+                while !self.registers.bdcr.is_set(BDCR::LSERDY) {}
+                self.registers.bdcr.modify(BDCR::RTCSEL.val(0b01));
+            }
+            RtcClockSource::LSI => {
+                self.registers.csr.modify(CSR::LSION::SET);
+                while !self.registers.csr.is_set(CSR::LSIRDY) {}
+                self.registers.bdcr.modify(BDCR::RTCSEL.val(0b10));
+            }
+        }
+        self.registers.bdcr.modify(BDCR::RTCEN::SET);
+    }
+}
+
+/// Clock sources for the RTC
+#[derive(Copy, Clone, PartialEq)]
+pub enum RtcClockSource {
+    /// 32.768 kHz external crystal
+    LSE,
+    /// 32 kHz internal oscillator, less accurate
+    LSI,
 }
 
 /// Clock sources for CPU
@@ -1033,6 +1169,7 @@ pub enum PeripheralClockType {
 /// Peripherals clocked by HCLK1
 pub enum HCLK1 {
     DMA1,
+    ETHMAC,
     GPIOH,
     GPIOG,
     GPIOF,
@@ -1046,6 +1183,7 @@ pub enum HCLK1 {
 /// Peripherals clocked by HCLK3
 pub enum HCLK3 {
     FMC,
+    QSPI,
 }
 
 /// Peripherals clocked by HCLK2
@@ -1057,10 +1195,14 @@ pub enum HCLK2 {
 /// Peripherals clocked by PCLK1
 pub enum PCLK1 {
     TIM2,
+    TIM3,
+    TIM5,
     USART2,
     USART3,
     SPI3,
     I2C1,
+    CAN1,
+    PWR,
 }
 
 /// Peripherals clocked by PCLK2
@@ -1077,6 +1219,20 @@ impl<'a> PeripheralClock<'a> {
     pub fn configure_rng_clock(&self) {
         self.rcc.configure_rng_clock();
     }
+
+    /// Reset the Ethernet MAC, which must be done after selecting its PHY
+    /// interface.
+    pub fn reset_ethmac(&self) {
+        self.rcc.reset_ethmac();
+    }
+
+    pub fn is_enabled_rtc_clock(&self) -> bool {
+        self.rcc.is_enabled_rtc_clock()
+    }
+
+    pub fn configure_rtc_clock(&self, source: RtcClockSource) {
+        self.rcc.configure_rtc_clock(source);
+    }
 }
 
 impl<'a> ClockInterface for PeripheralClock<'a> {
@@ -1084,6 +1240,7 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
         match self.clock {
             PeripheralClockType::AHB1(ref v) => match v {
                 HCLK1::DMA1 => self.rcc.is_enabled_dma1_clock(),
+                HCLK1::ETHMAC => self.rcc.is_enabled_ethmac_clock(),
                 HCLK1::GPIOH => self.rcc.is_enabled_gpioh_clock(),
                 HCLK1::GPIOG => self.rcc.is_enabled_gpiog_clock(),
                 HCLK1::GPIOF => self.rcc.is_enabled_gpiof_clock(),
@@ -1099,13 +1256,18 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
             },
             PeripheralClockType::AHB3(ref v) => match v {
                 HCLK3::FMC => self.rcc.is_enabled_fmc_clock(),
+                HCLK3::QSPI => self.rcc.is_enabled_qspi_clock(),
             },
             PeripheralClockType::APB1(ref v) => match v {
                 PCLK1::TIM2 => self.rcc.is_enabled_tim2_clock(),
+                PCLK1::TIM3 => self.rcc.is_enabled_tim3_clock(),
+                PCLK1::TIM5 => self.rcc.is_enabled_tim5_clock(),
                 PCLK1::USART2 => self.rcc.is_enabled_usart2_clock(),
                 PCLK1::USART3 => self.rcc.is_enabled_usart3_clock(),
                 PCLK1::I2C1 => self.rcc.is_enabled_i2c1_clock(),
                 PCLK1::SPI3 => self.rcc.is_enabled_spi3_clock(),
+                PCLK1::CAN1 => self.rcc.is_enabled_can1_clock(),
+                PCLK1::PWR => self.rcc.is_enabled_pwr_clock(),
             },
             PeripheralClockType::APB2(ref v) => match v {
                 PCLK2::ADC1 => self.rcc.is_enabled_adc1_clock(),
@@ -1120,6 +1282,9 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
                 HCLK1::DMA1 => {
                     self.rcc.enable_dma1_clock();
                 }
+                HCLK1::ETHMAC => {
+                    self.rcc.enable_ethmac_clock();
+                }
                 HCLK1::GPIOH => {
                     self.rcc.enable_gpioh_clock();
                 }
@@ -1155,11 +1320,18 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
             },
             PeripheralClockType::AHB3(ref v) => match v {
                 HCLK3::FMC => self.rcc.enable_fmc_clock(),
+                HCLK3::QSPI => self.rcc.enable_qspi_clock(),
             },
             PeripheralClockType::APB1(ref v) => match v {
                 PCLK1::TIM2 => {
                     self.rcc.enable_tim2_clock();
                 }
+                PCLK1::TIM3 => {
+                    self.rcc.enable_tim3_clock();
+                }
+                PCLK1::TIM5 => {
+                    self.rcc.enable_tim5_clock();
+                }
                 PCLK1::USART2 => {
                     self.rcc.enable_usart2_clock();
                 }
@@ -1172,6 +1344,12 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
                 PCLK1::SPI3 => {
                     self.rcc.enable_spi3_clock();
                 }
+                PCLK1::CAN1 => {
+                    self.rcc.enable_can1_clock();
+                }
+                PCLK1::PWR => {
+                    self.rcc.enable_pwr_clock();
+                }
             },
             PeripheralClockType::APB2(ref v) => match v {
                 PCLK2::ADC1 => {
@@ -1190,6 +1368,9 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
                 HCLK1::DMA1 => {
                     self.rcc.disable_dma1_clock();
                 }
+                HCLK1::ETHMAC => {
+                    self.rcc.disable_ethmac_clock();
+                }
                 HCLK1::GPIOH => {
                     self.rcc.disable_gpioh_clock();
                 }
@@ -1225,11 +1406,18 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
             },
             PeripheralClockType::AHB3(ref v) => match v {
                 HCLK3::FMC => self.rcc.disable_fmc_clock(),
+                HCLK3::QSPI => self.rcc.disable_qspi_clock(),
             },
             PeripheralClockType::APB1(ref v) => match v {
                 PCLK1::TIM2 => {
                     self.rcc.disable_tim2_clock();
                 }
+                PCLK1::TIM3 => {
+                    self.rcc.disable_tim3_clock();
+                }
+                PCLK1::TIM5 => {
+                    self.rcc.disable_tim5_clock();
+                }
                 PCLK1::USART2 => {
                     self.rcc.disable_usart2_clock();
                 }
@@ -1242,6 +1430,12 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
                 PCLK1::SPI3 => {
                     self.rcc.disable_spi3_clock();
                 }
+                PCLK1::CAN1 => {
+                    self.rcc.disable_can1_clock();
+                }
+                PCLK1::PWR => {
+                    self.rcc.disable_pwr_clock();
+                }
             },
             PeripheralClockType::APB2(ref v) => match v {
                 PCLK2::ADC1 => {
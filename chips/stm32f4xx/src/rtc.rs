@@ -0,0 +1,336 @@
+//! Real-time clock
+//!
+//! The RTC keeps the date and time in BCD calendar registers, clocked from
+//! a 32 kHz oscillator. It sits in the backup domain, so once started it
+//! keeps counting across resets of the rest of the chip. Alarm A provides the
+//! `DateTime` alarm; it compares the day of the month, hour, minute and
+//! second, and reaches the NVIC through EXTI line 17.
+//!
+//! The calendar only counts years `2000` to `2099`.
+
+use crate::exti;
+use crate::rcc;
+use kernel::common::cells::OptionalCell;
+use kernel::common::registers::interfaces::{ReadWriteable, Readable, Writeable};
+use kernel::common::registers::{register_bitfields, ReadWrite};
+use kernel::common::StaticRef;
+use kernel::hil::date_time::{self, DateTimeValues};
+use kernel::ClockInterface;
+use kernel::ErrorCode;
+
+#[repr(C)]
+struct RtcRegisters {
+    /// time register
+    tr: ReadWrite<u32, TR::Register>,
+    /// date register
+    dr: ReadWrite<u32, DR::Register>,
+    /// control register
+    cr: ReadWrite<u32, CR::Register>,
+    /// initialization and status register
+    isr: ReadWrite<u32, ISR::Register>,
+    /// prescaler register
+    prer: ReadWrite<u32, PRER::Register>,
+    /// wakeup timer register
+    wutr: ReadWrite<u32>,
+    /// calibration register
+    calibr: ReadWrite<u32>,
+    /// alarm A register
+    alrmar: ReadWrite<u32, ALRMAR::Register>,
+    /// alarm B register
+    alrmbr: ReadWrite<u32, ALRMAR::Register>,
+    /// write protection register
+    wpr: ReadWrite<u32, WPR::Register>,
+}
+
+#[repr(C)]
+struct PwrRegisters {
+    /// power control register
+    cr: ReadWrite<u32, PWR_CR::Register>,
+    /// power control/status register
+    csr: ReadWrite<u32>,
+}
+
+register_bitfields![u32,
+    TR [
+        /// AM/PM notation
+        PM OFFSET(22) NUMBITS(1) [],
+        /// Hour tens
+        HT OFFSET(20) NUMBITS(2) [],
+        /// Hour units
+        HU OFFSET(16) NUMBITS(4) [],
+        /// Minute tens
+        MNT OFFSET(12) NUMBITS(3) [],
+        /// Minute units
+        MNU OFFSET(8) NUMBITS(4) [],
+        /// Second tens
+        ST OFFSET(4) NUMBITS(3) [],
+        /// Second units
+        SU OFFSET(0) NUMBITS(4) []
+    ],
+    DR [
+        /// Year tens
+        YT OFFSET(20) NUMBITS(4) [],
+        /// Year units
+        YU OFFSET(16) NUMBITS(4) [],
+        /// Week day units, 1 for Monday
+        WDU OFFSET(13) NUMBITS(3) [],
+        /// Month tens
+        MT OFFSET(12) NUMBITS(1) [],
+        /// Month units
+        MU OFFSET(8) NUMBITS(4) [],
+        /// Date tens
+        DT OFFSET(4) NUMBITS(2) [],
+        /// Date units
+        DU OFFSET(0) NUMBITS(4) []
+    ],
+    CR [
+        /// Alarm A interrupt enable
+        ALRAIE OFFSET(12) NUMBITS(1) [],
+        /// Alarm A enable
+        ALRAE OFFSET(8) NUMBITS(1) [],
+        /// Hour format, 12 hours if set
+        FMT OFFSET(6) NUMBITS(1) [],
+        /// Bypass the shadow registers
+        BYPSHAD OFFSET(5) NUMBITS(1) []
+    ],
+    ISR [
+        /// Alarm A flag
+        ALRAF OFFSET(8) NUMBITS(1) [],
+        /// Initialization mode
+        INIT OFFSET(7) NUMBITS(1) [],
+        /// Initialization flag
+        INITF OFFSET(6) NUMBITS(1) [],
+        /// Registers synchronization flag
+        RSF OFFSET(5) NUMBITS(1) [],
+        /// Initialization status flag
+        INITS OFFSET(4) NUMBITS(1) [],
+        /// Alarm A write flag
+        ALRAWF OFFSET(0) NUMBITS(1) []
+    ],
+    PRER [
+        /// Asynchronous prescaler factor
+        PREDIV_A OFFSET(16) NUMBITS(7) [],
+        /// Synchronous prescaler factor
+        PREDIV_S OFFSET(0) NUMBITS(15) []
+    ],
+    ALRMAR [
+        /// Date mask
+        MSK4 OFFSET(31) NUMBITS(1) [],
+        /// Week day selection
+        WDSEL OFFSET(30) NUMBITS(1) [],
+        /// Date tens
+        DT OFFSET(28) NUMBITS(2) [],
+        /// Date units
+        DU OFFSET(24) NUMBITS(4) [],
+        /// Hours mask
+        MSK3 OFFSET(23) NUMBITS(1) [],
+        /// AM/PM notation
+        PM OFFSET(22) NUMBITS(1) [],
+        /// Hour tens
+        HT OFFSET(20) NUMBITS(2) [],
+        /// Hour units
+        HU OFFSET(16) NUMBITS(4) [],
+        /// Minutes mask
+        MSK2 OFFSET(15) NUMBITS(1) [],
+        /// Minute tens
+        MNT OFFSET(12) NUMBITS(3) [],
+        /// Minute units
+        MNU OFFSET(8) NUMBITS(4) [],
+        /// Seconds mask
+        MSK1 OFFSET(7) NUMBITS(1) [],
+        /// Second tens
+        ST OFFSET(4) NUMBITS(3) [],
+        /// Second units
+        SU OFFSET(0) NUMBITS(4) []
+    ],
+    WPR [
+        /// Write protection key
+        KEY OFFSET(0) NUMBITS(8) []
+    ],
+    PWR_CR [
+        /// Disable backup domain write protection
+        DBP OFFSET(8) NUMBITS(1) []
+    ]
+];
+
+const RTC_BASE: StaticRef<RtcRegisters> =
+    unsafe { StaticRef::new(0x4000_2800 as *const RtcRegisters) };
+
+const PWR_BASE: StaticRef<PwrRegisters> =
+    unsafe { StaticRef::new(0x4000_7000 as *const PwrRegisters) };
+
+fn to_bcd(value: u8) -> (u32, u32) {
+    ((value / 10) as u32, (value % 10) as u32)
+}
+
+fn from_bcd(tens: u32, units: u32) -> u8 {
+    (tens * 10 + units) as u8
+}
+
+pub struct Rtc<'a> {
+    registers: StaticRef<RtcRegisters>,
+    pwr: StaticRef<PwrRegisters>,
+    clock: rcc::PeripheralClock<'a>,
+    exti: &'a exti::Exti<'a>,
+    client: OptionalCell<&'a dyn date_time::DateTimeClient>,
+}
+
+impl<'a> Rtc<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc, exti: &'a exti::Exti<'a>) -> Rtc<'a> {
+        Rtc {
+            registers: RTC_BASE,
+            pwr: PWR_BASE,
+            clock: rcc::PeripheralClock::new(rcc::PeripheralClockType::APB1(rcc::PCLK1::PWR), rcc),
+            exti: exti,
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Give access to the RTC, and start it from `source` unless it has kept
+    /// running since before the last reset.
+    pub fn init(&self, source: rcc::RtcClockSource) {
+        self.clock.enable();
+        self.pwr.cr.modify(PWR_CR::DBP::SET);
+
+        // Unlock the RTC registers.
+        self.registers.wpr.write(WPR::KEY.val(0xCA));
+        self.registers.wpr.write(WPR::KEY.val(0x53));
+
+        if !self.clock.is_enabled_rtc_clock() {
+            self.clock.configure_rtc_clock(source);
+            // Divide the oscillator down to 1 Hz.
+            let prediv_s = match source {
+                rcc::RtcClockSource::LSE => 255,
+                rcc::RtcClockSource::LSI => 249,
+            };
+            self.enter_init_mode();
+            self.registers
+                .prer
+                .write(PRER::PREDIV_A.val(127) + PRER::PREDIV_S.val(prediv_s));
+            self.registers
+                .cr
+                .modify(CR::FMT::CLEAR + CR::BYPSHAD::CLEAR);
+            self.exit_init_mode();
+        }
+    }
+
+    fn enter_init_mode(&self) {
+        self.registers.isr.modify(ISR::INIT::SET);
+        while !self.registers.isr.is_set(ISR::INITF) {}
+    }
+
+    fn exit_init_mode(&self) {
+        // The shadow registers are stale until they resynchronize.
+        self.registers
+            .isr
+            .modify(ISR::INIT::CLEAR + ISR::RSF::CLEAR);
+    }
+
+    fn disable_alarm(&self) {
+        self.registers
+            .cr
+            .modify(CR::ALRAE::CLEAR + CR::ALRAIE::CLEAR);
+        self.exti.disable_rtc_alarm_line();
+    }
+
+    pub fn handle_interrupt(&self) {
+        if self.registers.isr.is_set(ISR::ALRAF) {
+            self.registers.isr.modify(ISR::ALRAF::CLEAR);
+            self.exti.clear_rtc_alarm_pending();
+            self.disable_alarm();
+            self.client.map(|client| client.alarm());
+        }
+    }
+}
+
+impl<'a> date_time::DateTime<'a> for Rtc<'a> {
+    fn get_date_time(&self) -> Result<DateTimeValues, ErrorCode> {
+        if !self.registers.isr.is_set(ISR::INITS) {
+            // The calendar has never been set.
+            return Err(ErrorCode::OFF);
+        }
+        while !self.registers.isr.is_set(ISR::RSF) {}
+        // Reading TR locks DR until it is read, so the two match.
+        let tr = self.registers.tr.extract();
+        let dr = self.registers.dr.extract();
+        Ok(DateTimeValues {
+            year: 2000 + from_bcd(dr.read(DR::YT), dr.read(DR::YU)) as u16,
+            month: from_bcd(dr.read(DR::MT), dr.read(DR::MU)),
+            day: from_bcd(dr.read(DR::DT), dr.read(DR::DU)),
+            hour: from_bcd(tr.read(TR::HT), tr.read(TR::HU)),
+            minute: from_bcd(tr.read(TR::MNT), tr.read(TR::MNU)),
+            second: from_bcd(tr.read(TR::ST), tr.read(TR::SU)),
+        })
+    }
+
+    fn set_date_time(&self, date_time: DateTimeValues) -> Result<(), ErrorCode> {
+        if !date_time.is_valid() || date_time.year < 2000 || date_time.year > 2099 {
+            return Err(ErrorCode::INVAL);
+        }
+        let (yt, yu) = to_bcd((date_time.year - 2000) as u8);
+        let (mt, mu) = to_bcd(date_time.month);
+        let (dt, du) = to_bcd(date_time.day);
+        let (ht, hu) = to_bcd(date_time.hour);
+        let (mnt, mnu) = to_bcd(date_time.minute);
+        let (st, su) = to_bcd(date_time.second);
+
+        self.enter_init_mode();
+        self.registers.tr.write(
+            TR::HT.val(ht)
+                + TR::HU.val(hu)
+                + TR::MNT.val(mnt)
+                + TR::MNU.val(mnu)
+                + TR::ST.val(st)
+                + TR::SU.val(su),
+        );
+        self.registers.dr.write(
+            DR::YT.val(yt)
+                + DR::YU.val(yu)
+                + DR::WDU.val(date_time.day_of_week() as u32)
+                + DR::MT.val(mt)
+                + DR::MU.val(mu)
+                + DR::DT.val(dt)
+                + DR::DU.val(du),
+        );
+        self.exit_init_mode();
+        Ok(())
+    }
+
+    fn set_alarm(&self, at: DateTimeValues) -> Result<(), ErrorCode> {
+        if !at.is_valid() {
+            return Err(ErrorCode::INVAL);
+        }
+        let (dt, du) = to_bcd(at.day);
+        let (ht, hu) = to_bcd(at.hour);
+        let (mnt, mnu) = to_bcd(at.minute);
+        let (st, su) = to_bcd(at.second);
+
+        self.disable_alarm();
+        while !self.registers.isr.is_set(ISR::ALRAWF) {}
+        // Compare the day of the month, hour, minute and second.
+        self.registers.alrmar.write(
+            ALRMAR::DT.val(dt)
+                + ALRMAR::DU.val(du)
+                + ALRMAR::HT.val(ht)
+                + ALRMAR::HU.val(hu)
+                + ALRMAR::MNT.val(mnt)
+                + ALRMAR::MNU.val(mnu)
+                + ALRMAR::ST.val(st)
+                + ALRMAR::SU.val(su),
+        );
+        self.registers.isr.modify(ISR::ALRAF::CLEAR);
+        self.exti.clear_rtc_alarm_pending();
+        self.exti.enable_rtc_alarm_line();
+        self.registers.cr.modify(CR::ALRAE::SET + CR::ALRAIE::SET);
+        Ok(())
+    }
+
+    fn disarm(&self) -> Result<(), ErrorCode> {
+        self.disable_alarm();
+        Ok(())
+    }
+
+    fn set_client(&self, client: &'a dyn date_time::DateTimeClient) {
+        self.client.set(client);
+    }
+}
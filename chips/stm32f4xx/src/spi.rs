@@ -438,10 +438,18 @@ impl<'a> spi::SpiMaster for Spi<'a> {
 
     fn hold_low(&self) {
         self.active_after.set(true);
+        self.active_slave.map(|p| {
+            p.clear();
+        });
     }
 
     fn release_low(&self) {
         self.active_after.set(false);
+        if self.transfers_in_progress.get() == 0 {
+            self.active_slave.map(|p| {
+                p.set();
+            });
+        }
     }
 
     fn specify_chip_select(&self, cs: Self::ChipSelect) {
@@ -143,6 +143,14 @@ impl<'a> Syscfg<'a> {
         self.clock.disable();
     }
 
+    /// Select the RMII or the MII interface to the Ethernet PHY. Only takes
+    /// effect once the MAC is reset.
+    pub fn select_ethernet_rmii(&self, rmii: bool) {
+        self.registers
+            .pmc
+            .modify(PMC::MII_RMII_SEL.val(rmii as u32));
+    }
+
     /// Configures the SYSCFG_EXTICR{1, 2, 3, 4} registers
     pub fn configure_interrupt(&self, pinid: gpio::PinId) {
         let exticrid = self.get_exticrid_from_port_num(pinid.get_port_number());
@@ -0,0 +1,253 @@
+//! TIM3 general purpose timer, as a quadrature decoder.
+//!
+//! The timer runs in encoder mode 3: its 16-bit counter counts up or down on
+//! every edge of TI1 and TI2, depending on the level of the other input. The
+//! pins of channels 1 and 2 (for example PA6 and PA7) must be set to the
+//! alternate function of TIM3 by the board.
+//!
+//! Steps are the difference of the counter since the previous take. To keep
+//! that difference unambiguous, compare channels 3 and 4 are set a quarter
+//! of the counter range above and below the counter at each take, and the
+//! client is told steps are ready when the counter reaches either of them.
+//! The counter wrapping around is not an overflow, so `overflow` is never
+//! reported.
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::common::registers::interfaces::{ReadWriteable, Readable, Writeable};
+use kernel::common::registers::{register_bitfields, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::qdec;
+use kernel::ClockInterface;
+use kernel::ErrorCode;
+
+use crate::rcc;
+
+/// General purpose timers
+#[repr(C)]
+struct Tim3Registers {
+    /// control register 1
+    cr1: ReadWrite<u32, CR1::Register>,
+    /// control register 2
+    cr2: ReadWrite<u32>,
+    /// slave mode control register
+    smcr: ReadWrite<u32, SMCR::Register>,
+    /// DMA/Interrupt enable register
+    dier: ReadWrite<u32, DIER::Register>,
+    /// status register
+    sr: ReadWrite<u32, SR::Register>,
+    /// event generation register
+    egr: WriteOnly<u32, EGR::Register>,
+    /// capture/compare mode register 1 (input mode)
+    ccmr1_input: ReadWrite<u32, CCMR1_Input::Register>,
+    /// capture/compare mode register 2 (output mode)
+    ccmr2_output: ReadWrite<u32>,
+    /// capture/compare enable register
+    ccer: ReadWrite<u32, CCER::Register>,
+    /// counter
+    cnt: ReadWrite<u32>,
+    /// prescaler
+    psc: ReadWrite<u32>,
+    /// auto-reload register
+    arr: ReadWrite<u32>,
+    _reserved0: [u8; 4],
+    /// capture/compare register 1
+    ccr1: ReadWrite<u32>,
+    /// capture/compare register 2
+    ccr2: ReadWrite<u32>,
+    /// capture/compare register 3
+    ccr3: ReadWrite<u32>,
+    /// capture/compare register 4
+    ccr4: ReadWrite<u32>,
+}
+
+register_bitfields![u32,
+    CR1 [
+        /// Counter enable
+        CEN OFFSET(0) NUMBITS(1) []
+    ],
+    SMCR [
+        /// Slave mode selection
+        SMS OFFSET(0) NUMBITS(3) [
+            Disabled = 0b000,
+            /// Counts on both TI1FP1 and TI2FP2 edges
+            EncoderMode3 = 0b011
+        ]
+    ],
+    DIER [
+        /// Capture/Compare 4 interrupt enable
+        CC4IE OFFSET(4) NUMBITS(1) [],
+        /// Capture/Compare 3 interrupt enable
+        CC3IE OFFSET(3) NUMBITS(1) []
+    ],
+    SR [
+        /// Capture/Compare 4 interrupt flag
+        CC4IF OFFSET(4) NUMBITS(1) [],
+        /// Capture/Compare 3 interrupt flag
+        CC3IF OFFSET(3) NUMBITS(1) []
+    ],
+    EGR [
+        /// Update generation
+        UG OFFSET(0) NUMBITS(1) []
+    ],
+    CCMR1_Input [
+        /// Input capture 2 filter
+        IC2F OFFSET(12) NUMBITS(4) [],
+        /// Capture/Compare 2 selection
+        CC2S OFFSET(8) NUMBITS(2) [
+            /// IC2 is mapped on TI2
+            TI2 = 0b01
+        ],
+        /// Input capture 1 filter
+        IC1F OFFSET(4) NUMBITS(4) [],
+        /// Capture/Compare 1 selection
+        CC1S OFFSET(0) NUMBITS(2) [
+            /// IC1 is mapped on TI1
+            TI1 = 0b01
+        ]
+    ],
+    CCER [
+        /// Capture/Compare 2 output Polarity
+        CC2P OFFSET(5) NUMBITS(1) [],
+        /// Capture/Compare 1 output Polarity
+        CC1P OFFSET(1) NUMBITS(1) []
+    ]
+];
+
+const TIM3_BASE: StaticRef<Tim3Registers> =
+    unsafe { StaticRef::new(0x40000400 as *const Tim3Registers) };
+
+/// Distance of the compare channels from the counter at a take.
+const READY_DISTANCE: u16 = 0x4000;
+
+/// Input filter of 8 samples at fCK_INT / 8, against contact bounce.
+const INPUT_FILTER: u32 = 0b1001;
+
+pub struct Tim3<'a> {
+    registers: StaticRef<Tim3Registers>,
+    clock: Tim3Clock<'a>,
+    client: OptionalCell<&'a dyn qdec::Client>,
+    /// The counter at the previous take.
+    last_count: Cell<u16>,
+}
+
+impl<'a> Tim3<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc) -> Self {
+        Self {
+            registers: TIM3_BASE,
+            clock: Tim3Clock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::APB1(rcc::PCLK1::TIM3),
+                rcc,
+            )),
+            client: OptionalCell::empty(),
+            last_count: Cell::new(0),
+        }
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled()
+    }
+
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+    }
+
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+    }
+
+    pub fn handle_interrupt(&self) {
+        if self.registers.sr.is_set(SR::CC3IF) || self.registers.sr.is_set(SR::CC4IF) {
+            self.registers
+                .sr
+                .modify(SR::CC3IF::CLEAR + SR::CC4IF::CLEAR);
+            self.client.map(|client| client.steps_ready());
+        }
+    }
+
+    fn set_ready_compares(&self, count: u16) {
+        self.registers
+            .ccr3
+            .set(count.wrapping_add(READY_DISTANCE) as u32);
+        self.registers
+            .ccr4
+            .set(count.wrapping_sub(READY_DISTANCE) as u32);
+    }
+}
+
+impl<'a> qdec::Qdec<'a> for Tim3<'a> {
+    fn set_client(&self, client: &'a dyn qdec::Client) {
+        self.client.set(client);
+    }
+
+    fn enable(&self) -> Result<(), ErrorCode> {
+        if self.is_enabled() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.enable_clock();
+
+        self.registers.ccmr1_input.write(
+            CCMR1_Input::CC1S::TI1
+                + CCMR1_Input::CC2S::TI2
+                + CCMR1_Input::IC1F.val(INPUT_FILTER)
+                + CCMR1_Input::IC2F.val(INPUT_FILTER),
+        );
+        // Non-inverted inputs
+        self.registers
+            .ccer
+            .write(CCER::CC1P::CLEAR + CCER::CC2P::CLEAR);
+        self.registers.smcr.write(SMCR::SMS::EncoderMode3);
+        self.registers.arr.set(0xFFFF);
+        self.registers.psc.set(0);
+        self.registers.egr.write(EGR::UG::SET);
+
+        self.registers.cnt.set(0);
+        self.last_count.set(0);
+        self.set_ready_compares(0);
+        self.registers.sr.set(0);
+        self.registers
+            .dier
+            .modify(DIER::CC3IE::SET + DIER::CC4IE::SET);
+        self.registers.cr1.modify(CR1::CEN::SET);
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        self.registers.cr1.modify(CR1::CEN::CLEAR);
+        self.registers
+            .dier
+            .modify(DIER::CC3IE::CLEAR + DIER::CC4IE::CLEAR);
+        self.registers.smcr.write(SMCR::SMS::Disabled);
+        self.registers.sr.set(0);
+        self.disable_clock();
+        Ok(())
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.is_enabled_clock() && self.registers.cr1.is_set(CR1::CEN)
+    }
+
+    fn take_steps(&self) -> i32 {
+        let count = self.registers.cnt.get() as u16;
+        let steps = count.wrapping_sub(self.last_count.get()) as i16;
+        self.last_count.set(count);
+        self.set_ready_compares(count);
+        steps as i32
+    }
+}
+
+struct Tim3Clock<'a>(rcc::PeripheralClock<'a>);
+
+impl ClockInterface for Tim3Clock<'_> {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
@@ -0,0 +1,249 @@
+//! TIM5 general purpose timer, as the input capture of a PWM signal.
+//!
+//! The timer runs in PWM input mode on channel 1: a rising edge of TI1
+//! captures the counter into CCR1 and resets it, and a falling edge
+//! captures it into CCR2. CCR1 then holds the period of the signal, and
+//! CCR2 the time it was high. The pin of channel 1 (for example PA0) must
+//! be set to the alternate function of TIM5 by the board.
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::common::registers::interfaces::{ReadWriteable, Readable, Writeable};
+use kernel::common::registers::{register_bitfields, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::pwm_capture;
+use kernel::ClockInterface;
+use kernel::ErrorCode;
+
+use crate::rcc;
+
+/// General purpose timers
+#[repr(C)]
+struct Tim5Registers {
+    /// control register 1
+    cr1: ReadWrite<u32, CR1::Register>,
+    /// control register 2
+    cr2: ReadWrite<u32>,
+    /// slave mode control register
+    smcr: ReadWrite<u32, SMCR::Register>,
+    /// DMA/Interrupt enable register
+    dier: ReadWrite<u32, DIER::Register>,
+    /// status register
+    sr: ReadWrite<u32, SR::Register>,
+    /// event generation register
+    egr: WriteOnly<u32, EGR::Register>,
+    /// capture/compare mode register 1 (input mode)
+    ccmr1_input: ReadWrite<u32, CCMR1_Input::Register>,
+    /// capture/compare mode register 2
+    ccmr2: ReadWrite<u32>,
+    /// capture/compare enable register
+    ccer: ReadWrite<u32, CCER::Register>,
+    /// counter
+    cnt: ReadWrite<u32>,
+    /// prescaler
+    psc: ReadWrite<u32>,
+    /// auto-reload register
+    arr: ReadWrite<u32>,
+    _reserved0: [u8; 4],
+    /// capture/compare register 1
+    ccr1: ReadWrite<u32>,
+    /// capture/compare register 2
+    ccr2: ReadWrite<u32>,
+}
+
+register_bitfields![u32,
+    CR1 [
+        /// Counter enable
+        CEN OFFSET(0) NUMBITS(1) []
+    ],
+    SMCR [
+        /// Trigger selection
+        TS OFFSET(4) NUMBITS(3) [
+            /// Filtered timer input 1
+            TI1FP1 = 0b101
+        ],
+        /// Slave mode selection
+        SMS OFFSET(0) NUMBITS(3) [
+            Disabled = 0b000,
+            /// The trigger reinitializes the counter
+            Reset = 0b100
+        ]
+    ],
+    DIER [
+        /// Capture/Compare 1 interrupt enable
+        CC1IE OFFSET(1) NUMBITS(1) []
+    ],
+    SR [
+        /// Capture/Compare 2 overcapture flag
+        CC2OF OFFSET(10) NUMBITS(1) [],
+        /// Capture/Compare 1 overcapture flag
+        CC1OF OFFSET(9) NUMBITS(1) [],
+        /// Capture/Compare 2 interrupt flag
+        CC2IF OFFSET(2) NUMBITS(1) [],
+        /// Capture/compare 1 interrupt flag
+        CC1IF OFFSET(1) NUMBITS(1) []
+    ],
+    EGR [
+        /// Update generation
+        UG OFFSET(0) NUMBITS(1) []
+    ],
+    CCMR1_Input [
+        /// Input capture 2 filter
+        IC2F OFFSET(12) NUMBITS(4) [],
+        /// Capture/Compare 2 selection
+        CC2S OFFSET(8) NUMBITS(2) [
+            /// IC2 is mapped on TI1
+            TI1 = 0b10
+        ],
+        /// Input capture 1 filter
+        IC1F OFFSET(4) NUMBITS(4) [],
+        /// Capture/Compare 1 selection
+        CC1S OFFSET(0) NUMBITS(2) [
+            /// IC1 is mapped on TI1
+            TI1 = 0b01
+        ]
+    ],
+    CCER [
+        /// Capture/Compare 2 output Polarity
+        CC2NP OFFSET(7) NUMBITS(1) [],
+        /// Capture/Compare 2 output Polarity
+        CC2P OFFSET(5) NUMBITS(1) [],
+        /// Capture/Compare 2 output enable
+        CC2E OFFSET(4) NUMBITS(1) [],
+        /// Capture/Compare 1 output Polarity
+        CC1NP OFFSET(3) NUMBITS(1) [],
+        /// Capture/Compare 1 output Polarity
+        CC1P OFFSET(1) NUMBITS(1) [],
+        /// Capture/Compare 1 output enable
+        CC1E OFFSET(0) NUMBITS(1) []
+    ]
+];
+
+const TIM5_BASE: StaticRef<Tim5Registers> =
+    unsafe { StaticRef::new(0x40000C00 as *const Tim5Registers) };
+
+/// Frequency the counter runs at: the 16 MHz PCLK1 divided by 16.
+const CAPTURE_FREQUENCY: u32 = 1_000_000;
+
+pub struct Tim5<'a> {
+    registers: StaticRef<Tim5Registers>,
+    clock: Tim5Clock<'a>,
+    client: OptionalCell<&'a dyn pwm_capture::Client>,
+    /// Whether a rising edge has been captured since the capture started,
+    /// as the first one does not end a whole period.
+    started: Cell<bool>,
+}
+
+impl<'a> Tim5<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc) -> Self {
+        Self {
+            registers: TIM5_BASE,
+            clock: Tim5Clock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::APB1(rcc::PCLK1::TIM5),
+                rcc,
+            )),
+            client: OptionalCell::empty(),
+            started: Cell::new(false),
+        }
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled()
+    }
+
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+    }
+
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+    }
+
+    pub fn handle_interrupt(&self) {
+        if !self.registers.sr.is_set(SR::CC1IF) {
+            return;
+        }
+        // Reading CCR1 clears CC1IF. A period that was overwritten by the
+        // next one before it was read is lost, but the one read is whole.
+        let period = self.registers.ccr1.get();
+        let high = self.registers.ccr2.get();
+        self.registers
+            .sr
+            .modify(SR::CC1OF::CLEAR + SR::CC2OF::CLEAR + SR::CC2IF::CLEAR);
+
+        if self.started.get() {
+            self.client.map(|client| client.captured(period, high));
+        }
+        self.started.set(true);
+    }
+}
+
+impl<'a> pwm_capture::PwmCapture<'a> for Tim5<'a> {
+    fn set_client(&self, client: &'a dyn pwm_capture::Client) {
+        self.client.set(client);
+    }
+
+    fn get_frequency(&self) -> u32 {
+        CAPTURE_FREQUENCY
+    }
+
+    fn start(&self) -> Result<(), ErrorCode> {
+        if self.registers.cr1.is_set(CR1::CEN) {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.enable_clock();
+        self.started.set(false);
+
+        // IC1 captures rising edges of TI1, and IC2 its falling edges.
+        self.registers
+            .ccmr1_input
+            .write(CCMR1_Input::CC1S::TI1 + CCMR1_Input::CC2S::TI1);
+        self.registers.ccer.write(
+            CCER::CC1P::CLEAR
+                + CCER::CC1NP::CLEAR
+                + CCER::CC2P::SET
+                + CCER::CC2NP::CLEAR
+                + CCER::CC1E::SET
+                + CCER::CC2E::SET,
+        );
+        // Rising edges reset the counter.
+        self.registers
+            .smcr
+            .write(SMCR::TS::TI1FP1 + SMCR::SMS::Reset);
+
+        self.registers.arr.set(0xFFFF_FFFF);
+        self.registers.psc.set(16_000_000 / CAPTURE_FREQUENCY - 1);
+        // The prescaler only takes effect on an update event.
+        self.registers.egr.write(EGR::UG::SET);
+        self.registers.sr.set(0);
+        self.registers.dier.modify(DIER::CC1IE::SET);
+        self.registers.cr1.modify(CR1::CEN::SET);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), ErrorCode> {
+        self.registers.cr1.modify(CR1::CEN::CLEAR);
+        self.registers.dier.modify(DIER::CC1IE::CLEAR);
+        self.registers.ccer.set(0);
+        self.registers.smcr.write(SMCR::SMS::Disabled);
+        self.registers.sr.set(0);
+        self.disable_clock();
+        Ok(())
+    }
+}
+
+struct Tim5Clock<'a>(rcc::PeripheralClock<'a>);
+
+impl ClockInterface for Tim5Clock<'_> {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
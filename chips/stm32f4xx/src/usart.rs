@@ -150,6 +150,9 @@ pub const USART2_BASE: StaticRef<UsartRegisters> =
 pub const USART3_BASE: StaticRef<UsartRegisters> =
     unsafe { StaticRef::new(0x40004800 as *const UsartRegisters) };
 
+/// The data bits of a word in the data register.
+const WORD_MASK: u32 = 0x1FF;
+
 // for use by dma1
 pub(crate) fn get_address_dr(regs: StaticRef<UsartRegisters>) -> u32 {
     &regs.dr as *const ReadWrite<u32> as u32
@@ -160,6 +163,7 @@ pub(crate) fn get_address_dr(regs: StaticRef<UsartRegisters>) -> u32 {
 enum USARTStateRX {
     Idle,
     DMA_Receiving,
+    Word_Receiving,
 }
 
 #[allow(non_camel_case_types)]
@@ -168,6 +172,7 @@ enum USARTStateTX {
     Idle,
     DMA_Transmitting,
     Transfer_Completing, // DMA finished, but not all bytes sent
+    Word_Transmitting,
 }
 
 pub struct Usart<'a> {
@@ -264,9 +269,37 @@ impl<'a> Usart<'a> {
     // According to section 25.4.13, we need to make sure that USART TC flag is
     // set before disabling the DMA TX on the peripheral side.
     pub fn handle_interrupt(&self) {
+        if self.registers.cr1.is_set(CR1::IDLEIE) && self.registers.sr.is_set(SR::IDLE) {
+            // Reading the data register after the status register clears the
+            // flag. The DMA already moved the last byte out of it.
+            self.registers.dr.get();
+            if self.usart_rx_state.get() == USARTStateRX::DMA_Receiving {
+                self.abort_rx(Ok(()), hil::uart::Error::Aborted);
+            }
+        }
+
+        if self.registers.cr1.is_set(CR1::RXNEIE) && self.registers.sr.is_set(SR::RXNE) {
+            self.registers.cr1.modify(CR1::RXNEIE::CLEAR);
+            let word = self.registers.dr.get() & WORD_MASK;
+            if self.usart_rx_state.get() == USARTStateRX::Word_Receiving {
+                self.usart_rx_state.set(USARTStateRX::Idle);
+                self.rx_client.map(|client| {
+                    client.received_word(word, Ok(()), hil::uart::Error::None);
+                });
+            }
+        }
+
+        if !self.registers.cr1.is_set(CR1::TCIE) {
+            return;
+        }
         self.clear_transmit_complete();
         self.disable_transmit_complete_interrupt();
 
+        if self.usart_tx_state.get() == USARTStateTX::Word_Transmitting {
+            self.usart_tx_state.set(USARTStateTX::Idle);
+            self.tx_client.map(|client| client.transmitted_word(Ok(())));
+        }
+
         // Ignore if USARTStateTX is in some other state other than
         // Transfer_Completing.
         if self.usart_tx_state.get() == USARTStateTX::Transfer_Completing {
@@ -310,9 +343,11 @@ impl<'a> Usart<'a> {
         self.registers.cr3.modify(CR3::DMAR::SET);
     }
 
-    // disable DMA RX from the peripheral side
+    // disable DMA RX from the peripheral side, and the end of reception on an
+    // idle line
     fn disable_rx(&self) {
         self.registers.cr3.modify(CR3::DMAR::CLEAR);
+        self.registers.cr1.modify(CR1::IDLEIE::CLEAR);
     }
 
     fn abort_tx(&self, rcode: Result<(), ErrorCode>) {
@@ -406,12 +441,27 @@ impl<'a> hil::uart::Transmit<'a> for Usart<'a> {
         Ok(())
     }
 
-    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+    /// Transmit a word of up to 9 bits, without DMA. With 8-bit words the
+    /// ninth bit is ignored.
+    fn transmit_word(&self, word: u32) -> Result<(), ErrorCode> {
+        if self.usart_tx_state.get() != USARTStateTX::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.usart_tx_state.set(USARTStateTX::Word_Transmitting);
+        self.clear_transmit_complete();
+        self.registers.dr.set(word & WORD_MASK);
+        self.enable_transmit_complete_interrupt();
+        Ok(())
     }
 
     fn transmit_abort(&self) -> Result<(), ErrorCode> {
-        if self.usart_tx_state.get() != USARTStateTX::Idle {
+        if self.usart_tx_state.get() == USARTStateTX::Word_Transmitting {
+            self.disable_transmit_complete_interrupt();
+            self.usart_tx_state.set(USARTStateTX::Idle);
+            self.tx_client
+                .map(|client| client.transmitted_word(Err(ErrorCode::CANCEL)));
+            Err(ErrorCode::BUSY)
+        } else if self.usart_tx_state.get() != USARTStateTX::Idle {
             self.abort_tx(Err(ErrorCode::CANCEL));
             Err(ErrorCode::BUSY)
         } else {
@@ -426,15 +476,21 @@ impl<'a> hil::uart::Configure for Usart<'a> {
             || params.stop_bits != hil::uart::StopBits::One
             || params.parity != hil::uart::Parity::None
             || params.hw_flow_control != false
-            || params.width != hil::uart::Width::Eight
+            || (params.width != hil::uart::Width::Eight && params.width != hil::uart::Width::Nine)
         {
             panic!(
-                "Currently we only support uart setting of 115200bps 8N1, no hardware flow control"
+                "Currently we only support uart setting of 115200bps 8N1 or 9N1, no hardware flow control"
             );
         }
 
         // Configure the word length - 0: 1 Start bit, 8 Data bits, n Stop bits
-        self.registers.cr1.modify(CR1::M::CLEAR);
+        // 1: 1 Start bit, 9 Data bits, n Stop bits. Buffers are transferred
+        // a byte at a time, so with 9 data bits the ninth is always clear.
+        if params.width == hil::uart::Width::Nine {
+            self.registers.cr1.modify(CR1::M::SET);
+        } else {
+            self.registers.cr1.modify(CR1::M::CLEAR);
+        }
 
         // Set the stop bit length - 00: 1 Stop bits
         self.registers.cr2.modify(CR2::STOP.val(0b00 as u32));
@@ -494,18 +550,67 @@ impl<'a> hil::uart::Receive<'a> for Usart<'a> {
         Ok(())
     }
 
+    /// Receive a word of up to 9 bits, without DMA.
     fn receive_word(&self) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+        if self.usart_rx_state.get() != USARTStateRX::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.usart_rx_state.set(USARTStateRX::Word_Receiving);
+        self.registers.cr1.modify(CR1::RXNEIE::SET);
+        Ok(())
     }
 
     fn receive_abort(&self) -> Result<(), ErrorCode> {
+        if self.usart_rx_state.get() == USARTStateRX::Word_Receiving {
+            self.registers.cr1.modify(CR1::RXNEIE::CLEAR);
+            self.usart_rx_state.set(USARTStateRX::Idle);
+            self.rx_client.map(|client| {
+                client.received_word(0, Err(ErrorCode::CANCEL), hil::uart::Error::Aborted)
+            });
+            return Err(ErrorCode::BUSY);
+        }
         self.abort_rx(Err(ErrorCode::CANCEL), hil::uart::Error::Aborted);
         Err(ErrorCode::BUSY)
     }
 }
 
+impl<'a> hil::uart::ReceiveAdvanced<'a> for Usart<'a> {
+    /// The USART only detects an idle line of one frame: 10 bit periods, or
+    /// 11 with 9 data bits. A shorter `interbyte_timeout` ends the reception
+    /// after that frame, and a longer one is rejected with `INVAL`.
+    fn receive_automatic(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        interbyte_timeout: u8,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.usart_rx_state.get() != USARTStateRX::Idle {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+        // One start bit, the data bits and one stop bit.
+        let frame_bits = if self.registers.cr1.is_set(CR1::M) {
+            11
+        } else {
+            10
+        };
+        if interbyte_timeout > frame_bits {
+            return Err((ErrorCode::INVAL, rx_buffer));
+        }
+
+        // Clear an idle line detected before this reception, without dropping
+        // a byte that is waiting for the DMA.
+        if self.registers.sr.is_set(SR::IDLE) && !self.registers.sr.is_set(SR::RXNE) {
+            self.registers.dr.get();
+        }
+        hil::uart::Receive::receive_buffer(self, rx_buffer, rx_len)?;
+        self.registers.cr1.modify(CR1::IDLEIE::SET);
+        Ok(())
+    }
+}
+
 impl<'a> hil::uart::UartData<'a> for Usart<'a> {}
 impl<'a> hil::uart::Uart<'a> for Usart<'a> {}
+impl<'a> hil::uart::UartAdvanced<'a> for Usart<'a> {}
 
 impl dma1::StreamClient for Usart<'_> {
     fn transfer_done(&self, pid: dma1::Dma1Peripheral) {
@@ -90,3 +90,9 @@ pub unsafe trait CreatePortTableCapability {}
 /// of the networking stack. A capsule would never hold this capability although
 /// it may hold capabilities created via this capability.
 pub unsafe trait NetworkCapabilityCreationCapability {}
+
+/// The `PeripheralAccessCapability` allows the holder to drive peripherals
+/// directly, outside of the capsules that normally use them, for example to
+/// bring up a board from a debugging console. Such accesses can interfere with
+/// those capsules, so they are only enabled by trusted code.
+pub unsafe trait PeripheralAccessCapability {}
@@ -2,7 +2,10 @@
 //!
 //! For printing, this module uses an internal buffer to write the strings into.
 //! If you are writing and the buffer fills up, you can make the size of
-//! `output_buffer` larger.
+//! `output_buffer` larger. Messages that do not fit are cut short, and the
+//! number of bytes dropped is printed once there is room again, and by the
+//! panic handler. `debug_statistics()` tells how full the buffer got, to
+//! choose its size.
 //!
 //! Before debug interfaces can be used, the board file must assign them hardware:
 //!
@@ -365,6 +368,26 @@ pub struct DebugWriter {
     internal_buffer: TakeCell<'static, RingBuffer<'static, u8>>,
     // Number of debug!() calls.
     count: Cell<usize>,
+    // Bytes dropped because the internal buffer was full, in total and since
+    // the last report.
+    dropped_bytes: Cell<usize>,
+    unreported_dropped_bytes: Cell<usize>,
+    truncated_messages: Cell<usize>,
+    // Most bytes held in the internal buffer.
+    peak_buffered: Cell<usize>,
+}
+
+/// Statistics of the `debug!()` buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct DebugStatistics {
+    /// Size of the internal buffer, in bytes.
+    pub capacity: usize,
+    /// Most bytes the internal buffer held waiting for the UART.
+    pub peak_buffered: usize,
+    /// Bytes dropped because the internal buffer was full.
+    pub dropped_bytes: usize,
+    /// Messages cut short because the internal buffer was full.
+    pub truncated_messages: usize,
 }
 
 /// Static variable that holds the kernel's reference to the debug tool. This is
@@ -403,6 +426,21 @@ impl DebugWriter {
             output_buffer: TakeCell::new(out_buffer),
             internal_buffer: TakeCell::new(internal_buffer),
             count: Cell::new(0), // how many debug! calls
+            dropped_bytes: Cell::new(0),
+            unreported_dropped_bytes: Cell::new(0),
+            truncated_messages: Cell::new(0),
+            peak_buffered: Cell::new(0),
+        }
+    }
+
+    fn statistics(&self) -> DebugStatistics {
+        DebugStatistics {
+            capacity: self.internal_buffer.map_or(0, |ring_buffer| {
+                ring_buffer.len() + ring_buffer.available_len()
+            }),
+            peak_buffered: self.peak_buffered.get(),
+            dropped_bytes: self.dropped_bytes.get(),
+            truncated_messages: self.truncated_messages.get(),
         }
     }
 
@@ -493,11 +531,47 @@ impl DebugWriterWrapper {
     }
 }
 
+/// Writes the decimal digits of a number into a fixed buffer, to report
+/// dropped bytes without going through `debug!()` again.
+struct DroppedMessage {
+    buffer: [u8; 48],
+    len: usize,
+}
+
+impl Write for DroppedMessage {
+    fn write_str(&mut self, s: &str) -> Result {
+        let bytes = s.as_bytes();
+        let len = core::cmp::min(bytes.len(), self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
 impl IoWrite for DebugWriterWrapper {
     fn write(&mut self, bytes: &[u8]) {
         const FULL_MSG: &[u8] = b"\n*** DEBUG BUFFER FULL ***\n";
         self.dw.map(|dw| {
             dw.internal_buffer.map(|ring_buffer| {
+                // Once there is room again, say how much was dropped.
+                let unreported = dw.unreported_dropped_bytes.get();
+                if unreported > 0 {
+                    let mut message = DroppedMessage {
+                        buffer: [0; 48],
+                        len: 0,
+                    };
+                    let _ = message.write_fmt(format_args!(
+                        "*** {} DEBUG BYTES DROPPED ***\r\n",
+                        unreported
+                    ));
+                    if ring_buffer.available_len() >= message.len + bytes.len() + FULL_MSG.len() {
+                        for &b in &message.buffer[..message.len] {
+                            ring_buffer.enqueue(b);
+                        }
+                        dw.unreported_dropped_bytes.set(0);
+                    }
+                }
+
                 let available_len_for_msg =
                     ring_buffer.available_len().saturating_sub(FULL_MSG.len());
 
@@ -509,17 +583,34 @@ impl IoWrite for DebugWriterWrapper {
                     for &b in &bytes[..available_len_for_msg] {
                         ring_buffer.enqueue(b);
                     }
+                    let dropped = bytes.len() - available_len_for_msg;
+                    dw.dropped_bytes.set(dw.dropped_bytes.get() + dropped);
+                    dw.unreported_dropped_bytes
+                        .set(dw.unreported_dropped_bytes.get() + dropped);
+                    dw.truncated_messages.increment();
                     // When the buffer is close to full, print a warning and drop the current
-                    // string.
-                    for &b in FULL_MSG {
-                        ring_buffer.enqueue(b);
+                    // string, unless the warning is already waiting in the buffer.
+                    if ring_buffer.available_len() >= FULL_MSG.len() {
+                        for &b in FULL_MSG {
+                            ring_buffer.enqueue(b);
+                        }
                     }
                 }
+
+                if ring_buffer.len() > dw.peak_buffered.get() {
+                    dw.peak_buffered.set(ring_buffer.len());
+                }
             });
         });
     }
 }
 
+/// Returns the statistics of the `debug!()` buffer, or `None` if the board
+/// did not set a debug writer.
+pub fn debug_statistics() -> Option<DebugStatistics> {
+    unsafe { try_get_debug_writer() }.and_then(|writer| writer.dw.map(|dw| dw.statistics()))
+}
+
 impl Write for DebugWriterWrapper {
     fn write_str(&mut self, s: &str) -> Result {
         self.write(s.as_bytes());
@@ -600,8 +691,21 @@ impl Default for Debug {
     }
 }
 
+/// Write everything waiting in the `debug!()` buffer and the debug queue to
+/// `writer`, blocking until it is written. Used by panic handlers, so that
+/// the last messages before the panic are not lost.
+///
+/// **NOTE:** The supplied `writer` must be synchronous.
 pub unsafe fn flush<W: Write + IoWrite>(writer: &mut W) {
     if let Some(debug_writer) = try_get_debug_writer() {
+        if let Some(statistics) = debug_writer.dw.map(|dw| dw.statistics()) {
+            if statistics.dropped_bytes > 0 {
+                let _ = writer.write_fmt(format_args!(
+                    "\r\n---| {} debug bytes dropped in {} messages, buffer of {} bytes.\r\n",
+                    statistics.dropped_bytes, statistics.truncated_messages, statistics.capacity
+                ));
+            }
+        }
         if let Some(ring_buffer) = debug_writer.extract() {
             if ring_buffer.has_elements() {
                 let _ = writer.write_str(
@@ -147,6 +147,58 @@ impl CommandReturn {
     pub fn success_u64_u32(data0: u64, data1: u32) -> Self {
         CommandReturn(SyscallReturn::SuccessU64U32(data0, data1))
     }
+
+    /// Returns true if this CommandReturn is of type success
+    pub fn is_success(&self) -> bool {
+        match self.0 {
+            SyscallReturn::Success
+            | SyscallReturn::SuccessU32(_)
+            | SyscallReturn::SuccessU32U32(_, _)
+            | SyscallReturn::SuccessU32U32U32(_, _, _)
+            | SyscallReturn::SuccessU64(_)
+            | SyscallReturn::SuccessU64U32(_, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the error code of a failure, or None if this CommandReturn is
+    /// not a failure
+    pub fn get_failure(&self) -> Option<ErrorCode> {
+        match self.0 {
+            SyscallReturn::Failure(e)
+            | SyscallReturn::FailureU32(e, _)
+            | SyscallReturn::FailureU32U32(e, _, _)
+            | SyscallReturn::FailureU64(e, _) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Returns the data of a success with one 32-bit data field, or None if
+    /// this CommandReturn is of another type
+    pub fn get_success_u32(&self) -> Option<u32> {
+        match self.0 {
+            SyscallReturn::SuccessU32(data0) => Some(data0),
+            _ => None,
+        }
+    }
+
+    /// Returns the data of a success with two 32-bit data fields, or None if
+    /// this CommandReturn is of another type
+    pub fn get_success_u32_u32(&self) -> Option<(u32, u32)> {
+        match self.0 {
+            SyscallReturn::SuccessU32U32(data0, data1) => Some((data0, data1)),
+            _ => None,
+        }
+    }
+
+    /// Returns the data of a success with a 64-bit and a 32-bit data field,
+    /// or None if this CommandReturn is of another type
+    pub fn get_success_u64_u32(&self) -> Option<(u64, u32)> {
+        match self.0 {
+            SyscallReturn::SuccessU64U32(data0, data1) => Some((data0, data1)),
+            _ => None,
+        }
+    }
 }
 
 impl From<Result<(), ErrorCode>> for CommandReturn {
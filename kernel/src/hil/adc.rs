@@ -99,6 +99,38 @@ pub trait AdcHighSpeed: Adc {
     ) -> Result<(Option<&'static mut [u16]>, Option<&'static mut [u16]>), ErrorCode>;
 }
 
+/// Interface for continuously sampling a sequence of channels into buffers.
+/// Requires the AdcHighSpeed interface to have been implemented as well.
+pub trait AdcStreaming: AdcHighSpeed {
+    /// The most channels a sequence passed to `sample_sequence` can hold.
+    /// Chips that cannot scan several channels return 1.
+    fn max_sequence_length(&self) -> usize;
+
+    /// Start sampling a sequence of channels continuously into buffers.
+    /// `frequency` times a second, every channel of the sequence is sampled
+    /// once, in order, and the samples are stored one after the other, so
+    /// the buffers hold the sequence over and over. The channels are set up
+    /// before the call returns, so the slice need not outlive it.
+    ///
+    /// Buffers are double-buffered as with `sample_highspeed`, passed on
+    /// with `provide_buffer` and returned through
+    /// `HighSpeedClient::samples_ready`, and sampling stops with
+    /// `stop_sampling`. Every length must be a multiple of the length of the
+    /// sequence, so that each buffer starts with the first channel. If an
+    /// error occurs, the buffers will be returned.
+    ///
+    /// All ADC samples will be the raw ADC value left-justified in the u16.
+    fn sample_sequence(
+        &self,
+        channels: &[&Self::Channel],
+        frequency: u32,
+        buffer1: &'static mut [u16],
+        length1: usize,
+        buffer2: &'static mut [u16],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])>;
+}
+
 /// Trait for handling callbacks from high-speed ADC calls.
 pub trait HighSpeedClient {
     /// Called when a buffer is full.
@@ -108,6 +140,52 @@ pub trait HighSpeedClient {
     fn samples_ready(&self, buf: &'static mut [u16], length: usize);
 }
 
+// *** Interfaces for the voltage reference and calibration of an ADC ***
+
+/// A voltage reference for ADC samples.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VoltageReference {
+    /// A reference generated inside the chip.
+    Internal,
+    /// A reference supplied to a pin of the chip.
+    External,
+    /// The analog supply voltage of the chip.
+    Vdd,
+}
+
+/// Interface for selecting the voltage reference of an ADC, and for
+/// calibrating it.
+/// Requires the AdcSimple interface to have been implemented as well.
+pub trait AdcReference: Adc {
+    /// Select the voltage reference of the samples taken from now on.
+    /// Returns `BUSY` while sampling, and `NOSUPPORT` if the chip cannot
+    /// sample against that reference.
+    fn set_voltage_reference(&self, reference: VoltageReference) -> Result<(), ErrorCode>;
+
+    /// The voltage reference currently selected.
+    fn get_voltage_reference(&self) -> VoltageReference;
+
+    /// The voltage that a sample of full scale (`0xFFFF` once left-justified)
+    /// stands for, with the selected reference.
+    ///
+    /// The returned voltage is in microvolts, or `None` if unknown, as for
+    /// an external reference.
+    fn get_voltage_reference_uv(&self) -> Option<usize>;
+
+    /// Start a self-calibration of the ADC, which ends with a call to
+    /// `CalibrationClient::calibration_done`. Returns `BUSY` while sampling,
+    /// and `NOSUPPORT` if the chip cannot calibrate on request.
+    fn calibrate(&self) -> Result<(), ErrorCode>;
+
+    fn set_calibration_client(&self, client: &'static dyn CalibrationClient);
+}
+
+/// Trait for handling callbacks from ADC calibration.
+pub trait CalibrationClient {
+    /// Called when a calibration started by `calibrate` has ended.
+    fn calibration_done(&self, result: Result<(), ErrorCode>);
+}
+
 pub trait AdcChannel {
     /// Request a single ADC sample on a particular channel.
     /// Used for individual samples that have no timing requirements.
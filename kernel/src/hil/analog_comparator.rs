@@ -5,6 +5,28 @@ use crate::ErrorCode;
 // Author: Danilo Verhaert <verhaert@cs.stanford.edu>
 // Last modified August 9th, 2018
 
+/// The crossings of the threshold that cause an interrupt.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InterruptMode {
+    /// Interrupt when Vp rises above Vn.
+    RisingEdge,
+    /// Interrupt when Vp falls below Vn.
+    FallingEdge,
+    /// Interrupt on both crossings.
+    Toggle,
+}
+
+/// The changes of a window comparison that cause an interrupt.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WindowEvent {
+    /// Interrupt when the common input enters the window.
+    Enter,
+    /// Interrupt when the common input leaves the window.
+    Leave,
+    /// Interrupt when the common input enters or leaves the window.
+    Toggle,
+}
+
 pub trait AnalogComparator<'a> {
     /// The chip-dependent type of an analog comparator channel.
     type Channel;
@@ -16,13 +38,38 @@ pub trait AnalogComparator<'a> {
     fn comparison(&self, channel: &Self::Channel) -> bool;
 
     /// Start interrupt-based comparison for the chosen channel (e.g. channel 1
-    /// for AC1). This will make it listen and send an interrupt as soon as
-    /// Vp > Vn.
+    /// for AC1). This will make it listen and send an interrupt when the
+    /// inputs cross as selected by `set_interrupt_mode()`, by default as soon
+    /// as Vp > Vn.
     fn start_comparing(&self, channel: &Self::Channel) -> Result<(), ErrorCode>;
 
+    /// Select which crossings of the inputs interrupt-based comparisons on
+    /// the chosen channel report. Takes effect for comparisons started
+    /// afterwards. Returns `NOSUPPORT` if the hardware cannot detect the
+    /// requested crossings.
+    fn set_interrupt_mode(
+        &self,
+        channel: &Self::Channel,
+        mode: InterruptMode,
+    ) -> Result<(), ErrorCode>;
+
     /// Stop interrupt-based comparison for the chosen channel.
     fn stop_comparing(&self, channel: &Self::Channel) -> Result<(), ErrorCode>;
 
+    /// Do a single window comparison. A window is a pair of comparators
+    /// sharing a common input, whose other inputs set the lower and upper
+    /// bounds of the window. Returns `true` if the common input is inside the
+    /// window. Returns `INVAL` if the window does not exist and `NOSUPPORT` if
+    /// the hardware has no window mode.
+    fn window_comparison(&self, window: usize) -> Result<bool, ErrorCode>;
+
+    /// Start interrupt-based window comparison for the chosen window. The
+    /// client's `window_fired()` is called on each selected `event`.
+    fn start_window(&self, window: usize, event: WindowEvent) -> Result<(), ErrorCode>;
+
+    /// Stop interrupt-based window comparison for the chosen window.
+    fn stop_window(&self, window: usize) -> Result<(), ErrorCode>;
+
     fn set_client(&self, client: &'a dyn Client);
 }
 
@@ -30,4 +77,8 @@ pub trait Client {
     /// Fires when handle_interrupt is called, returning the channel on which
     /// the interrupt occurred.
     fn fired(&self, _: usize);
+
+    /// Fires on a window interrupt, returning the window on which the
+    /// interrupt occurred and whether the common input is now inside it.
+    fn window_fired(&self, window: usize, inside: bool);
 }
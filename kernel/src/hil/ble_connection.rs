@@ -0,0 +1,119 @@
+//! Interface for radios running the link layer of a Bluetooth Low Energy
+//! peripheral or central.
+//!
+//! A peripheral advertises that it accepts connections, and then answers
+//! each packet of the central in connection events. A central scans for
+//! advertisements, answers the one of the peripheral it connects to with a
+//! connection request, and then starts each connection event with a packet
+//! the peripheral answers. These are packet exchanges with strict timing,
+//! which the radio performs on its own: the link layer only prepares the
+//! packets and decides when the exchanges start and when to stop
+//! listening.
+//!
+//! Packets start with a 2-byte header, whose second byte is the length of
+//! the payload that follows. Buffers must hold the header and the longest
+//! payload to receive.
+//!
+//! Only the 1 Mbps PHY is supported.
+
+use crate::hil::ble_advertising::RadioChannel;
+use crate::ErrorCode;
+
+/// Access address of advertising channel packets.
+pub const ADVERTISING_ACCESS_ADDRESS: u32 = 0x8e89bed6;
+
+/// CRC initial value of advertising channel packets.
+pub const ADVERTISING_CRC_INIT: u32 = 0x555555;
+
+/// Inter frame space, between two packets of an exchange.
+pub const T_IFS_US: u32 = 150;
+
+/// Duration of a packet of `len` bytes, headers included, on the 1 Mbps
+/// PHY: a preamble, the access address and the CRC surround it.
+pub fn air_time_us(len: usize) -> u32 {
+    (1 + 4 + len as u32 + 3) * 8
+}
+
+pub trait BleConnectionRadio<'a> {
+    fn set_connection_client(&self, client: &'a dyn ConnectionClient);
+
+    /// Transmit the advertising channel packet in `tx` on `channel`, and then
+    /// listen there for a request of a scanner or an initiator, written to
+    /// `rx`, until `close_receive_window()` is called.
+    fn advertise(
+        &self,
+        channel: RadioChannel,
+        tx: &'static mut [u8],
+        rx: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [u8])>;
+
+    /// Start a connection event: listen on data `channel` for a packet of
+    /// the central, with the access address and CRC initial value of the
+    /// connection, until `close_receive_window()` is called. The packet is
+    /// written to `rx`, and answered with the packet the client writes to
+    /// `tx` in `respond()`, transmitted `T_IFS_US` after it.
+    fn connection_event(
+        &self,
+        channel: RadioChannel,
+        access_address: u32,
+        crc_init: u32,
+        tx: &'static mut [u8],
+        rx: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [u8])>;
+
+    /// Listen on advertising `channel` for advertising channel packets,
+    /// written to `rx`, until `close_receive_window()` is called. Each packet
+    /// received with a valid CRC is passed to `scanned()`. If `answer` is
+    /// set, the client can answer a packet with the one it writes to `tx`,
+    /// which ends the exchange.
+    fn scan(
+        &self,
+        channel: RadioChannel,
+        answer: bool,
+        tx: &'static mut [u8],
+        rx: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [u8])>;
+
+    /// Start a connection event of the central: transmit the packet in `tx`
+    /// on data `channel`, with the access address and CRC initial value of
+    /// the connection, and then listen for the response of the peripheral,
+    /// written to `rx`, until `close_receive_window()` is called.
+    fn central_event(
+        &self,
+        channel: RadioChannel,
+        access_address: u32,
+        crc_init: u32,
+        tx: &'static mut [u8],
+        rx: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8], &'static mut [u8])>;
+
+    /// Stop listening, unless a packet is being received. The exchange
+    /// completes with `NOACK` if nothing was received.
+    fn close_receive_window(&self);
+}
+
+pub trait ConnectionClient {
+    /// A packet of the central was received in a connection event, with a
+    /// valid CRC if `crc_ok`. The client must write the response to `tx`
+    /// right away, since the radio transmits it `T_IFS_US` after the end of
+    /// the packet.
+    fn respond(&self, rx: &[u8], crc_ok: bool, tx: &mut [u8]);
+
+    /// An advertising channel packet was received while scanning, with
+    /// signal strength `rssi`, in dBm. If the scan allows it, the client
+    /// answers the packet by writing the answer to `tx` right away and
+    /// returning `true`.
+    fn scanned(&self, rx: &[u8], rssi: i8, tx: &mut [u8]) -> bool;
+
+    /// The exchange completed. The result is `Ok(())` if a packet of `len`
+    /// bytes, headers included, was received in `rx` with a valid CRC (and
+    /// answered, in a connection event or a scan), `FAIL` if its CRC was
+    /// invalid, and `NOACK` if nothing was received.
+    fn exchange_done(
+        &self,
+        tx: &'static mut [u8],
+        rx: &'static mut [u8],
+        len: usize,
+        result: Result<(), ErrorCode>,
+    );
+}
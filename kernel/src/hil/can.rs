@@ -0,0 +1,134 @@
+//! Interface for CAN (Controller Area Network) controllers.
+//!
+//! A CAN controller sends and receives frames of up to eight data bytes,
+//! identified by an 11-bit standard or 29-bit extended identifier. Received
+//! frames are passed through the controller's acceptance filters, and the
+//! controller reports when it leaves the bus after too many errors.
+
+use crate::ErrorCode;
+
+/// Maximum number of data bytes in a frame.
+pub const MAX_DATA_LEN: usize = 8;
+
+/// Largest standard (11-bit) identifier.
+pub const STANDARD_ID_MAX: u32 = 0x7FF;
+
+/// Largest extended (29-bit) identifier.
+pub const EXTENDED_ID_MAX: u32 = 0x1FFF_FFFF;
+
+/// The identifier of a frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Id {
+    /// An 11-bit identifier.
+    Standard(u16),
+    /// A 29-bit identifier.
+    Extended(u32),
+}
+
+impl Id {
+    /// Create a standard identifier, or `None` if `id` does not fit in 11
+    /// bits.
+    pub fn standard(id: u32) -> Option<Id> {
+        if id <= STANDARD_ID_MAX {
+            Some(Id::Standard(id as u16))
+        } else {
+            None
+        }
+    }
+
+    /// Create an extended identifier, or `None` if `id` does not fit in 29
+    /// bits.
+    pub fn extended(id: u32) -> Option<Id> {
+        if id <= EXTENDED_ID_MAX {
+            Some(Id::Extended(id))
+        } else {
+            None
+        }
+    }
+
+    /// The numeric value of the identifier.
+    pub fn value(&self) -> u32 {
+        match *self {
+            Id::Standard(id) => id as u32,
+            Id::Extended(id) => id,
+        }
+    }
+
+    pub fn is_extended(&self) -> bool {
+        match *self {
+            Id::Standard(_) => false,
+            Id::Extended(_) => true,
+        }
+    }
+}
+
+/// An acceptance filter. A frame matches if its identifier is of the same
+/// kind as `id` and equal to it in all bits set in `mask`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Filter {
+    pub id: Id,
+    pub mask: u32,
+}
+
+impl Filter {
+    pub fn matches(&self, id: Id) -> bool {
+        self.id.is_extended() == id.is_extended() && (self.id.value() ^ id.value()) & self.mask == 0
+    }
+}
+
+/// Control of the controller and its bus.
+pub trait Controller<'a> {
+    fn set_controller_client(&self, client: &'a dyn ControllerClient);
+
+    /// Join the bus at `bitrate` bits per second.
+    fn enable(&self, bitrate: u32) -> Result<(), ErrorCode>;
+
+    /// Leave the bus. Pending transmissions are aborted.
+    fn disable(&self) -> Result<(), ErrorCode>;
+
+    /// The number of acceptance filters the controller has.
+    fn filter_count(&self) -> usize;
+
+    /// Set or, with `None`, clear acceptance filter `index`. Only frames
+    /// matching at least one filter are received. Returns `INVAL` if `index`
+    /// is not below `filter_count()`.
+    fn set_filter(&self, index: usize, filter: Option<Filter>) -> Result<(), ErrorCode>;
+}
+
+pub trait ControllerClient {
+    /// The controller left the bus after too many transmit errors. It has to
+    /// be enabled again to rejoin the bus.
+    fn bus_off(&self);
+}
+
+pub trait Transmit<'a> {
+    fn set_transmit_client(&self, client: &'a dyn TransmitClient);
+
+    /// Send a frame with the first `len` bytes of `buffer` as data. `len`
+    /// must not be larger than `MAX_DATA_LEN`. On error, the buffer is
+    /// returned.
+    fn send(
+        &self,
+        id: Id,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
+
+pub trait TransmitClient {
+    /// A frame passed to `send()` was sent, or failed to be sent because the
+    /// controller left the bus or was disabled.
+    fn transmit_complete(&self, status: Result<(), ErrorCode>, buffer: &'static mut [u8]);
+}
+
+pub trait Receive<'a> {
+    fn set_receive_client(&self, client: &'a dyn ReceiveClient);
+}
+
+pub trait ReceiveClient {
+    /// A frame that passed the acceptance filters was received.
+    fn frame_received(&self, id: Id, data: &[u8]);
+}
+
+pub trait Can<'a>: Controller<'a> + Transmit<'a> + Receive<'a> {}
+pub trait Client: ControllerClient + TransmitClient + ReceiveClient {}
@@ -0,0 +1,137 @@
+//! Interface for calendar date and time, as kept by real-time clocks.
+//!
+//! A real-time clock counts wall-clock time in seconds from a low-power
+//! oscillator, and usually keeps counting across resets of the rest of the
+//! chip. Unlike `time::Alarm`, it counts in calendar units, so it can be read
+//! and set as a date and time, and it can raise an alarm at a date and time.
+//!
+//! Times carry no time zone; they are usually UTC.
+
+use crate::ErrorCode;
+
+/// A calendar date and time of day.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTimeValues {
+    /// Full year, e.g. `2021`.
+    pub year: u16,
+    /// Month, from `1` (January) to `12`.
+    pub month: u8,
+    /// Day of the month, from `1`.
+    pub day: u8,
+    /// Hour, from `0` to `23`.
+    pub hour: u8,
+    /// Minute, from `0` to `59`.
+    pub minute: u8,
+    /// Second, from `0` to `59`.
+    pub second: u8,
+}
+
+impl DateTimeValues {
+    fn is_leap_year(&self) -> bool {
+        (self.year % 4 == 0 && self.year % 100 != 0) || self.year % 400 == 0
+    }
+
+    fn days_in_month(&self) -> u8 {
+        match self.month {
+            2 if self.is_leap_year() => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    /// Whether every field is in range, including the day in its month.
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= self.days_in_month()
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// The date and time `seconds` after 1970-01-01 00:00:00.
+    pub fn from_unix_time(seconds: u64) -> DateTimeValues {
+        // Count days from 0000-03-01, so that leap days end the years.
+        let days = seconds / 86400 + 719468;
+        let era = days / 146097;
+        let day_of_era = days % 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        let time = seconds % 86400;
+        DateTimeValues {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Seconds since 1970-01-01 00:00:00, for valid dates from 1970.
+    pub fn unix_time(&self) -> u64 {
+        let year = self.year as u64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let month = self.month as u64;
+        let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * month_from_march + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// Day of the week, from `1` (Monday) to `7` (Sunday), for valid dates.
+    pub fn day_of_week(&self) -> u8 {
+        // Sakamoto's method, which counts from Sunday as 0.
+        const OFFSETS: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let year = if self.month < 3 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let day = (year + year / 4 - year / 100
+            + year / 400
+            + OFFSETS[(self.month as usize - 1) % 12]
+            + self.day as u16)
+            % 7;
+        if day == 0 {
+            7
+        } else {
+            day as u8
+        }
+    }
+}
+
+pub trait DateTime<'a> {
+    /// Read the current date and time.
+    fn get_date_time(&self) -> Result<DateTimeValues, ErrorCode>;
+
+    /// Set the current date and time. Returns `INVAL` for invalid dates and
+    /// for dates the clock cannot represent.
+    fn set_date_time(&self, date_time: DateTimeValues) -> Result<(), ErrorCode>;
+
+    /// Call the client's `alarm` at `at`, replacing any alarm already set.
+    ///
+    /// Clocks may not compare every field of the date: the alarm can then
+    /// fire at an earlier time that matches `at` in the fields the clock
+    /// compares, at least the day, hour, minute and second. Clients that set
+    /// alarms more than a month ahead must check the date when it fires.
+    fn set_alarm(&self, at: DateTimeValues) -> Result<(), ErrorCode>;
+
+    /// Disable the alarm.
+    fn disarm(&self) -> Result<(), ErrorCode>;
+
+    fn set_client(&self, client: &'a dyn DateTimeClient);
+}
+
+pub trait DateTimeClient {
+    /// The alarm set with `set_alarm` fired.
+    fn alarm(&self);
+}
@@ -70,3 +70,10 @@ pub trait HMACSha256 {
     /// The key used for the HMAC is passed to this function.
     fn set_mode_hmacsha256(&self, key: &[u8; 32]) -> Result<(), ErrorCode>;
 }
+
+pub trait Sha256 {
+    /// Call before `Digest::run()` to perform Sha256
+    ///
+    /// This clears a key set by a previous `set_mode_hmacsha256()`.
+    fn set_mode_sha256(&self) -> Result<(), ErrorCode>;
+}
@@ -0,0 +1,141 @@
+//! Interface for Ethernet MACs.
+//!
+//! An Ethernet MAC sends and receives frames on a wired link. A frame starts
+//! with the destination and source MAC addresses and the EtherType, and
+//! carries up to 1500 bytes of payload. The MAC pads short frames and adds
+//! the frame check sequence when sending, and checks and removes it when
+//! receiving, so frames passed through this interface never include it.
+//!
+//! The MAC only receives frames addressed to it, to the broadcast address,
+//! or to a multicast address it listens to, unless its filter is configured
+//! to let more through. The link to the PHY, and whether a cable is plugged
+//! in, is reported separately from the frames.
+
+use crate::ErrorCode;
+
+/// Length of the frame header: two MAC addresses and the EtherType.
+pub const HEADER_LEN: usize = 14;
+
+/// Maximum length of the payload of a frame.
+pub const MAX_PAYLOAD_LEN: usize = 1500;
+
+/// Maximum length of a frame, without the frame check sequence.
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN;
+
+/// Ethernet CRC-32, as used for the frame check sequence and, over a MAC
+/// address, by the multicast hash filters of most MACs.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mix = crc & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0xEDB8_8320;
+            }
+        }
+    }
+    !crc
+}
+
+/// The 64-bit multicast hash table that lets frames to `addresses` through.
+/// `index` maps the CRC of an address to its bit of the table.
+pub fn multicast_hash_table(addresses: &[MacAddress], index: fn(u32) -> u32) -> u64 {
+    addresses.iter().fold(0, |table, address| {
+        table | 1 << (index(crc32(&address.0)) & 0x3F)
+    })
+}
+
+/// A 48-bit MAC address, in the order it is sent on the wire.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+
+    /// Whether this is a group address, which includes the broadcast
+    /// address.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == MacAddress::BROADCAST
+    }
+}
+
+/// The state of the link between the PHY and its link partner.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LinkStatus {
+    Down,
+    Up {
+        /// Speed of the link, in Mbit/s.
+        speed: u32,
+        full_duplex: bool,
+    },
+}
+
+/// Which frames not addressed to the MAC are received.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Filter {
+    /// Receive every frame.
+    pub promiscuous: bool,
+    /// Receive frames to every multicast address, not only those set with
+    /// `set_multicast_addresses()`.
+    pub all_multicast: bool,
+    /// Receive frames to the broadcast address.
+    pub broadcast: bool,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter {
+            promiscuous: false,
+            all_multicast: false,
+            broadcast: true,
+        }
+    }
+}
+
+pub trait Mac<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Start the MAC and its PHY, receiving frames addressed to `address`.
+    fn enable(&self, address: MacAddress) -> Result<(), ErrorCode>;
+
+    /// Stop the MAC. Fails with `BUSY` while a frame is being sent.
+    fn disable(&self) -> Result<(), ErrorCode>;
+
+    fn set_filter(&self, filter: Filter) -> Result<(), ErrorCode>;
+
+    /// Receive frames to `addresses`. MACs with a hash filter can let frames
+    /// to other multicast addresses through, so clients must still check the
+    /// destination of received frames. Fails with `INVAL` if an address is
+    /// not a multicast address.
+    fn set_multicast_addresses(&self, addresses: &[MacAddress]) -> Result<(), ErrorCode>;
+
+    /// The last known state of the link.
+    fn link_status(&self) -> LinkStatus;
+
+    /// Send the frame in the first `len` bytes of `buffer`, which starts
+    /// with the header. Only one frame is sent at a time: fails with `BUSY`
+    /// until the previous frame is done. On error, the buffer is returned.
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
+
+pub trait Client {
+    /// A frame passed to `transmit()` was sent, or failed to be sent.
+    fn transmit_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>);
+
+    /// A frame that passed the filter was received. The frame starts with
+    /// the header.
+    fn frame_received(&self, frame: &[u8]);
+
+    /// The link went up or down.
+    fn link_status_changed(&self, status: LinkStatus);
+}
@@ -0,0 +1,71 @@
+//! Interface for a filesystem of named files.
+//!
+//! Files are identified by a name of one to `MAX_NAME_LEN` non-zero bytes,
+//! and operations on other names fail with `SIZE`. Files hold a sequence of
+//! bytes. A file is written as a whole: a write replaces the previous
+//! content of the file, or creates it. Writes and deletions are atomic, so
+//! after a power loss a file either has its old or its new content.
+
+use crate::ErrorCode;
+
+/// The longest name of a file, in bytes.
+pub const MAX_NAME_LEN: usize = 16;
+
+/// The name of a file, padded with zeros.
+pub type FileName = [u8; MAX_NAME_LEN];
+
+/// An interface for storing named files.
+pub trait FileSystem<'a> {
+    /// Set the client of the filesystem. The client will be called when
+    /// operations complete.
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Find the files of the filesystem. It must complete, which is
+    /// signaled by `mount_done`, before other operations are used.
+    fn mount(&self) -> Result<(), ErrorCode>;
+
+    /// Returns the length of the file `name`, or `INVAL` if it does not
+    /// exist.
+    fn file_len(&self, name: &[u8]) -> Result<usize, ErrorCode>;
+
+    /// Returns the name and length of the file at position `index` of the
+    /// list of files, or `None` if there are fewer files.
+    fn file(&self, index: usize) -> Option<(FileName, usize)>;
+
+    /// Read `length` bytes of the file `name` from `offset` in to `buffer`.
+    /// Fewer bytes are read if the file ends before.
+    fn read(
+        &self,
+        name: &[u8],
+        offset: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Replace the content of the file `name` with the first `length` bytes
+    /// of `buffer`, creating the file if it does not exist.
+    fn write(
+        &self,
+        name: &[u8],
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Delete the file `name`.
+    fn delete(&self, name: &[u8]) -> Result<(), ErrorCode>;
+}
+
+/// Receive callbacks from `FileSystem`.
+pub trait Client {
+    /// The filesystem is ready to use, or could not be mounted.
+    fn mount_done(&self, result: Result<(), ErrorCode>);
+
+    /// Returns the buffer of a read and the number of bytes read in to it.
+    fn read_done(&self, result: Result<(), ErrorCode>, buffer: &'static mut [u8], length: usize);
+
+    /// Returns the buffer of a write and the number of bytes written.
+    fn write_done(&self, result: Result<(), ErrorCode>, buffer: &'static mut [u8], length: usize);
+
+    /// A file was deleted, or could not be.
+    fn delete_done(&self, result: Result<(), ErrorCode>);
+}
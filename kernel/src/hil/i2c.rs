@@ -25,6 +25,14 @@ pub enum Error {
     /// The requested operation wasn't supported.
     NotSupported,
 
+    /// The operation did not complete in time, most likely because a slave
+    /// is holding the bus, and was aborted.
+    Timeout,
+
+    /// The SMBus packet error code received from the slave did not match the
+    /// one computed over the transaction.
+    PecMismatch,
+
     /// No error occured and the command completed successfully.
     CommandComplete,
 }
@@ -37,12 +45,32 @@ impl Display for Error {
             Error::ArbitrationLost => "I2C Bus Arbitration Lost",
             Error::Overrun => "I2C receive overrun",
             Error::NotSupported => "I2C/SMBus command not supported",
+            Error::Timeout => "I2C operation timed out",
+            Error::PecMismatch => "SMBus packet error check failed",
             Error::CommandComplete => "I2C Command Completed",
         };
         write!(fmt, "{}", display_str)
     }
 }
 
+/// Compute the SMBus packet error code (PEC) over `data`, continuing from
+/// `crc`.
+///
+/// The PEC is a CRC-8 with polynomial `x^8 + x^2 + x + 1`, calculated over
+/// every byte of the transaction, including the address bytes, starting from
+/// `0`.
+pub fn smbus_pec(crc: u8, data: &[u8]) -> u8 {
+    data.iter().fold(crc, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 == 0x80 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
 /// This specifies what type of transmission just finished from a Master device.
 #[derive(Copy, Clone, Debug)]
 pub enum SlaveTransmissionType {
@@ -58,6 +86,18 @@ pub trait I2CMaster {
     fn write_read(&self, addr: u8, data: &'static mut [u8], write_len: u8, read_len: u8);
     fn write(&self, addr: u8, data: &'static mut [u8], len: u8);
     fn read(&self, addr: u8, buffer: &'static mut [u8], len: u8);
+
+    /// Abort the operation in progress, if any, and return its buffer. No
+    /// `command_complete` callback occurs for an aborted operation.
+    ///
+    /// If a slave is holding SDA low, the bus is recovered by clocking SCL
+    /// until the slave releases SDA, and generating a stop condition.
+    ///
+    /// Drivers that cannot abort an operation return `None`, and complete it
+    /// as usual.
+    fn abort(&self) -> Option<&'static mut [u8]> {
+        None
+    }
 }
 
 /// Interface for an SMBus Master hardware driver.
@@ -0,0 +1,77 @@
+//! Interface for I2S audio interfaces.
+//!
+//! An I2S interface streams PCM samples to and from audio codecs,
+//! microphones or amplifiers, in both directions at the same time. Samples
+//! are transferred from and to buffers of 32-bit words, each holding one
+//! sample of 24 bits or less, or two 16-bit or four 8-bit samples packed
+//! from the least significant end.
+//!
+//! Streaming is double-buffered: while one set of buffers is being
+//! transferred, the client queues the next one, so that the stream continues
+//! without gaps. Each set is made of a buffer to transmit, a buffer to receive
+//! into, or both.
+
+use crate::ErrorCode;
+
+/// Width of a single sample.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SampleWidth {
+    Bits8,
+    Bits16,
+    Bits24,
+}
+
+/// Channels in the stream.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Channels {
+    /// Alternating left and right samples.
+    Stereo,
+    /// Only the left channel.
+    Left,
+    /// Only the right channel.
+    Right,
+}
+
+/// A buffer to transmit and a buffer to receive into.
+pub type BufferSet = (Option<&'static mut [u32]>, Option<&'static mut [u32]>);
+
+pub trait I2s<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Configure the sample format while not streaming. Returns the sample
+    /// rate the hardware actually uses, which is as close to `sample_rate`
+    /// (in Hz) as possible.
+    fn configure(
+        &self,
+        sample_rate: u32,
+        width: SampleWidth,
+        channels: Channels,
+    ) -> Result<u32, ErrorCode>;
+
+    /// Start streaming with a first set of buffers. Whether samples are
+    /// transmitted, received or both is fixed by which buffers are present,
+    /// and so is the number of words per transfer, by their length. If both
+    /// are present, they must have the same length. On error, the buffers are
+    /// returned.
+    fn start(&self, buffers: BufferSet) -> Result<(), (ErrorCode, BufferSet)>;
+
+    /// Queue the next set of buffers, with the same directions and length as
+    /// the set passed to `start()`. Up to two sets can be queued behind the
+    /// one being transferred. If none is queued when a transfer completes,
+    /// the last set is transferred again. On error, the buffers are returned.
+    fn queue(&self, buffers: BufferSet) -> Result<(), (ErrorCode, BufferSet)>;
+
+    /// Stop streaming. All buffers are returned through `buffers_done()`,
+    /// followed by `stopped()`.
+    fn stop(&self) -> Result<(), ErrorCode>;
+}
+
+pub trait Client {
+    /// The transfer of a set of buffers completed, or was abandoned because
+    /// streaming stopped.
+    fn buffers_done(&self, buffers: BufferSet);
+
+    /// Streaming stopped after a call to `stop()`, and all buffers were
+    /// returned.
+    fn stopped(&self);
+}
@@ -0,0 +1,130 @@
+//! Interface for sending and receiving LoRa packets.
+//!
+//! Hardware independent interface for a LoRa transceiver, such as the
+//! Semtech SX127x and SX126x families. Unlike 802.15.4 radios, LoRa radios
+//! are not kept listening: each transmission or reception is a single
+//! operation with its own modulation parameters, after which the radio goes
+//! back to sleep. This matches how LoRaWAN end devices use the radio, with
+//! every uplink followed by at most two short receive windows.
+//!
+//! Packets are sent with an explicit header and a payload CRC. Received
+//! packets must have an explicit header, and are checked against their CRC
+//! if they carry one.
+
+use crate::ErrorCode;
+
+/// Largest payload of a LoRa packet.
+pub const MAX_PAYLOAD_LEN: usize = 255;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Bandwidth {
+    Bw125kHz,
+    Bw250kHz,
+    Bw500kHz,
+}
+
+impl Bandwidth {
+    pub fn hz(&self) -> u32 {
+        match *self {
+            Bandwidth::Bw125kHz => 125_000,
+            Bandwidth::Bw250kHz => 250_000,
+            Bandwidth::Bw500kHz => 500_000,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CodingRate {
+    Cr4_5 = 1,
+    Cr4_6 = 2,
+    Cr4_7 = 3,
+    Cr4_8 = 4,
+}
+
+/// Parameters of a transmission or reception.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Modulation {
+    /// Carrier frequency, in Hz.
+    pub frequency: u32,
+    /// Spreading factor, from 7 to 12.
+    pub spreading_factor: u8,
+    pub bandwidth: Bandwidth,
+    pub coding_rate: CodingRate,
+    /// Whether the I and Q signals are inverted. LoRaWAN gateways invert
+    /// them when transmitting, so that end devices only hear gateways.
+    pub invert_iq: bool,
+    /// Whether to use the sync word of public LoRaWAN networks rather than
+    /// the private one.
+    pub public_network: bool,
+}
+
+impl Modulation {
+    /// Duration of a symbol, in microseconds.
+    pub fn symbol_time_us(&self) -> u32 {
+        ((1_000_000u64 << self.spreading_factor) / self.bandwidth.hz() as u64) as u32
+    }
+
+    /// Whether the low data rate optimization must be enabled, which is
+    /// required when symbols are longer than 16 ms.
+    pub fn low_data_rate(&self) -> bool {
+        self.symbol_time_us() >= 16_000
+    }
+}
+
+pub trait TransmitClient {
+    /// The packet in `buf` was sent, or could not be.
+    fn transmit_done(&self, buf: &'static mut [u8], result: Result<(), ErrorCode>);
+}
+
+pub trait ReceiveClient {
+    /// A packet of `len` bytes was received into `buf`, or the reception
+    /// failed. `rssi` is the signal strength of the packet in dBm and `snr`
+    /// its signal to noise ratio in dB.
+    ///
+    /// `result` is:
+    /// - `Ok(())` if a packet was received.
+    /// - `Err(NOACK)` if no packet started before the timeout.
+    /// - `Err(FAIL)` if a packet was received with an invalid CRC or header.
+    fn receive_done(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        rssi: i16,
+        snr: i8,
+        result: Result<(), ErrorCode>,
+    );
+}
+
+pub trait Radio<'a> {
+    fn set_transmit_client(&self, client: &'a dyn TransmitClient);
+    fn set_receive_client(&self, client: &'a dyn ReceiveClient);
+
+    /// Send the first `len` bytes of `buf` with the given modulation and
+    /// transmit power, in dBm. The power is clamped to what the radio
+    /// supports.
+    ///
+    /// Returns `BUSY` if an operation is in progress, or `SIZE` if `len` is
+    /// larger than `buf` or `MAX_PAYLOAD_LEN`.
+    fn transmit(
+        &self,
+        modulation: Modulation,
+        power: i8,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Listen for a single packet with the given modulation, giving up if
+    /// none starts within `timeout_ms` milliseconds. `buf` should be at least
+    /// `MAX_PAYLOAD_LEN` bytes long; longer packets are truncated.
+    ///
+    /// Returns `BUSY` if an operation is in progress.
+    fn receive(
+        &self,
+        modulation: Modulation,
+        buf: &'static mut [u8],
+        timeout_ms: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Whether a transmission or reception is in progress.
+    fn is_busy(&self) -> bool;
+}
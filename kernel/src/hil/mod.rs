@@ -3,25 +3,38 @@
 pub mod adc;
 pub mod analog_comparator;
 pub mod ble_advertising;
+pub mod ble_connection;
 pub mod bus8080;
+pub mod can;
 pub mod crc;
 pub mod dac;
+pub mod date_time;
 pub mod digest;
 pub mod eic;
 pub mod entropy;
+pub mod ethernet;
+pub mod filesystem;
 pub mod flash;
 pub mod gpio;
 pub mod gpio_async;
 pub mod i2c;
+pub mod i2s;
 pub mod kv_system;
 pub mod led;
 pub mod log;
+pub mod lora;
 pub mod nonvolatile_storage;
+pub mod one_wire;
+pub mod power;
 pub mod pwm;
+pub mod pwm_capture;
+pub mod qdec;
+pub mod qspi;
 pub mod radio;
 pub mod rng;
 pub mod screen;
 pub mod sensors;
+pub mod signature;
 pub mod spi;
 pub mod symmetric_encryption;
 pub mod text_screen;
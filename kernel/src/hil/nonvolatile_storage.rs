@@ -14,7 +14,9 @@ pub trait NonvolatileStorage<'a> {
 
     /// Write `length` bytes starting at address `address` from the provided
     /// buffer. The buffer must be at least `length` bytes long. This address
-    /// must be in the address space of the physical storage.
+    /// must be in the address space of the physical storage. Afterwards the
+    /// storage must hold exactly these bytes, so implementations on memories
+    /// that cannot set bits from 0 to 1 in place must erase as needed.
     fn write(&self, buffer: &'a mut [u8], address: usize, length: usize) -> Result<(), ErrorCode>;
 }
 
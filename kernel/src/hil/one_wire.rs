@@ -0,0 +1,85 @@
+//! Interface for 1-Wire bus masters.
+//!
+//! A 1-Wire bus carries data and, optionally, power over a single line with
+//! a pull-up resistor. The master starts every transaction with a reset
+//! pulse, which devices answer with a presence pulse, and then transfers
+//! bits in time slots, least significant bit first. Each device has a unique
+//! 64-bit ROM code, which the master uses to address it, and which it can
+//! discover with the ROM search.
+//!
+//! Bus operations are split-phase: each one is started by a call that
+//! returns at once, and its slots are over when the client is called back.
+
+use crate::ErrorCode;
+
+/// ROM command to enumerate the devices on the bus.
+pub const SEARCH_ROM: u8 = 0xF0;
+/// ROM command to address the device with the ROM code that follows.
+pub const MATCH_ROM: u8 = 0x55;
+/// ROM command to address all devices on the bus.
+pub const SKIP_ROM: u8 = 0xCC;
+
+/// Dallas/Maxim CRC-8 (polynomial x^8 + x^5 + x^4 + 1), as used for ROM codes
+/// and device memory.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        let mut b = *byte;
+        for _ in 0..8 {
+            let mix = (crc ^ b) & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            b >>= 1;
+        }
+    }
+    crc
+}
+
+pub trait OneWire<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Send a reset pulse. `reset_done` reports whether any device answered
+    /// with a presence pulse.
+    fn reset(&self) -> Result<(), ErrorCode>;
+
+    fn write_bit(&self, bit: bool) -> Result<(), ErrorCode>;
+
+    fn read_bit(&self) -> Result<(), ErrorCode>;
+
+    fn write_byte(&self, byte: u8) -> Result<(), ErrorCode>;
+
+    fn read_byte(&self) -> Result<(), ErrorCode>;
+
+    /// Reset the bus and address either the device with `rom`, or all
+    /// devices. `select_done` fails with `NODEVICE` if no device is present.
+    fn select(&self, rom: Option<[u8; 8]>) -> Result<(), ErrorCode>;
+
+    /// Enumerate the devices on the bus with the ROM search, storing up to
+    /// `roms.len()` ROM codes. `search_done` returns the buffer with the
+    /// number of devices found.
+    fn search(
+        &self,
+        roms: &'static mut [[u8; 8]],
+    ) -> Result<(), (ErrorCode, &'static mut [[u8; 8]])>;
+}
+
+/// Each operation started on the bus ends with one of these callbacks, after
+/// which the bus is free for the next one.
+pub trait Client {
+    fn reset_done(&self, present: bool);
+
+    /// A bit or a byte has been written.
+    fn write_done(&self);
+
+    fn read_bit_done(&self, bit: bool);
+
+    fn read_byte_done(&self, byte: u8);
+
+    fn select_done(&self, result: Result<(), ErrorCode>);
+
+    /// The ROM search has finished. Fails with `NODEVICE` if no device is
+    /// present, and with `FAIL` if the search was corrupted.
+    fn search_done(&self, roms: &'static mut [[u8; 8]], result: Result<usize, ErrorCode>);
+}
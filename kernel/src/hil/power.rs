@@ -0,0 +1,102 @@
+//! Interface for sharing peripheral clocks and power domains between users.
+//!
+//! `ClockInterface` turns a clock on and off, so whoever turns it off must
+//! know that nobody else still needs it. Boards usually enable every clock
+//! at startup and never disable them. With `PowerControl`, each driver
+//! instead requests the clock or power domain it uses for the duration of
+//! an operation and releases it afterwards. The clock is turned on by the
+//! first request and off by the last release, so peripherals that nobody
+//! uses stay off without the board doing anything.
+//!
+//! `RefCountedClock` implements `PowerControl` over any `ClockInterface`.
+//! For register accesses that complete synchronously, `PowerRequest` holds
+//! the clock until it is dropped:
+//!
+//! ```rust,ignore
+//! fn read_status(&self) -> u32 {
+//!     let _power = PowerRequest::new(&self.clock);
+//!     self.registers.status.get()
+//! }
+//! ```
+
+use crate::ClockInterface;
+use core::cell::Cell;
+
+/// A clock or power domain that several users can keep on at once.
+pub trait PowerControl {
+    /// Keep the clock on until the matching `release()`, turning it on if
+    /// this is the only request.
+    fn request(&self);
+
+    /// Undo one `request()`, turning the clock off if it was the last one.
+    fn release(&self);
+
+    /// Whether any request is outstanding.
+    fn is_requested(&self) -> bool;
+}
+
+/// Counts the requests for a clock, and only enables it while there are
+/// any.
+pub struct RefCountedClock<C: ClockInterface> {
+    clock: C,
+    requests: Cell<usize>,
+}
+
+impl<C: ClockInterface> RefCountedClock<C> {
+    pub const fn new(clock: C) -> RefCountedClock<C> {
+        RefCountedClock {
+            clock: clock,
+            requests: Cell::new(0),
+        }
+    }
+
+    /// The clock itself, for example to check whether it is enabled.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+}
+
+impl<C: ClockInterface> PowerControl for RefCountedClock<C> {
+    fn request(&self) {
+        let requests = self.requests.get();
+        if requests == 0 {
+            self.clock.enable();
+        }
+        self.requests.set(requests + 1);
+    }
+
+    fn release(&self) {
+        match self.requests.get() {
+            // An unmatched release is a bug in the caller; the clock is
+            // already off.
+            0 => {}
+            1 => {
+                self.requests.set(0);
+                self.clock.disable();
+            }
+            requests => self.requests.set(requests - 1),
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requests.get() > 0
+    }
+}
+
+/// Requests a clock when created and releases it when dropped.
+pub struct PowerRequest<'a> {
+    power: &'a dyn PowerControl,
+}
+
+impl<'a> PowerRequest<'a> {
+    pub fn new(power: &'a dyn PowerControl) -> PowerRequest<'a> {
+        power.request();
+        PowerRequest { power: power }
+    }
+}
+
+impl Drop for PowerRequest<'_> {
+    fn drop(&mut self) {
+        self.power.release();
+    }
+}
@@ -0,0 +1,31 @@
+//! Interface for measuring a PWM signal with the input capture of a timer.
+//!
+//! The timer latches its counter on the edges of the signal in hardware, so
+//! the period and duty cycle measured do not depend on interrupt latency,
+//! unlike timestamping GPIO interrupts. Each period of the signal, from one
+//! rising edge to the next, is reported with the time the signal was high,
+//! in ticks of the capture clock.
+//!
+//! A signal that stops toggling is not reported: periods simply stop
+//! arriving, and detecting a lost signal is left to the client.
+
+use crate::ErrorCode;
+
+pub trait PwmCapture<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Frequency of the clock periods are measured with, in Hz.
+    fn get_frequency(&self) -> u32;
+
+    /// Start capturing the signal. Every period that follows is reported.
+    fn start(&self) -> Result<(), ErrorCode>;
+
+    /// Stop capturing the signal.
+    fn stop(&self) -> Result<(), ErrorCode>;
+}
+
+pub trait Client {
+    /// A period of the signal ended with a rising edge. It lasted `period`
+    /// ticks, of which the signal was high for `high`.
+    fn captured(&self, period: u32, high: u32);
+}
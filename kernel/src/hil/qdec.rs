@@ -0,0 +1,39 @@
+//! Interface for quadrature decoders.
+//!
+//! A quadrature decoder counts the steps of a rotary encoder from its two
+//! phase-shifted signals, up in one direction and down in the other. Its
+//! hardware counter is narrow, so steps are taken from it as they come,
+//! relative to the previous take, and accumulated by the client.
+//!
+//! The decoder tells the client when steps are waiting to be taken, often
+//! enough that its counter does not overflow while the encoder turns at a
+//! reasonable speed. If steps are lost anyway, it tells the client so.
+
+use crate::ErrorCode;
+
+pub trait Qdec<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Start counting steps, from zero.
+    fn enable(&self) -> Result<(), ErrorCode>;
+
+    /// Stop counting steps.
+    fn disable(&self) -> Result<(), ErrorCode>;
+
+    fn is_enabled(&self) -> bool;
+
+    /// Take the steps counted since the previous take, or since the decoder
+    /// was enabled: positive in the forward direction, negative in the
+    /// other.
+    fn take_steps(&self) -> i32;
+}
+
+pub trait Client {
+    /// Steps are waiting to be taken, and should be before the counter of
+    /// the decoder overflows.
+    fn steps_ready(&self);
+
+    /// The decoder counted more steps than its counter holds, and some of
+    /// them were lost.
+    fn overflow(&self);
+}
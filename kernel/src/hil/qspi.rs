@@ -0,0 +1,120 @@
+//! Interface for Quad SPI controllers of external NOR flash.
+//!
+//! A QSPI controller talks to a serial NOR flash over up to four data lines.
+//! It reads, programs and erases the flash with indirect operations, which
+//! transfer between the flash and a buffer and complete with a callback. It
+//! can also map the flash into the address space of the chip, so that code
+//! executes in place (XIP) and data is read directly.
+//!
+//! Addresses and lengths of indirect operations are multiples of 4 bytes,
+//! and buffers are word aligned, as controllers with DMA require. A program
+//! must not cross a page of `PROGRAM_PAGE_SIZE` bytes, and can only clear
+//! bits, so the area has to be erased first.
+//!
+//! The controller enables writes of the flash before programs and erases,
+//! and waits until the flash is done before it completes them. Quad read and
+//! write modes need the quad mode of the flash enabled, and 32-bit addresses
+//! its 4-byte address mode, both of which are left to the board.
+
+use crate::ErrorCode;
+
+/// Largest program, and the alignment it must not cross.
+pub const PROGRAM_PAGE_SIZE: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AddressWidth {
+    Bits24,
+    Bits32,
+}
+
+/// Command that reads the flash, which also reads the memory-mapped flash.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReadMode {
+    /// FAST_READ (0x0B), on a single line.
+    Fast,
+    /// DOR (0x3B), with data on two lines.
+    DualOutput,
+    /// DIOR (0xBB), with address and data on two lines.
+    DualIo,
+    /// QOR (0x6B), with data on four lines.
+    QuadOutput,
+    /// QIOR (0xEB), with address and data on four lines.
+    QuadIo,
+}
+
+/// Command that programs the flash.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WriteMode {
+    /// PP (0x02), on a single line.
+    Single,
+    /// QPP (0x32), with data on four lines.
+    QuadOutput,
+}
+
+/// Area of an erase, aligned to its size.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EraseSize {
+    Sector4K,
+    Block64K,
+    Chip,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Highest SCK frequency in Hz. The controller uses the closest one it
+    /// supports that is not higher.
+    pub frequency: u32,
+    /// Size of the flash in bytes, a power of two.
+    pub size: usize,
+    pub address_width: AddressWidth,
+    pub read_mode: ReadMode,
+    pub write_mode: WriteMode,
+}
+
+pub trait Qspi<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Configure the controller for the flash, while no operation is in
+    /// progress and the flash is not memory-mapped.
+    fn configure(&self, config: Config) -> Result<(), ErrorCode>;
+
+    /// Read `len` bytes at `address` into `buffer`. On error, the buffer is
+    /// returned.
+    fn read(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Program the first `len` bytes of `buffer` at `address`. On error, the
+    /// buffer is returned.
+    fn write(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Erase the area of `size` at `address`, setting every byte to 0xFF.
+    /// `address` is ignored for a chip erase.
+    fn erase(&self, address: usize, size: EraseSize) -> Result<(), ErrorCode>;
+
+    /// Map the flash into memory, and return the address of its first byte.
+    /// While mapped, indirect operations fail with `BUSY`.
+    fn enable_memory_mapped(&self) -> Result<usize, ErrorCode>;
+
+    /// Stop mapping the flash into memory.
+    fn disable_memory_mapped(&self) -> Result<(), ErrorCode>;
+}
+
+pub trait Client {
+    /// A read completed, and `buffer` holds `len` bytes of the flash.
+    fn read_complete(&self, buffer: &'static mut [u8], len: usize, result: Result<(), ErrorCode>);
+
+    /// A program of `len` bytes completed.
+    fn write_complete(&self, buffer: &'static mut [u8], len: usize, result: Result<(), ErrorCode>);
+
+    /// An erase completed.
+    fn erase_complete(&self, result: Result<(), ErrorCode>);
+}
@@ -31,6 +31,12 @@ pub trait PowerClient {
     fn changed(&self, on: bool);
 }
 
+pub trait EnergyScanClient {
+    /// The energy scan of `channel` finished. `energy` is the peak energy
+    /// detected during the scan, in dBm.
+    fn scan_done(&self, channel: u8, energy: Result<i8, ErrorCode>);
+}
+
 /// These constants are used for interacting with the SPI buffer, which contains
 /// a 1-byte SPI command, a 1-byte PHY header, and then the 802.15.4 frame. In
 /// theory, the number of extra bytes in front of the frame can depend on the
@@ -62,6 +68,16 @@ pub const PSDU_OFFSET: usize = 2;
 pub const MAX_BUF_SIZE: usize = PSDU_OFFSET + MAX_MTU;
 pub const MIN_PAYLOAD_OFFSET: usize = PSDU_OFFSET + MIN_MHR_SIZE;
 
+/// Outcome of the clear channel assessments (CCA) run before transmitting on
+/// a channel.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub struct CcaStats {
+    /// Number of CCAs run.
+    pub attempts: u32,
+    /// Number of CCAs that found the channel busy.
+    pub busy: u32,
+}
+
 pub trait Radio: RadioConfig + RadioData {}
 
 /// Configure the 802.15.4 radio.
@@ -99,6 +115,10 @@ pub trait RadioConfig {
     fn set_pan(&self, id: u16);
     fn set_tx_power(&self, power: i8) -> Result<(), ErrorCode>;
     fn set_channel(&self, chan: u8) -> Result<(), ErrorCode>;
+    /// Sets whether automatically generated ACKs have the frame pending bit
+    /// set, telling a polling device that frames are queued for it. Radios
+    /// that do not generate ACKs may ignore this.
+    fn set_ack_frame_pending(&self, pending: bool);
 }
 
 pub trait RadioData {
@@ -112,3 +132,20 @@ pub trait RadioData {
         frame_len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])>;
 }
+
+/// Measure the activity on 802.15.4 channels, to pick a quiet one.
+pub trait RadioEnergy {
+    fn set_energy_scan_client(&self, client: &'static dyn EnergyScanClient);
+
+    /// Measure the energy on `channel` for at least `duration_us`
+    /// microseconds, then issue a callback with the peak energy. Frames are
+    /// neither sent nor received during the scan, after which the radio
+    /// returns to the configured channel. Returns `INVAL` for an invalid
+    /// channel and `BUSY` if a frame is being sent or a scan is running.
+    fn energy_scan(&self, channel: u8, duration_us: u32) -> Result<(), ErrorCode>;
+
+    /// The CCA counts of `channel` since the last reset. Returns `INVAL` for
+    /// an invalid channel.
+    fn cca_stats(&self, channel: u8) -> Result<CcaStats, ErrorCode>;
+    fn reset_cca_stats(&self);
+}
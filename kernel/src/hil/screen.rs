@@ -222,11 +222,29 @@ pub trait Screen {
 
 pub trait ScreenAdvanced: Screen + ScreenSetup {}
 
+/// Screens that can signal their vertical blanking interval, for example from
+/// a tearing effect line, so writes can start while the panel is not being
+/// refreshed.
+pub trait ScreenVsync {
+    fn set_vsync_client(&self, client: Option<&'static dyn ScreenVsyncClient>);
+
+    /// Starts calling `vsync()` at every vertical blanking interval.
+    fn enable_vsync(&self) -> Result<(), ErrorCode>;
+
+    /// Stops the `vsync()` callbacks.
+    fn disable_vsync(&self) -> Result<(), ErrorCode>;
+}
+
 pub trait ScreenSetupClient {
     /// The screen will call this function to notify that a command has finished.
     fn command_complete(&self, r: Result<(), ErrorCode>);
 }
 
+pub trait ScreenVsyncClient {
+    /// The screen will call this function when it enters the vertical blanking interval.
+    fn vsync(&self);
+}
+
 pub trait ScreenClient {
     /// The screen will call this function to notify that a command (except write) has finished.
     fn command_complete(&self, r: Result<(), ErrorCode>);
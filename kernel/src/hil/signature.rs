@@ -0,0 +1,15 @@
+//! Interface for verifying digital signatures.
+
+use crate::ErrorCode;
+
+/// Verifies signatures of message digests with the public key of the
+/// verifier.
+///
+/// Verification is synchronous, as processes are checked while they are
+/// loaded at boot, before the kernel services interrupts.
+pub trait SignatureVerify {
+    /// Returns `Ok(true)` if `signature` is a valid signature of `hash`, and
+    /// `Ok(false)` if it is not. Fails with `SIZE` if `hash` or `signature`
+    /// do not have the lengths the verifier expects.
+    fn verify(&self, hash: &[u8], signature: &[u8]) -> Result<bool, ErrorCode>;
+}
@@ -118,6 +118,11 @@ pub trait SpiMaster {
     // allow an application to manually control when the
     // CS line is high or low, such that it can issue multi-byte
     // requests with single byte operations.
+    //
+    // Controllers that can drive the chip select outside of a
+    // transfer also bring it low as soon as hold_low() is called,
+    // and bring a held chip select high as soon as release_low()
+    // is called if no transfer is in progress.
     fn hold_low(&self);
     fn release_low(&self);
 }
@@ -149,6 +154,14 @@ pub trait SpiMasterDevice {
     fn get_polarity(&self) -> ClockPolarity;
     fn get_phase(&self) -> ClockPhase;
     fn get_rate(&self) -> u32;
+
+    /// Hold the chip select low after transfers complete, as with
+    /// `SpiMaster::hold_low()`. While the chip select is held, the bus
+    /// is not used to talk to any other device.
+    fn hold_low(&self);
+    /// Stop holding the chip select low, as with
+    /// `SpiMaster::release_low()`, and let other devices use the bus.
+    fn release_low(&self);
 }
 
 pub trait SpiSlaveClient {
@@ -135,3 +135,44 @@ pub trait AES128CCM<'a> {
         encrypting: bool,
     ) -> Result<(), (ErrorCode, &'static mut [u8])>;
 }
+
+pub trait GCMClient {
+    /// `res` is Ok(()) if the encryption/decryption process succeeded. This
+    /// does not mean that the message has been verified in the case of
+    /// decryption.
+    /// If we are encrypting: `tag_is_valid` is `true` iff `res` is Ok(()).
+    /// If we are decrypting: `tag_is_valid` is `true` iff `res` is Ok(()) and the
+    /// message authentication tag is valid. The message is decrypted in
+    /// either case, and must not be used unless the tag is valid.
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool);
+}
+
+pub const GCM_NONCE_LENGTH: usize = 12;
+pub const GCM_TAG_LENGTH: usize = 16;
+
+pub trait AES128GCM<'a> {
+    /// Set the client instance which will receive `crypt_done()` callbacks
+    fn set_client(&'a self, client: &'a dyn GCMClient);
+
+    /// Set the key to be used for GCM encryption
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Set the nonce (length GCM_NONCE_LENGTH) to be used for GCM encryption
+    fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Try to begin the encryption/decryption process.
+    ///
+    /// `buf` holds the additional authenticated data from `a_off` to `m_off`,
+    /// the message of `m_len` bytes from `m_off`, and then the
+    /// `GCM_TAG_LENGTH` byte tag. The message is encrypted or decrypted in
+    /// place. When encrypting the tag is written, and when decrypting it is
+    /// checked.
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        a_off: usize,
+        m_off: usize,
+        m_len: usize,
+        encrypting: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
@@ -12,6 +12,7 @@
 //! into these more general ones.
 
 use crate::ErrorCode;
+use core::cell::Cell;
 use core::cmp::{Eq, Ord, Ordering, PartialOrd};
 use core::fmt;
 
@@ -104,6 +105,36 @@ fn ticks_from_val<T: Ticks>(val: u64) -> T {
     }
 }
 
+/// Extends the lower 32 bits of a counter to a 64-bit tick count that does
+/// not wrap, by counting the times the counter wraps. A wrap is only seen if
+/// `extend` is called at least once per wrap period, e.g. from an alarm set
+/// half a period ahead.
+pub struct OverflowTracker {
+    last: Cell<u32>,
+    overflows: Cell<u64>,
+}
+
+impl OverflowTracker {
+    pub const fn new() -> OverflowTracker {
+        OverflowTracker {
+            last: Cell::new(0),
+            overflows: Cell::new(0),
+        }
+    }
+
+    /// Returns the 64-bit tick count at `now`, a recent value of the
+    /// counter.
+    pub fn extend<T: Ticks>(&self, now: T) -> u64 {
+        let now = now.into_u32();
+        if now < self.last.get() {
+            self.overflows.set(self.overflows.get() + 1);
+        }
+        self.last.set(now);
+        let period = T::max_value().into_u32() as u64 + 1;
+        self.overflows.get() * period + now as u64
+    }
+}
+
 /// Represents a static moment in time, that does not change over
 /// repeated calls to `Time::now`.
 pub trait Timestamp: Time {}
@@ -22,6 +22,10 @@ pub enum Width {
     Six = 6,
     Seven = 7,
     Eight = 8,
+    /// Nine data bits, which only fit in words: see `transmit_word` and
+    /// `receive_word`. In multidrop (RS-485) networks the ninth bit marks
+    /// address bytes.
+    Nine = 9,
 }
 
 #[derive(Copy, Clone, Debug)]
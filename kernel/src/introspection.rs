@@ -152,4 +152,14 @@ impl KernelInfo {
         });
         count.get()
     }
+
+    /// Returns the total time all processes have run with a timeslice, in
+    /// microseconds.
+    pub fn execution_time_us(&self, _capability: &dyn ProcessManagementCapability) -> u64 {
+        let total: Cell<u64> = Cell::new(0);
+        self.kernel.process_each(|proc| {
+            total.set(total.get() + proc.debug_execution_time_us());
+        });
+        total.get()
+    }
 }
@@ -2,6 +2,13 @@
 //!
 //! This is a special syscall driver that allows userspace applications to
 //! share memory.
+//!
+//! Services are found either by the package name of their process, or by a
+//! service name and version they register at runtime. Clients can subscribe
+//! to service events to learn when a service with the name in their discovery
+//! buffer registers, for example after it restarted.
+
+use core::cell::Cell;
 
 use crate::capabilities::MemoryAllocationCapability;
 use crate::grant::Grant;
@@ -15,6 +22,13 @@ use crate::{CommandReturn, Driver, ErrorCode, ReadOnlyAppSlice, ReadWriteAppSlic
 /// Syscall number
 pub const DRIVER_NUM: usize = 0x10000;
 
+/// Subscribe number of the service event upcall. Other subscribe numbers
+/// are service identifiers, which never get this large.
+pub const SERVICE_EVENTS_SUBSCRIBE: usize = 0xFFFF_FFFF;
+
+/// Maximum length of a registered service name, in bytes.
+pub const MAX_SERVICE_NAME_LEN: usize = 32;
+
 /// Enum to mark which type of upcall is scheduled for the IPC mechanism.
 #[derive(Copy, Clone, Debug)]
 pub enum IPCUpcallType {
@@ -37,6 +51,14 @@ struct IPCData<const NUM_PROCS: usize> {
     client_upcalls: [Upcall; NUM_PROCS],
     /// The upcall setup by a service. Each process can only be one service.
     upcall: Upcall,
+    /// Name to register the service under.
+    register_slice: ReadOnlyAppSlice,
+    /// The registered service name, empty if the process did not register.
+    service_name: [u8; MAX_SERVICE_NAME_LEN],
+    service_name_len: usize,
+    service_version: u32,
+    /// Upcall for services registering under the name in `search_slice`.
+    events_upcall: Upcall,
 }
 
 impl<const NUM_PROCS: usize> Default for IPCData<NUM_PROCS> {
@@ -47,6 +69,11 @@ impl<const NUM_PROCS: usize> Default for IPCData<NUM_PROCS> {
             search_slice: ReadOnlyAppSlice::default(),
             client_upcalls: [Upcall::default(); NUM_PROCS],
             upcall: Upcall::default(),
+            register_slice: ReadOnlyAppSlice::default(),
+            service_name: [0; MAX_SERVICE_NAME_LEN],
+            service_name_len: 0,
+            service_version: 0,
+            events_upcall: Upcall::default(),
         }
     }
 }
@@ -120,6 +147,100 @@ impl<const NUM_PROCS: usize> IPC<NUM_PROCS> {
             })
             .and_then(|x| x)
     }
+
+    /// Copy the name in `slice` of `appid`, selected by `select`, into
+    /// `name`. Returns its length, or `None` if it is empty or too long.
+    fn copy_name<F>(
+        &self,
+        appid: ProcessId,
+        name: &mut [u8; MAX_SERVICE_NAME_LEN],
+        select: F,
+    ) -> Option<usize>
+    where
+        F: FnOnce(&IPCData<NUM_PROCS>) -> &ReadOnlyAppSlice,
+    {
+        self.data
+            .enter(appid, |data| {
+                select(data).map_or(None, |slice| {
+                    if slice.len() == 0 || slice.len() > MAX_SERVICE_NAME_LEN {
+                        return None;
+                    }
+                    name[..slice.len()].copy_from_slice(&slice[..]);
+                    Some(slice.len())
+                })
+            })
+            .unwrap_or(None)
+    }
+
+    /// Register `appid` as the service named in its registration buffer,
+    /// and tell the clients looking for that name.
+    fn register_service(&self, appid: ProcessId, version: u32) -> CommandReturn {
+        let mut name = [0; MAX_SERVICE_NAME_LEN];
+        let len = match self.copy_name(appid, &mut name, |data| &data.register_slice) {
+            Some(len) => len,
+            None => return CommandReturn::failure(ErrorCode::SIZE),
+        };
+        let name = &name[..len];
+
+        // Names are unique.
+        let taken = Cell::new(false);
+        self.data.each(|processid, data| {
+            if processid != appid && &data.service_name[..data.service_name_len] == name {
+                taken.set(true);
+            }
+        });
+        if taken.get() {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+
+        let res = self.data.enter(appid, |data| {
+            data.service_name[..len].copy_from_slice(name);
+            data.service_name_len = len;
+            data.service_version = version;
+        });
+        if let Err(e) = res {
+            return CommandReturn::failure(e.into());
+        }
+
+        // A process that registers after restarting is a service that
+        // restarted, rather than a new one.
+        let restarted = self
+            .data
+            .kernel
+            .process_map_or(false, appid, |process| process.get_restart_count() > 0);
+        self.data.each(|processid, data| {
+            if processid != appid && data.search_slice.map_or(false, |slice| &slice[..] == name) {
+                data.events_upcall
+                    .schedule(appid.id() + 1, version as usize, restarted as usize);
+            }
+        });
+        CommandReturn::success()
+    }
+
+    /// Find the service registered under the name in the discovery buffer
+    /// of `appid`, with at least version `min_version`.
+    fn discover_service(&self, appid: ProcessId, min_version: u32) -> CommandReturn {
+        let mut name = [0; MAX_SERVICE_NAME_LEN];
+        let len = match self.copy_name(appid, &mut name, |data| &data.search_slice) {
+            Some(len) => len,
+            None => return CommandReturn::failure(ErrorCode::NODEVICE),
+        };
+        let name = &name[..len];
+
+        let found = Cell::new(None);
+        self.data.each(|processid, data| {
+            if &data.service_name[..data.service_name_len] == name {
+                found.set(Some((processid, data.service_version)));
+            }
+        });
+        match found.get() {
+            Some((service, version)) if version >= min_version => {
+                CommandReturn::success_u32_u32(service.id() as u32 + 1, version)
+            }
+            Some(_) => CommandReturn::failure(ErrorCode::NOSUPPORT),
+            None => CommandReturn::failure(ErrorCode::NODEVICE),
+        }
+    }
 }
 
 impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
@@ -148,6 +269,19 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
                 })
                 .map_err(|e| (upcall, e.into())),
 
+            // subscribe(SERVICE_EVENTS_SUBSCRIBE)
+            //
+            // The upcall is called when a service registers under the name
+            // in the discovery buffer, with the service descriptor, its
+            // version, and `1` if the service restarted or `0` if it is new.
+            SERVICE_EVENTS_SUBSCRIBE => self
+                .data
+                .enter(app_id, |data| {
+                    core::mem::swap(&mut data.events_upcall, &mut upcall);
+                    upcall
+                })
+                .map_err(|e| (upcall, e.into())),
+
             // subscribe(>=1)
             //
             // Subscribe with subscribe_num >= 1 is how a client registers
@@ -208,6 +342,13 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
     /// - `3`: Notify a client with descriptor `target_id`, typically in response to a previous
     ///        notify from the client. Returns an error if `target_id` refers to an invalid client
     ///        or the notify fails to enqueue.
+    /// - `4`: Perform discovery on the service name passed to `allow_readonly`, for a service
+    ///        with at least version `target_id`. Returns the service descriptor and version
+    ///        if found, `NOSUPPORT` if the service has an older version, and `NODEVICE` if no
+    ///        service registered that name.
+    /// - `5`: Register this process as a service, under the name passed to `allow_readonly`
+    ///        with subdriver number `1` and with version `target_id`. Returns `BUSY` if
+    ///        another process registered that name.
     fn command(
         &self,
         command_number: usize,
@@ -251,6 +392,13 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
             {
                 let cb_type = IPCUpcallType::Service;
                 let app_identifier = target_id - 1;
+                // The service works on behalf of the client, so it must not
+                // wait behind processes with a lower priority than the
+                // client's.
+                let priority = self
+                    .data
+                    .kernel
+                    .process_map_or(u32::MAX, appid, |client| client.get_priority());
 
                 self.data
                     .kernel
@@ -262,7 +410,10 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
                             |target| {
                                 let ret = target.enqueue_task(process::Task::IPC((appid, cb_type)));
                                 match ret {
-                                    true => CommandReturn::success(),
+                                    true => {
+                                        target.inherit_priority(priority);
+                                        CommandReturn::success()
+                                    }
                                     false => CommandReturn::failure(ErrorCode::FAIL),
                                 }
                             },
@@ -292,12 +443,16 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
                         )
                     })
             }
+            4 => self.discover_service(appid, target_id as u32),
+            5 => self.register_service(appid, target_id as u32),
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
 
     /// allow_readonly with subdriver number `0` stores the provided buffer for service discovery.
-    /// The buffer should contain the package name of a process that exports an IPC service.
+    /// The buffer should contain the package name of a process that exports an IPC service, or
+    /// the name a service registered. Subdriver number `1` stores the name to register the
+    /// process as a service under.
     fn allow_readonly(
         &self,
         appid: ProcessId,
@@ -313,6 +468,15 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
                 Ok(_) => Ok(slice),
                 Err(e) => Err((slice, e.into())),
             }
+        } else if subdriver == 1 {
+            // Name to register the service under
+            let res = self.data.enter(appid, |data| {
+                core::mem::swap(&mut data.register_slice, &mut slice);
+            });
+            match res {
+                Ok(_) => Ok(slice),
+                Err(e) => Err((slice, e.into())),
+            }
         } else {
             Err((slice, ErrorCode::NOSUPPORT))
         }
@@ -0,0 +1,336 @@
+//! Message queues between processes.
+//!
+//! With `ipc`, processes share whole buffers, which must be aligned for the
+//! MPU, and signal each other with a single notify. This driver instead
+//! copies small messages, of up to `MAX_MESSAGE_LEN` bytes, from the sender
+//! into a queue in the grant region of the receiver. There is one queue for
+//! each pair of processes, so a sender cannot fill the queues of the others.
+//! Neither process can access the memory of the other, which makes it simpler
+//! to write request and response services.
+//!
+//! Processes find each other with the discovery command of `ipc`, and use the
+//! same identifiers.
+//!
+//! When the queue to a receiver is full, sending fails with `BUSY`. The sender
+//! can then wait for the space upcall, which the kernel schedules when the
+//! receiver takes a message out of the queue. Messages still queued when their
+//! sender restarts are dropped.
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: The message to send.
+//! - Read-write `0`: The buffer messages are received into.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Message upcall, with the identifier of the sender and the length of
+//!   the message, when a message is queued.
+//! - `1`: Space upcall, with the identifier of the receiver, when a queue that
+//!   was full has room again.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Send the first `data2` bytes of the message buffer to the process
+//!   `data1`. Returns `BUSY` if its queue is full, and `SIZE` if the message
+//!   is longer than `MAX_MESSAGE_LEN` or the buffer.
+//! - `2`: Receive the oldest message from the process `data1`, or from any
+//!   process if `data1` is `0`, into the receive buffer. Returns the
+//!   identifier of the sender and the length of the message, `FAIL` if there
+//!   is no message, and `SIZE` if the receive buffer is too short, in which
+//!   case the message stays queued.
+
+use crate::capabilities::MemoryAllocationCapability;
+use crate::grant::Grant;
+use crate::mem::{Read, ReadWrite};
+use crate::process::ProcessId;
+use crate::sched::Kernel;
+use crate::upcall::Upcall;
+use crate::{CommandReturn, Driver, ErrorCode, ReadOnlyAppSlice, ReadWriteAppSlice};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x10005;
+
+/// Size of the queue from a sender to a receiver, in bytes. Each message
+/// takes its length plus one byte.
+pub const QUEUE_LEN: usize = 64;
+
+/// Maximum length of a message, in bytes.
+pub const MAX_MESSAGE_LEN: usize = 32;
+
+/// Messages from one sender, as a ring of length-prefixed messages.
+struct Queue {
+    /// The process the queued messages are from.
+    sender: Option<ProcessId>,
+    buffer: [u8; QUEUE_LEN],
+    start: usize,
+    len: usize,
+    /// Whether the sender was told the queue is full, and should get the
+    /// space upcall.
+    sender_waiting: bool,
+}
+
+const EMPTY_QUEUE: Queue = Queue {
+    sender: None,
+    buffer: [0; QUEUE_LEN],
+    start: 0,
+    len: 0,
+    sender_waiting: false,
+};
+
+impl Queue {
+    /// Add `message` from `sender`. Returns `false` if the queue is full.
+    fn push(&mut self, sender: ProcessId, message: &[u8]) -> bool {
+        if self.sender != Some(sender) {
+            // The previous sender in this slot restarted or exited.
+            *self = EMPTY_QUEUE;
+            self.sender = Some(sender);
+        }
+        if self.len + message.len() + 1 > QUEUE_LEN {
+            return false;
+        }
+        let end = self.start + self.len;
+        self.buffer[end % QUEUE_LEN] = message.len() as u8;
+        for (i, byte) in message.iter().enumerate() {
+            self.buffer[(end + 1 + i) % QUEUE_LEN] = *byte;
+        }
+        self.len += message.len() + 1;
+        true
+    }
+
+    /// Length of the oldest message, if any.
+    fn peek_len(&self) -> Option<usize> {
+        if self.len == 0 {
+            None
+        } else {
+            Some(self.buffer[self.start] as usize)
+        }
+    }
+
+    /// Copy the oldest message into `out`, which must be long enough, and
+    /// remove it.
+    fn pop(&mut self, out: &mut [u8]) {
+        if let Some(len) = self.peek_len() {
+            for (i, byte) in out[..len].iter_mut().enumerate() {
+                *byte = self.buffer[(self.start + 1 + i) % QUEUE_LEN];
+            }
+            self.start = (self.start + len + 1) % QUEUE_LEN;
+            self.len -= len + 1;
+        }
+    }
+}
+
+/// State that is stored in each process's grant region to support message
+/// queues.
+struct QueueData<const NUM_PROCS: usize> {
+    /// Messages sent to this process, by sender index.
+    queues: [Queue; NUM_PROCS],
+    send_slice: ReadOnlyAppSlice,
+    receive_slice: ReadWriteAppSlice,
+    message_upcall: Upcall,
+    space_upcall: Upcall,
+}
+
+impl<const NUM_PROCS: usize> Default for QueueData<NUM_PROCS> {
+    fn default() -> QueueData<NUM_PROCS> {
+        QueueData {
+            queues: [EMPTY_QUEUE; NUM_PROCS],
+            send_slice: ReadOnlyAppSlice::default(),
+            receive_slice: ReadWriteAppSlice::default(),
+            message_upcall: Upcall::default(),
+            space_upcall: Upcall::default(),
+        }
+    }
+}
+
+/// The message queue IPC mechanism struct.
+pub struct IPCQueue<const NUM_PROCS: usize> {
+    /// The grant regions for each process that holds the per-process queues.
+    data: Grant<QueueData<NUM_PROCS>>,
+}
+
+impl<const NUM_PROCS: usize> IPCQueue<NUM_PROCS> {
+    pub fn new(kernel: &'static Kernel, capability: &dyn MemoryAllocationCapability) -> Self {
+        Self {
+            data: kernel.create_grant(capability),
+        }
+    }
+
+    /// Send the first `len` bytes of the message buffer of `appid` to the
+    /// process whose identifier is `target_id - 1`.
+    fn send(&self, appid: ProcessId, target_id: usize, len: usize) -> CommandReturn {
+        let target = match target_id
+            .checked_sub(1)
+            .and_then(|identifier| self.data.kernel.lookup_app_by_identifier(identifier))
+        {
+            Some(target) => target,
+            None => return CommandReturn::failure(ErrorCode::INVAL),
+        };
+        let sender_index = match appid.index() {
+            Some(i) if i < NUM_PROCS => i,
+            _ => return CommandReturn::failure(ErrorCode::INVAL),
+        };
+        if len > MAX_MESSAGE_LEN {
+            return CommandReturn::failure(ErrorCode::SIZE);
+        }
+
+        let mut message = [0; MAX_MESSAGE_LEN];
+        let copied = self
+            .data
+            .enter(appid, |data| {
+                data.send_slice.map_or(false, |slice| {
+                    if slice.len() < len {
+                        return false;
+                    }
+                    message[..len].copy_from_slice(&slice[..len]);
+                    true
+                })
+            })
+            .unwrap_or(false);
+        if !copied {
+            return CommandReturn::failure(ErrorCode::SIZE);
+        }
+
+        self.data
+            .enter(target, |data| {
+                let queue = &mut data.queues[sender_index];
+                if queue.push(appid, &message[..len]) {
+                    data.message_upcall.schedule(appid.id() + 1, len, 0);
+                    CommandReturn::success()
+                } else {
+                    queue.sender_waiting = true;
+                    CommandReturn::failure(ErrorCode::BUSY)
+                }
+            })
+            .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+    }
+
+    /// Receive the oldest message from the process whose identifier is
+    /// `from_id - 1`, or from any process if `from_id` is `0`.
+    fn receive(&self, appid: ProcessId, from_id: usize) -> CommandReturn {
+        let mut waiting_sender = None;
+        let ret = self
+            .data
+            .enter(appid, |data| {
+                let data: &mut QueueData<NUM_PROCS> = data;
+                let queue = data.queues.iter_mut().find(|queue| {
+                    queue.peek_len().is_some()
+                        && queue
+                            .sender
+                            .map_or(false, |sender| from_id == 0 || sender.id() + 1 == from_id)
+                });
+                let queue = match queue {
+                    Some(queue) => queue,
+                    None => return CommandReturn::failure(ErrorCode::FAIL),
+                };
+                let len = queue.peek_len().unwrap_or(0);
+                let received = data.receive_slice.mut_map_or(false, |slice| {
+                    if slice.len() < len {
+                        return false;
+                    }
+                    queue.pop(slice);
+                    true
+                });
+                if !received {
+                    return CommandReturn::failure(ErrorCode::SIZE);
+                }
+                if queue.sender_waiting {
+                    queue.sender_waiting = false;
+                    waiting_sender = queue.sender;
+                }
+                let sender_id = queue.sender.map_or(0, |sender| sender.id() + 1);
+                CommandReturn::success_u32_u32(sender_id as u32, len as u32)
+            })
+            .unwrap_or_else(|err| CommandReturn::failure(err.into()));
+
+        // Tell a sender that was blocked on the queue that it has room.
+        if let Some(sender) = waiting_sender {
+            let _ = self.data.enter(sender, |data| {
+                data.space_upcall.schedule(appid.id() + 1, 0, 0);
+            });
+        }
+        ret
+    }
+}
+
+impl<const NUM_PROCS: usize> Driver for IPCQueue<NUM_PROCS> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut upcall: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .data
+            .enter(app_id, |data| match subscribe_num {
+                0 => {
+                    core::mem::swap(&mut data.message_upcall, &mut upcall);
+                    Ok(())
+                }
+                1 => {
+                    core::mem::swap(&mut data.space_upcall, &mut upcall);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(upcall),
+            Err(e) => Err((upcall, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_number: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_number {
+            0 => CommandReturn::success(),
+            1 => self.send(appid, data1, data2),
+            2 => self.receive(appid, data1),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        subdriver: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        if subdriver == 0 {
+            let res = self.data.enter(appid, |data| {
+                core::mem::swap(&mut data.send_slice, &mut slice);
+            });
+            match res {
+                Ok(_) => Ok(slice),
+                Err(e) => Err((slice, e.into())),
+            }
+        } else {
+            Err((slice, ErrorCode::NOSUPPORT))
+        }
+    }
+
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        subdriver: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        if subdriver == 0 {
+            let res = self.data.enter(appid, |data| {
+                core::mem::swap(&mut data.receive_slice, &mut slice);
+            });
+            match res {
+                Ok(_) => Ok(slice),
+                Err(e) => Err((slice, e.into())),
+            }
+        } else {
+            Err((slice, ErrorCode::NOSUPPORT))
+        }
+    }
+}
@@ -96,6 +96,8 @@ pub mod debug;
 pub mod hil;
 pub mod introspection;
 pub mod ipc;
+pub mod ipc_queue;
+pub mod shared_memory;
 pub mod syscall;
 
 mod config;
@@ -106,10 +108,12 @@ mod mem;
 mod memop;
 mod platform;
 mod process;
+mod process_checker;
 mod process_policies;
 mod process_standard;
 mod process_utilities;
 mod sched;
+mod syscall_filter;
 mod upcall;
 
 pub use crate::driver::{CommandReturn, Driver};
@@ -117,17 +121,21 @@ pub use crate::errorcode::into_statuscode;
 pub use crate::errorcode::ErrorCode;
 pub use crate::grant::{Grant, ProcessGrant};
 pub use crate::mem::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+pub use crate::platform::power;
 pub use crate::platform::scheduler_timer::{SchedulerTimer, VirtualSchedulerTimer};
+pub use crate::platform::trace;
 pub use crate::platform::watchdog;
 pub use crate::platform::{mpu, Chip, InterruptService, Platform};
 pub use crate::platform::{ClockInterface, NoClockControl, NO_CLOCK_CONTROL};
-pub use crate::process::ProcessId;
+pub use crate::process::{ProcessId, ShortID};
 pub use crate::sched::cooperative::{CoopProcessNode, CooperativeSched};
+pub use crate::sched::edf::{DeadlineScheduler, EdfProcessNode, EdfSched};
 pub use crate::sched::mlfq::{MLFQProcessNode, MLFQSched};
 pub use crate::sched::priority::PrioritySched;
 pub use crate::sched::round_robin::{RoundRobinProcessNode, RoundRobinSched};
 pub use crate::sched::{Kernel, Scheduler};
-pub use crate::upcall::Upcall;
+pub use crate::syscall_filter::{SyscallFilter, TbfHeaderFilterDefaultAllow};
+pub use crate::upcall::{Upcall, UpcallId};
 
 // Export only select items from the process module. To remove the name conflict
 // this cannot be called `process`, so we use a shortened version. These
@@ -136,7 +144,11 @@ pub use crate::upcall::Upcall;
 /// Publicly available process-related objects.
 pub mod procs {
     pub use crate::process::{
-        Error, FaultAction, FunctionCall, FunctionCallSource, Process, State, Task,
+        Error, FaultAction, FunctionCall, FunctionCallSource, Process, ProcessCustomGrantIdentifer,
+        State, Task,
+    };
+    pub use crate::process_checker::{
+        AppCredentialsChecker, AppPermissions, CheckResult, CredentialsPolicy, PermissionsPolicy,
     };
     pub use crate::process_policies::{
         PanicFaultPolicy, ProcessFaultPolicy, RestartFaultPolicy, StopFaultPolicy,
@@ -144,5 +156,8 @@ pub mod procs {
         ThresholdRestartThenPanicFaultPolicy,
     };
     pub use crate::process_standard::ProcessStandard;
-    pub use crate::process_utilities::{load_processes, ProcessLoadError};
+    pub use crate::process_utilities::{
+        load_processes, load_processes_checked, ProcessLoadError, StagingProcessLoader,
+    };
+    pub use crate::syscall::FaultInfo;
 }
@@ -7,7 +7,9 @@ use crate::syscall;
 use core::fmt::Write;
 
 pub mod mpu;
+pub mod power;
 pub(crate) mod scheduler_timer;
+pub mod trace;
 pub mod watchdog;
 
 /// Interface for individual boards.
@@ -53,6 +55,9 @@ pub trait Platform {
     /// be returned to the calling application. The default implementation
     /// allows all system calls.
     ///
+    /// Boards can implement this with a `SyscallFilter`, such as
+    /// `TbfHeaderFilterDefaultAllow`.
+    ///
     /// This API should be considered unstable, and is likely to change in the
     /// future.
     fn filter_syscall(
@@ -99,6 +104,30 @@ pub trait Platform {
     fn process_fault_hook(&self, process: &dyn process::Process) -> Result<(), ()> {
         Err(())
     }
+
+    /// Return the `PowerManager` the kernel asks before putting the chip in
+    /// deep sleep.
+    ///
+    /// By default there is none, and the kernel only uses `Chip::sleep()`.
+    fn power_manager(&self) -> Option<&power::PowerManager> {
+        None
+    }
+
+    /// Return the `Tracer` the kernel records its events with.
+    ///
+    /// By default there is none, and the kernel records nothing.
+    fn tracer(&self) -> Option<&trace::Tracer> {
+        None
+    }
+
+    /// Return a watchdog that the kernel loop uses instead of the chip's,
+    /// for example a virtual watchdog that only tickles the chip's watchdog
+    /// when all of its clients checked in.
+    ///
+    /// By default there is none, and the kernel uses `Chip::watchdog()`.
+    fn watchdog(&self) -> Option<&dyn watchdog::WatchDog> {
+        None
+    }
 }
 
 /// Interface for individual MCUs.
@@ -158,6 +187,16 @@ pub trait Chip {
     /// chip and resumes the scheduler.
     fn sleep(&self);
 
+    /// Called instead of `sleep()` when every `PowerClient` of the board is
+    /// ready for deep sleep. Chips can use a deeper sleep state that stops
+    /// the fast clocks, as long as the timer behind the kernel's alarm and
+    /// the interrupts used to wake up keep working.
+    ///
+    /// By default this is the same as `sleep()`.
+    fn deep_sleep(&self) {
+        self.sleep();
+    }
+
     /// Run a function in an atomic state, which means that interrupts are
     /// disabled so that an interrupt will not fire during the passed in
     /// function's execution.
@@ -158,6 +158,26 @@ pub trait MPU {
         }
     }
 
+    /// Removes an MPU region allocated with `allocate_region()`.
+    ///
+    /// An implementation must remove the region that starts at the address
+    /// and has the size of `region` from `config`. It must not remove the
+    /// region covering app-owned memory.
+    ///
+    /// # Arguments
+    ///
+    /// - `region`: the region, as returned by `allocate_region()`
+    /// - `config`: MPU region configuration
+    ///
+    /// # Return Value
+    ///
+    /// Returns an error if `config` has no such region, or if the
+    /// implementation cannot remove regions.
+    #[allow(unused_variables)]
+    fn remove_memory_region(&self, region: Region, config: &mut Self::MpuConfig) -> Result<(), ()> {
+        Err(())
+    }
+
     /// Chooses the location for a process's memory, and allocates an MPU region
     /// covering the app-owned part.
     ///
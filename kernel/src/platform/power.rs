@@ -0,0 +1,48 @@
+//! Interface for letting the chip enter deep sleep between events.
+//!
+//! When no process is ready, the kernel sleeps the chip with
+//! `Chip::sleep()`, which keeps every clock running so that any peripheral
+//! can keep working. A chip can also implement `Chip::deep_sleep()`, which
+//! stops the fast clocks and takes longer to wake from. The kernel only uses
+//! it when the board provides a `PowerManager` and every `PowerClient`
+//! registered with it reports that it is ready for deep sleep.
+//!
+//! Clients are the drivers whose hardware stops working in deep sleep, such
+//! as radios, and the alarm, which is not ready when it expires too soon to
+//! be worth the wake-up latency. The low-power timer behind the alarm keeps
+//! running in deep sleep and wakes the chip when it expires, so no periodic
+//! tick is needed.
+//!
+//! ```rust,ignore
+//! let power_clients = static_init!(
+//!     [&'static dyn kernel::power::PowerClient; 2],
+//!     [mux_alarm, &base_peripherals.ble_radio]
+//! );
+//! let power_manager = static_init!(
+//!     kernel::power::PowerManager<'static>,
+//!     kernel::power::PowerManager::new(power_clients)
+//! );
+//! ```
+
+/// A driver that can prevent the chip from entering deep sleep.
+pub trait PowerClient {
+    /// Return `false` if the chip must not enter deep sleep now, for example
+    /// because a transfer that needs the fast clocks is in progress.
+    fn deep_sleep_ready(&self) -> bool;
+}
+
+/// The set of `PowerClient`s the kernel asks before entering deep sleep.
+pub struct PowerManager<'a> {
+    clients: &'a [&'a dyn PowerClient],
+}
+
+impl<'a> PowerManager<'a> {
+    pub const fn new(clients: &'a [&'a dyn PowerClient]) -> PowerManager<'a> {
+        PowerManager { clients: clients }
+    }
+
+    /// Whether every client is ready for deep sleep.
+    pub fn deep_sleep_ready(&self) -> bool {
+        self.clients.iter().all(|client| client.deep_sleep_ready())
+    }
+}
@@ -0,0 +1,328 @@
+//! Tracing of kernel events for debugging timing problems.
+//!
+//! When the board provides a `Tracer`, the kernel records scheduling
+//! decisions, system call entries and exits, the handling of interrupts and
+//! deferred calls, and sleep. Each event is written as a fixed-size binary
+//! record to a `TraceSink`: a Segger RTT channel or the Cortex-M ITM to watch
+//! events live, or a `RingBufferSink` in RAM to read the last events with a
+//! debugger after a crash. Boards without a tracer pay only for the check.
+//!
+//! Records are `RECORD_LEN` bytes, in little endian:
+//!
+//! | Offset | Size | Field                                               |
+//! |--------|------|-----------------------------------------------------|
+//! | 0      | 1    | Event kind, see `TraceEvent::kind()`                |
+//! | 1      | 1    | Process index, or `0xff` for kernel events          |
+//! | 2      | 1    | Syscall class, or why a process stopped             |
+//! | 3      | 1    | Reserved, `0`                                       |
+//! | 4      | 4    | Timestamp, in ticks of the tracer's clock           |
+//! | 8      | 4    | First argument                                      |
+//! | 12     | 4    | Second argument                                     |
+//!
+//! Sinks are called from the kernel loop, so they must not wait for
+//! interrupts: they write records synchronously or drop them.
+//!
+//! ```rust,ignore
+//! static mut TRACE_BUFFER: [u8; 64 * kernel::trace::RECORD_LEN] =
+//!     [0; 64 * kernel::trace::RECORD_LEN];
+//!
+//! let trace_sink = static_init!(
+//!     kernel::trace::RingBufferSink<'static>,
+//!     kernel::trace::RingBufferSink::new(&mut TRACE_BUFFER)
+//! );
+//! let tracer = static_init!(
+//!     kernel::trace::Tracer<'static>,
+//!     kernel::trace::Tracer::new(&base_peripherals.rtc, trace_sink, kernel::trace::TRACE_ALL)
+//! );
+//! ```
+
+use crate::common::cells::TakeCell;
+use crate::hil::time::{Ticks, Time};
+use crate::process::ProcessId;
+use crate::sched::StoppedExecutingReason;
+use crate::syscall::Syscall;
+use core::cell::Cell;
+
+/// Length of a trace record, in bytes.
+pub const RECORD_LEN: usize = 16;
+
+/// Trace process scheduling.
+pub const TRACE_SCHEDULER: u32 = 1 << 0;
+/// Trace system calls.
+pub const TRACE_SYSCALLS: u32 = 1 << 1;
+/// Trace kernel work (interrupt bottom halves and deferred calls) and sleep.
+pub const TRACE_KERNEL: u32 = 1 << 2;
+/// Trace every event.
+pub const TRACE_ALL: u32 = TRACE_SCHEDULER | TRACE_SYSCALLS | TRACE_KERNEL;
+
+/// An event recorded by the kernel.
+#[derive(Copy, Clone)]
+pub enum TraceEvent {
+    /// The scheduler chose to run `process`, with a timeslice in
+    /// microseconds or `None` to run it cooperatively.
+    Run {
+        process: ProcessId,
+        timeslice_us: Option<u32>,
+    },
+    /// `process` stopped running, after `executed_us` microseconds if it ran
+    /// with a timeslice. `reason` is why, as `StoppedExecutingReason`
+    /// numbered from `0`.
+    Stopped {
+        process: ProcessId,
+        reason: u8,
+        executed_us: Option<u32>,
+    },
+    /// `process` made a system call of class `class`. The arguments are the
+    /// driver and subdriver numbers, or the first two arguments of classes
+    /// without a driver.
+    SyscallEntry {
+        process: ProcessId,
+        class: u8,
+        arg0: u32,
+        arg1: u32,
+    },
+    /// The kernel finished handling a system call of class `class` from
+    /// `process`.
+    SyscallExit { process: ProcessId, class: u8 },
+    /// The kernel started handling interrupts and deferred calls.
+    KernelWorkStart,
+    /// The kernel finished handling interrupts and deferred calls.
+    KernelWorkEnd,
+    /// The chip went to sleep, `deep` if in deep sleep.
+    Sleep { deep: bool },
+    /// The chip woke up.
+    Wake,
+}
+
+impl TraceEvent {
+    /// The class of `syscall`, and its driver and subdriver numbers or first
+    /// two arguments.
+    fn syscall_fields(syscall: &Syscall) -> (u8, usize, usize) {
+        match *syscall {
+            Syscall::Yield { which, .. } => (0, which, 0),
+            Syscall::Subscribe {
+                driver_number,
+                subdriver_number,
+                ..
+            } => (1, driver_number, subdriver_number),
+            Syscall::Command {
+                driver_number,
+                subdriver_number,
+                ..
+            } => (2, driver_number, subdriver_number),
+            Syscall::ReadWriteAllow {
+                driver_number,
+                subdriver_number,
+                ..
+            } => (3, driver_number, subdriver_number),
+            Syscall::ReadOnlyAllow {
+                driver_number,
+                subdriver_number,
+                ..
+            } => (4, driver_number, subdriver_number),
+            Syscall::Memop { operand, arg0 } => (5, operand, arg0),
+            Syscall::Exit {
+                which,
+                completion_code,
+            } => (6, which, completion_code),
+        }
+    }
+
+    /// Event for the system call `syscall` of `process`.
+    pub(crate) fn syscall_entry(process: ProcessId, syscall: &Syscall) -> TraceEvent {
+        let (class, arg0, arg1) = TraceEvent::syscall_fields(syscall);
+        TraceEvent::SyscallEntry {
+            process: process,
+            class: class,
+            arg0: arg0 as u32,
+            arg1: arg1 as u32,
+        }
+    }
+
+    /// Event for the end of the system call `syscall` of `process`.
+    pub(crate) fn syscall_exit(process: ProcessId, syscall: &Syscall) -> TraceEvent {
+        let (class, _, _) = TraceEvent::syscall_fields(syscall);
+        TraceEvent::SyscallExit {
+            process: process,
+            class: class,
+        }
+    }
+
+    /// Event for `process` stopping for `reason`.
+    pub(crate) fn stopped(
+        process: ProcessId,
+        reason: &StoppedExecutingReason,
+        executed_us: Option<u32>,
+    ) -> TraceEvent {
+        let reason = match reason {
+            StoppedExecutingReason::NoWorkLeft => 0,
+            StoppedExecutingReason::StoppedFaulted => 1,
+            StoppedExecutingReason::Stopped => 2,
+            StoppedExecutingReason::TimesliceExpired => 3,
+            StoppedExecutingReason::KernelPreemption => 4,
+        };
+        TraceEvent::Stopped {
+            process: process,
+            reason: reason,
+            executed_us: executed_us,
+        }
+    }
+
+    /// The kind of event, as written in the first byte of records.
+    pub fn kind(&self) -> u8 {
+        match self {
+            TraceEvent::Run { .. } => 1,
+            TraceEvent::Stopped { .. } => 2,
+            TraceEvent::SyscallEntry { .. } => 3,
+            TraceEvent::SyscallExit { .. } => 4,
+            TraceEvent::KernelWorkStart => 5,
+            TraceEvent::KernelWorkEnd => 6,
+            TraceEvent::Sleep { .. } => 7,
+            TraceEvent::Wake => 8,
+        }
+    }
+
+    /// The `TRACE_*` category of the event.
+    fn category(&self) -> u32 {
+        match self {
+            TraceEvent::Run { .. } | TraceEvent::Stopped { .. } => TRACE_SCHEDULER,
+            TraceEvent::SyscallEntry { .. } | TraceEvent::SyscallExit { .. } => TRACE_SYSCALLS,
+            _ => TRACE_KERNEL,
+        }
+    }
+
+    /// Encode the event as a record, with the timestamp `now`.
+    pub fn encode(&self, now: u32) -> [u8; RECORD_LEN] {
+        let (process, code, arg0, arg1) = match *self {
+            TraceEvent::Run {
+                process,
+                timeslice_us,
+            } => (Some(process), 0, timeslice_us.unwrap_or(0), 0),
+            TraceEvent::Stopped {
+                process,
+                reason,
+                executed_us,
+            } => (Some(process), reason, executed_us.unwrap_or(0), 0),
+            TraceEvent::SyscallEntry {
+                process,
+                class,
+                arg0,
+                arg1,
+            } => (Some(process), class, arg0, arg1),
+            TraceEvent::SyscallExit { process, class } => (Some(process), class, 0, 0),
+            TraceEvent::Sleep { deep } => (None, deep as u8, 0, 0),
+            TraceEvent::KernelWorkStart | TraceEvent::KernelWorkEnd | TraceEvent::Wake => {
+                (None, 0, 0, 0)
+            }
+        };
+        let mut record = [0; RECORD_LEN];
+        record[0] = self.kind();
+        record[1] = process.map_or(0xff, |process| process.index as u8);
+        record[2] = code;
+        record[4..8].copy_from_slice(&now.to_le_bytes());
+        record[8..12].copy_from_slice(&arg0.to_le_bytes());
+        record[12..16].copy_from_slice(&arg1.to_le_bytes());
+        record
+    }
+}
+
+/// Destination of trace records.
+pub trait TraceSink {
+    /// Write `record`, or drop it if there is no room. Must not wait for an
+    /// interrupt.
+    fn write(&self, record: &[u8; RECORD_LEN]);
+}
+
+/// Source of trace timestamps.
+pub trait TraceClock {
+    /// The current time, in ticks.
+    fn now(&self) -> u32;
+}
+
+impl<T: Time> TraceClock for T {
+    fn now(&self) -> u32 {
+        Time::now(self).into_u32()
+    }
+}
+
+/// Timestamps the events of the enabled categories and writes them to a
+/// sink.
+pub struct Tracer<'a> {
+    clock: &'a dyn TraceClock,
+    sink: &'a dyn TraceSink,
+    categories: Cell<u32>,
+}
+
+impl<'a> Tracer<'a> {
+    /// `categories` is a mask of the `TRACE_*` categories to record.
+    pub fn new(clock: &'a dyn TraceClock, sink: &'a dyn TraceSink, categories: u32) -> Tracer<'a> {
+        Tracer {
+            clock: clock,
+            sink: sink,
+            categories: Cell::new(categories),
+        }
+    }
+
+    /// Change the categories to record.
+    pub fn set_categories(&self, categories: u32) {
+        self.categories.set(categories);
+    }
+
+    pub fn trace(&self, event: TraceEvent) {
+        if self.categories.get() & event.category() != 0 {
+            self.sink.write(&event.encode(self.clock.now()));
+        }
+    }
+}
+
+/// Keeps the most recent records in a buffer in RAM.
+///
+/// Records are written one after the other, and overwrite the oldest ones
+/// once the buffer is full. Slots that were never written have kind `0`. To
+/// read the trace after a crash, dump the buffer with a debugger and sort its
+/// records by timestamp, or print them from the panic handler with
+/// `for_each_record()`.
+pub struct RingBufferSink<'a> {
+    buffer: TakeCell<'a, [u8]>,
+    /// Offset of the next record to write.
+    position: Cell<usize>,
+}
+
+impl<'a> RingBufferSink<'a> {
+    /// The buffer should be a multiple of `RECORD_LEN` long; bytes past the
+    /// last whole record are unused.
+    pub fn new(buffer: &'a mut [u8]) -> RingBufferSink<'a> {
+        RingBufferSink {
+            buffer: TakeCell::new(buffer),
+            position: Cell::new(0),
+        }
+    }
+
+    /// Call `f` with every record written, from the oldest.
+    pub fn for_each_record<F: FnMut(&[u8])>(&self, mut f: F) {
+        let position = self.position.get();
+        self.buffer.map(|buffer| {
+            let len = buffer.len() - buffer.len() % RECORD_LEN;
+            let (newest, oldest) = buffer[..len].split_at(position);
+            oldest
+                .chunks(RECORD_LEN)
+                .chain(newest.chunks(RECORD_LEN))
+                .filter(|record| record[0] != 0)
+                .for_each(|record| f(record));
+        });
+    }
+}
+
+impl<'a> TraceSink for RingBufferSink<'a> {
+    fn write(&self, record: &[u8; RECORD_LEN]) {
+        self.buffer.map(|buffer| {
+            let len = buffer.len() - buffer.len() % RECORD_LEN;
+            if len == 0 {
+                return;
+            }
+            let position = self.position.get();
+            buffer[position..position + RECORD_LEN].copy_from_slice(record);
+            self.position.set((position + RECORD_LEN) % len);
+        });
+    }
+}
@@ -12,7 +12,7 @@ use crate::ipc;
 use crate::mem::{ReadOnlyAppSlice, ReadWriteAppSlice};
 use crate::platform::mpu::{self};
 use crate::sched::Kernel;
-use crate::syscall::{self, Syscall, SyscallReturn};
+use crate::syscall::{self, FaultInfo, Syscall, SyscallReturn};
 use crate::upcall::UpcallId;
 
 /// Userspace process identifier.
@@ -72,6 +72,37 @@ pub struct ProcessId {
     identifier: usize,
 }
 
+/// A short identifier of an application.
+///
+/// Unlike a `ProcessId`, which changes every time a process starts, a
+/// `ShortID` identifies the same application across restarts and reboots. It
+/// is derived from the package name in the TBF header of the application.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ShortID {
+    /// The application has no persistent identity, for example because it
+    /// has no package name. It is only told apart from the other
+    /// applications by its `ProcessId`.
+    LocallyUnique,
+    /// The persistent identifier of the application.
+    Fixed(core::num::NonZeroU32),
+}
+
+impl ShortID {
+    /// The short ID of the application with package name `name`: the 32-bit
+    /// FNV-1a hash of the name.
+    pub fn from_name(name: &str) -> ShortID {
+        if name.is_empty() {
+            return ShortID::LocallyUnique;
+        }
+        let mut hash: u32 = 0x811c_9dc5;
+        for b in name.bytes() {
+            hash ^= b as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+        core::num::NonZeroU32::new(hash).map_or(ShortID::LocallyUnique, ShortID::Fixed)
+    }
+}
+
 impl PartialEq for ProcessId {
     fn eq(&self, other: &ProcessId) -> bool {
         self.identifier == other.identifier
@@ -120,7 +151,11 @@ impl ProcessId {
     /// This will return `Some(index)` if the identifier stored in this `ProcessId`
     /// matches the app saved at the known index. If the identifier does not
     /// match then `None` will be returned.
-    pub(crate) fn index(&self) -> Option<usize> {
+    ///
+    /// A process keeps its index when it restarts, so capsules can use it to
+    /// tell whether a `ProcessId` is still valid, and to recognize the place
+    /// a restarted process was loaded in.
+    pub fn index(&self) -> Option<usize> {
         // Do a lookup to make sure that the index we have is correct.
         if self.kernel.processid_is_valid(self) {
             Some(self.index)
@@ -158,6 +193,31 @@ impl ProcessId {
             (start, end)
         })
     }
+
+    /// Returns the name of the app from its TBF header, or an empty string if
+    /// it has no name or no longer exists.
+    pub fn get_process_name(&self) -> &'static str {
+        self.kernel
+            .process_map_or("", *self, |process| process.get_process_name())
+    }
+
+    /// Returns the short ID of the app, which stays the same when the app
+    /// restarts, or after a reboot. Capsules can use it to keep persistent
+    /// state for the app.
+    pub fn short_app_id(&self) -> ShortID {
+        self.kernel
+            .process_map_or(ShortID::LocallyUnique, *self, |process| {
+                ShortID::from_name(process.get_process_name())
+            })
+    }
+}
+
+/// Client notified whenever a process terminates, whether it then restarts
+/// or not, so that it can revoke what other processes were given of the
+/// terminated process.
+pub trait ProcessTerminationClient {
+    /// `processid` terminated. Its grants are still allocated.
+    fn process_terminated(&self, processid: ProcessId);
 }
 
 /// This trait represents a generic process that the Tock scheduler can
@@ -222,12 +282,45 @@ pub trait Process {
     /// `FaultResponse` for this process to occur.
     fn set_fault_state(&self);
 
+    /// Save why the process faulted, as reported by the architecture. The
+    /// kernel calls this when the process faults while running, before
+    /// `Platform::process_fault_hook()` and `set_fault_state()`.
+    fn record_fault(&self);
+
+    /// Returns why the process last faulted. Faults without a recorded
+    /// reason, such as those forced by capsules, have a zero `FaultInfo`.
+    /// This is kept when the process restarts after the fault, so that the
+    /// restarted process can learn why, and cleared when it restarts for
+    /// another reason.
+    fn get_last_fault(&self) -> Option<FaultInfo>;
+
     /// Returns how many times this process has been restarted.
     fn get_restart_count(&self) -> usize;
 
     /// Get the name of the process. Used for IPC.
     fn get_process_name(&self) -> &'static str;
 
+    /// Whether the credentials policy of the board accepted the credentials
+    /// of the process. `false` if the board does not check credentials.
+    fn credentials_verified(&self) -> bool;
+
+    /// Get the scheduling priority of the process, where `0` is the highest.
+    ///
+    /// This is the priority from the TBF header, or the lowest priority
+    /// (`u32::MAX`) without one, unless the process inherited a higher one
+    /// with `inherit_priority()`.
+    fn get_priority(&self) -> u32;
+
+    /// Get the driver numbers the process may make system calls to, from its
+    /// TBF header, or `None` if the header does not restrict them.
+    fn get_permitted_drivers(&self) -> Option<&[u32]>;
+
+    /// Raise the priority of the process to at least `priority`, because it
+    /// has been asked to do work (such as an IPC request) on behalf of a
+    /// process with that priority. The process keeps the inherited priority
+    /// until it yields with no tasks left.
+    fn inherit_priority(&self, priority: u32);
+
     /// Stop and clear a process's state, putting it into the `Terminated`
     /// state.
     ///
@@ -294,6 +387,10 @@ pub trait Process {
     /// The lowest address of the grant region for the process.
     fn kernel_memory_break(&self) -> *const u8;
 
+    /// The first address after the memory the process can access, that is
+    /// its current break.
+    fn app_memory_break(&self) -> *const u8;
+
     /// How many writeable flash regions defined in the TBF header for this
     /// process.
     fn number_writeable_flash_regions(&self) -> usize;
@@ -397,6 +494,22 @@ pub trait Process {
         min_region_size: usize,
     ) -> Option<mpu::Region>;
 
+    /// Allocate a new MPU region, like `add_mpu_region()`, that the process
+    /// can only read.
+    fn add_read_only_mpu_region(
+        &self,
+        unallocated_memory_start: *const u8,
+        unallocated_memory_size: usize,
+        min_region_size: usize,
+    ) -> Option<mpu::Region>;
+
+    /// Remove an MPU region added with `add_mpu_region()` or
+    /// `add_read_only_mpu_region()`.
+    ///
+    /// Returns `INVAL` if the process has no such region, and `NOSUPPORT` if
+    /// the MPU cannot remove regions.
+    fn remove_mpu_region(&self, region: mpu::Region) -> Result<(), ErrorCode>;
+
     // grants
 
     /// Allocate memory from the grant region and store the reference in the
@@ -471,6 +584,14 @@ pub trait Process {
     /// Useful for debugging/inspecting the system.
     fn grant_allocated_count(&self) -> Option<usize>;
 
+    /// Return how many bytes of the grant region the grant `grant_num` uses,
+    /// or `None` if it is not allocated or the process is not active. This
+    /// includes padding for alignment, and any custom grant allocated just
+    /// after it.
+    ///
+    /// Useful for debugging/inspecting the system.
+    fn grant_allocated_size(&self, grant_num: usize) -> Option<usize>;
+
     // functions for processes that are architecture specific
 
     /// Set the return value the process should see when it begins executing
@@ -512,6 +633,11 @@ pub trait Process {
     /// context, and the state of the memory protection unit (MPU).
     fn print_full_process(&self, writer: &mut dyn Write);
 
+    /// Copy the top of the process's stack, from its stack pointer up, into
+    /// `buffer`. Returns the stack pointer and the number of bytes copied, or
+    /// `None` if the stack pointer is outside the memory of the process.
+    fn debug_stack_snapshot(&self, buffer: &mut [u8]) -> Option<(*const u8, usize)>;
+
     // debug
 
     /// Returns how many syscalls this app has called.
@@ -529,6 +655,34 @@ pub trait Process {
     /// Increment the number of times the process called a syscall and record
     /// the last syscall that was called.
     fn debug_syscall_called(&self, last_syscall: Syscall);
+
+    /// Returns how many times the scheduler has run this process.
+    fn debug_run_count(&self) -> usize;
+
+    /// Returns the most stack the process has used since it started, in
+    /// bytes, as seen at system calls, if the kernel knows where its stack
+    /// starts.
+    fn debug_stack_high_water_mark(&self) -> Option<usize>;
+
+    /// Returns how long this process has run, in microseconds. Only runs with
+    /// a timeslice are measured, as the scheduler timer does not run while
+    /// processes run cooperatively.
+    fn debug_execution_time_us(&self) -> u64;
+
+    /// Record that the scheduler ran this process, and for how long if it was
+    /// measured.
+    fn debug_executed(&self, execution_time_us: Option<u32>);
+
+    /// Set whether the system calls of this process are logged to the debug
+    /// console, even if the kernel was not configured to trace system calls.
+    fn debug_set_syscall_tracing(&self, enabled: bool);
+
+    /// Returns whether the system calls of this process are logged.
+    fn debug_syscall_tracing(&self) -> bool;
+
+    /// Called by the kernel for each system call of the process. Returns
+    /// whether it should be logged, which is rate limited.
+    fn debug_trace_syscall(&self) -> bool;
 }
 
 /// Opaque identifier for custom grants allocated dynamically from a process's
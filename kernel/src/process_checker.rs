@@ -0,0 +1,214 @@
+//! Checking the credentials of processes before they run.
+//!
+//! An app can carry credentials, such as a signature, in footers after its
+//! binary. They cover its integrity region: its TBF header and binary. When a
+//! board loads processes with a `CredentialsPolicy`, each credential of an app
+//! is passed to an `AppCredentialsChecker`, which accepts the app, rejects it,
+//! or passes on the credential. The policy then decides whether the app runs,
+//! so boards can refuse to run unsigned or incorrectly signed apps.
+//!
+//! A `PermissionsPolicy` then gives apps whose credentials were accepted
+//! permissions by their `ShortID`: the drivers they may use, and how much
+//! grant memory and writeable flash they may have. Boards that host apps
+//! from several vendors can so give each vendor's apps different
+//! capabilities, and restrict apps that are not signed.
+
+use tock_tbf::types::{TbfFooterV2Credentials, TbfHeader};
+
+use crate::capabilities::ProcessManagementCapability;
+use crate::common::cells::OptionalCell;
+use crate::config;
+use crate::debug;
+use crate::errorcode::ErrorCode;
+use crate::process::{Process, ShortID};
+use crate::syscall::Syscall;
+use crate::syscall_filter::SyscallFilter;
+
+/// The decision of an `AppCredentialsChecker` about one credential.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckResult {
+    /// The credential is valid, and the app may run.
+    Accept,
+    /// The credential is invalid, and the app must not run.
+    Reject,
+    /// The checker does not handle this credential. The next credential of
+    /// the app, if any, is checked.
+    Pass,
+}
+
+/// Checks the credentials of apps.
+pub trait AppCredentialsChecker {
+    /// Check `credentials` over the `integrity_region` of an app.
+    fn check_credentials(
+        &self,
+        credentials: &TbfFooterV2Credentials,
+        integrity_region: &[u8],
+    ) -> CheckResult;
+}
+
+/// Decides which apps run from their credentials.
+///
+/// The credentials of an app are checked in order until the checker accepts
+/// or rejects one. If it does neither, the app runs unless the policy requires
+/// credentials.
+pub struct CredentialsPolicy {
+    checker: &'static dyn AppCredentialsChecker,
+    require_credentials: bool,
+    permissions: OptionalCell<&'static PermissionsPolicy>,
+}
+
+impl CredentialsPolicy {
+    /// Create a policy that checks credentials with `checker`. If
+    /// `require_credentials` is `true`, apps without credentials the checker
+    /// accepts do not run.
+    pub fn new(
+        checker: &'static dyn AppCredentialsChecker,
+        require_credentials: bool,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> CredentialsPolicy {
+        CredentialsPolicy {
+            checker: checker,
+            require_credentials: require_credentials,
+            permissions: OptionalCell::empty(),
+        }
+    }
+
+    /// Give the apps the quotas of `permissions` when they are loaded.
+    pub fn set_permissions(&self, permissions: &'static PermissionsPolicy) {
+        self.permissions.set(permissions);
+    }
+
+    /// The permissions of the app with header `header`, if the board set a
+    /// `PermissionsPolicy`.
+    pub(crate) fn permissions(&self, header: &TbfHeader, verified: bool) -> Option<AppPermissions> {
+        let short_id = ShortID::from_name(header.get_package_name().unwrap_or(""));
+        self.permissions
+            .map(|permissions| permissions.permissions(short_id, verified))
+    }
+
+    /// Returns whether the app in `app_flash` with header `header` may run,
+    /// and if so whether the checker accepted its credentials.
+    pub(crate) fn check(&self, header: &TbfHeader, app_flash: &'static [u8]) -> Option<bool> {
+        // Without a program header, the app has no footers, and the whole
+        // app is its integrity region.
+        let binary_end = header
+            .get_binary_end_offset()
+            .map_or(app_flash.len(), |offset| offset as usize);
+        let (integrity_region, mut footers) =
+            match (app_flash.get(..binary_end), app_flash.get(binary_end..)) {
+                (Some(integrity_region), Some(footers)) => (integrity_region, footers),
+                _ => return None,
+            };
+
+        while footers.len() > 0 {
+            let (credentials, footer_len) = match tock_tbf::parse::parse_tbf_footer(footers) {
+                Ok(footer) => footer,
+                Err(_) => break,
+            };
+            match self
+                .checker
+                .check_credentials(&credentials, integrity_region)
+            {
+                CheckResult::Accept => return Some(true),
+                CheckResult::Reject => {
+                    if config::CONFIG.debug_load_processes {
+                        debug!(
+                            "Credentials {:?} rejected for process {:?}",
+                            credentials.format,
+                            header.get_package_name()
+                        );
+                    }
+                    return None;
+                }
+                CheckResult::Pass => {}
+            }
+            footers = match footers.get(footer_len as usize..) {
+                Some(footers) => footers,
+                None => break,
+            };
+        }
+
+        if self.require_credentials {
+            None
+        } else {
+            Some(false)
+        }
+    }
+}
+
+/// What an app may use. Restrictions that are `None` do not apply.
+#[derive(Clone, Copy, Debug)]
+pub struct AppPermissions {
+    /// The driver numbers the app may make system calls to.
+    pub drivers: Option<&'static [usize]>,
+    /// The most memory its grants may use, in bytes.
+    pub max_grant_size: Option<usize>,
+    /// The most flash its writeable flash regions may cover, in bytes. Apps
+    /// that declare more are not loaded.
+    pub max_flash_region_size: Option<usize>,
+}
+
+impl AppPermissions {
+    /// Permissions that do not restrict the app.
+    pub const UNRESTRICTED: AppPermissions = AppPermissions {
+        drivers: None,
+        max_grant_size: None,
+        max_flash_region_size: None,
+    };
+}
+
+/// Gives apps permissions by the `ShortID` of their package name, if their
+/// credentials were accepted.
+///
+/// The quotas are enforced when the board also passes the policy to
+/// `CredentialsPolicy::set_permissions()`, and the drivers when the board
+/// filters system calls with it from `Platform::filter_syscall()`.
+pub struct PermissionsPolicy {
+    apps: &'static [(ShortID, AppPermissions)],
+    default: AppPermissions,
+}
+
+impl PermissionsPolicy {
+    /// Apps listed in `apps` get their permissions if their credentials were
+    /// accepted. Other apps get `default`.
+    pub fn new(
+        apps: &'static [(ShortID, AppPermissions)],
+        default: AppPermissions,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> PermissionsPolicy {
+        PermissionsPolicy {
+            apps: apps,
+            default: default,
+        }
+    }
+
+    /// The permissions of the app `short_id`, whose credentials were
+    /// accepted if `verified`.
+    pub fn permissions(&self, short_id: ShortID, verified: bool) -> AppPermissions {
+        if !verified || short_id == ShortID::LocallyUnique {
+            return self.default;
+        }
+        self.apps
+            .iter()
+            .find(|(id, _)| *id == short_id)
+            .map_or(self.default, |(_, permissions)| *permissions)
+    }
+}
+
+impl SyscallFilter for PermissionsPolicy {
+    fn filter_syscall(&self, process: &dyn Process, syscall: &Syscall) -> Result<(), ErrorCode> {
+        let driver_number = match *syscall {
+            Syscall::Subscribe { driver_number, .. }
+            | Syscall::Command { driver_number, .. }
+            | Syscall::ReadWriteAllow { driver_number, .. }
+            | Syscall::ReadOnlyAllow { driver_number, .. } => driver_number,
+            _ => return Ok(()),
+        };
+        let short_id = ShortID::from_name(process.get_process_name());
+        let permissions = self.permissions(short_id, process.credentials_verified());
+        match permissions.drivers {
+            Some(drivers) if !drivers.contains(&driver_number) => Err(ErrorCode::NODEVICE),
+            _ => Ok(()),
+        }
+    }
+}
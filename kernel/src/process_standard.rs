@@ -19,15 +19,21 @@ use crate::platform::mpu::{self, MPU};
 use crate::platform::Chip;
 use crate::process::{Error, FunctionCall, FunctionCallSource, Process, State, Task};
 use crate::process::{FaultAction, ProcessCustomGrantIdentifer, ProcessId, ProcessStateCell};
+use crate::process_checker::CredentialsPolicy;
 use crate::process_policies::ProcessFaultPolicy;
 use crate::process_utilities::ProcessLoadError;
 use crate::sched::Kernel;
-use crate::syscall::{self, Syscall, SyscallReturn, UserspaceKernelBoundary};
+use crate::syscall::{self, FaultInfo, Syscall, SyscallReturn, UserspaceKernelBoundary};
 use crate::upcall::UpcallId;
 
 // The completion code for a process if it faulted.
 const COMPLETION_FAULT: u32 = 0xffffffff;
 
+/// The most system calls traced with `debug_set_syscall_tracing()` each time
+/// the process runs. Further calls are counted, but not logged, so that a
+/// process that loops on system calls does not flood the debug output.
+const SYSCALL_TRACE_LIMIT: usize = 16;
+
 /// State for helping with debugging apps.
 ///
 /// These pointers and counters are not strictly required for kernel operation,
@@ -67,6 +73,23 @@ struct ProcessStandardDebug {
     /// How many times this process has been paused because it exceeded its
     /// timeslice.
     timeslice_expiration_count: usize,
+
+    /// How many times the scheduler has run this process.
+    run_count: usize,
+
+    /// How long this process has run with a timeslice, in microseconds.
+    execution_time_us: u64,
+
+    /// Whether the system calls of the process are logged. Kept across
+    /// restarts.
+    trace_syscalls: bool,
+
+    /// How many system calls were logged in the current run of the process.
+    traced_in_run: usize,
+
+    /// How many system calls were not logged because of the rate limit since
+    /// the last one that was.
+    untraced_count: usize,
 }
 
 /// A type for userspace processes in Tock.
@@ -178,6 +201,27 @@ pub struct ProcessStandard<'a, C: 'static + Chip> {
     /// determine if the process should be restarted or not.
     restart_count: Cell<usize>,
 
+    /// Why the process last faulted, kept across restarts after faults.
+    last_fault: Cell<Option<FaultInfo>>,
+
+    /// Whether `record_fault()` recorded the reason of the fault being
+    /// handled.
+    fault_recorded: Cell<bool>,
+
+    /// Priority inherited from a process this process is doing work for, if
+    /// higher than its own.
+    inherited_priority: Cell<u32>,
+
+    /// Whether the credentials policy accepted the credentials of the app.
+    credentials_verified: bool,
+
+    /// Most memory the grants of the process may use, in bytes.
+    grant_quota: Option<usize>,
+
+    /// The kernel memory break when the process started, below which grants
+    /// are allocated.
+    grants_start: Cell<*const u8>,
+
     /// Name of the app.
     process_name: &'static str,
 
@@ -257,6 +301,9 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         if self.state.get() == State::Running {
             self.state.update(State::Yielded);
         }
+        if !self.has_tasks() {
+            self.inherited_priority.set(u32::MAX);
+        }
     }
 
     fn stop(&self) {
@@ -276,6 +323,10 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
     }
 
     fn set_fault_state(&self) {
+        if !self.fault_recorded.replace(false) {
+            self.last_fault.set(Some(FaultInfo::default()));
+        }
+
         // Use the per-process fault policy to determine what action the kernel
         // should take since the process faulted.
         let action = self.fault_policy.action(self);
@@ -302,6 +353,10 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
     }
 
     fn try_restart(&self, completion_code: u32) {
+        if completion_code != COMPLETION_FAULT {
+            self.last_fault.set(None);
+        }
+
         // Terminate the process, freeing its state and removing any
         // pending tasks from the scheduler's queue.
         self.terminate(completion_code);
@@ -327,15 +382,37 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             tasks.empty();
         });
 
+        // Let the kernel revoke what other processes hold of this one while
+        // its grants still exist.
+        self.kernel.process_terminated(self.process_id.get());
+
         // Clear any grant regions this app has setup with any capsules.
         unsafe {
             self.grant_ptrs_reset();
         }
 
+        self.inherited_priority.set(u32::MAX);
+
         // Mark the app as stopped so the scheduler won't try to run it.
         self.state.update(State::Terminated);
     }
 
+    fn record_fault(&self) {
+        let fault = self
+            .stored_state
+            .map_or(FaultInfo::default(), |stored_state| {
+                self.chip
+                    .userspace_kernel_boundary()
+                    .get_fault_info(stored_state)
+            });
+        self.last_fault.set(Some(fault));
+        self.fault_recorded.set(true);
+    }
+
+    fn get_last_fault(&self) -> Option<FaultInfo> {
+        self.last_fault.get()
+    }
+
     fn get_restart_count(&self) -> usize {
         self.restart_count.get()
     }
@@ -377,6 +454,10 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         self.kernel_memory_break.get()
     }
 
+    fn app_memory_break(&self) -> *const u8 {
+        self.app_break.get()
+    }
+
     fn number_writeable_flash_regions(&self) -> usize {
         self.header.number_writeable_flash_regions()
     }
@@ -417,29 +498,46 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         unallocated_memory_size: usize,
         min_region_size: usize,
     ) -> Option<mpu::Region> {
-        self.mpu_config.and_then(|mut config| {
-            let new_region = self.chip.mpu().allocate_region(
-                unallocated_memory_start,
-                unallocated_memory_size,
-                min_region_size,
-                mpu::Permissions::ReadWriteOnly,
-                &mut config,
-            );
-
-            if new_region.is_none() {
-                return None;
-            }
+        self.allocate_mpu_region(
+            unallocated_memory_start,
+            unallocated_memory_size,
+            min_region_size,
+            mpu::Permissions::ReadWriteOnly,
+        )
+    }
 
-            for region in self.mpu_regions.iter() {
-                if region.get().is_none() {
-                    region.set(new_region);
-                    return new_region;
-                }
-            }
+    fn add_read_only_mpu_region(
+        &self,
+        unallocated_memory_start: *const u8,
+        unallocated_memory_size: usize,
+        min_region_size: usize,
+    ) -> Option<mpu::Region> {
+        self.allocate_mpu_region(
+            unallocated_memory_start,
+            unallocated_memory_size,
+            min_region_size,
+            mpu::Permissions::ReadOnly,
+        )
+    }
 
-            // Not enough room in Process struct to store the MPU region.
-            None
-        })
+    fn remove_mpu_region(&self, region: mpu::Region) -> Result<(), ErrorCode> {
+        let slot = self
+            .mpu_regions
+            .iter()
+            .find(|slot| {
+                slot.get().map_or(false, |r| {
+                    r.start_address() == region.start_address() && r.size() == region.size()
+                })
+            })
+            .ok_or(ErrorCode::INVAL)?;
+        self.mpu_config.map_or(Err(ErrorCode::FAIL), |config| {
+            self.chip
+                .mpu()
+                .remove_memory_region(region, config)
+                .map_err(|()| ErrorCode::NOSUPPORT)
+        })?;
+        slot.set(None);
+        Ok(())
     }
 
     fn sbrk(&self, increment: isize) -> Result<*const u8, Error> {
@@ -815,10 +913,52 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         })
     }
 
+    fn grant_allocated_size(&self, grant_num: usize) -> Option<usize> {
+        if !self.is_active() {
+            return None;
+        }
+
+        self.grant_pointers.map_or(None, |grant_pointers| {
+            // The lowest bit of a grant pointer marks whether it is entered.
+            let start = *grant_pointers.get(grant_num)? as usize & !0x1;
+            if start == 0 {
+                return None;
+            }
+            // Grants are allocated downwards, so this grant ends where the
+            // next higher one starts, or where the grant region starts.
+            let end = grant_pointers
+                .iter()
+                .map(|grant_ptr| *grant_ptr as usize & !0x1)
+                .filter(|grant_ptr| *grant_ptr > start)
+                .min()
+                .unwrap_or(self.grants_start.get() as usize);
+            Some(end - start)
+        })
+    }
+
     fn get_process_name(&self) -> &'static str {
         self.process_name
     }
 
+    fn credentials_verified(&self) -> bool {
+        self.credentials_verified
+    }
+
+    fn get_priority(&self) -> u32 {
+        let priority = self.header.get_priority().unwrap_or(u32::MAX);
+        cmp::min(priority, self.inherited_priority.get())
+    }
+
+    fn get_permitted_drivers(&self) -> Option<&[u32]> {
+        self.header.get_permitted_drivers()
+    }
+
+    fn inherit_priority(&self, priority: u32) {
+        if priority < self.inherited_priority.get() {
+            self.inherited_priority.set(priority);
+        }
+    }
+
     fn set_syscall_return_value(&self, return_value: SyscallReturn) {
         match self.stored_state.map(|stored_state| unsafe {
             // Actually set the return value for a particular process.
@@ -908,6 +1048,10 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             return None;
         }
 
+        // A fault recorded before the process runs again was handled by the
+        // platform, so it is not the reason of any later fault.
+        self.fault_recorded.set(false);
+
         let (switch_reason, stack_pointer) =
             self.stored_state.map_or((None, None), |stored_state| {
                 // Switch to the process. We guarantee that the memory pointers
@@ -966,6 +1110,67 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         });
     }
 
+    fn debug_run_count(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.run_count)
+    }
+
+    fn debug_stack_high_water_mark(&self) -> Option<usize> {
+        self.debug.map_or(None, |debug| {
+            match (debug.app_stack_start_pointer, debug.app_stack_min_pointer) {
+                (Some(start), Some(min)) => Some((start as usize).saturating_sub(min as usize)),
+                _ => None,
+            }
+        })
+    }
+
+    fn debug_execution_time_us(&self) -> u64 {
+        self.debug.map_or(0, |debug| debug.execution_time_us)
+    }
+
+    fn debug_executed(&self, execution_time_us: Option<u32>) {
+        self.debug.map(|debug| {
+            debug.run_count += 1;
+            debug.execution_time_us += execution_time_us.unwrap_or(0) as u64;
+            debug.traced_in_run = 0;
+        });
+    }
+
+    fn debug_set_syscall_tracing(&self, enabled: bool) {
+        self.debug.map(|debug| {
+            debug.trace_syscalls = enabled;
+            debug.traced_in_run = 0;
+            debug.untraced_count = 0;
+        });
+    }
+
+    fn debug_syscall_tracing(&self) -> bool {
+        self.debug.map_or(false, |debug| debug.trace_syscalls)
+    }
+
+    fn debug_trace_syscall(&self) -> bool {
+        let processid = self.processid();
+        self.debug.map_or(false, |debug| {
+            if !debug.trace_syscalls {
+                return false;
+            }
+            if debug.traced_in_run >= SYSCALL_TRACE_LIMIT {
+                debug.untraced_count += 1;
+                return false;
+            }
+            if debug.traced_in_run == 0 {
+                // Start the trace of each run with when it happened, and
+                // what the rate limit left out.
+                debug!(
+                    "[{:?}] run {}, {} us of CPU time, {} syscalls not traced",
+                    processid, debug.run_count, debug.execution_time_us, debug.untraced_count
+                );
+                debug.untraced_count = 0;
+            }
+            debug.traced_in_run += 1;
+            true
+        })
+    }
+
     fn print_memory_map(&self, writer: &mut dyn Write) {
         // Flash
         let flash_end = self.flash.as_ptr().wrapping_add(self.flash.len()) as usize;
@@ -1214,6 +1419,24 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             }
         });
     }
+
+    fn debug_stack_snapshot(&self, buffer: &mut [u8]) -> Option<(*const u8, usize)> {
+        let stack_pointer = self.stored_state.map_or(ptr::null(), |stored_state| {
+            self.chip
+                .userspace_kernel_boundary()
+                .get_stack_pointer(stored_state)
+        });
+        let app_break = self.app_break.get();
+        if stack_pointer < self.mem_start() || stack_pointer >= app_break {
+            return None;
+        }
+        let len = cmp::min(buffer.len(), app_break as usize - stack_pointer as usize);
+        // The stack pointer is within the memory of the process, and `len`
+        // bytes from it are before the break.
+        let stack = unsafe { slice::from_raw_parts(stack_pointer, len) };
+        buffer[..len].copy_from_slice(stack);
+        Some((stack_pointer, len))
+    }
 }
 
 fn exceeded_check(size: usize, allocated: usize) -> &'static str {
@@ -1232,6 +1455,39 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
     // Memory offset to make room for this process's metadata.
     const PROCESS_STRUCT_OFFSET: usize = mem::size_of::<ProcessStandard<C>>();
 
+    /// Allocate a new MPU region with `permissions`, and keep track of it.
+    fn allocate_mpu_region(
+        &self,
+        unallocated_memory_start: *const u8,
+        unallocated_memory_size: usize,
+        min_region_size: usize,
+        permissions: mpu::Permissions,
+    ) -> Option<mpu::Region> {
+        self.mpu_config.and_then(|mut config| {
+            let new_region = self.chip.mpu().allocate_region(
+                unallocated_memory_start,
+                unallocated_memory_size,
+                min_region_size,
+                permissions,
+                &mut config,
+            );
+
+            if new_region.is_none() {
+                return None;
+            }
+
+            for region in self.mpu_regions.iter() {
+                if region.get().is_none() {
+                    region.set(new_region);
+                    return new_region;
+                }
+            }
+
+            // Not enough room in Process struct to store the MPU region.
+            None
+        })
+    }
+
     pub(crate) unsafe fn create<'a>(
         kernel: &'static Kernel,
         chip: &'static C,
@@ -1240,6 +1496,7 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
         app_version: u16,
         remaining_memory: &'a mut [u8],
         fault_policy: &'static dyn ProcessFaultPolicy,
+        credentials_policy: Option<&CredentialsPolicy>,
         index: usize,
     ) -> Result<(Option<&'static dyn Process>, &'a mut [u8]), ProcessLoadError> {
         // Get a slice for just the app header.
@@ -1294,6 +1551,48 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
             return Ok((None, remaining_memory));
         }
 
+        // If the board checks credentials, only load the app if they allow
+        // it to run.
+        let mut credentials_verified = false;
+        let mut permissions = None;
+        if let Some(policy) = credentials_policy {
+            match policy.check(&tbf_header, app_flash) {
+                Some(verified) => {
+                    credentials_verified = verified;
+                    permissions = policy.permissions(&tbf_header, verified);
+                }
+                None => {
+                    if config::CONFIG.debug_load_processes {
+                        debug!(
+                            "Process not allowed to run flash={:#010X}-{:#010X} process={:?}",
+                            app_flash.as_ptr() as usize,
+                            app_flash.as_ptr() as usize + app_flash.len() - 1,
+                            process_name
+                        );
+                    }
+                    return Ok((None, remaining_memory));
+                }
+            }
+        }
+
+        // Do not load apps that declare more writeable flash than their
+        // permissions allow.
+        let max_flash_region_size = permissions.and_then(|p| p.max_flash_region_size);
+        if let Some(max_flash_region_size) = max_flash_region_size {
+            let flash_region_size: usize = (0..tbf_header.number_writeable_flash_regions())
+                .map(|i| tbf_header.get_writeable_flash_region(i).1 as usize)
+                .sum();
+            if flash_region_size > max_flash_region_size {
+                if config::CONFIG.debug_load_processes {
+                    debug!(
+                        "Process writeable flash of {} bytes exceeds quota of {} process={:?}",
+                        flash_region_size, max_flash_region_size, process_name
+                    );
+                }
+                return Ok((None, remaining_memory));
+            }
+        }
+
         // Otherwise, actually load the app.
         let process_ram_requested_size = tbf_header.get_minimum_app_ram_size() as usize;
         let init_fn = app_flash
@@ -1546,6 +1845,7 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
         process.memory_len = app_memory.len();
         process.header = tbf_header;
         process.kernel_memory_break = Cell::new(kernel_memory_break);
+        process.grants_start = Cell::new(kernel_memory_break);
         process.app_break = Cell::new(initial_app_brk);
         process.grant_pointers = MapCell::new(opts);
 
@@ -1556,6 +1856,11 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
         process.state = ProcessStateCell::new(process.kernel);
         process.fault_policy = fault_policy;
         process.restart_count = Cell::new(0);
+        process.last_fault = Cell::new(None);
+        process.fault_recorded = Cell::new(false);
+        process.inherited_priority = Cell::new(u32::MAX);
+        process.credentials_verified = credentials_verified;
+        process.grant_quota = permissions.and_then(|p| p.max_grant_size);
 
         process.mpu_config = MapCell::new(mpu_config);
         process.mpu_regions = [
@@ -1579,6 +1884,11 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
             last_syscall: None,
             dropped_upcall_count: 0,
             timeslice_expiration_count: 0,
+            run_count: 0,
+            execution_time_us: 0,
+            trace_syscalls: false,
+            traced_in_run: 0,
+            untraced_count: 0,
         });
 
         let flash_protected_size = process.header.get_protected_size() as usize;
@@ -1651,6 +1961,10 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
             debug.last_syscall = None;
             debug.dropped_upcall_count = 0;
             debug.timeslice_expiration_count = 0;
+            debug.run_count = 0;
+            debug.execution_time_us = 0;
+            debug.traced_in_run = 0;
+            debug.untraced_count = 0;
         });
 
         // FLASH
@@ -1733,6 +2047,7 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
             .wrapping_add(app_mpu_mem_len)
             .wrapping_sub(initial_kernel_memory_size);
         self.kernel_memory_break.set(kernel_brk);
+        self.grants_start.set(kernel_brk);
         // High water mark for `allow`ed memory is reset to the start of the
         // process's memory region.
         self.allow_high_water_mark.set(app_mpu_mem_start);
@@ -1865,6 +2180,11 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
             // Verify it didn't wrap around
             } else if new_break > self.kernel_memory_break.get() {
                 None
+            // Verify the grants stay within the quota of the process.
+            } else if self.grant_quota.map_or(false, |quota| {
+                (self.grants_start.get() as usize).saturating_sub(new_break as usize) > quota
+            }) {
+                None
             // Verify this is compatible with the MPU.
             } else if let Err(_) = self.chip.mpu().update_app_memory_region(
                 self.app_break.get(),
@@ -4,10 +4,12 @@ use core::convert::TryInto;
 use core::fmt;
 
 use crate::capabilities::ProcessManagementCapability;
+use crate::common::cells::{OptionalCell, TakeCell};
 use crate::config;
 use crate::debug;
 use crate::platform::Chip;
 use crate::process::Process;
+use crate::process_checker::CredentialsPolicy;
 use crate::process_policies::ProcessFaultPolicy;
 use crate::process_standard::ProcessStandard;
 use crate::sched::Kernel;
@@ -45,6 +47,10 @@ pub enum ProcessLoadError {
         expected_address: u32,
     },
 
+    /// Every slot of the processes array already holds a process, so there is
+    /// nowhere to store another one.
+    NoProcessSlot,
+
     /// Process loading error due (likely) to a bug in the kernel. If you get
     /// this error please open a bug report.
     InternalError,
@@ -98,11 +104,65 @@ impl fmt::Debug for ProcessLoadError {
                 actual_address, expected_address
             ),
 
+            ProcessLoadError::NoProcessSlot => write!(f, "No free slot for another process"),
+
             ProcessLoadError::InternalError => write!(f, "Error in kernel. Likely a bug."),
         }
     }
 }
 
+/// Find the TBF entry at the start of `flash`. Returns the TBF version, the
+/// length of the header and the flash of the whole entry, or `None` if there
+/// are no more entries. A header length of 0 means the entry could not be
+/// parsed and should be skipped.
+fn discover_entry(
+    flash: &'static [u8],
+) -> Result<Option<(u16, u16, &'static [u8])>, ProcessLoadError> {
+    // Get the first eight bytes of flash to check if there is another
+    // app.
+    let test_header_slice = match flash.get(0..8) {
+        Some(s) => s,
+        None => {
+            // Not enough flash to test for another app. This just means
+            // we are at the end of flash, and there are no more apps to
+            // load.
+            return Ok(None);
+        }
+    };
+
+    // Pass the first eight bytes to tbfheader to parse out the length of
+    // the tbf header and app. We then use those values to see if we have
+    // enough flash remaining to parse the remainder of the header.
+    let (version, header_length, entry_length) = match tock_tbf::parse::parse_tbf_header_lengths(
+        test_header_slice
+            .try_into()
+            .or(Err(ProcessLoadError::InternalError))?,
+    ) {
+        Ok((v, hl, el)) => (v, hl, el),
+        Err(tock_tbf::types::InitialTbfParseError::InvalidHeader(entry_length)) => {
+            // If we could not parse the header, then we want to skip over
+            // this app and look for the next one.
+            (0, 0, entry_length)
+        }
+        Err(tock_tbf::types::InitialTbfParseError::UnableToParse) => {
+            // Since Tock apps use a linked list, it is very possible the
+            // header we started to parse is intentionally invalid to signal
+            // the end of apps. This is ok and just means we have finished
+            // loading apps.
+            return Ok(None);
+        }
+    };
+
+    // Now we can get a slice which only encompasses the length of flash
+    // described by this tbf header.  We will either parse this as an actual
+    // app, or skip over this region.
+    let entry_flash = flash
+        .get(0..entry_length as usize)
+        .ok_or(ProcessLoadError::NotEnoughFlash)?;
+
+    Ok(Some((version, header_length, entry_flash)))
+}
+
 /// Helper function to load processes from flash into an array of active
 /// processes. This is the default template for loading processes, but a board
 /// is able to create its own `load_processes()` function and use that instead.
@@ -132,6 +192,53 @@ pub fn load_processes<C: Chip>(
     app_memory: &mut [u8], // not static, so that process.rs cannot hold on to slice w/o unsafe
     procs: &'static mut [Option<&'static dyn Process>],
     fault_policy: &'static dyn ProcessFaultPolicy,
+    capability: &dyn ProcessManagementCapability,
+) -> Result<(), ProcessLoadError> {
+    load_processes_inner(
+        kernel,
+        chip,
+        app_flash,
+        app_memory,
+        procs,
+        fault_policy,
+        None,
+        capability,
+    )
+}
+
+/// Like `load_processes()`, but only creates processes for the apps whose
+/// credentials `credentials_policy` allows to run. Apps that are not allowed
+/// to run are skipped, like disabled apps.
+pub fn load_processes_checked<C: Chip>(
+    kernel: &'static Kernel,
+    chip: &'static C,
+    app_flash: &'static [u8],
+    app_memory: &mut [u8], // not static, so that process.rs cannot hold on to slice w/o unsafe
+    procs: &'static mut [Option<&'static dyn Process>],
+    fault_policy: &'static dyn ProcessFaultPolicy,
+    credentials_policy: &'static CredentialsPolicy,
+    capability: &dyn ProcessManagementCapability,
+) -> Result<(), ProcessLoadError> {
+    load_processes_inner(
+        kernel,
+        chip,
+        app_flash,
+        app_memory,
+        procs,
+        fault_policy,
+        Some(credentials_policy),
+        capability,
+    )
+}
+
+fn load_processes_inner<C: Chip>(
+    kernel: &'static Kernel,
+    chip: &'static C,
+    app_flash: &'static [u8],
+    app_memory: &mut [u8],
+    procs: &'static mut [Option<&'static dyn Process>],
+    fault_policy: &'static dyn ProcessFaultPolicy,
+    credentials_policy: Option<&'static CredentialsPolicy>,
     _capability: &dyn ProcessManagementCapability,
 ) -> Result<(), ProcessLoadError> {
     if config::CONFIG.debug_load_processes {
@@ -149,48 +256,11 @@ pub fn load_processes<C: Chip>(
 
     // Try to discover up to `procs.len()` processes in flash.
     for i in 0..procs.len() {
-        // Get the first eight bytes of flash to check if there is another
-        // app.
-        let test_header_slice = match remaining_flash.get(0..8) {
-            Some(s) => s,
-            None => {
-                // Not enough flash to test for another app. This just means
-                // we are at the end of flash, and there are no more apps to
-                // load.
-                return Ok(());
-            }
-        };
-
-        // Pass the first eight bytes to tbfheader to parse out the length of
-        // the tbf header and app. We then use those values to see if we have
-        // enough flash remaining to parse the remainder of the header.
-        let (version, header_length, entry_length) = match tock_tbf::parse::parse_tbf_header_lengths(
-            test_header_slice
-                .try_into()
-                .or(Err(ProcessLoadError::InternalError))?,
-        ) {
-            Ok((v, hl, el)) => (v, hl, el),
-            Err(tock_tbf::types::InitialTbfParseError::InvalidHeader(entry_length)) => {
-                // If we could not parse the header, then we want to skip over
-                // this app and look for the next one.
-                (0, 0, entry_length)
-            }
-            Err(tock_tbf::types::InitialTbfParseError::UnableToParse) => {
-                // Since Tock apps use a linked list, it is very possible the
-                // header we started to parse is intentionally invalid to signal
-                // the end of apps. This is ok and just means we have finished
-                // loading apps.
-                return Ok(());
-            }
+        let (version, header_length, entry_flash) = match discover_entry(remaining_flash)? {
+            Some(entry) => entry,
+            None => return Ok(()),
         };
 
-        // Now we can get a slice which only encompasses the length of flash
-        // described by this tbf header.  We will either parse this as an actual
-        // app, or skip over this region.
-        let entry_flash = remaining_flash
-            .get(0..entry_length as usize)
-            .ok_or(ProcessLoadError::NotEnoughFlash)?;
-
         // Advance the flash slice for process discovery beyond this last entry.
         // This will be the start of where we look for a new process since Tock
         // processes are allocated back-to-back in flash.
@@ -218,6 +288,7 @@ pub fn load_processes<C: Chip>(
                     version,
                     remaining_memory,
                     fault_policy,
+                    credentials_policy,
                     i,
                 )?
             };
@@ -247,3 +318,167 @@ pub fn load_processes<C: Chip>(
 
     Ok(())
 }
+
+/// Loads processes at runtime from a staging area of flash, so that an
+/// application written there, for example by an over-the-air update, can run
+/// without a reboot.
+///
+/// The staging area holds TBF entries back-to-back, like the flash that
+/// `load_processes()` loads from. Each call to `load()` looks for entries that
+/// no process runs from yet, validates their headers, and creates a process
+/// for each in a free slot of the processes array. New processes start like
+/// processes loaded at boot, the next time the scheduler picks them. Memory
+/// for new processes is allocated from a region the board sets aside for this
+/// loader, and is not reclaimed.
+///
+/// Usage
+/// -----
+///
+/// ```rust,ignore
+/// let loader = static_init!(
+///     kernel::procs::StagingProcessLoader<sam4l::chip::Sam4l>,
+///     kernel::procs::StagingProcessLoader::new(
+///         board_kernel,
+///         chip,
+///         staging_flash,
+///         staging_memory,
+///         &mut PROCESSES,
+///         &FAULT_RESPONSE,
+///         &process_mgmt_cap,
+///     )
+/// );
+/// // Once a new application is written to the staging area:
+/// loader.load();
+/// ```
+pub struct StagingProcessLoader<C: 'static + Chip> {
+    kernel: &'static Kernel,
+    chip: &'static C,
+    staging_flash: &'static [u8],
+    app_memory: TakeCell<'static, [u8]>,
+    procs: TakeCell<'static, [Option<&'static dyn Process>]>,
+    fault_policy: &'static dyn ProcessFaultPolicy,
+    credentials_policy: OptionalCell<&'static CredentialsPolicy>,
+}
+
+impl<C: 'static + Chip> StagingProcessLoader<C> {
+    /// Create a loader for the processes in `staging_flash`, which get their
+    /// memory from `app_memory`.
+    ///
+    /// This is `unsafe` because `procs` must be the array of processes that
+    /// `kernel` was created with, and `app_memory` must not be used by
+    /// anything else, including the processes loaded at boot.
+    pub unsafe fn new(
+        kernel: &'static Kernel,
+        chip: &'static C,
+        staging_flash: &'static [u8],
+        app_memory: &'static mut [u8],
+        procs: &'static mut [Option<&'static dyn Process>],
+        fault_policy: &'static dyn ProcessFaultPolicy,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> StagingProcessLoader<C> {
+        StagingProcessLoader {
+            kernel: kernel,
+            chip: chip,
+            staging_flash: staging_flash,
+            app_memory: TakeCell::new(app_memory),
+            procs: TakeCell::new(procs),
+            fault_policy: fault_policy,
+            credentials_policy: OptionalCell::empty(),
+        }
+    }
+
+    /// Only create processes for the apps whose credentials `policy` allows
+    /// to run.
+    pub fn set_credentials_policy(&self, policy: &'static CredentialsPolicy) {
+        self.credentials_policy.set(policy);
+    }
+
+    /// Create a process for each application in the staging area that no
+    /// process runs from yet. Returns the number of processes created.
+    ///
+    /// Loading stops at the first error, and the processes created before it
+    /// keep running.
+    pub fn load(&self) -> Result<usize, ProcessLoadError> {
+        let mut loaded = 0;
+        let mut remaining_flash = self.staging_flash;
+
+        while let Some((version, header_length, entry_flash)) = discover_entry(remaining_flash)? {
+            remaining_flash = remaining_flash
+                .get(entry_flash.len()..)
+                .ok_or(ProcessLoadError::NotEnoughFlash)?;
+
+            // Skip entries that could not be parsed, and applications that
+            // are already running.
+            if header_length == 0 || self.is_loaded(entry_flash) {
+                continue;
+            }
+
+            self.procs
+                .map_or(Err(ProcessLoadError::InternalError), |procs| {
+                    let index = procs
+                        .iter()
+                        .position(|process| process.is_none())
+                        .ok_or(ProcessLoadError::NoProcessSlot)?;
+                    let memory = self
+                        .app_memory
+                        .take()
+                        .ok_or(ProcessLoadError::InternalError)?;
+                    let memory_ptr = memory.as_mut_ptr();
+                    let memory_len = memory.len();
+                    let result = unsafe {
+                        ProcessStandard::create(
+                            self.kernel,
+                            self.chip,
+                            entry_flash,
+                            header_length as usize,
+                            version,
+                            memory,
+                            self.fault_policy,
+                            self.credentials_policy.extract(),
+                            index,
+                        )
+                    };
+                    match result {
+                        Ok((process_option, unused_memory)) => {
+                            self.app_memory.replace(unused_memory);
+                            process_option.map(|process| {
+                                if config::CONFIG.debug_load_processes {
+                                    debug!(
+                                        "Loaded staged process[{}] from flash={:#010X}-{:#010X} = {:?}",
+                                        index,
+                                        entry_flash.as_ptr() as usize,
+                                        entry_flash.as_ptr() as usize + entry_flash.len() - 1,
+                                        process.get_process_name()
+                                    );
+                                }
+                                procs[index] = Some(process);
+                                loaded += 1;
+                            });
+                            Ok(())
+                        }
+                        Err(e) => {
+                            // `create()` does not hand the memory back on
+                            // failure, but it did not give it to a process.
+                            self.app_memory.replace(unsafe {
+                                core::slice::from_raw_parts_mut(memory_ptr, memory_len)
+                            });
+                            Err(e)
+                        }
+                    }
+                })?;
+        }
+
+        Ok(loaded)
+    }
+
+    /// Returns whether a process already runs from `entry_flash`.
+    fn is_loaded(&self, entry_flash: &[u8]) -> bool {
+        self.procs.map_or(false, |procs| {
+            procs.iter().any(|process| {
+                process.map_or(false, |process| {
+                    process.flash_start() == entry_flash.as_ptr()
+                })
+            })
+        })
+    }
+}
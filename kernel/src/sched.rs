@@ -6,6 +6,7 @@
 //! selected by a board.
 
 pub(crate) mod cooperative;
+pub(crate) mod edf;
 pub(crate) mod mlfq;
 pub(crate) mod priority;
 pub(crate) mod round_robin;
@@ -14,7 +15,7 @@ use core::cell::Cell;
 use core::ptr::NonNull;
 
 use crate::capabilities;
-use crate::common::cells::NumericCellExt;
+use crate::common::cells::{NumericCellExt, OptionalCell};
 use crate::common::dynamic_deferred_call::DynamicDeferredCall;
 use crate::config;
 use crate::debug;
@@ -25,6 +26,7 @@ use crate::ipc;
 use crate::memop;
 use crate::platform::mpu::MPU;
 use crate::platform::scheduler_timer::SchedulerTimer;
+use crate::platform::trace::TraceEvent;
 use crate::platform::watchdog::WatchDog;
 use crate::platform::{Chip, Platform};
 use crate::process::ProcessId;
@@ -146,6 +148,9 @@ pub struct Kernel {
     /// created and the data structures for grants have already been
     /// established.
     grants_finalized: Cell<bool>,
+
+    /// Client notified when a process terminates.
+    termination_client: OptionalCell<&'static dyn process::ProcessTerminationClient>,
 }
 
 /// Enum used to inform scheduler why a process stopped executing (aka why
@@ -181,9 +186,28 @@ impl Kernel {
             process_identifier_max: Cell::new(0),
             grant_counter: Cell::new(0),
             grants_finalized: Cell::new(false),
+            termination_client: OptionalCell::empty(),
         }
     }
 
+    /// Set the client notified whenever a process terminates, before its
+    /// grants are cleared.
+    pub fn set_process_termination_client(
+        &self,
+        client: &'static dyn process::ProcessTerminationClient,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        self.termination_client.set(client);
+    }
+
+    /// Tell the termination client that `processid` terminated.
+    ///
+    /// This is only exposed in the core kernel crate.
+    pub(crate) fn process_terminated(&self, processid: ProcessId) {
+        self.termination_client
+            .map(|client| client.process_terminated(processid));
+    }
+
     /// Something was scheduled for a process, so there is more work to do.
     ///
     /// This is only exposed in the core kernel crate.
@@ -466,9 +490,10 @@ impl Kernel {
         scheduler: &SC,
         _capability: &dyn capabilities::MainLoopCapability,
     ) -> ! {
-        chip.watchdog().setup();
+        let watchdog: &dyn WatchDog = platform.watchdog().unwrap_or(chip.watchdog());
+        watchdog.setup();
         loop {
-            chip.watchdog().tickle();
+            watchdog.tickle();
             unsafe {
                 // Ask the scheduler if we should do tasks inside of the kernel,
                 // such as handle interrupts. A scheduler may want to prioritize
@@ -478,13 +503,25 @@ impl Kernel {
                         // Execute kernel work. This includes handling
                         // interrupts and is how code in the chips/ and capsules
                         // crates is able to execute.
+                        platform
+                            .tracer()
+                            .map(|tracer| tracer.trace(TraceEvent::KernelWorkStart));
                         scheduler.execute_kernel_work(chip);
+                        platform
+                            .tracer()
+                            .map(|tracer| tracer.trace(TraceEvent::KernelWorkEnd));
                     }
                     false => {
                         // No kernel work ready, so ask scheduler for a process.
                         match scheduler.next(self) {
                             SchedulingDecision::RunProcess((appid, timeslice_us)) => {
                                 self.process_map_or((), appid, |process| {
+                                    platform.tracer().map(|tracer| {
+                                        tracer.trace(TraceEvent::Run {
+                                            process: appid,
+                                            timeslice_us: timeslice_us,
+                                        })
+                                    });
                                     let (reason, time_executed) = self.do_process(
                                         platform,
                                         chip,
@@ -493,6 +530,14 @@ impl Kernel {
                                         ipc,
                                         timeslice_us,
                                     );
+                                    process.debug_executed(time_executed);
+                                    platform.tracer().map(|tracer| {
+                                        tracer.trace(TraceEvent::stopped(
+                                            appid,
+                                            &reason,
+                                            time_executed,
+                                        ))
+                                    });
                                     scheduler.result(reason, time_executed);
                                 });
                             }
@@ -511,9 +556,22 @@ impl Kernel {
                                         && !DynamicDeferredCall::global_instance_calls_pending()
                                             .unwrap_or(false)
                                     {
-                                        chip.watchdog().suspend();
-                                        chip.sleep();
-                                        chip.watchdog().resume();
+                                        watchdog.suspend();
+                                        let deep = platform
+                                            .power_manager()
+                                            .map_or(false, |pm| pm.deep_sleep_ready());
+                                        platform.tracer().map(|tracer| {
+                                            tracer.trace(TraceEvent::Sleep { deep: deep })
+                                        });
+                                        if deep {
+                                            chip.deep_sleep();
+                                        } else {
+                                            chip.sleep();
+                                        }
+                                        platform
+                                            .tracer()
+                                            .map(|tracer| tracer.trace(TraceEvent::Wake));
+                                        watchdog.resume();
                                     }
                                 });
                             }
@@ -636,15 +694,27 @@ impl Kernel {
                     // why and handle the process as appropriate.
                     match context_switch_reason {
                         Some(ContextSwitchReason::Fault) => {
-                            // The app faulted, check if the chip wants to
-                            // handle the fault.
+                            // The app faulted. Save why first, so that the
+                            // reason is known whether or not the chip handles
+                            // the fault.
+                            process.record_fault();
+                            // Check if the chip wants to handle the fault.
                             if platform.process_fault_hook(process).is_err() {
                                 // Let process deal with it as appropriate.
                                 process.set_fault_state();
                             }
                         }
                         Some(ContextSwitchReason::SyscallFired { syscall }) => {
+                            let tracer = platform.tracer();
+                            tracer.map(|tracer| {
+                                tracer
+                                    .trace(TraceEvent::syscall_entry(process.processid(), &syscall))
+                            });
                             self.handle_syscall(platform, process, syscall);
+                            tracer.map(|tracer| {
+                                tracer
+                                    .trace(TraceEvent::syscall_exit(process.processid(), &syscall))
+                            });
                         }
                         Some(ContextSwitchReason::Interrupted) => {
                             if scheduler_timer.get_remaining_us().is_none() {
@@ -766,6 +836,7 @@ impl Kernel {
     ) {
         // Hook for process debugging.
         process.debug_syscall_called(syscall);
+        let trace = process.debug_trace_syscall() || config::CONFIG.trace_syscalls;
 
         // Enforce platform-specific syscall filtering here.
         //
@@ -808,7 +879,7 @@ impl Kernel {
         match syscall {
             Syscall::Memop { operand, arg0 } => {
                 let rval = memop::memop(process, operand, arg0);
-                if config::CONFIG.trace_syscalls {
+                if trace {
                     debug!(
                         "[{:?}] memop({}, {:#x}) = {:?}",
                         process.processid(),
@@ -820,7 +891,7 @@ impl Kernel {
                 process.set_syscall_return_value(rval);
             }
             Syscall::Yield { which, address } => {
-                if config::CONFIG.trace_syscalls {
+                if trace {
                     debug!("[{:?}] yield. which: {}", process.processid(), which);
                 }
                 if which > (YieldCall::Wait as usize) {
@@ -898,7 +969,7 @@ impl Kernel {
                     }
                     None => upcall.into_subscribe_failure(ErrorCode::NODEVICE),
                 });
-                if config::CONFIG.trace_syscalls {
+                if trace {
                     debug!(
                         "[{:?}] subscribe({:#x}, {}, @{:#x}, {:#x}) = {:?}",
                         process.processid(),
@@ -925,7 +996,7 @@ impl Kernel {
 
                 let res = SyscallReturn::from_command_return(cres);
 
-                if config::CONFIG.trace_syscalls {
+                if trace {
                     debug!(
                         "[{:?}] cmd({:#x}, {}, {:#x}, {:#x}) = {:?}",
                         process.processid(),
@@ -995,7 +1066,7 @@ impl Kernel {
                     ),
                 });
 
-                if config::CONFIG.trace_syscalls {
+                if trace {
                     debug!(
                         "[{:?}] read-write allow({:#x}, {}, @{:#x}, {:#x}) = {:?}",
                         process.processid(),
@@ -1071,7 +1142,7 @@ impl Kernel {
                     ),
                 });
 
-                if config::CONFIG.trace_syscalls {
+                if trace {
                     debug!(
                         "[{:?}] read-only allow({:#x}, {}, @{:#x}, {:#x}) = {:?}",
                         process.processid(),
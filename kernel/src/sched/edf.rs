@@ -0,0 +1,250 @@
+//! Earliest Deadline First Scheduler for Tock
+//!
+//! Processes declare jobs through a `DeadlineScheduler`, usually from the
+//! deadline syscall driver. A job must finish within a deadline of its
+//! release, and periodic jobs are released again every period. This
+//! scheduler always runs the ready process whose current job has the
+//! earliest deadline, and runs processes whose job missed its deadline
+//! first. Processes without a released job are background processes: they
+//! run round robin, with a timeslice, only while no process with a job is
+//! ready.
+//!
+//! A job runs until its process yields, or until a process with an earlier
+//! deadline becomes ready and preempts it. The scheduler does not enforce a
+//! budget: a job that never finishes keeps processes with later deadlines and
+//! background processes from running.
+
+use crate::common::dynamic_deferred_call::DynamicDeferredCall;
+use crate::hil::time::{self, Ticks};
+use crate::platform::Chip;
+use crate::process::ProcessId;
+use crate::sched::{Kernel, Scheduler, SchedulingDecision, StoppedExecutingReason};
+use crate::ErrorCode;
+use core::cell::Cell;
+
+/// Interface to declare the jobs of processes.
+pub trait DeadlineScheduler {
+    /// Release a job of `process` now, that must finish within `deadline_us`
+    /// microseconds. If `period_us` is not zero, a new job is released every
+    /// `period_us` microseconds. This replaces the jobs of the process.
+    ///
+    /// Returns `INVAL` if the deadline is zero, longer than the period, or
+    /// too long for the timer.
+    fn set_job(
+        &self,
+        process: ProcessId,
+        deadline_us: u32,
+        period_us: u32,
+    ) -> Result<(), ErrorCode>;
+
+    /// The current job of `process` finished. Returns whether it finished
+    /// after its deadline.
+    ///
+    /// Returns `INVAL` if the process has no job, and `ALREADY` if the next
+    /// periodic job has not been released yet.
+    fn finish_job(&self, process: ProcessId) -> Result<bool, ErrorCode>;
+
+    /// Remove the jobs of `process`, which becomes a background process.
+    fn clear_jobs(&self, process: ProcessId);
+}
+
+/// A job released at `release`, which must finish `deadline` ticks later.
+/// The next job is released `period` ticks after this one, if `period` is
+/// not zero.
+#[derive(Copy, Clone)]
+struct Job<T: Ticks> {
+    process: ProcessId,
+    release: T,
+    deadline: T,
+    period: T,
+}
+
+/// Per-process state, one for each slot of the `PROCESSES` array.
+pub struct EdfProcessNode<T: Ticks> {
+    job: Cell<Option<Job<T>>>,
+}
+
+impl<T: Ticks> EdfProcessNode<T> {
+    pub const fn new() -> EdfProcessNode<T> {
+        EdfProcessNode {
+            job: Cell::new(None),
+        }
+    }
+}
+
+pub struct EdfSched<'a, A: 'static + time::Alarm<'static>> {
+    kernel: &'static Kernel,
+    alarm: &'static A,
+    nodes: &'a [EdfProcessNode<A::Ticks>],
+    /// Index of the background process that ran last.
+    last_background: Cell<usize>,
+}
+
+impl<'a, A: 'static + time::Alarm<'static>> EdfSched<'a, A> {
+    /// Timeslice of background processes.
+    pub const BACKGROUND_TIMESLICE_US: u32 = 10000;
+
+    pub fn new(
+        kernel: &'static Kernel,
+        alarm: &'static A,
+        nodes: &'a [EdfProcessNode<A::Ticks>],
+    ) -> Self {
+        Self {
+            kernel,
+            alarm,
+            nodes,
+            last_background: Cell::new(0),
+        }
+    }
+
+    /// Ticks in half a period of the timer. Times further than this after
+    /// a release are taken to be before it.
+    fn half_range() -> u32 {
+        A::Ticks::max_value().into_u32() / 2
+    }
+
+    fn job(&self, process: ProcessId) -> Option<Job<A::Ticks>> {
+        self.nodes
+            .get(process.index)?
+            .job
+            .get()
+            .filter(|job| job.process == process)
+    }
+
+    /// Ticks left before the deadline of the released job of `process`, or
+    /// zero if it missed its deadline. `None` if the process has no released
+    /// job.
+    fn time_left(&self, process: ProcessId, now: A::Ticks) -> Option<A::Ticks> {
+        let job = self.job(process)?;
+        let elapsed = now.wrapping_sub(job.release);
+        if elapsed.into_u32() > Self::half_range() {
+            // The next periodic job is not released yet.
+            None
+        } else if elapsed < job.deadline {
+            Some(job.deadline.wrapping_sub(elapsed))
+        } else {
+            Some(A::Ticks::from(0))
+        }
+    }
+
+    /// The ready process whose job has the earliest deadline, with the ticks
+    /// left before it.
+    fn earliest_deadline(&self, now: A::Ticks) -> Option<(A::Ticks, ProcessId)> {
+        self.kernel
+            .get_process_iter()
+            .filter(|proc| proc.ready())
+            .filter_map(|proc| {
+                self.time_left(proc.processid(), now)
+                    .map(|left| (left, proc.processid()))
+            })
+            // `min_by_key` returns the first of equal elements, which is the
+            // earliest in the array.
+            .min_by_key(|(left, _)| *left)
+    }
+
+    /// The next ready process after the last background process to run.
+    fn next_background(&self) -> Option<ProcessId> {
+        let last = self.last_background.get();
+        let mut ready = self
+            .kernel
+            .get_process_iter()
+            .filter(|proc| proc.ready())
+            .map(|proc| proc.processid());
+        let first = ready.next()?;
+        Some(
+            core::iter::once(first)
+                .chain(ready)
+                .find(|processid| processid.index > last)
+                .unwrap_or(first),
+        )
+    }
+}
+
+impl<'a, A: 'static + time::Alarm<'static>> DeadlineScheduler for EdfSched<'a, A> {
+    fn set_job(
+        &self,
+        process: ProcessId,
+        deadline_us: u32,
+        period_us: u32,
+    ) -> Result<(), ErrorCode> {
+        let node = self.nodes.get(process.index).ok_or(ErrorCode::INVAL)?;
+        let deadline = A::ticks_from_us(deadline_us);
+        let period = A::ticks_from_us(period_us);
+        if deadline_us == 0
+            || (period_us != 0 && period_us < deadline_us)
+            || deadline.into_u32() > Self::half_range()
+            || period.into_u32() > Self::half_range()
+        {
+            return Err(ErrorCode::INVAL);
+        }
+        node.job.set(Some(Job {
+            process: process,
+            release: self.alarm.now(),
+            deadline: deadline,
+            period: period,
+        }));
+        Ok(())
+    }
+
+    fn finish_job(&self, process: ProcessId) -> Result<bool, ErrorCode> {
+        let mut job = self.job(process).ok_or(ErrorCode::INVAL)?;
+        let now = self.alarm.now();
+        let missed = match self.time_left(process, now) {
+            Some(left) => left == A::Ticks::from(0),
+            None => return Err(ErrorCode::ALREADY),
+        };
+        if job.period == A::Ticks::from(0) {
+            self.clear_jobs(process);
+            return Ok(missed);
+        }
+        job.release = job.release.wrapping_add(job.period);
+        // If the next job already missed its deadline too, release it now
+        // rather than running a backlog of late jobs.
+        let elapsed = now.wrapping_sub(job.release);
+        if elapsed.into_u32() <= Self::half_range() && elapsed >= job.deadline {
+            job.release = now;
+        }
+        self.nodes[process.index].job.set(Some(job));
+        Ok(missed)
+    }
+
+    fn clear_jobs(&self, process: ProcessId) {
+        if self.job(process).is_some() {
+            self.nodes[process.index].job.set(None);
+        }
+    }
+}
+
+impl<'a, A: 'static + time::Alarm<'static>, C: Chip> Scheduler<C> for EdfSched<'a, A> {
+    fn next(&self, kernel: &Kernel) -> SchedulingDecision {
+        if kernel.processes_blocked() {
+            // No processes ready
+            return SchedulingDecision::TrySleep;
+        }
+        if let Some((_, next)) = self.earliest_deadline(self.alarm.now()) {
+            return SchedulingDecision::RunProcess((next, None));
+        }
+        // Panic if fail bc processes_blocked()!
+        let next = self.next_background().unwrap();
+        self.last_background.set(next.index);
+        SchedulingDecision::RunProcess((next, Some(Self::BACKGROUND_TIMESLICE_US)))
+    }
+
+    fn result(&self, _: StoppedExecutingReason, _: Option<u32>) {}
+
+    unsafe fn continue_process(&self, id: ProcessId, chip: &C) -> bool {
+        if chip.has_pending_interrupts()
+            || DynamicDeferredCall::global_instance_calls_pending().unwrap_or(false)
+        {
+            return false;
+        }
+        // The process may have made a process with an earlier deadline
+        // ready, or declared or finished its own job.
+        let now = self.alarm.now();
+        match (self.time_left(id, now), self.earliest_deadline(now)) {
+            (Some(left), Some((earliest, _))) => earliest >= left,
+            (None, Some(_)) => false,
+            (_, None) => true,
+        }
+    }
+}
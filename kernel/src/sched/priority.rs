@@ -1,14 +1,25 @@
 //! Fixed Priority Scheduler for Tock
 //!
-//! This scheduler assigns priority to processes based on their order in the
-//! `PROCESSES` array, and runs the highest priority process available at any
-//! point in time. Kernel tasks (bottom half interrupt handling / deferred call
-//! handling) always take priority over userspace processes.
+//! This scheduler runs the highest priority process available at any point in
+//! time. Each process's priority comes from the priority TBF header, `0`
+//! being the highest, and processes without one get the lowest priority.
+//! Between processes with the same priority, the one earlier in the
+//! `PROCESSES` array runs first, so without priority headers the priority is
+//! the order of the processes in the array. Kernel tasks (bottom half
+//! interrupt handling / deferred call handling) always take priority over
+//! userspace processes.
 //!
 //! Notably, there is no need to enforce timeslices, as it is impossible for a
 //! process running to not be the highest priority process at any point while it
 //! is running. The only way for a process to longer be the highest priority is
-//! for an interrupt to occur, which will cause the process to stop running.
+//! for an interrupt to occur, which will cause the process to stop running, or
+//! for the process itself to make a higher priority process ready, for example
+//! with IPC. In both cases the higher priority process preempts it as soon as
+//! the kernel regains control.
+//!
+//! A service that receives an IPC request inherits the priority of its client
+//! until it yields with no tasks left, so that a process with a priority
+//! between the two cannot delay the client's request.
 
 use crate::common::cells::OptionalCell;
 use crate::common::dynamic_deferred_call::DynamicDeferredCall;
@@ -16,7 +27,8 @@ use crate::platform::Chip;
 use crate::process::ProcessId;
 use crate::sched::{Kernel, Scheduler, SchedulingDecision, StoppedExecutingReason};
 
-/// Priority scheduler based on the order of processes in the `PROCESSES` array.
+/// Priority scheduler based on the priority TBF header of processes, and on
+/// their order in the `PROCESSES` array.
 pub struct PrioritySched {
     kernel: &'static Kernel,
     running: OptionalCell<ProcessId>,
@@ -29,6 +41,17 @@ impl PrioritySched {
             running: OptionalCell::empty(),
         }
     }
+
+    /// The ready process that should run first, with its priority.
+    fn highest_ready(&self) -> Option<(u32, ProcessId)> {
+        self.kernel
+            .get_process_iter()
+            .filter(|proc| proc.ready())
+            .map(|proc| (proc.get_priority(), proc.processid()))
+            // `min_by_key` returns the first of equal elements, which is the
+            // earliest in the array.
+            .min_by_key(|(priority, _)| *priority)
+    }
 }
 
 impl<C: Chip> Scheduler<C> for PrioritySched {
@@ -37,14 +60,7 @@ impl<C: Chip> Scheduler<C> for PrioritySched {
             // No processes ready
             SchedulingDecision::TrySleep
         } else {
-            // Iterates in-order through the process array, always running the
-            // first process it finds that is ready to run. This enforces the
-            // priorities of all processes.
-            let next = self
-                .kernel
-                .get_process_iter()
-                .find(|&proc| proc.ready())
-                .map_or(None, |proc| Some(proc.processid()));
+            let next = self.highest_ready().map(|(_, processid)| processid);
             self.running.insert(next);
 
             SchedulingDecision::RunProcess((next.unwrap(), None))
@@ -58,14 +74,15 @@ impl<C: Chip> Scheduler<C> for PrioritySched {
         // this app is communicating via IPC with a higher priority app.
         !(chip.has_pending_interrupts()
             || DynamicDeferredCall::global_instance_calls_pending().unwrap_or(false)
-            || self
-                .kernel
-                .get_process_iter()
-                .position(|proc| proc.ready())
-                .map_or(false, |ready_idx| {
-                    self.running
-                        .map_or(false, |running| ready_idx < running.index)
-                }))
+            || self.running.map_or(false, |running| {
+                let running_priority = self
+                    .kernel
+                    .process_map_or(u32::MAX, *running, |proc| proc.get_priority());
+                self.highest_ready().map_or(false, |(priority, processid)| {
+                    priority < running_priority
+                        || (priority == running_priority && processid.index < running.index)
+                })
+            }))
     }
 
     fn result(&self, _: StoppedExecutingReason, _: Option<u32>) {
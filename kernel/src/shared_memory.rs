@@ -0,0 +1,286 @@
+//! Read-only memory regions shared by one process with others.
+//!
+//! A process can export a region of its memory, such as a snapshot of sensor
+//! data or a lookup table, and grant other processes access to it. A process
+//! that was granted access maps the region, and the kernel adds an MPU region
+//! to that process so that it can read the region in place, without copying
+//! it through IPC. The exporting process can revoke access at any time, which
+//! unmaps the region.
+//!
+//! Access does not outlive the processes involved. When the exporting
+//! process terminates, restarts or faults, the region is unmapped from every
+//! process that mapped it, and these processes get a revoke upcall, so that
+//! they cannot read what the next instance of the exporter puts in that
+//! memory. A new instance must export and grant again. When a process that
+//! mapped regions terminates, its MPU regions are dropped with the rest of
+//! its state and its grants are forgotten, so a restarted instance must be
+//! granted access again. This relies on the board registering the driver
+//! with `Kernel::set_process_termination_client()`.
+//!
+//! The MPU must be able to cover exactly the exported region, so the region
+//! should be aligned to its size, and on Cortex-M its size should be a power
+//! of two of at least 32 bytes. Mapping uses one of the MPU regions of the
+//! process that are left after its memory and flash.
+//!
+//! Processes are named by their identifier plus one, as in `ipc`. Exporting
+//! a new region unmaps the previous one from every process.
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: The region to export.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Access upcall, with the identifier of the exporting process and `1`
+//!   when it grants access to this process or `0` when it revokes it.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Grant the process `data1` access to the exported region.
+//! - `2`: Revoke the access of the process `data1`, unmapping the region if
+//!   it mapped it.
+//! - `3`: Map the region exported by the process `data1`. Returns its address
+//!   and length. Returns `RESERVE` if this process was not granted access,
+//!   `SIZE` if the MPU cannot cover the region exactly, and `NOMEM` if no MPU
+//!   region is left.
+//! - `4`: Unmap the region exported by the process `data1`.
+
+use crate::capabilities::MemoryAllocationCapability;
+use crate::grant::Grant;
+use crate::mem::Read;
+use crate::platform::mpu;
+use crate::process::{ProcessId, ProcessTerminationClient};
+use crate::sched::Kernel;
+use crate::upcall::Upcall;
+use crate::{CommandReturn, Driver, ErrorCode, ReadOnlyAppSlice};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x10006;
+
+/// State that is stored in each process's grant region to share memory.
+struct SharedMemoryData<const NUM_PROCS: usize> {
+    /// The region this process exports.
+    region: ReadOnlyAppSlice,
+    /// The processes allowed to map the region, by index.
+    granted: [Option<ProcessId>; NUM_PROCS],
+    /// The regions of other processes mapped in this process, by index of
+    /// the exporting process.
+    mapped: [Option<mpu::Region>; NUM_PROCS],
+    upcall: Upcall,
+}
+
+impl<const NUM_PROCS: usize> Default for SharedMemoryData<NUM_PROCS> {
+    fn default() -> SharedMemoryData<NUM_PROCS> {
+        SharedMemoryData {
+            region: ReadOnlyAppSlice::default(),
+            granted: [None; NUM_PROCS],
+            mapped: [None; NUM_PROCS],
+            upcall: Upcall::default(),
+        }
+    }
+}
+
+/// The shared memory mechanism struct.
+pub struct SharedMemory<const NUM_PROCS: usize> {
+    /// The grant regions for each process that holds the per-process data.
+    data: Grant<SharedMemoryData<NUM_PROCS>>,
+}
+
+impl<const NUM_PROCS: usize> SharedMemory<NUM_PROCS> {
+    pub fn new(kernel: &'static Kernel, capability: &dyn MemoryAllocationCapability) -> Self {
+        Self {
+            data: kernel.create_grant(capability),
+        }
+    }
+
+    /// The process named `target_id` by userspace, and its index.
+    fn lookup(&self, target_id: usize) -> Option<(ProcessId, usize)> {
+        let process = self
+            .data
+            .kernel
+            .lookup_app_by_identifier(target_id.checked_sub(1)?)?;
+        match process.index() {
+            Some(index) if index < NUM_PROCS => Some((process, index)),
+            _ => None,
+        }
+    }
+
+    /// Remove the region of the exporter at `exporter_index` from the MPU
+    /// regions of `processid`, if it is mapped.
+    fn unmap(&self, processid: ProcessId, exporter_index: usize) {
+        let _ = self.data.enter(processid, |data| {
+            if let Some(region) = data.mapped[exporter_index].take() {
+                self.data.kernel.process_map_or((), processid, |process| {
+                    let _ = process.remove_mpu_region(region);
+                });
+            }
+        });
+    }
+
+    /// Set whether `target_id` may map the region of `appid`.
+    fn set_access(&self, appid: ProcessId, target_id: usize, granted: bool) -> CommandReturn {
+        let (target, target_index) = match self.lookup(target_id) {
+            Some(target) if target.0 != appid => target,
+            _ => return CommandReturn::failure(ErrorCode::INVAL),
+        };
+        let res = self.data.enter(appid, |data| {
+            data.granted[target_index] = if granted { Some(target) } else { None };
+        });
+        if let Err(e) = res {
+            return CommandReturn::failure(e.into());
+        }
+
+        if !granted {
+            if let Some(index) = appid.index() {
+                self.unmap(target, index);
+            }
+        }
+        let _ = self.data.enter(target, |data| {
+            data.upcall.schedule(appid.id() + 1, granted as usize, 0);
+        });
+        CommandReturn::success()
+    }
+
+    /// Map the region of `exporter_id` in `appid`.
+    fn map(&self, appid: ProcessId, exporter_id: usize) -> CommandReturn {
+        let (exporter, exporter_index) = match self.lookup(exporter_id) {
+            Some(exporter) if exporter.0 != appid => exporter,
+            _ => return CommandReturn::failure(ErrorCode::INVAL),
+        };
+        let index = match appid.index() {
+            Some(index) if index < NUM_PROCS => index,
+            _ => return CommandReturn::failure(ErrorCode::INVAL),
+        };
+
+        let region = self
+            .data
+            .enter(exporter, |data| {
+                if data.granted[index] != Some(appid) || data.region.len() == 0 {
+                    None
+                } else {
+                    Some((data.region.ptr(), data.region.len()))
+                }
+            })
+            .unwrap_or(None);
+        let (start, len) = match region {
+            Some(region) => region,
+            None => return CommandReturn::failure(ErrorCode::RESERVE),
+        };
+
+        self.data
+            .enter(appid, |data| {
+                if data.mapped[exporter_index].is_some() {
+                    return CommandReturn::failure(ErrorCode::ALREADY);
+                }
+                self.data.kernel.process_map_or(
+                    CommandReturn::failure(ErrorCode::FAIL),
+                    appid,
+                    |process| match process.add_read_only_mpu_region(start, len, len) {
+                        Some(region) if region.start_address() == start && region.size() == len => {
+                            data.mapped[exporter_index] = Some(region);
+                            CommandReturn::success_u32_u32(start as u32, len as u32)
+                        }
+                        Some(region) => {
+                            // The region would expose more than was exported.
+                            let _ = process.remove_mpu_region(region);
+                            CommandReturn::failure(ErrorCode::SIZE)
+                        }
+                        None => CommandReturn::failure(ErrorCode::NOMEM),
+                    },
+                )
+            })
+            .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+    }
+}
+
+impl<const NUM_PROCS: usize> Driver for SharedMemory<NUM_PROCS> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut upcall: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        match subscribe_num {
+            0 => self
+                .data
+                .enter(app_id, |data| {
+                    core::mem::swap(&mut data.upcall, &mut upcall);
+                    upcall
+                })
+                .map_err(|e| (upcall, e.into())),
+            _ => Err((upcall, ErrorCode::NOSUPPORT)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_number: usize,
+        target_id: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_number {
+            0 => CommandReturn::success(),
+            1 => self.set_access(appid, target_id, true),
+            2 => self.set_access(appid, target_id, false),
+            3 => self.map(appid, target_id),
+            4 => match self.lookup(target_id) {
+                Some((_, exporter_index)) => {
+                    self.unmap(appid, exporter_index);
+                    CommandReturn::success()
+                }
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        subdriver: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        if subdriver != 0 {
+            return Err((slice, ErrorCode::NOSUPPORT));
+        }
+        let res = self.data.enter(appid, |data| {
+            core::mem::swap(&mut data.region, &mut slice);
+        });
+        if let Err(e) = res {
+            return Err((slice, e.into()));
+        }
+
+        // Processes must not keep reading the previous region.
+        if let Some(index) = appid.index().filter(|index| *index < NUM_PROCS) {
+            self.data.each(|processid, data| {
+                if let Some(region) = data.mapped[index].take() {
+                    self.data.kernel.process_map_or((), processid, |process| {
+                        let _ = process.remove_mpu_region(region);
+                    });
+                }
+            });
+        }
+        Ok(slice)
+    }
+}
+
+impl<const NUM_PROCS: usize> ProcessTerminationClient for SharedMemory<NUM_PROCS> {
+    fn process_terminated(&self, processid: ProcessId) {
+        let index = match processid.index() {
+            Some(index) if index < NUM_PROCS => index,
+            _ => return,
+        };
+        self.data.each(|importer, data| {
+            // The next instance of the process must not inherit its grants.
+            data.granted[index] = None;
+            if let Some(region) = data.mapped[index].take() {
+                self.data.kernel.process_map_or((), importer, |process| {
+                    let _ = process.remove_mpu_region(region);
+                });
+                data.upcall.schedule(processid.id() + 1, 0, 0);
+            }
+        });
+    }
+}
@@ -408,6 +408,20 @@ pub enum ContextSwitchReason {
     Interrupted,
 }
 
+/// Why a process faulted, as reported by the architecture.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FaultInfo {
+    /// Architecture-specific fault status: the CFSR on Cortex-M, `mcause` on
+    /// RISC-V. On Cortex-M, `0` if the fault was not raised by the hardware,
+    /// for example if the process corrupted its stack pointer.
+    pub status: u32,
+    /// The HFSR on Cortex-M, `0` on other architectures.
+    pub hard_fault_status: u32,
+    /// The address the process faulted on, if the hardware reported one: the
+    /// MMFAR or BFAR on Cortex-M, `mtval` on RISC-V.
+    pub address: Option<u32>,
+}
+
 /// The `UserspaceKernelBoundary` trait is implemented by the
 /// architectural component of the chip implementation of Tock. This
 /// trait allows the kernel to switch to and from processes
@@ -586,4 +600,13 @@ pub trait UserspaceKernelBoundary {
         state: &Self::StoredState,
         writer: &mut dyn Write,
     );
+
+    /// Return the stack pointer of the process, as stored in `state` when it
+    /// stopped running. It is not validated, and may point anywhere if the
+    /// process faulted.
+    fn get_stack_pointer(&self, state: &Self::StoredState) -> *const u8;
+
+    /// Return why the process faulted, after `switch_to_process()` returned
+    /// `ContextSwitchReason::Fault`.
+    fn get_fault_info(&self, state: &Self::StoredState) -> FaultInfo;
 }
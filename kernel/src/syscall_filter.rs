@@ -0,0 +1,39 @@
+//! Policies for filtering the system calls of processes.
+
+use crate::errorcode::ErrorCode;
+use crate::process::Process;
+use crate::syscall::Syscall;
+
+/// Decides whether a process may make a system call, before the kernel
+/// dispatches it. Boards consult it from `Platform::filter_syscall()`.
+pub trait SyscallFilter {
+    /// Return `Ok(())` if `process` may make `syscall`, or the `ErrorCode`
+    /// to return to the process otherwise.
+    fn filter_syscall(&self, process: &dyn Process, syscall: &Syscall) -> Result<(), ErrorCode>;
+}
+
+/// Allow processes to use only the drivers listed in the permissions
+/// element of their TBF header. Processes without one may use all drivers.
+///
+/// Only the system calls to a driver (subscribe, command and allows) are
+/// filtered. Calls to other drivers return `NODEVICE`, as if the board did not
+/// have them.
+pub struct TbfHeaderFilterDefaultAllow {}
+
+impl SyscallFilter for TbfHeaderFilterDefaultAllow {
+    fn filter_syscall(&self, process: &dyn Process, syscall: &Syscall) -> Result<(), ErrorCode> {
+        let driver_number = match *syscall {
+            Syscall::Subscribe { driver_number, .. }
+            | Syscall::Command { driver_number, .. }
+            | Syscall::ReadWriteAllow { driver_number, .. }
+            | Syscall::ReadOnlyAllow { driver_number, .. } => driver_number,
+            _ => return Ok(()),
+        };
+        match process.get_permitted_drivers() {
+            Some(drivers) if !drivers.iter().any(|d| *d as usize == driver_number) => {
+                Err(ErrorCode::NODEVICE)
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 use core::ptr::NonNull;
 
+use crate::capabilities;
 use crate::config;
 use crate::debug;
 use crate::process;
@@ -46,6 +47,20 @@ impl Upcall {
         }
     }
 
+    /// Create a new `Upcall` for the process `app_id`.
+    ///
+    /// This constructor is public but protected with a capability so that
+    /// external implementations of `Process` can use it.
+    pub fn new_external(
+        app_id: ProcessId,
+        upcall_id: UpcallId,
+        appdata: usize,
+        fn_ptr: NonNull<*mut ()>,
+        _capability: &dyn capabilities::ExternalProcessCapability,
+    ) -> Upcall {
+        Upcall::new(app_id, upcall_id, appdata, fn_ptr)
+    }
+
     /// Tell the scheduler to run this upcall for the process.
     ///
     /// The three arguments are passed to the upcall in userspace.
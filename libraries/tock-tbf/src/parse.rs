@@ -126,10 +126,13 @@ pub fn parse_tbf_header(
                 // Places to save fields that we parse out of the header
                 // options.
                 let mut main_pointer: Option<types::TbfHeaderV2Main> = None;
+                let mut program_pointer: Option<types::TbfHeaderV2Program> = None;
                 let mut wfr_pointer: [Option<types::TbfHeaderV2WriteableFlashRegion>; 4] =
                     Default::default();
                 let mut app_name_str = "";
                 let mut fixed_address_pointer: Option<types::TbfHeaderV2FixedAddresses> = None;
+                let mut priority_pointer: Option<types::TbfHeaderV2Priority> = None;
+                let mut permissions_pointer: Option<types::TbfHeaderV2Permissions> = None;
 
                 // Iterate the remainder of the header looking for TLV entries.
                 while remaining.len() > 0 {
@@ -157,6 +160,17 @@ pub fn parse_tbf_header(
                             }
                         }
 
+                        types::TbfHeaderTypes::TbfHeaderProgram => {
+                            let entry_len = mem::size_of::<types::TbfHeaderV2Program>();
+                            if tlv_header.length as usize == entry_len {
+                                program_pointer = Some(remaining.try_into()?);
+                            } else {
+                                return Err(types::TbfParseError::BadTlvEntry(
+                                    tlv_header.tipe as usize,
+                                ));
+                            }
+                        }
+
                         types::TbfHeaderTypes::TbfHeaderWriteableFlashRegions => {
                             // Length must be a multiple of the size of a region definition.
                             if tlv_header.length as usize
@@ -219,6 +233,26 @@ pub fn parse_tbf_header(
                             }
                         }
 
+                        types::TbfHeaderTypes::TbfHeaderPermissions => {
+                            permissions_pointer = Some(
+                                remaining
+                                    .get(0..tlv_header.length as usize)
+                                    .ok_or(types::TbfParseError::NotEnoughFlash)?
+                                    .try_into()?,
+                            );
+                        }
+
+                        types::TbfHeaderTypes::TbfHeaderPriority => {
+                            let entry_len = 4;
+                            if tlv_header.length as usize == entry_len {
+                                priority_pointer = Some(remaining.try_into()?);
+                            } else {
+                                return Err(types::TbfParseError::BadTlvEntry(
+                                    tlv_header.tipe as usize,
+                                ));
+                            }
+                        }
+
                         _ => {}
                     }
 
@@ -233,9 +267,12 @@ pub fn parse_tbf_header(
                 let tbf_header = types::TbfHeaderV2 {
                     base: tbf_header_base,
                     main: main_pointer,
+                    program: program_pointer,
                     package_name: Some(app_name_str),
                     writeable_regions: Some(wfr_pointer),
                     fixed_addresses: fixed_address_pointer,
+                    priority: priority_pointer,
+                    permissions: permissions_pointer,
                 };
 
                 Ok(types::TbfHeader::TbfHeaderV2(tbf_header))
@@ -244,3 +281,31 @@ pub fn parse_tbf_header(
         _ => Err(types::TbfParseError::UnsupportedVersion(version)),
     }
 }
+
+/// Parse the footer at the start of `footers`, the part of an app's flash
+/// after its binary.
+///
+/// ## Return
+///
+/// The credentials in the footer and the length of the footer, including its
+/// TLV header and padding, to find the next footer. Footers of other types
+/// are returned as credentials of the `Reserved` format.
+pub fn parse_tbf_footer(
+    footers: &'static [u8],
+) -> Result<(types::TbfFooterV2Credentials, u32), types::TbfParseError> {
+    let tlv_header: types::TbfHeaderTlv = footers.try_into()?;
+    let data = footers
+        .get(4..4 + tlv_header.length as usize)
+        .ok_or(types::TbfParseError::NotEnoughFlash)?;
+    let footer_len = 4 + align4!(tlv_header.length as u32);
+    match tlv_header.tipe {
+        types::TbfHeaderTypes::TbfFooterCredentials => Ok((data.try_into()?, footer_len)),
+        _ => Ok((
+            types::TbfFooterV2Credentials {
+                format: types::TbfFooterV2CredentialsType::Reserved,
+                data: data,
+            },
+            footer_len,
+        )),
+    }
+}
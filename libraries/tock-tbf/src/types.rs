@@ -105,6 +105,10 @@ pub enum TbfHeaderTypes {
     TbfHeaderWriteableFlashRegions = 2,
     TbfHeaderPackageName = 3,
     TbfHeaderFixedAddresses = 5,
+    TbfHeaderPermissions = 6,
+    TbfHeaderProgram = 9,
+    TbfHeaderPriority = 10,
+    TbfFooterCredentials = 128,
 
     /// Some field in the header that we do not understand. Since the TLV format
     /// specifies the length of each section, if we get a field we do not
@@ -130,6 +134,20 @@ pub struct TbfHeaderV2Main {
     minimum_ram_size: u32,
 }
 
+/// The v2 program section for apps.
+///
+/// It holds the same fields as the main section, and also marks where the
+/// binary ends and the footers start. If an app has both sections, the
+/// program section is used.
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2Program {
+    init_fn_offset: u32,
+    protected_size: u32,
+    minimum_ram_size: u32,
+    binary_end_offset: u32,
+    version: u32,
+}
+
 /// Writeable flash regions only need an offset and size.
 ///
 /// There can be multiple (or zero) flash regions defined, so this is its own
@@ -164,6 +182,51 @@ pub struct TbfHeaderV2FixedAddresses {
     start_process_flash: u32,
 }
 
+/// The scheduling priority of the process.
+///
+/// Schedulers that support priorities run the ready process with the lowest
+/// value first. Processes without this header get the lowest priority.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TbfHeaderV2Priority {
+    priority: u32,
+}
+
+/// Maximum number of driver numbers in a permissions header.
+pub const MAX_PERMITTED_DRIVERS: usize = 16;
+
+/// The drivers the process may use.
+///
+/// A process with this header may only make system calls to the listed
+/// drivers, on boards that filter system calls with it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TbfHeaderV2Permissions {
+    driver_numbers: [u32; MAX_PERMITTED_DRIVERS],
+    length: usize,
+}
+
+/// Formats of the credentials in a credentials footer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TbfFooterV2CredentialsType {
+    Reserved = 0,
+    Rsa3072Key = 1,
+    Rsa4096Key = 2,
+    SHA256 = 3,
+    SHA384 = 4,
+    SHA512 = 5,
+    /// An ECDSA signature with the NIST P-256 curve and SHA-256: the `r` and
+    /// `s` values as 32 byte big-endian integers.
+    EcdsaNistP256 = 6,
+}
+
+/// Credentials of an app, such as a signature, found in a footer after its
+/// binary. They cover the TBF header and the binary, which is called the
+/// integrity region.
+#[derive(Clone, Copy, Debug)]
+pub struct TbfFooterV2Credentials {
+    pub format: TbfFooterV2CredentialsType,
+    pub data: &'static [u8],
+}
+
 // Conversion functions from slices to the various TBF fields.
 
 impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Base {
@@ -209,6 +272,10 @@ impl core::convert::TryFrom<u16> for TbfHeaderTypes {
             2 => Ok(TbfHeaderTypes::TbfHeaderWriteableFlashRegions),
             3 => Ok(TbfHeaderTypes::TbfHeaderPackageName),
             5 => Ok(TbfHeaderTypes::TbfHeaderFixedAddresses),
+            6 => Ok(TbfHeaderTypes::TbfHeaderPermissions),
+            9 => Ok(TbfHeaderTypes::TbfHeaderProgram),
+            10 => Ok(TbfHeaderTypes::TbfHeaderPriority),
+            128 => Ok(TbfHeaderTypes::TbfFooterCredentials),
             _ => Ok(TbfHeaderTypes::Unknown),
         }
     }
@@ -258,6 +325,75 @@ impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Main {
     }
 }
 
+impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Program {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderV2Program, Self::Error> {
+        Ok(TbfHeaderV2Program {
+            init_fn_offset: u32::from_le_bytes(
+                b.get(0..4)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+            protected_size: u32::from_le_bytes(
+                b.get(4..8)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+            minimum_ram_size: u32::from_le_bytes(
+                b.get(8..12)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+            binary_end_offset: u32::from_le_bytes(
+                b.get(12..16)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+            version: u32::from_le_bytes(
+                b.get(16..20)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+        })
+    }
+}
+
+impl core::convert::TryFrom<u32> for TbfFooterV2CredentialsType {
+    type Error = TbfParseError;
+
+    fn try_from(format: u32) -> Result<TbfFooterV2CredentialsType, Self::Error> {
+        match format {
+            0 => Ok(TbfFooterV2CredentialsType::Reserved),
+            1 => Ok(TbfFooterV2CredentialsType::Rsa3072Key),
+            2 => Ok(TbfFooterV2CredentialsType::Rsa4096Key),
+            3 => Ok(TbfFooterV2CredentialsType::SHA256),
+            4 => Ok(TbfFooterV2CredentialsType::SHA384),
+            5 => Ok(TbfFooterV2CredentialsType::SHA512),
+            6 => Ok(TbfFooterV2CredentialsType::EcdsaNistP256),
+            _ => Err(TbfParseError::BadTlvEntry(
+                TbfHeaderTypes::TbfFooterCredentials as usize,
+            )),
+        }
+    }
+}
+
+impl core::convert::TryFrom<&'static [u8]> for TbfFooterV2Credentials {
+    type Error = TbfParseError;
+
+    fn try_from(b: &'static [u8]) -> Result<TbfFooterV2Credentials, Self::Error> {
+        let format = u32::from_le_bytes(
+            b.get(0..4)
+                .ok_or(TbfParseError::NotEnoughFlash)?
+                .try_into()?,
+        );
+        Ok(TbfFooterV2Credentials {
+            format: format.try_into()?,
+            data: b.get(4..).ok_or(TbfParseError::NotEnoughFlash)?,
+        })
+    }
+}
+
 impl core::convert::TryFrom<&[u8]> for TbfHeaderV2WriteableFlashRegion {
     type Error = TbfParseError;
 
@@ -296,6 +432,42 @@ impl core::convert::TryFrom<&[u8]> for TbfHeaderV2FixedAddresses {
     }
 }
 
+impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Priority {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderV2Priority, Self::Error> {
+        Ok(TbfHeaderV2Priority {
+            priority: u32::from_le_bytes(
+                b.get(0..4)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+        })
+    }
+}
+
+impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Permissions {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderV2Permissions, Self::Error> {
+        let length = b.len() / 4;
+        if b.len() % 4 != 0 || length > MAX_PERMITTED_DRIVERS {
+            return Err(TbfParseError::BadTlvEntry(
+                TbfHeaderTypes::TbfHeaderPermissions as usize,
+            ));
+        }
+
+        let mut permissions = TbfHeaderV2Permissions {
+            driver_numbers: [0; MAX_PERMITTED_DRIVERS],
+            length: length,
+        };
+        for (i, driver_number) in b.chunks(4).enumerate() {
+            permissions.driver_numbers[i] = u32::from_le_bytes(driver_number.try_into()?);
+        }
+        Ok(permissions)
+    }
+}
+
 /// Single header that can contain all parts of a v2 header.
 ///
 /// Note, this struct limits the number of writeable regions an app can have to
@@ -305,9 +477,12 @@ impl core::convert::TryFrom<&[u8]> for TbfHeaderV2FixedAddresses {
 pub struct TbfHeaderV2 {
     pub(crate) base: TbfHeaderV2Base,
     pub(crate) main: Option<TbfHeaderV2Main>,
+    pub(crate) program: Option<TbfHeaderV2Program>,
     pub(crate) package_name: Option<&'static str>,
     pub(crate) writeable_regions: Option<[Option<TbfHeaderV2WriteableFlashRegion>; 4]>,
     pub(crate) fixed_addresses: Option<TbfHeaderV2FixedAddresses>,
+    pub(crate) priority: Option<TbfHeaderV2Priority>,
+    pub(crate) permissions: Option<TbfHeaderV2Permissions>,
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -348,7 +523,10 @@ impl TbfHeader {
     /// needed for this app.
     pub fn get_minimum_app_ram_size(&self) -> u32 {
         match *self {
-            TbfHeader::TbfHeaderV2(hd) => hd.main.map_or(0, |m| m.minimum_ram_size),
+            TbfHeader::TbfHeaderV2(hd) => match hd.program {
+                Some(p) => p.minimum_ram_size,
+                None => hd.main.map_or(0, |m| m.minimum_ram_size),
+            },
             _ => 0,
         }
     }
@@ -358,7 +536,11 @@ impl TbfHeader {
     pub fn get_protected_size(&self) -> u32 {
         match *self {
             TbfHeader::TbfHeaderV2(hd) => {
-                hd.main.map_or(0, |m| m.protected_size) + (hd.base.header_size as u32)
+                let protected_size = match hd.program {
+                    Some(p) => p.protected_size,
+                    None => hd.main.map_or(0, |m| m.protected_size),
+                };
+                protected_size + (hd.base.header_size as u32)
             }
             _ => 0,
         }
@@ -369,12 +551,35 @@ impl TbfHeader {
     pub fn get_init_function_offset(&self) -> u32 {
         match *self {
             TbfHeader::TbfHeaderV2(hd) => {
-                hd.main.map_or(0, |m| m.init_fn_offset) + (hd.base.header_size as u32)
+                let init_fn_offset = match hd.program {
+                    Some(p) => p.init_fn_offset,
+                    None => hd.main.map_or(0, |m| m.init_fn_offset),
+                };
+                init_fn_offset + (hd.base.header_size as u32)
             }
             _ => 0,
         }
     }
 
+    /// Get the offset from the beginning of the app's flash region where the
+    /// binary ends and the footers start. If the app has no program section,
+    /// it has no footers, and this is `None`.
+    pub fn get_binary_end_offset(&self) -> Option<u32> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.program.map(|p| p.binary_end_offset),
+            _ => None,
+        }
+    }
+
+    /// Get the version of the app from its program section, or 0 if it has
+    /// none.
+    pub fn get_app_version(&self) -> u32 {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.program.map_or(0, |p| p.version),
+            _ => 0,
+        }
+    }
+
     /// Get the name of the app.
     pub fn get_package_name(&self) -> Option<&'static str> {
         match *self {
@@ -434,4 +639,25 @@ impl TbfHeader {
             start => Some(start),
         }
     }
+
+    /// Get the scheduling priority of the process, where `0` is the highest.
+    /// If the process has no priority header, return `None`.
+    pub fn get_priority(&self) -> Option<u32> {
+        match self {
+            TbfHeader::TbfHeaderV2(hd) => hd.priority.map(|p| p.priority),
+            _ => None,
+        }
+    }
+
+    /// Get the driver numbers the process may make system calls to. If the
+    /// process has no permissions header, return `None`.
+    pub fn get_permitted_drivers(&self) -> Option<&[u32]> {
+        match self {
+            TbfHeader::TbfHeaderV2(hd) => hd
+                .permissions
+                .as_ref()
+                .map(|p| &p.driver_numbers[..p.length]),
+            _ => None,
+        }
+    }
 }